@@ -0,0 +1,64 @@
+//! The kernel's high-resolution monotonic clock is the system counter, which counts at a constant frequency that
+//! `CNTFRQ_EL0` tells us, and is synchronized between CPUs. The EL1 physical timer, which compares against the
+//! same counter, provides the kernel's timer interrupt.
+
+use crate::{exception::TIMER_PERIOD, interrupts};
+use core::time::Duration;
+use fdt::Fdt;
+use hal_aarch64::hw::sysreg::{CntfrqEl0, CntkctlEl1, CntpCtlEl0, CntpTvalEl0, CntpctEl0};
+use mulch::InitGuard;
+use tracing::info;
+
+/// The frequency the system counter counts at, in Hz.
+static FREQUENCY: InitGuard<u64> = InitGuard::uninit();
+/// The value of the system counter when the clock was initialized. Time is measured from this point.
+static BOOT_TIME: InitGuard<u64> = InitGuard::uninit();
+
+/// The interrupt ID of the EL1 physical timer. This is a PPI, so is the same on every CPU.
+pub static TIMER_INTERRUPT: InitGuard<u32> = InitGuard::uninit();
+
+pub fn init() {
+    let frequency = CntfrqEl0::read();
+    info!("System counter frequency is {}Hz", frequency);
+
+    FREQUENCY.initialize(frequency);
+    BOOT_TIME.initialize(CntpctEl0::read());
+
+    // Let EL0 read the system counter, so tasks can get the time from the vDSO page
+    unsafe {
+        CntkctlEl1::write(CntkctlEl1::read() | 0b1);
+    }
+    kernel::vdso::set_clock(frequency, *BOOT_TIME.get());
+}
+
+/// Start the timer interrupt. The interrupt controller must have been initialized first.
+pub fn start_timer(fdt: &Fdt) {
+    /*
+     * The timer's node lists the interrupts of the secure physical timer, the non-secure physical timer, the
+     * virtual timer, and the hypervisor timer, in that order. We use the non-secure physical timer.
+     */
+    let node = fdt.find_compatible(&["arm,armv8-timer"]).expect("Device tree does not contain a timer");
+    let interrupt = interrupts::fdt_interrupts(node).nth(1).expect("Timer does not have a physical interrupt");
+    // The timer's interrupt is handled specially by the exception handler, so this handler should not be called
+    let id = interrupts::handle_wired_interrupt(interrupt, |_| unreachable!());
+    TIMER_INTERRUPT.initialize(id);
+
+    set_next_tick();
+}
+
+/// Program the timer to fire again after another `TIMER_PERIOD`.
+pub fn set_next_tick() {
+    let ticks = *FREQUENCY.get() * TIMER_PERIOD.as_millis() as u64 / 1000;
+    unsafe {
+        CntpTvalEl0::write(ticks);
+        // Enable the timer, and don't mask its interrupt
+        CntpCtlEl0::write(0b1);
+    }
+}
+
+/// Get the time since the clock was initialized.
+pub fn now() -> Duration {
+    let ticks = CntpctEl0::read().saturating_sub(*BOOT_TIME.get());
+    let frequency = *FREQUENCY.get();
+    Duration::new(ticks / frequency, ((ticks % frequency) * 1_000_000_000 / frequency) as u32)
+}
@@ -0,0 +1,327 @@
+use crate::interrupts;
+use bit_field::BitField;
+use core::{arch::global_asm, time::Duration};
+use hal::memory::VAddr;
+use hal_aarch64::{
+    hw::sysreg::{EsrEl1, FarEl1, Vbar},
+    platform::kernel_map,
+};
+use kernel::{
+    object::address_space::{PageFaultAccess, PageFaultResolution},
+    profile::InterruptedContext,
+};
+use mulch::backtrace::Backtrace;
+use poplar::syscall::{ExceptionKind, FaultAccess, Registers};
+
+global_asm!(include_str!("exception.s"));
+extern "C" {
+    static exception_vectors: u8;
+}
+
+/// The period of the timer interrupt, which drives the kernel's timer wheel. This must match the
+/// interval we program the timer with.
+pub const TIMER_PERIOD: Duration = Duration::from_millis(20);
+
+/// Install the exception vector table. Unlike on RISC-V, exceptions from userspace are taken on `SP_EL1`, which
+/// is always the running task's kernel stack, so the same handler can be used from early in initialization.
+pub fn install_handler() {
+    Vbar::set(VAddr::new(unsafe { &exception_vectors } as *const u8 as usize));
+}
+
+/*
+ * The kind of an exception is formed from where it was taken from, and its type. These match the order of the
+ * vectors in the exception vector table.
+ */
+const SOURCE_CURRENT_SP0: usize = 0;
+const SOURCE_CURRENT_SPX: usize = 1;
+const SOURCE_LOWER_AARCH64: usize = 2;
+const SOURCE_LOWER_AARCH32: usize = 3;
+const TYPE_SYNCHRONOUS: usize = 0;
+const TYPE_IRQ: usize = 1;
+const TYPE_FIQ: usize = 2;
+const TYPE_SERROR: usize = 3;
+
+/*
+ * Exception classes, from the `EC` field of `ESR_EL1`.
+ */
+const EC_UNKNOWN: usize = 0x00;
+const EC_SVC: usize = 0x15;
+const EC_INSTRUCTION_ABORT_LOWER: usize = 0x20;
+const EC_INSTRUCTION_ABORT_CURRENT: usize = 0x21;
+const EC_PC_ALIGNMENT: usize = 0x22;
+const EC_DATA_ABORT_LOWER: usize = 0x24;
+const EC_DATA_ABORT_CURRENT: usize = 0x25;
+const EC_SP_ALIGNMENT: usize = 0x26;
+
+#[no_mangle]
+extern "C" fn exception_handler(trap_frame: &mut TrapFrame, kind: usize) {
+    let (source, typ) = (kind / 4, kind % 4);
+    match (source, typ) {
+        (SOURCE_CURRENT_SPX | SOURCE_LOWER_AARCH64, TYPE_SYNCHRONOUS) => handle_synchronous(trap_frame),
+        (SOURCE_CURRENT_SPX | SOURCE_LOWER_AARCH64, TYPE_IRQ) => handle_irq(trap_frame),
+        _ => {
+            let source = match source {
+                SOURCE_CURRENT_SP0 => "EL1 (SP_EL0)",
+                SOURCE_CURRENT_SPX => "EL1",
+                SOURCE_LOWER_AARCH64 => "EL0",
+                SOURCE_LOWER_AARCH32 => "EL0 (AArch32)",
+                _ => unreachable!(),
+            };
+            let typ = match typ {
+                TYPE_SYNCHRONOUS => "Synchronous exception",
+                TYPE_IRQ => "IRQ",
+                TYPE_FIQ => "FIQ",
+                TYPE_SERROR => "SError",
+                _ => unreachable!(),
+            };
+            unhandled_exception(trap_frame, format_args!("{} from {}", typ, source), FarEl1::read() as usize);
+        }
+    }
+}
+
+fn handle_synchronous(trap_frame: &mut TrapFrame) {
+    let esr = EsrEl1::read() as usize;
+    let far = FarEl1::read() as usize;
+    let class = esr.get_bits(26..32);
+    let iss = esr.get_bits(0..25);
+
+    match class {
+        EC_SVC => {
+            // `ELR_EL1` already points to the instruction after the `svc`, so we don't need to skip it
+            trap_frame.x0 = kernel::syscall::handle_syscall(
+                crate::SCHEDULER.get(),
+                crate::KERNEL_PAGE_TABLES.get(),
+                trap_frame.x0,
+                trap_frame.x1,
+                trap_frame.x2,
+                trap_frame.x3,
+                trap_frame.x4,
+                trap_frame.x5,
+            );
+        }
+        EC_INSTRUCTION_ABORT_LOWER
+        | EC_INSTRUCTION_ABORT_CURRENT
+        | EC_DATA_ABORT_LOWER
+        | EC_DATA_ABORT_CURRENT => {
+            let access = if class == EC_INSTRUCTION_ABORT_LOWER || class == EC_INSTRUCTION_ABORT_CURRENT {
+                PageFaultAccess::Execute
+            } else if iss.get_bit(6) {
+                PageFaultAccess::Write
+            } else {
+                PageFaultAccess::Read
+            };
+
+            /*
+             * The Fault Status Code tells us why the access faulted. Alignment faults can't be resolved, but
+             * some translation, access flag, and permission faults are expected, and can be resolved by the
+             * kernel (e.g. writes to copy-on-write memory). If so, we return to retry the faulting access.
+             */
+            let status = iss.get_bits(0..6);
+            if status == 0b100001 {
+                deliver_to_task(trap_frame, ExceptionKind::MisalignedAccess, far);
+                return;
+            }
+            let is_page_fault = matches!(status.get_bits(2..6), 0b0001 | 0b0010 | 0b0011);
+
+            let from_user = trap_frame.is_from_user();
+            let resolution = match crate::SCHEDULER.try_get() {
+                Some(scheduler) if is_page_fault && far < usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START) => {
+                    scheduler.handle_page_fault(VAddr::new(far), access, from_user)
+                }
+                _ => PageFaultResolution::Invalid,
+            };
+            let kind = match resolution {
+                PageFaultResolution::Resolved => return,
+                PageFaultResolution::StackOverflow => ExceptionKind::StackOverflow,
+                PageFaultResolution::Invalid => ExceptionKind::PageFault(match access {
+                    PageFaultAccess::Read => FaultAccess::Read,
+                    PageFaultAccess::Write => FaultAccess::Write,
+                    PageFaultAccess::Execute => FaultAccess::Execute,
+                }),
+            };
+            deliver_to_task(trap_frame, kind, far);
+        }
+        EC_PC_ALIGNMENT | EC_SP_ALIGNMENT => {
+            deliver_to_task(trap_frame, ExceptionKind::MisalignedAccess, far);
+        }
+        EC_UNKNOWN => {
+            let address = trap_frame.elr;
+            deliver_to_task(trap_frame, ExceptionKind::IllegalInstruction, address);
+        }
+        _ => unhandled_exception(trap_frame, format_args!("Unhandled exception (ESR = {:#x})", esr), far),
+    }
+}
+
+fn handle_irq(trap_frame: &mut TrapFrame) {
+    let Some(interrupt) = interrupts::acknowledge() else {
+        return;
+    };
+
+    if Some(&interrupt) == crate::clock::TIMER_INTERRUPT.try_get() {
+        /*
+         * We might not return from here for a while if we pre-empt the running task, so the timer interrupt is
+         * ended before anything else.
+         */
+        interrupts::end_of_interrupt(interrupt);
+
+        let context = InterruptedContext {
+            instruction_pointer: trap_frame.elr,
+            frame_pointer: trap_frame.x29,
+            in_user: trap_frame.is_from_user(),
+        };
+        kernel::profile::sample(crate::SCHEDULER.get(), &context, |address| {
+            address >= usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START)
+        });
+
+        crate::SCHEDULER.get().tasklet_scheduler.advance_timer(TIMER_PERIOD);
+        crate::clock::set_next_tick();
+
+        /*
+         * Pre-empt the running task if it's used up its time slice. We can only do this if
+         * we've interrupted userspace, and must do it after setting the next tick as we might
+         * not return here for a while.
+         */
+        if trap_frame.is_from_user() {
+            crate::SCHEDULER.get().tick(TIMER_PERIOD);
+        }
+    } else {
+        interrupts::dispatch(interrupt);
+        interrupts::end_of_interrupt(interrupt);
+    }
+}
+
+/// Deliver an exception caused by a user task to its exception channel. If the exception is resolved by
+/// resuming the task, any changes to its registers are written back to the trap frame. Exceptions caused by
+/// the kernel are fatal.
+fn deliver_to_task(trap_frame: &mut TrapFrame, kind: ExceptionKind, address: usize) {
+    if !trap_frame.is_from_user() {
+        unhandled_exception(trap_frame, format_args!("{:?}", kind), address);
+    }
+
+    let mut registers = trap_frame.to_registers();
+    crate::SCHEDULER.get().handle_exception(kind, address, &mut registers);
+    trap_frame.set_registers(&registers);
+}
+
+fn unhandled_exception(trap_frame: &TrapFrame, cause: core::fmt::Arguments, far: usize) -> ! {
+    let registers = [
+        ("pc", trap_frame.elr),
+        ("spsr", trap_frame.spsr),
+        ("sp_el0", trap_frame.sp),
+        ("x0", trap_frame.x0),
+        ("x1", trap_frame.x1),
+        ("x2", trap_frame.x2),
+        ("x3", trap_frame.x3),
+        ("x4", trap_frame.x4),
+        ("x5", trap_frame.x5),
+        ("x6", trap_frame.x6),
+        ("x7", trap_frame.x7),
+        ("x8", trap_frame.x8),
+        ("x9", trap_frame.x9),
+        ("x10", trap_frame.x10),
+        ("x11", trap_frame.x11),
+        ("x12", trap_frame.x12),
+        ("x13", trap_frame.x13),
+        ("x14", trap_frame.x14),
+        ("x15", trap_frame.x15),
+        ("x16", trap_frame.x16),
+        ("x17", trap_frame.x17),
+        ("x18", trap_frame.x18),
+        ("x19", trap_frame.x19),
+        ("x20", trap_frame.x20),
+        ("x21", trap_frame.x21),
+        ("x22", trap_frame.x22),
+        ("x23", trap_frame.x23),
+        ("x24", trap_frame.x24),
+        ("x25", trap_frame.x25),
+        ("x26", trap_frame.x26),
+        ("x27", trap_frame.x27),
+        ("x28", trap_frame.x28),
+        ("x29", trap_frame.x29),
+        ("x30", trap_frame.x30),
+        ("far", far),
+        ("esr", EsrEl1::read() as usize),
+    ];
+
+    // Walk the stack from the context that took the exception, rather than from the exception handler
+    let backtrace = unsafe {
+        Backtrace::from_frame_pointer(trap_frame.x29, |address| {
+            address >= usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START)
+        })
+    };
+    crate::serial::crash(&cause, None, &registers, &backtrace)
+}
+
+/*
+ * XXX: the offsets of fields in this struct are used in assembly, so care must be taken when
+ * re-ordering / adding fields.
+ */
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    x0: usize,
+    x1: usize,
+    x2: usize,
+    x3: usize,
+    x4: usize,
+    x5: usize,
+    x6: usize,
+    x7: usize,
+    x8: usize,
+    x9: usize,
+    x10: usize,
+    x11: usize,
+    x12: usize,
+    x13: usize,
+    x14: usize,
+    x15: usize,
+    x16: usize,
+    x17: usize,
+    x18: usize,
+    x19: usize,
+    x20: usize,
+    x21: usize,
+    x22: usize,
+    x23: usize,
+    x24: usize,
+    x25: usize,
+    x26: usize,
+    x27: usize,
+    x28: usize,
+    x29: usize,
+    x30: usize,
+    /// The stack pointer of EL0 (`SP_EL0`).
+    sp: usize,
+    elr: usize,
+    spsr: usize,
+}
+
+/// Copy the listed registers between a `TrapFrame` and a `Registers`, which share the same names for them.
+macro_rules! copy_registers {
+    ($from:expr => $to:expr; $($reg:ident),*) => {
+        $($to.$reg = $from.$reg;)*
+    };
+}
+
+impl TrapFrame {
+    /// Whether the exception was taken from EL0. The bottom four bits of `SPSR_EL1` hold the Exception Level and
+    /// stack pointer that were in use.
+    pub fn is_from_user(&self) -> bool {
+        self.spsr.get_bits(0..4) == 0
+    }
+
+    pub fn to_registers(&self) -> Registers {
+        let mut registers = Registers { pc: self.elr, pstate: self.spsr, ..Default::default() };
+        copy_registers!(self => registers; sp, x0, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12, x13, x14,
+            x15, x16, x17, x18, x19, x20, x21, x22, x23, x24, x25, x26, x27, x28, x29, x30);
+        registers
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.elr = registers.pc;
+        // Only the condition flags can be changed - the rest of `SPSR_EL1` controls which mode we return to
+        self.spsr.set_bits(28..32, registers.pstate.get_bits(28..32));
+        copy_registers!(registers => self; sp, x0, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12, x13, x14,
+            x15, x16, x17, x18, x19, x20, x21, x22, x23, x24, x25, x26, x27, x28, x29, x30);
+    }
+}
@@ -0,0 +1,77 @@
+.macro exception_vector kind
+    .balign 0x80
+    // Push a trap frame, and save enough registers to pass the kind of exception to `exception_common`
+    sub sp, sp, #0x110
+    stp x0, x1, [sp, #0]
+    mov x1, #\kind
+    b exception_common
+.endm
+
+/*
+ * The exception vector table. There are four groups of four vectors, for exceptions from the current Exception
+ * Level using `SP_EL0`, the current Exception Level using `SP_ELx`, a lower Exception Level running AArch64, and a
+ * lower Exception Level running AArch32. Each group has a vector for synchronous exceptions, IRQs, FIQs, and
+ * SErrors, in that order.
+ */
+.section .text.exception_vectors, "ax"
+.balign 0x800
+.global exception_vectors
+exception_vectors:
+    .irp kind, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+    exception_vector \kind
+    .endr
+
+.text
+exception_common:
+    // Save the rest of the general-purpose registers
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    mrs x2, sp_el0
+    stp x30, x2, [sp, #240]
+
+    // Save `elr_el1` and `spsr_el1` - the handler can change these to resume somewhere else
+    mrs x2, elr_el1
+    mrs x3, spsr_el1
+    stp x2, x3, [sp, #256]
+
+    // Pass the trap frame in `x0`, and the kind of exception in `x1`
+    mov x0, sp
+    bl exception_handler
+
+    ldp x2, x3, [sp, #256]
+    msr elr_el1, x2
+    msr spsr_el1, x3
+    ldp x30, x2, [sp, #240]
+    msr sp_el0, x2
+
+    // Restore the general-purpose registers
+    ldp x0, x1, [sp, #0]
+    ldp x2, x3, [sp, #16]
+    ldp x4, x5, [sp, #32]
+    ldp x6, x7, [sp, #48]
+    ldp x8, x9, [sp, #64]
+    ldp x10, x11, [sp, #80]
+    ldp x12, x13, [sp, #96]
+    ldp x14, x15, [sp, #112]
+    ldp x16, x17, [sp, #128]
+    ldp x18, x19, [sp, #144]
+    ldp x20, x21, [sp, #160]
+    ldp x22, x23, [sp, #176]
+    ldp x24, x25, [sp, #192]
+    ldp x26, x27, [sp, #208]
+    ldp x28, x29, [sp, #224]
+    add sp, sp, #0x110
+
+    eret
@@ -0,0 +1,152 @@
+use crate::PlatformImpl;
+use alloc::collections::BTreeMap;
+use bit_field::BitField;
+use core::{mem, ptr};
+use fdt::{node::FdtNode, Fdt};
+use hal::memory::PAddr;
+use hal_aarch64::{
+    hw::gicv3::{GicV3, FIRST_SPI},
+    platform::kernel_map,
+};
+use kernel::interrupts::{InterruptVectorAllocator, INTERRUPT_VECTORS};
+use mulch::InitGuard;
+use poplar::trace::TraceEvent;
+use spinning_top::Spinlock;
+use tracing::{info, warn};
+
+pub static GIC: InitGuard<GicV3> = InitGuard::uninit();
+// TODO: wrap in a guard to disable interrupts
+static HANDLERS: Spinlock<BTreeMap<u32, InterruptHandler>> = Spinlock::new(BTreeMap::new());
+
+/// The first interrupt ID of the Private Peripheral Interrupts, which are private to each CPU (like SGIs), but are
+/// raised by devices (like SPIs).
+const FIRST_PPI: u32 = 16;
+
+pub fn init(fdt: &Fdt) {
+    let node = fdt.find_compatible(&["arm,gic-v3"]).expect("No supported interrupt controller found!");
+    let mut reg = node.reg().unwrap();
+    let distributor = reg.next().unwrap().starting_address as usize;
+    let redistributors = reg.next().unwrap().starting_address as usize;
+
+    let mpidr = crate::per_cpu::get().mpidr;
+    let gic = unsafe {
+        GicV3::new(
+            kernel_map::physical_to_virtual(PAddr::new(distributor).unwrap()),
+            kernel_map::physical_to_virtual(PAddr::new(redistributors).unwrap()),
+            mpidr,
+        )
+    };
+    info!(
+        "Found GICv3 (Distributor @ {:#x}, Redistributors @ {:#x}) with {} interrupts",
+        distributor,
+        redistributors,
+        gic.num_interrupts()
+    );
+    gic.init(mpidr);
+
+    /*
+     * We don't support the GIC's Interrupt Translation Service, so can't receive MSIs, and every interrupt we can
+     * deliver comes from a wired source. We reserve them all, so nothing else can be allocated.
+     */
+    let mut vectors = InterruptVectorAllocator::new(0..gic.num_interrupts());
+    vectors.reserve_range(0..gic.num_interrupts());
+    INTERRUPT_VECTORS.initialize(Spinlock::new(vectors));
+
+    GIC.initialize(gic);
+}
+
+pub struct InterruptHandler(pub *const ());
+unsafe impl Send for InterruptHandler {}
+
+impl InterruptHandler {
+    pub unsafe fn call(&self, number: u16) {
+        assert!(self.0 != ptr::null());
+        unsafe {
+            let ptr: fn(u16) = mem::transmute(self.0);
+            (ptr)(number);
+        }
+    }
+}
+
+/// A wired interrupt, decoded from an interrupt specifier of the GIC.
+#[derive(Clone, Copy, Debug)]
+pub struct WiredInterrupt {
+    pub id: u32,
+    pub edge_triggered: bool,
+}
+
+impl WiredInterrupt {
+    /// Decode an interrupt specifier of the GIC. These are three cells: the type of the interrupt (`0` for an SPI,
+    /// and `1` for a PPI), its number within that type, and flags describing how it's triggered.
+    pub fn from_cells(cells: [u32; 3]) -> WiredInterrupt {
+        let id = match cells[0] {
+            0 => FIRST_SPI + cells[1],
+            1 => FIRST_PPI + cells[1],
+            other => panic!("Unsupported type of GIC interrupt: {}", other),
+        };
+        // The bottom four bits of the flags are the trigger type - rising and falling edges are bits `0` and `1`
+        WiredInterrupt { id, edge_triggered: cells[2].get_bits(0..2) != 0 }
+    }
+}
+
+/// Decode the `interrupts` property of a device tree node. The fdt crate assumes each interrupt is a single cell,
+/// so we parse the property ourselves.
+pub fn fdt_interrupts<'a>(node: FdtNode<'_, 'a>) -> impl Iterator<Item = WiredInterrupt> + 'a {
+    node.property("interrupts").into_iter().flat_map(|property| {
+        property.value.chunks_exact(12).map(|specifier| WiredInterrupt::from_cells(read_cells(specifier)))
+    })
+}
+
+/// Read a GIC interrupt specifier from the raw bytes of a device tree property.
+pub fn read_cells(bytes: &[u8]) -> [u32; 3] {
+    let cell = |i: usize| u32::from_be_bytes(bytes[(i * 4)..(i * 4 + 4)].try_into().unwrap());
+    [cell(0), cell(1), cell(2)]
+}
+
+pub fn handle_wired_fdt_device_interrupt(node: FdtNode<'_, '_>, handler: fn(u16)) -> u32 {
+    handle_wired_interrupt(fdt_interrupts(node).next().unwrap(), handler)
+}
+
+/// Install a handler for a wired interrupt. Returns the number the handler will be called with, which is also
+/// used to mask and unmask the interrupt. Interrupts can be shared, in which case each handler for the interrupt
+/// must be the same.
+pub fn handle_wired_interrupt(interrupt: WiredInterrupt, handler: fn(u16)) -> u32 {
+    let gic = GIC.get();
+    gic.set_edge_triggered(interrupt.id, interrupt.edge_triggered);
+    HANDLERS.lock().insert(interrupt.id, InterruptHandler(handler as *const _));
+    gic.enable_interrupt(interrupt.id);
+    interrupt.id
+}
+
+/// Stop a wired interrupt from being delivered until it's unmasked with `unmask_wired_interrupt`. `interrupt` is
+/// the number passed to the interrupt's handler.
+pub fn mask_wired_interrupt(interrupt: u32) {
+    GIC.get().disable_interrupt(interrupt);
+}
+
+pub fn unmask_wired_interrupt(interrupt: u32) {
+    GIC.get().enable_interrupt(interrupt);
+}
+
+/// Acknowledge the pending interrupt with the highest priority, returning its ID. This must be followed by a call
+/// to `end_of_interrupt` once the interrupt has been handled. Returns `None` if the interrupt was spurious.
+pub fn acknowledge() -> Option<u32> {
+    GIC.get().acknowledge()
+}
+
+pub fn end_of_interrupt(interrupt: u32) {
+    GIC.get().end_of_interrupt(interrupt);
+}
+
+pub fn dispatch(interrupt: u32) {
+    let handlers = HANDLERS.lock();
+
+    kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptEnter, [interrupt as u64, 0, 0]);
+    match handlers.get(&interrupt) {
+        Some(handler) => unsafe {
+            handler.call(interrupt as u16);
+        },
+        None => warn!("Unhandled interrupt: {}", interrupt),
+    }
+    kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptExit, [interrupt as u64, 0, 0]);
+}
@@ -0,0 +1,293 @@
+/*
+ * Copyright 2022, Isaac Woods
+ * SPDX-License-Identifier: MPL-2.0
+ */
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod clock;
+mod exception;
+mod interrupts;
+mod pci;
+mod per_cpu;
+mod platform_devices;
+mod rtc;
+mod serial;
+mod task;
+
+use alloc::string::String;
+use core::time::Duration;
+use hal::memory::{Frame, PAddr, VAddr};
+use hal_aarch64::{
+    hw::{
+        psci::{self, Conduit},
+        sysreg::{MpidrEl1, Ttbr},
+    },
+    platform::{kernel_map, PageTableImpl},
+};
+use kernel::{
+    memory::{Pmm, Vmm},
+    scheduler::Scheduler,
+    DmaDirection,
+    PerfEvent,
+    Platform,
+};
+use mulch::InitGuard;
+use seed::boot_info::BootInfo;
+use spinning_top::RwSpinlock;
+use tracing::info;
+
+pub struct PlatformImpl;
+
+impl Platform for PlatformImpl {
+    type PageTableSize = hal::memory::Size4KiB;
+    type PageTable = hal_aarch64::platform::PageTableImpl;
+    type TaskContext = task::TaskContext;
+
+    fn cpu_id() -> usize {
+        per_cpu::get().cpu_id
+    }
+
+    fn new_task_context(
+        kernel_stack: &kernel::memory::vmm::Stack,
+        user_stack: &kernel::memory::vmm::Stack,
+        task_entry_point: VAddr,
+        argument: usize,
+        thread_pointer: usize,
+    ) -> Self::TaskContext {
+        task::new_task_context(kernel_stack, user_stack, task_entry_point, argument, thread_pointer)
+    }
+
+    unsafe fn context_switch(from_context: *mut Self::TaskContext, to_context: *const Self::TaskContext) {
+        task::context_switch(from_context, to_context);
+    }
+
+    unsafe fn drop_into_userspace(context: *const Self::TaskContext) -> ! {
+        task::drop_into_userspace(context)
+    }
+
+    unsafe fn read_from_phys_memory(address: PAddr, data: &mut [u8]) {
+        let virt: *const u8 = hal_aarch64::platform::kernel_map::physical_to_virtual(address).ptr();
+        unsafe {
+            core::ptr::copy(virt, data.as_mut_ptr(), data.len());
+        }
+    }
+
+    unsafe fn write_to_phys_memory(address: PAddr, data: &[u8]) {
+        let virt: *mut u8 = hal_aarch64::platform::kernel_map::physical_to_virtual(address).mut_ptr();
+        unsafe {
+            core::ptr::copy(data.as_ptr(), virt, data.len());
+        }
+    }
+
+    unsafe fn copy_phys_memory(from: PAddr, to: PAddr, size: usize) {
+        let from: *const u8 = hal_aarch64::platform::kernel_map::physical_to_virtual(from).ptr();
+        let to: *mut u8 = hal_aarch64::platform::kernel_map::physical_to_virtual(to).mut_ptr();
+        unsafe {
+            core::ptr::copy_nonoverlapping(from, to, size);
+        }
+    }
+
+    unsafe fn zero_phys_memory(address: PAddr, size: usize) {
+        let virt: *mut u8 = hal_aarch64::platform::kernel_map::physical_to_virtual(address).mut_ptr();
+        unsafe {
+            core::ptr::write_bytes(virt, 0, size);
+        }
+    }
+
+    fn monotonic_time() -> Duration {
+        clock::now()
+    }
+
+    fn hardware_random() -> Option<u64> {
+        use hal_aarch64::hw::sysreg;
+        if sysreg::rndr_supported() {
+            sysreg::read_rndr()
+        } else {
+            None
+        }
+    }
+
+    unsafe fn sync_dma(address: PAddr, size: usize, direction: DmaDirection) {
+        use hal_aarch64::platform::cache;
+
+        unsafe {
+            match direction {
+                DmaDirection::ToDevice => cache::clean(address, size),
+                DmaDirection::FromDevice => cache::invalidate(address, size),
+                DmaDirection::Bidirectional => cache::clean_and_invalidate(address, size),
+            }
+        }
+    }
+
+    fn shootdown_tlb(_cpus: u64) {
+        // TLB invalidations are broadcast to the other CPUs in the inner shareable domain by the hardware
+        hal_aarch64::paging::flush_tlb(None);
+    }
+
+    fn perf_supports(_events: &[PerfEvent]) -> bool {
+        // TODO: support the PMU
+        false
+    }
+
+    unsafe fn perf_start(_events: &[PerfEvent], _counters: &mut [usize]) {}
+
+    fn perf_read(_counters: &[usize], _counts: &mut [u64]) {}
+
+    unsafe fn perf_stop(_counters: &[usize]) {}
+
+    fn idle() {
+        // `wfi` wakes up when an interrupt is pending, even if it's masked
+        unsafe {
+            core::arch::asm!("wfi");
+        }
+    }
+
+    fn set_cpu_performance(_performance: u8) {}
+
+    fn power_off() {
+        if let Some(&conduit) = PSCI.try_get() {
+            psci::system_off(conduit);
+        }
+    }
+
+    fn reset() {
+        if let Some(&conduit) = PSCI.try_get() {
+            psci::system_reset(conduit);
+        }
+    }
+
+    #[cfg(feature = "test_runner")]
+    fn exit_qemu(passed: bool) -> ! {
+        use hal_aarch64::hw::qemu::ExitCode;
+        serial::exit_qemu(if passed { ExitCode::Success } else { ExitCode::TestsFailed })
+    }
+}
+
+pub static SCHEDULER: InitGuard<Scheduler<PlatformImpl>> = InitGuard::uninit();
+pub static KERNEL_PAGE_TABLES: InitGuard<RwSpinlock<hal_aarch64::platform::PageTableImpl>> = InitGuard::uninit();
+/// How to make calls to PSCI, which is used to power off and reset the system.
+static PSCI: InitGuard<Conduit> = InitGuard::uninit();
+
+#[no_mangle]
+pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
+    let fdt = {
+        let address = hal_aarch64::platform::kernel_map::physical_to_virtual(boot_info.fdt_address.unwrap());
+        unsafe { fdt::Fdt::from_ptr(address.ptr()).unwrap() }
+    };
+    serial::init(&fdt);
+    info!("Hello from the kernel");
+
+    exception::install_handler();
+
+    if boot_info.magic != seed::boot_info::BOOT_INFO_MAGIC {
+        panic!("Boot info has incorrect magic!");
+    }
+    kernel::KERNEL_SLIDE.initialize(boot_info.kernel_slide);
+
+    // info!("Boot info: {:#?}", boot_info);
+    // info!("FDT: {:#?}", fdt);
+
+    /*
+     * Initialise the heap allocator. After this, the kernel is free to use collections etc. that
+     * can allocate on the heap through the global allocator.
+     */
+    info!("Initializing heap at {:#x} of size {} bytes", boot_info.heap_address, boot_info.heap_size);
+    unsafe {
+        kernel::ALLOCATOR.init(boot_info.heap_address.mut_ptr(), boot_info.heap_size);
+    }
+    kernel::initialize_object_caches::<PlatformImpl>();
+
+    /*
+     * The boot CPU is always CPU 0. We don't bring up any other CPUs yet.
+     */
+    per_cpu::PerCpuImpl::install(0, MpidrEl1::read());
+
+    /*
+     * Seed installs the same set of page tables in both `TTBR0_EL1` and `TTBR1_EL1`, so we can find them from
+     * either.
+     */
+    let kernel_page_table = unsafe {
+        PageTableImpl::from_frame(Frame::starts_with(Ttbr::read_ttbr0()), kernel_map::PHYSICAL_MAP_BASE)
+    };
+    KERNEL_PAGE_TABLES.initialize(RwSpinlock::new(kernel_page_table));
+    kernel::memory::verify_kernel_image::<PlatformImpl>(&KERNEL_PAGE_TABLES.get().read());
+
+    kernel::PMM.initialize(Pmm::new(boot_info));
+    kernel::VMM.initialize(Vmm::new(
+        kernel_map::KERNEL_STACKS_BASE,
+        kernel_map::KERNEL_STACKS_BASE + kernel_map::STACK_SLOT_SIZE * kernel_map::MAX_TASKS,
+        kernel_map::STACK_SLOT_SIZE,
+    ));
+
+    clock::init();
+    interrupts::init(&fdt);
+    hal_aarch64::hw::sysreg::enable_interrupts();
+
+    if let Some(access) = pci::PciAccess::new(&fdt) {
+        kernel::initialize_pci(access);
+    }
+    if let Some(rtc) = rtc::Pl031::new(&fdt) {
+        kernel::initialize_rtc(rtc);
+    }
+    kernel::initialize_platform_devices(platform_devices::enumerate(&fdt));
+    if let Some(conduit) = fdt
+        .find_compatible(&["arm,psci-1.0", "arm,psci-0.2"])
+        .and_then(|node| node.property("method"))
+        .and_then(|method| method.as_str())
+        .and_then(Conduit::from_method)
+    {
+        PSCI.initialize(conduit);
+    }
+
+    SCHEDULER.initialize(Scheduler::new(1, exception::TIMER_PERIOD));
+    kernel::trace::init::<PlatformImpl>(1);
+    kernel::profile::init::<PlatformImpl>(1);
+    kernel::vdso::set_cpu_count(1);
+    maitake::time::set_global_timer(&SCHEDULER.get().tasklet_scheduler.timer).unwrap();
+
+    let (uart_prod, uart_cons) = kernel::tasklets::queue::SpscQueue::new();
+    serial::enable_input(&fdt, uart_prod);
+    SCHEDULER.get().tasklet_scheduler.spawn(async move {
+        loop {
+            let line = {
+                let mut line = String::new();
+                loop {
+                    let bytes = uart_cons.read().await;
+                    let as_str = core::str::from_utf8(&bytes).unwrap();
+                    if let Some(index) = as_str.find('\r') {
+                        let (before, _after) = as_str.split_at(index);
+                        line += before;
+                        // Only release up to (and including) the newline so the next pass can consume any bytes
+                        // after it
+                        bytes.release(index + 1);
+                        break;
+                    } else {
+                        line += as_str;
+                        let num_bytes = bytes.len();
+                        bytes.release(num_bytes);
+                    }
+                }
+                line
+            };
+            info!("Line from UART: {}", line);
+        }
+    });
+
+    /*
+     * Create kernel objects from loaded images and schedule them.
+     */
+    kernel::initialize_random::<PlatformImpl>(&boot_info);
+    kernel::load_userspace(SCHEDULER.get(), &boot_info, &mut KERNEL_PAGE_TABLES.get().write());
+
+    /*
+     * Kick the timer off. Its interrupt drives the timer wheel and pre-empts tasks, so this needs to happen
+     * after the scheduler has been created.
+     */
+    clock::start_timer(&fdt);
+
+    SCHEDULER.get().start_scheduling()
+}
@@ -0,0 +1,177 @@
+use crate::interrupts::{self, WiredInterrupt};
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use bit_field::BitField;
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use fdt::Fdt;
+use hal::memory::PAddr;
+use kernel::{object::event::Event, pci::PciInterruptConfigurator};
+use mulch::InitGuard;
+use pci_types::{
+    capability::{MsiCapability, MsixCapability},
+    Bar,
+    ConfigRegionAccess,
+    PciAddress,
+};
+use spinning_top::Spinlock;
+use tracing::{debug, info};
+
+// TODO: this should have an interrupt guard as well
+static INTERRUPT_ROUTING: Spinlock<BTreeMap<u32, Vec<Arc<Event>>>> = Spinlock::new(BTreeMap::new());
+/// For each legacy interrupt line, the number of events signalled by its last interrupt that are yet to be
+/// acknowledged. Lines are shared between devices, and are level-triggered, so a line is masked when it fires,
+/// and only unmasked once the drivers of every device sharing it have serviced their device.
+static LEGACY_PENDING_ACKS: InitGuard<BTreeMap<u32, AtomicUsize>> = InitGuard::uninit();
+
+pub struct PciAccess {
+    start: *const u8,
+    size: usize,
+    legacy_interrupt_remapping: BTreeMap<(PciAddress, u8), u32>,
+}
+
+impl PciAccess {
+    pub fn new(fdt: &Fdt) -> Option<PciAccess> {
+        let pci_node = fdt.find_compatible(&["pci-host-ecam-generic", "pci-host-cam-generic"])?;
+        let ecam_window = pci_node.reg().expect("PCI entry doesn't have a reg property").next().unwrap();
+        let ecam_address = hal_aarch64::platform::kernel_map::physical_to_virtual(
+            PAddr::new(ecam_window.starting_address as usize).unwrap(),
+        );
+
+        /*
+         * Find routing information for legacy interrupt pins from the device tree. The fdt crate assumes the
+         * parent's interrupt specifiers are a single cell, but the GIC's are three cells, so we parse the
+         * `interrupt-map` ourselves. Each entry is the child's unit address (three cells), the child's
+         * interrupt specifier (one cell), the phandle of the interrupt controller, the interrupt controller's unit
+         * address, and then the GIC's interrupt specifier.
+         */
+        let remapping = {
+            let mut remapping = BTreeMap::new();
+            let interrupt_map = pci_node.property("interrupt-map").unwrap().value;
+            let interrupt_map_mask = pci_node.interrupt_map_mask().unwrap();
+            let parent_address_cells = fdt
+                .find_compatible(&["arm,gic-v3"])
+                .and_then(|gic| gic.property("#address-cells"))
+                .and_then(|cells| cells.as_usize())
+                .unwrap_or(0);
+            let cell =
+                |bytes: &[u8], i: usize| u32::from_be_bytes(bytes[(i * 4)..(i * 4 + 4)].try_into().unwrap());
+            let parent_specifier_offset = (5 + parent_address_cells) * 4;
+
+            for mapping in interrupt_map.chunks_exact(parent_specifier_offset + 12) {
+                let child_address_hi = cell(mapping, 0) & interrupt_map_mask.address_mask_hi;
+                let address = PciAddress::new(
+                    0,
+                    child_address_hi.get_bits(16..24) as u8,
+                    child_address_hi.get_bits(11..16) as u8,
+                    child_address_hi.get_bits(8..11) as u8,
+                );
+                let pin = cell(mapping, 3) & interrupt_map_mask.interrupt_mask;
+                let interrupt =
+                    WiredInterrupt::from_cells(interrupts::read_cells(&mapping[parent_specifier_offset..]));
+
+                debug!(
+                    "Legacy PCI interrupt remapping: {:#?}, pin = {} -> {} ({:#x})",
+                    address, pin, interrupt.id, interrupt.id
+                );
+                let line = interrupts::handle_wired_interrupt(interrupt, pci_interrupt_handler);
+
+                INTERRUPT_ROUTING.lock().insert(line, Vec::new());
+                remapping.insert((address, pin as u8), line);
+            }
+            remapping
+        };
+        LEGACY_PENDING_ACKS
+            .initialize(remapping.values().map(|&interrupt| (interrupt, AtomicUsize::new(0))).collect());
+
+        Some(PciAccess {
+            start: ecam_address.ptr(),
+            size: ecam_window.size.unwrap(),
+            legacy_interrupt_remapping: remapping,
+        })
+    }
+
+    fn address_for(&self, pci_address: PciAddress) -> *const u8 {
+        unsafe {
+            self.start.add(
+                usize::from(pci_address.bus()) << 20
+                    | usize::from(pci_address.device()) << 15
+                    | usize::from(pci_address.function()) << 12,
+            )
+        }
+    }
+
+    /// We can't receive MSIs without support for the GIC's Interrupt Translation Service, so devices that support
+    /// them are configured to use their legacy interrupt pin instead.
+    fn configure_legacy_fallback(&self, function: PciAddress) -> Arc<Event> {
+        let pin = unsafe { self.read(function, 0x3c) }.get_bits(8..16) as u8;
+        self.configure_legacy(function, pin)
+    }
+}
+
+unsafe impl Send for PciAccess {}
+
+impl ConfigRegionAccess for PciAccess {
+    unsafe fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        ptr::read_volatile(self.address_for(address).add(offset as usize) as *const u32)
+    }
+
+    unsafe fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        ptr::write_volatile(self.address_for(address).add(offset as usize) as *mut u32, value);
+    }
+}
+
+impl PciInterruptConfigurator for PciAccess {
+    fn configure_legacy(&self, function: PciAddress, pin: u8) -> Arc<Event> {
+        info!("Configuring PCI device to use legacy interrupts: {:?}", function);
+
+        let remapped_interrupt =
+            self.legacy_interrupt_remapping.get(&(function, pin)).expect("PCI interrupt not in remapping!");
+        let event = Event::new_interrupt(*remapped_interrupt, acknowledge_legacy_interrupt);
+        INTERRUPT_ROUTING.lock().get_mut(&remapped_interrupt).unwrap().push(event.clone());
+
+        event
+    }
+
+    fn configure_msi(&self, function: PciAddress, _msi: &mut MsiCapability) -> Arc<Event> {
+        self.configure_legacy_fallback(function)
+    }
+
+    fn configure_msix(
+        &self,
+        function: PciAddress,
+        _table_bar: Bar,
+        _msix: &mut MsixCapability,
+        _num_vectors: u16,
+    ) -> Vec<Arc<Event>> {
+        vec![self.configure_legacy_fallback(function)]
+    }
+}
+
+fn pci_interrupt_handler(number: u16) {
+    let routing = INTERRUPT_ROUTING.lock();
+    if let Some(events) = routing.get(&(number as u32)) {
+        /*
+         * Mask the interrupt until every event sharing the line has been acknowledged. Otherwise, it would keep
+         * firing until the device has been serviced by its driver.
+         */
+        if let Some(pending_acks) = LEGACY_PENDING_ACKS.try_get().and_then(|acks| acks.get(&(number as u32))) {
+            if !events.is_empty() {
+                interrupts::mask_wired_interrupt(number as u32);
+                pending_acks.store(events.len(), Ordering::SeqCst);
+            }
+        }
+
+        for event in events {
+            event.signal();
+        }
+    }
+}
+
+fn acknowledge_legacy_interrupt(line: u32) {
+    let pending_acks = LEGACY_PENDING_ACKS.get().get(&line).unwrap();
+    if pending_acks.fetch_sub(1, Ordering::SeqCst) == 1 {
+        interrupts::unmask_wired_interrupt(line);
+    }
+}
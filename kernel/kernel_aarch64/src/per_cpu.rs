@@ -0,0 +1,25 @@
+use alloc::boxed::Box;
+use hal_aarch64::hw::sysreg::TpidrEl1;
+
+/// Get the per-CPU data of the running CPU. `TPIDR_EL1` always points to it, and can't be changed by userspace.
+/// It is not valid to call this before `PerCpuImpl::install` has been called on the running CPU.
+pub fn get<'a>() -> &'a PerCpuImpl {
+    unsafe { &*(TpidrEl1::read() as *const PerCpuImpl) }
+}
+
+/// Represents data that is held individually for each CPU.
+pub struct PerCpuImpl {
+    pub cpu_id: usize,
+    /// The value of the CPU's `MPIDR_EL1`, which identifies it to the interrupt controller.
+    pub mpidr: u64,
+}
+
+impl PerCpuImpl {
+    pub fn install(cpu_id: usize, mpidr: u64) {
+        let per_cpu = Box::leak(Box::new(PerCpuImpl { cpu_id, mpidr }));
+
+        unsafe {
+            TpidrEl1::write(per_cpu as *const PerCpuImpl as u64);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Driver for the ARM PrimeCell PL031 real-time clock, which QEMU's `virt` machine provides. It keeps the time
+//! as a number of seconds since the Unix epoch.
+
+use core::{ptr, time::Duration};
+use fdt::Fdt;
+use hal::memory::PAddr;
+use kernel::rtc::Rtc;
+
+/// The Data Register, which holds the current time.
+const RTCDR: usize = 0x00;
+/// The Load Register. Writing to this sets the current time.
+const RTCLR: usize = 0x08;
+
+pub struct Pl031 {
+    base: usize,
+}
+
+impl Pl031 {
+    pub fn new(fdt: &Fdt) -> Option<Pl031> {
+        let node = fdt.find_compatible(&["arm,pl031"])?;
+        let address = node.reg()?.next()?.starting_address as usize;
+        let base = hal_aarch64::platform::kernel_map::physical_to_virtual(PAddr::new(address)?);
+        Some(Pl031 { base: usize::from(base) })
+    }
+}
+
+impl Rtc for Pl031 {
+    fn read(&self) -> Duration {
+        let seconds = unsafe { ptr::read_volatile((self.base + RTCDR) as *const u32) };
+        Duration::from_secs(seconds as u64)
+    }
+
+    fn write(&self, time: Duration) -> Result<(), ()> {
+        // The PL031 only counts whole seconds, and will run out of them in 2106
+        let seconds = u32::try_from(time.as_secs()).map_err(|_| ())?;
+        unsafe {
+            ptr::write_volatile((self.base + RTCLR) as *mut u32, seconds);
+        }
+        Ok(())
+    }
+}
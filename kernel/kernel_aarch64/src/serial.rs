@@ -0,0 +1,233 @@
+/*
+ * Copyright 2022, Isaac Woods
+ * SPDX-License-Identifier: MPL-2.0
+ */
+
+use core::{
+    fmt,
+    fmt::Write,
+    panic::{Location, PanicInfo},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use fdt::Fdt;
+use hal::memory::PAddr;
+use hal_aarch64::{
+    hw::pl011::Pl011,
+    platform::kernel_map::{physical_to_virtual, KERNEL_ADDRESS_SPACE_START},
+};
+use kernel::{klog::LogWriter, tasklets::queue::QueueProducer};
+use mulch::{backtrace::Backtrace, InitGuard};
+use spinning_top::Spinlock;
+use tracing::{span, Collect, Event, Level, Metadata};
+use tracing_core::span::Current as CurrentSpan;
+
+static SERIAL: InitGuard<Pl011<'static>> = InitGuard::uninit();
+static SERIAL_PRODUCER: InitGuard<kernel::tasklets::queue::QueueProducer> = InitGuard::uninit();
+static LOGGER: Logger = Logger::new();
+
+pub fn init(fdt: &Fdt) {
+    let Some(stdout) = fdt.chosen().stdout() else {
+        // TODO: not sure the point of this as we won't be able to print the message? Can we report
+        // the error through semihosting or something instead?
+        panic!("FDT must contain a chosen stdout node!");
+    };
+    // TODO: check the compatible to make sure it's something we support
+    let addr = stdout.node().reg().unwrap().next().unwrap().starting_address as usize;
+
+    let serial_mapped_address = physical_to_virtual(PAddr::new(addr).unwrap());
+    let serial = unsafe { Pl011::new(serial_mapped_address) };
+    serial.init();
+    SERIAL.initialize(serial);
+
+    tracing::dispatch::set_global_default(tracing::dispatch::Dispatch::from_static(&LOGGER))
+        .expect("Failed to set default tracing dispatch");
+}
+
+pub fn enable_input(fdt: &Fdt, producer: QueueProducer) {
+    let stdout = fdt.chosen().stdout().unwrap().node();
+    crate::interrupts::handle_wired_fdt_device_interrupt(stdout, interrupt_handler);
+    SERIAL_PRODUCER.initialize(producer);
+}
+
+fn interrupt_handler(_: u16) {
+    let serial = SERIAL.get();
+    if let Some(producer) = SERIAL_PRODUCER.try_get() {
+        while let Some(byte) = serial.read() {
+            // TODO: with more stuff running and higher baud we might end up with multiple
+            // chars - would be more efficient to use a bigger grant.
+            let mut write = producer.grant_sync(1).unwrap();
+            write[0] = byte;
+            write.commit(1);
+        }
+    } else {
+        /*
+         * Nothing's interested in the serial input, so just blackhole it to avoid repeat
+         * interrupts.
+         */
+        while let Some(_) = serial.read() {}
+    }
+}
+
+struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let serial = SERIAL.get();
+        for byte in s.bytes() {
+            serial.write(byte);
+        }
+
+        Ok(())
+    }
+}
+
+struct Logger {
+    next_id: AtomicU64,
+    pub serial: Spinlock<SerialWriter>,
+}
+
+impl Logger {
+    const fn new() -> Logger {
+        Logger { next_id: AtomicU64::new(1), serial: Spinlock::new(SerialWriter) }
+    }
+}
+
+impl Collect for Logger {
+    fn current_span(&self) -> CurrentSpan {
+        todo!()
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // TODO: support more extensive + customizable filtering
+        *metadata.level() <= Level::INFO
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn event(&self, event: &Event) {
+        use core::ops::DerefMut;
+
+        if self.enabled(event.metadata()) {
+            let level = event.metadata().level();
+            let color = match *level {
+                Level::TRACE => "\x1b[36m",
+                Level::DEBUG => "\x1b[34m",
+                Level::INFO => "\x1b[32m",
+                Level::WARN => "\x1b[33m",
+                Level::ERROR => "\x1b[31m",
+            };
+            let mut serial = self.serial.lock();
+            // Everything logged is also kept in the kernel log, so userspace can read it back
+            let mut writer = LogWriter::new(serial.deref_mut());
+            write!(writer, "[{}{:5}\x1b[0m] {}: ", color, level, event.metadata().target()).unwrap();
+            event.record(&mut Visitor::new(&mut writer));
+            write!(writer, "\n").unwrap();
+        }
+    }
+
+    fn exit(&self, _span: &span::Id) {}
+
+    fn new_span(&self, _span: &span::Attributes) -> span::Id {
+        let id = self.next_id.fetch_add(1, Ordering::Acquire);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record) {
+        todo!()
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {
+        todo!()
+    }
+}
+
+struct Visitor<'w, W>
+where
+    W: Write,
+{
+    writer: &'w mut W,
+}
+
+impl<'w, W> Visitor<'w, W>
+where
+    W: Write,
+{
+    fn new(writer: &'w mut W) -> Visitor<'w, W> {
+        Visitor { writer }
+    }
+
+    fn record(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        // Handle the `message` field explicitly to declutter the output
+        if field.name() == "message" {
+            write!(self.writer, "{:?}", value).unwrap();
+        } else {
+            write!(self.writer, "{}={:?}", field, value).unwrap();
+        }
+    }
+}
+
+impl<'w, W> tracing::field::Visit for Visitor<'w, W>
+where
+    W: Write,
+{
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.record(field, &value);
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.record(field, &value);
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.record(field, &value);
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.record(field, &value);
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        self.record(field, &value);
+    }
+}
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    let (sp, x29, x30, daif, ttbr0): (usize, usize, usize, usize, usize);
+    unsafe {
+        core::arch::asm!("mov {}, sp", out(reg) sp);
+        core::arch::asm!("mov {}, x29", out(reg) x29);
+        core::arch::asm!("mov {}, x30", out(reg) x30);
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr0);
+    }
+    let registers = [("sp", sp), ("x29", x29), ("x30", x30), ("daif", daif), ("ttbr0_el1", ttbr0)];
+
+    let backtrace = Backtrace::capture(|address| address >= usize::from(KERNEL_ADDRESS_SPACE_START));
+    crash(&info.message(), info.location(), &registers, &backtrace)
+}
+
+/// Exit QEMU with `exit_code` through semihosting.
+#[cfg(feature = "qemu_exit")]
+pub fn exit_qemu(exit_code: hal_aarch64::hw::qemu::ExitCode) -> ! {
+    hal_aarch64::hw::qemu::exit(exit_code)
+}
+
+/// Write a crash report (see `kernel::crash`) to the serial port, and stop. If the `qemu_exit` feature is set,
+/// this exits QEMU with a failure code instead, so whatever ran QEMU can tell that the kernel crashed.
+pub fn crash(
+    message: &dyn fmt::Display,
+    location: Option<&Location>,
+    registers: &[(&'static str, usize)],
+    backtrace: &Backtrace,
+) -> ! {
+    if kernel::crash::begin() {
+        let crash = kernel::crash::Crash { message, location, registers, backtrace };
+        let _ = kernel::crash::write_report(&mut SerialWriter, &crash, crate::SCHEDULER.try_get());
+    }
+
+    #[cfg(feature = "qemu_exit")]
+    hal_aarch64::hw::qemu::exit(hal_aarch64::hw::qemu::ExitCode::Failed);
+
+    loop {}
+}
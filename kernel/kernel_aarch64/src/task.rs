@@ -0,0 +1,93 @@
+use core::{arch::global_asm, ptr};
+use hal::memory::VAddr;
+use kernel::memory::vmm::Stack;
+
+global_asm!(include_str!("task.s"));
+extern "C" {
+    fn task_entry_trampoline() -> !;
+    fn do_drop_to_userspace(context: *const ContextSwitchFrame) -> !;
+    fn do_context_switch(from_context: *mut ContextSwitchFrame, to_context: *const ContextSwitchFrame);
+}
+
+/*
+ * XXX: the offsets of fields in this struct are used in assembly, so care must be taken when
+ * re-ordering / adding fields.
+ */
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct ContextSwitchFrame {
+    pub x19: usize,
+    pub x20: usize,
+    pub x21: usize,
+    pub x22: usize,
+    pub x23: usize,
+    pub x24: usize,
+    pub x25: usize,
+    pub x26: usize,
+    pub x27: usize,
+    pub x28: usize,
+    /// The frame pointer.
+    pub x29: usize,
+    /// The link register.
+    pub x30: usize,
+    pub sp: usize,
+    /// The task's user thread pointer. The kernel doesn't use `TPIDR_EL0` itself, so it's only switched here,
+    /// rather than on every exception.
+    pub tpidr_el0: usize,
+}
+
+/// The context stored for each task. On AArch64, we only need to store the context switch state, as exceptions
+/// from userspace are taken on `SP_EL1`, which is left pointing at the task's kernel stack when we return to
+/// userspace.
+pub struct TaskContext {
+    context_switch_frame: ContextSwitchFrame,
+}
+
+pub fn new_task_context(
+    kernel_stack: &Stack,
+    user_stack: &Stack,
+    task_entry_point: VAddr,
+    argument: usize,
+    thread_pointer: usize,
+) -> TaskContext {
+    /*
+     * Initialize the kernel stack. Firstly, we need to make sure the top of the stack is 16-byte
+     * aligned, according to the AAPCS64.
+     */
+    const REQUIRED_INITIAL_STACK_ALIGNMENT: usize = 16;
+    let mut kernel_stack_pointer = kernel_stack.top.align_down(REQUIRED_INITIAL_STACK_ALIGNMENT);
+    let user_stack_pointer = user_stack.top.align_down(REQUIRED_INITIAL_STACK_ALIGNMENT);
+
+    /*
+     * Start off with a zeroed frame record to terminate backtraces at task entry.
+     */
+    kernel_stack_pointer -= 16;
+    unsafe { ptr::write(kernel_stack_pointer.mut_ptr() as *mut [u64; 2], [0x0; 2]) };
+
+    let context_switch_frame = ContextSwitchFrame {
+        x19: usize::from(task_entry_point),
+        x20: usize::from(user_stack_pointer),
+        // Moved into `x0` on entry to userspace
+        x21: argument,
+        x29: usize::from(kernel_stack_pointer),
+        x30: task_entry_trampoline as usize,
+        sp: usize::from(kernel_stack_pointer),
+        tpidr_el0: thread_pointer,
+        ..Default::default()
+    };
+
+    TaskContext { context_switch_frame }
+}
+
+pub unsafe fn context_switch(from_context: *mut TaskContext, to_context: *const TaskContext) {
+    unsafe {
+        do_context_switch(
+            &raw mut (*from_context).context_switch_frame,
+            &raw const (*to_context).context_switch_frame,
+        );
+    }
+}
+
+pub unsafe fn drop_into_userspace(context: *const TaskContext) -> ! {
+    unsafe { do_drop_to_userspace(&raw const (*context).context_switch_frame) }
+}
@@ -0,0 +1,74 @@
+.global task_entry_trampoline
+task_entry_trampoline:
+    // Return to EL0, using `SP_EL0`, with all exceptions unmasked
+    msr spsr_el1, xzr
+
+    // Load `elr_el1` with userspace's entry point
+    msr elr_el1, x19
+    mov x19, xzr
+
+    // Set the user's stack pointer. `sp` (`SP_EL1`) is left pointing at the task's kernel stack.
+    msr sp_el0, x20
+    mov x20, xzr
+
+    // Pass the task's argument in `x0`
+    mov x0, x21
+    mov x21, xzr
+
+    eret
+
+.global do_drop_to_userspace
+do_drop_to_userspace:
+    // Load registers from context-switch frame
+    ldp x19, x20, [x0, #0]
+    ldp x21, x22, [x0, #16]
+    ldp x23, x24, [x0, #32]
+    ldp x25, x26, [x0, #48]
+    ldp x27, x28, [x0, #64]
+    ldp x29, x30, [x0, #80]
+    ldp x9, x10, [x0, #96]
+    mov sp, x9
+    msr tpidr_el0, x10
+
+    // TODO: load other registers with zero/known-values to avoid leaking stuff to userspace?
+
+    // Return to EL0, using `SP_EL0`, with all exceptions unmasked
+    msr spsr_el1, xzr
+
+    // Load `elr_el1` with userspace's entry point
+    msr elr_el1, x19
+    mov x19, xzr
+
+    // Set the user's stack pointer. `sp` (`SP_EL1`) is left pointing at the task's kernel stack.
+    msr sp_el0, x20
+    mov x20, xzr
+
+    // Pass the task's argument in `x0`
+    mov x0, x21
+    mov x21, xzr
+
+    eret
+
+.global do_context_switch
+do_context_switch:
+    stp x19, x20, [x0, #0]
+    stp x21, x22, [x0, #16]
+    stp x23, x24, [x0, #32]
+    stp x25, x26, [x0, #48]
+    stp x27, x28, [x0, #64]
+    stp x29, x30, [x0, #80]
+    mov x9, sp
+    mrs x10, tpidr_el0
+    stp x9, x10, [x0, #96]
+
+    ldp x19, x20, [x1, #0]
+    ldp x21, x22, [x1, #16]
+    ldp x23, x24, [x1, #32]
+    ldp x25, x26, [x1, #48]
+    ldp x27, x28, [x1, #64]
+    ldp x29, x30, [x1, #80]
+    ldp x9, x10, [x1, #96]
+    mov sp, x9
+    msr tpidr_el0, x10
+
+    ret
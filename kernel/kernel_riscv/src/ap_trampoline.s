@@ -0,0 +1,24 @@
+/*
+ * Secondary HARTs are started here by the SBI, in S-mode with paging disabled. `a0` contains the HART's ID, and
+ * `a1` contains the physical address of an `ApBootInfo` describing how to enter the kernel. This code has to be
+ * identity-mapped in the kernel's page tables so we can keep executing it after enabling paging, and so is
+ * aligned to a page boundary to make sure it only occupies a single page.
+ */
+.section .text.ap_trampoline
+.p2align 12
+.global ap_trampoline
+ap_trampoline:
+    // Load everything we need from the boot info while we can still access it by its physical address
+    mv t2, a0
+    ld t0, 0(a1)        // satp
+    ld sp, 8(a1)
+    ld gp, 16(a1)
+    ld t1, 24(a1)       // Entry point
+    ld a0, 32(a1)       // CPU id
+    mv a1, t2           // HART id
+
+    // Switch to the kernel's page tables
+    csrw satp, t0
+    sfence.vma
+
+    jr t1
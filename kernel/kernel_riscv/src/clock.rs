@@ -0,0 +1,44 @@
+//! The kernel's high-resolution monotonic clock is the `time` CSR, which counts at a constant frequency that the
+//! device tree tells us, and is synchronized between HARTs.
+
+use core::time::Duration;
+use fdt::Fdt;
+use hal_riscv::hw::csr::{Scounteren, Time};
+use mulch::InitGuard;
+use tracing::info;
+
+/// The frequency the `time` CSR counts at, in Hz.
+static TIMEBASE_FREQUENCY: InitGuard<u64> = InitGuard::uninit();
+/// The value of the `time` CSR when the clock was initialized. Time is measured from this point.
+static BOOT_TIME: InitGuard<u64> = InitGuard::uninit();
+
+pub fn init(fdt: &Fdt) {
+    let frequency = fdt
+        .find_node("/cpus")
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(|frequency| frequency.as_usize())
+        .expect("Device tree does not contain timebase frequency") as u64;
+    info!("Timebase frequency is {}Hz", frequency);
+
+    TIMEBASE_FREQUENCY.initialize(frequency);
+    BOOT_TIME.initialize(Time::read() as u64);
+
+    enable_user_access();
+    kernel::vdso::set_clock(frequency, *BOOT_TIME.get());
+}
+
+/// Let U-mode read the `time` CSR on the current HART, so tasks can get the time from the vDSO page. This relies
+/// on the SBI implementation letting S-mode read it, which every one we support does. It must be called on each
+/// HART.
+pub fn enable_user_access() {
+    unsafe {
+        Scounteren::enable_time();
+    }
+}
+
+/// Get the time since the clock was initialized.
+pub fn now() -> Duration {
+    let ticks = (Time::read() as u64).saturating_sub(*BOOT_TIME.get());
+    let frequency = *TIMEBASE_FREQUENCY.get();
+    Duration::new(ticks / frequency, ((ticks % frequency) * 1_000_000_000 / frequency) as u32)
+}
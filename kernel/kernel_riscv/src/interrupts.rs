@@ -1,6 +1,7 @@
 use alloc::collections::BTreeMap;
 use bit_field::BitField;
 use core::{mem, ptr};
+use crate::PlatformImpl;
 use fdt::{node::FdtNode, Fdt};
 use hal::memory::PAddr;
 use hal_riscv::hw::{
@@ -8,17 +9,26 @@ use hal_riscv::hw::{
     imsic::Imsic,
     plic::Plic,
 };
+use kernel::{
+    interrupts::{InterruptVectorAllocator, INTERRUPT_VECTORS},
+    pci::{MsiController, MsiMessage},
+};
 use mulch::InitGuard;
+use poplar::trace::TraceEvent;
 use spinning_top::Spinlock;
 use tracing::{info, warn};
 
 pub static INTERRUPT_CONTROLLER: InitGuard<InterruptController> = InitGuard::uninit();
 
-pub fn init(fdt: &Fdt) {
+/// Describes how PCI devices should signal MSIs. This is only initialized if the platform has an
+/// IMSIC - the PLIC can't receive MSIs.
+pub static MSI_CONTROLLER: InitGuard<ImsicMsiController> = InitGuard::uninit();
+
+pub fn init(fdt: &Fdt, boot_hart_id: usize) {
     if let Some(plic_node) = fdt.find_compatible(&["riscv,plic0"]) {
         InterruptController::init_plic(plic_node);
     } else if fdt.find_compatible(&["riscv,aplic"]).is_some() {
-        InterruptController::init_aia(fdt);
+        InterruptController::init_aia(fdt, boot_hart_id);
     } else {
         panic!("No supported interrupt controller found!");
     }
@@ -63,31 +73,41 @@ impl InterruptController {
         plic.init(num_interrupts);
         plic.set_context_threshold(1, 0);
 
+        /*
+         * The PLIC can't receive MSIs, so every interrupt it can deliver comes from a wired
+         * source. We reserve them all, so nothing else can be allocated.
+         */
+        let mut vectors = InterruptVectorAllocator::new(1..(num_interrupts as u32 + 1));
+        vectors.reserve_range(1..(num_interrupts as u32 + 1));
+        INTERRUPT_VECTORS.initialize(Spinlock::new(vectors));
+
         INTERRUPT_CONTROLLER
             .initialize(InterruptController::Plic { plic, handlers: Spinlock::new(BTreeMap::new()) });
     }
 
-    pub fn init_aia(fdt: &Fdt) {
+    pub fn init_aia(fdt: &Fdt, boot_hart_id: usize) {
         /*
-         * This gets the physical address of the area of memory used to trigger messages on the
-         * S-mode IMSIC.
+         * There are separate IMSICs and APLICs for M-mode and S-mode in the device tree. We find
+         * the S-mode IMSIC by the interrupt it delivers to the HARTs, and then the APLIC that
+         * forwards wired interrupts to it as MSIs.
          */
-        let imsic_area = {
-            // TODO: same problem as below re multiple entries
-            let node = fdt.find_compatible(&["riscv,imsics"]).unwrap();
-            PAddr::new(node.reg().unwrap().next().unwrap().starting_address as usize).unwrap()
-        };
+        let imsic_node = find_s_mode_imsic(fdt).expect("No S-mode IMSIC in the device tree");
+        let imsic_phandle = imsic_node.property("phandle").unwrap().as_usize().unwrap();
+        let imsic_area = PAddr::new(imsic_node.reg().unwrap().next().unwrap().starting_address as usize).unwrap();
+        let num_ids = imsic_node.property("riscv,num-ids").unwrap().as_usize().unwrap();
 
-        let (aplic_phys, aplic) = {
-            /*
-             * TODO: there are actually multiple APLICs and IMSICs in the FDT - one for M-mode and
-             * one for S-mode. We should instead find the one that is marked as enabled, but `fdt`
-             * doesn't seem to have good support for this so this probs will work for now.
-             */
-            let node = fdt.find_compatible(&["riscv,aplic"]).unwrap();
+        let (aplic_phys, aplic, num_sources) = {
+            let node = fdt
+                .all_nodes()
+                .find(|node| {
+                    node.compatible().map_or(false, |c| c.all().any(|c| c == "riscv,aplic"))
+                        && node.property("msi-parent").and_then(|parent| parent.as_usize()) == Some(imsic_phandle)
+                })
+                .expect("No APLIC delivering interrupts to the S-mode IMSIC");
             let aplic_address = node.reg().unwrap().next().unwrap().starting_address as usize;
             let address = hal_riscv::platform::kernel_map::physical_to_virtual(PAddr::new(aplic_address).unwrap());
-            (aplic_address, unsafe { &*(address.ptr() as *const AplicDomain) })
+            let num_sources = node.property("riscv,num-sources").unwrap().as_usize().unwrap();
+            (aplic_address, unsafe { &*(address.ptr() as *const AplicDomain) }, num_sources)
         };
 
         info!(
@@ -99,11 +119,94 @@ impl InterruptController {
         aplic.init();
         aplic.set_msi_address(usize::from(imsic_area));
 
+        /*
+         * Interrupt identity `0` is not valid on the IMSIC. Wired interrupts are routed through the
+         * APLIC, which we configure to send a message with an identity matching the source number,
+         * so we need to reserve an identity for each of its sources.
+         */
+        let mut vectors = InterruptVectorAllocator::new(1..(num_ids as u32 + 1));
+        vectors.reserve_range(1..(num_sources as u32 + 1));
+        INTERRUPT_VECTORS.initialize(Spinlock::new(vectors));
+
+        /*
+         * We currently only handle external interrupts on the boot HART, so direct MSIs from PCI
+         * devices to its interrupt file.
+         */
+        MSI_CONTROLLER.initialize(ImsicMsiController {
+            interrupt_file: imsic_interrupt_file(fdt, imsic_node, imsic_area, boot_hart_id),
+        });
+
         INTERRUPT_CONTROLLER
             .initialize(InterruptController::Aia { aplic, handlers: Spinlock::new(BTreeMap::new()) });
     }
 }
 
+pub struct ImsicMsiController {
+    interrupt_file: PAddr,
+}
+
+impl MsiController for ImsicMsiController {
+    fn message_for(&self, vector: u32) -> MsiMessage {
+        /*
+         * Writing an interrupt identity to the `seteipnum_le` register, which is at the start of a
+         * HART's interrupt file, raises that identity on the HART.
+         */
+        MsiMessage { address: usize::from(self.interrupt_file) as u64, data: vector }
+    }
+}
+
+/// Find the IMSIC that delivers interrupts to S-mode. Its `interrupts-extended` property is a list
+/// of `<phandle, interrupt>` pairs, one for each HART, and the interrupt is the supervisor external
+/// interrupt (`9`) for the S-mode IMSIC, and the machine external interrupt (`11`) for the M-mode
+/// one.
+fn find_s_mode_imsic<'b, 'a>(fdt: &'b Fdt<'a>) -> Option<FdtNode<'b, 'a>> {
+    const SUPERVISOR_EXTERNAL_INTERRUPT: u32 = 9;
+
+    fdt.all_nodes().find(|node| {
+        node.compatible().map_or(false, |c| c.all().any(|c| c == "riscv,imsics"))
+            && node.property("interrupts-extended").map_or(false, |property| {
+                property.value.chunks_exact(8).all(|entry| {
+                    u32::from_be_bytes(entry[4..8].try_into().unwrap()) == SUPERVISOR_EXTERNAL_INTERRUPT
+                })
+            })
+    })
+}
+
+/// Find the physical address of the interrupt file for the HART with ID `hart_id` on an IMSIC. The
+/// interrupt files are laid out in the order of the IMSIC's `interrupts-extended` property, each
+/// taking up a page for the HART itself plus a page for each guest interrupt file.
+// TODO: this doesn't support IMSICs that are split into multiple groups
+fn imsic_interrupt_file(fdt: &Fdt, imsic_node: FdtNode<'_, '_>, imsic_area: PAddr, hart_id: usize) -> PAddr {
+    let guest_index_bits =
+        imsic_node.property("riscv,guest-index-bits").and_then(|bits| bits.as_usize()).unwrap_or(0);
+    let file_size = 0x1000 << guest_index_bits;
+
+    /*
+     * Each entry references the interrupt controller of a HART, which is a child of the HART's
+     * node in `/cpus`.
+     */
+    let hart_intc_phandle = fdt
+        .find_node("/cpus")
+        .unwrap()
+        .children()
+        .find(|cpu| {
+            cpu.reg().and_then(|mut reg| reg.next()).map(|reg| reg.starting_address as usize) == Some(hart_id)
+        })
+        .and_then(|cpu| cpu.children().find(|child| child.name.starts_with("interrupt-controller")))
+        .and_then(|intc| intc.property("phandle"))
+        .and_then(|phandle| phandle.as_usize())
+        .expect("Couldn't find interrupt controller of HART");
+    let index = imsic_node
+        .property("interrupts-extended")
+        .unwrap()
+        .value
+        .chunks_exact(8)
+        .position(|entry| u32::from_be_bytes(entry[0..4].try_into().unwrap()) as usize == hart_intc_phandle)
+        .expect("HART is not connected to the IMSIC");
+
+    imsic_area + index * file_size
+}
+
 pub fn handle_interrupt(number: u16, handler: fn(u16)) {
     match INTERRUPT_CONTROLLER.get() {
         InterruptController::Plic { plic, handlers } => {
@@ -121,11 +224,13 @@ pub fn handle_interrupt(number: u16, handler: fn(u16)) {
     }
 }
 
-pub fn handle_wired_fdt_device_interrupt(node: FdtNode<'_, '_>, handler: fn(u16)) {
-    handle_wired_device_interrupt(node.interrupts().unwrap().next().unwrap(), handler);
+pub fn handle_wired_fdt_device_interrupt(node: FdtNode<'_, '_>, handler: fn(u16)) -> u32 {
+    handle_wired_device_interrupt(node.interrupts().unwrap().next().unwrap(), handler)
 }
 
-pub fn handle_wired_device_interrupt(interrupt: usize, handler: fn(u16)) {
+/// Install a handler for a wired interrupt, described by an entry of a device's `interrupts` property. Returns the
+/// number the handler will be called with, which is also used to mask and unmask the interrupt.
+pub fn handle_wired_device_interrupt(interrupt: usize, handler: fn(u16)) -> u32 {
     match INTERRUPT_CONTROLLER.get() {
         InterruptController::Plic { plic, handlers } => {
             // TODO: don't just assume all interrupts should go to the first context
@@ -135,6 +240,7 @@ pub fn handle_wired_device_interrupt(interrupt: usize, handler: fn(u16)) {
 
             assert!(handlers.lock().get(&(interrupt as usize)).is_none());
             handlers.lock().insert(interrupt as usize, InterruptHandler(handler as *const _));
+            interrupt as u32
         }
         InterruptController::Aia { aplic, handlers } => {
             /*
@@ -156,10 +262,27 @@ pub fn handle_wired_device_interrupt(interrupt: usize, handler: fn(u16)) {
             aplic.enable_interrupt(interrupt as u32);
 
             handlers.lock().insert(interrupt as usize, InterruptHandler(handler as *const _));
+            interrupt as u32
         }
     }
 }
 
+/// Stop a wired interrupt from being delivered until it's unmasked with `unmask_wired_interrupt`. `interrupt` is
+/// the number passed to the interrupt's handler.
+pub fn mask_wired_interrupt(interrupt: u32) {
+    match INTERRUPT_CONTROLLER.get() {
+        InterruptController::Plic { plic, .. } => plic.disable_interrupt(1, interrupt as usize),
+        InterruptController::Aia { aplic, .. } => aplic.disable_interrupt(interrupt),
+    }
+}
+
+pub fn unmask_wired_interrupt(interrupt: u32) {
+    match INTERRUPT_CONTROLLER.get() {
+        InterruptController::Plic { plic, .. } => plic.enable_interrupt(1, interrupt as usize),
+        InterruptController::Aia { aplic, .. } => aplic.enable_interrupt(interrupt),
+    }
+}
+
 pub fn handle_external_interrupt() {
     // TODO: it feels a little strange to do this on every interrupt. Maybe dynamically dispatch to
     // a specialised handler for PLIC vs AIA?
@@ -168,12 +291,14 @@ pub fn handle_external_interrupt() {
             let interrupt = plic.claim_interrupt(1);
 
             let handlers = handlers.lock();
+            kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptEnter, [interrupt as u64, 0, 0]);
             match handlers.get(&(interrupt as usize)) {
                 Some(handler) => unsafe {
                     handler.call(interrupt as u16);
                 },
                 None => warn!("Unhandled interrupt: {}", interrupt),
             }
+            kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptExit, [interrupt as u64, 0, 0]);
 
             plic.complete_interrupt(1, interrupt);
         }
@@ -181,12 +306,14 @@ pub fn handle_external_interrupt() {
             let interrupt = Imsic::pop() as usize;
             let handlers = handlers.lock();
 
+            kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptEnter, [interrupt as u64, 0, 0]);
             match handlers.get(&interrupt) {
                 Some(handler) => unsafe {
                     handler.call(interrupt as u16);
                 },
                 None => warn!("Unhandled interrupt: {}", interrupt),
             }
+            kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptExit, [interrupt as u64, 0, 0]);
         }
     }
 }
@@ -0,0 +1,303 @@
+//! Support for IOMMUs that implement the RISC-V IOMMU specification. The IOMMU finds how to translate DMA from a
+//! device by looking up its device ID (for PCI devices, its requester ID) in the Device Directory Table, which
+//! holds a Device Context for each device.
+//!
+//! Devices that aren't attached to a domain have both stages of translation set to `Bare`. Devices attached to a
+//! domain use its first-stage page tables, which have the same format as the CPU's, and so are managed with the
+//! same `PageTableImpl`.
+
+use alloc::{boxed::Box, sync::Arc};
+use bit_field::BitField;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use fdt::Fdt;
+use hal::memory::{Flags, FrameAllocator, PAddr, PageTable, VAddr};
+use hal_riscv::{
+    paging::PagingMode,
+    platform::{kernel_map, PageTableImpl},
+};
+use kernel::{
+    iommu::{Iommu, IommuDomain},
+    pci::MsiController,
+};
+use pci_types::PciAddress;
+use spinning_top::Spinlock;
+use tracing::{info, warn};
+
+const REG_CAPABILITIES: usize = 0x00;
+const REG_DDTP: usize = 0x10;
+const REG_CQB: usize = 0x18;
+const REG_CQH: usize = 0x20;
+const REG_CQT: usize = 0x24;
+const REG_CQCSR: usize = 0x48;
+
+const DDTP_MODE_3LVL: u64 = 4;
+const IOSATP_MODE_SV39: u64 = 8;
+const IOSATP_MODE_SV48: u64 = 9;
+const IOSATP_MODE_SV57: u64 = 10;
+
+const PAGE_SIZE: usize = 0x1000;
+/// The command queue is a single page, which holds 256 16-byte commands.
+const COMMAND_QUEUE_ENTRIES: u32 = 256;
+
+pub struct RiscvIommu(Arc<Inner>);
+
+struct Inner {
+    registers: VAddr,
+    /// The root of the three-level Device Directory Table.
+    ddt_root: PAddr,
+    /// Device Contexts use the extended (64-byte) format if the IOMMU supports MSI translation through flat
+    /// tables, and the base (32-byte) format otherwise.
+    extended_contexts: bool,
+    /// The paging mode used for domains' page tables, and the first-stage translation mode that matches it.
+    paging_mode: PagingMode,
+    iosatp_mode: u64,
+    command_queue: Spinlock<CommandQueue>,
+    next_pscid: AtomicU32,
+    /// The page that devices write to to signal MSIs. Writes to it are translated like any other DMA, so it is
+    /// mapped into every domain.
+    msi_page: Option<PAddr>,
+}
+
+struct CommandQueue {
+    base: PAddr,
+    tail: u32,
+}
+
+impl RiscvIommu {
+    pub fn new(fdt: &Fdt) -> Option<RiscvIommu> {
+        let node = fdt.find_compatible(&["riscv,iommu"])?;
+        let reg = node.reg()?.next()?;
+        let registers = kernel_map::physical_to_virtual(PAddr::new(reg.starting_address as usize).unwrap());
+        // TODO: we assume device IDs are the same as requester IDs. We should check the PCI host's `iommu-map`.
+
+        let capabilities = unsafe { ptr::read_volatile((registers + REG_CAPABILITIES).ptr::<u64>()) };
+        /*
+         * Domains map DMA addresses to the same physical addresses, so they don't have to use the same paging mode
+         * as the CPU. We try to use the same one, but fall back to smaller ones if the IOMMU doesn't support it.
+         */
+        let supported = |mode: PagingMode| match mode {
+            PagingMode::Sv39 => capabilities.get_bit(9),
+            PagingMode::Sv48 => capabilities.get_bit(10),
+            PagingMode::Sv57 => capabilities.get_bit(11),
+        };
+        let paging_mode = match PagingMode::current().expect("Translation is not enabled") {
+            PagingMode::Sv57 if supported(PagingMode::Sv57) => PagingMode::Sv57,
+            PagingMode::Sv57 | PagingMode::Sv48 if supported(PagingMode::Sv48) => PagingMode::Sv48,
+            _ if supported(PagingMode::Sv39) => PagingMode::Sv39,
+            _ => {
+                warn!("RISC-V IOMMU does not support the paging mode we use. Not using the IOMMU.");
+                return None;
+            }
+        };
+        let iosatp_mode = match paging_mode {
+            PagingMode::Sv39 => IOSATP_MODE_SV39,
+            PagingMode::Sv48 => IOSATP_MODE_SV48,
+            PagingMode::Sv57 => IOSATP_MODE_SV57,
+        };
+
+        let msi_page = crate::interrupts::MSI_CONTROLLER.try_get().map(|controller| {
+            PAddr::new(controller.message_for(0).address as usize).unwrap().align_down(PAGE_SIZE)
+        });
+
+        let inner = Inner {
+            registers,
+            ddt_root: allocate_table(),
+            extended_contexts: capabilities.get_bit(22),
+            paging_mode,
+            iosatp_mode,
+            command_queue: Spinlock::new(CommandQueue { base: allocate_table(), tail: 0 }),
+            next_pscid: AtomicU32::new(1),
+            msi_page,
+        };
+
+        /*
+         * Enable the command queue. This is needed before we can invalidate any of the IOMMU's caches.
+         */
+        let command_queue_base = usize::from(inner.command_queue.lock().base) as u64;
+        inner.write_64(REG_CQB, (command_queue_base >> 12) << 10 | (COMMAND_QUEUE_ENTRIES.ilog2() as u64 - 1));
+        inner.write_32(REG_CQT, 0);
+        inner.write_32(REG_CQCSR, 0b1);
+        while !inner.read_32(REG_CQCSR).get_bit(16) {
+            core::hint::spin_loop();
+        }
+
+        info!("Found RISC-V IOMMU at {:#x}", reg.starting_address as usize);
+        Some(RiscvIommu(Arc::new(inner)))
+    }
+}
+
+impl Iommu for RiscvIommu {
+    fn add_device(&self, device: PciAddress) {
+        self.0.set_context(device, None);
+    }
+
+    fn enable(&self) {
+        self.0.write_64(REG_DDTP, (usize::from(self.0.ddt_root) as u64 >> 12) << 10 | DDTP_MODE_3LVL);
+        while self.0.read_64(REG_DDTP).get_bit(4) {
+            core::hint::spin_loop();
+        }
+
+        if self.0.read_64(REG_DDTP).get_bits(0..4) != DDTP_MODE_3LVL {
+            warn!(
+                "RISC-V IOMMU does not support three-level device directory tables. DMA will not be translated."
+            );
+        }
+    }
+
+    fn create_domain(&self) -> Box<dyn IommuDomain> {
+        let page_tables =
+            PageTableImpl::new(kernel::PMM.get().allocate(), self.0.paging_mode, kernel_map::PHYSICAL_MAP_BASE);
+        let domain = RiscvDomain {
+            iommu: self.0.clone(),
+            pscid: self.0.next_pscid.fetch_add(1, Ordering::Relaxed),
+            page_tables: Spinlock::new(page_tables),
+        };
+
+        if let Some(msi_page) = self.0.msi_page {
+            domain.map(msi_page, PAGE_SIZE, true);
+        }
+
+        Box::new(domain)
+    }
+}
+
+impl Inner {
+    fn read_32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.registers + offset).ptr()) }
+    }
+
+    fn write_32(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.registers + offset).mut_ptr(), value) }
+    }
+
+    fn read_64(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.registers + offset).ptr()) }
+    }
+
+    fn write_64(&self, offset: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.registers + offset).mut_ptr(), value) }
+    }
+
+    /// Submit commands to the command queue, followed by an `IOFENCE.C`, and wait for them to complete.
+    fn submit_commands(&self, commands: &[[u64; 2]]) {
+        const IOFENCE_C: [u64; 2] = [0b0000010, 0];
+
+        let mut queue = self.command_queue.lock();
+        for command in commands.iter().chain(core::iter::once(&IOFENCE_C)) {
+            let entry = table_ptr(queue.base, queue.tail as usize * 2);
+            unsafe {
+                ptr::write_volatile(entry, command[0]);
+                ptr::write_volatile(entry.add(1), command[1]);
+            }
+            queue.tail = (queue.tail + 1) % COMMAND_QUEUE_ENTRIES;
+        }
+
+        core::sync::atomic::fence(Ordering::SeqCst);
+        self.write_32(REG_CQT, queue.tail);
+        while self.read_32(REG_CQH) != queue.tail {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Set the Device Context for a device. If `translation` is `None`, the device's DMA is not translated.
+    /// Otherwise, it should contain the root of the domain's first-stage page tables and its process context ID.
+    fn set_context(&self, device: PciAddress, translation: Option<(PAddr, u32)>) {
+        let device_id =
+            (device.bus() as usize) << 8 | (device.device() as usize) << 3 | device.function() as usize;
+        let (leaf_bits, context_size) = if self.extended_contexts { (6, 8) } else { (7, 4) };
+
+        /*
+         * Walk the non-leaf levels of the Device Directory Table, creating them as needed. The first level is
+         * indexed by the top bits of the device ID, and each level after by the next 9 bits.
+         */
+        let mut table = self.ddt_root;
+        for shift in [leaf_bits + 9, leaf_bits] {
+            let entry = table_ptr(table, (device_id >> shift) % 512);
+            let value = unsafe { ptr::read_volatile(entry) };
+            table = if value.get_bit(0) {
+                PAddr::new(value.get_bits(10..54) as usize * PAGE_SIZE).unwrap()
+            } else {
+                let next = allocate_table();
+                unsafe {
+                    ptr::write_volatile(entry, (usize::from(next) as u64 >> 12) << 10 | 0b1);
+                }
+                next
+            };
+        }
+
+        let (ta, fsc) = match translation {
+            Some((root, pscid)) => ((pscid as u64) << 12, self.iosatp_mode << 60 | usize::from(root) as u64 >> 12),
+            None => (0, 0),
+        };
+
+        /*
+         * The context is laid out as `tc`, `iohgatp`, `ta`, and `fsc`. We leave second-stage translation as
+         * `Bare`. The context is made invalid while it's changed, so the IOMMU never sees a half-updated context.
+         */
+        let invalidate = [[0b0000011 | 1 << 33 | (device_id as u64) << 40, 0]];
+        let context = table_ptr(table, (device_id % (1 << leaf_bits)) * context_size);
+        unsafe {
+            ptr::write_volatile(context, 0);
+            core::sync::atomic::fence(Ordering::SeqCst);
+            self.submit_commands(&invalidate);
+
+            ptr::write_volatile(context.add(1), 0);
+            ptr::write_volatile(context.add(2), ta);
+            ptr::write_volatile(context.add(3), fsc);
+            core::sync::atomic::fence(Ordering::SeqCst);
+            ptr::write_volatile(context, 0b1);
+        }
+        self.submit_commands(&invalidate);
+    }
+}
+
+struct RiscvDomain {
+    iommu: Arc<Inner>,
+    /// The Process Soft-Context ID that tags this domain's translations in the IOMMU's caches.
+    pscid: u32,
+    page_tables: Spinlock<PageTableImpl>,
+}
+
+impl IommuDomain for RiscvDomain {
+    fn map(&self, address: PAddr, size: usize, writable: bool) {
+        /*
+         * Device accesses without a process ID are treated as being made from U-mode, so the mappings need to be
+         * user-accessible.
+         */
+        let flags = Flags { writable, user_accessible: true, ..Default::default() };
+        self.page_tables
+            .lock()
+            .map_area(VAddr::new(usize::from(address)), address, size, flags, kernel::PMM.get())
+            .expect("Failed to map memory into IOMMU domain");
+
+        // Invalidate any cached translations for this domain (`IOTINVAL.VMA` with `PSCV` set)
+        self.iommu.submit_commands(&[[0b0000001 | (self.pscid as u64) << 12 | 1 << 32, 0]]);
+    }
+
+    fn attach(&self, device: PciAddress) -> bool {
+        let root = self.page_tables.lock().frame().start;
+        self.iommu.set_context(device, Some((root, self.pscid)));
+        true
+    }
+
+    fn detach(&self, device: PciAddress) {
+        self.iommu.set_context(device, None);
+    }
+}
+
+/// Allocate a zeroed page to hold part of the Device Directory Table, or the command queue.
+fn allocate_table() -> PAddr {
+    let table = kernel::PMM.get().alloc(1);
+    unsafe {
+        ptr::write_bytes(kernel_map::physical_to_virtual(table).mut_ptr::<u8>(), 0, PAGE_SIZE);
+    }
+    table
+}
+
+/// Get a pointer to the `index`th `u64` of the table at `table`.
+fn table_ptr(table: PAddr, index: usize) -> *mut u64 {
+    unsafe { kernel_map::physical_to_virtual(table).mut_ptr::<u64>().add(index) }
+}
@@ -9,24 +9,37 @@
 
 extern crate alloc;
 
+mod clock;
 mod interrupts;
+mod iommu;
 mod pci;
+mod per_cpu;
+mod perf;
+mod platform_devices;
+mod power;
+mod rtc;
 mod serial;
+mod smp;
 mod task;
 mod trap;
 
 use alloc::string::String;
+use core::time::Duration;
 use hal::memory::{Frame, PAddr, VAddr};
 use hal_riscv::{
     hw::csr::Satp,
+    paging::PagingMode,
     platform::{kernel_map, PageTableImpl},
 };
 use kernel::{
     memory::{Pmm, Vmm},
     scheduler::Scheduler,
+    DmaDirection,
+    PerfEvent,
     Platform,
 };
 use mulch::InitGuard;
+use poplar::syscall::PERF_MAX_EVENTS;
 use seed::boot_info::BootInfo;
 use spinning_top::RwSpinlock;
 use tracing::info;
@@ -38,12 +51,18 @@ impl Platform for PlatformImpl {
     type PageTable = hal_riscv::platform::PageTableImpl;
     type TaskContext = task::TaskContext;
 
+    fn cpu_id() -> usize {
+        per_cpu::get().cpu_id
+    }
+
     fn new_task_context(
         kernel_stack: &kernel::memory::vmm::Stack,
         user_stack: &kernel::memory::vmm::Stack,
         task_entry_point: VAddr,
+        argument: usize,
+        thread_pointer: usize,
     ) -> Self::TaskContext {
-        task::new_task_context(kernel_stack, user_stack, task_entry_point)
+        task::new_task_context(kernel_stack, user_stack, task_entry_point, argument, thread_pointer)
     }
 
     unsafe fn context_switch(from_context: *mut Self::TaskContext, to_context: *const Self::TaskContext) {
@@ -54,19 +73,127 @@ impl Platform for PlatformImpl {
         task::drop_into_userspace(context)
     }
 
+    unsafe fn read_from_phys_memory(address: PAddr, data: &mut [u8]) {
+        let virt: *const u8 = hal_riscv::platform::kernel_map::physical_to_virtual(address).ptr();
+        unsafe {
+            core::ptr::copy(virt, data.as_mut_ptr(), data.len());
+        }
+    }
+
     unsafe fn write_to_phys_memory(address: PAddr, data: &[u8]) {
         let virt: *mut u8 = hal_riscv::platform::kernel_map::physical_to_virtual(address).mut_ptr();
         unsafe {
             core::ptr::copy(data.as_ptr(), virt, data.len());
         }
     }
+
+    unsafe fn copy_phys_memory(from: PAddr, to: PAddr, size: usize) {
+        let from: *const u8 = hal_riscv::platform::kernel_map::physical_to_virtual(from).ptr();
+        let to: *mut u8 = hal_riscv::platform::kernel_map::physical_to_virtual(to).mut_ptr();
+        unsafe {
+            core::ptr::copy_nonoverlapping(from, to, size);
+        }
+    }
+
+    unsafe fn zero_phys_memory(address: PAddr, size: usize) {
+        let virt: *mut u8 = hal_riscv::platform::kernel_map::physical_to_virtual(address).mut_ptr();
+        unsafe {
+            core::ptr::write_bytes(virt, 0, size);
+        }
+    }
+
+    fn monotonic_time() -> Duration {
+        clock::now()
+    }
+
+    fn hardware_random() -> Option<u64> {
+        /*
+         * SBI doesn't provide an entropy source, and we don't yet detect harts that implement the Zkr extension's
+         * `seed` CSR, so the kernel relies on timing jitter alone.
+         */
+        None
+    }
+
+    unsafe fn sync_dma(address: PAddr, size: usize, direction: DmaDirection) {
+        use hal_riscv::platform::cache;
+
+        unsafe {
+            match direction {
+                DmaDirection::ToDevice => cache::clean(address, size),
+                DmaDirection::FromDevice => cache::invalidate(address, size),
+                DmaDirection::Bidirectional => cache::clean_and_invalidate(address, size),
+            }
+        }
+    }
+
+    fn shootdown_tlb(cpus: u64) {
+        /*
+         * The SBI asks each HART to execute an `sfence.vma` with an IPI to M-mode, which is taken even if the HART
+         * has interrupts disabled, and waits for them to do so before returning. A size of `usize::MAX` flushes
+         * the whole address space.
+         */
+        let harts = (0..u64::BITS as usize)
+            .filter(|&cpu| cpus & (1 << cpu) != 0)
+            .fold(sbi::HartMask::new(0), |harts, cpu| harts.with(per_cpu::hart_id(cpu)));
+        sbi::rfence::remote_sfence_vma(harts, 0, usize::MAX).unwrap();
+    }
+
+    fn perf_supports(events: &[PerfEvent]) -> bool {
+        /*
+         * The SBI is the only thing that knows which counters can count which events, so the only way to find out
+         * is to ask it for counters, and then give them straight back.
+         */
+        if events.len() > PERF_MAX_EVENTS {
+            return false;
+        }
+        let mut counters = [0; PERF_MAX_EVENTS];
+        let counters = &mut counters[0..events.len()];
+        if perf::allocate(events, counters, false) {
+            perf::release(counters);
+            true
+        } else {
+            false
+        }
+    }
+
+    unsafe fn perf_start(events: &[PerfEvent], counters: &mut [usize]) {
+        perf::allocate(events, counters, true);
+    }
+
+    fn perf_read(counters: &[usize], counts: &mut [u64]) {
+        perf::read(counters, counts)
+    }
+
+    unsafe fn perf_stop(counters: &[usize]) {
+        perf::release(counters)
+    }
+
+    fn idle() {
+        power::idle();
+    }
+
+    fn set_cpu_performance(_performance: u8) {}
+
+    fn power_off() {
+        power::power_off();
+    }
+
+    fn reset() {
+        power::reset();
+    }
+
+    #[cfg(feature = "test_runner")]
+    fn exit_qemu(passed: bool) -> ! {
+        use hal_riscv::hw::qemu::ExitCode;
+        serial::exit_qemu(if passed { ExitCode::Success } else { ExitCode::TestsFailed })
+    }
 }
 
 pub static SCHEDULER: InitGuard<Scheduler<PlatformImpl>> = InitGuard::uninit();
 pub static KERNEL_PAGE_TABLES: InitGuard<RwSpinlock<hal_riscv::platform::PageTableImpl>> = InitGuard::uninit();
 
 #[no_mangle]
-pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
+pub extern "C" fn kentry(boot_info: &BootInfo, boot_hart_id: usize) -> ! {
     let fdt = {
         let address = hal_riscv::platform::kernel_map::physical_to_virtual(boot_info.fdt_address.unwrap());
         unsafe { fdt::Fdt::from_ptr(address.ptr()).unwrap() }
@@ -79,6 +206,7 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     if boot_info.magic != seed::boot_info::BOOT_INFO_MAGIC {
         panic!("Boot info has incorrect magic!");
     }
+    kernel::KERNEL_SLIDE.initialize(boot_info.kernel_slide);
 
     // info!("Boot info: {:#?}", boot_info);
     // info!("FDT: {:#?}", fdt);
@@ -89,25 +217,32 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
      */
     info!("Initializing heap at {:#x} of size {} bytes", boot_info.heap_address, boot_info.heap_size);
     unsafe {
-        kernel::ALLOCATOR.lock().init(boot_info.heap_address.mut_ptr(), boot_info.heap_size);
+        kernel::ALLOCATOR.init(boot_info.heap_address.mut_ptr(), boot_info.heap_size);
     }
+    kernel::initialize_object_caches::<PlatformImpl>();
+
+    /*
+     * The boot HART is always CPU 0. Secondary HARTs are given IDs when they're brought up.
+     */
+    per_cpu::PerCpuImpl::install(0, boot_hart_id);
 
     let kernel_page_table = unsafe {
-        match Satp::read() {
-            Satp::Sv39 { root, .. } => {
-                assert!(hal_riscv::platform::VIRTUAL_ADDRESS_BITS == 39);
-                PageTableImpl::from_frame(Frame::starts_with(root), kernel_map::PHYSICAL_MAP_BASE)
-            }
-            Satp::Sv48 { root, .. } => {
-                assert!(hal_riscv::platform::VIRTUAL_ADDRESS_BITS == 48);
-                PageTableImpl::from_frame(Frame::starts_with(root), kernel_map::PHYSICAL_MAP_BASE)
-            }
-            _ => {
-                panic!("Kernel booted in an unexpected paging mode! Have we been built for the correct platform?");
-            }
-        }
+        let (mode, root) = match Satp::read() {
+            Satp::Sv39 { root, .. } => (PagingMode::Sv39, root),
+            Satp::Sv48 { root, .. } => (PagingMode::Sv48, root),
+            Satp::Sv57 { root, .. } => (PagingMode::Sv57, root),
+            Satp::Bare => panic!("Kernel booted with translation turned off!"),
+        };
+        assert!(
+            hal_riscv::platform::PAGING_MODES.contains(&mode),
+            "Kernel booted in an unexpected paging mode ({:?})! Have we been built for the correct platform?",
+            mode
+        );
+        info!("Kernel is using paging mode: {:?}", mode);
+        PageTableImpl::from_frame(Frame::starts_with(root), mode, kernel_map::PHYSICAL_MAP_BASE)
     };
     KERNEL_PAGE_TABLES.initialize(RwSpinlock::new(kernel_page_table));
+    kernel::memory::verify_kernel_image::<PlatformImpl>(&KERNEL_PAGE_TABLES.get().read());
 
     kernel::PMM.initialize(Pmm::new(boot_info));
     kernel::VMM.initialize(Vmm::new(
@@ -116,19 +251,45 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
         kernel_map::STACK_SLOT_SIZE,
     ));
 
-    interrupts::init(&fdt);
+    clock::init(&fdt);
+    perf::init();
+    power::init();
+    interrupts::init(&fdt, boot_hart_id);
     unsafe {
         hal_riscv::hw::csr::Sie::enable_all();
         hal_riscv::hw::csr::Sstatus::enable_interrupts();
     }
 
+    /*
+     * If the platform has an IOMMU, it needs to be registered before PCI devices are enumerated so they can be
+     * added to it. It maps the MSI target into domains, so this must happen after the interrupt controller has
+     * been initialized.
+     */
+    if let Some(iommu) = iommu::RiscvIommu::new(&fdt) {
+        kernel::initialize_iommu(iommu);
+    }
+
     if let Some(access) = pci::PciAccess::new(&fdt) {
         kernel::initialize_pci(access);
     }
+    if let Some(rtc) = rtc::GoldfishRtc::new(&fdt) {
+        kernel::initialize_rtc(rtc);
+    }
+    kernel::initialize_platform_devices(platform_devices::enumerate(&fdt));
 
-    SCHEDULER.initialize(Scheduler::new());
+    let application_harts = smp::application_harts(&fdt, boot_hart_id);
+    SCHEDULER.initialize(Scheduler::new(1 + application_harts.len(), trap::TIMER_PERIOD));
+    kernel::trace::init::<PlatformImpl>(1 + application_harts.len());
+    kernel::profile::init::<PlatformImpl>(1 + application_harts.len());
+    kernel::vdso::set_cpu_count(1 + application_harts.len());
     maitake::time::set_global_timer(&SCHEDULER.get().tasklet_scheduler.timer).unwrap();
 
+    /*
+     * Bring up the other HARTs. They'll start scheduling tasks as soon as they're ready, so this
+     * needs to happen after the scheduler has been created.
+     */
+    smp::boot_application_harts(&application_harts);
+
     let (uart_prod, uart_cons) = kernel::tasklets::queue::SpscQueue::new();
     serial::enable_input(&fdt, uart_prod);
     SCHEDULER.get().tasklet_scheduler.spawn(async move {
@@ -160,6 +321,7 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     /*
      * Create kernel objects from loaded images and schedule them.
      */
+    kernel::initialize_random::<PlatformImpl>(&boot_info);
     kernel::load_userspace(SCHEDULER.get(), &boot_info, &mut KERNEL_PAGE_TABLES.get().write());
 
     /*
@@ -1,10 +1,18 @@
 use crate::interrupts;
 use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
 use bit_field::BitField;
-use core::ptr;
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use fdt::Fdt;
 use hal::memory::PAddr;
-use kernel::{object::event::Event, pci::PciInterruptConfigurator};
+use kernel::{
+    interrupts::INTERRUPT_VECTORS,
+    object::event::Event,
+    pci::{MsiController, PciInterruptConfigurator},
+};
+use mulch::InitGuard;
 use pci_types::{
     capability::{MsiCapability, MsixCapability},
     Bar,
@@ -12,10 +20,14 @@ use pci_types::{
     PciAddress,
 };
 use spinning_top::Spinlock;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 // TODO: this should have an interrupt guard as well
 static INTERRUPT_ROUTING: Spinlock<BTreeMap<u32, Vec<Arc<Event>>>> = Spinlock::new(BTreeMap::new());
+/// For each legacy interrupt line, the number of events signalled by its last interrupt that are yet to be
+/// acknowledged. Lines are shared between devices, and are level-triggered, so a line is masked when it fires,
+/// and only unmasked once the drivers of every device sharing it have serviced their device.
+static LEGACY_PENDING_ACKS: InitGuard<BTreeMap<u32, AtomicUsize>> = InitGuard::uninit();
 
 pub struct PciAccess {
     start: *const u8,
@@ -73,6 +85,8 @@ impl PciAccess {
             }
             remapping
         };
+        LEGACY_PENDING_ACKS
+            .initialize(remapping.values().map(|&interrupt| (interrupt, AtomicUsize::new(0))).collect());
 
         Some(PciAccess {
             start: ecam_address.ptr(),
@@ -107,47 +121,39 @@ impl ConfigRegionAccess for PciAccess {
 impl PciInterruptConfigurator for PciAccess {
     fn configure_legacy(&self, function: PciAddress, pin: u8) -> Arc<Event> {
         info!("Configuring PCI device to use legacy interrupts: {:?}", function);
-        let event = Event::new();
 
         let remapped_interrupt =
             self.legacy_interrupt_remapping.get(&(function, pin)).expect("PCI interrupt not in remapping!");
+        let event = Event::new_interrupt(*remapped_interrupt, acknowledge_legacy_interrupt);
         INTERRUPT_ROUTING.lock().get_mut(&remapped_interrupt).unwrap().push(event.clone());
 
         event
     }
 
     fn configure_msi(&self, function: PciAddress, msi: &mut MsiCapability) -> Arc<Event> {
-        let event = Event::new();
+        // Message-signalled interrupts are edge-triggered, so count them to avoid losing any that arrive together
+        let event = Event::new_counting();
         info!("Configuring PCI device to use MSI interrupts: {:?}", function);
 
-        // TODO: allocate numbers from somewhere???
-        // TODO: we need a way to track unused interrupt vectors - can we find the valid range from
-        // the device tree and then reserve ones used by other devices or something? (this feels
-        // like it could live in the common kernel and be useful for everyone)
-        let message_number = 2;
+        let message_number = INTERRUPT_VECTORS.get().lock().allocate().expect("No free interrupt vectors for MSI");
         INTERRUPT_ROUTING.lock().insert(message_number, vec![event.clone()]);
 
         interrupts::handle_interrupt(message_number as u16, pci_interrupt_handler);
 
-        // TODO: get out of the device tree
-        msi.set_message_info(0x28000000, message_number as u32, self);
-        msi.set_enabled(true, self);
+        interrupts::MSI_CONTROLLER.get().configure_msi(message_number, msi, self);
 
         event
     }
 
-    fn configure_msix(&self, function: PciAddress, table_bar: Bar, msix: &mut MsixCapability) -> Arc<Event> {
-        let event = Event::new();
-        info!("Configuring PCI device to use MSI-X interrupts: {:?}", function);
-
-        // TODO: this is bad and we should allocate these for real as per above
-        let message_number = 3;
-        INTERRUPT_ROUTING.lock().insert(message_number, vec![event.clone()]);
-
-        interrupts::handle_interrupt(message_number as u16, pci_interrupt_handler);
+    fn configure_msix(
+        &self,
+        function: PciAddress,
+        table_bar: Bar,
+        msix: &mut MsixCapability,
+        num_vectors: u16,
+    ) -> Vec<Arc<Event>> {
+        info!("Configuring PCI device to use {} MSI-X interrupts: {:?}", num_vectors, function);
 
-        // TODO: get out of the device tree
-        let message_address = 0x28000000;
         msix.set_enabled(true, self);
 
         let table_base_phys = match table_bar {
@@ -157,31 +163,49 @@ impl PciInterruptConfigurator for PciAccess {
         };
         let table_base_virt =
             hal_riscv::platform::kernel_map::physical_to_virtual(PAddr::new(table_base_phys).unwrap());
-        // TODO: offset into the table if we ever need an entry that isn't the first
-        let entry_ptr = table_base_virt.mut_ptr() as *mut u32;
 
-        /*
-         * Each entry of the MSI-X table is laid out as:
-         *    0x00 => Message Address
-         *    0x04 => Message Upper Address
-         *    0x08 => Message Data
-         *    0x0c => Vector Control
-         */
-        unsafe {
-            ptr::write_volatile(entry_ptr.byte_add(0x00), message_address);
-            ptr::write_volatile(entry_ptr.byte_add(0x04), 0);
-            ptr::write_volatile(entry_ptr.byte_add(0x08), message_number as u32);
-            ptr::write_volatile(entry_ptr.byte_add(0x0c), 0);
+        let mut events = Vec::new();
+        for entry in 0..num_vectors {
+            let Some(message_number) = INTERRUPT_VECTORS.get().lock().allocate() else {
+                warn!("Ran out of interrupt vectors for MSI-X. Only configuring {} vectors.", entry);
+                break;
+            };
+            let event = Event::new_counting();
+            INTERRUPT_ROUTING.lock().insert(message_number, vec![event.clone()]);
+            interrupts::handle_interrupt(message_number as u16, pci_interrupt_handler);
+
+            interrupts::MSI_CONTROLLER.get().configure_msix_entry(message_number, table_base_virt, entry);
+            events.push(event);
         }
 
-        event
+        assert!(!events.is_empty(), "No free interrupt vectors for MSI-X");
+        events
     }
 }
+
 fn pci_interrupt_handler(number: u16) {
     let routing = INTERRUPT_ROUTING.lock();
     if let Some(events) = routing.get(&(number as u32)) {
+        /*
+         * If this is a legacy interrupt, mask it until every event sharing the line has been acknowledged.
+         * Otherwise, it would keep firing until the device has been serviced by its driver.
+         */
+        if let Some(pending_acks) = LEGACY_PENDING_ACKS.try_get().and_then(|acks| acks.get(&(number as u32))) {
+            if !events.is_empty() {
+                interrupts::mask_wired_interrupt(number as u32);
+                pending_acks.store(events.len(), Ordering::SeqCst);
+            }
+        }
+
         for event in events {
             event.signal();
         }
     }
 }
+
+fn acknowledge_legacy_interrupt(line: u32) {
+    let pending_acks = LEGACY_PENDING_ACKS.get().get(&line).unwrap();
+    if pending_acks.fetch_sub(1, Ordering::SeqCst) == 1 {
+        interrupts::unmask_wired_interrupt(line);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::task::{self, Scratch};
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use hal::memory::VAddr;
+
+/// The most CPUs we can run on. Address spaces track which CPUs they're active on in a `u64`.
+pub const MAX_CPUS: usize = 64;
+
+/// The HART ID of each CPU, so requests can be sent to other HARTs through the SBI.
+static HART_IDS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Get the HART ID of the given CPU, which must have installed its per-CPU data.
+pub fn hart_id(cpu_id: usize) -> usize {
+    HART_IDS[cpu_id].load(Ordering::SeqCst)
+}
+
+/// Get the per-CPU data of the running HART. The kernel's thread pointer (`tp`) always points to it,
+/// and the trap handler restores it from the `sscratch` area on entry to the kernel. It is not
+/// valid to call this before `PerCpuImpl::install` has been called on the running HART.
+pub fn get<'a>() -> &'a PerCpuImpl {
+    unsafe { &*task::tp().ptr() }
+}
+
+/// Represents data that is held individually for each HART.
+pub struct PerCpuImpl {
+    pub cpu_id: usize,
+    pub hart_id: usize,
+    /// The area that `sscratch` points to while a task is running on this HART. This is used by the
+    /// trap handler to find the kernel's stack, thread pointer, and global pointer.
+    pub scratch: Cell<Scratch>,
+}
+
+impl PerCpuImpl {
+    pub fn install(cpu_id: usize, hart_id: usize) {
+        HART_IDS[cpu_id].store(hart_id, Ordering::SeqCst);
+        let per_cpu = Box::leak(Box::new(PerCpuImpl {
+            cpu_id,
+            hart_id,
+            scratch: Cell::new(Scratch {
+                kernel_stack_pointer: VAddr::new(0x0),
+                kernel_thread_pointer: VAddr::new(0x0),
+                kernel_global_pointer: VAddr::new(0x0),
+                scratch_stack_pointer: VAddr::new(0x0),
+            }),
+        }));
+
+        unsafe {
+            asm!("mv tp, {}", in(reg) per_cpu as *const PerCpuImpl as usize);
+        }
+    }
+}
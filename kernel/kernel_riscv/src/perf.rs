@@ -0,0 +1,147 @@
+//! Performance counters, using the SBI's Performance Monitoring Unit extension. The events counted by the
+//! `hpmcounter`s can only be selected from M-mode, so the SBI finds a counter that can count each event and
+//! programs it for us. We can then read the counters directly, through the `cycle`, `instret`, and `hpmcounter`
+//! CSRs.
+//!
+//! Counters are identified by the index the SBI gives them, which is also the number of their CSR (relative to
+//! `cycle`).
+
+use core::arch::asm;
+use kernel::PerfEvent;
+use mulch::InitGuard;
+use tracing::info;
+
+static NUM_COUNTERS: InitGuard<usize> = InitGuard::uninit();
+
+pub const BASE_EXTENSION: usize = 0x10;
+pub const BASE_PROBE_EXTENSION: usize = 3;
+
+const PMU_EXTENSION: usize = 0x504d55;
+const PMU_NUM_COUNTERS: usize = 0;
+const PMU_COUNTER_CONFIG_MATCHING: usize = 2;
+const PMU_COUNTER_STOP: usize = 4;
+
+/// Flags for `PMU_COUNTER_CONFIG_MATCHING`
+const CONFIG_CLEAR_VALUE: usize = 1 << 1;
+const CONFIG_AUTO_START: usize = 1 << 2;
+const CONFIG_INHIBIT_SUPERVISOR: usize = 1 << 6;
+const CONFIG_INHIBIT_MACHINE: usize = 1 << 7;
+/// Flags for `PMU_COUNTER_STOP`. Resetting a counter releases it, so it can be used for another event.
+const STOP_RESET: usize = 1 << 0;
+
+/// Find out whether the SBI supports performance counters. This must be called on the boot HART, and we assume the
+/// other HARTs have the same counters.
+pub fn init() {
+    // Probing returns `0` if the extension isn't available
+    let probe = sbi_call(BASE_EXTENSION, BASE_PROBE_EXTENSION, [PMU_EXTENSION, 0, 0, 0, 0]);
+    if matches!(probe, Ok(available) if available != 0) {
+        if let Ok(num_counters) = sbi_call(PMU_EXTENSION, PMU_NUM_COUNTERS, [0; 5]) {
+            info!("Performance counters: {} through the SBI", num_counters);
+            NUM_COUNTERS.initialize(usize::min(num_counters, 64));
+            return;
+        }
+    }
+    info!("SBI does not support performance counters");
+}
+
+/// Ask the SBI for a counter for each of `events`, and start them counting from zero if `start` is set. If any of
+/// the events can't be counted, the counters that were allocated are released, and this returns `false`.
+pub fn allocate(events: &[PerfEvent], counters: &mut [usize], start: bool) -> bool {
+    let Some(&num_counters) = NUM_COUNTERS.try_get() else {
+        return false;
+    };
+    if events.len() > num_counters {
+        return false;
+    }
+
+    let mask = if num_counters == 64 { usize::MAX } else { (1 << num_counters) - 1 };
+    let mut flags = CONFIG_CLEAR_VALUE | CONFIG_INHIBIT_SUPERVISOR | CONFIG_INHIBIT_MACHINE;
+    if start {
+        flags |= CONFIG_AUTO_START;
+    }
+
+    for (i, &event) in events.iter().enumerate() {
+        match sbi_call(PMU_EXTENSION, PMU_COUNTER_CONFIG_MATCHING, [0, mask, flags, event_index(event), 0]) {
+            Ok(counter) => counters[i] = counter,
+            Err(_) => {
+                release(&counters[0..i]);
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+pub fn read(counters: &[usize], counts: &mut [u64]) {
+    for (&counter, count) in counters.iter().zip(counts.iter_mut()) {
+        *count = read_counter(counter);
+    }
+}
+
+/// Stop `counters` and give them back to the SBI.
+pub fn release(counters: &[usize]) {
+    for &counter in counters {
+        let _ = sbi_call(PMU_EXTENSION, PMU_COUNTER_STOP, [counter, 1, STOP_RESET, 0, 0]);
+    }
+}
+
+/// The SBI's index for each event, which are all "hardware general events".
+fn event_index(event: PerfEvent) -> usize {
+    match event {
+        PerfEvent::Cycles => 1,
+        PerfEvent::Instructions => 2,
+        PerfEvent::CacheMisses => 4,
+        PerfEvent::BranchMisses => 6,
+    }
+}
+
+fn read_counter(counter: usize) -> u64 {
+    macro read_csr($($index: literal => $csr: ident),*) {
+        match counter {
+            $(
+                $index => {
+                    let value: u64;
+                    unsafe {
+                        asm!(concat!("csrr {}, ", stringify!($csr)), out(reg) value);
+                    }
+                    value
+                }
+            )*
+            _ => 0,
+        }
+    }
+
+    read_csr!(
+        0 => cycle, 1 => time, 2 => instret, 3 => hpmcounter3, 4 => hpmcounter4, 5 => hpmcounter5,
+        6 => hpmcounter6, 7 => hpmcounter7, 8 => hpmcounter8, 9 => hpmcounter9, 10 => hpmcounter10,
+        11 => hpmcounter11, 12 => hpmcounter12, 13 => hpmcounter13, 14 => hpmcounter14, 15 => hpmcounter15,
+        16 => hpmcounter16, 17 => hpmcounter17, 18 => hpmcounter18, 19 => hpmcounter19, 20 => hpmcounter20,
+        21 => hpmcounter21, 22 => hpmcounter22, 23 => hpmcounter23, 24 => hpmcounter24, 25 => hpmcounter25,
+        26 => hpmcounter26, 27 => hpmcounter27, 28 => hpmcounter28, 29 => hpmcounter29, 30 => hpmcounter30,
+        31 => hpmcounter31
+    )
+}
+
+/// Make a call to the SBI. The `sbi` crate doesn't support the PMU extension, or suspending HARTs, yet, so we
+/// make these calls ourselves.
+pub fn sbi_call(extension: usize, function: usize, args: [usize; 5]) -> Result<usize, isize> {
+    let (error, value): (isize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(error)
+    }
+}
@@ -0,0 +1,100 @@
+//! Devices on RISC-V platforms are mostly described by the device tree, rather than being found on a bus. We
+//! hand the devices the kernel doesn't use itself out to userspace, so they can be driven by drivers found through
+//! the Platform Bus.
+
+use crate::interrupts;
+use alloc::{collections::BTreeMap, string::ToString, sync::Arc, vec::Vec};
+use fdt::{node::FdtNode, Fdt};
+use hal::memory::PAddr;
+use kernel::{object::event::Event, platform_devices::PlatformDevice};
+use spinning_top::Spinlock;
+use tracing::info;
+
+/// Devices that are used by the kernel itself, and so shouldn't be handed out to userspace.
+const KERNEL_DEVICES: &[&str] = &[
+    "riscv,clint0",
+    "sifive,clint0",
+    "riscv,plic0",
+    "sifive,plic-1.0.0",
+    "riscv,aplic",
+    "riscv,imsics",
+    "riscv,iommu",
+    "pci-host-ecam-generic",
+    "pci-host-cam-generic",
+    "google,goldfish-rtc",
+];
+
+// TODO: this should have an interrupt guard as well
+static INTERRUPT_EVENTS: Spinlock<BTreeMap<u32, Arc<Event>>> = Spinlock::new(BTreeMap::new());
+
+/// Find the devices under the `/soc` node of the device tree that should be handed out to userspace. Each device
+/// is given its first interrupt, if it has any, which is masked when it fires until the device's driver
+/// acknowledges it.
+pub fn enumerate(fdt: &Fdt) -> Vec<PlatformDevice> {
+    let Some(soc) = fdt.find_node("/soc") else {
+        return Vec::new();
+    };
+    let stdout = fdt.chosen().stdout().map(|stdout| stdout.node().name);
+
+    let mut devices = Vec::new();
+    for node in soc.children() {
+        let Some(compatible) = node.compatible() else {
+            continue;
+        };
+        let Some(reg) = node.reg() else {
+            continue;
+        };
+        if !is_enabled(node)
+            || Some(node.name) == stdout
+            || node.property("interrupt-controller").is_some()
+            || compatible.all().any(|c| KERNEL_DEVICES.contains(&c))
+        {
+            continue;
+        }
+
+        let regions = reg
+            .filter_map(|region| {
+                let address = PAddr::new(region.starting_address as usize)?;
+                Some((address, region.size?))
+            })
+            .collect();
+
+        let interrupt = node.interrupts().and_then(|mut interrupts| interrupts.next()).map(|interrupt| {
+            let line = interrupts::handle_wired_device_interrupt(interrupt, interrupt_handler);
+            let event = Event::new_interrupt(line, acknowledge_interrupt);
+            INTERRUPT_EVENTS.lock().insert(line, event.clone());
+            event
+        });
+
+        info!("Found platform device: {} (compatible with {:?})", node.name, compatible.first());
+        devices.push(PlatformDevice {
+            name: "/soc/".to_string() + node.name,
+            compatible: compatible.all().map(|c| c.to_string()).collect(),
+            regions,
+            io_ports: Vec::new(),
+            interrupt,
+        });
+    }
+
+    devices
+}
+
+fn is_enabled(node: FdtNode<'_, '_>) -> bool {
+    match node.property("status").and_then(|status| status.as_str()) {
+        Some(status) => status == "okay" || status == "ok",
+        None => true,
+    }
+}
+
+/// Device interrupts are generally level-triggered, and only the device's driver can service the device, so we
+/// mask the interrupt until the driver acknowledges it.
+fn interrupt_handler(number: u16) {
+    if let Some(event) = INTERRUPT_EVENTS.lock().get(&(number as u32)) {
+        interrupts::mask_wired_interrupt(number as u32);
+        event.signal();
+    }
+}
+
+fn acknowledge_interrupt(line: u32) {
+    interrupts::unmask_wired_interrupt(line);
+}
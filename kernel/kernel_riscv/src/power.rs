@@ -0,0 +1,65 @@
+//! Idling HARTs, using the SBI's Hart State Management extension if it supports suspending them. A default
+//! retentive suspend lets the SBI put the HART into whatever platform-specific low-power state it knows about,
+//! and returns once an interrupt is pending, like `wfi`. We don't have a way to control the frequency of HARTs,
+//! so performance requests from the governor are ignored.
+//!
+//! The system is powered off and reset through the SBI's System Reset extension.
+
+use crate::perf::{sbi_call, BASE_EXTENSION, BASE_PROBE_EXTENSION};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing::info;
+
+static SUSPEND_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+const HSM_EXTENSION: usize = 0x48534d;
+const HSM_HART_SUSPEND: usize = 3;
+const SRST_EXTENSION: usize = 0x53525354;
+const SRST_SYSTEM_RESET: usize = 0;
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_TYPE_COLD_REBOOT: usize = 1;
+const RESET_REASON_NONE: usize = 0;
+
+/// Suspend types below `0x80000000` are retentive: the HART resumes from where it was suspended, with its
+/// registers preserved. `0` is the default retentive state, which every platform supports.
+const SUSPEND_DEFAULT_RETENTIVE: usize = 0;
+
+/// Find out whether the SBI supports suspending HARTs. This must be called on the boot HART, and we assume the
+/// other HARTs are the same.
+pub fn init() {
+    // Probing returns `0` if the extension isn't available
+    let probe = sbi_call(BASE_EXTENSION, BASE_PROBE_EXTENSION, [HSM_EXTENSION, 0, 0, 0, 0]);
+    if matches!(probe, Ok(available) if available != 0) {
+        info!("Idling HARTs with SBI suspends");
+        SUSPEND_SUPPORTED.store(true, Ordering::Relaxed);
+    } else {
+        info!("SBI does not support suspending HARTs. Idling with `wfi`.");
+    }
+}
+
+/// Idle the running HART until an interrupt is pending. This works regardless of whether interrupts are enabled in
+/// `sstatus`, as both `wfi` and retentive suspends wake up on any interrupt that's enabled in `sie`.
+pub fn idle() {
+    if SUSPEND_SUPPORTED.load(Ordering::Relaxed) {
+        let result = sbi_call(HSM_EXTENSION, HSM_HART_SUSPEND, [SUSPEND_DEFAULT_RETENTIVE, 0, 0, 0, 0]);
+        if result.is_ok() {
+            return;
+        }
+    }
+
+    unsafe {
+        asm!("wfi");
+    }
+}
+
+/// Power the system off. This only returns if the SBI doesn't support the System Reset extension.
+pub fn power_off() {
+    let _ = sbi_call(SRST_EXTENSION, SRST_SYSTEM_RESET, [RESET_TYPE_SHUTDOWN, RESET_REASON_NONE, 0, 0, 0]);
+}
+
+/// Reset the system. This only returns if the SBI doesn't support the System Reset extension.
+pub fn reset() {
+    let _ = sbi_call(SRST_EXTENSION, SRST_SYSTEM_RESET, [RESET_TYPE_COLD_REBOOT, RESET_REASON_NONE, 0, 0, 0]);
+}
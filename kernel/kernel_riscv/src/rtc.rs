@@ -0,0 +1,47 @@
+//! Driver for the Goldfish real-time clock, which QEMU's `virt` machine provides. It keeps the time as a number of
+//! nanoseconds since the Unix epoch.
+
+use core::{ptr, time::Duration};
+use fdt::Fdt;
+use hal::memory::PAddr;
+use kernel::rtc::Rtc;
+use spinning_top::Spinlock;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+pub struct GoldfishRtc {
+    base: usize,
+    /// The two halves of the time have to be accessed in order, so accesses can't be interleaved.
+    lock: Spinlock<()>,
+}
+
+impl GoldfishRtc {
+    pub fn new(fdt: &Fdt) -> Option<GoldfishRtc> {
+        let node = fdt.find_compatible(&["google,goldfish-rtc"])?;
+        let address = node.reg()?.next()?.starting_address as usize;
+        let base = hal_riscv::platform::kernel_map::physical_to_virtual(PAddr::new(address)?);
+        Some(GoldfishRtc { base: usize::from(base), lock: Spinlock::new(()) })
+    }
+}
+
+impl Rtc for GoldfishRtc {
+    fn read(&self) -> Duration {
+        let _guard = self.lock.lock();
+        // Reading the low half latches the high half, so it must be read first
+        let low = unsafe { ptr::read_volatile((self.base + TIME_LOW) as *const u32) } as u64;
+        let high = unsafe { ptr::read_volatile((self.base + TIME_HIGH) as *const u32) } as u64;
+        Duration::from_nanos(high << 32 | low)
+    }
+
+    fn write(&self, time: Duration) -> Result<(), ()> {
+        let nanos = u64::try_from(time.as_nanos()).map_err(|_| ())?;
+        let _guard = self.lock.lock();
+        // The time is updated when the low half is written, so the high half must be written first
+        unsafe {
+            ptr::write_volatile((self.base + TIME_HIGH) as *mut u32, (nanos >> 32) as u32);
+            ptr::write_volatile((self.base + TIME_LOW) as *mut u32, nanos as u32);
+        }
+        Ok(())
+    }
+}
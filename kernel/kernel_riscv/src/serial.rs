@@ -6,14 +6,17 @@
 use core::{
     fmt,
     fmt::Write,
-    panic::PanicInfo,
+    panic::{Location, PanicInfo},
     sync::atomic::{AtomicU64, Ordering},
 };
 use fdt::Fdt;
 use hal::memory::PAddr;
-use hal_riscv::{hw::uart16550::Uart16550, platform::kernel_map::physical_to_virtual};
-use kernel::tasklets::queue::QueueProducer;
-use mulch::InitGuard;
+use hal_riscv::{
+    hw::uart16550::Uart16550,
+    platform::kernel_map::{physical_to_virtual, KERNEL_ADDRESS_SPACE_START},
+};
+use kernel::{klog::LogWriter, tasklets::queue::QueueProducer};
+use mulch::{backtrace::Backtrace, InitGuard};
 use spinning_top::Spinlock;
 use tracing::{span, Collect, Event, Level, Metadata};
 use tracing_core::span::Current as CurrentSpan;
@@ -21,6 +24,8 @@ use tracing_core::span::Current as CurrentSpan;
 static SERIAL: InitGuard<Uart16550<'static>> = InitGuard::uninit();
 static SERIAL_PRODUCER: InitGuard<kernel::tasklets::queue::QueueProducer> = InitGuard::uninit();
 static LOGGER: Logger = Logger::new();
+#[cfg(feature = "qemu_exit")]
+static QEMU_TEST_DEVICE: InitGuard<hal_riscv::hw::qemu::TestDevice> = InitGuard::uninit();
 
 pub fn init(fdt: &Fdt) {
     let Some(stdout) = fdt.chosen().stdout() else {
@@ -42,6 +47,12 @@ pub fn init(fdt: &Fdt) {
     serial.init();
     SERIAL.initialize(serial);
 
+    #[cfg(feature = "qemu_exit")]
+    if let Some(reg) = fdt.find_compatible(&["sifive,test1", "sifive,test0"]).and_then(|node| node.reg()?.next()) {
+        let address = physical_to_virtual(PAddr::new(reg.starting_address as usize).unwrap());
+        QEMU_TEST_DEVICE.initialize(unsafe { hal_riscv::hw::qemu::TestDevice::new(address) });
+    }
+
     tracing::dispatch::set_global_default(tracing::dispatch::Dispatch::from_static(&LOGGER))
         .expect("Failed to set default tracing dispatch");
 }
@@ -120,9 +131,11 @@ impl Collect for Logger {
                 Level::ERROR => "\x1b[31m",
             };
             let mut serial = self.serial.lock();
-            write!(serial, "[{}{:5}\x1b[0m] {}: ", color, level, event.metadata().target()).unwrap();
-            event.record(&mut Visitor::new(serial.deref_mut()));
-            write!(serial, "\n").unwrap();
+            // Everything logged is also kept in the kernel log, so userspace can read it back
+            let mut writer = LogWriter::new(serial.deref_mut());
+            write!(writer, "[{}{:5}\x1b[0m] {}: ", color, level, event.metadata().target()).unwrap();
+            event.record(&mut Visitor::new(&mut writer));
+            write!(writer, "\n").unwrap();
         }
     }
 
@@ -194,17 +207,48 @@ where
 
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
-    if let Some(location) = info.location() {
-        let _ = writeln!(
-            SerialWriter,
-            "PANIC: {} ({} - {}:{})",
-            info.message(),
-            location.file(),
-            location.line(),
-            location.column()
-        );
-    } else {
-        let _ = writeln!(SerialWriter, "PANIC: {} (no location info)", info.message());
+    let (sp, s0, ra, sstatus, satp): (usize, usize, usize, usize, usize);
+    unsafe {
+        core::arch::asm!("mv {}, sp", out(reg) sp);
+        core::arch::asm!("mv {}, s0", out(reg) s0);
+        core::arch::asm!("mv {}, ra", out(reg) ra);
+        core::arch::asm!("csrr {}, sstatus", out(reg) sstatus);
+        core::arch::asm!("csrr {}, satp", out(reg) satp);
+    }
+    let registers = [("sp", sp), ("s0", s0), ("ra", ra), ("sstatus", sstatus), ("satp", satp)];
+
+    let backtrace = Backtrace::capture(|address| address >= usize::from(KERNEL_ADDRESS_SPACE_START));
+    crash(&info.message(), info.location(), &registers, &backtrace)
+}
+
+/// Exit QEMU with `exit_code` through its test device. If we didn't find the test device, this stops instead.
+#[cfg(feature = "qemu_exit")]
+pub fn exit_qemu(exit_code: hal_riscv::hw::qemu::ExitCode) -> ! {
+    if let Some(test_device) = QEMU_TEST_DEVICE.try_get() {
+        test_device.exit(exit_code);
+    }
+
+    loop {}
+}
+
+/// Write a crash report (see `kernel::crash`) to the serial port, and stop. If the `qemu_exit` feature is set, and
+/// we found QEMU's test device, this exits QEMU with a failure code instead, so whatever ran QEMU can tell that
+/// the kernel crashed.
+pub fn crash(
+    message: &dyn fmt::Display,
+    location: Option<&Location>,
+    registers: &[(&'static str, usize)],
+    backtrace: &Backtrace,
+) -> ! {
+    if kernel::crash::begin() {
+        let crash = kernel::crash::Crash { message, location, registers, backtrace };
+        let _ = kernel::crash::write_report(&mut SerialWriter, &crash, crate::SCHEDULER.try_get());
     }
+
+    #[cfg(feature = "qemu_exit")]
+    if let Some(test_device) = QEMU_TEST_DEVICE.try_get() {
+        test_device.exit(hal_riscv::hw::qemu::ExitCode::Failed);
+    }
+
     loop {}
 }
@@ -0,0 +1,149 @@
+//! Secondary HARTs are brought up using the SBI's HART State Management (HSM) extension. Each HART is
+//! started at `ap_trampoline` with paging disabled, which switches to the kernel's page tables and
+//! jumps to `ap_entry` to finish initializing the HART and start scheduling tasks on it.
+
+use crate::{
+    clock,
+    per_cpu::{PerCpuImpl, MAX_CPUS},
+    task,
+    trap,
+    PlatformImpl,
+    KERNEL_PAGE_TABLES,
+};
+use alloc::vec::Vec;
+use core::{
+    arch::global_asm,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use fdt::Fdt;
+use hal::memory::{Flags, Frame, Page, PageTable, Size4KiB, VAddr};
+use hal_riscv::{hw::csr::Satp, platform::kernel_map};
+use tracing::{info, warn};
+
+global_asm!(include_str!("ap_trampoline.s"));
+extern "C" {
+    fn ap_trampoline() -> !;
+}
+
+/// Passed to each secondary HART through the SBI's `opaque` parameter, and read by the trampoline
+/// before paging is enabled. The layout of this must match `ap_trampoline.s`.
+#[repr(C)]
+struct ApBootInfo {
+    satp: u64,
+    stack_pointer: u64,
+    global_pointer: u64,
+    entry_point: u64,
+    cpu_id: u64,
+}
+
+/// Size of the kernel stack each HART runs on until it switches to its first task.
+const AP_STACK_SIZE: usize = 0x4000;
+
+/// Set by each secondary HART once it has been initialized, so the boot HART knows it can reuse
+/// the `ApBootInfo` for the next one.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Find the IDs of the HARTs, other than the boot HART, that we should try to bring up. These are
+/// given kernel CPU IDs in the order they're returned, starting from `1`. HARTs beyond `MAX_CPUS`
+/// are ignored.
+pub fn application_harts(fdt: &Fdt, boot_hart_id: usize) -> Vec<usize> {
+    fdt.cpus()
+        .filter(|cpu| {
+            cpu.property("status").and_then(|status| status.as_str()).map_or(true, |status| status == "okay")
+        })
+        .map(|cpu| cpu.ids().first())
+        .filter(|&hart_id| hart_id != boot_hart_id)
+        .take(MAX_CPUS - 1)
+        .collect()
+}
+
+/// Bring up each of the given HARTs in turn. This must be called by the boot HART once the
+/// scheduler has been created, as each HART starts scheduling tasks as soon as it has been
+/// initialized.
+pub fn boot_application_harts(application_harts: &[usize]) {
+    if application_harts.is_empty() {
+        return;
+    }
+
+    let pmm = kernel::PMM.get();
+
+    /*
+     * The trampoline enables paging while it's still running from its physical address, so it
+     * needs to be identity-mapped until all the HARTs are up.
+     */
+    let trampoline_physical =
+        KERNEL_PAGE_TABLES.get().read().translate(VAddr::new(ap_trampoline as usize)).unwrap();
+    let identity_page = Page::<Size4KiB>::starts_with(VAddr::new(usize::from(trampoline_physical)));
+    KERNEL_PAGE_TABLES
+        .get()
+        .write()
+        .map(
+            identity_page,
+            Frame::starts_with(trampoline_physical),
+            Flags { executable: true, ..Default::default() },
+            pmm,
+        )
+        .unwrap();
+
+    let boot_info_physical = pmm.alloc(1);
+    let boot_info = kernel_map::physical_to_virtual(boot_info_physical).mut_ptr::<ApBootInfo>();
+
+    for (index, &hart_id) in application_harts.iter().enumerate() {
+        let cpu_id = index + 1;
+        let stack = kernel::VMM
+            .get()
+            .alloc_kernel_stack::<PlatformImpl>(AP_STACK_SIZE, pmm, &mut KERNEL_PAGE_TABLES.get().write())
+            .expect("Failed to allocate kernel stack for HART");
+
+        unsafe {
+            ptr::write_volatile(
+                boot_info,
+                ApBootInfo {
+                    satp: Satp::read().raw(),
+                    stack_pointer: usize::from(stack.top.align_down(16)) as u64,
+                    global_pointer: usize::from(task::gp()) as u64,
+                    entry_point: ap_entry as usize as u64,
+                    cpu_id: cpu_id as u64,
+                },
+            );
+        }
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        if let Err(err) = sbi::hart_state_management::hart_start(
+            hart_id,
+            usize::from(trampoline_physical),
+            usize::from(boot_info_physical),
+        ) {
+            warn!("Failed to start HART {}: {:?}", hart_id, err);
+            continue;
+        }
+
+        while !AP_STARTED.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    }
+
+    KERNEL_PAGE_TABLES.get().write().unmap(identity_page);
+    pmm.free(boot_info_physical, 1);
+}
+
+/// This is jumped to by the trampoline, on the stack allocated for this HART. At this point,
+/// we're on the kernel's page tables, but have no trap handler installed.
+extern "C" fn ap_entry(cpu_id: usize, hart_id: usize) -> ! {
+    trap::install_early_handler();
+    PerCpuImpl::install(cpu_id, hart_id);
+
+    /*
+     * We don't enable interrupts on secondary HARTs yet - the timer and external interrupts are
+     * currently only handled by the boot HART. We do need the full trap handler to be able to run
+     * tasks, though.
+     */
+    trap::install_full_handler();
+    clock::enable_user_access();
+
+    info!("HART {} is up as CPU {}", hart_id, cpu_id);
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    crate::SCHEDULER.get().start_scheduling()
+}
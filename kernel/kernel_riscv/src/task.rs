@@ -1,6 +1,5 @@
 use core::{
     arch::{asm, global_asm},
-    cell::Cell,
     ptr,
 };
 use hal::memory::VAddr;
@@ -14,18 +13,6 @@ extern "C" {
     fn do_context_switch(from_context: *mut ContextSwitchFrame, to_context: *const ContextSwitchFrame);
 }
 
-// TODO: this is definitely pretty bad and probably a LLVM crime. Plan in the future is to use
-// `thread_local` for HART-local data so this can be a `Cell` directly.
-pub struct NotGreatCell<T>(pub Cell<T>);
-unsafe impl<T> Sync for NotGreatCell<T> {}
-
-static SCRATCH: NotGreatCell<Scratch> = NotGreatCell(Cell::new(Scratch {
-    kernel_stack_pointer: VAddr::new(0x0),
-    kernel_thread_pointer: VAddr::new(0x0),
-    kernel_global_pointer: VAddr::new(0x0),
-    scratch_stack_pointer: VAddr::new(0x0),
-}));
-
 /*
  * XXX: the offsets of fields in this struct are used in assembly, so care must be taken when
  * re-ordering / adding fields.
@@ -90,7 +77,13 @@ pub struct TaskContext {
     kernel_stack_pointer: VAddr,
 }
 
-pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> TaskContext {
+pub fn new_task_context(
+    kernel_stack: &Stack,
+    user_stack: &Stack,
+    task_entry_point: VAddr,
+    argument: usize,
+    thread_pointer: usize,
+) -> TaskContext {
     /*
      * Initialize the kernel stack. Firstly, we need to make sure the top of the stack is 16-byte
      * aligned, according to the Sys-V ABI.
@@ -110,8 +103,10 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
         sp: usize::from(kernel_stack_pointer),
         s0: usize::from(task_entry_point),
         s1: usize::from(user_stack_pointer),
-        s2: 0,
-        s3: 0,
+        // Moved into `a0` on entry to userspace
+        s2: argument,
+        // Moved into `tp` on entry to userspace
+        s3: thread_pointer,
         s4: 0,
         s5: 0,
         s6: 0,
@@ -126,11 +121,12 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
 }
 
 pub unsafe fn context_switch(from_context: *mut TaskContext, to_context: *const TaskContext) {
+    let scratch = &crate::per_cpu::get().scratch;
     unsafe {
-        (*from_context).kernel_stack_pointer = (*SCRATCH.0.as_ptr()).kernel_stack_pointer;
+        (*from_context).kernel_stack_pointer = (*scratch.as_ptr()).kernel_stack_pointer;
     }
     let new_kernel_stack_pointer = unsafe { (*to_context).kernel_stack_pointer };
-    SCRATCH.0.set(Scratch::new(new_kernel_stack_pointer));
+    scratch.set(Scratch::new(new_kernel_stack_pointer));
     do_context_switch(
         &raw mut (*from_context).context_switch_frame,
         &raw const (*to_context).context_switch_frame,
@@ -139,9 +135,10 @@ pub unsafe fn context_switch(from_context: *mut TaskContext, to_context: *const
 
 pub unsafe fn drop_into_userspace(context: *const TaskContext) -> ! {
     // Initialize this HART's `sscratch` area
+    let scratch = &crate::per_cpu::get().scratch;
     let kernel_stack_pointer = unsafe { (*context).kernel_stack_pointer };
-    SCRATCH.0.set(Scratch::new(kernel_stack_pointer));
-    Sscratch::write(VAddr::from(SCRATCH.0.as_ptr()));
+    scratch.set(Scratch::new(kernel_stack_pointer));
+    Sscratch::write(VAddr::from(scratch.as_ptr()));
 
     unsafe { do_drop_to_userspace(&raw const (*context).context_switch_frame) }
 }
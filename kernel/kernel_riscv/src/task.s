@@ -13,6 +13,14 @@ task_entry_trampoline:
     // Switch to the user's stack
     mv sp, s1
 
+    // Pass the task's argument in `a0`
+    mv a0, s2
+    li s2, 0
+
+    // Set up the task's thread pointer. The user's `tp` is saved and restored on traps from here on.
+    mv tp, s3
+    li s3, 0
+
     sret
 
 .global do_drop_to_userspace
@@ -48,6 +56,14 @@ do_drop_to_userspace:
     // Switch to the user's stack
     mv sp, s1
 
+    // Pass the task's argument in `a0`
+    mv a0, s2
+    li s2, 0
+
+    // Set up the task's thread pointer. The user's `tp` is saved and restored on traps from here on.
+    mv tp, s3
+    li s3, 0
+
     sret
 
 .global do_context_switch
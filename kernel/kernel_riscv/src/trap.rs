@@ -1,11 +1,20 @@
 use crate::interrupts;
-use core::arch::naked_asm;
+use core::{arch::naked_asm, time::Duration};
 use hal::memory::VAddr;
 use hal_riscv::{
     hw::csr::{Scause, Sepc, Stvec},
     platform::kernel_map,
 };
-use tracing::info;
+use kernel::{
+    object::address_space::{PageFaultAccess, PageFaultResolution},
+    profile::InterruptedContext,
+};
+use mulch::backtrace::Backtrace;
+use poplar::syscall::{ExceptionKind, FaultAccess, Registers};
+
+/// The period of the timer interrupt, which drives the kernel's timer wheel. This must match the
+/// interval we program the timer with.
+pub const TIMER_PERIOD: Duration = Duration::from_millis(20);
 
 /// Install the proper trap handler. This handler is able to take traps from both S-mode and
 /// U-mode, but requires the `sscratch` context to be correctly installed to facilitate switching
@@ -39,26 +48,143 @@ extern "C" fn trap_handler(trap_frame: &mut TrapFrame, scause: usize, stval: usi
             interrupts::handle_external_interrupt();
         }
         Ok(Scause::SupervisorTimerInterrupt) => {
-            crate::SCHEDULER.get().tasklet_scheduler.advance_timer(1);
+            let context = InterruptedContext {
+                instruction_pointer: trap_frame.sepc,
+                frame_pointer: trap_frame.s0,
+                in_user: trap_frame.sepc < usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START),
+            };
+            kernel::profile::sample(crate::SCHEDULER.get(), &context, |address| {
+                address >= usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START)
+            });
+
+            crate::SCHEDULER.get().tasklet_scheduler.advance_timer(TIMER_PERIOD);
             // Schedule the next tick in 20ms time (TODO: I have no idea what a sensible interval
             // should be). `Timer::advance` returns a `Turn` struct that tells us when the next
             // deadline is - the most efficient thing if this is all we need the timer interrupt
             // for would be to wait til then?
             sbi::timer::set_timer(hal_riscv::hw::csr::Time::read() as u64 + 0x989680 / 50).unwrap();
-        }
-        Ok(other) => {
-            info!("Trap! Cause = {:?}. Stval = {:#x?}", other, stval);
+
+            /*
+             * Pre-empt the running task if it's used up its time slice. We can only do this if
+             * we've interrupted userspace, and must do it after setting the next tick as we might
+             * not return here for a while.
+             */
             if trap_frame.sepc < usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START) {
-                let cpu_scheduler = crate::SCHEDULER.get().for_this_cpu();
-                info!("Trap occurred in user task: {}", cpu_scheduler.running_task.as_ref().unwrap().name);
+                crate::SCHEDULER.get().tick(TIMER_PERIOD);
             }
-            info!("Trap frame: {:#x?}", trap_frame);
-            panic!();
         }
+        Ok(cause @ (Scause::InstructionPageFault | Scause::LoadPageFault | Scause::StorePageFault)) => {
+            /*
+             * Some page faults are expected, and can be resolved by the kernel (e.g. writes to
+             * copy-on-write memory). If so, we return to retry the faulting access.
+             */
+            let access = match cause {
+                Scause::InstructionPageFault => PageFaultAccess::Execute,
+                Scause::LoadPageFault => PageFaultAccess::Read,
+                _ => PageFaultAccess::Write,
+            };
+            let from_user = trap_frame.sepc < usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START);
+            let resolution = match crate::SCHEDULER.try_get() {
+                Some(scheduler) if stval < usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START) => {
+                    scheduler.handle_page_fault(VAddr::new(stval), access, from_user)
+                }
+                _ => PageFaultResolution::Invalid,
+            };
+            let kind = match resolution {
+                PageFaultResolution::Resolved => return,
+                PageFaultResolution::StackOverflow => ExceptionKind::StackOverflow,
+                PageFaultResolution::Invalid => ExceptionKind::PageFault(match access {
+                    PageFaultAccess::Read => FaultAccess::Read,
+                    PageFaultAccess::Write => FaultAccess::Write,
+                    PageFaultAccess::Execute => FaultAccess::Execute,
+                }),
+            };
+            deliver_to_task(trap_frame, cause, kind, stval);
+        }
+        Ok(cause @ Scause::IllegalInstruction) => {
+            // `stval` holds the faulting instruction, rather than its address
+            let address = trap_frame.sepc;
+            deliver_to_task(trap_frame, cause, ExceptionKind::IllegalInstruction, address);
+        }
+        Ok(
+            cause @ (Scause::InstructionAddressMisaligned
+            | Scause::LoadAddressMisaligned
+            | Scause::StoreAddressMisaligned),
+        ) => {
+            deliver_to_task(trap_frame, cause, ExceptionKind::MisalignedAccess, stval);
+        }
+        Ok(cause @ (Scause::InstructionAccessFault | Scause::LoadAccessFault | Scause::StoreAccessFault)) => {
+            let access = match cause {
+                Scause::InstructionAccessFault => FaultAccess::Execute,
+                Scause::LoadAccessFault => FaultAccess::Read,
+                _ => FaultAccess::Write,
+            };
+            deliver_to_task(trap_frame, cause, ExceptionKind::PageFault(access), stval);
+        }
+        Ok(other) => unhandled_trap(trap_frame, other, stval),
         Err(()) => panic!("Unrecognised trap cause!"),
     }
 }
 
+/// Deliver an exception caused by a user task to its exception channel. If the exception is resolved by
+/// resuming the task, any changes to its registers are written back to the trap frame. Exceptions caused by
+/// the kernel are fatal.
+fn deliver_to_task(trap_frame: &mut TrapFrame, cause: Scause, kind: ExceptionKind, address: usize) {
+    if trap_frame.sepc >= usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START) {
+        unhandled_trap(trap_frame, cause, address);
+    }
+
+    let mut registers = trap_frame.to_registers();
+    crate::SCHEDULER.get().handle_exception(kind, address, &mut registers);
+    trap_frame.set_registers(&registers);
+}
+
+fn unhandled_trap(trap_frame: &TrapFrame, cause: Scause, stval: usize) -> ! {
+    let registers = [
+        ("pc", trap_frame.sepc),
+        ("ra", trap_frame.ra),
+        ("sp", trap_frame.sp),
+        ("gp", trap_frame.gp),
+        ("tp", trap_frame.tp),
+        ("t0", trap_frame.t0),
+        ("t1", trap_frame.t1),
+        ("t2", trap_frame.t2),
+        ("s0", trap_frame.s0),
+        ("s1", trap_frame.s1),
+        ("a0", trap_frame.a0),
+        ("a1", trap_frame.a1),
+        ("a2", trap_frame.a2),
+        ("a3", trap_frame.a3),
+        ("a4", trap_frame.a4),
+        ("a5", trap_frame.a5),
+        ("a6", trap_frame.a6),
+        ("a7", trap_frame.a7),
+        ("s2", trap_frame.s2),
+        ("s3", trap_frame.s3),
+        ("s4", trap_frame.s4),
+        ("s5", trap_frame.s5),
+        ("s6", trap_frame.s6),
+        ("s7", trap_frame.s7),
+        ("s8", trap_frame.s8),
+        ("s9", trap_frame.s9),
+        ("s10", trap_frame.s10),
+        ("s11", trap_frame.s11),
+        ("t3", trap_frame.t3),
+        ("t4", trap_frame.t4),
+        ("t5", trap_frame.t5),
+        ("t6", trap_frame.t6),
+        ("stval", stval),
+    ];
+
+    // Walk the stack from the context that trapped, rather than from the trap handler
+    let backtrace = unsafe {
+        Backtrace::from_frame_pointer(trap_frame.s0, |address| {
+            address >= usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START)
+        })
+    };
+    crate::serial::crash(&format_args!("Unhandled trap: {:?}", cause), None, &registers, &backtrace)
+}
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct TrapFrame {
@@ -96,6 +222,28 @@ pub struct TrapFrame {
     t6: usize,
 }
 
+/// Copy the listed registers between a `TrapFrame` and a `Registers`, which share the same names for them.
+macro_rules! copy_registers {
+    ($from:expr => $to:expr; $($reg:ident),*) => {
+        $($to.$reg = $from.$reg;)*
+    };
+}
+
+impl TrapFrame {
+    pub fn to_registers(&self) -> Registers {
+        let mut registers = Registers { pc: self.sepc, ..Default::default() };
+        copy_registers!(self => registers; ra, sp, gp, tp, t0, t1, t2, s0, s1, a0, a1, a2, a3, a4, a5, a6, a7, s2,
+            s3, s4, s5, s6, s7, s8, s9, s10, s11, t3, t4, t5, t6);
+        registers
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.sepc = registers.pc;
+        copy_registers!(registers => self; ra, sp, gp, tp, t0, t1, t2, s0, s1, a0, a1, a2, a3, a4, a5, a6, a7, s2,
+            s3, s4, s5, s6, s7, s8, s9, s10, s11, t3, t4, t5, t6);
+    }
+}
+
 #[repr(align(4))]
 #[naked]
 extern "C" fn trap_handler_shim() -> ! {
@@ -0,0 +1,101 @@
+/*
+ * This is the code that application processors start executing when they're sent a Startup IPI. The bootstrap
+ * processor copies it into a page below 1MiB, and patches in the fields marked below. The SIPI starts the
+ * processor in real mode with `cs:ip` pointing at the start of the page, so we can only address things relative
+ * to the start of the trampoline until we're in long mode.
+ *
+ * We move directly from real mode into long mode, without passing through protected mode. This requires the
+ * trampoline to be identity-mapped in the page tables we enable paging with, which the bootstrap processor takes
+ * care of.
+ */
+.section .rodata.ap_trampoline, "a"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+
+    // Make data accesses relative to the start of the trampoline
+    mov ax, cs
+    mov ds, ax
+
+    // Enable PAE and global pages, and 5-level paging if the kernel is using it
+    mov eax, dword ptr [DATA_OFFSET + 32]
+    mov cr4, eax
+
+    // Load the kernel's page tables. These must be below 4GiB for us to be able to load them from real mode.
+    mov eax, dword ptr [DATA_OFFSET]
+    mov cr3, eax
+
+    // Enable long mode and the NX bit (the kernel's page tables use it) in EFER
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // Load the temporary GDT. It's below 1MiB, so a 24-bit base is enough.
+    lgdt [GDTR_OFFSET]
+
+    // Enable paging and protection at the same time to move directly into long mode
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+
+    /*
+     * Far-jump into the 64-bit code segment. The target can be above 64KiB, so we need a 32-bit offset, which we
+     * have to encode manually. The target is patched in by the bootstrap processor.
+     */
+    .byte 0x66, 0xea
+.global ap_trampoline_long_mode_target
+ap_trampoline_long_mode_target:
+    .long 0x0
+    .word 0x8
+
+.code64
+.global ap_trampoline_long_mode
+ap_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    // Load the stack, CPU id, and entry point from the data area
+    mov rsp, qword ptr [rip + ap_trampoline_data + 8]
+    mov rax, qword ptr [rip + ap_trampoline_data + 16]
+    mov rdi, qword ptr [rip + ap_trampoline_data + 24]
+
+    // Push a zero return address to terminate backtraces, and jump into the kernel
+    push 0
+    jmp rax
+
+.p2align 3
+.global ap_trampoline_gdt
+ap_trampoline_gdt:
+    .quad 0x0                       // Null segment
+    .quad 0x00af9a000000ffff        // 64-bit code segment
+    .quad 0x00cf92000000ffff        // Data segment
+ap_trampoline_gdtr:
+    .word ap_trampoline_gdtr - ap_trampoline_gdt - 1
+.global ap_trampoline_gdt_base
+ap_trampoline_gdt_base:
+    .long 0x0                       // Patched with the physical address of `ap_trampoline_gdt`
+
+/*
+ * This area is filled in by the bootstrap processor for each processor it starts. Its layout must match
+ * `ApTrampolineData`.
+ */
+.p2align 3
+.global ap_trampoline_data
+ap_trampoline_data:
+    .quad 0x0                       // Physical address of the kernel's page tables
+    .quad 0x0                       // Stack pointer
+    .quad 0x0                       // Entry point
+    .quad 0x0                       // CPU id
+    .quad 0x0                       // Value of CR4 to enable paging with
+.global ap_trampoline_end
+ap_trampoline_end:
+
+.set DATA_OFFSET, ap_trampoline_data - ap_trampoline_start
+.set GDTR_OFFSET, ap_trampoline_gdtr - ap_trampoline_start
@@ -0,0 +1,103 @@
+//! The kernel's high-resolution monotonic clock is the TSC. We need to know its frequency to convert it into a
+//! time, which we get from `cpuid` if we can, and otherwise calibrate against the PIT.
+
+use core::{arch::x86_64::_rdtsc, time::Duration};
+use hal_x86_64::hw::{cpu::CpuInfo, port::Port};
+use mulch::InitGuard;
+use tracing::{info, warn};
+
+/// The frequency of the TSC, in Hz.
+static TSC_FREQUENCY: InitGuard<u64> = InitGuard::uninit();
+/// The value of the TSC when the clock was initialized. Time is measured from this point.
+static BOOT_TSC: InitGuard<u64> = InitGuard::uninit();
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_2_PORT: u16 = 0x42;
+const PIT_COMMAND_PORT: u16 = 0x43;
+/// Controls the gate of PIT channel 2, and lets us read its output. This is shared with the PC speaker, which we
+/// make sure is disabled.
+const PIT_CHANNEL_2_CONTROL_PORT: u16 = 0x61;
+const CONTROL_GATE: u8 = 1 << 0;
+const CONTROL_SPEAKER: u8 = 1 << 1;
+const CONTROL_OUTPUT: u8 = 1 << 5;
+/// How long to count the TSC for when calibrating it. Longer calibrations are more accurate, but delay boot.
+const CALIBRATION_PERIOD_MS: u64 = 10;
+
+/// Initialize the monotonic clock. This must be called on the bootstrap processor before interrupts are enabled,
+/// as calibrating the TSC is timing-sensitive.
+pub fn init(cpu_info: &CpuInfo) {
+    let frequency = match cpu_info.tsc_frequency() {
+        Some(frequency) => frequency,
+        None => calibrate_tsc(),
+    };
+    info!("TSC runs at {}.{:03} MHz", frequency / 1_000_000, (frequency / 1000) % 1000);
+
+    /*
+     * We assume the TSC is invariant and synchronized between processors, which is true of the processors we
+     * support. If it isn't, the clock won't be monotonic across processors.
+     */
+    // TODO: check the invariant TSC bit of cpuid leaf `0x8000_0007`
+    TSC_FREQUENCY.initialize(frequency);
+    BOOT_TSC.initialize(read_tsc());
+
+    // Userspace can read the TSC with `rdtsc` too, as `topo::check_support_and_enable_features` clears `CR4.TSD`
+    kernel::vdso::set_clock(frequency, *BOOT_TSC.get());
+}
+
+/// Get the time since the clock was initialized.
+pub fn now() -> Duration {
+    let ticks = read_tsc().saturating_sub(*BOOT_TSC.get());
+    let frequency = *TSC_FREQUENCY.get();
+    // Split the calculation so the nanoseconds don't overflow until the clock has been running for centuries
+    Duration::new(ticks / frequency, ((ticks % frequency) * 1_000_000_000 / frequency) as u32)
+}
+
+/// Spin for (at least) `duration`. Cannot be called before `init`.
+pub fn spin_delay(duration: Duration) {
+    let end = now() + duration;
+    while now() < end {
+        core::hint::spin_loop();
+    }
+}
+
+fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Work out the frequency of the TSC by counting how many times it ticks while PIT channel 2 counts down
+/// `CALIBRATION_PERIOD_MS`.
+fn calibrate_tsc() -> u64 {
+    let mut command = unsafe { Port::<u8>::new(PIT_COMMAND_PORT) };
+    let mut channel_2 = unsafe { Port::<u8>::new(PIT_CHANNEL_2_PORT) };
+    let mut control = unsafe { Port::<u8>::new(PIT_CHANNEL_2_CONTROL_PORT) };
+    let count = PIT_FREQUENCY * CALIBRATION_PERIOD_MS / 1000;
+
+    unsafe {
+        // Disable the gate (and the speaker) while we program the channel
+        let value = control.read() & !(CONTROL_GATE | CONTROL_SPEAKER);
+        control.write(value);
+
+        // Channel 2, access the low and then high byte of the count, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        // Enabling the gate starts the count. The channel's output goes high when it reaches zero.
+        control.write(value | CONTROL_GATE);
+    }
+
+    let start = read_tsc();
+    let mut spins = 0u64;
+    while unsafe { control.read() } & CONTROL_OUTPUT == 0 {
+        spins += 1;
+        if spins > 1_000_000_000 {
+            // The PIT doesn't seem to be there. Guess a frequency rather than hanging.
+            warn!("Failed to calibrate TSC against the PIT. Assuming it runs at 1GHz.");
+            return 1_000_000_000;
+        }
+        core::hint::spin_loop();
+    }
+    let end = read_tsc();
+
+    (end - start) * 1000 / CALIBRATION_PERIOD_MS
+}
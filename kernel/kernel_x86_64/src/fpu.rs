@@ -0,0 +1,102 @@
+//! Userspace can use the x87 FPU, SSE, and AVX, so each task has its own copy of their registers, which is saved
+//! and restored with `xsave` and `xrstor` when we switch between tasks. The kernel itself is built without
+//! floating point or SIMD, so it never touches these registers, and they don't need to be saved on system calls
+//! or interrupts.
+//!
+//! We switch the state on every context switch, rather than lazily (by setting `CR0.TS`, and waiting for the new
+//! task to fault when it first uses the FPU). Switching lazily leaves the previous task's registers loaded while
+//! another task runs, which can be leaked through speculative execution (the "LazyFP" attack), and most tasks
+//! use SSE anyway, because the compiler uses it for copying memory. Instead, we let the processor avoid work where
+//! it can: `xsaveopt` only writes out the state components that have changed since they were restored, and
+//! components in their initial state (like the upper halves of the `ymm` registers, for tasks that don't use
+//! AVX) are neither saved nor loaded.
+
+use alloc::{boxed::Box, vec};
+use bit_field::BitField;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use hal_x86_64::hw::{
+    cpu::CpuInfo,
+    registers::{read_xcr, write_xcr, XCR0, XCR0_AVX, XCR0_AVX512, XCR0_SSE, XCR0_X87},
+};
+use tracing::info;
+
+/// The size of the area needed to save the state components we enable. This is the same on every processor.
+static XSAVE_AREA_SIZE: AtomicUsize = AtomicUsize::new(0);
+static USE_XSAVEOPT: AtomicBool = AtomicBool::new(false);
+
+/// The value `MXCSR` is reset to: all SIMD floating-point exceptions masked, and round-to-nearest.
+const INITIAL_MXCSR: u32 = 0x1f80;
+const MXCSR_OFFSET: usize = 24;
+
+/// Enable the state components userspace can use in `XCR0`. This must be called on every processor we bring up,
+/// after `CR4.OSXSAVE` has been set.
+pub fn enable(cpu_info: &CpuInfo) {
+    let supported = cpu_info.supported_xsave_components();
+    let mut xcr0 = read_xcr(XCR0);
+    xcr0.set_bit(XCR0_X87, true);
+    xcr0.set_bit(XCR0_SSE, true);
+    if cpu_info.supported_features.avx {
+        xcr0.set_bit(XCR0_AVX, true);
+
+        let avx512 = 0b111 << XCR0_AVX512.start;
+        if supported & avx512 == avx512 {
+            xcr0 |= avx512;
+        }
+    }
+    unsafe {
+        write_xcr(XCR0, xcr0);
+    }
+
+    let area_size = cpu_info.xsave_area_size();
+    if XSAVE_AREA_SIZE.swap(area_size, Ordering::Relaxed) == 0 {
+        info!("Enabled XSAVE state components {:#x} ({} bytes per task)", xcr0, area_size);
+    }
+    USE_XSAVEOPT.store(cpu_info.supports_xsaveopt(), Ordering::Relaxed);
+}
+
+/// The saved FPU and SIMD state of a task, in the format used by `xsave`.
+pub struct FpuState {
+    area: Box<[XsaveChunk]>,
+}
+
+/// `xsave` and `xrstor` need the save area to be aligned to 64 bytes, so we allocate it in 64-byte chunks.
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct XsaveChunk([u8; 64]);
+
+impl FpuState {
+    /// Create the state a task starts with. The header of the save area is zeroed, which tells `xrstor` to put
+    /// every state component in its initial state, except `MXCSR`, which is always loaded from the area.
+    pub fn new() -> FpuState {
+        let chunks = XSAVE_AREA_SIZE.load(Ordering::Relaxed).div_ceil(64);
+        assert!(chunks != 0, "Tried to create FPU state before enabling XSAVE!");
+
+        let mut area = vec![XsaveChunk([0; 64]); chunks].into_boxed_slice();
+        area[0].0[MXCSR_OFFSET..(MXCSR_OFFSET + 4)].copy_from_slice(&INITIAL_MXCSR.to_le_bytes());
+        FpuState { area }
+    }
+
+    /// Save the state of the FPU into this area. This must be the state of the task that owns this area, as
+    /// `xsaveopt` assumes that the components that haven't changed were last restored from the same area.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if USE_XSAVEOPT.load(Ordering::Relaxed) {
+                asm!("xsaveopt64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            }
+        }
+    }
+
+    /// Load this state into the FPU.
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        }
+    }
+}
@@ -0,0 +1,533 @@
+//! A stub for GDB's Remote Serial Protocol, which lets the kernel be debugged with GDB over the second serial port
+//! (COM2). When the kernel is built with the `gdb` feature, it stops in the stub early in boot to wait for GDB to
+//! connect, and then again whenever a breakpoint is hit, a single step completes, or GDB interrupts it (with
+//! Ctrl-C, which is noticed by the bootstrap processor's timer interrupt).
+//!
+//! While a CPU is stopped, GDB can read and change its registers, and read and write memory through whichever
+//! page tables are loaded. If a task was running when the CPU stopped, that means the task's memory can be
+//! inspected as well as the kernel's. Other CPUs keep running while one is stopped in the stub.
+
+use bit_field::BitField;
+use core::arch::asm;
+use hal::memory::{Frame, PAddr, PageTable, VAddr};
+use hal_x86_64::{
+    hw::{
+        idt::InterruptStackFrame,
+        registers::{read_control_reg, CpuFlags},
+        serial::{SerialPort, COM2},
+    },
+    kernel_map,
+    paging::{PageTableImpl, PagingMode},
+};
+use spinning_top::Spinlock;
+use tracing::info;
+
+/// The largest packet we can receive or send. This is advertised to GDB, which won't send larger packets.
+const PACKET_SIZE: usize = 0x1000;
+const MAX_BREAKPOINTS: usize = 64;
+const BREAKPOINT_INSTRUCTION: u8 = 0xcc;
+/// Sent by GDB, outside of a packet, to stop the kernel while it's running.
+const INTERRUPT: u8 = 0x03;
+const CR3_ADDRESS_MASK: usize = 0x000f_ffff_ffff_f000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+static STUB: Spinlock<GdbStub> = Spinlock::new(GdbStub::new());
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    /// An `int3` was executed, either at one of GDB's breakpoints or one that was compiled in.
+    Breakpoint,
+    /// A single step has completed.
+    Step,
+    /// GDB asked for the kernel to be stopped.
+    Interrupted,
+}
+
+/// Set up the serial port that GDB talks to, and then stop in the stub so that GDB can connect and set breakpoints
+/// before we go any further.
+pub fn init() {
+    unsafe {
+        STUB.lock().serial.initialize();
+    }
+
+    info!("Waiting for GDB to connect on COM2");
+    unsafe {
+        asm!("int3");
+    }
+}
+
+/// Stop in the stub, and hand control to GDB until it resumes the kernel. Called from the breakpoint and debug
+/// exception handlers.
+pub fn handle_exception(stack_frame: &mut InterruptStackFrame, reason: StopReason) {
+    STUB.lock().stop(stack_frame, reason);
+}
+
+/// Check whether GDB has asked for the kernel to be stopped, and stop in the stub if it has. If another CPU is
+/// already stopped, it's using the serial port, so this does nothing.
+pub fn poll(stack_frame: &mut InterruptStackFrame) {
+    let Some(mut stub) = STUB.try_lock() else {
+        return;
+    };
+
+    if unsafe { stub.serial.try_read() } == Some(INTERRUPT) {
+        stub.stop(stack_frame, StopReason::Interrupted);
+    }
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct GdbStub {
+    serial: SerialPort,
+    input: PacketBuffer,
+    output: PacketBuffer,
+    breakpoints: Breakpoints,
+}
+
+impl GdbStub {
+    const fn new() -> GdbStub {
+        GdbStub {
+            serial: unsafe { SerialPort::new(COM2) },
+            input: PacketBuffer::new(),
+            output: PacketBuffer::new(),
+            breakpoints: Breakpoints::new(),
+        }
+    }
+
+    fn stop(&mut self, stack_frame: &mut InterruptStackFrame, reason: StopReason) {
+        /*
+         * `int3` is a trap, so the instruction pointer is left after it. If it's one of GDB's breakpoints, we wind
+         * it back, so that GDB sees the kernel stopped at the breakpoint, and it's executed when it's resumed (GDB
+         * removes its breakpoints while the kernel is stopped).
+         */
+        let mut software_breakpoint = false;
+        if reason == StopReason::Breakpoint {
+            let address = usize::from(stack_frame.instruction_pointer) - 1;
+            if self.breakpoints.contains(address) {
+                stack_frame.instruction_pointer = VAddr::new(address);
+                software_breakpoint = true;
+            }
+        }
+
+        let signal = match reason {
+            StopReason::Breakpoint | StopReason::Step => SIGTRAP,
+            StopReason::Interrupted => SIGINT,
+        };
+        let send_stop_reply = |output: &mut PacketBuffer| {
+            if software_breakpoint {
+                output.push(b'T');
+                output.push_hex(&[signal]);
+                output.push_str("swbreak:;");
+            } else {
+                output.push(b'S');
+                output.push_hex(&[signal]);
+            }
+        };
+
+        self.output.clear();
+        send_stop_reply(&mut self.output);
+        send(&mut self.serial, &self.output);
+
+        let resume = loop {
+            receive(&mut self.serial, &mut self.input);
+            self.output.clear();
+
+            let packet = self.input.as_bytes();
+            match packet {
+                [b'?'] => send_stop_reply(&mut self.output),
+                [b'g'] => read_registers(stack_frame, &mut self.output),
+                [b'G', registers @ ..] => self.output.push_result(write_registers(stack_frame, registers)),
+                [b'm', arguments @ ..] => match parse_address_and_length(arguments) {
+                    Some((address, length)) => read_memory(address, length, &mut self.output),
+                    None => self.output.push_str("E01"),
+                },
+                [b'M', arguments @ ..] => self.output.push_result(write_memory(arguments)),
+                [b'c', address @ ..] => {
+                    resume_at(stack_frame, address);
+                    break Resume::Continue;
+                }
+                [b's', address @ ..] => {
+                    resume_at(stack_frame, address);
+                    break Resume::Step;
+                }
+                [b'Z', b'0', b',', arguments @ ..] => {
+                    let result = match parse_address_and_length(arguments) {
+                        Some((address, _)) => self.breakpoints.insert(address),
+                        None => None,
+                    };
+                    self.output.push_result(result);
+                }
+                [b'z', b'0', b',', arguments @ ..] => {
+                    let result = match parse_address_and_length(arguments) {
+                        Some((address, _)) => self.breakpoints.remove(address),
+                        None => None,
+                    };
+                    self.output.push_result(result);
+                }
+                [b'D', ..] => {
+                    self.breakpoints.remove_all();
+                    self.output.push_str("OK");
+                    send(&mut self.serial, &self.output);
+                    break Resume::Continue;
+                }
+                [b'k'] => {
+                    // We can't be killed, so treat this like GDB detaching, but without a reply
+                    self.breakpoints.remove_all();
+                    break Resume::Continue;
+                }
+                [b'H', ..] => self.output.push_str("OK"),
+                b"qAttached" => self.output.push_str("1"),
+                _ if packet.starts_with(b"qSupported") => {
+                    self.output.push_str("PacketSize=");
+                    self.output.push_hex(&(PACKET_SIZE as u16).to_be_bytes());
+                    self.output.push_str(";swbreak+");
+                }
+                // Anything else isn't supported, which is signalled to GDB with an empty reply
+                _ => (),
+            }
+
+            send(&mut self.serial, &self.output);
+        };
+
+        let mut flags = u64::from(stack_frame.cpu_flags);
+        flags.set_bit(CpuFlags::TRAP_FLAG as usize, matches!(resume, Resume::Step));
+        stack_frame.cpu_flags = CpuFlags::new(flags);
+    }
+}
+
+struct PacketBuffer {
+    data: [u8; PACKET_SIZE],
+    length: usize,
+}
+
+impl PacketBuffer {
+    const fn new() -> PacketBuffer {
+        PacketBuffer { data: [0; PACKET_SIZE], length: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    /// Add a byte to the packet. Bytes that don't fit are dropped.
+    fn push(&mut self, byte: u8) {
+        if self.length < PACKET_SIZE {
+            self.data[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    fn push_str(&mut self, value: &str) {
+        for byte in value.bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(hex_digit(byte >> 4));
+            self.push(hex_digit(byte & 0xf));
+        }
+    }
+
+    /// Reply to a command that has no other result with whether it succeeded.
+    fn push_result(&mut self, result: Option<()>) {
+        match result {
+            Some(()) => self.push_str("OK"),
+            None => self.push_str("E01"),
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[0..self.length]
+    }
+}
+
+/// Receive a packet (`${data}#{checksum}`) into `buffer`. Anything received outside of a packet (acknowledgements,
+/// and requests to stop that arrive while we're already stopped) is ignored. Packets that arrive corrupted are
+/// rejected, and GDB sends them again.
+fn receive(serial: &mut SerialPort, buffer: &mut PacketBuffer) {
+    loop {
+        while unsafe { serial.read() } != b'$' {}
+
+        buffer.clear();
+        let mut checksum = 0u8;
+        loop {
+            match unsafe { serial.read() } {
+                b'#' => break,
+                byte => {
+                    checksum = checksum.wrapping_add(byte);
+                    buffer.push(byte);
+                }
+            }
+        }
+
+        let expected = unsafe { [serial.read(), serial.read()] };
+        if parse_hex(&expected) == Some(checksum as usize) {
+            unsafe {
+                serial.write(b'+');
+            }
+            return;
+        }
+
+        unsafe {
+            serial.write(b'-');
+        }
+    }
+}
+
+/// Send `packet` to GDB, sending it again until GDB acknowledges that it arrived intact.
+fn send(serial: &mut SerialPort, packet: &PacketBuffer) {
+    loop {
+        let mut checksum = 0u8;
+        unsafe {
+            serial.write(b'$');
+            for &byte in packet.as_bytes() {
+                checksum = checksum.wrapping_add(byte);
+                serial.write(byte);
+            }
+            serial.write(b'#');
+        }
+
+        unsafe {
+            serial.write(hex_digit(checksum >> 4));
+            serial.write(hex_digit(checksum & 0xf));
+        }
+
+        loop {
+            match unsafe { serial.read() } {
+                b'+' => return,
+                b'-' => break,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Write the registers in the order GDB expects for x86_64: the general registers and `rip`, each 64 bits, and
+/// then `eflags`, `cs`, and `ss`, each 32 bits. GDB treats the registers we don't send (the other segment
+/// registers and the floating point state) as unavailable.
+fn read_registers(stack_frame: &InterruptStackFrame, output: &mut PacketBuffer) {
+    for register in general_registers(stack_frame) {
+        output.push_hex(&register.to_le_bytes());
+    }
+    for register in [u64::from(stack_frame.cpu_flags), stack_frame.code_segment, stack_frame.stack_segment] {
+        output.push_hex(&(register as u32).to_le_bytes());
+    }
+}
+
+/// Update the registers from the contents of a `G` packet, which is in the same format as `read_registers` writes.
+/// The segment registers can't be changed.
+fn write_registers(stack_frame: &mut InterruptStackFrame, data: &[u8]) -> Option<()> {
+    let mut values = [0u64; 17];
+    let mut chunks = data.chunks_exact(16);
+    for value in values.iter_mut() {
+        *value = u64::from_le_bytes(parse_hex_bytes(chunks.next()?)?);
+    }
+    let flags = u32::from_le_bytes(parse_hex_bytes(data.get((17 * 16)..(17 * 16 + 8))?)?);
+
+    let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip] = values;
+    stack_frame.rax = rax;
+    stack_frame.rbx = rbx;
+    stack_frame.rcx = rcx;
+    stack_frame.rdx = rdx;
+    stack_frame.rsi = rsi;
+    stack_frame.rdi = rdi;
+    stack_frame.rbp = rbp;
+    stack_frame.stack_pointer = VAddr::new(rsp as usize);
+    stack_frame.r8 = r8;
+    stack_frame.r9 = r9;
+    stack_frame.r10 = r10;
+    stack_frame.r11 = r11;
+    stack_frame.r12 = r12;
+    stack_frame.r13 = r13;
+    stack_frame.r14 = r14;
+    stack_frame.r15 = r15;
+    stack_frame.instruction_pointer = VAddr::new(rip as usize);
+    // Bit 1 of `rflags` is reserved, and always set
+    stack_frame.cpu_flags = CpuFlags::new(u64::from(flags) | 0b10);
+    Some(())
+}
+
+fn general_registers(stack_frame: &InterruptStackFrame) -> [u64; 17] {
+    [
+        stack_frame.rax,
+        stack_frame.rbx,
+        stack_frame.rcx,
+        stack_frame.rdx,
+        stack_frame.rsi,
+        stack_frame.rdi,
+        stack_frame.rbp,
+        usize::from(stack_frame.stack_pointer) as u64,
+        stack_frame.r8,
+        stack_frame.r9,
+        stack_frame.r10,
+        stack_frame.r11,
+        stack_frame.r12,
+        stack_frame.r13,
+        stack_frame.r14,
+        stack_frame.r15,
+        usize::from(stack_frame.instruction_pointer) as u64,
+    ]
+}
+
+/// Read `length` bytes of memory from `address`. If only some of them are mapped, as many as can be read are
+/// returned, which GDB understands.
+fn read_memory(address: usize, length: usize, output: &mut PacketBuffer) {
+    let length = usize::min(length, PACKET_SIZE / 2);
+    for i in 0..length {
+        match address.checked_add(i).and_then(physical_mapping_of) {
+            Some(mapping) => output.push_hex(&[unsafe { mapping.ptr::<u8>().read_volatile() }]),
+            None => {
+                if i == 0 {
+                    output.push_str("E14");
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Write memory from the contents of an `M` packet (`{address},{length}:{data}`).
+fn write_memory(arguments: &[u8]) -> Option<()> {
+    let separator = arguments.iter().position(|&byte| byte == b':')?;
+    let (address, length) = parse_address_and_length(&arguments[0..separator])?;
+    let data = &arguments[(separator + 1)..];
+    if data.len() != length * 2 {
+        return None;
+    }
+
+    /*
+     * Check that the whole range is mapped before changing any of it, so a failed write doesn't leave memory
+     * half-written.
+     */
+    for i in 0..length {
+        physical_mapping_of(address.checked_add(i)?)?;
+    }
+    for (i, digits) in data.chunks_exact(2).enumerate() {
+        let [byte] = parse_hex_bytes(digits)?;
+        unsafe {
+            physical_mapping_of(address + i)?.mut_ptr::<u8>().write_volatile(byte);
+        }
+    }
+    Some(())
+}
+
+/// If `c` or `s` were given an address, resume from there instead of where the kernel stopped.
+fn resume_at(stack_frame: &mut InterruptStackFrame, address: &[u8]) {
+    if let Some(address) = parse_hex(address) {
+        stack_frame.instruction_pointer = VAddr::new(address);
+    }
+}
+
+/// Find where `address` is mapped in the page tables that are currently loaded, and get its address in the
+/// physical mapping. Memory is accessed through the physical mapping so that GDB asking for an address that isn't
+/// mapped can't fault the kernel, and so that GDB can write to code, which is mapped read-only.
+fn physical_mapping_of(address: usize) -> Option<VAddr> {
+    if usize::from(VAddr::new(address)) != address {
+        return None;
+    }
+
+    let page_tables = unsafe {
+        PageTableImpl::from_frame(
+            Frame::starts_with(PAddr::new(read_control_reg!(cr3) as usize & CR3_ADDRESS_MASK)?),
+            PagingMode::current(),
+            kernel_map::PHYSICAL_MAPPING_BASE,
+        )
+    };
+    let physical = page_tables.translate(VAddr::new(address))?;
+    Some(kernel_map::physical_to_virtual(physical))
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: usize,
+    /// Where the breakpoint's byte is in the physical mapping. This is found when the breakpoint is inserted, so
+    /// that removing it changes the same memory, even if different page tables are loaded by then.
+    mapping: VAddr,
+    original: u8,
+}
+
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+impl Breakpoints {
+    const fn new() -> Breakpoints {
+        Breakpoints([None; MAX_BREAKPOINTS])
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        self.0.iter().flatten().any(|breakpoint| breakpoint.address == address)
+    }
+
+    fn insert(&mut self, address: usize) -> Option<()> {
+        if self.contains(address) {
+            return Some(());
+        }
+
+        let slot = self.0.iter_mut().find(|slot| slot.is_none())?;
+        let mapping = physical_mapping_of(address)?;
+        let original = unsafe { mapping.ptr::<u8>().read_volatile() };
+        unsafe {
+            mapping.mut_ptr::<u8>().write_volatile(BREAKPOINT_INSTRUCTION);
+        }
+        *slot = Some(Breakpoint { address, mapping, original });
+        Some(())
+    }
+
+    fn remove(&mut self, address: usize) -> Option<()> {
+        let slot = self.0.iter_mut().find(|slot| slot.map_or(false, |breakpoint| breakpoint.address == address))?;
+        let breakpoint = slot.take()?;
+        unsafe {
+            breakpoint.mapping.mut_ptr::<u8>().write_volatile(breakpoint.original);
+        }
+        Some(())
+    }
+
+    fn remove_all(&mut self) {
+        for slot in self.0.iter_mut() {
+            if let Some(breakpoint) = slot.take() {
+                unsafe {
+                    breakpoint.mapping.mut_ptr::<u8>().write_volatile(breakpoint.original);
+                }
+            }
+        }
+    }
+}
+
+/// Parse the `{address},{length}` arguments that many packets take. Breakpoint packets take an address and a kind
+/// in the same format.
+fn parse_address_and_length(arguments: &[u8]) -> Option<(usize, usize)> {
+    let separator = arguments.iter().position(|&byte| byte == b',')?;
+    Some((parse_hex(&arguments[0..separator])?, parse_hex(&arguments[(separator + 1)..])?))
+}
+
+fn parse_hex(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() {
+        return None;
+    }
+
+    digits.iter().try_fold(0usize, |value, &digit| {
+        value.checked_mul(16)?.checked_add((digit as char).to_digit(16)? as usize)
+    })
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[value as usize]
+}
+
+/// Parse pairs of hex digits into bytes, in the order they're sent.
+fn parse_hex_bytes<const N: usize>(digits: &[u8]) -> Option<[u8; N]> {
+    if digits.len() != N * 2 {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = parse_hex(pair)? as u8;
+    }
+    Some(bytes)
+}
@@ -1,19 +1,46 @@
 //! This module contains all the interrupt handlers used to handle CPU exceptions. Some of these
-//! exceptions are handled and recovered from, while some are fatal errors and lead to kernel
-//! panics.
+//! exceptions are handled and recovered from, while some are fatal errors and crash the kernel.
 
 use bit_field::BitField;
-use hal_x86_64::hw::{
-    idt::{ExceptionWithErrorStackFrame, InterruptStackFrame},
-    registers::read_control_reg,
+use hal::memory::VAddr;
+use hal_x86_64::{
+    hw::{
+        idt::{ExceptionWithErrorStackFrame, InterruptStackFrame},
+        registers::{read_control_reg, CpuFlags},
+    },
+    kernel_map,
+    paging::PagingMode,
 };
-use mulch::BinaryPrettyPrint;
+use kernel::object::address_space::{PageFaultAccess, PageFaultResolution};
+use mulch::{backtrace::Backtrace, BinaryPrettyPrint};
+use poplar::syscall::{ExceptionKind, FaultAccess, Registers, EXCEPTION_EXIT_STATUS};
 use tracing::{error, info};
 
 pub extern "C" fn nmi_handler(_: &InterruptStackFrame) {
+    // Other processors send NMIs to ask us to flush our TLB
+    if crate::tlb::handle_nmi() {
+        return;
+    }
+
     info!("NMI occured!");
 }
 
+#[cfg(feature = "gdb")]
+pub extern "C" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    crate::gdb::handle_exception(stack_frame, crate::gdb::StopReason::Breakpoint);
+}
+
+/// Debug exceptions are raised after each instruction while the trap flag is set, which is how GDB single-steps.
+#[cfg(feature = "gdb")]
+pub extern "C" fn debug_exception_handler(stack_frame: &mut InterruptStackFrame) {
+    // Clear the debug status register, as the processor doesn't clear it itself
+    unsafe {
+        core::arch::asm!("mov dr6, {}", in(reg) 0u64);
+    }
+    crate::gdb::handle_exception(stack_frame, crate::gdb::StopReason::Step);
+}
+
+#[cfg(not(feature = "gdb"))]
 pub extern "C" fn breakpoint_handler(stack_frame: &InterruptStackFrame) {
     info!("BREAKPOINT: {:#x?}", stack_frame);
 
@@ -38,83 +65,244 @@ pub extern "C" fn breakpoint_handler(stack_frame: &InterruptStackFrame) {
     }
 }
 
-pub extern "C" fn invalid_opcode_handler(stack_frame: &InterruptStackFrame) {
-    error!("INVALID OPCODE AT: {:#x}", stack_frame.instruction_pointer);
-    error!("Stack frame: {:x?}", stack_frame);
+/// The flags that userspace can change when it resumes a task with new registers after an exception: the status
+/// flags, and the direction flag.
+const USER_CHANGEABLE_FLAGS: u64 = 0xcd5;
+
+/// Deliver an exception that a user task caused to its exception channel. If the exception is resolved by
+/// resuming the task, this writes any changes to its registers back to the stack frame and returns. Otherwise,
+/// the task is killed.
+macro deliver_to_task($stack_frame:expr, $kind:expr, $address:expr) {{
+    let stack_frame = $stack_frame;
+    let mut registers = Registers {
+        rax: stack_frame.rax,
+        rbx: stack_frame.rbx,
+        rcx: stack_frame.rcx,
+        rdx: stack_frame.rdx,
+        rsi: stack_frame.rsi,
+        rdi: stack_frame.rdi,
+        rbp: stack_frame.rbp,
+        rsp: usize::from(stack_frame.stack_pointer) as u64,
+        r8: stack_frame.r8,
+        r9: stack_frame.r9,
+        r10: stack_frame.r10,
+        r11: stack_frame.r11,
+        r12: stack_frame.r12,
+        r13: stack_frame.r13,
+        r14: stack_frame.r14,
+        r15: stack_frame.r15,
+        rip: usize::from(stack_frame.instruction_pointer) as u64,
+        rflags: u64::from(stack_frame.cpu_flags),
+    };
+
+    let scheduler = crate::SCHEDULER.get();
+    scheduler.handle_exception($kind, $address, &mut registers);
 
     /*
-     * TEMP: Do a stacktrace.
+     * Returning to a non-canonical or kernel address would fault in the kernel, so we kill the task instead.
      */
-    let mut rbp = stack_frame.rbp as usize;
-    info!("Starting stacktrace. First frame is at: {:#x}", rbp);
-    if rbp != 0 {
-        for i in 0..16 {
-            let next_rbp = unsafe { *(rbp as *const usize) };
-            let return_address = unsafe { *((rbp + 8) as *const usize) };
+    let user_address_space_end = usize::from(kernel_map::user_address_space_end(PagingMode::current()));
+    if registers.rip as usize > user_address_space_end || registers.rsp as usize > user_address_space_end {
+        scheduler.exit(EXCEPTION_EXIT_STATUS);
+    }
 
-            info!("     {}: return address: {:#x}. next frame is at: {:#x}", i, return_address, next_rbp);
+    stack_frame.rax = registers.rax;
+    stack_frame.rbx = registers.rbx;
+    stack_frame.rcx = registers.rcx;
+    stack_frame.rdx = registers.rdx;
+    stack_frame.rsi = registers.rsi;
+    stack_frame.rdi = registers.rdi;
+    stack_frame.rbp = registers.rbp;
+    stack_frame.stack_pointer = VAddr::new(registers.rsp as usize);
+    stack_frame.r8 = registers.r8;
+    stack_frame.r9 = registers.r9;
+    stack_frame.r10 = registers.r10;
+    stack_frame.r11 = registers.r11;
+    stack_frame.r12 = registers.r12;
+    stack_frame.r13 = registers.r13;
+    stack_frame.r14 = registers.r14;
+    stack_frame.r15 = registers.r15;
+    stack_frame.instruction_pointer = VAddr::new(registers.rip as usize);
+    stack_frame.cpu_flags = CpuFlags::new(
+        (u64::from(stack_frame.cpu_flags) & !USER_CHANGEABLE_FLAGS) | (registers.rflags & USER_CHANGEABLE_FLAGS),
+    );
+}}
 
-            if next_rbp == 0x0 {
-                break;
-            } else {
-                rbp = next_rbp;
-            }
-        }
+fn caused_by_user(instruction_pointer: VAddr) -> bool {
+    instruction_pointer < kernel_map::KERNEL_ADDRESS_SPACE_START
+}
+
+pub extern "C" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+    if caused_by_user(stack_frame.instruction_pointer) {
+        let address = usize::from(stack_frame.instruction_pointer);
+        deliver_to_task!(stack_frame, ExceptionKind::IllegalInstruction, address);
+        return;
     }
 
-    panic!("Unrecoverable fault");
+    fatal_fault!(format_args!("Invalid opcode at {:#x}", stack_frame.instruction_pointer), stack_frame);
 }
 
-pub extern "C" fn general_protection_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
-    error!("General protection fault (error code = {:#x}). Interrupt stack frame: ", stack_frame.error_code);
-    error!("{:#x?}", stack_frame);
-    panic!("Unrecoverable fault");
+/// Raised for an unmasked x87 FPU exception. The kernel doesn't use the FPU, so these can only be caused by
+/// userspace.
+pub extern "C" fn x87_fault_handler(stack_frame: &mut InterruptStackFrame) {
+    if caused_by_user(stack_frame.instruction_pointer) {
+        let address = usize::from(stack_frame.instruction_pointer);
+        deliver_to_task!(stack_frame, ExceptionKind::FloatingPoint, address);
+        return;
+    }
+
+    fatal_fault!("x87 FPU exception in the kernel", stack_frame);
 }
 
-pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
-    error!(
-        "PAGE_FAULT: {} ({:#x})",
-        match (
-            stack_frame.error_code.get_bit(2), // User / Supervisor
-            stack_frame.error_code.get_bit(4), // Instruction / Data
-            stack_frame.error_code.get_bit(1), // Read / Write
-            stack_frame.error_code.get_bit(0)  // Present
-        ) {
-            // Page faults caused by the kernel
-            (false, false, false, false) => "Kernel read non-present page",
-            (false, false, false, true) => "Kernel read present page",
-            (false, false, true, false) => "Kernel wrote to non-present page",
-            (false, false, true, true) => "Kernel wrote to present page",
-            (false, true, _, false) => "Kernel fetched instruction from non-present page",
-            (false, true, _, true) => "Kernel fetched instruction from present page",
-
-            // Page faults caused by user processes
-            (true, false, false, false) => "User process read non-present page",
-            (true, false, false, true) => "User process read present page (probable access violation)",
-            (true, false, true, false) => "User process wrote to non-present page",
-            (true, false, true, true) => "User process wrote to present page (probable access violation)",
-            (true, true, _, false) => "User process fetched instruction from non-present page",
-            (true, true, _, true) => {
-                "User process fetched instruction from present page (probable access violation)"
-            }
-        },
-        read_control_reg!(cr2) // CR2 holds the address of the page that caused the #PF
-    );
+/// Raised for an unmasked SIMD floating-point exception. Like x87 exceptions, these can only be caused by
+/// userspace.
+pub extern "C" fn simd_exception_handler(stack_frame: &mut InterruptStackFrame) {
+    if caused_by_user(stack_frame.instruction_pointer) {
+        let address = usize::from(stack_frame.instruction_pointer);
+        deliver_to_task!(stack_frame, ExceptionKind::FloatingPoint, address);
+        return;
+    }
 
-    error!("Error code: {}", BinaryPrettyPrint(stack_frame.error_code));
-    error!("{:#x?}", stack_frame);
+    fatal_fault!("SIMD floating-point exception in the kernel", stack_frame);
+}
+
+pub extern "C" fn general_protection_fault_handler(stack_frame: &mut ExceptionWithErrorStackFrame) {
+    if caused_by_user(stack_frame.instruction_pointer) {
+        let address = usize::from(stack_frame.instruction_pointer);
+        deliver_to_task!(stack_frame, ExceptionKind::ProtectionFault, address);
+        return;
+    }
 
+    fatal_fault!("General protection fault", stack_frame, [("error_code", stack_frame.error_code as usize)]);
+}
+
+pub extern "C" fn page_fault_handler(stack_frame: &mut ExceptionWithErrorStackFrame) {
     /*
-     * Page-faults can be recovered from and so are faults, but we never will so just give up.
+     * Some page faults are expected, and can be resolved by the kernel (e.g. writes to copy-on-write memory).
+     * These can be caused both by userspace, and by the kernel accessing userspace memory on its behalf.
      */
+    let address = VAddr::new(read_control_reg!(cr2) as usize);
+    if address < kernel_map::KERNEL_ADDRESS_SPACE_START {
+        let access = if stack_frame.error_code.get_bit(4) {
+            PageFaultAccess::Execute
+        } else if stack_frame.error_code.get_bit(1) {
+            PageFaultAccess::Write
+        } else {
+            PageFaultAccess::Read
+        };
+
+        let resolution = match crate::SCHEDULER.try_get() {
+            Some(scheduler) => scheduler.handle_page_fault(address, access, stack_frame.error_code.get_bit(2)),
+            None => PageFaultResolution::Invalid,
+        };
+
+        /*
+         * Accesses made by userspace that the kernel can't resolve are delivered to the task as exceptions.
+         * Faults caused by the kernel accessing userspace memory are still fatal.
+         */
+        let kind = match resolution {
+            PageFaultResolution::Resolved => return,
+            PageFaultResolution::StackOverflow => ExceptionKind::StackOverflow,
+            PageFaultResolution::Invalid => ExceptionKind::PageFault(match access {
+                PageFaultAccess::Read => FaultAccess::Read,
+                PageFaultAccess::Write => FaultAccess::Write,
+                PageFaultAccess::Execute => FaultAccess::Execute,
+            }),
+        };
+        if stack_frame.error_code.get_bit(2) {
+            deliver_to_task!(stack_frame, kind, usize::from(address));
+            return;
+        }
+    }
+
+    let description = match (
+        stack_frame.error_code.get_bit(2), // User / Supervisor
+        stack_frame.error_code.get_bit(4), // Instruction / Data
+        stack_frame.error_code.get_bit(1), // Read / Write
+        stack_frame.error_code.get_bit(0)  // Present
+    ) {
+        // Page faults caused by the kernel
+        (false, false, false, false) => "Kernel read non-present page",
+        (false, false, false, true) => "Kernel read present page",
+        (false, false, true, false) => "Kernel wrote to non-present page",
+        (false, false, true, true) => "Kernel wrote to present page",
+        (false, true, _, false) => "Kernel fetched instruction from non-present page",
+        (false, true, _, true) => "Kernel fetched instruction from present page",
+
+        // Page faults caused by user processes
+        (true, false, false, false) => "User process read non-present page",
+        (true, false, false, true) => "User process read present page (probable access violation)",
+        (true, false, true, false) => "User process wrote to non-present page",
+        (true, false, true, true) => "User process wrote to present page (probable access violation)",
+        (true, true, _, false) => "User process fetched instruction from non-present page",
+        (true, true, _, true) => "User process fetched instruction from present page (probable access violation)",
+    };
+    error!("Error code: {}", BinaryPrettyPrint(stack_frame.error_code));
+
+    // CR2 holds the address of the page that caused the #PF, and is included in the crash report's registers
+    fatal_fault!(
+        format_args!("Page fault: {} ({:#x})", description, address),
+        stack_frame,
+        [("error_code", stack_frame.error_code as usize)]
+    );
+}
+
+pub extern "C" fn double_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
     /*
-     * In the future, page faults can be used for demand paging and so are recoverable. At the moment, they're
-     * always bad, so we panic here.
+     * Double faults are handled on their own stack. If the kernel overflows its stack, the CPU can't push the
+     * page fault's stack frame, which causes a double fault - CR2 then holds the address below the stack that
+     * was accessed.
      */
-    panic!("Unrecoverable fault");
+    let address = VAddr::new(read_control_reg!(cr2) as usize);
+    if kernel::VMM.try_get().map_or(false, |vmm| vmm.is_kernel_stack_address(address)) {
+        fatal_fault!(
+            format_args!("Kernel stack overflow ({:#x})", address),
+            stack_frame,
+            [("error_code", stack_frame.error_code as usize)]
+        );
+    } else {
+        fatal_fault!("Double fault", stack_frame, [("error_code", stack_frame.error_code as usize)]);
+    }
 }
 
-pub extern "C" fn double_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
-    error!("EXCEPTION: DOUBLE FAULT   (Error code: {})\n{:#?}", stack_frame.error_code, stack_frame);
-    panic!("Unrecoverable fault");
+/// Crash the kernel because of a fault it can't recover from. The crash report includes the registers of the
+/// context that faulted, and a backtrace walked from its frame pointer (rather than from the exception handler).
+macro fatal_fault {
+    ($message:expr, $stack_frame:expr) => {
+        fatal_fault!($message, $stack_frame, [])
+    },
+    ($message:expr, $stack_frame:expr, [$($extra:expr),*]) => {{
+        let stack_frame = &*$stack_frame;
+        let registers = [
+            ("rax", stack_frame.rax as usize),
+            ("rbx", stack_frame.rbx as usize),
+            ("rcx", stack_frame.rcx as usize),
+            ("rdx", stack_frame.rdx as usize),
+            ("rsi", stack_frame.rsi as usize),
+            ("rdi", stack_frame.rdi as usize),
+            ("rbp", stack_frame.rbp as usize),
+            ("rsp", usize::from(stack_frame.stack_pointer)),
+            ("r8", stack_frame.r8 as usize),
+            ("r9", stack_frame.r9 as usize),
+            ("r10", stack_frame.r10 as usize),
+            ("r11", stack_frame.r11 as usize),
+            ("r12", stack_frame.r12 as usize),
+            ("r13", stack_frame.r13 as usize),
+            ("r14", stack_frame.r14 as usize),
+            ("r15", stack_frame.r15 as usize),
+            ("rip", usize::from(stack_frame.instruction_pointer)),
+            ("rflags", u64::from(stack_frame.cpu_flags) as usize),
+            ("cs", stack_frame.code_segment as usize),
+            ("ss", stack_frame.stack_segment as usize),
+            ("cr2", read_control_reg!(cr2) as usize),
+            ("cr3", read_control_reg!(cr3) as usize),
+            $($extra),*
+        ];
+        let backtrace = unsafe {
+            Backtrace::from_frame_pointer(stack_frame.rbp as usize, |address| {
+                address >= usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START)
+            })
+        };
+        crate::logger::crash(&$message, None, &registers, &backtrace)
+    }},
 }
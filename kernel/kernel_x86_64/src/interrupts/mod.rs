@@ -1,9 +1,13 @@
 mod exception;
 
-use acpi::InterruptModel;
-use alloc::{alloc::Global, vec};
+use acpi::{
+    platform::interrupt::{Apic, Polarity, TriggerMode as AcpiTriggerMode},
+    InterruptModel,
+};
+use alloc::{alloc::Global, boxed::Box, collections::BTreeMap, vec, vec::Vec};
 use aml::{value::Args as AmlArgs, AmlContext, AmlName, AmlValue};
 use core::time::Duration;
+use crate::PlatformImpl;
 use hal::memory::PAddr;
 use hal_x86_64::{
     hw::{
@@ -11,15 +15,24 @@ use hal_x86_64::{
         gdt::{PrivilegeLevel, KERNEL_CODE_SELECTOR},
         i8259_pic::Pic,
         idt::{wrap_handler, wrap_handler_with_error_code, Idt, InterruptStackFrame},
+        io_apic::{DeliveryMode, IoApic, PinPolarity, TriggerMode},
         local_apic::LocalApic,
+        tss::Tss,
     },
     kernel_map,
 };
+use kernel::{
+    interrupts::{InterruptVectorAllocator, INTERRUPT_VECTORS},
+    pci::{MsiController, MsiMessage},
+    profile::InterruptedContext,
+};
 use mulch::InitGuard;
+use poplar::trace::TraceEvent;
 use spinning_top::Spinlock;
-use tracing::warn;
+use tracing::{info, warn};
 
-/// This should only be accessed directly by the bootstrap processor.
+/// This should only be modified by the bootstrap processor. Application processors share the same IDT, and so
+/// just load it when they're brought up.
 ///
 /// The IDT is laid out like so:
 /// |------------------|-----------------------------|
@@ -27,14 +40,29 @@ use tracing::warn;
 /// |------------------|-----------------------------|
 /// |       00-1f      | Intel Reserved (Exceptions) |
 /// |       20-2f      | i8259 PIC Interrupts        |
-/// |       30-??      | IOAPIC Interrupts           |
-/// |        ..        |                             |
+/// |       30-ef      | Dynamically-allocated       |
+/// |       f0-fd      | Unused                      |
 /// |        fe        | Local APIC timer            |
 /// |        ff        | APIC spurious interrupt     |
 /// |------------------|-----------------------------|
+///
+/// The dynamically-allocated vectors are handed out by `kernel::interrupts::INTERRUPT_VECTORS`.
+/// Vectors starting at `FREE_VECTORS_START` are reserved for the IOAPICs' redirection entries (so
+/// Global System Interrupt `n` is delivered on vector `FREE_VECTORS_START + n`), and the rest can
+/// be allocated to MSIs.
 static IDT: Spinlock<Idt> = Spinlock::new(Idt::empty());
 
 static LOCAL_APIC: InitGuard<LocalApic> = InitGuard::uninit();
+static MSI_CONTROLLER: InitGuard<LocalApicMsiController> = InitGuard::uninit();
+// TODO: wrap in a guard to disable interrupts
+static IO_APICS: InitGuard<Spinlock<Vec<IoApic>>> = InitGuard::uninit();
+/// How each ISA IRQ is connected to the IOAPICs, indexed by ISA IRQ number. Populated from the MADT by
+/// `configure_isa_interrupts`.
+static ISA_INTERRUPTS: InitGuard<[IsaInterrupt; 16]> = InitGuard::uninit();
+
+/// Handlers for the dynamically-allocated vectors, registered with `handle_interrupt`.
+// TODO: wrap in a guard to disable interrupts
+static DYNAMIC_HANDLERS: Spinlock<BTreeMap<u8, fn(u8)>> = Spinlock::new(BTreeMap::new());
 
 /*
  * These constants define the IDT's layout. Refer to the documentation of the `IDT` static for
@@ -42,9 +70,20 @@ static LOCAL_APIC: InitGuard<LocalApic> = InitGuard::uninit();
  */
 const LEGACY_PIC_VECTOR: u8 = 0x20;
 const FREE_VECTORS_START: u8 = 0x30;
+const FREE_VECTORS_END: u8 = 0xf0;
 const APIC_TIMER_VECTOR: u8 = 0xfe;
 const APIC_SPURIOUS_VECTOR: u8 = 0xff;
 
+/// The period of each processor's local APIC timer. The bootstrap processor's timer drives the
+/// kernel's timer wheel.
+pub const LOCAL_TIMER_PERIOD: Duration = Duration::from_millis(10);
+
+/// Double faults are handled on their own stack, which is in this entry of each processor's Interrupt Stack
+/// Table. Overflowing a kernel stack causes a page fault that can't be handled on the same stack, so this lets us
+/// report the overflow instead of triple-faulting.
+const DOUBLE_FAULT_IST_INDEX: u8 = 1;
+const DOUBLE_FAULT_STACK_SIZE: usize = 0x4000;
+
 pub struct InterruptController {}
 
 impl InterruptController {
@@ -56,6 +95,8 @@ impl InterruptController {
         idt.breakpoint()
             .set_handler(wrap_handler!(exception::breakpoint_handler), KERNEL_CODE_SELECTOR)
             .set_privilege_level(PrivilegeLevel::Ring3);
+        #[cfg(feature = "gdb")]
+        idt.debug_exception().set_handler(wrap_handler!(exception::debug_exception_handler), KERNEL_CODE_SELECTOR);
         idt.invalid_opcode().set_handler(wrap_handler!(exception::invalid_opcode_handler), KERNEL_CODE_SELECTOR);
         idt.general_protection_fault().set_handler(
             wrap_handler_with_error_code!(exception::general_protection_fault_handler),
@@ -63,13 +104,24 @@ impl InterruptController {
         );
         idt.page_fault()
             .set_handler(wrap_handler_with_error_code!(exception::page_fault_handler), KERNEL_CODE_SELECTOR);
+        idt.x87_fault().set_handler(wrap_handler!(exception::x87_fault_handler), KERNEL_CODE_SELECTOR);
+        idt.simd_exception().set_handler(wrap_handler!(exception::simd_exception_handler), KERNEL_CODE_SELECTOR);
+        /*
+         * The bootstrap processor's TSS, which holds the stack for double faults, isn't installed until just
+         * after this. Until then, a double fault will triple fault whether or not it has its own stack.
+         */
         idt.double_fault()
-            .set_handler(wrap_handler_with_error_code!(exception::double_fault_handler), KERNEL_CODE_SELECTOR);
+            .set_handler(wrap_handler_with_error_code!(exception::double_fault_handler), KERNEL_CODE_SELECTOR)
+            .set_ist_index(DOUBLE_FAULT_IST_INDEX);
 
         idt.load();
     }
 
-    pub fn init(interrupt_model: &InterruptModel<Global>, aml_context: &mut AmlContext) -> InterruptController {
+    pub fn init(
+        interrupt_model: &InterruptModel<Global>,
+        cpu_info: &CpuInfo,
+        aml_context: &mut AmlContext,
+    ) -> InterruptController {
         match interrupt_model {
             InterruptModel::Apic(info) => {
                 if info.also_has_legacy_pics {
@@ -77,16 +129,51 @@ impl InterruptController {
                 }
 
                 /*
-                 * Initialise `LOCAL_APIC` to point at the right address.
+                 * Use the local APIC in x2APIC mode if it's supported, which accesses its registers through
+                 * MSRs. Otherwise, initialise `LOCAL_APIC` to point at its configuration area.
                  * TODO: we might need to map it separately or something so we can set custom flags on the
                  * paging entry (do we need to set NO_CACHE on it?)
                  */
                 // TODO: change the region to be NO_CACHE
-                LOCAL_APIC.initialize(unsafe {
-                    LocalApic::new(kernel_map::physical_to_virtual(
-                        PAddr::new(info.local_apic_address as usize).unwrap(),
-                    ))
-                });
+                if cpu_info.supported_features.x2apic {
+                    info!("Using local APIC in x2APIC mode");
+                    LOCAL_APIC.initialize(unsafe { LocalApic::new_x2apic() });
+                } else {
+                    info!("Using local APIC in xAPIC mode");
+                    LOCAL_APIC.initialize(unsafe {
+                        LocalApic::new(kernel_map::physical_to_virtual(
+                            PAddr::new(info.local_apic_address as usize).unwrap(),
+                        ))
+                    });
+                }
+
+                /*
+                 * Set up the allocator for the dynamic vectors, reserving the vectors that the
+                 * IOAPICs' redirection entries map to.
+                 */
+                let mut vectors =
+                    InterruptVectorAllocator::new(FREE_VECTORS_START as u32..FREE_VECTORS_END as u32);
+                let mut io_apics = Vec::new();
+                for io_apic in info.io_apics.iter() {
+                    let mut io_apic = unsafe {
+                        IoApic::new(
+                            kernel_map::physical_to_virtual(PAddr::new(io_apic.address as usize).unwrap()),
+                            io_apic.global_system_interrupt_base,
+                        )
+                    };
+                    let first_vector = FREE_VECTORS_START as u32 + io_apic.global_interrupt_base;
+                    vectors.reserve_range(first_vector..(first_vector + io_apic.num_redirection_entries()));
+
+                    /*
+                     * Mask every entry until something asks for its interrupt to be routed with `route_gsi`.
+                     */
+                    for entry in 0..io_apic.num_redirection_entries() {
+                        io_apic.set_irq_mask(entry, true);
+                    }
+                    io_apics.push(io_apic);
+                }
+                INTERRUPT_VECTORS.initialize(Spinlock::new(vectors));
+                IO_APICS.initialize(Spinlock::new(io_apics));
 
                 /*
                  * Tell ACPI that we intend to use the APICs instead of the legacy PIC.
@@ -100,17 +187,26 @@ impl InterruptController {
 
                 /*
                  * Install handlers for the spurious interrupt and local APIC timer, and then
-                 * enable the local APIC.
-                 * Install a spurious interrupt handler and enable the local APIC.
+                 * enable the local APIC. In x2APIC mode, this is also what moves the local APIC into
+                 * x2APIC mode, so its other registers can't be accessed until it's enabled.
                  */
                 unsafe {
                     let mut idt = IDT.lock();
                     idt[APIC_TIMER_VECTOR]
                         .set_handler(wrap_handler!(local_apic_timer_handler), KERNEL_CODE_SELECTOR);
                     idt[APIC_SPURIOUS_VECTOR].set_handler(wrap_handler!(spurious_handler), KERNEL_CODE_SELECTOR);
+                    install_dynamic_handlers!(idt; 0x3 0x4 0x5 0x6 0x7 0x8 0x9 0xa 0xb 0xc 0xd 0xe);
                     LOCAL_APIC.get().enable(APIC_SPURIOUS_VECTOR);
                 }
 
+                /*
+                 * We currently direct all MSIs, and all interrupts from the IOAPICs, to the bootstrap
+                 * processor.
+                 */
+                MSI_CONTROLLER.initialize(LocalApicMsiController { destination_apic_id: LOCAL_APIC.get().id() });
+                configure_isa_interrupts(info);
+                configure_nmi_sources(info);
+
                 InterruptController {}
             }
 
@@ -118,6 +214,17 @@ impl InterruptController {
         }
     }
 
+    /// Initialise the interrupt controller on an application processor. The bootstrap processor must have already
+    /// called `init`, so the IDT is populated and we know where the local APIC lives. Each processor
+    /// has its own local APIC, but they're all mapped at the same address.
+    pub fn init_application_processor() -> InterruptController {
+        IDT.lock().load();
+        unsafe {
+            LOCAL_APIC.get().enable(APIC_SPURIOUS_VECTOR);
+        }
+        InterruptController {}
+    }
+
     /// Enable the per-CPU timer on the local APIC, so that it ticks every `period` ms. Cannot be
     /// called before interrupt handlers are installed, because this borrows `self`.
     pub fn enable_local_timer(&mut self, cpu_info: &CpuInfo, period: Duration) {
@@ -134,10 +241,302 @@ impl InterruptController {
     }
 }
 
-extern "C" fn local_apic_timer_handler(_: &InterruptStackFrame) {
+/// Create a TSS for a processor, with a stack for it to handle double faults on.
+pub fn create_tss() -> Box<Tss> {
+    let stack = kernel::VMM
+        .get()
+        .alloc_kernel_stack::<PlatformImpl>(
+            DOUBLE_FAULT_STACK_SIZE,
+            kernel::PMM.get(),
+            &mut crate::KERNEL_PAGE_TABLES.get().write(),
+        )
+        .expect("Failed to allocate stack for double faults");
+
+    let mut tss = Box::new(Tss::new());
+    tss.set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, stack.top.align_down(16));
+    tss
+}
+
+/// Get the `MsiController` that describes how PCI devices should signal MSIs. Cannot be called before
+/// `InterruptController::init`.
+pub fn msi_controller() -> &'static LocalApicMsiController {
+    MSI_CONTROLLER.get()
+}
+
+/// Access the running processor's local APIC. Cannot be called before `InterruptController::init`.
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.get()
+}
+
+pub struct LocalApicMsiController {
+    destination_apic_id: u32,
+}
+
+impl MsiController for LocalApicMsiController {
+    fn message_for(&self, vector: u32) -> MsiMessage {
+        /*
+         * The message address is in the range `0xfee0_0000..0xfef0_0000`, with the ID of the
+         * destination local APIC in bits `12..20`. We use physical destination mode and don't
+         * request redirection, so the rest of the address is zero.
+         *
+         * The message data contains the vector in its low byte. The other fields select fixed
+         * delivery mode and edge-triggering when left as zero.
+         */
+        assert!(
+            self.destination_apic_id < 0x100,
+            "Can't target local APIC with MSIs: {}",
+            self.destination_apic_id
+        );
+        MsiMessage { address: 0xfee0_0000 | (u64::from(self.destination_apic_id) << 12), data: vector }
+    }
+}
+
+extern "C" fn local_apic_timer_handler(stack_frame: &mut InterruptStackFrame) {
+    // Check whether GDB wants the kernel to stop. This only needs to be done by one processor.
+    #[cfg(feature = "gdb")]
+    if crate::per_cpu::cpu_id() == crate::topo::BOOT_PROCESSOR_ID as usize {
+        crate::gdb::poll(stack_frame);
+    }
+
+    if let Some(scheduler) = crate::SCHEDULER.try_get() {
+        let context = InterruptedContext {
+            instruction_pointer: usize::from(stack_frame.instruction_pointer),
+            frame_pointer: stack_frame.rbp as usize,
+            in_user: stack_frame.code_segment & 0b11 == 3,
+        };
+        kernel::profile::sample(scheduler, &context, |address| {
+            address >= usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START)
+        });
+    }
+
+    /*
+     * Each processor's local APIC timer fires, but the timer wheel should only be advanced once
+     * per period, so we only drive it from the bootstrap processor. The timer is started before
+     * the scheduler is created, so we may need to skip the first few ticks.
+     */
+    if crate::per_cpu::cpu_id() == crate::topo::BOOT_PROCESSOR_ID as usize {
+        if let Some(scheduler) = crate::SCHEDULER.try_get() {
+            scheduler.tasklet_scheduler.advance_timer(LOCAL_TIMER_PERIOD);
+        }
+    }
+
     unsafe {
         LOCAL_APIC.get().send_eoi();
     }
+
+    /*
+     * Pre-empt the running task if it's used up its time slice. We can only do this if we've
+     * interrupted userspace, and the interrupt has to have been acknowledged first, as we might
+     * not return here for a while.
+     */
+    if stack_frame.code_segment & 0b11 == 3 {
+        crate::SCHEDULER.get().tick(LOCAL_TIMER_PERIOD);
+    }
 }
 
 extern "C" fn spurious_handler(_: &InterruptStackFrame) {}
+
+/// Register `handler` to be called when an interrupt is delivered on `vector`, which should have been allocated
+/// from `INTERRUPT_VECTORS`. The handler is called with the vector, and the interrupt is acknowledged after it
+/// returns.
+pub fn handle_interrupt(vector: u8, handler: fn(u8)) {
+    assert!((FREE_VECTORS_START..FREE_VECTORS_END).contains(&vector));
+    DYNAMIC_HANDLERS.lock().insert(vector, handler);
+}
+
+/// Route the Global System Interrupt `gsi` to the bootstrap processor, calling `handler` when it fires. Returns
+/// the vector the interrupt is delivered on, which is what `handler` is called with. The interrupt is unmasked.
+pub fn route_gsi(gsi: u32, polarity: PinPolarity, trigger_mode: TriggerMode, handler: fn(u8)) -> u8 {
+    let vector = vector_for_gsi(gsi);
+    handle_interrupt(vector, handler);
+
+    with_io_apic_for(gsi, |io_apic, entry| {
+        io_apic.write_entry(
+            entry,
+            vector,
+            DeliveryMode::Fixed,
+            polarity,
+            trigger_mode,
+            false,
+            io_apic_destination(),
+        );
+    });
+    vector
+}
+
+/// Get the destination field to use in IOAPIC redirection entries that target the bootstrap processor. We use
+/// physical destination mode, so this is the ID of its local APIC, which must fit in the field's eight bits.
+/// This is always the case in xAPIC mode, but not necessarily in x2APIC mode.
+fn io_apic_destination() -> u8 {
+    let id = LOCAL_APIC.get().id();
+    u8::try_from(id).unwrap_or_else(|_| panic!("Can't target local APIC with IOAPIC interrupts: {}", id))
+}
+
+/// Route the ISA IRQ `isa_irq` to the bootstrap processor, calling `handler` when it fires. The GSI it's
+/// connected to, and how it's triggered, come from the MADT. Returns the GSI, which is also what `mask_gsi` and
+/// `unmask_gsi` take.
+pub fn route_isa_irq(isa_irq: u8, handler: fn(u8)) -> u32 {
+    let IsaInterrupt { gsi, polarity, trigger_mode } = ISA_INTERRUPTS.get()[isa_irq as usize];
+    route_gsi(gsi, polarity, trigger_mode, handler);
+    gsi
+}
+
+#[derive(Clone, Copy)]
+struct IsaInterrupt {
+    gsi: u32,
+    polarity: PinPolarity,
+    trigger_mode: TriggerMode,
+}
+
+/// Work out which GSI each ISA IRQ is connected to, and program their redirection entries. By default, ISA IRQs
+/// are edge-triggered, active-high, and identity-mapped to GSIs, but the MADT can override any of these with an
+/// Interrupt Source Override. The entries are left masked until the IRQ is routed with `route_isa_irq`, but this
+/// makes sure a GSI that's wired to an ISA IRQ is never left configured with the wrong polarity or trigger mode.
+fn configure_isa_interrupts(info: &Apic<Global>) {
+    let mut isa_interrupts =
+        [IsaInterrupt { gsi: 0, polarity: PinPolarity::High, trigger_mode: TriggerMode::Edge }; 16];
+    for isa_irq in 0..16 {
+        let (gsi, polarity, trigger_mode) =
+            match info.interrupt_source_overrides.iter().find(|entry| entry.isa_source == isa_irq) {
+                Some(entry) => {
+                    info!(
+                        "ISA IRQ {} is overridden to GSI {} ({:?}, {:?})",
+                        isa_irq, entry.global_system_interrupt, entry.polarity, entry.trigger_mode
+                    );
+                    (
+                        entry.global_system_interrupt,
+                        to_pin_polarity(entry.polarity, PinPolarity::High),
+                        to_trigger_mode(entry.trigger_mode, TriggerMode::Edge),
+                    )
+                }
+                None => (u32::from(isa_irq), PinPolarity::High, TriggerMode::Edge),
+            };
+        isa_interrupts[isa_irq as usize] = IsaInterrupt { gsi, polarity, trigger_mode };
+
+        if !IO_APICS.get().lock().iter().any(|io_apic| handles_gsi(io_apic, gsi)) {
+            warn!("ISA IRQ {} is routed to GSI {}, which isn't handled by an IOAPIC", isa_irq, gsi);
+            continue;
+        }
+        with_io_apic_for(gsi, |io_apic, entry| {
+            io_apic.write_entry(
+                entry,
+                vector_for_gsi(gsi),
+                DeliveryMode::Fixed,
+                polarity,
+                trigger_mode,
+                true,
+                io_apic_destination(),
+            );
+        });
+    }
+    ISA_INTERRUPTS.initialize(isa_interrupts);
+}
+
+/// Program the redirection entries of any GSIs the MADT says should be delivered as NMIs. These are unmasked
+/// straight away, as they don't need a handler to be registered.
+fn configure_nmi_sources(info: &Apic<Global>) {
+    for nmi_source in info.nmi_sources.iter() {
+        info!("GSI {} is an NMI source", nmi_source.global_system_interrupt);
+        with_io_apic_for(nmi_source.global_system_interrupt, |io_apic, entry| {
+            io_apic.write_entry(
+                entry,
+                0,
+                DeliveryMode::NMI,
+                to_pin_polarity(nmi_source.polarity, PinPolarity::High),
+                /*
+                 * NMIs are always delivered as if edge-triggered, so the trigger mode must be edge.
+                 */
+                TriggerMode::Edge,
+                false,
+                io_apic_destination(),
+            );
+        });
+    }
+}
+
+fn to_pin_polarity(polarity: Polarity, bus_default: PinPolarity) -> PinPolarity {
+    match polarity {
+        Polarity::SameAsBus => bus_default,
+        Polarity::ActiveHigh => PinPolarity::High,
+        Polarity::ActiveLow => PinPolarity::Low,
+    }
+}
+
+fn to_trigger_mode(trigger_mode: AcpiTriggerMode, bus_default: TriggerMode) -> TriggerMode {
+    match trigger_mode {
+        AcpiTriggerMode::SameAsBus => bus_default,
+        AcpiTriggerMode::Edge => TriggerMode::Edge,
+        AcpiTriggerMode::Level => TriggerMode::Level,
+    }
+}
+
+/// Get the vector that the Global System Interrupt `gsi` is delivered on, once it's been routed with `route_gsi`.
+pub fn vector_for_gsi(gsi: u32) -> u8 {
+    u8::try_from(FREE_VECTORS_START as u32 + gsi)
+        .ok()
+        .filter(|&vector| vector < FREE_VECTORS_END)
+        .unwrap_or_else(|| panic!("GSI {} does not have a vector reserved for it", gsi))
+}
+
+/// Get the Global System Interrupt that is delivered on `vector`, if it's one of the vectors reserved for the
+/// IOAPICs.
+pub fn gsi_for_vector(vector: u8) -> Option<u32> {
+    let gsi = vector.checked_sub(FREE_VECTORS_START)? as u32;
+    let io_apics = IO_APICS.get().lock();
+    io_apics.iter().any(|io_apic| handles_gsi(io_apic, gsi)).then_some(gsi)
+}
+
+pub fn mask_gsi(gsi: u32) {
+    with_io_apic_for(gsi, |io_apic, entry| io_apic.set_irq_mask(entry, true));
+}
+
+pub fn unmask_gsi(gsi: u32) {
+    with_io_apic_for(gsi, |io_apic, entry| io_apic.set_irq_mask(entry, false));
+}
+
+/// Call `f` with the IOAPIC that handles `gsi`, and the index of its redirection entry for it.
+fn with_io_apic_for(gsi: u32, f: impl FnOnce(&mut IoApic, u32)) {
+    let mut io_apics = IO_APICS.get().lock();
+    let io_apic = io_apics
+        .iter_mut()
+        .find(|io_apic| handles_gsi(io_apic, gsi))
+        .unwrap_or_else(|| panic!("No IOAPIC handles GSI {}", gsi));
+    let entry = gsi - io_apic.global_interrupt_base;
+    f(io_apic, entry);
+}
+
+fn handles_gsi(io_apic: &IoApic, gsi: u32) -> bool {
+    (io_apic.global_interrupt_base..(io_apic.global_interrupt_base + io_apic.num_redirection_entries()))
+        .contains(&gsi)
+}
+
+extern "C" fn dynamic_handler<const VECTOR: u8>(_: &InterruptStackFrame) {
+    kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptEnter, [VECTOR as u64, 0, 0]);
+    match DYNAMIC_HANDLERS.lock().get(&VECTOR) {
+        Some(handler) => handler(VECTOR),
+        None => warn!("Unhandled interrupt on vector {:#x}", VECTOR),
+    }
+    kernel::trace::record::<PlatformImpl>(TraceEvent::InterruptExit, [VECTOR as u64, 0, 0]);
+
+    unsafe {
+        LOCAL_APIC.get().send_eoi();
+    }
+}
+
+/*
+ * Our interrupt handlers don't know which vector they were called for, so we install a separate
+ * instance of `dynamic_handler` for each dynamic vector. These macros install one for each vector
+ * of the form `0xHL`, for each `H` passed to `install_dynamic_handlers`.
+ */
+macro install_dynamic_handlers($idt: expr; $($high: literal)*) {
+    $(
+        install_dynamic_handlers_row!($idt, $high; 0x0 0x1 0x2 0x3 0x4 0x5 0x6 0x7 0x8 0x9 0xa 0xb 0xc 0xd 0xe 0xf);
+    )*
+}
+
+macro install_dynamic_handlers_row($idt: expr, $high: literal; $($low: literal)*) {
+    $(
+        $idt[$high * 0x10 + $low]
+            .set_handler(wrap_handler!(dynamic_handler::<{ $high * 0x10 + $low }>), KERNEL_CODE_SELECTOR);
+    )*
+}
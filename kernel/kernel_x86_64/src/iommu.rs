@@ -0,0 +1,455 @@
+//! Support for Intel's VT-d IOMMUs, which are described by the ACPI DMAR table. Each DMA remapping hardware unit
+//! translates DMA from the PCI devices in its scope. It finds the translations to use for a device through a root
+//! table, indexed by bus number, which points to a context table for each bus, indexed by device and function.
+//!
+//! We use legacy-mode translation. Devices that aren't attached to a domain are set to pass-through, while devices
+//! attached to a domain use its second-level page tables. Every unit uses the same tables for a domain.
+
+use acpi::{
+    sdt::{SdtHeader, Signature},
+    AcpiTable,
+    AcpiTables,
+};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use bit_field::BitField;
+use core::{
+    mem,
+    ptr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+use hal::memory::{PAddr, VAddr};
+use hal_x86_64::kernel_map;
+use kernel::iommu::{Iommu, IommuDomain};
+use pci_types::PciAddress;
+use spinning_top::Spinlock;
+use tracing::{info, warn};
+
+const REG_CAPABILITY: usize = 0x08;
+const REG_EXTENDED_CAPABILITY: usize = 0x10;
+const REG_GLOBAL_COMMAND: usize = 0x18;
+const REG_GLOBAL_STATUS: usize = 0x1c;
+const REG_ROOT_TABLE_ADDRESS: usize = 0x20;
+const REG_CONTEXT_COMMAND: usize = 0x28;
+
+const GLOBAL_TRANSLATION_ENABLE: usize = 31;
+const GLOBAL_SET_ROOT_TABLE_POINTER: usize = 30;
+/// The bits of the Global Status register that reflect persistent state, rather than one-shot commands. These
+/// must be preserved when writing to the Global Command register.
+const GLOBAL_PERSISTENT_MASK: u32 = 0x96ff_ffff;
+
+/// The domain ID used by the context entries of devices that have been set to pass-through. Domain `0` is
+/// reserved on some hardware, so domains we create start at `2`.
+const PASS_THROUGH_DOMAIN_ID: u16 = 1;
+
+const PAGE_SIZE: usize = 0x1000;
+const ENTRIES_PER_TABLE: usize = 512;
+
+#[repr(C, packed)]
+struct Dmar {
+    header: SdtHeader,
+    host_address_width: u8,
+    flags: u8,
+    _reserved: [u8; 10],
+}
+
+unsafe impl AcpiTable for Dmar {
+    const SIGNATURE: Signature = Signature::DMAR;
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+pub struct VtdIommu(Arc<Inner>);
+
+struct Inner {
+    units: Vec<RemappingUnit>,
+    /// The number of levels of second-level page tables. This is supported by all of the units.
+    levels: usize,
+    /// Whether all of the units snoop the CPU's caches when they access the page tables. If they don't, we
+    /// need to flush any changes to the tables out of the caches.
+    coherent: bool,
+    next_domain_id: AtomicU16,
+    max_domains: usize,
+}
+
+struct RemappingUnit {
+    registers: VAddr,
+    segment: u16,
+    /// If this is set, this unit translates DMA for all devices on the segment that aren't in the scope of
+    /// another unit.
+    include_all: bool,
+    /// The devices explicitly in this unit's scope, as `(bus, device, function)`.
+    devices: Vec<(u8, u8, u8)>,
+    root_table: PAddr,
+    /// The context table for each bus that has had a device added.
+    context_tables: Spinlock<BTreeMap<u8, PAddr>>,
+    iotlb_registers: usize,
+    caching_mode: bool,
+}
+
+impl VtdIommu {
+    /// Find the VT-d remapping units described by the DMAR table, if the platform has one. Returns `None` if
+    /// there isn't one, or if the units don't support the features we need.
+    pub fn new(acpi_tables: &AcpiTables<crate::acpi_handler::PoplarAcpiHandler>) -> Option<VtdIommu> {
+        let dmar = acpi_tables.find_table::<Dmar>().ok()?;
+        let dmar_address = dmar.virtual_start().as_ptr() as *const u8;
+        let dmar_length = { dmar.header.length } as usize;
+
+        let mut units = Vec::new();
+        let mut offset = mem::size_of::<Dmar>();
+        while offset < dmar_length {
+            let structure = unsafe { dmar_address.add(offset) };
+            let typ = unsafe { ptr::read_unaligned(structure as *const u16) };
+            let length = unsafe { ptr::read_unaligned(structure.add(2) as *const u16) } as usize;
+
+            // Type `0` is a DMA Remapping Hardware Unit Definition. We don't care about any of the others.
+            if typ == 0 {
+                units.push(unsafe { RemappingUnit::from_drhd(structure, length) });
+            }
+            offset += length;
+        }
+
+        if units.is_empty() {
+            return None;
+        }
+
+        let mut levels = 4;
+        let mut coherent = true;
+        let mut max_domains = usize::MAX;
+        for unit in &units {
+            let capability = unit.read_64(REG_CAPABILITY);
+            let extended_capability = unit.read_64(REG_EXTENDED_CAPABILITY);
+
+            if !extended_capability.get_bit(6) {
+                warn!("VT-d unit does not support pass-through. Not using the IOMMU.");
+                return None;
+            }
+
+            let supported_widths = capability.get_bits(8..13);
+            if !supported_widths.get_bit(2) {
+                levels = 3;
+            }
+            if !supported_widths.get_bit(1) && !supported_widths.get_bit(2) {
+                warn!("VT-d unit does not support 3- or 4-level page tables. Not using the IOMMU.");
+                return None;
+            }
+
+            coherent &= extended_capability.get_bit(0);
+            max_domains = usize::min(max_domains, 1 << (4 + 2 * capability.get_bits(0..3)));
+        }
+
+        info!("Found {} VT-d remapping units (using {}-level page tables)", units.len(), levels);
+        Some(VtdIommu(Arc::new(Inner {
+            units,
+            levels,
+            coherent,
+            next_domain_id: AtomicU16::new(PASS_THROUGH_DOMAIN_ID + 1),
+            max_domains,
+        })))
+    }
+}
+
+impl Iommu for VtdIommu {
+    fn add_device(&self, device: PciAddress) {
+        if let Some(unit) = self.0.unit_for(device) {
+            self.0.set_context(unit, device, None);
+        }
+    }
+
+    fn enable(&self) {
+        for unit in &self.0.units {
+            unit.enable();
+        }
+    }
+
+    fn create_domain(&self) -> Box<dyn IommuDomain> {
+        let id = self.0.next_domain_id.fetch_add(1, Ordering::Relaxed);
+        assert!((id as usize) < self.0.max_domains, "Run out of VT-d domain IDs");
+
+        Box::new(VtdDomain {
+            iommu: self.0.clone(),
+            id,
+            root: Spinlock::new(allocate_table()),
+            attached: Spinlock::new(Vec::new()),
+        })
+    }
+}
+
+impl Inner {
+    fn unit_for(&self, device: PciAddress) -> Option<&RemappingUnit> {
+        let address = (device.bus(), device.device(), device.function());
+        self.units
+            .iter()
+            .find(|unit| unit.segment == device.segment() && unit.devices.contains(&address))
+            .or_else(|| self.units.iter().find(|unit| unit.segment == device.segment() && unit.include_all))
+    }
+
+    /// Set the context entry for a device on `unit`. If `translation` is `None`, the device is set to
+    /// pass-through. Otherwise, it should contain the root of the domain's page tables and its domain ID.
+    fn set_context(&self, unit: &RemappingUnit, device: PciAddress, translation: Option<(PAddr, u16)>) {
+        let context_table = *unit.context_tables.lock().entry(device.bus()).or_insert_with(|| {
+            let table = allocate_table();
+            let root_entry = table_ptr(unit.root_table, device.bus() as usize * 2);
+            unsafe {
+                ptr::write_volatile(root_entry, usize::from(table) as u64 | 0b1);
+            }
+            flush(root_entry, self.coherent);
+            table
+        });
+
+        let (mut low, mut high) = (0u64, 0u64);
+        low.set_bit(0, true);
+        match translation {
+            Some((root, domain_id)) => {
+                low.set_bits(2..4, 0b00); // Translate through the second-level page tables
+                low.set_bits(12..64, usize::from(root) as u64 >> 12);
+                high.set_bits(8..24, domain_id as u64);
+            }
+            None => {
+                low.set_bits(2..4, 0b10); // Pass-through
+                high.set_bits(8..24, PASS_THROUGH_DOMAIN_ID as u64);
+            }
+        }
+        // The address width is `1` for 3-level tables, and `2` for 4-level tables
+        high.set_bits(0..3, self.levels as u64 - 2);
+
+        /*
+         * Clear the present bit before changing the entry, so the hardware never sees a half-updated entry. The
+         * new entry is only made present once the rest of it has been written.
+         */
+        let index = (device.device() as usize) << 3 | device.function() as usize;
+        let entry = table_ptr(context_table, index * 2);
+        unsafe {
+            ptr::write_volatile(entry, 0);
+            flush(entry, self.coherent);
+            unit.invalidate_context_cache();
+
+            ptr::write_volatile(entry.add(1), high);
+            ptr::write_volatile(entry, low);
+            flush(entry, self.coherent);
+        }
+
+        unit.invalidate_context_cache();
+        unit.invalidate_iotlb(None);
+    }
+}
+
+impl RemappingUnit {
+    /// Create a `RemappingUnit` from a DMA Remapping Hardware Unit Definition structure of the DMAR.
+    unsafe fn from_drhd(structure: *const u8, length: usize) -> RemappingUnit {
+        let flags = unsafe { *structure.add(4) };
+        let segment = unsafe { ptr::read_unaligned(structure.add(6) as *const u16) };
+        let register_base = unsafe { ptr::read_unaligned(structure.add(8) as *const u64) };
+
+        /*
+         * The rest of the structure is made up of Device Scope entries. We only handle endpoint devices (type `1`)
+         * directly on the start bus, which covers all the devices on the root bus.
+         * TODO: handle devices behind bridges, and bridges' sub-hierarchies (type `2`)
+         */
+        let mut devices = Vec::new();
+        let mut offset = 16;
+        while offset < length {
+            let scope = unsafe { structure.add(offset) };
+            let (typ, scope_length) = unsafe { (*scope, *scope.add(1) as usize) };
+            let start_bus = unsafe { *scope.add(5) };
+            let path_length = (scope_length - 6) / 2;
+
+            if typ == 1 && path_length == 1 {
+                devices.push(unsafe { (start_bus, *scope.add(6), *scope.add(7)) });
+            } else if typ == 1 || typ == 2 {
+                warn!("VT-d device scope entry of type {} is not supported", typ);
+            }
+            offset += scope_length;
+        }
+
+        let registers = kernel_map::physical_to_virtual(PAddr::new(register_base as usize).unwrap());
+        let extended_capability =
+            unsafe { ptr::read_volatile((registers + REG_EXTENDED_CAPABILITY).ptr() as *const u64) };
+        let capability = unsafe { ptr::read_volatile((registers + REG_CAPABILITY).ptr() as *const u64) };
+
+        RemappingUnit {
+            registers,
+            segment,
+            include_all: flags.get_bit(0),
+            devices,
+            root_table: allocate_table(),
+            context_tables: Spinlock::new(BTreeMap::new()),
+            iotlb_registers: extended_capability.get_bits(8..18) as usize * 16,
+            caching_mode: capability.get_bit(7),
+        }
+    }
+
+    fn read_32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.registers + offset).ptr()) }
+    }
+
+    fn write_32(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.registers + offset).mut_ptr(), value) }
+    }
+
+    fn read_64(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.registers + offset).ptr()) }
+    }
+
+    fn write_64(&self, offset: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.registers + offset).mut_ptr(), value) }
+    }
+
+    /// Issue a command through the Global Command register, and wait for the hardware to acknowledge it.
+    fn global_command(&self, bit: usize, value: bool) {
+        let mut command = self.read_32(REG_GLOBAL_STATUS) & GLOBAL_PERSISTENT_MASK;
+        command.set_bit(bit, value);
+        self.write_32(REG_GLOBAL_COMMAND, command);
+        while self.read_32(REG_GLOBAL_STATUS).get_bit(bit) != value {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn enable(&self) {
+        // Use legacy-mode translation (`TTM = 00`)
+        self.write_64(REG_ROOT_TABLE_ADDRESS, usize::from(self.root_table) as u64);
+        self.global_command(GLOBAL_SET_ROOT_TABLE_POINTER, true);
+
+        self.invalidate_context_cache();
+        self.invalidate_iotlb(None);
+
+        self.global_command(GLOBAL_TRANSLATION_ENABLE, true);
+    }
+
+    /// Invalidate all cached context entries.
+    fn invalidate_context_cache(&self) {
+        let mut command = 0u64;
+        command.set_bit(63, true);
+        command.set_bits(61..63, 0b01); // Global invalidation
+        self.write_64(REG_CONTEXT_COMMAND, command);
+        while self.read_64(REG_CONTEXT_COMMAND).get_bit(63) {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Invalidate cached translations for the given domain, or for all domains if `domain` is `None`.
+    fn invalidate_iotlb(&self, domain: Option<u16>) {
+        let mut command = 0u64;
+        command.set_bit(63, true);
+        match domain {
+            Some(domain) => {
+                command.set_bits(60..62, 0b10);
+                command.set_bits(32..48, domain as u64);
+            }
+            None => {
+                command.set_bits(60..62, 0b01);
+            }
+        }
+        // Drain pending reads and writes before the invalidation completes
+        command.set_bit(49, true);
+        command.set_bit(48, true);
+
+        let offset = self.iotlb_registers + 8;
+        self.write_64(offset, command);
+        while self.read_64(offset).get_bit(63) {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+struct VtdDomain {
+    iommu: Arc<Inner>,
+    id: u16,
+    root: Spinlock<PAddr>,
+    /// The devices attached to this domain. We need to know which units to invalidate when the page tables
+    /// change.
+    attached: Spinlock<Vec<PciAddress>>,
+}
+
+impl IommuDomain for VtdDomain {
+    fn map(&self, address: PAddr, size: usize, writable: bool) {
+        let root = self.root.lock();
+
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let page = usize::from(address) + offset;
+            let mut table = *root;
+
+            for level in (1..self.iommu.levels).rev() {
+                let entry = table_ptr(table, (page >> (12 + 9 * level)) % ENTRIES_PER_TABLE);
+                let value = unsafe { ptr::read_volatile(entry) };
+
+                table = if value.get_bits(0..2) == 0 {
+                    let next = allocate_table();
+                    // Allow both reads and writes through non-leaf entries, and restrict access at the leaves
+                    unsafe {
+                        ptr::write_volatile(entry, usize::from(next) as u64 | 0b11);
+                    }
+                    flush(entry, self.iommu.coherent);
+                    next
+                } else {
+                    PAddr::new(value.get_bits(12..52) as usize * PAGE_SIZE).unwrap()
+                };
+            }
+
+            let entry = table_ptr(table, (page >> 12) % ENTRIES_PER_TABLE);
+            let mut value = page as u64;
+            value.set_bit(0, true);
+            value.set_bit(1, writable);
+            unsafe {
+                ptr::write_volatile(entry, value);
+            }
+            flush(entry, self.iommu.coherent);
+        }
+
+        /*
+         * Hardware that reports Caching Mode may cache not-present entries, so we need to invalidate its caches
+         * even though we've only added new translations.
+         */
+        for &device in self.attached.lock().iter() {
+            if let Some(unit) = self.iommu.unit_for(device) {
+                if unit.caching_mode {
+                    unit.invalidate_iotlb(Some(self.id));
+                }
+            }
+        }
+    }
+
+    fn attach(&self, device: PciAddress) -> bool {
+        match self.iommu.unit_for(device) {
+            Some(unit) => {
+                self.iommu.set_context(unit, device, Some((*self.root.lock(), self.id)));
+                self.attached.lock().push(device);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn detach(&self, device: PciAddress) {
+        if let Some(unit) = self.iommu.unit_for(device) {
+            self.iommu.set_context(unit, device, None);
+        }
+        self.attached.lock().retain(|&attached| attached != device);
+    }
+}
+
+/// Allocate a zeroed page to hold a root, context, or page table.
+fn allocate_table() -> PAddr {
+    let table = kernel::PMM.get().alloc(1);
+    unsafe {
+        ptr::write_bytes(kernel_map::physical_to_virtual(table).mut_ptr::<u8>(), 0, PAGE_SIZE);
+    }
+    table
+}
+
+/// Get a pointer to the `index`th `u64` of the table at `table`.
+fn table_ptr(table: PAddr, index: usize) -> *mut u64 {
+    unsafe { kernel_map::physical_to_virtual(table).mut_ptr::<u64>().add(index) }
+}
+
+/// Make sure a write to a table can be seen by the IOMMU. If its accesses to the tables don't snoop the CPU's
+/// caches, we need to write the cache line back to memory.
+fn flush(entry: *mut u64, coherent: bool) {
+    if !coherent {
+        unsafe {
+            core::arch::x86_64::_mm_clflush(entry as *const u8);
+        }
+    }
+    core::sync::atomic::fence(Ordering::SeqCst);
+}
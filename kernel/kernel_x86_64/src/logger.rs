@@ -6,10 +6,18 @@
 use core::{
     fmt,
     fmt::Write,
+    panic::Location,
     sync::atomic::{AtomicU64, Ordering},
 };
-use hal_x86_64::hw::serial::SerialPort;
-use mulch::InitGuard;
+use hal_x86_64::{
+    hw::{
+        registers::{read_control_reg, CpuFlags},
+        serial::SerialPort,
+    },
+    kernel_map::KERNEL_ADDRESS_SPACE_START,
+};
+use kernel::klog::LogWriter;
+use mulch::{backtrace::Backtrace, InitGuard};
 use spinning_top::Spinlock;
 use tracing::{span, Collect, Event, Level, Metadata};
 use tracing_core::span::Current as CurrentSpan;
@@ -90,9 +98,11 @@ impl Collect for Logger {
                 Level::ERROR => "\x1b[31m",
             };
             let mut serial = self.serial.lock();
-            write!(serial, "[{}{:5}\x1b[0m] {}: ", color, level, event.metadata().target()).unwrap();
-            event.record(&mut Visitor::new(serial.deref_mut()));
-            write!(serial, "\n").unwrap();
+            // Everything logged is also kept in the kernel log, so userspace can read it back
+            let mut writer = LogWriter::new(serial.deref_mut());
+            write!(writer, "[{}{:5}\x1b[0m] {}: ", color, level, event.metadata().target()).unwrap();
+            event.record(&mut Visitor::new(&mut writer));
+            write!(writer, "\n").unwrap();
         }
     }
 
@@ -167,22 +177,39 @@ where
 #[cfg(not(test))]
 #[panic_handler]
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
-    if let Some(location) = info.location() {
-        let _ = writeln!(
-            LOGGER.serial.lock(),
-            "PANIC: {} ({} - {}:{})",
-            info.message(),
-            location.file(),
-            location.line(),
-            location.column()
-        );
-    } else {
-        let _ = writeln!(LOGGER.serial.lock(), "PANIC: {} (no location info)", info.message());
-    }
-
-    /*
-     * If the `qemu_exit` feature is set, we use the debug port to exit.
-     */
+    let rsp: usize;
+    let rbp: usize;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp);
+        core::arch::asm!("mov {}, rbp", out(reg) rbp);
+    }
+    let registers = [
+        ("rsp", rsp),
+        ("rbp", rbp),
+        ("rflags", u64::from(CpuFlags::read()) as usize),
+        ("cr0", read_control_reg!(cr0) as usize),
+        ("cr2", read_control_reg!(cr2) as usize),
+        ("cr3", read_control_reg!(cr3) as usize),
+        ("cr4", read_control_reg!(cr4) as usize),
+    ];
+
+    let backtrace = Backtrace::capture(|address| address >= usize::from(KERNEL_ADDRESS_SPACE_START));
+    crash(&info.message(), info.location(), &registers, &backtrace)
+}
+
+/// Write a crash report (see `kernel::crash`) to the serial port, and stop. If the `qemu_exit` feature is set,
+/// this exits QEMU with a failure code instead, so whatever ran QEMU can tell that the kernel crashed.
+pub fn crash(
+    message: &dyn fmt::Display,
+    location: Option<&Location>,
+    registers: &[(&'static str, usize)],
+    backtrace: &Backtrace,
+) -> ! {
+    if kernel::crash::begin() {
+        let crash = kernel::crash::Crash { message, location, registers, backtrace };
+        let _ = kernel::crash::write_report(&mut *LOGGER.serial.lock(), &crash, crate::SCHEDULER.try_get());
+    }
+
     #[cfg(feature = "qemu_exit")]
     {
         use hal_x86_64::hw::qemu::{ExitCode, ExitPort};
@@ -10,11 +10,23 @@
 extern crate alloc;
 
 mod acpi_handler;
+mod clock;
+mod fpu;
+#[cfg(feature = "gdb")]
+mod gdb;
 mod interrupts;
+mod iommu;
 mod logger;
 mod pci;
 mod per_cpu;
+mod perf;
+mod platform_devices;
+mod power;
+mod random;
+mod rtc;
+mod smp;
 mod task;
+mod tlb;
 mod topo;
 
 use acpi::{AcpiTables, PciConfigRegions};
@@ -26,21 +38,24 @@ use hal::memory::{Frame, PAddr, VAddr};
 use hal_x86_64::{
     hw::{registers::read_control_reg, tss::Tss},
     kernel_map,
-    paging::PageTableImpl,
+    paging::{PageTableImpl, PagingMode},
 };
 use interrupts::InterruptController;
 use kernel::{
     memory::{vmm::Stack, Pmm, Vmm},
     pci::PciResolver,
     scheduler::Scheduler,
+    DmaDirection,
+    PerfEvent,
     Platform,
 };
 use mulch::InitGuard;
 use per_cpu::PerCpuImpl;
+use poplar::syscall::PERF_MAX_EVENTS;
 use seed::boot_info::BootInfo;
 use spinning_top::RwSpinlock;
 use topo::Topology;
-use tracing::info;
+use tracing::{info, warn};
 
 pub struct PlatformImpl {
     topology: Topology,
@@ -51,8 +66,18 @@ impl Platform for PlatformImpl {
     type PageTable = PageTableImpl;
     type TaskContext = task::TaskContext;
 
-    fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> Self::TaskContext {
-        task::new_task_context(kernel_stack, user_stack, task_entry_point)
+    fn cpu_id() -> usize {
+        per_cpu::cpu_id()
+    }
+
+    fn new_task_context(
+        kernel_stack: &Stack,
+        user_stack: &Stack,
+        task_entry_point: VAddr,
+        argument: usize,
+        thread_pointer: usize,
+    ) -> Self::TaskContext {
+        task::new_task_context(kernel_stack, user_stack, task_entry_point, argument, thread_pointer)
     }
 
     unsafe fn context_switch(from_context: *mut Self::TaskContext, to_context: *const Self::TaskContext) {
@@ -65,12 +90,94 @@ impl Platform for PlatformImpl {
         task::drop_into_userspace(context)
     }
 
+    unsafe fn read_from_phys_memory(address: PAddr, data: &mut [u8]) {
+        let virt: *const u8 = hal_x86_64::kernel_map::physical_to_virtual(address).ptr();
+        unsafe {
+            core::ptr::copy(virt, data.as_mut_ptr(), data.len());
+        }
+    }
+
     unsafe fn write_to_phys_memory(address: PAddr, data: &[u8]) {
         let virt: *mut u8 = hal_x86_64::kernel_map::physical_to_virtual(address).mut_ptr();
         unsafe {
             core::ptr::copy(data.as_ptr(), virt, data.len());
         }
     }
+
+    unsafe fn copy_phys_memory(from: PAddr, to: PAddr, size: usize) {
+        let from: *const u8 = hal_x86_64::kernel_map::physical_to_virtual(from).ptr();
+        let to: *mut u8 = hal_x86_64::kernel_map::physical_to_virtual(to).mut_ptr();
+        unsafe {
+            core::ptr::copy_nonoverlapping(from, to, size);
+        }
+    }
+
+    unsafe fn zero_phys_memory(address: PAddr, size: usize) {
+        let virt: *mut u8 = hal_x86_64::kernel_map::physical_to_virtual(address).mut_ptr();
+        unsafe {
+            core::ptr::write_bytes(virt, 0, size);
+        }
+    }
+
+    fn monotonic_time() -> Duration {
+        clock::now()
+    }
+
+    fn hardware_random() -> Option<u64> {
+        random::rdrand()
+    }
+
+    unsafe fn sync_dma(_address: PAddr, _size: usize, _direction: DmaDirection) {
+        // DMA is cache-coherent on x86_64, so we only need to make sure accesses to the memory are ordered
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn shootdown_tlb(cpus: u64) {
+        tlb::shootdown(cpus);
+    }
+
+    fn perf_supports(events: &[PerfEvent]) -> bool {
+        let mut counters = [0; PERF_MAX_EVENTS];
+        events.len() <= PERF_MAX_EVENTS && perf::allocate(events, &mut counters[0..events.len()])
+    }
+
+    unsafe fn perf_start(events: &[PerfEvent], counters: &mut [usize]) {
+        // `perf_supports` has already checked that these can be allocated
+        perf::allocate(events, counters);
+        unsafe {
+            perf::start(events, counters);
+        }
+    }
+
+    fn perf_read(counters: &[usize], counts: &mut [u64]) {
+        perf::read(counters, counts)
+    }
+
+    unsafe fn perf_stop(counters: &[usize]) {
+        unsafe { perf::stop(counters) }
+    }
+
+    fn idle() {
+        power::idle();
+    }
+
+    fn set_cpu_performance(performance: u8) {
+        power::set_performance(performance);
+    }
+
+    fn power_off() {
+        power::power_off();
+    }
+
+    fn reset() {
+        power::reset();
+    }
+
+    #[cfg(feature = "test_runner")]
+    fn exit_qemu(passed: bool) -> ! {
+        use hal_x86_64::hw::qemu::{ExitCode, ExitPort};
+        unsafe { ExitPort::new() }.exit(if passed { ExitCode::Success } else { ExitCode::TestsFailed })
+    }
 }
 
 pub static SCHEDULER: InitGuard<Scheduler<PlatformImpl>> = InitGuard::uninit();
@@ -84,6 +191,7 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     if boot_info.magic != seed::boot_info::BOOT_INFO_MAGIC {
         panic!("Boot info magic is not correct!");
     }
+    kernel::KERNEL_SLIDE.initialize(boot_info.kernel_slide);
 
     /*
      * Get the kernel page tables set up by the loader. We have to assume that the loader has set up a correct set
@@ -92,18 +200,21 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     let kernel_page_tables = unsafe {
         PageTableImpl::from_frame(
             Frame::starts_with(PAddr::new(read_control_reg!(cr3) as usize).unwrap()),
+            PagingMode::current(),
             kernel_map::PHYSICAL_MAPPING_BASE,
         )
     };
     KERNEL_PAGE_TABLES.initialize(RwSpinlock::new(kernel_page_tables));
+    kernel::memory::verify_kernel_image::<PlatformImpl>(&KERNEL_PAGE_TABLES.get().read());
 
     /*
      * Initialise the heap allocator. After this, the kernel is free to use collections etc. that
      * can allocate on the heap through the global allocator.
      */
     unsafe {
-        kernel::ALLOCATOR.lock().init(boot_info.heap_address.mut_ptr(), boot_info.heap_size);
+        kernel::ALLOCATOR.init(boot_info.heap_address.mut_ptr(), boot_info.heap_size);
     }
+    kernel::initialize_object_caches::<PlatformImpl>();
 
     kernel::PMM.initialize(Pmm::new(boot_info));
     kernel::VMM.initialize(Vmm::new(
@@ -129,16 +240,22 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     InterruptController::install_exception_handlers();
 
     /*
-     * Install a TSS for this processor. This then allows us to set up the per-CPU data structures.
+     * If we're being debugged, wait for GDB to connect now, so that it can set breakpoints in the rest of
+     * initialization.
+     */
+    #[cfg(feature = "gdb")]
+    gdb::init();
+
+    /*
+     * Install a TSS for this processor, which holds the stack double faults are handled on. This then allows us to
+     * set up the per-CPU data structures.
      */
-    let tss = Box::new(Tss::new());
+    let tss = interrupts::create_tss();
     let tss_selector = hal_x86_64::hw::gdt::GDT.lock().add_tss(0, tss.as_ref() as *const Tss);
     unsafe {
         core::arch::asm!("ltr ax", in("ax") tss_selector.0);
     }
-    PerCpuImpl::install(tss);
-
-    // TODO: go back and set the #PF handler to use a separate kernel stack via the TSS
+    PerCpuImpl::install(topo::BOOT_PROCESSOR_ID as usize, tss);
 
     /*
      * Parse the static ACPI tables.
@@ -154,62 +271,126 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     let acpi_platform_info = acpi_tables.platform_info().unwrap();
     let topology = Topology::new(&acpi_platform_info);
 
-    let pci_access = pci::EcamAccess::new(PciConfigRegions::new(&acpi_tables).unwrap());
+    /*
+     * Start the monotonic clock. We might need to calibrate the TSC against the PIT, so do this before interrupts
+     * are enabled.
+     */
+    clock::init(&topology.cpu_info);
+    perf::init(&topology.cpu_info);
+    random::init(&topology.cpu_info);
+
+    let ecam_access = pci::EcamAccess::new(PciConfigRegions::new(&acpi_tables).unwrap());
 
     /*
-     * Parse the DSDT.
+     * Parse the DSDT, and then the SSDTs, which add to the namespace it defines.
      */
     let mut aml_context =
-        AmlContext::new(Box::new(AmlHandler::new(pci_access.clone())), aml::DebugVerbosity::None);
+        AmlContext::new(Box::new(AmlHandler::new(ecam_access.clone())), aml::DebugVerbosity::None);
     if let Ok(ref dsdt) = acpi_tables.dsdt() {
         let virtual_address = kernel_map::physical_to_virtual(PAddr::new(dsdt.address).unwrap());
-        info!(
-            "DSDT parse: {:?}",
-            aml_context
-                .parse_table(unsafe { core::slice::from_raw_parts(virtual_address.ptr(), dsdt.length as usize) })
-        );
-
-        // TODO: we should parse the SSDTs here. Only bother if we've managed to parse the DSDT.
+        let result = aml_context
+            .parse_table(unsafe { core::slice::from_raw_parts(virtual_address.ptr(), dsdt.length as usize) });
+        info!("DSDT parse: {:?}", result);
+
+        if result.is_ok() {
+            for ssdt in acpi_tables.ssdts() {
+                let virtual_address = kernel_map::physical_to_virtual(PAddr::new(ssdt.address).unwrap());
+                let result = aml_context.parse_table(unsafe {
+                    core::slice::from_raw_parts(virtual_address.ptr(), ssdt.length as usize)
+                });
+                info!("SSDT parse: {:?}", result);
+            }
+        }
 
         // info!("----- Printing AML namespace -----");
         // info!("{:#?}", aml_context.namespace);
         // info!("----- Finished AML namespace -----");
     }
 
-    kernel::initialize_pci(pci_access);
-
-    // TODO: if we need to route PCI interrupts, this might be useful at some point?
-    // let routing_table =
-    //     PciRoutingTable::from_prt_path(&AmlName::from_str("\\_SB.PCI0._PRT").unwrap(), aml_context)
-    //         .expect("Failed to parse _PRT");
-
     /*
-     * Initialize devices defined in AML.
+     * Initialize devices defined in AML, by running their `_STA` and `_INI` methods. Devices described by AML
+     * are only enumerated after this, as `_INI` can change the resources they report.
      * TODO: We should probably call `_REG` on all the op-regions we allow access to at this point before this.
      */
-    // aml_context.initialize_objects().expect("Failed to initialize AML objects");
+    if let Err(err) = aml_context.initialize_objects() {
+        warn!("Failed to initialize AML objects: {:?}", err);
+    }
+
+    /*
+     * Find the processors' idle and performance states. These are described by objects in the AML namespace.
+     */
+    power::init(&topology.cpu_info, &mut aml_context);
+    power::init_cpu();
 
     /*
      * Initialise the interrupt controller, which enables interrupts, and start the per-cpu timer.
      */
     let mut interrupt_controller =
-        InterruptController::init(&acpi_platform_info.interrupt_model, &mut aml_context);
+        InterruptController::init(&acpi_platform_info.interrupt_model, &topology.cpu_info, &mut aml_context);
     unsafe {
         core::arch::asm!("sti");
     }
-    interrupt_controller.enable_local_timer(&topology.cpu_info, Duration::from_millis(10));
+    interrupt_controller.enable_local_timer(&topology.cpu_info, interrupts::LOCAL_TIMER_PERIOD);
+    tlb::init_cpu(interrupts::local_apic().id());
+
+    /*
+     * Find how legacy PCI interrupts are routed. The routing table depends on the interrupt model we've told
+     * ACPI we're using, so this must happen after the interrupt controller has been initialized.
+     */
+    let pci_access = pci::PciAccess::new(ecam_access, &mut aml_context);
+
+    /*
+     * If the platform has an IOMMU, it needs to be registered before PCI devices are enumerated so they can be
+     * added to it.
+     */
+    if let Some(iommu) = iommu::VtdIommu::new(&acpi_tables) {
+        kernel::initialize_iommu(iommu);
+    }
+
+    /*
+     * Enumerate PCI devices. This configures their interrupts, and so must happen after the
+     * interrupt controller has been initialized.
+     */
+    kernel::initialize_pci(pci_access);
+
+    /*
+     * Hand the devices described by the AML namespace (e.g. the PS/2 controller) out to userspace. Their
+     * interrupts are routed through the IOAPICs, so this must also happen after the interrupt controller has
+     * been initialized.
+     */
+    kernel::initialize_platform_devices(platform_devices::enumerate(&mut aml_context));
+
+    /*
+     * The FADT tells us which CMOS register holds the century, if the RTC keeps track of it, and how to power
+     * off and reset the system.
+     */
+    let fadt = acpi_tables.find_table::<acpi::fadt::Fadt>().ok();
+    let century_register = fadt.as_ref().map(|fadt| fadt.century).filter(|&register| register != 0);
+    kernel::initialize_rtc(rtc::CmosRtc::new(century_register));
+    if let Some(ref fadt) = fadt {
+        power::init_system_power(fadt, &mut aml_context);
+    }
 
     task::install_syscall_handler();
 
-    let platform = PlatformImpl { topology };
+    SCHEDULER.initialize(Scheduler::new(smp::num_cpus(&topology), interrupts::LOCAL_TIMER_PERIOD));
+    kernel::trace::init::<PlatformImpl>(smp::num_cpus(&topology));
+    kernel::profile::init::<PlatformImpl>(smp::num_cpus(&topology));
+    kernel::vdso::set_cpu_count(smp::num_cpus(&topology));
+    maitake::time::set_global_timer(&SCHEDULER.get().tasklet_scheduler.timer).unwrap();
 
-    // TODO: we need to support the tasklet scheduler on x64 too - maybe use the HPET to drive
-    // `maitake`'s timer wheel?
-    SCHEDULER.initialize(Scheduler::new());
+    /*
+     * Bring up the other processors. They'll start scheduling tasks as soon as they're ready, so this
+     * needs to happen after the scheduler has been created.
+     */
+    smp::boot_application_processors(&topology);
+
+    let platform = PlatformImpl { topology };
 
     /*
      * Create kernel objects from loaded images and schedule them.
      */
+    kernel::initialize_random::<PlatformImpl>(&boot_info);
     kernel::load_userspace(SCHEDULER.get(), &boot_info, &mut KERNEL_PAGE_TABLES.get().write());
     if let Some(ref video_info) = boot_info.video_mode {
         kernel::create_framebuffer(video_info);
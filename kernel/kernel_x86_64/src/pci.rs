@@ -1,16 +1,42 @@
+use crate::{interrupts, platform_devices};
 use acpi::PciConfigRegions;
-use alloc::{alloc::Global, sync::Arc};
-use core::ptr;
+use alloc::{alloc::Global, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use aml::{
+    pci_routing::{PciRoutingTable, Pin},
+    resource::{InterruptPolarity, InterruptTrigger, IrqDescriptor},
+    AmlContext,
+    AmlName,
+};
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use hal::memory::PAddr;
-use hal_x86_64::kernel_map;
-use kernel::{object::event::Event, pci::PciInterruptConfigurator};
+use hal_x86_64::{
+    hw::io_apic::{PinPolarity, TriggerMode},
+    kernel_map,
+};
+use kernel::{
+    interrupts::INTERRUPT_VECTORS,
+    object::event::Event,
+    pci::{MsiController, PciInterruptConfigurator},
+};
+use mulch::InitGuard;
 use pci_types::{
     capability::{MsiCapability, MsixCapability},
     Bar,
     ConfigRegionAccess,
     PciAddress,
 };
-use tracing::warn;
+use spinning_top::Spinlock;
+use tracing::{debug, info, warn};
+
+// TODO: this should have an interrupt guard as well
+static INTERRUPT_ROUTING: Spinlock<BTreeMap<u8, Vec<Arc<Event>>>> = Spinlock::new(BTreeMap::new());
+/// For each level-triggered GSI used by a legacy PCI interrupt, the number of events signalled by its last
+/// interrupt that are yet to be acknowledged. The GSI is masked when it fires, and only unmasked once the drivers
+/// of every device sharing it have serviced their device.
+static LEGACY_PENDING_ACKS: InitGuard<BTreeMap<u32, AtomicUsize>> = InitGuard::uninit();
 
 #[derive(Clone)]
 pub struct EcamAccess<'a>(Arc<PciConfigRegions<'a, Global>>);
@@ -45,26 +71,193 @@ impl<'a> ConfigRegionAccess for EcamAccess<'a> {
     }
 }
 
-impl<'a> PciInterruptConfigurator for EcamAccess<'a> {
-    fn configure_legacy(&self, _function: PciAddress, _pin: u8) -> Arc<Event> {
-        // TODO: this will need to read the result of the `_PRT` object out of the interepreted AML
-        // namespace
-        let event = Event::new();
-        warn!("Legacy PCI interrupt support is incomplete on x86_64. PCI interrupts will not trigger delegated `Event` objects!");
-        event
+/// Provides access to PCI configuration space through `EcamAccess`, and configures interrupts for PCI devices.
+/// Legacy interrupts are routed through the IOAPICs, using the routing described by the root bridge's `_PRT`
+/// object in the AML namespace.
+pub struct PciAccess<'a> {
+    ecam: EcamAccess<'a>,
+    /// Maps a (device, pin) on the root bus to the GSI its legacy interrupt is connected to.
+    legacy_interrupt_routing: BTreeMap<(u8, u8), IrqDescriptor>,
+}
+
+impl<'a> PciAccess<'a> {
+    pub fn new(ecam: EcamAccess<'a>, aml_context: &mut AmlContext) -> PciAccess<'a> {
+        /*
+         * Evaluate the routing table for every device on the root bus up front, as we don't have access to the
+         * AML context when devices are configured. The root bridge is found by its hardware ID.
+         * TODO: devices behind PCI-to-PCI bridges need their pins swizzled, or the bridge's own `_PRT` used.
+         */
+        let mut routing = BTreeMap::new();
+        let prt_path = platform_devices::find_devices(aml_context)
+            .into_iter()
+            .find(|device| {
+                platform_devices::hardware_ids(aml_context, device)
+                    .iter()
+                    .any(|id| platform_devices::PCI_ROOT_BRIDGE_IDS.contains(&id.as_str()))
+            })
+            .and_then(|root_bridge| AmlName::from_str("_PRT").unwrap().resolve(&root_bridge).ok());
+        match prt_path.map(|prt_path| PciRoutingTable::from_prt_path(&prt_path, aml_context)) {
+            Some(Ok(routing_table)) => {
+                for device in 0..32 {
+                    for (pin, prt_pin) in [(1, Pin::IntA), (2, Pin::IntB), (3, Pin::IntC), (4, Pin::IntD)] {
+                        if let Ok(descriptor) = routing_table.route(device, 0xffff, prt_pin, aml_context) {
+                            debug!(
+                                "Legacy PCI interrupt routing: device {}, pin = {} -> GSI {}",
+                                device, pin, descriptor.irq
+                            );
+                            routing.insert((device as u8, pin), descriptor);
+                        }
+                    }
+                }
+            }
+            Some(Err(err)) => {
+                warn!("Failed to parse PCI routing table: {:?}. Legacy PCI interrupts won't work.", err)
+            }
+            None => {
+                warn!("Couldn't find the PCI root bridge in the AML namespace. Legacy PCI interrupts won't work.")
+            }
+        }
+
+        LEGACY_PENDING_ACKS.initialize(
+            routing
+                .values()
+                .filter(|descriptor| descriptor.trigger == InterruptTrigger::Level)
+                .map(|descriptor| (descriptor.irq, AtomicUsize::new(0)))
+                .collect(),
+        );
+
+        PciAccess { ecam, legacy_interrupt_routing: routing }
     }
+}
+
+impl<'a> ConfigRegionAccess for PciAccess<'a> {
+    unsafe fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        unsafe { self.ecam.read(address, offset) }
+    }
+
+    unsafe fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        unsafe { self.ecam.write(address, offset, value) }
+    }
+}
+
+impl<'a> PciInterruptConfigurator for PciAccess<'a> {
+    fn configure_legacy(&self, function: PciAddress, pin: u8) -> Arc<Event> {
+        let descriptor =
+            if function.bus() == 0 { self.legacy_interrupt_routing.get(&(function.device(), pin)) } else { None };
+        let Some(descriptor) = descriptor else {
+            warn!(
+                "No legacy interrupt routing for PCI device {:?} (pin {}). Its interrupts won't be delivered.",
+                function, pin
+            );
+            return Event::new();
+        };
+        info!("Configuring PCI device to use legacy interrupt (GSI {}): {:?}", descriptor.irq, function);
+
+        let polarity = match descriptor.polarity {
+            InterruptPolarity::ActiveHigh => PinPolarity::High,
+            InterruptPolarity::ActiveLow => PinPolarity::Low,
+        };
+        let (trigger_mode, event) = match descriptor.trigger {
+            InterruptTrigger::Level => {
+                (TriggerMode::Level, Event::new_interrupt(descriptor.irq, acknowledge_legacy_interrupt))
+            }
+            InterruptTrigger::Edge => (TriggerMode::Edge, Event::new_counting()),
+        };
+
+        /*
+         * Lines are shared between devices, so only route the GSI the first time it's used, and add the event
+         * to any that are already signalled by it.
+         */
+        let vector = interrupts::vector_for_gsi(descriptor.irq);
+        let mut routing = INTERRUPT_ROUTING.lock();
+        match routing.get_mut(&vector) {
+            Some(events) => events.push(event.clone()),
+            None => {
+                routing.insert(vector, vec![event.clone()]);
+                interrupts::route_gsi(descriptor.irq, polarity, trigger_mode, pci_interrupt_handler);
+            }
+        }
 
-    fn configure_msi(&self, _function: PciAddress, _msi: &mut MsiCapability) -> Arc<Event> {
-        // TODO
-        let event = Event::new();
-        warn!("MSI support is incomplete on x86_64! PCI interrupts will not trigger delegated `Event` objects!");
         event
     }
 
-    fn configure_msix(&self, _function: PciAddress, _bar: Bar, _msi: &mut MsixCapability) -> Arc<Event> {
-        // TODO
-        let event = Event::new();
-        warn!("MSI-X support is incomplete on x86_64! PCI interrupts will not trigger delegated `Event` objects!");
+    fn configure_msi(&self, function: PciAddress, msi: &mut MsiCapability) -> Arc<Event> {
+        // Message-signalled interrupts are edge-triggered, so count them to avoid losing any that arrive together
+        let event = Event::new_counting();
+        info!("Configuring PCI device to use MSI interrupts: {:?}", function);
+
+        let vector = INTERRUPT_VECTORS.get().lock().allocate().expect("No free interrupt vectors for MSI") as u8;
+        INTERRUPT_ROUTING.lock().insert(vector, vec![event.clone()]);
+        interrupts::handle_interrupt(vector, pci_interrupt_handler);
+
+        interrupts::msi_controller().configure_msi(vector as u32, msi, self);
+
         event
     }
+
+    fn configure_msix(
+        &self,
+        function: PciAddress,
+        table_bar: Bar,
+        msix: &mut MsixCapability,
+        num_vectors: u16,
+    ) -> Vec<Arc<Event>> {
+        info!("Configuring PCI device to use {} MSI-X interrupts: {:?}", num_vectors, function);
+
+        msix.set_enabled(true, self);
+
+        let table_base_phys = match table_bar {
+            Bar::Memory32 { address, .. } => (address + msix.table_offset()) as usize,
+            Bar::Memory64 { address, .. } => address as usize + msix.table_offset() as usize,
+            _ => panic!(),
+        };
+        let table_base_virt = kernel_map::physical_to_virtual(PAddr::new(table_base_phys).unwrap());
+
+        let mut events = Vec::new();
+        for entry in 0..num_vectors {
+            let Some(vector) = INTERRUPT_VECTORS.get().lock().allocate() else {
+                warn!("Ran out of interrupt vectors for MSI-X. Only configuring {} vectors.", entry);
+                break;
+            };
+            let event = Event::new_counting();
+            INTERRUPT_ROUTING.lock().insert(vector as u8, vec![event.clone()]);
+            interrupts::handle_interrupt(vector as u8, pci_interrupt_handler);
+
+            interrupts::msi_controller().configure_msix_entry(vector, table_base_virt, entry);
+            events.push(event);
+        }
+
+        assert!(!events.is_empty(), "No free interrupt vectors for MSI-X");
+        events
+    }
+}
+
+fn pci_interrupt_handler(vector: u8) {
+    let routing = INTERRUPT_ROUTING.lock();
+    if let Some(events) = routing.get(&vector) {
+        /*
+         * If this is a level-triggered legacy interrupt, it'll keep firing until the device is serviced, so mask
+         * it until every driver sharing it has acknowledged it.
+         */
+        let gsi = interrupts::gsi_for_vector(vector);
+        if let Some((gsi, pending_acks)) =
+            gsi.and_then(|gsi| Some((gsi, LEGACY_PENDING_ACKS.try_get()?.get(&gsi)?)))
+        {
+            if !events.is_empty() {
+                interrupts::mask_gsi(gsi);
+                pending_acks.store(events.len(), Ordering::SeqCst);
+            }
+        }
+
+        for event in events {
+            event.signal();
+        }
+    }
+}
+
+fn acknowledge_legacy_interrupt(gsi: u32) {
+    let pending_acks = LEGACY_PENDING_ACKS.get().get(&gsi).unwrap();
+    if pending_acks.fetch_sub(1, Ordering::SeqCst) == 1 {
+        interrupts::unmask_gsi(gsi);
+    }
 }
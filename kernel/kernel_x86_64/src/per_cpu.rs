@@ -12,6 +12,16 @@ pub unsafe fn get_per_cpu_data<'a>() -> &'a mut PerCpuImpl {
     &mut *(ptr as *mut PerCpuImpl)
 }
 
+/// Get the kernel's ID for the running CPU. Unlike `get_per_cpu_data`, this is safe, as it doesn't create a
+/// reference to the per-CPU data, but it must still not be called before the per-CPU data has been installed.
+pub fn cpu_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("mov {}, gs:0x18", out(reg) id);
+    }
+    id
+}
+
 /// Represents data that is held individually for each CPU.
 ///
 /// Per-CPU data on x86_64 is accessed by reading a pointer to itself from the start of the structure. Various
@@ -30,12 +40,14 @@ pub struct PerCpuImpl {
     current_task_kernel_rsp: VAddr,
     /// This field must remain at `gs:0x10`, and so cannot be moved.
     current_task_user_rsp: VAddr,
+    /// The kernel's ID for this CPU. This is read from `gs:0x18` by `cpu_id`, and so cannot be moved.
+    cpu_id: usize,
 
     pub tss: Box<Tss>,
 }
 
 impl PerCpuImpl {
-    pub fn install(tss: Box<Tss>) {
+    pub fn install(cpu_id: usize, tss: Box<Tss>) {
         use hal_x86_64::hw::registers::{write_msr, IA32_GS_BASE};
 
         let per_cpu = Box::new(PerCpuImpl {
@@ -43,6 +55,7 @@ impl PerCpuImpl {
 
             current_task_kernel_rsp: VAddr::new(0x0),
             current_task_user_rsp: VAddr::new(0x0),
+            cpu_id,
             tss,
         });
         let address = Box::into_raw(per_cpu) as usize;
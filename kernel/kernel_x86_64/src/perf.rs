@@ -0,0 +1,140 @@
+//! Performance counters, using the architectural performance-monitoring unit that Intel processors describe in
+//! `cpuid` leaf `0xa`. Cycles and instructions are counted with the fixed-function counters if there are enough
+//! of them, and everything else with the general-purpose counters.
+//!
+//! Counters are identified in the same way as by `rdpmc` - general-purpose counters by their index, and
+//! fixed-function counters by their index with bit 30 set.
+
+use bit_field::BitField;
+use hal_x86_64::hw::{
+    cpu::{ArchitecturalEvent, CpuInfo, PerformanceMonitoringInfo},
+    registers::{
+        read_msr,
+        write_msr,
+        IA32_FIXED_CTR0,
+        IA32_FIXED_CTR_CTRL,
+        IA32_PERFEVTSEL0,
+        IA32_PERF_GLOBAL_CTRL,
+        IA32_PMC0,
+    },
+};
+use kernel::PerfEvent;
+use mulch::InitGuard;
+use tracing::info;
+
+static PMU: InitGuard<PerformanceMonitoringInfo> = InitGuard::uninit();
+
+const FIXED_COUNTER: usize = 1 << 30;
+const FIXED_INSTRUCTIONS: usize = 0;
+const FIXED_CYCLES: usize = 1;
+
+/// Bits of `IA32_PERFEVTSELn`
+const EVTSEL_USER: u64 = 1 << 16;
+const EVTSEL_ENABLE: u64 = 1 << 22;
+/// Counts a fixed-function counter's event while the CPU is in ring 3. Shifted into place for each counter.
+const FIXED_CTRL_USER: u64 = 0b0010;
+
+/// Find out what the performance-monitoring unit can do. This must be called on the bootstrap processor, and we
+/// assume the other processors have the same counters.
+pub fn init(cpu_info: &CpuInfo) {
+    // We need the global control register, which was added in version 2
+    match cpu_info.performance_monitoring() {
+        Some(pmu) if pmu.version >= 2 => {
+            info!(
+                "Performance counters: version {}, {} general-purpose, {} fixed-function",
+                pmu.version, pmu.num_general_counters, pmu.num_fixed_counters
+            );
+            PMU.initialize(pmu);
+        }
+        _ => info!("Processor does not have supported performance counters"),
+    }
+}
+
+/// Choose a counter for each of `events`. Returns `false` if they can't all be counted at once.
+pub fn allocate(events: &[PerfEvent], counters: &mut [usize]) -> bool {
+    let Some(pmu) = PMU.try_get() else {
+        return false;
+    };
+
+    let mut fixed_used = 0u64;
+    let mut next_general = 0;
+    for (&event, counter) in events.iter().zip(counters.iter_mut()) {
+        let fixed = match event {
+            PerfEvent::Instructions => Some(FIXED_INSTRUCTIONS),
+            PerfEvent::Cycles => Some(FIXED_CYCLES),
+            _ => None,
+        };
+        if let Some(fixed) = fixed {
+            if fixed < pmu.num_fixed_counters as usize && !fixed_used.get_bit(fixed) {
+                fixed_used.set_bit(fixed, true);
+                *counter = FIXED_COUNTER | fixed;
+                continue;
+            }
+        }
+
+        if !pmu.event_available(architectural_event(event)) || next_general >= pmu.num_general_counters as usize {
+            return false;
+        }
+        *counter = next_general;
+        next_general += 1;
+    }
+
+    true
+}
+
+/// Program and enable `counters` (chosen by `allocate`) to count `events` in userspace, from zero.
+pub unsafe fn start(events: &[PerfEvent], counters: &[usize]) {
+    let mut fixed_control = 0;
+    let mut global_control = 0;
+
+    unsafe {
+        write_msr(IA32_PERF_GLOBAL_CTRL, 0);
+        for (&event, &counter) in events.iter().zip(counters.iter()) {
+            if counter & FIXED_COUNTER != 0 {
+                let index = counter & !FIXED_COUNTER;
+                write_msr(IA32_FIXED_CTR0 + index as u32, 0);
+                fixed_control |= FIXED_CTRL_USER << (index * 4);
+                global_control |= 1 << (32 + index);
+            } else {
+                let (event_select, unit_mask) = architectural_event(event).event_select();
+                write_msr(IA32_PMC0 + counter as u32, 0);
+                write_msr(
+                    IA32_PERFEVTSEL0 + counter as u32,
+                    u64::from(event_select) | (u64::from(unit_mask) << 8) | EVTSEL_USER | EVTSEL_ENABLE,
+                );
+                global_control |= 1 << counter;
+            }
+        }
+        write_msr(IA32_FIXED_CTR_CTRL, fixed_control);
+        write_msr(IA32_PERF_GLOBAL_CTRL, global_control);
+    }
+}
+
+pub fn read(counters: &[usize], counts: &mut [u64]) {
+    for (&counter, count) in counters.iter().zip(counts.iter_mut()) {
+        *count = if counter & FIXED_COUNTER != 0 {
+            read_msr(IA32_FIXED_CTR0 + (counter & !FIXED_COUNTER) as u32)
+        } else {
+            read_msr(IA32_PMC0 + counter as u32)
+        };
+    }
+}
+
+pub unsafe fn stop(counters: &[usize]) {
+    unsafe {
+        write_msr(IA32_PERF_GLOBAL_CTRL, 0);
+        write_msr(IA32_FIXED_CTR_CTRL, 0);
+        for &counter in counters.iter().filter(|&&counter| counter & FIXED_COUNTER == 0) {
+            write_msr(IA32_PERFEVTSEL0 + counter as u32, 0);
+        }
+    }
+}
+
+fn architectural_event(event: PerfEvent) -> ArchitecturalEvent {
+    match event {
+        PerfEvent::Cycles => ArchitecturalEvent::CoreCycles,
+        PerfEvent::Instructions => ArchitecturalEvent::InstructionsRetired,
+        PerfEvent::CacheMisses => ArchitecturalEvent::LastLevelCacheMisses,
+        PerfEvent::BranchMisses => ArchitecturalEvent::BranchMispredictsRetired,
+    }
+}
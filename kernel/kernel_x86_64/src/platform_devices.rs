@@ -0,0 +1,216 @@
+//! Devices on x86_64 that aren't found by enumerating a bus like PCI are described by the ACPI namespace, by
+//! `Device` objects whose `_HID`, `_STA`, and `_CRS` objects are evaluated by the AML interpreter. We hand the
+//! devices the kernel doesn't use itself out to userspace, so they can be driven by drivers found through the
+//! Platform Bus.
+
+use crate::interrupts;
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use aml::{
+    resource::{
+        self,
+        AddressSpaceResourceType,
+        InterruptPolarity,
+        InterruptTrigger,
+        MemoryRangeDescriptor,
+        Resource,
+    },
+    value::Args as AmlArgs,
+    AmlContext,
+    AmlError,
+    AmlName,
+    AmlValue,
+    LevelType,
+};
+use bit_field::BitField;
+use hal::memory::PAddr;
+use hal_x86_64::hw::io_apic::{PinPolarity, TriggerMode};
+use kernel::{object::event::Event, platform_devices::PlatformDevice};
+use spinning_top::Spinlock;
+use tracing::{debug, info, warn};
+
+/// The hardware IDs of PCI root bridges. The kernel enumerates PCI itself, so uses these to find the root bridge's
+/// `_PRT` object.
+pub const PCI_ROOT_BRIDGE_IDS: &[&str] = &["PNP0A08", "PNP0A03"];
+
+/// Devices that are used by the kernel itself, or that only reserve resources rather than describing a device, and
+/// so shouldn't be handed out to userspace. These are matched against each device's hardware and compatible IDs.
+const KERNEL_DEVICES: &[&str] = &[
+    "PNP0000",  // 8259 PIC
+    "PNP0100",  // PIT, used to calibrate the TSC
+    "PNP0B00",  // CMOS RTC
+    "PNP0501",  // 16550 UARTs, used for the kernel's log and GDB
+    "PNP0A03",  // PCI root bridge
+    "PNP0A08",  // PCI Express root bridge
+    "PNP0C01",  // System board resources
+    "PNP0C02",  // Motherboard resources
+    "PNP0C0F",  // PCI interrupt link device
+    "ACPI0007", // Processor
+];
+
+/// The events signalled by each vector used by a platform device's interrupt. Level-triggered interrupts also
+/// have the GSI they're delivered on, which is masked when it fires until the device's driver acknowledges it.
+// TODO: this should have an interrupt guard as well
+static INTERRUPT_EVENTS: Spinlock<BTreeMap<u8, (Arc<Event>, Option<u32>)>> = Spinlock::new(BTreeMap::new());
+
+/// Find the devices in the ACPI namespace that should be handed out to userspace. Each device is given its
+/// memory-mapped regions and I/O port ranges from `_CRS`, and its first interrupt, if it has any.
+pub fn enumerate(aml_context: &mut AmlContext) -> Vec<PlatformDevice> {
+    let mut devices = Vec::new();
+    for device in find_devices(aml_context) {
+        let compatible = hardware_ids(aml_context, &device);
+        if compatible.is_empty()
+            || compatible.iter().any(|id| KERNEL_DEVICES.contains(&id.as_str()))
+            || !is_enabled(aml_context, &device)
+        {
+            continue;
+        }
+
+        let resources = match evaluate(aml_context, &device, "_CRS")
+            .and_then(|crs| resource::resource_descriptor_list(&crs))
+        {
+            Ok(resources) => resources,
+            Err(err) => {
+                debug!("Couldn't get resources of ACPI device {}: {:?}", device.as_string(), err);
+                Vec::new()
+            }
+        };
+
+        let mut regions = Vec::new();
+        let mut io_ports = Vec::new();
+        let mut interrupt = None;
+        for resource in resources {
+            match resource {
+                Resource::MemoryRange(MemoryRangeDescriptor::FixedLocation {
+                    base_address, range_length, ..
+                }) => {
+                    if let Some(address) = PAddr::new(base_address as usize) {
+                        regions.push((address, range_length as usize));
+                    }
+                }
+                Resource::AddressSpace(descriptor)
+                    if matches!(descriptor.resource_type, AddressSpaceResourceType::MemoryRange) =>
+                {
+                    if let Some(address) = PAddr::new(descriptor.address_range.0 as usize) {
+                        regions.push((address, descriptor.length as usize));
+                    }
+                }
+                Resource::IOPort(descriptor) => {
+                    io_ports.push((descriptor.memory_range.0, u16::from(descriptor.range_length)));
+                }
+                Resource::Irq(descriptor) if interrupt.is_none() => {
+                    interrupt = Some(route_interrupt(descriptor.irq, descriptor.polarity, descriptor.trigger));
+                }
+                _ => (),
+            }
+        }
+
+        info!("Found platform device: {} (compatible with {:?})", device.as_string(), compatible.first());
+        devices.push(PlatformDevice { name: device.as_string(), compatible, regions, io_ports, interrupt });
+    }
+
+    devices
+}
+
+/// Find the absolute paths of every device in the ACPI namespace.
+pub fn find_devices(aml_context: &mut AmlContext) -> Vec<AmlName> {
+    let mut devices = Vec::new();
+    let result = aml_context.namespace.traverse(|name, level| {
+        if let LevelType::Device = level.typ {
+            devices.push(name.clone());
+        }
+        Ok(true)
+    });
+    if let Err(err) = result {
+        warn!("Failed to traverse AML namespace: {:?}", err);
+    }
+    devices
+}
+
+/// Get the hardware ID (`_HID`) of a device, followed by its compatible IDs (`_CID`), as strings.
+pub fn hardware_ids(aml_context: &mut AmlContext, device: &AmlName) -> Vec<String> {
+    let mut ids = Vec::new();
+    if let Ok(hid) = evaluate(aml_context, device, "_HID") {
+        ids.extend(decode_id(&hid));
+    }
+    match evaluate(aml_context, device, "_CID") {
+        Ok(AmlValue::Package(cids)) => ids.extend(cids.iter().filter_map(decode_id)),
+        Ok(cid) => ids.extend(decode_id(&cid)),
+        Err(_) => (),
+    }
+    ids
+}
+
+/// Evaluate `object` in the scope of `device`, invoking it if it's a method.
+pub fn evaluate(aml_context: &mut AmlContext, device: &AmlName, object: &str) -> Result<AmlValue, AmlError> {
+    let path = AmlName::from_str(object).unwrap().resolve(device)?;
+    aml_context.invoke_method(&path, AmlArgs::from_list(Vec::new()).unwrap())
+}
+
+/// Devices without a `_STA` object are present and enabled.
+fn is_enabled(aml_context: &mut AmlContext, device: &AmlName) -> bool {
+    match evaluate(aml_context, device, "_STA") {
+        Ok(AmlValue::Integer(status)) => status.get_bit(0) && status.get_bit(1),
+        _ => true,
+    }
+}
+
+/// IDs are either strings, or compressed EISA IDs, which we decode into their string form (e.g. `PNP0303`).
+fn decode_id(value: &AmlValue) -> Option<String> {
+    match value {
+        AmlValue::String(id) => Some(id.clone()),
+        AmlValue::Integer(id) => {
+            /*
+             * Compressed EISA IDs are stored byte-swapped. Once swapped back, the top 15 bits are three
+             * five-bit letters (where `1` is `A`), and the bottom 16 bits are the product number in hex.
+             */
+            let id = (*id as u32).swap_bytes();
+            let letter = |bits| char::from(b'@' + id.get_bits(bits) as u8);
+            Some(format!("{}{}{}{:04X}", letter(26..31), letter(21..26), letter(16..21), id.get_bits(0..16)))
+        }
+        _ => None,
+    }
+}
+
+/// Route a device's interrupt, and create the `Event` it signals. Interrupts described by `_CRS` with a number
+/// below 16 are ISA IRQs, which may be connected to a different GSI, so are routed using the MADT.
+fn route_interrupt(irq: u32, polarity: InterruptPolarity, trigger: InterruptTrigger) -> Arc<Event> {
+    let polarity = match polarity {
+        InterruptPolarity::ActiveHigh => PinPolarity::High,
+        InterruptPolarity::ActiveLow => PinPolarity::Low,
+    };
+    let trigger_mode = match trigger {
+        InterruptTrigger::Edge => TriggerMode::Edge,
+        InterruptTrigger::Level => TriggerMode::Level,
+    };
+
+    let gsi = if irq < 16 {
+        interrupts::route_isa_irq(irq as u8, interrupt_handler)
+    } else {
+        interrupts::route_gsi(irq, polarity, trigger_mode, interrupt_handler);
+        irq
+    };
+
+    /*
+     * Edge-triggered interrupts (which most ISA devices use) would be lost if they fired while masked, so we
+     * count them instead of masking the line until the driver acknowledges them.
+     */
+    let (event, masked_gsi) = match trigger_mode {
+        TriggerMode::Level => (Event::new_interrupt(gsi, acknowledge_interrupt), Some(gsi)),
+        TriggerMode::Edge => (Event::new_counting(), None),
+    };
+    INTERRUPT_EVENTS.lock().insert(interrupts::vector_for_gsi(gsi), (event.clone(), masked_gsi));
+    event
+}
+
+fn interrupt_handler(vector: u8) {
+    if let Some((event, masked_gsi)) = INTERRUPT_EVENTS.lock().get(&vector) {
+        if let Some(gsi) = masked_gsi {
+            interrupts::mask_gsi(*gsi);
+        }
+        event.signal();
+    }
+}
+
+fn acknowledge_interrupt(gsi: u32) {
+    interrupts::unmask_gsi(gsi);
+}
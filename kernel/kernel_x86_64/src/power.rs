@@ -0,0 +1,339 @@
+//! Idle and performance states of the processors. Idle states (C-states) are described by the processor's `_CST`
+//! object in the AML namespace, and are entered with `mwait` or by reading from an I/O port. Performance states
+//! are either managed by the processor itself (Hardware-controlled Performance States, or HWP), in which case we
+//! just give it a range to pick from, or are described by the processor's `_PSS` object and requested through
+//! `IA32_PERF_CTL`. We assume every processor has the same states as the first one in the namespace.
+//!
+//! This also powers off and resets the system. Powering off puts the system into the `S5` sleep state, through
+//! the `PM1` control registers described by the FADT, and resetting uses the FADT's reset register.
+
+use crate::platform_devices;
+use acpi::{address::AddressSpace, fadt::Fadt};
+use alloc::vec::Vec;
+use aml::{AmlContext, AmlName, AmlValue, LevelType};
+use bit_field::BitField;
+use core::{arch::asm, sync::atomic::AtomicU64, time::Duration};
+use hal_x86_64::hw::{
+    cpu::CpuInfo,
+    port::Port,
+    registers::{
+        read_msr,
+        write_msr,
+        CpuFlags,
+        IA32_HWP_CAPABILITIES,
+        IA32_HWP_REQUEST,
+        IA32_PERF_CTL,
+        IA32_PM_ENABLE,
+    },
+};
+use mulch::InitGuard;
+use tracing::{info, warn};
+
+/// The idle states we can use, in order of increasing exit latency. If this is empty (or hasn't been initialized
+/// yet), processors idle with `hlt`.
+static IDLE_STATES: InitGuard<Vec<IdleState>> = InitGuard::uninit();
+static PERFORMANCE_CONTROL: InitGuard<PerformanceControl> = InitGuard::uninit();
+static SYSTEM_POWER: InitGuard<SystemPower> = InitGuard::uninit();
+
+/// The longest exit latency of the idle states we'll enter. The local APIC timer ticks every 10ms, so this keeps
+/// the cost of waking up well below a tick.
+const MAX_IDLE_LATENCY: Duration = Duration::from_micros(500);
+
+/// The address spaces of Generic Register Descriptors that we understand.
+const SYSTEM_IO: u8 = 0x01;
+const FUNCTIONAL_FIXED_HARDWARE: u8 = 0x7f;
+
+/// `mwait` monitors an address range, and wakes up if it's written to. We only want to be woken by interrupts,
+/// so monitor something nothing writes to.
+static MONITOR_LINE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug)]
+enum IdleEntry {
+    Halt,
+    /// Enter the state with `mwait`, passing the given hint in `eax`.
+    Mwait(u32),
+    /// Enter the state by reading from the given I/O port.
+    IoPort(u16),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IdleState {
+    entry: IdleEntry,
+    latency: Duration,
+}
+
+enum PerformanceControl {
+    Hwp {
+        lowest: u8,
+        highest: u8,
+    },
+    /// The states described by `_PSS`, fastest first, as `(frequency in MHz, value for IA32_PERF_CTL)` pairs.
+    PerfCtl(Vec<(u64, u64)>),
+}
+
+struct SystemPower {
+    /// The I/O ports of the `PM1a` and `PM1b` control registers, and the `SLP_TYP` values to write to each of
+    /// them to enter `S5`, if the system supports it.
+    soft_off: Option<((u16, u16), Option<(u16, u16)>)>,
+    /// The I/O port of the FADT's reset register, and the value to write to it, if the FADT has one.
+    reset: Option<(u16, u8)>,
+}
+
+/// Find the idle and performance states of the processors. This must be called on the bootstrap processor,
+/// after the AML namespace has been initialized.
+pub fn init(cpu_info: &CpuInfo, aml_context: &mut AmlContext) {
+    let Some(processor) = find_processor(aml_context) else {
+        info!("No processors found in AML namespace. Idling with `hlt`, and not managing performance.");
+        return;
+    };
+
+    /*
+     * We only use `mwait` if it can be woken by interrupts that are disabled, as the scheduler can idle with them
+     * disabled.
+     */
+    let mwait_usable = cpu_info.mwait_breaks_on_masked_interrupts();
+    let mut idle_states = match platform_devices::evaluate(aml_context, &processor, "_CST").ok() {
+        Some(AmlValue::Package(entries)) => {
+            entries.iter().skip(1).filter_map(|entry| parse_cst_entry(entry, mwait_usable)).collect()
+        }
+        _ => Vec::new(),
+    };
+    idle_states.sort_by_key(|state: &IdleState| state.latency);
+    info!("Idle states: {:?}", idle_states);
+    IDLE_STATES.initialize(idle_states);
+
+    if cpu_info.supports_hwp() {
+        let capabilities = read_msr(IA32_HWP_CAPABILITIES);
+        let highest = capabilities.get_bits(0..8) as u8;
+        let lowest = capabilities.get_bits(24..32) as u8;
+        info!("Performance states: managed by HWP (levels {}..={})", lowest, highest);
+        PERFORMANCE_CONTROL.initialize(PerformanceControl::Hwp { lowest, highest });
+    } else if cpu_info.supported_features.eist {
+        /*
+         * We can only request states with `IA32_PERF_CTL` if `_PCT` says that's how they're controlled.
+         */
+        let controlled_by_msr = match platform_devices::evaluate(aml_context, &processor, "_PCT").ok() {
+            Some(AmlValue::Package(registers)) => registers
+                .first()
+                .and_then(generic_register)
+                .map_or(false, |(address_space, _, _)| address_space == FUNCTIONAL_FIXED_HARDWARE),
+            _ => false,
+        };
+        let states: Vec<(u64, u64)> = match platform_devices::evaluate(aml_context, &processor, "_PSS").ok() {
+            Some(AmlValue::Package(states)) if controlled_by_msr => {
+                states.iter().filter_map(parse_pss_entry).collect()
+            }
+            _ => Vec::new(),
+        };
+        if !states.is_empty() {
+            let frequencies: Vec<u64> = states.iter().map(|&(frequency, _)| frequency).collect();
+            info!("Performance states: {:?} MHz", frequencies);
+            PERFORMANCE_CONTROL.initialize(PerformanceControl::PerfCtl(states));
+        }
+    }
+}
+
+/// Prepare the running processor for having its performance managed. This must be called on each processor,
+/// after `init` has been called on the bootstrap processor.
+pub fn init_cpu() {
+    if let Some(PerformanceControl::Hwp { .. }) = PERFORMANCE_CONTROL.try_get() {
+        unsafe {
+            write_msr(IA32_PM_ENABLE, 1);
+        }
+    }
+    set_performance(100);
+}
+
+/// Find how to power off and reset the system. This must be called after the AML namespace has been
+/// initialized, as the values to write to enter `S5` are described by the `\_S5` object.
+pub fn init_system_power(fadt: &Fadt, aml_context: &mut AmlContext) {
+    let sleep_types = match aml_context.namespace.get_by_path(&AmlName::from_str("\\_S5").unwrap()) {
+        Ok(AmlValue::Package(values)) => match values.as_slice() {
+            [AmlValue::Integer(a), AmlValue::Integer(b), ..] => Some((*a as u16, *b as u16)),
+            _ => None,
+        },
+        _ => None,
+    };
+    let io_port = |register: acpi::address::GenericAddress| match register.address_space {
+        AddressSpace::SystemIo if register.address != 0 => Some(register.address as u16),
+        _ => None,
+    };
+
+    let soft_off = sleep_types.and_then(|(slp_typ_a, slp_typ_b)| {
+        let pm1a = fadt.pm1a_control_block().ok().and_then(io_port)?;
+        let pm1b = fadt.pm1b_control_block().ok().flatten().and_then(io_port);
+        Some(((pm1a, slp_typ_a), pm1b.map(|pm1b| (pm1b, slp_typ_b))))
+    });
+    if soft_off.is_none() {
+        warn!("Can't find how to enter S5. Powering off the system will not be supported.");
+    }
+    let reset = fadt.reset_register().ok().and_then(io_port).map(|port| (port, fadt.reset_value));
+
+    SYSTEM_POWER.initialize(SystemPower { soft_off, reset });
+}
+
+/// Idle the running processor until an interrupt arrives, using the deepest idle state whose exit latency is
+/// acceptable.
+pub fn idle() {
+    let state = IDLE_STATES
+        .try_get()
+        .and_then(|states| states.iter().rev().find(|state| state.latency <= MAX_IDLE_LATENCY));
+
+    match state.map_or(IdleEntry::Halt, |state| state.entry) {
+        /*
+         * `hlt` can't be woken by an interrupt that's disabled, so we can't use it if interrupts are disabled.
+         */
+        IdleEntry::Halt if CpuFlags::read().interrupts_enabled() => unsafe {
+            asm!("hlt");
+        },
+        IdleEntry::Halt => core::hint::spin_loop(),
+        IdleEntry::Mwait(hint) => unsafe {
+            asm!("monitor", in("rax") &MONITOR_LINE as *const AtomicU64, in("ecx") 0, in("edx") 0);
+            // Setting bit `0` of `ecx` wakes us up on interrupts, even if they're disabled
+            asm!("mwait", in("eax") hint, in("ecx") 1);
+        },
+        IdleEntry::IoPort(port) => unsafe {
+            let _: u8 = Port::new(port).read();
+        },
+    }
+}
+
+/// Ask the running processor to run at `performance`% of its maximum performance.
+pub fn set_performance(performance: u8) {
+    match PERFORMANCE_CONTROL.try_get() {
+        Some(&PerformanceControl::Hwp { lowest, highest }) => {
+            let desired = lowest + ((highest - lowest) as u32 * performance as u32 / 100) as u8;
+            let mut request = 0u64;
+            request.set_bits(0..8, lowest as u64);
+            request.set_bits(8..16, highest as u64);
+            request.set_bits(16..24, desired as u64);
+            // Use a balanced energy-performance preference
+            request.set_bits(24..32, 0x80);
+            unsafe {
+                write_msr(IA32_HWP_REQUEST, request);
+            }
+        }
+        Some(PerformanceControl::PerfCtl(states)) => {
+            /*
+             * Use the slowest state that's at least as fast as the requested performance.
+             */
+            let target = states[0].0 * performance as u64 / 100;
+            let &(_, control) = states.iter().rev().find(|&&(frequency, _)| frequency >= target).unwrap();
+            unsafe {
+                write_msr(IA32_PERF_CTL, control.get_bits(0..16));
+            }
+        }
+        None => (),
+    }
+}
+
+/// Power the system off, by entering the `S5` sleep state. This only returns if it fails.
+pub fn power_off() {
+    let Some(&SystemPower { soft_off: Some((pm1a, pm1b)), .. }) = SYSTEM_POWER.try_get() else {
+        return;
+    };
+
+    /*
+     * A sleep state is entered by writing its `SLP_TYP` value to bits `10..13` of the `PM1` control registers,
+     * along with the `SLP_EN` bit.
+     */
+    let sleep = |(port, slp_typ): (u16, u16)| unsafe {
+        let mut value = 0u16;
+        value.set_bits(10..13, slp_typ);
+        value.set_bit(13, true);
+        Port::new(port).write(value);
+    };
+    sleep(pm1a);
+    if let Some(pm1b) = pm1b {
+        sleep(pm1b);
+    }
+}
+
+/// Reset the system. This only returns if it fails.
+pub fn reset() {
+    if let Some(&SystemPower { reset: Some((port, value)), .. }) = SYSTEM_POWER.try_get() {
+        unsafe {
+            Port::new(port).write(value);
+        }
+    }
+
+    /*
+     * Fall back to pulsing the CPU's reset line through the keyboard controller. Most chipsets (including
+     * QEMU's) still support this.
+     */
+    unsafe {
+        Port::<u8>::new(0x64).write(0xfe);
+    }
+}
+
+/// Find the first processor in the AML namespace. These are either declared with `Processor`, or as devices with
+/// the `ACPI0007` hardware ID.
+fn find_processor(aml_context: &mut AmlContext) -> Option<AmlName> {
+    let mut processor = None;
+    let _ = aml_context.namespace.traverse(|name, level| {
+        if let LevelType::Processor = level.typ {
+            processor.get_or_insert_with(|| name.clone());
+        }
+        Ok(processor.is_none())
+    });
+
+    processor.or_else(|| {
+        platform_devices::find_devices(aml_context)
+            .into_iter()
+            .find(|device| platform_devices::hardware_ids(aml_context, device).iter().any(|id| id == "ACPI0007"))
+    })
+}
+
+/// Each entry of `_CST` is a package of the register used to enter the state, the type of the state (`C1`,
+/// `C2`, or `C3`), its worst-case exit latency in microseconds, and its average power consumption.
+fn parse_cst_entry(entry: &AmlValue, mwait_usable: bool) -> Option<IdleState> {
+    let AmlValue::Package(fields) = entry else {
+        return None;
+    };
+    let [register, AmlValue::Integer(typ), AmlValue::Integer(latency), ..] = fields.as_slice() else {
+        return None;
+    };
+    let (address_space, class, address) = generic_register(register)?;
+
+    let entry = match address_space {
+        /*
+         * Functional fixed hardware registers with a class of `1` describe native C-states entered with
+         * `mwait`, and the address is the hint to pass it.
+         */
+        FUNCTIONAL_FIXED_HARDWARE if class == 1 && mwait_usable => IdleEntry::Mwait(address as u32),
+        _ if *typ == 1 => IdleEntry::Halt,
+        /*
+         * Entering C3 through an I/O port requires us to manage bus master arbitration and flush the caches
+         * ourselves, so we only use C2 this way.
+         */
+        SYSTEM_IO if *typ == 2 => IdleEntry::IoPort(address as u16),
+        _ => return None,
+    };
+    Some(IdleState { entry, latency: Duration::from_micros(*latency) })
+}
+
+/// Each entry of `_PSS` is a package of the state's frequency in MHz, its power consumption, its transition
+/// latencies, and the values to write to the control register and expect in the status register.
+fn parse_pss_entry(entry: &AmlValue) -> Option<(u64, u64)> {
+    let AmlValue::Package(fields) = entry else {
+        return None;
+    };
+    match fields.as_slice() {
+        [AmlValue::Integer(frequency), _, _, _, AmlValue::Integer(control), _] => Some((*frequency, *control)),
+        _ => None,
+    }
+}
+
+/// Decode a buffer containing a Generic Register Descriptor, returning its address space, its class (which is
+/// where the register's bit offset is for other address spaces), and its address.
+fn generic_register(value: &AmlValue) -> Option<(u8, u8, u64)> {
+    let AmlValue::Buffer(buffer) = value else {
+        return None;
+    };
+    let buffer = buffer.lock();
+    if buffer.len() < 15 || buffer[0] != 0x82 {
+        return None;
+    }
+    let address = u64::from_le_bytes(buffer[7..15].try_into().unwrap());
+    Some((buffer[3], buffer[5], address))
+}
@@ -0,0 +1,47 @@
+//! Random numbers from the processor's hardware random number generator, through `rdrand`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use hal_x86_64::hw::cpu::CpuInfo;
+use tracing::info;
+
+static RDRAND_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// `rdrand` can fail if the generator is being used faster than it can produce random numbers. Intel recommends
+/// retrying up to ten times before giving up.
+const RDRAND_RETRIES: usize = 10;
+
+/// Find out if the processor supports `rdrand`. This must be called on the bootstrap processor, and we assume the
+/// other processors are the same.
+pub fn init(cpu_info: &CpuInfo) {
+    if cpu_info.supported_features.rdrand {
+        RDRAND_SUPPORTED.store(true, Ordering::Relaxed);
+    } else {
+        info!("Processor does not support rdrand");
+    }
+}
+
+/// Get a random number from `rdrand`. Returns `None` if it's not supported, or if it keeps failing.
+pub fn rdrand() -> Option<u64> {
+    if !RDRAND_SUPPORTED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let succeeded: u8;
+        unsafe {
+            // `rdrand` sets the carry flag if it succeeded
+            core::arch::asm!(
+                "rdrand {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) succeeded,
+                options(nomem, nostack)
+            );
+        }
+        if succeeded != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
@@ -0,0 +1,177 @@
+//! Driver for the CMOS real-time clock found on PC-compatible platforms. It's accessed through a pair of IO ports,
+//! and keeps the time in a set of registers, either in binary or BCD, depending on how the firmware configured it.
+
+use core::time::Duration;
+use hal_x86_64::hw::port::Port;
+use kernel::rtc::{DateTime, Rtc};
+use spinning_top::Spinlock;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+/// Set in the index port to disable NMIs. We keep them disabled while accessing the RTC, as the firmware expects.
+const NMI_DISABLE: u8 = 1 << 7;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0a;
+const REGISTER_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// In 12-hour mode, this bit of the hours register is set for PM.
+const HOURS_PM: u8 = 1 << 7;
+
+/// The year the century is assumed to start at, if the firmware doesn't tell us where the century is stored.
+const DEFAULT_CENTURY: u16 = 2000;
+
+pub struct CmosRtc {
+    /// The CMOS register the century is stored in, as given by the FADT. `None` if the RTC doesn't store it.
+    century_register: Option<u8>,
+    lock: Spinlock<()>,
+}
+
+impl CmosRtc {
+    pub fn new(century_register: Option<u8>) -> CmosRtc {
+        CmosRtc { century_register, lock: Spinlock::new(()) }
+    }
+
+    fn read_date_time(&self) -> DateTime {
+        /*
+         * The registers can change while we read them, so we wait for any update to finish, and then read them
+         * until we get the same value twice in a row.
+         */
+        let mut date_time = self.read_registers();
+        loop {
+            let next = self.read_registers();
+            if next == date_time {
+                break;
+            }
+            date_time = next;
+        }
+
+        let status_b = read_register(REGISTER_STATUS_B);
+        let decode = |value: u8| if status_b & STATUS_B_BINARY != 0 { value } else { from_bcd(value) };
+
+        let (mut hour, pm) = if status_b & STATUS_B_24_HOUR != 0 {
+            (decode(date_time.hour), false)
+        } else {
+            (decode(date_time.hour & !HOURS_PM), date_time.hour & HOURS_PM != 0)
+        };
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // In 12-hour mode, midnight and noon are both hour 12
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year = match self.century_register {
+            Some(register) => decode(read_register(register)) as u16 * 100,
+            None => DEFAULT_CENTURY,
+        } + decode(date_time.year) as u16;
+
+        DateTime {
+            year,
+            month: decode(date_time.month),
+            day: decode(date_time.day),
+            hour,
+            minute: decode(date_time.minute),
+            second: decode(date_time.second),
+        }
+    }
+
+    /// Read the raw values of the time registers. The year is only the year within the century.
+    fn read_registers(&self) -> RawDateTime {
+        while read_register(REGISTER_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        RawDateTime {
+            year: read_register(REGISTER_YEAR),
+            month: read_register(REGISTER_MONTH),
+            day: read_register(REGISTER_DAY),
+            hour: read_register(REGISTER_HOURS),
+            minute: read_register(REGISTER_MINUTES),
+            second: read_register(REGISTER_SECONDS),
+        }
+    }
+}
+
+impl Rtc for CmosRtc {
+    fn read(&self) -> Duration {
+        let _guard = self.lock.lock();
+        let date_time = self.read_date_time();
+        // If the RTC holds a nonsensical time (e.g. because its battery is flat), start from the epoch
+        Duration::from_secs(date_time.to_unix_timestamp().unwrap_or(0))
+    }
+
+    fn write(&self, time: Duration) -> Result<(), ()> {
+        let date_time = DateTime::from_unix_timestamp(time.as_secs());
+        let (century, year) = (date_time.year / 100, date_time.year % 100);
+        if self.century_register.is_none() && century * 100 != DEFAULT_CENTURY {
+            return Err(());
+        }
+
+        let _guard = self.lock.lock();
+        let status_b = read_register(REGISTER_STATUS_B);
+        let encode = |value: u8| if status_b & STATUS_B_BINARY != 0 { value } else { to_bcd(value) };
+        let hour = if status_b & STATUS_B_24_HOUR != 0 {
+            encode(date_time.hour)
+        } else {
+            let hour_12 = if date_time.hour % 12 == 0 { 12 } else { date_time.hour % 12 };
+            encode(hour_12) | if date_time.hour >= 12 { HOURS_PM } else { 0 }
+        };
+
+        // Stop the RTC from updating the registers while we write them
+        write_register(REGISTER_STATUS_B, status_b | STATUS_B_SET);
+        write_register(REGISTER_SECONDS, encode(date_time.second));
+        write_register(REGISTER_MINUTES, encode(date_time.minute));
+        write_register(REGISTER_HOURS, hour);
+        write_register(REGISTER_DAY, encode(date_time.day));
+        write_register(REGISTER_MONTH, encode(date_time.month));
+        write_register(REGISTER_YEAR, encode(year as u8));
+        if let Some(register) = self.century_register {
+            write_register(register, encode(century as u8));
+        }
+        write_register(REGISTER_STATUS_B, status_b & !STATUS_B_SET);
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawDateTime {
+    year: u8,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(INDEX_PORT).write(NMI_DISABLE | register);
+        Port::<u8>::new(DATA_PORT).read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(INDEX_PORT).write(NMI_DISABLE | register);
+        Port::<u8>::new(DATA_PORT).write(value);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
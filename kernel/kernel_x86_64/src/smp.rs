@@ -0,0 +1,222 @@
+//! This module brings up the application processors (APs) described by the MADT. Each AP is started with the
+//! INIT-SIPI-SIPI sequence, which starts it in real mode at a trampoline we copy below 1MiB. The trampoline moves
+//! the AP into long mode with the kernel's page tables, and jumps to `ap_entry`, which finishes initializing the
+//! processor and then starts scheduling tasks on it.
+
+use crate::{
+    clock::spin_delay,
+    interrupts::InterruptController,
+    per_cpu::PerCpuImpl,
+    task,
+    topo::{self, Topology},
+    PlatformImpl,
+    KERNEL_PAGE_TABLES,
+};
+use bit_field::BitField;
+use core::{
+    arch::{asm, global_asm},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use hal::memory::{Flags, Frame, PAddr, Page, PageTable, Size4KiB, VAddr};
+use hal_x86_64::{
+    hw::{
+        cpu::CpuInfo,
+        gdt::MAX_CPUS,
+        registers::{read_control_reg, CR4_ENABLE_GLOBAL_PAGES, CR4_ENABLE_LA57, CR4_ENABLE_PAE},
+        tss::Tss,
+    },
+    kernel_map,
+    paging::PagingMode,
+};
+use tracing::{info, warn};
+
+global_asm!(include_str!("ap_trampoline.s"));
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_long_mode_target: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdt_base: u8;
+    static ap_trampoline_data: u8;
+}
+
+/// The layout of the data area at the end of the trampoline. This must match `ap_trampoline.s`.
+#[repr(C)]
+struct ApTrampolineData {
+    page_table: u64,
+    stack_pointer: u64,
+    entry_point: u64,
+    cpu_id: u64,
+    /// The value to load into `CR4` before paging is enabled. This sets the paging mode, so it has to match the
+    /// BSP's.
+    cr4: u64,
+}
+
+/// Size of the kernel stack each AP runs on until it switches to its first task.
+const AP_STACK_SIZE: usize = 0x4000;
+
+/// Set by each AP once it has been initialized, so the BSP knows it can move on to the next one.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The number of CPUs we're going to try to run on. We can't support more CPUs than we have space
+/// for TSSs in the GDT, so we ignore any APs above that.
+pub fn num_cpus(topology: &Topology) -> usize {
+    usize::min(1 + topology.application_processors.len(), MAX_CPUS)
+}
+
+/// Bring up each AP in turn. This must be called by the BSP once the scheduler has been created,
+/// as each AP starts scheduling tasks as soon as it has been initialized.
+pub fn boot_application_processors(topology: &Topology) {
+    if topology.application_processors.is_empty() {
+        return;
+    }
+
+    let pmm = kernel::PMM.get();
+    let trampoline_physical =
+        pmm.alloc_below(1, PAddr::new(0x10_0000).unwrap()).expect("No memory below 1MiB for AP trampoline!");
+    let trampoline_virtual = kernel_map::physical_to_virtual(trampoline_physical);
+
+    /*
+     * Copy the trampoline into place, and patch the physical addresses it needs into it.
+     */
+    let (offset_of, trampoline_len) = unsafe {
+        let start = &raw const ap_trampoline_start as usize;
+        let offset_of = move |symbol: *const u8| symbol as usize - start;
+        (offset_of, offset_of(&raw const ap_trampoline_end))
+    };
+    assert!(trampoline_len <= Size4KiB::SIZE);
+    unsafe {
+        ptr::copy_nonoverlapping(&raw const ap_trampoline_start, trampoline_virtual.mut_ptr(), trampoline_len);
+
+        let long_mode_physical = usize::from(trampoline_physical) + offset_of(&raw const ap_trampoline_long_mode);
+        ptr::write_unaligned(
+            (trampoline_virtual + offset_of(&raw const ap_trampoline_long_mode_target)).mut_ptr::<u32>(),
+            long_mode_physical as u32,
+        );
+        let gdt_physical = usize::from(trampoline_physical) + offset_of(&raw const ap_trampoline_gdt);
+        ptr::write_unaligned(
+            (trampoline_virtual + offset_of(&raw const ap_trampoline_gdt_base)).mut_ptr::<u32>(),
+            gdt_physical as u32,
+        );
+    }
+    let data =
+        unsafe { (trampoline_virtual + offset_of(&raw const ap_trampoline_data)).mut_ptr::<ApTrampolineData>() };
+
+    /*
+     * The trampoline enables paging with the kernel's page tables while it's still running from its physical
+     * address, so it needs to be identity-mapped until all the APs are up.
+     */
+    let identity_page = Page::<Size4KiB>::starts_with(VAddr::new(usize::from(trampoline_physical)));
+    KERNEL_PAGE_TABLES
+        .get()
+        .write()
+        .map(
+            identity_page,
+            Frame::starts_with(trampoline_physical),
+            Flags { executable: true, ..Default::default() },
+            pmm,
+        )
+        .unwrap();
+
+    let page_table = read_control_reg!(cr3);
+    assert!(page_table < 0x1_0000_0000, "Kernel page tables must be below 4GiB to start APs");
+    let mut cr4 = 0u64;
+    cr4.set_bit(CR4_ENABLE_PAE, true);
+    cr4.set_bit(CR4_ENABLE_GLOBAL_PAGES, true);
+    cr4.set_bit(CR4_ENABLE_LA57, PagingMode::current() == PagingMode::FiveLevel);
+
+    let local_apic = crate::interrupts::local_apic();
+    for processor in topology.application_processors.iter().take(num_cpus(topology) - 1) {
+        let stack = kernel::VMM
+            .get()
+            .alloc_kernel_stack::<PlatformImpl>(AP_STACK_SIZE, pmm, &mut KERNEL_PAGE_TABLES.get().write())
+            .expect("Failed to allocate kernel stack for AP");
+
+        unsafe {
+            ptr::write_volatile(
+                data,
+                ApTrampolineData {
+                    page_table,
+                    stack_pointer: usize::from(stack.top.align_down(16)) as u64,
+                    entry_point: ap_entry as usize as u64,
+                    cpu_id: processor.id as u64,
+                    cr4,
+                },
+            );
+        }
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        /*
+         * Start the processor with the INIT-SIPI-SIPI sequence. The second SIPI is only sent if the
+         * processor didn't start after the first one, as recommended by the Intel manual.
+         */
+        let vector_page = (usize::from(trampoline_physical) / Size4KiB::SIZE) as u8;
+        unsafe {
+            local_apic.send_init_ipi(processor.local_apic_id);
+            spin_delay(Duration::from_millis(10));
+            local_apic.send_startup_ipi(processor.local_apic_id, vector_page);
+            if !wait_for_ap(Duration::from_millis(1)) {
+                local_apic.send_startup_ipi(processor.local_apic_id, vector_page);
+            }
+        }
+
+        if !wait_for_ap(Duration::from_millis(1000)) {
+            warn!("Application processor with local APIC id {} failed to start!", processor.local_apic_id);
+        }
+    }
+
+    KERNEL_PAGE_TABLES.get().write().unmap(identity_page);
+    pmm.free(trampoline_physical, 1);
+}
+
+/// Wait for the AP being started to signal that it's running. Returns `false` if it didn't
+/// start within `timeout`.
+fn wait_for_ap(timeout: Duration) -> bool {
+    let deadline = crate::clock::now() + timeout;
+    while crate::clock::now() < deadline {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    AP_STARTED.load(Ordering::SeqCst)
+}
+
+/// This is jumped to by the trampoline, on the stack allocated for this AP. At this point, we're in long mode with
+/// the kernel's page tables, but on the trampoline's GDT and without an IDT.
+extern "C" fn ap_entry(cpu_id: usize) -> ! {
+    unsafe {
+        hal_x86_64::hw::gdt::GDT.lock().load();
+    }
+
+    /*
+     * Install a TSS and the per-CPU data for this processor, like we do for the BSP.
+     */
+    let tss = crate::interrupts::create_tss();
+    let tss_selector = hal_x86_64::hw::gdt::GDT.lock().add_tss(cpu_id, tss.as_ref() as *const Tss);
+    unsafe {
+        asm!("ltr ax", in("ax") tss_selector.0);
+    }
+    PerCpuImpl::install(cpu_id, tss);
+
+    let cpu_info = CpuInfo::new();
+    topo::check_support_and_enable_features(&cpu_info);
+    crate::power::init_cpu();
+
+    let mut interrupt_controller = InterruptController::init_application_processor();
+    unsafe {
+        asm!("sti");
+    }
+    interrupt_controller.enable_local_timer(&cpu_info, crate::interrupts::LOCAL_TIMER_PERIOD);
+    crate::tlb::init_cpu(crate::interrupts::local_apic().id());
+
+    task::install_syscall_handler();
+
+    info!("CPU {} is up", cpu_id);
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    crate::SCHEDULER.get().start_scheduling()
+}
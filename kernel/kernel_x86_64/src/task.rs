@@ -1,6 +1,7 @@
+use crate::fpu::FpuState;
 use core::{arch::global_asm, mem, ptr};
 use hal::memory::VAddr;
-use hal_x86_64::hw::registers::{write_msr, CpuFlags};
+use hal_x86_64::hw::registers::{write_msr, CpuFlags, IA32_FS_BASE};
 use kernel::memory::vmm::Stack;
 
 global_asm!(include_str!("task.s"));
@@ -65,12 +66,24 @@ pub struct ContextSwitchFrame {
 /// The context stored for each task. We track the user and kernel stack pointers, as we need to
 /// keep the per-CPU versions of each of them coordinated with the scheduled task. On x64, the
 /// context switch frame is stored on the kernel stack itself, so doesn't need to be stored here.
+///
+/// We also track the task's thread pointer, which is loaded into the FS base when the task is switched to.
+/// Userspace can't change the FS base itself, so this doesn't need to be saved when switching away from a task.
+/// The task's FPU and SIMD registers are saved and restored separately - see the `fpu` module.
 pub struct TaskContext {
     kernel_stack_pointer: VAddr,
     user_stack_pointer: VAddr,
+    thread_pointer: usize,
+    fpu_state: FpuState,
 }
 
-pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> TaskContext {
+pub fn new_task_context(
+    kernel_stack: &Stack,
+    user_stack: &Stack,
+    task_entry_point: VAddr,
+    argument: usize,
+    thread_pointer: usize,
+) -> TaskContext {
     /*
      * These are the set of flags we enter the task for the first time with. We allow, set the parity flag to
      * even, and leave everything else unset.
@@ -108,7 +121,8 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
                 r15: usize::from(task_entry_point) as u64,
                 // TODO: if we keep the new flags thing, revisit this
                 r14: INITIAL_RFLAGS.into(),
-                r13: 0x0,
+                // Moved into `rdi` on entry to userspace
+                r13: argument as u64,
                 r12: 0x0,
                 rbp: 0x0,
                 rbx: 0x0,
@@ -117,7 +131,7 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
         );
     }
 
-    TaskContext { kernel_stack_pointer, user_stack_pointer }
+    TaskContext { kernel_stack_pointer, user_stack_pointer, thread_pointer, fpu_state: FpuState::new() }
 }
 
 pub unsafe fn context_switch(from_context: *mut TaskContext, to_context: *const TaskContext) {
@@ -125,6 +139,11 @@ pub unsafe fn context_switch(from_context: *mut TaskContext, to_context: *const
     (*from_context).user_stack_pointer = per_cpu.user_stack_pointer();
     per_cpu.set_user_stack_pointer((*to_context).user_stack_pointer);
     per_cpu.set_kernel_stack_pointer((*to_context).kernel_stack_pointer);
+    unsafe {
+        write_msr(IA32_FS_BASE, (*to_context).thread_pointer as u64);
+        (*from_context).fpu_state.save();
+        (*to_context).fpu_state.restore();
+    }
     do_context_switch(&raw mut (*from_context).kernel_stack_pointer, (*to_context).kernel_stack_pointer);
 }
 
@@ -132,6 +151,10 @@ pub unsafe fn drop_into_userspace(context: *const TaskContext) -> ! {
     let per_cpu = unsafe { crate::per_cpu::get_per_cpu_data() };
     per_cpu.set_kernel_stack_pointer((*context).kernel_stack_pointer);
     per_cpu.set_user_stack_pointer((*context).user_stack_pointer);
+    unsafe {
+        write_msr(IA32_FS_BASE, (*context).thread_pointer as u64);
+        (*context).fpu_state.restore();
+    }
     do_drop_to_usermode();
 }
 
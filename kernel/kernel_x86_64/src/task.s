@@ -9,6 +9,8 @@
  *     - `r15` is moved into `rcx`
  *     - `r14` is moved into `r11`
  *
+ * The task's entry point is also passed an argument in `rdi`, which is moved from `r13`.
+ *
  * We also need to switch to the task's user stack, which we access through the per-CPU data.
  */
 .global task_entry_trampoline
@@ -23,13 +25,14 @@ task_entry_trampoline:
     xor r15, r15
     mov r11, r14
     xor r14, r14
+    mov rdi, r13
+    xor r13, r13
 
     // Zero all registers not zerod as part of the context load, to avoid leaking kernel data into userspace
-    // XXX: leave `rcx` and `r11` alone as they're needed for `sysret`
+    // XXX: leave `rcx` and `r11` alone as they're needed for `sysret`, and `rdi` as it holds the argument
     xor rax, rax
     xor rdx, rdx
     xor rsi, rsi
-    xor rdi, rdi
     xor r8, r8
     xor r9, r9
     xor r10, r10
@@ -59,15 +62,18 @@ do_drop_to_usermode:
     mov gs:0x8, rsp
     mov rsp, gs:0x10
 
+    // Pass the task's argument in `rdi` (see `task_entry_trampoline`)
+    mov rdi, r13
+    xor r13, r13
+
     /*
      * Zero all registers that weren't zeroed as part of the context load, except rcx and r11, as they're needed by
-     * `sysret`. We also zero `r14` and `r15`, which would normally be loaded from the saved context but weren't
-     * because we use them to populate `r11` and `rcx` instead.
+     * `sysret`, and rdi, which holds the argument. We also zero `r14` and `r15`, which would normally be loaded
+     * from the saved context but weren't because we use them to populate `r11` and `rcx` instead.
      */
     xor rax, rax
     xor rdx, rdx
     xor rsi, rsi
-    xor rdi, rdi
     xor r8, r8
     xor r9, r9
     xor r10, r10
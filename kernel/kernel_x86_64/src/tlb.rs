@@ -0,0 +1,55 @@
+//! TLB shootdowns. When memory is unmapped from an address space that's active on other processors, their TLBs
+//! might still hold entries for it, so we ask each of them to flush its TLB, and wait for them to do so, before
+//! the memory can be freed.
+//!
+//! The request is sent as an NMI rather than an IPI with a vector, because the processor asking might be holding
+//! a lock that the others are spinning on with interrupts disabled (e.g. while they're handling a page fault).
+//! Each processor has a count of the shootdowns that have been requested of it, and a count of those it has
+//! completed, so requests that arrive while a processor is already flushing its TLB are handled together.
+
+use crate::per_cpu;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use hal_x86_64::hw::{gdt::MAX_CPUS, tlb};
+
+static LOCAL_APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+static REQUESTED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static COMPLETED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Record the local APIC id of the running processor, so other processors can send it shootdowns. This must be
+/// called on each processor once its per-CPU data and local APIC have been set up.
+pub fn init_cpu(local_apic_id: u32) {
+    LOCAL_APIC_IDS[per_cpu::cpu_id()].store(local_apic_id, Ordering::SeqCst);
+}
+
+/// Flush the TLB of each processor in `cpus`, and wait for them all to do so. This must not include the running
+/// processor.
+pub fn shootdown(cpus: u64) {
+    let local_apic = crate::interrupts::local_apic();
+    let mut targets = [0; MAX_CPUS];
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpus & (1 << cpu) != 0) {
+        targets[cpu] = REQUESTED[cpu].fetch_add(1, Ordering::SeqCst) + 1;
+        unsafe {
+            local_apic.send_nmi(LOCAL_APIC_IDS[cpu].load(Ordering::SeqCst));
+        }
+    }
+
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpus & (1 << cpu) != 0) {
+        while COMPLETED[cpu].load(Ordering::SeqCst) < targets[cpu] {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Handle any shootdowns that have been requested of the running processor. This is called from the NMI handler,
+/// and returns `false` if there weren't any, in which case the NMI came from somewhere else.
+pub fn handle_nmi() -> bool {
+    let cpu = per_cpu::cpu_id();
+    let requested = REQUESTED[cpu].load(Ordering::SeqCst);
+    if COMPLETED[cpu].load(Ordering::SeqCst) >= requested {
+        return false;
+    }
+
+    tlb::flush();
+    COMPLETED[cpu].fetch_max(requested, Ordering::SeqCst);
+    true
+}
@@ -71,15 +71,22 @@ impl Topology {
 }
 
 /// We rely on certain processor features to be present for simplicity and sanity-retention. This
-/// function checks that we support everything we need to, and enable features that we need.
-fn check_support_and_enable_features(cpu_info: &CpuInfo) {
+/// function checks that we support everything we need to, and enable features that we need. It must be called
+/// on every processor we bring up.
+pub fn check_support_and_enable_features(cpu_info: &CpuInfo) {
     use bit_field::BitField;
     use hal_x86_64::hw::registers::{
         read_control_reg,
         read_msr,
         write_control_reg,
         write_msr,
+        CR0_EMULATE_COPROCESSOR,
+        CR0_MONITOR_COPROCESSOR,
+        CR0_NUMERIC_ERROR,
+        CR0_TASK_SWITCHED,
         CR4_ENABLE_GLOBAL_PAGES,
+        CR4_ENABLE_SIMD_EXCEPTIONS,
+        CR4_ENABLE_SSE,
         CR4_RESTRICT_RDTSC,
         CR4_XSAVE_ENABLE_BIT,
         EFER,
@@ -91,13 +98,30 @@ fn check_support_and_enable_features(cpu_info: &CpuInfo) {
         panic!("Processor does not support xsave instruction!");
     }
 
+    /*
+     * Userspace can use the FPU and SIMD instructions, so make sure they don't fault, and that errors in them are
+     * reported with exceptions, which are delivered to the task.
+     */
+    let mut cr0 = read_control_reg!(CR0);
+    cr0.set_bit(CR0_EMULATE_COPROCESSOR, false);
+    cr0.set_bit(CR0_TASK_SWITCHED, false);
+    cr0.set_bit(CR0_MONITOR_COPROCESSOR, true);
+    cr0.set_bit(CR0_NUMERIC_ERROR, true);
+    unsafe {
+        write_control_reg!(CR0, cr0);
+    }
+
     let mut cr4 = read_control_reg!(CR4);
     cr4.set_bit(CR4_XSAVE_ENABLE_BIT, true);
+    cr4.set_bit(CR4_ENABLE_SSE, true);
+    cr4.set_bit(CR4_ENABLE_SIMD_EXCEPTIONS, true);
     cr4.set_bit(CR4_ENABLE_GLOBAL_PAGES, true);
-    cr4.set_bit(CR4_RESTRICT_RDTSC, true);
+    // Userspace reads the TSC directly to get the time through the vDSO page, so it must be allowed to use `rdtsc`
+    cr4.set_bit(CR4_RESTRICT_RDTSC, false);
     unsafe {
         write_control_reg!(CR4, cr4);
     }
+    crate::fpu::enable(cpu_info);
 
     let mut efer = read_msr(EFER);
     efer.set_bit(EFER_ENABLE_SYSCALL, true);
@@ -0,0 +1,101 @@
+//! Crash reports, which the platform writes to the serial port when the kernel panics, or takes a fault it can't
+//! recover from. Every line of a report starts with `CRASH`, so reports can be picked out of a log by whatever
+//! ran the kernel:
+//! ```text
+//! CRASH BEGIN
+//! CRASH MESSAGE {message}
+//! CRASH LOCATION {file}:{line}:{column}
+//! CRASH CPU {cpu}
+//! CRASH TASK {id} {name}
+//! CRASH SLIDE {slide}
+//! CRASH REGISTER {name} {value}
+//! CRASH FRAME #{n}: {address}
+//! CRASH END
+//! ```
+//! A message that spans multiple lines has a `MESSAGE` line for each of them. There's a `REGISTER` line for each
+//! register the platform has, and a `FRAME` line for each frame of the backtrace, which are in the format
+//! `cargo xtask symbolicate` understands. The `SLIDE` line gives how far the kernel has been moved from the
+//! address it was linked at, which `symbolicate` subtracts from the frames that follow it. The location, CPU,
+//! task, and slide are left out if they aren't known (e.g. if the kernel crashes before the scheduler has been
+//! created, or while a CPU isn't running a task).
+
+use crate::{object::KernelObject, scheduler::Scheduler, Platform};
+use core::{
+    fmt::{self, Write},
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use mulch::backtrace::Backtrace;
+
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+pub struct Crash<'a> {
+    pub message: &'a dyn fmt::Display,
+    pub location: Option<&'a Location<'a>>,
+    /// The registers of the context that crashed, by name. When the kernel panics, this is whatever the platform
+    /// can usefully report from the panic handler. When it takes a fault, this is the state it faulted in.
+    pub registers: &'a [(&'static str, usize)],
+    pub backtrace: &'a Backtrace,
+}
+
+/// Mark the kernel as having crashed. Returns `false` if it already had - the platform shouldn't try to report a
+/// crash that happens while reporting another one, as it's likely to crash in the same way again.
+pub fn begin() -> bool {
+    !CRASHED.swap(true, Ordering::SeqCst)
+}
+
+/// Write a report of `crash` to `writer`. The running task is found through `scheduler`, if it's been created.
+pub fn write_report<P, W>(writer: &mut W, crash: &Crash, scheduler: Option<&Scheduler<P>>) -> fmt::Result
+where
+    P: Platform,
+    W: fmt::Write,
+{
+    writeln!(writer, "CRASH BEGIN")?;
+
+    write!(writer, "CRASH MESSAGE ")?;
+    write!(MessageWriter(writer), "{}", crash.message)?;
+    writeln!(writer)?;
+
+    if let Some(location) = crash.location {
+        writeln!(writer, "CRASH LOCATION {}:{}:{}", location.file(), location.line(), location.column())?;
+    }
+
+    // The platform's per-CPU data is installed before the scheduler is created
+    if let Some(scheduler) = scheduler {
+        writeln!(writer, "CRASH CPU {}", P::cpu_id())?;
+        if let Some(task) = scheduler.try_running_task() {
+            writeln!(writer, "CRASH TASK {} {}", u64::from(task.id()), task.name)?;
+        }
+    }
+
+    if let Some(slide) = crate::KERNEL_SLIDE.try_get() {
+        writeln!(writer, "CRASH SLIDE {:#x}", slide)?;
+    }
+
+    for (name, value) in crash.registers {
+        writeln!(writer, "CRASH REGISTER {} {:#018x}", name, value)?;
+    }
+    for (i, address) in crash.backtrace.frames().iter().enumerate() {
+        writeln!(writer, "CRASH FRAME #{}: {:#x}", i, address)?;
+    }
+
+    writeln!(writer, "CRASH END")
+}
+
+/// Writes a message, starting a new `MESSAGE` line for each line of it.
+struct MessageWriter<'a, W: fmt::Write>(&'a mut W);
+
+impl<'a, W> fmt::Write for MessageWriter<'a, W>
+where
+    W: fmt::Write,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                write!(self.0, "\nCRASH MESSAGE ")?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
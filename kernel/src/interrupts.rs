@@ -0,0 +1,133 @@
+use alloc::collections::BTreeSet;
+use core::ops::Range;
+use mulch::InitGuard;
+use spinning_top::Spinlock;
+
+/// The platform's interrupt vector allocator. This must be initialized by the platform, with the range of vectors
+/// it can deliver and with any vectors already in use (e.g. by wired interrupts) reserved, before anything tries
+/// to allocate vectors from it.
+pub static INTERRUPT_VECTORS: InitGuard<Spinlock<InterruptVectorAllocator>> = InitGuard::uninit();
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VectorAllocError {
+    OutOfRange,
+    AlreadyInUse,
+}
+
+/// Tracks which of a platform's interrupt vectors are free to be handed out to devices. What a vector means is
+/// up to the platform - on x86_64, it's an index into the IDT, and on RISC-V it's an interrupt identity of the
+/// IMSIC (or a source of the PLIC).
+pub struct InterruptVectorAllocator {
+    range: Range<u32>,
+    used: BTreeSet<u32>,
+}
+
+impl InterruptVectorAllocator {
+    /// Create an allocator that can allocate any of the vectors in `range`.
+    pub fn new(range: Range<u32>) -> InterruptVectorAllocator {
+        InterruptVectorAllocator { range, used: BTreeSet::new() }
+    }
+
+    /// Mark a single vector as used, so it's not allocated to anything else. Fails if the vector is outside the
+    /// range of the allocator, or has already been allocated or reserved.
+    pub fn reserve(&mut self, vector: u32) -> Result<(), VectorAllocError> {
+        if !self.range.contains(&vector) {
+            return Err(VectorAllocError::OutOfRange);
+        }
+        if !self.used.insert(vector) {
+            return Err(VectorAllocError::AlreadyInUse);
+        }
+        Ok(())
+    }
+
+    /// Mark a range of vectors as used. This is useful for reserving the vectors used by wired interrupts, which
+    /// are generally described as a range (e.g. by the device tree, or by an IOAPIC's redirection entries), and
+    /// so, unlike `reserve`, this ignores vectors outside the allocator's range and vectors that are already
+    /// reserved.
+    pub fn reserve_range(&mut self, range: Range<u32>) {
+        let start = u32::max(range.start, self.range.start);
+        let end = u32::min(range.end, self.range.end);
+        self.used.extend(start..end);
+    }
+
+    /// Allocate a single free vector.
+    pub fn allocate(&mut self) -> Option<u32> {
+        self.allocate_block(1)
+    }
+
+    /// Allocate a block of `count` contiguous vectors, returning the first vector of the block. The block is
+    /// aligned to `count`, which must be a power of two, as this is required by multiple-message MSI (the device
+    /// sets the low bits of the message data to the number of the message it's sending).
+    pub fn allocate_block(&mut self, count: u32) -> Option<u32> {
+        assert!(count.is_power_of_two());
+
+        let mut start = self.range.start.next_multiple_of(count);
+        while start.checked_add(count)? <= self.range.end {
+            match (start..(start + count)).find(|vector| self.used.contains(vector)) {
+                Some(used) => start = (used + 1).next_multiple_of(count),
+                None => {
+                    self.used.extend(start..(start + count));
+                    return Some(start);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Return a vector to the allocator, so it can be allocated again.
+    pub fn free(&mut self, vector: u32) {
+        self.free_block(vector, 1);
+    }
+
+    /// Return a block of vectors previously allocated with `allocate_block`.
+    pub fn free_block(&mut self, start: u32, count: u32) {
+        for vector in start..(start + count) {
+            assert!(self.used.remove(&vector), "Tried to free interrupt vector that isn't in use: {}", vector);
+        }
+    }
+
+    pub fn is_used(&self, vector: u32) -> bool {
+        self.used.contains(&vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_reserved() {
+        let mut allocator = InterruptVectorAllocator::new(1..8);
+        allocator.reserve_range(0..3);
+        assert_eq!(allocator.reserve(4), Ok(()));
+        assert_eq!(allocator.reserve(4), Err(VectorAllocError::AlreadyInUse));
+        assert_eq!(allocator.reserve(8), Err(VectorAllocError::OutOfRange));
+
+        assert_eq!(allocator.allocate(), Some(3));
+        assert_eq!(allocator.allocate(), Some(5));
+        assert_eq!(allocator.allocate(), Some(6));
+        assert_eq!(allocator.allocate(), Some(7));
+        assert_eq!(allocator.allocate(), None);
+
+        allocator.free(5);
+        assert!(!allocator.is_used(5));
+        assert_eq!(allocator.allocate(), Some(5));
+    }
+
+    #[test]
+    fn test_allocate_block() {
+        let mut allocator = InterruptVectorAllocator::new(0x30..0x50);
+        allocator.reserve(0x31).unwrap();
+
+        assert_eq!(allocator.allocate_block(4), Some(0x34));
+        assert_eq!(allocator.allocate_block(8), Some(0x38));
+        assert_eq!(allocator.allocate_block(16), Some(0x40));
+        assert_eq!(allocator.allocate_block(2), Some(0x32));
+        assert_eq!(allocator.allocate_block(2), None);
+        assert_eq!(allocator.allocate(), Some(0x30));
+
+        allocator.free_block(0x38, 8);
+        assert_eq!(allocator.allocate_block(8), Some(0x38));
+    }
+}
@@ -0,0 +1,38 @@
+//! IOMMUs translate the addresses devices use to access memory through DMA, which allows the kernel to restrict
+//! the memory each device can access. Devices are isolated by attaching them to a `DmaDomain`, after which they
+//! can only access memory that has been allocated in that domain.
+//!
+//! Memory is mapped into domains at its physical address, so bus addresses are the same as physical addresses.
+//! Devices that are not attached to a domain can access all of physical memory, so drivers that don't use
+//! domains keep working.
+
+use alloc::boxed::Box;
+use hal::memory::PAddr;
+use pci_types::PciAddress;
+
+/// Implemented by platforms that have an IOMMU. Each platform is responsible for discovering its IOMMU, and
+/// passing it to the kernel with `kernel::initialize_iommu` before PCI devices are enumerated.
+pub trait Iommu: Send + Sync {
+    /// Let a device access all of physical memory until it is attached to a domain. This is called for each PCI
+    /// device before the IOMMU is enabled.
+    fn add_device(&self, device: PciAddress);
+
+    /// Start translating DMA from devices. This is called once all PCI devices have been added.
+    fn enable(&self);
+
+    fn create_domain(&self) -> Box<dyn IommuDomain>;
+}
+
+/// The translations shared by the devices attached to a `DmaDomain`.
+pub trait IommuDomain: Send + Sync {
+    /// Allow devices attached to this domain to access `size` bytes of memory, starting at the physical address
+    /// `address`.
+    fn map(&self, address: PAddr, size: usize, writable: bool);
+
+    /// Attach a device to this domain. Returns `false` if the device's DMA is not translated by this IOMMU, in
+    /// which case it can still access all of physical memory.
+    fn attach(&self, device: PciAddress) -> bool;
+
+    /// Detach a device from this domain, so it can access all of physical memory again.
+    fn detach(&self, device: PciAddress);
+}
@@ -0,0 +1,130 @@
+//! The kernel log keeps a copy of everything the kernel logs in a ring buffer, so that tasks with the
+//! `READ_KERNEL_LOG` capability can read it with the `read_kernel_log` system call (e.g. to show the kernel's log on
+//! a display, rather than only on the serial port). Once the buffer is full, the oldest records are overwritten.
+
+use core::fmt;
+use spinning_top::Spinlock;
+
+pub const KERNEL_LOG_SIZE: usize = 64 * 1024;
+
+pub static KERNEL_LOG: Spinlock<KernelLog<KERNEL_LOG_SIZE>> = Spinlock::new(KernelLog::new());
+
+/// A ring buffer of log output. Positions in the log count the bytes that have ever been written to it, so a reader
+/// can keep track of how far through the log it's read, and can tell if the bytes it wanted to read next have
+/// already been overwritten.
+pub struct KernelLog<const N: usize> {
+    buffer: [u8; N],
+    /// The position the next byte written to the log will be at. The byte at position `p` is kept at
+    /// `buffer[p % N]` until the head reaches `p + N`.
+    head: u64,
+}
+
+impl<const N: usize> KernelLog<N> {
+    pub const fn new() -> KernelLog<N> {
+        KernelLog { buffer: [0; N], head: 0 }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        // Only the last `N` bytes would survive, so don't bother copying the rest
+        let skip = bytes.len().saturating_sub(N);
+        self.head += skip as u64;
+
+        for &byte in &bytes[skip..] {
+            self.buffer[(self.head % N as u64) as usize] = byte;
+            self.head += 1;
+        }
+    }
+
+    /// The position of the oldest byte still in the log.
+    pub fn tail(&self) -> u64 {
+        self.head.saturating_sub(N as u64)
+    }
+
+    /// Read the log from position `from` into `buffer`. If `from` has already been overwritten, the read starts
+    /// from the oldest byte still in the log instead. Returns the position the read started at, and the number of
+    /// bytes read.
+    pub fn read(&self, from: u64, buffer: &mut [u8]) -> (u64, usize) {
+        let start = u64::min(u64::max(from, self.tail()), self.head);
+        let length = usize::min(buffer.len(), (self.head - start) as usize);
+
+        for (i, byte) in buffer[0..length].iter_mut().enumerate() {
+            *byte = self.buffer[((start + i as u64) % N as u64) as usize];
+        }
+        (start, length)
+    }
+}
+
+impl<const N: usize> fmt::Write for KernelLog<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Wraps the writer the kernel's log is usually written to (e.g. the serial port), so that everything written to
+/// it is also written to the kernel log.
+pub struct LogWriter<'a, W>
+where
+    W: fmt::Write,
+{
+    writer: &'a mut W,
+}
+
+impl<'a, W> LogWriter<'a, W>
+where
+    W: fmt::Write,
+{
+    pub fn new(writer: &'a mut W) -> LogWriter<'a, W> {
+        LogWriter { writer }
+    }
+}
+
+impl<'a, W> fmt::Write for LogWriter<'a, W>
+where
+    W: fmt::Write,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        KERNEL_LOG.lock().write(s.as_bytes());
+        self.writer.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_and_write() {
+        let mut log = KernelLog::<8>::new();
+        let mut buffer = [0; 8];
+        assert_eq!(log.read(0, &mut buffer), (0, 0));
+
+        log.write(b"abc");
+        assert_eq!(log.read(0, &mut buffer), (0, 3));
+        assert_eq!(&buffer[0..3], b"abc");
+        assert_eq!(log.read(1, &mut buffer[0..1]), (1, 1));
+        assert_eq!(&buffer[0..1], b"b");
+        assert_eq!(log.read(3, &mut buffer), (3, 0));
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let mut log = KernelLog::<8>::new();
+        let mut buffer = [0; 8];
+
+        log.write(b"abcdef");
+        log.write(b"ghijk");
+        assert_eq!(log.tail(), 3);
+        // Reads from positions that have been overwritten skip ahead to the oldest byte
+        assert_eq!(log.read(0, &mut buffer), (3, 8));
+        assert_eq!(&buffer, b"defghijk");
+        assert_eq!(log.read(9, &mut buffer), (9, 2));
+        assert_eq!(&buffer[0..2], b"jk");
+
+        // Writes longer than the buffer only keep their end
+        log.write(b"0123456789");
+        assert_eq!(log.tail(), 13);
+        assert_eq!(log.read(0, &mut buffer), (13, 8));
+        assert_eq!(&buffer, b"23456789");
+    }
+}
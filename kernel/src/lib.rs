@@ -11,41 +11,81 @@
 #[macro_use]
 extern crate alloc;
 
+pub mod crash;
+pub mod interrupts;
+pub mod iommu;
+pub mod klog;
+pub mod loader;
 pub mod memory;
 pub mod object;
 pub mod pci;
+pub mod per_cpu;
+pub mod perf;
+pub mod platform_devices;
+pub mod power;
+pub mod profile;
+pub mod random;
+pub mod rtc;
 pub mod scheduler;
 pub mod syscall;
 pub mod tasklets;
+#[cfg(feature = "test_runner")]
+pub mod test_runner;
+pub mod trace;
+pub mod vdso;
 
 use alloc::{boxed::Box, string::ToString, sync::Arc, vec::Vec};
+use core::time::Duration;
 use hal::memory::{FrameSize, PAddr, PageTable, Size4KiB, VAddr};
-use memory::{vmm::Stack, Pmm, Vmm};
+use iommu::Iommu;
+use memory::{heap::KernelHeap, vmm::Stack, Pmm, Vmm};
 use mulch::InitGuard;
 use object::{address_space::AddressSpace, memory_object::MemoryObject, task::Task};
 use pci::{PciInfo, PciInterruptConfigurator, PciResolver};
 use pci_types::ConfigRegionAccess as PciConfigRegionAccess;
+use platform_devices::PlatformDevice;
+pub use poplar::syscall::{DmaDirection, PerfEvent};
+use rtc::{Rtc, WallClock};
 use scheduler::Scheduler;
 use seed::boot_info::BootInfo;
 use spinning_top::{RwSpinlock, Spinlock};
 
-#[cfg(not(test))]
-#[global_allocator]
-pub static ALLOCATOR: linked_list_allocator::LockedHeap = linked_list_allocator::LockedHeap::empty();
+#[cfg_attr(not(test), global_allocator)]
+pub static ALLOCATOR: KernelHeap = KernelHeap::empty();
 
 pub static PMM: InitGuard<Pmm> = InitGuard::uninit();
 pub static VMM: InitGuard<Vmm> = InitGuard::uninit();
 pub static FRAMEBUFFER: InitGuard<(poplar::syscall::FramebufferInfo, Arc<MemoryObject>)> = InitGuard::uninit();
 pub static PCI_INFO: RwSpinlock<Option<PciInfo>> = RwSpinlock::new(None);
 pub static PCI_ACCESS: InitGuard<Option<Spinlock<Box<dyn PciConfigRegionAccess + Send>>>> = InitGuard::uninit();
+pub static IOMMU: InitGuard<Box<dyn Iommu>> = InitGuard::uninit();
+pub static PLATFORM_DEVICES: RwSpinlock<Vec<PlatformDevice>> = RwSpinlock::new(Vec::new());
+pub static WALL_CLOCK: InitGuard<WallClock> = InitGuard::uninit();
+/// How far the loader moved the kernel from the address it was linked at. This needs to be subtracted from
+/// addresses in the kernel image before they'll match up with its symbols.
+pub static KERNEL_SLIDE: InitGuard<usize> = InitGuard::uninit();
 
 pub trait Platform: Sized + 'static {
     type PageTableSize: FrameSize;
     type PageTable: PageTable<Self::PageTableSize> + Send;
     type TaskContext;
 
-    /// Create a `TaskContext` for a new task with the supplied kernel and user stacks.
-    fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> Self::TaskContext;
+    /// Get the ID of the CPU we're currently running on. CPU IDs are allocated contiguously from
+    /// `0`, which is always the boot processor. This must not be called before the platform has
+    /// installed its per-CPU data on the running CPU.
+    fn cpu_id() -> usize;
+
+    /// Create a `TaskContext` for a new task with the supplied kernel and user stacks. The task starts executing
+    /// at `task_entry_point`, with `argument` in the register used to pass the first argument to a function. The
+    /// task's thread pointer (used to find its TLS block) is set to `thread_pointer`, and must be restored each
+    /// time the task is switched to.
+    fn new_task_context(
+        kernel_stack: &Stack,
+        user_stack: &Stack,
+        task_entry_point: VAddr,
+        argument: usize,
+        thread_pointer: usize,
+    ) -> Self::TaskContext;
 
     /// Do the arch-dependent part of the context switch. This should save the context of the
     /// currently running task into `from_context`, and restore `to_context` to start executing.
@@ -56,7 +96,97 @@ pub trait Platform: Sized + 'static {
 
     // TODO: this should not exist long-term. The common kernel VMM should know about the direct
     // physical mapping and should be able to write to physical memory itself.
+    unsafe fn read_from_phys_memory(address: PAddr, data: &mut [u8]);
     unsafe fn write_to_phys_memory(address: PAddr, data: &[u8]);
+    unsafe fn copy_phys_memory(from: PAddr, to: PAddr, size: usize);
+    unsafe fn zero_phys_memory(address: PAddr, size: usize);
+
+    /// Get the time since boot from the platform's high-resolution monotonic clock. Unlike the uptime tracked by
+    /// the tasklet scheduler, this is not limited to the granularity of the timer interrupt. It must be safe to
+    /// call from any CPU, and must never go backwards.
+    fn monotonic_time() -> Duration;
+
+    /// Get 64 bits of entropy from the platform's hardware random number generator. Returns `None` if the
+    /// platform doesn't have one, or if it couldn't produce a value. This is used to seed and reseed the kernel's
+    /// random number generator (see `random`), so it must be safe to call from any CPU.
+    fn hardware_random() -> Option<u64>;
+
+    /// Make an area of physical memory coherent between the CPU and devices accessing it through DMA, by cleaning
+    /// and/or invalidating the CPU's caches as `direction` requires. Platforms where DMA is cache-coherent only
+    /// need to order memory accesses.
+    unsafe fn sync_dma(address: PAddr, size: usize, direction: DmaDirection);
+
+    /// Invalidate the TLB entries for userspace addresses on each CPU whose bit is set in `cpus` (bit `n` for CPU
+    /// `n`), and wait until all of them have done so. The page tables only invalidate the running CPU's TLB, so
+    /// this is used when memory is unmapped from (or made less accessible in) an address space that's active on
+    /// other CPUs, before the memory can be freed. It can be called with locks held and interrupts disabled, so
+    /// the other CPUs must respond even if they have interrupts disabled.
+    fn shootdown_tlb(cpus: u64);
+
+    /// Returns `true` if the CPU's performance counters can count all of `events` at the same time. Platforms
+    /// without performance counters should return `false` for any events.
+    fn perf_supports(events: &[PerfEvent]) -> bool;
+
+    /// Start counting `events` on the running CPU from zero, only while it's running in userspace. This is only
+    /// called with events that `perf_supports` has accepted. The platform puts whatever it needs to find the
+    /// counter of each event again into `counters`.
+    unsafe fn perf_start(events: &[PerfEvent], counters: &mut [usize]);
+
+    /// Read the current values of the counters started by `perf_start` on the running CPU.
+    fn perf_read(counters: &[usize], counts: &mut [u64]);
+
+    /// Stop the counters started by `perf_start` on the running CPU.
+    unsafe fn perf_stop(counters: &[usize]);
+
+    /// Put the running CPU into a low-power state until an interrupt arrives. The platform chooses how deep a
+    /// state to enter. This is called with interrupts enabled when the CPU is idling between tasks, but may also
+    /// be called with them disabled, in which case it can return as soon as an interrupt is pending (or straight
+    /// away, if the CPU can't be woken by a masked interrupt).
+    fn idle();
+
+    /// Ask for the running CPU to run at `performance`% of its maximum performance. This is called by the
+    /// frequency governor (see `power::Governor`). Platforms that can't control the CPU's frequency can ignore it.
+    fn set_cpu_performance(performance: u8);
+
+    /// Power the platform off. This only returns if the platform can't be powered off, or didn't power off when
+    /// asked to.
+    fn power_off();
+
+    /// Reset the platform. This only returns if the platform can't be reset, or didn't reset when asked to.
+    fn reset();
+
+    /// Exit QEMU with a status that tells whatever ran it whether the kernel's tests passed. This is only used by
+    /// the test runner, and so only needs to work when the kernel is running in QEMU.
+    #[cfg(feature = "test_runner")]
+    fn exit_qemu(passed: bool) -> !;
+}
+
+/// Create caches for the kernel objects that are allocated most often, so they're allocated from slabs rather
+/// than from the general-purpose heap. This should be called as soon as the heap has been initialized.
+pub fn initialize_object_caches<P>()
+where
+    P: Platform,
+{
+    use memory::heap::arc_layout;
+    use object::{channel::ChannelEnd, event::Event};
+
+    ALLOCATOR.register_cache("task", arc_layout::<Task<P>>());
+    ALLOCATOR.register_cache("channel", arc_layout::<ChannelEnd>());
+    ALLOCATOR.register_cache("event", arc_layout::<Event>());
+}
+
+/// Seed the kernel's random number generator, and turn off address space layout randomization if the loader
+/// has asked us to. This must be called once the platform's monotonic clock is running, and before any address
+/// spaces are created.
+pub fn initialize_random<P>(boot_info: &BootInfo)
+where
+    P: Platform,
+{
+    random::init::<P>();
+    if boot_info.disable_aslr {
+        tracing::info!("Address space layout randomization is disabled");
+        object::address_space::disable_aslr();
+    }
 }
 
 pub fn load_userspace<P>(scheduler: &Scheduler<P>, boot_info: &BootInfo, kernel_page_table: &mut P::PageTable)
@@ -64,8 +194,11 @@ where
     P: Platform,
 {
     use hal::memory::Flags;
-    use object::{task::Handles, SENTINEL_KERNEL_ID};
-    use poplar::manifest::BootstrapManifest;
+    use object::{job::Job, task::Handles, SENTINEL_KERNEL_ID};
+    use poplar::{caps::Capabilities, manifest::BootstrapManifest, syscall::Priority, HandleRights};
+
+    #[cfg(feature = "test_runner")]
+    test_runner::start::<P>(boot_info);
 
     if boot_info.loaded_images.is_empty() {
         return;
@@ -76,18 +209,23 @@ where
     let address_space = AddressSpace::new(SENTINEL_KERNEL_ID, kernel_page_table, pmm);
     let handles = Handles::new();
 
+    // TODO: boot tasks don't get TLS yet, as the loader doesn't pass their TLS segment's size and alignment on to
+    // us. Once it does, it should become the address space's TLS template, like in `loader::load_image`.
     for segment in &bootstrap_task.segments {
         // TODO: this now uses the wrong task id...
         let memory_object = MemoryObject::from_boot_info(SENTINEL_KERNEL_ID, segment);
         handles.add(memory_object.clone());
-        address_space.map_memory_object(memory_object, segment.virtual_address, pmm).unwrap();
+        address_space.map_memory_object(memory_object, segment.virtual_address, true, pmm).unwrap();
     }
 
     /*
      * Add other loaded tasks' segments to the bootstrap task and add each task to the manifest.
      */
-    let mut manifest =
-        BootstrapManifest { task_name: bootstrap_task.name.as_str().to_string(), boot_tasks: Vec::new() };
+    let mut manifest = BootstrapManifest {
+        task_name: bootstrap_task.name.as_str().to_string(),
+        boot_tasks: Vec::new(),
+        initramfs: None,
+    };
     for image in &boot_info.loaded_images[1..] {
         let mut service = poplar::manifest::BootTask {
             name: image.name.as_str().to_string(),
@@ -102,32 +240,50 @@ where
         }
         manifest.boot_tasks.push(service);
     }
-    let mut buffer = Vec::new();
-    let bytes_written = ptah::to_wire(&manifest, &mut buffer).unwrap();
 
-    const MANIFEST_ADDRESS: VAddr = VAddr::new(0x20000000);
-    let mem_object_len = mulch::math::align_up(bytes_written, 0x1000);
-    let manifest_object = {
-        let phys = pmm.alloc(mem_object_len / Size4KiB::SIZE);
-        unsafe {
-            P::write_to_phys_memory(phys, &(bytes_written as u32).to_le_bytes());
-            P::write_to_phys_memory(phys + 4, &buffer);
-        }
-        MemoryObject::new(
+    /*
+     * Hand the initramfs to the bootstrap task, if Seed loaded one. It can't be written to, so tasks can share it
+     * without being able to interfere with each other.
+     */
+    if let Some(initramfs) = boot_info.initramfs {
+        let memory_object = MemoryObject::new(
             SENTINEL_KERNEL_ID,
-            phys,
-            mem_object_len,
+            initramfs.address,
+            mulch::math::align_up(initramfs.size, Size4KiB::SIZE),
             Flags { user_accessible: true, ..Default::default() },
-        )
-    };
-    address_space.map_memory_object(manifest_object, MANIFEST_ADDRESS, pmm).unwrap();
+        );
+        let handle = handles.add_with_rights(
+            memory_object,
+            HandleRights::READ | HandleRights::MAP | HandleRights::DUPLICATE | HandleRights::TRANSFER,
+        );
+        manifest.initramfs = Some((handle.0, initramfs.size));
+    }
+
+    map_manifest(SENTINEL_KERNEL_ID, &manifest, &address_space, pmm).unwrap();
+
+    /*
+     * The bootstrap task is started in the root job. Every other task is created in it, or in one of its
+     * descendants.
+     */
+    let root_job = Job::new(SENTINEL_KERNEL_ID, None);
+
+    /*
+     * When an allocation can't be satisfied, memory is reclaimed from the discardable memory objects of every
+     * task before we give up.
+     */
+    let reclaim_from = root_job.clone();
+    pmm.add_reclaimer(Box::new(move |allocator: &Pmm, count| reclaim_from.discard_memory(allocator, count)));
 
     let task = Task::new(
         SENTINEL_KERNEL_ID,
         address_space.clone(),
+        root_job,
         bootstrap_task.name.to_string(),
         bootstrap_task.entry_point,
         handles,
+        // The bootstrap task is trusted, and hands out capabilities to the tasks it starts
+        Capabilities::all(),
+        Priority::default(),
         pmm,
         kernel_page_table,
     )
@@ -135,6 +291,39 @@ where
     scheduler.add_task(task);
 }
 
+/// Encode a task's manifest, and map it into the task's address space at `poplar::manifest::MANIFEST_ADDRESS`.
+pub fn map_manifest<P, M>(
+    owner: object::KernelObjectId,
+    manifest: &M,
+    address_space: &Arc<AddressSpace<P>>,
+    allocator: &Pmm,
+) -> Result<(), poplar::syscall::MapMemoryObjectError>
+where
+    P: Platform,
+    M: ptah::Serialize,
+{
+    use hal::memory::Flags;
+
+    let mut buffer = Vec::new();
+    let bytes_written = ptah::to_wire(manifest, &mut buffer).unwrap();
+
+    let mem_object_len = mulch::math::align_up(bytes_written + 4, Size4KiB::SIZE);
+    let manifest_object = {
+        let phys = allocator.alloc(mem_object_len / Size4KiB::SIZE);
+        unsafe {
+            P::write_to_phys_memory(phys, &(bytes_written as u32).to_le_bytes());
+            P::write_to_phys_memory(phys + 4, &buffer);
+        }
+        MemoryObject::new(owner, phys, mem_object_len, Flags { user_accessible: true, ..Default::default() })
+    };
+    address_space.map_memory_object(
+        manifest_object,
+        VAddr::new(poplar::manifest::MANIFEST_ADDRESS),
+        true,
+        allocator,
+    )
+}
+
 pub fn create_framebuffer(video_info: &seed::boot_info::VideoModeInfo) {
     use hal::memory::{Flags, Size4KiB};
     use poplar::syscall::{FramebufferInfo, PixelFormat};
@@ -169,10 +358,43 @@ where
     A: PciConfigRegionAccess + PciInterruptConfigurator + Send + 'static,
 {
     let (access, info) = PciResolver::resolve(access);
+
+    /*
+     * Now we know about all the PCI devices, the IOMMU can start translating their DMA. Devices can access all of
+     * physical memory until they're attached to a `DmaDomain`.
+     */
+    if let Some(iommu) = IOMMU.try_get() {
+        for &address in info.devices.keys() {
+            iommu.add_device(address);
+        }
+        iommu.enable();
+    }
+
     *PCI_INFO.write() = Some(info);
     PCI_ACCESS.initialize(Some(Spinlock::new(Box::new(access))));
 }
 
+/// Register the platform's IOMMU. This must be done before PCI devices are enumerated with `initialize_pci`.
+pub fn initialize_iommu<I>(iommu: I)
+where
+    I: Iommu + 'static,
+{
+    IOMMU.initialize(Box::new(iommu));
+}
+
+/// Register the platform's real-time clock, which the kernel uses to keep track of wall-clock time.
+pub fn initialize_rtc<R>(rtc: R)
+where
+    R: Rtc + 'static,
+{
+    WALL_CLOCK.initialize(WallClock::new(Box::new(rtc)));
+}
+
+/// Register the devices described by the platform's firmware, so they can be handed out to userspace drivers.
+pub fn initialize_platform_devices(devices: Vec<PlatformDevice>) {
+    *PLATFORM_DEVICES.write() = devices;
+}
+
 #[cfg(not(test))]
 #[alloc_error_handler]
 fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
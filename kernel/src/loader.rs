@@ -0,0 +1,474 @@
+//! Tasks created with `task_create` are loaded from an ELF image held in a `MemoryObject`. We only need a small
+//! part of the ELF format to do this (the file header, and the program headers of loadable segments), so we
+//! parse it ourselves, reading the image through the memory object's pages rather than mapping it.
+//!
+//! Images can either be linked at a fixed address, or be position-independent executables, which are loaded at a
+//! random address in `IMAGE_REGION_BOTTOM..IMAGE_REGION_TOP` (unless ASLR has been disabled) and then relocated.
+//! Position-independent images are statically linked, so we only need to support the relative relocations the
+//! linker produces for them.
+//!
+//! Loadable segments are usually copied into new memory. If the image can't be changed by the task creating the
+//! new one (e.g. because it's a mapping of a file from the VFS), read-only segments that don't need relocating
+//! are instead shared with the image, through copy-on-write clones of the parts of it they're in.
+
+use crate::{
+    memory::Pmm,
+    object::{
+        address_space::{
+            aslr_enabled,
+            aslr_offset,
+            AddressSpace,
+            TlsTemplate,
+            IMAGE_REGION_BOTTOM,
+            IMAGE_REGION_TOP,
+            MAX_TLS_SIZE,
+        },
+        job::Job,
+        memory_object::MemoryObject,
+        KernelObjectId,
+    },
+    Platform,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::convert::TryInto;
+use hal::memory::{Flags, FrameSize, PAddr, Size4KiB, VAddr};
+use mulch::math::{align_down, align_up};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_EXECUTABLE: u16 = 2;
+/// Position-independent executables have the same type as shared objects.
+const ELF_TYPE_POSITION_INDEPENDENT: u16 = 3;
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        const ELF_MACHINE: u16 = 62;
+        const RELOCATION_RELATIVE: u32 = 8;
+    } else if #[cfg(target_arch = "riscv64")] {
+        const ELF_MACHINE: u16 = 243;
+        const RELOCATION_RELATIVE: u32 = 3;
+    } else if #[cfg(target_arch = "aarch64")] {
+        const ELF_MACHINE: u16 = 183;
+        const RELOCATION_RELATIVE: u32 = 1027;
+    }
+}
+
+const FILE_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SEGMENT_TYPE_LOAD: u32 = 1;
+const SEGMENT_TYPE_DYNAMIC: u32 = 2;
+const SEGMENT_TYPE_TLS: u32 = 7;
+const SEGMENT_FLAG_EXECUTABLE: u32 = 1 << 0;
+const SEGMENT_FLAG_WRITABLE: u32 = 1 << 1;
+
+const DYNAMIC_ENTRY_SIZE: usize = 16;
+const DYNAMIC_TAG_NULL: u64 = 0;
+const DYNAMIC_TAG_RELA: u64 = 7;
+const DYNAMIC_TAG_RELA_SIZE: u64 = 8;
+const DYNAMIC_TAG_RELA_ENTRY_SIZE: u64 = 9;
+/// Images that use these kinds of relocations can't be loaded - they're only produced for dynamic linking.
+const DYNAMIC_TAG_REL: u64 = 17;
+const DYNAMIC_TAG_JMPREL: u64 = 23;
+const DYNAMIC_TAG_RELR: u64 = 36;
+const RELA_SIZE: usize = 24;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoadError {
+    /// The image is not a 64-bit, little-endian ELF executable for this architecture.
+    NotAnElf,
+    /// A header or segment extends past the end of the image.
+    Truncated,
+    /// A loadable segment does not start at the beginning of a page.
+    UnalignedSegment,
+    /// A loadable segment could not be mapped into the new address space (e.g. because it overlaps another).
+    CannotMapSegment,
+    /// A loadable segment is not in the part of the address space userspace can use.
+    SegmentNotInUserSpace,
+    /// Loading the image would exceed the memory limit of the job it's being loaded for.
+    MemoryLimitExceeded,
+    /// There isn't enough free memory to load the image.
+    OutOfMemory,
+    /// The TLS segment is too large, or needs to be aligned to more than a page.
+    InvalidTls,
+    /// A position-independent image is too large to fit in the region images are loaded into.
+    ImageTooLarge,
+    /// A position-independent image needs a relocation other than a relative relocation.
+    UnsupportedRelocation,
+    /// A relocation, or the table of relocations, is not inside one of the image's loadable segments.
+    InvalidRelocation,
+    /// A loadable segment is both writable and executable.
+    WritableAndExecutable,
+}
+
+/// A program header, with the fields we use.
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    file_offset: usize,
+    virtual_address: usize,
+    file_size: usize,
+    mem_size: usize,
+    align: usize,
+}
+
+/// A relative relocation, which needs the bias adding to `addend`, and the result writing to `offset` (an
+/// address before relocation).
+struct Relocation {
+    offset: usize,
+    addend: u64,
+}
+
+/// A loadable segment that has been copied into memory, at its address before relocation.
+struct LoadedSegment {
+    virtual_address: usize,
+    size: usize,
+    physical_start: PAddr,
+}
+
+/// Load the ELF image held in `image` (which contains `image_size` bytes of data) into `address_space`. Each
+/// loadable segment is copied into a new `MemoryObject`, owned by `owner` and charged to `job`, unless `share` is
+/// set and the segment can be shared with the image instead (see the module documentation). `share` should only be
+/// set if the image won't be changed while the new task is running. If the image has a TLS segment, it becomes the
+/// address space's TLS template. Position-independent images are loaded at a random address and relocated.
+/// Returns the image's entry point.
+pub fn load_image<P>(
+    owner: KernelObjectId,
+    job: &Arc<Job<P>>,
+    image: &Arc<MemoryObject>,
+    image_size: usize,
+    share: bool,
+    address_space: &Arc<AddressSpace<P>>,
+    allocator: &Pmm,
+) -> Result<VAddr, LoadError>
+where
+    P: Platform,
+{
+    let image_size = image_size.min(image.size);
+
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    read_image::<P>(image, image_size, 0, &mut header)?;
+    let image_type = read_u16(&header, 16);
+    if header[0..4] != ELF_MAGIC
+        || header[4] != ELF_CLASS_64
+        || header[5] != ELF_DATA_LITTLE_ENDIAN
+        || (image_type != ELF_TYPE_EXECUTABLE && image_type != ELF_TYPE_POSITION_INDEPENDENT)
+        || read_u16(&header, 18) != ELF_MACHINE
+    {
+        return Err(LoadError::NotAnElf);
+    }
+
+    let entry_point = read_u64(&header, 24) as usize;
+    let program_headers_offset = read_u64(&header, 32) as usize;
+    let program_header_size = read_u16(&header, 54) as usize;
+    let num_program_headers = read_u16(&header, 56) as usize;
+    if program_header_size < PROGRAM_HEADER_SIZE {
+        return Err(LoadError::NotAnElf);
+    }
+
+    let mut program_headers = Vec::with_capacity(num_program_headers);
+    for i in 0..num_program_headers {
+        let mut program_header = [0u8; PROGRAM_HEADER_SIZE];
+        let offset = i
+            .checked_mul(program_header_size)
+            .and_then(|offset| offset.checked_add(program_headers_offset))
+            .ok_or(LoadError::Truncated)?;
+        read_image::<P>(image, image_size, offset, &mut program_header)?;
+
+        program_headers.push(ProgramHeader {
+            segment_type: read_u32(&program_header, 0),
+            flags: read_u32(&program_header, 4),
+            file_offset: read_u64(&program_header, 8) as usize,
+            virtual_address: read_u64(&program_header, 16) as usize,
+            file_size: read_u64(&program_header, 32) as usize,
+            mem_size: read_u64(&program_header, 40) as usize,
+            align: read_u64(&program_header, 48) as usize,
+        });
+    }
+
+    /*
+     * Position-independent images are moved by `bias` bytes from the addresses they're linked at. When ASLR is
+     * disabled, they're loaded at the addresses they're linked at, so addresses in them can be symbolicated
+     * without knowing where they were loaded.
+     */
+    let bias = if image_type == ELF_TYPE_POSITION_INDEPENDENT && aslr_enabled() {
+        let loadable = program_headers.iter().filter(|header| header.segment_type == SEGMENT_TYPE_LOAD);
+        let bottom = loadable.clone().map(|header| header.virtual_address).min().unwrap_or(0);
+        let top = loadable.map(|header| header.virtual_address.saturating_add(header.mem_size)).max().unwrap_or(0);
+        let bottom = align_down(bottom, Size4KiB::SIZE);
+        let span = align_up(top - bottom, Size4KiB::SIZE);
+
+        let region_size = usize::from(IMAGE_REGION_TOP) + 1 - usize::from(IMAGE_REGION_BOTTOM);
+        let range = region_size.checked_sub(span).ok_or(LoadError::ImageTooLarge)?;
+        let base = usize::from(IMAGE_REGION_BOTTOM) + aslr_offset::<P>(range);
+        base.wrapping_sub(bottom)
+    } else {
+        0
+    };
+
+    /*
+     * The relocations of position-independent images are read before their segments are loaded, so we know
+     * which segments need relocating, and so can't be shared with the image.
+     */
+    let dynamic_segment = program_headers.iter().find(|header| header.segment_type == SEGMENT_TYPE_DYNAMIC);
+    let relocations = match dynamic_segment {
+        Some(header) if image_type == ELF_TYPE_POSITION_INDEPENDENT => {
+            if header.file_size > image_size {
+                return Err(LoadError::Truncated);
+            }
+            let mut dynamic = vec![0; header.file_size];
+            read_image::<P>(image, image_size, header.file_offset, &mut dynamic)?;
+            read_relocations::<P>(image, image_size, &dynamic, &program_headers)?
+        }
+        _ => Vec::new(),
+    };
+    // Discardable memory can be freed from under the task, so it can't hold the task's code
+    let share = share && !image.is_discardable();
+
+    let mut loaded_segments = Vec::new();
+    for header in &program_headers {
+        let &ProgramHeader { segment_type, flags, file_offset, virtual_address, file_size, mem_size, align } =
+            header;
+
+        if segment_type == SEGMENT_TYPE_TLS {
+            if file_size > mem_size || mem_size > MAX_TLS_SIZE || align > Size4KiB::SIZE {
+                return Err(LoadError::InvalidTls);
+            }
+
+            let mut data = vec![0; file_size];
+            read_image::<P>(image, image_size, file_offset, &mut data)?;
+            *address_space.tls_template.lock() = Some(TlsTemplate { data, size: mem_size, align });
+            continue;
+        }
+        if segment_type != SEGMENT_TYPE_LOAD || mem_size == 0 {
+            continue;
+        }
+        if virtual_address % Size4KiB::SIZE != 0 {
+            return Err(LoadError::UnalignedSegment);
+        }
+        if file_size > mem_size || file_offset.checked_add(file_size).map_or(true, |end| end > image_size) {
+            return Err(LoadError::Truncated);
+        }
+        let writable = flags & SEGMENT_FLAG_WRITABLE != 0;
+        let executable = flags & SEGMENT_FLAG_EXECUTABLE != 0;
+        if writable && executable {
+            return Err(LoadError::WritableAndExecutable);
+        }
+
+        /*
+         * The segment has to be in the part of the address space userspace can use. This is checked before we
+         * make a `VAddr` from its address, as that would quietly make a non-canonical address canonical.
+         */
+        let address = virtual_address.wrapping_add(bias);
+        if !address_space.is_in_user_space(address, mem_size) {
+            return Err(LoadError::SegmentNotInUserSpace);
+        }
+
+        let size = align_up(mem_size, Size4KiB::SIZE);
+        let flags = Flags { writable, executable, user_accessible: true, ..Default::default() };
+
+        /*
+         * Read-only segments can be shared with the image if they start at the beginning of a page of it, and
+         * don't need any zeroed memory after their data (which would have to be zeroed in the image). If the
+         * segment ends part-way through a page, the rest of the page is whatever follows it in the image.
+         */
+        let shareable = share
+            && !writable
+            && file_offset % Size4KiB::SIZE == 0
+            && file_size == mem_size
+            && file_offset + size <= image.size
+            && !relocations.iter().any(|relocation| {
+                relocation.offset >= virtual_address && relocation.offset - virtual_address < mem_size
+            });
+
+        let memory_object = if shareable {
+            image.clone_cow_range(owner, file_offset, size, flags)
+        } else {
+            /*
+             * Copy the segment's data into new, zeroed, memory. The segment's data may be smaller than its
+             * size in memory (e.g. to make space for `.bss`), in which case the rest is left zeroed. The memory
+             * object owns the memory, so it's freed (and uncharged) if we fail to load the image.
+             */
+            let charge = job.try_charge_memory(size).ok_or(LoadError::MemoryLimitExceeded)?;
+            let physical_start = allocator.try_alloc(size / Size4KiB::SIZE).ok_or(LoadError::OutOfMemory)?;
+            unsafe {
+                P::zero_phys_memory(physical_start, size);
+            }
+            copy_from_image::<P>(image, file_offset, file_size, physical_start);
+            loaded_segments.push(LoadedSegment { virtual_address, size, physical_start });
+            MemoryObject::new_owned(owner, physical_start, size, flags, charge)
+        };
+
+        address_space
+            .map_memory_object(memory_object, VAddr::new(address), true, allocator)
+            .map_err(|_| LoadError::CannotMapSegment)?;
+    }
+
+    /*
+     * Apply the relocations to the loaded segments, for the `bias` bytes the image has been moved by. They're
+     * written through the segments' physical memory, so they can be applied to read-only segments too.
+     */
+    for relocation in &relocations {
+        let value = relocation.addend.wrapping_add(bias as u64);
+        unsafe {
+            P::write_to_phys_memory(
+                physical_address(&loaded_segments, relocation.offset, 8)?,
+                &value.to_le_bytes(),
+            );
+        }
+    }
+
+    Ok(VAddr::new(entry_point.wrapping_add(bias)))
+}
+
+/// Read the relocations described by the image's dynamic table (`dynamic`). The table of relocations is given by
+/// its address in memory, so it's found in the image through the program headers of its loadable segments.
+fn read_relocations<P>(
+    image: &MemoryObject,
+    image_size: usize,
+    dynamic: &[u8],
+    program_headers: &[ProgramHeader],
+) -> Result<Vec<Relocation>, LoadError>
+where
+    P: Platform,
+{
+    let mut rela_address = None;
+    let mut rela_size = 0;
+    let mut rela_entry_size = RELA_SIZE;
+    for entry in dynamic.chunks_exact(DYNAMIC_ENTRY_SIZE) {
+        let value = read_u64(entry, 8) as usize;
+        match read_u64(entry, 0) {
+            DYNAMIC_TAG_NULL => break,
+            DYNAMIC_TAG_RELA => rela_address = Some(value),
+            DYNAMIC_TAG_RELA_SIZE => rela_size = value,
+            DYNAMIC_TAG_RELA_ENTRY_SIZE => rela_entry_size = value,
+            DYNAMIC_TAG_REL | DYNAMIC_TAG_JMPREL | DYNAMIC_TAG_RELR => {
+                return Err(LoadError::UnsupportedRelocation)
+            }
+            _ => (),
+        }
+    }
+
+    let Some(rela_address) = rela_address else {
+        return Ok(Vec::new());
+    };
+    if rela_entry_size < RELA_SIZE {
+        return Err(LoadError::InvalidRelocation);
+    }
+
+    let rela_offset = program_headers
+        .iter()
+        .find(|header| {
+            header.segment_type == SEGMENT_TYPE_LOAD
+                && rela_address >= header.virtual_address
+                && rela_address
+                    .checked_add(rela_size)
+                    .map_or(false, |end| end <= header.virtual_address + header.file_size)
+        })
+        .map(|header| header.file_offset + (rela_address - header.virtual_address))
+        .ok_or(LoadError::InvalidRelocation)?;
+    if rela_size > image_size {
+        return Err(LoadError::Truncated);
+    }
+    let mut table = vec![0; rela_size];
+    read_image::<P>(image, image_size, rela_offset, &mut table)?;
+
+    let mut relocations = Vec::with_capacity(rela_size / rela_entry_size);
+    for relocation in table.chunks_exact(rela_entry_size) {
+        let offset = read_u64(relocation, 0) as usize;
+        let info = read_u64(relocation, 8);
+        let addend = read_u64(relocation, 16);
+
+        // Relative relocations don't refer to a symbol - they just need the bias adding to them
+        if info as u32 != RELOCATION_RELATIVE || info >> 32 != 0 {
+            return Err(LoadError::UnsupportedRelocation);
+        }
+        relocations.push(Relocation { offset, addend });
+    }
+
+    Ok(relocations)
+}
+
+/// Find the physical memory that `length` bytes at `virtual_address` (before relocation) were loaded into. They
+/// must all be part of the same segment.
+fn physical_address(
+    segments: &[LoadedSegment],
+    virtual_address: usize,
+    length: usize,
+) -> Result<PAddr, LoadError> {
+    segments
+        .iter()
+        .find(|segment| {
+            virtual_address >= segment.virtual_address
+                && virtual_address
+                    .checked_add(length)
+                    .map_or(false, |end| end <= segment.virtual_address + segment.size)
+        })
+        .map(|segment| segment.physical_start + (virtual_address - segment.virtual_address))
+        .ok_or(LoadError::InvalidRelocation)
+}
+
+/// Read `buffer.len()` bytes from `image`, starting at `offset`. Pages of the image that haven't had memory
+/// committed to them yet read as zeros.
+fn read_image<P>(
+    image: &MemoryObject,
+    image_size: usize,
+    offset: usize,
+    buffer: &mut [u8],
+) -> Result<(), LoadError>
+where
+    P: Platform,
+{
+    if offset.checked_add(buffer.len()).map_or(true, |end| end > image_size) {
+        return Err(LoadError::Truncated);
+    }
+
+    let mut done = 0;
+    while done < buffer.len() {
+        let page_offset = align_down(offset + done, Size4KiB::SIZE);
+        let offset_in_page = offset + done - page_offset;
+        let length = (Size4KiB::SIZE - offset_in_page).min(buffer.len() - done);
+
+        match image.page(page_offset) {
+            Some((frame, _)) => unsafe {
+                P::read_from_phys_memory(frame + offset_in_page, &mut buffer[done..(done + length)]);
+            },
+            None => buffer[done..(done + length)].fill(0),
+        }
+        done += length;
+    }
+
+    Ok(())
+}
+
+/// Copy `length` bytes of `image`, starting at `offset`, to the physical memory at `destination`. Pages of the
+/// image that haven't had memory committed to them are skipped, so the destination should already be zeroed.
+fn copy_from_image<P>(image: &MemoryObject, offset: usize, length: usize, destination: PAddr)
+where
+    P: Platform,
+{
+    let mut done = 0;
+    while done < length {
+        let page_offset = align_down(offset + done, Size4KiB::SIZE);
+        let offset_in_page = offset + done - page_offset;
+        let chunk = (Size4KiB::SIZE - offset_in_page).min(length - done);
+
+        if let Some((frame, _)) = image.page(page_offset) {
+            unsafe {
+                P::copy_phys_memory(frame + offset_in_page, destination + done, chunk);
+            }
+        }
+        done += chunk;
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..(offset + 2)].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..(offset + 8)].try_into().unwrap())
+}
@@ -0,0 +1,393 @@
+//! The kernel heap. Most allocations are made by a general-purpose linked-list allocator, but the kernel objects
+//! that are created and destroyed most often (`Task`s, `ChannelEnd`s, and `Event`s) each have an `ObjectCache`,
+//! which packs them into slabs carved out of the heap. This avoids searching the heap's free list for each of
+//! them, and stops them fragmenting it.
+//!
+//! Caches are matched to allocations by their layout, so any other allocation with the same size and alignment
+//! as a cached object is also made from its cache. Allocations made before a cache is registered are freed back
+//! to the heap, as each cache only frees objects that lie inside one of its slabs.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use linked_list_allocator::LockedHeap;
+use poplar::syscall::{ObjectCacheStats, MAX_OBJECT_CACHES};
+use spinning_top::Spinlock;
+
+/// Each slab is sized to fit at least this many objects, so that larger objects don't leave most of a slab unused.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+const MIN_SLAB_SIZE: usize = 0x1000;
+
+pub struct KernelHeap {
+    heap: LockedHeap,
+    caches: [ObjectCache; MAX_OBJECT_CACHES],
+    num_caches: AtomicUsize,
+}
+
+impl KernelHeap {
+    pub const fn empty() -> KernelHeap {
+        const EMPTY_CACHE: ObjectCache = ObjectCache::empty();
+        KernelHeap {
+            heap: LockedHeap::empty(),
+            caches: [EMPTY_CACHE; MAX_OBJECT_CACHES],
+            num_caches: AtomicUsize::new(0),
+        }
+    }
+
+    /// Initialize the heap to manage the `size` bytes of memory starting at `bottom`.
+    ///
+    /// ### Safety
+    /// The memory must be mapped, and not be used for anything else. This must only be called once.
+    pub unsafe fn init(&self, bottom: *mut u8, size: usize) {
+        unsafe {
+            self.heap.lock().init(bottom, size);
+        }
+    }
+
+    /// Create a cache for allocations of `layout`. Caches should be registered early, before many objects of the
+    /// layout are allocated. If all the caches are in use, allocations of `layout` continue to be made from the
+    /// heap.
+    pub fn register_cache(&self, name: &'static str, layout: Layout) {
+        let index = self.num_caches.load(Ordering::Acquire);
+        if index >= MAX_OBJECT_CACHES || self.cache_for(layout).is_some() {
+            return;
+        }
+
+        self.caches[index].init(name, layout);
+        self.num_caches.store(index + 1, Ordering::Release);
+    }
+
+    /// The size of the heap, and how much of it is in use, in bytes.
+    pub fn usage(&self) -> (usize, usize) {
+        let heap = self.heap.lock();
+        (heap.size(), heap.used())
+    }
+
+    /// Get statistics about each of the heap's caches. Returns the number of caches.
+    pub fn cache_stats(&self, stats: &mut [ObjectCacheStats; MAX_OBJECT_CACHES]) -> usize {
+        let num_caches = self.num_caches.load(Ordering::Acquire);
+        for (cache, stats) in self.caches[0..num_caches].iter().zip(stats.iter_mut()) {
+            *stats = cache.stats();
+        }
+        num_caches
+    }
+
+    fn cache_for(&self, layout: Layout) -> Option<&ObjectCache> {
+        let num_caches = self.num_caches.load(Ordering::Acquire);
+        self.caches[0..num_caches].iter().find(|cache| cache.serves(layout))
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.cache_for(layout) {
+            Some(cache) => cache.alloc(&self.heap),
+            None => unsafe { self.heap.alloc(layout) },
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(cache) = self.cache_for(layout) {
+            if cache.free(&self.heap, ptr) {
+                return;
+            }
+        }
+        unsafe { self.heap.dealloc(ptr, layout) }
+    }
+}
+
+/// The layout of the allocation `Arc::new` makes for a `T`. `Arc`'s allocations hold the strong and weak
+/// reference counts, followed by the value.
+pub fn arc_layout<T>() -> Layout {
+    Layout::new::<[AtomicUsize; 2]>().extend(Layout::new::<T>()).unwrap().0.pad_to_align()
+}
+
+/// A cache of objects of a single layout. Objects are allocated from slabs, which are allocated from the heap as
+/// they're needed. Each slab starts with a `SlabHeader`, which is followed by its objects. Free objects hold a
+/// pointer to the next free object in their slab.
+pub struct ObjectCache {
+    /// The size and alignment of the objects this cache serves. These are set once, when the cache is registered,
+    /// so can be read without taking the lock.
+    size: AtomicUsize,
+    align: AtomicUsize,
+    inner: Spinlock<CacheInner>,
+}
+
+struct CacheInner {
+    name: &'static str,
+    /// The size of each object in the slabs. This is at least large enough to hold a pointer to the next free
+    /// object, and keeps each object aligned.
+    object_size: usize,
+    /// The offset of the first object in each slab, after the slab's header.
+    first_object: usize,
+    slab_layout: Layout,
+    slabs: Option<NonNull<SlabHeader>>,
+    num_slabs: usize,
+    /// The number of slabs with no objects allocated from them. We keep one around, so that an object being
+    /// repeatedly allocated and freed doesn't allocate and free a whole slab each time.
+    empty_slabs: usize,
+    objects_in_use: usize,
+}
+
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+// XXX: the slabs are only accessed with the cache's lock held
+unsafe impl Send for CacheInner {}
+
+impl ObjectCache {
+    const fn empty() -> ObjectCache {
+        ObjectCache {
+            size: AtomicUsize::new(0),
+            align: AtomicUsize::new(0),
+            inner: Spinlock::new(CacheInner {
+                name: "",
+                object_size: 0,
+                first_object: 0,
+                slab_layout: Layout::new::<SlabHeader>(),
+                slabs: None,
+                num_slabs: 0,
+                empty_slabs: 0,
+                objects_in_use: 0,
+            }),
+        }
+    }
+
+    fn init(&self, name: &'static str, layout: Layout) {
+        let align = usize::max(layout.align(), mem::align_of::<FreeObject>());
+        let object_size =
+            Layout::from_size_align(usize::max(layout.size(), mem::size_of::<FreeObject>()), align).unwrap();
+        let object_size = object_size.pad_to_align().size();
+        let first_object = Layout::new::<SlabHeader>().align_to(align).unwrap().pad_to_align().size();
+        let slab_size = usize::max(first_object + object_size * MIN_OBJECTS_PER_SLAB, MIN_SLAB_SIZE);
+
+        let mut inner = self.inner.lock();
+        inner.name = name;
+        inner.object_size = object_size;
+        inner.first_object = first_object;
+        inner.slab_layout = Layout::from_size_align(slab_size.next_power_of_two(), align).unwrap();
+
+        self.size.store(layout.size(), Ordering::Relaxed);
+        self.align.store(layout.align(), Ordering::Relaxed);
+    }
+
+    fn serves(&self, layout: Layout) -> bool {
+        self.size.load(Ordering::Relaxed) == layout.size() && self.align.load(Ordering::Relaxed) == layout.align()
+    }
+
+    fn alloc(&self, heap: &LockedHeap) -> *mut u8 {
+        let mut inner = self.inner.lock();
+
+        let slab = match inner.find_slab_with_free_object() {
+            Some(slab) => slab,
+            None => match inner.new_slab(heap) {
+                Some(slab) => slab,
+                None => return ptr::null_mut(),
+            },
+        };
+
+        let slab = unsafe { &mut *slab.as_ptr() };
+        let object = slab.free.unwrap();
+        slab.free = unsafe { object.as_ref().next };
+        if slab.in_use == 0 {
+            inner.empty_slabs -= 1;
+        }
+        slab.in_use += 1;
+        inner.objects_in_use += 1;
+        object.as_ptr() as *mut u8
+    }
+
+    /// Free an object allocated from this cache. Returns `false` if `ptr` doesn't lie inside one of the cache's
+    /// slabs, in which case it must have been allocated from the heap.
+    fn free(&self, heap: &LockedHeap, ptr: *mut u8) -> bool {
+        let mut inner = self.inner.lock();
+        let Some(slab) = inner.find_slab_containing(ptr) else {
+            return false;
+        };
+
+        let object = ptr as *mut FreeObject;
+        let slab_header = unsafe { &mut *slab.as_ptr() };
+        unsafe {
+            object.write(FreeObject { next: slab_header.free });
+        }
+        slab_header.free = NonNull::new(object);
+        slab_header.in_use -= 1;
+        inner.objects_in_use -= 1;
+
+        if slab_header.in_use == 0 {
+            if inner.empty_slabs > 0 {
+                inner.release_slab(heap, slab);
+            } else {
+                inner.empty_slabs += 1;
+            }
+        }
+        true
+    }
+
+    fn stats(&self) -> ObjectCacheStats {
+        let inner = self.inner.lock();
+        let mut name = [0u8; 16];
+        let length = usize::min(inner.name.len(), name.len());
+        name[0..length].copy_from_slice(&inner.name.as_bytes()[0..length]);
+
+        ObjectCacheStats {
+            name,
+            object_size: inner.object_size,
+            objects_in_use: inner.objects_in_use,
+            slabs: inner.num_slabs,
+            slab_size: inner.slab_layout.size(),
+        }
+    }
+}
+
+impl CacheInner {
+    fn slabs(&self) -> impl Iterator<Item = NonNull<SlabHeader>> {
+        let mut next = self.slabs;
+        core::iter::from_fn(move || {
+            let slab = next?;
+            next = unsafe { slab.as_ref().next };
+            Some(slab)
+        })
+    }
+
+    fn find_slab_with_free_object(&self) -> Option<NonNull<SlabHeader>> {
+        self.slabs().find(|slab| unsafe { slab.as_ref().free.is_some() })
+    }
+
+    fn find_slab_containing(&self, ptr: *mut u8) -> Option<NonNull<SlabHeader>> {
+        let slab_size = self.slab_layout.size();
+        self.slabs().find(|slab| {
+            let start = slab.as_ptr() as usize + self.first_object;
+            let end = slab.as_ptr() as usize + slab_size;
+            (start..end).contains(&(ptr as usize))
+        })
+    }
+
+    /// Allocate a new slab from the heap, and add its objects to its free list.
+    fn new_slab(&mut self, heap: &LockedHeap) -> Option<NonNull<SlabHeader>> {
+        let slab = NonNull::new(unsafe { heap.alloc(self.slab_layout) } as *mut SlabHeader)?;
+        let num_objects = (self.slab_layout.size() - self.first_object) / self.object_size;
+
+        let mut free = None;
+        for i in (0..num_objects).rev() {
+            let object =
+                unsafe { slab.as_ptr().byte_add(self.first_object + i * self.object_size) } as *mut FreeObject;
+            unsafe {
+                object.write(FreeObject { next: free });
+            }
+            free = NonNull::new(object);
+        }
+
+        unsafe {
+            slab.as_ptr().write(SlabHeader { next: self.slabs, free, in_use: 0 });
+        }
+        self.slabs = Some(slab);
+        self.num_slabs += 1;
+        self.empty_slabs += 1;
+        Some(slab)
+    }
+
+    /// Remove an empty slab from the cache, and return its memory to the heap.
+    fn release_slab(&mut self, heap: &LockedHeap, slab: NonNull<SlabHeader>) {
+        let next = unsafe { slab.as_ref().next };
+        if self.slabs == Some(slab) {
+            self.slabs = next;
+        } else {
+            let previous = self.slabs().find(|previous| unsafe { previous.as_ref().next } == Some(slab)).unwrap();
+            unsafe {
+                (*previous.as_ptr()).next = next;
+            }
+        }
+
+        self.num_slabs -= 1;
+        unsafe {
+            heap.dealloc(slab.as_ptr() as *mut u8, self.slab_layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[repr(align(0x1000))]
+    struct HeapMemory([u8; 0x20000]);
+
+    fn test_heap(memory: &mut HeapMemory) -> KernelHeap {
+        let heap = KernelHeap::empty();
+        unsafe {
+            heap.init(memory.0.as_mut_ptr(), memory.0.len());
+        }
+        heap
+    }
+
+    #[test]
+    fn test_objects_come_from_cache() {
+        let mut memory = HeapMemory([0; 0x20000]);
+        let heap = test_heap(&mut memory);
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        heap.register_cache("test", layout);
+
+        let objects: Vec<*mut u8> = (0..100).map(|_| unsafe { heap.alloc(layout) }).collect();
+        assert!(objects.iter().all(|object| !object.is_null()));
+
+        let mut stats = [ObjectCacheStats::default(); MAX_OBJECT_CACHES];
+        assert_eq!(heap.cache_stats(&mut stats), 1);
+        assert_eq!(&stats[0].name[0..5], b"test\0");
+        assert_eq!(stats[0].object_size, 48);
+        assert_eq!(stats[0].objects_in_use, 100);
+        assert_eq!(stats[0].slabs, 2);
+
+        // Allocations of other layouts are made from the heap
+        let other_layout = Layout::from_size_align(64, 8).unwrap();
+        let other = unsafe { heap.alloc(other_layout) };
+        assert!(!other.is_null());
+        heap.cache_stats(&mut stats);
+        assert_eq!(stats[0].objects_in_use, 100);
+
+        unsafe {
+            heap.dealloc(other, other_layout);
+            for object in objects {
+                heap.dealloc(object, layout);
+            }
+        }
+        heap.cache_stats(&mut stats);
+        assert_eq!(stats[0].objects_in_use, 0);
+        // One empty slab is kept around
+        assert_eq!(stats[0].slabs, 1);
+    }
+
+    #[test]
+    fn test_allocation_before_registering() {
+        let mut memory = HeapMemory([0; 0x20000]);
+        let heap = test_heap(&mut memory);
+        let layout = Layout::from_size_align(48, 8).unwrap();
+
+        let (_, used_before) = heap.usage();
+        let early = unsafe { heap.alloc(layout) };
+        heap.register_cache("test", layout);
+        let late = unsafe { heap.alloc(layout) };
+
+        // The early allocation should be freed back to the heap, and not pollute the cache
+        unsafe {
+            heap.dealloc(early, layout);
+            heap.dealloc(late, layout);
+        }
+        let mut stats = [ObjectCacheStats::default(); MAX_OBJECT_CACHES];
+        heap.cache_stats(&mut stats);
+        assert_eq!(stats[0].objects_in_use, 0);
+        let (_, used_after) = heap.usage();
+        assert_eq!(used_after - used_before, stats[0].slab_size);
+    }
+}
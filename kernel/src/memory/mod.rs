@@ -1,3 +1,4 @@
+pub mod heap;
 pub mod pmm;
 pub mod slab_allocator;
 pub mod vmm;
@@ -5,3 +6,50 @@ pub mod vmm;
 pub use pmm::Pmm;
 pub use slab_allocator::SlabAllocator;
 pub use vmm::Vmm;
+
+use crate::Platform;
+use hal::memory::{FrameSize, PageTable, Size4KiB, VAddr};
+use mulch::linker::LinkerSymbol;
+
+/// Check that the kernel image is mapped with the permissions of its sections: code must not be writable,
+/// read-only data must be neither writable nor executable, and data must not be executable. Pages that aren't
+/// mapped (such as the guard page below the boot stack) are skipped. Panics if any page is mapped incorrectly.
+pub fn verify_kernel_image<P>(kernel_page_table: &P::PageTable)
+where
+    P: Platform,
+{
+    extern "C" {
+        static _kernel_start: LinkerSymbol;
+        static _text_end: LinkerSymbol;
+        static _rodata_end: LinkerSymbol;
+        static _kernel_end: LinkerSymbol;
+    }
+
+    let (kernel_start, text_end, rodata_end, kernel_end) = unsafe {
+        (
+            VAddr::from(_kernel_start.ptr()),
+            VAddr::from(_text_end.ptr()),
+            VAddr::from(_rodata_end.ptr()),
+            VAddr::from(_kernel_end.ptr()),
+        )
+    };
+
+    let mut address = kernel_start;
+    while address < kernel_end {
+        if let Some(flags) = kernel_page_table.flags(address) {
+            let (section, allowed) = if address < text_end {
+                ("text", !flags.writable)
+            } else if address < rodata_end {
+                ("rodata", !flags.writable && !flags.executable)
+            } else {
+                ("data", !flags.executable)
+            };
+
+            if !allowed {
+                panic!("Kernel {} page at {:#x} has incorrect permissions: {:?}", section, address, flags);
+            }
+        }
+
+        address += Size4KiB::SIZE;
+    }
+}
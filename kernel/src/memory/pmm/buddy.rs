@@ -36,12 +36,14 @@
 //! free, at which point the block is added to the correct bin.
 //!
 //! Overall, the buddy allocator is an efficient allocator that has a much lower cost than other
-//! algorithms such as first-fit. It also helps reduce external fragmentation, but can suffer from
-//! internal fragmentation in the case of allocations that are slightly larger than a block size
-//! (e.g. allocating 17 frames actually returns 32, wasting 15 frames). If the kernel needs to make
-//! many allocations of a constant, "known bad" size (e.g. 3 frames at a time), it would be better
-//! served allocating a larger block of frames at a time, and using a slab allocator to make the
-//! individual allocations.
+//! algorithms such as first-fit. It also helps reduce external fragmentation. Allocations that
+//! aren't a power-of-2 frames in size are made from the smallest block that can hold them, and the
+//! frames past the end of the allocation are split off and freed again (e.g. allocating 17 frames
+//! splits a block of 32, and returns the last 15 to the allocator). This keeps the allocation
+//! aligned to the size of the block it came from, which is what allows the kernel to use it for
+//! huge pages. Freeing an allocation of any size splits it into blocks in the same way as
+//! `free_range`. Small, frequently-allocated kernel objects are better served by the slab caches of
+//! the kernel heap (see `memory::heap`).
 
 use alloc::collections::BTreeSet;
 use core::{cmp::min, ops::Range};
@@ -49,7 +51,7 @@ use hal::memory::{Bytes, Frame, FrameSize, PAddr, Size4KiB};
 
 /// The largest block stored by the buddy allocator is `2^MAX_ORDER`.
 const MAX_ORDER: usize = 12;
-const NUM_BINS: usize = MAX_ORDER + 1;
+pub const NUM_BINS: usize = MAX_ORDER + 1;
 
 /// The "base" block size - the smallest block size this allocator tracks. This is chosen at the moment to be
 /// `4096` bytes - the size of the smallest physical frame for all the architectures we wish to support at this
@@ -73,15 +75,10 @@ impl BuddyAllocator {
 
     /// Free a range of `Frame`s into this allocator, marking them free to allocate.
     pub fn free_range(&mut self, range: Range<Frame<Size4KiB>>) {
-        /*
-         * Add each frame in the range to the allocator, allowing it to coalesce as it goes.
-         */
-        for frame in range {
-            self.free_block(frame.start, 0);
-        }
+        let count = (usize::from(range.end.start) - usize::from(range.start.start)) / BASE_SIZE;
+        self.free(range.start.start, count);
     }
 
-    #[allow(dead_code)]
     pub fn available_bytes(&self) -> Bytes {
         let mut bytes = 0;
         for i in 0..NUM_BINS {
@@ -90,27 +87,67 @@ impl BuddyAllocator {
         bytes
     }
 
-    /// Allocate a block of `count` base-blocks from this allocator. Returns `None` if the allocator can't satisfy
-    /// the allocation.
+    /// The number of free blocks of each order.
+    pub fn free_blocks(&self) -> [usize; NUM_BINS] {
+        core::array::from_fn(|order| self.bins[order].len())
+    }
+
+    /// Allocate `count` base-blocks from this allocator. The allocation is made from a block of the smallest
+    /// order that can hold it, and so is aligned to the size of that block (e.g. allocating `512` frames returns
+    /// a 2MiB-aligned area, which can be mapped as a huge page). Any frames of the block that aren't needed are
+    /// returned to the allocator straight away. Returns `None` if the allocator can't satisfy the allocation, or
+    /// if `count` is zero.
     pub fn alloc(&mut self, count: usize) -> Option<PAddr> {
-        /*
-         * TODO: this requirement wastes a lot of space on larger allocations that aren't sized
-         * nicely. We should be allocate a larger block, and then split the remaining space into
-         * smaller blocks (this is the same problem as the initial block allocation - the blocks
-         * need to be well-aligned for their sizes. Work out the maths at some point).
-         */
-        let count = count.next_power_of_two();
+        if count == 0 {
+            return None;
+        }
+        let order = Self::order_for(count);
+        let block = self.allocate_block(order)?;
+        self.free_tail(block, order, count);
+        Some(block)
+    }
 
-        let order = count.trailing_zeros() as usize;
-        self.allocate_block(order)
+    /// Allocate `count` base-blocks that lie entirely below the physical address `limit`. This is used for memory
+    /// that has to be addressable by hardware with a restricted view of physical memory (e.g. trampolines run by
+    /// processors in real mode, or devices that can only do 32-bit DMA). Returns `None` if no suitable block is
+    /// available, or if `count` is zero.
+    pub fn alloc_below(&mut self, count: usize, limit: PAddr) -> Option<PAddr> {
+        if count == 0 {
+            return None;
+        }
+        let order = Self::order_for(count);
+        let block = self.allocate_block_below(order, limit)?;
+        self.free_tail(block, order, count);
+        Some(block)
     }
 
-    /// Free a block starting at `base` of `count` base-blocks. `count` must be a power-of-2.
+    /// Free `count` base-blocks, starting at `base`. `count` does not need to be a power-of-2 - the area is split
+    /// into the largest blocks its alignment allows, each of which is coalesced with its buddy if it can be.
     pub fn free(&mut self, base: PAddr, count: usize) {
-        assert!(count.is_power_of_two());
+        let mut base = base;
+        let mut remaining = count;
+        while remaining > 0 {
+            let alignment_order = (usize::from(base) / BASE_SIZE).trailing_zeros() as usize;
+            let size_order = (usize::BITS - 1 - remaining.leading_zeros()) as usize;
+            let order = min(min(alignment_order, size_order), MAX_ORDER);
+
+            self.free_block(base, order);
+            base = base + (1 << order) * BASE_SIZE;
+            remaining -= 1 << order;
+        }
+    }
 
-        let order = count.trailing_zeros() as usize;
-        self.free_block(base, order);
+    /// The order of the smallest block that can hold `count` base-blocks.
+    fn order_for(count: usize) -> usize {
+        count.next_power_of_two().trailing_zeros() as usize
+    }
+
+    /// Return the frames of a block of order `order` starting at `block` past the first `count` to the allocator.
+    fn free_tail(&mut self, block: PAddr, order: usize, count: usize) {
+        let block_size = 1 << order;
+        if count < block_size {
+            self.free(block + count * BASE_SIZE, block_size - count);
+        }
     }
 
     /// Tries to allocate a block of the given order. If no blocks of the correct size are
@@ -146,6 +183,33 @@ impl BuddyAllocator {
         }
     }
 
+    /// Like `allocate_block`, but only considers blocks that end at or below `limit`. Because blocks are
+    /// ordered by address within each bin, we only need to look at the lowest block of each order.
+    fn allocate_block_below(&mut self, order: usize, limit: PAddr) -> Option<PAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let block_size = (1 << order) * BASE_SIZE;
+        if let Some(&block) = self.bins[order].iter().next() {
+            if usize::from(block) + block_size <= usize::from(limit) {
+                return self.bins[order].take(&block);
+            }
+        }
+
+        /*
+         * Split a larger block if we can find one below the limit. The first half of the split block is then
+         * also below the limit.
+         */
+        if let Some(block) = self.allocate_block_below(order + 1, limit) {
+            let second_half = BuddyAllocator::buddy_of(block, order);
+            self.free_block(second_half, order);
+            Some(block)
+        } else {
+            None
+        }
+    }
+
     /// Free a block starting at `start` of order `order`.
     fn free_block(&mut self, start: PAddr, order: usize) {
         if order == MAX_ORDER {
@@ -404,4 +468,60 @@ mod tests {
         // Allocate another frame - this should force a larger block to split
         assert_eq!(allocator.alloc(1), Some(PAddr::new(0x8000).unwrap()));
     }
+
+    #[test]
+    fn test_non_power_of_two_allocation() {
+        let mut allocator = BuddyAllocator::new();
+        allocator.free_range(n_frames_at(0x0, 32));
+
+        // Allocating 17 frames splits the order-5 block, and returns the 15 frames past the allocation
+        assert_eq!(allocator.alloc(17), Some(PAddr::new(0x0).unwrap()));
+        assert_eq!(allocator.available_bytes(), 15 * BASE_SIZE);
+        check_bins(
+            allocator.clone(),
+            vec![Block::new(0, 0x11000), Block::new(1, 0x12000), Block::new(2, 0x14000), Block::new(3, 0x18000)],
+        );
+
+        // Freeing the allocation coalesces it back into a single block
+        allocator.free(PAddr::new(0x0).unwrap(), 17);
+        check_bins(allocator, vec![Block::new(5, 0x0)]);
+    }
+
+    #[test]
+    fn test_allocation_alignment() {
+        let mut allocator = BuddyAllocator::new();
+        allocator.free_range(n_frames_at(0x1000, 1023));
+
+        // A 2MiB allocation is aligned to 2MiB, so can't be made from the start of the range
+        assert_eq!(allocator.alloc(512), Some(PAddr::new(0x200000).unwrap()));
+        assert_eq!(allocator.alloc(512), None);
+    }
+
+    #[test]
+    fn test_zero_sized_allocation() {
+        let mut allocator = BuddyAllocator::new();
+        allocator.free_range(n_frames_at(0x0, 4));
+
+        assert_eq!(allocator.alloc(0), None);
+        assert_eq!(allocator.alloc_below(0, PAddr::new(0x100000).unwrap()), None);
+        assert_eq!(allocator.available_bytes(), 0x4000);
+    }
+
+    #[test]
+    fn test_allocation_below() {
+        let mut allocator = BuddyAllocator::new();
+        allocator.free_range(n_frames_at(0x8000, 8));
+        allocator.free_range(n_frames_at(0x200000, 1));
+
+        // Nothing lies entirely below the first block
+        assert_eq!(allocator.alloc_below(1, PAddr::new(0x8000).unwrap()), None);
+
+        // Single frames below 0x100000 should be split from the order-3 block at 0x8000
+        assert_eq!(allocator.alloc_below(1, PAddr::new(0x100000).unwrap()), Some(PAddr::new(0x8000).unwrap()));
+        assert_eq!(allocator.alloc_below(1, PAddr::new(0x100000).unwrap()), Some(PAddr::new(0x9000).unwrap()));
+
+        // A block that would straddle the limit can't be used
+        assert_eq!(allocator.alloc_below(4, PAddr::new(0xe000).unwrap()), None);
+        assert_eq!(allocator.alloc_below(4, PAddr::new(0x10000).unwrap()), Some(PAddr::new(0xc000).unwrap()));
+    }
 }
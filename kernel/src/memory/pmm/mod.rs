@@ -1,16 +1,43 @@
 mod buddy;
 
-use buddy::BuddyAllocator;
-use core::ops::Range;
+use crate::object::event::Event;
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use buddy::{BuddyAllocator, NUM_BINS};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use hal::memory::{Frame, FrameAllocator, FrameSize, PAddr, Size4KiB};
 use seed::boot_info::BootInfo;
 use spinning_top::Spinlock;
 
+/// Memory pressure starts when free memory falls below `1 / LOW_WATERMARK_DIVISOR` of the total, and ends when it
+/// rises back above `1 / HIGH_WATERMARK_DIVISOR` of it. The gap between the two stops subscribers being signalled
+/// over and over as memory is allocated and freed around a single watermark.
+const LOW_WATERMARK_DIVISOR: usize = 32;
+const HIGH_WATERMARK_DIVISOR: usize = 16;
+
+/// A reclaimer frees memory that the kernel can do without (e.g. the memory of discardable memory objects), when
+/// an allocation can't otherwise be satisfied. It's called with the number of frames that are needed, and returns
+/// the number of frames it freed.
+pub type Reclaimer = Box<dyn Fn(&Pmm, usize) -> usize + Send + Sync>;
+
 /// The Physical Memory Manager (PMM) manages the system's supply of physical memory. It operates
 /// in **frames** of 4KiB, which matches the base frame size on the architectures we're interested
 /// in.
 pub struct Pmm {
     buddy: Spinlock<BuddyAllocator>,
+    total_bytes: usize,
+    low_watermark: usize,
+    high_watermark: usize,
+    under_pressure: AtomicBool,
+    /// The events to signal when memory pressure starts. See `subscribe_to_pressure`.
+    pressure_subscribers: Spinlock<Vec<Weak<Event>>>,
+    reclaimers: Spinlock<Vec<Reclaimer>>,
 }
 
 impl Pmm {
@@ -23,17 +50,120 @@ impl Pmm {
             }
         }
 
-        Pmm { buddy: Spinlock::new(buddy_allocator) }
+        let total_bytes = buddy_allocator.available_bytes();
+        Pmm {
+            buddy: Spinlock::new(buddy_allocator),
+            total_bytes,
+            low_watermark: total_bytes / LOW_WATERMARK_DIVISOR,
+            high_watermark: total_bytes / HIGH_WATERMARK_DIVISOR,
+            under_pressure: AtomicBool::new(false),
+            pressure_subscribers: Spinlock::new(Vec::new()),
+            reclaimers: Spinlock::new(Vec::new()),
+        }
     }
 
-    /// Allocate `count` frames.
+    /// Allocate `count` frames. The frames are aligned to `count` rounded up to a power-of-2 frames, so e.g.
+    /// allocating `512` frames gives a 2MiB-aligned area, which can be mapped as a huge page.
     pub fn alloc(&self, count: usize) -> PAddr {
-        self.buddy.lock().alloc(count).expect("Failed to allocate requested physical memory")
+        self.try_alloc(count).expect("Failed to allocate requested physical memory")
+    }
+
+    /// Allocate `count` frames. If there isn't enough free memory, memory is reclaimed (see `add_reclaimer`)
+    /// before giving up. Returns `None` if there still isn't enough, rather than panicking.
+    pub fn try_alloc(&self, count: usize) -> Option<PAddr> {
+        let allocated = self.buddy.lock().alloc(count);
+        let allocated = allocated.or_else(|| {
+            self.reclaim(count);
+            self.buddy.lock().alloc(count)
+        });
+        self.check_pressure();
+        allocated
+    }
+
+    /// Allocate `count` frames that lie entirely below the physical address `limit`. Returns `None` if there is
+    /// no suitable memory free, as the caller is generally better placed to decide how to handle this.
+    pub fn alloc_below(&self, count: usize, limit: PAddr) -> Option<PAddr> {
+        let allocated = self.buddy.lock().alloc_below(count, limit);
+        self.check_pressure();
+        allocated
     }
 
     /// Free `count` frames, starting at address `base`.
     pub fn free(&self, base: PAddr, count: usize) {
-        self.buddy.lock().free(base, count)
+        self.buddy.lock().free(base, count);
+        self.check_pressure();
+    }
+
+    /// Create an `Event` that is signalled each time free memory falls below the low watermark. If it's already
+    /// below it, the event starts off signalled. Only a weak reference to the event is kept, so it stops being
+    /// signalled once it's dropped.
+    pub fn subscribe_to_pressure(&self) -> Arc<Event> {
+        let event = Event::new();
+        let mut subscribers = self.pressure_subscribers.lock();
+        subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+        subscribers.push(Arc::downgrade(&event));
+
+        if self.under_pressure.load(Ordering::SeqCst) {
+            event.signal();
+        }
+        event
+    }
+
+    /// Add a reclaimer, which is asked to free memory when an allocation can't be satisfied.
+    pub fn add_reclaimer(&self, reclaimer: Reclaimer) {
+        self.reclaimers.lock().push(reclaimer);
+    }
+
+    /// Ask each reclaimer in turn to free memory, until `count` frames have been freed. Returns the number of
+    /// frames that were.
+    fn reclaim(&self, count: usize) -> usize {
+        /*
+         * If memory is already being reclaimed (either on another CPU, or because a reclaimer has tried to
+         * allocate memory itself), we don't wait for it to finish, as that could deadlock.
+         */
+        let Some(reclaimers) = self.reclaimers.try_lock() else {
+            return 0;
+        };
+
+        let mut freed = 0;
+        for reclaimer in reclaimers.iter() {
+            if freed >= count {
+                break;
+            }
+            freed += reclaimer(self, count - freed);
+        }
+        freed
+    }
+
+    /// Signal the memory pressure subscribers if free memory has just fallen below the low watermark, or end the
+    /// period of memory pressure if it has risen back above the high watermark.
+    fn check_pressure(&self) {
+        let free = self.buddy.lock().available_bytes();
+        if free < self.low_watermark {
+            if !self.under_pressure.swap(true, Ordering::SeqCst) {
+                self.pressure_subscribers.lock().retain(|subscriber| match subscriber.upgrade() {
+                    Some(event) => {
+                        event.signal();
+                        true
+                    }
+                    None => false,
+                });
+            }
+        } else if free > self.high_watermark {
+            self.under_pressure.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// The amount of physical memory managed by the PMM, in bytes.
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// The amount of physical memory that's free, in bytes, and the number of free blocks of each order. A block
+    /// of order `n` is `2^n` frames.
+    pub fn free_memory(&self) -> (usize, [usize; NUM_BINS]) {
+        let buddy = self.buddy.lock();
+        (buddy.available_bytes(), buddy.free_blocks())
     }
 }
 
@@ -42,12 +172,11 @@ where
     S: FrameSize,
 {
     fn allocate_n(&self, n: usize) -> Range<Frame<S>> {
-        let start =
-            self.buddy.lock().alloc(n * S::SIZE / Size4KiB::SIZE).expect("Failed to allocate physical memory!");
+        let start = self.try_alloc(n * S::SIZE / Size4KiB::SIZE).expect("Failed to allocate physical memory!");
         Frame::<S>::starts_with(start)..(Frame::<S>::starts_with(start) + n)
     }
 
     fn free_n(&self, start: Frame<S>, num_frames: usize) {
-        self.buddy.lock().free(start.start, num_frames * S::SIZE / Size4KiB::SIZE);
+        self.free(start.start, num_frames * S::SIZE / Size4KiB::SIZE);
     }
 }
@@ -5,6 +5,8 @@ use hal::memory::{FrameSize, PAddr, Size4KiB, VAddr};
 use spinning_top::Spinlock;
 
 pub struct Vmm {
+    kernel_stacks_bottom: VAddr,
+    kernel_stacks_top: VAddr,
     kernel_stack_slots: Spinlock<SlabAllocator>,
     kernel_stack_slot_size: usize,
 }
@@ -12,6 +14,8 @@ pub struct Vmm {
 impl Vmm {
     pub fn new(kernel_stacks_bottom: VAddr, kernel_stacks_top: VAddr, kernel_stack_slot_size: usize) -> Vmm {
         Vmm {
+            kernel_stacks_bottom,
+            kernel_stacks_top,
             kernel_stack_slots: Spinlock::new(SlabAllocator::new(
                 kernel_stacks_bottom,
                 kernel_stacks_top,
@@ -32,6 +36,8 @@ impl Vmm {
     {
         use hal::memory::{Flags, PageTable};
 
+        // Leave at least a page at the bottom of the slot unmapped, as a guard page
+        assert!(initial_size <= self.kernel_stack_slot_size - Size4KiB::SIZE, "Kernel stack is too large");
         let slot_bottom = self.kernel_stack_slots.lock().alloc()?;
         let top = slot_bottom + self.kernel_stack_slot_size - 1;
         let stack_bottom = top - initial_size + 1;
@@ -50,16 +56,27 @@ impl Vmm {
 
         Some(Stack { top, slot_bottom, stack_bottom, physical_start })
     }
+
+    /// Returns `true` if `address` is in the region kernel stacks are allocated from. Only the stacks themselves
+    /// are mapped in this region, so a fault on an address in it is almost certainly caused by a kernel stack
+    /// overflowing into the unmapped memory below it. This doesn't take any locks, so can be used from exception
+    /// handlers.
+    pub fn is_kernel_stack_address(&self, address: VAddr) -> bool {
+        address >= self.kernel_stacks_bottom && address < self.kernel_stacks_top
+    }
 }
 
 /// Represents a stack, either in kernel-space or user-space. Stacks are allocated in "slots" of fixed size, but
-/// only a subset of the slot may be mapped initially (to reduce physical memory usage). Stacks can't grow above
-/// the size of their slot.
+/// only a subset of the slot may be mapped initially (to reduce physical memory usage). User stacks can grow
+/// downwards when they're accessed below `stack_bottom`, but stacks can't grow above the size of their slot, and
+/// the bottom of each slot is never mapped so that overflowing a stack causes a page fault.
 #[derive(Clone, Debug)]
 pub struct Stack {
     pub top: VAddr,
     pub slot_bottom: VAddr,
     pub stack_bottom: VAddr,
 
+    /// The start of the physical memory the stack was created with. Memory added when a user stack grows isn't
+    /// contiguous with it.
     pub physical_start: PAddr,
 }
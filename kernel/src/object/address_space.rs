@@ -1,12 +1,47 @@
-use super::{alloc_kernel_object_id, memory_object::MemoryObject, KernelObject, KernelObjectId, KernelObjectType};
+use super::{
+    alloc_kernel_object_id,
+    job::MemoryCharge,
+    memory_object::{Mapper, MemoryObject},
+    KernelObject,
+    KernelObjectId,
+    KernelObjectType,
+};
 use crate::{
     memory::{vmm::Stack, Pmm},
+    random,
+    vdso,
     Platform,
 };
-use alloc::{sync::Arc, vec::Vec};
-use hal::memory::{mebibytes, Bytes, FrameAllocator, FrameSize, PageTable, Size4KiB, VAddr};
-use mulch::bitmap::Bitmap;
-use poplar::syscall::MapMemoryObjectError;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    mem,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use hal::memory::{
+    gibibytes,
+    kibibytes,
+    mebibytes,
+    Bytes,
+    Flags,
+    Frame,
+    FrameAllocator,
+    FrameSize,
+    PAddr,
+    Page,
+    PageTable,
+    PagingError,
+    Size2MiB,
+    Size4KiB,
+    VAddr,
+};
+use mulch::{bitmap::Bitmap, math::align_up};
+use poplar::syscall::{MapMemoryObjectError, MemoryUsage, ProtectMemoryObjectError, UnmapMemoryObjectError};
 use spinning_top::Spinlock;
 
 const MAX_TASKS: usize = 64;
@@ -17,10 +52,79 @@ const USER_STACK_BOTTOM: VAddr = VAddr::new(0x00000002_00000000);
 const USER_STACK_TOP: VAddr = VAddr::new(0x00000003_ffffffff);
 const USER_STACK_SLOT_SIZE: Bytes = mebibytes(4);
 
+/// Memory objects that are mapped without being given an address are placed in this region.
+const DYNAMIC_MAPPING_BOTTOM: VAddr = VAddr::new(0x00000020_00000000);
+const DYNAMIC_MAPPING_TOP: VAddr = VAddr::new(0x0000003f_ffffffff);
+/// How far into the dynamic mapping region each address space starts placing memory objects, at most. The rest
+/// of the region is left for the mappings themselves.
+const DYNAMIC_MAPPING_MAX_OFFSET: Bytes = gibibytes(64);
+
+/// Position-independent images loaded by `loader::load_image` are placed at a random address in this region.
+pub const IMAGE_REGION_BOTTOM: VAddr = VAddr::new(0x00000001_00000000);
+pub const IMAGE_REGION_TOP: VAddr = VAddr::new(0x00000001_ffffffff);
+
+/// Whether to randomize where tasks' stacks, images, and dynamic mappings are placed. This can be turned off by
+/// the loader so that addresses are the same on every boot, which makes debugging easier.
+static ASLR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// The largest TLS block a thread can have. Each thread's TLS block is placed at the bottom of its task slot, so
+/// this must leave space for its user stack.
+pub const MAX_TLS_SIZE: Bytes = mebibytes(1);
+/// The region of each task slot between its TLS block and the lowest address its user stack can grow to is never
+/// mapped, so a stack overflow faults instead of corrupting the TLS block. It's larger than a page so that a
+/// function with a large stack frame can't skip over it.
+const USER_STACK_GUARD_SIZE: Bytes = kibibytes(64);
+/// The largest a user stack can grow to - the rest of the task slot once the TLS block and guard region are
+/// taken out.
+const MAX_USER_STACK_SIZE: Bytes = USER_STACK_SLOT_SIZE - MAX_TLS_SIZE - USER_STACK_GUARD_SIZE;
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum State {
     NotActive,
-    Active,
+    /// The address space is active on one or more CPUs. Tasks sharing an address space can be
+    /// running on different CPUs at the same time, so we track which CPUs it's active on (bit `n` is
+    /// set for CPU `n`), as their TLBs need to be shot down when memory is unmapped from it.
+    Active(u64),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageFaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageFaultResolution {
+    /// The fault has been handled, and the access can be retried.
+    Resolved,
+    /// The access was to memory below a user stack that the stack can't grow into, which usually means it has
+    /// overflowed.
+    StackOverflow,
+    /// The access was not valid.
+    Invalid,
+}
+
+#[derive(Debug)]
+pub struct Mapping {
+    pub memory_object: Arc<MemoryObject>,
+    pub virtual_address: VAddr,
+    /// The flags the memory object is mapped with. These can be more restrictive than the memory object's own
+    /// flags (e.g. if it was mapped through a handle without the `WRITE` right).
+    pub flags: Flags,
+    /// The most permissive flags the mapping can be given with `protect_memory_object`. If these are both
+    /// writable and executable, the mapping can be made either one, but never both at once.
+    pub max_flags: Flags,
+}
+
+/// The template each thread's thread-local storage (TLS) block is initialized from, which comes from an image's
+/// `PT_TLS` segment. `data` is the initialized part of the block (`.tdata`), and the rest of the block, up to
+/// `size`, is zeroed (`.tbss`).
+#[derive(Clone, Debug)]
+pub struct TlsTemplate {
+    pub data: Vec<u8>,
+    pub size: usize,
+    pub align: usize,
 }
 
 #[derive(Debug)]
@@ -37,9 +141,20 @@ where
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
     pub state: Spinlock<State>,
-    pub memory_objects: Spinlock<Vec<Arc<MemoryObject>>>,
+    pub mappings: Spinlock<Vec<Mapping>>,
     page_table: Spinlock<P::PageTable>,
+    /// The template for the TLS blocks of tasks in this address space, if the image loaded into it uses TLS.
+    pub tls_template: Spinlock<Option<TlsTemplate>>,
     slot_bitmap: Spinlock<u64>,
+    /// The bottom of the first task slot. Where the slots start within the user stack region is randomized for
+    /// each address space.
+    slots_bottom: VAddr,
+    /// Where to start looking for free space for memory objects mapped without an address. This is randomized
+    /// for each address space.
+    dynamic_mapping_bottom: VAddr,
+    /// The tasks waiting on addresses in this address space with `wait_on_address`, in the order they started
+    /// waiting. Each waiter is woken by setting its flag.
+    address_waiters: Spinlock<BTreeMap<usize, VecDeque<Arc<AtomicBool>>>>,
 }
 
 impl<P> AddressSpace<P>
@@ -50,50 +165,352 @@ where
     where
         A: FrameAllocator<P::PageTableSize>,
     {
+        let stack_region_size = usize::from(USER_STACK_TOP) + 1 - usize::from(USER_STACK_BOTTOM);
+        let mut page_table = P::PageTable::new_with_kernel_mapped(kernel_page_table, allocator);
+        vdso::map::<P, A>(&mut page_table, kernel_page_table, allocator);
+
         Arc::new(AddressSpace {
             id: alloc_kernel_object_id(),
             owner,
             state: Spinlock::new(State::NotActive),
-            memory_objects: Spinlock::new(vec![]),
-            page_table: Spinlock::new(P::PageTable::new_with_kernel_mapped(kernel_page_table, allocator)),
+            mappings: Spinlock::new(vec![]),
+            page_table: Spinlock::new(page_table),
+            tls_template: Spinlock::new(None),
+            address_waiters: Spinlock::new(BTreeMap::new()),
             slot_bitmap: Spinlock::new(0),
+            slots_bottom: USER_STACK_BOTTOM
+                + aslr_offset::<P>(stack_region_size - MAX_TASKS * USER_STACK_SLOT_SIZE),
+            dynamic_mapping_bottom: DYNAMIC_MAPPING_BOTTOM + aslr_offset::<P>(DYNAMIC_MAPPING_MAX_OFFSET),
         })
     }
 
+    /// Map a memory object into this address space at `virtual_address`. The memory is mapped writable only if
+    /// both the memory object and `writable` allow it.
     pub fn map_memory_object(
-        &self,
+        self: &Arc<Self>,
         memory_object: Arc<MemoryObject>,
         virtual_address: VAddr,
+        writable: bool,
         allocator: &Pmm,
     ) -> Result<(), MapMemoryObjectError> {
-        use hal::memory::PagingError;
+        self.map_memory_object_locked(
+            &mut self.mappings.lock(),
+            memory_object,
+            virtual_address,
+            writable,
+            allocator,
+        )
+    }
+
+    /// Map a memory object into this address space at a free address chosen by the kernel, which is returned. The
+    /// memory is mapped writable only if both the memory object and `writable` allow it.
+    pub fn map_memory_object_anywhere(
+        self: &Arc<Self>,
+        memory_object: Arc<MemoryObject>,
+        writable: bool,
+        allocator: &Pmm,
+    ) -> Result<VAddr, MapMemoryObjectError> {
+        /*
+         * We hold the lock over the mappings while the object is mapped, so another thread can't choose the same
+         * region.
+         */
+        let mut mappings = self.mappings.lock();
+
+        /*
+         * If the object is physically contiguous and large enough, we choose an address that has the same
+         * alignment within a 2MiB page as its physical memory, so that it can be mapped with huge pages.
+         */
+        let (alignment, offset) = match memory_object.physical_address() {
+            Some(physical_address) if memory_object.size >= Size2MiB::SIZE => {
+                (Size2MiB::SIZE, usize::from(physical_address) % Size2MiB::SIZE)
+            }
+            _ => (Size4KiB::SIZE, 0),
+        };
+        let virtual_address =
+            find_free_region(&mappings, self.dynamic_mapping_bottom, memory_object.size + offset, alignment)
+                .ok_or(MapMemoryObjectError::NoSpaceForMapping)?
+                + offset;
+        self.map_memory_object_locked(&mut mappings, memory_object, virtual_address, writable, allocator)?;
+        Ok(virtual_address)
+    }
+
+    /// Whether `size` bytes starting at `address` are all in the part of this address space userspace can use. The
+    /// kernel's part of the address space is shared between every address space, so memory can't be mapped into it
+    /// from here. How much of the address space userspace can use depends on the paging mode.
+    pub fn is_in_user_space(&self, address: usize, size: usize) -> bool {
+        let user_address_space_end = usize::from(self.page_table.lock().user_address_space_end());
+        address
+            .checked_add(size.saturating_sub(1))
+            .map_or(false, |last_address| last_address <= user_address_space_end)
+    }
+
+    fn map_memory_object_locked(
+        self: &Arc<Self>,
+        mappings: &mut Vec<Mapping>,
+        memory_object: Arc<MemoryObject>,
+        virtual_address: VAddr,
+        writable: bool,
+        allocator: &Pmm,
+    ) -> Result<(), MapMemoryObjectError> {
+        if !self.is_in_user_space(usize::from(virtual_address), memory_object.size) {
+            return Err(MapMemoryObjectError::AddressNotInUserSpace);
+        }
+
+        if !memory_object.mark_mapped() {
+            return Err(MapMemoryObjectError::CopyOnWriteAlreadyMapped);
+        }
+
+        /*
+         * Pages are never mapped writable and executable at the same time. Memory objects that are allowed to be
+         * both are mapped writable, and can be made executable (but then not writable) with
+         * `protect_memory_object`.
+         *
+         * The memory object keeps track of where it's mapped, so it can write-protect its pages when it's cloned.
+         * This is recorded before the pages are mapped, so a clone created while they're being mapped can't be
+         * missed.
+         */
+        let max_flags = Flags { writable: memory_object.flags.writable && writable, ..memory_object.flags };
+        let mapping_flags = Flags { executable: max_flags.executable && !max_flags.writable, ..max_flags };
+        memory_object.add_mapping(Arc::downgrade(self) as Weak<dyn Mapper>, virtual_address);
+        let result =
+            map_pages::<P>(&mut self.page_table.lock(), &memory_object, virtual_address, mapping_flags, allocator);
+        result.map_err(|err| {
+            memory_object.remove_mapping(self.as_ref(), virtual_address);
+            memory_object.mark_unmapped();
+            match err {
+                // XXX: these are explicity enumerated to avoid a bug if variants are added to `PagingError`.
+                PagingError::AlreadyMapped => MapMemoryObjectError::RegionAlreadyMapped,
+            }
+        })?;
+
+        mappings.push(Mapping { memory_object, virtual_address, flags: mapping_flags, max_flags });
+        Ok(())
+    }
+
+    /// Unmap the memory object mapped at `virtual_address`, which must be the address it was mapped at.
+    pub fn unmap_memory_object(&self, virtual_address: VAddr) -> Result<(), UnmapMemoryObjectError> {
+        let mut mappings = self.mappings.lock();
+        let index = mappings
+            .iter()
+            .position(|mapping| mapping.virtual_address == virtual_address)
+            .ok_or(UnmapMemoryObjectError::NotMapped)?;
 
+        /*
+         * Physically contiguous objects can be mapped with huge pages, so we let the page tables unmap each part
+         * with whichever page size it was mapped with. Pages of other objects that were never mapped (e.g. those
+         * that haven't had memory committed to them) are skipped. The mapping holds a reference to the memory
+         * object, so its memory can't be freed until the other CPUs' TLBs have been shot down.
+         */
+        let mapping = mappings.remove(index);
+        let mut page_table = self.page_table.lock();
+        page_table.unmap_area(virtual_address, align_up(mapping.memory_object.size, Size4KiB::SIZE));
+        self.shootdown_tlb();
+        drop(page_table);
+
+        mapping.memory_object.remove_mapping(self, virtual_address);
+        mapping.memory_object.mark_unmapped();
+        Ok(())
+    }
+
+    /// Change the flags of the memory object mapped at `virtual_address`, which must be the address it was
+    /// mapped at. Only whether the mapping is writable and executable can be changed, the mapping can't be made
+    /// more permissive than it was when it was created, and it can't be both writable and executable.
+    pub fn protect_memory_object(
+        &self,
+        virtual_address: VAddr,
+        writable: bool,
+        executable: bool,
+        allocator: &Pmm,
+    ) -> Result<(), ProtectMemoryObjectError> {
+        let mut mappings = self.mappings.lock();
+        let mapping = mappings
+            .iter_mut()
+            .find(|mapping| mapping.virtual_address == virtual_address)
+            .ok_or(ProtectMemoryObjectError::NotMapped)?;
+
+        if writable && executable {
+            return Err(ProtectMemoryObjectError::WritableAndExecutable);
+        }
+        if (writable && !mapping.max_flags.writable) || (executable && !mapping.max_flags.executable) {
+            return Err(ProtectMemoryObjectError::NotPermitted);
+        }
+        let flags = Flags { writable, executable, ..mapping.max_flags };
+
+        /*
+         * We remap the whole object with the new flags. Pages that aren't committed yet stay unmapped, and
+         * will be mapped with the new flags when they're accessed. Other CPUs could still access the memory
+         * with the old flags through their TLBs, so they're shot down before we return.
+         */
+        let mut page_table = self.page_table.lock();
+        page_table.unmap_area(virtual_address, align_up(mapping.memory_object.size, Size4KiB::SIZE));
+        map_pages::<P>(&mut page_table, &mapping.memory_object, virtual_address, flags, allocator)
+            .expect("Memory object should have just been unmapped");
+        self.shootdown_tlb();
+        mapping.flags = flags;
+        Ok(())
+    }
+
+    /// Try to resolve a page fault caused by an access to `address` while this address space was active.
+    /// Returns `true` if the fault has been handled and the access can be retried, or `false` if the access
+    /// was not valid.
+    pub fn handle_page_fault(
+        &self,
+        address: VAddr,
+        access: PageFaultAccess,
+        allocator: &Pmm,
+        charge: &dyn Fn(usize) -> Option<MemoryCharge>,
+    ) -> bool {
+        let Some((memory_object, mapped_at, mapping_flags)) = self.mappings.lock().iter().find_map(|mapping| {
+            let end = mapping.virtual_address + mapping.memory_object.size;
+            (address >= mapping.virtual_address && address < end)
+                .then(|| (mapping.memory_object.clone(), mapping.virtual_address, mapping.flags))
+        }) else {
+            return false;
+        };
+
+        let page_offset = mulch::math::align_down(usize::from(address) - usize::from(mapped_at), Size4KiB::SIZE);
+        let page = Page::<Size4KiB>::starts_with(mapped_at + page_offset);
+
+        /*
+         * Accesses the mapping doesn't permit are genuine faults. Other faults are caused by memory
+         * that hasn't been committed or copied yet, which the memory object can fix up.
+         */
+        let permitted = match access {
+            PageFaultAccess::Read => true,
+            PageFaultAccess::Write => mapping_flags.writable,
+            PageFaultAccess::Execute => mapping_flags.executable,
+        };
+        if !permitted {
+            return false;
+        }
+
+        let Some((frame, flags)) = memory_object.resolve_fault::<P>(page_offset, access, allocator, charge) else {
+            return false;
+        };
+
+        /*
+         * A clone of the memory object could have been created since the fault was resolved, in which case the
+         * page has to stay write-protected. Clones remap the object with the page tables locked, so checking
+         * again here means we can't map it writable after it has been write-protected.
+         */
+        let mut page_table = self.page_table.lock();
+        let flags = Flags { writable: flags.writable && memory_object.page_flags(page_offset).writable, ..flags };
+        page_table.unmap(page);
+        page_table
+            .map(page, Frame::<Size4KiB>::starts_with(frame), restrict_flags(flags, mapping_flags), allocator)
+            .expect("Page should have just been unmapped");
+        true
+    }
+
+    /// Try to resolve a page fault caused by an access to `address` by growing `stack` down to the page containing
+    /// it. The stack can grow to at most `max_size` bytes, and never past `MAX_USER_STACK_SIZE` - accesses below
+    /// that (including accesses to the guard region) are stack overflows. Memory committed to grow the stack is
+    /// charged with `charge`, and stays charged, as user stacks are never freed.
+    pub fn grow_user_stack(
+        &self,
+        stack: &mut Stack,
+        address: VAddr,
+        max_size: usize,
+        allocator: &Pmm,
+        charge: &dyn Fn(usize) -> Option<MemoryCharge>,
+    ) -> PageFaultResolution {
+        if address < stack.slot_bottom + MAX_TLS_SIZE || address >= stack.stack_bottom {
+            return PageFaultResolution::Invalid;
+        }
+
+        let new_bottom = VAddr::new(mulch::math::align_down(usize::from(address), Size4KiB::SIZE));
+        if usize::from(stack.top) + 1 - usize::from(new_bottom) > usize::min(max_size, MAX_USER_STACK_SIZE) {
+            return PageFaultResolution::StackOverflow;
+        }
+
+        let size = usize::from(stack.stack_bottom) - usize::from(new_bottom);
+        let Some(charge) = charge(size) else {
+            return PageFaultResolution::Invalid;
+        };
+        let Some(physical_start) = allocator.try_alloc(size / Size4KiB::SIZE) else {
+            return PageFaultResolution::Invalid;
+        };
+        unsafe {
+            P::zero_phys_memory(physical_start, size);
+        }
         self.page_table
             .lock()
             .map_area(
-                virtual_address,
-                memory_object.physical_address,
-                memory_object.size,
-                memory_object.flags,
+                new_bottom,
+                physical_start,
+                size,
+                Flags { writable: true, user_accessible: true, ..Default::default() },
                 allocator,
             )
-            .map_err(|err| match err {
-                // XXX: these are explicity enumerated to avoid a bug if variants are added to `PagingError`.
-                PagingError::AlreadyMapped => MapMemoryObjectError::RegionAlreadyMapped,
-            })?;
-        self.memory_objects.lock().push(memory_object);
-        Ok(())
+            .unwrap();
+
+        charge.keep();
+        stack.stack_bottom = new_bottom;
+        PageFaultResolution::Resolved
+    }
+
+    /// Get how much memory is mapped into this address space, and how much of it has had physical memory
+    /// committed to it. Memory objects mapped into multiple address spaces are counted in each of them.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.mappings.lock().iter().fold(MemoryUsage { reserved: 0, committed: 0 }, |usage, mapping| MemoryUsage {
+            reserved: usage.reserved + mapping.memory_object.size,
+            committed: usage.committed + mapping.memory_object.committed(),
+        })
+    }
+
+    /// Discard the memory of the discardable memory objects mapped into this address space that are marked as
+    /// discardable. Their pages are unmapped, and so are committed again (zeroed) when they're next accessed.
+    /// Returns the number of frames freed.
+    ///
+    /// This is called to reclaim memory when an allocation fails, which can happen while this address space is
+    /// locked (e.g. while a page table is being allocated for it), so nothing is discarded if it is.
+    pub fn discard_memory(&self, allocator: &Pmm) -> usize {
+        let Some(mappings) = self.mappings.try_lock() else {
+            return 0;
+        };
+        let Some(mut page_table) = self.page_table.try_lock() else {
+            return 0;
+        };
+
+        let mut num_frames = 0;
+        for mapping in mappings.iter().filter(|mapping| mapping.memory_object.is_discardable()) {
+            num_frames += mapping.memory_object.discard(
+                allocator,
+                |offset| {
+                    page_table.unmap(Page::<Size4KiB>::starts_with(mapping.virtual_address + offset));
+                },
+                || self.shootdown_tlb(),
+            );
+        }
+        num_frames
+    }
+
+    /// Get the physical address that `address` is mapped to in this address space, if it is mapped.
+    pub fn translate(&self, address: VAddr) -> Option<PAddr> {
+        self.page_table.lock().translate(address)
+    }
+
+    /// Read a word of this address space's memory through its page tables, so that unmapped memory can't cause a
+    /// fault. `address` must be aligned to the size of a word. Returns `None` if the word isn't mapped, or if the
+    /// page tables are locked (e.g. because we've interrupted code that's changing them), so this can be used from
+    /// interrupt handlers.
+    pub fn try_read_word(&self, address: VAddr) -> Option<usize> {
+        let physical = self.page_table.try_lock()?.translate(address)?;
+        let mut bytes = [0; mem::size_of::<usize>()];
+        unsafe {
+            P::read_from_phys_memory(physical, &mut bytes);
+        }
+        Some(usize::from_ne_bytes(bytes))
     }
 
     /// Try to allocate a slot for a Task. Creates a user stack with `initial_stack_size` bytes initially
-    /// allocated. Returs `None` if no more tasks can be created in this Address Space.
+    /// allocated, which can grow with `grow_user_stack`. Returs `None` if no more tasks can be created in this
+    /// Address Space.
     pub fn alloc_task_slot(&self, initial_stack_size: usize, allocator: &Pmm) -> Option<TaskSlot> {
-        use hal::memory::Flags;
-
         let index = self.slot_bitmap.lock().alloc(1)?;
 
         let user_stack = {
-            let slot_bottom = USER_STACK_BOTTOM + USER_STACK_SLOT_SIZE * index;
+            let slot_bottom = self.slots_bottom + USER_STACK_SLOT_SIZE * index;
             let top = slot_bottom + USER_STACK_SLOT_SIZE - 1;
             let stack_bottom = (top + 1) - initial_stack_size;
 
@@ -115,17 +532,284 @@ where
         Some(TaskSlot { index, user_stack })
     }
 
+    /// Create the TLS block for the task in `slot` from this address space's TLS template, at the bottom of the
+    /// task's slot. Returns the value the task's thread pointer should be set to, or `None` if the address space
+    /// doesn't have a TLS template.
+    pub fn alloc_tls(&self, slot: &TaskSlot, allocator: &Pmm) -> Option<VAddr> {
+        let template = self.tls_template.lock();
+        let template = template.as_ref()?;
+
+        /*
+         * On x86_64, the TLS block is placed below the thread pointer, which points to a thread control block
+         * that starts with a pointer to itself (TLS variant II). On RISC-V, the thread pointer points to the start
+         * of the TLS block, and there is no thread control block (TLS variant I). AArch64 also uses variant I, but
+         * reserves a 16-byte thread control block at the thread pointer, before the TLS block. The slot's bottom
+         * is page-aligned, so the block is aligned correctly as long as its alignment is at most a page.
+         */
+        let block_size = align_up(template.size, template.align.max(1));
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                let thread_pointer_offset = block_size;
+                let block_offset = 0;
+                let area_size = block_size + core::mem::size_of::<usize>();
+            } else if #[cfg(target_arch = "aarch64")] {
+                let thread_pointer_offset = 0;
+                let block_offset = align_up(16, template.align.max(1));
+                let area_size = block_offset + block_size;
+            } else {
+                let thread_pointer_offset = 0;
+                let block_offset = 0;
+                let area_size = block_size;
+            }
+        }
+        let area_size = align_up(area_size.max(1), Size4KiB::SIZE);
+        let area_start = slot.user_stack.slot_bottom;
+        let thread_pointer = area_start + thread_pointer_offset;
+
+        let physical_start = allocator.alloc(area_size / Size4KiB::SIZE);
+        unsafe {
+            P::zero_phys_memory(physical_start, area_size);
+            P::write_to_phys_memory(physical_start + block_offset, &template.data);
+            #[cfg(target_arch = "x86_64")]
+            P::write_to_phys_memory(
+                physical_start + thread_pointer_offset,
+                &usize::from(thread_pointer).to_ne_bytes(),
+            );
+        }
+        self.page_table
+            .lock()
+            .map_area(
+                area_start,
+                physical_start,
+                area_size,
+                Flags { writable: true, user_accessible: true, ..Default::default() },
+                allocator,
+            )
+            .unwrap();
+
+        Some(thread_pointer)
+    }
+
+    /// Start waiting on `address`, if `should_wait` succeeds. `should_wait` is called with the waiters locked, so
+    /// a task can't wake the address between it being checked and the waiter being added. Returns a flag that is
+    /// set when the waiter is woken.
+    pub fn add_address_waiter<E>(
+        &self,
+        address: usize,
+        should_wait: impl FnOnce() -> Result<(), E>,
+    ) -> Result<Arc<AtomicBool>, E> {
+        let mut waiters = self.address_waiters.lock();
+        should_wait()?;
+
+        let waiter = Arc::new(AtomicBool::new(false));
+        waiters.entry(address).or_default().push_back(waiter.clone());
+        Ok(waiter)
+    }
+
+    /// Stop waiting on `address` (e.g. because the wait has timed out). Returns `false` if the waiter has already
+    /// been woken.
+    pub fn remove_address_waiter(&self, address: usize, waiter: &Arc<AtomicBool>) -> bool {
+        let mut waiters = self.address_waiters.lock();
+        let Some(queue) = waiters.get_mut(&address) else { return false };
+        let Some(index) = queue.iter().position(|other| Arc::ptr_eq(other, waiter)) else { return false };
+
+        queue.remove(index);
+        if queue.is_empty() {
+            waiters.remove(&address);
+        }
+        true
+    }
+
+    /// Wake up to `count` of the tasks waiting on `address`, in the order they started waiting. Returns the number
+    /// of tasks woken.
+    pub fn wake_address(&self, address: usize, count: usize) -> usize {
+        let mut waiters = self.address_waiters.lock();
+        let Some(queue) = waiters.get_mut(&address) else { return 0 };
+
+        let num_woken = count.min(queue.len());
+        for waiter in queue.drain(0..num_woken) {
+            waiter.store(true, Ordering::SeqCst);
+        }
+        if queue.is_empty() {
+            waiters.remove(&address);
+        }
+        num_woken
+    }
+
     pub fn switch_to(&self) {
-        assert_eq!(*self.state.lock(), State::NotActive);
+        let cpu = P::cpu_id();
+        assert!(cpu < u64::BITS as usize);
+
+        /*
+         * We mark the address space as active on this CPU before loading its page tables, so a change made to
+         * them after they're loaded always shoots down this CPU's TLB. The state is unlocked before the page
+         * tables are locked, as `shootdown_tlb` locks the state while the page tables are locked.
+         */
+        {
+            let mut state = self.state.lock();
+            *state = match *state {
+                State::NotActive => State::Active(1 << cpu),
+                State::Active(cpus) => State::Active(cpus | (1 << cpu)),
+            };
+        }
         unsafe {
             self.page_table.lock().switch_to();
         }
-        *self.state.lock() = State::Active;
     }
 
     pub fn switch_from(&self) {
-        assert_eq!(*self.state.lock(), State::Active);
-        *self.state.lock() = State::NotActive;
+        let cpu = P::cpu_id();
+        let mut state = self.state.lock();
+        *state = match *state {
+            State::Active(cpus) if cpus & (1 << cpu) != 0 => match cpus & !(1 << cpu) {
+                0 => State::NotActive,
+                cpus => State::Active(cpus),
+            },
+            _ => panic!("Tried to switch away from an address space that isn't active!"),
+        };
+    }
+
+    /// Make sure no other CPU this address space is active on can still access memory through a stale TLB
+    /// entry, after its page tables have been changed to unmap memory or make it less accessible. This must be
+    /// done before unmapped memory is freed.
+    fn shootdown_tlb(&self) {
+        let other_cpus = match *self.state.lock() {
+            State::NotActive => 0,
+            State::Active(cpus) => cpus & !(1 << P::cpu_id()),
+        };
+        if other_cpus != 0 {
+            P::shootdown_tlb(other_cpus);
+        }
+    }
+}
+
+/// Stop randomizing the layout of address spaces created from now on.
+pub fn disable_aslr() {
+    ASLR_ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Whether the layout of new address spaces is being randomized.
+pub fn aslr_enabled() -> bool {
+    ASLR_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Get a random page-aligned offset that's less than `range` bytes, for placing something at a random address
+/// within a region. Returns `0` if ASLR has been disabled.
+pub fn aslr_offset<P>(range: usize) -> usize
+where
+    P: Platform,
+{
+    let num_pages = range / Size4KiB::SIZE;
+    if !aslr_enabled() || num_pages == 0 {
+        return 0;
+    }
+    random::next_below::<P>(num_pages as u64) as usize * Size4KiB::SIZE
+}
+
+/// Find a free region of `size` bytes in the part of the address space that memory objects are mapped into when
+/// they aren't given an address, starting from `bottom`.
+fn find_free_region(mappings: &[Mapping], bottom: VAddr, size: usize, alignment: usize) -> Option<VAddr> {
+    let size = align_up(size, Size4KiB::SIZE);
+    let mut used: Vec<(usize, usize)> = mappings
+        .iter()
+        .map(|mapping| {
+            let start = usize::from(mapping.virtual_address);
+            (start, start + mapping.memory_object.size)
+        })
+        .filter(|&(start, end)| end > usize::from(bottom) && start <= usize::from(DYNAMIC_MAPPING_TOP))
+        .collect();
+    used.sort_unstable();
+
+    let mut candidate = align_up(usize::from(bottom), alignment);
+    for (start, end) in used {
+        if start >= candidate + size {
+            break;
+        }
+        candidate = candidate.max(align_up(end, alignment));
+    }
+
+    (candidate + size - 1 <= usize::from(DYNAMIC_MAPPING_TOP)).then(|| VAddr::new(candidate))
+}
+
+/// Map the pages of `memory_object` at `virtual_address` with the given `flags`.
+fn map_pages<P>(
+    page_table: &mut P::PageTable,
+    memory_object: &MemoryObject,
+    virtual_address: VAddr,
+    flags: Flags,
+    allocator: &Pmm,
+) -> Result<(), PagingError>
+where
+    P: Platform,
+{
+    match memory_object.physical_address().filter(|_| !memory_object.has_protected_pages()) {
+        Some(physical_address) => {
+            page_table.map_area(virtual_address, physical_address, memory_object.size, flags, allocator)
+        }
+        None => {
+            /*
+             * The object isn't physically contiguous (or some of its pages are shared with a clone, and so have
+             * to be mapped read-only), so we map it a page at a time. Pages that are shared with a copy-on-write
+             * object's parent or clones are mapped read-only, and pages that don't have memory committed to them
+             * yet are left unmapped - both are fixed up when they're accessed.
+             */
+            (0..memory_object.size).step_by(Size4KiB::SIZE).try_for_each(|offset| {
+                match memory_object.page(offset) {
+                    Some((physical_address, page_flags)) => page_table.map::<Size4KiB, _>(
+                        Page::starts_with(virtual_address + offset),
+                        Frame::starts_with(physical_address),
+                        restrict_flags(page_flags, flags),
+                        allocator,
+                    ),
+                    None => Ok(()),
+                }
+            })
+        }
+    }
+}
+
+/// Memory objects provide the flags each of their pages should be mapped with, which can't be more permissive
+/// than the flags of the mapping they're part of.
+fn restrict_flags(flags: Flags, mapping_flags: Flags) -> Flags {
+    Flags { writable: flags.writable && mapping_flags.writable, ..flags }
+}
+
+impl<P> Mapper for AddressSpace<P>
+where
+    P: Platform,
+{
+    fn remap(&self, memory_object: &MemoryObject, virtual_address: VAddr, offsets: Range<usize>) {
+        let mappings = self.mappings.lock();
+        let Some(mapping) = mappings.iter().find(|mapping| {
+            mapping.virtual_address == virtual_address
+                && ptr::eq(Arc::as_ptr(&mapping.memory_object), memory_object)
+        }) else {
+            return;
+        };
+
+        /*
+         * Physically contiguous objects can be mapped with huge pages, so we remap the whole object, like
+         * `protect_memory_object` does. Other objects are mapped a page at a time, so we only need to remap the
+         * pages that have changed.
+         */
+        let allocator = crate::PMM.get();
+        let mut page_table = self.page_table.lock();
+        if memory_object.physical_address().is_some() {
+            page_table.unmap_area(virtual_address, align_up(memory_object.size, Size4KiB::SIZE));
+            map_pages::<P>(&mut page_table, memory_object, virtual_address, mapping.flags, allocator)
+                .expect("Memory object should have just been unmapped");
+        } else {
+            for offset in offsets.step_by(Size4KiB::SIZE) {
+                let page = Page::<Size4KiB>::starts_with(virtual_address + offset);
+                page_table.unmap(page);
+                if let Some((frame, flags)) = memory_object.page(offset) {
+                    page_table
+                        .map(page, Frame::starts_with(frame), restrict_flags(flags, mapping.flags), allocator)
+                        .expect("Page should have just been unmapped");
+                }
+            }
+        }
+        self.shootdown_tlb();
     }
 }
 
@@ -5,7 +5,11 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use poplar::syscall::{GetMessageError, SendMessageError, CHANNEL_MAX_NUM_HANDLES};
+use core::sync::atomic::{AtomicU16, Ordering};
+use poplar::{
+    syscall::{GetMessageError, SendMessageError},
+    HandleRights,
+};
 use spinning_top::Spinlock;
 use tracing::warn;
 
@@ -13,9 +17,13 @@ use tracing::warn;
 pub struct ChannelEnd {
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
-    pub messages: Spinlock<VecDeque<Message>>,
+    messages: Spinlock<VecDeque<Message>>,
     /// The other end of the channel. If this is `None`, the channel's messages come from the kernel.
     other_end: Option<Weak<ChannelEnd>>,
+    /// The transaction IDs of the calls made through this end that are still waiting for a reply. Replies to
+    /// these calls are held back from `receive`, and can only be received by passing their transaction ID.
+    pending_calls: Spinlock<Vec<u16>>,
+    next_txid: AtomicU16,
 }
 
 impl ChannelEnd {
@@ -25,6 +33,8 @@ impl ChannelEnd {
             owner,
             messages: Spinlock::new(VecDeque::new()),
             other_end: Some(Weak::default()),
+            pending_calls: Spinlock::new(Vec::new()),
+            next_txid: AtomicU16::new(1),
         });
 
         let end_b = Arc::new(ChannelEnd {
@@ -32,6 +42,8 @@ impl ChannelEnd {
             owner,
             messages: Spinlock::new(VecDeque::new()),
             other_end: Some(Arc::downgrade(&end_a)),
+            pending_calls: Spinlock::new(Vec::new()),
+            next_txid: AtomicU16::new(1),
         });
 
         // TODO: is there a nicer way of doing this?
@@ -48,12 +60,28 @@ impl ChannelEnd {
             owner,
             messages: Spinlock::new(VecDeque::new()),
             other_end: None,
+            pending_calls: Spinlock::new(Vec::new()),
+            next_txid: AtomicU16::new(1),
         })
     }
 
+    /// Get the other end of the channel, if there is one and it hasn't been dropped.
+    pub fn other_end(&self) -> Option<Arc<ChannelEnd>> {
+        self.other_end.as_ref().and_then(|other_end| other_end.upgrade())
+    }
+
+    /// Whether the other end of the channel has been dropped. Kernel channels are never closed.
+    pub fn is_peer_closed(&self) -> bool {
+        self.other_end.as_ref().map_or(false, |other_end| other_end.strong_count() == 0)
+    }
+
     /// Add a message *to* this `ChannelEnd`. Use `send` if you want to send a message *through* this
     /// `ChannelEnd` (i.e. to the other end of the Channel).
     pub fn add_message(&self, message: Message) {
+        if message.is_reply && !self.pending_calls.lock().contains(&message.txid) {
+            warn!("Discarding reply to call that isn't waiting for one (txid = {})", message.txid);
+            return;
+        }
         self.messages.lock().push_back(message);
     }
 
@@ -74,20 +102,81 @@ impl ChannelEnd {
         }
     }
 
-    /// Try to "receive" a message from this `ChannelEnd`, potentially removing it from the queue. Note that this
-    /// keeps a lock over the message queue while the passed function is called - if the handling of the message
-    /// fails (for example, the buffer to put it into is too small), the passed function can return it with
-    /// `Err((message, some_error))`, and the message will be placed back into the queue (preserving message
-    /// order), and the error will be returned.
-    pub fn receive<F, R>(&self, f: F) -> Result<R, GetMessageError>
+    /// Start a call through this `ChannelEnd`, returning the transaction ID that the call's message should be
+    /// sent with. The reply to the call is held back from `receive` until it's received by passing this ID, or
+    /// the call is abandoned with `end_call`. Returns `None` if every transaction ID is in use by a pending call.
+    pub fn begin_call(&self) -> Option<u16> {
+        let mut pending_calls = self.pending_calls.lock();
+        if pending_calls.len() >= usize::from(u16::MAX) {
+            return None;
+        }
+
+        /*
+         * There's at least one free transaction ID, so this finds one before `next_txid` wraps round to where it
+         * started.
+         */
+        loop {
+            // `0` means a message isn't part of a call, so is skipped over
+            let txid = self.next_txid.fetch_add(1, Ordering::Relaxed);
+            if txid != 0 && !pending_calls.contains(&txid) {
+                pending_calls.push(txid);
+                return Some(txid);
+            }
+        }
+    }
+
+    /// Stop waiting for the reply to the call with the given transaction ID. Any reply that has already arrived
+    /// is discarded.
+    pub fn end_call(&self, txid: u16) {
+        self.pending_calls.lock().retain(|&pending| pending != txid);
+        self.messages.lock().retain(|message| !(message.is_reply && message.txid == txid));
+    }
+
+    /// Whether there are any messages waiting to be received with `receive`. This doesn't include replies to
+    /// calls.
+    pub fn has_message(&self) -> bool {
+        self.messages.lock().iter().any(|message| !message.is_reply)
+    }
+
+    /// Whether a reply has arrived to any call made through this end. If `txid` is not `0`, only replies to the
+    /// call with that transaction ID are considered.
+    pub fn has_reply(&self, txid: u16) -> bool {
+        self.messages.lock().iter().any(|message| message.is_reply && (txid == 0 || message.txid == txid))
+    }
+
+    /// Call `f` with the next message that would be received by `receive` with the same `txid`, without removing
+    /// it. Returns `None` if there are no messages waiting to be received.
+    pub fn peek<F, R>(&self, txid: u16, f: F) -> Option<R>
+    where
+        F: FnOnce(&Message) -> R,
+    {
+        self.messages.lock().iter().find(|message| message.matches(txid)).map(f)
+    }
+
+    /// Try to "receive" a message from this `ChannelEnd`, potentially removing it from the queue. If `txid` is
+    /// `0`, this is the next message that isn't a reply to a call. Otherwise, it is the reply to the call with
+    /// that transaction ID, which completes the call.
+    ///
+    /// Note that this keeps a lock over the message queue while the passed function is called - if the handling
+    /// of the message fails (for example, the buffer to put it into is too small), the passed function can return
+    /// it with `Err((message, some_error))`, and the message will be placed back into the queue (preserving
+    /// message order), and the error will be returned.
+    pub fn receive<F, R>(&self, txid: u16, f: F) -> Result<R, GetMessageError>
     where
         F: FnOnce(Message) -> Result<R, (Message, GetMessageError)>,
     {
         let mut message_queue = self.messages.lock();
-        match f(message_queue.pop_front().ok_or(GetMessageError::NoMessage)?) {
-            Ok(value) => Ok(value),
+        let index =
+            message_queue.iter().position(|message| message.matches(txid)).ok_or(GetMessageError::NoMessage)?;
+        match f(message_queue.remove(index).unwrap()) {
+            Ok(value) => {
+                if txid != 0 {
+                    self.pending_calls.lock().retain(|&pending| pending != txid);
+                }
+                Ok(value)
+            }
             Err((message, err)) => {
-                message_queue.push_front(message);
+                message_queue.insert(index, message);
                 Err(err)
             }
         }
@@ -106,20 +195,42 @@ impl KernelObject for ChannelEnd {
 
 pub struct Message {
     pub bytes: Vec<u8>,
-    /// The actual objects extracted from the handles transferred by a message. When a task receives this message,
-    /// these objects are added to that task, and the new handles are put into the message. The non-`None` entries
-    /// of this array must be contiguous - there cannot be a `None` entry before more non-`None` entries.
-    pub handle_objects: [Option<Arc<dyn KernelObject>>; CHANNEL_MAX_NUM_HANDLES],
+    /// The actual objects extracted from the handles transferred by a message, along with the rights of those
+    /// handles. When a task receives this message, these objects are added to that task with the same rights,
+    /// and the new handles are put into the message, in the same order.
+    pub handle_objects: Vec<(Arc<dyn KernelObject>, HandleRights)>,
+    /// The transaction ID of the call this message is part of, or `0` if it isn't part of a call.
+    pub txid: u16,
+    /// Whether this message is the reply to a call, rather than the message that made it.
+    pub is_reply: bool,
 }
 
 impl fmt::Debug for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Message").field("bytes", &self.bytes).finish_non_exhaustive()
+        f.debug_struct("Message")
+            .field("bytes", &self.bytes)
+            .field("txid", &self.txid)
+            .field("is_reply", &self.is_reply)
+            .finish_non_exhaustive()
     }
 }
 
 impl Message {
+    /// Create a message that isn't part of a call.
+    pub fn new(bytes: Vec<u8>, handle_objects: Vec<(Arc<dyn KernelObject>, HandleRights)>) -> Message {
+        Message { bytes, handle_objects, txid: 0, is_reply: false }
+    }
+
     pub fn num_handles(&self) -> usize {
-        self.handle_objects.iter().fold(0, |n, ref handle| if handle.is_some() { n + 1 } else { n })
+        self.handle_objects.len()
+    }
+
+    /// Whether this message would be received by a `receive` with the given `txid`.
+    fn matches(&self, txid: u16) -> bool {
+        if txid == 0 {
+            !self.is_reply
+        } else {
+            self.is_reply && self.txid == txid
+        }
     }
 }
@@ -0,0 +1,95 @@
+use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType};
+use crate::iommu::IommuDomain;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use hal::memory::PAddr;
+use pci_types::PciAddress;
+use poplar::syscall::{DmaDomainAttachError, DmaDomainDetachError};
+use spinning_top::Spinlock;
+use tracing::warn;
+
+/// The domain each attached device is attached to. A device can only be attached to a single domain at a time.
+static ATTACHED_DEVICES: Spinlock<BTreeMap<PciAddress, KernelObjectId>> = Spinlock::new(BTreeMap::new());
+
+/// A `DmaDomain` restricts the memory that the devices attached to it can access through DMA to the memory that
+/// has been allocated in it. See the `iommu` module for more details.
+pub struct DmaDomain {
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    /// The IOMMU's translations for this domain. This is `None` if the platform does not have an IOMMU, in which
+    /// case attached devices can still access all of physical memory.
+    iommu_domain: Option<Box<dyn IommuDomain>>,
+}
+
+impl DmaDomain {
+    pub fn new(owner: KernelObjectId) -> Arc<DmaDomain> {
+        Arc::new(DmaDomain {
+            id: alloc_kernel_object_id(),
+            owner,
+            iommu_domain: crate::IOMMU.try_get().map(|iommu| iommu.create_domain()),
+        })
+    }
+
+    /// Allow devices attached to this domain to access an area of physical memory.
+    pub fn map(&self, address: PAddr, size: usize, writable: bool) {
+        if let Some(ref iommu_domain) = self.iommu_domain {
+            iommu_domain.map(address, size, writable);
+        }
+    }
+
+    pub fn attach(&self, device: PciAddress) -> Result<(), DmaDomainAttachError> {
+        let mut attached_devices = ATTACHED_DEVICES.lock();
+        if attached_devices.contains_key(&device) {
+            return Err(DmaDomainAttachError::DeviceAlreadyAttached);
+        }
+        attached_devices.insert(device, self.id);
+
+        if let Some(ref iommu_domain) = self.iommu_domain {
+            if !iommu_domain.attach(device) {
+                warn!("PCI device {} is not behind the IOMMU. Its DMA will not be restricted.", device);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn detach(&self, device: PciAddress) -> Result<(), DmaDomainDetachError> {
+        let mut attached_devices = ATTACHED_DEVICES.lock();
+        if attached_devices.get(&device) != Some(&self.id) {
+            return Err(DmaDomainDetachError::DeviceNotAttached);
+        }
+        attached_devices.remove(&device);
+
+        if let Some(ref iommu_domain) = self.iommu_domain {
+            iommu_domain.detach(device);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DmaDomain {
+    fn drop(&mut self) {
+        /*
+         * Detach any devices that are still attached, so they don't keep using translations that refer to
+         * memory that may have been freed.
+         */
+        ATTACHED_DEVICES.lock().retain(|&device, domain| {
+            if *domain == self.id {
+                if let Some(ref iommu_domain) = self.iommu_domain {
+                    iommu_domain.detach(device);
+                }
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+impl KernelObject for DmaDomain {
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+
+    fn typ(&self) -> KernelObjectType {
+        KernelObjectType::DmaDomain
+    }
+}
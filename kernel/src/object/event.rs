@@ -1,26 +1,98 @@
 use super::{KernelObject, KernelObjectId, KernelObjectType};
 use alloc::sync::Arc;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 #[derive(Debug)]
 pub struct Event {
     pub id: KernelObjectId,
-    pub signalled: AtomicBool,
+    /// The number of times the event has been signalled and not yet consumed. For events that don't count their
+    /// signals, this is only ever `0` or `1`.
+    count: AtomicU64,
+    /// Whether each signal increments the count, rather than just setting it. See `Event::new_counting`.
+    counting: bool,
+    /// Set for events that are signalled by a level-triggered interrupt. See `Event::new_interrupt`.
+    interrupt: Option<InterruptSource>,
+    /// Whether the interrupt has been signalled and is yet to be acknowledged by the receiver of the event.
+    awaiting_ack: AtomicBool,
+}
+
+/// A level-triggered interrupt line that signals an `Event`. The platform masks the line when it signals the
+/// event, and `acknowledge` is called with `line` when the event's receiver has serviced the device.
+#[derive(Debug)]
+struct InterruptSource {
+    line: u32,
+    acknowledge: fn(u32),
 }
 
 impl Event {
+    fn create(counting: bool, interrupt: Option<InterruptSource>) -> Arc<Event> {
+        Arc::new(Event {
+            id: super::alloc_kernel_object_id(),
+            count: AtomicU64::new(0),
+            counting,
+            interrupt,
+            awaiting_ack: AtomicBool::new(false),
+        })
+    }
+
     pub fn new() -> Arc<Event> {
-        Arc::new(Event { id: super::alloc_kernel_object_id(), signalled: AtomicBool::new(false) })
+        Event::create(false, None)
+    }
+
+    /// Create an `Event` that counts the number of times it has been signalled. Each wait on the event consumes
+    /// one signal, so signals that arrive before the receiver gets round to waiting (e.g. edge-triggered
+    /// interrupts that fire in quick succession) aren't coalesced into one.
+    pub fn new_counting() -> Arc<Event> {
+        Event::create(true, None)
+    }
+
+    /// Create an `Event` that is signalled by a level-triggered interrupt. The interrupt keeps firing until the
+    /// device has been serviced, which is done by the (userspace) receiver of the event, so the platform masks
+    /// the line when it signals the event, and it's the receiver's responsibility to acknowledge the interrupt
+    /// (with `acknowledge`) once it has serviced the device. `acknowledge` is called with `line` when it does.
+    pub fn new_interrupt(line: u32, acknowledge: fn(u32)) -> Arc<Event> {
+        Event::create(false, Some(InterruptSource { line, acknowledge }))
     }
 
     pub fn signal(&self) {
-        // TODO: ordering?
-        self.signalled.store(true, Ordering::SeqCst);
+        if self.interrupt.is_some() {
+            self.awaiting_ack.store(true, Ordering::SeqCst);
+        }
+        if self.counting {
+            self.count.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.count.store(1, Ordering::SeqCst);
+        }
     }
 
+    /// Clear the event, discarding any signals that have not yet been consumed.
     pub fn clear(&self) {
-        // TODO: ordering?
-        self.signalled.store(false, Ordering::SeqCst);
+        self.count.store(0, Ordering::SeqCst);
+    }
+
+    pub fn is_signalled(&self) -> bool {
+        self.count.load(Ordering::SeqCst) != 0
+    }
+
+    /// Consume a signal of the event, if it has been signalled. Counting events are only cleared once every signal
+    /// has been consumed. Returns `true` if a signal was consumed.
+    pub fn consume(&self) -> bool {
+        if self.counting {
+            self.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1)).is_ok()
+        } else {
+            self.count.swap(0, Ordering::SeqCst) != 0
+        }
+    }
+
+    /// Acknowledge the interrupt that signalled this event, allowing the platform to unmask it. This does nothing
+    /// if the event isn't signalled by a level-triggered interrupt, or if it has already been acknowledged since it
+    /// was last signalled.
+    pub fn acknowledge(&self) {
+        if let Some(ref interrupt) = self.interrupt {
+            if self.awaiting_ack.swap(false, Ordering::SeqCst) {
+                (interrupt.acknowledge)(interrupt.line);
+            }
+        }
     }
 }
 
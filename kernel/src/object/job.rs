@@ -0,0 +1,304 @@
+use super::{alloc_kernel_object_id, task::Task, KernelObject, KernelObjectId, KernelObjectType};
+use crate::{memory::Pmm, Platform};
+use alloc::{
+    collections::BTreeSet,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use poplar::syscall::{JobLimits, JobUsage, MemoryUsage};
+use spinning_top::Spinlock;
+
+/// The period over which a job's CPU share is measured. A job that has used up its share of a period is
+/// throttled until the next one starts.
+const CPU_SHARE_PERIOD: Duration = Duration::from_millis(100);
+
+/// A `Job` groups tasks together, so they can be accounted for and killed as a unit. Jobs form a tree: every
+/// task belongs to a job, and every job apart from the root job (which the boot tasks are started in) has a
+/// parent. Killing a job kills all of its tasks, and all of the tasks in its child jobs.
+///
+/// Jobs only hold weak references to their tasks and children, so they don't keep them alive. A task keeps its
+/// job (and so all of the job's ancestors) alive.
+pub struct Job<P>
+where
+    P: Platform,
+{
+    id: KernelObjectId,
+    pub owner: KernelObjectId,
+    parent: Option<Arc<Job<P>>>,
+    children: Spinlock<Vec<Weak<Job<P>>>>,
+    tasks: Spinlock<Vec<Weak<Task<P>>>>,
+    killed: AtomicBool,
+    /// The CPU time used by the tasks in this job, not including the time used by its child jobs. This is
+    /// sampled by the scheduler on each timer tick, so is only as accurate as the timer period.
+    cpu_time_ns: AtomicU64,
+
+    limits: Spinlock<JobLimits>,
+    /// The memory committed on behalf of the tasks in this job and its descendants, which is checked against
+    /// `JobLimits::max_committed_memory`. Memory is uncharged when the `MemoryCharge` for it is dropped.
+    committed_memory: AtomicUsize,
+    /// When the current CPU share period started, and the CPU time used by this job and its descendants during it,
+    /// both in nanoseconds.
+    period_start_ns: AtomicU64,
+    period_cpu_time_ns: AtomicU64,
+}
+
+impl<P> Job<P>
+where
+    P: Platform,
+{
+    /// Create a new job. If `parent` is `None`, the job is the root of a new tree of jobs.
+    pub fn new(owner: KernelObjectId, parent: Option<Arc<Job<P>>>) -> Arc<Job<P>> {
+        let job = Arc::new(Job {
+            id: alloc_kernel_object_id(),
+            owner,
+            parent: parent.clone(),
+            children: Spinlock::new(Vec::new()),
+            tasks: Spinlock::new(Vec::new()),
+            killed: AtomicBool::new(false),
+            cpu_time_ns: AtomicU64::new(0),
+            limits: Spinlock::new(JobLimits::UNLIMITED),
+            committed_memory: AtomicUsize::new(0),
+            period_start_ns: AtomicU64::new(0),
+            period_cpu_time_ns: AtomicU64::new(0),
+        });
+
+        if let Some(parent) = parent {
+            let mut children = parent.children.lock();
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&job));
+        }
+        job
+    }
+
+    pub fn add_task(&self, task: &Arc<Task<P>>) {
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|task| task.strong_count() > 0);
+        tasks.push(Arc::downgrade(task));
+    }
+
+    /// Kill this job. Its tasks, and the tasks of its child jobs, are killed the next time they enter the kernel
+    /// (at the latest, on the next timer tick while they are running).
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if this job, or any of its ancestors, has been killed.
+    pub fn is_killed(&self) -> bool {
+        self.ancestry().any(|job| job.killed.load(Ordering::SeqCst))
+    }
+
+    /// Get the root of the tree of jobs this job is in.
+    pub fn root(self: &Arc<Self>) -> Arc<Job<P>> {
+        let mut job = self.clone();
+        while let Some(parent) = job.parent.clone() {
+            job = parent;
+        }
+        job
+    }
+
+    /// Collect the tasks in this job and all of its descendants that haven't exited into `tasks`.
+    pub fn live_tasks(&self, tasks: &mut Vec<Arc<Task<P>>>) {
+        tasks.extend(
+            self.tasks
+                .lock()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|task| task.state.lock().exit_status().is_none()),
+        );
+
+        let children: Vec<Arc<Job<P>>> = self.children.lock().iter().filter_map(Weak::upgrade).collect();
+        for child in children {
+            child.live_tasks(tasks);
+        }
+    }
+
+    /// Discard the memory of discardable memory objects mapped into the address spaces of the tasks in this job
+    /// and its descendants, until at least `count` frames have been freed (if possible). Returns the number of
+    /// frames freed. See `AddressSpace::discard_memory`.
+    pub fn discard_memory(&self, allocator: &Pmm, count: usize) -> usize {
+        let mut tasks = Vec::new();
+        self.live_tasks(&mut tasks);
+
+        // Threads share their address space with the task that created them, so each is only visited once
+        let mut visited = BTreeSet::new();
+        let mut num_frames = 0;
+        for task in tasks {
+            if num_frames >= count {
+                break;
+            }
+            if visited.insert(task.address_space.id) {
+                num_frames += task.address_space.discard_memory(allocator);
+            }
+        }
+        num_frames
+    }
+
+    /// Iterate over this job and each of its ancestors, starting with this job.
+    fn ancestry(&self) -> impl Iterator<Item = &Job<P>> {
+        core::iter::successors(Some(self), |job| job.parent.as_deref())
+    }
+
+    pub fn limits(&self) -> JobLimits {
+        *self.limits.lock()
+    }
+
+    pub fn set_limits(&self, limits: JobLimits) {
+        *self.limits.lock() = limits;
+    }
+
+    /// Get the limits that apply to the tasks in this job, which are the tightest of the limits of this job and
+    /// its ancestors.
+    pub fn effective_limits(&self) -> JobLimits {
+        self.ancestry().fold(JobLimits::UNLIMITED, |limits, job| limits.min(job.limits()))
+    }
+
+    /// Charge `bytes` of committed memory to this job and its ancestors, if doing so doesn't take any of them over
+    /// their memory limit. Returns `None` (and charges nothing) if it would.
+    pub fn try_charge_memory(self: &Arc<Self>, bytes: usize) -> Option<MemoryCharge> {
+        if self.ancestry().any(|job| {
+            job.committed_memory.load(Ordering::Relaxed).saturating_add(bytes) > job.limits().max_committed_memory
+        }) {
+            return None;
+        }
+        Some(self.charge_memory(bytes))
+    }
+
+    /// Charge `bytes` of committed memory to this job and its ancestors, even if it takes them over their limits.
+    /// This is used when the kernel has to commit memory on a task's behalf (e.g. when it accesses the task's
+    /// memory in a system call), and can't fail.
+    pub fn charge_memory(self: &Arc<Self>, bytes: usize) -> MemoryCharge {
+        for job in self.ancestry() {
+            job.committed_memory.fetch_add(bytes, Ordering::Relaxed);
+        }
+        MemoryCharge { job: self.clone(), bytes }
+    }
+
+    /// Charge `time` spent running one of this job's tasks, which was measured at `now` (the time since boot). The
+    /// time also counts against the CPU share of each of the job's ancestors.
+    pub fn charge_cpu_time(&self, time: Duration, now: Duration) {
+        let time = time.as_nanos() as u64;
+        let now = now.as_nanos() as u64;
+        self.cpu_time_ns.fetch_add(time, Ordering::Relaxed);
+
+        for job in self.ancestry() {
+            // XXX: this can race with other CPUs charging the job, but the share is only approximate anyway
+            if now.saturating_sub(job.period_start_ns.load(Ordering::Relaxed))
+                >= CPU_SHARE_PERIOD.as_nanos() as u64
+            {
+                job.period_start_ns.store(now, Ordering::Relaxed);
+                job.period_cpu_time_ns.store(0, Ordering::Relaxed);
+            }
+            job.period_cpu_time_ns.fetch_add(time, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns `true` if this job, or any of its ancestors, has used up its CPU share for the current period. `now`
+    /// is the time since boot.
+    pub fn is_throttled(&self, now: Duration) -> bool {
+        let now = now.as_nanos() as u64;
+        self.ancestry().any(|job| {
+            let share = job.limits().cpu_share as u64;
+            let in_period = now.saturating_sub(job.period_start_ns.load(Ordering::Relaxed))
+                < CPU_SHARE_PERIOD.as_nanos() as u64;
+            share < 100
+                && in_period
+                && job.period_cpu_time_ns.load(Ordering::Relaxed)
+                    >= CPU_SHARE_PERIOD.as_nanos() as u64 * share / 100
+        })
+    }
+
+    /// Get the resources used by the tasks in this job and all of its descendants. The memory usage of each
+    /// address space is only counted once, even if several of the tasks (e.g. threads of the same task) share
+    /// it.
+    pub fn usage(&self) -> JobUsage {
+        let mut usage =
+            JobUsage { memory: MemoryUsage { reserved: 0, committed: 0 }, cpu_time_ns: 0, num_tasks: 0 };
+        let mut address_spaces = BTreeSet::new();
+        self.accumulate_usage(&mut usage, &mut address_spaces);
+        usage
+    }
+
+    fn accumulate_usage(&self, usage: &mut JobUsage, address_spaces: &mut BTreeSet<KernelObjectId>) {
+        usage.cpu_time_ns += self.cpu_time_ns.load(Ordering::Relaxed);
+
+        let tasks: Vec<Arc<Task<P>>> = self.tasks.lock().iter().filter_map(Weak::upgrade).collect();
+        for task in tasks {
+            if task.state.lock().exit_status().is_some() {
+                continue;
+            }
+
+            usage.num_tasks += 1;
+            if address_spaces.insert(task.address_space.id()) {
+                let memory = task.address_space.memory_usage();
+                usage.memory.reserved += memory.reserved;
+                usage.memory.committed += memory.committed;
+            }
+        }
+
+        let children: Vec<Arc<Job<P>>> = self.children.lock().iter().filter_map(Weak::upgrade).collect();
+        for child in children {
+            child.accumulate_usage(usage, address_spaces);
+        }
+    }
+}
+
+/// Lets a `MemoryCharge` uncharge memory from a job without knowing which platform it's for.
+trait MemoryAccount: Send + Sync {
+    fn uncharge_memory(&self, bytes: usize);
+}
+
+impl<P> MemoryAccount for Job<P>
+where
+    P: Platform,
+{
+    fn uncharge_memory(&self, bytes: usize) {
+        for job in self.ancestry() {
+            job.committed_memory.fetch_sub(bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Memory committed on behalf of a job, which stays charged to the job and its ancestors until this is dropped.
+/// Whatever owns the memory should hold its charge, and drop it once the memory has been freed.
+pub struct MemoryCharge {
+    job: Arc<dyn MemoryAccount>,
+    bytes: usize,
+}
+
+impl MemoryCharge {
+    /// Leave the memory charged to the job permanently. This is used for memory that is never freed, or that
+    /// can't be reused because something outside the kernel might still be accessing it.
+    pub fn keep(mut self) {
+        self.bytes = 0;
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.job.uncharge_memory(self.bytes);
+    }
+}
+
+impl fmt::Debug for MemoryCharge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryCharge").field("bytes", &self.bytes).finish_non_exhaustive()
+    }
+}
+
+impl<P> KernelObject for Job<P>
+where
+    P: Platform,
+{
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+
+    fn typ(&self) -> KernelObjectType {
+        KernelObjectType::Job
+    }
+}
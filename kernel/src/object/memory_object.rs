@@ -1,32 +1,594 @@
-use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType};
-use alloc::sync::Arc;
-use hal::memory::{Flags, PAddr};
+use super::{
+    address_space::PageFaultAccess,
+    alloc_kernel_object_id,
+    job::MemoryCharge,
+    KernelObject,
+    KernelObjectId,
+    KernelObjectType,
+};
+use crate::{memory::Pmm, Platform};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use hal::memory::{Flags, FrameSize, PAddr, Size4KiB, VAddr};
 use seed::boot_info::Segment;
+use spinning_top::Spinlock;
 
 #[derive(Debug)]
 pub struct MemoryObject {
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
-    pub physical_address: PAddr,
     /// Size of this MemoryObject in bytes.
     pub size: usize,
     pub flags: Flags,
+    backing: Backing,
+    /// The copy-on-write clones of this object, and which of its pages they still share with it.
+    sharing: Spinlock<Sharing>,
+    /// Where this object is mapped, so that its mappings can be updated when its pages change.
+    mapped_at: Spinlock<Vec<(Weak<dyn Mapper>, VAddr)>>,
+}
+
+/// Something a memory object can be mapped into. This lets a memory object update its mappings when the memory
+/// or flags of its pages change, without knowing which platform the address spaces it's mapped into are for.
+pub trait Mapper: Send + Sync {
+    /// Remap the pages between `offsets` of `memory_object`, which is mapped at `virtual_address`, with the
+    /// memory and flags it now gives them. This must make sure no CPU can still access the old mappings.
+    fn remap(&self, memory_object: &MemoryObject, virtual_address: VAddr, offsets: Range<usize>);
+}
+
+#[derive(Debug)]
+enum Backing {
+    /// The object is backed by a physically-contiguous area of memory, starting at `physical_address`. If the
+    /// memory was allocated for the object, it holds the `charge` for it, and frees it when it's dropped.
+    Contiguous { physical_address: PAddr, charge: Option<MemoryCharge> },
+    /// Memory is only committed to the object when each page is first accessed, and is zeroed when it is.
+    Lazy {
+        /// The pages that have been committed, and the charges for them, keyed by the page's offset into the
+        /// object.
+        committed: Spinlock<BTreeMap<usize, (PAddr, MemoryCharge)>>,
+        /// Set if the object's memory can be discarded. See `MemoryObject::new_discardable`.
+        discard: Option<DiscardState>,
+    },
+    /// The object is a copy-on-write clone of `parent`, as it was when the clone was created. Pages are shared
+    /// with the parent until they're first written to through the clone, at which point they're copied into a
+    /// private frame. The parent's mappings of the shared pages are write-protected, and a page is copied into
+    /// the clone before the parent can write to it (see `Sharing`), so writes to the parent are never visible
+    /// through the clone.
+    CopyOnWrite {
+        parent: Arc<MemoryObject>,
+        /// The offset into `parent` of the start of the clone. Clones can be of any page-aligned part of the
+        /// parent.
+        parent_offset: usize,
+        /// The private copies of pages that have been written to, and the charges for them, keyed by the page's
+        /// offset into the object.
+        copied: Spinlock<BTreeMap<usize, (PAddr, MemoryCharge)>>,
+        /// Copy-on-write objects can only be mapped into a single address space, as copying a page only
+        /// updates the mapping in the address space that faulted.
+        mapped: AtomicBool,
+    },
+}
+
+/// Tracks the copy-on-write clones of an object. Each page shared with a clone is write-protected in the object's
+/// mappings, and when one of them is written to, it's copied into each clone that still shares it before it's
+/// made writable again.
+#[derive(Debug, Default)]
+struct Sharing {
+    clones: Vec<Weak<MemoryObject>>,
+    /// The offsets of the pages that might be shared with a clone, which can't be written to.
+    protected: BTreeSet<usize>,
+    /// Incremented each time a clone is created, so a page isn't unprotected if a clone that shares it is created
+    /// while it's being copied into the others.
+    generation: u64,
+}
+
+/// Tracks whether the memory of a discardable object can be discarded, and whether it has been. Both flags are
+/// only changed with the object's `committed` lock held, so memory can't be discarded once the object's owner has
+/// marked it as not discardable.
+#[derive(Debug)]
+struct DiscardState {
+    discardable: AtomicBool,
+    /// Whether any memory has been discarded since the owner last called `set_discardable`.
+    discarded: AtomicBool,
+    /// Discardable objects can only be mapped into a single address space, as discarding a page only unmaps it
+    /// from the address space its memory is being reclaimed through.
+    mapped: AtomicBool,
 }
 
 impl MemoryObject {
+    /// Create a memory object backed by an existing area of physical memory, which isn't freed when the object is
+    /// dropped.
     pub fn new(owner: KernelObjectId, physical_address: PAddr, size: usize, flags: Flags) -> Arc<MemoryObject> {
-        Arc::new(MemoryObject { id: alloc_kernel_object_id(), owner, physical_address, size, flags })
+        Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            size,
+            flags,
+            backing: Backing::Contiguous { physical_address, charge: None },
+            sharing: Spinlock::new(Sharing::default()),
+            mapped_at: Spinlock::new(Vec::new()),
+        })
+    }
+
+    /// Create a memory object that owns the physical memory allocated for it, which is freed (and uncharged with
+    /// `charge`) when the object is dropped.
+    pub fn new_owned(
+        owner: KernelObjectId,
+        physical_address: PAddr,
+        size: usize,
+        flags: Flags,
+        charge: MemoryCharge,
+    ) -> Arc<MemoryObject> {
+        Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            size,
+            flags,
+            backing: Backing::Contiguous { physical_address, charge: Some(charge) },
+            sharing: Spinlock::new(Sharing::default()),
+            mapped_at: Spinlock::new(Vec::new()),
+        })
     }
 
     pub fn from_boot_info(owner: KernelObjectId, segment: &Segment) -> Arc<MemoryObject> {
         Arc::new(MemoryObject {
             id: alloc_kernel_object_id(),
             owner,
-            physical_address: segment.physical_address,
             size: segment.size,
             flags: segment.flags,
+            backing: Backing::Contiguous { physical_address: segment.physical_address, charge: None },
+            sharing: Spinlock::new(Sharing::default()),
+            mapped_at: Spinlock::new(Vec::new()),
+        })
+    }
+
+    /// Create a memory object that physical memory is only committed to as it is accessed.
+    pub fn new_lazy(owner: KernelObjectId, size: usize, flags: Flags) -> Arc<MemoryObject> {
+        Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            size,
+            flags,
+            backing: Backing::Lazy { committed: Spinlock::new(BTreeMap::new()), discard: None },
+            sharing: Spinlock::new(Sharing::default()),
+            mapped_at: Spinlock::new(Vec::new()),
+        })
+    }
+
+    /// Create a lazy memory object whose memory can be discarded when memory is short, while it's marked as
+    /// discardable with `set_discardable`. Objects start off not discardable.
+    pub fn new_discardable(owner: KernelObjectId, size: usize, flags: Flags) -> Arc<MemoryObject> {
+        Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            size,
+            flags,
+            backing: Backing::Lazy {
+                committed: Spinlock::new(BTreeMap::new()),
+                discard: Some(DiscardState {
+                    discardable: AtomicBool::new(false),
+                    discarded: AtomicBool::new(false),
+                    mapped: AtomicBool::new(false),
+                }),
+            },
+            sharing: Spinlock::new(Sharing::default()),
+            mapped_at: Spinlock::new(Vec::new()),
         })
     }
+
+    /// Create a copy-on-write clone of this memory object. No memory is copied until a page of the clone (or the
+    /// same page of this object) is written to.
+    pub fn clone_cow(self: &Arc<Self>, owner: KernelObjectId) -> Arc<MemoryObject> {
+        self.clone_cow_range(owner, 0, self.size, self.flags)
+    }
+
+    /// Create a copy-on-write clone of `size` bytes of this memory object, starting at `offset`, which is mapped
+    /// with `flags` instead of the flags of this object.
+    pub fn clone_cow_range(
+        self: &Arc<Self>,
+        owner: KernelObjectId,
+        offset: usize,
+        size: usize,
+        flags: Flags,
+    ) -> Arc<MemoryObject> {
+        assert!(offset % Size4KiB::SIZE == 0 && size % Size4KiB::SIZE == 0);
+        assert!(offset.checked_add(size).map_or(false, |end| end <= self.size));
+
+        let clone = Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            size,
+            flags,
+            backing: Backing::CopyOnWrite {
+                parent: self.clone(),
+                parent_offset: offset,
+                copied: Spinlock::new(BTreeMap::new()),
+                mapped: AtomicBool::new(false),
+            },
+            sharing: Spinlock::new(Sharing::default()),
+            mapped_at: Spinlock::new(Vec::new()),
+        });
+
+        /*
+         * The clone is a snapshot of this object, so the pages it shares with us are write-protected in our
+         * mappings, and are copied into it before we can write to them again.
+         */
+        {
+            let mut sharing = self.sharing.lock();
+            sharing.clones.retain(|clone| clone.strong_count() > 0);
+            sharing.clones.push(Arc::downgrade(&clone));
+            sharing.protected.extend((offset..(offset + size)).step_by(Size4KiB::SIZE));
+            sharing.generation += 1;
+        }
+        self.remap(offset..(offset + size));
+
+        clone
+    }
+
+    /// Get the physical address of the start of the object, if it's backed by physically-contiguous memory.
+    pub fn physical_address(&self) -> Option<PAddr> {
+        match self.backing {
+            Backing::Contiguous { physical_address, .. } => Some(physical_address),
+            _ => None,
+        }
+    }
+
+    /// Get the physical address of the page at `offset` into the object, and the flags it should currently be
+    /// mapped with. Returns `None` if no memory has been committed to the page yet.
+    pub fn page(&self, offset: usize) -> Option<(PAddr, Flags)> {
+        assert!(offset < self.size);
+        assert!(offset % Size4KiB::SIZE == 0);
+
+        let flags = self.page_flags(offset);
+        match self.backing {
+            Backing::Contiguous { physical_address, .. } => Some((physical_address + offset, flags)),
+            Backing::Lazy { ref committed, .. } => committed.lock().get(&offset).map(|&(frame, _)| (frame, flags)),
+            Backing::CopyOnWrite { ref parent, parent_offset, ref copied, .. } => {
+                match copied.lock().get(&offset) {
+                    Some(&(copy, _)) => Some((copy, flags)),
+                    None => parent
+                        .page(parent_offset + offset)
+                        .map(|(frame, _)| (frame, Flags { writable: false, ..self.flags })),
+                }
+            }
+        }
+    }
+
+    /// Get the flags the page at `offset` should be mapped with, if memory has been committed to it. Pages shared
+    /// with a clone are never writable.
+    pub fn page_flags(&self, offset: usize) -> Flags {
+        Flags { writable: self.flags.writable && !self.is_protected(offset), ..self.flags }
+    }
+
+    fn is_protected(&self, offset: usize) -> bool {
+        self.sharing.lock().protected.contains(&offset)
+    }
+
+    /// Whether any of this object's pages are write-protected because they're shared with a clone. If they are,
+    /// the object must be mapped a page at a time, even if it's physically contiguous.
+    pub fn has_protected_pages(&self) -> bool {
+        !self.sharing.lock().protected.is_empty()
+    }
+
+    /// Record that this object has been mapped at `virtual_address` by `mapper`. This must be done before any of
+    /// its pages are mapped, so that changes to them made while they're being mapped aren't missed.
+    pub fn add_mapping(&self, mapper: Weak<dyn Mapper>, virtual_address: VAddr) {
+        let mut mapped_at = self.mapped_at.lock();
+        mapped_at.retain(|(mapper, _)| mapper.strong_count() > 0);
+        mapped_at.push((mapper, virtual_address));
+    }
+
+    /// Record that this object is no longer mapped at `virtual_address` by `mapper`.
+    pub fn remove_mapping(&self, mapper: &dyn Mapper, virtual_address: VAddr) {
+        self.mapped_at.lock().retain(|(other, other_address)| {
+            !(ptr::addr_eq(other.as_ptr(), mapper as *const dyn Mapper) && *other_address == virtual_address)
+        });
+    }
+
+    /// Remap the pages between `offsets` in each of this object's mappings.
+    fn remap(&self, offsets: Range<usize>) {
+        let mappings: Vec<_> = self
+            .mapped_at
+            .lock()
+            .iter()
+            .filter_map(|(mapper, virtual_address)| Some((mapper.upgrade()?, *virtual_address)))
+            .collect();
+        for (mapper, virtual_address) in mappings {
+            mapper.remap(self, virtual_address, offsets.clone());
+        }
+    }
+
+    /// Get the number of bytes of memory that have been committed to this object. For copy-on-write objects,
+    /// only memory that isn't shared with the parent is counted.
+    pub fn committed(&self) -> usize {
+        match self.backing {
+            Backing::Contiguous { .. } => self.size,
+            Backing::Lazy { ref committed, .. } => committed.lock().len() * Size4KiB::SIZE,
+            Backing::CopyOnWrite { ref copied, .. } => copied.lock().len() * Size4KiB::SIZE,
+        }
+    }
+
+    /// Mark a copy-on-write or discardable object as having been mapped. Returns `false` if it has already been
+    /// mapped, in which case it shouldn't be mapped again.
+    pub fn mark_mapped(&self) -> bool {
+        match self.backing {
+            Backing::Contiguous { .. } | Backing::Lazy { discard: None, .. } => true,
+            Backing::Lazy { discard: Some(ref discard), .. } => !discard.mapped.swap(true, Ordering::SeqCst),
+            Backing::CopyOnWrite { ref mapped, .. } => !mapped.swap(true, Ordering::SeqCst),
+        }
+    }
+
+    /// Mark a copy-on-write or discardable object as no longer mapped, once it has been unmapped, so that it can
+    /// be mapped again.
+    pub fn mark_unmapped(&self) {
+        match self.backing {
+            Backing::Contiguous { .. } | Backing::Lazy { discard: None, .. } => (),
+            Backing::Lazy { discard: Some(ref discard), .. } => discard.mapped.store(false, Ordering::SeqCst),
+            Backing::CopyOnWrite { ref mapped, .. } => mapped.store(false, Ordering::SeqCst),
+        }
+    }
+
+    pub fn is_discardable(&self) -> bool {
+        matches!(self.backing, Backing::Lazy { discard: Some(_), .. })
+    }
+
+    /// Mark whether the memory committed to a discardable object can be discarded. Returns whether any of it has
+    /// been discarded since this was last called, or `None` if the object isn't discardable.
+    pub fn set_discardable(&self, discardable: bool) -> Option<bool> {
+        let Backing::Lazy { ref committed, discard: Some(ref discard) } = self.backing else {
+            return None;
+        };
+
+        let _committed = committed.lock();
+        discard.discardable.store(discardable, Ordering::SeqCst);
+        Some(discard.discarded.swap(false, Ordering::SeqCst))
+    }
+
+    /// Free the memory committed to this object, if it's discardable and is currently marked as discardable.
+    /// `unmap` is called with the offset of each page, and must remove any mapping of it. Once every page has
+    /// been unmapped, `shootdown` is called, and must make sure no CPU can still access them through its TLB
+    /// before their memory is freed. Returns the number of frames freed.
+    ///
+    /// This is called to reclaim memory when an allocation fails, which can happen while the object's pages are
+    /// locked (e.g. while memory is being committed to it), so nothing is discarded if they are.
+    pub fn discard(&self, allocator: &Pmm, mut unmap: impl FnMut(usize), shootdown: impl FnOnce()) -> usize {
+        let Backing::Lazy { ref committed, discard: Some(ref discard) } = self.backing else {
+            return 0;
+        };
+        let Some(mut committed) = committed.try_lock() else {
+            return 0;
+        };
+        if !discard.discardable.load(Ordering::SeqCst) || committed.is_empty() {
+            return 0;
+        }
+
+        let pages = core::mem::take(&mut *committed);
+        for &offset in pages.keys() {
+            unmap(offset);
+        }
+        shootdown();
+
+        let num_frames = pages.len();
+        for (frame, _charge) in pages.into_values() {
+            allocator.free(frame, 1);
+        }
+        discard.discarded.store(true, Ordering::SeqCst);
+        num_frames
+    }
+
+    /// Try to resolve a fault caused by a permitted `access` to the page at `offset` into the object, by
+    /// committing memory to it or by copying it. Returns the physical address and flags the page should now be
+    /// mapped with, or `None` if the fault can't be resolved by this object (i.e. it's a genuine fault).
+    ///
+    /// `charge` is called with the number of bytes of memory about to be committed, before any memory is
+    /// allocated, and the charge it returns is held until the memory is freed. If it returns `None`, or the memory
+    /// can't be allocated, the fault is not resolved.
+    pub fn resolve_fault<P>(
+        &self,
+        offset: usize,
+        access: PageFaultAccess,
+        allocator: &Pmm,
+        charge: &dyn Fn(usize) -> Option<MemoryCharge>,
+    ) -> Option<(PAddr, Flags)>
+    where
+        P: Platform,
+    {
+        /*
+         * Before a page shared with a clone can be written to, it has to be copied into the clone.
+         */
+        if access == PageFaultAccess::Write && !self.unshare_page::<P>(offset, allocator, charge) {
+            return None;
+        }
+
+        match self.backing {
+            Backing::Contiguous { physical_address, .. } => {
+                /*
+                 * Contiguous objects are always fully mapped, so only writes to pages that were shared with a
+                 * clone (or that have been unshared by another CPU) can fault.
+                 */
+                (access == PageFaultAccess::Write).then(|| (physical_address + offset, self.page_flags(offset)))
+            }
+            Backing::Lazy { ref committed, .. } => {
+                let mut committed = committed.lock();
+                let frame = match committed.get(&offset) {
+                    Some(&(frame, _)) => frame,
+                    None => {
+                        let charge = charge(Size4KiB::SIZE)?;
+                        let frame = allocator.try_alloc(1)?;
+                        unsafe {
+                            P::zero_phys_memory(frame, Size4KiB::SIZE);
+                        }
+                        committed.insert(offset, (frame, charge));
+                        frame
+                    }
+                };
+                Some((frame, self.page_flags(offset)))
+            }
+            Backing::CopyOnWrite { ref parent, parent_offset, ref copied, .. } => {
+                if access != PageFaultAccess::Write {
+                    /*
+                     * The page hasn't been written to, so is shared with the parent. If we've faulted,
+                     * the parent can't have committed memory to it yet.
+                     */
+                    let (frame, _) =
+                        parent.resolve_fault::<P>(parent_offset + offset, access, allocator, charge)?;
+                    return Some((frame, Flags { writable: false, ..self.flags }));
+                }
+
+                /*
+                 * This is the first write to the page (or another CPU has already copied it, and
+                 * we've faulted on a stale TLB entry), so copy it from the parent. If the parent
+                 * hasn't committed memory to the page, there's nothing to copy.
+                 */
+                let mut copied = copied.lock();
+                let copy = match copied.get(&offset) {
+                    Some(&(copy, _)) => copy,
+                    None => {
+                        let charge = charge(Size4KiB::SIZE)?;
+                        let copy = allocator.try_alloc(1)?;
+                        unsafe {
+                            match parent.page(parent_offset + offset) {
+                                Some((frame, _)) => P::copy_phys_memory(frame, copy, Size4KiB::SIZE),
+                                None => P::zero_phys_memory(copy, Size4KiB::SIZE),
+                            }
+                        }
+                        copied.insert(offset, (copy, charge));
+                        copy
+                    }
+                };
+                Some((copy, self.page_flags(offset)))
+            }
+        }
+    }
+
+    /// Copy the page at `offset` into each clone that might still share it, so that it can be written to. Returns
+    /// `false` if memory couldn't be committed to one of the copies.
+    fn unshare_page<P>(
+        &self,
+        offset: usize,
+        allocator: &Pmm,
+        charge: &dyn Fn(usize) -> Option<MemoryCharge>,
+    ) -> bool
+    where
+        P: Platform,
+    {
+        loop {
+            let (clones, generation) = {
+                let sharing = self.sharing.lock();
+                if !sharing.protected.contains(&offset) {
+                    return true;
+                }
+                (sharing.clones.clone(), sharing.generation)
+            };
+
+            for clone in clones.iter().filter_map(Weak::upgrade) {
+                if !clone.copy_from_parent::<P>(offset, allocator, charge) {
+                    return false;
+                }
+            }
+
+            /*
+             * If another clone has been created while we were copying the page, it might share it too, so we
+             * have to go round again.
+             */
+            let mut sharing = self.sharing.lock();
+            if sharing.generation == generation {
+                sharing.protected.remove(&offset);
+                return true;
+            }
+        }
+    }
+
+    /// Give a copy-on-write clone its own copy of the page at `parent_offset` into its parent, if it covers that
+    /// page and hasn't already copied it. Returns `false` if memory couldn't be committed to the copy.
+    fn copy_from_parent<P>(
+        &self,
+        parent_offset: usize,
+        allocator: &Pmm,
+        charge: &dyn Fn(usize) -> Option<MemoryCharge>,
+    ) -> bool
+    where
+        P: Platform,
+    {
+        let Backing::CopyOnWrite { ref parent, parent_offset: start, ref copied, .. } = self.backing else {
+            return true;
+        };
+        let Some(offset) = parent_offset.checked_sub(start).filter(|&offset| offset < self.size) else {
+            return true;
+        };
+
+        {
+            let mut copied = copied.lock();
+            if copied.contains_key(&offset) {
+                return true;
+            }
+            let Some(charge) = charge(Size4KiB::SIZE) else {
+                return false;
+            };
+            let Some(copy) = allocator.try_alloc(1) else {
+                return false;
+            };
+            unsafe {
+                match parent.page(parent_offset) {
+                    Some((frame, _)) => P::copy_phys_memory(frame, copy, Size4KiB::SIZE),
+                    None => P::zero_phys_memory(copy, Size4KiB::SIZE),
+                }
+            }
+            copied.insert(offset, (copy, charge));
+        }
+
+        self.page_changed(offset);
+        true
+    }
+
+    /// Update the mappings of the page at `offset` once its memory has changed, and those of any clones that are
+    /// still reading it through this object.
+    fn page_changed(&self, offset: usize) {
+        self.remap(offset..(offset + Size4KiB::SIZE));
+
+        let clones = self.sharing.lock().clones.clone();
+        for clone in clones.iter().filter_map(Weak::upgrade) {
+            let Backing::CopyOnWrite { parent_offset, ref copied, .. } = clone.backing else {
+                continue;
+            };
+            let Some(clone_offset) = offset.checked_sub(parent_offset).filter(|&offset| offset < clone.size)
+            else {
+                continue;
+            };
+            if !copied.lock().contains_key(&clone_offset) {
+                clone.page_changed(clone_offset);
+            }
+        }
+    }
+}
+
+impl Drop for MemoryObject {
+    /// Free the memory committed to lazy objects, the private copies made by copy-on-write objects, and the memory
+    /// of physically-contiguous objects that own it, and uncharge it from the jobs it was charged to. Other
+    /// physically-contiguous objects don't own their memory (e.g. the framebuffer), so it's left alone.
+    fn drop(&mut self) {
+        let allocator = crate::PMM.get();
+        let frames = match self.backing {
+            Backing::Contiguous { physical_address, ref mut charge } => {
+                if let Some(_charge) = charge.take() {
+                    allocator.free(physical_address, self.size / Size4KiB::SIZE);
+                }
+                return;
+            }
+            Backing::Lazy { ref mut committed, .. } => core::mem::take(committed.get_mut()),
+            Backing::CopyOnWrite { ref mut copied, .. } => core::mem::take(copied.get_mut()),
+        };
+
+        for (frame, _charge) in frames.into_values() {
+            allocator.free(frame, 1);
+        }
+    }
 }
 
 impl KernelObject for MemoryObject {
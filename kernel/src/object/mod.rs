@@ -1,7 +1,10 @@
 pub mod address_space;
 pub mod channel;
+pub mod dma_domain;
 pub mod event;
+pub mod job;
 pub mod memory_object;
+pub mod pipe;
 pub mod task;
 
 use core::sync::atomic::{AtomicU64, Ordering};
@@ -12,6 +15,12 @@ use mulch::{downcast::DowncastSync, impl_downcast};
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct KernelObjectId(u64);
 
+impl From<KernelObjectId> for u64 {
+    fn from(id: KernelObjectId) -> u64 {
+        id.0
+    }
+}
+
 /// A kernel object ID of `0` is reserved as a sentinel value that will never point to a real kernel object. It is
 /// used to mark things like the `owner` of a kernel object being the kernel itself.
 pub const SENTINEL_KERNEL_ID: KernelObjectId = KernelObjectId(0);
@@ -31,6 +40,9 @@ pub enum KernelObjectType {
     MemoryObject,
     Channel,
     Event,
+    DmaDomain,
+    Job,
+    Pipe,
 }
 
 /// This trait should be implemented by all types that implement kernel objects, and allows common code to
@@ -0,0 +1,94 @@
+use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType};
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+};
+use poplar::syscall::PIPE_CAPACITY;
+use spinning_top::Spinlock;
+
+/// One end of a pipe, which carries a stream of bytes from its write end to its read end. The bytes are held in a
+/// buffer shared by the two ends, which can hold at most `PIPE_CAPACITY` bytes.
+#[derive(Debug)]
+pub struct PipeEnd {
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    pub is_read_end: bool,
+    buffer: Arc<Spinlock<VecDeque<u8>>>,
+    other_end: Weak<PipeEnd>,
+}
+
+impl PipeEnd {
+    /// Create a new pipe, returning its read end and its write end.
+    pub fn new_pipe(owner: KernelObjectId) -> (Arc<PipeEnd>, Arc<PipeEnd>) {
+        let buffer = Arc::new(Spinlock::new(VecDeque::new()));
+        let mut read_end = Arc::new(PipeEnd {
+            id: alloc_kernel_object_id(),
+            owner,
+            is_read_end: true,
+            buffer: buffer.clone(),
+            other_end: Weak::default(),
+        });
+
+        let write_end = Arc::new(PipeEnd {
+            id: alloc_kernel_object_id(),
+            owner,
+            is_read_end: false,
+            buffer,
+            other_end: Arc::downgrade(&read_end),
+        });
+
+        unsafe {
+            Arc::get_mut_unchecked(&mut read_end).other_end = Arc::downgrade(&write_end);
+        }
+
+        (read_end, write_end)
+    }
+
+    /// Get the other end of the pipe, if it hasn't been dropped.
+    pub fn other_end(&self) -> Option<Arc<PipeEnd>> {
+        self.other_end.upgrade()
+    }
+
+    pub fn is_peer_closed(&self) -> bool {
+        self.other_end.strong_count() == 0
+    }
+
+    /// Whether there's data waiting to be read from the pipe. This is only asserted for the read end.
+    pub fn is_readable(&self) -> bool {
+        self.is_read_end && !self.buffer.lock().is_empty()
+    }
+
+    /// Whether there's space in the pipe to write to. This is only asserted for the write end.
+    pub fn is_writable(&self) -> bool {
+        !self.is_read_end && self.buffer.lock().len() < PIPE_CAPACITY
+    }
+
+    /// Move as many bytes as are waiting (up to the size of `buffer`) out of the pipe and into `buffer`, returning
+    /// the number of bytes read.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        let mut pipe = self.buffer.lock();
+        let length = usize::min(buffer.len(), pipe.len());
+        for (to, from) in buffer.iter_mut().zip(pipe.drain(0..length)) {
+            *to = from;
+        }
+        length
+    }
+
+    /// Write as many bytes of `data` as there is space for into the pipe, returning the number of bytes written.
+    pub fn write(&self, data: &[u8]) -> usize {
+        let mut pipe = self.buffer.lock();
+        let length = usize::min(data.len(), PIPE_CAPACITY - pipe.len());
+        pipe.extend(&data[0..length]);
+        length
+    }
+}
+
+impl KernelObject for PipeEnd {
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+
+    fn typ(&self) -> KernelObjectType {
+        KernelObjectType::Pipe
+    }
+}
@@ -1,22 +1,39 @@
 use super::{
     address_space::{AddressSpace, TaskSlot},
     alloc_kernel_object_id,
+    channel::ChannelEnd,
     event::Event,
+    job::Job,
     KernelObject,
     KernelObjectId,
     KernelObjectType,
 };
 use crate::{
     memory::{vmm::Stack, Pmm},
+    perf::TaskPerf,
     Platform,
 };
-use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use core::{
     cell::UnsafeCell,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use hal::memory::{FrameSize, Size4KiB, VAddr};
+use mulch::math::align_down;
+use poplar::{
+    caps::Capabilities,
+    syscall::{
+        MemoryRegion,
+        Priority,
+        Registers,
+        TaskInfo,
+        TaskInfoState,
+        TaskReadMemoryError,
+        TASK_INFO_NAME_LENGTH,
+    },
+    Handle,
+    HandleRights,
 };
-use hal::memory::VAddr;
-use poplar::Handle;
 use spinning_top::{RwSpinlock, Spinlock};
 
 #[derive(Clone, Debug)]
@@ -29,6 +46,8 @@ pub enum TaskState {
     Ready,
     Running,
     Blocked(TaskBlock),
+    /// The task has exited with the given status. It will never be scheduled again.
+    Exited(i32),
 }
 
 impl TaskState {
@@ -52,6 +71,29 @@ impl TaskState {
             _ => false,
         }
     }
+
+    pub fn exit_status(&self) -> Option<i32> {
+        match self {
+            TaskState::Exited(status) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+/// Tracks whether a task is stopped because of an exception that's been delivered to its exception channel.
+#[derive(Clone, Debug)]
+pub enum ExceptionState {
+    NotStopped,
+    /// The task is stopped, and waiting for the exception to be resolved with `task_resume`.
+    Stopped,
+    Resolved(ExceptionResolution),
+}
+
+#[derive(Clone, Debug)]
+pub enum ExceptionResolution {
+    Resume,
+    ResumeWithRegisters(Registers),
+    Kill,
 }
 
 #[derive(Debug)]
@@ -78,13 +120,30 @@ where
     pub name: String,
     pub address_space: Arc<AddressSpace<P>>,
     pub state: Spinlock<TaskState>,
+    /// The job this task belongs to. Threads belong to the same job as the task that created them.
+    pub job: Arc<Job<P>>,
 
     pub user_slot: Spinlock<TaskSlot>,
     pub kernel_stack: Spinlock<Stack>,
 
     pub context: UnsafeCell<P::TaskContext>,
 
-    pub handles: Handles,
+    /// The handles the task holds. These are shared between all of a task's threads.
+    pub handles: Arc<Handles>,
+    /// The task's capabilities. Like handles, these are shared between all of a task's threads.
+    pub capabilities: Arc<TaskCapabilities>,
+    pub priority: TaskPriority,
+    /// Whether the task's system calls are recorded in the trace buffers. Like capabilities, this is shared by
+    /// all of a task's threads.
+    pub audit: Arc<AtomicBool>,
+    /// The hardware events counted for this task. Unlike capabilities, these are counted for each thread
+    /// separately.
+    pub perf: TaskPerf,
+
+    /// Where exceptions caused by this task are delivered. This is a kernel channel, so the only way to get
+    /// messages out of it is through the handle returned by `task_exception_channel`.
+    pub exception_channel: Spinlock<Option<Arc<ChannelEnd>>>,
+    pub exception_state: Spinlock<ExceptionState>,
 }
 
 /*
@@ -102,9 +161,67 @@ where
     pub fn new(
         owner: KernelObjectId,
         address_space: Arc<AddressSpace<P>>,
+        job: Arc<Job<P>>,
         name: String,
         entry_point: VAddr,
         handles: Handles,
+        capabilities: Capabilities,
+        priority: Priority,
+        allocator: &Pmm,
+        kernel_page_table: &mut P::PageTable,
+    ) -> Result<Arc<Task<P>>, TaskCreationError> {
+        Self::create(
+            owner,
+            address_space,
+            job,
+            name,
+            entry_point,
+            0,
+            Arc::new(handles),
+            Arc::new(TaskCapabilities::new(capabilities)),
+            priority,
+            Arc::new(AtomicBool::new(false)),
+            allocator,
+            kernel_page_table,
+        )
+    }
+
+    /// Create a new thread of `task`. The thread shares the task's `AddressSpace`, handles, capabilities, and
+    /// audit flag, but has its own stacks. It starts executing at `entry_point`, and is passed `argument`.
+    pub fn new_thread(
+        task: &Task<P>,
+        entry_point: VAddr,
+        argument: usize,
+        allocator: &Pmm,
+        kernel_page_table: &mut P::PageTable,
+    ) -> Result<Arc<Task<P>>, TaskCreationError> {
+        Self::create(
+            task.id,
+            task.address_space.clone(),
+            task.job.clone(),
+            task.name.clone(),
+            entry_point,
+            argument,
+            task.handles.clone(),
+            task.capabilities.clone(),
+            task.priority.base(),
+            task.audit.clone(),
+            allocator,
+            kernel_page_table,
+        )
+    }
+
+    fn create(
+        owner: KernelObjectId,
+        address_space: Arc<AddressSpace<P>>,
+        job: Arc<Job<P>>,
+        name: String,
+        entry_point: VAddr,
+        argument: usize,
+        handles: Arc<Handles>,
+        capabilities: Arc<TaskCapabilities>,
+        priority: Priority,
+        audit: Arc<AtomicBool>,
         allocator: &Pmm,
         kernel_page_table: &mut P::PageTable,
     ) -> Result<Arc<Task<P>>, TaskCreationError> {
@@ -118,20 +235,154 @@ where
             .alloc_kernel_stack::<P>(0x4000, allocator, kernel_page_table)
             .ok_or(TaskCreationError::NoKernelStackSlots)?;
 
-        let context = P::new_task_context(&kernel_stack, &task_slot.user_stack, entry_point);
+        let thread_pointer = address_space.alloc_tls(&task_slot, allocator);
+        let context = P::new_task_context(
+            &kernel_stack,
+            &task_slot.user_stack,
+            entry_point,
+            argument,
+            thread_pointer.map_or(0, usize::from),
+        );
 
-        Ok(Arc::new(Task {
+        let task = Arc::new(Task {
             id,
             owner,
             name,
             address_space,
             state: Spinlock::new(TaskState::Ready),
+            job,
             user_slot: Spinlock::new(task_slot),
             kernel_stack: Spinlock::new(kernel_stack),
             context: UnsafeCell::new(context),
 
             handles,
-        }))
+            capabilities,
+            priority: TaskPriority::new(priority),
+            audit,
+            perf: TaskPerf::new(),
+            exception_channel: Spinlock::new(None),
+            exception_state: Spinlock::new(ExceptionState::NotStopped),
+        });
+        task.job.add_task(&task);
+        crate::trace::task_name(&task);
+        Ok(task)
+    }
+
+    /// Returns `true` if the task can be given `count` more handles without going over its job's handle limit.
+    pub fn can_add_handles(&self, count: usize) -> bool {
+        self.handles.len().saturating_add(count) <= self.job.effective_limits().max_handles
+    }
+
+    /// Describe this task for `task_info`.
+    pub fn info(&self) -> TaskInfo {
+        let mut name = [0; TASK_INFO_NAME_LENGTH];
+        let name_length = usize::min(self.name.len(), TASK_INFO_NAME_LENGTH);
+        name[0..name_length].copy_from_slice(&self.name.as_bytes()[0..name_length]);
+
+        let state = match *self.state.lock() {
+            TaskState::Running => TaskInfoState::Running,
+            TaskState::Blocked(_) => TaskInfoState::Blocked,
+            TaskState::Ready | TaskState::Exited(_) => TaskInfoState::Ready,
+        };
+
+        TaskInfo {
+            id: u64::from(self.id),
+            name,
+            job: u64::from(self.job.id()),
+            address_space: u64::from(self.address_space.id()),
+            memory: self.address_space.memory_usage(),
+            num_handles: self.handles.len(),
+            priority: self.priority.effective(),
+            state,
+        }
+    }
+
+    /// Get the regions of memory this task can access - the memory objects mapped into its address space, and its
+    /// user stack - in order of address. The stacks of other threads in the same address space aren't included.
+    pub fn memory_regions(&self) -> Vec<MemoryRegion> {
+        let mut regions: Vec<MemoryRegion> = self
+            .address_space
+            .mappings
+            .lock()
+            .iter()
+            .map(|mapping| MemoryRegion {
+                address: usize::from(mapping.virtual_address),
+                size: mapping.memory_object.size,
+                writable: mapping.flags.writable,
+                executable: mapping.flags.executable,
+                device: !mapping.flags.cached,
+            })
+            .collect();
+
+        let slot = self.user_slot.lock();
+        let stack = &slot.user_stack;
+        regions.push(MemoryRegion {
+            address: usize::from(stack.stack_bottom),
+            size: usize::from(stack.top) + 1 - usize::from(stack.stack_bottom),
+            writable: true,
+            executable: false,
+            device: false,
+        });
+
+        regions.sort_by_key(|region| region.address);
+        regions
+    }
+
+    /// Read this task's memory at `address` into `buffer`. The memory is read from the frames backing it, rather
+    /// than through the task's page tables, so memory that hasn't been committed yet reads as zeros. Returns the
+    /// number of bytes read, which is less than the length of `buffer` if the read reaches the end of the region
+    /// `address` is in.
+    pub fn read_memory(&self, address: usize, buffer: &mut [u8]) -> Result<usize, TaskReadMemoryError> {
+        {
+            // The user stack isn't physically contiguous once it's grown, so it's read through the page tables
+            let slot = self.user_slot.lock();
+            let stack = &slot.user_stack;
+            if address >= usize::from(stack.stack_bottom) && address <= usize::from(stack.top) {
+                let length = usize::min(buffer.len(), usize::from(stack.top) + 1 - address);
+                let mut read = 0;
+                while read < length {
+                    let in_page = usize::min(Size4KiB::SIZE - (address + read) % Size4KiB::SIZE, length - read);
+                    let physical = self
+                        .address_space
+                        .translate(VAddr::new(address + read))
+                        .expect("User stack should be mapped");
+                    unsafe {
+                        P::read_from_phys_memory(physical, &mut buffer[read..(read + in_page)]);
+                    }
+                    read += in_page;
+                }
+                return Ok(length);
+            }
+        }
+
+        let mappings = self.address_space.mappings.lock();
+        let mapping = mappings
+            .iter()
+            .find(|mapping| {
+                let start = usize::from(mapping.virtual_address);
+                address >= start && address < start + mapping.memory_object.size
+            })
+            .ok_or(TaskReadMemoryError::AddressNotMapped)?;
+        if !mapping.flags.cached {
+            return Err(TaskReadMemoryError::DeviceMemory);
+        }
+
+        let offset = address - usize::from(mapping.virtual_address);
+        let length = usize::min(buffer.len(), mapping.memory_object.size - offset);
+        let mut read = 0;
+        while read < length {
+            let page_offset = align_down(offset + read, Size4KiB::SIZE);
+            let in_page = usize::min(Size4KiB::SIZE - (offset + read - page_offset), length - read);
+            let chunk = &mut buffer[read..(read + in_page)];
+            match mapping.memory_object.page(page_offset) {
+                Some((physical, _)) => unsafe {
+                    P::read_from_phys_memory(physical + (offset + read - page_offset), chunk);
+                },
+                None => chunk.fill(0),
+            }
+            read += in_page;
+        }
+        Ok(length)
     }
 }
 
@@ -148,8 +399,93 @@ where
     }
 }
 
+/// A task is scheduled at its *effective* priority, which is the highest of the priority it has been given and
+/// any priorities it has inherited from tasks that are blocked waiting on it. Inheriting priorities stops a
+/// high-priority task being held up indefinitely by medium-priority tasks while it waits on a low-priority one.
+pub struct TaskPriority {
+    base: Spinlock<Priority>,
+    /// The priorities lent to this task, keyed by the ID of the task that lent them.
+    inherited: Spinlock<BTreeMap<KernelObjectId, Priority>>,
+}
+
+impl TaskPriority {
+    pub fn new(base: Priority) -> TaskPriority {
+        TaskPriority { base: Spinlock::new(base), inherited: Spinlock::new(BTreeMap::new()) }
+    }
+
+    pub fn base(&self) -> Priority {
+        *self.base.lock()
+    }
+
+    pub fn set_base(&self, priority: Priority) {
+        *self.base.lock() = priority;
+    }
+
+    pub fn effective(&self) -> Priority {
+        let base = self.base();
+        self.inherited.lock().values().copied().fold(base, Priority::max)
+    }
+
+    /// Lend this task the priority of the task with ID `lender`, until it is returned with `release`. If the
+    /// lender has already lent this task a priority, it is replaced.
+    pub fn inherit(&self, lender: KernelObjectId, priority: Priority) {
+        self.inherited.lock().insert(lender, priority);
+    }
+
+    pub fn release(&self, lender: KernelObjectId) {
+        self.inherited.lock().remove(&lender);
+    }
+}
+
+/// The capabilities of a task. These can be granted and revoked while the task is running, by a task with the
+/// `ManageCapabilities` capability. As they're checked each time the task asks the kernel to do something
+/// privileged, a change takes effect from the task's next system call.
+///
+/// Revoking a capability doesn't take away anything the task has already used it to get (e.g. handles to
+/// devices).
+pub struct TaskCapabilities(AtomicU32);
+
+impl TaskCapabilities {
+    pub fn new(capabilities: Capabilities) -> TaskCapabilities {
+        TaskCapabilities(AtomicU32::new(capabilities.bits()))
+    }
+
+    pub fn get(&self) -> Capabilities {
+        Capabilities::from_bits_retain(self.0.load(Ordering::Acquire))
+    }
+
+    pub fn contains(&self, capabilities: Capabilities) -> bool {
+        self.get().contains(capabilities)
+    }
+
+    pub fn grant(&self, capabilities: Capabilities) {
+        self.0.fetch_or(capabilities.bits(), Ordering::AcqRel);
+    }
+
+    pub fn revoke(&self, capabilities: Capabilities) {
+        self.0.fetch_and(!capabilities.bits(), Ordering::AcqRel);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HandleError {
+    InvalidHandle,
+    MissingRights,
+}
+
+impl HandleError {
+    /// Turn this into the equivalent error of a system call that operates on the handle.
+    pub fn to_syscall_error<E>(self, invalid_handle: E, missing_rights: E) -> E {
+        match self {
+            HandleError::InvalidHandle => invalid_handle,
+            HandleError::MissingRights => missing_rights,
+        }
+    }
+}
+
+/// The handles a task holds, and the rights each of them gives it over the kernel object it refers to.
 pub struct Handles {
-    handles: RwSpinlock<BTreeMap<Handle, Arc<dyn KernelObject>>>,
+    handles: RwSpinlock<BTreeMap<Handle, (Arc<dyn KernelObject>, HandleRights)>>,
     next: AtomicU32,
 }
 
@@ -162,17 +498,69 @@ impl Handles {
         }
     }
 
+    /// Add a handle to `object` with all rights.
     pub fn add(&self, object: Arc<dyn KernelObject>) -> Handle {
+        self.add_with_rights(object, HandleRights::all())
+    }
+
+    pub fn add_with_rights(&self, object: Arc<dyn KernelObject>, rights: HandleRights) -> Handle {
         let handle_num = self.next.fetch_add(1, Ordering::Relaxed);
-        self.handles.write().insert(Handle(handle_num), object);
+        self.handles.write().insert(Handle(handle_num), (object, rights));
         Handle(handle_num)
     }
 
-    pub fn remove(&self, handle: Handle) {
-        self.handles.write().remove(&handle);
+    pub fn len(&self) -> usize {
+        self.handles.read().len()
+    }
+
+    pub fn remove(&self, handle: Handle) -> Option<(Arc<dyn KernelObject>, HandleRights)> {
+        self.handles.write().remove(&handle)
+    }
+
+    /// Remove each of `handles`, if they're all valid, are all different, and all have (at least) the `required`
+    /// rights. Either all of the handles are removed, or none of them are.
+    pub fn remove_all(
+        &self,
+        handles: &[Handle],
+        required: HandleRights,
+    ) -> Result<Vec<(Arc<dyn KernelObject>, HandleRights)>, HandleError> {
+        let mut table = self.handles.write();
+        for (i, handle) in handles.iter().enumerate() {
+            if handles[..i].contains(handle) {
+                return Err(HandleError::InvalidHandle);
+            }
+            let (_, rights) = table.get(handle).ok_or(HandleError::InvalidHandle)?;
+            if !rights.contains(required) {
+                return Err(HandleError::MissingRights);
+            }
+        }
+
+        Ok(handles.iter().map(|handle| table.remove(handle).unwrap()).collect())
+    }
+
+    /// Get the object `handle` refers to, if the handle has (at least) the `required` rights.
+    pub fn get(&self, handle: Handle, required: HandleRights) -> Result<Arc<dyn KernelObject>, HandleError> {
+        let (object, rights) = self.get_with_rights(handle).ok_or(HandleError::InvalidHandle)?;
+        if rights.contains(required) {
+            Ok(object)
+        } else {
+            Err(HandleError::MissingRights)
+        }
     }
 
-    pub fn get(&self, handle: Handle) -> Option<Arc<dyn KernelObject>> {
+    pub fn get_with_rights(&self, handle: Handle) -> Option<(Arc<dyn KernelObject>, HandleRights)> {
         self.handles.read().get(&handle).cloned()
     }
+
+    /// Remove all of the handles. The kernel objects they refer to are dropped after the lock is released, as
+    /// dropping them can have side effects (e.g. closing a `Channel`).
+    pub fn clear(&self) {
+        let handles = core::mem::take(&mut *self.handles.write());
+        drop(handles);
+    }
+
+    /// Returns `true` if any of these handles refer to the kernel object with the given ID.
+    pub fn refers_to(&self, id: KernelObjectId) -> bool {
+        self.handles.read().values().any(|(object, _)| object.id() == id)
+    }
 }
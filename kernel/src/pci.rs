@@ -1,5 +1,7 @@
 use crate::object::event::Event;
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::ptr;
+use hal::memory::VAddr;
 use pci_types::{
     capability::{MsiCapability, MsixCapability, PciCapability},
     device_type::DeviceType,
@@ -17,6 +19,7 @@ use pci_types::{
     VendorId,
     MAX_BARS,
 };
+use poplar::ddk::pci::MAX_INTERRUPTS;
 use tracing::info;
 
 #[derive(Clone, Debug)]
@@ -28,7 +31,10 @@ pub struct PciDevice {
     pub sub_class: SubClass,
     pub interface: Interface,
     pub bars: [Option<Bar>; MAX_BARS],
-    pub interrupt_event: Option<Arc<Event>>,
+    /// Events that are signalled when the device raises an interrupt. Devices using MSI-X may have one for each
+    /// entry of their MSI-X table (up to `MAX_INTERRUPTS`), in the order of the entries. Otherwise, there is at
+    /// most one.
+    pub interrupt_events: Vec<Arc<Event>>,
 }
 
 #[derive(Clone, Debug)]
@@ -48,10 +54,66 @@ pub trait PciInterruptConfigurator {
     /// capability.
     fn configure_msi(&self, function: PciAddress, msi: &mut MsiCapability) -> Arc<Event>;
 
-    /// Create an `Event` that is signalled when an interrupt arrives from the specified PCI
-    /// device. The device must support configuration of its interrupts via the passed MSI-X
-    /// capability.
-    fn configure_msix(&self, function: PciAddress, table_bar: Bar, msix: &mut MsixCapability) -> Arc<Event>;
+    /// Create `num_vectors` `Event`s, each signalled when the specified PCI device signals an
+    /// interrupt with the corresponding entry of its MSI-X table. This programs the first
+    /// `num_vectors` entries of the table, each with its own vector. Fewer events may be returned
+    /// if the platform runs out of vectors, but there will always be at least one. The device must
+    /// support configuration of its interrupts via the passed MSI-X capability.
+    fn configure_msix(
+        &self,
+        function: PciAddress,
+        table_bar: Bar,
+        msix: &mut MsixCapability,
+        num_vectors: u16,
+    ) -> Vec<Arc<Event>>;
+}
+
+/// A message that a device can write to trigger a message-signalled interrupt.
+#[derive(Clone, Copy, Debug)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// Implemented by platforms that can receive message-signalled interrupts, to describe how a device
+/// should signal an interrupt vector. How vectors are allocated is up to the platform (generally
+/// from `kernel::interrupts::INTERRUPT_VECTORS`).
+pub trait MsiController {
+    /// Get the message that should be written to signal an interrupt on `vector`.
+    fn message_for(&self, vector: u32) -> MsiMessage;
+
+    /// Configure a device to signal `vector` with MSIs, via its MSI capability.
+    fn configure_msi<A>(&self, vector: u32, msi: &mut MsiCapability, access: &A)
+    where
+        A: ConfigRegionAccess,
+    {
+        let message = self.message_for(vector);
+        // TODO: `MsiCapability` only supports 32-bit message addresses
+        assert!(message.address <= u32::MAX as u64, "MSI message address does not fit in 32 bits");
+        msi.set_message_info(message.address as u32, message.data, access);
+        msi.set_enabled(true, access);
+    }
+
+    /// Configure an entry of a device's MSI-X table to signal `vector`. `table` must be the virtual
+    /// address of the table (described by the MSI-X capability).
+    fn configure_msix_entry(&self, vector: u32, table: VAddr, entry: u16) {
+        let message = self.message_for(vector);
+        let entry_ptr = (table + usize::from(entry) * 16).mut_ptr() as *mut u32;
+
+        /*
+         * Each entry of the MSI-X table is laid out as:
+         *    0x00 => Message Address
+         *    0x04 => Message Upper Address
+         *    0x08 => Message Data
+         *    0x0c => Vector Control
+         */
+        unsafe {
+            ptr::write_volatile(entry_ptr.byte_add(0x00), message.address as u32);
+            ptr::write_volatile(entry_ptr.byte_add(0x04), (message.address >> 32) as u32);
+            ptr::write_volatile(entry_ptr.byte_add(0x08), message.data);
+            ptr::write_volatile(entry_ptr.byte_add(0x0c), 0);
+        }
+    }
 }
 
 pub struct PciResolver<A>
@@ -153,17 +215,18 @@ where
                  * We try to use MSI or MSI-X if the device supports it, otherwise we have to use
                  * the shared interrupt pins.
                  */
-                let interrupt_event = endpoint_header
+                let interrupt_events = endpoint_header
                     .capabilities(&self.access)
                     .find_map(|capability| match capability {
-                        PciCapability::Msi(mut msi) => Some(self.access.configure_msi(address, &mut msi)),
+                        PciCapability::Msi(mut msi) => Some(vec![self.access.configure_msi(address, &mut msi)]),
                         PciCapability::MsiX(mut msix) => {
                             let table_bar = bars[msix.table_bar() as usize].unwrap();
-                            Some(self.access.configure_msix(address, table_bar, &mut msix))
+                            let num_vectors = u16::min(msix.table_size(), MAX_INTERRUPTS as u16);
+                            Some(self.access.configure_msix(address, table_bar, &mut msix, num_vectors))
                         }
                         _ => None,
                     })
-                    .or_else(|| {
+                    .unwrap_or_else(|| {
                         /*
                          * If the device does not support MSI or MSI-X, we're forced to use the
                          * legacy interrupt pins.
@@ -172,9 +235,9 @@ where
                         match pin {
                             0x00 => {
                                 // Device does not support interrupts of any kind
-                                None
+                                Vec::new()
                             }
-                            0x01..0x05 => Some(self.access.configure_legacy(address, pin)),
+                            0x01..0x05 => vec![self.access.configure_legacy(address, pin)],
                             _ => panic!("Invalid legacy interrupt pin!"),
                         }
                     });
@@ -189,7 +252,7 @@ where
                         sub_class,
                         interface,
                         bars,
-                        interrupt_event,
+                        interrupt_events,
                     },
                 );
             }
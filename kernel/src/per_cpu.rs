@@ -0,0 +1,42 @@
+use crate::Platform;
+use alloc::vec::Vec;
+
+/// Holds an instance of `T` for each CPU in the system, and allows the running CPU to access its
+/// own instance. The number of CPUs must be known when this is created, and so this should be
+/// constructed after the platform has discovered the topology of the system.
+///
+/// This does not provide any synchronization itself - other CPUs may access any of the instances
+/// (e.g. to place a task on another CPU's run queue), so `T` will generally need to be `Sync`.
+pub struct PerCpu<T> {
+    data: Vec<T>,
+}
+
+impl<T> PerCpu<T> {
+    /// Create a new `PerCpu`, calling `f` with each CPU's ID to create its instance.
+    pub fn new(num_cpus: usize, f: impl FnMut(usize) -> T) -> PerCpu<T> {
+        assert!(num_cpus > 0);
+        PerCpu { data: (0..num_cpus).map(f).collect() }
+    }
+
+    /// Get the instance for the CPU we're currently running on.
+    pub fn get<P>(&self) -> &T
+    where
+        P: Platform,
+    {
+        &self.data[P::cpu_id()]
+    }
+
+    /// Get the instance for the CPU with the given ID.
+    pub fn get_for(&self, cpu_id: usize) -> &T {
+        &self.data[cpu_id]
+    }
+
+    pub fn num_cpus(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Iterate over the instances of every CPU, alongside the ID of the CPU they belong to.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.data.iter().enumerate()
+    }
+}
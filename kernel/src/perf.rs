@@ -0,0 +1,144 @@
+//! Counts hardware events (like cycles and cache misses) for each task, using the CPU's performance counters.
+//! The counters are programmed with a task's events when it's switched to, and the counts are added to the task's
+//! totals when it's switched away from, so each task only sees the events that happened while it was running.
+
+use crate::Platform;
+use poplar::syscall::{PerfEvent, PERF_MAX_EVENTS};
+use spinning_top::Spinlock;
+
+pub struct TaskPerf(Spinlock<PerfState>);
+
+struct PerfState {
+    events: [PerfEvent; PERF_MAX_EVENTS],
+    num_events: usize,
+    /// The counts of the events, up to when the task last stopped running.
+    totals: [u64; PERF_MAX_EVENTS],
+    /// The counters counting the task's events on the CPU it's running on. `num_counters` is `0` if the task isn't
+    /// running, or isn't counting any events.
+    counters: [usize; PERF_MAX_EVENTS],
+    num_counters: usize,
+    /// Set if the task's events were changed by another CPU while it was running. The counters are still counting
+    /// the old events, so their counts are thrown away when it stops running.
+    stale: bool,
+}
+
+impl TaskPerf {
+    pub fn new() -> TaskPerf {
+        TaskPerf(Spinlock::new(PerfState {
+            events: [PerfEvent::Cycles; PERF_MAX_EVENTS],
+            num_events: 0,
+            totals: [0; PERF_MAX_EVENTS],
+            counters: [0; PERF_MAX_EVENTS],
+            num_counters: 0,
+            stale: false,
+        }))
+    }
+
+    /// Start counting `events` for the task from zero, replacing any events it was counting before. If the task
+    /// is running on this CPU (i.e. it's the caller), this takes effect immediately - otherwise, it takes effect
+    /// the next time the task is switched to. Returns `Err` if the CPU can't count the events.
+    pub fn configure<P>(&self, events: &[PerfEvent], running_here: bool) -> Result<(), ()>
+    where
+        P: Platform,
+    {
+        assert!(events.len() <= PERF_MAX_EVENTS);
+        if !events.is_empty() && !P::perf_supports(events) {
+            return Err(());
+        }
+
+        let mut state = self.0.lock();
+        if running_here {
+            state.stop::<P>();
+        } else if state.num_counters > 0 {
+            state.stale = true;
+        }
+
+        state.events[0..events.len()].copy_from_slice(events);
+        state.num_events = events.len();
+        state.totals = [0; PERF_MAX_EVENTS];
+
+        if running_here {
+            state.start::<P>();
+        }
+        Ok(())
+    }
+
+    /// Read the counts of the task's events into `counts`. If the task is running on this CPU, its current counts
+    /// are read from the counters. Returns the number of events, or `Err` if `counts` doesn't have space for them.
+    pub fn read<P>(&self, counts: &mut [u64], running_here: bool) -> Result<usize, ()>
+    where
+        P: Platform,
+    {
+        let state = self.0.lock();
+        let num_events = state.num_events;
+        if counts.len() < num_events {
+            return Err(());
+        }
+        counts[0..num_events].copy_from_slice(&state.totals[0..num_events]);
+
+        if running_here && state.num_counters > 0 && !state.stale {
+            let mut live = [0; PERF_MAX_EVENTS];
+            P::perf_read(&state.counters[0..num_events], &mut live[0..num_events]);
+            for (count, live) in counts.iter_mut().zip(live.iter()) {
+                *count += live;
+            }
+        }
+        Ok(num_events)
+    }
+
+    /// Called by the scheduler when the task is switched to, on the CPU it's going to run on.
+    pub fn switch_to<P>(&self)
+    where
+        P: Platform,
+    {
+        self.0.lock().start::<P>();
+    }
+
+    /// Called by the scheduler when the task is switched away from, on the CPU it was running on.
+    pub fn switch_from<P>(&self)
+    where
+        P: Platform,
+    {
+        let mut state = self.0.lock();
+        if state.num_counters > 0 && !state.stale {
+            let num_counters = state.num_counters;
+            let mut counts = [0; PERF_MAX_EVENTS];
+            P::perf_read(&state.counters[0..num_counters], &mut counts[0..num_counters]);
+            for (total, count) in state.totals.iter_mut().zip(counts.iter()) {
+                *total += count;
+            }
+        }
+        state.stop::<P>();
+    }
+}
+
+impl PerfState {
+    fn start<P>(&mut self)
+    where
+        P: Platform,
+    {
+        if self.num_events == 0 {
+            return;
+        }
+
+        let num_events = self.num_events;
+        unsafe {
+            P::perf_start(&self.events[0..num_events], &mut self.counters[0..num_events]);
+        }
+        self.num_counters = num_events;
+    }
+
+    /// Stop the counters counting the task's events, if there are any. Their counts are thrown away.
+    fn stop<P>(&mut self)
+    where
+        P: Platform,
+    {
+        if self.num_counters > 0 {
+            unsafe {
+                P::perf_stop(&self.counters[0..self.num_counters]);
+            }
+        }
+        self.num_counters = 0;
+        self.stale = false;
+    }
+}
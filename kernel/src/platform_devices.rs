@@ -0,0 +1,20 @@
+use crate::object::event::Event;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use hal::memory::PAddr;
+
+/// A device that is described by the platform's firmware (e.g. by a node of the device tree, or a device in the
+/// ACPI namespace), rather than found by
+/// enumerating a bus. The platform is responsible for only describing devices that userspace should drive - it
+/// should not describe devices the kernel uses itself, such as interrupt controllers.
+#[derive(Clone, Debug)]
+pub struct PlatformDevice {
+    pub name: String,
+    /// The devices this device is compatible with, most specific first.
+    pub compatible: Vec<String>,
+    /// The memory-mapped regions of the device, as `(address, size)` pairs. These do not need to be page-aligned.
+    pub regions: Vec<(PAddr, usize)>,
+    /// The ranges of I/O ports used by the device, as `(base, length)` pairs. These only exist on x86_64.
+    pub io_ports: Vec<(u16, u16)>,
+    /// An `Event` that is signalled when the device raises an interrupt, if it has one.
+    pub interrupt: Option<Arc<Event>>,
+}
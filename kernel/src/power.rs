@@ -0,0 +1,122 @@
+//! CPU power management. Each CPU has a `Governor`, which keeps track of how much of its time it spends idle,
+//! and picks how fast the CPU should run from that. The platform decides how to idle the CPU (`Platform::idle`),
+//! and how to turn a performance level into a frequency (`Platform::set_cpu_performance`).
+
+use core::time::Duration;
+
+/// How often the governor re-evaluates how busy the CPU is.
+pub const GOVERNOR_WINDOW: Duration = Duration::from_millis(100);
+
+/// The lowest performance level the governor will pick, as a percentage of the CPU's maximum performance.
+pub const MIN_PERFORMANCE: u8 = 20;
+
+/// If the CPU was busy for more than this percentage of a window, it's immediately moved to its maximum
+/// performance. If it was busy for less than `LOW_LOAD`, its performance is lowered by a step. This makes it react
+/// quickly to bursts of work, and only slowly back off once they're over.
+const HIGH_LOAD: u64 = 80;
+const LOW_LOAD: u64 = 30;
+const PERFORMANCE_STEP: u8 = 20;
+
+/// A simple "ondemand" frequency governor for a single CPU.
+pub struct Governor {
+    window_start: Option<Duration>,
+    idle_in_window: Duration,
+    performance: u8,
+}
+
+impl Governor {
+    /// Create a governor for a CPU that starts at its maximum performance.
+    pub const fn new() -> Governor {
+        Governor { window_start: None, idle_in_window: Duration::ZERO, performance: 100 }
+    }
+
+    /// Record that the CPU was idle for `idle` in the current window.
+    pub fn record_idle(&mut self, idle: Duration) {
+        self.idle_in_window += idle;
+    }
+
+    /// The performance level the CPU should be running at, as a percentage of its maximum performance.
+    pub fn performance(&self) -> u8 {
+        self.performance
+    }
+
+    /// Re-evaluate the CPU's performance level if the current window has finished. Returns the new level, if
+    /// it has changed. `now` must come from the platform's monotonic clock.
+    pub fn update(&mut self, now: Duration) -> Option<u8> {
+        let Some(window_start) = self.window_start else {
+            self.window_start = Some(now);
+            return None;
+        };
+        let elapsed = now.saturating_sub(window_start);
+        if elapsed < GOVERNOR_WINDOW {
+            return None;
+        }
+
+        let busy = elapsed.saturating_sub(self.idle_in_window);
+        let load = (busy.as_micros() * 100 / elapsed.as_micros()) as u64;
+        self.window_start = Some(now);
+        self.idle_in_window = Duration::ZERO;
+
+        let performance = if load > HIGH_LOAD {
+            100
+        } else if load < LOW_LOAD {
+            u8::max(self.performance.saturating_sub(PERFORMANCE_STEP), MIN_PERFORMANCE)
+        } else {
+            self.performance
+        };
+
+        if performance != self.performance {
+            self.performance = performance;
+            Some(performance)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_backs_off_when_idle() {
+        let mut governor = Governor::new();
+        assert_eq!(governor.update(ms(0)), None);
+
+        governor.record_idle(ms(95));
+        assert_eq!(governor.update(ms(50)), None);
+        assert_eq!(governor.update(ms(100)), Some(80));
+
+        for (now, expected) in [(200, 60), (300, 40), (400, 20)] {
+            governor.record_idle(ms(100));
+            assert_eq!(governor.update(ms(now)), Some(expected));
+        }
+
+        // The governor never goes below `MIN_PERFORMANCE`
+        governor.record_idle(ms(100));
+        assert_eq!(governor.update(ms(500)), None);
+        assert_eq!(governor.performance(), MIN_PERFORMANCE);
+    }
+
+    #[test]
+    fn test_jumps_to_max_when_busy() {
+        let mut governor = Governor::new();
+        governor.update(ms(0));
+        governor.record_idle(ms(100));
+        governor.update(ms(100));
+        governor.record_idle(ms(100));
+        governor.update(ms(200));
+        assert_eq!(governor.performance(), 60);
+
+        // Moderate load keeps the current performance level
+        governor.record_idle(ms(50));
+        assert_eq!(governor.update(ms(300)), None);
+
+        governor.record_idle(ms(5));
+        assert_eq!(governor.update(ms(400)), Some(100));
+    }
+}
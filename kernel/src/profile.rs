@@ -0,0 +1,161 @@
+//! A sampling profiler, driven by each CPU's timer interrupt. While profiling is enabled, the platform calls
+//! `sample` on each tick with the state of the interrupted context, and a sample of what the CPU was doing is
+//! written into the CPU's profile buffer. Like the trace buffers, these are backed by read-only `MemoryObject`s,
+//! so a task with the `TRACE` capability can map them and read the samples out itself. The layout of the buffers
+//! and samples is shared with userspace - see `poplar::profile`.
+
+use crate::{
+    object::{memory_object::MemoryObject, KernelObject, SENTINEL_KERNEL_ID},
+    per_cpu::PerCpu,
+    scheduler::Scheduler,
+    Platform,
+};
+use alloc::sync::Arc;
+use core::{
+    mem,
+    slice,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use hal::memory::{Flags, FrameSize, PAddr, Size4KiB, VAddr};
+use mulch::{backtrace::Backtrace, InitGuard};
+use poplar::{
+    profile::{
+        ProfileBufferHeader,
+        ProfileSample,
+        PROFILE_BUFFER_SAMPLES,
+        PROFILE_BUFFER_SIZE,
+        PROFILE_MAX_FRAMES,
+        PROFILE_SAMPLES_OFFSET,
+    },
+    syscall::ProfilingMode,
+    trace::pack_task_name,
+};
+
+pub static PROFILE_BUFFERS: InitGuard<PerCpu<ProfileBuffer>> = InitGuard::uninit();
+static MODE: AtomicUsize = AtomicUsize::new(ProfilingMode::Off as usize);
+
+pub struct ProfileBuffer {
+    pub memory_object: Arc<MemoryObject>,
+    physical_start: PAddr,
+    /// The number of samples that have been written to the buffer. This is mirrored into the buffer's header once
+    /// each sample has been written.
+    head: AtomicU64,
+}
+
+/// The state of the context a timer interrupt interrupted.
+pub struct InterruptedContext {
+    pub instruction_pointer: usize,
+    pub frame_pointer: usize,
+    /// Whether the CPU was running a task in userspace, rather than the kernel.
+    pub in_user: bool,
+}
+
+/// Allocate a profile buffer for each CPU. Profiling starts off disabled.
+pub fn init<P>(num_cpus: usize)
+where
+    P: Platform,
+{
+    let pmm = crate::PMM.get();
+    PROFILE_BUFFERS.initialize(PerCpu::new(num_cpus, |cpu| {
+        let physical_start = pmm.alloc(PROFILE_BUFFER_SIZE / Size4KiB::SIZE);
+        unsafe {
+            P::zero_phys_memory(physical_start, PROFILE_BUFFER_SIZE);
+            P::write_to_phys_memory(
+                physical_start + mem::offset_of!(ProfileBufferHeader, cpu),
+                &(cpu as u64).to_ne_bytes(),
+            );
+        }
+
+        let flags = Flags { writable: false, user_accessible: true, ..Default::default() };
+        let memory_object = MemoryObject::new(SENTINEL_KERNEL_ID, physical_start, PROFILE_BUFFER_SIZE, flags);
+        ProfileBuffer { memory_object, physical_start, head: AtomicU64::new(0) }
+    }));
+}
+
+pub fn set_mode(mode: ProfilingMode) {
+    MODE.store(mode as usize, Ordering::Relaxed);
+}
+
+/// Take a sample of the context interrupted by a timer interrupt, if profiling is enabled. This should be called
+/// from each CPU's timer interrupt, and doesn't wait for any locks, so it's safe to call no matter what was
+/// interrupted. `is_kernel_address` should check that an address is in the kernel's half of the address space, so
+/// that a corrupt frame pointer doesn't cause a fault while walking the kernel's stack.
+pub fn sample<P>(scheduler: &Scheduler<P>, context: &InterruptedContext, is_kernel_address: fn(usize) -> bool)
+where
+    P: Platform,
+{
+    let mode = MODE.load(Ordering::Relaxed);
+    if mode == ProfilingMode::Off as usize {
+        return;
+    }
+    let Some(buffers) = PROFILE_BUFFERS.try_get() else {
+        return;
+    };
+
+    let cpu = P::cpu_id();
+    let task = scheduler.try_running_task();
+    let mut sample = ProfileSample {
+        timestamp: P::monotonic_time().as_nanos() as u64,
+        task: task.as_ref().map_or(0, |task| u64::from(task.id())),
+        task_name: task.as_ref().map_or([0; 2], |task| pack_task_name(&task.name)),
+        cpu: cpu as u32,
+        num_kernel_frames: 0,
+        num_user_frames: 0,
+        frames: [0; PROFILE_MAX_FRAMES],
+    };
+
+    let backtrace = if !context.in_user {
+        Some(unsafe { Backtrace::from_frame_pointer(context.frame_pointer, is_kernel_address) })
+    } else {
+        match task {
+            Some(ref task) if mode == ProfilingMode::KernelAndUser as usize => {
+                /*
+                 * The task's stack is read through its page tables, so a corrupt frame pointer can't cause a
+                 * fault, and we don't need to be able to access user memory from here. The kernel is mapped into
+                 * every address space, so we have to make sure we don't read it into the sample.
+                 */
+                Some(Backtrace::walk(context.frame_pointer, |address| {
+                    let virtual_address = VAddr::new(address);
+                    if is_kernel_address(address) || usize::from(virtual_address) != address {
+                        None
+                    } else {
+                        task.address_space.try_read_word(virtual_address)
+                    }
+                }))
+            }
+            _ => None,
+        }
+    };
+
+    /*
+     * The first frame is the interrupted instruction, and then the return addresses above it. Kernel frames are
+     * recorded at the addresses the kernel was linked at, so they can be symbolized without knowing where the
+     * kernel was loaded (and so the slide isn't given away to userspace).
+     */
+    let slide = if context.in_user { 0 } else { crate::KERNEL_SLIDE.try_get().copied().unwrap_or(0) };
+    let mut num_frames = 1;
+    sample.frames[0] = context.instruction_pointer.wrapping_sub(slide) as u64;
+    for &frame in backtrace.iter().flat_map(|backtrace| backtrace.frames()).take(PROFILE_MAX_FRAMES - 1) {
+        sample.frames[num_frames] = frame.wrapping_sub(slide) as u64;
+        num_frames += 1;
+    }
+    if context.in_user {
+        sample.num_user_frames = num_frames as u16;
+    } else {
+        sample.num_kernel_frames = num_frames as u16;
+    }
+
+    let buffer = buffers.get_for(cpu);
+    let index = buffer.head.fetch_add(1, Ordering::Relaxed);
+    let offset =
+        PROFILE_SAMPLES_OFFSET + (index as usize % PROFILE_BUFFER_SAMPLES) * mem::size_of::<ProfileSample>();
+    unsafe {
+        let bytes =
+            slice::from_raw_parts(&sample as *const ProfileSample as *const u8, mem::size_of::<ProfileSample>());
+        P::write_to_phys_memory(buffer.physical_start + offset, bytes);
+        P::write_to_phys_memory(
+            buffer.physical_start + mem::offset_of!(ProfileBufferHeader, head),
+            &(index + 1).to_ne_bytes(),
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! The kernel's random number generator, which is used to randomize the layout of tasks' address spaces (see
+//! `object::address_space`). It's a xoshiro256** generator, seeded from the platform's hardware random number
+//! generator if it has one (`Platform::hardware_random`), and from the jitter between reads of the monotonic
+//! clock. If the platform has a hardware random number generator, it's also mixed into the state each time a
+//! number is generated. This is not suitable for cryptography.
+
+use crate::Platform;
+use mulch::InitGuard;
+use spinning_top::Spinlock;
+use tracing::info;
+
+static GENERATOR: InitGuard<Spinlock<[u64; 4]>> = InitGuard::uninit();
+
+/// How many times to sample the jitter of the monotonic clock when seeding the generator.
+const JITTER_SAMPLES: usize = 64;
+/// How many times to read the monotonic clock while waiting for it to tick during each sample.
+const MAX_SPINS_PER_SAMPLE: u64 = 1000;
+
+/// Seed the generator. This must be called before `next_u64`, once the platform's monotonic clock is running.
+pub fn init<P>()
+where
+    P: Platform,
+{
+    /*
+     * Each sample counts how many times we can read the clock before it ticks, and what it reads when it does.
+     * Neither is very random on its own, but both are affected by things like caches, interrupts, and the state of
+     * the hardware we've booted on.
+     */
+    let mut entropy = 0;
+    for _ in 0..JITTER_SAMPLES {
+        let start = P::monotonic_time();
+        let mut spins = 0u64;
+        while P::monotonic_time() == start && spins < MAX_SPINS_PER_SAMPLE {
+            spins += 1;
+        }
+        let mut sample = entropy ^ spins.rotate_left(32) ^ P::monotonic_time().as_nanos() as u64;
+        entropy = splitmix64(&mut sample);
+    }
+
+    match P::hardware_random() {
+        Some(value) => entropy ^= value,
+        None => info!("Platform does not have a hardware random number generator. Seeding from timing jitter."),
+    }
+
+    let state =
+        [splitmix64(&mut entropy), splitmix64(&mut entropy), splitmix64(&mut entropy), splitmix64(&mut entropy)];
+    GENERATOR.initialize(Spinlock::new(state));
+}
+
+/// Generate a random 64-bit number.
+pub fn next_u64<P>() -> u64
+where
+    P: Platform,
+{
+    let mut state = GENERATOR.get().lock();
+    if let Some(value) = P::hardware_random() {
+        state[0] ^= value;
+    }
+
+    let result = state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+    let t = state[1] << 17;
+    state[2] ^= state[0];
+    state[3] ^= state[1];
+    state[1] ^= state[2];
+    state[0] ^= state[3];
+    state[2] ^= t;
+    state[3] = state[3].rotate_left(45);
+
+    /*
+     * The generator must never reach the all-zero state, which it can't leave. That can only happen if hardware
+     * entropy happened to cancel out the whole state, but we may as well be sure.
+     */
+    if *state == [0; 4] {
+        state[0] = 1;
+    }
+    result
+}
+
+/// Generate a random number in `0..bound`. `bound` must not be zero.
+pub fn next_below<P>(bound: u64) -> u64
+where
+    P: Platform,
+{
+    /*
+     * Take the top 64 bits of the 128-bit product, which maps the full range of the generator onto `0..bound`. This
+     * is very slightly biased towards some values when `bound` isn't a power of two, which doesn't matter for our
+     * uses.
+     */
+    assert!(bound != 0);
+    ((next_u64::<P>() as u128 * bound as u128) >> 64) as u64
+}
+
+/// The SplitMix64 generator, which we use to turn the entropy we've collected into a well-distributed seed.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
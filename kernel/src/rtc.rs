@@ -0,0 +1,55 @@
+//! The kernel keeps track of wall-clock time using the platform's real-time clock (RTC). RTCs generally only have
+//! a resolution of a second, and can be slow to read, so we only read it once, and then track the time using the
+//! kernel's monotonic clock. The wall-clock time when the monotonic clock was zero is published on the vDSO page,
+//! so userspace can work out the time in the same way.
+
+use crate::vdso;
+use alloc::boxed::Box;
+use core::time::Duration;
+pub use poplar::time::DateTime;
+use spinning_top::Spinlock;
+
+/// Implemented by platforms that have a real-time clock. Each platform is responsible for discovering its RTC,
+/// and passing it to the kernel with `kernel::initialize_rtc`.
+pub trait Rtc: Send + Sync {
+    /// Read the time from the RTC, as a duration since the Unix epoch.
+    fn read(&self) -> Duration;
+
+    /// Set the RTC to the given time, as a duration since the Unix epoch. Returns `Err` if the RTC can't represent
+    /// the time. RTCs that have a resolution of a second can discard the fractional part.
+    fn write(&self, time: Duration) -> Result<(), ()>;
+}
+
+pub struct WallClock {
+    rtc: Box<dyn Rtc>,
+    /// The wall-clock time when the kernel's monotonic clock was zero. This is `None` until the RTC is first read.
+    boot_time: Spinlock<Option<Duration>>,
+}
+
+impl WallClock {
+    pub fn new(rtc: Box<dyn Rtc>) -> WallClock {
+        WallClock { rtc, boot_time: Spinlock::new(None) }
+    }
+
+    /// Get the wall-clock time, as a duration since the Unix epoch. `uptime` is the current time of the kernel's
+    /// monotonic clock.
+    pub fn now(&self, uptime: Duration) -> Duration {
+        let mut boot_time = self.boot_time.lock();
+        let boot_time = *boot_time.get_or_insert_with(|| {
+            let boot_time = self.rtc.read().saturating_sub(uptime);
+            vdso::set_realtime_offset(boot_time);
+            boot_time
+        });
+        boot_time + uptime
+    }
+
+    /// Set the wall-clock time, and the RTC. `uptime` is the current time of the kernel's monotonic clock.
+    pub fn set(&self, time: Duration, uptime: Duration) -> Result<(), ()> {
+        let mut boot_time = self.boot_time.lock();
+        self.rtc.write(time)?;
+        let offset = time.saturating_sub(uptime);
+        *boot_time = Some(offset);
+        vdso::set_realtime_offset(offset);
+        Ok(())
+    }
+}
@@ -1,12 +1,56 @@
 use crate::{
-    object::task::{Task, TaskState},
+    object::{
+        address_space::{PageFaultAccess, PageFaultResolution},
+        channel::Message,
+        task::{ExceptionResolution, ExceptionState, Task, TaskState},
+        KernelObject,
+        KernelObjectId,
+    },
+    per_cpu::PerCpu,
+    power::Governor,
     tasklets::TaskletScheduler,
     Platform,
 };
 use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::time::Duration;
+use hal::memory::VAddr;
+use poplar::{
+    syscall::{ExceptionInfo, ExceptionKind, Priority, Registers, EXCEPTION_EXIT_STATUS, JOB_KILLED_EXIT_STATUS},
+    trace::TraceEvent,
+};
 use spinning_top::{guard::SpinlockGuard, Spinlock};
 use tracing::{info, trace};
 
+pub struct SchedulerConfig {
+    /// How long a task of each priority can run before it is pre-empted in favour of another ready task of the
+    /// same priority. Indexed by priority. These are rounded up to the platform's timer period.
+    pub time_slices: [Duration; Priority::NUM_PRIORITIES],
+}
+
+impl SchedulerConfig {
+    pub fn time_slice(&self, priority: Priority) -> Duration {
+        self.time_slices[priority as usize]
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        /*
+         * Higher-priority tasks tend to be latency-sensitive, and so get shorter slices so they take turns
+         * more quickly. Lower-priority tasks tend to be throughput-bound, and benefit from running for longer.
+         */
+        SchedulerConfig {
+            time_slices: [
+                Duration::from_millis(100),
+                Duration::from_millis(50),
+                Duration::from_millis(30),
+                Duration::from_millis(20),
+                Duration::from_millis(10),
+            ],
+        }
+    }
+}
+
 /// The global `Scheduler` coordinates the main 'run loop' of the kernel, allocating CPU time to
 /// userspace tasks. There is one global `Scheduler` instance, which then holds a `CpuScheduler`
 /// for each running processor to coordinate tasks running on that processor.
@@ -19,12 +63,14 @@ pub struct Scheduler<P>
 where
     P: Platform,
 {
-    // TODO: in the future, this will be a vec with a CpuScheduler for each CPU
-    task_scheduler: Spinlock<CpuScheduler<P>>,
-    // TODO: have a maitake scheduler for each processor (ACTUALLY I can't work out if we need one
-    // - LocalScheduler could be the core-local one, but both say single-core... Maybe we can just
-    // have one and tick it from whatever processor is available?)
+    task_schedulers: PerCpu<Spinlock<CpuScheduler<P>>>,
+    /*
+     * There is one tasklet scheduler shared between all the CPUs. It is ticked by whichever CPU
+     * happens to be scheduling at the time, and locks internally, so tasklets can make progress
+     * on any CPU.
+     */
     pub tasklet_scheduler: TaskletScheduler,
+    config: SchedulerConfig,
 }
 
 pub struct CpuScheduler<P>
@@ -35,63 +81,339 @@ where
     /// List of Tasks ready to be scheduled. Backed by a `VecDeque` so we can rotate objects in the queue efficiently.
     ready_queue: VecDeque<Arc<Task<P>>>,
     blocked_queue: Vec<Arc<Task<P>>>,
+    /// The last task to exit on this CPU. We're still running on its kernel stack when we switch away from it,
+    /// so it's kept alive until another task exits here, by which point the CPU is on a different stack.
+    exited_task: Option<Arc<Task<P>>>,
+    /// How much longer the running task can run before it can be pre-empted by another task of the same
+    /// priority.
+    time_slice_remaining: Duration,
+    /// Whether this CPU has started scheduling tasks. New tasks are only placed on CPUs that are online.
+    online: bool,
+    governor: Governor,
 }
 
 impl<P> CpuScheduler<P>
 where
     P: Platform,
 {
-    pub fn new() -> CpuScheduler<P> {
-        CpuScheduler { running_task: None, ready_queue: VecDeque::new(), blocked_queue: Vec::new() }
+    pub fn new(online: bool) -> CpuScheduler<P> {
+        CpuScheduler {
+            running_task: None,
+            ready_queue: VecDeque::new(),
+            blocked_queue: Vec::new(),
+            exited_task: None,
+            time_slice_remaining: Duration::ZERO,
+            online,
+            governor: Governor::new(),
+        }
     }
 
     /// Choose the next task to be run. Returns `None` if no suitable task could be found to be run.
-    fn choose_next(&mut self) -> Option<Arc<Task<P>>> {
-        // TODO: in the future, this should consider task priorities etc.
-        self.ready_queue.pop_front()
+    ///
+    /// This is the first ready task with the highest effective priority, preferring tasks whose jobs haven't
+    /// used up their CPU share (see `schedule_key`). Tasks are placed at the back of the ready queue when they
+    /// stop running, so tasks of the same priority are run in turn.
+    fn choose_next(&mut self, now: Duration) -> Option<Arc<Task<P>>> {
+        let mut best: Option<(usize, (bool, Priority))> = None;
+        for (index, task) in self.ready_queue.iter().enumerate() {
+            let key = schedule_key(task, now);
+            if best.map_or(true, |(_, best_key)| key > best_key) {
+                best = Some((index, key));
+            }
+        }
+
+        let (index, _) = best?;
+        self.ready_queue.remove(index)
+    }
+
+    fn highest_ready_key(&self, now: Duration) -> Option<(bool, Priority)> {
+        self.ready_queue.iter().map(|task| schedule_key(task, now)).max()
+    }
+
+    fn all_tasks(&self) -> impl Iterator<Item = &Arc<Task<P>>> {
+        self.running_task.iter().chain(self.ready_queue.iter()).chain(self.blocked_queue.iter())
     }
+
+    /// An estimate of how busy this CPU is, used to balance new tasks between CPUs.
+    fn load(&self) -> usize {
+        self.ready_queue.len() + if self.running_task.is_some() { 1 } else { 0 }
+    }
+}
+
+/// Tasks are ordered for scheduling first by whether their job has CPU share left in the current period, and
+/// then by their effective priority. This makes CPU shares work-conserving: a task whose job has used up its
+/// share still runs if nothing else is ready, whatever its priority.
+fn schedule_key<P>(task: &Task<P>, now: Duration) -> (bool, Priority)
+where
+    P: Platform,
+{
+    (!task.job.is_throttled(now), task.priority.effective())
 }
 
 impl<P> Scheduler<P>
 where
     P: Platform,
 {
-    pub fn new() -> Scheduler<P> {
+    /// Create a new `Scheduler` for a system with `num_cpus` CPUs. Only the boot processor is
+    /// initially marked as online - other processors come online when they call `start_scheduling`.
+    /// `tick_period` is the period of the platform's timer interrupt, which advances the tasklet timer.
+    pub fn new(num_cpus: usize, tick_period: Duration) -> Scheduler<P> {
+        Self::with_config(num_cpus, tick_period, SchedulerConfig::default())
+    }
+
+    pub fn with_config(num_cpus: usize, tick_period: Duration, config: SchedulerConfig) -> Scheduler<P> {
         Scheduler {
-            task_scheduler: Spinlock::new(CpuScheduler::new()),
-            tasklet_scheduler: TaskletScheduler::new(),
+            task_schedulers: PerCpu::new(num_cpus, |cpu_id| Spinlock::new(CpuScheduler::new(cpu_id == 0))),
+            tasklet_scheduler: TaskletScheduler::new(tick_period),
+            config,
         }
     }
 
+    /// Add a new task to the scheduler. Ready tasks are placed on the run queue of the least-busy
+    /// online CPU. Once a task has been placed on a CPU, it stays on that CPU's queues.
     pub fn add_task(&self, task: Arc<Task<P>>) {
-        let mut scheduler = self.for_this_cpu();
+        let mut scheduler = self.least_loaded_cpu();
 
         let current_state = task.state.lock().clone();
         match current_state {
             TaskState::Ready => scheduler.ready_queue.push_back(task),
             TaskState::Blocked(_) => scheduler.blocked_queue.push(task),
             TaskState::Running => panic!("Tried to schedule task that's already running!"),
+            TaskState::Exited(_) => panic!("Tried to schedule task that has exited!"),
         }
     }
 
     pub fn for_this_cpu(&self) -> SpinlockGuard<CpuScheduler<P>> {
-        // XXX: this will need to take into account which CPU we're running on in the future
-        self.task_scheduler.lock()
+        self.task_schedulers.get::<P>().lock()
+    }
+
+    /// Get the task running on this CPU, without waiting for the lock on its scheduler. Returns `None` if the CPU
+    /// isn't running a task, or if the lock is held (e.g. because we've interrupted code that holds it), so this
+    /// can be used from interrupt handlers.
+    pub fn try_running_task(&self) -> Option<Arc<Task<P>>> {
+        self.task_schedulers.get::<P>().try_lock()?.running_task.clone()
+    }
+
+    pub fn num_cpus(&self) -> usize {
+        self.task_schedulers.num_cpus()
+    }
+
+    /// Lock the scheduler of the online CPU with the least work to do. Ties are broken in favour of
+    /// the CPU with the lowest ID. We only hold one `CpuScheduler` lock at a time, so the result is
+    /// only an estimate, but that's fine for balancing.
+    fn least_loaded_cpu(&self) -> SpinlockGuard<CpuScheduler<P>> {
+        let mut best: Option<(usize, usize)> = None;
+        for (cpu_id, scheduler) in self.task_schedulers.iter() {
+            let scheduler = scheduler.lock();
+            if !scheduler.online {
+                continue;
+            }
+
+            let load = scheduler.load();
+            if best.map_or(true, |(_, best_load)| load < best_load) {
+                best = Some((cpu_id, load));
+            }
+        }
+
+        let (cpu_id, _) = best.expect("No CPUs are online to schedule tasks on!");
+        self.task_schedulers.get_for(cpu_id).lock()
     }
 
-    /// Start scheduling! This should be called after a platform has finished initializing, and is
-    /// diverging. It gives kernel tasklets an initial poll while we're here in the kernel, and
-    /// then drops down into userspace.
+    /// Find all the tasks, on any CPU, for which `predicate` returns `true`.
+    pub fn find_tasks(&self, predicate: impl Fn(&Task<P>) -> bool) -> Vec<Arc<Task<P>>> {
+        let mut found = Vec::new();
+        for (_, scheduler) in self.task_schedulers.iter() {
+            let scheduler = scheduler.lock();
+            found.extend(scheduler.all_tasks().filter(|task| predicate(task)).cloned());
+        }
+        found
+    }
+
+    /// Lend the effective priority of `waiter` to every other task that holds a handle to any of the kernel
+    /// objects in `objects`, because `waiter` is about to block until one of them does something with it. The
+    /// tasks that have been lent the priority are returned, and `Scheduler::return_priority` should be called
+    /// with them once the waiter is unblocked.
+    pub fn lend_priority(&self, waiter: &Task<P>, objects: &[KernelObjectId]) -> Vec<Arc<Task<P>>> {
+        if objects.is_empty() {
+            return Vec::new();
+        }
+
+        let priority = waiter.priority.effective();
+        let lent_to = self.find_tasks(|task| {
+            task.id() != waiter.id() && objects.iter().any(|&object| task.handles.refers_to(object))
+        });
+        for task in &lent_to {
+            if priority > task.priority.effective() {
+                trace!("Task '{}' inherits priority {:?} from task '{}'", task.name, priority, waiter.name);
+            }
+            task.priority.inherit(waiter.id(), priority);
+        }
+        lent_to
+    }
+
+    pub fn return_priority(&self, waiter: &Task<P>, lent_to: Vec<Arc<Task<P>>>) {
+        for task in lent_to {
+            task.priority.release(waiter.id());
+        }
+    }
+
+    /// Try to resolve a page fault caused by the task running on this CPU (or by the kernel accessing its
+    /// memory on its behalf, if `from_user` is `false`). Faults on memory mapped into the task's address space
+    /// are resolved by the memory object mapped there, and faults below the task's user stack grow the stack, up
+    /// to the stack size limit of the task's job.
+    ///
+    /// Memory committed to resolve the fault is charged to the task's job. Faults caused by the task itself fail
+    /// if this would take the job over its memory limit, but faults caused by the kernel can't fail, so are
+    /// charged regardless.
+    pub fn handle_page_fault(
+        &self,
+        address: VAddr,
+        access: PageFaultAccess,
+        from_user: bool,
+    ) -> PageFaultResolution {
+        let Some(task) = self.for_this_cpu().running_task.clone() else {
+            return PageFaultResolution::Invalid;
+        };
+        let charge = |bytes| {
+            if from_user {
+                task.job.try_charge_memory(bytes)
+            } else {
+                Some(task.job.charge_memory(bytes))
+            }
+        };
+        if task.address_space.handle_page_fault(address, access, crate::PMM.get(), &charge) {
+            return PageFaultResolution::Resolved;
+        }
+
+        let max_stack_size = task.job.effective_limits().max_stack_size;
+        task.address_space.grow_user_stack(
+            &mut task.user_slot.lock().user_stack,
+            address,
+            max_stack_size,
+            crate::PMM.get(),
+            &charge,
+        )
+    }
+
+    /// Deliver an exception caused by the task running on this CPU to its exception channel, and stop the task
+    /// until the exception is resolved with `task_resume`. If nothing can receive the exception, the task is
+    /// killed instead. This only returns if the task should be resumed, in which case `registers` may have been
+    /// replaced with the registers it should be resumed with.
+    pub fn handle_exception(&self, kind: ExceptionKind, address: usize, registers: &mut Registers) {
+        let task = self.for_this_cpu().running_task.clone().unwrap();
+        info!("Task '{}' caused an exception: {:?} at {:#x}", task.name, kind, address);
+
+        /*
+         * The channel is a kernel channel, so the only reference to it outside the task is the handle to it. If
+         * that's been closed, nothing will ever resolve the exception.
+         */
+        let channel = task.exception_channel.lock().clone().filter(|channel| Arc::strong_count(channel) > 2);
+        let Some(channel) = channel else {
+            info!("Nothing is handling exceptions for task '{}'. Killing it.", task.name);
+            drop(task);
+            self.exit(EXCEPTION_EXIT_STATUS);
+        };
+
+        let mut bytes = Vec::new();
+        ptah::to_wire(&ExceptionInfo { kind, address, registers: *registers }, &mut bytes).unwrap();
+        *task.exception_state.lock() = ExceptionState::Stopped;
+        channel.add_message(Message::new(bytes, Vec::new()));
+
+        // XXX: like `block_until`, we wait by yielding until the exception is resolved
+        let resolution = loop {
+            if let ExceptionState::Resolved(resolution) = &*task.exception_state.lock() {
+                break resolution.clone();
+            }
+            if Arc::strong_count(&channel) <= 2 {
+                break ExceptionResolution::Kill;
+            }
+            if task.job.is_killed() {
+                drop(channel);
+                drop(task);
+                self.exit(JOB_KILLED_EXIT_STATUS);
+            }
+            self.schedule(TaskState::Ready);
+        };
+        *task.exception_state.lock() = ExceptionState::NotStopped;
+
+        match resolution {
+            ExceptionResolution::Resume => (),
+            ExceptionResolution::ResumeWithRegisters(new_registers) => *registers = new_registers,
+            ExceptionResolution::Kill => {
+                drop(channel);
+                drop(task);
+                self.exit(EXCEPTION_EXIT_STATUS);
+            }
+        }
+    }
+
+    /// Account for `elapsed` time passing on this CPU, pre-empting the running task if it has used up its
+    /// time slice and another task of the same priority is ready, or if a task of a higher priority is ready.
+    /// The time is charged to the running task's job, and the task exits if its job has been killed. A task
+    /// whose job has used up its CPU share is also pre-empted if a task whose job hasn't is ready.
+    /// This should be called from the platform's timer interrupt, but only if the interrupt arrived while the
+    /// CPU was running userspace - the interrupted kernel code could be holding locks the next task needs.
+    pub fn tick(&self, elapsed: Duration) {
+        self.exit_if_killed();
+
+        let should_preempt = {
+            let mut scheduler = self.for_this_cpu();
+            if let Some(performance) = scheduler.governor.update(P::monotonic_time()) {
+                P::set_cpu_performance(performance);
+            }
+            let Some(task) = scheduler.running_task.as_ref() else {
+                return;
+            };
+            let now = self.tasklet_scheduler.uptime();
+            task.job.charge_cpu_time(elapsed, now);
+            let key = schedule_key(task, now);
+            let priority = task.priority.effective();
+
+            scheduler.time_slice_remaining = scheduler.time_slice_remaining.saturating_sub(elapsed);
+            match scheduler.highest_ready_key(now) {
+                Some(ready) if ready > key => true,
+                Some(ready) if ready == key => scheduler.time_slice_remaining.is_zero(),
+                _ => {
+                    /*
+                     * Nothing else of the same or higher priority is ready, so the task can have
+                     * another slice.
+                     */
+                    if scheduler.time_slice_remaining.is_zero() {
+                        scheduler.time_slice_remaining = self.config.time_slice(priority);
+                    }
+                    false
+                }
+            }
+        };
+
+        if should_preempt {
+            self.schedule(TaskState::Ready);
+        }
+    }
+
+    /// Start scheduling! This should be called by each CPU after the platform has finished
+    /// initializing it, and is diverging. It gives kernel tasklets an initial poll while we're
+    /// here in the kernel, and then drops down into userspace.
+    ///
+    /// If there are no tasks for this CPU to run yet, it waits until one is given to it.
     pub fn start_scheduling(&self) -> ! {
-        info!("Kernel initialization done. Dropping to userspace.");
+        info!("Kernel initialization done on CPU {}. Dropping to userspace.", P::cpu_id());
 
-        self.tasklet_scheduler.tick();
+        self.for_this_cpu().online = true;
 
-        let mut scheduler = self.for_this_cpu();
-        assert!(scheduler.running_task.is_none());
-        let task = scheduler.choose_next().expect("Tried to drop into userspace with no ready tasks!");
-        assert!(task.state.lock().is_ready());
-        Self::drop_to_userspace(scheduler, task);
+        loop {
+            self.tasklet_scheduler.tick();
+
+            let mut scheduler = self.for_this_cpu();
+            assert!(scheduler.running_task.is_none());
+            if let Some(task) = scheduler.choose_next(self.tasklet_scheduler.uptime()) {
+                assert!(task.state.lock().is_ready());
+                self.drop_to_userspace(scheduler, task);
+            }
+
+            drop(scheduler);
+            self.idle();
+        }
     }
 
     /// Called when a userspace task yields or is pre-empted. This is responsible for the
@@ -108,13 +430,17 @@ where
     /// If the current task is switched away from, it will be placed in the state `new_state`. This
     /// allows the caller to block the current task on a dependency. If a task has been pre-empted
     /// or yields, it should be placed into `TaskState::Ready`.
+    ///
+    /// The current task is switched away from if any other task is ready, even if it is of a lower
+    /// priority than the current task. This is because tasks waiting on kernel objects currently
+    /// wait by yielding, and need the tasks they're waiting on to make progress.
     pub fn schedule(&self, new_state: TaskState) {
         self.tasklet_scheduler.tick();
 
         let mut scheduler = self.for_this_cpu();
         assert!(scheduler.running_task.is_some());
-        if let Some(next_task) = scheduler.choose_next() {
-            Self::switch_to(scheduler, new_state, next_task);
+        if let Some(next_task) = scheduler.choose_next(self.tasklet_scheduler.uptime()) {
+            self.switch_to(scheduler, new_state, next_task);
         } else {
             /*
              * There aren't any schedulable tasks. For now, we just return to the current one (by
@@ -127,13 +453,81 @@ where
         }
     }
 
+    /// Exit the task running on this CPU with the given status, and switch to another task. If this is the last
+    /// of the task's threads, its handles are dropped straight away, so the objects it held (e.g. the other ends
+    /// of its `Channel`s) observe it going away. Tasks waiting on it are released even if this CPU has nothing
+    /// else to run yet.
+    pub fn exit(&self, status: i32) -> ! {
+        let task = self.for_this_cpu().running_task.clone().unwrap();
+        info!("Task '{}' exited with status {}", task.name, status);
+        *task.state.lock() = TaskState::Exited(status);
+
+        /*
+         * We mark the task as exited before looking for other threads, so if the last two threads exit at the
+         * same time, at least one of them will close the handles.
+         */
+        let other_threads = self.find_tasks(|other| {
+            other.id() != task.id()
+                && Arc::ptr_eq(&other.handles, &task.handles)
+                && other.state.lock().exit_status().is_none()
+        });
+        if other_threads.is_empty() {
+            task.handles.clear();
+            #[cfg(feature = "test_runner")]
+            crate::test_runner::task_exited::<P>(&task.name, status);
+        }
+        drop(other_threads);
+        drop(task);
+
+        loop {
+            self.tasklet_scheduler.tick();
+
+            let mut scheduler = self.for_this_cpu();
+            if let Some(next_task) = scheduler.choose_next(self.tasklet_scheduler.uptime()) {
+                self.switch_to(scheduler, TaskState::Exited(status), next_task);
+                unreachable!("Switched back to a task that has exited!");
+            }
+
+            drop(scheduler);
+            self.idle();
+        }
+    }
+
+    /// Idle this CPU until an interrupt arrives, because it has nothing to run. The time spent idle is
+    /// accounted to the CPU's governor, which lowers the CPU's performance if it's spending a lot of its time
+    /// idle. This must not be called with this CPU's scheduler locked.
+    fn idle(&self) {
+        let start = P::monotonic_time();
+        P::idle();
+        let end = P::monotonic_time();
+
+        let mut scheduler = self.for_this_cpu();
+        scheduler.governor.record_idle(end.saturating_sub(start));
+        if let Some(performance) = scheduler.governor.update(end) {
+            P::set_cpu_performance(performance);
+        }
+    }
+
+    /// Exit the task running on this CPU if its job, or one of the job's ancestors, has been killed. This should
+    /// only be called when the task is about to return to userspace (or has just left it), as the kernel can't
+    /// hold any references to the task when it exits.
+    pub fn exit_if_killed(&self) {
+        let killed = self.for_this_cpu().running_task.as_ref().map_or(false, |task| task.job.is_killed());
+        if killed {
+            self.exit(JOB_KILLED_EXIT_STATUS);
+        }
+    }
+
     /// Perform the first transistion from the kernel into userspace. On some platforms, this has
     /// to be done differently to just a regular context-switch, so we handle it here separately.
-    fn drop_to_userspace(mut scheduler: SpinlockGuard<CpuScheduler<P>>, task: Arc<Task<P>>) -> ! {
+    fn drop_to_userspace(&self, mut scheduler: SpinlockGuard<CpuScheduler<P>>, task: Arc<Task<P>>) -> ! {
         trace!("Dropping into usermode into task: '{}'", task.name);
+        crate::trace::record::<P>(TraceEvent::TaskSwitch, [0, u64::from(task.id()), 0]);
+        task.perf.switch_to::<P>();
 
         *task.state.lock() = TaskState::Running;
         scheduler.running_task = Some(task.clone());
+        scheduler.time_slice_remaining = self.config.time_slice(task.priority.effective());
         task.address_space.switch_to();
 
         drop(scheduler);
@@ -150,20 +544,30 @@ where
     ///
     /// This function returns when the userspace task that originally called `schedule` is
     /// scheduled again, as if nothing happened.
-    fn switch_to(mut scheduler: SpinlockGuard<CpuScheduler<P>>, new_state: TaskState, next_task: Arc<Task<P>>) {
+    fn switch_to(
+        &self,
+        mut scheduler: SpinlockGuard<CpuScheduler<P>>,
+        new_state: TaskState,
+        next_task: Arc<Task<P>>,
+    ) {
         /*
          * We're switching task! We sort out the internal scheduler state, and then ask the
          * platform to perform the context switch for us!
          * NOTE: This temporarily allows `running_task` to be `None`.
          */
         let current_task = scheduler.running_task.take().unwrap();
-        assert!(current_task.state.lock().is_running());
+        assert!(matches!(*current_task.state.lock(), TaskState::Running | TaskState::Exited(_)));
         assert!(next_task.state.lock().is_ready());
 
         trace!("Switching from task '{}' to task '{}'", current_task.name, next_task.name);
+        crate::trace::record::<P>(
+            TraceEvent::TaskSwitch,
+            [u64::from(current_task.id()), u64::from(next_task.id()), 0],
+        );
 
         scheduler.running_task = Some(next_task.clone());
         *scheduler.running_task.as_ref().unwrap().state.lock() = TaskState::Running;
+        scheduler.time_slice_remaining = self.config.time_slice(next_task.priority.effective());
         match new_state {
             TaskState::Running => panic!("Tried to switch away from a task to state of Running!"),
             TaskState::Ready => {
@@ -175,14 +579,25 @@ where
                 *current_task.state.lock() = TaskState::Blocked(block);
                 scheduler.blocked_queue.push(current_task.clone());
             }
+            TaskState::Exited(_) => {
+                scheduler.exited_task = Some(current_task.clone());
+            }
         }
 
         current_task.address_space.switch_from();
         next_task.address_space.switch_to();
+        current_task.perf.switch_from::<P>();
+        next_task.perf.switch_to::<P>();
 
         let from_context = current_task.context.get();
         let to_context = scheduler.running_task.as_ref().unwrap().context.get() as *const P::TaskContext;
 
+        /*
+         * Both tasks are kept alive by the scheduler, so we can drop our references to them now. We need to,
+         * because a task that has exited is never switched back to, so this function never returns for it.
+         */
+        drop(current_task);
+        drop(next_task);
         drop(scheduler);
 
         unsafe {
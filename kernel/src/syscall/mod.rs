@@ -4,41 +4,133 @@ use crate::{
     object::{
         address_space::AddressSpace,
         channel::{ChannelEnd, Message},
+        dma_domain::DmaDomain,
         event::Event,
+        job::Job,
         memory_object::MemoryObject,
-        task::{Task, TaskState},
+        pipe::PipeEnd,
+        task::{ExceptionResolution, ExceptionState, Task, TaskState},
         KernelObject,
+        KernelObjectId,
         KernelObjectType,
     },
     scheduler::Scheduler,
     Platform,
 };
-use alloc::{string::ToString, sync::Arc};
+use alloc::{string::ToString, sync::Arc, vec::Vec};
 use bit_field::BitField;
-use core::{convert::TryFrom, sync::atomic::Ordering};
+use core::{
+    convert::TryFrom,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 use hal::memory::{Flags, PAddr, VAddr};
 use poplar::{
+    caps::Capabilities,
     syscall::{
         self,
         result::{handle_to_syscall_repr, status_to_syscall_repr, status_with_payload_to_syscall_repr},
+        AckInterruptError,
+        ChannelCallDetails,
+        ChannelCallError,
+        ChannelCallFlags,
+        ChannelCancelCallError,
+        ClearEventError,
+        CloneMemoryObjectError,
         CreateAddressSpaceError,
         CreateChannelError,
+        CreateDmaDomainError,
+        CreateDmaMemoryObjectError,
+        CreateEventError,
         CreateMemoryObjectError,
+        CreatePipeError,
+        DmaConstraints,
+        DmaDirection,
+        DmaDomainAttachError,
+        DmaDomainDetachError,
+        DmaSegment,
+        DmaSyncError,
         EarlyLogError,
+        EventFlags,
         FramebufferInfo,
         GetFramebufferError,
+        GetKernelMemoryStatsError,
+        GetMemoryUsageError,
         GetMessageError,
+        GetPlatformDevicesError,
+        GetProfileBuffersError,
+        GetTimeError,
+        GetTraceBuffersError,
+        HandleCloseError,
+        HandleDuplicateWithRightsError,
+        JobCreateError,
+        JobGetLimitsError,
+        JobKillError,
+        JobLimits,
+        JobSetLimitsError,
+        JobUsage,
+        JobUsageError,
+        KernelMemoryStats,
         MapMemoryObjectError,
+        MapMemoryObjectFlags,
         MemoryObjectFlags,
+        MemoryObjectSizeError,
+        MemoryRegion,
+        MemoryUsage,
+        ObjectWaitManyError,
+        PciConfigReadError,
+        PciConfigWriteError,
         PciGetInfoError,
+        PeekMessageError,
+        PerfConfigureError,
+        PerfEvent,
+        PerfReadError,
+        PipeReadError,
+        PipeWriteError,
         PollInterestError,
+        Priority,
+        ProfilingMode,
+        ProtectMemoryObjectError,
+        ReadKernelLogError,
+        Registers,
         SendMessageError,
+        SetMemoryObjectDiscardableError,
+        SetPriorityError,
+        SetProfilingError,
+        SetTimeError,
+        SetTracingError,
+        SignalEventError,
+        Signals,
         SpawnTaskDetails,
         SpawnTaskError,
+        SubscribeMemoryPressureError,
+        SystemRebootError,
+        SystemShutdownError,
+        TaskCreateDetails,
+        TaskCreateError,
+        TaskExceptionChannelError,
+        TaskGrantCapabilitiesError,
+        TaskInfo,
+        TaskInfoError,
+        TaskMemoryRegionsError,
+        TaskReadMemoryError,
+        TaskResumeError,
+        TaskRevokeCapabilitiesError,
+        TaskSetAuditError,
+        TaskWaitError,
+        ThreadCreateError,
+        UnmapMemoryObjectError,
         WaitForEventError,
+        WaitForMessageError,
+        WaitItem,
+        WaitOnAddressError,
         CHANNEL_MAX_NUM_HANDLES,
+        OBJECT_WAIT_MANY_MAX_ITEMS,
+        PERF_MAX_EVENTS,
     },
+    trace::{pack_syscall, TraceEvent},
     Handle,
+    HandleRights,
 };
 use spinning_top::RwSpinlock;
 use tracing::{info, warn};
@@ -61,6 +153,12 @@ pub fn handle_syscall<P>(
 where
     P: Platform,
 {
+    /*
+     * Tasks in jobs that have been killed exit the next time they make a system call. If the job is killed while
+     * the task is in the kernel, it exits on the way back to userspace instead.
+     */
+    scheduler.exit_if_killed();
+
     // Clone the current task out of the scheduler as we can't hold a lock on the scheduler
     let task = {
         let cpu_scheduler = scheduler.for_this_cpu();
@@ -72,18 +170,25 @@ where
     //     task.name, number, a, b, c, d, e
     // );
 
-    match number {
+    // Audited tasks have their system calls recorded, so driver authors can see what a task asked of the kernel
+    let audit = task.audit.load(Ordering::Relaxed).then(|| pack_syscall(u64::from(task.id()), number));
+    if let Some(packed) = audit {
+        crate::trace::write::<P>(TraceEvent::SyscallEnter, [packed, a as u64, b as u64]);
+    }
+
+    let result = match number {
         syscall::SYSCALL_YIELD => yield_syscall(scheduler),
         syscall::SYSCALL_EARLY_LOG => status_to_syscall_repr(early_log(&task, a, b)),
         syscall::SYSCALL_GET_FRAMEBUFFER => handle_to_syscall_repr(get_framebuffer(&task, a)),
         syscall::SYSCALL_CREATE_MEMORY_OBJECT => handle_to_syscall_repr(create_memory_object(&task, a, b, c)),
-        syscall::SYSCALL_MAP_MEMORY_OBJECT => status_to_syscall_repr(map_memory_object(&task, a, b, c, d)),
+        syscall::SYSCALL_MAP_MEMORY_OBJECT => status_to_syscall_repr(map_memory_object(&task, a, b, c, d, e)),
         syscall::SYSCALL_CREATE_CHANNEL => handle_to_syscall_repr(create_channel(&task, a)),
         syscall::SYSCALL_SEND_MESSAGE => status_to_syscall_repr(send_message(&task, a, b, c, d, e)),
         syscall::SYSCALL_GET_MESSAGE => status_with_payload_to_syscall_repr(get_message(&task, a, b, c, d, e)),
-        syscall::SYSCALL_WAIT_FOR_MESSAGE => todo!(),
+        syscall::SYSCALL_PEEK_MESSAGE => status_with_payload_to_syscall_repr(peek_message(&task, a)),
+        syscall::SYSCALL_WAIT_FOR_MESSAGE => status_to_syscall_repr(wait_for_message(scheduler, &task, a, b)),
         syscall::SYSCALL_PCI_GET_INFO => status_with_payload_to_syscall_repr(pci_get_info(&task, a, b)),
-        syscall::SYSCALL_WAIT_FOR_EVENT => status_to_syscall_repr(wait_for_event(scheduler, &task, a, b)),
+        syscall::SYSCALL_WAIT_FOR_EVENT => status_to_syscall_repr(wait_for_event(scheduler, &task, a, b, c)),
         syscall::SYSCALL_POLL_INTEREST => status_with_payload_to_syscall_repr(poll_interest(&task, a)),
         syscall::SYSCALL_CREATE_ADDRESS_SPACE => {
             handle_to_syscall_repr(create_address_space(&task, &mut kernel_page_tables.write()))
@@ -91,12 +196,109 @@ where
         syscall::SYSCALL_SPAWN_TASK => {
             handle_to_syscall_repr(spawn_task(&task, a, scheduler, &mut kernel_page_tables.write()))
         }
+        syscall::SYSCALL_TASK_CREATE => {
+            handle_to_syscall_repr(task_create(&task, a, scheduler, &mut kernel_page_tables.write()))
+        }
+        syscall::SYSCALL_SLEEP => sleep(scheduler, &task, a),
+        syscall::SYSCALL_GET_UPTIME => scheduler.tasklet_scheduler.uptime().as_nanos() as usize,
+        syscall::SYSCALL_SET_PRIORITY => status_to_syscall_repr(set_priority(&task, a, b)),
+        syscall::SYSCALL_CLONE_MEMORY_OBJECT => handle_to_syscall_repr(clone_memory_object(&task, a)),
+        syscall::SYSCALL_GET_MEMORY_USAGE => status_to_syscall_repr(get_memory_usage(&task, a, b)),
+        syscall::SYSCALL_GET_KERNEL_MEMORY_STATS => status_to_syscall_repr(get_kernel_memory_stats(a)),
+        syscall::SYSCALL_PROTECT_MEMORY_OBJECT => status_to_syscall_repr(protect_memory_object(&task, a, b, c)),
+        syscall::SYSCALL_TASK_INFO => status_with_payload_to_syscall_repr(task_info(&task, a, b)),
+        syscall::SYSCALL_SET_MEMORY_OBJECT_DISCARDABLE => {
+            status_with_payload_to_syscall_repr(set_memory_object_discardable(&task, a, b))
+        }
+        syscall::SYSCALL_SUBSCRIBE_MEMORY_PRESSURE => handle_to_syscall_repr(subscribe_memory_pressure(&task)),
+        syscall::SYSCALL_OBJECT_WAIT_MANY => {
+            status_to_syscall_repr(object_wait_many(scheduler, &task, a, b, c, d))
+        }
+        syscall::SYSCALL_HANDLE_DUPLICATE_WITH_RIGHTS => {
+            handle_to_syscall_repr(handle_duplicate_with_rights(&task, a, b))
+        }
+        syscall::SYSCALL_CREATE_DMA_MEMORY_OBJECT => {
+            handle_to_syscall_repr(create_dma_memory_object(&task, a, b, c, d, e))
+        }
+        syscall::SYSCALL_DMA_SYNC => status_to_syscall_repr(dma_sync(&task, a, b, c, d)),
+        syscall::SYSCALL_CREATE_DMA_DOMAIN => handle_to_syscall_repr(create_dma_domain(&task)),
+        syscall::SYSCALL_DMA_DOMAIN_ATTACH => status_to_syscall_repr(dma_domain_attach(&task, a, b)),
+        syscall::SYSCALL_DMA_DOMAIN_DETACH => status_to_syscall_repr(dma_domain_detach(&task, a, b)),
+        syscall::SYSCALL_PCI_CONFIG_READ => status_to_syscall_repr(pci_config_read(&task, a, b, c)),
+        syscall::SYSCALL_PCI_CONFIG_WRITE => status_to_syscall_repr(pci_config_write(&task, a, b, c)),
+        syscall::SYSCALL_ACK_INTERRUPT => status_to_syscall_repr(ack_interrupt(&task, a)),
+        syscall::SYSCALL_GET_PLATFORM_DEVICES => {
+            status_with_payload_to_syscall_repr(get_platform_devices(&task, a, b))
+        }
+        syscall::SYSCALL_GET_TIME => status_to_syscall_repr(get_time::<P>(a)),
+        syscall::SYSCALL_SET_TIME => status_to_syscall_repr(set_time(&task, a)),
+        syscall::SYSCALL_CLOCK_MONOTONIC => P::monotonic_time().as_nanos() as usize,
+        syscall::SYSCALL_SYSTEM_SHUTDOWN => status_to_syscall_repr(system_shutdown(&task)),
+        syscall::SYSCALL_SYSTEM_REBOOT => status_to_syscall_repr(system_reboot(&task)),
+        syscall::SYSCALL_TASK_EXIT => {
+            // `exit` never returns, so drop our reference to the task first, or it would never be freed
+            drop(task);
+            scheduler.exit(a as u32 as i32)
+        }
+        syscall::SYSCALL_TASK_WAIT => status_to_syscall_repr(task_wait(scheduler, &task, a, b, c)),
+        syscall::SYSCALL_THREAD_CREATE => {
+            handle_to_syscall_repr(thread_create(&task, a, b, scheduler, &mut kernel_page_tables.write()))
+        }
+        syscall::SYSCALL_WAIT_ON_ADDRESS => status_to_syscall_repr(wait_on_address(scheduler, &task, a, b, c)),
+        syscall::SYSCALL_WAKE_ADDRESS => task.address_space.wake_address(a, b),
+        syscall::SYSCALL_TASK_EXCEPTION_CHANNEL => handle_to_syscall_repr(task_exception_channel(&task, a)),
+        syscall::SYSCALL_TASK_RESUME => status_to_syscall_repr(task_resume(&task, a, b, c)),
+        syscall::SYSCALL_TASK_MEMORY_REGIONS => {
+            status_with_payload_to_syscall_repr(task_memory_regions(&task, a, b, c))
+        }
+        syscall::SYSCALL_TASK_READ_MEMORY => {
+            status_with_payload_to_syscall_repr(task_read_memory(&task, a, b, c, d))
+        }
+        syscall::SYSCALL_JOB_CREATE => handle_to_syscall_repr(job_create(&task, a)),
+        syscall::SYSCALL_JOB_KILL => status_to_syscall_repr(job_kill(&task, a)),
+        syscall::SYSCALL_JOB_USAGE => status_to_syscall_repr(job_usage(&task, a, b)),
+        syscall::SYSCALL_JOB_SET_LIMITS => status_to_syscall_repr(job_set_limits(&task, a, b)),
+        syscall::SYSCALL_JOB_GET_LIMITS => status_to_syscall_repr(job_get_limits(&task, a, b)),
+        syscall::SYSCALL_TASK_GRANT_CAPABILITIES => status_to_syscall_repr(task_grant_capabilities(&task, a, b)),
+        syscall::SYSCALL_TASK_REVOKE_CAPABILITIES => status_to_syscall_repr(task_revoke_capabilities(&task, a, b)),
+        syscall::SYSCALL_UNMAP_MEMORY_OBJECT => status_to_syscall_repr(unmap_memory_object(&task, a, b)),
+        syscall::SYSCALL_HANDLE_CLOSE => status_to_syscall_repr(handle_close(&task, a)),
+        syscall::SYSCALL_MEMORY_OBJECT_SIZE => status_to_syscall_repr(memory_object_size(&task, a, b)),
+        syscall::SYSCALL_CHANNEL_CALL => {
+            status_with_payload_to_syscall_repr(channel_call(scheduler, &task, a, b, c))
+        }
+        syscall::SYSCALL_CREATE_EVENT => handle_to_syscall_repr(create_event(&task, a)),
+        syscall::SYSCALL_SIGNAL_EVENT => status_to_syscall_repr(signal_event(&task, a)),
+        syscall::SYSCALL_CLEAR_EVENT => status_to_syscall_repr(clear_event(&task, a)),
+        syscall::SYSCALL_READ_KERNEL_LOG => status_with_payload_to_syscall_repr(read_kernel_log(&task, a, b, c)),
+        syscall::SYSCALL_GET_TRACE_BUFFERS => status_with_payload_to_syscall_repr(get_trace_buffers(&task, a, b)),
+        syscall::SYSCALL_SET_TRACING => status_to_syscall_repr(set_tracing(scheduler, &task, a)),
+        syscall::SYSCALL_TASK_SET_AUDIT => status_to_syscall_repr(task_set_audit(&task, a, b)),
+        syscall::SYSCALL_PERF_CONFIGURE => status_to_syscall_repr(perf_configure(&task, a, b, c)),
+        syscall::SYSCALL_PERF_READ => status_with_payload_to_syscall_repr(perf_read(&task, a, b, c)),
+        syscall::SYSCALL_GET_PROFILE_BUFFERS => {
+            status_with_payload_to_syscall_repr(get_profile_buffers(&task, a, b))
+        }
+        syscall::SYSCALL_SET_PROFILING => status_to_syscall_repr(set_profiling(&task, a)),
+        syscall::SYSCALL_CREATE_PIPE => handle_to_syscall_repr(create_pipe(&task, a)),
+        syscall::SYSCALL_PIPE_READ => status_with_payload_to_syscall_repr(pipe_read(scheduler, &task, a, b, c, d)),
+        syscall::SYSCALL_PIPE_WRITE => {
+            status_with_payload_to_syscall_repr(pipe_write(scheduler, &task, a, b, c, d))
+        }
+        syscall::SYSCALL_CHANNEL_CANCEL_CALL => status_to_syscall_repr(channel_cancel_call(&task, a)),
 
         _ => {
             warn!("Process made system call with invalid syscall number: {}", number);
             usize::MAX
         }
+    };
+
+    drop(task);
+    if let Some(packed) = audit {
+        crate::trace::write::<P>(TraceEvent::SyscallExit, [packed, result as u64, 0]);
     }
+    scheduler.exit_if_killed();
+    result
 }
 
 fn yield_syscall<P>(scheduler: &Scheduler<P>) -> usize
@@ -107,283 +309,1590 @@ where
     0
 }
 
-fn early_log<P>(task: &Arc<Task<P>>, str_length: usize, str_address: usize) -> Result<(), EarlyLogError>
+fn sleep<P>(scheduler: &Scheduler<P>, task: &Arc<Task<P>>, duration: usize) -> usize
 where
     P: Platform,
 {
-    // Check if the message is too long
-    if str_length > 8192 {
-        return Err(EarlyLogError::MessageTooLong);
+    if duration == 0 {
+        return yield_syscall(scheduler);
     }
 
-    // Check the message is valid UTF-8
-    let message = UserString::new(str_address as *mut u8, str_length)
-        .validate()
-        .map_err(|_| EarlyLogError::MessageNotValidUtf8)?;
+    block_until(scheduler, task, duration, &[], || false);
+    0
+}
 
-    info!("[{}]: {}", task.name, message);
+pub fn set_priority<P>(task: &Arc<Task<P>>, task_handle: usize, priority: usize) -> Result<(), SetPriorityError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::SET_PRIORITY) {
+        return Err(SetPriorityError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let priority = Priority::try_from(priority).map_err(|()| SetPriorityError::InvalidPriority)?;
+    let task_handle = Handle::try_from(task_handle).map_err(|_| SetPriorityError::InvalidHandle)?;
+    let target = if task_handle == Handle::ZERO {
+        task.clone()
+    } else {
+        task.handles
+            .get(task_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(SetPriorityError::InvalidHandle, SetPriorityError::TaskCannotBeModified)
+            })?
+            .downcast_arc::<Task<P>>()
+            .ok()
+            .ok_or(SetPriorityError::NotATask)?
+    };
+
+    /*
+     * If this lowers the priority of a running task, it'll be pre-empted at the next timer tick if
+     * a higher-priority task is ready.
+     */
+    target.priority.set_base(priority);
     Ok(())
 }
 
-fn get_framebuffer<P>(task: &Arc<Task<P>>, info_address: usize) -> Result<Handle, GetFramebufferError>
+fn task_grant_capabilities<P>(
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    capabilities: usize,
+) -> Result<(), TaskGrantCapabilitiesError>
 where
     P: Platform,
 {
-    let (info, memory_object) = crate::FRAMEBUFFER.try_get().ok_or(GetFramebufferError::NoFramebufferCreated)?;
-    let handle = task.handles.add(memory_object.clone());
+    if !task.capabilities.contains(Capabilities::MANAGE_CAPABILITIES) {
+        return Err(TaskGrantCapabilitiesError::TaskDoesNotHaveCorrectCapability);
+    }
 
-    UserPointer::new(info_address as *mut FramebufferInfo, true)
-        .validate_write(*info)
-        .map_err(|()| GetFramebufferError::InfoAddressIsInvalid)?;
+    let capabilities =
+        Capabilities::from_bits(capabilities as u32).ok_or(TaskGrantCapabilitiesError::InvalidCapabilities)?;
+    if !task.capabilities.contains(capabilities) {
+        return Err(TaskGrantCapabilitiesError::CapabilitiesNotHeld);
+    }
 
-    Ok(handle)
+    let task_handle = Handle::try_from(task_handle).map_err(|_| TaskGrantCapabilitiesError::InvalidHandle)?;
+    let target = task
+        .handles
+        .get(task_handle, HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(
+                TaskGrantCapabilitiesError::InvalidHandle,
+                TaskGrantCapabilitiesError::TaskCannotBeModified,
+            )
+        })?
+        .downcast_arc::<Task<P>>()
+        .ok()
+        .ok_or(TaskGrantCapabilitiesError::NotATask)?;
+
+    info!("Task '{}' granted capabilities {:?} to task '{}'", task.name, capabilities, target.name);
+    target.capabilities.grant(capabilities);
+    Ok(())
 }
 
-fn create_memory_object<P>(
+fn task_revoke_capabilities<P>(
     task: &Arc<Task<P>>,
-    size: usize,
-    flags: usize,
-    physical_address_ptr: usize,
-) -> Result<Handle, CreateMemoryObjectError>
+    task_handle: usize,
+    capabilities: usize,
+) -> Result<(), TaskRevokeCapabilitiesError>
 where
     P: Platform,
 {
-    use hal::memory::{FrameSize, Size4KiB};
-    use mulch::math::align_up;
+    let capabilities =
+        Capabilities::from_bits(capabilities as u32).ok_or(TaskRevokeCapabilitiesError::InvalidCapabilities)?;
+    let task_handle = Handle::try_from(task_handle).map_err(|_| TaskRevokeCapabilitiesError::InvalidHandle)?;
 
-    // TODO: should we require that the size be multiple of the page size, or just up it here?
-    let size = align_up(size, Size4KiB::SIZE);
-    let flags = MemoryObjectFlags::from_bits_truncate(flags as u32);
+    /*
+     * Any task can give up its own capabilities, but revoking them from other tasks needs the
+     * `ManageCapabilities` capability.
+     */
+    let target = if task_handle == Handle::ZERO {
+        task.clone()
+    } else {
+        if !task.capabilities.contains(Capabilities::MANAGE_CAPABILITIES) {
+            return Err(TaskRevokeCapabilitiesError::TaskDoesNotHaveCorrectCapability);
+        }
+        task.handles
+            .get(task_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(
+                    TaskRevokeCapabilitiesError::InvalidHandle,
+                    TaskRevokeCapabilitiesError::TaskCannotBeModified,
+                )
+            })?
+            .downcast_arc::<Task<P>>()
+            .ok()
+            .ok_or(TaskRevokeCapabilitiesError::NotATask)?
+    };
 
-    // TODO: do something more sensible with this when we have a concept of physical memory "ownership"
-    assert!(size % Size4KiB::SIZE == 0);
-    let physical_start = crate::PMM.get().alloc(size / Size4KiB::SIZE);
+    info!("Task '{}' revoked capabilities {:?} from task '{}'", task.name, capabilities, target.name);
+    target.capabilities.revoke(capabilities);
+    Ok(())
+}
 
-    let memory_object = MemoryObject::new(
-        task.id(),
-        physical_start,
-        size,
-        Flags {
-            writable: flags.contains(MemoryObjectFlags::WRITABLE),
-            executable: flags.contains(MemoryObjectFlags::EXECUTABLE),
-            user_accessible: true,
-            ..Default::default()
-        },
+fn get_time<P>(time_ptr: usize) -> Result<(), GetTimeError>
+where
+    P: Platform,
+{
+    let wall_clock = crate::WALL_CLOCK.try_get().ok_or(GetTimeError::PlatformDoesNotHaveRtc)?;
+    let now = wall_clock.now(P::monotonic_time());
+
+    UserPointer::new(time_ptr as *mut u64, true)
+        .validate_write(now.as_nanos() as u64)
+        .map_err(|()| GetTimeError::TimeAddressInvalid)
+}
+
+fn set_time<P>(task: &Arc<Task<P>>, nanos: usize) -> Result<(), SetTimeError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::SET_TIME) {
+        return Err(SetTimeError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let wall_clock = crate::WALL_CLOCK.try_get().ok_or(SetTimeError::PlatformDoesNotHaveRtc)?;
+    let time = Duration::from_nanos(nanos as u64);
+    info!(
+        "[{}] Setting wall-clock time to {}",
+        task.name,
+        poplar::time::DateTime::from_unix_timestamp(time.as_secs())
     );
+    wall_clock.set(time, P::monotonic_time()).map_err(|()| SetTimeError::InvalidTime)
+}
 
-    if physical_address_ptr != 0x0 {
-        UserPointer::new(physical_address_ptr as *mut PAddr, true)
-            .validate_write(physical_start)
-            .map_err(|()| CreateMemoryObjectError::InvalidPhysicalAddressPointer)?;
+fn system_shutdown<P>(task: &Arc<Task<P>>) -> Result<(), SystemShutdownError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::SYSTEM_POWER) {
+        return Err(SystemShutdownError::TaskDoesNotHaveCorrectCapability);
     }
 
-    Ok(task.handles.add(memory_object))
+    info!("[{}] Powering off the system", task.name);
+    P::power_off();
+    warn!("Failed to power off the system");
+    Err(SystemShutdownError::NotSupported)
 }
 
-fn map_memory_object<P>(
+fn system_reboot<P>(task: &Arc<Task<P>>) -> Result<(), SystemRebootError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::SYSTEM_POWER) {
+        return Err(SystemRebootError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    info!("[{}] Resetting the system", task.name);
+    P::reset();
+    warn!("Failed to reset the system");
+    Err(SystemRebootError::NotSupported)
+}
+
+fn read_kernel_log<P>(
     task: &Arc<Task<P>>,
-    memory_object_handle: usize,
-    address_space_handle: usize,
-    virtual_address: usize,
-    address_ptr: usize,
-) -> Result<(), MapMemoryObjectError>
+    position_address: usize,
+    buffer_address: usize,
+    buffer_length: usize,
+) -> Result<usize, ReadKernelLogError>
 where
     P: Platform,
 {
-    let memory_object_handle =
-        Handle::try_from(memory_object_handle).map_err(|_| MapMemoryObjectError::InvalidMemoryObjectHandle)?;
-    let address_space_handle =
-        Handle::try_from(address_space_handle).map_err(|_| MapMemoryObjectError::InvalidAddressSpaceHandle)?;
+    if !task.capabilities.contains(Capabilities::READ_KERNEL_LOG) {
+        return Err(ReadKernelLogError::TaskDoesNotHaveCorrectCapability);
+    }
 
-    let memory_object = task
-        .handles
-        .get(memory_object_handle)
-        .ok_or(MapMemoryObjectError::InvalidMemoryObjectHandle)?
-        .downcast_arc::<MemoryObject>()
-        .ok()
-        .ok_or(MapMemoryObjectError::InvalidMemoryObjectHandle)?;
+    let mut position = UserPointer::new(position_address as *mut u64, true);
+    let from = position.validate_read().map_err(|()| ReadKernelLogError::PositionAddressInvalid)?;
+    let buffer = UserSlice::new(buffer_address as *mut u8, buffer_length)
+        .validate_write()
+        .map_err(|()| ReadKernelLogError::BufferAddressInvalid)?;
 
-    let (virtual_address, write_to_ptr) = if virtual_address == 0x0 {
-        /*
-         * No virtual address supplied: we should find a suitable area of the virtual address space
-         * to map the object to, and write the address to the supplied pointer.
-         */
-        todo!()
-    } else {
-        // TODO: we need to actually validate that the supplied address is canonical and all that jazz
-        (VAddr::new(virtual_address), false)
-    };
+    let (start, length) = crate::klog::KERNEL_LOG.lock().read(from, buffer);
+    position.validate_write(start).map_err(|()| ReadKernelLogError::PositionAddressInvalid)?;
 
-    if address_space_handle == Handle::ZERO {
-        /*
-         * If the AddressSpace handle is the zero handle, we map the MemoryObject into the calling task's
-         * address space.
-         */
-        task.address_space.map_memory_object(memory_object.clone(), virtual_address, &crate::PMM.get())?;
-    } else {
-        task.handles
-            .get(address_space_handle)
-            .ok_or(MapMemoryObjectError::InvalidAddressSpaceHandle)?
-            .downcast_arc::<AddressSpace<P>>()
-            .ok()
-            .ok_or(MapMemoryObjectError::InvalidAddressSpaceHandle)?
-            .map_memory_object(memory_object.clone(), virtual_address, &crate::PMM.get())?;
+    let mut status = 0;
+    status.set_bits(16..48, length);
+    Ok(status)
+}
+
+fn get_trace_buffers<P>(
+    task: &Arc<Task<P>>,
+    buffer_address: usize,
+    buffer_length: usize,
+) -> Result<usize, GetTraceBuffersError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::TRACE) {
+        return Err(GetTraceBuffersError::TaskDoesNotHaveCorrectCapability);
     }
 
-    /*
-     * Only write to the pointer if: 1) we had to allocate an address 2) the caller wants to know,
-     * and 3) the mapping actually succeeded.
-     */
-    if write_to_ptr && address_ptr != 0x0 {
-        let mut address_ptr = UserPointer::new(address_ptr as *mut VAddr, true);
-        address_ptr.validate_write(virtual_address).map_err(|()| MapMemoryObjectError::AddressPointerInvalid)?;
+    let trace_buffers = crate::trace::TRACE_BUFFERS.try_get().ok_or(GetTraceBuffersError::TracingNotSupported)?;
+    let num_cpus = trace_buffers.num_cpus();
+    if buffer_length < num_cpus {
+        return Err(GetTraceBuffersError::BufferNotLargeEnough);
+    }
+    let handles = UserSlice::new(buffer_address as *mut Handle, num_cpus)
+        .validate_write()
+        .map_err(|()| GetTraceBuffersError::BufferAddressInvalid)?;
+    if !task.can_add_handles(num_cpus) {
+        return Err(GetTraceBuffersError::TooManyHandles);
+    }
+
+    for (handle, (_, buffer)) in handles.iter_mut().zip(trace_buffers.iter()) {
+        *handle =
+            task.handles.add_with_rights(buffer.memory_object.clone(), HandleRights::READ | HandleRights::MAP);
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, num_cpus);
+    Ok(status)
+}
+
+fn set_tracing<P>(scheduler: &Scheduler<P>, task: &Arc<Task<P>>, enabled: usize) -> Result<(), SetTracingError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::TRACE) {
+        return Err(SetTracingError::TaskDoesNotHaveCorrectCapability);
+    }
+    if crate::trace::TRACE_BUFFERS.try_get().is_none() {
+        return Err(SetTracingError::TracingNotSupported);
     }
 
+    let enabled = enabled != 0;
+    info!("[{}] Turning tracing {}", task.name, if enabled { "on" } else { "off" });
+    crate::trace::set_enabled(enabled);
+    if enabled {
+        for task in scheduler.find_tasks(|_| true) {
+            crate::trace::task_name(&task);
+        }
+    }
     Ok(())
 }
 
-fn create_channel<P>(task: &Arc<Task<P>>, other_end_address: usize) -> Result<Handle, CreateChannelError>
+fn task_set_audit<P>(task: &Arc<Task<P>>, task_handle: usize, enabled: usize) -> Result<(), TaskSetAuditError>
 where
     P: Platform,
 {
-    let (end_a, end_b) = ChannelEnd::new_channel(task.id());
-    let end_a_handle = task.handles.add(end_a);
-    let end_b_handle = task.handles.add(end_b);
+    if !task.capabilities.contains(Capabilities::TRACE) {
+        return Err(TaskSetAuditError::TaskDoesNotHaveCorrectCapability);
+    }
 
-    let mut other_end_ptr = UserPointer::new(other_end_address as *mut Handle, true);
-    other_end_ptr.validate_write(end_b_handle).map_err(|()| CreateChannelError::InvalidHandleAddress)?;
+    let task_handle = Handle::try_from(task_handle).map_err(|_| TaskSetAuditError::InvalidHandle)?;
+    let target = if task_handle == Handle::ZERO {
+        task.clone()
+    } else {
+        task.handles
+            .get(task_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(TaskSetAuditError::InvalidHandle, TaskSetAuditError::TaskCannotBeModified)
+            })?
+            .downcast_arc::<Task<P>>()
+            .ok()
+            .ok_or(TaskSetAuditError::NotATask)?
+    };
 
-    Ok(end_a_handle)
+    let enabled = enabled != 0;
+    info!("[{}] Turning auditing of task '{}' {}", task.name, target.name, if enabled { "on" } else { "off" });
+    target.audit.store(enabled, Ordering::Relaxed);
+    Ok(())
 }
 
-fn send_message<P>(
+fn perf_configure<P>(
     task: &Arc<Task<P>>,
-    channel_handle: usize,
-    byte_address: usize,
-    num_bytes: usize,
-    handles_address: usize,
-    num_handles: usize,
-) -> Result<(), SendMessageError>
+    task_handle: usize,
+    events_address: usize,
+    num_events: usize,
+) -> Result<(), PerfConfigureError>
 where
     P: Platform,
 {
-    use poplar::syscall::CHANNEL_MAX_NUM_BYTES;
-
-    if num_bytes > CHANNEL_MAX_NUM_BYTES {
-        return Err(SendMessageError::TooManyBytes);
-    }
-    if num_handles > CHANNEL_MAX_NUM_HANDLES {
-        return Err(SendMessageError::TooManyHandles);
+    if !task.capabilities.contains(Capabilities::PERF) {
+        return Err(PerfConfigureError::TaskDoesNotHaveCorrectCapability);
     }
 
-    let channel_handle = Handle::try_from(channel_handle).map_err(|_| SendMessageError::InvalidChannelHandle)?;
-    let bytes = if num_bytes == 0 {
-        &[]
-    } else {
-        UserSlice::new(byte_address as *mut u8, num_bytes)
-            .validate_read()
-            .map_err(|()| SendMessageError::BytesAddressInvalid)?
-    };
-    let handles = if num_handles == 0 {
-        &[]
+    let task_handle = Handle::try_from(task_handle).map_err(|_| PerfConfigureError::InvalidHandle)?;
+    let target = if task_handle == Handle::ZERO {
+        task.clone()
     } else {
-        UserSlice::new(handles_address as *mut Handle, num_handles)
-            .validate_read()
-            .map_err(|()| SendMessageError::HandlesAddressInvalid)?
+        task.handles
+            .get(task_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(PerfConfigureError::InvalidHandle, PerfConfigureError::TaskCannotBeModified)
+            })?
+            .downcast_arc::<Task<P>>()
+            .ok()
+            .ok_or(PerfConfigureError::NotATask)?
     };
-    let handle_objects = {
-        let mut arr = [const { None }; CHANNEL_MAX_NUM_HANDLES];
-        for (i, handle) in handles.iter().enumerate() {
-            arr[i] = match task.handles.get(*handle) {
-                Some(object) => Some(object.clone()),
-                None => return Err(SendMessageError::InvalidTransferredHandle),
-            };
 
-            /*
-             * We're transferring the handle's object, so we remove the handle to it from the sending task.
-             */
-            task.handles.remove(*handle);
-        }
-        arr
-    };
+    if num_events > PERF_MAX_EVENTS {
+        return Err(PerfConfigureError::TooManyEvents);
+    }
+    let mut events = [PerfEvent::Cycles; PERF_MAX_EVENTS];
+    let raw_events = UserSlice::new(events_address as *mut usize, num_events)
+        .validate_read()
+        .map_err(|()| PerfConfigureError::EventsAddressInvalid)?;
+    for (event, &raw_event) in events.iter_mut().zip(raw_events.iter()) {
+        *event = PerfEvent::try_from(raw_event).map_err(|()| PerfConfigureError::InvalidEvent)?;
+    }
 
-    task.handles
-        .get(channel_handle)
-        .ok_or(SendMessageError::InvalidChannelHandle)?
-        .downcast_arc::<ChannelEnd>()
-        .ok()
-        .ok_or(SendMessageError::NotAChannel)?
-        .send(Message { bytes: bytes.to_vec(), handle_objects })
+    target
+        .perf
+        .configure::<P>(&events[0..num_events], Arc::ptr_eq(&target, task))
+        .map_err(|()| PerfConfigureError::EventsNotSupported)
 }
 
-fn get_message<P>(
+fn perf_read<P>(
     task: &Arc<Task<P>>,
-    channel_handle: usize,
-    bytes_address: usize,
-    bytes_len: usize,
-    handles_address: usize,
-    handles_len: usize,
-) -> Result<usize, GetMessageError>
+    task_handle: usize,
+    buffer_address: usize,
+    buffer_length: usize,
+) -> Result<usize, PerfReadError>
 where
     P: Platform,
 {
-    let channel_handle = Handle::try_from(channel_handle).map_err(|_| GetMessageError::InvalidChannelHandle)?;
-
-    let channel = task
-        .handles
-        .get(channel_handle)
-        .ok_or(GetMessageError::InvalidChannelHandle)?
-        .downcast_arc::<ChannelEnd>()
-        .ok()
-        .ok_or(GetMessageError::NotAChannel)?;
-
-    channel.receive(|message| {
-        let num_handles = message.num_handles();
-
-        if message.bytes.len() > bytes_len {
-            return Err((message, GetMessageError::BytesBufferTooSmall));
-        }
-        if num_handles > handles_len {
-            return Err((message, GetMessageError::HandlesBufferTooSmall));
-        }
+    if !task.capabilities.contains(Capabilities::PERF) {
+        return Err(PerfReadError::TaskDoesNotHaveCorrectCapability);
+    }
 
-        if bytes_len > 0 && bytes_address != 0x0 {
-            let byte_buffer = match UserSlice::new(bytes_address as *mut u8, message.bytes.len()).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::BytesAddressInvalid)),
-            };
-            byte_buffer.copy_from_slice(&message.bytes);
-        }
+    let task_handle = Handle::try_from(task_handle).map_err(|_| PerfReadError::InvalidHandle)?;
+    let target = if task_handle == Handle::ZERO {
+        task.clone()
+    } else {
+        task.handles
+            .get(task_handle, HandleRights::READ)
+            .map_err(|err| err.to_syscall_error(PerfReadError::InvalidHandle, PerfReadError::TaskCannotBeRead))?
+            .downcast_arc::<Task<P>>()
+            .ok()
+            .ok_or(PerfReadError::NotATask)?
+    };
 
-        if handles_len > 0 && handles_address != 0x0 {
-            let handles_buffer = match UserSlice::new(handles_address as *mut Handle, num_handles).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::HandlesAddressInvalid)),
-            };
-            for i in 0..num_handles {
-                handles_buffer[i] = task.handles.add(message.handle_objects[i].as_ref().unwrap().clone());
-            }
-        }
+    let mut counts = [0; PERF_MAX_EVENTS];
+    let num_events = target
+        .perf
+        .read::<P>(&mut counts[0..usize::min(buffer_length, PERF_MAX_EVENTS)], Arc::ptr_eq(&target, task))
+        .map_err(|()| PerfReadError::BufferNotLargeEnough)?;
+    let buffer = UserSlice::new(buffer_address as *mut u64, num_events)
+        .validate_write()
+        .map_err(|()| PerfReadError::BufferAddressInvalid)?;
+    buffer.copy_from_slice(&counts[0..num_events]);
 
-        let mut status = 0;
-        status.set_bits(16..32, message.bytes.len());
-        status.set_bits(32..48, num_handles);
-        Ok(status)
-    })
+    let mut status = 0;
+    status.set_bits(16..48, num_events);
+    Ok(status)
 }
 
-fn pci_get_info<P>(
+fn get_profile_buffers<P>(
     task: &Arc<Task<P>>,
     buffer_address: usize,
-    buffer_size: usize,
-) -> Result<usize, PciGetInfoError>
+    buffer_length: usize,
+) -> Result<usize, GetProfileBuffersError>
 where
     P: Platform,
 {
-    use pci_types::{Bar, MAX_BARS};
-    use poplar::ddk::pci::PciDeviceInfo;
+    if !task.capabilities.contains(Capabilities::TRACE) {
+        return Err(GetProfileBuffersError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let profile_buffers =
+        crate::profile::PROFILE_BUFFERS.try_get().ok_or(GetProfileBuffersError::ProfilingNotSupported)?;
+    let num_cpus = profile_buffers.num_cpus();
+    if buffer_length < num_cpus {
+        return Err(GetProfileBuffersError::BufferNotLargeEnough);
+    }
+    let handles = UserSlice::new(buffer_address as *mut Handle, num_cpus)
+        .validate_write()
+        .map_err(|()| GetProfileBuffersError::BufferAddressInvalid)?;
+    if !task.can_add_handles(num_cpus) {
+        return Err(GetProfileBuffersError::TooManyHandles);
+    }
+
+    for (handle, (_, buffer)) in handles.iter_mut().zip(profile_buffers.iter()) {
+        *handle =
+            task.handles.add_with_rights(buffer.memory_object.clone(), HandleRights::READ | HandleRights::MAP);
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, num_cpus);
+    Ok(status)
+}
+
+fn set_profiling<P>(task: &Arc<Task<P>>, mode: usize) -> Result<(), SetProfilingError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::TRACE) {
+        return Err(SetProfilingError::TaskDoesNotHaveCorrectCapability);
+    }
+    if crate::profile::PROFILE_BUFFERS.try_get().is_none() {
+        return Err(SetProfilingError::ProfilingNotSupported);
+    }
+
+    let mode = ProfilingMode::try_from(mode).map_err(|()| SetProfilingError::InvalidMode)?;
+    info!("[{}] Setting profiling mode to {:?}", task.name, mode);
+    crate::profile::set_mode(mode);
+    Ok(())
+}
+
+fn early_log<P>(task: &Arc<Task<P>>, str_length: usize, str_address: usize) -> Result<(), EarlyLogError>
+where
+    P: Platform,
+{
+    // Check if the message is too long
+    if str_length > 8192 {
+        return Err(EarlyLogError::MessageTooLong);
+    }
+
+    // Check the message is valid UTF-8
+    let message = UserString::new(str_address as *mut u8, str_length)
+        .validate()
+        .map_err(|_| EarlyLogError::MessageNotValidUtf8)?;
+
+    info!("[{}]: {}", task.name, message);
+    Ok(())
+}
+
+fn get_framebuffer<P>(task: &Arc<Task<P>>, info_address: usize) -> Result<Handle, GetFramebufferError>
+where
+    P: Platform,
+{
+    let (info, memory_object) = crate::FRAMEBUFFER.try_get().ok_or(GetFramebufferError::NoFramebufferCreated)?;
+
+    if !task.can_add_handles(1) {
+        return Err(GetFramebufferError::TooManyHandles);
+    }
+
+    UserPointer::new(info_address as *mut FramebufferInfo, true)
+        .validate_write(*info)
+        .map_err(|()| GetFramebufferError::InfoAddressIsInvalid)?;
+
+    Ok(task.handles.add(memory_object.clone()))
+}
+
+fn create_memory_object<P>(
+    task: &Arc<Task<P>>,
+    size: usize,
+    flags: usize,
+    physical_address_ptr: usize,
+) -> Result<Handle, CreateMemoryObjectError>
+where
+    P: Platform,
+{
+    use hal::memory::{FrameSize, Size4KiB};
+    use mulch::math::align_up;
+
+    if size == 0 {
+        return Err(CreateMemoryObjectError::InvalidSize);
+    }
+    // TODO: should we require that the size be multiple of the page size, or just up it here?
+    let size = align_up(size, Size4KiB::SIZE);
+    let flags = MemoryObjectFlags::from_bits_truncate(flags as u32);
+
+    if flags.contains(MemoryObjectFlags::WRITABLE | MemoryObjectFlags::EXECUTABLE)
+        && !task.capabilities.contains(Capabilities::WRITABLE_EXECUTABLE_MEMORY)
+    {
+        return Err(CreateMemoryObjectError::WritableAndExecutable);
+    }
+
+    let mapping_flags = Flags {
+        writable: flags.contains(MemoryObjectFlags::WRITABLE),
+        executable: flags.contains(MemoryObjectFlags::EXECUTABLE),
+        user_accessible: true,
+        ..Default::default()
+    };
+
+    if !task.can_add_handles(1) {
+        return Err(CreateMemoryObjectError::TooManyHandles);
+    }
+
+    /*
+     * Lazy objects are charged to the job of the task that faults memory into them, as it's committed.
+     */
+    if flags.contains(MemoryObjectFlags::LAZY) {
+        if physical_address_ptr != 0x0 {
+            return Err(CreateMemoryObjectError::LazyObjectHasNoPhysicalAddress);
+        }
+        let memory_object = if flags.contains(MemoryObjectFlags::DISCARDABLE) {
+            MemoryObject::new_discardable(task.id(), size, mapping_flags)
+        } else {
+            MemoryObject::new_lazy(task.id(), size, mapping_flags)
+        };
+        return Ok(task.handles.add(memory_object));
+    }
+    if flags.contains(MemoryObjectFlags::DISCARDABLE) {
+        return Err(CreateMemoryObjectError::InvalidFlags);
+    }
+
+    let charge = task.job.try_charge_memory(size).ok_or(CreateMemoryObjectError::MemoryLimitExceeded)?;
+    assert!(size % Size4KiB::SIZE == 0);
+    let physical_start =
+        crate::PMM.get().try_alloc(size / Size4KiB::SIZE).ok_or(CreateMemoryObjectError::OutOfMemory)?;
+
+    /*
+     * The object owns its memory, so if we fail from here on, dropping it frees the memory and uncharges it.
+     */
+    let memory_object = MemoryObject::new_owned(task.id(), physical_start, size, mapping_flags, charge);
+
+    if physical_address_ptr != 0x0 {
+        UserPointer::new(physical_address_ptr as *mut PAddr, true)
+            .validate_write(physical_start)
+            .map_err(|()| CreateMemoryObjectError::InvalidPhysicalAddressPointer)?;
+    }
+
+    Ok(task.handles.add(memory_object))
+}
+
+fn create_dma_memory_object<P>(
+    task: &Arc<Task<P>>,
+    size: usize,
+    flags: usize,
+    constraints_ptr: usize,
+    segments_ptr: usize,
+    num_segments: usize,
+) -> Result<Handle, CreateDmaMemoryObjectError>
+where
+    P: Platform,
+{
+    use hal::memory::{FrameSize, Size4KiB};
+    use mulch::math::align_up;
+
+    if !task.capabilities.contains(Capabilities::DMA) {
+        return Err(CreateDmaMemoryObjectError::TaskDoesNotHaveCorrectCapability);
+    }
+    if size == 0 {
+        return Err(CreateDmaMemoryObjectError::InvalidSize);
+    }
+
+    if !task.can_add_handles(1) {
+        return Err(CreateDmaMemoryObjectError::TooManyHandles);
+    }
+
+    let size = align_up(size, Size4KiB::SIZE);
+    let flags = MemoryObjectFlags::from_bits_truncate(flags as u32);
+    if flags.contains(MemoryObjectFlags::WRITABLE | MemoryObjectFlags::EXECUTABLE)
+        && !task.capabilities.contains(Capabilities::WRITABLE_EXECUTABLE_MEMORY)
+    {
+        return Err(CreateDmaMemoryObjectError::WritableAndExecutable);
+    }
+    let constraints = UserPointer::new(constraints_ptr as *mut DmaConstraints, false)
+        .validate_read()
+        .map_err(|()| CreateDmaMemoryObjectError::ConstraintsAddressInvalid)?;
+
+    // Check we can report the segments before allocating any memory
+    if num_segments < constraints.num_segments(size) {
+        return Err(CreateDmaMemoryObjectError::SegmentsBufferTooSmall);
+    }
+    let segments = UserSlice::new(segments_ptr as *mut DmaSegment, num_segments)
+        .validate_write()
+        .map_err(|()| CreateDmaMemoryObjectError::SegmentsAddressInvalid)?;
+
+    let domain = if constraints.domain != 0 {
+        let domain = task
+            .handles
+            .get(Handle(constraints.domain), HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(
+                    CreateDmaMemoryObjectError::InvalidDomainHandle,
+                    CreateDmaMemoryObjectError::DomainCannotBeModified,
+                )
+            })?
+            .downcast_arc::<DmaDomain>()
+            .ok()
+            .ok_or(CreateDmaMemoryObjectError::InvalidDomainHandle)?;
+        Some(domain)
+    } else {
+        None
+    };
+
+    /*
+     * We always allocate physically-contiguous memory, which satisfies any segment size. If the highest address
+     * the device can access can't be represented as a physical address, all of physical memory is below it.
+     */
+    let charge = task.job.try_charge_memory(size).ok_or(CreateDmaMemoryObjectError::MemoryLimitExceeded)?;
+    let pmm = crate::PMM.get();
+    let physical_start = match PAddr::new(constraints.max_address.saturating_add(1) as usize) {
+        Some(limit) => pmm.alloc_below(size / Size4KiB::SIZE, limit),
+        None => pmm.try_alloc(size / Size4KiB::SIZE),
+    }
+    .ok_or(CreateDmaMemoryObjectError::CannotSatisfyConstraints)?;
+    unsafe {
+        P::zero_phys_memory(physical_start, size);
+    }
+
+    let mapping_flags = Flags {
+        writable: flags.contains(MemoryObjectFlags::WRITABLE),
+        executable: flags.contains(MemoryObjectFlags::EXECUTABLE),
+        user_accessible: true,
+        ..Default::default()
+    };
+
+    /*
+     * Memory is mapped into domains at its physical address, so the bus addresses devices use are the same
+     * whether or not they're attached to a domain. Domains can't unmap memory, so memory mapped into one can't
+     * be safely reused, and is never freed.
+     */
+    let memory_object = if let Some(domain) = domain {
+        domain.map(physical_start, size, mapping_flags.writable);
+        charge.keep();
+        MemoryObject::new(task.id(), physical_start, size, mapping_flags)
+    } else {
+        MemoryObject::new_owned(task.id(), physical_start, size, mapping_flags, charge)
+    };
+
+    let segment_size = constraints.segment_size();
+    for (i, offset) in (0..size).step_by(segment_size).enumerate() {
+        segments[i] = DmaSegment {
+            bus_address: usize::from(physical_start + offset) as u64,
+            size: usize::min(segment_size, size - offset),
+        };
+    }
+
+    Ok(task.handles.add(memory_object))
+}
+
+fn dma_sync<P>(
+    task: &Arc<Task<P>>,
+    memory_object_handle: usize,
+    offset: usize,
+    length: usize,
+    direction: usize,
+) -> Result<(), DmaSyncError>
+where
+    P: Platform,
+{
+    let direction = DmaDirection::try_from(direction).map_err(|()| DmaSyncError::InvalidDirection)?;
+    let memory_object_handle =
+        Handle::try_from(memory_object_handle).map_err(|_| DmaSyncError::InvalidMemoryObjectHandle)?;
+    let memory_object = task
+        .handles
+        .get(memory_object_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(DmaSyncError::InvalidMemoryObjectHandle, DmaSyncError::MemoryObjectCannotBeRead)
+        })?
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(DmaSyncError::InvalidMemoryObjectHandle)?;
+
+    let physical_address = memory_object.physical_address().ok_or(DmaSyncError::NotPhysicallyContiguous)?;
+    if offset.checked_add(length).map_or(true, |end| end > memory_object.size) {
+        return Err(DmaSyncError::InvalidRegion);
+    }
+
+    unsafe {
+        P::sync_dma(physical_address + offset, length, direction);
+    }
+    Ok(())
+}
+
+fn create_dma_domain<P>(task: &Arc<Task<P>>) -> Result<Handle, CreateDmaDomainError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::PCI_BUS_DRIVER) {
+        return Err(CreateDmaDomainError::TaskDoesNotHaveCorrectCapability);
+    }
+    if !task.can_add_handles(1) {
+        return Err(CreateDmaDomainError::TooManyHandles);
+    }
+
+    Ok(task.handles.add(DmaDomain::new(task.id())))
+}
+
+fn dma_domain_attach<P>(
+    task: &Arc<Task<P>>,
+    domain_handle: usize,
+    device: usize,
+) -> Result<(), DmaDomainAttachError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::PCI_BUS_DRIVER) {
+        return Err(DmaDomainAttachError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let domain_handle = Handle::try_from(domain_handle).map_err(|_| DmaDomainAttachError::InvalidDomainHandle)?;
+    let domain = task
+        .handles
+        .get(domain_handle, HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(
+                DmaDomainAttachError::InvalidDomainHandle,
+                DmaDomainAttachError::DomainCannotBeModified,
+            )
+        })?
+        .downcast_arc::<DmaDomain>()
+        .ok()
+        .ok_or(DmaDomainAttachError::InvalidDomainHandle)?;
+
+    let device = poplar::ddk::pci::address_from_raw(device as u32);
+    let device_exists = crate::PCI_INFO.read().as_ref().map_or(false, |info| info.devices.contains_key(&device));
+    if !device_exists {
+        return Err(DmaDomainAttachError::NoSuchDevice);
+    }
+
+    domain.attach(device)
+}
+
+fn dma_domain_detach<P>(
+    task: &Arc<Task<P>>,
+    domain_handle: usize,
+    device: usize,
+) -> Result<(), DmaDomainDetachError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::PCI_BUS_DRIVER) {
+        return Err(DmaDomainDetachError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let domain_handle = Handle::try_from(domain_handle).map_err(|_| DmaDomainDetachError::InvalidDomainHandle)?;
+    let domain = task
+        .handles
+        .get(domain_handle, HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(
+                DmaDomainDetachError::InvalidDomainHandle,
+                DmaDomainDetachError::DomainCannotBeModified,
+            )
+        })?
+        .downcast_arc::<DmaDomain>()
+        .ok()
+        .ok_or(DmaDomainDetachError::InvalidDomainHandle)?;
+
+    domain.detach(poplar::ddk::pci::address_from_raw(device as u32))
+}
+
+fn clone_memory_object<P>(
+    task: &Arc<Task<P>>,
+    memory_object_handle: usize,
+) -> Result<Handle, CloneMemoryObjectError>
+where
+    P: Platform,
+{
+    let memory_object_handle =
+        Handle::try_from(memory_object_handle).map_err(|_| CloneMemoryObjectError::InvalidMemoryObjectHandle)?;
+    let memory_object = task
+        .handles
+        .get(memory_object_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(
+                CloneMemoryObjectError::InvalidMemoryObjectHandle,
+                CloneMemoryObjectError::MemoryObjectCannotBeRead,
+            )
+        })?
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(CloneMemoryObjectError::InvalidMemoryObjectHandle)?;
+    if memory_object.is_discardable() {
+        return Err(CloneMemoryObjectError::MemoryObjectIsDiscardable);
+    }
+    if !task.can_add_handles(1) {
+        return Err(CloneMemoryObjectError::TooManyHandles);
+    }
+
+    Ok(task.handles.add(memory_object.clone_cow(task.id())))
+}
+
+fn set_memory_object_discardable<P>(
+    task: &Arc<Task<P>>,
+    memory_object_handle: usize,
+    discardable: usize,
+) -> Result<usize, SetMemoryObjectDiscardableError>
+where
+    P: Platform,
+{
+    let memory_object_handle = Handle::try_from(memory_object_handle)
+        .map_err(|_| SetMemoryObjectDiscardableError::InvalidMemoryObjectHandle)?;
+    let memory_object = task
+        .handles
+        .get(memory_object_handle, HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(
+                SetMemoryObjectDiscardableError::InvalidMemoryObjectHandle,
+                SetMemoryObjectDiscardableError::MemoryObjectCannotBeModified,
+            )
+        })?
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(SetMemoryObjectDiscardableError::InvalidMemoryObjectHandle)?;
+
+    let discarded =
+        memory_object.set_discardable(discardable != 0).ok_or(SetMemoryObjectDiscardableError::NotDiscardable)?;
+    let mut status = 0;
+    status.set_bit(16, discarded);
+    Ok(status)
+}
+
+fn subscribe_memory_pressure<P>(task: &Arc<Task<P>>) -> Result<Handle, SubscribeMemoryPressureError>
+where
+    P: Platform,
+{
+    if !task.can_add_handles(1) {
+        return Err(SubscribeMemoryPressureError::TooManyHandles);
+    }
+    Ok(task.handles.add(crate::PMM.get().subscribe_to_pressure()))
+}
+
+fn get_memory_usage<P>(
+    task: &Arc<Task<P>>,
+    address_space_handle: usize,
+    usage_ptr: usize,
+) -> Result<(), GetMemoryUsageError>
+where
+    P: Platform,
+{
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| GetMemoryUsageError::InvalidAddressSpaceHandle)?;
+    let usage = if address_space_handle == Handle::ZERO {
+        task.address_space.memory_usage()
+    } else {
+        task.handles
+            .get(address_space_handle, HandleRights::READ)
+            .map_err(|err| {
+                err.to_syscall_error(
+                    GetMemoryUsageError::InvalidAddressSpaceHandle,
+                    GetMemoryUsageError::AddressSpaceCannotBeRead,
+                )
+            })?
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(GetMemoryUsageError::InvalidAddressSpaceHandle)?
+            .memory_usage()
+    };
+
+    UserPointer::new(usage_ptr as *mut MemoryUsage, true)
+        .validate_write(usage)
+        .map_err(|()| GetMemoryUsageError::UsageAddressInvalid)
+}
+
+fn get_kernel_memory_stats(stats_ptr: usize) -> Result<(), GetKernelMemoryStatsError> {
+    let mut stats = KernelMemoryStats::default();
+
+    let pmm = crate::PMM.get();
+    let (physical_free, free_blocks) = pmm.free_memory();
+    stats.physical_total = pmm.total_bytes();
+    stats.physical_free = physical_free;
+    for (stat, free) in stats.free_blocks.iter_mut().zip(free_blocks) {
+        *stat = free;
+    }
+
+    (stats.heap_size, stats.heap_used) = crate::ALLOCATOR.usage();
+    stats.num_object_caches = crate::ALLOCATOR.cache_stats(&mut stats.object_caches);
+
+    UserPointer::new(stats_ptr as *mut KernelMemoryStats, true)
+        .validate_write(stats)
+        .map_err(|()| GetKernelMemoryStatsError::StatsAddressInvalid)
+}
+
+fn task_info<P>(task: &Arc<Task<P>>, buffer_address: usize, buffer_length: usize) -> Result<usize, TaskInfoError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::TRACE) {
+        return Err(TaskInfoError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    // Every task is in the tree of jobs below the root job, so we can find all of them from there
+    let mut tasks = Vec::new();
+    task.job.root().live_tasks(&mut tasks);
+
+    let num_written = usize::min(tasks.len(), buffer_length);
+    if num_written > 0 {
+        let infos: Vec<TaskInfo> = tasks[0..num_written].iter().map(|task| task.info()).collect();
+        let buffer = UserSlice::new(buffer_address as *mut TaskInfo, num_written)
+            .validate_write()
+            .map_err(|()| TaskInfoError::BufferAddressInvalid)?;
+        buffer.copy_from_slice(&infos);
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, tasks.len());
+    Ok(status)
+}
+
+fn map_memory_object<P>(
+    task: &Arc<Task<P>>,
+    memory_object_handle: usize,
+    address_space_handle: usize,
+    virtual_address: usize,
+    address_ptr: usize,
+    flags: usize,
+) -> Result<(), MapMemoryObjectError>
+where
+    P: Platform,
+{
+    use hal::memory::{FrameSize, Size2MiB};
+
+    let memory_object_handle =
+        Handle::try_from(memory_object_handle).map_err(|_| MapMemoryObjectError::InvalidMemoryObjectHandle)?;
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| MapMemoryObjectError::InvalidAddressSpaceHandle)?;
+
+    let (memory_object, rights) = task
+        .handles
+        .get_with_rights(memory_object_handle)
+        .ok_or(MapMemoryObjectError::InvalidMemoryObjectHandle)?;
+    if !rights.contains(HandleRights::MAP) {
+        return Err(MapMemoryObjectError::MemoryObjectCannotBeMapped);
+    }
+    let memory_object = memory_object
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(MapMemoryObjectError::InvalidMemoryObjectHandle)?;
+
+    /*
+     * The memory is only mapped writable if the handle has the `WRITE` right, so tasks can be given read-only
+     * access to a writable memory object.
+     */
+    let writable = rights.contains(HandleRights::WRITE);
+
+    /*
+     * Physically contiguous objects are mapped with huge pages wherever the alignment of the virtual and physical
+     * addresses allows it. Asking for huge pages makes sure that they can be used, rather than silently falling
+     * back to smaller pages. Objects smaller than a huge page are always mapped with smaller pages.
+     */
+    let flags = MapMemoryObjectFlags::from_bits_truncate(flags as u32);
+    if flags.contains(MapMemoryObjectFlags::HUGE_PAGES) {
+        let physical_address = memory_object.physical_address().ok_or(MapMemoryObjectError::CannotUseHugePages)?;
+        if virtual_address != 0x0
+            && memory_object.size >= Size2MiB::SIZE
+            && virtual_address % Size2MiB::SIZE != usize::from(physical_address) % Size2MiB::SIZE
+        {
+            return Err(MapMemoryObjectError::CannotUseHugePages);
+        }
+    }
+
+    let address_space = if address_space_handle == Handle::ZERO {
+        /*
+         * If the AddressSpace handle is the zero handle, we map the MemoryObject into the calling task's
+         * address space.
+         */
+        task.address_space.clone()
+    } else {
+        task.handles
+            .get(address_space_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(
+                    MapMemoryObjectError::InvalidAddressSpaceHandle,
+                    MapMemoryObjectError::AddressSpaceCannotBeModified,
+                )
+            })?
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(MapMemoryObjectError::InvalidAddressSpaceHandle)?
+    };
+
+    if virtual_address == 0x0 {
+        /*
+         * No virtual address supplied: we find a free area of the address space to map the object to, and
+         * write the address to the supplied pointer if the caller wants to know it.
+         */
+        let virtual_address =
+            address_space.map_memory_object_anywhere(memory_object, writable, &crate::PMM.get())?;
+        if address_ptr != 0x0 {
+            let mut address_ptr = UserPointer::new(address_ptr as *mut VAddr, true);
+            address_ptr
+                .validate_write(virtual_address)
+                .map_err(|()| MapMemoryObjectError::AddressPointerInvalid)?;
+        }
+    } else {
+        /*
+         * `VAddr` would quietly make a non-canonical address canonical, so we reject them here instead. The
+         * address space checks that the region is in the part of the address space userspace can use.
+         */
+        if usize::from(VAddr::new(virtual_address)) != virtual_address {
+            return Err(MapMemoryObjectError::AddressNotInUserSpace);
+        }
+        address_space.map_memory_object(
+            memory_object,
+            VAddr::new(virtual_address),
+            writable,
+            &crate::PMM.get(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn unmap_memory_object<P>(
+    task: &Arc<Task<P>>,
+    address_space_handle: usize,
+    virtual_address: usize,
+) -> Result<(), UnmapMemoryObjectError>
+where
+    P: Platform,
+{
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| UnmapMemoryObjectError::InvalidAddressSpaceHandle)?;
+
+    let address_space = if address_space_handle == Handle::ZERO {
+        task.address_space.clone()
+    } else {
+        task.handles
+            .get(address_space_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(
+                    UnmapMemoryObjectError::InvalidAddressSpaceHandle,
+                    UnmapMemoryObjectError::AddressSpaceCannotBeModified,
+                )
+            })?
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(UnmapMemoryObjectError::InvalidAddressSpaceHandle)?
+    };
+
+    address_space.unmap_memory_object(VAddr::new(virtual_address))
+}
+
+fn protect_memory_object<P>(
+    task: &Arc<Task<P>>,
+    address_space_handle: usize,
+    virtual_address: usize,
+    flags: usize,
+) -> Result<(), ProtectMemoryObjectError>
+where
+    P: Platform,
+{
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| ProtectMemoryObjectError::InvalidAddressSpaceHandle)?;
+    let flags = MemoryObjectFlags::from_bits_truncate(flags as u32);
+
+    let address_space = if address_space_handle == Handle::ZERO {
+        task.address_space.clone()
+    } else {
+        task.handles
+            .get(address_space_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(
+                    ProtectMemoryObjectError::InvalidAddressSpaceHandle,
+                    ProtectMemoryObjectError::AddressSpaceCannotBeModified,
+                )
+            })?
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(ProtectMemoryObjectError::InvalidAddressSpaceHandle)?
+    };
+
+    address_space.protect_memory_object(
+        VAddr::new(virtual_address),
+        flags.contains(MemoryObjectFlags::WRITABLE),
+        flags.contains(MemoryObjectFlags::EXECUTABLE),
+        &crate::PMM.get(),
+    )
+}
+
+fn memory_object_size<P>(
+    task: &Arc<Task<P>>,
+    memory_object_handle: usize,
+    size_address: usize,
+) -> Result<(), MemoryObjectSizeError>
+where
+    P: Platform,
+{
+    let memory_object_handle =
+        Handle::try_from(memory_object_handle).map_err(|_| MemoryObjectSizeError::InvalidHandle)?;
+    let memory_object = task
+        .handles
+        .get_with_rights(memory_object_handle)
+        .ok_or(MemoryObjectSizeError::InvalidHandle)?
+        .0
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(MemoryObjectSizeError::NotAMemoryObject)?;
+
+    let mut size_ptr = UserPointer::new(size_address as *mut usize, true);
+    size_ptr.validate_write(memory_object.size).map_err(|()| MemoryObjectSizeError::SizeAddressInvalid)?;
+    Ok(())
+}
+
+fn create_channel<P>(task: &Arc<Task<P>>, other_end_address: usize) -> Result<Handle, CreateChannelError>
+where
+    P: Platform,
+{
+    if !task.can_add_handles(2) {
+        return Err(CreateChannelError::TooManyHandles);
+    }
+
+    let (end_a, end_b) = ChannelEnd::new_channel(task.id());
+    let end_a_handle = task.handles.add(end_a);
+    let end_b_handle = task.handles.add(end_b);
+
+    let mut other_end_ptr = UserPointer::new(other_end_address as *mut Handle, true);
+    other_end_ptr.validate_write(end_b_handle).map_err(|()| CreateChannelError::InvalidHandleAddress)?;
+
+    Ok(end_a_handle)
+}
+
+fn create_pipe<P>(task: &Arc<Task<P>>, write_end_address: usize) -> Result<Handle, CreatePipeError>
+where
+    P: Platform,
+{
+    if !task.can_add_handles(2) {
+        return Err(CreatePipeError::TooManyHandles);
+    }
+
+    let (read_end, write_end) = PipeEnd::new_pipe(task.id());
+    let read_end_handle = task.handles.add(read_end);
+    let write_end_handle = task.handles.add(write_end);
+
+    let mut write_end_ptr = UserPointer::new(write_end_address as *mut Handle, true);
+    write_end_ptr.validate_write(write_end_handle).map_err(|()| CreatePipeError::InvalidHandleAddress)?;
+
+    Ok(read_end_handle)
+}
+
+fn pipe_read<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    pipe_handle: usize,
+    buffer_address: usize,
+    buffer_length: usize,
+    block: usize,
+) -> Result<usize, PipeReadError>
+where
+    P: Platform,
+{
+    let pipe_handle = Handle::try_from(pipe_handle).map_err(|_| PipeReadError::InvalidHandle)?;
+    let pipe = task
+        .handles
+        .get(pipe_handle, HandleRights::READ)
+        .map_err(|err| err.to_syscall_error(PipeReadError::InvalidHandle, PipeReadError::PipeCannotBeRead))?
+        .downcast_arc::<PipeEnd>()
+        .ok()
+        .ok_or(PipeReadError::NotAPipe)?;
+    if !pipe.is_read_end {
+        return Err(PipeReadError::PipeCannotBeRead);
+    }
+    let buffer = UserSlice::new(buffer_address as *mut u8, buffer_length)
+        .validate_write()
+        .map_err(|()| PipeReadError::BufferAddressInvalid)?;
+
+    /*
+     * The read is done once there's some data to read, or once the write end has been closed, at which point
+     * there's nothing more to come and `0` bytes are read. Whether the write end has been closed is checked before
+     * reading, so data written just before it was closed isn't missed.
+     */
+    let mut length = 0;
+    let mut try_read = || {
+        let closed = pipe.is_peer_closed();
+        length = pipe.read(buffer);
+        length > 0 || buffer.is_empty() || closed
+    };
+    let done = if block != 0 {
+        // Data is written by the tasks holding the write end, so they inherit our priority while we wait
+        let waiting_on: Vec<KernelObjectId> =
+            pipe.other_end().map(|other_end| other_end.id()).into_iter().collect();
+        block_until(scheduler, task, 0, &waiting_on, try_read)
+    } else {
+        try_read()
+    };
+    if !done {
+        return Err(PipeReadError::WouldBlock);
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, length);
+    Ok(status)
+}
+
+fn pipe_write<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    pipe_handle: usize,
+    data_address: usize,
+    data_length: usize,
+    block: usize,
+) -> Result<usize, PipeWriteError>
+where
+    P: Platform,
+{
+    let pipe_handle = Handle::try_from(pipe_handle).map_err(|_| PipeWriteError::InvalidHandle)?;
+    let pipe = task
+        .handles
+        .get(pipe_handle, HandleRights::WRITE)
+        .map_err(|err| err.to_syscall_error(PipeWriteError::InvalidHandle, PipeWriteError::PipeCannotBeWritten))?
+        .downcast_arc::<PipeEnd>()
+        .ok()
+        .ok_or(PipeWriteError::NotAPipe)?;
+    if pipe.is_read_end {
+        return Err(PipeWriteError::PipeCannotBeWritten);
+    }
+    let data = UserSlice::new(data_address as *mut u8, data_length)
+        .validate_read()
+        .map_err(|()| PipeWriteError::BufferAddressInvalid)?;
+
+    let mut length = 0;
+    let mut closed = false;
+    let mut try_write = || {
+        closed = pipe.is_peer_closed();
+        if !closed {
+            length = pipe.write(data);
+        }
+        length > 0 || data.is_empty() || closed
+    };
+    let done = if block != 0 {
+        // Space is made by the tasks holding the read end, so they inherit our priority while we wait
+        let waiting_on: Vec<KernelObjectId> =
+            pipe.other_end().map(|other_end| other_end.id()).into_iter().collect();
+        block_until(scheduler, task, 0, &waiting_on, try_write)
+    } else {
+        try_write()
+    };
+    if closed {
+        return Err(PipeWriteError::ReadEndClosed);
+    }
+    if !done {
+        return Err(PipeWriteError::WouldBlock);
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, length);
+    Ok(status)
+}
+
+fn send_message<P>(
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    byte_address: usize,
+    num_bytes: usize,
+    handles_address: usize,
+    num_handles: usize,
+) -> Result<(), SendMessageError>
+where
+    P: Platform,
+{
+    use poplar::syscall::CHANNEL_MAX_NUM_BYTES;
+
+    if num_bytes > CHANNEL_MAX_NUM_BYTES {
+        return Err(SendMessageError::TooManyBytes);
+    }
+    if num_handles > CHANNEL_MAX_NUM_HANDLES {
+        return Err(SendMessageError::TooManyHandles);
+    }
+
+    /*
+     * If a transaction ID is passed above the channel handle, the message is the reply to the call with that ID.
+     */
+    let txid = channel_handle.get_bits(32..48) as u16;
+    let channel_handle =
+        Handle::try_from(channel_handle.get_bits(0..32)).map_err(|_| SendMessageError::InvalidChannelHandle)?;
+    let bytes = if num_bytes == 0 {
+        &[]
+    } else {
+        UserSlice::new(byte_address as *mut u8, num_bytes)
+            .validate_read()
+            .map_err(|()| SendMessageError::BytesAddressInvalid)?
+    };
+    let handles = if num_handles == 0 {
+        &[]
+    } else {
+        UserSlice::new(handles_address as *mut Handle, num_handles)
+            .validate_read()
+            .map_err(|()| SendMessageError::HandlesAddressInvalid)?
+    };
+    let channel = task
+        .handles
+        .get(channel_handle, HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(SendMessageError::InvalidChannelHandle, SendMessageError::ChannelCannotSend)
+        })?
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(SendMessageError::NotAChannel)?;
+
+    let handle_objects = take_transferred_handles(task, handles)?;
+    crate::trace::record::<P>(
+        TraceEvent::ChannelSend,
+        [u64::from(channel.id()), bytes.len() as u64, handle_objects.len() as u64],
+    );
+    channel.send(Message { bytes: bytes.to_vec(), handle_objects, txid, is_reply: txid != 0 })
+}
+
+/// Remove the handles to be transferred by a message from the sending task, returning the objects they refer to
+/// along with their rights. If any of the handles can't be transferred, the sending task keeps all of them.
+fn take_transferred_handles<P>(
+    task: &Arc<Task<P>>,
+    handles: &[Handle],
+) -> Result<Vec<(Arc<dyn KernelObject>, HandleRights)>, SendMessageError>
+where
+    P: Platform,
+{
+    /*
+     * We're transferring the handles' objects, so we remove the handles to them from the sending task. The
+     * rights of each handle are transferred with it. The whole list is checked before any of them are removed
+     * (including that no handle is transferred twice), so the sending task keeps its handles if any of them
+     * can't be transferred.
+     */
+    task.handles.remove_all(handles, HandleRights::TRANSFER).map_err(|err| {
+        err.to_syscall_error(SendMessageError::InvalidTransferredHandle, SendMessageError::CannotTransferHandle)
+    })
+}
+
+fn get_message<P>(
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    bytes_address: usize,
+    bytes_len: usize,
+    handles_address: usize,
+    handles_len: usize,
+) -> Result<usize, GetMessageError>
+where
+    P: Platform,
+{
+    /*
+     * If a transaction ID is passed above the channel handle, we receive the reply to the call with that ID, instead
+     * of the next message.
+     */
+    let txid = channel_handle.get_bits(32..48) as u16;
+    let channel_handle =
+        Handle::try_from(channel_handle.get_bits(0..32)).map_err(|_| GetMessageError::InvalidChannelHandle)?;
+
+    let channel = task
+        .handles
+        .get(channel_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(GetMessageError::InvalidChannelHandle, GetMessageError::ChannelCannotReceive)
+        })?
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(GetMessageError::NotAChannel)?;
+
+    receive_message(task, &channel, txid, bytes_address, bytes_len, handles_address, handles_len)
+}
+
+/// Receive a message from `channel` into the given userspace buffers. See `ChannelEnd::receive` for the meaning of
+/// `txid`.
+fn receive_message<P>(
+    task: &Arc<Task<P>>,
+    channel: &ChannelEnd,
+    txid: u16,
+    bytes_address: usize,
+    bytes_len: usize,
+    handles_address: usize,
+    handles_len: usize,
+) -> Result<usize, GetMessageError>
+where
+    P: Platform,
+{
+    channel.receive(txid, |message| {
+        let num_handles = message.num_handles();
+
+        if message.bytes.len() > bytes_len {
+            return Err((message, GetMessageError::BytesBufferTooSmall));
+        }
+        if num_handles > handles_len {
+            return Err((message, GetMessageError::HandlesBufferTooSmall));
+        }
+
+        if bytes_len > 0 && bytes_address != 0x0 {
+            let byte_buffer = match UserSlice::new(bytes_address as *mut u8, message.bytes.len()).validate_write()
+            {
+                Ok(buffer) => buffer,
+                Err(()) => return Err((message, GetMessageError::BytesAddressInvalid)),
+            };
+            byte_buffer.copy_from_slice(&message.bytes);
+        }
+
+        if handles_len > 0 && handles_address != 0x0 {
+            let handles_buffer = match UserSlice::new(handles_address as *mut Handle, num_handles).validate_write()
+            {
+                Ok(buffer) => buffer,
+                Err(()) => return Err((message, GetMessageError::HandlesAddressInvalid)),
+            };
+            for (i, (object, rights)) in message.handle_objects.iter().enumerate() {
+                handles_buffer[i] = task.handles.add_with_rights(object.clone(), *rights);
+            }
+        }
+
+        crate::trace::record::<P>(
+            TraceEvent::ChannelReceive,
+            [u64::from(channel.id()), message.bytes.len() as u64, num_handles as u64],
+        );
+        Ok(message_info_to_payload(&message))
+    })
+}
+
+fn peek_message<P>(task: &Arc<Task<P>>, channel_handle: usize) -> Result<usize, PeekMessageError>
+where
+    P: Platform,
+{
+    let txid = channel_handle.get_bits(32..48) as u16;
+    let channel_handle =
+        Handle::try_from(channel_handle.get_bits(0..32)).map_err(|_| PeekMessageError::InvalidChannelHandle)?;
+
+    let channel = task
+        .handles
+        .get(channel_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(PeekMessageError::InvalidChannelHandle, PeekMessageError::ChannelCannotReceive)
+        })?
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(PeekMessageError::NotAChannel)?;
+
+    channel.peek(txid, message_info_to_payload).ok_or(PeekMessageError::NoMessage)
+}
+
+/// Encode the size of a message, and the transaction ID of the call it's part of, into the payload returned by
+/// `get_message`, `peek_message`, and `channel_call`.
+fn message_info_to_payload(message: &Message) -> usize {
+    let mut payload = 0;
+    payload.set_bits(16..32, message.bytes.len());
+    payload.set_bits(32..48, message.num_handles());
+    payload.set_bits(48..64, message.txid as usize);
+    payload
+}
+
+fn channel_call<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    details_address: usize,
+    timeout: usize,
+) -> Result<usize, ChannelCallError>
+where
+    P: Platform,
+{
+    use poplar::syscall::CHANNEL_MAX_NUM_BYTES;
+
+    let mut details_ptr = UserPointer::new(details_address as *mut ChannelCallDetails, true);
+    let mut details = details_ptr.validate_read().map_err(|()| ChannelCallError::DetailsAddressInvalid)?;
+    let flags = ChannelCallFlags::from_bits_truncate(details.flags);
+
+    if details.num_bytes > CHANNEL_MAX_NUM_BYTES {
+        return Err(ChannelCallError::TooManyBytes);
+    }
+    if details.num_handles > CHANNEL_MAX_NUM_HANDLES {
+        return Err(ChannelCallError::TooManyHandles);
+    }
+
+    let channel_handle = Handle::try_from(channel_handle).map_err(|_| ChannelCallError::InvalidChannelHandle)?;
+    let bytes = if details.num_bytes == 0 {
+        &[]
+    } else {
+        UserSlice::new(details.bytes_ptr as *mut u8, details.num_bytes)
+            .validate_read()
+            .map_err(|()| ChannelCallError::BytesAddressInvalid)?
+    };
+    let handles = if details.num_handles == 0 {
+        &[]
+    } else {
+        UserSlice::new(details.handles_ptr as *mut Handle, details.num_handles)
+            .validate_read()
+            .map_err(|()| ChannelCallError::HandlesAddressInvalid)?
+    };
+    let channel = task
+        .handles
+        .get(channel_handle, HandleRights::READ | HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(ChannelCallError::InvalidChannelHandle, ChannelCallError::ChannelCannotCall)
+        })?
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(ChannelCallError::NotAChannel)?;
+
+    /*
+     * The call has to be started before the message is sent, so the reply isn't discarded if it arrives before
+     * we start waiting for it. If we fail before the caller has been told the call's transaction ID, or stop
+     * waiting for the reply, the call is abandoned, as nothing could receive its reply.
+     */
+    let txid = channel.begin_call().ok_or(ChannelCallError::TooManyPendingCalls)?;
+    let handle_objects = match take_transferred_handles(task, handles) {
+        Ok(handle_objects) => handle_objects,
+        Err(err) => {
+            channel.end_call(txid);
+            return Err(match err {
+                SendMessageError::CannotTransferHandle => ChannelCallError::CannotTransferHandle,
+                _ => ChannelCallError::InvalidTransferredHandle,
+            });
+        }
+    };
+
+    crate::trace::record::<P>(
+        TraceEvent::ChannelSend,
+        [u64::from(channel.id()), bytes.len() as u64, handle_objects.len() as u64],
+    );
+    if channel.send(Message { bytes: bytes.to_vec(), handle_objects, txid, is_reply: false }).is_err() {
+        channel.end_call(txid);
+        return Err(ChannelCallError::OtherEndDisconnected);
+    }
+
+    details.txid = txid;
+    if details_ptr.validate_write(details).is_err() {
+        channel.end_call(txid);
+        return Err(ChannelCallError::DetailsAddressInvalid);
+    }
+
+    if flags.contains(ChannelCallFlags::NO_WAIT) {
+        return Err(ChannelCallError::ReplyPending);
+    }
+
+    /*
+     * The reply is sent by the tasks that hold the other end, so they inherit our priority until it arrives.
+     */
+    let other_end: Vec<KernelObjectId> = channel.other_end().map(|other_end| other_end.id()).into_iter().collect();
+    if !block_until(scheduler, task, timeout, &other_end, || channel.has_reply(txid)) {
+        channel.end_call(txid);
+        return Err(ChannelCallError::TimedOut);
+    }
+
+    receive_message(
+        task,
+        &channel,
+        txid,
+        details.reply_bytes_ptr as usize,
+        details.reply_bytes_len,
+        details.reply_handles_ptr as usize,
+        details.reply_handles_len,
+    )
+    .map_err(|err| match err {
+        GetMessageError::BytesAddressInvalid => ChannelCallError::ReplyBytesAddressInvalid,
+        GetMessageError::BytesBufferTooSmall => ChannelCallError::ReplyBytesBufferTooSmall,
+        GetMessageError::HandlesAddressInvalid => ChannelCallError::ReplyHandlesAddressInvalid,
+        GetMessageError::HandlesBufferTooSmall => ChannelCallError::ReplyHandlesBufferTooSmall,
+        // We hold the channel, and the reply has arrived, so these can't happen
+        _ => ChannelCallError::ReplyPending,
+    })
+}
+
+fn channel_cancel_call<P>(task: &Arc<Task<P>>, channel_handle: usize) -> Result<(), ChannelCancelCallError>
+where
+    P: Platform,
+{
+    let txid = channel_handle.get_bits(32..48) as u16;
+    let channel_handle = Handle::try_from(channel_handle.get_bits(0..32))
+        .map_err(|_| ChannelCancelCallError::InvalidChannelHandle)?;
+
+    let channel = task
+        .handles
+        .get(channel_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(
+                ChannelCancelCallError::InvalidChannelHandle,
+                ChannelCancelCallError::ChannelCannotReceive,
+            )
+        })?
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(ChannelCancelCallError::NotAChannel)?;
+
+    // `0` isn't the transaction ID of a call, so there's nothing to cancel
+    if txid != 0 {
+        channel.end_call(txid);
+    }
+    Ok(())
+}
+
+fn pci_get_info<P>(
+    task: &Arc<Task<P>>,
+    buffer_address: usize,
+    buffer_size: usize,
+) -> Result<usize, PciGetInfoError>
+where
+    P: Platform,
+{
+    use pci_types::{Bar, MAX_BARS};
+    use poplar::ddk::pci::{PciDeviceInfo, MAX_INTERRUPTS};
 
     // TODO: request this through the platform nicely instead of through a huge global
     if let Some(ref pci_info) = *crate::PCI_INFO.read() {
@@ -398,8 +1907,27 @@ where
                 .validate_write()
                 .map_err(|()| PciGetInfoError::BufferPointerInvalid)?;
 
+            let num_handles: usize = pci_info
+                .devices
+                .values()
+                .map(|device| {
+                    let num_bars = device
+                        .bars
+                        .iter()
+                        .filter(|bar| matches!(bar, Some(Bar::Memory32 { .. } | Bar::Memory64 { .. })))
+                        .count();
+                    usize::min(device.interrupt_events.len(), MAX_INTERRUPTS) + num_bars
+                })
+                .sum();
+            if !task.can_add_handles(num_handles) {
+                return Err(PciGetInfoError::TooManyHandles);
+            }
+
             for (i, (&address, device)) in pci_info.devices.iter().enumerate() {
-                let interrupt_handle = device.interrupt_event.clone().map(|interrupt| task.handles.add(interrupt));
+                let mut interrupts = [None; MAX_INTERRUPTS];
+                for (handle, event) in interrupts.iter_mut().zip(device.interrupt_events.iter()) {
+                    *handle = Some(task.handles.add(event.clone()));
+                }
 
                 let mut device_descriptor = poplar::ddk::pci::PciDeviceInfo {
                     address,
@@ -410,7 +1938,7 @@ where
                     sub_class: device.sub_class,
                     interface: device.interface,
                     bars: [const { None }; MAX_BARS],
-                    interrupt: interrupt_handle,
+                    interrupts,
                 };
 
                 for i in 0..MAX_BARS {
@@ -470,11 +1998,169 @@ where
     }
 }
 
+/// PCI configuration space is accessed in dwords, and (through ECAM) each function has 4KiB of it.
+const PCI_CONFIG_SPACE_SIZE: usize = 0x1000;
+
+fn pci_config_read<P>(
+    task: &Arc<Task<P>>,
+    device: usize,
+    offset: usize,
+    value_ptr: usize,
+) -> Result<(), PciConfigReadError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::PCI_BUS_DRIVER) {
+        return Err(PciConfigReadError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let access = crate::PCI_ACCESS
+        .try_get()
+        .and_then(|access| access.as_ref())
+        .ok_or(PciConfigReadError::PlatformDoesNotSupportPci)?;
+    let device = poplar::ddk::pci::address_from_raw(device as u32);
+    if !crate::PCI_INFO.read().as_ref().map_or(false, |info| info.devices.contains_key(&device)) {
+        return Err(PciConfigReadError::NoSuchDevice);
+    }
+    if offset % 4 != 0 || offset >= PCI_CONFIG_SPACE_SIZE {
+        return Err(PciConfigReadError::InvalidOffset);
+    }
+
+    let value = unsafe { access.lock().read(device, offset as u16) };
+    UserPointer::new(value_ptr as *mut u32, true)
+        .validate_write(value)
+        .map_err(|()| PciConfigReadError::ValueAddressInvalid)
+}
+
+fn pci_config_write<P>(
+    task: &Arc<Task<P>>,
+    device: usize,
+    offset: usize,
+    value: usize,
+) -> Result<(), PciConfigWriteError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(Capabilities::PCI_BUS_DRIVER) {
+        return Err(PciConfigWriteError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let access = crate::PCI_ACCESS
+        .try_get()
+        .and_then(|access| access.as_ref())
+        .ok_or(PciConfigWriteError::PlatformDoesNotSupportPci)?;
+    let device = poplar::ddk::pci::address_from_raw(device as u32);
+    if !crate::PCI_INFO.read().as_ref().map_or(false, |info| info.devices.contains_key(&device)) {
+        return Err(PciConfigWriteError::NoSuchDevice);
+    }
+    if offset % 4 != 0 || offset >= PCI_CONFIG_SPACE_SIZE {
+        return Err(PciConfigWriteError::InvalidOffset);
+    }
+
+    unsafe {
+        access.lock().write(device, offset as u16, value as u32);
+    }
+    Ok(())
+}
+
+fn get_platform_devices<P>(
+    task: &Arc<Task<P>>,
+    buffer_address: usize,
+    buffer_size: usize,
+) -> Result<usize, GetPlatformDevicesError>
+where
+    P: Platform,
+{
+    use hal::memory::{FrameSize, Size4KiB};
+    use mulch::math::{align_down, align_up};
+    use poplar::ddk::platform::{
+        IoPortRange,
+        PlatformDeviceInfo,
+        Region,
+        MAX_COMPATIBLE_LENGTH,
+        MAX_IO_PORT_RANGES,
+        MAX_NAME_LENGTH,
+        MAX_REGIONS,
+    };
+
+    if !task.capabilities.contains(Capabilities::PLATFORM_DEVICES) {
+        return Err(GetPlatformDevicesError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let devices = crate::PLATFORM_DEVICES.read();
+    let num_descriptors = devices.len();
+
+    if buffer_size == 0 || buffer_address == 0x0 || buffer_size < num_descriptors {
+        return Err(GetPlatformDevicesError::BufferNotLargeEnough(num_descriptors as u32));
+    }
+
+    let descriptor_buffer = UserSlice::new(buffer_address as *mut PlatformDeviceInfo, buffer_size)
+        .validate_write()
+        .map_err(|()| GetPlatformDevicesError::BufferPointerInvalid)?;
+
+    let num_handles: usize = devices
+        .iter()
+        .map(|device| usize::min(device.regions.len(), MAX_REGIONS) + device.interrupt.iter().count())
+        .sum();
+    if !task.can_add_handles(num_handles) {
+        return Err(GetPlatformDevicesError::TooManyHandles);
+    }
+
+    for (descriptor, device) in descriptor_buffer.iter_mut().zip(devices.iter()) {
+        let mut name = [0u8; MAX_NAME_LENGTH];
+        let name_length = usize::min(device.name.len(), MAX_NAME_LENGTH);
+        name[0..name_length].copy_from_slice(&device.name.as_bytes()[0..name_length]);
+
+        /*
+         * Each compatible string is followed by a zero. We drop any that don't fit entirely, which are the least
+         * specific.
+         */
+        let mut compatible = [0u8; MAX_COMPATIBLE_LENGTH];
+        let mut offset = 0;
+        for entry in &device.compatible {
+            if offset + entry.len() + 1 > MAX_COMPATIBLE_LENGTH {
+                break;
+            }
+            compatible[offset..(offset + entry.len())].copy_from_slice(entry.as_bytes());
+            offset += entry.len() + 1;
+        }
+
+        let mut regions = [None; MAX_REGIONS];
+        for (region, &(address, size)) in regions.iter_mut().zip(device.regions.iter()) {
+            let start = align_down(usize::from(address), Size4KiB::SIZE);
+            let offset = usize::from(address) - start;
+            let flags = Flags { writable: true, executable: false, user_accessible: true, cached: false };
+            // TODO: should the requesting task own the device's memory objects, or should the kernel?
+            let memory_object = MemoryObject::new(
+                task.id(),
+                PAddr::new(start).unwrap(),
+                align_up(offset + size, Size4KiB::SIZE),
+                flags,
+            );
+            *region = Some(Region { memory_object: task.handles.add(memory_object), size, offset });
+        }
+
+        let mut io_ports = [None; MAX_IO_PORT_RANGES];
+        for (range, &(base, length)) in io_ports.iter_mut().zip(device.io_ports.iter()) {
+            *range = Some(IoPortRange { base, length });
+        }
+
+        let interrupt = device.interrupt.as_ref().map(|event| task.handles.add(event.clone()));
+
+        *descriptor = PlatformDeviceInfo { name, compatible, regions, io_ports, interrupt };
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, num_descriptors);
+    Ok(status)
+}
+
 pub fn wait_for_event<P>(
     scheduler: &Scheduler<P>,
     task: &Arc<Task<P>>,
     event_handle: usize,
     block: usize,
+    timeout: usize,
 ) -> Result<(), WaitForEventError>
 where
     P: Platform,
@@ -483,29 +2169,326 @@ where
     let block = block != 0;
     let event = task
         .handles
-        .get(event_handle)
-        .ok_or(WaitForEventError::InvalidHandle)?
+        .get(event_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(WaitForEventError::InvalidHandle, WaitForEventError::EventCannotBeWaitedFor)
+        })?
         .downcast_arc::<Event>()
         .ok()
         .ok_or(WaitForEventError::NotAnEvent)?;
 
-    if block {
-        /*
-         * XXX: This is an extremely simple way of implementing this. We should instead probably block
-         * the task, and spawn a tasklet that is awoken when the event is triggered to unblock it. For
-         * now, though, this will work well enough.
-         */
-        while !event.signalled.load(Ordering::SeqCst) {
-            scheduler.schedule(TaskState::Ready);
+    let consume_event = || event.consume();
+
+    if block {
+        if block_until(scheduler, task, timeout, &[], consume_event) {
+            Ok(())
+        } else {
+            Err(WaitForEventError::TimedOut)
+        }
+    } else if consume_event() {
+        Ok(())
+    } else {
+        Err(WaitForEventError::NoEvent)
+    }
+}
+
+fn ack_interrupt<P>(task: &Arc<Task<P>>, event_handle: usize) -> Result<(), AckInterruptError>
+where
+    P: Platform,
+{
+    let event_handle = Handle::try_from(event_handle).map_err(|_| AckInterruptError::InvalidHandle)?;
+    let event = task
+        .handles
+        .get(event_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(AckInterruptError::InvalidHandle, AckInterruptError::EventCannotBeAcknowledged)
+        })?
+        .downcast_arc::<Event>()
+        .ok()
+        .ok_or(AckInterruptError::NotAnEvent)?;
+
+    event.acknowledge();
+    Ok(())
+}
+
+fn create_event<P>(task: &Arc<Task<P>>, flags: usize) -> Result<Handle, CreateEventError>
+where
+    P: Platform,
+{
+    let flags = EventFlags::from_bits_truncate(flags as u32);
+    if !task.can_add_handles(1) {
+        return Err(CreateEventError::TooManyHandles);
+    }
+
+    let event = if flags.contains(EventFlags::COUNTING) { Event::new_counting() } else { Event::new() };
+    Ok(task.handles.add(event))
+}
+
+fn signal_event<P>(task: &Arc<Task<P>>, event_handle: usize) -> Result<(), SignalEventError>
+where
+    P: Platform,
+{
+    let event_handle = Handle::try_from(event_handle).map_err(|_| SignalEventError::InvalidHandle)?;
+    let event = task
+        .handles
+        .get(event_handle, HandleRights::SIGNAL)
+        .map_err(|err| {
+            err.to_syscall_error(SignalEventError::InvalidHandle, SignalEventError::EventCannotBeSignalled)
+        })?
+        .downcast_arc::<Event>()
+        .ok()
+        .ok_or(SignalEventError::NotAnEvent)?;
+
+    event.signal();
+    Ok(())
+}
+
+fn clear_event<P>(task: &Arc<Task<P>>, event_handle: usize) -> Result<(), ClearEventError>
+where
+    P: Platform,
+{
+    let event_handle = Handle::try_from(event_handle).map_err(|_| ClearEventError::InvalidHandle)?;
+    let event = task
+        .handles
+        .get(event_handle, HandleRights::SIGNAL)
+        .map_err(|err| {
+            err.to_syscall_error(ClearEventError::InvalidHandle, ClearEventError::EventCannotBeCleared)
+        })?
+        .downcast_arc::<Event>()
+        .ok()
+        .ok_or(ClearEventError::NotAnEvent)?;
+
+    event.clear();
+    Ok(())
+}
+
+pub fn wait_for_message<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    timeout: usize,
+) -> Result<(), WaitForMessageError>
+where
+    P: Platform,
+{
+    let channel_handle =
+        Handle::try_from(channel_handle).map_err(|_| WaitForMessageError::InvalidChannelHandle)?;
+    let channel = task
+        .handles
+        .get(channel_handle, HandleRights::READ)
+        .map_err(|err| {
+            err.to_syscall_error(
+                WaitForMessageError::InvalidChannelHandle,
+                WaitForMessageError::ChannelCannotReceive,
+            )
+        })?
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(WaitForMessageError::NotAChannel)?;
+
+    /*
+     * Messages are sent to this end by the tasks that hold the other end, so they inherit our
+     * priority until one arrives.
+     */
+    let other_end: Vec<KernelObjectId> = channel.other_end().map(|other_end| other_end.id()).into_iter().collect();
+    if block_until(scheduler, task, timeout, &other_end, || channel.has_message()) {
+        Ok(())
+    } else {
+        Err(WaitForMessageError::TimedOut)
+    }
+}
+
+pub fn task_wait<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    status_ptr: usize,
+    timeout: usize,
+) -> Result<(), TaskWaitError>
+where
+    P: Platform,
+{
+    let task_handle = Handle::try_from(task_handle).map_err(|_| TaskWaitError::InvalidHandle)?;
+    let target = task
+        .handles
+        .get(task_handle, HandleRights::READ)
+        .map_err(|err| err.to_syscall_error(TaskWaitError::InvalidHandle, TaskWaitError::TaskCannotBeWaitedFor))?
+        .downcast_arc::<Task<P>>()
+        .ok()
+        .ok_or(TaskWaitError::NotATask)?;
+
+    let mut status = None;
+    if !block_until(scheduler, task, timeout, &[], || {
+        status = target.state.lock().exit_status();
+        status.is_some()
+    }) {
+        return Err(TaskWaitError::TimedOut);
+    }
+
+    UserPointer::new(status_ptr as *mut i32, true)
+        .validate_write(status.unwrap())
+        .map_err(|()| TaskWaitError::StatusAddressInvalid)
+}
+
+/// Block the calling task until `condition` returns `true`, or until `timeout` (in nanoseconds)
+/// has elapsed. A `timeout` of `0` means the task waits indefinitely. Returns `false` if the
+/// timeout elapsed before the condition was met.
+///
+/// If the condition will be met by another task doing something with one of the kernel objects in
+/// `waiting_on`, the tasks holding handles to them inherit the calling task's priority while it waits.
+///
+/// The timeout is tracked by a tasklet on the kernel's timer wheel.
+/// XXX: This is an extremely simple way of implementing this - we just keep yielding to other tasks
+/// until the condition is met. We should instead block the task, and have it unblocked by whatever
+/// makes the condition true (or by the timer).
+fn block_until<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    timeout: usize,
+    waiting_on: &[KernelObjectId],
+    mut condition: impl FnMut() -> bool,
+) -> bool
+where
+    P: Platform,
+{
+    if condition() {
+        return true;
+    }
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let timer = if timeout != 0 {
+        let timed_out = timed_out.clone();
+        Some(scheduler.tasklet_scheduler.spawn(async move {
+            maitake::time::sleep(Duration::from_nanos(timeout as u64)).await;
+            timed_out.store(true, Ordering::SeqCst);
+        }))
+    } else {
+        None
+    };
+
+    let lent_to = scheduler.lend_priority(task, waiting_on);
+
+    let condition_met = loop {
+        if condition() {
+            if let Some(timer) = timer {
+                timer.cancel();
+            }
+            break true;
+        }
+        // If the task's job has been killed, we stop waiting so it can exit on its way back to userspace
+        if timed_out.load(Ordering::SeqCst) || task.job.is_killed() {
+            break false;
+        }
+
+        scheduler.schedule(TaskState::Ready);
+    };
+
+    scheduler.return_priority(task, lent_to);
+    condition_met
+}
+
+pub fn object_wait_many<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    items_address: usize,
+    num_items: usize,
+    block: usize,
+    timeout: usize,
+) -> Result<(), ObjectWaitManyError>
+where
+    P: Platform,
+{
+    if num_items > OBJECT_WAIT_MANY_MAX_ITEMS {
+        return Err(ObjectWaitManyError::TooManyItems);
+    }
+    let block = block != 0;
+
+    let items: &mut [WaitItem] = if num_items == 0 {
+        &mut []
+    } else {
+        UserSlice::new(items_address as *mut WaitItem, num_items)
+            .validate_write()
+            .map_err(|()| ObjectWaitManyError::ItemsAddressInvalid)?
+    };
+
+    let mut objects = Vec::with_capacity(num_items);
+    for item in items.iter() {
+        let handle = Handle::try_from(item.handle as usize).map_err(|_| ObjectWaitManyError::InvalidHandle)?;
+        let object = task.handles.get(handle, HandleRights::READ).map_err(|err| {
+            err.to_syscall_error(ObjectWaitManyError::InvalidHandle, ObjectWaitManyError::HandleCannotBeRead)
+        })?;
+        objects.push((object, Signals::from_bits_truncate(item.signals)));
+    }
+
+    let any_asserted = || objects.iter().any(|(object, signals)| object_signals::<P>(object).intersects(*signals));
+    let result = if block {
+        /*
+         * Messages arrive on channels (and data on pipes) from the tasks holding the other ends, so they inherit
+         * our priority while we wait.
+         */
+        let waiting_on: Vec<KernelObjectId> = objects
+            .iter()
+            .filter_map(|(object, _)| match object.typ() {
+                KernelObjectType::Channel => {
+                    object.clone().downcast_arc::<ChannelEnd>().ok().unwrap().other_end().map(|end| end.id())
+                }
+                KernelObjectType::Pipe => {
+                    object.clone().downcast_arc::<PipeEnd>().ok().unwrap().other_end().map(|end| end.id())
+                }
+                _ => None,
+            })
+            .collect();
+
+        if block_until(scheduler, task, timeout, &waiting_on, any_asserted) {
+            Ok(())
+        } else {
+            Err(ObjectWaitManyError::TimedOut)
+        }
+    } else if any_asserted() {
+        Ok(())
+    } else {
+        Err(ObjectWaitManyError::NoSignals)
+    };
+
+    for (item, (object, _)) in items.iter_mut().zip(objects.iter()) {
+        item.observed = object_signals::<P>(object).bits();
+    }
+
+    result
+}
+
+/// Work out which signals are currently asserted on a kernel object.
+fn object_signals<P>(object: &Arc<dyn KernelObject>) -> Signals
+where
+    P: Platform,
+{
+    let mut signals = Signals::empty();
+
+    match object.typ() {
+        KernelObjectType::Channel => {
+            let channel = object.clone().downcast_arc::<ChannelEnd>().ok().unwrap();
+            signals.set(Signals::READABLE, channel.has_message());
+            signals.set(Signals::REPLIED, channel.has_reply(0));
+            signals.set(Signals::PEER_CLOSED, channel.is_peer_closed());
         }
-        assert_eq!(Ok(true), event.signalled.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst));
-        Ok(())
-    } else {
-        match event.signalled.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst) {
-            Ok(true) => Ok(()),
-            _ => Err(WaitForEventError::NoEvent),
+        KernelObjectType::Event => {
+            let event = object.clone().downcast_arc::<Event>().ok().unwrap();
+            signals.set(Signals::SIGNALLED, event.is_signalled());
+        }
+        KernelObjectType::Task => {
+            let task = object.clone().downcast_arc::<Task<P>>().ok().unwrap();
+            signals.set(Signals::TERMINATED, task.state.lock().exit_status().is_some());
         }
+        KernelObjectType::Pipe => {
+            let pipe = object.clone().downcast_arc::<PipeEnd>().ok().unwrap();
+            signals.set(Signals::READABLE, pipe.is_readable());
+            signals.set(Signals::WRITABLE, pipe.is_writable());
+            signals.set(Signals::PEER_CLOSED, pipe.is_peer_closed());
+        }
+        _ => (),
     }
+
+    signals
 }
 
 pub fn poll_interest<P>(task: &Arc<Task<P>>, object_handle: usize) -> Result<usize, PollInterestError>
@@ -513,17 +2496,22 @@ where
     P: Platform,
 {
     let object_handle = Handle::try_from(object_handle).map_err(|_| PollInterestError::InvalidHandle)?;
-    let object = task.handles.get(object_handle).ok_or(PollInterestError::InvalidHandle)?;
+    let object = task.handles.get(object_handle, HandleRights::READ).map_err(|err| {
+        err.to_syscall_error(PollInterestError::InvalidHandle, PollInterestError::HandleCannotBeRead)
+    })?;
 
     let interesting = match object.typ() {
         KernelObjectType::Channel => {
             let channel = object.downcast_arc::<ChannelEnd>().ok().unwrap();
-            let messages = channel.messages.lock();
-            messages.len() > 0
+            channel.has_message()
         }
         KernelObjectType::Event => {
             let event = object.downcast_arc::<Event>().ok().unwrap();
-            event.signalled.load(Ordering::SeqCst)
+            event.is_signalled()
+        }
+        KernelObjectType::Pipe => {
+            let pipe = object.downcast_arc::<PipeEnd>().ok().unwrap();
+            pipe.is_readable() || pipe.is_writable()
         }
 
         // TODO: should this return an error instead?
@@ -540,6 +2528,10 @@ pub fn create_address_space<P>(
 where
     P: Platform,
 {
+    if !task.can_add_handles(1) {
+        return Err(CreateAddressSpaceError::TooManyHandles);
+    }
+
     let address_space = AddressSpace::<P>::new(task.id(), kernel_page_tables, crate::PMM.get());
     Ok(task.handles.add(address_space))
 }
@@ -554,6 +2546,7 @@ where
     P: Platform,
 {
     use crate::object::task::Handles;
+    use poplar::manifest::{StdioHandles, TaskManifest};
 
     let details = UserPointer::new(details_ptr as *mut SpawnTaskDetails, false).validate_read().unwrap();
 
@@ -564,8 +2557,10 @@ where
         Handle::try_from(details.address_space as usize).map_err(|_| SpawnTaskError::NotAnAddressSpace)?;
     let address_space = task
         .handles
-        .get(address_space_handle)
-        .ok_or(SpawnTaskError::NotAnAddressSpace)?
+        .get(address_space_handle, HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(SpawnTaskError::NotAnAddressSpace, SpawnTaskError::AddressSpaceCannotBeModified)
+        })?
         .downcast_arc::<AddressSpace<P>>()
         .ok()
         .ok_or(SpawnTaskError::NotAnAddressSpace)?;
@@ -578,20 +2573,42 @@ where
 
     let handles_to_transfer =
         UserSlice::new(details.object_array as *mut u32, details.object_array_len).validate_read().unwrap();
+    let mut transferred = Vec::with_capacity(handles_to_transfer.len());
     for to_transfer in handles_to_transfer {
         let handle =
             Handle::try_from(*to_transfer as usize).map_err(|_| SpawnTaskError::InvalidHandleToTransfer)?;
-        let object = task.handles.get(handle).ok_or(SpawnTaskError::InvalidHandleToTransfer)?;
-        handles.add(object);
+        let (object, rights) =
+            task.handles.get_with_rights(handle).ok_or(SpawnTaskError::InvalidHandleToTransfer)?;
+        if !rights.contains(HandleRights::TRANSFER) {
+            return Err(SpawnTaskError::CannotTransferHandle);
+        }
+        transferred.push(handles.add_with_rights(object, rights).0);
     }
 
+    /*
+     * Tasks spawned like this are given a manifest too, so `std` can find the task's name and handles in the same
+     * way for every task. They don't have any arguments or standard handles.
+     */
     let pmm = crate::PMM.get();
+    let manifest = TaskManifest {
+        task_name: name.to_string(),
+        arguments: Vec::new(),
+        handles: transferred,
+        stdio: StdioHandles::default(),
+    };
+    crate::map_manifest(task.id(), &manifest, &address_space, pmm)
+        .map_err(|_| SpawnTaskError::ManifestCannotBeMapped)?;
+
     let new_task = Task::new(
         task.id(),
         address_space,
+        task.job.clone(),
         name.to_string(),
         VAddr::new(details.entry_point),
         handles,
+        // TODO: tasks should be given the capabilities encoded in their images, not those of their parent
+        task.capabilities.get(),
+        task.priority.base(),
         &pmm,
         kernel_page_tables,
     )
@@ -600,3 +2617,616 @@ where
 
     Ok(task.handles.add(new_task))
 }
+
+pub fn task_create<P>(
+    task: &Arc<Task<P>>,
+    details_ptr: usize,
+    scheduler: &Scheduler<P>,
+    kernel_page_tables: &mut P::PageTable,
+) -> Result<Handle, TaskCreateError>
+where
+    P: Platform,
+{
+    use crate::object::task::Handles;
+    use poplar::manifest::{StdioHandle, StdioHandles, TaskManifest};
+
+    if !task.capabilities.contains(Capabilities::SPAWN_TASK) {
+        return Err(TaskCreateError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let details = UserPointer::new(details_ptr as *mut TaskCreateDetails, false)
+        .validate_read()
+        .map_err(|()| TaskCreateError::InvalidDetails)?;
+    let name = UserString::new(details.name_ptr as *mut u8, details.name_len)
+        .validate()
+        .map_err(|()| TaskCreateError::InvalidTaskName)?;
+    let arguments = UserString::new(details.arguments_ptr as *mut u8, details.arguments_len)
+        .validate()
+        .map_err(|()| TaskCreateError::InvalidArguments)?;
+
+    /*
+     * A task can't give the tasks it creates any capabilities it doesn't have itself.
+     */
+    let capabilities =
+        Capabilities::from_bits(details.capabilities).ok_or(TaskCreateError::CapabilitiesNotHeld)?;
+    if !task.capabilities.contains(capabilities) {
+        return Err(TaskCreateError::CapabilitiesNotHeld);
+    }
+
+    let job_handle = Handle::try_from(details.job as usize).map_err(|_| TaskCreateError::InvalidJob)?;
+    let job = if job_handle == Handle::ZERO {
+        task.job.clone()
+    } else {
+        task.handles
+            .get(job_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(TaskCreateError::InvalidJob, TaskCreateError::JobCannotBeModified)
+            })?
+            .downcast_arc::<Job<P>>()
+            .ok()
+            .ok_or(TaskCreateError::InvalidJob)?
+    };
+    if job.is_killed() {
+        return Err(TaskCreateError::JobKilled);
+    }
+    if !task.can_add_handles(1) {
+        return Err(TaskCreateError::TooManyHandles);
+    }
+
+    let image_handle = Handle::try_from(details.image as usize).map_err(|_| TaskCreateError::InvalidImage)?;
+    let image = task
+        .handles
+        .get(image_handle, HandleRights::READ)
+        .map_err(|err| err.to_syscall_error(TaskCreateError::InvalidImage, TaskCreateError::ImageCannotBeRead))?
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(TaskCreateError::InvalidImage)?;
+
+    /*
+     * Collect the handles to transfer before we do anything else, so we don't load the image if any of them
+     * can't be transferred. They're only removed from the calling task once the new task has been created, so
+     * they aren't lost if creating it fails.
+     */
+    let handles_to_transfer = UserSlice::new(details.object_array as *mut u32, details.object_array_len)
+        .validate_read()
+        .map_err(|()| TaskCreateError::InvalidHandleToTransfer)?;
+    let get_to_transfer = |to_transfer: u32| -> Result<_, TaskCreateError> {
+        let handle =
+            Handle::try_from(to_transfer as usize).map_err(|_| TaskCreateError::InvalidHandleToTransfer)?;
+        let (object, rights) =
+            task.handles.get_with_rights(handle).ok_or(TaskCreateError::InvalidHandleToTransfer)?;
+        if !rights.contains(HandleRights::TRANSFER) {
+            return Err(TaskCreateError::CannotTransferHandle);
+        }
+        Ok((handle, object, rights))
+    };
+    let mut objects = Vec::with_capacity(handles_to_transfer.len());
+    for to_transfer in handles_to_transfer {
+        objects.push(get_to_transfer(*to_transfer)?);
+    }
+    /*
+     * Each standard handle is optional, and is `0` if it isn't given. Otherwise, it must be a channel, or the end
+     * of a pipe the new task can use - the read end for stdin, and the write end for stdout and stderr.
+     */
+    let get_stdio = |to_transfer: u32, is_input: bool| -> Result<_, TaskCreateError> {
+        if to_transfer == 0 {
+            return Ok(None);
+        }
+        let (handle, object, rights) = get_to_transfer(to_transfer)?;
+        let is_pipe = match object.typ() {
+            KernelObjectType::Channel => false,
+            KernelObjectType::Pipe
+                if object.clone().downcast_arc::<PipeEnd>().ok().unwrap().is_read_end == is_input =>
+            {
+                true
+            }
+            _ => return Err(TaskCreateError::InvalidStdioHandle),
+        };
+        Ok(Some((handle, object, rights, is_pipe)))
+    };
+    let (stdin, stdout, stderr) =
+        (get_stdio(details.stdin, true)?, get_stdio(details.stdout, false)?, get_stdio(details.stderr, false)?);
+
+    /*
+     * If the creating task can't write to the image, neither can anyone it hands the image to, so parts of it can
+     * be shared with the new task instead of copied. The VFS hands out read-only handles to file mappings, so
+     * tasks loaded from files share their code.
+     */
+    let share = task
+        .handles
+        .get_with_rights(image_handle)
+        .map_or(false, |(_, rights)| !rights.contains(HandleRights::WRITE));
+    let pmm = crate::PMM.get();
+    let address_space = AddressSpace::<P>::new(task.id(), kernel_page_tables, pmm);
+    let entry_point =
+        crate::loader::load_image(task.id(), &job, &image, details.image_size, share, &address_space, pmm)
+            .map_err(|err| {
+                warn!("Failed to load image for task '{}': {:?}", name, err);
+                match err {
+                    crate::loader::LoadError::MemoryLimitExceeded => TaskCreateError::MemoryLimitExceeded,
+                    _ => TaskCreateError::InvalidElf,
+                }
+            })?;
+
+    let handles = Handles::new();
+    handles.add(address_space.clone());
+    let mut transferred: Vec<Handle> = objects.iter().map(|&(handle, _, _)| handle).collect();
+    let transferred_handles =
+        objects.into_iter().map(|(_, object, rights)| handles.add_with_rights(object, rights).0).collect();
+
+    /*
+     * The same handle can be given as more than one of the standard handles (e.g. a console channel for both
+     * stdout and stderr), in which case the new task is given a single handle for them.
+     */
+    let mut stdio_handles: Vec<(Handle, u32)> = Vec::new();
+    let mut add_stdio = |stdio: Option<(Handle, Arc<dyn KernelObject>, HandleRights, bool)>| {
+        stdio.map(|(handle, object, rights, is_pipe)| {
+            let added = match stdio_handles.iter().find(|&&(given, _)| given == handle) {
+                Some(&(_, added)) => added,
+                None => {
+                    let added = handles.add_with_rights(object, rights).0;
+                    stdio_handles.push((handle, added));
+                    added
+                }
+            };
+            if is_pipe {
+                StdioHandle::Pipe(added)
+            } else {
+                StdioHandle::Console(added)
+            }
+        })
+    };
+    let stdio = StdioHandles { stdin: add_stdio(stdin), stdout: add_stdio(stdout), stderr: add_stdio(stderr) };
+    transferred.extend(stdio_handles.iter().map(|&(handle, _)| handle));
+
+    let manifest = TaskManifest {
+        task_name: name.to_string(),
+        arguments: arguments.split_terminator('\0').map(ToString::to_string).collect(),
+        handles: transferred_handles,
+        stdio,
+    };
+    crate::map_manifest(task.id(), &manifest, &address_space, pmm).map_err(|err| {
+        warn!("Failed to map manifest for task '{}': {:?}", name, err);
+        TaskCreateError::CannotMapManifest
+    })?;
+
+    let new_task = Task::new(
+        task.id(),
+        address_space,
+        job,
+        name.to_string(),
+        entry_point,
+        handles,
+        capabilities,
+        task.priority.base(),
+        &pmm,
+        kernel_page_tables,
+    )
+    .map_err(|err| {
+        warn!("Failed to create task '{}': {:?}", name, err);
+        TaskCreateError::OutOfResources
+    })?;
+
+    /*
+     * Creating the task was the last thing that could fail, so the transferred handles can now be moved to it. If
+     * one has been closed in the meantime, or one was given more than once, the new task is dropped before it's
+     * ever run.
+     */
+    task.handles.remove_all(&transferred, HandleRights::TRANSFER).map_err(|err| {
+        err.to_syscall_error(TaskCreateError::InvalidHandleToTransfer, TaskCreateError::CannotTransferHandle)
+    })?;
+    scheduler.add_task(new_task.clone());
+
+    Ok(task.handles.add(new_task))
+}
+
+pub fn thread_create<P>(
+    task: &Arc<Task<P>>,
+    entry_point: usize,
+    argument: usize,
+    scheduler: &Scheduler<P>,
+    kernel_page_tables: &mut P::PageTable,
+) -> Result<Handle, ThreadCreateError>
+where
+    P: Platform,
+{
+    if !task.can_add_handles(1) {
+        return Err(ThreadCreateError::TooManyHandles);
+    }
+
+    /*
+     * The thread enters userspace at its entry point, so it must be in the user part of the address space -
+     * returning to an address in the kernel's part (or a non-canonical one) would fault in the kernel.
+     */
+    if !task.address_space.is_in_user_space(entry_point, 1) {
+        return Err(ThreadCreateError::InvalidEntryPoint);
+    }
+
+    let thread = Task::new_thread(task, VAddr::new(entry_point), argument, crate::PMM.get(), kernel_page_tables)
+        .map_err(|_| ThreadCreateError::TooManyThreads)?;
+    scheduler.add_task(thread.clone());
+
+    Ok(task.handles.add(thread))
+}
+
+/// Block until another task wakes `address` with `wake_address`, as long as the `u32` at `address` is still
+/// `expected`. Checking the value and starting to wait happen atomically with respect to `wake_address`, which is
+/// what allows userspace to build blocking locks on top of this.
+fn wait_on_address<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    address: usize,
+    expected: usize,
+    timeout: usize,
+) -> Result<(), WaitOnAddressError>
+where
+    P: Platform,
+{
+    if address == 0 || address % core::mem::align_of::<u32>() != 0 {
+        return Err(WaitOnAddressError::AddressInvalid);
+    }
+
+    let waiter = task.address_space.add_address_waiter(address, || {
+        let value = UserPointer::new(address as *mut u32, false)
+            .validate_read()
+            .map_err(|()| WaitOnAddressError::AddressInvalid)?;
+        if value == expected as u32 {
+            Ok(())
+        } else {
+            Err(WaitOnAddressError::ValueChanged)
+        }
+    })?;
+
+    /*
+     * If the timeout elapses, we need to stop waiting before another task can wake us. If we've already been
+     * woken, the wake has been counted, so we report it instead of the timeout.
+     */
+    if block_until(scheduler, task, timeout, &[], || waiter.load(Ordering::SeqCst))
+        || !task.address_space.remove_address_waiter(address, &waiter)
+    {
+        Ok(())
+    } else {
+        Err(WaitOnAddressError::TimedOut)
+    }
+}
+
+fn task_exception_channel<P>(task: &Arc<Task<P>>, task_handle: usize) -> Result<Handle, TaskExceptionChannelError>
+where
+    P: Platform,
+{
+    let task_handle = Handle::try_from(task_handle).map_err(|_| TaskExceptionChannelError::InvalidHandle)?;
+    let target = if task_handle == Handle::ZERO {
+        task.clone()
+    } else {
+        task.handles
+            .get(task_handle, HandleRights::WRITE)
+            .map_err(|err| {
+                err.to_syscall_error(
+                    TaskExceptionChannelError::InvalidHandle,
+                    TaskExceptionChannelError::TaskCannotBeModified,
+                )
+            })?
+            .downcast_arc::<Task<P>>()
+            .ok()
+            .ok_or(TaskExceptionChannelError::NotATask)?
+    };
+
+    let channel = ChannelEnd::new_kernel_channel(task.id());
+    *target.exception_channel.lock() = Some(channel.clone());
+    Ok(task.handles.add_with_rights(channel, HandleRights::READ))
+}
+
+fn task_resume<P>(
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    resolution: usize,
+    registers_ptr: usize,
+) -> Result<(), TaskResumeError>
+where
+    P: Platform,
+{
+    let task_handle = Handle::try_from(task_handle).map_err(|_| TaskResumeError::InvalidHandle)?;
+    let target = task
+        .handles
+        .get(task_handle, HandleRights::WRITE)
+        .map_err(|err| {
+            err.to_syscall_error(TaskResumeError::InvalidHandle, TaskResumeError::TaskCannotBeModified)
+        })?
+        .downcast_arc::<Task<P>>()
+        .ok()
+        .ok_or(TaskResumeError::NotATask)?;
+
+    let resolution = match resolution {
+        0 => ExceptionResolution::Resume,
+        1 => ExceptionResolution::ResumeWithRegisters(
+            UserPointer::new(registers_ptr as *mut Registers, false)
+                .validate_read()
+                .map_err(|()| TaskResumeError::RegistersAddressInvalid)?,
+        ),
+        2 => ExceptionResolution::Kill,
+        _ => return Err(TaskResumeError::InvalidResolution),
+    };
+
+    let mut state = target.exception_state.lock();
+    if !matches!(*state, ExceptionState::Stopped) {
+        return Err(TaskResumeError::NotStopped);
+    }
+    *state = ExceptionState::Resolved(resolution);
+    Ok(())
+}
+
+/// Get the task referred to by `task_handle` for a debugging system call, which can only be made on a task that's
+/// stopped because of an exception.
+fn get_stopped_task<P, E>(
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    invalid_handle: E,
+    missing_rights: E,
+    not_a_task: E,
+    not_stopped: E,
+) -> Result<Arc<Task<P>>, E>
+where
+    P: Platform,
+{
+    let task_handle = match Handle::try_from(task_handle) {
+        Ok(handle) => handle,
+        Err(_) => return Err(invalid_handle),
+    };
+    let target = task
+        .handles
+        .get(task_handle, HandleRights::WRITE)
+        .map_err(|err| err.to_syscall_error(invalid_handle, missing_rights))?
+        .downcast_arc::<Task<P>>()
+        .ok()
+        .ok_or(not_a_task)?;
+
+    if !matches!(*target.exception_state.lock(), ExceptionState::Stopped) {
+        return Err(not_stopped);
+    }
+    Ok(target)
+}
+
+fn task_memory_regions<P>(
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    buffer_address: usize,
+    buffer_length: usize,
+) -> Result<usize, TaskMemoryRegionsError>
+where
+    P: Platform,
+{
+    let target = get_stopped_task(
+        task,
+        task_handle,
+        TaskMemoryRegionsError::InvalidHandle,
+        TaskMemoryRegionsError::TaskCannotBeModified,
+        TaskMemoryRegionsError::NotATask,
+        TaskMemoryRegionsError::NotStopped,
+    )?;
+
+    let regions = target.memory_regions();
+    let num_written = usize::min(regions.len(), buffer_length);
+    if num_written > 0 {
+        let buffer = UserSlice::new(buffer_address as *mut MemoryRegion, num_written)
+            .validate_write()
+            .map_err(|()| TaskMemoryRegionsError::BufferAddressInvalid)?;
+        buffer.copy_from_slice(&regions[0..num_written]);
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, regions.len());
+    Ok(status)
+}
+
+fn task_read_memory<P>(
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    address: usize,
+    buffer_address: usize,
+    buffer_length: usize,
+) -> Result<usize, TaskReadMemoryError>
+where
+    P: Platform,
+{
+    let target = get_stopped_task(
+        task,
+        task_handle,
+        TaskReadMemoryError::InvalidHandle,
+        TaskReadMemoryError::TaskCannotBeModified,
+        TaskReadMemoryError::NotATask,
+        TaskReadMemoryError::NotStopped,
+    )?;
+
+    let buffer = UserSlice::new(buffer_address as *mut u8, buffer_length)
+        .validate_write()
+        .map_err(|()| TaskReadMemoryError::BufferAddressInvalid)?;
+    let length = target.read_memory(address, buffer)?;
+
+    let mut status = 0;
+    status.set_bits(16..48, length);
+    Ok(status)
+}
+
+/// Get the `Job` referred to by `job_handle`, if the handle has the `required` rights. `Handle::ZERO` refers to the
+/// calling task's job.
+fn get_job<P, E>(
+    task: &Arc<Task<P>>,
+    job_handle: usize,
+    required: HandleRights,
+    invalid_handle: E,
+    missing_rights: E,
+    not_a_job: E,
+) -> Result<Arc<Job<P>>, E>
+where
+    P: Platform,
+{
+    let job_handle = match Handle::try_from(job_handle) {
+        Ok(handle) => handle,
+        Err(_) => return Err(invalid_handle),
+    };
+    if job_handle == Handle::ZERO {
+        return Ok(task.job.clone());
+    }
+
+    task.handles
+        .get(job_handle, required)
+        .map_err(|err| err.to_syscall_error(invalid_handle, missing_rights))?
+        .downcast_arc::<Job<P>>()
+        .ok()
+        .ok_or(not_a_job)
+}
+
+fn job_create<P>(task: &Arc<Task<P>>, parent_handle: usize) -> Result<Handle, JobCreateError>
+where
+    P: Platform,
+{
+    let parent = get_job(
+        task,
+        parent_handle,
+        HandleRights::WRITE,
+        JobCreateError::InvalidHandle,
+        JobCreateError::JobCannotBeModified,
+        JobCreateError::NotAJob,
+    )?;
+    if parent.is_killed() {
+        return Err(JobCreateError::JobKilled);
+    }
+    if !task.can_add_handles(1) {
+        return Err(JobCreateError::TooManyHandles);
+    }
+
+    Ok(task.handles.add(Job::new(task.id(), Some(parent))))
+}
+
+fn job_kill<P>(task: &Arc<Task<P>>, job_handle: usize) -> Result<(), JobKillError>
+where
+    P: Platform,
+{
+    // Killing the calling task's own job isn't allowed without a handle to it
+    if job_handle == 0 {
+        return Err(JobKillError::InvalidHandle);
+    }
+
+    let job = get_job(
+        task,
+        job_handle,
+        HandleRights::WRITE,
+        JobKillError::InvalidHandle,
+        JobKillError::JobCannotBeModified,
+        JobKillError::NotAJob,
+    )?;
+    info!("Task '{}' killed job {:?}", task.name, job.id());
+    job.kill();
+    Ok(())
+}
+
+fn job_usage<P>(task: &Arc<Task<P>>, job_handle: usize, usage_ptr: usize) -> Result<(), JobUsageError>
+where
+    P: Platform,
+{
+    let job = get_job(
+        task,
+        job_handle,
+        HandleRights::READ,
+        JobUsageError::InvalidHandle,
+        JobUsageError::JobCannotBeRead,
+        JobUsageError::NotAJob,
+    )?;
+
+    UserPointer::new(usage_ptr as *mut JobUsage, true)
+        .validate_write(job.usage())
+        .map_err(|()| JobUsageError::UsageAddressInvalid)
+}
+
+fn job_set_limits<P>(task: &Arc<Task<P>>, job_handle: usize, limits_ptr: usize) -> Result<(), JobSetLimitsError>
+where
+    P: Platform,
+{
+    /*
+     * A task can't change the limits of its own job through `Handle::ZERO`, or it could lift the limits it was
+     * created with.
+     */
+    if job_handle == 0 {
+        return Err(JobSetLimitsError::InvalidHandle);
+    }
+
+    let job = get_job(
+        task,
+        job_handle,
+        HandleRights::WRITE,
+        JobSetLimitsError::InvalidHandle,
+        JobSetLimitsError::JobCannotBeModified,
+        JobSetLimitsError::NotAJob,
+    )?;
+    let limits = UserPointer::new(limits_ptr as *mut JobLimits, false)
+        .validate_read()
+        .map_err(|()| JobSetLimitsError::LimitsAddressInvalid)?;
+    if limits.cpu_share == 0 || limits.cpu_share > 100 {
+        return Err(JobSetLimitsError::InvalidLimits);
+    }
+
+    job.set_limits(limits);
+    Ok(())
+}
+
+fn job_get_limits<P>(task: &Arc<Task<P>>, job_handle: usize, limits_ptr: usize) -> Result<(), JobGetLimitsError>
+where
+    P: Platform,
+{
+    let job = get_job(
+        task,
+        job_handle,
+        HandleRights::READ,
+        JobGetLimitsError::InvalidHandle,
+        JobGetLimitsError::JobCannotBeRead,
+        JobGetLimitsError::NotAJob,
+    )?;
+
+    UserPointer::new(limits_ptr as *mut JobLimits, true)
+        .validate_write(job.limits())
+        .map_err(|()| JobGetLimitsError::LimitsAddressInvalid)
+}
+
+pub fn handle_duplicate_with_rights<P>(
+    task: &Arc<Task<P>>,
+    handle: usize,
+    rights: usize,
+) -> Result<Handle, HandleDuplicateWithRightsError>
+where
+    P: Platform,
+{
+    let handle = Handle::try_from(handle).map_err(|_| HandleDuplicateWithRightsError::InvalidHandle)?;
+    let (object, existing_rights) =
+        task.handles.get_with_rights(handle).ok_or(HandleDuplicateWithRightsError::InvalidHandle)?;
+
+    if !existing_rights.contains(HandleRights::DUPLICATE) {
+        return Err(HandleDuplicateWithRightsError::HandleCannotBeDuplicated);
+    }
+
+    /*
+     * Rights can only be removed from a handle, so the new handle can't have any rights the original doesn't.
+     * We don't truncate unknown rights here, as that would let a task think it had been given a right that we
+     * don't know about.
+     */
+    let rights = u32::try_from(rights)
+        .ok()
+        .and_then(HandleRights::from_bits)
+        .ok_or(HandleDuplicateWithRightsError::RightsNotHeld)?;
+    if !existing_rights.contains(rights) {
+        return Err(HandleDuplicateWithRightsError::RightsNotHeld);
+    }
+    if !task.can_add_handles(1) {
+        return Err(HandleDuplicateWithRightsError::TooManyHandles);
+    }
+
+    Ok(task.handles.add_with_rights(object, rights))
+}
+
+fn handle_close<P>(task: &Arc<Task<P>>, handle: usize) -> Result<(), HandleCloseError>
+where
+    P: Platform,
+{
+    let handle = Handle::try_from(handle).map_err(|_| HandleCloseError::InvalidHandle)?;
+    task.handles.remove(handle).ok_or(HandleCloseError::InvalidHandle)?;
+    Ok(())
+}
@@ -1,6 +1,10 @@
 pub mod queue;
 
-use core::{future::Future, time::Duration};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 use maitake::task::JoinHandle;
 use spinning_top::Spinlock;
 
@@ -9,15 +13,22 @@ use spinning_top::Spinlock;
 /// runtime.
 pub struct TaskletScheduler {
     scheduler: Spinlock<maitake::scheduler::Scheduler>,
+    /// A timer wheel that tasklets can use to wait for a period of time. This is driven by the
+    /// platform's timer interrupt, through `advance_timer`.
     pub timer: maitake::time::Timer,
+    /// The time since the platform started driving the timer, in nanoseconds.
+    uptime: AtomicU64,
 }
 
 impl TaskletScheduler {
-    pub fn new() -> TaskletScheduler {
+    /// Create a `TaskletScheduler` whose timer wheel ticks every `tick_period`. This must be the period of the
+    /// platform's timer interrupt, as `Timer::advance` only counts whole ticks - if the timer is advanced by
+    /// less than a tick at a time, it never moves.
+    pub fn new(tick_period: Duration) -> TaskletScheduler {
         TaskletScheduler {
             scheduler: Spinlock::new(maitake::scheduler::Scheduler::new()),
-            // TODO: probs need to be able to supply a tick granularity?
-            timer: maitake::time::Timer::new(Duration::from_millis(20)),
+            timer: maitake::time::Timer::new(tick_period),
+            uptime: AtomicU64::new(0),
         }
     }
 
@@ -34,7 +45,16 @@ impl TaskletScheduler {
         self.scheduler.lock().tick();
     }
 
-    pub fn advance_timer(&self, ticks: u64) {
-        self.timer.advance_ticks(1);
+    /// Advance the timer wheel by `elapsed`. This should be called by the platform each time its
+    /// timer fires, on a single CPU. It is safe to call from an interrupt handler.
+    pub fn advance_timer(&self, elapsed: Duration) {
+        self.uptime.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.timer.advance(elapsed);
+    }
+
+    /// Get the time since the platform started driving the timer. This has the same granularity as
+    /// the platform's timer interrupt.
+    pub fn uptime(&self) -> Duration {
+        Duration::from_nanos(self.uptime.load(Ordering::Relaxed))
     }
 }
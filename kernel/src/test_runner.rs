@@ -0,0 +1,240 @@
+//! The test runner is built into the kernel with the `test_runner` feature, which `cargo xtask test` enables.
+//! Before userspace is loaded, it runs the kernel's own tests. It then waits for each boot task whose name starts
+//! with `test_` to exit - a test task passes if it exits with a status of `0` - and once they all have, exits QEMU
+//! with a status that says whether every test passed.
+//!
+//! Results are logged as markers, which can be picked out of the serial output by whatever ran the kernel:
+//! ```text
+//! TEST BEGIN {name}
+//! TEST PASS {name}
+//! TEST FAIL {name} {reason}
+//! TEST DONE {passed} {failed}
+//! ```
+//! Kernel tests are named `kernel::{test}`, and userspace tests are named after their task. Userspace tests run at
+//! the same time, so their `BEGIN` markers are all logged before any of them finish.
+
+use crate::{Platform, PMM};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::time::Duration;
+use hal::memory::{FrameSize, Size4KiB};
+use seed::boot_info::BootInfo;
+use spinning_top::Spinlock;
+use tracing::info;
+
+/// Boot tasks whose names start with this are tests.
+pub const TEST_TASK_PREFIX: &str = "test_";
+
+static STATE: Spinlock<State> = Spinlock::new(State { running: Vec::new(), passed: 0, failed: 0 });
+
+struct State {
+    /// The test tasks that haven't exited yet.
+    running: Vec<String>,
+    passed: usize,
+    failed: usize,
+}
+
+type KernelTest = fn() -> Result<(), &'static str>;
+
+fn kernel_tests<P>() -> [(&'static str, KernelTest); 4]
+where
+    P: Platform,
+{
+    [
+        ("heap", test_heap),
+        ("pmm", test_pmm::<P>),
+        ("phys_memory", test_phys_memory::<P>),
+        ("monotonic_time", test_monotonic_time::<P>),
+    ]
+}
+
+/// Run the kernel's tests, and find the test tasks among the boot tasks, so we can tell when they've all finished.
+/// This is called before userspace is loaded. If there aren't any test tasks, this exits QEMU straight away.
+pub fn start<P>(boot_info: &BootInfo)
+where
+    P: Platform,
+{
+    for (name, test) in kernel_tests::<P>() {
+        info!("TEST BEGIN kernel::{}", name);
+        let result = test();
+
+        let mut state = STATE.lock();
+        match result {
+            Ok(()) => {
+                info!("TEST PASS kernel::{}", name);
+                state.passed += 1;
+            }
+            Err(reason) => {
+                info!("TEST FAIL kernel::{} {}", name, reason);
+                state.failed += 1;
+            }
+        }
+    }
+
+    let mut state = STATE.lock();
+    for image in &boot_info.loaded_images {
+        if image.name.as_str().starts_with(TEST_TASK_PREFIX) {
+            info!("TEST BEGIN {}", image.name.as_str());
+            state.running.push(image.name.as_str().into());
+        }
+    }
+
+    if state.running.is_empty() {
+        drop(state);
+        finish::<P>();
+    }
+}
+
+/// Called when the last thread of a task exits, with the status it exited with. If it's the last test task to
+/// finish, this exits QEMU.
+pub fn task_exited<P>(name: &str, status: i32)
+where
+    P: Platform,
+{
+    let mut state = STATE.lock();
+    let Some(index) = state.running.iter().position(|test| test == name) else {
+        return;
+    };
+    state.running.remove(index);
+
+    if status == 0 {
+        info!("TEST PASS {}", name);
+        state.passed += 1;
+    } else {
+        info!("TEST FAIL {} exited with status {}", name, status);
+        state.failed += 1;
+    }
+
+    if state.running.is_empty() {
+        drop(state);
+        finish::<P>();
+    }
+}
+
+fn finish<P>() -> !
+where
+    P: Platform,
+{
+    let state = STATE.lock();
+    info!("TEST DONE {} {}", state.passed, state.failed);
+    P::exit_qemu(state.failed == 0)
+}
+
+fn test_heap() -> Result<(), &'static str> {
+    for size in [1, 7, 64, 4096, 65536] {
+        let bytes: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        if bytes.iter().enumerate().any(|(i, &byte)| byte != i as u8) {
+            return Err("allocation was not written correctly");
+        }
+    }
+
+    let boxed = Box::new([0xa5u8; 8192]);
+    if boxed.iter().any(|&byte| byte != 0xa5) {
+        return Err("boxed array was not written correctly");
+    }
+    if (boxed.as_ptr() as usize) % core::mem::align_of::<[u8; 8192]>() != 0 {
+        return Err("boxed array is not aligned");
+    }
+    Ok(())
+}
+
+fn test_pmm<P>() -> Result<(), &'static str>
+where
+    P: Platform,
+{
+    let pmm = PMM.get();
+    let single = pmm.alloc(1);
+    let multiple = pmm.alloc(16);
+
+    let result = (|| {
+        if !single.is_aligned(Size4KiB::SIZE) || !multiple.is_aligned(Size4KiB::SIZE) {
+            return Err("frames are not page-aligned");
+        }
+        let single_range = usize::from(single)..(usize::from(single) + Size4KiB::SIZE);
+        let multiple_range = usize::from(multiple)..(usize::from(multiple) + 16 * Size4KiB::SIZE);
+        if single_range.start < multiple_range.end && multiple_range.start < single_range.end {
+            return Err("allocations overlap");
+        }
+
+        // Each allocation should be usable without affecting the other
+        unsafe {
+            P::write_to_phys_memory(single, &vec![0x11; Size4KiB::SIZE]);
+            P::zero_phys_memory(multiple, 16 * Size4KiB::SIZE);
+        }
+        let mut page = vec![0u8; Size4KiB::SIZE];
+        unsafe {
+            P::read_from_phys_memory(single, &mut page);
+        }
+        if page.iter().any(|&byte| byte != 0x11) {
+            return Err("zeroing one allocation affected the other");
+        }
+        Ok(())
+    })();
+
+    pmm.free(single, 1);
+    pmm.free(multiple, 16);
+    result
+}
+
+fn test_phys_memory<P>() -> Result<(), &'static str>
+where
+    P: Platform,
+{
+    let pmm = PMM.get();
+    let from = pmm.alloc(1);
+    let to = pmm.alloc(1);
+
+    let result = (|| {
+        let pattern: Vec<u8> = (0..Size4KiB::SIZE).map(|i| (i % 251) as u8).collect();
+        let mut page = vec![0u8; Size4KiB::SIZE];
+
+        unsafe {
+            P::write_to_phys_memory(from, &pattern);
+            P::copy_phys_memory(from, to, Size4KiB::SIZE);
+            P::read_from_phys_memory(to, &mut page);
+        }
+        if page != pattern {
+            return Err("copied memory does not match");
+        }
+
+        unsafe {
+            P::zero_phys_memory(to + 16, 32);
+            P::read_from_phys_memory(to, &mut page);
+        }
+        if page[16..48].iter().any(|&byte| byte != 0) {
+            return Err("memory was not zeroed");
+        }
+        if page[0..16] != pattern[0..16] || page[48..] != pattern[48..] {
+            return Err("zeroing memory affected the memory around it");
+        }
+        Ok(())
+    })();
+
+    pmm.free(from, 1);
+    pmm.free(to, 1);
+    result
+}
+
+fn test_monotonic_time<P>() -> Result<(), &'static str>
+where
+    P: Platform,
+{
+    /*
+     * The clock should advance by at least a millisecond in this many tries, even on a slow emulated CPU. If it
+     * doesn't, it's probably not running.
+     */
+    const MAX_TRIES: usize = 100_000_000;
+
+    let start = P::monotonic_time();
+    let mut previous = start;
+    for _ in 0..MAX_TRIES {
+        let now = P::monotonic_time();
+        if now < previous {
+            return Err("clock went backwards");
+        }
+        if now - start >= Duration::from_millis(1) {
+            return Ok(());
+        }
+        previous = now;
+    }
+    Err("clock did not advance")
+}
@@ -0,0 +1,122 @@
+//! Static tracepoints, which write fixed-size records into a ring buffer for each CPU while tracing is enabled.
+//! The buffers are backed by read-only `MemoryObject`s, so a task with the `TRACE` capability can map them and
+//! read the records out itself. The layout of the buffers and records is shared with userspace - see
+//! `poplar::trace`.
+
+use crate::{
+    object::{memory_object::MemoryObject, task::Task, KernelObject, SENTINEL_KERNEL_ID},
+    per_cpu::PerCpu,
+    Platform,
+};
+use alloc::sync::Arc;
+use core::{
+    mem,
+    slice,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use hal::memory::{Flags, FrameSize, PAddr, Size4KiB};
+use mulch::InitGuard;
+use poplar::trace::{
+    pack_task_name,
+    TraceBufferHeader,
+    TraceEvent,
+    TraceRecord,
+    TRACE_BUFFER_RECORDS,
+    TRACE_BUFFER_SIZE,
+    TRACE_RECORDS_OFFSET,
+};
+
+pub static TRACE_BUFFERS: InitGuard<PerCpu<TraceBuffer>> = InitGuard::uninit();
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub struct TraceBuffer {
+    pub memory_object: Arc<MemoryObject>,
+    physical_start: PAddr,
+    /// The number of records that have been written to the buffer. This is mirrored into the buffer's header once
+    /// each record has been written.
+    head: AtomicU64,
+}
+
+/// Allocate a trace buffer for each CPU. Tracing starts off disabled.
+pub fn init<P>(num_cpus: usize)
+where
+    P: Platform,
+{
+    let pmm = crate::PMM.get();
+    TRACE_BUFFERS.initialize(PerCpu::new(num_cpus, |cpu| {
+        let physical_start = pmm.alloc(TRACE_BUFFER_SIZE / Size4KiB::SIZE);
+        unsafe {
+            P::zero_phys_memory(physical_start, TRACE_BUFFER_SIZE);
+            P::write_to_phys_memory(
+                physical_start + mem::offset_of!(TraceBufferHeader, cpu),
+                &(cpu as u64).to_ne_bytes(),
+            );
+        }
+
+        let flags = Flags { writable: false, user_accessible: true, ..Default::default() };
+        let memory_object = MemoryObject::new(SENTINEL_KERNEL_ID, physical_start, TRACE_BUFFER_SIZE, flags);
+        TraceBuffer { memory_object, physical_start, head: AtomicU64::new(0) }
+    }));
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Write a record for `event` into the running CPU's trace buffer, if tracing is enabled.
+pub fn record<P>(event: TraceEvent, data: [u64; 3])
+where
+    P: Platform,
+{
+    if is_enabled() {
+        write::<P>(event, data);
+    }
+}
+
+/// Write a record for `event` into the running CPU's trace buffer, even if tracing isn't enabled. This is used for
+/// records that have been asked for specifically, like those of audited tasks' system calls.
+///
+/// Records aren't written atomically, so if an interrupt writes a record while we're writing ours, the reader
+/// may briefly see the interrupt's record published before ours has been completely written.
+pub fn write<P>(event: TraceEvent, data: [u64; 3])
+where
+    P: Platform,
+{
+    let Some(buffers) = TRACE_BUFFERS.try_get() else {
+        return;
+    };
+
+    let cpu = P::cpu_id();
+    let buffer = buffers.get_for(cpu);
+    let record = TraceRecord {
+        timestamp: P::monotonic_time().as_nanos() as u64,
+        event: event as u32,
+        cpu: cpu as u32,
+        data,
+    };
+
+    let index = buffer.head.fetch_add(1, Ordering::Relaxed);
+    let offset = TRACE_RECORDS_OFFSET + (index as usize % TRACE_BUFFER_RECORDS) * mem::size_of::<TraceRecord>();
+    unsafe {
+        let bytes =
+            slice::from_raw_parts(&record as *const TraceRecord as *const u8, mem::size_of::<TraceRecord>());
+        P::write_to_phys_memory(buffer.physical_start + offset, bytes);
+        P::write_to_phys_memory(
+            buffer.physical_start + mem::offset_of!(TraceBufferHeader, head),
+            &(index + 1).to_ne_bytes(),
+        );
+    }
+}
+
+/// Record the name of `task`, so the task IDs in other records can be named.
+pub fn task_name<P>(task: &Task<P>)
+where
+    P: Platform,
+{
+    let [first, second] = pack_task_name(&task.name);
+    record::<P>(TraceEvent::TaskName, [u64::from(task.id()), first, second]);
+}
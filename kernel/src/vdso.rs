@@ -0,0 +1,69 @@
+//! The vDSO page is a page of read-only data that's mapped into every address space at
+//! `poplar::vdso::VDSO_ADDRESS`. It holds the parameters userspace needs to read the monotonic and wall-clock
+//! time itself, without making a system call, and some information about the system. Its layout is defined by
+//! `poplar::vdso::VdsoData`.
+//!
+//! The page is a static in the kernel's image, so the kernel can update it directly. Each address space maps the
+//! same physical frame, so updates are seen by every task at once.
+
+use crate::Platform;
+use core::{sync::atomic::Ordering, time::Duration};
+use hal::memory::{Flags, Frame, FrameAllocator, Page, PageTable, Size4KiB, VAddr};
+use poplar::vdso::{VdsoData, FLAG_COUNTER, FLAG_REALTIME, VDSO_ADDRESS};
+use spinning_top::Spinlock;
+
+#[repr(C, align(4096))]
+struct VdsoPage(VdsoData);
+
+static VDSO_PAGE: VdsoPage = VdsoPage(VdsoData::new());
+/// Held while the page is being updated, as the sequence lock only allows one writer at a time.
+static WRITER: Spinlock<()> = Spinlock::new(());
+
+/// Publish the counter behind the platform's monotonic clock, so userspace can read it directly. `frequency` is
+/// in Hz, and `boot_value` is the value of the counter when the monotonic clock was zero. Platforms should only
+/// call this once they've made sure userspace can read the counter.
+pub fn set_clock(frequency: u64, boot_value: u64) {
+    update(|data| {
+        data.counter_frequency.store(frequency, Ordering::Relaxed);
+        data.counter_boot_value.store(boot_value, Ordering::Relaxed);
+        data.flags.fetch_or(FLAG_COUNTER, Ordering::Relaxed);
+    });
+}
+
+/// Publish the wall-clock time when the monotonic clock was zero. This is called by `rtc::WallClock` whenever it
+/// changes.
+pub fn set_realtime_offset(offset: Duration) {
+    update(|data| {
+        data.realtime_offset.store(offset.as_nanos() as u64, Ordering::Relaxed);
+        data.flags.fetch_or(FLAG_REALTIME, Ordering::Relaxed);
+    });
+}
+
+/// Publish the number of CPUs the kernel is scheduling tasks on.
+pub fn set_cpu_count(count: usize) {
+    update(|data| data.cpu_count.store(count as u32, Ordering::Relaxed));
+}
+
+/// Map the vDSO page into `page_table`, read-only.
+pub fn map<P, A>(page_table: &mut P::PageTable, kernel_page_table: &P::PageTable, allocator: &A)
+where
+    P: Platform,
+    A: FrameAllocator<P::PageTableSize>,
+{
+    let physical = kernel_page_table
+        .translate(VAddr::from(&VDSO_PAGE as *const VdsoPage))
+        .expect("vDSO page is not mapped into the kernel");
+    page_table
+        .map::<Size4KiB, _>(
+            Page::starts_with(VAddr::new(VDSO_ADDRESS)),
+            Frame::starts_with(physical),
+            Flags { user_accessible: true, ..Default::default() },
+            allocator,
+        )
+        .unwrap();
+}
+
+fn update(f: impl FnOnce(&VdsoData)) {
+    let _guard = WRITER.lock();
+    VDSO_PAGE.0.write(f);
+}
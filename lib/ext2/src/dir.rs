@@ -0,0 +1,180 @@
+//! Directories on an ext2 filesystem are files made up of variable-length entries, which each hold the inode
+//! number and name of a file in the directory. Entries can't cross a block boundary, and each entry's length
+//! (`rec_len`) covers any unused space after it, so the entries of a block cover all of it. Entries with an
+//! inode number of `0` are unused.
+
+use crate::{inode::FileType, Ext2Error};
+use alloc::{string::String, vec::Vec};
+
+/// The size of the fixed part of an entry, before its name.
+const HEADER_SIZE: usize = 8;
+const MAX_NAME_LENGTH: usize = 255;
+
+/// The values of the `file_type` field of entries, on filesystems that have `INCOMPAT_FILETYPE`.
+const TYPE_UNKNOWN: u8 = 0;
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+const TYPE_SYMLINK: u8 = 7;
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+    pub file_type: FileType,
+}
+
+/// An entry, as it's stored in a directory block.
+pub struct RawEntry<'a> {
+    pub offset: usize,
+    pub inode: u32,
+    pub rec_len: usize,
+    pub name: &'a [u8],
+    /// The type of the file the entry refers to. This is `None` if the filesystem doesn't store the types of files
+    /// in their entries.
+    pub file_type: Option<FileType>,
+}
+
+/// Parse the entries of a directory block. `file_types` is whether the filesystem stores the types of files in
+/// their entries. Before it did, the byte that holds the type was the top half of the length of the name.
+pub fn parse_block(block: &[u8], file_types: bool) -> Result<Vec<RawEntry<'_>>, Ext2Error> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        if offset + HEADER_SIZE > block.len() {
+            return Err(Ext2Error::Corrupt);
+        }
+        let inode = u32::from_le_bytes(block[offset..(offset + 4)].try_into().unwrap());
+        let rec_len = u16::from_le_bytes([block[offset + 4], block[offset + 5]]) as usize;
+        let name_len = if file_types {
+            block[offset + 6] as usize
+        } else {
+            u16::from_le_bytes([block[offset + 6], block[offset + 7]]) as usize
+        };
+        if rec_len < HEADER_SIZE
+            || !rec_len.is_multiple_of(4)
+            || offset + rec_len > block.len()
+            || HEADER_SIZE + name_len > rec_len
+        {
+            return Err(Ext2Error::Corrupt);
+        }
+
+        let file_type = if file_types {
+            match block[offset + 7] {
+                TYPE_UNKNOWN => None,
+                TYPE_FILE => Some(FileType::File),
+                TYPE_DIRECTORY => Some(FileType::Directory),
+                TYPE_SYMLINK => Some(FileType::Symlink),
+                _ => Some(FileType::Other),
+            }
+        } else {
+            None
+        };
+        let name = &block[(offset + HEADER_SIZE)..(offset + HEADER_SIZE + name_len)];
+        entries.push(RawEntry { offset, inode, rec_len, name, file_type });
+        offset += rec_len;
+    }
+    Ok(entries)
+}
+
+/// The space taken up by an entry with a name of `name_length` bytes. Entries are aligned to 4 bytes.
+pub fn entry_size(name_length: usize) -> usize {
+    (HEADER_SIZE + name_length).next_multiple_of(4)
+}
+
+/// Write an entry to a directory block. `file_type` should be `None` if the filesystem doesn't store the types
+/// of files in their entries.
+pub fn write_entry(
+    block: &mut [u8],
+    offset: usize,
+    inode: u32,
+    rec_len: usize,
+    name: &[u8],
+    file_type: Option<FileType>,
+) {
+    let type_code = match file_type {
+        None => TYPE_UNKNOWN,
+        Some(FileType::File) => TYPE_FILE,
+        Some(FileType::Directory) => TYPE_DIRECTORY,
+        Some(FileType::Symlink) => TYPE_SYMLINK,
+        Some(FileType::Other) => TYPE_UNKNOWN,
+    };
+    block[offset..(offset + 4)].copy_from_slice(&inode.to_le_bytes());
+    block[(offset + 4)..(offset + 6)].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    block[offset + 6] = name.len() as u8;
+    block[offset + 7] = type_code;
+    block[(offset + HEADER_SIZE)..(offset + HEADER_SIZE + name.len())].copy_from_slice(name);
+}
+
+/// Add an entry to a directory block, if it has space for it. New entries either take over an unused entry, or
+/// the space at the end of an entry that's longer than it needs to be. Returns whether the entry was added.
+pub fn insert_entry(
+    block: &mut [u8],
+    file_types: bool,
+    name: &[u8],
+    inode: u32,
+    file_type: Option<FileType>,
+) -> Result<bool, Ext2Error> {
+    let needed = entry_size(name.len());
+    let slot = parse_block(block, file_types)?.iter().find_map(|entry| {
+        let used = if entry.inode == 0 { 0 } else { entry_size(entry.name.len()) };
+        (entry.rec_len - used >= needed).then_some((entry.offset, used, entry.rec_len))
+    });
+    let Some((offset, used, rec_len)) = slot else {
+        return Ok(false);
+    };
+
+    if used != 0 {
+        block[(offset + 4)..(offset + 6)].copy_from_slice(&(used as u16).to_le_bytes());
+    }
+    write_entry(block, offset + used, inode, rec_len - used, name, file_type);
+    Ok(true)
+}
+
+/// Check that `name` can be used as the name of a new file.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && name.len() <= MAX_NAME_LENGTH && !name.contains(['/', '\0'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_into_block() {
+        let mut block = [0; 64];
+        write_entry(&mut block, 0, 2, 12, b".", Some(FileType::Directory));
+        write_entry(&mut block, 12, 2, 52, b"..", Some(FileType::Directory));
+
+        // The new entry is placed in the space after `..`, which is shortened to fit its name
+        assert!(insert_entry(&mut block, true, b"hello", 12, Some(FileType::File)).unwrap());
+        let entries = parse_block(&block, true).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].rec_len, 12);
+        assert_eq!((entries[2].offset, entries[2].inode, entries[2].rec_len), (24, 12, 40));
+        assert_eq!(entries[2].name, b"hello");
+        assert_eq!(entries[2].file_type, Some(FileType::File));
+
+        // There isn't enough space left for an entry with a 30-byte name
+        assert!(!insert_entry(&mut block, true, &[b'a'; 30], 13, Some(FileType::File)).unwrap());
+    }
+
+    #[test]
+    fn corrupt_blocks() {
+        let mut block = [0; 64];
+        write_entry(&mut block, 0, 2, 12, b".", Some(FileType::Directory));
+        assert!(parse_block(&block, true).is_err());
+        write_entry(&mut block, 12, 2, 56, b"..", Some(FileType::Directory));
+        assert!(parse_block(&block, true).is_err());
+        write_entry(&mut block, 12, 2, 52, b"..", Some(FileType::Directory));
+        assert!(parse_block(&block, true).is_ok());
+    }
+
+    #[test]
+    fn name_validity() {
+        assert!(is_valid_name("hello world.txt"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name("a/b"));
+        assert!(!is_valid_name(&"a".repeat(256)));
+    }
+}
@@ -0,0 +1,151 @@
+//! Each file on an ext2 filesystem is described by an inode, which holds its type, permissions, size, and the
+//! blocks its data is stored in. The first 12 blocks of a file are listed in the inode itself. Later blocks are
+//! reached through indirect blocks, which are blocks full of block numbers: the inode points to a single
+//! indirect block, a doubly-indirect block (which points to indirect blocks), and a triply-indirect block.
+//!
+//! Any block number can be `0`, which means the part of the file it would cover is a hole, and reads as zeros.
+
+/// The inode of the root directory.
+pub const ROOT_INODE: u32 = 2;
+
+pub const NUM_DIRECT_BLOCKS: usize = 12;
+pub const INDIRECT_BLOCK: usize = 12;
+pub const DOUBLY_INDIRECT_BLOCK: usize = 13;
+pub const TRIPLY_INDIRECT_BLOCK: usize = 14;
+pub const NUM_BLOCK_POINTERS: usize = 15;
+
+pub const MODE_TYPE_MASK: u16 = 0xf000;
+pub const MODE_FILE: u16 = 0x8000;
+pub const MODE_DIRECTORY: u16 = 0x4000;
+pub const MODE_SYMLINK: u16 = 0xa000;
+const MODE_PERMISSIONS_MASK: u16 = 0o7777;
+
+/// Set on directories that are indexed by a hashed B-tree, as well as holding their entries in the usual way.
+pub const INDEX_FLAG: u32 = 0x1000;
+
+/// Symlinks with targets shorter than this are stored in the space for the inode's block numbers, instead of in a
+/// data block ("fast" symlinks).
+pub const FAST_SYMLINK_MAX: usize = 60;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    /// Devices, FIFOs, and sockets, which we can't do anything with.
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Inode {
+    pub number: u32,
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u64,
+    pub links_count: u16,
+    /// The number of 512-byte sectors allocated to the file, including indirect blocks and its extended
+    /// attribute block.
+    pub(crate) sectors: u32,
+    pub(crate) flags: u32,
+    pub(crate) blocks: [u32; NUM_BLOCK_POINTERS],
+    /// The block holding the file's extended attributes, if it has any. We don't use them, but they take up
+    /// space that's counted in `sectors`.
+    pub(crate) file_acl: u32,
+}
+
+impl Inode {
+    pub fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_FILE => FileType::File,
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == FileType::Directory
+    }
+
+    /// The file's permission bits (e.g. `0o755`), including the setuid, setgid, and sticky bits.
+    pub fn permissions(&self) -> u16 {
+        self.mode & MODE_PERMISSIONS_MASK
+    }
+
+    /// Create a new inode of the type and with the permissions given by `mode`, which doesn't have any blocks
+    /// yet.
+    pub(crate) fn new(number: u32, mode: u16) -> Inode {
+        Inode {
+            number,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            links_count: 1,
+            sectors: 0,
+            flags: 0,
+            blocks: [0; NUM_BLOCK_POINTERS],
+            file_acl: 0,
+        }
+    }
+
+    pub(crate) fn parse(number: u32, data: &[u8]) -> Inode {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..(offset + 4)].try_into().unwrap());
+
+        let mode = u16_at(0);
+        let mut inode = Inode {
+            number,
+            mode,
+            uid: u16_at(2),
+            gid: u16_at(24),
+            size: u32_at(4) as u64,
+            links_count: u16_at(26),
+            sectors: u32_at(28),
+            flags: u32_at(32),
+            blocks: core::array::from_fn(|i| u32_at(40 + i * 4)),
+            file_acl: u32_at(104),
+        };
+        // The field that holds the top half of the size is only used for that by regular files
+        if inode.file_type() == FileType::File {
+            inode.size |= (u32_at(108) as u64) << 32;
+        }
+        inode
+    }
+
+    /// Update the fields of an inode on disk that we know about, leaving the others as they are.
+    pub(crate) fn update(&self, data: &mut [u8]) {
+        data[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        data[2..4].copy_from_slice(&self.uid.to_le_bytes());
+        data[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        data[24..26].copy_from_slice(&self.gid.to_le_bytes());
+        data[26..28].copy_from_slice(&self.links_count.to_le_bytes());
+        data[28..32].copy_from_slice(&self.sectors.to_le_bytes());
+        data[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        for (i, block) in self.blocks.iter().enumerate() {
+            data[(40 + i * 4)..(44 + i * 4)].copy_from_slice(&block.to_le_bytes());
+        }
+        data[104..108].copy_from_slice(&self.file_acl.to_le_bytes());
+        if self.file_type() == FileType::File {
+            data[108..112].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+        }
+    }
+
+    /// The data held in the space for the block numbers, which is where fast symlinks keep their targets.
+    pub(crate) fn inline_data(&self) -> [u8; FAST_SYMLINK_MAX] {
+        let mut data = [0; FAST_SYMLINK_MAX];
+        for (i, block) in self.blocks.iter().enumerate() {
+            data[(i * 4)..(i * 4 + 4)].copy_from_slice(&block.to_le_bytes());
+        }
+        data
+    }
+
+    pub(crate) fn set_inline_data(&mut self, data: &[u8]) {
+        let mut padded = [0; FAST_SYMLINK_MAX];
+        padded[0..data.len()].copy_from_slice(data);
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            *block = u32::from_le_bytes(padded[(i * 4)..(i * 4 + 4)].try_into().unwrap());
+        }
+    }
+}
@@ -0,0 +1,1200 @@
+//! A `no_std` driver for ext2 filesystems, supporting directories, symlinks, and files of any size the format
+//! can hold. Files can be created and written to, with blocks and inodes being allocated from the bitmaps of
+//! each block group, but nothing is journaled, so the filesystem may need checking if the system goes down
+//! part-way through a change. The filesystem is accessed through a `BlockDevice`.
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(test)]
+extern crate std;
+
+mod dir;
+mod inode;
+mod superblock;
+
+pub use dir::DirEntry;
+pub use inode::{FileType, Inode};
+
+use alloc::{string::String, vec, vec::Vec};
+use inode::{
+    DOUBLY_INDIRECT_BLOCK,
+    FAST_SYMLINK_MAX,
+    INDEX_FLAG,
+    INDIRECT_BLOCK,
+    MODE_DIRECTORY,
+    MODE_FILE,
+    MODE_SYMLINK,
+    MODE_TYPE_MASK,
+    NUM_BLOCK_POINTERS,
+    NUM_DIRECT_BLOCKS,
+    ROOT_INODE,
+    TRIPLY_INDIRECT_BLOCK,
+};
+use superblock::{GroupDescriptor, Superblock, GROUP_DESCRIPTOR_SIZE, SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// The most symlinks that are followed while looking up a single path, so a loop of symlinks can't send us round
+/// in circles forever.
+const MAX_SYMLINKS: usize = 8;
+
+/// The permissions new files, directories, and symlinks are created with.
+const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
+const DEFAULT_DIR_PERMISSIONS: u16 = 0o755;
+const SYMLINK_PERMISSIONS: u16 = 0o777;
+
+/// A device that an ext2 filesystem is stored on. Sector `0` should be the first sector of the filesystem.
+pub trait BlockDevice {
+    /// Read whole sectors, starting at `sector`. The length of `buffer` is a multiple of `SECTOR_SIZE`.
+    fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), Ext2Error>;
+    /// Write whole sectors, starting at `sector`. The length of `data` is a multiple of `SECTOR_SIZE`.
+    fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), Ext2Error>;
+    /// Make sure all previous writes have reached persistent storage.
+    fn flush(&mut self) -> Result<(), Ext2Error>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Ext2Error {
+    /// The underlying device failed to read or write a sector.
+    Io,
+    /// The filesystem is not an ext2 filesystem, or uses features we don't support.
+    Unsupported,
+    /// The structures on the filesystem are not consistent.
+    Corrupt,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    NotASymlink,
+    InvalidName,
+    /// There are no free blocks or inodes left, or the file would be larger than the filesystem can hold.
+    NoSpace,
+    /// The filesystem was mounted read-only, or uses features we can't keep up to date when writing to it.
+    ReadOnly,
+    /// More than `MAX_SYMLINKS` symlinks were followed while looking up a path.
+    SymlinkLoop,
+}
+
+/// What a path leads to, when it's looked up without following symlinks (see `FileSystem::resolve`).
+#[derive(Clone, Debug)]
+pub enum Resolved {
+    Inode(Inode),
+    /// The path goes through a symlink, which is `depth` components into it.
+    Symlink { depth: usize, target: String },
+}
+
+pub struct FileSystem<D> {
+    device: D,
+    superblock: Superblock,
+    groups: Vec<GroupDescriptor>,
+    /// The first block of the table of group descriptors, which follows the block holding the superblock.
+    group_table_block: u32,
+    writable: bool,
+    /// Set when the free counts held in the superblock have changed, and so it needs writing back.
+    superblock_dirty: bool,
+    /// The most recently accessed indirect block, which avoids reading it again for each block of a file.
+    indirect_cache: Option<(u32, Vec<u8>)>,
+}
+
+impl<D> FileSystem<D>
+where
+    D: BlockDevice,
+{
+    /// Mount the filesystem on `device`. If `writable` is not set, or the filesystem uses features that we can't
+    /// keep up to date, it is mounted read-only, and any changes fail with `Ext2Error::ReadOnly`.
+    pub fn mount(mut device: D, writable: bool) -> Result<FileSystem<D>, Ext2Error> {
+        let mut data = [0; SUPERBLOCK_SIZE];
+        device.read((SUPERBLOCK_OFFSET / SECTOR_SIZE) as u64, &mut data)?;
+        let superblock = Superblock::parse(&data)?;
+
+        let group_table_block = superblock.first_data_block + 1;
+        let num_groups = superblock.num_groups();
+        let mut fs = FileSystem {
+            device,
+            writable: writable && superblock.is_writable(),
+            superblock,
+            groups: Vec::with_capacity(num_groups),
+            group_table_block,
+            superblock_dirty: false,
+            indirect_cache: None,
+        };
+        if fs.superblock.inodes_count > num_groups as u32 * fs.superblock.inodes_per_group {
+            return Err(Ext2Error::Corrupt);
+        }
+
+        let mut block = vec![0; fs.block_size()];
+        let descriptors_per_block = fs.block_size() / GROUP_DESCRIPTOR_SIZE;
+        for i in 0..num_groups {
+            if i % descriptors_per_block == 0 {
+                fs.read_block(group_table_block + (i / descriptors_per_block) as u32, &mut block)?;
+            }
+            let offset = (i % descriptors_per_block) * GROUP_DESCRIPTOR_SIZE;
+            fs.groups.push(GroupDescriptor::parse(&block[offset..(offset + GROUP_DESCRIPTOR_SIZE)]));
+        }
+
+        Ok(fs)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    pub fn root(&mut self) -> Result<Inode, Ext2Error> {
+        self.inode(ROOT_INODE)
+    }
+
+    /// Find the file at `path`, starting at the root directory. Symlinks are followed, including if the file at
+    /// `path` is itself a symlink. Symlinks with absolute targets are followed from the root of this filesystem.
+    pub fn lookup(&mut self, path: &[&str]) -> Result<Inode, Ext2Error> {
+        // The components we have left to look up, in reverse, so the targets of symlinks can be pushed on
+        let mut remaining: Vec<String> = path.iter().rev().map(|&component| String::from(component)).collect();
+        let mut inode = self.root()?;
+        let mut symlinks_followed = 0;
+
+        while let Some(component) = remaining.pop() {
+            if component.is_empty() {
+                continue;
+            }
+            if !inode.is_dir() {
+                return Err(Ext2Error::NotADirectory);
+            }
+
+            let entry = self.find_entry(&inode, component.as_bytes())?.ok_or(Ext2Error::NotFound)?;
+            let next = self.inode(entry.inode)?;
+            if next.file_type() == FileType::Symlink {
+                symlinks_followed += 1;
+                if symlinks_followed > MAX_SYMLINKS {
+                    return Err(Ext2Error::SymlinkLoop);
+                }
+
+                // Relative targets are followed from the directory holding the symlink, which is `inode`
+                let target = self.read_link(&next)?;
+                if target.starts_with('/') {
+                    inode = self.root()?;
+                }
+                remaining.extend(target.split('/').rev().map(String::from));
+                continue;
+            }
+            inode = next;
+        }
+
+        Ok(inode)
+    }
+
+    /// Find the file at `path`, starting at the root directory, without following symlinks. If a component of
+    /// `path` (including the last) is a symlink, its target is returned instead, so the caller can follow it.
+    pub fn resolve(&mut self, path: &[&str]) -> Result<Resolved, Ext2Error> {
+        let mut inode = self.root()?;
+        for (i, component) in path.iter().enumerate() {
+            if component.is_empty() {
+                continue;
+            }
+            if !inode.is_dir() {
+                return Err(Ext2Error::NotADirectory);
+            }
+
+            let entry = self.find_entry(&inode, component.as_bytes())?.ok_or(Ext2Error::NotFound)?;
+            inode = self.inode(entry.inode)?;
+            if inode.file_type() == FileType::Symlink {
+                return Ok(Resolved::Symlink { depth: i + 1, target: self.read_link(&inode)? });
+            }
+        }
+        Ok(Resolved::Inode(inode))
+    }
+
+    /// List the entries of a directory. The `.` and `..` entries are not included.
+    pub fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, Ext2Error> {
+        if !dir.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+
+        let mut raw_entries = Vec::new();
+        let mut block = vec![0; self.block_size()];
+        for index in 0..self.num_blocks(dir) {
+            self.read_dir_block(dir, index, &mut block)?;
+            for entry in dir::parse_block(&block, self.file_types_in_entries())? {
+                if entry.inode != 0 && entry.name != b"." && entry.name != b".." {
+                    raw_entries.push((
+                        String::from_utf8_lossy(entry.name).into_owned(),
+                        entry.inode,
+                        entry.file_type,
+                    ));
+                }
+            }
+        }
+
+        // Filesystems that don't store the types of files in their entries need us to look at each file
+        let mut entries = Vec::with_capacity(raw_entries.len());
+        for (name, inode, file_type) in raw_entries {
+            let file_type = match file_type {
+                Some(file_type) => file_type,
+                None => self.inode(inode)?.file_type(),
+            };
+            entries.push(DirEntry { name, inode, file_type });
+        }
+        Ok(entries)
+    }
+
+    /// Read from a file, starting at `offset`. Returns the number of bytes read, which is less than the length
+    /// of `buffer` if the end of the file is reached.
+    pub fn read(&mut self, file: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext2Error> {
+        if file.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        if offset >= file.size {
+            return Ok(0);
+        }
+
+        let block_size = self.block_size();
+        let length = usize::min(buffer.len(), (file.size - offset).try_into().unwrap_or(usize::MAX));
+        let mut block_data = vec![0; block_size];
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let block_offset = (position % block_size as u64) as usize;
+            let n = usize::min(block_size - block_offset, length - done);
+
+            match self.block_at(file, position / block_size as u64)? {
+                0 => buffer[done..(done + n)].fill(0),
+                block => {
+                    self.read_block(block, &mut block_data)?;
+                    buffer[done..(done + n)].copy_from_slice(&block_data[block_offset..(block_offset + n)]);
+                }
+            }
+            done += n;
+        }
+
+        Ok(length)
+    }
+
+    /// Write `data` to a file, starting at `offset`, allocating blocks for the file as needed. If `offset` is past
+    /// the end of the file, the gap is left as a hole, which reads as zeros. `file` is updated with the file's
+    /// new size and blocks.
+    pub fn write(&mut self, file: &mut Inode, offset: u64, data: &[u8]) -> Result<(), Ext2Error> {
+        self.check_writable()?;
+        if file.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        let end = offset.checked_add(data.len() as u64).ok_or(Ext2Error::NoSpace)?;
+        if end > self.max_file_size() {
+            return Err(Ext2Error::NoSpace);
+        }
+
+        let block_size = self.block_size();
+        let mut block_data = vec![0; block_size];
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < data.len() {
+            let position = offset + done as u64;
+            let block_offset = (position % block_size as u64) as usize;
+            let n = usize::min(block_size - block_offset, data.len() - done);
+
+            let (block, is_new) = match self.allocate_block_at(file, position / block_size as u64) {
+                Ok(block) => block,
+                Err(err) => {
+                    // Record what we did manage to write, and any blocks we allocated, so they aren't lost
+                    result = Err(err);
+                    break;
+                }
+            };
+            // We only need the existing contents of the block if we're not replacing all of it
+            if n != block_size {
+                if is_new {
+                    block_data.fill(0);
+                } else {
+                    self.read_block(block, &mut block_data)?;
+                }
+            }
+            block_data[block_offset..(block_offset + n)].copy_from_slice(&data[done..(done + n)]);
+            self.write_block(block, &block_data)?;
+            done += n;
+        }
+
+        if done > 0 {
+            file.size = u64::max(file.size, offset + done as u64);
+        }
+        // Linux marks filesystems as holding large files as soon as any file needs more than 31 bits for its size
+        if file.size > i32::MAX as u64 && self.superblock.feature_ro_compat & superblock::RO_COMPAT_LARGE_FILE == 0
+        {
+            self.superblock.feature_ro_compat |= superblock::RO_COMPAT_LARGE_FILE;
+            self.superblock_dirty = true;
+        }
+        self.write_inode(file)?;
+        result
+    }
+
+    /// Truncate a file to zero length, freeing all of its blocks.
+    pub fn truncate(&mut self, file: &mut Inode) -> Result<(), Ext2Error> {
+        self.check_writable()?;
+        if file.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+
+        self.free_blocks(file)?;
+        file.size = 0;
+        self.write_inode(file)
+    }
+
+    /// Create an empty file called `name` in the directory `parent`.
+    pub fn create_file(&mut self, parent: &Inode, name: &str) -> Result<Inode, Ext2Error> {
+        self.create(parent, name, MODE_FILE | DEFAULT_FILE_PERMISSIONS, |_, _| Ok(()))
+    }
+
+    /// Create an empty directory called `name` in the directory `parent`.
+    pub fn create_dir(&mut self, parent: &Inode, name: &str) -> Result<Inode, Ext2Error> {
+        let dir = self.create(parent, name, MODE_DIRECTORY | DEFAULT_DIR_PERMISSIONS, |fs, dir| {
+            /*
+             * Every directory starts with `.` and `..` entries, which refer to itself and its parent. The
+             * entry in its parent and its own `.` entry make two links to the new directory.
+             */
+            let (block, _) = fs.allocate_block_at(dir, 0)?;
+            let mut data = vec![0; fs.block_size()];
+            let file_type = fs.file_types_in_entries().then_some(FileType::Directory);
+            dir::write_entry(&mut data, 0, dir.number, dir::entry_size(1), b".", file_type);
+            dir::write_entry(
+                &mut data,
+                dir::entry_size(1),
+                parent.number,
+                fs.block_size() - dir::entry_size(1),
+                b"..",
+                file_type,
+            );
+            fs.write_block(block, &data)?;
+            dir.size = fs.block_size() as u64;
+            dir.links_count = 2;
+            Ok(())
+        })?;
+
+        // The new directory's `..` entry is another link to its parent
+        let mut parent = self.inode(parent.number)?;
+        parent.links_count += 1;
+        self.write_inode(&parent)?;
+        Ok(dir)
+    }
+
+    /// Create a symlink called `name` in the directory `parent`, which points to `target`.
+    pub fn create_symlink(&mut self, parent: &Inode, name: &str, target: &str) -> Result<Inode, Ext2Error> {
+        if target.is_empty() || target.len() >= self.block_size() {
+            return Err(Ext2Error::InvalidName);
+        }
+
+        self.create(parent, name, MODE_SYMLINK | SYMLINK_PERMISSIONS, |fs, link| {
+            if target.len() < FAST_SYMLINK_MAX {
+                link.set_inline_data(target.as_bytes());
+                link.size = target.len() as u64;
+                Ok(())
+            } else {
+                let (block, _) = fs.allocate_block_at(link, 0)?;
+                let mut data = vec![0; fs.block_size()];
+                data[0..target.len()].copy_from_slice(target.as_bytes());
+                fs.write_block(block, &data)?;
+                link.size = target.len() as u64;
+                Ok(())
+            }
+        })
+    }
+
+    /// Read the target of a symlink.
+    pub fn read_link(&mut self, link: &Inode) -> Result<String, Ext2Error> {
+        if link.file_type() != FileType::Symlink {
+            return Err(Ext2Error::NotASymlink);
+        }
+        let length = link.size as usize;
+        if length >= self.block_size() {
+            return Err(Ext2Error::Corrupt);
+        }
+
+        let target = if self.is_fast_symlink(link) {
+            let data = link.inline_data();
+            String::from_utf8_lossy(data.get(0..length).ok_or(Ext2Error::Corrupt)?).into_owned()
+        } else {
+            let block = self.block_at(link, 0)?;
+            if block == 0 {
+                return Err(Ext2Error::Corrupt);
+            }
+            let mut data = vec![0; self.block_size()];
+            self.read_block(block, &mut data)?;
+            String::from_utf8_lossy(&data[0..length]).into_owned()
+        };
+        Ok(target)
+    }
+
+    /// Write any cached state back to the filesystem, and flush the device.
+    pub fn flush(&mut self) -> Result<(), Ext2Error> {
+        if self.superblock_dirty {
+            let sector = (SUPERBLOCK_OFFSET / SECTOR_SIZE) as u64;
+            let mut data = [0; SUPERBLOCK_SIZE];
+            self.device.read(sector, &mut data)?;
+            self.superblock.update(&mut data);
+            self.device.write(sector, &data)?;
+            self.superblock_dirty = false;
+        }
+
+        self.device.flush()
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn inode(&mut self, number: u32) -> Result<Inode, Ext2Error> {
+        let (block, offset) = self.inode_location(number)?;
+        let mut data = vec![0; self.block_size()];
+        self.read_block(block, &mut data)?;
+        Ok(Inode::parse(number, &data[offset..(offset + self.superblock.inode_size)]))
+    }
+
+    /// Allocate an inode for a new file called `name` in `parent`, with the type and permissions given by `mode`.
+    /// `init` sets up the new file's contents before it's added to `parent`.
+    fn create(
+        &mut self,
+        parent: &Inode,
+        name: &str,
+        mode: u16,
+        init: impl FnOnce(&mut Self, &mut Inode) -> Result<(), Ext2Error>,
+    ) -> Result<Inode, Ext2Error> {
+        self.check_writable()?;
+        if !parent.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        if !dir::is_valid_name(name) {
+            return Err(Ext2Error::InvalidName);
+        }
+        // Make sure we're looking at the latest version of the parent, as adding entries changes it
+        let mut parent = self.inode(parent.number)?;
+        if self.find_entry(&parent, name.as_bytes())?.is_some() {
+            return Err(Ext2Error::AlreadyExists);
+        }
+
+        let is_dir = mode & MODE_TYPE_MASK == MODE_DIRECTORY;
+        let number = self.allocate_inode(self.group_of_inode(parent.number), is_dir)?;
+        let mut inode = Inode::new(number, mode);
+        let result = init(self, &mut inode)
+            .and_then(|()| self.write_inode(&inode))
+            .and_then(|()| self.add_entry(&mut parent, name, &inode));
+        if let Err(err) = result {
+            // Give back anything we allocated, so it isn't lost
+            self.free_blocks(&mut inode)?;
+            self.free_inode(number, is_dir)?;
+            return Err(err);
+        }
+        Ok(inode)
+    }
+
+    /// Add an entry for `inode` to the directory `dir`, extending the directory if none of its blocks have
+    /// space for it.
+    fn add_entry(&mut self, dir: &mut Inode, name: &str, inode: &Inode) -> Result<(), Ext2Error> {
+        let file_types = self.file_types_in_entries();
+        let file_type = file_types.then(|| inode.file_type());
+        let mut block = vec![0; self.block_size()];
+
+        let mut added = false;
+        for index in 0..self.num_blocks(dir) {
+            let block_number = self.read_dir_block(dir, index, &mut block)?;
+            if dir::insert_entry(&mut block, file_types, name.as_bytes(), inode.number, file_type)? {
+                self.write_block(block_number, &block)?;
+                added = true;
+                break;
+            }
+        }
+        if !added {
+            let (block_number, _) = self.allocate_block_at(dir, self.num_blocks(dir))?;
+            block.fill(0);
+            dir::write_entry(&mut block, 0, inode.number, self.block_size(), name.as_bytes(), file_type);
+            self.write_block(block_number, &block)?;
+            dir.size += self.block_size() as u64;
+        }
+
+        /*
+         * Indexed directories hold their entries in the usual way too, but we don't update the index, so
+         * we clear the flag that says it can be used. Linux then treats it as an unindexed directory.
+         */
+        dir.flags &= !INDEX_FLAG;
+        self.write_inode(dir)
+    }
+
+    /// Find the entry called `name` in the directory `dir`.
+    fn find_entry(&mut self, dir: &Inode, name: &[u8]) -> Result<Option<DirEntry>, Ext2Error> {
+        let mut block = vec![0; self.block_size()];
+        for index in 0..self.num_blocks(dir) {
+            self.read_dir_block(dir, index, &mut block)?;
+            let found = dir::parse_block(&block, self.file_types_in_entries())?
+                .into_iter()
+                .find(|entry| entry.inode != 0 && entry.name == name)
+                .map(|entry| (entry.inode, entry.file_type));
+            if let Some((inode, file_type)) = found {
+                let file_type = match file_type {
+                    Some(file_type) => file_type,
+                    None => self.inode(inode)?.file_type(),
+                };
+                return Ok(Some(DirEntry { name: String::from_utf8_lossy(name).into_owned(), inode, file_type }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read block `index` of a directory into `buffer`, returning the number of the block. Directories can't
+    /// have holes.
+    fn read_dir_block(&mut self, dir: &Inode, index: u64, buffer: &mut [u8]) -> Result<u32, Ext2Error> {
+        let block = self.block_at(dir, index)?;
+        if block == 0 {
+            return Err(Ext2Error::Corrupt);
+        }
+        self.read_block(block, buffer)?;
+        Ok(block)
+    }
+
+    fn block_size(&self) -> usize {
+        self.superblock.block_size
+    }
+
+    fn sectors_per_block(&self) -> u32 {
+        (self.block_size() / SECTOR_SIZE) as u32
+    }
+
+    /// The number of block numbers that fit in an indirect block.
+    fn pointers_per_block(&self) -> u64 {
+        (self.block_size() / 4) as u64
+    }
+
+    fn num_blocks(&self, inode: &Inode) -> u64 {
+        inode.size.div_ceil(self.block_size() as u64)
+    }
+
+    fn file_types_in_entries(&self) -> bool {
+        self.superblock.feature_incompat & superblock::INCOMPAT_FILETYPE != 0
+    }
+
+    /// The largest file we can create, which is limited by how many blocks can be reached from an inode, the
+    /// number of sectors that can be counted in `i_blocks`, and (on revision `0` filesystems, which don't have
+    /// `RO_COMPAT_LARGE_FILE`) the 31-bit size field.
+    fn max_file_size(&self) -> u64 {
+        let pointers = self.pointers_per_block();
+        let addressable_blocks = NUM_DIRECT_BLOCKS as u64 + pointers + pointers.pow(2) + pointers.pow(3);
+        let max_size =
+            u64::min(addressable_blocks * self.block_size() as u64, u32::MAX as u64 * SECTOR_SIZE as u64);
+        if self.superblock.rev_level == 0 {
+            u64::min(max_size, i32::MAX as u64)
+        } else {
+            max_size
+        }
+    }
+
+    fn is_fast_symlink(&self, link: &Inode) -> bool {
+        let attribute_sectors = if link.file_acl != 0 { self.sectors_per_block() } else { 0 };
+        link.sectors == attribute_sectors
+    }
+
+    fn check_writable(&self) -> Result<(), Ext2Error> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(Ext2Error::ReadOnly)
+        }
+    }
+
+    fn read_block(&mut self, block: u32, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.superblock.blocks_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        self.device.read(block as u64 * self.sectors_per_block() as u64, buffer)
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) -> Result<(), Ext2Error> {
+        if block >= self.superblock.blocks_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        self.device.write(block as u64 * self.sectors_per_block() as u64, data)
+    }
+
+    /// Find the block that inode `number` is in, and its offset into that block.
+    fn inode_location(&self, number: u32) -> Result<(u32, usize), Ext2Error> {
+        if number == 0 || number > self.superblock.inodes_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        let group = &self.groups[self.group_of_inode(number)];
+        let offset = ((number - 1) % self.superblock.inodes_per_group) as u64 * self.superblock.inode_size as u64;
+        let block = group.inode_table as u64 + offset / self.block_size() as u64;
+        Ok((block as u32, (offset % self.block_size() as u64) as usize))
+    }
+
+    fn write_inode(&mut self, inode: &Inode) -> Result<(), Ext2Error> {
+        let (block, offset) = self.inode_location(inode.number)?;
+        let mut data = vec![0; self.block_size()];
+        self.read_block(block, &mut data)?;
+        inode.update(&mut data[offset..(offset + self.superblock.inode_size)]);
+        self.write_block(block, &data)
+    }
+
+    fn group_of_inode(&self, number: u32) -> usize {
+        ((number - 1) / self.superblock.inodes_per_group) as usize
+    }
+
+    /// Work out the path through the inode's block numbers and any indirect blocks to block `index` of a file.
+    /// The first element of the path is an index into the inode's block numbers, and each following element is
+    /// an index into an indirect block. Returns the path and its length, or `None` if the block is past the
+    /// largest file the filesystem can hold.
+    fn block_path(&self, index: u64) -> Option<([usize; 4], usize)> {
+        let pointers = self.pointers_per_block();
+        if index < NUM_DIRECT_BLOCKS as u64 {
+            return Some(([index as usize, 0, 0, 0], 1));
+        }
+        let index = index - NUM_DIRECT_BLOCKS as u64;
+        if index < pointers {
+            return Some(([INDIRECT_BLOCK, index as usize, 0, 0], 2));
+        }
+        let index = index - pointers;
+        if index < pointers.pow(2) {
+            return Some((
+                [DOUBLY_INDIRECT_BLOCK, (index / pointers) as usize, (index % pointers) as usize, 0],
+                3,
+            ));
+        }
+        let index = index - pointers.pow(2);
+        if index < pointers.pow(3) {
+            return Some((
+                [
+                    TRIPLY_INDIRECT_BLOCK,
+                    (index / pointers.pow(2)) as usize,
+                    ((index / pointers) % pointers) as usize,
+                    (index % pointers) as usize,
+                ],
+                4,
+            ));
+        }
+        None
+    }
+
+    /// Find the block holding block `index` of a file. Returns `0` if that part of the file is a hole.
+    fn block_at(&mut self, inode: &Inode, index: u64) -> Result<u32, Ext2Error> {
+        let (path, length) = self.block_path(index).ok_or(Ext2Error::Corrupt)?;
+        let mut block = inode.blocks[path[0]];
+        for &i in &path[1..length] {
+            if block == 0 {
+                return Ok(0);
+            }
+            block = self.indirect_entry(block, i)?;
+        }
+        Ok(block)
+    }
+
+    /// Find the block holding block `index` of a file, allocating it (and any indirect blocks needed to reach it)
+    /// if that part of the file is a hole. Also returns whether the block was newly allocated, in which case its
+    /// contents are undefined.
+    fn allocate_block_at(&mut self, inode: &mut Inode, index: u64) -> Result<(u32, bool), Ext2Error> {
+        let (path, length) = self.block_path(index).ok_or(Ext2Error::NoSpace)?;
+        let goal = self.group_of_inode(inode.number);
+
+        let mut is_new = false;
+        let mut block = inode.blocks[path[0]];
+        if block == 0 {
+            block = self.allocate_file_block(inode, goal, length > 1)?;
+            inode.blocks[path[0]] = block;
+            is_new = true;
+        }
+        for (level, &index) in path.iter().enumerate().take(length).skip(1) {
+            let is_indirect = level + 1 < length;
+            let mut next = if is_new { 0 } else { self.indirect_entry(block, index)? };
+            is_new = next == 0;
+            if is_new {
+                next = self.allocate_file_block(inode, goal, is_indirect)?;
+                self.set_indirect_entry(block, index, next)?;
+            }
+            block = next;
+        }
+        Ok((block, is_new))
+    }
+
+    /// Allocate a block for a file, counting it in the file's sectors. Indirect blocks are zeroed, so the blocks
+    /// they point to start off as holes.
+    fn allocate_file_block(
+        &mut self,
+        inode: &mut Inode,
+        goal: usize,
+        is_indirect: bool,
+    ) -> Result<u32, Ext2Error> {
+        let block = self.allocate_block(goal)?;
+        inode.sectors += self.sectors_per_block();
+        if is_indirect {
+            let zeros = vec![0; self.block_size()];
+            self.write_block(block, &zeros)?;
+            if self.indirect_cache.as_ref().is_some_and(|(cached, _)| *cached == block) {
+                self.indirect_cache = Some((block, zeros));
+            }
+        }
+        Ok(block)
+    }
+
+    fn indirect_entry(&mut self, block: u32, index: usize) -> Result<u32, Ext2Error> {
+        let data = match self.indirect_cache {
+            Some((cached, ref data)) if cached == block => data,
+            _ => {
+                let mut data = vec![0; self.block_size()];
+                self.read_block(block, &mut data)?;
+                &self.indirect_cache.insert((block, data)).1
+            }
+        };
+        Ok(u32::from_le_bytes(data[(index * 4)..(index * 4 + 4)].try_into().unwrap()))
+    }
+
+    fn set_indirect_entry(&mut self, block: u32, index: usize, value: u32) -> Result<(), Ext2Error> {
+        let mut data = match self.indirect_cache.take() {
+            Some((cached, data)) if cached == block => data,
+            _ => {
+                let mut data = vec![0; self.block_size()];
+                self.read_block(block, &mut data)?;
+                data
+            }
+        };
+        data[(index * 4)..(index * 4 + 4)].copy_from_slice(&value.to_le_bytes());
+        self.write_block(block, &data)?;
+        self.indirect_cache = Some((block, data));
+        Ok(())
+    }
+
+    /// Free all of a file's blocks, including its indirect blocks.
+    fn free_blocks(&mut self, inode: &mut Inode) -> Result<(), Ext2Error> {
+        // Fast symlinks hold their targets where the block numbers would be
+        if inode.file_type() == FileType::Symlink && self.is_fast_symlink(inode) {
+            inode.blocks = [0; NUM_BLOCK_POINTERS];
+            return Ok(());
+        }
+
+        for i in 0..NUM_BLOCK_POINTERS {
+            let depth = match i {
+                INDIRECT_BLOCK => 1,
+                DOUBLY_INDIRECT_BLOCK => 2,
+                TRIPLY_INDIRECT_BLOCK => 3,
+                _ => 0,
+            };
+            if inode.blocks[i] != 0 {
+                self.free_block_tree(inode.blocks[i], depth)?;
+                inode.blocks[i] = 0;
+            }
+        }
+        inode.sectors = if inode.file_acl != 0 { self.sectors_per_block() } else { 0 };
+        Ok(())
+    }
+
+    /// Free a block, and if it's an indirect block (`depth` is the number of levels of blocks below it), every
+    /// block it points to.
+    fn free_block_tree(&mut self, block: u32, depth: usize) -> Result<(), Ext2Error> {
+        if depth > 0 {
+            let mut data = vec![0; self.block_size()];
+            self.read_block(block, &mut data)?;
+            for entry in data.chunks_exact(4) {
+                let entry = u32::from_le_bytes(entry.try_into().unwrap());
+                if entry != 0 {
+                    self.free_block_tree(entry, depth - 1)?;
+                }
+            }
+        }
+        if self.indirect_cache.as_ref().is_some_and(|(cached, _)| *cached == block) {
+            self.indirect_cache = None;
+        }
+        self.free_block(block)
+    }
+
+    /// The number of blocks in a block group. The last group may be smaller than the others.
+    fn blocks_in_group(&self, group: usize) -> u32 {
+        let start = group as u32 * self.superblock.blocks_per_group;
+        u32::min(
+            self.superblock.blocks_per_group,
+            self.superblock.blocks_count - self.superblock.first_data_block - start,
+        )
+    }
+
+    /// Allocate a free block, preferring one in the block group `goal`, to keep a file's blocks near its inode.
+    fn allocate_block(&mut self, goal: usize) -> Result<u32, Ext2Error> {
+        for i in 0..self.groups.len() {
+            let group = (goal + i) % self.groups.len();
+            if self.groups[group].free_blocks_count == 0 {
+                continue;
+            }
+
+            let bitmap = self.groups[group].block_bitmap;
+            if let Some(bit) = self.allocate_bit(bitmap, self.blocks_in_group(group))? {
+                self.groups[group].free_blocks_count -= 1;
+                self.superblock.free_blocks_count = self.superblock.free_blocks_count.saturating_sub(1);
+                self.write_group_descriptor(group)?;
+                return Ok(self.superblock.first_data_block
+                    + group as u32 * self.superblock.blocks_per_group
+                    + bit);
+            }
+        }
+
+        Err(Ext2Error::NoSpace)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), Ext2Error> {
+        if block < self.superblock.first_data_block || block >= self.superblock.blocks_count {
+            return Err(Ext2Error::Corrupt);
+        }
+        let index = block - self.superblock.first_data_block;
+        let group = (index / self.superblock.blocks_per_group) as usize;
+
+        self.clear_bit(self.groups[group].block_bitmap, index % self.superblock.blocks_per_group)?;
+        self.groups[group].free_blocks_count += 1;
+        self.superblock.free_blocks_count += 1;
+        self.write_group_descriptor(group)
+    }
+
+    /// Allocate a free inode, preferring one in the block group `goal`. Reserved inodes are never allocated.
+    fn allocate_inode(&mut self, goal: usize, is_dir: bool) -> Result<u32, Ext2Error> {
+        for i in 0..self.groups.len() {
+            let group = (goal + i) % self.groups.len();
+            if self.groups[group].free_inodes_count == 0 {
+                continue;
+            }
+
+            let first = group as u32 * self.superblock.inodes_per_group + 1;
+            let count = u32::min(self.superblock.inodes_per_group, self.superblock.inodes_count + 1 - first);
+            let bitmap = self.groups[group].inode_bitmap;
+            let reserved = self.superblock.first_inode.saturating_sub(first);
+            if let Some(bit) = self.allocate_bit_from(bitmap, reserved, count)? {
+                self.groups[group].free_inodes_count -= 1;
+                if is_dir {
+                    self.groups[group].used_dirs_count += 1;
+                }
+                self.superblock.free_inodes_count = self.superblock.free_inodes_count.saturating_sub(1);
+                self.write_group_descriptor(group)?;
+
+                // The inode may have been used before, so we clear out anything it was left holding
+                let number = first + bit;
+                let (block, offset) = self.inode_location(number)?;
+                let mut data = vec![0; self.block_size()];
+                self.read_block(block, &mut data)?;
+                data[offset..(offset + self.superblock.inode_size)].fill(0);
+                self.write_block(block, &data)?;
+                return Ok(number);
+            }
+        }
+
+        Err(Ext2Error::NoSpace)
+    }
+
+    fn free_inode(&mut self, number: u32, is_dir: bool) -> Result<(), Ext2Error> {
+        let group = self.group_of_inode(number);
+        self.clear_bit(self.groups[group].inode_bitmap, (number - 1) % self.superblock.inodes_per_group)?;
+        self.groups[group].free_inodes_count += 1;
+        if is_dir {
+            self.groups[group].used_dirs_count -= 1;
+        }
+        self.superblock.free_inodes_count += 1;
+        self.write_group_descriptor(group)
+    }
+
+    fn allocate_bit(&mut self, bitmap: u32, count: u32) -> Result<Option<u32>, Ext2Error> {
+        self.allocate_bit_from(bitmap, 0, count)
+    }
+
+    /// Find a clear bit in the first `count` bits of a bitmap, skipping the first `start` bits, and set it.
+    fn allocate_bit_from(&mut self, bitmap: u32, start: u32, count: u32) -> Result<Option<u32>, Ext2Error> {
+        let mut data = vec![0; self.block_size()];
+        self.read_block(bitmap, &mut data)?;
+        let Some(bit) = (start..count).find(|&bit| data[bit as usize / 8] & (1 << (bit % 8)) == 0) else {
+            return Ok(None);
+        };
+        data[bit as usize / 8] |= 1 << (bit % 8);
+        self.write_block(bitmap, &data)?;
+        Ok(Some(bit))
+    }
+
+    fn clear_bit(&mut self, bitmap: u32, bit: u32) -> Result<(), Ext2Error> {
+        let mut data = vec![0; self.block_size()];
+        self.read_block(bitmap, &mut data)?;
+        if data[bit as usize / 8] & (1 << (bit % 8)) == 0 {
+            return Err(Ext2Error::Corrupt);
+        }
+        data[bit as usize / 8] &= !(1 << (bit % 8));
+        self.write_block(bitmap, &data)
+    }
+
+    /// Write a group descriptor back to the table. The free counts in the superblock change along with those in
+    /// the group descriptors, so it's marked as needing writing back too.
+    fn write_group_descriptor(&mut self, group: usize) -> Result<(), Ext2Error> {
+        let descriptors_per_block = self.block_size() / GROUP_DESCRIPTOR_SIZE;
+        let block = self.group_table_block + (group / descriptors_per_block) as u32;
+        let offset = (group % descriptors_per_block) * GROUP_DESCRIPTOR_SIZE;
+
+        let mut data = vec![0; self.block_size()];
+        self.read_block(block, &mut data)?;
+        self.groups[group].update(&mut data[offset..(offset + GROUP_DESCRIPTOR_SIZE)]);
+        self.write_block(block, &data)?;
+        self.superblock_dirty = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{string::String, vec::Vec};
+
+    struct RamDisk(Vec<u8>);
+
+    impl BlockDevice for RamDisk {
+        fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+            let start = sector as usize * SECTOR_SIZE;
+            buffer.copy_from_slice(self.0.get(start..(start + buffer.len())).ok_or(Ext2Error::Io)?);
+            Ok(())
+        }
+
+        fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), Ext2Error> {
+            let start = sector as usize * SECTOR_SIZE;
+            self.0.get_mut(start..(start + data.len())).ok_or(Ext2Error::Io)?.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Ext2Error> {
+            Ok(())
+        }
+    }
+
+    const BLOCK_SIZE: usize = 1024;
+    const INODES: u32 = 64;
+    const INODE_SIZE: usize = 128;
+    /// The blocks used by an empty filesystem: the boot block, superblock, group descriptors, the two bitmaps,
+    /// the inode table, and the root directory. All but the boot block are in the first block group.
+    const USED_BLOCKS: u32 = 5 + (INODES as usize * INODE_SIZE / BLOCK_SIZE) as u32 + 1;
+
+    /// Create an empty ext2 filesystem with 1KiB blocks and a single block group, the way `mke2fs` would
+    /// (without a `lost+found` directory).
+    fn format(num_blocks: u32) -> RamDisk {
+        let mut disk = vec![0u8; num_blocks as usize * BLOCK_SIZE];
+        let u16_at = |disk: &mut Vec<u8>, offset: usize, value: u16| {
+            disk[offset..(offset + 2)].copy_from_slice(&value.to_le_bytes())
+        };
+        let u32_at = |disk: &mut Vec<u8>, offset: usize, value: u32| {
+            disk[offset..(offset + 4)].copy_from_slice(&value.to_le_bytes())
+        };
+        let inode_table = 5;
+        let root_block = USED_BLOCKS - 1;
+
+        let superblock = SUPERBLOCK_OFFSET;
+        u32_at(&mut disk, superblock, INODES);
+        u32_at(&mut disk, superblock + 4, num_blocks);
+        u32_at(&mut disk, superblock + 12, num_blocks - USED_BLOCKS);
+        u32_at(&mut disk, superblock + 16, INODES - 10);
+        u32_at(&mut disk, superblock + 20, 1);
+        u32_at(&mut disk, superblock + 32, 8192);
+        u32_at(&mut disk, superblock + 40, INODES);
+        u16_at(&mut disk, superblock + 56, 0xef53);
+        u16_at(&mut disk, superblock + 58, 1);
+        u32_at(&mut disk, superblock + 76, 1);
+        u32_at(&mut disk, superblock + 84, 11);
+        u16_at(&mut disk, superblock + 88, INODE_SIZE as u16);
+        u32_at(&mut disk, superblock + 96, superblock::INCOMPAT_FILETYPE);
+        u32_at(&mut disk, superblock + 100, superblock::RO_COMPAT_SPARSE_SUPER);
+
+        let group = 2 * BLOCK_SIZE;
+        u32_at(&mut disk, group, 3);
+        u32_at(&mut disk, group + 4, 4);
+        u32_at(&mut disk, group + 8, inode_table);
+        u16_at(&mut disk, group + 12, (num_blocks - USED_BLOCKS) as u16);
+        u16_at(&mut disk, group + 14, (INODES - 10) as u16);
+        u16_at(&mut disk, group + 16, 1);
+
+        // Blocks past the end of the filesystem are marked as used, so they're never allocated
+        let block_bitmap = 3 * BLOCK_SIZE;
+        for bit in (0..(USED_BLOCKS - 1)).chain((num_blocks - 1)..(BLOCK_SIZE as u32 * 8)) {
+            disk[block_bitmap + bit as usize / 8] |= 1 << (bit % 8);
+        }
+        let inode_bitmap = 4 * BLOCK_SIZE;
+        for bit in (0..10).chain(INODES..(BLOCK_SIZE as u32 * 8)) {
+            disk[inode_bitmap + bit as usize / 8] |= 1 << (bit % 8);
+        }
+
+        let root = inode_table as usize * BLOCK_SIZE + (ROOT_INODE as usize - 1) * INODE_SIZE;
+        u16_at(&mut disk, root, MODE_DIRECTORY | 0o755);
+        u32_at(&mut disk, root + 4, BLOCK_SIZE as u32);
+        u16_at(&mut disk, root + 26, 2);
+        u32_at(&mut disk, root + 28, (BLOCK_SIZE / SECTOR_SIZE) as u32);
+        u32_at(&mut disk, root + 40, root_block);
+        let root_dir = &mut disk[(root_block as usize * BLOCK_SIZE)..((root_block as usize + 1) * BLOCK_SIZE)];
+        dir::write_entry(root_dir, 0, ROOT_INODE, 12, b".", Some(FileType::Directory));
+        dir::write_entry(root_dir, 12, ROOT_INODE, BLOCK_SIZE - 12, b"..", Some(FileType::Directory));
+
+        RamDisk(disk)
+    }
+
+    fn names(entries: &[DirEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn empty_filesystem() {
+        let mut fs = FileSystem::mount(format(1024), true).unwrap();
+        let root = fs.root().unwrap();
+        assert!(root.is_dir());
+        assert_eq!(root.permissions(), 0o755);
+        assert!(fs.read_dir(&root).unwrap().is_empty());
+        assert_eq!(fs.lookup(&["missing"]).unwrap_err(), Ext2Error::NotFound);
+        assert_eq!(FileSystem::mount(RamDisk(vec![0; 4096]), true).err().unwrap(), Ext2Error::Unsupported);
+    }
+
+    #[test]
+    fn create_and_read_back() {
+        let mut fs = FileSystem::mount(format(1024), true).unwrap();
+        let root = fs.root().unwrap();
+        let mut file = fs.create_file(&root, "a file").unwrap();
+        assert_eq!(file.permissions(), 0o644);
+
+        // This is long enough to need an indirect block
+        let contents: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        fs.write(&mut file, 0, &contents).unwrap();
+
+        let file = fs.lookup(&["a file"]).unwrap();
+        assert_eq!(file.size, 20000);
+        let mut buffer = vec![0; 30000];
+        assert_eq!(fs.read(&file, 0, &mut buffer).unwrap(), 20000);
+        assert_eq!(&buffer[0..20000], &contents[..]);
+        assert_eq!(fs.read(&file, 15000, &mut buffer[0..100]).unwrap(), 100);
+        assert_eq!(&buffer[0..100], &contents[15000..15100]);
+
+        // Names are case-sensitive
+        assert_eq!(fs.lookup(&["A FILE"]).unwrap_err(), Ext2Error::NotFound);
+        assert_eq!(fs.create_file(&root, "a file").unwrap_err(), Ext2Error::AlreadyExists);
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = FileSystem::mount(format(1024), true).unwrap();
+        let root = fs.root().unwrap();
+        let etc = fs.create_dir(&root, "etc").unwrap();
+        let conf = fs.create_dir(&etc, "conf.d").unwrap();
+        let mut file = fs.create_file(&conf, "network").unwrap();
+        fs.write(&mut file, 0, b"hello").unwrap();
+
+        assert_eq!(names(&fs.read_dir(&root).unwrap()), ["etc"]);
+        assert_eq!(names(&fs.read_dir(&etc).unwrap()), ["conf.d"]);
+        assert_eq!(fs.read_dir(&etc).unwrap()[0].file_type, FileType::Directory);
+        let file = fs.lookup(&["etc", "conf.d", "network"]).unwrap();
+        assert_eq!(file.size, 5);
+        assert_eq!(fs.lookup(&["etc", "conf.d", "..", "conf.d", "."]).unwrap().number, conf.number);
+        assert_eq!(fs.lookup(&["etc", "conf.d", "network", "foo"]).unwrap_err(), Ext2Error::NotADirectory);
+        assert_eq!(fs.read_dir(&file).unwrap_err(), Ext2Error::NotADirectory);
+
+        // Each subdirectory's `..` entry is a link to its parent
+        assert_eq!(fs.root().unwrap().links_count, 3);
+        assert_eq!(fs.lookup(&["etc"]).unwrap().links_count, 3);
+        assert_eq!(fs.groups[0].used_dirs_count, 3);
+    }
+
+    #[test]
+    fn directory_grows() {
+        let mut fs = FileSystem::mount(format(1024), true).unwrap();
+        let root = fs.root().unwrap();
+        let expected: Vec<String> =
+            (0..(INODES - 10)).map(|i| std::format!("a file with a long name, number {}", i)).collect();
+        for name in &expected {
+            fs.create_file(&root, name).unwrap();
+        }
+        let root = fs.root().unwrap();
+        assert!(root.size > BLOCK_SIZE as u64);
+        assert_eq!(names(&fs.read_dir(&root).unwrap()), expected);
+        assert_eq!(fs.create_file(&root, "one more").unwrap_err(), Ext2Error::NoSpace);
+    }
+
+    #[test]
+    fn symlinks() {
+        let mut fs = FileSystem::mount(format(1024), true).unwrap();
+        let root = fs.root().unwrap();
+        let bin = fs.create_dir(&root, "bin").unwrap();
+        let mut file = fs.create_file(&bin, "hello_world").unwrap();
+        fs.write(&mut file, 0, b"\x7fELF").unwrap();
+
+        // Short targets are stored in the inode, and longer ones in a block of their own
+        let long_target = std::format!("{}/../bin/hello_world", ["."; 40].join("/"));
+        fs.create_symlink(&root, "relative", "bin/hello_world").unwrap();
+        fs.create_symlink(&bin, "absolute", "/bin/hello_world").unwrap();
+        let long = fs.create_symlink(&bin, "long", &long_target).unwrap();
+        fs.create_symlink(&root, "usr", "/").unwrap();
+        assert_eq!(fs.read_link(&long).unwrap(), long_target);
+        assert_eq!(long.sectors, 2);
+
+        for path in
+            [&["relative"][..], &["bin", "absolute"], &["bin", "long"], &["usr", "usr", "bin", "hello_world"]]
+        {
+            assert_eq!(fs.lookup(path).unwrap().number, file.number);
+        }
+        let entries = fs.read_dir(&bin).unwrap();
+        assert_eq!(entries[1].file_type, FileType::Symlink);
+
+        fs.create_symlink(&root, "loop", "loop").unwrap();
+        assert_eq!(fs.lookup(&["loop"]).unwrap_err(), Ext2Error::SymlinkLoop);
+        fs.create_symlink(&root, "dangling", "nowhere").unwrap();
+        assert_eq!(fs.lookup(&["dangling"]).unwrap_err(), Ext2Error::NotFound);
+
+        // Without following symlinks, we find out where the first one on the path is
+        match fs.resolve(&["usr", "bin", "absolute"]).unwrap() {
+            Resolved::Symlink { depth, target } => assert_eq!((depth, target.as_str()), (1, "/")),
+            Resolved::Inode(_) => panic!("Symlink was followed"),
+        }
+        match fs.resolve(&["bin", "hello_world"]).unwrap() {
+            Resolved::Inode(inode) => assert_eq!(inode.number, file.number),
+            Resolved::Symlink { .. } => panic!("Found symlink on path without one"),
+        }
+    }
+
+    #[test]
+    fn sparse_files() {
+        let mut fs = FileSystem::mount(format(1024), true).unwrap();
+        let root = fs.root().unwrap();
+        let mut file = fs.create_file(&root, "sparse").unwrap();
+        let free_blocks = fs.superblock.free_blocks_count;
+
+        // This is far enough into the file to need a doubly-indirect block
+        let offset = 1_000_000;
+        fs.write(&mut file, offset, b"end").unwrap();
+        fs.write(&mut file, 0, b"start").unwrap();
+        assert_eq!(free_blocks - fs.superblock.free_blocks_count, 4);
+
+        let file = fs.lookup(&["sparse"]).unwrap();
+        assert_eq!(file.size, offset + 3);
+        let mut buffer = vec![0xff; 2000];
+        fs.read(&file, 0, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..5], b"start");
+        assert!(buffer[5..].iter().all(|&b| b == 0));
+        assert_eq!(fs.read(&file, offset - 2, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[0..5], b"\0\0end");
+    }
+
+    #[test]
+    fn truncate_frees_blocks() {
+        let mut fs = FileSystem::mount(format(256), true).unwrap();
+        let root = fs.root().unwrap();
+        let mut file = fs.create_file(&root, "big").unwrap();
+        let free_blocks = fs.superblock.free_blocks_count;
+        let too_big = free_blocks as usize * BLOCK_SIZE;
+        assert_eq!(fs.write(&mut file, 0, &vec![1; too_big]).unwrap_err(), Ext2Error::NoSpace);
+        assert_eq!(fs.superblock.free_blocks_count, 0);
+
+        fs.truncate(&mut file).unwrap();
+        assert_eq!(fs.superblock.free_blocks_count, free_blocks);
+        assert_eq!(fs.lookup(&["big"]).unwrap().size, 0);
+        fs.write(&mut file, 0, &vec![1; 100 * BLOCK_SIZE]).unwrap();
+        fs.flush().unwrap();
+
+        // Make sure the filesystem still makes sense after being mounted again
+        let mut fs = FileSystem::mount(fs.device, true).unwrap();
+        let file = fs.lookup(&["big"]).unwrap();
+        assert_eq!(file.size as usize, 100 * BLOCK_SIZE);
+        assert_eq!(fs.superblock.free_blocks_count, free_blocks - 101);
+        assert_eq!(fs.groups[0].free_blocks_count as u32, fs.superblock.free_blocks_count);
+    }
+
+    #[test]
+    fn read_only() {
+        let mut fs = FileSystem::mount(format(1024), false).unwrap();
+        let root = fs.root().unwrap();
+        assert_eq!(fs.create_file(&root, "file").unwrap_err(), Ext2Error::ReadOnly);
+
+        // Filesystems with features we can't keep up to date can only be mounted read-only
+        let mut disk = format(1024);
+        disk.0[SUPERBLOCK_OFFSET + 100] |= 0x40;
+        assert!(!FileSystem::mount(disk, true).unwrap().is_writable());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::Ext2Error;
+
+/// The superblock is always `1024` bytes into the filesystem, no matter the block size.
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+pub const SUPERBLOCK_SIZE: usize = 1024;
+pub const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+const EXT2_MAGIC: u16 = 0xef53;
+/// The largest block size we support, which is also the largest that Linux will mount.
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+/// Directory entries hold the type of the file they refer to.
+pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Only some block groups hold backups of the superblock and group descriptors.
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+/// Regular files can be larger than 2GiB, and hold the top half of their size in `i_size_high`.
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+/// Features we don't understand in `s_feature_incompat` change the layout of the filesystem, so we can't mount
+/// it at all.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+/// Features we don't understand in `s_feature_ro_compat` can be ignored when reading, but we can't write to the
+/// filesystem without keeping the structures they add up to date.
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+/// The fields of the superblock that we need to find our way around the filesystem, and those that we change as
+/// blocks and inodes are allocated.
+#[derive(Clone, Debug)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    /// The block that the first block group starts at. This is `1` for filesystems with 1KiB blocks (as the
+    /// superblock takes up block `1`), and `0` otherwise.
+    pub first_data_block: u32,
+    pub block_size: usize,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub rev_level: u32,
+    /// The first inode that isn't reserved, which is `11` for revision `0` filesystems.
+    pub first_inode: u32,
+    pub inode_size: usize,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+}
+
+impl Superblock {
+    pub fn parse(data: &[u8]) -> Result<Superblock, Ext2Error> {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..(offset + 4)].try_into().unwrap());
+
+        if u16_at(56) != EXT2_MAGIC {
+            return Err(Ext2Error::Unsupported);
+        }
+
+        let log_block_size = u32_at(24);
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(Ext2Error::Unsupported);
+        }
+        let block_size = 1024 << log_block_size;
+
+        let rev_level = u32_at(76);
+        let (first_inode, inode_size, feature_incompat, feature_ro_compat) = match rev_level {
+            0 => (11, 128, 0, 0),
+            _ => (u32_at(84), u16_at(88) as usize, u32_at(96), u32_at(100)),
+        };
+        if feature_incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(Ext2Error::Unsupported);
+        }
+
+        let superblock = Superblock {
+            inodes_count: u32_at(0),
+            blocks_count: u32_at(4),
+            free_blocks_count: u32_at(12),
+            free_inodes_count: u32_at(16),
+            first_data_block: u32_at(20),
+            block_size,
+            blocks_per_group: u32_at(32),
+            inodes_per_group: u32_at(40),
+            rev_level,
+            first_inode,
+            inode_size,
+            feature_incompat,
+            feature_ro_compat,
+        };
+
+        // Each block group's bitmaps are a single block
+        let bits_per_block = block_size as u32 * 8;
+        if superblock.blocks_per_group == 0
+            || superblock.blocks_per_group > bits_per_block
+            || superblock.inodes_per_group == 0
+            || superblock.inodes_per_group > bits_per_block
+            || superblock.blocks_count <= superblock.first_data_block
+            || !inode_size.is_power_of_two()
+            || inode_size < 128
+            || inode_size > block_size
+        {
+            return Err(Ext2Error::Corrupt);
+        }
+
+        Ok(superblock)
+    }
+
+    /// Whether we can write to the filesystem without breaking structures we don't know about.
+    pub fn is_writable(&self) -> bool {
+        self.feature_ro_compat & !SUPPORTED_RO_COMPAT == 0
+    }
+
+    pub fn num_groups(&self) -> usize {
+        (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group) as usize
+    }
+
+    /// Update the fields of the superblock that can change while the filesystem is mounted.
+    pub fn update(&self, data: &mut [u8]) {
+        data[12..16].copy_from_slice(&self.free_blocks_count.to_le_bytes());
+        data[16..20].copy_from_slice(&self.free_inodes_count.to_le_bytes());
+        if self.rev_level != 0 {
+            data[100..104].copy_from_slice(&self.feature_ro_compat.to_le_bytes());
+        }
+    }
+}
+
+/// Each block group is described by a group descriptor, which are held in a table in the blocks following the
+/// superblock.
+#[derive(Clone, Debug)]
+pub struct GroupDescriptor {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16,
+}
+
+impl GroupDescriptor {
+    pub fn parse(data: &[u8]) -> GroupDescriptor {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..(offset + 4)].try_into().unwrap());
+
+        GroupDescriptor {
+            block_bitmap: u32_at(0),
+            inode_bitmap: u32_at(4),
+            inode_table: u32_at(8),
+            free_blocks_count: u16_at(12),
+            free_inodes_count: u16_at(14),
+            used_dirs_count: u16_at(16),
+        }
+    }
+
+    /// Update the counts held in an existing group descriptor.
+    pub fn update(&self, data: &mut [u8]) {
+        data[12..14].copy_from_slice(&self.free_blocks_count.to_le_bytes());
+        data[14..16].copy_from_slice(&self.free_inodes_count.to_le_bytes());
+        data[16..18].copy_from_slice(&self.used_dirs_count.to_le_bytes());
+    }
+}
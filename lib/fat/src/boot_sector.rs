@@ -0,0 +1,74 @@
+use crate::{FatError, SECTOR_SIZE};
+
+/// The fields of the BIOS Parameter Block (BPB) in the first sector of a FAT32 volume that we need to find our
+/// way around the volume.
+#[derive(Clone, Debug)]
+pub struct BootSector {
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub num_fats: u32,
+    pub total_sectors: u32,
+    /// The size of each FAT, in sectors.
+    pub fat_size: u32,
+    pub root_cluster: u32,
+    pub fs_info_sector: Option<u32>,
+}
+
+impl BootSector {
+    pub fn parse(sector: &[u8; SECTOR_SIZE]) -> Result<BootSector, FatError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(sector[offset..(offset + 4)].try_into().unwrap());
+
+        if sector[510] != 0x55 || sector[511] != 0xaa {
+            return Err(FatError::Unsupported);
+        }
+
+        // TODO: support volumes with larger sectors
+        let bytes_per_sector = u16_at(11);
+        if bytes_per_sector as usize != SECTOR_SIZE {
+            return Err(FatError::Unsupported);
+        }
+
+        let sectors_per_cluster = sector[13] as u32;
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err(FatError::Corrupt);
+        }
+
+        /*
+         * The FAT type of a volume is strictly determined by its number of clusters, but we identify
+         * FAT32 volumes by the parts of their BPB that differ from FAT12/16 volumes instead. This lets
+         * us use very small FAT32 volumes, which are useful for testing.
+         */
+        let root_entry_count = u16_at(17);
+        let fat_size_16 = u16_at(22);
+        let fat_size = u32_at(36);
+        if root_entry_count != 0 || fat_size_16 != 0 || fat_size == 0 {
+            return Err(FatError::Unsupported);
+        }
+
+        let num_fats = sector[16] as u32;
+        if num_fats == 0 {
+            return Err(FatError::Corrupt);
+        }
+
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            total_sectors => total_sectors as u32,
+        };
+
+        let fs_info_sector = match u16_at(48) {
+            0 | 0xffff => None,
+            sector => Some(sector as u32),
+        };
+
+        Ok(BootSector {
+            sectors_per_cluster,
+            reserved_sectors: u16_at(14) as u32,
+            num_fats,
+            total_sectors,
+            fat_size,
+            root_cluster: u32_at(44),
+            fs_info_sector,
+        })
+    }
+}
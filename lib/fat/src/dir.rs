@@ -0,0 +1,407 @@
+//! Directories on a FAT volume are arrays of 32-byte entries. Each file has a "short" entry, which holds its
+//! 8.3 name, attributes, first cluster, and size. Files with names that can't be represented as 8.3 names also
+//! have a series of "long file name" (LFN) entries directly before their short entry, which each hold 13
+//! UTF-16 code units of the file's full name.
+
+use crate::SECTOR_SIZE;
+use alloc::{format, string::String, vec::Vec};
+
+pub const ENTRY_SIZE: usize = 32;
+
+/// Marks the end of a directory. All following entries are also free.
+pub const END_OF_DIRECTORY: u8 = 0x00;
+/// Marks an entry that has been deleted, and so is free.
+pub const DELETED_ENTRY: u8 = 0xe5;
+
+const LFN_CHARS_PER_ENTRY: usize = 13;
+const LFN_LAST_ENTRY: u8 = 0x40;
+const LFN_MAX_LENGTH: usize = 255;
+
+/// Set in the reserved byte of a short entry if its base name should be displayed in lowercase.
+const LOWERCASE_BASE: u8 = 0x08;
+/// Set in the reserved byte of a short entry if its extension should be displayed in lowercase.
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// FAT dates count from 1980. As we don't have a source of time, all files are created on the 1st January
+/// 1980.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Attributes(pub u8);
+
+impl Attributes {
+    pub const READ_ONLY: Attributes = Attributes(0x01);
+    pub const HIDDEN: Attributes = Attributes(0x02);
+    pub const SYSTEM: Attributes = Attributes(0x04);
+    pub const VOLUME_ID: Attributes = Attributes(0x08);
+    pub const DIRECTORY: Attributes = Attributes(0x10);
+    pub const ARCHIVE: Attributes = Attributes(0x20);
+    /// The combination of attributes that marks a long file name entry.
+    pub const LONG_NAME: Attributes = Attributes(0x0f);
+
+    pub fn contains(self, other: Attributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// The location of an entry on the volume.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EntryLocation {
+    pub sector: u64,
+    pub offset: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub attributes: Attributes,
+    pub first_cluster: u32,
+    pub size: u32,
+    pub(crate) short_name: [u8; 11],
+    /// The location of this file's short entry. This is `None` for the root directory, which doesn't have an
+    /// entry.
+    pub(crate) location: Option<EntryLocation>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    /// Whether this entry and `other` refer to the same file on the volume.
+    pub fn is_same_file(&self, other: &DirEntry) -> bool {
+        self.location == other.location
+    }
+
+    /// A number that identifies this file on the volume, which is the byte offset of its short entry. This is `0`
+    /// for the root directory.
+    pub fn inode(&self) -> u64 {
+        self.location.map_or(0, |location| location.sector * SECTOR_SIZE as u64 + location.offset as u64)
+    }
+
+    /// Whether this entry is called `name`. Names on FAT volumes are case-insensitive, and files can also be
+    /// referred to by their short names.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || display_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+
+    pub(crate) fn parse_short(entry: &[u8], location: EntryLocation) -> DirEntry {
+        let first_cluster = (u32::from(u16::from_le_bytes([entry[20], entry[21]])) << 16)
+            | u32::from(u16::from_le_bytes([entry[26], entry[27]]));
+        let short_name: [u8; 11] = entry[0..11].try_into().unwrap();
+
+        DirEntry {
+            name: display_short_name(&short_name, entry[12]),
+            attributes: Attributes(entry[11]),
+            first_cluster,
+            size: u32::from_le_bytes(entry[28..32].try_into().unwrap()),
+            short_name,
+            location: Some(location),
+        }
+    }
+
+    /// Update the fields of an existing short entry that can change as the file is written to.
+    pub(crate) fn update_short(&self, entry: &mut [u8]) {
+        entry[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&self.size.to_le_bytes());
+    }
+}
+
+/// Create a new short entry.
+pub fn short_entry(short_name: &[u8; 11], attributes: Attributes, first_cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0..11].copy_from_slice(short_name);
+    entry[11] = attributes.0;
+    entry[16..18].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    entry[18..20].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    entry[24..26].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    entry
+}
+
+/// Turn an 11-byte short name into the form it's usually displayed in (e.g. `FOO     TXT` becomes `FOO.TXT`).
+fn display_short_name(short_name: &[u8; 11], flags: u8) -> String {
+    let mut base = [0u8; 8];
+    base.copy_from_slice(&short_name[0..8]);
+    // `0xe5` marks a deleted entry, so a name that really starts with it is stored with `0x05` instead
+    if base[0] == 0x05 {
+        base[0] = DELETED_ENTRY;
+    }
+    if flags & LOWERCASE_BASE != 0 {
+        base.make_ascii_lowercase();
+    }
+    let mut extension = [0u8; 3];
+    extension.copy_from_slice(&short_name[8..11]);
+    if flags & LOWERCASE_EXTENSION != 0 {
+        extension.make_ascii_lowercase();
+    }
+
+    /*
+     * Short names are nominally in an OEM code page, which we don't know. ASCII covers the vast
+     * majority of names in practice, so we replace anything else.
+     */
+    let mut name: String =
+        trim_padding(&base).iter().map(|&c| if c.is_ascii() { c as char } else { '?' }).collect();
+    let extension = trim_padding(&extension);
+    if !extension.is_empty() {
+        name.push('.');
+        name.extend(extension.iter().map(|&c| if c.is_ascii() { c as char } else { '?' }));
+    }
+    name
+}
+
+fn trim_padding(bytes: &[u8]) -> &[u8] {
+    let length = bytes.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+    &bytes[0..length]
+}
+
+/// The checksum of a short name, which is stored in each of the LFN entries belonging to it. This is used to
+/// detect LFN entries that have been orphaned by software that doesn't understand them.
+pub fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+/// Collects the LFN entries preceding a short entry, to reconstruct the long name of a file.
+#[derive(Default)]
+pub struct LongNameBuilder {
+    chars: Vec<u16>,
+    checksum: u8,
+    /// The sequence number of the next LFN entry we expect. LFN entries are stored in reverse, so this counts
+    /// down to `1`. This is `0` if we're not part-way through a valid long name.
+    next: u8,
+}
+
+impl LongNameBuilder {
+    pub fn reset(&mut self) {
+        self.chars.clear();
+        self.next = 0;
+    }
+
+    pub fn push(&mut self, entry: &[u8]) {
+        let sequence = entry[0] & !LFN_LAST_ENTRY;
+        if entry[0] & LFN_LAST_ENTRY != 0 {
+            if sequence == 0 || sequence as usize * LFN_CHARS_PER_ENTRY > LFN_MAX_LENGTH + LFN_CHARS_PER_ENTRY {
+                self.reset();
+                return;
+            }
+            self.chars.clear();
+            self.chars.resize(sequence as usize * LFN_CHARS_PER_ENTRY, 0xffff);
+            self.checksum = entry[13];
+            self.next = sequence;
+        }
+
+        if self.next == 0 || sequence != self.next || entry[13] != self.checksum {
+            self.reset();
+            return;
+        }
+
+        let start = (sequence as usize - 1) * LFN_CHARS_PER_ENTRY;
+        for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = u16::from_le_bytes([entry[*offset], entry[*offset + 1]]);
+        }
+        self.next -= 1;
+    }
+
+    /// Finish the long name, given the short name of the entry following the LFN entries. Returns `None` if
+    /// the LFN entries didn't form a valid long name for that entry.
+    pub fn finish(&mut self, short_name: &[u8; 11]) -> Option<String> {
+        /*
+         * Once all the entries have been seen, `next` has counted down to `0`, but it is also `0` if
+         * we've not seen any. We distinguish the two by whether we've started a name at all.
+         */
+        let complete = self.next == 0 && !self.chars.is_empty();
+        let chars = core::mem::take(&mut self.chars);
+        if !complete || self.checksum != short_name_checksum(short_name) {
+            return None;
+        }
+
+        let length = chars.iter().position(|&c| c == 0x0000).unwrap_or(chars.len());
+        char::decode_utf16(chars[0..length].iter().copied()).collect::<Result<String, _>>().ok()
+    }
+}
+
+/// The byte offsets of each of the 13 characters held in an LFN entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Create the LFN entries for `name`, in the order they should appear in the directory.
+pub fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    // The name is terminated by a null, unless it exactly fills the entries, and then padded with `0xffff`
+    if !chars.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        chars.push(0x0000);
+    }
+    while !chars.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        chars.push(0xffff);
+    }
+
+    let checksum = short_name_checksum(short_name);
+    let num_entries = chars.len() / LFN_CHARS_PER_ENTRY;
+    (1..=num_entries)
+        .rev()
+        .map(|sequence| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = if sequence == num_entries { sequence as u8 | LFN_LAST_ENTRY } else { sequence as u8 };
+            entry[11] = Attributes::LONG_NAME.0;
+            entry[13] = checksum;
+            let start = (sequence - 1) * LFN_CHARS_PER_ENTRY;
+            for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                entry[*offset..(*offset + 2)].copy_from_slice(&chars[start + i].to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Check that `name` can be used as the name of a new file.
+pub fn is_valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= LFN_MAX_LENGTH
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+fn is_valid_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// If `name` is a valid 8.3 name, return it in the form it's stored in a short entry. Names that can be stored
+/// like this don't need LFN entries.
+pub fn as_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = match name.split_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (name, ""),
+    };
+    if base.is_empty()
+        || base.len() > 8
+        || extension.len() > 3
+        || !base.bytes().chain(extension.bytes()).all(is_valid_short_char)
+    {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[0..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..(8 + extension.len())].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+/// Generate a unique short name for a file with the long name `name`, in the style of `LONGNA~1.TXT`.
+/// `exists` is used to check whether a short name is already used in the directory.
+pub fn generate_short_name(name: &str, exists: impl Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+    fn convert(part: &str) -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && is_valid_short_char(c as u8) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    }
+
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) => (convert(base), convert(extension)),
+        None => (convert(name), Vec::new()),
+    };
+    let base = if base.is_empty() { Vec::from(*b"_") } else { base };
+
+    let mut short_name = [b' '; 11];
+    let extension_length = usize::min(extension.len(), 3);
+    short_name[8..(8 + extension_length)].copy_from_slice(&extension[0..extension_length]);
+
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{}", n);
+        let base_length = usize::min(base.len(), 8 - tail.len());
+        short_name[0..8].fill(b' ');
+        short_name[0..base_length].copy_from_slice(&base[0..base_length]);
+        short_name[base_length..(base_length + tail.len())].copy_from_slice(tail.as_bytes());
+
+        if !exists(&short_name) {
+            return Some(short_name);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_names() {
+        assert_eq!(as_short_name("README.TXT"), Some(*b"README  TXT"));
+        assert_eq!(as_short_name("KERNEL"), Some(*b"KERNEL     "));
+        assert_eq!(as_short_name("readme.txt"), None);
+        assert_eq!(as_short_name("TOOLONGNAME.TXT"), None);
+        assert_eq!(as_short_name("A.B.C"), None);
+
+        assert_eq!(display_short_name(b"README  TXT", 0), "README.TXT");
+        assert_eq!(display_short_name(b"README  TXT", LOWERCASE_BASE | LOWERCASE_EXTENSION), "readme.txt");
+        assert_eq!(display_short_name(b"EFI        ", 0), "EFI");
+    }
+
+    #[test]
+    fn generated_short_names() {
+        assert_eq!(generate_short_name("readme.txt", |_| false), Some(*b"README~1TXT"));
+        assert_eq!(generate_short_name("A long name.text", |_| false), Some(*b"ALONGN~1TEX"));
+        assert_eq!(generate_short_name(".config", |_| false), Some(*b"CONFIG~1   "));
+        assert_eq!(generate_short_name("naïve", |_| false), Some(*b"NA_VE~1    "));
+        assert_eq!(generate_short_name("readme.txt", |name| name == b"README~1TXT"), Some(*b"README~2TXT"));
+        assert_eq!(
+            generate_short_name("readme.txt", |name| name[6] != b'1' || name[7] != b'0'),
+            Some(*b"READM~10TXT")
+        );
+    }
+
+    #[test]
+    fn long_names() {
+        for name in ["a", "exactly13char", "A much longer name, with ünïcödé.txt"] {
+            let short_name = generate_short_name(name, |_| false).unwrap();
+            let mut builder = LongNameBuilder::default();
+            for entry in long_name_entries(name, &short_name) {
+                builder.push(&entry);
+            }
+            assert_eq!(builder.finish(&short_name).as_deref(), Some(name));
+        }
+    }
+
+    #[test]
+    fn orphaned_long_names() {
+        let entries = long_name_entries("a much longer name", b"AMUCHL~1   ");
+
+        // The checksum doesn't match the short entry
+        let mut builder = LongNameBuilder::default();
+        for entry in &entries {
+            builder.push(entry);
+        }
+        assert_eq!(builder.finish(b"SOMETHNGELS"), None);
+
+        // An entry is missing
+        let mut builder = LongNameBuilder::default();
+        builder.push(&entries[0]);
+        assert_eq!(builder.finish(b"AMUCHL~1   "), None);
+
+        // The entries are out of order
+        let mut builder = LongNameBuilder::default();
+        builder.push(&entries[1]);
+        builder.push(&entries[0]);
+        assert_eq!(builder.finish(b"AMUCHL~1   "), None);
+    }
+
+    #[test]
+    fn long_name_validity() {
+        assert!(is_valid_long_name("hello world.txt"));
+        assert!(!is_valid_long_name(""));
+        assert!(!is_valid_long_name(".."));
+        assert!(!is_valid_long_name("what?"));
+        assert!(!is_valid_long_name("a/b"));
+    }
+}
@@ -0,0 +1,741 @@
+//! A `no_std` driver for FAT32 filesystems, supporting long file names. The volume is accessed through a
+//! `BlockDevice`, which makes it usable from both bootloaders and userspace.
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(test)]
+extern crate std;
+
+mod boot_sector;
+mod dir;
+
+pub use dir::{Attributes, DirEntry};
+
+use alloc::{vec, vec::Vec};
+use boot_sector::BootSector;
+use dir::{EntryLocation, LongNameBuilder, DELETED_ENTRY, END_OF_DIRECTORY, ENTRY_SIZE};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Each entry in the FAT is 28 bits. The top 4 bits are reserved, and must be preserved when an entry is
+/// changed.
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FREE_CLUSTER: u32 = 0;
+/// Values of FAT entries at or above this mark the end of a cluster chain.
+const END_OF_CHAIN_MIN: u32 = 0x0fff_fff8;
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// Written as the free cluster count in the FSInfo sector when we don't know it.
+const UNKNOWN_FREE_COUNT: u32 = 0xffff_ffff;
+
+/// The largest file that can be stored on a FAT volume, as the size is held in a 32-bit field.
+pub const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+/// A device that a FAT volume is stored on. Sector `0` should be the first sector of the volume.
+pub trait BlockDevice {
+    /// Read whole sectors, starting at `sector`. The length of `buffer` is a multiple of `SECTOR_SIZE`.
+    fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), FatError>;
+    /// Write whole sectors, starting at `sector`. The length of `data` is a multiple of `SECTOR_SIZE`.
+    fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), FatError>;
+    /// Make sure all previous writes have reached persistent storage.
+    fn flush(&mut self) -> Result<(), FatError>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FatError {
+    /// The underlying device failed to read or write a sector.
+    Io,
+    /// The volume is not a FAT32 volume, or uses features we don't support.
+    Unsupported,
+    /// The structures on the volume are not consistent.
+    Corrupt,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    InvalidName,
+    /// There are no free clusters left, or the file would exceed `MAX_FILE_SIZE`.
+    NoSpace,
+}
+
+pub struct FileSystem<D> {
+    device: D,
+    fat_start: u64,
+    fat_size: u64,
+    num_fats: u32,
+    data_start: u64,
+    sectors_per_cluster: u32,
+    /// The number of clusters in the data region. Valid cluster numbers are `2..(num_clusters + 2)`.
+    num_clusters: u32,
+    root_cluster: u32,
+    fs_info_sector: Option<u64>,
+    /// Where to start looking for a free cluster the next time one is allocated.
+    next_free: u32,
+    /// Set when we've changed which clusters are allocated, and so need to update the FSInfo sector.
+    fs_info_dirty: bool,
+    /// The most recently accessed sector of the FAT, which avoids reading it again when following chains.
+    fat_cache: Option<(u64, [u8; SECTOR_SIZE])>,
+}
+
+impl<D> FileSystem<D>
+where
+    D: BlockDevice,
+{
+    pub fn mount(mut device: D) -> Result<FileSystem<D>, FatError> {
+        let mut sector = [0; SECTOR_SIZE];
+        device.read(0, &mut sector)?;
+        let boot_sector = BootSector::parse(&sector)?;
+
+        let fat_start = boot_sector.reserved_sectors as u64;
+        let data_start = fat_start + boot_sector.num_fats as u64 * boot_sector.fat_size as u64;
+        if boot_sector.total_sectors as u64 <= data_start {
+            return Err(FatError::Corrupt);
+        }
+        let num_clusters = {
+            let in_data_region =
+                (boot_sector.total_sectors as u64 - data_start) / boot_sector.sectors_per_cluster as u64;
+            let in_fat = (boot_sector.fat_size as u64 * SECTOR_SIZE as u64 / 4) - 2;
+            u64::min(in_data_region, in_fat) as u32
+        };
+        if boot_sector.root_cluster < 2 || boot_sector.root_cluster >= num_clusters + 2 {
+            return Err(FatError::Corrupt);
+        }
+
+        let mut fs = FileSystem {
+            device,
+            fat_start,
+            fat_size: boot_sector.fat_size as u64,
+            num_fats: boot_sector.num_fats,
+            data_start,
+            sectors_per_cluster: boot_sector.sectors_per_cluster,
+            num_clusters,
+            root_cluster: boot_sector.root_cluster,
+            fs_info_sector: boot_sector.fs_info_sector.map(u64::from),
+            next_free: 2,
+            fs_info_dirty: false,
+            fat_cache: None,
+        };
+
+        // The FSInfo sector can tell us where free clusters are likely to be, but it is only a hint
+        if let Some(fs_info) = fs.read_fs_info()? {
+            let next_free = u32::from_le_bytes(fs_info[492..496].try_into().unwrap());
+            if next_free >= 2 && next_free < num_clusters + 2 {
+                fs.next_free = next_free;
+            }
+        }
+
+        Ok(fs)
+    }
+
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: alloc::string::String::new(),
+            attributes: Attributes::DIRECTORY,
+            first_cluster: self.root_cluster,
+            size: 0,
+            short_name: [b' '; 11],
+            location: None,
+        }
+    }
+
+    /// Find the entry at `path`, starting at the root directory.
+    pub fn lookup(&mut self, path: &[&str]) -> Result<DirEntry, FatError> {
+        let mut entry = self.root();
+        for component in path {
+            if !entry.is_dir() {
+                return Err(FatError::NotADirectory);
+            }
+            entry = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|entry| entry.matches(component))
+                .ok_or(FatError::NotFound)?;
+        }
+        Ok(entry)
+    }
+
+    /// List the entries of a directory. The `.` and `..` entries, and volume labels, are not included.
+    pub fn read_dir(&mut self, dir: &DirEntry) -> Result<Vec<DirEntry>, FatError> {
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+
+        let mut entries = Vec::new();
+        let mut long_name = LongNameBuilder::default();
+        let mut sector_data = [0; SECTOR_SIZE];
+        for sector in self.dir_sectors(dir)? {
+            self.device.read(sector, &mut sector_data)?;
+            for (i, entry) in sector_data.chunks_exact(ENTRY_SIZE).enumerate() {
+                match entry[0] {
+                    END_OF_DIRECTORY => return Ok(entries),
+                    DELETED_ENTRY => long_name.reset(),
+                    _ if Attributes(entry[11] & 0x3f) == Attributes::LONG_NAME => long_name.push(entry),
+                    _ if Attributes(entry[11]).contains(Attributes::VOLUME_ID) => long_name.reset(),
+                    _ if entry[0] == b'.' => long_name.reset(),
+                    _ => {
+                        let mut dir_entry =
+                            DirEntry::parse_short(entry, EntryLocation { sector, offset: i * ENTRY_SIZE });
+                        if let Some(name) = long_name.finish(&dir_entry.short_name) {
+                            dir_entry.name = name;
+                        }
+                        entries.push(dir_entry);
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Read from a file, starting at `offset`. Returns the number of bytes read, which is less than the length
+    /// of `buffer` if the end of the file is reached.
+    pub fn read(&mut self, file: &DirEntry, offset: u64, buffer: &mut [u8]) -> Result<usize, FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        if offset >= file.size as u64 {
+            return Ok(0);
+        }
+
+        let length = usize::min(buffer.len(), (file.size as u64 - offset) as usize);
+        let chain = self.chain(file.first_cluster)?;
+        let mut sector_data = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let sector = self.sector_at(&chain, position)?;
+            let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+            let n = usize::min(SECTOR_SIZE - sector_offset, length - done);
+
+            self.device.read(sector, &mut sector_data)?;
+            buffer[done..(done + n)].copy_from_slice(&sector_data[sector_offset..(sector_offset + n)]);
+            done += n;
+        }
+
+        Ok(length)
+    }
+
+    /// Write `data` to a file, starting at `offset`, allocating clusters for the file as needed. If `offset` is
+    /// past the end of the file, the gap is filled with zeros. `file` is updated with the file's new size.
+    pub fn write(&mut self, file: &mut DirEntry, offset: u64, data: &[u8]) -> Result<(), FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let end = offset + data.len() as u64;
+        if end > MAX_FILE_SIZE {
+            return Err(FatError::NoSpace);
+        }
+
+        if offset > file.size as u64 {
+            let zeros = vec![0; self.cluster_size()];
+            while (file.size as u64) < offset {
+                let n = usize::min(zeros.len(), (offset - file.size as u64) as usize);
+                self.write(file, file.size as u64, &zeros[0..n])?;
+            }
+        }
+
+        // Make sure the file has enough clusters to hold the new data
+        let mut chain = self.chain(file.first_cluster)?;
+        let clusters_needed = end.div_ceil(self.cluster_size() as u64) as usize;
+        while chain.len() < clusters_needed {
+            let cluster = match self.allocate_cluster(chain.last().copied()) {
+                Ok(cluster) => cluster,
+                Err(err) => {
+                    // Record any clusters we did manage to allocate, so they aren't lost
+                    self.update_entry(file)?;
+                    return Err(err);
+                }
+            };
+            if chain.is_empty() {
+                file.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+
+        let mut sector_data = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let sector = self.sector_at(&chain, position)?;
+            let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+            let n = usize::min(SECTOR_SIZE - sector_offset, data.len() - done);
+
+            // We only need to read the existing contents of the sector if we're not replacing all of it
+            if n != SECTOR_SIZE {
+                self.device.read(sector, &mut sector_data)?;
+            }
+            sector_data[sector_offset..(sector_offset + n)].copy_from_slice(&data[done..(done + n)]);
+            self.device.write(sector, &sector_data)?;
+            done += n;
+        }
+
+        file.size = u32::max(file.size, end as u32);
+        self.update_entry(file)
+    }
+
+    /// Truncate a file to zero length, freeing all of its clusters.
+    pub fn truncate(&mut self, file: &mut DirEntry) -> Result<(), FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+
+        let chain = self.chain(file.first_cluster)?;
+        for cluster in chain {
+            self.set_fat_entry(cluster, FREE_CLUSTER)?;
+        }
+        file.first_cluster = 0;
+        file.size = 0;
+        self.update_entry(file)
+    }
+
+    /// Create an empty file called `name` in the directory `parent`.
+    pub fn create_file(&mut self, parent: &DirEntry, name: &str) -> Result<DirEntry, FatError> {
+        self.create_entry(parent, name, Attributes::ARCHIVE, 0)
+    }
+
+    /// Create an empty directory called `name` in the directory `parent`.
+    pub fn create_dir(&mut self, parent: &DirEntry, name: &str) -> Result<DirEntry, FatError> {
+        if !parent.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+
+        /*
+         * Every directory apart from the root starts with `.` and `..` entries, which refer to itself
+         * and its parent. A `..` entry that refers to the root directory uses cluster `0`.
+         */
+        let cluster = self.allocate_cluster(None)?;
+        self.zero_cluster(cluster)?;
+        let mut sector_data = [0; SECTOR_SIZE];
+        let parent_cluster = if parent.location.is_none() { 0 } else { parent.first_cluster };
+        sector_data[0..ENTRY_SIZE].copy_from_slice(&dir::short_entry(
+            b".          ",
+            Attributes::DIRECTORY,
+            cluster,
+        ));
+        sector_data[ENTRY_SIZE..(2 * ENTRY_SIZE)].copy_from_slice(&dir::short_entry(
+            b"..         ",
+            Attributes::DIRECTORY,
+            parent_cluster,
+        ));
+        self.device.write(self.cluster_sector(cluster), &sector_data)?;
+
+        match self.create_entry(parent, name, Attributes::DIRECTORY, cluster) {
+            Ok(entry) => Ok(entry),
+            Err(err) => {
+                self.set_fat_entry(cluster, FREE_CLUSTER)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Write any cached state back to the volume, and flush the device.
+    pub fn flush(&mut self) -> Result<(), FatError> {
+        if self.fs_info_dirty {
+            /*
+             * We don't keep track of the number of free clusters, so we mark it as unknown, rather than
+             * leaving a count that is now wrong.
+             */
+            if let (Some(sector), Some(mut fs_info)) = (self.fs_info_sector, self.read_fs_info()?) {
+                fs_info[488..492].copy_from_slice(&UNKNOWN_FREE_COUNT.to_le_bytes());
+                fs_info[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+                self.device.write(sector, &fs_info)?;
+            }
+            self.fs_info_dirty = false;
+        }
+
+        self.device.flush()
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    fn create_entry(
+        &mut self,
+        parent: &DirEntry,
+        name: &str,
+        attributes: Attributes,
+        first_cluster: u32,
+    ) -> Result<DirEntry, FatError> {
+        if !parent.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        if !dir::is_valid_long_name(name) {
+            return Err(FatError::InvalidName);
+        }
+
+        let existing = self.read_dir(parent)?;
+        if existing.iter().any(|entry| entry.matches(name)) {
+            return Err(FatError::AlreadyExists);
+        }
+
+        // Names that can't be stored in the short entry itself need LFN entries too
+        let (short_name, mut entries) = match dir::as_short_name(name) {
+            Some(short_name) => (short_name, Vec::new()),
+            None => {
+                let short_name = dir::generate_short_name(name, |short_name| {
+                    existing.iter().any(|entry| entry.short_name == *short_name)
+                })
+                .ok_or(FatError::NoSpace)?;
+                (short_name, dir::long_name_entries(name, &short_name))
+            }
+        };
+        entries.push(dir::short_entry(&short_name, attributes, first_cluster));
+
+        let slots = self.find_free_slots(parent, entries.len())?;
+        let mut sector_data = [0; SECTOR_SIZE];
+        for (slot, entry) in slots.iter().zip(entries.iter()) {
+            self.device.read(slot.sector, &mut sector_data)?;
+            sector_data[slot.offset..(slot.offset + ENTRY_SIZE)].copy_from_slice(entry);
+            self.device.write(slot.sector, &sector_data)?;
+        }
+
+        let short_slot = *slots.last().unwrap();
+        let mut entry = DirEntry::parse_short(entries.last().unwrap(), short_slot);
+        entry.name = alloc::string::String::from(name);
+        Ok(entry)
+    }
+
+    /// Find `count` consecutive free entries in a directory, extending the directory if there isn't a long
+    /// enough run of free entries already.
+    fn find_free_slots(&mut self, dir: &DirEntry, count: usize) -> Result<Vec<EntryLocation>, FatError> {
+        let mut run = Vec::with_capacity(count);
+        let mut sector_data = [0; SECTOR_SIZE];
+        for sector in self.dir_sectors(dir)? {
+            self.device.read(sector, &mut sector_data)?;
+            for (i, entry) in sector_data.chunks_exact(ENTRY_SIZE).enumerate() {
+                if entry[0] == END_OF_DIRECTORY || entry[0] == DELETED_ENTRY {
+                    run.push(EntryLocation { sector, offset: i * ENTRY_SIZE });
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+        }
+
+        // Add new clusters to the directory until the run is long enough
+        let mut last_cluster = self.chain(dir.first_cluster)?.last().copied();
+        while run.len() < count {
+            let cluster = self.allocate_cluster(last_cluster)?;
+            self.zero_cluster(cluster)?;
+            let first_sector = self.cluster_sector(cluster);
+            for sector in first_sector..(first_sector + self.sectors_per_cluster as u64) {
+                for i in 0..(SECTOR_SIZE / ENTRY_SIZE) {
+                    if run.len() < count {
+                        run.push(EntryLocation { sector, offset: i * ENTRY_SIZE });
+                    }
+                }
+            }
+            last_cluster = Some(cluster);
+        }
+        Ok(run)
+    }
+
+    /// Write the size and first cluster of a file back to its entry.
+    fn update_entry(&mut self, file: &DirEntry) -> Result<(), FatError> {
+        let location = file.location.ok_or(FatError::IsADirectory)?;
+        let mut sector_data = [0; SECTOR_SIZE];
+        self.device.read(location.sector, &mut sector_data)?;
+        file.update_short(&mut sector_data[location.offset..(location.offset + ENTRY_SIZE)]);
+        self.device.write(location.sector, &sector_data)
+    }
+
+    fn dir_sectors(&mut self, dir: &DirEntry) -> Result<Vec<u64>, FatError> {
+        let chain = self.chain(dir.first_cluster)?;
+        Ok(chain
+            .into_iter()
+            .flat_map(|cluster| {
+                let first_sector = self.cluster_sector(cluster);
+                first_sector..(first_sector + self.sectors_per_cluster as u64)
+            })
+            .collect())
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster as u64
+    }
+
+    /// Find the sector that holds the byte at `position` in a file made up of the clusters in `chain`.
+    fn sector_at(&self, chain: &[u32], position: u64) -> Result<u64, FatError> {
+        let cluster = *chain.get((position / self.cluster_size() as u64) as usize).ok_or(FatError::Corrupt)?;
+        let sector_in_cluster = (position % self.cluster_size() as u64) / SECTOR_SIZE as u64;
+        Ok(self.cluster_sector(cluster) + sector_in_cluster)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.num_clusters + 2
+    }
+
+    /// Follow the chain of clusters starting at `first`. Empty files have a first cluster of `0`, and so an
+    /// empty chain.
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>, FatError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            if !self.is_valid_cluster(cluster) || chain.len() >= self.num_clusters as usize {
+                return Err(FatError::Corrupt);
+            }
+            chain.push(cluster);
+
+            cluster = match self.fat_entry(cluster)? {
+                next if next >= END_OF_CHAIN_MIN => 0,
+                FREE_CLUSTER => return Err(FatError::Corrupt),
+                next => next,
+            };
+        }
+        Ok(chain)
+    }
+
+    /// Allocate a free cluster, adding it to the end of the chain ending in `previous` if there is one.
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, FatError> {
+        for i in 0..self.num_clusters {
+            let cluster = 2 + (self.next_free - 2 + i) % self.num_clusters;
+            if self.fat_entry(cluster)? == FREE_CLUSTER {
+                self.set_fat_entry(cluster, END_OF_CHAIN)?;
+                if let Some(previous) = previous {
+                    self.set_fat_entry(previous, cluster)?;
+                }
+                self.next_free = if cluster + 1 < self.num_clusters + 2 { cluster + 1 } else { 2 };
+                return Ok(cluster);
+            }
+        }
+
+        Err(FatError::NoSpace)
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), FatError> {
+        let zeros = vec![0; self.cluster_size()];
+        self.device.write(self.cluster_sector(cluster), &zeros)
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, FatError> {
+        let sector = self.fat_start + (cluster as u64 * 4) / SECTOR_SIZE as u64;
+        let offset = (cluster as usize * 4) % SECTOR_SIZE;
+
+        let sector_data = match self.fat_cache {
+            Some((cached_sector, ref data)) if cached_sector == sector => data,
+            _ => {
+                let mut data = [0; SECTOR_SIZE];
+                self.device.read(sector, &mut data)?;
+                &self.fat_cache.insert((sector, data)).1
+            }
+        };
+        Ok(u32::from_le_bytes(sector_data[offset..(offset + 4)].try_into().unwrap()) & FAT_ENTRY_MASK)
+    }
+
+    /// Set an entry in the FAT. Each copy of the FAT is updated.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let sector_in_fat = (cluster as u64 * 4) / SECTOR_SIZE as u64;
+        let offset = (cluster as usize * 4) % SECTOR_SIZE;
+
+        let mut sector_data = [0; SECTOR_SIZE];
+        for fat in 0..self.num_fats {
+            let sector = self.fat_start + fat as u64 * self.fat_size + sector_in_fat;
+            self.device.read(sector, &mut sector_data)?;
+            let existing = u32::from_le_bytes(sector_data[offset..(offset + 4)].try_into().unwrap());
+            let new = (existing & !FAT_ENTRY_MASK) | (value & FAT_ENTRY_MASK);
+            sector_data[offset..(offset + 4)].copy_from_slice(&new.to_le_bytes());
+            self.device.write(sector, &sector_data)?;
+
+            if fat == 0 && self.fat_cache.is_some_and(|(cached_sector, _)| cached_sector == sector) {
+                self.fat_cache = Some((sector, sector_data));
+            }
+        }
+
+        self.fs_info_dirty = true;
+        Ok(())
+    }
+
+    /// Read the FSInfo sector, if the volume has a valid one.
+    fn read_fs_info(&mut self) -> Result<Option<[u8; SECTOR_SIZE]>, FatError> {
+        let Some(sector) = self.fs_info_sector else {
+            return Ok(None);
+        };
+        let mut fs_info = [0; SECTOR_SIZE];
+        self.device.read(sector, &mut fs_info)?;
+
+        let lead_signature = u32::from_le_bytes(fs_info[0..4].try_into().unwrap());
+        let struct_signature = u32::from_le_bytes(fs_info[484..488].try_into().unwrap());
+        if lead_signature != FS_INFO_LEAD_SIGNATURE || struct_signature != FS_INFO_STRUCT_SIGNATURE {
+            return Ok(None);
+        }
+        Ok(Some(fs_info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{string::String, vec::Vec};
+
+    struct RamDisk(Vec<u8>);
+
+    impl BlockDevice for RamDisk {
+        fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), FatError> {
+            let start = sector as usize * SECTOR_SIZE;
+            buffer.copy_from_slice(self.0.get(start..(start + buffer.len())).ok_or(FatError::Io)?);
+            Ok(())
+        }
+
+        fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), FatError> {
+            let start = sector as usize * SECTOR_SIZE;
+            self.0.get_mut(start..(start + data.len())).ok_or(FatError::Io)?.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), FatError> {
+            Ok(())
+        }
+    }
+
+    /// Create an empty FAT32 volume with one sector per cluster, the way `mkfs.fat` would.
+    fn format(total_sectors: u32) -> RamDisk {
+        const RESERVED_SECTORS: u32 = 32;
+        const NUM_FATS: u32 = 2;
+        let fat_size = (total_sectors * 4).div_ceil(SECTOR_SIZE as u32);
+
+        let mut disk = vec![0u8; total_sectors as usize * SECTOR_SIZE];
+        let boot_sector = &mut disk[0..SECTOR_SIZE];
+        boot_sector[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        boot_sector[3..11].copy_from_slice(b"POPLAR  ");
+        boot_sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot_sector[13] = 1;
+        boot_sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot_sector[16] = NUM_FATS as u8;
+        boot_sector[21] = 0xf8;
+        boot_sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+        boot_sector[36..40].copy_from_slice(&fat_size.to_le_bytes());
+        boot_sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot_sector[48..50].copy_from_slice(&1u16.to_le_bytes());
+        boot_sector[510] = 0x55;
+        boot_sector[511] = 0xaa;
+
+        let fs_info = &mut disk[SECTOR_SIZE..(2 * SECTOR_SIZE)];
+        fs_info[0..4].copy_from_slice(&FS_INFO_LEAD_SIGNATURE.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&FS_INFO_STRUCT_SIGNATURE.to_le_bytes());
+        fs_info[488..492].copy_from_slice(&UNKNOWN_FREE_COUNT.to_le_bytes());
+        fs_info[492..496].copy_from_slice(&3u32.to_le_bytes());
+        fs_info[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+
+        // The first two entries of each FAT are reserved, and the root directory takes up cluster 2
+        for fat in 0..NUM_FATS {
+            let start = (RESERVED_SECTORS + fat * fat_size) as usize * SECTOR_SIZE;
+            for (i, entry) in [0x0fff_fff8u32, END_OF_CHAIN, END_OF_CHAIN].iter().enumerate() {
+                disk[(start + i * 4)..(start + i * 4 + 4)].copy_from_slice(&entry.to_le_bytes());
+            }
+        }
+
+        RamDisk(disk)
+    }
+
+    fn names(entries: &[DirEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn empty_volume() {
+        let mut fs = FileSystem::mount(format(1024)).unwrap();
+        let root = fs.root();
+        assert!(fs.read_dir(&root).unwrap().is_empty());
+        assert_eq!(fs.lookup(&["missing"]).unwrap_err(), FatError::NotFound);
+        assert_eq!(FileSystem::mount(RamDisk(vec![0; 4096])).err(), Some(FatError::Unsupported));
+    }
+
+    #[test]
+    fn create_and_read_back() {
+        let mut fs = FileSystem::mount(format(1024)).unwrap();
+        let root = fs.root();
+        let mut file = fs.create_file(&root, "A file with a long name.txt").unwrap();
+        let contents: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        fs.write(&mut file, 0, &contents).unwrap();
+
+        let file = fs.lookup(&["a FILE with a long name.TXT"]).unwrap();
+        assert_eq!(file.name, "A file with a long name.txt");
+        assert_eq!(file.size, 2000);
+        let mut buffer = vec![0; 3000];
+        assert_eq!(fs.read(&file, 0, &mut buffer).unwrap(), 2000);
+        assert_eq!(&buffer[0..2000], &contents[..]);
+        assert_eq!(fs.read(&file, 1500, &mut buffer[0..100]).unwrap(), 100);
+        assert_eq!(&buffer[0..100], &contents[1500..1600]);
+
+        // Files can also be found by their short names
+        assert_eq!(fs.lookup(&["AFILEW~1.TXT"]).unwrap().name, "A file with a long name.txt");
+        assert_eq!(fs.create_file(&root, "a file with a long name.txt").unwrap_err(), FatError::AlreadyExists);
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = FileSystem::mount(format(1024)).unwrap();
+        let root = fs.root();
+        let efi = fs.create_dir(&root, "EFI").unwrap();
+        let boot = fs.create_dir(&efi, "boot").unwrap();
+        let mut file = fs.create_file(&boot, "BOOTX64.EFI").unwrap();
+        fs.write(&mut file, 0, b"hello").unwrap();
+
+        assert_eq!(names(&fs.read_dir(&root).unwrap()), ["EFI"]);
+        assert_eq!(names(&fs.read_dir(&efi).unwrap()), ["boot"]);
+        let file = fs.lookup(&["efi", "BOOT", "bootx64.efi"]).unwrap();
+        assert_eq!(file.size, 5);
+        assert!(fs.lookup(&["EFI", "boot"]).unwrap().is_dir());
+        assert_eq!(fs.lookup(&["EFI", "boot", "BOOTX64.EFI", "foo"]).unwrap_err(), FatError::NotADirectory);
+        assert_eq!(fs.read_dir(&file).unwrap_err(), FatError::NotADirectory);
+    }
+
+    #[test]
+    fn directory_grows() {
+        let mut fs = FileSystem::mount(format(1024)).unwrap();
+        let root = fs.root();
+        let expected: Vec<String> = (0..40).map(|i| std::format!("file number {}", i)).collect();
+        for name in &expected {
+            fs.create_file(&root, name).unwrap();
+        }
+        assert_eq!(names(&fs.read_dir(&root).unwrap()), expected);
+    }
+
+    #[test]
+    fn writes_extend_files() {
+        let mut fs = FileSystem::mount(format(1024)).unwrap();
+        let root = fs.root();
+        let mut file = fs.create_file(&root, "sparse").unwrap();
+        fs.write(&mut file, 1000, b"end").unwrap();
+        fs.write(&mut file, 0, b"start").unwrap();
+
+        let file = fs.lookup(&["sparse"]).unwrap();
+        assert_eq!(file.size, 1003);
+        let mut buffer = vec![0xff; 1003];
+        fs.read(&file, 0, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..5], b"start");
+        assert!(buffer[5..1000].iter().all(|&b| b == 0));
+        assert_eq!(&buffer[1000..], b"end");
+    }
+
+    #[test]
+    fn truncate_frees_clusters() {
+        let mut fs = FileSystem::mount(format(128)).unwrap();
+        let root = fs.root();
+        let mut file = fs.create_file(&root, "big").unwrap();
+        let free_space = (fs.num_clusters as usize - 1) * SECTOR_SIZE;
+        assert_eq!(fs.write(&mut file, 0, &vec![1; free_space + 1]).unwrap_err(), FatError::NoSpace);
+
+        fs.truncate(&mut file).unwrap();
+        assert_eq!(fs.lookup(&["big"]).unwrap().size, 0);
+        fs.write(&mut file, 0, &vec![1; free_space]).unwrap();
+        fs.flush().unwrap();
+
+        // Make sure the volume still makes sense after being mounted again
+        let mut fs = FileSystem::mount(fs.device).unwrap();
+        let file = fs.lookup(&["big"]).unwrap();
+        assert_eq!(file.size as usize, free_space);
+        assert_eq!(fs.chain(file.first_cluster).unwrap().len(), free_space / SECTOR_SIZE);
+    }
+}
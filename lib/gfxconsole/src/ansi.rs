@@ -0,0 +1,218 @@
+//! A parser for the subset of ANSI (ECMA-48) escape sequences understood by VT100-style terminals. The parser is
+//! fed a character at a time, and produces `Action`s for the console to carry out - it doesn't interpret the
+//! sequences itself.
+
+/// The maximum number of parameters a control sequence can have. Extra parameters are ignored.
+pub const MAX_PARAMS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Display a character at the cursor.
+    Print(char),
+    /// Carry out a C0 control character (e.g. `\n`, `\r`, or backspace).
+    Execute(char),
+    /// Carry out a Control Sequence (`ESC [ ... <final>`).
+    Csi(ControlSequence),
+    /// Carry out an escape sequence other than a Control Sequence (e.g. `ESC 7`, which saves the cursor).
+    Escape(char),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ControlSequence {
+    params: [u16; MAX_PARAMS],
+    num_params: usize,
+    /// Whether the sequence has a `?` before its parameters, which marks it as a DEC private sequence.
+    pub private: bool,
+    pub final_char: char,
+}
+
+impl ControlSequence {
+    pub fn params(&self) -> &[u16] {
+        &self.params[0..self.num_params]
+    }
+
+    /// Get the parameter at `index`, or `default` if it is missing or zero. This is how most sequences treat
+    /// their parameters (e.g. `ESC [ A` and `ESC [ 0 A` both move the cursor up by one line).
+    pub fn param_or(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(0) | None => default,
+            Some(&param) => param,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Ground,
+    Escape,
+    CsiParams,
+    /// We're in a Control Sequence we can't handle, and are waiting for it to end.
+    CsiIgnore,
+}
+
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    num_params: usize,
+    private: bool,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser { state: State::Ground, params: [0; MAX_PARAMS], num_params: 0, private: false }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        /*
+         * Escape starts a new sequence from any state, abandoning any sequence in progress. Cancel and Substitute
+         * abandon the sequence without starting a new one.
+         */
+        match c {
+            '\x1b' => {
+                self.state = State::Escape;
+                return None;
+            }
+            '\x18' | '\x1a' => {
+                self.state = State::Ground;
+                return None;
+            }
+            _ => (),
+        }
+
+        match self.state {
+            State::Ground => match c {
+                '\x00'..='\x1f' => Some(Action::Execute(c)),
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.state = State::CsiParams;
+                    self.params = [0; MAX_PARAMS];
+                    self.num_params = 0;
+                    self.private = false;
+                    None
+                }
+                // Control characters are carried out in the middle of sequences
+                '\x00'..='\x1f' => Some(Action::Execute(c)),
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(c))
+                }
+            },
+            State::CsiParams => match c {
+                '\x00'..='\x1f' => Some(Action::Execute(c)),
+                '0'..='9' => {
+                    if self.num_params == 0 {
+                        self.num_params = 1;
+                    }
+                    if self.num_params <= MAX_PARAMS {
+                        let param = &mut self.params[self.num_params - 1];
+                        *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                    }
+                    None
+                }
+                ';' => {
+                    // An empty parameter before a `;` still counts as a (zero) parameter
+                    self.num_params = if self.num_params == 0 { 2 } else { self.num_params + 1 };
+                    None
+                }
+                '?' if self.num_params == 0 && !self.private => {
+                    self.private = true;
+                    None
+                }
+                '@'..='~' => {
+                    self.state = State::Ground;
+                    Some(Action::Csi(ControlSequence {
+                        params: self.params,
+                        num_params: self.num_params.min(MAX_PARAMS),
+                        private: self.private,
+                        final_char: c,
+                    }))
+                }
+                // Intermediate bytes, and other parameter bytes, aren't used by any sequence we support
+                _ => {
+                    self.state = State::CsiIgnore;
+                    None
+                }
+            },
+            State::CsiIgnore => match c {
+                '\x00'..='\x1f' => Some(Action::Execute(c)),
+                '@'..='~' => {
+                    self.state = State::Ground;
+                    None
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn parse(s: &str) -> Vec<Action> {
+        let mut parser = Parser::new();
+        s.chars().filter_map(|c| parser.advance(c)).collect()
+    }
+
+    fn csi(s: &str) -> ControlSequence {
+        match parse(s).as_slice() {
+            [Action::Csi(sequence)] => *sequence,
+            other => panic!("Expected a single control sequence, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn text_and_controls() {
+        assert_eq!(
+            parse("a\nb\r"),
+            [Action::Print('a'), Action::Execute('\n'), Action::Print('b'), Action::Execute('\r')]
+        );
+    }
+
+    #[test]
+    fn control_sequences() {
+        let sequence = csi("\x1b[12;34H");
+        assert_eq!(sequence.final_char, 'H');
+        assert_eq!(sequence.params(), [12, 34]);
+        assert!(!sequence.private);
+
+        let sequence = csi("\x1b[m");
+        assert_eq!(sequence.params(), []);
+        assert_eq!(sequence.param_or(0, 1), 1);
+
+        let sequence = csi("\x1b[;5H");
+        assert_eq!(sequence.params(), [0, 5]);
+        assert_eq!(sequence.param_or(0, 1), 1);
+
+        let sequence = csi("\x1b[?25l");
+        assert!(sequence.private);
+        assert_eq!(sequence.params(), [25]);
+    }
+
+    #[test]
+    fn too_many_params() {
+        let sequence = csi("\x1b[1;2;3;4;5;6;7;8;9;10;11;12;13;14;15;16;17;18m");
+        assert_eq!(sequence.params().len(), MAX_PARAMS);
+        assert_eq!(sequence.params()[MAX_PARAMS - 1], 16);
+    }
+
+    #[test]
+    fn interrupted_sequences() {
+        // A new escape abandons the sequence in progress
+        assert_eq!(parse("\x1b[12\x1b[2J"), [Action::Csi(csi("\x1b[2J"))]);
+        // Cancel abandons the sequence, and the rest is printed
+        assert_eq!(parse("\x1b[1\x18m"), [Action::Print('m')]);
+        // Unsupported sequences are skipped
+        assert_eq!(parse("\x1b[1$pa"), [Action::Print('a')]);
+        assert_eq!(parse("\x1b7"), [Action::Escape('7')]);
+    }
+}
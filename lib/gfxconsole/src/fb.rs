@@ -1,81 +1,235 @@
-use bit_field::BitField;
-use font8x8::UnicodeFonts;
+use crate::{
+    font::{Font, Glyph},
+    Rect,
+};
 
 pub type Rgb32 = u32;
-pub type PixelFormat = u32;
+
+/// The ways a framebuffer can represent its pixels. Colors are always given to the framebuffer as `Rgb32`s, and
+/// converted to the framebuffer's format when they are drawn.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// 32 bits per pixel, with red in the lowest byte, then green, then blue. The highest byte is unused.
+    Rgb32,
+    /// 32 bits per pixel, with blue in the lowest byte, then green, then red. The highest byte is unused.
+    Bgr32,
+    /// 16 bits per pixel, with 5 bits of red in the highest bits, then 6 bits of green, then 5 bits of blue.
+    Rgb565,
+    /// 8 bits per pixel, each of which is the brightness of a shade of grey.
+    Grey8,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "rgb32" => Some(Format::Rgb32),
+            "bgr32" => Some(Format::Bgr32),
+            "rgb565" => Some(Format::Rgb565),
+            "grey8" => Some(Format::Grey8),
+            _ => None,
+        }
+    }
+
+    /// The name of the format, as used by `from_name`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Rgb32 => "rgb32",
+            Format::Bgr32 => "bgr32",
+            Format::Rgb565 => "rgb565",
+            Format::Grey8 => "grey8",
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Format::Rgb32 | Format::Bgr32 => 4,
+            Format::Rgb565 => 2,
+            Format::Grey8 => 1,
+        }
+    }
+
+    /// Convert a color to the representation of a pixel of that color in this format. Only the lowest
+    /// `bytes_per_pixel` bytes of the result are used.
+    pub fn encode(self, color: Rgb32) -> u32 {
+        let r = (color >> 16) & 0xff;
+        let g = (color >> 8) & 0xff;
+        let b = color & 0xff;
+
+        match self {
+            Format::Rgb32 => r | (g << 8) | (b << 16),
+            Format::Bgr32 => b | (g << 8) | (r << 16),
+            Format::Rgb565 => ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3),
+            // Weight each channel by how bright it appears (using the weights from ITU-R BT.601)
+            Format::Grey8 => (r * 77 + g * 150 + b * 29) >> 8,
+        }
+    }
+}
 
 pub struct Framebuffer {
-    fb: *mut PixelFormat,
+    fb: *mut u8,
 
     pub width: usize,
     pub height: usize,
+    /// The number of pixels (not bytes) between the start of each row.
     pub stride: usize,
-
-    // Pixel format
-    red_shift: u8,
-    green_shift: u8,
-    blue_shift: u8,
+    pub format: Format,
 }
 
 unsafe impl Send for Framebuffer {}
 
 impl Framebuffer {
-    pub fn new(
-        fb: *mut u32,
-        width: usize,
-        height: usize,
-        stride: usize,
-        red_shift: u8,
-        green_shift: u8,
-        blue_shift: u8,
-    ) -> Framebuffer {
-        Framebuffer { fb, width, height, stride, red_shift, green_shift, blue_shift }
+    pub fn new(fb: *mut u8, width: usize, height: usize, stride: usize, format: Format) -> Framebuffer {
+        Framebuffer { fb, width, height, stride, format }
     }
 
     pub fn draw_rect(&mut self, start_x: usize, start_y: usize, width: usize, height: usize, fill: Rgb32) {
         assert!((start_x + width) <= self.width);
         assert!((start_y + height) <= self.height);
 
-        let fill = self.rgb_to_pixel_format(fill);
+        let fill = self.format.encode(fill);
 
         for y in start_y..(start_y + height) {
             for x in start_x..(start_x + width) {
                 unsafe {
-                    *(self.fb.offset((y * self.stride + x) as isize)) = fill;
+                    self.write_pixel(x, y, fill);
                 }
             }
         }
     }
 
+    /// Draw a single pixel. Pixels outside the framebuffer are ignored.
+    pub fn draw_pixel(&mut self, x: usize, y: usize, fill: Rgb32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let fill = self.format.encode(fill);
+        unsafe {
+            self.write_pixel(x, y, fill);
+        }
+    }
+
+    /// Move the rows of pixels in `lines..region_height` up by `lines` rows, to the top of the framebuffer. The
+    /// bottom `lines` rows of the region are left as they were.
+    pub fn scroll_up(&mut self, lines: usize, region_height: usize) {
+        assert!(region_height <= self.height);
+        if lines >= region_height {
+            return;
+        }
+
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        unsafe {
+            core::ptr::copy(
+                self.fb.add(lines * self.stride * bytes_per_pixel),
+                self.fb,
+                ((region_height - lines - 1) * self.stride + self.width) * bytes_per_pixel,
+            );
+        }
+    }
+
+    /// Copy a region of this framebuffer to the same position in `target`, which must be the same size and use the
+    /// same pixel format.
+    pub fn copy_to(&self, target: &mut Framebuffer, rect: Rect) {
+        assert!(target.width == self.width && target.height == self.height);
+        self.blit(target, rect, rect.x, rect.y);
+    }
+
+    /// Copy a region of this framebuffer to `target`, with its top-left corner at `(target_x, target_y)`. The
+    /// framebuffers must use the same pixel format, and the region must fit within both of them.
+    pub fn blit(&self, target: &mut Framebuffer, rect: Rect, target_x: usize, target_y: usize) {
+        assert!(target.format == self.format);
+        assert!((rect.x + rect.width) <= self.width);
+        assert!((rect.y + rect.height) <= self.height);
+        assert!((target_x + rect.width) <= target.width);
+        assert!((target_y + rect.height) <= target.height);
+
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        for row in 0..rect.height {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.fb.add(((rect.y + row) * self.stride + rect.x) * bytes_per_pixel),
+                    target.fb.add(((target_y + row) * target.stride + target_x) * bytes_per_pixel),
+                    rect.width * bytes_per_pixel,
+                );
+            }
+        }
+    }
+
     pub fn clear(&mut self, fill: Rgb32) {
         self.draw_rect(0, 0, self.width, self.height, fill);
     }
 
-    pub fn draw_glyph(&mut self, key: char, x: usize, y: usize, fill: Rgb32) {
-        let fill = self.rgb_to_pixel_format(fill);
-        for (line, line_data) in font8x8::BASIC_FONTS.get(key).unwrap().iter().enumerate() {
-            // TODO: this is amazingly inefficient. We could replace with a lookup table and multiply by the color
-            // if this is too slow.
-            for bit in 0..8 {
-                if line_data.get_bit(bit) {
+    pub fn draw_glyph(&mut self, glyph: &Glyph, x: usize, y: usize, fill: Rgb32) {
+        let fill = self.format.encode(fill);
+        // TODO: this is amazingly inefficient. We could replace with a lookup table and multiply by the color if
+        // this is too slow.
+        for line in 0..glyph.height() {
+            for column in 0..glyph.width() {
+                if glyph.is_set(column, line) {
+                    unsafe {
+                        self.write_pixel(x + column, y + line, fill);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draw a glyph in a heavier weight, by drawing it again shifted right by one pixel. The extra pixels are kept
+    /// within the glyph's cell.
+    pub fn draw_bold_glyph(&mut self, glyph: &Glyph, x: usize, y: usize, fill: Rgb32) {
+        let fill = self.format.encode(fill);
+        for line in 0..glyph.height() {
+            for column in 0..glyph.width() {
+                if glyph.is_set(column, line) || (column > 0 && glyph.is_set(column - 1, line)) {
                     unsafe {
-                        *(self.fb.offset(((y + line) * self.stride + (x + bit)) as isize)) = fill;
+                        self.write_pixel(x + column, y + line, fill);
                     }
                 }
             }
         }
     }
 
+    /// Draw a string using the built-in font.
     pub fn draw_string(&mut self, string: &str, start_x: usize, start_y: usize, fill: Rgb32) {
+        let font = Font::Builtin;
         for (index, c) in string.chars().enumerate() {
-            self.draw_glyph(c, start_x + (index * 8), start_y, fill);
+            self.draw_glyph(&font.glyph(c), start_x + (index * font.width()), start_y, fill);
+        }
+    }
+
+    /// Write an encoded pixel (see `Format::encode`). The caller must make sure the pixel is within the
+    /// framebuffer.
+    #[inline(always)]
+    unsafe fn write_pixel(&mut self, x: usize, y: usize, pixel: u32) {
+        let offset = y * self.stride + x;
+        match self.format.bytes_per_pixel() {
+            4 => (self.fb as *mut u32).add(offset).write(pixel),
+            2 => (self.fb as *mut u16).add(offset).write(pixel as u16),
+            _ => self.fb.add(offset).write(pixel as u8),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        assert_eq!(Format::Rgb32.encode(0x112233), 0x332211);
+        assert_eq!(Format::Bgr32.encode(0x112233), 0x112233);
+        assert_eq!(Format::Rgb565.encode(0xffffff), 0xffff);
+        assert_eq!(Format::Rgb565.encode(0xff0000), 0xf800);
+        assert_eq!(Format::Rgb565.encode(0x00ff00), 0x07e0);
+        assert_eq!(Format::Rgb565.encode(0x0000ff), 0x001f);
+        assert_eq!(Format::Grey8.encode(0x000000), 0x00);
+        assert_eq!(Format::Grey8.encode(0xffffff), 0xff);
+    }
 
-    fn rgb_to_pixel_format(&self, color: Rgb32) -> PixelFormat {
-        let r = ((color >> 16) & 0xff) as u32;
-        let g = ((color >> 8) & 0xff) as u32;
-        let b = (color & 0xff) as u32;
-        (r << self.red_shift) | (g << self.green_shift) | (b << self.blue_shift)
+    #[test]
+    fn format_names() {
+        for format in [Format::Rgb32, Format::Bgr32, Format::Rgb565, Format::Grey8] {
+            assert_eq!(Format::from_name(format.name()), Some(format));
+        }
     }
 }
@@ -0,0 +1,253 @@
+//! Fonts are used to draw the text of the console. We have a built-in 8x8 bitmap font (from `font8x8`), which
+//! covers ASCII and the Latin-1 Supplement, and can also load PC Screen Fonts (version 2), as used by the Linux
+//! console. PSF2 fonts come in many sizes, and many of them cover far more characters than the built-in font.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use bit_field::BitField;
+use core::convert::TryInto;
+use font8x8::UnicodeFonts;
+
+pub enum Font {
+    Builtin,
+    Psf2(Psf2Font),
+}
+
+impl Font {
+    pub fn width(&self) -> usize {
+        match self {
+            Font::Builtin => 8,
+            Font::Psf2(font) => font.width,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            Font::Builtin => 8,
+            Font::Psf2(font) => font.height,
+        }
+    }
+
+    /// Get the glyph for a character. Characters that the font doesn't have a glyph for are drawn as `?`.
+    pub fn glyph(&self, c: char) -> Glyph<'_> {
+        match self {
+            Font::Builtin => Glyph::Builtin(
+                font8x8::BASIC_FONTS
+                    .get(c)
+                    .or_else(|| font8x8::LATIN_FONTS.get(c))
+                    .unwrap_or_else(|| font8x8::BASIC_FONTS.get('?').unwrap()),
+            ),
+            Font::Psf2(font) => {
+                let index = font.glyph_index(c).or_else(|| font.glyph_index('?')).unwrap_or(0);
+                Glyph::Psf2 { font, bitmap: font.bitmap(index) }
+            }
+        }
+    }
+}
+
+pub enum Glyph<'a> {
+    /// Each byte is a row of the glyph, with the leftmost pixel in the least-significant bit.
+    Builtin([u8; 8]),
+    /// Each row of the glyph is padded to a whole number of bytes, with the leftmost pixel in the most-significant
+    /// bit of the first byte.
+    Psf2 { font: &'a Psf2Font, bitmap: &'a [u8] },
+}
+
+impl<'a> Glyph<'a> {
+    pub fn width(&self) -> usize {
+        match self {
+            Glyph::Builtin(_) => 8,
+            Glyph::Psf2 { font, .. } => font.width,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            Glyph::Builtin(_) => 8,
+            Glyph::Psf2 { font, .. } => font.height,
+        }
+    }
+
+    /// Whether the pixel at `(x, y)` within the glyph should be drawn.
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        match self {
+            Glyph::Builtin(rows) => rows[y].get_bit(x),
+            Glyph::Psf2 { font, bitmap } => {
+                let row_bytes = font.width.div_ceil(8);
+                bitmap[y * row_bytes + x / 8].get_bit(7 - (x % 8))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FontError {
+    /// The data is too short to contain everything the header says it does.
+    TooShort,
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    /// The header describes glyphs that don't make sense (e.g. they have no pixels).
+    InvalidGlyphSize,
+}
+
+const PSF2_MAGIC: u32 = 0x864ab572;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x1;
+/// Ends the list of characters for a glyph in the Unicode table.
+const PSF2_SEPARATOR: u8 = 0xff;
+/// Starts a sequence of characters (e.g. a letter followed by a combining accent) that a glyph represents.
+const PSF2_START_SEQUENCE: u8 = 0xfe;
+
+pub struct Psf2Font {
+    data: Vec<u8>,
+    glyphs_offset: usize,
+    num_glyphs: usize,
+    bytes_per_glyph: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Maps characters to the index of their glyph. If the font doesn't have a Unicode table, glyphs are indexed
+    /// by the character's value instead.
+    unicode_table: Option<BTreeMap<char, usize>>,
+}
+
+impl Psf2Font {
+    pub fn parse(data: Vec<u8>) -> Result<Psf2Font, FontError> {
+        let field = |index: usize| -> Result<u32, FontError> {
+            let bytes = data.get((index * 4)..(index * 4 + 4)).ok_or(FontError::TooShort)?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        if field(0)? != PSF2_MAGIC {
+            return Err(FontError::InvalidMagic);
+        }
+        let version = field(1)?;
+        if version != 0 {
+            return Err(FontError::UnsupportedVersion(version));
+        }
+        let glyphs_offset = field(2)? as usize;
+        let flags = field(3)?;
+        let num_glyphs = field(4)? as usize;
+        let bytes_per_glyph = field(5)? as usize;
+        let height = field(6)? as usize;
+        let width = field(7)? as usize;
+
+        if width == 0 || height == 0 || bytes_per_glyph < width.div_ceil(8) * height {
+            return Err(FontError::InvalidGlyphSize);
+        }
+        let glyphs_end = glyphs_offset + num_glyphs * bytes_per_glyph;
+        if data.len() < glyphs_end {
+            return Err(FontError::TooShort);
+        }
+
+        let unicode_table = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            Some(parse_unicode_table(&data[glyphs_end..], num_glyphs))
+        } else {
+            None
+        };
+
+        Ok(Psf2Font { data, glyphs_offset, num_glyphs, bytes_per_glyph, width, height, unicode_table })
+    }
+
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        let index = match &self.unicode_table {
+            Some(table) => *table.get(&c)?,
+            None => c as usize,
+        };
+        if index < self.num_glyphs {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn bitmap(&self, index: usize) -> &[u8] {
+        let start = self.glyphs_offset + index * self.bytes_per_glyph;
+        &self.data[start..(start + self.bytes_per_glyph)]
+    }
+}
+
+/// The Unicode table has an entry for each glyph, which lists the characters it represents (encoded in UTF-8),
+/// then any sequences of characters it represents, and is terminated by `PSF2_SEPARATOR`. We only draw single
+/// characters, so sequences are ignored.
+fn parse_unicode_table(table: &[u8], num_glyphs: usize) -> BTreeMap<char, usize> {
+    let mut map = BTreeMap::new();
+    for (index, entry) in table.split(|&byte| byte == PSF2_SEPARATOR).take(num_glyphs).enumerate() {
+        let characters = match entry.iter().position(|&byte| byte == PSF2_START_SEQUENCE) {
+            Some(sequences_start) => &entry[..sequences_start],
+            None => entry,
+        };
+        for c in core::str::from_utf8(characters).unwrap_or("").chars() {
+            // If more than one glyph claims a character, use the first
+            map.entry(c).or_insert(index);
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Make a PSF2 font with 4x2 glyphs. Glyph `n` has row 0 set to `n` (in the top four bits).
+    fn make_font(num_glyphs: u32, unicode_table: Option<&[u8]>) -> Vec<u8> {
+        let flags = if unicode_table.is_some() { PSF2_HAS_UNICODE_TABLE } else { 0 };
+        let mut data = Vec::new();
+        for field in [PSF2_MAGIC, 0, 32, flags, num_glyphs, 2, 2, 4] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for n in 0..num_glyphs {
+            data.extend_from_slice(&[(n as u8) << 4, 0]);
+        }
+        if let Some(table) = unicode_table {
+            data.extend_from_slice(table);
+        }
+        data
+    }
+
+    #[test]
+    fn parse_header() {
+        let font = Psf2Font::parse(make_font(3, None)).unwrap();
+        assert_eq!(font.width, 4);
+        assert_eq!(font.height, 2);
+        assert_eq!(font.num_glyphs, 3);
+
+        assert_eq!(Psf2Font::parse(vec![0; 8]).err(), Some(FontError::InvalidMagic));
+        let mut truncated = make_font(3, None);
+        truncated.pop();
+        assert_eq!(Psf2Font::parse(truncated).err(), Some(FontError::TooShort));
+    }
+
+    #[test]
+    fn glyphs_without_unicode_table() {
+        let font = Font::Psf2(Psf2Font::parse(make_font(3, None)).unwrap());
+        let glyph = font.glyph('\u{2}');
+        assert!(!glyph.is_set(0, 0));
+        assert!(!glyph.is_set(1, 0));
+        assert!(glyph.is_set(2, 0));
+        assert!(!glyph.is_set(3, 0));
+        assert!(!glyph.is_set(2, 1));
+    }
+
+    #[test]
+    fn glyphs_with_unicode_table() {
+        // Glyph 0 is `a`, glyph 1 is `é` (and the sequence `e` + combining acute), glyph 2 is `?` and `¿`
+        let table = "a\u{ff}é\u{fe}e\u{301}\u{ff}?¿\u{ff}";
+        let table: Vec<u8> = table
+            .chars()
+            .flat_map(|c| match c {
+                '\u{ff}' => vec![PSF2_SEPARATOR],
+                '\u{fe}' => vec![PSF2_START_SEQUENCE],
+                c => c.encode_utf8(&mut [0; 4]).as_bytes().to_vec(),
+            })
+            .collect();
+        let font = Psf2Font::parse(make_font(3, Some(&table))).unwrap();
+        assert_eq!(font.glyph_index('a'), Some(0));
+        assert_eq!(font.glyph_index('é'), Some(1));
+        assert_eq!(font.glyph_index('e'), None);
+        assert_eq!(font.glyph_index('?'), Some(2));
+        assert_eq!(font.glyph_index('¿'), Some(2));
+
+        // Missing characters fall back to `?`
+        let font = Font::Psf2(font);
+        assert!(font.glyph('z').is_set(2, 0));
+    }
+}
@@ -1,24 +1,93 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(test)]
+extern crate std;
 
+pub mod ansi;
 pub mod fb;
-pub use fb::{Framebuffer, Rgb32};
+pub mod font;
+pub mod pointer;
+pub use fb::{Format, Framebuffer, Rgb32};
+pub use font::Font;
 
 use alloc::vec::Vec;
-use core::fmt;
+use ansi::{Action, ControlSequence, Parser};
+use core::{fmt, ops::Range};
+use pointer::{POINTER_HEIGHT, POINTER_WIDTH};
 
-const GLYPH_SIZE: usize = 8;
+const TAB_WIDTH: usize = 8;
+
+/// The colors selected by the basic SGR color parameters (`30`-`37` and `40`-`47`), followed by their bright
+/// variants (`90`-`97` and `100`-`107`). These are also the first 16 colors of the 256-color palette.
+const PALETTE: [Rgb32; 16] = [
+    0x000000, 0xaa0000, 0x00aa00, 0xaa5500, 0x0000aa, 0xaa00aa, 0x00aaaa, 0xaaaaaa, 0x555555, 0xff5555, 0x55ff55,
+    0xffff55, 0x5555ff, 0xff55ff, 0x55ffff, 0xffffff,
+];
 
 pub struct GfxConsole {
     pub framebuffer: Framebuffer,
+    font: Font,
     bg_color: Rgb32,
     text_color: Rgb32,
     cursor_x: usize,
     cursor_y: usize,
+    saved_cursor: (usize, usize),
+    /// Whether the cursor should be shown. It's hidden by default, and can be blinked by toggling this.
+    show_cursor: bool,
+    /// The cell the cursor is currently drawn over, if it's drawn. The cursor is drawn by swapping the foreground
+    /// and background colors of the cell under it.
+    drawn_cursor: Option<(usize, usize)>,
     width: usize,
     height: usize,
     cells: Vec<Cell>,
+    /// The attributes that newly-written characters are given. These are changed by SGR escape sequences.
+    attributes: Attributes,
+    parser: Parser,
+    /// The position of the pointer's hotspot in pixels, if it should be shown.
+    pointer: Option<(usize, usize)>,
+    /// The region of the framebuffer that has been drawn to since the damage was last taken with `take_damage`.
+    damage: Option<Rect>,
+}
+
+/// A rectangular region of the framebuffer, in pixels.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    /// The smallest rectangle that contains both `self` and `other`.
+    pub fn union(self, other: Rect) -> Rect {
+        let x = usize::min(self.x, other.x);
+        let y = usize::min(self.y, other.y);
+        let right = usize::max(self.x + self.width, other.x + other.width);
+        let bottom = usize::max(self.y + self.height, other.y + other.height);
+        Rect { x, y, width: right - x, height: bottom - y }
+    }
+
+    /// The region covered by both `self` and `other`, or `None` if they don't overlap.
+    pub fn intersection(self, other: Rect) -> Option<Rect> {
+        let x = usize::max(self.x, other.x);
+        let y = usize::max(self.y, other.y);
+        let right = usize::min(self.x + self.width, other.x + other.width);
+        let bottom = usize::min(self.y + self.height, other.y + other.height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Rect { x, y, width: right - x, height: bottom - y })
+    }
+
+    pub fn contains(self, x: usize, y: usize) -> bool {
+        x >= self.x && x < (self.x + self.width) && y >= self.y && y < (self.y + self.height)
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -26,116 +95,571 @@ pub struct Cell {
     c: char,
     fg: Rgb32,
     bg: Rgb32,
+    bold: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Attributes {
+    fg: Rgb32,
+    bg: Rgb32,
+    bold: bool,
+    inverse: bool,
 }
 
 impl GfxConsole {
     pub fn new(mut framebuffer: Framebuffer, bg_color: Rgb32, text_color: Rgb32) -> GfxConsole {
-        let width = framebuffer.width / GLYPH_SIZE;
-        let height = framebuffer.height / GLYPH_SIZE;
+        let font = Font::Builtin;
+        let width = framebuffer.width / font.width();
+        let height = framebuffer.height / font.height();
         let mut cells = Vec::with_capacity(width * height);
 
         for _ in 0..(width * height) {
-            cells.push(Cell { c: ' ', fg: text_color, bg: bg_color });
+            cells.push(Cell { c: ' ', fg: text_color, bg: bg_color, bold: false });
         }
 
         framebuffer.clear(bg_color);
-        GfxConsole { framebuffer, bg_color, text_color, cursor_x: 0, cursor_y: 0, width, height, cells }
+        let damage = Rect::new(0, 0, width * font.width(), height * font.height());
+        GfxConsole {
+            framebuffer,
+            font,
+            bg_color,
+            text_color,
+            cursor_x: 0,
+            cursor_y: 0,
+            saved_cursor: (0, 0),
+            show_cursor: false,
+            drawn_cursor: None,
+            width,
+            height,
+            cells,
+            attributes: Attributes { fg: text_color, bg: bg_color, bold: false, inverse: false },
+            parser: Parser::new(),
+            pointer: None,
+            // The whole framebuffer has been cleared
+            damage: Some(damage),
+        }
     }
 
     pub fn clear(&mut self) {
         self.framebuffer.clear(self.bg_color);
+        self.mark_damaged(Rect::new(0, 0, self.framebuffer.width, self.framebuffer.height));
         self.cursor_x = 0;
         self.cursor_y = 0;
 
         for i in 0..(self.width * self.height) {
-            self.cells[i] = Cell { c: ' ', fg: self.text_color, bg: self.bg_color };
+            self.cells[i] = Cell { c: ' ', fg: self.text_color, bg: self.bg_color, bold: false };
+        }
+
+        self.drawn_cursor = None;
+        self.update_cursor();
+        self.draw_pointer();
+    }
+
+    /// Switch to drawing the console with `font`. The cell grid is resized to fit the framebuffer with the new
+    /// font's glyph size, keeping as much of the existing contents (from the top-left) as still fits, and the
+    /// whole console is redrawn. Fonts with glyphs too large to fit a single cell on the framebuffer are ignored.
+    pub fn set_font(&mut self, font: Font) {
+        if font.width() > self.framebuffer.width || font.height() > self.framebuffer.height {
+            return;
         }
+
+        self.font = font;
+        self.rebuild_grid();
+    }
+
+    /// Switch to drawing the console on a new framebuffer, which may be a different size (e.g. because the
+    /// resolution of the display has changed). The cell grid is resized in the same way as by `set_font`. Panics
+    /// if the framebuffer is too small to hold a single cell.
+    pub fn resize(&mut self, framebuffer: Framebuffer) {
+        assert!(framebuffer.width >= self.font.width() && framebuffer.height >= self.font.height());
+        self.framebuffer = framebuffer;
+        self.rebuild_grid();
+    }
+
+    /// Resize the cell grid to fit the framebuffer with the current font, and redraw the whole console.
+    fn rebuild_grid(&mut self) {
+        let width = self.framebuffer.width / self.font.width();
+        let height = self.framebuffer.height / self.font.height();
+
+        let blank = Cell { c: ' ', fg: self.text_color, bg: self.bg_color, bold: false };
+        let mut cells = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                if x < self.width && y < self.height {
+                    cells.push(self.cells[y * self.width + x]);
+                } else {
+                    cells.push(blank);
+                }
+            }
+        }
+
+        self.width = width;
+        self.height = height;
+        self.cells = cells;
+        self.cursor_x = usize::min(self.cursor_x, width - 1);
+        self.cursor_y = usize::min(self.cursor_y, height - 1);
+        self.saved_cursor =
+            (usize::min(self.saved_cursor.0, width - 1), usize::min(self.saved_cursor.1, height - 1));
+        self.drawn_cursor = if self.show_cursor { Some((self.cursor_x, self.cursor_y)) } else { None };
+
+        // The grid might not cover the whole framebuffer, so clear the edges too
+        self.framebuffer.clear(self.bg_color);
+        self.mark_damaged(Rect::new(0, 0, self.framebuffer.width, self.framebuffer.height));
+        for y in 0..height {
+            for x in 0..width {
+                self.draw_cell(x, y);
+            }
+        }
+        self.draw_pointer();
+    }
+
+    /// Take the region of the framebuffer that has changed since this was last called. Returns `None` if
+    /// nothing has been drawn. Only this region needs to be flushed to the display.
+    pub fn take_damage(&mut self) -> Option<Rect> {
+        self.damage.take()
+    }
+
+    /// Copy the parts of the framebuffer that have changed to `front`. This is used when the console draws into an
+    /// off-screen back buffer, so partly-drawn changes are never visible. Returns the region that was copied,
+    /// which then needs to be flushed to the display.
+    pub fn present(&mut self, front: &mut Framebuffer) -> Option<Rect> {
+        let damage = self.take_damage()?;
+        self.framebuffer.copy_to(front, damage);
+        Some(damage)
+    }
+
+    fn mark_damaged(&mut self, rect: Rect) {
+        self.damage = Some(match self.damage {
+            Some(damage) => damage.union(rect),
+            None => rect,
+        });
     }
 
     #[inline(always)]
     pub fn put_cell(&mut self, x: usize, y: usize, c: Cell) {
+        // Only rasterize cells that have actually changed
+        if self.cells[y * self.width + x] == c {
+            return;
+        }
         self.cells[y * self.width + x] = c;
-        self.framebuffer.draw_glyph(c.c, x * GLYPH_SIZE, y * GLYPH_SIZE, c.fg);
+        self.redraw_cell(x, y);
     }
-}
 
-impl fmt::Write for GfxConsole {
-    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        /*
-         * We include a small font that only includes ASCII characters, which also allows us to take some shortcuts
-         * here.
-         */
-        assert!(s.is_ascii());
+    /// Show or hide the cursor. The cursor is drawn over the cell that the next character will be written to.
+    pub fn set_cursor_shown(&mut self, shown: bool) {
+        self.show_cursor = shown;
+        self.update_cursor();
+    }
 
-        for c in s.chars() {
-            match c {
-                '\n' => {
-                    self.cursor_x = 0;
-                    self.cursor_y += 1;
-                }
-                '\x08' => {
-                    // XXX: this is a backspace ('\b'), but Rust doesn't have an escape for it
-                    self.cursor_x -= 1;
-                }
-                '\x7f' => {
-                    /*
-                     * This is an ASCII `DEL` code, which deletes the last character. It is
-                     * produced when backspace on a keyboard is pressed.
-                     */
-                    self.cursor_x -= 1;
-                    self.cells[self.cursor_y * self.width + self.cursor_x] =
-                        Cell { c: ' ', fg: self.text_color, bg: self.bg_color };
-                    self.framebuffer.draw_rect(
-                        self.cursor_x * GLYPH_SIZE,
-                        self.cursor_y * GLYPH_SIZE,
-                        GLYPH_SIZE,
-                        GLYPH_SIZE,
-                        self.bg_color,
-                    );
-                }
+    /// Draw the cursor where it should be, erasing it from where it was drawn before if it's moved.
+    fn update_cursor(&mut self) {
+        let cursor = if self.show_cursor { Some((self.cursor_x, self.cursor_y)) } else { None };
+        if cursor == self.drawn_cursor {
+            return;
+        }
+
+        if let Some((x, y)) = core::mem::replace(&mut self.drawn_cursor, cursor) {
+            self.redraw_cell(x, y);
+        }
+        if let Some((x, y)) = cursor {
+            self.redraw_cell(x, y);
+        }
+    }
+
+    /// Erase the cursor, if it's drawn. This is done before the console is changed, so the cursor doesn't need to
+    /// be kept track of as the contents move (e.g. when the console scrolls).
+    fn erase_cursor(&mut self) {
+        if let Some((x, y)) = self.drawn_cursor.take() {
+            self.redraw_cell(x, y);
+        }
+    }
+
+    /// Redraw the cell at `(x, y)`, and then the pointer if the cell is under it.
+    fn redraw_cell(&mut self, x: usize, y: usize) {
+        self.draw_cell(x, y);
+
+        // Redraw the pointer if we've just drawn over part of it
+        if let Some((pointer_x, pointer_y)) = self.pointer {
+            let (columns, rows) = self.cells_under_pointer(pointer_x, pointer_y);
+            if columns.contains(&x) && rows.contains(&y) {
+                self.draw_pointer();
+            }
+        }
+    }
+
+    /// Move the pointer so its hotspot is at `(x, y)` (in pixels), showing it if it's hidden.
+    pub fn move_pointer(&mut self, x: usize, y: usize) {
+        self.hide_pointer();
+        self.pointer = Some((x, y));
+        self.draw_pointer();
+    }
 
-                _ => {
-                    self.put_cell(
-                        self.cursor_x,
-                        self.cursor_y,
-                        Cell { c, fg: self.text_color, bg: self.bg_color },
-                    );
-                    self.cursor_x += 1;
+    pub fn hide_pointer(&mut self) {
+        if let Some((x, y)) = self.pointer.take() {
+            let (columns, rows) = self.cells_under_pointer(x, y);
+            for y in rows {
+                for x in columns.clone() {
+                    self.draw_cell(x, y);
                 }
             }
+        }
+    }
+
+    /// Draw the pointer, if it's shown. It's clipped to the area covered by cells, so that `hide_pointer` can
+    /// always erase it by redrawing the cells underneath it.
+    fn draw_pointer(&mut self) {
+        if let Some((x, y)) = self.pointer {
+            let (clip_width, clip_height) = (self.width * self.font.width(), self.height * self.font.height());
+            pointer::draw_pointer(&mut self.framebuffer, x, y, clip_width, clip_height);
+            let right = usize::min(x + POINTER_WIDTH, clip_width);
+            let bottom = usize::min(y + POINTER_HEIGHT, clip_height);
+            if x < right && y < bottom {
+                self.mark_damaged(Rect::new(x, y, right - x, bottom - y));
+            }
+        }
+    }
+
+    /// Draw the cell at `(x, y)` to the framebuffer, without affecting the pointer.
+    fn draw_cell(&mut self, x: usize, y: usize) {
+        let mut c = self.cells[y * self.width + x];
+        if self.drawn_cursor == Some((x, y)) {
+            core::mem::swap(&mut c.fg, &mut c.bg);
+        }
+        let (glyph_width, glyph_height) = (self.font.width(), self.font.height());
+        let (pixel_x, pixel_y) = (x * glyph_width, y * glyph_height);
+        self.mark_damaged(Rect::new(pixel_x, pixel_y, glyph_width, glyph_height));
+        self.framebuffer.draw_rect(pixel_x, pixel_y, glyph_width, glyph_height, c.bg);
+        if c.c != ' ' {
+            let glyph = self.font.glyph(c.c);
+            if c.bold {
+                self.framebuffer.draw_bold_glyph(&glyph, pixel_x, pixel_y, c.fg);
+            } else {
+                self.framebuffer.draw_glyph(&glyph, pixel_x, pixel_y, c.fg);
+            }
+        }
+    }
+
+    /// The columns and rows of the cells that are (at least partly) covered by a pointer at `(x, y)`.
+    fn cells_under_pointer(&self, x: usize, y: usize) -> (Range<usize>, Range<usize>) {
+        let (glyph_width, glyph_height) = (self.font.width(), self.font.height());
+        let columns =
+            (x / glyph_width).min(self.width)..((x + POINTER_WIDTH).div_ceil(glyph_width)).min(self.width);
+        let rows =
+            (y / glyph_height).min(self.height)..((y + POINTER_HEIGHT).div_ceil(glyph_height)).min(self.height);
+        (columns, rows)
+    }
+
+    /// Create a cell containing `c`, with the current attributes.
+    fn cell(&self, c: char) -> Cell {
+        let Attributes { fg, bg, bold, inverse } = self.attributes;
+        if inverse {
+            Cell { c, fg: bg, bg: fg, bold }
+        } else {
+            Cell { c, fg, bg, bold }
+        }
+    }
+
+    /// Erase the cells from `start` to `end` (exclusive), counted in cells from the top-left of the console.
+    /// Erased cells keep the current background color.
+    fn erase(&mut self, start: usize, end: usize) {
+        let blank = self.cell(' ');
+        for i in start..end {
+            self.put_cell(i % self.width, i / self.width, blank);
+        }
+    }
+
+    fn scroll_up(&mut self) {
+        /*
+         * Move the pixels of every line up, instead of redrawing each cell, as this is much faster. The pointer
+         * is hidden first so it isn't moved with them.
+         */
+        let pointer = self.pointer;
+        self.hide_pointer();
+        let (glyph_width, glyph_height) = (self.font.width(), self.font.height());
+        self.framebuffer.scroll_up(glyph_height, self.height * glyph_height);
+        self.cells.copy_within(self.width.., 0);
+        self.mark_damaged(Rect::new(0, 0, self.width * glyph_width, self.height * glyph_height));
+
+        /*
+         * The last line still holds what was there before the scroll, both in `cells` and on the framebuffer,
+         * so we can erase it like any other line.
+         */
+        self.erase((self.height - 1) * self.width, self.height * self.width);
+
+        if let Some((x, y)) = pointer {
+            self.move_pointer(x, y);
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.cursor_y += 1;
+        if self.cursor_y == self.height {
+            self.scroll_up();
+            self.cursor_y -= 1;
+        }
+    }
 
-            /*
-             * If we've reached the end of the line, advance to the next line.
-             */
-            if self.cursor_x == self.width {
+    fn print(&mut self, c: char) {
+        self.put_cell(self.cursor_x, self.cursor_y, self.cell(c));
+        self.cursor_x += 1;
+
+        // If we've reached the end of the line, advance to the next line
+        if self.cursor_x == self.width {
+            self.cursor_x = 0;
+            self.new_line();
+        }
+    }
+
+    fn execute(&mut self, c: char) {
+        match c {
+            '\n' => {
                 self.cursor_x = 0;
-                self.cursor_y += 1;
+                self.new_line();
+            }
+            '\r' => self.cursor_x = 0,
+            '\t' => self.cursor_x = usize::min((self.cursor_x / TAB_WIDTH + 1) * TAB_WIDTH, self.width - 1),
+            '\x08' => {
+                // XXX: this is a backspace ('\b'), but Rust doesn't have an escape for it
+                self.cursor_x = self.cursor_x.saturating_sub(1);
             }
+            _ => (),
+        }
+    }
+
+    fn escape(&mut self, c: char) {
+        match c {
+            '7' => self.saved_cursor = (self.cursor_x, self.cursor_y),
+            '8' => (self.cursor_x, self.cursor_y) = self.saved_cursor,
+            'c' => {
+                self.attributes =
+                    Attributes { fg: self.text_color, bg: self.bg_color, bold: false, inverse: false };
+                self.clear();
+            }
+            _ => (),
+        }
+    }
+
+    fn control_sequence(&mut self, sequence: ControlSequence) {
+        // We don't support any of the DEC private modes (e.g. hiding the cursor), so ignore them
+        if sequence.private {
+            return;
+        }
+
+        let n = sequence.param_or(0, 1) as usize;
+        match sequence.final_char {
+            // Cursor Up, Down, Forward, and Back
+            'A' => self.cursor_y = self.cursor_y.saturating_sub(n),
+            'B' => self.cursor_y = usize::min(self.cursor_y + n, self.height - 1),
+            'C' => self.cursor_x = usize::min(self.cursor_x + n, self.width - 1),
+            'D' => self.cursor_x = self.cursor_x.saturating_sub(n),
+            // Cursor Next Line and Previous Line
+            'E' => (self.cursor_x, self.cursor_y) = (0, usize::min(self.cursor_y + n, self.height - 1)),
+            'F' => (self.cursor_x, self.cursor_y) = (0, self.cursor_y.saturating_sub(n)),
+            // Cursor Horizontal Absolute
+            'G' => self.cursor_x = usize::min(n - 1, self.width - 1),
+            // Cursor Position. Rows and columns are numbered from 1.
+            'H' | 'f' => {
+                self.cursor_y = usize::min(n - 1, self.height - 1);
+                self.cursor_x = usize::min(sequence.param_or(1, 1) as usize - 1, self.width - 1);
+            }
+            // Vertical Position Absolute
+            'd' => self.cursor_y = usize::min(n - 1, self.height - 1),
+            // Erase in Display
+            'J' => {
+                let cursor = self.cursor_y * self.width + self.cursor_x;
+                match sequence.params().first().copied().unwrap_or(0) {
+                    0 => self.erase(cursor, self.width * self.height),
+                    1 => self.erase(0, cursor + 1),
+                    2 | 3 => self.erase(0, self.width * self.height),
+                    _ => (),
+                }
+            }
+            // Erase in Line
+            'K' => {
+                let line_start = self.cursor_y * self.width;
+                let cursor = line_start + self.cursor_x;
+                match sequence.params().first().copied().unwrap_or(0) {
+                    0 => self.erase(cursor, line_start + self.width),
+                    1 => self.erase(line_start, cursor + 1),
+                    2 => self.erase(line_start, line_start + self.width),
+                    _ => (),
+                }
+            }
+            // Select Graphic Rendition
+            'm' => self.select_graphic_rendition(sequence.params()),
+            // Save and Restore Cursor
+            's' => self.saved_cursor = (self.cursor_x, self.cursor_y),
+            'u' => (self.cursor_x, self.cursor_y) = self.saved_cursor,
+            _ => (),
+        }
+    }
 
-            /*
-             * If we've reached the end of the screen, scroll the console up.
-             */
-            if self.cursor_y == self.height {
-                self.framebuffer.clear(self.bg_color);
-
-                // Copy each line up one, minus the last line
-                for y in 0..(self.height - 1) {
-                    for x in 0..self.width {
-                        let cell_below = self.cells[(y + 1) * self.width + x];
-                        self.put_cell(x, y, cell_below);
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        // No parameters is the same as a single `0`, which resets all attributes
+        if params.is_empty() {
+            self.select_graphic_rendition(&[0]);
+            return;
+        }
+
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => {
+                    self.attributes =
+                        Attributes { fg: self.text_color, bg: self.bg_color, bold: false, inverse: false }
+                }
+                1 => self.attributes.bold = true,
+                22 => self.attributes.bold = false,
+                7 => self.attributes.inverse = true,
+                27 => self.attributes.inverse = false,
+                30..=37 => self.attributes.fg = PALETTE[(param - 30) as usize],
+                38 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.attributes.fg = color;
                     }
                 }
+                39 => self.attributes.fg = self.text_color,
+                40..=47 => self.attributes.bg = PALETTE[(param - 40) as usize],
+                48 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.attributes.bg = color;
+                    }
+                }
+                49 => self.attributes.bg = self.bg_color,
+                90..=97 => self.attributes.fg = PALETTE[(param - 90 + 8) as usize],
+                100..=107 => self.attributes.bg = PALETTE[(param - 100 + 8) as usize],
+                _ => (),
+            }
+        }
+    }
+}
 
-                // Clear the last line
-                for x in 0..self.width {
-                    self.cells[(self.height - 1) * self.width + x] =
-                        Cell { c: ' ', fg: self.text_color, bg: self.bg_color };
+/// Parse the parameters of an extended color (after a `38` or `48` SGR parameter). These are either `5;n`, for
+/// color `n` of the 256-color palette, or `2;r;g;b` for a 24-bit color.
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Rgb32> {
+    match params.next()? {
+        5 => {
+            let index = params.next()?;
+            match index {
+                0..=15 => Some(PALETTE[index as usize]),
+                // A 6x6x6 color cube
+                16..=231 => {
+                    let level = |value: u16| if value == 0 { 0 } else { 55 + value as u32 * 40 };
+                    let index = index - 16;
+                    Some((level(index / 36) << 16) | (level((index / 6) % 6) << 8) | level(index % 6))
                 }
-                self.cursor_x = 0;
-                self.cursor_y -= 1;
+                // A ramp of grays, from dark to light
+                232..=255 => {
+                    let gray = 8 + (index - 232) as u32 * 10;
+                    Some((gray << 16) | (gray << 8) | gray)
+                }
+                _ => None,
+            }
+        }
+        2 => {
+            let (r, g, b) = (params.next()?, params.next()?, params.next()?);
+            Some(((r.min(255) as u32) << 16) | ((g.min(255) as u32) << 8) | b.min(255) as u32)
+        }
+        _ => None,
+    }
+}
+
+impl fmt::Write for GfxConsole {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        /*
+         * Each character takes up a single cell. Characters that the current font doesn't have a glyph for are
+         * drawn as `?`.
+         */
+        self.erase_cursor();
+        for c in s.chars() {
+            match self.parser.advance(c) {
+                Some(Action::Print('\x7f')) => {
+                    /*
+                     * This is an ASCII `DEL` code, which deletes the last character. It is
+                     * produced when backspace on a keyboard is pressed.
+                     */
+                    self.cursor_x = self.cursor_x.saturating_sub(1);
+                    let blank = self.cell(' ');
+                    self.put_cell(self.cursor_x, self.cursor_y, blank);
+                }
+                Some(Action::Print(c)) => self.print(c),
+                Some(Action::Execute(c)) => self.execute(c),
+                Some(Action::Csi(sequence)) => self.control_sequence(sequence),
+                Some(Action::Escape(c)) => self.escape(c),
+                None => (),
             }
         }
+        self.update_cursor();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rect_union() {
+        assert_eq!(Rect::new(0, 0, 8, 8).union(Rect::new(0, 0, 8, 8)), Rect::new(0, 0, 8, 8));
+        assert_eq!(Rect::new(8, 16, 8, 8).union(Rect::new(0, 0, 8, 8)), Rect::new(0, 0, 16, 24));
+        assert_eq!(Rect::new(4, 4, 2, 2).union(Rect::new(0, 0, 16, 16)), Rect::new(0, 0, 16, 16));
+        assert_eq!(Rect::new(0, 8, 32, 8).union(Rect::new(16, 0, 4, 30)), Rect::new(0, 0, 32, 30));
+    }
+
+    #[test]
+    fn resize_keeps_contents() {
+        use core::fmt::Write;
+        use std::vec;
+
+        let mut large = vec![0u32; 64 * 64];
+        let mut small = vec![0u32; 16 * 8];
+        let mut console = GfxConsole::new(
+            Framebuffer::new(large.as_mut_ptr() as *mut u8, 64, 64, 64, Format::Rgb32),
+            0x000000,
+            0xffffff,
+        );
+        write!(console, "abc\ndef").unwrap();
+        assert_eq!((console.cursor_x, console.cursor_y), (3, 1));
+
+        console.resize(Framebuffer::new(small.as_mut_ptr() as *mut u8, 16, 8, 16, Format::Rgb32));
+        assert_eq!((console.width, console.height), (2, 1));
+        assert_eq!(console.cells.iter().map(|cell| cell.c).collect::<Vec<_>>(), ['a', 'b']);
+        assert_eq!((console.cursor_x, console.cursor_y), (1, 0));
+    }
+
+    #[test]
+    fn cursor_follows_writes() {
+        use core::fmt::Write;
+        use std::vec;
+
+        let mut pixels = vec![0u32; 64 * 64];
+        let mut console = GfxConsole::new(
+            Framebuffer::new(pixels.as_mut_ptr() as *mut u8, 64, 64, 64, Format::Rgb32),
+            0x000000,
+            0xffffff,
+        );
+        console.set_cursor_shown(true);
+        assert_eq!(console.drawn_cursor, Some((0, 0)));
+        assert_eq!(pixels[0], 0xffffff);
+
+        write!(console, "ab\nc").unwrap();
+        assert_eq!(console.drawn_cursor, Some((1, 1)));
+        // The cursor has moved off the first cell, which is drawn normally again
+        assert_eq!(pixels[0], 0x000000);
+        assert_eq!(pixels[8 * 64 + 8], 0xffffff);
+
+        console.set_cursor_shown(false);
+        assert_eq!(console.drawn_cursor, None);
+        assert_eq!(pixels[8 * 64 + 8], 0x000000);
+    }
+
+    #[test]
+    fn rect_intersection() {
+        assert_eq!(Rect::new(0, 0, 8, 8).intersection(Rect::new(0, 0, 8, 8)), Some(Rect::new(0, 0, 8, 8)));
+        assert_eq!(Rect::new(0, 0, 8, 8).intersection(Rect::new(4, 6, 8, 8)), Some(Rect::new(4, 6, 4, 2)));
+        assert_eq!(Rect::new(4, 4, 2, 2).intersection(Rect::new(0, 0, 16, 16)), Some(Rect::new(4, 4, 2, 2)));
+        assert_eq!(Rect::new(0, 0, 8, 8).intersection(Rect::new(8, 0, 8, 8)), None);
+        assert_eq!(Rect::new(0, 0, 8, 8).intersection(Rect::new(20, 20, 1, 1)), None);
+    }
+}
@@ -0,0 +1,61 @@
+//! The pointer is drawn on top of the console's cells. We don't need to save what's underneath it, as the console
+//! can redraw the cells it covers whenever it moves.
+
+use crate::fb::Framebuffer;
+
+pub const POINTER_WIDTH: usize = 11;
+pub const POINTER_HEIGHT: usize = 16;
+
+const OUTLINE_COLOR: u32 = 0x000000;
+const FILL_COLOR: u32 = 0xffffff;
+
+/// An arrow, with its point (the pointer's hotspot) at the top-left. `X` is drawn in the outline color, `o` in
+/// the fill color, and spaces are transparent.
+const SPRITE: [&str; POINTER_HEIGHT] = [
+    "X          ",
+    "XX         ",
+    "XoX        ",
+    "XooX       ",
+    "XoooX      ",
+    "XooooX     ",
+    "XoooooX    ",
+    "XooooooX   ",
+    "XoooooooX  ",
+    "XooooooooX ",
+    "XoooooXXXXX",
+    "XooXooX    ",
+    "XoX XooX   ",
+    "XX  XooX   ",
+    "X    XooX  ",
+    "     XXXX  ",
+];
+
+/// Draw the pointer with its hotspot at `(x, y)`. Parts of the pointer outside of `(0, 0)` to `(clip_width,
+/// clip_height)` are not drawn.
+pub fn draw_pointer(framebuffer: &mut Framebuffer, x: usize, y: usize, clip_width: usize, clip_height: usize) {
+    for (row, line) in SPRITE.iter().enumerate() {
+        for (column, pixel) in line.bytes().enumerate() {
+            let color = match pixel {
+                b'X' => OUTLINE_COLOR,
+                b'o' => FILL_COLOR,
+                _ => continue,
+            };
+            if x + column >= clip_width || y + row >= clip_height {
+                continue;
+            }
+            framebuffer.draw_pixel(x + column, y + row, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprite_is_correct_size() {
+        for line in SPRITE {
+            assert_eq!(line.len(), POINTER_WIDTH);
+        }
+    }
+}
@@ -8,6 +8,8 @@ impl Guid {
     pub const UNUSED: Self = Self::parse("00000000-0000-0000-0000-000000000000").unwrap();
     pub const EFI_SYSTEM_PARTITION: Self = Self::parse("c12a7328-f81f-11d2-ba4b-00a0c93ec93b").unwrap();
     pub const LEGACY_MBR_PARTITION: Self = Self::parse("024dee41-33e7-11d3-9d69-0008c781f39f").unwrap();
+    /// Used for partitions holding Linux filesystems, such as ext2.
+    pub const LINUX_FILESYSTEM: Self = Self::parse("0fc63daf-8483-4772-8e79-3d69d8477de4").unwrap();
 
     /// Parse a GUID in the standard text representation as described by Appendix A of the UEFI standard (sometimes
     /// called the "registry format").
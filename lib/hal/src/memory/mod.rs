@@ -57,9 +57,21 @@ macro frame_size($name: ident, $size: expr, $condition: meta) {
     }
 }
 
-frame_size!(Size4KiB, kibibytes(4), cfg(any(target_arch = "x86_64", target_arch = "riscv64")));
-frame_size!(Size2MiB, mebibytes(2), cfg(any(target_arch = "x86_64", target_arch = "riscv64")));
-frame_size!(Size1GiB, gibibytes(1), cfg(any(target_arch = "x86_64", target_arch = "riscv64")));
+frame_size!(
+    Size4KiB,
+    kibibytes(4),
+    cfg(any(target_arch = "x86_64", target_arch = "riscv64", target_arch = "aarch64"))
+);
+frame_size!(
+    Size2MiB,
+    mebibytes(2),
+    cfg(any(target_arch = "x86_64", target_arch = "riscv64", target_arch = "aarch64"))
+);
+frame_size!(
+    Size1GiB,
+    gibibytes(1),
+    cfg(any(target_arch = "x86_64", target_arch = "riscv64", target_arch = "aarch64"))
+);
 
 /// `FrameAllocator` is used to interact with a physical memory manager in a platform-independent way. Methods on
 /// `FrameAllocator` take `&self` and so are expected to use interior-mutability through a type such as `Mutex` to
@@ -61,10 +61,17 @@ where
     /// Install these page tables as the current set.
     unsafe fn switch_to(&self);
 
+    /// Get the highest address that userspace can use in the address space described by these page tables.
+    /// Addresses above it belong to the kernel, or can't be translated in the paging mode the tables are using.
+    fn user_address_space_end(&self) -> VAddr;
+
     /// Get the physical address that a given virtual address is mapped to, if it's mapped. Returns `None` if the
     /// address is not mapped into physical memory.
     fn translate(&self, address: VAddr) -> Option<PAddr>;
 
+    /// Get the flags that the page containing `address` is mapped with, if it's mapped.
+    fn flags(&self, address: VAddr) -> Option<Flags>;
+
     /// Map a `Page` to a `Frame` with the given flags.
     fn map<S, A>(
         &mut self,
@@ -113,6 +120,11 @@ where
     fn unmap<S>(&mut self, page: Page<S>) -> Option<Frame<S>>
     where
         S: FrameSize;
+
+    /// Unmap an area of `size` bytes starting at `virtual_start`, which was mapped with `map_area`. Each part of
+    /// the area is unmapped with whichever page size it was mapped with. Parts of the area that aren't mapped are
+    /// skipped.
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize);
 }
 
 #[cfg(test)]
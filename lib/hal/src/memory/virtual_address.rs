@@ -30,7 +30,17 @@ impl VAddr {
      */
     cfg_if! {
         if #[cfg(any(target_arch = "x86_64", feature = "platform_rv64_virt"))] {
-            /// Canonicalise this virtual address. On x86_64 and RV64-Sv48, that involves making
+            /// Canonicalise this virtual address. On x86_64 and RV64, the number of bits of virtual address that
+            /// are translated depends on the paging mode picked at boot, which could be 48 bits (4-level paging or
+            /// Sv48) or 57 bits (5-level paging or Sv57). We sign-extend bits 57..64 from bit 56, which is
+            /// correct for both - addresses that are only canonical with 57-bit virtual addresses are rejected
+            /// by the page tables when they're used with a 48-bit mode.
+            pub const fn canonicalise(self) -> VAddr {
+                const SIGN_EXTENSION: usize = 0xfe00_0000_0000_0000;
+                VAddr((SIGN_EXTENSION * ((self.0 >> 56) & 0b1)) | (self.0 & ((1 << 57) - 1)))
+            }
+        } else if #[cfg(target_arch = "aarch64")] {
+            /// Canonicalise this virtual address. On AArch64 (with 48-bit virtual addresses), that involves making
             /// sure that bits 48..64 are sign extended from bit 47.
             pub const fn canonicalise(self) -> VAddr {
                 const SIGN_EXTENSION: usize = 0o177777_000_000_000_000_0000;
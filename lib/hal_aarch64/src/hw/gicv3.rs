@@ -0,0 +1,229 @@
+//! Driver for version 3 of Arm's Generic Interrupt Controller (GICv3). The GIC is made up of a Distributor, which
+//! routes Shared Peripheral Interrupts (SPIs) to CPUs, a Redistributor for each CPU, which handles the CPU's
+//! private interrupts (SGIs and PPIs), and a CPU interface on each CPU, which is accessed through system
+//! registers.
+//! The architecture is described in the GICv3 and GICv4 Architecture Specification (Arm IHI 0069).
+
+use super::sysreg::sysreg;
+use bit_field::BitField;
+use core::arch::asm;
+use hal::memory::VAddr;
+use volatile::Volatile;
+
+sysreg!(IccSreEl1, "icc_sre_el1");
+sysreg!(IccPmrEl1, "icc_pmr_el1");
+sysreg!(IccBpr1El1, "icc_bpr1_el1");
+sysreg!(IccIgrpen1El1, "icc_igrpen1_el1");
+sysreg!(IccIar1El1, "icc_iar1_el1");
+sysreg!(IccEoir1El1, "icc_eoir1_el1");
+
+/// Interrupt IDs below this are private to each CPU, and are configured through its Redistributor. IDs `0..16`
+/// are Software Generated Interrupts (SGIs), and `16..32` are Private Peripheral Interrupts (PPIs).
+pub const FIRST_SPI: u32 = 32;
+/// The priority given to every interrupt. We don't currently make use of interrupt priorities.
+const DEFAULT_PRIORITY: u8 = 0xa0;
+/// The size of the area of each CPU's Redistributor: a frame for its control registers, and a frame for its SGIs
+/// and PPIs.
+const REDISTRIBUTOR_STRIDE: usize = 0x20000;
+
+#[repr(C)]
+pub struct Distributor {
+    control: Volatile<u32>,
+    typer: Volatile<u32>,
+    _reserved0: [u8; 0x78],
+    group: Volatile<[u32; 32]>,
+    set_enable: Volatile<[u32; 32]>,
+    clear_enable: Volatile<[u32; 32]>,
+    _set_pending: [u32; 32],
+    clear_pending: Volatile<[u32; 32]>,
+    _reserved1: [u8; 0x100],
+    priority: Volatile<[u8; 1024]>,
+    _reserved2: [u8; 0x400],
+    config: Volatile<[u32; 64]>,
+    _reserved3: [u8; 0x5300],
+    routing: Volatile<[u64; 1020]>,
+}
+
+impl Distributor {
+    const CTLR_ENABLE_GROUP_1: u32 = 1 << 1;
+    const CTLR_AFFINITY_ROUTING: u32 = 1 << 4;
+    const CTLR_REGISTER_WRITE_PENDING: u32 = 1 << 31;
+
+    fn wait_for_writes(&self) {
+        while self.control.read() & Self::CTLR_REGISTER_WRITE_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The registers of a single CPU's Redistributor, including its SGI and PPI frame.
+#[repr(C)]
+pub struct Redistributor {
+    _control: [u8; 4],
+    _iidr: [u8; 4],
+    typer: Volatile<u64>,
+    _status: [u8; 4],
+    waker: Volatile<u32>,
+    _reserved0: [u8; 0xffe8],
+    /*
+     * The SGI and PPI frame starts here.
+     */
+    _reserved1: [u8; 0x80],
+    group: Volatile<u32>,
+    _reserved2: [u8; 0x7c],
+    set_enable: Volatile<u32>,
+    _reserved3: [u8; 0x7c],
+    clear_enable: Volatile<u32>,
+    _reserved4: [u8; 0x27c],
+    priority: Volatile<[u8; 32]>,
+    _reserved5: [u8; 0x7e0],
+    config: Volatile<[u32; 2]>,
+}
+
+impl Redistributor {
+    const WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+    const WAKER_CHILDREN_ASLEEP: usize = 2;
+    const TYPER_LAST: usize = 4;
+}
+
+pub struct GicV3 {
+    distributor: &'static Distributor,
+    /// The Redistributor of the CPU we're running on.
+    // TODO: this will need to be per-CPU when we bring up secondary CPUs
+    redistributor: &'static Redistributor,
+    num_interrupts: u32,
+}
+
+impl GicV3 {
+    /// Create a `GicV3` from the virtual addresses the Distributor and the Redistributor region are mapped at. The
+    /// Redistributor of the running CPU is found by its affinity, which is given by `mpidr`.
+    pub unsafe fn new(distributor: VAddr, redistributors: VAddr, mpidr: u64) -> GicV3 {
+        let distributor = unsafe { &*(distributor.ptr() as *const Distributor) };
+        let num_interrupts = u32::min(32 * (distributor.typer.read().get_bits(0..5) + 1), 1020);
+
+        /*
+         * Each Redistributor reports the affinity of the CPU it belongs to, in the same format as the affinity
+         * fields of `MPIDR_EL1` (but with `Aff3` next to the others).
+         */
+        let affinity = mpidr.get_bits(0..24) | (mpidr.get_bits(32..40) << 24);
+        let mut address = redistributors;
+        let redistributor = loop {
+            let redistributor = unsafe { &*(address.ptr() as *const Redistributor) };
+            let typer = redistributor.typer.read();
+            if typer.get_bits(32..64) == affinity {
+                break redistributor;
+            }
+            assert!(!typer.get_bit(Redistributor::TYPER_LAST), "Couldn't find Redistributor of the running CPU");
+            address += REDISTRIBUTOR_STRIDE;
+        };
+
+        GicV3 { distributor, redistributor, num_interrupts }
+    }
+
+    pub fn num_interrupts(&self) -> u32 {
+        self.num_interrupts
+    }
+
+    /// Initialize the Distributor, the running CPU's Redistributor, and its CPU interface. Every interrupt starts
+    /// off disabled, and is configured as a level-triggered Group 1 interrupt that's routed to the running CPU.
+    pub fn init(&self, mpidr: u64) {
+        let distributor = self.distributor;
+        distributor.control.write(0);
+        distributor.wait_for_writes();
+
+        let routing = mpidr.get_bits(0..24) | (mpidr.get_bits(32..40) << 32);
+        for i in (FIRST_SPI / 32)..(self.num_interrupts / 32) {
+            distributor.group[i as usize].write(u32::MAX);
+            distributor.clear_enable[i as usize].write(u32::MAX);
+            distributor.clear_pending[i as usize].write(u32::MAX);
+        }
+        for interrupt in FIRST_SPI..self.num_interrupts {
+            distributor.priority[interrupt as usize].write(DEFAULT_PRIORITY);
+            distributor.routing[interrupt as usize].write(routing);
+        }
+        for i in (FIRST_SPI / 16)..(self.num_interrupts / 16) {
+            distributor.config[i as usize].write(0);
+        }
+        distributor.wait_for_writes();
+        distributor.control.write(Distributor::CTLR_AFFINITY_ROUTING | Distributor::CTLR_ENABLE_GROUP_1);
+        distributor.wait_for_writes();
+
+        /*
+         * Wake up the Redistributor, and configure the CPU's private interrupts.
+         */
+        let redistributor = self.redistributor;
+        redistributor.waker.write(redistributor.waker.read() & !Redistributor::WAKER_PROCESSOR_SLEEP);
+        while redistributor.waker.read().get_bit(Redistributor::WAKER_CHILDREN_ASLEEP) {
+            core::hint::spin_loop();
+        }
+        redistributor.group.write(u32::MAX);
+        redistributor.clear_enable.write(u32::MAX);
+        for interrupt in 0..FIRST_SPI {
+            redistributor.priority[interrupt as usize].write(DEFAULT_PRIORITY);
+        }
+
+        /*
+         * Enable the system register interface to the CPU interface, and then accept Group 1 interrupts of any
+         * priority.
+         */
+        unsafe {
+            IccSreEl1::write(IccSreEl1::read() | 0b1);
+            asm!("isb");
+            IccPmrEl1::write(0xff);
+            IccBpr1El1::write(0);
+            IccIgrpen1El1::write(1);
+            asm!("isb");
+        }
+    }
+
+    pub fn enable_interrupt(&self, interrupt: u32) {
+        if interrupt < FIRST_SPI {
+            self.redistributor.set_enable.write(1 << interrupt);
+        } else {
+            self.distributor.set_enable[(interrupt / 32) as usize].write(1 << (interrupt % 32));
+        }
+    }
+
+    pub fn disable_interrupt(&self, interrupt: u32) {
+        if interrupt < FIRST_SPI {
+            self.redistributor.clear_enable.write(1 << interrupt);
+        } else {
+            self.distributor.clear_enable[(interrupt / 32) as usize].write(1 << (interrupt % 32));
+        }
+    }
+
+    /// Configure whether an interrupt is edge-triggered or level-sensitive. SGIs are always edge-triggered.
+    pub fn set_edge_triggered(&self, interrupt: u32, edge_triggered: bool) {
+        let config = if interrupt < FIRST_SPI {
+            &self.redistributor.config[(interrupt / 16) as usize]
+        } else {
+            &self.distributor.config[(interrupt / 16) as usize]
+        };
+        let mut value = config.read();
+        value.set_bit(2 * (interrupt as usize % 16) + 1, edge_triggered);
+        config.write(value);
+    }
+
+    /// Acknowledge the highest-priority pending interrupt, returning its ID. Returns `None` if there isn't an
+    /// interrupt pending (the interrupt was spurious). Each acknowledged interrupt must be completed with
+    /// `end_of_interrupt`.
+    pub fn acknowledge(&self) -> Option<u32> {
+        let interrupt = IccIar1El1::read() as u32;
+        // IDs `1020..1024` are special, and are used to signal spurious interrupts
+        if (1020..1024).contains(&interrupt) {
+            None
+        } else {
+            unsafe {
+                asm!("dsb sy");
+            }
+            Some(interrupt)
+        }
+    }
+
+    pub fn end_of_interrupt(&self, interrupt: u32) {
+        unsafe {
+            IccEoir1El1::write(interrupt as u64);
+            asm!("isb");
+        }
+    }
+}
@@ -0,0 +1,7 @@
+pub mod gicv3;
+pub mod pl011;
+pub mod psci;
+pub mod sysreg;
+
+#[cfg(feature = "qemu")]
+pub mod qemu;
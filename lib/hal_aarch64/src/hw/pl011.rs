@@ -0,0 +1,78 @@
+use bit_field::BitField;
+use hal::memory::VAddr;
+use volatile::Volatile;
+
+/// The register block of an Arm PrimeCell UART (PL011). The registers are described in the PL011 Technical
+/// Reference Manual (Arm DDI 0183).
+#[repr(C)]
+pub struct Registers {
+    data: Volatile<u32>,
+    _receive_status: Volatile<u32>,
+    _reserved0: [u32; 4],
+    flags: Volatile<u32>,
+    _reserved1: [u32; 2],
+    integer_baud_rate: Volatile<u32>,
+    fractional_baud_rate: Volatile<u32>,
+    line_control: Volatile<u32>,
+    control: Volatile<u32>,
+    _interrupt_fifo_level: Volatile<u32>,
+    interrupt_mask: Volatile<u32>,
+    _raw_interrupt_status: Volatile<u32>,
+    _masked_interrupt_status: Volatile<u32>,
+    interrupt_clear: Volatile<u32>,
+}
+
+pub struct Pl011<'a>(&'a Registers);
+
+impl<'a> Pl011<'a> {
+    const FLAG_RECEIVE_FIFO_EMPTY: usize = 4;
+    const FLAG_TRANSMIT_FIFO_FULL: usize = 5;
+
+    pub unsafe fn new(addr: VAddr) -> Pl011<'a> {
+        Pl011(unsafe { &*(addr.ptr() as *const Registers) })
+    }
+
+    /// Initialize the UART, assuming its reference clock is 24MHz (as it is on QEMU's `virt` machine).
+    pub fn init(&self) {
+        let registers = self.0;
+
+        // Disable the UART while it's configured
+        registers.control.write(0);
+        while !registers.flags.read().get_bit(Self::FLAG_RECEIVE_FIFO_EMPTY) {
+            registers.data.read();
+        }
+
+        // Set a baud rate of 115200 (24MHz / (16 * 115200) = 13.02, and the fraction is in 64ths)
+        registers.integer_baud_rate.write(13);
+        registers.fractional_baud_rate.write(1);
+        // 8 data bits, no parity, one stop bit, with FIFOs enabled
+        registers.line_control.write((0b11 << 5) | (1 << 4));
+        // Clear any pending interrupts, and interrupt on data received (or when received data times out)
+        registers.interrupt_clear.write(0x7ff);
+        registers.interrupt_mask.write((1 << 4) | (1 << 6));
+        // Enable the UART, with both transmit and receive enabled
+        registers.control.write((1 << 0) | (1 << 8) | (1 << 9));
+    }
+
+    pub fn write(&self, data: u8) {
+        while self.0.flags.read().get_bit(Self::FLAG_TRANSMIT_FIFO_FULL) {}
+        self.0.data.write(data as u32);
+    }
+
+    pub fn read(&self) -> Option<u8> {
+        if self.0.flags.read().get_bit(Self::FLAG_RECEIVE_FIFO_EMPTY) {
+            None
+        } else {
+            Some(self.0.data.read() as u8)
+        }
+    }
+}
+
+impl<'a> core::fmt::Write for Pl011<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.write(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+//! The Power State Coordination Interface is implemented by firmware running at a higher exception level (or by
+//! the hypervisor, when running virtualized), and manages the power of CPUs and of the system as a whole. Calls
+//! are made with `hvc` or `smc`, which is described by the `method` property of the device tree's `psci` node.
+
+use core::arch::asm;
+
+const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// How calls to PSCI are made.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Conduit {
+    Hvc,
+    Smc,
+}
+
+impl Conduit {
+    /// Decode the `method` property of the device tree's `psci` node.
+    pub fn from_method(method: &str) -> Option<Conduit> {
+        match method {
+            "hvc" => Some(Conduit::Hvc),
+            "smc" => Some(Conduit::Smc),
+            _ => None,
+        }
+    }
+}
+
+/// Power the system off. This only returns if the call fails.
+pub fn system_off(conduit: Conduit) {
+    call(conduit, PSCI_SYSTEM_OFF);
+}
+
+/// Reset the system. This only returns if the call fails.
+pub fn system_reset(conduit: Conduit) {
+    call(conduit, PSCI_SYSTEM_RESET);
+}
+
+fn call(conduit: Conduit, function: u32) -> i64 {
+    let result: i64;
+    unsafe {
+        match conduit {
+            Conduit::Hvc => asm!("hvc #0", inlateout("x0") function as u64 => result),
+            Conduit::Smc => asm!("smc #0", inlateout("x0") function as u64 => result),
+        }
+    }
+    result
+}
@@ -0,0 +1,33 @@
+use core::arch::asm;
+
+/// Exit codes to use with `exit`. These are the same as the codes QEMU exits with when the x86_64 kernel uses
+/// its exit port, so whatever invokes QEMU can handle every platform in the same way.
+#[derive(Clone, Copy, Debug)]
+pub enum ExitCode {
+    Success,
+    Failed,
+    /// The kernel's test runner finished, but some tests failed.
+    TestsFailed,
+}
+
+/// Exit QEMU with the given exit code, using the `SYS_EXIT` semihosting call. This only works if QEMU has been
+/// started with semihosting enabled - otherwise, it causes an exception.
+pub fn exit(exit_code: ExitCode) -> ! {
+    const SYS_EXIT: u64 = 0x18;
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+    let code = match exit_code {
+        ExitCode::Success => 0,
+        ExitCode::Failed => 0x23,
+        ExitCode::TestsFailed => 0x25,
+    };
+    /*
+     * On AArch64, the parameter of `SYS_EXIT` is a pointer to a block holding the reason for the exit, and then
+     * the code to exit with.
+     */
+    let block: [u64; 2] = [ADP_STOPPED_APPLICATION_EXIT, code];
+    unsafe {
+        asm!("hlt #0xf000", in("x0") SYS_EXIT, in("x1") &block as *const [u64; 2]);
+    }
+    unreachable!()
+}
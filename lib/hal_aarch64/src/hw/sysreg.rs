@@ -0,0 +1,227 @@
+/*
+ * Copyright 2022, Isaac Woods
+ * SPDX-License-Identifier: MPL-2.0
+ */
+
+use bit_field::BitField;
+use core::arch::asm;
+use hal::memory::{PAddr, VAddr};
+
+/// Define a type for accessing a system register with `mrs` and `msr`. Writes are unsafe, as most system
+/// registers control the state of the processor in some way.
+macro_rules! sysreg {
+    ($(#[$attr:meta])* $name:ident, $reg:literal) => {
+        $(#[$attr])*
+        pub struct $name;
+
+        impl $name {
+            #[inline(always)]
+            pub fn read() -> u64 {
+                let value: u64;
+                unsafe {
+                    core::arch::asm!(concat!("mrs {}, ", $reg), out(reg) value, options(nomem, nostack));
+                }
+                value
+            }
+
+            #[inline(always)]
+            pub unsafe fn write(value: u64) {
+                unsafe {
+                    core::arch::asm!(concat!("msr ", $reg, ", {}"), in(reg) value, options(nostack));
+                }
+            }
+        }
+    };
+}
+pub(crate) use sysreg;
+
+sysreg!(
+    /// The Exception Level the processor is currently running at, in bits `2..4`.
+    CurrentEl,
+    "currentel"
+);
+sysreg!(
+    /// Controls the MMU and caches at EL1 and EL0.
+    SctlrEl1,
+    "sctlr_el1"
+);
+sysreg!(
+    /// Controls how virtual addresses are translated at EL1 and EL0.
+    TcrEl1,
+    "tcr_el1"
+);
+sysreg!(
+    /// The memory attributes that the `AttrIndx` field of a page table entry selects between.
+    MairEl1,
+    "mair_el1"
+);
+sysreg!(
+    /// The address of the exception vector table for exceptions taken to EL1.
+    VbarEl1,
+    "vbar_el1"
+);
+sysreg!(
+    /// Describes the cause of a synchronous exception taken to EL1.
+    EsrEl1,
+    "esr_el1"
+);
+sysreg!(
+    /// Holds the faulting virtual address of some synchronous exceptions taken to EL1.
+    FarEl1,
+    "far_el1"
+);
+sysreg!(
+    /// The address to return to when returning from an exception taken to EL1.
+    ElrEl1,
+    "elr_el1"
+);
+sysreg!(
+    /// The processor state to restore when returning from an exception taken to EL1.
+    SpsrEl1,
+    "spsr_el1"
+);
+sysreg!(
+    /// The stack pointer used at EL0.
+    SpEl0,
+    "sp_el0"
+);
+sysreg!(
+    /// A register that software running at EL1 is free to use. The kernel uses it to hold a pointer to the
+    /// per-CPU data.
+    TpidrEl1,
+    "tpidr_el1"
+);
+sysreg!(
+    /// The thread pointer of EL0.
+    TpidrEl0,
+    "tpidr_el0"
+);
+sysreg!(
+    /// Identifies the processor in a multiprocessor system.
+    MpidrEl1,
+    "mpidr_el1"
+);
+sysreg!(
+    /// The frequency of the system counter, in Hz.
+    CntfrqEl0,
+    "cntfrq_el0"
+);
+sysreg!(
+    /// The value of the system counter, as seen by the physical timer.
+    CntpctEl0,
+    "cntpct_el0"
+);
+sysreg!(
+    /// Controls which of the generic timer's registers can be accessed from EL0. Bit `0` (`EL0PCTEN`) allows EL0
+    /// to read `CNTPCT_EL0`.
+    CntkctlEl1,
+    "cntkctl_el1"
+);
+sysreg!(
+    /// Writing this sets the EL1 physical timer to fire after the given number of system counter ticks.
+    CntpTvalEl0,
+    "cntp_tval_el0"
+);
+sysreg!(
+    /// Controls the EL1 physical timer.
+    CntpCtlEl0,
+    "cntp_ctl_el0"
+);
+sysreg!(
+    /// Describes which instructions are implemented. Bits `60..64` are non-zero if the `RNDR` register is.
+    IdAa64Isar0El1,
+    "id_aa64isar0_el1"
+);
+
+/// The Exception Level the processor is running at.
+pub fn current_el() -> u8 {
+    CurrentEl::read().get_bits(2..4) as u8
+}
+
+/// Whether the processor has a random number generator that can be read through `RNDR`.
+pub fn rndr_supported() -> bool {
+    IdAa64Isar0El1::read().get_bits(60..64) != 0
+}
+
+/// Read a random number from the processor's random number generator through `RNDR`. Returns `None` if the
+/// generator couldn't produce one in a reasonable time. This must only be called if `rndr_supported` returns
+/// `true`.
+pub fn read_rndr() -> Option<u64> {
+    let value: u64;
+    let failed: u64;
+    unsafe {
+        /*
+         * `RNDR` is `s3_3_c2_c4_0`, which we have to name by its encoding as not every assembler knows it. A
+         * failed read sets the `Z` flag.
+         */
+        asm!(
+            "
+                mrs {}, s3_3_c2_c4_0
+                cset {}, eq
+            ",
+            out(reg) value,
+            out(reg) failed,
+            options(nomem, nostack)
+        );
+    }
+    (failed == 0).then_some(value)
+}
+
+/// Unmask IRQs at the current Exception Level.
+pub fn enable_interrupts() {
+    unsafe {
+        asm!("msr daifclr, #2", options(nomem, nostack));
+    }
+}
+
+/// Mask IRQs at the current Exception Level.
+pub fn disable_interrupts() {
+    unsafe {
+        asm!("msr daifset, #2", options(nomem, nostack));
+    }
+}
+
+pub struct Vbar;
+
+impl Vbar {
+    /// Install the exception vector table at `address`. The table must be 2KiB-aligned.
+    pub fn set(address: VAddr) {
+        assert!(address.is_aligned(0x800), "Exception vector table must be 2KiB-aligned");
+        unsafe {
+            VbarEl1::write(usize::from(address) as u64);
+            asm!("isb");
+        }
+    }
+}
+
+/// The translation table base registers. `TTBR0_EL1` is used to translate the lower half of the address space,
+/// and `TTBR1_EL1` the upper half.
+pub struct Ttbr;
+
+impl Ttbr {
+    pub fn read_ttbr0() -> PAddr {
+        let value: u64;
+        unsafe {
+            asm!("mrs {}, ttbr0_el1", out(reg) value, options(nomem, nostack));
+        }
+        PAddr::new((value.get_bits(12..48) << 12) as usize).unwrap()
+    }
+
+    /// Install a set of page tables for both halves of the address space, and invalidate the TLB.
+    pub unsafe fn write_both(table: PAddr) {
+        unsafe {
+            asm!(
+                "
+                    msr ttbr0_el1, {0}
+                    msr ttbr1_el1, {0}
+                    isb
+                    tlbi vmalle1is
+                    dsb ish
+                    isb
+                ",
+                in(reg) usize::from(table),
+                options(nostack)
+            );
+        }
+    }
+}
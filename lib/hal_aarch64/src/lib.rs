@@ -0,0 +1,76 @@
+#![no_std]
+
+pub mod hw;
+pub mod paging;
+
+pub mod platform_virt;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "platform_aarch64_virt")] {
+        pub use platform_virt as platform;
+    } else {
+        pub mod platform {
+            /*
+             * If a platform feature hasn't been selected, we define what's effectively a fake platform
+             * module. This documents the desired API a platform should provide, but also provides
+             * information to tools such as `rust-analyzer` such as to allow completions without
+             * faffing about with fake platform features.
+             */
+            pub mod memory {
+                use hal::memory::PAddr;
+
+                pub const DRAM_START: PAddr = PAddr::new(0x0).unwrap();
+                pub const SEED_ADDR: PAddr = PAddr::new(0x0).unwrap();
+                pub const RAMDISK_ADDR: PAddr = PAddr::new(0x0).unwrap();
+            }
+
+            pub const VIRTUAL_ADDRESS_BITS: usize = 48;
+            pub type PageTableImpl = crate::paging::PageTableImpl<crate::paging::Level4>;
+
+            pub mod kernel_map {
+                use hal::memory::{PAddr, VAddr, Bytes};
+
+                /// The P4 entry that is duplicated across all page tables as the kernel's entry.
+                pub const KERNEL_P4_ENTRY: usize = 0;
+
+                pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_ff80_0000_0000);
+                pub const PHYSICAL_MAP_BASE: VAddr = KERNEL_ADDRESS_SPACE_START;
+                /// The highest address userspace can use.
+                pub const USER_ADDRESS_SPACE_END: VAddr = VAddr::new(0x0);
+
+                /// Access a given physical address through the physical mapping. This cannot be used until the kernel page tables
+                /// have been switched to.
+                ///
+                /// # Safety
+                /// This itself is safe, because to cause memory unsafety a raw pointer must be created and accessed from the
+                /// `VAddr`, which is unsafe.
+                pub fn physical_to_virtual(address: PAddr) -> VAddr {
+                    PHYSICAL_MAP_BASE + usize::from(address)
+                }
+
+                pub const KERNEL_STACKS_BASE: VAddr = VAddr::new(0x0);
+                pub const STACK_SLOT_SIZE: Bytes = 0;
+                pub const MAX_TASKS: usize = 0;
+
+                /// The kernel starts at -2GiB. The kernel image is loaded directly at this address, and the following space until
+                /// the top of memory is managed dynamically and contains the boot info structures, memory map, and kernel heap.
+                pub const KERNEL_BASE: VAddr = VAddr::new(0xffff_ffff_8000_0000);
+            }
+
+            /// Cache maintenance operations for memory accessed by devices through DMA.
+            pub mod cache {
+                use hal::memory::PAddr;
+
+                /// Write any dirty cache lines covering the given area of memory back to memory.
+                pub unsafe fn clean(_address: PAddr, _size: usize) {}
+
+                /// Discard any cache lines covering the given area of memory, so that it's next read from memory.
+                pub unsafe fn invalidate(_address: PAddr, _size: usize) {}
+
+                /// Write any dirty cache lines covering the given area of memory back to memory, and then discard
+                /// them.
+                pub unsafe fn clean_and_invalidate(_address: PAddr, _size: usize) {}
+            }
+        }
+    }
+}
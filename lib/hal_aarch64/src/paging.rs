@@ -0,0 +1,741 @@
+/*
+ * Copyright 2022, Isaac Woods
+ * SPDX-License-Identifier: MPL-2.0
+ */
+
+use crate::hw::sysreg::{MairEl1, TcrEl1, Ttbr};
+use bit_field::BitField;
+use bitflags::bitflags;
+use core::{
+    arch::asm,
+    cmp,
+    fmt,
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Index, IndexMut},
+};
+use hal::memory::{
+    Flags,
+    Frame,
+    FrameAllocator,
+    FrameSize,
+    PAddr,
+    Page,
+    PageTable,
+    PagingError,
+    Size1GiB,
+    Size2MiB,
+    Size4KiB,
+    VAddr,
+};
+
+/*
+ * We use the 4KiB translation granule with 48-bit virtual addresses, which gives us four levels of tables that
+ * are structured in the same way as on x86_64 and RISC-V's Sv48. AArch64 translates the lower and upper halves
+ * of the address space with separate tables (pointed to by `TTBR0_EL1` and `TTBR1_EL1`), but because both halves
+ * index into the top-level table with the same bits, we install the same set of tables in both. This means a set
+ * of page tables maps both userspace and the kernel, like on other architectures.
+ */
+
+/// The value of `MAIR_EL1`, which defines the memory types that page table entries can select between. Attribute
+/// `0` is Normal, write-back cacheable memory, and attribute `1` is Device-nGnRE memory.
+pub const MAIR: u64 = 0x04_ff;
+
+/// The value of `TCR_EL1`: 48-bit virtual and physical addresses for both halves, 4KiB granules, and table walks
+/// that are cacheable and inner-shareable.
+pub const TCR: u64 = {
+    const T0SZ: u64 = 16;
+    const T1SZ: u64 = 16 << 16;
+    const WALK_ATTRIBUTES_0: u64 = (0b01 << 8) | (0b01 << 10) | (0b11 << 12);
+    const WALK_ATTRIBUTES_1: u64 = (0b01 << 24) | (0b01 << 26) | (0b11 << 28);
+    const GRANULE_4KIB_1: u64 = 0b10 << 30;
+    const PHYSICAL_ADDRESS_48_BITS: u64 = 0b101 << 32;
+    T0SZ | T1SZ | WALK_ATTRIBUTES_0 | WALK_ATTRIBUTES_1 | GRANULE_4KIB_1 | PHYSICAL_ADDRESS_48_BITS
+};
+
+/// Configure the translation regime to match how we construct page tables. This must be done before the MMU is
+/// enabled.
+pub unsafe fn configure_translation() {
+    unsafe {
+        MairEl1::write(MAIR);
+        TcrEl1::write(TCR);
+        asm!("isb");
+    }
+}
+
+bitflags! {
+    pub struct EntryFlags: u64 {
+        const VALID                     = 1 << 0;
+        /// In entries of tables other than the last level, this marks an entry that points to another table,
+        /// rather than mapping a block of memory.
+        const TABLE                     = 1 << 1;
+        /// In entries of the last level of tables, this must be set for an entry that maps a page.
+        const PAGE                      = 1 << 1;
+        /// Selects memory attribute `1` of `MAIR_EL1` (device memory), rather than attribute `0`.
+        const DEVICE                    = 1 << 2;
+        const USER_ACCESSIBLE           = 1 << 6;
+        const READ_ONLY                 = 1 << 7;
+        const INNER_SHAREABLE           = 0b11 << 8;
+        const ACCESSED                  = 1 << 10;
+        const NOT_GLOBAL                = 1 << 11;
+        const PRIVILEGED_EXECUTE_NEVER  = 1 << 53;
+        const USER_EXECUTE_NEVER        = 1 << 54;
+    }
+}
+
+impl From<Flags> for EntryFlags {
+    fn from(flags: Flags) -> Self {
+        /*
+         * We set the Access flag on every mapping, as we don't track accesses, and otherwise the first access
+         * faults. Executable mappings are only executable at the privilege level they're accessible from.
+         */
+        let execute_never = match (flags.executable, flags.user_accessible) {
+            (true, true) => EntryFlags::PRIVILEGED_EXECUTE_NEVER,
+            (true, false) => EntryFlags::USER_EXECUTE_NEVER,
+            (false, _) => EntryFlags::PRIVILEGED_EXECUTE_NEVER | EntryFlags::USER_EXECUTE_NEVER,
+        };
+
+        EntryFlags::VALID
+            | EntryFlags::ACCESSED
+            | EntryFlags::INNER_SHAREABLE
+            | execute_never
+            | if flags.writable { EntryFlags::empty() } else { EntryFlags::READ_ONLY }
+            | if flags.user_accessible { EntryFlags::USER_ACCESSIBLE } else { EntryFlags::empty() }
+            | if flags.cached { EntryFlags::empty() } else { EntryFlags::DEVICE }
+    }
+}
+
+impl From<EntryFlags> for Flags {
+    fn from(flags: EntryFlags) -> Self {
+        let user_accessible = flags.contains(EntryFlags::USER_ACCESSIBLE);
+        let execute_never =
+            if user_accessible { EntryFlags::USER_EXECUTE_NEVER } else { EntryFlags::PRIVILEGED_EXECUTE_NEVER };
+
+        Flags {
+            writable: !flags.contains(EntryFlags::READ_ONLY),
+            executable: !flags.contains(execute_never),
+            user_accessible,
+            cached: !flags.contains(EntryFlags::DEVICE),
+        }
+    }
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct Entry(u64);
+
+impl Entry {
+    pub fn unused() -> Self {
+        Self(0)
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.0)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.flags().contains(EntryFlags::VALID)
+    }
+
+    /// Returns `true` if this entry maps a block of memory, rather than pointing to the next level of table. This
+    /// is only meaningful for entries of tables other than the last level.
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && !self.flags().contains(EntryFlags::TABLE)
+    }
+
+    pub fn address(&self) -> Option<PAddr> {
+        if self.is_valid() {
+            Some(PAddr::new((self.0.get_bits(12..48) as usize) << 12).unwrap())
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, entry: Option<(PAddr, EntryFlags)>) {
+        self.0 = match entry {
+            Some((address, flags)) => usize::from(address) as u64 | (flags | EntryFlags::VALID).bits(),
+            None => 0,
+        };
+    }
+}
+
+impl Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_valid() {
+            f.debug_tuple("Entry").field(&self.address().unwrap()).field(&self.flags()).finish()
+        } else {
+            write!(f, "Not Present")
+        }
+    }
+}
+
+// TODO: lots of this stuff has been duplicated from `hal_riscv`; abstract into `hal`?
+pub enum Level4 {}
+pub enum Level3 {}
+pub enum Level2 {}
+pub enum Level1 {}
+
+pub trait TableLevel {}
+impl TableLevel for Level4 {}
+impl TableLevel for Level3 {}
+impl TableLevel for Level2 {}
+impl TableLevel for Level1 {}
+
+/// Tables of levels that implement `HierarchicalLevel` are page tables whose entries are other
+/// tables, as opposed to actual frames (like in P1s). This makes accessing the next level
+/// type-safe, as the `next_table` methods are only implemented for tables that have child tables.
+pub trait HierarchicalLevel: TableLevel {
+    type NextLevel: TableLevel;
+}
+impl HierarchicalLevel for Level4 {
+    type NextLevel = Level3;
+}
+impl HierarchicalLevel for Level3 {
+    type NextLevel = Level2;
+}
+impl HierarchicalLevel for Level2 {
+    type NextLevel = Level1;
+}
+
+const ENTRY_COUNT: usize = 512;
+
+#[repr(C, align(4096))]
+pub struct Table<L>
+where
+    L: TableLevel,
+{
+    entries: [Entry; ENTRY_COUNT],
+    _phantom: PhantomData<L>,
+}
+
+impl<L> Index<usize> for Table<L>
+where
+    L: TableLevel,
+{
+    type Output = Entry;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.entries[index]
+    }
+}
+
+impl<L> IndexMut<usize> for Table<L>
+where
+    L: TableLevel,
+{
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.entries[index]
+    }
+}
+
+impl<L> Table<L>
+where
+    L: TableLevel,
+{
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.set(None);
+        }
+    }
+}
+
+impl<L> Table<L>
+where
+    L: HierarchicalLevel,
+{
+    /// Get a reference to the table at the given `index`, assuming the entirity of
+    /// the physical address space is mapped from `physical_base`.
+    pub fn next_table(&self, index: usize, physical_base: VAddr) -> Option<&Table<L::NextLevel>> {
+        if self[index].is_leaf() {
+            return None;
+        }
+        self[index]
+            .address()
+            .map(|physical_address| physical_base + usize::from(physical_address))
+            .map(|virtual_address| unsafe { &*(virtual_address.ptr()) })
+    }
+
+    /// Get a mutable reference to the table at the given `index`, assuming the entirity of
+    /// the physical address space is mapped from `physical_base`.
+    pub fn next_table_mut(&mut self, index: usize, physical_base: VAddr) -> Option<&mut Table<L::NextLevel>> {
+        if self[index].is_leaf() {
+            return None;
+        }
+        self[index]
+            .address()
+            .map(|physical_address| physical_base + usize::from(physical_address))
+            .map(|virtual_address| unsafe { &mut *(virtual_address.mut_ptr()) })
+    }
+
+    pub fn next_table_create<A>(
+        &mut self,
+        index: usize,
+        allocator: &A,
+        physical_base: VAddr,
+    ) -> Result<&mut Table<L::NextLevel>, PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        /*
+         * If there's already a block mapped by this entry, the whole region has already been mapped, so we can't
+         * create a table to map into it.
+         */
+        if self[index].is_leaf() {
+            return Err(PagingError::AlreadyMapped);
+        }
+
+        if self.next_table(index, physical_base).is_none() {
+            /*
+             * This entry is empty, so we create a new page table, zero it, and return that.
+             */
+            self.entries[index].set(Some((allocator.allocate().start, EntryFlags::TABLE)));
+            let table = self.next_table_mut(index, physical_base).unwrap();
+            table.zero();
+            Ok(table)
+        } else {
+            Ok(self.next_table_mut(index, physical_base).unwrap())
+        }
+    }
+}
+
+pub struct PageTableImpl<T: HierarchicalLevel> {
+    /// The frame that holds the top-level table.
+    frame: Frame,
+    /// The virtual address at which physical memory is mapped in the environment that these page
+    /// tables are being constructed in. This is **not** a property of the set of page tables being
+    /// mapped, but of the context the tables are being modified from.
+    physical_base: VAddr,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> PageTableImpl<T>
+where
+    T: HierarchicalLevel,
+{
+    pub fn new(frame: Frame, physical_base: VAddr) -> PageTableImpl<T> {
+        let mut table = PageTableImpl { frame, physical_base, _phantom: PhantomData };
+        table.top_mut().zero();
+        table
+    }
+
+    /// Create a `PageTableImpl` from a `Frame` that already contains a top-level table. This is
+    /// very unsafe because it assumes that the frame contains a valid page table, and that no
+    /// other `PageTableImpl`s currently exist that use this same backing frame (as calling
+    /// `mapper` on both could lead to two mutable references aliasing the same data to exist,
+    /// which is UB).
+    pub unsafe fn from_frame(frame: Frame, physical_base: VAddr) -> PageTableImpl<T> {
+        PageTableImpl { frame, physical_base, _phantom: PhantomData }
+    }
+
+    /// The physical address of the top-level table, which is installed in the translation table base registers.
+    pub fn address(&self) -> PAddr {
+        self.frame.start
+    }
+
+    pub fn top(&self) -> &Table<T> {
+        unsafe { &*((self.physical_base + usize::from(self.frame.start)).ptr()) }
+    }
+
+    pub fn top_mut(&mut self) -> &mut Table<T> {
+        unsafe { &mut *((self.physical_base + usize::from(self.frame.start)).mut_ptr()) }
+    }
+}
+
+impl fmt::Debug for PageTableImpl<Level4> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "PageTable {{")?;
+        let p4 = self.top();
+        for i in 0..512 {
+            if p4[i].is_valid() {
+                writeln!(f, "    P4 entry {}({:#x}): {:?}", i, VAddr::from_indices(i, 0, 0, 0), p4[i])?;
+                let p3 = p4.next_table(i, self.physical_base).unwrap();
+                for j in 0..512 {
+                    if p3[j].is_valid() {
+                        writeln!(
+                            f,
+                            "        P3 entry {}({:#x}): {:?}",
+                            j,
+                            VAddr::from_indices(i, j, 0, 0),
+                            p3[j]
+                        )?;
+                        if p3[j].is_leaf() {
+                            continue;
+                        }
+                        let p2 = p3.next_table(j, self.physical_base).unwrap();
+                        for k in 0..512 {
+                            if p2[k].is_valid() {
+                                writeln!(
+                                    f,
+                                    "            P2 entry {}({:#x}): {:?}",
+                                    k,
+                                    VAddr::from_indices(i, j, k, 0),
+                                    p2[k]
+                                )?;
+                                if p2[k].is_leaf() {
+                                    continue;
+                                }
+                                let p1 = p2.next_table(k, self.physical_base).unwrap();
+                                for m in 0..512 {
+                                    if p1[m].is_valid() {
+                                        writeln!(
+                                            f,
+                                            "                P1 entry {}({:#x}): {:?}",
+                                            m,
+                                            VAddr::from_indices(i, j, k, m),
+                                            p1[m]
+                                        )?;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(f, "}}")?;
+        Ok(())
+    }
+}
+
+impl PageTable<Size4KiB> for PageTableImpl<Level4> {
+    fn new_with_kernel_mapped<A>(kernel_page_table: &Self, allocator: &A) -> Self
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let mut page_table =
+            PageTableImpl::new(allocator.allocate(), crate::platform::kernel_map::PHYSICAL_MAP_BASE);
+
+        /*
+         * Install the address of the kernel's P3 in every address space, so that the kernel is always mapped.
+         * It's safe to unwrap the kernel P3 address, as we wouldn't be able to fetch these instructions
+         * if it wasn't there.
+         */
+        let kernel_p3_address =
+            kernel_page_table.top()[crate::platform::kernel_map::KERNEL_P4_ENTRY].address().unwrap();
+        page_table.top_mut()[crate::platform::kernel_map::KERNEL_P4_ENTRY]
+            .set(Some((kernel_p3_address, EntryFlags::TABLE)));
+
+        page_table
+    }
+
+    unsafe fn switch_to(&self) {
+        unsafe { Ttbr::write_both(self.frame.start) }
+    }
+
+    fn user_address_space_end(&self) -> VAddr {
+        crate::platform::kernel_map::USER_ADDRESS_SPACE_END
+    }
+
+    fn translate(&self, address: VAddr) -> Option<PAddr> {
+        let (entry, page_size) = self.leaf_entry(address)?;
+        Some(entry.address()? + (usize::from(address) % page_size))
+    }
+
+    fn flags(&self, address: VAddr) -> Option<Flags> {
+        let (entry, _) = self.leaf_entry(address)?;
+        entry.is_valid().then(|| Flags::from(entry.flags()))
+    }
+
+    fn map<S, A>(&mut self, page: Page<S>, frame: Frame<S>, flags: Flags, allocator: &A) -> Result<(), PagingError>
+    where
+        S: FrameSize,
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+
+        if S::SIZE == Size4KiB::SIZE {
+            let p1 = self
+                .top_mut()
+                .next_table_create(page.start.p4_index(), allocator, physical_base)?
+                .next_table_create(page.start.p3_index(), allocator, physical_base)?
+                .next_table_create(page.start.p2_index(), allocator, physical_base)?;
+
+            if p1[page.start.p1_index()].is_valid() {
+                return Err(PagingError::AlreadyMapped);
+            }
+
+            p1[page.start.p1_index()].set(Some((frame.start, EntryFlags::from(flags) | EntryFlags::PAGE)));
+        } else if S::SIZE == Size2MiB::SIZE {
+            let p2 = self
+                .top_mut()
+                .next_table_create(page.start.p4_index(), allocator, physical_base)?
+                .next_table_create(page.start.p3_index(), allocator, physical_base)?;
+
+            if p2[page.start.p2_index()].is_valid() {
+                return Err(PagingError::AlreadyMapped);
+            }
+
+            p2[page.start.p2_index()].set(Some((frame.start, EntryFlags::from(flags))));
+        } else {
+            assert_eq!(S::SIZE, Size1GiB::SIZE);
+
+            let p3 = self.top_mut().next_table_create(page.start.p4_index(), allocator, physical_base)?;
+
+            if p3[page.start.p3_index()].is_valid() {
+                return Err(PagingError::AlreadyMapped);
+            }
+
+            p3[page.start.p3_index()].set(Some((frame.start, EntryFlags::from(flags))));
+        }
+
+        // TODO: replace this with a returned 'token' or whatever to batch changes before a flush if possible
+        flush_tlb(Some(page.start));
+        Ok(())
+    }
+
+    fn map_area<A>(
+        &mut self,
+        virtual_start: VAddr,
+        physical_start: PAddr,
+        size: usize,
+        flags: Flags,
+        allocator: &A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        use mulch::math::{abs_difference, align_down};
+
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(physical_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        /*
+         * If the area is smaller than a single 2MiB page, or if the virtual and physical starts are "out of
+         * phase" such that we'll never be able to use larger pages, just use 4KiB pages.
+         */
+        let align_mismatch =
+            abs_difference(usize::from(physical_start), usize::from(virtual_start)) % Size2MiB::SIZE != 0;
+        if size < Size2MiB::SIZE || align_mismatch {
+            let pages = Page::starts_with(virtual_start)..Page::starts_with(virtual_start + size);
+            let frames = Frame::starts_with(physical_start)..Frame::starts_with(physical_start + size);
+            return self.map_range::<Size4KiB, A>(pages, frames, flags, allocator);
+        }
+
+        let mut cursor = virtual_start;
+        let virtual_end: VAddr = virtual_start + size;
+
+        while cursor < virtual_end {
+            let cursor_physical =
+                PAddr::new(usize::from(physical_start) + usize::from(cursor) - usize::from(virtual_start))
+                    .unwrap();
+            let bytes_left = usize::from(virtual_end) - usize::from(cursor);
+
+            if cursor.is_aligned(Size1GiB::SIZE)
+                && cursor_physical.is_aligned(Size1GiB::SIZE)
+                && bytes_left >= Size1GiB::SIZE
+            {
+                /*
+                 * We can fit at least 1GiB page in, and both virtual and physical cursors have the correct
+                 * alignment. Map as much as we can with 1GiB pages.
+                 */
+                let bytes_to_map = align_down(bytes_left, Size1GiB::SIZE);
+                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
+                let frames =
+                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
+                self.map_range::<Size1GiB, A>(pages, frames, flags, allocator)?;
+                cursor += bytes_to_map;
+            } else if cursor.is_aligned(Size2MiB::SIZE)
+                && cursor_physical.is_aligned(Size2MiB::SIZE)
+                && bytes_left >= Size2MiB::SIZE
+            {
+                /*
+                 * We couldn't use a 1GiB page, but we can use 2MiB pages! Map as much as we can.
+                 */
+                let bytes_to_map = align_down(bytes_left, Size2MiB::SIZE);
+                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
+                let frames =
+                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
+                self.map_range::<Size2MiB, A>(pages, frames, flags, allocator)?;
+                cursor += bytes_to_map;
+            } else {
+                /*
+                 * We can't use any larger pages, but we might be able to further in, if the data becomes more
+                 * aligned. If the next 2MiB-aligned address is still inside the range, stop there to have another
+                 * go. `cursor` might be 2MiB-aligned at this point, so we start from the next address so we don't
+                 * get stuck here.
+                 */
+                let next_boundary = (cursor + 1).align_up(Size2MiB::SIZE);
+                // Make sure not to go past the end of the region
+                let bytes_to_map = cmp::min(
+                    usize::from(next_boundary) - usize::from(cursor),
+                    usize::from(virtual_end) - usize::from(cursor),
+                );
+                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
+                let frames =
+                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
+                self.map_range::<Size4KiB, A>(pages, frames, flags, allocator)?;
+                cursor += bytes_to_map;
+            }
+        }
+
+        assert_eq!(cursor, virtual_end);
+        Ok(())
+    }
+
+    fn unmap<S>(&mut self, page: Page<S>) -> Option<Frame<S>>
+    where
+        S: FrameSize,
+    {
+        let physical_base = self.physical_base;
+
+        match S::SIZE {
+            Size4KiB::SIZE => {
+                let p1 = self
+                    .top_mut()
+                    .next_table_mut(page.start.p4_index(), physical_base)?
+                    .next_table_mut(page.start.p3_index(), physical_base)?
+                    .next_table_mut(page.start.p2_index(), physical_base)?;
+                let frame = Frame::starts_with(p1[page.start.p1_index()].address()?);
+                p1[page.start.p1_index()].set(None);
+                flush_tlb(Some(page.start));
+
+                Some(frame)
+            }
+            Size2MiB::SIZE => {
+                let p2 = self
+                    .top_mut()
+                    .next_table_mut(page.start.p4_index(), physical_base)?
+                    .next_table_mut(page.start.p3_index(), physical_base)?;
+                if !p2[page.start.p2_index()].is_leaf() {
+                    return None;
+                }
+                let frame = Frame::starts_with(p2[page.start.p2_index()].address()?);
+                p2[page.start.p2_index()].set(None);
+                flush_tlb(Some(page.start));
+
+                Some(frame)
+            }
+            Size1GiB::SIZE => {
+                let p3 = self.top_mut().next_table_mut(page.start.p4_index(), physical_base)?;
+                if !p3[page.start.p3_index()].is_leaf() {
+                    return None;
+                }
+                let frame = Frame::starts_with(p3[page.start.p3_index()].address()?);
+                p3[page.start.p3_index()].set(None);
+                flush_tlb(Some(page.start));
+
+                Some(frame)
+            }
+
+            _ => panic!("Unimplemented page size!"),
+        }
+    }
+
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize) {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let mut cursor = virtual_start;
+        let virtual_end: VAddr = virtual_start + size;
+
+        while cursor < virtual_end {
+            let page_size = self.page_size_at(cursor);
+            match page_size {
+                Some(Size1GiB::SIZE) => {
+                    self.unmap::<Size1GiB>(Page::starts_with(cursor));
+                }
+                Some(Size2MiB::SIZE) => {
+                    self.unmap::<Size2MiB>(Page::starts_with(cursor));
+                }
+                Some(_) => {
+                    self.unmap::<Size4KiB>(Page::starts_with(cursor));
+                }
+                None => (),
+            }
+            cursor += page_size.unwrap_or(Size4KiB::SIZE);
+        }
+    }
+}
+
+impl PageTableImpl<Level4> {
+    /// Find the size of the page that `address` is mapped with, or `None` if it isn't mapped.
+    fn page_size_at(&self, address: VAddr) -> Option<usize> {
+        let (entry, page_size) = self.leaf_entry(address)?;
+        entry.address().map(|_| page_size)
+    }
+
+    /// Find the entry that maps `address`, along with the size of the page it maps. The entry may not be
+    /// valid if `address` isn't mapped.
+    fn leaf_entry(&self, address: VAddr) -> Option<(Entry, usize)> {
+        let p3 = self.top().next_table(address.p4_index(), self.physical_base)?;
+        let p3_entry = p3[address.p3_index()];
+        if p3_entry.is_leaf() {
+            return Some((p3_entry, Size1GiB::SIZE));
+        }
+
+        let p2 = p3.next_table(address.p3_index(), self.physical_base)?;
+        let p2_entry = p2[address.p2_index()];
+        if p2_entry.is_leaf() {
+            return Some((p2_entry, Size2MiB::SIZE));
+        }
+
+        let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
+        Some((p1[address.p1_index()], Size4KiB::SIZE))
+    }
+}
+
+pub trait VAddrIndices {
+    fn p4_index(self) -> usize;
+    fn p3_index(self) -> usize;
+    fn p2_index(self) -> usize;
+    fn p1_index(self) -> usize;
+
+    fn from_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr;
+}
+
+impl VAddrIndices for VAddr {
+    fn p4_index(self) -> usize {
+        usize::from(self).get_bits(39..48)
+    }
+
+    fn p3_index(self) -> usize {
+        usize::from(self).get_bits(30..39)
+    }
+
+    fn p2_index(self) -> usize {
+        usize::from(self).get_bits(21..30)
+    }
+
+    fn p1_index(self) -> usize {
+        usize::from(self).get_bits(12..21)
+    }
+
+    fn from_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr {
+        let mut address = 0;
+        address.set_bits(12..21, p1);
+        address.set_bits(21..30, p2);
+        address.set_bits(30..39, p3);
+        address.set_bits(39..48, p4);
+        VAddr::new(address)
+    }
+}
+
+/// Invalidate the TLB entries for the given address on every CPU, or the entire TLB if no address is given. This
+/// first makes sure that any changes to the page tables are visible to the table walker.
+#[inline(always)]
+pub fn flush_tlb(address: Option<VAddr>) {
+    match address {
+        Some(address) => unsafe {
+            asm!(
+                "
+                    dsb ishst
+                    tlbi vaae1is, {}
+                    dsb ish
+                    isb
+                ",
+                in(reg) usize::from(address).get_bits(12..56)
+            )
+        },
+        None => unsafe {
+            asm!(
+                "
+                    dsb ishst
+                    tlbi vmalle1is
+                    dsb ish
+                    isb
+                "
+            )
+        },
+    }
+}
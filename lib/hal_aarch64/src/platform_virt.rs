@@ -0,0 +1,79 @@
+use crate::paging::Level4;
+
+pub mod memory {
+    use hal::memory::PAddr;
+
+    /*
+     * QEMU loads the device tree at the start of RAM when it boots an ELF image, so we leave the first 2MiB for
+     * it, and load Seed after that.
+     */
+    pub const DRAM_START: PAddr = PAddr::new(0x4000_0000).unwrap();
+    // TODO: when const traits are implemented, this should be rewritten in terms of DRAM_START
+    pub const SEED_ADDR: PAddr = PAddr::new(0x4020_0000).unwrap();
+    pub const RAMDISK_ADDR: PAddr = PAddr::new(0x7000_0000).unwrap();
+}
+
+pub const VIRTUAL_ADDRESS_BITS: usize = 48;
+pub type PageTableImpl = crate::paging::PageTableImpl<Level4>;
+
+/// This module contains constants that define how the kernel address space is laid out on AArch64. We use the
+/// same set of page tables to translate both halves of the address space (see `crate::paging`), with 48-bit
+/// virtual addresses.
+///
+/// The 511th P4 entry (virtual addresses `0xffff_ff80_0000_0000` through `0xffff_ffff_ffff_ffff`) is always
+/// mapped to the kernel P3. Userspace uses the lower half of the address space (virtual addresses
+/// `0x0000_0000_0000_0000` through `0x0000_7fff_ffff_ffff`).
+///
+/// This gives us 512 GiB of kernel space. The kernel itself lies within the top 2GiB of the address space, and
+/// below it we reserve 128GiB for task kernel stacks. The remainder is used to map the entirety of physical
+/// memory.
+pub mod kernel_map {
+    use hal::memory::{mebibytes, Bytes, PAddr, VAddr};
+
+    pub const KERNEL_P4_ENTRY: usize = 511;
+    pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_ff80_0000_0000);
+
+    pub const PHYSICAL_MAP_BASE: VAddr = KERNEL_ADDRESS_SPACE_START;
+    /// The highest address userspace can use. This is the top of the lower half of the address space.
+    pub const USER_ADDRESS_SPACE_END: VAddr = VAddr::new(0x0000_7fff_ffff_ffff);
+
+    /// Access a given physical address through the physical mapping. This cannot be used until the kernel page tables
+    /// have been switched to.
+    ///
+    /// # Safety
+    /// This itself is safe, because to cause memory unsafety a raw pointer must be created and accessed from the
+    /// `VAddr`, which is unsafe.
+    pub fn physical_to_virtual(address: PAddr) -> VAddr {
+        PHYSICAL_MAP_BASE + usize::from(address)
+    }
+
+    pub const KERNEL_STACKS_BASE: VAddr = VAddr::new(0xffff_ffdf_8000_0000);
+    /*
+     * There is an imposed maximum number of tasks because of the simple way we're allocating task kernel stacks.
+     * This is currently 65536 with a task kernel stack size of 2MiB.
+     */
+    pub const STACK_SLOT_SIZE: Bytes = mebibytes(2);
+    pub const MAX_TASKS: usize = 65536;
+
+    /// The kernel starts at -2GiB. The kernel image is loaded directly at this address, and the following space until
+    /// the top of memory is managed dynamically and contains the boot info structures, memory map, and kernel heap.
+    pub const KERNEL_BASE: VAddr = VAddr::new(0xffff_ffff_8000_0000);
+}
+
+/// DMA is cache-coherent on QEMU's `virt` machine, so cache maintenance only needs to order memory accesses.
+pub mod cache {
+    use core::arch::asm;
+    use hal::memory::PAddr;
+
+    pub unsafe fn clean(_address: PAddr, _size: usize) {
+        unsafe { asm!("dsb sy") };
+    }
+
+    pub unsafe fn invalidate(_address: PAddr, _size: usize) {
+        unsafe { asm!("dsb sy") };
+    }
+
+    pub unsafe fn clean_and_invalidate(_address: PAddr, _size: usize) {
+        unsafe { asm!("dsb sy") };
+    }
+}
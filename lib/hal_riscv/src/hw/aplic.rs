@@ -80,6 +80,11 @@ impl AplicDomain {
         let index = irq / 32;
         self.set_ie[index as usize].write(1 << ((irq as usize) % 32));
     }
+
+    pub fn disable_interrupt(&self, irq: u32) {
+        let index = irq / 32;
+        self.clear_ie[index as usize].write(1 << ((irq as usize) % 32));
+    }
 }
 
 #[repr(u32)]
@@ -19,6 +19,19 @@ impl Time {
     }
 }
 
+/// Controls which of the counters (`cycle`, `time`, `instret`, and the hardware performance counters) can be read
+/// from U-mode.
+pub struct Scounteren;
+
+impl Scounteren {
+    /// Set the `TM` bit of `scounteren`, allowing U-mode to read the `time` CSR.
+    pub unsafe fn enable_time() {
+        unsafe {
+            asm!("csrs scounteren, {}", in(reg) 1 << 1);
+        }
+    }
+}
+
 pub struct Sstatus;
 
 impl Sstatus {
@@ -3,3 +3,6 @@ pub mod csr;
 pub mod imsic;
 pub mod plic;
 pub mod uart16550;
+
+#[cfg(feature = "qemu")]
+pub mod qemu;
@@ -34,6 +34,10 @@ impl Plic {
         self.interrupt_enable[context].enable(source);
     }
 
+    pub fn disable_interrupt(&self, context: usize, source: usize) {
+        self.interrupt_enable[context].disable(source);
+    }
+
     pub fn set_context_threshold(&self, context: usize, threshold: u32) {
         self.threshold_and_claim[context].priority_threshold.write(threshold);
     }
@@ -0,0 +1,43 @@
+use hal::memory::VAddr;
+
+/// Exit codes to use with `TestDevice`. These are the same as the codes QEMU exits with when the x86_64 kernel
+/// uses its exit port, so whatever invokes QEMU can handle both platforms in the same way.
+#[derive(Clone, Copy, Debug)]
+pub enum ExitCode {
+    Success,
+    Failed,
+    /// The kernel's test runner finished, but some tests failed.
+    TestsFailed,
+}
+
+/// The SiFive test device found on QEMU's `virt` machine, which can be used to exit QEMU. This is described in the
+/// device tree as compatible with `sifive,test0`.
+pub struct TestDevice(VAddr);
+
+impl TestDevice {
+    const PASS: u32 = 0x5555;
+    const FAIL: u32 = 0x3333;
+    const FAILED_EXIT_CODE: u32 = 0x23;
+    const TESTS_FAILED_EXIT_CODE: u32 = 0x25;
+
+    /// Create a `TestDevice` from the virtual address its register is mapped at.
+    pub unsafe fn new(address: VAddr) -> TestDevice {
+        TestDevice(address)
+    }
+
+    pub fn exit(&self, exit_code: ExitCode) -> ! {
+        /*
+         * Writing `PASS` exits QEMU with a code of `0`. Writing `FAIL` exits QEMU with the code in the upper 16
+         * bits.
+         */
+        let value = match exit_code {
+            ExitCode::Success => Self::PASS,
+            ExitCode::Failed => (Self::FAILED_EXIT_CODE << 16) | Self::FAIL,
+            ExitCode::TestsFailed => (Self::TESTS_FAILED_EXIT_CODE << 16) | Self::FAIL,
+        };
+        unsafe {
+            core::ptr::write_volatile(self.0.mut_ptr::<u32>(), value);
+        }
+        unreachable!()
+    }
+}
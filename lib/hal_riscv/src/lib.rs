@@ -27,16 +27,14 @@ cfg_if::cfg_if! {
                 pub const RAMDISK_ADDR: PAddr = PAddr::new(0x0).unwrap();
             }
 
-            pub const VIRTUAL_ADDRESS_BITS: usize = 39;
-            pub type PageTableImpl = crate::paging::PageTableImpl<crate::paging::Level3>;
+            /// The paging modes that can be used on this platform, most preferred first. Seed uses the first one
+            /// the hart supports.
+            pub const PAGING_MODES: &[crate::paging::PagingMode] = &[crate::paging::PagingMode::Sv39];
+            pub type PageTableImpl = crate::paging::PageTableImpl;
 
             pub mod kernel_map {
                 use hal::memory::{PAddr, VAddr, Bytes};
 
-                /// Platforms using `Level4` paging schemes define this to set which P4 entry is
-                /// duplicated across all page tables as the kernel's entry.
-                pub const KERNEL_P4_ENTRY: usize = 0;
-
                 pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_ff80_0000_0000);
                 pub const PHYSICAL_MAP_BASE: VAddr = KERNEL_ADDRESS_SPACE_START;
 
@@ -61,6 +59,23 @@ cfg_if::cfg_if! {
                 /// The kernel starts at -2GiB. The kernel image is loaded directly at this address, and the following space until
                 /// the top of memory is managed dynamically and contains the boot info structures, memory map, and kernel heap.
                 pub const KERNEL_BASE: VAddr = VAddr::new(0xffff_ffff_8000_0000);
+                pub const KERNEL_SLIDE_RANGE: Bytes = 0;
+            }
+
+            /// Cache maintenance operations for memory accessed by devices through DMA. On platforms where DMA is
+            /// cache-coherent, these only need to order memory accesses.
+            pub mod cache {
+                use hal::memory::PAddr;
+
+                /// Write any dirty cache lines covering the given area of memory back to memory.
+                pub unsafe fn clean(_address: PAddr, _size: usize) {}
+
+                /// Discard any cache lines covering the given area of memory, so that it's next read from memory.
+                pub unsafe fn invalidate(_address: PAddr, _size: usize) {}
+
+                /// Write any dirty cache lines covering the given area of memory back to memory, and then discard
+                /// them.
+                pub unsafe fn clean_and_invalidate(_address: PAddr, _size: usize) {}
             }
 
             /// Get the ID of the PLIC context corresponding to the S-mode interrupt target for the
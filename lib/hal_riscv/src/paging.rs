@@ -53,6 +53,17 @@ impl From<Flags> for EntryFlags {
     }
 }
 
+impl From<EntryFlags> for Flags {
+    fn from(flags: EntryFlags) -> Self {
+        Flags {
+            writable: flags.contains(EntryFlags::WRITABLE),
+            executable: flags.contains(EntryFlags::EXECUTABLE),
+            user_accessible: flags.contains(EntryFlags::USER_ACCESSIBLE),
+            ..Flags::default()
+        }
+    }
+}
+
 #[repr(transparent)]
 #[derive(Clone, Copy)]
 pub struct Entry(u64);
@@ -110,12 +121,14 @@ impl Debug for Entry {
 }
 
 // TODO: lots of this stuff has been duplicated from `hal_x86_64`; abstract into `hal`?
+pub enum Level5 {}
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
 pub trait TableLevel {}
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -127,6 +140,9 @@ impl TableLevel for Level1 {}
 pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
+impl HierarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -244,61 +260,226 @@ where
     }
 }
 
-// TODO: make generic over which level of table is the top
-pub struct PageTableImpl<T: HierarchicalLevel> {
-    /// The frame that holds the top-level table.
+/// The paging modes supported on RV64. Which one is used is set in `satp`, and harts only have to support some of
+/// them - Sv48 needs Sv39, and Sv57 needs Sv48. Each platform lists the modes it can use in `PAGING_MODES`, and
+/// Seed picks the best one the hart supports (see `PagingMode::probe`). The kernel then uses the same mode for
+/// every address space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PagingMode {
+    /// Three levels of tables, which translate 39-bit virtual addresses.
+    Sv39,
+    /// Four levels of tables, which translate 48-bit virtual addresses.
+    Sv48,
+    /// Five levels of tables, which translate 57-bit virtual addresses.
+    Sv57,
+}
+
+impl PagingMode {
+    /// Get the paging mode the running hart is using, or `None` if translation is turned off.
+    pub fn current() -> Option<PagingMode> {
+        match Satp::read() {
+            Satp::Bare => None,
+            Satp::Sv39 { .. } => Some(PagingMode::Sv39),
+            Satp::Sv48 { .. } => Some(PagingMode::Sv48),
+            Satp::Sv57 { .. } => Some(PagingMode::Sv57),
+        }
+    }
+
+    pub const fn virtual_address_bits(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 39,
+            PagingMode::Sv48 => 48,
+            PagingMode::Sv57 => 57,
+        }
+    }
+
+    /// Returns `true` if `address` is canonical in this mode. `VAddr` makes sure addresses are canonical in the
+    /// largest mode the platform supports, so addresses can be canonical there but still not be translatable
+    /// with a smaller mode.
+    pub fn is_canonical(self, address: VAddr) -> bool {
+        let sign_bits = usize::from(address) >> (self.virtual_address_bits() - 1);
+        sign_bits == 0 || sign_bits == usize::MAX >> (self.virtual_address_bits() - 1)
+    }
+
+    /// Get the highest address userspace can use in this mode. Userspace always gets the lower half of the
+    /// address space.
+    pub const fn user_address_space_end(self) -> VAddr {
+        VAddr::new((1 << (self.virtual_address_bits() - 1)) - 1)
+    }
+
+    pub fn satp(self, root: PAddr) -> Satp {
+        match self {
+            PagingMode::Sv39 => Satp::Sv39 { asid: 0, root },
+            PagingMode::Sv48 => Satp::Sv48 { asid: 0, root },
+            PagingMode::Sv57 => Satp::Sv57 { asid: 0, root },
+        }
+    }
+
+    /// Check whether the hart supports this mode. Unsupported modes can't be written to `satp`, so we try to turn
+    /// translation on with the mode, and see if it sticks. Translation is turned back off before this returns.
+    ///
+    /// ### Safety
+    /// This must be called with translation turned off. `root` must be the root table of a set of page tables
+    /// for this mode that identity-map the code of this function.
+    pub unsafe fn probe(self, root: PAddr) -> bool {
+        let satp = self.satp(root).raw();
+        let read_back: u64;
+        unsafe {
+            asm!(
+                "
+                    csrw satp, {satp}
+                    sfence.vma
+                    csrr {read_back}, satp
+                    csrw satp, zero
+                    sfence.vma
+                ",
+                satp = in(reg) satp,
+                read_back = out(reg) read_back,
+            );
+        }
+        read_back == satp
+    }
+}
+
+pub struct PageTableImpl {
+    /// The frame that holds the top-level table. This is a P3 with Sv39, a P4 with Sv48, and a P5 with Sv57.
     frame: Frame,
+    mode: PagingMode,
     /// The virtual address at which physical memory is mapped in the environment that these page
     /// tables are being constructed in. This is **not** a property of the set of page tables being
     /// mapped, but of the context the tables are being modified from.
     physical_base: VAddr,
-    _phantom: PhantomData<T>,
 }
 
-impl<T> PageTableImpl<T>
-where
-    T: HierarchicalLevel,
-{
-    pub fn new(frame: Frame, physical_base: VAddr) -> PageTableImpl<T> {
-        let mut table = PageTableImpl { frame, physical_base, _phantom: PhantomData };
-        table.top_mut().zero();
+impl PageTableImpl {
+    pub fn new(frame: Frame, mode: PagingMode, physical_base: VAddr) -> PageTableImpl {
+        let mut table = PageTableImpl { frame, mode, physical_base };
+        table.top_mut::<Level3>().zero();
         table
     }
 
-    /// Create a `PageTableImpl` from a `Frame` that already contains a top-level table. This is
+    /// Create a `PageTableImpl` from a `Frame` that already contains a top-level table for `mode`. This is
     /// very unsafe because it assumes that the frame contains a valid page table, and that no
     /// other `PageTableImpl`s currently exist that use this same backing frame (as calling
     /// `mapper` on both could lead to two mutable references aliasing the same data to exist,
     /// which is UB).
-    pub unsafe fn from_frame(frame: Frame, physical_base: VAddr) -> PageTableImpl<T> {
-        PageTableImpl { frame, physical_base, _phantom: PhantomData }
+    pub unsafe fn from_frame(frame: Frame, mode: PagingMode, physical_base: VAddr) -> PageTableImpl {
+        PageTableImpl { frame, mode, physical_base }
+    }
+
+    pub fn frame(&self) -> Frame {
+        self.frame
+    }
+
+    pub fn mode(&self) -> PagingMode {
+        self.mode
     }
 
-    pub fn top(&self) -> &Table<T> {
+    pub fn satp(&self) -> Satp {
+        self.mode.satp(self.frame.start)
+    }
+
+    /// Get the top-level table. The caller must pick the level that matches `self.mode`, unless it's only going
+    /// to look at entries without following them.
+    fn top<L: TableLevel>(&self) -> &Table<L> {
         unsafe { &*((self.physical_base + usize::from(self.frame.start)).ptr()) }
     }
 
-    pub fn top_mut(&mut self) -> &mut Table<T> {
+    fn top_mut<L: TableLevel>(&mut self) -> &mut Table<L> {
         unsafe { &mut *((self.physical_base + usize::from(self.frame.start)).mut_ptr()) }
     }
-}
 
-/*
- * Implementation for `Sv48` systems, which support four levels of tables.
- */
-impl PageTableImpl<Level4> {
-    pub fn satp(&self) -> Satp {
-        Satp::Sv48 { asid: 0, root: self.frame.start }
+    /// Get the P3 that translates `address`, if there is one. This hides how many levels of tables are above
+    /// the P3s, so the rest of the implementation doesn't need to care which paging mode is in use.
+    fn p3(&self, address: VAddr) -> Option<&Table<Level3>> {
+        if !self.mode.is_canonical(address) {
+            return None;
+        }
+
+        match self.mode {
+            PagingMode::Sv39 => Some(self.top::<Level3>()),
+            PagingMode::Sv48 => self.top::<Level4>().next_table(address.p4_index(), self.physical_base),
+            PagingMode::Sv57 => self
+                .top::<Level5>()
+                .next_table(address.p5_index(), self.physical_base)?
+                .next_table(address.p4_index(), self.physical_base),
+        }
     }
-}
 
-impl fmt::Debug for PageTableImpl<Level4> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "PageTable {{")?;
-        let p4 = self.top();
+    fn p3_mut(&mut self, address: VAddr) -> Option<&mut Table<Level3>> {
+        if !self.mode.is_canonical(address) {
+            return None;
+        }
+
+        let physical_base = self.physical_base;
+        match self.mode {
+            PagingMode::Sv39 => Some(self.top_mut::<Level3>()),
+            PagingMode::Sv48 => self.top_mut::<Level4>().next_table_mut(address.p4_index(), physical_base),
+            PagingMode::Sv57 => self
+                .top_mut::<Level5>()
+                .next_table_mut(address.p5_index(), physical_base)?
+                .next_table_mut(address.p4_index(), physical_base),
+        }
+    }
+
+    /// Get the P3 that translates `address`, creating it and any tables above it if they don't exist.
+    fn p3_create<A>(&mut self, address: VAddr, allocator: &A) -> Result<&mut Table<Level3>, PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(self.mode.is_canonical(address), "Can't map non-canonical address: {:#x}", address);
+
+        let physical_base = self.physical_base;
+        match self.mode {
+            PagingMode::Sv39 => Ok(self.top_mut::<Level3>()),
+            PagingMode::Sv48 => {
+                self.top_mut::<Level4>().next_table_create(address.p4_index(), allocator, physical_base)
+            }
+            PagingMode::Sv57 => self
+                .top_mut::<Level5>()
+                .next_table_create(address.p5_index(), allocator, physical_base)?
+                .next_table_create(address.p4_index(), allocator, physical_base),
+        }
+    }
+
+    /// Find the size of the page that `address` is mapped with, or `None` if it isn't mapped.
+    fn page_size_at(&self, address: VAddr) -> Option<usize> {
+        let (entry, page_size) = self.leaf_entry(address)?;
+        entry.address().map(|_| page_size)
+    }
+
+    /// Find the entry that maps `address`, along with the size of the page it maps. The entry may not be
+    /// valid if `address` isn't mapped.
+    fn leaf_entry(&self, address: VAddr) -> Option<(Entry, usize)> {
+        let p3 = self.p3(address)?;
+        let p3_entry = p3[address.p3_index()];
+        if p3_entry.is_leaf() {
+            return Some((p3_entry, Size1GiB::SIZE));
+        }
+
+        let p2 = p3.next_table(address.p3_index(), self.physical_base)?;
+        let p2_entry = p2[address.p2_index()];
+        if p2_entry.is_leaf() {
+            return Some((p2_entry, Size2MiB::SIZE));
+        }
+
+        let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
+        Some((p1[address.p1_index()], Size4KiB::SIZE))
+    }
+
+    fn fmt_p4(&self, f: &mut fmt::Formatter<'_>, p4: &Table<Level4>, p5: Option<usize>) -> fmt::Result {
+        // Tables below a P5 entry are indented one more level
+        let indent = if p5.is_some() { 4 } else { 0 };
         for i in 0..512 {
             if p4[i].is_valid() {
-                writeln!(f, "    P4 entry {}({:#x}): {:?}", i, VAddr::from_indices(i, 0, 0, 0), p4[i])?;
+                writeln!(
+                    f,
+                    "{:indent$}    P4 entry {}({:#x}): {:?}",
+                    "",
+                    i,
+                    VAddr::from_indices(p5, i, 0, 0, 0),
+                    p4[i]
+                )?;
                 if p4[i].is_leaf() {
                     continue;
                 }
@@ -307,9 +488,10 @@ impl fmt::Debug for PageTableImpl<Level4> {
                     if p3[j].is_valid() {
                         writeln!(
                             f,
-                            "        P3 entry {}({:#x}): {:?}",
+                            "{:indent$}        P3 entry {}({:#x}): {:?}",
+                            "",
                             j,
-                            VAddr::from_indices(i, j, 0, 0),
+                            VAddr::from_indices(p5, i, j, 0, 0),
                             p3[j]
                         )?;
                         if p3[j].is_leaf() {
@@ -320,9 +502,10 @@ impl fmt::Debug for PageTableImpl<Level4> {
                             if p2[k].is_valid() {
                                 writeln!(
                                     f,
-                                    "            P2 entry {}({:#x}): {:?}",
+                                    "{:indent$}            P2 entry {}({:#x}): {:?}",
+                                    "",
                                     k,
-                                    VAddr::from_indices(i, j, k, 0),
+                                    VAddr::from_indices(p5, i, j, k, 0),
                                     p2[k]
                                 )?;
                                 if p2[k].is_leaf() {
@@ -333,9 +516,10 @@ impl fmt::Debug for PageTableImpl<Level4> {
                                     if p1[m].is_valid() {
                                         writeln!(
                                             f,
-                                            "                P1 entry {}({:#x}): {:?}",
+                                            "{:indent$}                P1 entry {}({:#x}): {:?}",
+                                            "",
                                             m,
-                                            VAddr::from_indices(i, j, k, m),
+                                            VAddr::from_indices(p5, i, j, k, m),
                                             p1[m]
                                         )?;
                                     }
@@ -346,233 +530,10 @@ impl fmt::Debug for PageTableImpl<Level4> {
                 }
             }
         }
-        writeln!(f, "}}")?;
-        Ok(())
-    }
-}
-
-impl PageTable<Size4KiB> for PageTableImpl<Level4> {
-    fn new_with_kernel_mapped<A>(kernel_page_table: &Self, allocator: &A) -> Self
-    where
-        A: FrameAllocator<Size4KiB>,
-    {
-        let mut page_table =
-            PageTableImpl::new(allocator.allocate(), crate::platform::kernel_map::PHYSICAL_MAP_BASE);
-
-        /*
-         * Install the address of the kernel's P3 in every address space, so that the kernel is always mapped.
-         * It's safe to unwrap the kernel P3 address, as we wouldn't be able to fetch these instructions
-         * if it wasn't there.
-         */
-        let kernel_p3_address =
-            kernel_page_table.top()[crate::platform::kernel_map::KERNEL_P4_ENTRY].address().unwrap();
-        page_table.top_mut()[crate::platform::kernel_map::KERNEL_P4_ENTRY]
-            .set(Some((kernel_p3_address, EntryFlags::empty())), false);
-
-        page_table
-    }
-
-    unsafe fn switch_to(&self) {
-        unsafe { self.satp().write() }
-    }
-
-    fn translate(&self, address: VAddr) -> Option<PAddr> {
-        // TODO: handle huge pages at the P3 level as well
-
-        let p2 = self
-            .top()
-            .next_table(address.p4_index(), self.physical_base)
-            .and_then(|p3| p3.next_table(address.p3_index(), self.physical_base))?;
-
-        let p2_entry = p2[address.p2_index()];
-        if p2_entry.is_leaf() {
-            return Some(p2_entry.address()? + (usize::from(address) % Size2MiB::SIZE));
-        }
-
-        let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
-        Some(p1[address.p1_index()].address()? + (usize::from(address) % Size4KiB::SIZE))
-    }
-
-    fn map<S, A>(&mut self, page: Page<S>, frame: Frame<S>, flags: Flags, allocator: &A) -> Result<(), PagingError>
-    where
-        S: FrameSize,
-        A: FrameAllocator<Size4KiB>,
-    {
-        let physical_base = self.physical_base;
-
-        if S::SIZE == Size4KiB::SIZE {
-            let p1 = self
-                .top_mut()
-                .next_table_create(page.start.p4_index(), allocator, physical_base)?
-                .next_table_create(page.start.p3_index(), allocator, physical_base)?
-                .next_table_create(page.start.p2_index(), allocator, physical_base)?;
-
-            if p1[page.start.p1_index()].is_valid() {
-                return Err(PagingError::AlreadyMapped);
-            }
-
-            p1[page.start.p1_index()].set(Some((frame.start, EntryFlags::from(flags))), true);
-        } else if S::SIZE == Size2MiB::SIZE {
-            let p2 = self
-                .top_mut()
-                .next_table_create(page.start.p4_index(), allocator, physical_base)?
-                .next_table_create(page.start.p3_index(), allocator, physical_base)?;
-
-            if p2[page.start.p2_index()].is_valid() {
-                return Err(PagingError::AlreadyMapped);
-            }
-
-            p2[page.start.p2_index()].set(Some((frame.start, EntryFlags::from(flags))), true);
-        } else {
-            assert_eq!(S::SIZE, Size1GiB::SIZE);
-
-            let p3 = self.top_mut().next_table_create(page.start.p4_index(), allocator, physical_base)?;
-
-            if p3[page.start.p3_index()].is_valid() {
-                return Err(PagingError::AlreadyMapped);
-            }
-
-            p3[page.start.p3_index()].set(Some((frame.start, EntryFlags::from(flags))), true);
-        }
-
-        // TODO: replace this with a returned 'token' or whatever to batch changes before a flush if possible
-        sfence_vma(None, Some(page.start));
-        Ok(())
-    }
-
-    fn map_area<A>(
-        &mut self,
-        virtual_start: VAddr,
-        physical_start: PAddr,
-        size: usize,
-        flags: Flags,
-        allocator: &A,
-    ) -> Result<(), PagingError>
-    where
-        A: FrameAllocator<Size4KiB>,
-    {
-        use mulch::math::{abs_difference, align_down};
-
-        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
-        assert!(physical_start.is_aligned(Size4KiB::SIZE));
-        assert!(size % Size4KiB::SIZE == 0);
-
-        /*
-         * If the area is smaller than a single 2MiB page, or if the virtual and physical starts are "out of
-         * phase" such that we'll never be able to use larger pages, just use 4KiB pages.
-         */
-        let align_mismatch =
-            abs_difference(usize::from(physical_start), usize::from(virtual_start)) % Size2MiB::SIZE != 0;
-        if size < Size2MiB::SIZE || align_mismatch {
-            let pages = Page::starts_with(virtual_start)..Page::starts_with(virtual_start + size);
-            let frames = Frame::starts_with(physical_start)..Frame::starts_with(physical_start + size);
-            return self.map_range::<Size4KiB, A>(pages, frames, flags, allocator);
-        }
-
-        let mut cursor = virtual_start;
-        let virtual_end: VAddr = virtual_start + size;
-
-        while cursor < virtual_end {
-            let cursor_physical =
-                PAddr::new(usize::from(physical_start) + usize::from(cursor) - usize::from(virtual_start))
-                    .unwrap();
-            let bytes_left = usize::from(virtual_end) - usize::from(cursor);
-
-            if cursor.is_aligned(Size1GiB::SIZE)
-                && cursor_physical.is_aligned(Size1GiB::SIZE)
-                && bytes_left >= Size1GiB::SIZE
-            {
-                /*
-                 * We can fit at least 1GiB page in, and both virtual and physical cursors have the correct
-                 * alignment. Map as much as we can with 1GiB pages.
-                 */
-                let bytes_to_map = align_down(bytes_left, Size1GiB::SIZE);
-                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
-                let frames =
-                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
-                self.map_range::<Size1GiB, A>(pages, frames, flags, allocator)?;
-                cursor += bytes_to_map;
-            } else if cursor.is_aligned(Size2MiB::SIZE)
-                && cursor_physical.is_aligned(Size2MiB::SIZE)
-                && bytes_left >= Size2MiB::SIZE
-            {
-                /*
-                 * We couldn't use a 1GiB page, but we can use 2MiB pages! Map as much as we can.
-                 *
-                 * TODO: we could do a similar thing to below to check if we can use 1GiB pages further in, but
-                 * it's probably unlikely enough that it's not really worth it.
-                 */
-                let bytes_to_map = align_down(bytes_left, Size2MiB::SIZE);
-                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
-                let frames =
-                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
-                self.map_range::<Size2MiB, A>(pages, frames, flags, allocator)?;
-                cursor += bytes_to_map;
-            } else {
-                /*
-                 * We can't use any larger pages, but we might be able to further in, if the data becomes more
-                 * aligned. If the next 2MiB-aligned address is still inside the range, stop there to have another
-                 * go.
-                 * NOTE: `cursor` might be 2MiB-aligned at this location, so we start from the next address so we don't get stuck here.
-                 */
-                let next_boundary = (cursor + 1).align_up(Size2MiB::SIZE);
-                // Make sure not to go past the end of the region
-                let bytes_to_map = cmp::min(
-                    usize::from(next_boundary) - usize::from(cursor),
-                    usize::from(virtual_end) - usize::from(cursor),
-                );
-                let pages = Page::starts_with(cursor)..Page::starts_with(cursor + bytes_to_map);
-                let frames =
-                    Frame::starts_with(cursor_physical)..Frame::starts_with(cursor_physical + bytes_to_map);
-                self.map_range::<Size4KiB, A>(pages, frames, flags, allocator)?;
-                cursor += bytes_to_map;
-            }
-        }
-
-        assert_eq!(cursor, virtual_end);
         Ok(())
     }
 
-    fn unmap<S>(&mut self, page: Page<S>) -> Option<Frame<S>>
-    where
-        S: FrameSize,
-    {
-        let physical_base = self.physical_base;
-
-        match S::SIZE {
-            Size4KiB::SIZE => {
-                let p1 = self
-                    .top_mut()
-                    .next_table_mut(page.start.p4_index(), physical_base)?
-                    .next_table_mut(page.start.p3_index(), physical_base)?
-                    .next_table_mut(page.start.p2_index(), physical_base)?;
-                let frame = Frame::starts_with(p1[page.start.p1_index()].address()?);
-                p1[page.start.p1_index()].set(None, true);
-                sfence_vma(None, Some(page.start));
-
-                Some(frame)
-            }
-            Size2MiB::SIZE => unimplemented!(),
-            Size1GiB::SIZE => unimplemented!(),
-
-            _ => panic!("Unimplemented page size!"),
-        }
-    }
-}
-
-/*
- * Implementation for `Sv39` systems, which support three levels of tables.
- */
-impl PageTableImpl<Level3> {
-    pub fn satp(&self) -> Satp {
-        Satp::Sv39 { asid: 0, root: self.frame.start }
-    }
-}
-
-impl fmt::Debug for PageTableImpl<Level3> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "PageTable {{")?;
-        let p3 = self.top();
+    fn fmt_p3(&self, f: &mut fmt::Formatter<'_>, p3: &Table<Level3>) -> fmt::Result {
         for i in 0..512 {
             if p3[i].is_valid() {
                 writeln!(f, "    P3 entry {}: {:?}", i, p3[i])?;
@@ -596,29 +557,63 @@ impl fmt::Debug for PageTableImpl<Level3> {
                 }
             }
         }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PageTableImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "PageTable ({:?}) {{", self.mode)?;
+        match self.mode {
+            PagingMode::Sv39 => self.fmt_p3(f, self.top::<Level3>())?,
+            PagingMode::Sv48 => self.fmt_p4(f, self.top::<Level4>(), None)?,
+            PagingMode::Sv57 => {
+                let p5 = self.top::<Level5>();
+                for i in 0..512 {
+                    if p5[i].is_valid() {
+                        writeln!(
+                            f,
+                            "    P5 entry {}({:#x}): {:?}",
+                            i,
+                            VAddr::from_indices(Some(i), 0, 0, 0, 0),
+                            p5[i]
+                        )?;
+                        if p5[i].is_leaf() {
+                            continue;
+                        }
+                        self.fmt_p4(f, p5.next_table(i, self.physical_base).unwrap(), Some(i))?;
+                    }
+                }
+            }
+        }
         writeln!(f, "}}")?;
         Ok(())
     }
 }
 
-impl PageTable<Size4KiB> for PageTableImpl<Level3> {
+impl PageTable<Size4KiB> for PageTableImpl {
     fn new_with_kernel_mapped<A>(kernel_page_table: &Self, allocator: &A) -> Self
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let mut page_table =
-            PageTableImpl::new(allocator.allocate(), crate::platform::kernel_map::PHYSICAL_MAP_BASE);
+        let mut page_table = PageTableImpl::new(
+            allocator.allocate(),
+            kernel_page_table.mode,
+            crate::platform::kernel_map::PHYSICAL_MAP_BASE,
+        );
 
         /*
-         * For three-level paging schemes, the entire upper half of the address space belongs to
-         * kernel-space. We need to walk the upper half of the kernel's P3 and copy it across.
-         * TODO: This could be problematic because the kernel could realistically need to map new
-         * top-level entries during the runtime, in which case tasks' page tables would need
-         * updating. Probably worth thinking about at some point...
+         * The kernel owns the upper half of the address space, so we copy the upper half of the kernel's
+         * top-level table into every address space. With Sv48 and Sv57, Seed creates all of these entries, and
+         * the kernel never adds more, so the copies can't get out of date. We're only copying entries, so it
+         * doesn't matter which level we treat the tables as.
+         * TODO: with Sv39, the upper half of the P3 maps the kernel directly, so this could be problematic
+         * because the kernel could realistically need to map new top-level entries during the runtime, in
+         * which case tasks' page tables would need updating. Probably worth thinking about at some point...
          */
-        for i in (ENTRY_COUNT / 2)..ENTRY_COUNT {
-            page_table.top_mut()[i] = kernel_page_table.top()[i];
-        }
+        let kernel_top = kernel_page_table.top::<Level3>();
+        let top = page_table.top_mut::<Level3>();
+        top.entries[(ENTRY_COUNT / 2)..].copy_from_slice(&kernel_top.entries[(ENTRY_COUNT / 2)..]);
 
         page_table
     }
@@ -627,18 +622,18 @@ impl PageTable<Size4KiB> for PageTableImpl<Level3> {
         unsafe { self.satp().write() }
     }
 
-    fn translate(&self, address: VAddr) -> Option<PAddr> {
-        // TODO: handle huge pages at the P3 level as well
-
-        let p2 = self.top().next_table(address.p3_index(), self.physical_base)?;
+    fn user_address_space_end(&self) -> VAddr {
+        self.mode.user_address_space_end()
+    }
 
-        let p2_entry = p2[address.p2_index()];
-        if p2_entry.is_leaf() {
-            return Some(p2_entry.address()? + (usize::from(address) % Size2MiB::SIZE));
-        }
+    fn translate(&self, address: VAddr) -> Option<PAddr> {
+        let (entry, page_size) = self.leaf_entry(address)?;
+        Some(entry.address()? + (usize::from(address) % page_size))
+    }
 
-        let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
-        Some(p1[address.p1_index()].address()? + (usize::from(address) % Size4KiB::SIZE))
+    fn flags(&self, address: VAddr) -> Option<Flags> {
+        let (entry, _) = self.leaf_entry(address)?;
+        entry.is_valid().then(|| Flags::from(entry.flags()))
     }
 
     fn map<S, A>(&mut self, page: Page<S>, frame: Frame<S>, flags: Flags, allocator: &A) -> Result<(), PagingError>
@@ -650,7 +645,7 @@ impl PageTable<Size4KiB> for PageTableImpl<Level3> {
 
         if S::SIZE == Size4KiB::SIZE {
             let p1 = self
-                .top_mut()
+                .p3_create(page.start, allocator)?
                 .next_table_create(page.start.p3_index(), allocator, physical_base)?
                 .next_table_create(page.start.p2_index(), allocator, physical_base)?;
 
@@ -660,7 +655,11 @@ impl PageTable<Size4KiB> for PageTableImpl<Level3> {
 
             p1[page.start.p1_index()].set(Some((frame.start, EntryFlags::from(flags))), true);
         } else if S::SIZE == Size2MiB::SIZE {
-            let p2 = self.top_mut().next_table_create(page.start.p3_index(), allocator, physical_base)?;
+            let p2 = self.p3_create(page.start, allocator)?.next_table_create(
+                page.start.p3_index(),
+                allocator,
+                physical_base,
+            )?;
 
             if p2[page.start.p2_index()].is_valid() {
                 return Err(PagingError::AlreadyMapped);
@@ -670,7 +669,7 @@ impl PageTable<Size4KiB> for PageTableImpl<Level3> {
         } else {
             assert_eq!(S::SIZE, Size1GiB::SIZE);
 
-            let p3 = self.top_mut();
+            let p3 = self.p3_create(page.start, allocator)?;
 
             if p3[page.start.p3_index()].is_valid() {
                 return Err(PagingError::AlreadyMapped);
@@ -786,7 +785,7 @@ impl PageTable<Size4KiB> for PageTableImpl<Level3> {
         match S::SIZE {
             Size4KiB::SIZE => {
                 let p1 = self
-                    .top_mut()
+                    .p3_mut(page.start)?
                     .next_table_mut(page.start.p3_index(), physical_base)?
                     .next_table_mut(page.start.p2_index(), physical_base)?;
                 let frame = Frame::starts_with(p1[page.start.p1_index()].address()?);
@@ -795,24 +794,76 @@ impl PageTable<Size4KiB> for PageTableImpl<Level3> {
 
                 Some(frame)
             }
-            Size2MiB::SIZE => unimplemented!(),
-            Size1GiB::SIZE => unimplemented!(),
+            Size2MiB::SIZE => {
+                let p2 = self.p3_mut(page.start)?.next_table_mut(page.start.p3_index(), physical_base)?;
+                if !p2[page.start.p2_index()].is_leaf() {
+                    return None;
+                }
+                let frame = Frame::starts_with(p2[page.start.p2_index()].address()?);
+                p2[page.start.p2_index()].set(None, true);
+                sfence_vma(None, Some(page.start));
+
+                Some(frame)
+            }
+            Size1GiB::SIZE => {
+                let p3 = self.p3_mut(page.start)?;
+                if !p3[page.start.p3_index()].is_leaf() {
+                    return None;
+                }
+                let frame = Frame::starts_with(p3[page.start.p3_index()].address()?);
+                p3[page.start.p3_index()].set(None, true);
+                sfence_vma(None, Some(page.start));
+
+                Some(frame)
+            }
 
             _ => panic!("Unimplemented page size!"),
         }
     }
+
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize) {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let mut cursor = virtual_start;
+        let virtual_end: VAddr = virtual_start + size;
+
+        while cursor < virtual_end {
+            let page_size = self.page_size_at(cursor);
+            match page_size {
+                Some(Size1GiB::SIZE) => {
+                    self.unmap::<Size1GiB>(Page::starts_with(cursor));
+                }
+                Some(Size2MiB::SIZE) => {
+                    self.unmap::<Size2MiB>(Page::starts_with(cursor));
+                }
+                Some(_) => {
+                    self.unmap::<Size4KiB>(Page::starts_with(cursor));
+                }
+                None => (),
+            }
+            cursor += page_size.unwrap_or(Size4KiB::SIZE);
+        }
+    }
 }
 
 pub trait VAddrIndices {
+    fn p5_index(self) -> usize;
     fn p4_index(self) -> usize;
     fn p3_index(self) -> usize;
     fn p2_index(self) -> usize;
     fn p1_index(self) -> usize;
 
-    fn from_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr;
+    /// Construct the address translated by the given table indices. `p5` should be `None` for addresses
+    /// translated with Sv48, in which case the address is sign-extended from bit 47.
+    fn from_indices(p5: Option<usize>, p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr;
 }
 
 impl VAddrIndices for VAddr {
+    fn p5_index(self) -> usize {
+        usize::from(self).get_bits(48..57)
+    }
+
     fn p4_index(self) -> usize {
         usize::from(self).get_bits(39..48)
     }
@@ -829,12 +880,22 @@ impl VAddrIndices for VAddr {
         usize::from(self).get_bits(12..21)
     }
 
-    fn from_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr {
-        let mut address = 0;
+    fn from_indices(p5: Option<usize>, p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr {
+        let mut address = 0usize;
         address.set_bits(12..21, p1);
         address.set_bits(21..30, p2);
         address.set_bits(30..39, p3);
         address.set_bits(39..48, p4);
+        match p5 {
+            Some(p5) => {
+                address.set_bits(48..57, p5);
+            }
+            None => {
+                if p4.get_bit(8) {
+                    address.set_bits(48..64, 0xffff);
+                }
+            }
+        }
         VAddr::new(address)
     }
 }
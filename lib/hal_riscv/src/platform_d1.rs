@@ -1,4 +1,4 @@
-use crate::paging::Level3;
+use crate::paging::PagingMode;
 
 pub mod memory {
     use hal::memory::{kibibytes, mebibytes, PAddr};
@@ -10,8 +10,9 @@ pub mod memory {
     pub const RAMDISK_ADDR: PAddr = PAddr::new(0x4000_0000 + mebibytes(1)).unwrap();
 }
 
-pub const VIRTUAL_ADDRESS_BITS: usize = 39;
-pub type PageTableImpl = crate::paging::PageTableImpl<Level3>;
+/// The paging modes that can be used on this platform, most preferred first. The C906 only supports Sv39.
+pub const PAGING_MODES: &[PagingMode] = &[PagingMode::Sv39];
+pub type PageTableImpl = crate::paging::PageTableImpl;
 
 /// This module contains constants that define how the kernel address space is laid out on RISC-V,
 /// using the Sv39 paging model. The Sv39 model provides us with a 512GiB address space, which is a
@@ -26,7 +27,7 @@ pub type PageTableImpl = crate::paging::PageTableImpl<Level3>;
 ///
 /// The top 1GiB is reserved for the kernel itself, starting at `0xffff_ffff_c000_0000`.
 pub mod kernel_map {
-    use hal::memory::{PAddr, VAddr};
+    use hal::memory::{mebibytes, Bytes, PAddr, VAddr};
 
     pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_ffc0_0000_0000);
     pub const PHYSICAL_MAP_BASE: VAddr = KERNEL_ADDRESS_SPACE_START;
@@ -41,7 +42,59 @@ pub mod kernel_map {
         PHYSICAL_MAP_BASE + usize::from(address)
     }
 
-    /// The kernel starts at -1GiB. The kernel image is loaded directly at this address, and the following space until
-    /// the top of memory is managed dynamically and contains the boot info structures, memory map, and kernel heap.
+    /// The kernel is linked at -1GiB. The kernel image is loaded at this address, moved up by a random slide (see
+    /// `KERNEL_SLIDE_RANGE`), and the following space until the top of memory is managed dynamically and contains
+    /// the boot info structures, memory map, and kernel heap.
     pub const KERNEL_BASE: VAddr = VAddr::new(0xffff_ffff_c000_0000);
+    /// The loader picks a page-aligned slide below this to relocate the kernel by. This is smaller than on other
+    /// platforms as the kernel region is only 1GiB in total.
+    pub const KERNEL_SLIDE_RANGE: Bytes = mebibytes(512);
+}
+
+/// The C906 core on the D1 does not keep its data cache coherent with DMA, and so the cache must be managed
+/// manually. This uses the cache instructions from T-Head's vendor extension, which are encoded by hand as the
+/// assembler doesn't support them.
+pub mod cache {
+    use core::arch::asm;
+    use hal::memory::PAddr;
+
+    const CACHE_LINE_SIZE: usize = 64;
+
+    /// Write any dirty cache lines covering the given area of memory back to memory.
+    pub unsafe fn clean(address: PAddr, size: usize) {
+        for line in lines(address, size) {
+            // `th.dcache.cpa a0`
+            unsafe { asm!(".word 0x0295000b", in("a0") line) };
+        }
+        unsafe { sync() };
+    }
+
+    /// Discard any cache lines covering the given area of memory, so that it's next read from memory.
+    pub unsafe fn invalidate(address: PAddr, size: usize) {
+        for line in lines(address, size) {
+            // `th.dcache.ipa a0`
+            unsafe { asm!(".word 0x02a5000b", in("a0") line) };
+        }
+        unsafe { sync() };
+    }
+
+    /// Write any dirty cache lines covering the given area of memory back to memory, and then discard them.
+    pub unsafe fn clean_and_invalidate(address: PAddr, size: usize) {
+        for line in lines(address, size) {
+            // `th.dcache.cipa a0`
+            unsafe { asm!(".word 0x02b5000b", in("a0") line) };
+        }
+        unsafe { sync() };
+    }
+
+    fn lines(address: PAddr, size: usize) -> impl Iterator<Item = usize> {
+        let start = mulch::math::align_down(usize::from(address), CACHE_LINE_SIZE);
+        (start..(usize::from(address) + size)).step_by(CACHE_LINE_SIZE)
+    }
+
+    /// Wait for all previous cache operations to complete.
+    unsafe fn sync() {
+        // `th.sync.s`
+        unsafe { asm!(".word 0x0190000b") };
+    }
 }
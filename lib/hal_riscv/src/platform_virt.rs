@@ -1,4 +1,4 @@
-use crate::paging::Level4;
+use crate::paging::PagingMode;
 
 pub mod memory {
     use hal::memory::PAddr;
@@ -10,33 +10,31 @@ pub mod memory {
     pub const RAMDISK_ADDR: PAddr = PAddr::new(0xb000_0000).unwrap();
 }
 
-pub const VIRTUAL_ADDRESS_BITS: usize = 48;
-pub type PageTableImpl = crate::paging::PageTableImpl<Level4>;
+/// The paging modes that can be used on this platform, most preferred first. Seed uses the first one the hart
+/// supports, so we use Sv57 where we can, and fall back to Sv48 on harts that don't support it.
+pub const PAGING_MODES: &[PagingMode] = &[PagingMode::Sv57, PagingMode::Sv48];
+pub type PageTableImpl = crate::paging::PageTableImpl;
 
-/// This module contains constants that define how the kernel address space is laid out on RISC-V
-/// using the Sv48 paging model. It is very similar to the layout on `x86_64`, as the structure of
-/// the page tables are almost identical on the two architectures.
+/// This module contains constants that define how the kernel address space is laid out on RISC-V using the Sv48 or
+/// Sv57 paging models. It is very similar to the layout on `x86_64`, as the structure of the page tables are
+/// almost identical on the two architectures. The layout is the same with both paging models: the kernel owns the
+/// upper half of the 48-bit address space, which is canonical with both 48-bit and 57-bit virtual addresses.
+/// Userspace gets the lower half of the address space translated by whichever paging model is in use - 128TiB
+/// with Sv48, and 64PiB with Sv57.
 ///
-/// The 511th P4 entry (virtual addresses `0xffff_ff80_0000_0000` through `0xffff_ffff_ffff_ffff`)
-/// is always mapped to the kernel P3. The rest of the virtual address space (virtual addresses
-/// `0x0000_0000_0000_0000` through `0xffff_ff7f_ffff_ffff`) are free for userspace to use.
+/// The kernel's part of the address space starts at `0xffff_8000_0000_0000`, and the start of it is used to map
+/// all of physical memory. The 511th P4 entry (virtual addresses `0xffff_ff80_0000_0000` through
+/// `0xffff_ffff_ffff_ffff`) is mapped to the kernel P3. The kernel itself lies within the top 2GiB of the address
+/// space (the top two entries of the kernel P3). Directly below the base of the kernel, we reserve 128GiB for task
+/// kernel stacks, which gives us a maximum of 65536 tasks if each one has the default stack size.
 ///
-/// This gives us 512 GiB of kernel space. The kernel itself lies within the top 2GiB of the
-/// address space (the top two entries of the kernel P3). The remaining 510 GiB of the kernel P3 is
-/// used to map the entirety of physical memory into the kernel address space, and for task kernel
-/// stacks.
-///
-/// Directly below the base of the kernel, we reserve 128GiB for task kernel stacks, which gives us
-/// a maximum of
-/// 65536 tasks if each one has the default stack size.
-///
-/// This leaves us 382GiB for the physical memory map, which should be sufficient for any system I
-/// can imagine us running on (famous last words).
+/// With Sv48, every address space shares the upper half of the kernel's P4. With Sv57, the kernel's part of the
+/// address space is all translated by the 511th P5 entry, and so every address space shares the kernel's P4
+/// instead.
 pub mod kernel_map {
-    use hal::memory::{mebibytes, Bytes, PAddr, VAddr};
+    use hal::memory::{gibibytes, mebibytes, Bytes, PAddr, VAddr};
 
-    pub const KERNEL_P4_ENTRY: usize = 511;
-    pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_ff80_0000_0000);
+    pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_8000_0000_0000);
 
     pub const PHYSICAL_MAP_BASE: VAddr = KERNEL_ADDRESS_SPACE_START;
 
@@ -58,9 +56,30 @@ pub mod kernel_map {
     pub const STACK_SLOT_SIZE: Bytes = mebibytes(2);
     pub const MAX_TASKS: usize = 65536;
 
-    /// The kernel starts at -2GiB. The kernel image is loaded directly at this address, and the following space until
-    /// the top of memory is managed dynamically and contains the boot info structures, memory map, and kernel heap.
+    /// The kernel is linked at -2GiB. The kernel image is loaded at this address, moved up by a random slide (see
+    /// `KERNEL_SLIDE_RANGE`), and the following space until the top of memory is managed dynamically and contains
+    /// the boot info structures, memory map, and kernel heap.
     pub const KERNEL_BASE: VAddr = VAddr::new(0xffff_ffff_8000_0000);
+    /// The loader picks a page-aligned slide below this to relocate the kernel by.
+    pub const KERNEL_SLIDE_RANGE: Bytes = gibibytes(1);
+}
+
+/// DMA is cache-coherent on QEMU's `virt` machine, so cache maintenance only needs to order memory accesses.
+pub mod cache {
+    use core::arch::asm;
+    use hal::memory::PAddr;
+
+    pub unsafe fn clean(_address: PAddr, _size: usize) {
+        unsafe { asm!("fence iorw, iorw") };
+    }
+
+    pub unsafe fn invalidate(_address: PAddr, _size: usize) {
+        unsafe { asm!("fence iorw, iorw") };
+    }
+
+    pub unsafe fn clean_and_invalidate(_address: PAddr, _size: usize) {
+        unsafe { asm!("fence iorw, iorw") };
+    }
 }
 
 pub fn hart_to_plic_context_id(hart_id: usize) -> usize {
@@ -9,6 +9,18 @@ use core::{arch::x86_64::CpuidResult, str};
 #[derive(Clone, Copy, Debug)]
 pub struct SupportedFeatures {
     pub xsave: bool,
+    /// Whether the local APIC can be put into x2APIC mode, where its registers are accessed through MSRs.
+    pub x2apic: bool,
+    /// Whether the `monitor` and `mwait` instructions are supported.
+    pub monitor: bool,
+    /// Whether the processor's performance state can be changed through `IA32_PERF_CTL` (Enhanced Intel
+    /// SpeedStep Technology).
+    pub eist: bool,
+    /// Whether the `rdrand` instruction is supported, which gets random numbers from the processor's hardware
+    /// random number generator.
+    pub rdrand: bool,
+    /// Whether the AVX instructions and `ymm` registers are supported. They also need to be enabled in `XCR0`.
+    pub avx: bool,
 }
 
 /// Describes information we know about the system we're running on.
@@ -122,6 +134,142 @@ impl CpuInfo {
         // running on.
         None
     }
+
+    /// Get the frequency the TSC runs at (in Hz), if `cpuid` tells us. If this returns `None`, the TSC has to be
+    /// calibrated against another timer.
+    pub fn tsc_frequency(&self) -> Option<u64> {
+        if let Some(ref hypervisor_info) = self.hypervisor_info {
+            if let Some(tsc_freq) = hypervisor_info.tsc_frequency {
+                return Some(tsc_freq);
+            }
+        }
+
+        /*
+         * The TSC frequency leaf describes the TSC's frequency as a ratio of the core crystal clock's. Some
+         * processors report the ratio without the crystal's frequency, in which case we can't use it.
+         */
+        if self.max_supported_standard_level >= 0x15 {
+            let tsc_entry = cpuid(CpuidEntry::TscFrequency);
+
+            if tsc_entry.eax != 0 && tsc_entry.ebx != 0 && tsc_entry.ecx != 0 {
+                return Some(u64::from(tsc_entry.ecx) * u64::from(tsc_entry.ebx) / u64::from(tsc_entry.eax));
+            }
+        }
+
+        None
+    }
+
+    /// Get information about the processor's architectural performance-monitoring unit, if it has one. This is
+    /// only described by Intel processors - AMD's performance counters work differently.
+    /// Whether `mwait` can be asked to wake up on interrupts even when they're disabled, by setting bit `0` of
+    /// `ecx`.
+    pub fn mwait_breaks_on_masked_interrupts(&self) -> bool {
+        if !self.supported_features.monitor || self.max_supported_standard_level < 0x5 {
+            return false;
+        }
+        let entry = cpuid(CpuidEntry::MonitorMwait);
+        entry.ecx.get_bit(0) && entry.ecx.get_bit(1)
+    }
+
+    /// Whether the processor supports Hardware-controlled Performance States (HWP), where the OS gives the
+    /// processor a range of performance levels through `IA32_HWP_REQUEST`, and it picks a frequency itself.
+    pub fn supports_hwp(&self) -> bool {
+        self.max_supported_standard_level >= 0x6 && cpuid(CpuidEntry::ThermalAndPower).eax.get_bit(7)
+    }
+
+    /// Whether the processor supports 5-level paging, which extends virtual addresses from 48 to 57 bits. It's
+    /// used if `CR4.LA57` is set when paging is enabled.
+    pub fn supports_la57(&self) -> bool {
+        self.max_supported_standard_level >= 0x7 && cpuid(CpuidEntry::ExtendedFeatures).ecx.get_bit(16)
+    }
+
+    /// Get the state components that can be enabled in `XCR0`, as a mask of its bits. This is only valid if the
+    /// processor supports `xsave`.
+    pub fn supported_xsave_components(&self) -> u64 {
+        let entry = cpuid(CpuidEntry::ExtendedState);
+        u64::from(entry.edx) << 32 | u64::from(entry.eax)
+    }
+
+    /// Get the size (in bytes) of the area `xsave` needs to save the state components that are currently enabled
+    /// in `XCR0`. This changes when `XCR0` does, so should be read after it has been set.
+    pub fn xsave_area_size(&self) -> usize {
+        cpuid(CpuidEntry::ExtendedState).ebx as usize
+    }
+
+    /// Whether the `xsaveopt` instruction is supported. It works like `xsave`, but skips writing out state
+    /// components that haven't been changed since they were restored from the same area with `xrstor`.
+    pub fn supports_xsaveopt(&self) -> bool {
+        self.supported_features.xsave && cpuid_subleaf(CpuidEntry::ExtendedState, 1).eax.get_bit(0)
+    }
+
+    pub fn performance_monitoring(&self) -> Option<PerformanceMonitoringInfo> {
+        if self.max_supported_standard_level < 0xa {
+            return None;
+        }
+
+        let entry = cpuid(CpuidEntry::PerformanceMonitoring);
+        let version = entry.eax.get_bits(0..8) as u8;
+        if version == 0 {
+            return None;
+        }
+
+        Some(PerformanceMonitoringInfo {
+            version,
+            num_general_counters: entry.eax.get_bits(8..16) as u8,
+            // The number of fixed-function counters is only reported from version 2
+            num_fixed_counters: if version >= 2 { entry.edx.get_bits(0..5) as u8 } else { 0 },
+            num_events: entry.eax.get_bits(24..32) as u8,
+            unavailable_events: entry.ebx,
+        })
+    }
+}
+
+/// Describes the architectural performance-monitoring unit. See `CpuInfo::performance_monitoring`.
+#[derive(Clone, Copy, Debug)]
+pub struct PerformanceMonitoringInfo {
+    pub version: u8,
+    pub num_general_counters: u8,
+    pub num_fixed_counters: u8,
+    /// The number of architectural events that `unavailable_events` describes.
+    num_events: u8,
+    /// Bit `n` is set if architectural event `n` can *not* be counted.
+    unavailable_events: u32,
+}
+
+impl PerformanceMonitoringInfo {
+    pub fn event_available(&self, event: ArchitecturalEvent) -> bool {
+        let index = event as u8;
+        index < self.num_events && !self.unavailable_events.get_bit(index as usize)
+    }
+}
+
+/// The events that can be counted in the same way on every processor with an architectural
+/// performance-monitoring unit. Not every processor supports all of them - see
+/// `PerformanceMonitoringInfo::event_available`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchitecturalEvent {
+    CoreCycles = 0,
+    InstructionsRetired = 1,
+    ReferenceCycles = 2,
+    LastLevelCacheReferences = 3,
+    LastLevelCacheMisses = 4,
+    BranchInstructionsRetired = 5,
+    BranchMispredictsRetired = 6,
+}
+
+impl ArchitecturalEvent {
+    /// The event select and unit mask to program into a general-purpose counter to count this event.
+    pub fn event_select(self) -> (u8, u8) {
+        match self {
+            ArchitecturalEvent::CoreCycles => (0x3c, 0x00),
+            ArchitecturalEvent::InstructionsRetired => (0xc0, 0x00),
+            ArchitecturalEvent::ReferenceCycles => (0x3c, 0x01),
+            ArchitecturalEvent::LastLevelCacheReferences => (0x2e, 0x4f),
+            ArchitecturalEvent::LastLevelCacheMisses => (0x2e, 0x41),
+            ArchitecturalEvent::BranchInstructionsRetired => (0xc4, 0x00),
+            ArchitecturalEvent::BranchMispredictsRetired => (0xc5, 0x00),
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -183,6 +331,7 @@ pub struct HypervisorInfo {
     pub vendor: HypervisorVendor,
     pub max_leaf: u32,
     pub apic_frequency: Option<u32>,
+    pub tsc_frequency: Option<u64>,
 }
 
 /// This is used to reinterpret the bytes of the vendor strings that are spread across the three
@@ -218,6 +367,8 @@ enum CpuidEntry {
     ///
     /// C = feature info (below are for individual bits. 1 = support)
     ///     0 = SSE3
+    ///     3 = MONITOR/MWAIT
+    ///     7 = Enhanced Intel SpeedStep Technology
     ///     19 = SSE4.1
     ///     20 = SSE4.2
     ///     21 = x2APIC
@@ -234,6 +385,31 @@ enum CpuidEntry {
     ///     19 = CLFLUSH
     ProcessorInfo = 0x01,
 
+    /// C(bit 0) = MWAIT extensions are enumerated
+    /// C(bit 1) = interrupts break out of MWAIT, even when they're disabled
+    MonitorMwait = 0x05,
+
+    /// A(bit 7) = Hardware-controlled Performance States (HWP)
+    ThermalAndPower = 0x06,
+
+    /// C(bit 16) = 57-bit virtual addresses and 5-level paging (LA57)
+    /// (this is sub-leaf 0, which `cpuid` always asks for)
+    ExtendedFeatures = 0x07,
+
+    /// Sub-leaf 0:
+    ///     A,D = state components that can be enabled in XCR0 (D holds bits 32-63)
+    ///     B = size of the XSAVE area needed for the state components currently enabled in XCR0
+    /// Sub-leaf 1:
+    ///     A(bit 0) = XSAVEOPT
+    ExtendedState = 0x0d,
+
+    /// A(bits 0-7) = version of the architectural performance-monitoring unit
+    /// A(bits 8-15) = number of general-purpose counters
+    /// A(bits 24-31) = number of architectural events described by B
+    /// B = bit n is set if architectural event n is *not* available
+    /// D(bits 0-4) = number of fixed-function counters (from version 2)
+    PerformanceMonitoring = 0x0a,
+
     /// A = denominator
     /// B = numerator
     /// C = core crystal clock frequency
@@ -245,7 +421,7 @@ enum CpuidEntry {
     /// B,C,D = vendor ID string
     HypervisorVendor = 0x4000_0000,
 
-    /// A = (virtual) TSC frequency
+    /// A = (virtual) TSC frequency in kHz
     /// B = (virtual) bus (local APIC timer) frequency in kHz
     HypervisorFrequencies = 0x4000_0010,
 }
@@ -274,7 +450,14 @@ fn decode_model_info(model_info: u32) -> ModelInfo {
 }
 
 fn decode_supported_features(processor_info_ecx: u32, _processor_info_edx: u32) -> SupportedFeatures {
-    SupportedFeatures { xsave: processor_info_ecx.get_bit(26) }
+    SupportedFeatures {
+        xsave: processor_info_ecx.get_bit(26),
+        x2apic: processor_info_ecx.get_bit(21),
+        monitor: processor_info_ecx.get_bit(3),
+        eist: processor_info_ecx.get_bit(7),
+        rdrand: processor_info_ecx.get_bit(30),
+        avx: processor_info_ecx.get_bit(28),
+    }
 }
 
 fn decode_hypervisor_info() -> Option<HypervisorInfo> {
@@ -304,17 +487,25 @@ fn decode_hypervisor_info() -> Option<HypervisorInfo> {
     };
 
     /*
-     * If cpuid has the hypervisor timing leaf, use the bus frequency of that.
-     * NOTE: this is in kHz, so we convert to Hz
+     * If cpuid has the hypervisor timing leaf, use the TSC and bus frequencies of that.
+     * NOTE: these are in kHz, so we convert to Hz
      * NOTE: for this to exist under KVM, the `vmware-cpuid-freq` and `invtsc` cpu flags must be
      * set.
      */
-    let apic_frequency =
-        if max_leaf >= 0x4000_0010 { Some(cpuid(CpuidEntry::HypervisorFrequencies).ebx * 1000) } else { None };
+    let (apic_frequency, tsc_frequency) = if max_leaf >= 0x4000_0010 {
+        let frequencies = cpuid(CpuidEntry::HypervisorFrequencies);
+        (Some(frequencies.ebx * 1000), Some(u64::from(frequencies.eax) * 1000))
+    } else {
+        (None, None)
+    };
 
-    Some(HypervisorInfo { vendor, max_leaf, apic_frequency })
+    Some(HypervisorInfo { vendor, max_leaf, apic_frequency, tsc_frequency })
 }
 
 fn cpuid(entry: CpuidEntry) -> CpuidResult {
     unsafe { core::arch::x86_64::__cpuid(entry as u32) }
 }
+
+fn cpuid_subleaf(entry: CpuidEntry, subleaf: u32) -> CpuidResult {
+    unsafe { core::arch::x86_64::__cpuid_count(entry as u32, subleaf) }
+}
@@ -1,3 +1,5 @@
+use super::registers::{read_msr, write_msr, IA32_APIC_BASE};
+use bit_field::BitField;
 use core::ptr;
 use hal::memory::VAddr;
 
@@ -24,20 +26,65 @@ impl LocalApicRegister {
     }
 }
 
-pub struct LocalApic(VAddr);
+/// How the local APIC's registers are accessed.
+#[derive(Clone, Copy, Debug)]
+pub enum LocalApicMode {
+    /// The registers are memory-mapped at the given address.
+    XApic(VAddr),
+    /// The registers are accessed as MSRs. Each register is at MSR `0x800 + offset / 16`, where `offset` is
+    /// its offset in the xAPIC's memory-mapped configuration area.
+    X2Apic,
+}
+
+pub struct LocalApic(LocalApicMode);
 
 impl LocalApic {
+    /// Access the local APIC in xAPIC mode, through its configuration area, which must be mapped at `address`.
     pub unsafe fn new(address: VAddr) -> LocalApic {
-        LocalApic(address)
+        LocalApic(LocalApicMode::XApic(address))
+    }
+
+    /// Access the local APIC in x2APIC mode, through MSRs. Each processor switches its own local APIC into
+    /// x2APIC mode in `enable`, so this must only be used if `cpuid` says x2APIC is supported.
+    pub unsafe fn new_x2apic() -> LocalApic {
+        LocalApic(LocalApicMode::X2Apic)
     }
 
+    pub fn mode(&self) -> LocalApicMode {
+        self.0
+    }
+
+    /// Enable the running processor's local APIC. In x2APIC mode, this also moves the local APIC into x2APIC
+    /// mode, which has to be done by each processor.
     pub unsafe fn enable(&self, spurious_vector: u8) {
+        if let LocalApicMode::X2Apic = self.0 {
+            /*
+             * Set both the global enable (bit 11) and x2APIC enable (bit 10) bits. The local APIC can't move
+             * straight from being disabled to x2APIC mode, but firmware always leaves it enabled.
+             */
+            unsafe {
+                let mut apic_base = read_msr(IA32_APIC_BASE);
+                apic_base.set_bit(11, true);
+                apic_base.set_bit(10, true);
+                write_msr(IA32_APIC_BASE, apic_base);
+            }
+        }
+
         /*
          * - Enable the local APIC by setting bit 8
          * - Set the IRQ for spurious interrupts
          */
         unsafe {
-            self.register(0xf0).write((1 << 8) | u32::from(spurious_vector));
+            self.write_register(0xf0, (1 << 8) | u32::from(spurious_vector));
+        }
+    }
+
+    /// Get the ID of this local APIC. This is the ID used to address the processor it belongs to with IPIs and
+    /// MSIs. In x2APIC mode, the whole register is the ID, while in xAPIC mode it's only the top byte.
+    pub fn id(&self) -> u32 {
+        match self.0 {
+            LocalApicMode::XApic(_) => unsafe { self.read_register(0x20) >> 24 },
+            LocalApicMode::X2Apic => unsafe { self.read_register(0x20) },
         }
     }
 
@@ -68,9 +115,9 @@ impl LocalApic {
                 entry.set_bits(17..19, 0b01); // Periodic mode
                 entry
             };
-            self.register(0x3e0).write(0b0011); // Step 1: Set the divider to 16
-            self.register(0x320).write(timer_entry); // Step 2: enable the timer
-            self.register(0x380).write(ticks); // Step 3: Set the initial count
+            self.write_register(0x3e0, 0b0011); // Step 1: Set the divider to 16
+            self.write_register(0x320, timer_entry); // Step 2: enable the timer
+            self.write_register(0x380, ticks); // Step 3: Set the initial count
         }
 
         /*
@@ -105,8 +152,77 @@ impl LocalApic {
         // }
     }
 
-    pub unsafe fn register(&self, offset: usize) -> LocalApicRegister {
-        unsafe { LocalApicRegister::new((self.0 + offset).mut_ptr() as *mut u32) }
+    /// Send an INIT IPI to the processor with the given local APIC id. This is the first step of
+    /// starting an application processor.
+    pub unsafe fn send_init_ipi(&self, local_apic_id: u32) {
+        unsafe {
+            // Delivery mode = INIT (0b101), level = assert
+            self.send_ipi(local_apic_id, (0b101 << 8) | (1 << 14));
+        }
+    }
+
+    /// Send a Startup IPI to the processor with the given local APIC id. The processor will start
+    /// executing in real mode at the physical address `vector_page * 0x1000`, so the page number must be below
+    /// `0x100` (i.e. the code must be in the first 1MiB of physical memory).
+    pub unsafe fn send_startup_ipi(&self, local_apic_id: u32, vector_page: u8) {
+        unsafe {
+            // Delivery mode = Start Up (0b110), level = assert
+            self.send_ipi(local_apic_id, u32::from(vector_page) | (0b110 << 8) | (1 << 14));
+        }
+    }
+
+    /// Send an NMI to the processor with the given local APIC id. Unlike an IPI with a vector, this is delivered
+    /// even if the processor has interrupts disabled.
+    pub unsafe fn send_nmi(&self, local_apic_id: u32) {
+        unsafe {
+            // Delivery mode = NMI (0b100), level = assert
+            self.send_ipi(local_apic_id, (0b100 << 8) | (1 << 14));
+        }
+    }
+
+    /// Send an IPI by writing to the Interrupt Command Register. In xAPIC mode, writing to the low
+    /// half of the ICR sends the IPI, so the destination must be written first. We then wait for
+    /// the local APIC to report that the IPI has been delivered. In x2APIC mode, the ICR is a
+    /// single 64-bit MSR, and there's no Delivery Status bit to wait for.
+    unsafe fn send_ipi(&self, local_apic_id: u32, command: u32) {
+        match self.0 {
+            LocalApicMode::XApic(address) => unsafe {
+                LocalApicRegister::new((address + 0x310).mut_ptr()).write(local_apic_id << 24);
+                LocalApicRegister::new((address + 0x300).mut_ptr()).write(command);
+
+                // Wait for the Delivery Status bit to clear
+                while LocalApicRegister::new((address + 0x300).mut_ptr()).read() & (1 << 12) != 0 {
+                    core::hint::spin_loop();
+                }
+            },
+            LocalApicMode::X2Apic => unsafe {
+                write_msr(Self::x2apic_msr(0x300), (u64::from(local_apic_id) << 32) | u64::from(command));
+            },
+        }
+    }
+
+    /// Read from a register. Unsafe because not all registers can be read from.
+    pub unsafe fn read_register(&self, offset: usize) -> u32 {
+        match self.0 {
+            LocalApicMode::XApic(address) => unsafe {
+                LocalApicRegister::new((address + offset).mut_ptr()).read()
+            },
+            LocalApicMode::X2Apic => read_msr(Self::x2apic_msr(offset)) as u32,
+        }
+    }
+
+    /// Write to a register. Unsafe because not all registers can be written to.
+    pub unsafe fn write_register(&self, offset: usize, value: u32) {
+        match self.0 {
+            LocalApicMode::XApic(address) => unsafe {
+                LocalApicRegister::new((address + offset).mut_ptr()).write(value);
+            },
+            LocalApicMode::X2Apic => unsafe { write_msr(Self::x2apic_msr(offset), u64::from(value)) },
+        }
+    }
+
+    fn x2apic_msr(offset: usize) -> u32 {
+        0x800 + (offset as u32 >> 4)
     }
 
     /// Send an End Of Interrupt to the local APIC. This should be called by interrupt handlers
@@ -118,7 +234,7 @@ impl LocalApic {
          * will cause a #GP.
          */
         unsafe {
-            self.register(0xb0).write(0);
+            self.write_register(0xb0, 0);
         }
     }
 }
@@ -4,12 +4,15 @@ use crate::hw::port::Port;
 /// can differentiate between success/failure in QEMU vs the kernel itself.
 ///
 /// The code passed to the exit port is then turned into a QEMU exit code with `(code << 1) | 1`, so `Success` ends
-/// up as `0x21`, and `Failed` ends up as `0x23`. These can be handled specially by whatever invokes QEMU.
+/// up as `0x21`, `Failed` ends up as `0x23`, and `TestsFailed` ends up as `0x25`. These can be handled specially by
+/// whatever invokes QEMU.
 #[derive(Clone, Copy, Debug)]
 #[repr(u32)]
 pub enum ExitCode {
     Success = 0x10,
     Failed = 0x11,
+    /// The kernel's test runner finished, but some tests failed.
+    TestsFailed = 0x12,
 }
 
 pub struct ExitPort(Port<u32>);
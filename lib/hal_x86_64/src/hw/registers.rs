@@ -103,6 +103,19 @@ impl fmt::Debug for CpuFlags {
     }
 }
 
+/*
+ * Constants for bits in CR0.
+ */
+/// If this is set along with `CR0_TASK_SWITCHED`, `wait` and `fwait` cause a `#NM`, as well as FPU instructions.
+pub const CR0_MONITOR_COPROCESSOR: usize = 1;
+/// If this is set, x87 FPU and SIMD instructions cause a `#NM` (or a `#UD`), as if there was no FPU.
+pub const CR0_EMULATE_COPROCESSOR: usize = 2;
+/// If this is set, the next x87 FPU or SIMD instruction causes a `#NM`. This can be used to switch the FPU state
+/// lazily, but we don't.
+pub const CR0_TASK_SWITCHED: usize = 3;
+/// If this is set, x87 FPU errors are reported with a `#MF`, rather than through the legacy external interrupt.
+pub const CR0_NUMERIC_ERROR: usize = 5;
+
 /*
  * Constants for bits in CR4.
  */
@@ -110,6 +123,12 @@ impl fmt::Debug for CpuFlags {
 pub const CR4_RESTRICT_RDTSC: usize = 2;
 pub const CR4_ENABLE_PAE: usize = 5;
 pub const CR4_ENABLE_GLOBAL_PAGES: usize = 7;
+/// If this is set, SSE instructions can be used, and `fxsave` and `fxrstor` include the SSE registers.
+pub const CR4_ENABLE_SSE: usize = 9;
+/// If this is set, unmasked SIMD floating-point exceptions cause a `#XM`, rather than a `#UD`.
+pub const CR4_ENABLE_SIMD_EXCEPTIONS: usize = 10;
+/// If this is set when paging is enabled, 5-level paging is used. It can't be changed while in long mode.
+pub const CR4_ENABLE_LA57: usize = 12;
 pub const CR4_XSAVE_ENABLE_BIT: usize = 18;
 
 /// Read a control register. The name of the control register should be passed as any of: `CR0`,
@@ -141,12 +160,93 @@ pub macro write_control_reg($reg: ident, $value: expr) {
     );
 }
 
+/// Controls which state components are managed by `xsave` and `xrstor`, and so which sets of registers (and
+/// the instructions that use them) can be used. This can only be accessed once `CR4.OSXSAVE` is set.
+pub const XCR0: u32 = 0;
+
+/*
+ * Constants for bits in XCR0.
+ */
+/// The x87 FPU state. This must always be set.
+pub const XCR0_X87: usize = 0;
+/// The `xmm` registers and `MXCSR`.
+pub const XCR0_SSE: usize = 1;
+/// The upper halves of the `ymm` registers. `XCR0_SSE` must be set too.
+pub const XCR0_AVX: usize = 2;
+/// AVX-512 needs three state components: the opmask registers, the upper halves of `zmm0` to `zmm15`, and
+/// `zmm16` to `zmm31`. They must be enabled together, along with `XCR0_AVX`.
+pub const XCR0_AVX512: Range<usize> = 5..8;
+
+/// Read an extended control register.
+pub fn read_xcr(reg: u32) -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("xgetbv",
+            in("ecx") reg,
+            out("eax") low,
+            out("edx") high
+        );
+    }
+    (high as u64) << 32 | (low as u64)
+}
+
+/// Write to an extended control register. This is unsafe, because disabling state components that are in use
+/// will lose their contents.
+pub unsafe fn write_xcr(reg: u32, value: u64) {
+    unsafe {
+        asm!("xsetbv",
+            in("ecx") reg,
+            in("eax") value.get_bits(0..32) as u32,
+            in("edx") value.get_bits(32..64) as u32
+        );
+    }
+}
+
 pub const EFER: u32 = 0xc000_0080;
 
 pub const EFER_ENABLE_SYSCALL: usize = 0;
 pub const EFER_ENABLE_LONG_MODE: usize = 8;
 pub const EFER_ENABLE_NX_BIT: usize = 11;
 
+/// Holds the physical address of the local APIC's configuration area, and controls which mode the local APIC is
+/// in. Bit `11` enables the local APIC, and bit `10` moves it into x2APIC mode.
+pub const IA32_APIC_BASE: u32 = 0x1b;
+
+/// The first general-purpose performance counter. Counter `n` is at `IA32_PMC0 + n`.
+pub const IA32_PMC0: u32 = 0xc1;
+
+/// Selects the event counted by the first general-purpose performance counter. Counter `n` is controlled by
+/// `IA32_PERFEVTSEL0 + n`.
+pub const IA32_PERFEVTSEL0: u32 = 0x186;
+
+/// The first fixed-function performance counter, which counts instructions retired. Counter `n` is at
+/// `IA32_FIXED_CTR0 + n` - counter `1` counts core cycles, and counter `2` counts reference cycles.
+pub const IA32_FIXED_CTR0: u32 = 0x309;
+
+/// Controls the fixed-function performance counters, with four bits for each counter.
+pub const IA32_FIXED_CTR_CTRL: u32 = 0x38d;
+
+/// Enables the performance counters. Bit `n` enables general-purpose counter `n`, and bit `32 + n` enables
+/// fixed-function counter `n`.
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+
+/// Requests a performance state, with the encoding of the state in bits `0..16`. The values to use come from the
+/// ACPI `_PSS` object.
+pub const IA32_PERF_CTL: u32 = 0x199;
+
+/// Enables Hardware-controlled Performance States when bit `0` is set. Once enabled, HWP can't be disabled again
+/// until the processor is reset.
+pub const IA32_PM_ENABLE: u32 = 0x770;
+
+/// Describes the range of performance levels that can be requested with HWP. Bits `0..8` contain the highest
+/// level, and bits `24..32` the lowest.
+pub const IA32_HWP_CAPABILITIES: u32 = 0x771;
+
+/// Requests a range of performance levels from HWP. Bits `0..8` contain the minimum level, `8..16` the maximum,
+/// `16..24` the desired level (or `0` to let the processor choose), and `24..32` the energy-performance
+/// preference.
+pub const IA32_HWP_REQUEST: u32 = 0x774;
+
 /// Contains the Ring 0 and Ring 3 code-segment selectors loaded by `syscall` and `sysret`,
 /// respectively:
 /// * `syscall` loads bits 32-47 into CS (so this should be the Ring 0 code-segment)
@@ -2,6 +2,7 @@ use super::port::Port;
 use core::{arch::asm, fmt};
 
 pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
 
 pub struct SerialPort {
     data_register: Port<u8>,
@@ -80,6 +81,15 @@ impl SerialPort {
         unsafe { self.data_register.read() }
     }
 
+    /// Read a byte if one has been received, without waiting for one.
+    pub unsafe fn try_read(&self) -> Option<u8> {
+        if (unsafe { self.line_status_register.read() } & 1) == 0 {
+            return None;
+        }
+
+        Some(unsafe { self.data_register.read() })
+    }
+
     pub unsafe fn write(&mut self, value: u8) {
         while (unsafe { self.line_status_register.read() } & 0x20) == 0 {
             unsafe {
@@ -32,4 +32,11 @@ impl Tss {
     pub fn set_kernel_stack(&mut self, stack_pointer: VAddr) {
         self.privilege_stack_table[0] = stack_pointer;
     }
+
+    /// Set the stack that's switched to when an interrupt whose IDT entry has an IST index of `index` (from `1`
+    /// to `7`) occurs.
+    pub fn set_interrupt_stack(&mut self, index: u8, stack_pointer: VAddr) {
+        assert!((1..=7).contains(&index));
+        self.interrupt_stack_table[index as usize - 1] = stack_pointer;
+    }
 }
@@ -1,24 +1,29 @@
-//! This module contains constants that define how the kernel address space is laid out on x86_64. The 511th P4
-//! entry (virtual addresses `0xffff_ff80_0000_0000` through `0xffff_ffff_ffff_ffff`) is always mapped to the
-//! kernel P3. The rest of the virtual address space (virtual addresses `0x0000_0000_0000_0000` through
-//! `0xffff_ff7f_ffff_ffff`) are free for userspace to use.
+//! This module contains constants that define how the kernel address space is laid out on x86_64. The layout is
+//! the same with 4-level and 5-level paging: the kernel owns the upper half of the 48-bit address space, which is
+//! canonical with both 48-bit and 57-bit virtual addresses. Userspace gets the lower half of the address space
+//! translated by whichever paging mode is in use - 128TiB with 4-level paging, and 64PiB with 5-level paging (see
+//! `user_address_space_end`).
 //!
-//! This gives us 512 GiB of kernel space. The kernel itself is built with the `kernel` mc-model, and so must lie
-//! in the -2GiB of the address space (the top two entries of the kernel P3). The remaining 510 GiB of the kernel
-//! P3 is used to map the entirety of physical memory into the kernel address space, and for task kernel stacks.
+//! The kernel's part of the address space starts at `0xffff_8000_0000_0000`. The first 64TiB of it is used to map
+//! the entirety of physical memory into the kernel address space, which limits us to machines with 64TiB of
+//! physical memory. The 511th P4 entry (virtual addresses `0xffff_ff80_0000_0000` through
+//! `0xffff_ffff_ffff_ffff`) is mapped to the kernel P3. The kernel itself is built with the `kernel` mc-model, and
+//! so must lie in the -2GiB of the address space (the top two entries of the kernel P3). Directly below the base
+//! of the kernel, we reserve 128GiB for task kernel stacks, which gives us a maximum of 65536 tasks if each one
+//! has the default stack size.
 //!
-//! Directly below the base of the kernel, we reserve 128GiB for task kernel stacks, which gives us a maximum of
-//! 65536 tasks if each one has the default stack size.
-//!
-//! This leaves us 382GiB for the physical memory map, which should be sufficient for any system I can imagine us
-//! running on (famous last words).
+//! With 4-level paging, every address space shares the upper half of the kernel's P4. With 5-level paging, the
+//! kernel's part of the address space is all translated by the 511th P5 entry, and so every address space shares
+//! the kernel's P4 instead.
 
-use hal::memory::{mebibytes, Bytes, PAddr, VAddr};
+use crate::paging::PagingMode;
+use hal::memory::{gibibytes, mebibytes, Bytes, PAddr, VAddr};
 
-pub const KERNEL_P4_ENTRY: usize = 511;
-pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_ff80_0000_0000);
+pub const KERNEL_ADDRESS_SPACE_START: VAddr = VAddr::new(0xffff_8000_0000_0000);
 
 pub const PHYSICAL_MAPPING_BASE: VAddr = KERNEL_ADDRESS_SPACE_START;
+/// The size of the region reserved for the physical mapping. Physical memory above this can't be accessed.
+pub const PHYSICAL_MAPPING_SIZE: Bytes = gibibytes(64 * 1024);
 
 /// Access a given physical address through the physical mapping. This cannot be used until the kernel page tables
 /// have been switched to.
@@ -30,6 +35,12 @@ pub fn physical_to_virtual(address: PAddr) -> VAddr {
     PHYSICAL_MAPPING_BASE + usize::from(address)
 }
 
+/// Get the highest address userspace can use with the given paging mode. This is the top of the lower half of the
+/// address space, which grows from 128TiB to 64PiB with 5-level paging.
+pub const fn user_address_space_end(mode: PagingMode) -> VAddr {
+    VAddr::new((1 << (mode.virtual_address_bits() - 1)) - 1)
+}
+
 pub const KERNEL_STACKS_BASE: VAddr = VAddr::new(0xffff_ffdf_8000_0000);
 /*
  * There is an imposed maximum number of tasks because of the simple way we're allocating task kernel stacks.
@@ -38,6 +49,10 @@ pub const KERNEL_STACKS_BASE: VAddr = VAddr::new(0xffff_ffdf_8000_0000);
 pub const STACK_SLOT_SIZE: Bytes = mebibytes(2);
 pub const MAX_TASKS: usize = 65536;
 
-/// The kernel starts at -2GiB. The kernel image is loaded directly at this address, and the following space until
-/// the top of memory is managed dynamically and contains the boot info structures, memory map, and kernel heap.
+/// The kernel is linked at -2GiB. The kernel image is loaded at this address, moved up by a random slide (see
+/// `KERNEL_SLIDE_RANGE`), and the following space until the top of memory is managed dynamically and contains the
+/// boot info structures, memory map, and kernel heap.
 pub const KERNEL_BASE: VAddr = VAddr::new(0xffff_ffff_8000_0000);
+/// The loader picks a page-aligned slide below this to relocate the kernel by. This keeps the kernel within the
+/// bottom half of the -2GiB region, leaving the top 1GiB free for the structures the loader places after it.
+pub const KERNEL_SLIDE_RANGE: Bytes = gibibytes(1);
@@ -1,4 +1,7 @@
-use crate::hw::{registers::write_control_reg, tlb};
+use crate::hw::{
+    registers::{read_control_reg, write_control_reg, CR4_ENABLE_LA57},
+    tlb,
+};
 use bit_field::BitField;
 use bitflags::bitflags;
 use core::{
@@ -59,6 +62,17 @@ impl From<Flags> for EntryFlags {
     }
 }
 
+impl From<EntryFlags> for Flags {
+    fn from(flags: EntryFlags) -> Self {
+        Flags {
+            writable: flags.contains(EntryFlags::WRITABLE),
+            executable: !flags.contains(EntryFlags::NO_EXECUTE),
+            user_accessible: flags.contains(EntryFlags::USER_ACCESSIBLE),
+            cached: !flags.contains(EntryFlags::NO_CACHE),
+        }
+    }
+}
+
 /// Represents an entry within a page table of any level. Contains a physical address to the next level (or to the
 /// physical memory region), and some flags.
 #[repr(transparent)]
@@ -115,12 +129,14 @@ impl fmt::Debug for Entry {
     }
 }
 
+pub enum Level5 {}
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
 pub trait TableLevel {}
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -132,6 +148,9 @@ impl TableLevel for Level1 {}
 pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
+impl HierarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -249,8 +268,48 @@ where
     }
 }
 
+/// The paging modes supported on x86_64. Which one is used is decided by `CR4.LA57` when paging is enabled, and
+/// it can't be changed while in long mode. Seed builds the kernel's page tables for the mode the firmware left
+/// enabled, and the kernel then uses the same mode for every address space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PagingMode {
+    /// Four levels of tables, which translate 48-bit virtual addresses.
+    FourLevel,
+    /// Five levels of tables, which translate 57-bit virtual addresses. This needs a processor that supports
+    /// LA57 (see `CpuInfo::supports_la57`).
+    FiveLevel,
+}
+
+impl PagingMode {
+    /// Get the paging mode the running processor is using.
+    pub fn current() -> PagingMode {
+        if read_control_reg!(CR4).get_bit(CR4_ENABLE_LA57) {
+            PagingMode::FiveLevel
+        } else {
+            PagingMode::FourLevel
+        }
+    }
+
+    pub const fn virtual_address_bits(self) -> usize {
+        match self {
+            PagingMode::FourLevel => 48,
+            PagingMode::FiveLevel => 57,
+        }
+    }
+
+    /// Returns `true` if `address` is canonical in this mode. `VAddr` makes sure addresses are canonical with
+    /// 57-bit virtual addresses, so addresses can be canonical there but still not be translatable with 4-level
+    /// paging.
+    pub fn is_canonical(self, address: VAddr) -> bool {
+        let sign_bits = usize::from(address) >> (self.virtual_address_bits() - 1);
+        sign_bits == 0 || sign_bits == usize::MAX >> (self.virtual_address_bits() - 1)
+    }
+}
+
 pub struct PageTableImpl {
-    p4_frame: Frame,
+    /// The frame that holds the top-level table - a P4 with 4-level paging, and a P5 with 5-level paging.
+    frame: Frame,
+    mode: PagingMode,
     /// The virtual address at which physical memory is mapped in the environment that these page
     /// tables are being constructed in. This is **not** a property of the set of page tables being
     /// mapped. For example, in the bootloader, we construct a set of page tables for the kernel
@@ -262,36 +321,122 @@ pub struct PageTableImpl {
 }
 
 impl PageTableImpl {
-    pub fn new(p4_frame: Frame, physical_base: VAddr) -> PageTableImpl {
-        let mut table = PageTableImpl { p4_frame, physical_base };
-        table.p4_mut().zero();
+    pub fn new(frame: Frame, mode: PagingMode, physical_base: VAddr) -> PageTableImpl {
+        let mut table = PageTableImpl { frame, mode, physical_base };
+        table.top_mut::<Level4>().zero();
         table
     }
 
-    /// Create a `PageTableImpl` from a `Frame` that already contains a P4. This is very unsafe because
-    /// it assumes that the frame contains a valid page table, and that no other `PageTableImpl`s
+    /// Create a `PageTableImpl` from a `Frame` that already contains a top-level table for `mode`. This is very
+    /// unsafe because it assumes that the frame contains a valid page table, and that no other `PageTableImpl`s
     /// currently exist that use this same backing frame (as calling `mapper` on both could lead to
     /// two mutable references aliasing the same data to exist, which is UB).
-    pub unsafe fn from_frame(p4_frame: Frame, physical_base: VAddr) -> PageTableImpl {
-        PageTableImpl { p4_frame, physical_base }
+    pub unsafe fn from_frame(frame: Frame, mode: PagingMode, physical_base: VAddr) -> PageTableImpl {
+        PageTableImpl { frame, mode, physical_base }
     }
 
-    pub fn p4(&self) -> &Table<Level4> {
-        unsafe { &*((self.physical_base + usize::from(self.p4_frame.start)).ptr()) }
+    /// Get the frame that holds the top-level table. This is what should be loaded into `CR3` to use these
+    /// tables.
+    pub fn frame(&self) -> Frame {
+        self.frame
     }
 
-    pub fn p4_mut(&mut self) -> &mut Table<Level4> {
-        unsafe { &mut *((self.physical_base + usize::from(self.p4_frame.start)).mut_ptr()) }
+    pub fn mode(&self) -> PagingMode {
+        self.mode
     }
-}
 
-impl fmt::Debug for PageTableImpl {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "PageTable {{")?;
-        let p4 = self.p4();
+    /// Get the top-level table. The caller must pick the level that matches `self.mode`, unless it's only going
+    /// to look at entries without following them.
+    fn top<L: TableLevel>(&self) -> &Table<L> {
+        unsafe { &*((self.physical_base + usize::from(self.frame.start)).ptr()) }
+    }
+
+    fn top_mut<L: TableLevel>(&mut self) -> &mut Table<L> {
+        unsafe { &mut *((self.physical_base + usize::from(self.frame.start)).mut_ptr()) }
+    }
+
+    /// Get the P3 that translates `address`, if there is one. This hides how many levels of tables are above
+    /// the P3s, so the rest of the implementation doesn't need to care which paging mode is in use.
+    fn p3(&self, address: VAddr) -> Option<&Table<Level3>> {
+        if !self.mode.is_canonical(address) {
+            return None;
+        }
+
+        let p4 = match self.mode {
+            PagingMode::FourLevel => self.top::<Level4>(),
+            PagingMode::FiveLevel => self.top::<Level5>().next_table(address.p5_index(), self.physical_base)?,
+        };
+        p4.next_table(address.p4_index(), self.physical_base)
+    }
+
+    fn p3_mut(&mut self, address: VAddr) -> Option<&mut Table<Level3>> {
+        if !self.mode.is_canonical(address) {
+            return None;
+        }
+
+        let physical_base = self.physical_base;
+        let p4 = match self.mode {
+            PagingMode::FourLevel => self.top_mut::<Level4>(),
+            PagingMode::FiveLevel => self.top_mut::<Level5>().next_table_mut(address.p5_index(), physical_base)?,
+        };
+        p4.next_table_mut(address.p4_index(), physical_base)
+    }
+
+    /// Get the P3 that translates `address`, creating it and any tables above it if they don't exist.
+    fn p3_create<A>(&mut self, address: VAddr, allocator: &A) -> Result<&mut Table<Level3>, PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(self.mode.is_canonical(address), "Can't map non-canonical address: {:#x}", address);
+
+        let physical_base = self.physical_base;
+        let p4 = match self.mode {
+            PagingMode::FourLevel => self.top_mut::<Level4>(),
+            PagingMode::FiveLevel => {
+                self.top_mut::<Level5>().next_table_create(address.p5_index(), allocator, physical_base)?
+            }
+        };
+        p4.next_table_create(address.p4_index(), allocator, physical_base)
+    }
+
+    /// Find the size of the page that `address` is mapped with, or `None` if it isn't mapped.
+    fn page_size_at(&self, address: VAddr) -> Option<usize> {
+        let (entry, page_size) = self.leaf_entry(address)?;
+        entry.address().map(|_| page_size)
+    }
+
+    /// Find the entry that maps `address`, along with the size of the page it maps. The entry may not be
+    /// present if `address` isn't mapped.
+    fn leaf_entry(&self, address: VAddr) -> Option<(Entry, usize)> {
+        let p3 = self.p3(address)?;
+        let p3_entry = p3[address.p3_index()];
+        if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return Some((p3_entry, Size1GiB::SIZE));
+        }
+
+        let p2 = p3.next_table(address.p3_index(), self.physical_base)?;
+        let p2_entry = p2[address.p2_index()];
+        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return Some((p2_entry, Size2MiB::SIZE));
+        }
+
+        let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
+        Some((p1[address.p1_index()], Size4KiB::SIZE))
+    }
+
+    fn fmt_p4(&self, f: &mut fmt::Formatter<'_>, p4: &Table<Level4>, p5: Option<usize>) -> fmt::Result {
+        // Tables below a P5 entry are indented one more level
+        let indent = if p5.is_some() { 4 } else { 0 };
         for i in 0..512 {
             if p4[i].is_present() {
-                writeln!(f, "    P4 entry {}({:#x}): {:?}", i, VAddr::from_indices(i, 0, 0, 0), p4[i])?;
+                writeln!(
+                    f,
+                    "{:indent$}    P4 entry {}({:#x}): {:?}",
+                    "",
+                    i,
+                    VAddr::from_indices(p5, i, 0, 0, 0),
+                    p4[i]
+                )?;
                 if p4[i].flags().contains(EntryFlags::HUGE_PAGE) {
                     continue;
                 }
@@ -300,9 +445,10 @@ impl fmt::Debug for PageTableImpl {
                     if p3[j].is_present() {
                         writeln!(
                             f,
-                            "        P3 entry {}({:#x}): {:?}",
+                            "{:indent$}        P3 entry {}({:#x}): {:?}",
+                            "",
                             j,
-                            VAddr::from_indices(i, j, 0, 0),
+                            VAddr::from_indices(p5, i, j, 0, 0),
                             p3[j]
                         )?;
                         if p3[j].flags().contains(EntryFlags::HUGE_PAGE) {
@@ -313,9 +459,10 @@ impl fmt::Debug for PageTableImpl {
                             if p2[k].is_present() {
                                 writeln!(
                                     f,
-                                    "            P2 entry {}({:#x}): {:?}",
+                                    "{:indent$}            P2 entry {}({:#x}): {:?}",
+                                    "",
                                     k,
-                                    VAddr::from_indices(i, j, k, 0),
+                                    VAddr::from_indices(p5, i, j, k, 0),
                                     p2[k]
                                 )?;
                                 if p2[k].flags().contains(EntryFlags::HUGE_PAGE) {
@@ -326,9 +473,10 @@ impl fmt::Debug for PageTableImpl {
                                     if p1[m].is_present() {
                                         writeln!(
                                             f,
-                                            "                P1 entry {}({:#x}): {:?}",
+                                            "{:indent$}                P1 entry {}({:#x}): {:?}",
+                                            "",
                                             m,
-                                            VAddr::from_indices(i, j, k, m),
+                                            VAddr::from_indices(p5, i, j, k, m),
                                             p1[m]
                                         )?;
                                     }
@@ -339,6 +487,31 @@ impl fmt::Debug for PageTableImpl {
                 }
             }
         }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PageTableImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "PageTable ({:?}) {{", self.mode)?;
+        match self.mode {
+            PagingMode::FourLevel => self.fmt_p4(f, self.top::<Level4>(), None)?,
+            PagingMode::FiveLevel => {
+                let p5 = self.top::<Level5>();
+                for i in 0..512 {
+                    if p5[i].is_present() {
+                        writeln!(
+                            f,
+                            "    P5 entry {}({:#x}): {:?}",
+                            i,
+                            VAddr::from_indices(Some(i), 0, 0, 0, 0),
+                            p5[i]
+                        )?;
+                        self.fmt_p4(f, p5.next_table(i, self.physical_base).unwrap(), Some(i))?;
+                    }
+                }
+            }
+        }
         writeln!(f, "}}")?;
         Ok(())
     }
@@ -349,41 +522,44 @@ impl PageTable<Size4KiB> for PageTableImpl {
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let mut page_table = PageTableImpl::new(allocator.allocate(), crate::kernel_map::PHYSICAL_MAPPING_BASE);
+        let mut page_table = PageTableImpl::new(
+            allocator.allocate(),
+            kernel_page_table.mode,
+            crate::kernel_map::PHYSICAL_MAPPING_BASE,
+        );
 
         /*
-         * Install the address of the kernel's P3 in every address space, so that the kernel is always mapped.
-         * It's safe to unwrap the kernel P3 address, as we wouldn't be able to fetch these instructions
-         * if it wasn't there.
+         * The kernel owns the upper half of the address space, so we copy the upper half of the kernel's
+         * top-level table into every address space. With 4-level paging, these are the P4 entries for the
+         * physical mapping and the kernel P3, and with 5-level paging, it's the P5 entry that points to the
+         * kernel's P4. Seed creates all of these entries, and the kernel never adds more, so the copies can't get
+         * out of date. We're only copying entries, so it doesn't matter which level we treat the tables as.
          */
-        let kernel_p3_address = kernel_page_table.p4()[crate::kernel_map::KERNEL_P4_ENTRY].address().unwrap();
-        page_table.p4_mut()[crate::kernel_map::KERNEL_P4_ENTRY]
-            .set(Some((kernel_p3_address, EntryFlags::WRITABLE)));
+        let kernel_top = kernel_page_table.top::<Level4>();
+        let top = page_table.top_mut::<Level4>();
+        top.entries[(ENTRY_COUNT / 2)..].copy_from_slice(&kernel_top.entries[(ENTRY_COUNT / 2)..]);
 
         page_table
     }
 
     unsafe fn switch_to(&self) {
         unsafe {
-            write_control_reg!(cr3, usize::from(self.p4_frame.start) as u64);
+            write_control_reg!(cr3, usize::from(self.frame.start) as u64);
         }
     }
 
-    fn translate(&self, address: VAddr) -> Option<PAddr> {
-        // TODO: handle huge pages at the P3 level as well
-
-        let p2 = self
-            .p4()
-            .next_table(address.p4_index(), self.physical_base)
-            .and_then(|p3| p3.next_table(address.p3_index(), self.physical_base))?;
+    fn user_address_space_end(&self) -> VAddr {
+        crate::kernel_map::user_address_space_end(self.mode)
+    }
 
-        let p2_entry = p2[address.p2_index()];
-        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
-            return Some(p2_entry.address()? + (usize::from(address) % Size2MiB::SIZE));
-        }
+    fn translate(&self, address: VAddr) -> Option<PAddr> {
+        let (entry, page_size) = self.leaf_entry(address)?;
+        Some(entry.address()? + (usize::from(address) % page_size))
+    }
 
-        let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
-        Some(p1[address.p1_index()].address()? + (usize::from(address) % Size4KiB::SIZE))
+    fn flags(&self, address: VAddr) -> Option<Flags> {
+        let (entry, _) = self.leaf_entry(address)?;
+        entry.is_present().then(|| Flags::from(entry.flags()))
     }
 
     fn map<S, A>(&mut self, page: Page<S>, frame: Frame<S>, flags: Flags, allocator: &A) -> Result<(), PagingError>
@@ -395,8 +571,7 @@ impl PageTable<Size4KiB> for PageTableImpl {
 
         if S::SIZE == Size4KiB::SIZE {
             let p1 = self
-                .p4_mut()
-                .next_table_create(page.start.p4_index(), allocator, physical_base)?
+                .p3_create(page.start, allocator)?
                 .next_table_create(page.start.p3_index(), allocator, physical_base)?
                 .next_table_create(page.start.p2_index(), allocator, physical_base)?;
 
@@ -406,10 +581,11 @@ impl PageTable<Size4KiB> for PageTableImpl {
 
             p1[page.start.p1_index()].set(Some((frame.start, EntryFlags::from(flags))));
         } else if S::SIZE == Size2MiB::SIZE {
-            let p2 = self
-                .p4_mut()
-                .next_table_create(page.start.p4_index(), allocator, physical_base)?
-                .next_table_create(page.start.p3_index(), allocator, physical_base)?;
+            let p2 = self.p3_create(page.start, allocator)?.next_table_create(
+                page.start.p3_index(),
+                allocator,
+                physical_base,
+            )?;
 
             if !p2[page.start.p2_index()].is_unused() {
                 return Err(PagingError::AlreadyMapped);
@@ -419,7 +595,7 @@ impl PageTable<Size4KiB> for PageTableImpl {
         } else {
             assert_eq!(S::SIZE, Size1GiB::SIZE);
 
-            let p3 = self.p4_mut().next_table_create(page.start.p4_index(), allocator, physical_base)?;
+            let p3 = self.p3_create(page.start, allocator)?;
 
             if !p3[page.start.p3_index()].is_unused() {
                 return Err(PagingError::AlreadyMapped);
@@ -537,8 +713,7 @@ impl PageTable<Size4KiB> for PageTableImpl {
         match S::SIZE {
             Size4KiB::SIZE => {
                 let p1 = self
-                    .p4_mut()
-                    .next_table_mut(page.start.p4_index(), physical_base)?
+                    .p3_mut(page.start)?
                     .next_table_mut(page.start.p3_index(), physical_base)?
                     .next_table_mut(page.start.p2_index(), physical_base)?;
                 let frame = Frame::starts_with(p1[page.start.p1_index()].address()?);
@@ -547,24 +722,76 @@ impl PageTable<Size4KiB> for PageTableImpl {
 
                 Some(frame)
             }
-            Size2MiB::SIZE => unimplemented!(),
-            Size1GiB::SIZE => unimplemented!(),
+            Size2MiB::SIZE => {
+                let p2 = self.p3_mut(page.start)?.next_table_mut(page.start.p3_index(), physical_base)?;
+                if !p2[page.start.p2_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+                    return None;
+                }
+                let frame = Frame::starts_with(p2[page.start.p2_index()].address()?);
+                p2[page.start.p2_index()].set(None);
+                tlb::invalidate_page(page.start);
+
+                Some(frame)
+            }
+            Size1GiB::SIZE => {
+                let p3 = self.p3_mut(page.start)?;
+                if !p3[page.start.p3_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+                    return None;
+                }
+                let frame = Frame::starts_with(p3[page.start.p3_index()].address()?);
+                p3[page.start.p3_index()].set(None);
+                tlb::invalidate_page(page.start);
+
+                Some(frame)
+            }
 
             _ => panic!("Unimplemented page size!"),
         }
     }
+
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize) {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let mut cursor = virtual_start;
+        let virtual_end: VAddr = virtual_start + size;
+
+        while cursor < virtual_end {
+            let page_size = self.page_size_at(cursor);
+            match page_size {
+                Some(Size1GiB::SIZE) => {
+                    self.unmap::<Size1GiB>(Page::starts_with(cursor));
+                }
+                Some(Size2MiB::SIZE) => {
+                    self.unmap::<Size2MiB>(Page::starts_with(cursor));
+                }
+                Some(_) => {
+                    self.unmap::<Size4KiB>(Page::starts_with(cursor));
+                }
+                None => (),
+            }
+            cursor += page_size.unwrap_or(Size4KiB::SIZE);
+        }
+    }
 }
 
 pub trait VAddrIndices {
+    fn p5_index(self) -> usize;
     fn p4_index(self) -> usize;
     fn p3_index(self) -> usize;
     fn p2_index(self) -> usize;
     fn p1_index(self) -> usize;
 
-    fn from_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr;
+    /// Construct the address translated by the given table indices. `p5` should be `None` for addresses
+    /// translated with 4-level paging, in which case the address is sign-extended from bit 47.
+    fn from_indices(p5: Option<usize>, p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr;
 }
 
 impl VAddrIndices for VAddr {
+    fn p5_index(self) -> usize {
+        usize::from(self).get_bits(48..57)
+    }
+
     fn p4_index(self) -> usize {
         usize::from(self).get_bits(39..48)
     }
@@ -581,12 +808,22 @@ impl VAddrIndices for VAddr {
         usize::from(self).get_bits(12..21)
     }
 
-    fn from_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr {
+    fn from_indices(p5: Option<usize>, p4: usize, p3: usize, p2: usize, p1: usize) -> VAddr {
         let mut address = 0usize;
         address.set_bits(12..21, p1);
         address.set_bits(21..30, p2);
         address.set_bits(30..39, p3);
         address.set_bits(39..48, p4);
+        match p5 {
+            Some(p5) => {
+                address.set_bits(48..57, p5);
+            }
+            None => {
+                if p4.get_bit(8) {
+                    address.set_bits(48..64, 0xffff);
+                }
+            }
+        }
         VAddr::new(address)
     }
 }
@@ -715,6 +952,60 @@ mod tests {
         page_table.ensure_all_mappings_made();
     }
 
+    #[test]
+    fn test_canonical_addresses() {
+        assert!(PagingMode::FourLevel.is_canonical(VAddr::new(0x0000_7fff_ffff_f000)));
+        assert!(PagingMode::FourLevel.is_canonical(VAddr::new(0xffff_8000_0000_0000)));
+        assert!(!PagingMode::FourLevel.is_canonical(VAddr::new(0x0000_8000_0000_0000)));
+        assert!(!PagingMode::FourLevel.is_canonical(VAddr::new(0xff80_0000_0000_0000)));
+
+        assert!(PagingMode::FiveLevel.is_canonical(VAddr::new(0x0000_8000_0000_0000)));
+        assert!(PagingMode::FiveLevel.is_canonical(VAddr::new(0x00ff_ffff_ffff_f000)));
+        assert!(PagingMode::FiveLevel.is_canonical(VAddr::new(0xff80_0000_0000_0000)));
+        assert!(PagingMode::FiveLevel.is_canonical(VAddr::new(0xffff_8000_0000_0000)));
+
+        // The kernel's part of the address space must be the same in both modes
+        assert!(PagingMode::FourLevel.is_canonical(crate::kernel_map::KERNEL_ADDRESS_SPACE_START));
+        assert!(PagingMode::FiveLevel.is_canonical(crate::kernel_map::KERNEL_ADDRESS_SPACE_START));
+    }
+
+    #[test]
+    fn test_indices() {
+        let address = VAddr::new(0x00ab_cdef_1234_5000);
+        assert_eq!(
+            VAddr::from_indices(
+                Some(address.p5_index()),
+                address.p4_index(),
+                address.p3_index(),
+                address.p2_index(),
+                address.p1_index()
+            ),
+            address
+        );
+
+        assert_eq!(VAddr::from_indices(None, 511, 0, 0, 0), VAddr::new(0xffff_ff80_0000_0000));
+        assert_eq!(VAddr::from_indices(None, 255, 0, 0, 0), VAddr::new(0x0000_7f80_0000_0000));
+        assert_eq!(VAddr::from_indices(Some(511), 511, 0, 0, 0), VAddr::new(0xffff_ff80_0000_0000));
+        assert_eq!(VAddr::from_indices(Some(1), 0, 0, 0, 0), VAddr::new(0x0001_0000_0000_0000));
+
+        // The kernel is reached through the top entry of the top-level table in both modes
+        assert_eq!(crate::kernel_map::KERNEL_BASE.p5_index(), 511);
+        assert_eq!(crate::kernel_map::KERNEL_BASE.p4_index(), 511);
+    }
+
+    #[test]
+    fn test_user_address_space_end() {
+        assert_eq!(
+            crate::kernel_map::user_address_space_end(PagingMode::FourLevel),
+            VAddr::new(0x0000_7fff_ffff_ffff)
+        );
+        assert_eq!(
+            crate::kernel_map::user_address_space_end(PagingMode::FiveLevel),
+            VAddr::new(0x00ff_ffff_ffff_ffff)
+        );
+    }
+
+    #[derive(Debug)]
     struct TestPageTable {
         expected_maps: VecDeque<(usize, VAddr, PAddr)>,
     }
@@ -752,10 +1043,18 @@ mod tests {
             unimplemented!()
         }
 
+        fn user_address_space_end(&self) -> VAddr {
+            unimplemented!()
+        }
+
         fn translate(&self, _address: VAddr) -> Option<PAddr> {
             unimplemented!()
         }
 
+        fn flags(&self, _address: VAddr) -> Option<Flags> {
+            unimplemented!()
+        }
+
         fn map<S, A>(&mut self, page: Page<S>, frame: Frame<S>, flags: Flags, _: &A) -> Result<(), PagingError>
         where
             S: FrameSize,
@@ -912,5 +1211,9 @@ mod tests {
         {
             unimplemented!()
         }
+
+        fn unmap_area(&mut self, _virtual_start: VAddr, _size: usize) {
+            unimplemented!()
+        }
     }
 }
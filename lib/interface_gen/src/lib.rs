@@ -0,0 +1,318 @@
+//! `interface_gen` generates the code for a service protocol from a single definition of the protocol, made with
+//! the `interface!` macro. An interface is a set of methods that a client can call on a server over a `Channel`.
+//! Methods can either return a value, in which case they're made as a call (see `Channel::call`) and the client
+//! waits for the server to reply, or be one-way, in which case the request is just sent and the client carries on.
+//!
+//! For an interface, `interface!` generates:
+//!    - A request enum, with a variant for each method that carries its arguments. This is what's sent over the
+//!      channel, and is serialized with Ptah, so the crate defining the interface must depend on `ptah`.
+//!    - A reply enum, with a variant for each method that carries its return value.
+//!    - A client, which wraps a `Channel` and has a method for each of the interface's methods. Methods that
+//!      return a value are `async`, and return once the server has replied.
+//!    - A call enum, which describes a request that has been received by a server. Each variant has a field for
+//!      each of the method's arguments, and methods that return a value also have a `responder` field, which is
+//!      used to send the reply.
+//!    - A server, which receives calls from clients. Requests are received into a buffer owned by the server (see
+//!      `ChannelRef`), so receiving a call doesn't allocate unless the request itself does. Servers are also
+//!      `Stream`s of the calls they receive, so they can be combined with other sources of events.
+//!
+//! For example:
+//! ```ignore
+//! interface! {
+//!     pub interface Example {
+//!         request: ExampleRequest,
+//!         reply: ExampleReply,
+//!         call: ExampleCall,
+//!         client: ExampleClient,
+//!         server: ExampleServer,
+//!
+//!         /// Add two numbers together.
+//!         Add => fn add(a: u64, b: u64) -> u64;
+//!         /// Log a message. This is one-way, so the client doesn't wait for the server to handle it.
+//!         Log => fn log(message: String);
+//!     }
+//! }
+//!
+//! // On the server
+//! loop {
+//!     match server.next().await.unwrap() {
+//!         ExampleCall::Add { a, b, responder } => responder.reply(a + b).unwrap(),
+//!         ExampleCall::Log { message } => info!("{}", message),
+//!     }
+//! }
+//!
+//! // On the client
+//! assert_eq!(client.add(2, 3).await.unwrap(), 5);
+//! client.log("Hello, World!".to_string()).unwrap();
+//! ```
+
+#![no_std]
+
+use core::fmt;
+use poplar::{
+    channel::{CallError, CallId, Channel, ChannelReceiveError, ChannelSendError},
+    Handle,
+};
+use ptah::{DeserializeOwned, Serialize};
+
+#[doc(hidden)]
+pub mod __private {
+    pub use poplar;
+}
+
+#[derive(Debug)]
+pub enum InterfaceError {
+    /// A request or reply couldn't be sent.
+    Send(ChannelSendError),
+    /// A request couldn't be received.
+    Receive(ChannelReceiveError),
+    /// A call to a method that returns a value failed.
+    Call(CallError),
+    /// The server replied to a call with the return value of a different method.
+    UnexpectedReply,
+}
+
+/// Replies to a call to a method that returns a value. The client waits until it gets a reply, so servers must
+/// reply to every call they receive - if a `Responder` is dropped without replying, the client will never return.
+pub struct Responder<S, T>
+where
+    S: Serialize + DeserializeOwned,
+{
+    channel: Handle,
+    call: CallId,
+    wrap: fn(T) -> S,
+}
+
+impl<S, T> Responder<S, T>
+where
+    S: Serialize + DeserializeOwned,
+{
+    #[doc(hidden)]
+    pub fn new(channel: Handle, call: CallId, wrap: fn(T) -> S) -> Responder<S, T> {
+        Responder { channel, call, wrap }
+    }
+
+    pub fn reply(self, value: T) -> Result<(), InterfaceError> {
+        Channel::<S, ()>::new_from_handle(self.channel)
+            .reply(self.call, &(self.wrap)(value))
+            .map_err(InterfaceError::Send)
+    }
+}
+
+impl<S, T> fmt::Debug for Responder<S, T>
+where
+    S: Serialize + DeserializeOwned,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Responder").field("channel", &self.channel).field("call", &self.call).finish()
+    }
+}
+
+/// Define an interface. See the crate documentation for what this generates. The names of the generated types
+/// are given at the start of the definition, followed by the interface's methods. Each method has a name for its
+/// variant in the generated enums, and its signature. Methods must take at least one argument, and arguments can't
+/// be called `responder`.
+#[macro_export]
+macro_rules! interface {
+    {
+        $vis:vis interface $name:ident {
+            request: $request:ident,
+            reply: $reply:ident,
+            call: $call:ident,
+            client: $client:ident,
+            server: $server:ident,
+            $(
+                $(#[doc = $doc:literal])*
+                $variant:ident => fn $method:ident($($arg:ident: $arg_ty:ty),+ $(,)?) $(-> $ret:ty)?;
+            )+
+        }
+    } => {
+        #[doc = concat!("Requests made by clients of the `", stringify!($name), "` interface.")]
+        #[derive(Debug, ptah::Serialize, ptah::Deserialize)]
+        $vis enum $request {
+            $(
+                $(#[doc = $doc])*
+                $variant($($arg_ty),+),
+            )+
+        }
+
+        #[doc = concat!("Replies to calls to methods of the `", stringify!($name), "` interface. One-way methods")]
+        /// still have a variant here, but it is never sent.
+        #[derive(Debug, ptah::Serialize, ptah::Deserialize)]
+        $vis enum $reply {
+            $(
+                $variant($crate::__reply_type!($($ret)?)),
+            )+
+        }
+
+        #[doc = concat!("A call to a method of the `", stringify!($name), "` interface, received by a server.")]
+        #[derive(Debug)]
+        $vis enum $call {
+            $(
+                $(#[doc = $doc])*
+                $variant {
+                    $($arg: $arg_ty,)+
+                    $(responder: $crate::Responder<$reply, $ret>,)?
+                },
+            )+
+        }
+
+        #[doc = concat!("Makes requests to a server of the `", stringify!($name), "` interface.")]
+        $vis struct $client {
+            channel: $crate::__private::poplar::channel::Channel<$request, $reply>,
+        }
+
+        impl $client {
+            pub fn new(channel: $crate::__private::poplar::channel::Channel<$request, $reply>) -> $client {
+                $client { channel }
+            }
+
+            pub fn from_handle(handle: $crate::__private::poplar::Handle) -> $client {
+                $client { channel: $crate::__private::poplar::channel::Channel::new_from_handle(handle) }
+            }
+
+            $(
+                $crate::__client_method! {
+                    $request, $reply,
+                    $(#[doc = $doc])*
+                    $variant => fn $method($($arg: $arg_ty),+) $(-> $ret)?
+                }
+            )+
+        }
+
+        #[doc = concat!("Receives calls from clients of the `", stringify!($name), "` interface.")]
+        $vis struct $server {
+            channel: $crate::__private::poplar::channel::ChannelRef<$reply>,
+            buffer: $crate::__private::poplar::sync::Mutex<$crate::__private::poplar::channel::ReceiveBuffer>,
+        }
+
+        impl $server {
+            pub fn new(channel: $crate::__private::poplar::channel::Channel<$reply, $request>) -> $server {
+                $server::from_handle(channel.handle())
+            }
+
+            pub fn from_handle(handle: $crate::__private::poplar::Handle) -> $server {
+                $server {
+                    channel: $crate::__private::poplar::channel::ChannelRef::new_from_handle(handle),
+                    buffer: $crate::__private::poplar::sync::Mutex::new(
+                        $crate::__private::poplar::channel::ReceiveBuffer::new(),
+                    ),
+                }
+            }
+
+            /// Wait for the next call from the client.
+            pub async fn next(&self) -> Result<$call, $crate::InterfaceError> {
+                loop {
+                    if let Some(call) = self.try_next()? {
+                        return Ok(call);
+                    }
+                    self.channel.wait_for_message().await;
+                }
+            }
+
+            /// Wait for the next call from the client, blocking the whole task until it arrives.
+            pub fn next_blocking(&self) -> Result<$call, $crate::InterfaceError> {
+                let (request, call) = self
+                    .channel
+                    .receive_call_blocking::<$request>(&mut self.buffer.lock())
+                    .map_err($crate::InterfaceError::Receive)?;
+                Ok(self.dispatch(request, call))
+            }
+
+            /// Get the next call from the client, if there is one waiting.
+            pub fn try_next(&self) -> Result<Option<$call>, $crate::InterfaceError> {
+                let received = self
+                    .channel
+                    .try_receive_call::<$request>(&mut self.buffer.lock())
+                    .map_err($crate::InterfaceError::Receive)?;
+                match received {
+                    Some((request, call)) => Ok(Some(self.dispatch(request, call))),
+                    None => Ok(None),
+                }
+            }
+
+            #[allow(unused_variables)]
+            fn dispatch(
+                &self,
+                request: $request,
+                call: $crate::__private::poplar::channel::CallId,
+            ) -> $call {
+                match request {
+                    $(
+                        $request::$variant($($arg),+) => $call::$variant {
+                            $($arg,)+
+                            $(responder: $crate::Responder::<$reply, $ret>::new(
+                                self.channel.handle(),
+                                call,
+                                $reply::$variant,
+                            ),)?
+                        },
+                    )+
+                }
+            }
+        }
+
+        /// A server is a stream of the calls it receives, so it can be combined with other sources of events with
+        /// the combinators in `poplar::stream`. The stream never ends.
+        impl $crate::__private::poplar::stream::Stream for $server {
+            type Item = Result<$call, $crate::InterfaceError>;
+
+            fn poll_next(
+                self: ::core::pin::Pin<&mut Self>,
+                context: &mut ::core::task::Context,
+            ) -> ::core::task::Poll<Option<Self::Item>> {
+                loop {
+                    match self.try_next() {
+                        Ok(Some(call)) => return ::core::task::Poll::Ready(Some(Ok(call))),
+                        Ok(None) => (),
+                        Err(err) => return ::core::task::Poll::Ready(Some(Err(err))),
+                    }
+                    let wait_for_message = ::core::pin::pin!(self.channel.wait_for_message());
+                    if ::core::future::Future::poll(wait_for_message, context).is_pending() {
+                        return ::core::task::Poll::Pending;
+                    }
+                }
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __reply_type {
+    () => {
+        ()
+    };
+    ($ret:ty) => {
+        $ret
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __client_method {
+    {
+        $request:ident, $reply:ident,
+        $(#[doc = $doc:literal])*
+        $variant:ident => fn $method:ident($($arg:ident: $arg_ty:ty),+) -> $ret:ty
+    } => {
+        $(#[doc = $doc])*
+        pub async fn $method(&self, $($arg: $arg_ty),+) -> Result<$ret, $crate::InterfaceError> {
+            #[allow(unreachable_patterns)]
+            match self.channel.call(&$request::$variant($($arg),+)).await.map_err($crate::InterfaceError::Call)? {
+                $reply::$variant(value) => Ok(value),
+                _ => Err($crate::InterfaceError::UnexpectedReply),
+            }
+        }
+    };
+    {
+        $request:ident, $reply:ident,
+        $(#[doc = $doc:literal])*
+        $variant:ident => fn $method:ident($($arg:ident: $arg_ty:ty),+)
+    } => {
+        $(#[doc = $doc])*
+        pub fn $method(&self, $($arg: $arg_ty),+) -> Result<(), $crate::InterfaceError> {
+            self.channel.send(&$request::$variant($($arg),+)).map_err($crate::InterfaceError::Send)
+        }
+    };
+}
@@ -0,0 +1,75 @@
+use bit_field::BitField;
+use scroll::Pread;
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum DynamicTag {
+    /// Marks the end of the dynamic table.
+    Null,
+
+    /// The address of the table of "Rela"-type relocations.
+    Rela,
+
+    /// The size of the table of "Rela"-type relocations, in bytes.
+    RelaSize,
+
+    /// The size of one entry in the table of "Rela"-type relocations, in bytes.
+    RelaEntrySize,
+
+    /// The address of the table of "Rel"-type relocations.
+    Rel,
+
+    /// The address of the relocations associated with the procedure linkage table.
+    JmpRel,
+
+    /// The address of the table of relative relocations in the packed "Relr" format.
+    Relr,
+
+    /// Any other tag. These aren't needed to load an executable, so aren't broken out.
+    Other(u64),
+}
+
+/// An entry in the dynamic table, which is held in the `PT_DYNAMIC` segment.
+#[derive(Debug, Pread)]
+#[repr(C)]
+pub struct DynamicEntry {
+    pub tag: u64,
+    /// Either an integer or an address, depending on the tag.
+    pub value: u64,
+}
+
+impl DynamicEntry {
+    pub fn tag(&self) -> DynamicTag {
+        match self.tag {
+            0 => DynamicTag::Null,
+            7 => DynamicTag::Rela,
+            8 => DynamicTag::RelaSize,
+            9 => DynamicTag::RelaEntrySize,
+            17 => DynamicTag::Rel,
+            23 => DynamicTag::JmpRel,
+            36 => DynamicTag::Relr,
+            other => DynamicTag::Other(other),
+        }
+    }
+}
+
+/// A relocation with an explicit addend.
+#[derive(Debug, Pread)]
+#[repr(C)]
+pub struct Rela {
+    /// The address to apply the relocation at, before the ELF has been relocated.
+    pub offset: u64,
+    pub info: u64,
+    pub addend: i64,
+}
+
+impl Rela {
+    /// The type of the relocation. What each type means is specific to the processor.
+    pub fn relocation_type(&self) -> u32 {
+        self.info.get_bits(0..32) as u32
+    }
+
+    /// The index of the symbol the relocation refers to, or `0` if it doesn't refer to a symbol.
+    pub fn symbol_index(&self) -> u32 {
+        self.info.get_bits(32..64) as u32
+    }
+}
@@ -1,5 +1,6 @@
 #![no_std]
 
+pub mod dynamic;
 pub mod header;
 pub mod note;
 pub mod program;
@@ -7,8 +8,9 @@ pub mod section;
 pub mod symbol;
 
 use crate::{
+    dynamic::{DynamicEntry, DynamicTag, Rela},
     header::Header,
-    program::ProgramHeader,
+    program::{ProgramHeader, SegmentType},
     section::{SectionHeader, SectionType},
     symbol::Symbol,
 };
@@ -92,6 +94,53 @@ impl Elf<'_> {
         }
     }
 
+    /// Iterate the entries of the dynamic table, if the ELF has a `PT_DYNAMIC` segment. The `Null` entry that ends
+    /// the table is not included.
+    pub fn dynamic_entries(&self) -> impl Iterator<Item = DynamicEntry> + '_ {
+        let data = match self.segments().find(|segment| segment.segment_type() == SegmentType::Dynamic) {
+            Some(segment) => segment.data(self),
+            None => &[],
+        };
+        let entry_size = mem::size_of::<DynamicEntry>();
+
+        EntryIter::<DynamicEntry>::new(data, (data.len() / entry_size) as u64, entry_size as u64)
+            .take_while(|entry| entry.tag() != DynamicTag::Null)
+    }
+
+    /// Iterate the relocations in the table described by the `Rela`, `RelaSize`, and `RelaEntrySize` entries of
+    /// the dynamic table. If the ELF doesn't have this table, this iterates nothing. Returns `None` if the table
+    /// isn't contained within the file data of one of the ELF's loadable segments.
+    pub fn rela_entries(&self) -> Option<EntryIter<Rela>> {
+        let mut address = None;
+        let mut size = 0;
+        let mut entry_size = mem::size_of::<Rela>() as u64;
+        for entry in self.dynamic_entries() {
+            match entry.tag() {
+                DynamicTag::Rela => address = Some(entry.value),
+                DynamicTag::RelaSize => size = entry.value,
+                DynamicTag::RelaEntrySize => entry_size = entry.value,
+                _ => (),
+            }
+        }
+
+        let Some(address) = address else {
+            return Some(EntryIter::new(&[], 0, 0));
+        };
+        if entry_size == 0 {
+            return None;
+        }
+
+        let offset =
+            self.segments().filter(|segment| segment.segment_type() == SegmentType::Load).find_map(|segment| {
+                let contains = address >= segment.virtual_address
+                    && address.checked_add(size)? <= segment.virtual_address + segment.file_size;
+                contains.then(|| segment.offset + (address - segment.virtual_address))
+            })?;
+        let data = self.bytes.get(offset as usize..(offset + size) as usize)?;
+
+        Some(EntryIter::new(data, size / entry_size, entry_size))
+    }
+
     pub fn entry_point(&self) -> usize {
         self.header.entry_point as usize
     }
@@ -0,0 +1,194 @@
+//! Capture backtraces by walking the chain of frame records that code compiled with frame pointers leaves on the
+//! stack. Each frame record holds the caller's frame pointer and the return address of the call, so the call stack
+//! can be walked without any unwinding tables. Only the return addresses are captured - they're turned back into
+//! function names and source locations offline (with `cargo xtask symbolicate`), so the binaries don't need to
+//! carry their symbols around.
+
+use core::fmt;
+
+/// The maximum number of frames captured in a `Backtrace`. Frames beyond this are dropped.
+pub const MAX_FRAMES: usize = 32;
+
+/*
+ * The frame pointer points at the frame record on x86_64 and AArch64, and to just above it (at the stack pointer
+ * on entry to the function) on RISC-V. In all cases, the record holds the caller's frame pointer, and then the
+ * return address.
+ */
+#[cfg(target_arch = "riscv64")]
+const FRAME_RECORD_OFFSET: usize = 16;
+#[cfg(not(target_arch = "riscv64"))]
+const FRAME_RECORD_OFFSET: usize = 0;
+
+pub struct Backtrace {
+    frames: [usize; MAX_FRAMES],
+    num_frames: usize,
+}
+
+impl Backtrace {
+    /// Capture a backtrace of the calling function. `is_valid` is called with the address of each frame record
+    /// before it's read, and the walk stops at the first record it returns `false` for - it should check that the
+    /// address is in the right address space (e.g. that a kernel stack is in the kernel's half), so that a corrupt
+    /// frame pointer doesn't cause a fault while capturing the backtrace.
+    #[inline(always)]
+    pub fn capture<F>(is_valid: F) -> Backtrace
+    where
+        F: Fn(usize) -> bool,
+    {
+        let frame_pointer: usize;
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            core::arch::asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack, preserves_flags));
+        }
+        #[cfg(target_arch = "riscv64")]
+        unsafe {
+            core::arch::asm!("mv {}, s0", out(reg) frame_pointer, options(nomem, nostack, preserves_flags));
+        }
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            core::arch::asm!("mov {}, x29", out(reg) frame_pointer, options(nomem, nostack, preserves_flags));
+        }
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "riscv64", target_arch = "aarch64")))]
+        let frame_pointer = 0;
+
+        unsafe { Backtrace::from_frame_pointer(frame_pointer, is_valid) }
+    }
+
+    /// Walk the frame records starting at `frame_pointer`. This can be used to capture the backtrace of some other
+    /// context, such as one that has been interrupted.
+    ///
+    /// # Safety
+    /// `frame_pointer` must be zero, or point to a valid chain of frame records, which is either terminated by a
+    /// null frame pointer or return address, or by a frame record `is_valid` returns `false` for.
+    pub unsafe fn from_frame_pointer<F>(frame_pointer: usize, is_valid: F) -> Backtrace
+    where
+        F: Fn(usize) -> bool,
+    {
+        Backtrace::walk(frame_pointer, |address| {
+            if is_valid(address) && is_valid(address + core::mem::size_of::<usize>() - 1) {
+                Some(unsafe { *(address as *const usize) })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Walk the frame records starting at `frame_pointer`, reading each word of them with `read`. The walk stops
+    /// at the first word `read` returns `None` for. This can be used to walk stacks that can't be read directly,
+    /// such as those in another address space.
+    pub fn walk<F>(mut frame_pointer: usize, read: F) -> Backtrace
+    where
+        F: Fn(usize) -> Option<usize>,
+    {
+        let mut backtrace = Backtrace { frames: [0; MAX_FRAMES], num_frames: 0 };
+
+        while backtrace.num_frames < MAX_FRAMES {
+            if frame_pointer < FRAME_RECORD_OFFSET || frame_pointer % core::mem::align_of::<usize>() != 0 {
+                break;
+            }
+            let record = frame_pointer - FRAME_RECORD_OFFSET;
+            let (Some(next_frame_pointer), Some(return_address)) =
+                (read(record), read(record + core::mem::size_of::<usize>()))
+            else {
+                break;
+            };
+            if return_address == 0 {
+                break;
+            }
+            backtrace.frames[backtrace.num_frames] = return_address;
+            backtrace.num_frames += 1;
+
+            /*
+             * The stack grows downwards, so callers' frames are always at higher addresses. If the next frame
+             * pointer isn't, the chain is corrupt (or we've reached a frame that wasn't compiled with frame
+             * pointers), and following it could loop forever.
+             */
+            if next_frame_pointer <= frame_pointer {
+                break;
+            }
+            frame_pointer = next_frame_pointer;
+        }
+
+        backtrace
+    }
+
+    /// The return address of each frame, starting with the innermost. These point to the instruction after each
+    /// call, so should be adjusted back by one to find the line the call was made from.
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[0..self.num_frames]
+    }
+}
+
+/// Backtraces are printed in a format that `cargo xtask symbolicate` can pick out of a log.
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, address) in self.frames().iter().enumerate() {
+            writeln!(f, "  #{}: {:#x}", i, address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a chain of frame records in `stack`, with the given return addresses, and return the frame pointer of
+    /// the innermost frame.
+    fn build_chain(stack: &mut [usize], return_addresses: &[usize]) -> usize {
+        let base = stack.as_ptr() as usize;
+        let frame_pointer = |index: usize| base + index * 2 * core::mem::size_of::<usize>() + FRAME_RECORD_OFFSET;
+        for (i, return_address) in return_addresses.iter().enumerate() {
+            stack[i * 2] = if i + 1 == return_addresses.len() { 0 } else { frame_pointer(i + 1) };
+            stack[i * 2 + 1] = *return_address;
+        }
+        frame_pointer(0)
+    }
+
+    #[test]
+    fn walks_chain() {
+        let mut stack = [0; 16];
+        let frame_pointer = build_chain(&mut stack, &[0x1000, 0x2000, 0x3000]);
+        let backtrace = unsafe { Backtrace::from_frame_pointer(frame_pointer, |_| true) };
+        assert_eq!(backtrace.frames(), &[0x1000, 0x2000, 0x3000]);
+    }
+
+    #[test]
+    fn stops_at_invalid_record() {
+        let mut stack = [0; 16];
+        let frame_pointer = build_chain(&mut stack, &[0x1000, 0x2000, 0x3000]);
+        let limit = stack.as_ptr() as usize + 4 * core::mem::size_of::<usize>();
+        let backtrace = unsafe { Backtrace::from_frame_pointer(frame_pointer, |address| address < limit) };
+        assert_eq!(backtrace.frames(), &[0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn stops_at_loop() {
+        let mut stack = [0; 16];
+        let frame_pointer = build_chain(&mut stack, &[0x1000, 0x2000]);
+        // Point the second frame back at the first
+        stack[2] = frame_pointer;
+        let frame_pointer = stack.as_ptr() as usize + FRAME_RECORD_OFFSET;
+        let backtrace = unsafe { Backtrace::from_frame_pointer(frame_pointer, |_| true) };
+        assert_eq!(backtrace.frames(), &[0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn walks_with_reader() {
+        // Two frame records at made-up addresses, as if they were in another address space
+        let words = [0x2010 + FRAME_RECORD_OFFSET, 0x1000, 0, 0x3000];
+        let read = |address: usize| {
+            (0x2000..0x2020).contains(&address).then(|| words[(address - 0x2000) / core::mem::size_of::<usize>()])
+        };
+        let backtrace = Backtrace::walk(0x2000 + FRAME_RECORD_OFFSET, read);
+        assert_eq!(backtrace.frames(), &[0x1000, 0x3000]);
+    }
+
+    #[test]
+    fn display() {
+        let mut stack = [0; 16];
+        let frame_pointer = build_chain(&mut stack, &[0x1000, 0xffff_ffff_8000_1234]);
+        let backtrace = unsafe { Backtrace::from_frame_pointer(frame_pointer, |_| true) };
+        assert_eq!(format!("{}", backtrace), "Backtrace:\n  #0: 0x1000\n  #1: 0xffffffff80001234\n");
+    }
+}
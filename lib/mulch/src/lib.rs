@@ -25,6 +25,7 @@ cfg_if::cfg_if! {
     }
 }
 
+pub mod backtrace;
 mod binary_pretty_print;
 pub mod bitmap;
 mod init_guard;
@@ -0,0 +1,147 @@
+//! Block devices are provided to other tasks by their drivers as the `block.device` service. Clients send
+//! `BlockRequest`s over the service channel, and the driver replies to each request, in order, with a single
+//! `BlockResponse`. Devices are addressed in blocks of `block_size` bytes, which can be found with a
+//! `GetInfo` request.
+//!
+//! Data is transferred in the messages themselves, and so the amount of data that can be read or written by a
+//! single request is limited to `MAX_TRANSFER_SIZE`.
+// TODO: transfer data through shared memory objects instead, so larger requests can be made
+
+use crate::channel::{Channel, ChannelReceiveError, ChannelSendError};
+use alloc::vec::Vec;
+use ptah::{Deserialize, Serialize};
+
+pub const BLOCK_DEVICE_SERVICE: &str = "block.device";
+
+/// The maximum number of bytes that can be read or written by a single request. This is limited by the size of
+/// the buffer messages are received into.
+pub const MAX_TRANSFER_SIZE: usize = 1024;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum BlockRequest {
+    GetInfo,
+    /// Read `count` blocks, starting at `block`.
+    Read {
+        block: u64,
+        count: u32,
+    },
+    /// Write `data` to the device, starting at `block`. The length of `data` must be a multiple of the block
+    /// size.
+    Write {
+        block: u64,
+        data: Vec<u8>,
+    },
+    /// Make sure all previous writes have reached persistent storage.
+    Flush,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum BlockResponse {
+    Info(BlockDeviceInfo),
+    Data(Vec<u8>),
+    Done,
+    Error(BlockError),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct BlockDeviceInfo {
+    pub block_size: u32,
+    pub num_blocks: u64,
+    pub read_only: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum BlockError {
+    /// The request accesses blocks past the end of the device.
+    OutOfRange,
+    /// The request would transfer more than `MAX_TRANSFER_SIZE` bytes.
+    TooLarge,
+    /// The data to write is not a multiple of the block size.
+    InvalidLength,
+    ReadOnly,
+    /// The device failed to carry out the request.
+    DeviceError,
+}
+
+#[derive(Debug)]
+pub enum BlockClientError {
+    Block(BlockError),
+    Send(ChannelSendError),
+    Receive(ChannelReceiveError),
+    /// The driver replied with a response that doesn't make sense for the request.
+    UnexpectedResponse,
+}
+
+/// A client of a block device, for use by tasks that consume the `block.device` service (e.g. filesystem
+/// drivers). Requests are made synchronously.
+pub struct BlockDeviceClient {
+    channel: Channel<BlockRequest, BlockResponse>,
+    pub info: BlockDeviceInfo,
+}
+
+impl BlockDeviceClient {
+    /// Create a client from a channel to the driver, querying the device's info.
+    pub fn new(channel: Channel<BlockRequest, BlockResponse>) -> Result<BlockDeviceClient, BlockClientError> {
+        let info = match Self::request(&channel, &BlockRequest::GetInfo)? {
+            BlockResponse::Info(info) => info,
+            _ => return Err(BlockClientError::UnexpectedResponse),
+        };
+        Ok(BlockDeviceClient { channel, info })
+    }
+
+    /// Read `count` blocks, starting at `block`. Requests larger than `MAX_TRANSFER_SIZE` are split into
+    /// multiple requests.
+    pub fn read(&self, block: u64, count: u32) -> Result<Vec<u8>, BlockClientError> {
+        let mut data = Vec::with_capacity(count as usize * self.info.block_size as usize);
+        for (block, count) in self.chunks(block, count) {
+            match Self::request(&self.channel, &BlockRequest::Read { block, count })? {
+                BlockResponse::Data(chunk) => data.extend_from_slice(&chunk),
+                _ => return Err(BlockClientError::UnexpectedResponse),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Write `data`, starting at `block`. Requests larger than `MAX_TRANSFER_SIZE` are split into multiple
+    /// requests.
+    pub fn write(&self, block: u64, data: &[u8]) -> Result<(), BlockClientError> {
+        let block_size = self.info.block_size as usize;
+        if data.len() % block_size != 0 {
+            return Err(BlockClientError::Block(BlockError::InvalidLength));
+        }
+
+        for (chunk_block, count) in self.chunks(block, (data.len() / block_size) as u32) {
+            let start = (chunk_block - block) as usize * block_size;
+            let data = data[start..(start + count as usize * block_size)].to_vec();
+            match Self::request(&self.channel, &BlockRequest::Write { block: chunk_block, data })? {
+                BlockResponse::Done => (),
+                _ => return Err(BlockClientError::UnexpectedResponse),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), BlockClientError> {
+        match Self::request(&self.channel, &BlockRequest::Flush)? {
+            BlockResponse::Done => Ok(()),
+            _ => Err(BlockClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Split a range of blocks into runs that can each be transferred by a single request.
+    fn chunks(&self, block: u64, count: u32) -> impl Iterator<Item = (u64, u32)> {
+        let max_blocks = u32::max(1, (MAX_TRANSFER_SIZE / self.info.block_size as usize) as u32);
+        (0..count).step_by(max_blocks as usize).map(move |i| (block + i as u64, u32::min(max_blocks, count - i)))
+    }
+
+    fn request(
+        channel: &Channel<BlockRequest, BlockResponse>,
+        request: &BlockRequest,
+    ) -> Result<BlockResponse, BlockClientError> {
+        channel.send(request).map_err(BlockClientError::Send)?;
+        match channel.receive_blocking().map_err(BlockClientError::Receive)? {
+            BlockResponse::Error(err) => Err(BlockClientError::Block(err)),
+            response => Ok(response),
+        }
+    }
+}
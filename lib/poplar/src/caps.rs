@@ -0,0 +1,44 @@
+bitflags::bitflags! {
+    /// Capabilities describe what a task is allowed to do, and are checked by the kernel before it performs
+    /// privileged operations on a task's behalf. See the book for how they're encoded into task images.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Capabilities: u32 {
+        const GET_FRAMEBUFFER = 1 << 0;
+        const EARLY_LOGGING = 1 << 1;
+        const SERVICE_PROVIDER = 1 << 2;
+        /*
+         * Bit 3 was `SERVICE_USER`, which allowed a task to subscribe to any service. Which services a task can
+         * subscribe to is now decided by `service_host`.
+         */
+        const PCI_BUS_DRIVER = 1 << 4;
+        /// Allows a task to change the scheduling priority of itself, and of tasks it has handles to.
+        const SET_PRIORITY = 1 << 5;
+        /// Allows a task to allocate memory for devices to access through DMA, and to learn the addresses the
+        /// devices should use to access it.
+        const DMA = 1 << 6;
+        /// Allows a task to access devices that are described by the platform's firmware (e.g. in the device
+        /// tree), rather than found on a bus like PCI.
+        const PLATFORM_DEVICES = 1 << 7;
+        /// Allows a task to set the wall-clock time, and the platform's real-time clock.
+        const SET_TIME = 1 << 8;
+        /// Allows a task to create new tasks from ELF images with `task_create`.
+        const SPAWN_TASK = 1 << 9;
+        /// Allows a task to grant the capabilities it holds to other tasks, and to revoke capabilities from them,
+        /// while they're running.
+        const MANAGE_CAPABILITIES = 1 << 10;
+        /// Allows a task to read the kernel's log with `read_kernel_log`.
+        const READ_KERNEL_LOG = 1 << 11;
+        /// Allows a task to turn the kernel's tracepoints, auditing of tasks, and sampling profiler on and off, and
+        /// to read the trace and profile buffers.
+        const TRACE = 1 << 12;
+        /// Allows a task to count hardware events (like cycles and cache misses) with the CPU's performance
+        /// counters, for itself and for tasks it has handles to.
+        const PERF = 1 << 13;
+        /// Allows a task to power off and reset the system with `system_shutdown` and `system_reboot`.
+        const SYSTEM_POWER = 1 << 14;
+        /// Allows a task to create memory objects that are both writable and executable, for generating code at
+        /// runtime (e.g. in a JIT compiler). Mappings are still never writable and executable at the same time -
+        /// the task must switch between the two with `protect_memory_object`.
+        const WRITABLE_EXECUTABLE_MEMORY = 1 << 15;
+    }
+}
@@ -1,25 +1,115 @@
 use crate::{
-    syscall::{self, CreateChannelError, GetMessageError, SendMessageError, CHANNEL_MAX_NUM_HANDLES},
+    memory_object::{MappedMemoryObject, MemoryObject},
+    rt::CancellationToken,
+    stream::Stream,
+    syscall::{
+        self,
+        ChannelCallError,
+        CreateChannelError,
+        CreateMemoryObjectError,
+        GetMessageError,
+        HandleDuplicateWithRightsError,
+        MapMemoryObjectError,
+        MemoryObjectFlags,
+        PeekMessageError,
+        SendMessageError,
+        Signals,
+        CHANNEL_MAX_NUM_BYTES,
+        CHANNEL_MAX_NUM_HANDLES,
+    },
     Handle,
+    HandleRights,
 };
-use alloc::vec::Vec;
-use core::{future::Future, marker::PhantomData, mem, task::Poll};
-use ptah::{DeserializeOwned, Serialize};
+use alloc::{vec, vec::Vec};
+use core::{
+    future::Future,
+    marker::PhantomData,
+    mem,
+    pin::Pin,
+    slice,
+    task::{Context, Poll},
+    time::Duration,
+};
+use mulch::math::align_up;
+use ptah::{Deserialize, DeserializeOwned, Serialize};
 
-// TODO: we now have heap-allocated buffers for sending, but still have bounded receives based on
-// stack sizes. Is there any way of dealing with larger messages on receive?
-const BYTES_BUFFER_SIZE: usize = 2048;
+/*
+ * Messages that are too large to be sent inline are sent "out-of-line" - instead of being copied through the
+ * kernel, they're serialized straight into a `MemoryObject`, which is transferred with the message and mapped by
+ * the receiver to deserialize it. Out-of-line messages are sent with no bytes, and with the memory object's handle
+ * before the message's own handles. This can't be mistaken for an inline message, as each handle in a message
+ * takes up at least one byte.
+ *
+ * The memory object starts with the length of the message, as a little-endian `u64`, followed by the message.
+ */
+const MAX_INLINE_MESSAGE_SIZE: usize = CHANNEL_MAX_NUM_BYTES;
+const OUT_OF_LINE_HEADER_SIZE: usize = mem::size_of::<u64>();
 
 #[derive(Debug)]
 pub enum ChannelSendError {
     FailedToSerialize(ptah::ser::Error),
     SendError(SendMessageError),
+    /// A memory object to send a large message out-of-line in could not be created.
+    CreateBufferError(CreateMemoryObjectError),
+    MapBufferError(MapMemoryObjectError),
+    DuplicateBufferError(HandleDuplicateWithRightsError),
 }
 
 #[derive(Debug)]
 pub enum ChannelReceiveError {
     FailedToDeserialize(ptah::de::Error),
+    PeekError(PeekMessageError),
     ReceiveError(GetMessageError),
+    /// An out-of-line message's memory object couldn't be mapped, or doesn't contain a valid message.
+    InvalidOutOfLineMessage,
+    /// No message arrived before the timeout elapsed.
+    TimedOut,
+    /// The `CancellationToken` passed to a cancellable receive was cancelled before a message arrived.
+    Cancelled,
+}
+
+#[derive(Debug)]
+pub enum CallError {
+    /// The request couldn't be serialized, or a buffer to send it out-of-line in couldn't be created.
+    Send(ChannelSendError),
+    Call(ChannelCallError),
+    /// The reply couldn't be received, or isn't a valid message.
+    Receive(ChannelReceiveError),
+}
+
+/// Identifies a call received through a `Channel`, so that it can be replied to with `Channel::reply`. Messages
+/// that weren't sent by a call can be replied to in the same way - the reply is just sent as a normal message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CallId(u16);
+
+/// A call made by `Channel::call` that hasn't had its reply received yet. The call is cancelled if this is
+/// dropped.
+struct PendingCall {
+    channel: Handle,
+    txid: u16,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        let _ = syscall::channel_cancel_call(self.channel, self.txid);
+    }
+}
+
+/// A message that has been serialized, ready to be sent.
+struct SerializedMessage {
+    bytes: Vec<u8>,
+    handles: Vec<Handle>,
+    out_of_line: bool,
+}
+
+impl SerializedMessage {
+    /// Clean up a message that couldn't be sent. The memory object an out-of-line message is in is transferred
+    /// with it, so we're left holding it if it isn't sent.
+    fn discard(self) {
+        if self.out_of_line {
+            let _ = syscall::handle_close(self.handles[0]);
+        }
+    }
 }
 
 pub struct Channel<S, R>(Handle, PhantomData<(S, R)>)
@@ -43,131 +133,656 @@ where
         Ok((Self::new_from_handle(this_end), other_end))
     }
 
+    pub fn handle(&self) -> Handle {
+        self.0
+    }
+
     pub fn send(&self, message: &S) -> Result<(), ChannelSendError> {
-        let mut writer = ChannelWriter::new();
-        ptah::to_wire(message, &mut writer).map_err(|err| ChannelSendError::FailedToSerialize(err))?;
-        syscall::send_message(self.0, writer.bytes(), writer.handles())
-            .map_err(|err| ChannelSendError::SendError(err))
+        self.send_with_version(message, ptah::LATEST_VERSION)
+    }
+
+    /// Send `message`, serialized for `version` of its protocol. This is used to talk to a peer that was built
+    /// against an older version of the protocol, once the version it understands has been negotiated.
+    pub fn send_with_version(&self, message: &S, version: ptah::Version) -> Result<(), ChannelSendError> {
+        let message = serialize(message, version)?;
+        syscall::send_message(self.0, &message.bytes, &message.handles).map_err(|err| {
+            message.discard();
+            ChannelSendError::SendError(err)
+        })
+    }
+
+    /// Reply to a call received with one of the `receive_call` methods.
+    pub fn reply(&self, call: CallId, message: &S) -> Result<(), ChannelSendError> {
+        self.reply_with_version(call, message, ptah::LATEST_VERSION)
+    }
+
+    /// Like `reply`, but serializes `message` for `version` of its protocol, like `send_with_version`.
+    pub fn reply_with_version(
+        &self,
+        call: CallId,
+        message: &S,
+        version: ptah::Version,
+    ) -> Result<(), ChannelSendError> {
+        let message = serialize(message, version)?;
+        syscall::send_reply(self.0, call.0, &message.bytes, &message.handles).map_err(|err| {
+            message.discard();
+            ChannelSendError::SendError(err)
+        })
+    }
+
+    /// Send `request`, and wait for the reply to it, in a single system call. The other end must reply to the
+    /// request with `reply`. Other messages that arrive while we're waiting are left to be received as normal, so
+    /// this can be used on channels that are also used for other messages, and from multiple threads at once.
+    pub fn call_blocking(&self, request: &S) -> Result<R, CallError> {
+        let message = serialize(request, ptah::LATEST_VERSION).map_err(CallError::Send)?;
+        let mut reply_bytes = vec![0u8; CHANNEL_MAX_NUM_BYTES];
+        let mut reply_handles = vec![Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
+        let mut txid = 0;
+
+        match syscall::channel_call(
+            self.0,
+            &message.bytes,
+            &message.handles,
+            &mut reply_bytes,
+            &mut reply_handles,
+            None,
+            &mut txid,
+        ) {
+            Ok((bytes, handles)) => Self::deserialize(bytes, handles).map_err(CallError::Receive),
+            Err(err) => {
+                if txid == 0 {
+                    message.discard();
+                }
+                Err(CallError::Call(err))
+            }
+        }
+    }
+
+    /// Send `request`, and wait for the reply to it. The other end must reply to the request with `reply`. Like
+    /// `call_blocking`, other messages that arrive while we're waiting are left to be received as normal.
+    pub async fn call(&self, request: &S) -> Result<R, CallError> {
+        let message = serialize(request, ptah::LATEST_VERSION).map_err(CallError::Send)?;
+        let txid = match syscall::channel_call_no_wait(self.0, &message.bytes, &message.handles) {
+            Ok(txid) => txid,
+            Err(err) => {
+                message.discard();
+                return Err(CallError::Call(err));
+            }
+        };
+
+        /*
+         * If this future is dropped before the reply is received, the call is cancelled, so the kernel doesn't
+         * keep the reply forever.
+         */
+        let pending = PendingCall { channel: self.0, txid };
+        let result = core::future::poll_fn(|context| match self.try_receive_inner(txid) {
+            Ok(Some((reply, _))) => Poll::Ready(Ok(reply)),
+            Ok(None) => {
+                crate::rt::RUNTIME.get().reactor.lock().register(
+                    self.0,
+                    Signals::REPLIED,
+                    context.waker().clone(),
+                );
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(CallError::Receive(err))),
+        })
+        .await;
+
+        // Receiving the reply completes the call, so there's nothing to cancel
+        if result.is_ok() {
+            mem::forget(pending);
+        }
+        result
     }
 
     /// Receive a message from the channel, if there's one waiting. Returns `Ok(None)` if there are no pending
     /// messages to be received.
     pub fn try_receive(&self) -> Result<Option<R>, ChannelReceiveError> {
-        let mut byte_buffer = [0u8; BYTES_BUFFER_SIZE];
-        let mut handle_buffer = [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
-
-        match syscall::get_message(self.0, &mut byte_buffer, &mut handle_buffer) {
-            Ok((bytes, handles)) => {
-                // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
-                // around a `u32`). There might be a better way.
-                let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
-
-                let message: R = ptah::from_wire(bytes, ptah_handles)
-                    .map_err(|err| ChannelReceiveError::FailedToDeserialize(err))?;
-                Ok(Some(message))
+        Ok(self.try_receive_call()?.map(|(message, _)| message))
+    }
+
+    /// Like `try_receive`, but also returns the `CallId` needed to reply to the message.
+    pub fn try_receive_call(&self) -> Result<Option<(R, CallId)>, ChannelReceiveError> {
+        Ok(self.try_receive_inner(0)?.map(|(message, txid)| (message, CallId(txid))))
+    }
+
+    /// Receive the next message, or the reply to the call with the transaction ID `txid` if it isn't `0`.
+    /// Returns the message, and the transaction ID of the call it was sent by.
+    fn try_receive_inner(&self, txid: u16) -> Result<Option<(R, u16)>, ChannelReceiveError> {
+        loop {
+            /*
+             * Find out how large the message is, so we can allocate buffers for it. If another thread receives it
+             * first, the next message might not fit, in which case we just try again.
+             */
+            let info =
+                match if txid == 0 { syscall::peek_message(self.0) } else { syscall::peek_reply(self.0, txid) } {
+                    Ok(info) => info,
+                    Err(PeekMessageError::NoMessage) => return Ok(None),
+                    Err(err) => return Err(ChannelReceiveError::PeekError(err)),
+                };
+            let mut byte_buffer = vec![0u8; info.num_bytes];
+            let mut handle_buffer = vec![Handle::ZERO; info.num_handles];
+
+            let result = if txid == 0 {
+                syscall::get_message(self.0, &mut byte_buffer, &mut handle_buffer)
+            } else {
+                syscall::get_reply(self.0, txid, &mut byte_buffer, &mut handle_buffer)
+                    .map(|(bytes, handles)| (bytes, handles, txid))
+            };
+            match result {
+                Ok((bytes, handles, message_txid)) => {
+                    return Self::deserialize(bytes, handles).map(|message| Some((message, message_txid)));
+                }
+                Err(GetMessageError::NoMessage) => return Ok(None),
+                Err(GetMessageError::BytesBufferTooSmall | GetMessageError::HandlesBufferTooSmall) => continue,
+                Err(err) => return Err(ChannelReceiveError::ReceiveError(err)),
             }
-            Err(GetMessageError::NoMessage) => Ok(None),
-            Err(err) => Err(ChannelReceiveError::ReceiveError(err)),
         }
     }
 
+    fn deserialize(bytes: &[u8], handles: &[Handle]) -> Result<R, ChannelReceiveError> {
+        if bytes.is_empty() && !handles.is_empty() {
+            return Self::receive_out_of_line(handles);
+        }
+
+        // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
+        // around a `u32`). There might be a better way.
+        let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
+        ptah::from_wire(bytes, ptah_handles).map_err(|err| ChannelReceiveError::FailedToDeserialize(err))
+    }
+
+    /// Deserialize an out-of-line message. `handles` are the handles sent with the message, the first of which is
+    /// the memory object containing it.
+    fn receive_out_of_line(handles: &[Handle]) -> Result<R, ChannelReceiveError> {
+        let buffer_handle = handles[0];
+        let result = (|| {
+            let size = syscall::memory_object_size(buffer_handle)
+                .map_err(|_| ChannelReceiveError::InvalidOutOfLineMessage)?;
+            let mapped =
+                unsafe { MemoryObject::from_handle(buffer_handle, size, MemoryObjectFlags::empty()).map() }
+                    .map_err(|_| ChannelReceiveError::InvalidOutOfLineMessage)?;
+            let buffer = unsafe { slice::from_raw_parts(mapped.ptr(), size) };
+
+            let message = match message_bytes(buffer) {
+                Some(bytes) => {
+                    let ptah_handles: &[u32] = unsafe { mem::transmute(&handles[1..]) };
+                    ptah::from_wire(bytes, ptah_handles)
+                        .map_err(|err| ChannelReceiveError::FailedToDeserialize(err))
+                }
+                None => Err(ChannelReceiveError::InvalidOutOfLineMessage),
+            };
+
+            /*
+             * This can fail if the sender gave us a physically contiguous memory object, which we can't unmap. We
+             * just leave it mapped in that case.
+             */
+            let _ = unsafe { mapped.unmap() };
+            message
+        })();
+
+        let _ = syscall::handle_close(buffer_handle);
+        result
+    }
+
     /// Wait for a message to arrive via the channel.
     pub fn receive_blocking(&self) -> Result<R, ChannelReceiveError> {
+        Ok(self.receive_call_blocking()?.0)
+    }
+
+    /// Like `receive_blocking`, but also returns the `CallId` needed to reply to the message.
+    pub fn receive_call_blocking(&self) -> Result<(R, CallId), ChannelReceiveError> {
         loop {
-            let mut byte_buffer = [0u8; BYTES_BUFFER_SIZE];
-            let mut handle_buffer = [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
-
-            match syscall::get_message(self.0, &mut byte_buffer, &mut handle_buffer) {
-                Ok((bytes, handles)) => {
-                    // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
-                    // around a `u32`). There might be a better way.
-                    let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
-
-                    let message: R = ptah::from_wire(bytes, ptah_handles)
-                        .map_err(|err| ChannelReceiveError::FailedToDeserialize(err))?;
-                    return Ok(message);
-                }
-                Err(GetMessageError::NoMessage) => {
-                    crate::syscall::yield_to_kernel();
-                }
-                Err(err) => {
-                    return Err(ChannelReceiveError::ReceiveError(err));
-                }
+            if let Some(message) = self.try_receive_call()? {
+                return Ok(message);
+            }
+
+            if let Err(err) = syscall::wait_for_message(self.0, None) {
+                panic!("Error waiting for message: {:?}", err);
             }
         }
     }
 
     pub fn receive(&self) -> impl Future<Output = Result<R, ChannelReceiveError>> + '_ {
-        core::future::poll_fn(|context| {
-            let mut byte_buffer = [0u8; BYTES_BUFFER_SIZE];
-            let mut handle_buffer = [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
-
-            match syscall::get_message(self.0, &mut byte_buffer, &mut handle_buffer) {
-                Ok((bytes, handles)) => {
-                    // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
-                    // around a `u32`). There might be a better way.
-                    let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
-
-                    let message: R = ptah::from_wire(bytes, ptah_handles)
-                        .map_err(|err| ChannelReceiveError::FailedToDeserialize(err))?;
-                    Poll::Ready(Ok(message))
-                }
-                Err(GetMessageError::NoMessage) => {
-                    crate::rt::RUNTIME.get().reactor.lock().register(self.0, context.waker().clone());
-                    Poll::Pending
-                }
-                Err(err) => Poll::Ready(Err(ChannelReceiveError::ReceiveError(err))),
+        let receive_call = self.receive_call();
+        async move { Ok(receive_call.await?.0) }
+    }
+
+    /// Like `receive`, but also returns the `CallId` needed to reply to the message.
+    pub fn receive_call(&self) -> impl Future<Output = Result<(R, CallId), ChannelReceiveError>> + '_ {
+        core::future::poll_fn(|context| self.poll_receive_call(context))
+    }
+
+    /// Receive the next message, if there's one waiting, or register the task to be woken when one arrives.
+    fn poll_receive_call(&self, context: &mut Context) -> Poll<Result<(R, CallId), ChannelReceiveError>> {
+        match self.try_receive_call() {
+            Ok(Some(message)) => Poll::Ready(Ok(message)),
+            Ok(None) => {
+                crate::rt::RUNTIME.get().reactor.lock().register(
+                    self.0,
+                    Signals::READABLE,
+                    context.waker().clone(),
+                );
+                Poll::Pending
             }
-        })
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    /// Wait for a message to arrive via the channel, giving up if one hasn't arrived after
+    /// `timeout`.
+    pub async fn receive_with_timeout(&self, timeout: Duration) -> Result<R, ChannelReceiveError> {
+        crate::rt::time::timeout(timeout, self.receive()).await.unwrap_or(Err(ChannelReceiveError::TimedOut))
+    }
+
+    /// Wait for a message to arrive via the channel, giving up with `ChannelReceiveError::Cancelled` if `token` is
+    /// cancelled first. Tasks serving a channel can use this to stop cleanly (e.g. when the runtime shuts down).
+    pub async fn receive_cancellable(&self, token: &CancellationToken) -> Result<R, ChannelReceiveError> {
+        Ok(self.receive_call_cancellable(token).await?.0)
+    }
+
+    /// Like `receive_cancellable`, but also returns the `CallId` needed to reply to the message.
+    pub async fn receive_call_cancellable(
+        &self,
+        token: &CancellationToken,
+    ) -> Result<(R, CallId), ChannelReceiveError> {
+        token.run_until_cancelled(self.receive_call()).await.unwrap_or(Err(ChannelReceiveError::Cancelled))
+    }
+}
+
+/*
+ * A `Channel` is a stream of the messages it receives. Channels are often shared between tasks (e.g. in an `Arc`),
+ * so references to them are streams too. The kernel doesn't tell us when the other end of a channel is closed, so
+ * these streams never end.
+ */
+impl<S, R> Stream for Channel<S, R>
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    type Item = Result<R, ChannelReceiveError>;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>> {
+        self.poll_receive_call(context).map(|result| Some(result.map(|(message, _)| message)))
+    }
+}
+
+impl<S, R> Stream for &Channel<S, R>
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    type Item = Result<R, ChannelReceiveError>;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>> {
+        self.poll_receive_call(context).map(|result| Some(result.map(|(message, _)| message)))
     }
 }
 
-struct ChannelWriter {
-    byte_buffer: Vec<u8>,
-    handle_buffer: [Handle; CHANNEL_MAX_NUM_HANDLES],
-    num_handles: u8,
+/// A buffer that messages are received into by a `ChannelRef`. Messages received into it can borrow from it, and
+/// it's reused for each message, so receiving a message doesn't need to allocate.
+pub struct ReceiveBuffer {
+    bytes: Vec<u8>,
+    handles: Vec<Handle>,
+    num_bytes: usize,
+    num_handles: usize,
+    /// The memory object the last message was sent in, if it was sent out-of-line. It's kept mapped until the
+    /// next message is received into the buffer, as the message can borrow from it.
+    out_of_line: Option<MappedMemoryObject>,
 }
 
-impl ChannelWriter {
-    pub fn new() -> ChannelWriter {
-        ChannelWriter {
-            byte_buffer: Vec::new(),
-            handle_buffer: [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES],
+impl ReceiveBuffer {
+    pub fn new() -> ReceiveBuffer {
+        ReceiveBuffer {
+            bytes: vec![0u8; CHANNEL_MAX_NUM_BYTES],
+            handles: vec![Handle::ZERO; CHANNEL_MAX_NUM_HANDLES],
+            num_bytes: 0,
             num_handles: 0,
+            out_of_line: None,
+        }
+    }
+
+    /// Deserialize the message that was last received into the buffer.
+    fn message<'b, R>(&'b self) -> Result<R, ChannelReceiveError>
+    where
+        R: Deserialize<'b>,
+    {
+        let (bytes, handles) = match self.out_of_line {
+            Some(ref mapped) => {
+                let size = mapped.inner.size;
+                let buffer = unsafe { slice::from_raw_parts(mapped.ptr(), size) };
+                let bytes = message_bytes(buffer).ok_or(ChannelReceiveError::InvalidOutOfLineMessage)?;
+                (bytes, &self.handles[1..self.num_handles])
+            }
+            None => (&self.bytes[0..self.num_bytes], &self.handles[0..self.num_handles]),
+        };
+
+        let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
+        ptah::from_wire(bytes, ptah_handles).map_err(|err| ChannelReceiveError::FailedToDeserialize(err))
+    }
+
+    /// Unmap and close the memory object the last message was sent in, if it was sent out-of-line.
+    fn release_out_of_line(&mut self) {
+        if let Some(mapped) = self.out_of_line.take() {
+            let handle = mapped.inner.handle;
+            // If the sender gave us a physically contiguous memory object, we can't unmap it, and so just leave it
+            let _ = unsafe { mapped.unmap() };
+            let _ = syscall::handle_close(handle);
+        }
+    }
+}
+
+impl Default for ReceiveBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ReceiveBuffer {
+    fn drop(&mut self) {
+        self.release_out_of_line();
+    }
+}
+
+/// A `ChannelRef` is like a `Channel`, but messages are received into a `ReceiveBuffer` supplied by the caller, and
+/// can borrow from it (e.g. with `&str` and `&[u8]` fields) instead of being copied into allocations of their own.
+/// Messages that are sent out-of-line are borrowed straight out of the memory object they were sent in. The type of
+/// message to receive is given with each call to receive, as it usually borrows from the buffer passed to it.
+///
+/// A message received into a buffer must be dropped before the buffer can be used to receive the next one.
+pub struct ChannelRef<S>(Handle, PhantomData<S>)
+where
+    S: Serialize;
+
+impl<S> ChannelRef<S>
+where
+    S: Serialize,
+{
+    pub fn new_from_handle(handle: Handle) -> ChannelRef<S> {
+        ChannelRef(handle, PhantomData)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.0
+    }
+
+    pub fn send(&self, message: &S) -> Result<(), ChannelSendError> {
+        let message = serialize(message, ptah::LATEST_VERSION)?;
+        syscall::send_message(self.0, &message.bytes, &message.handles).map_err(|err| {
+            message.discard();
+            ChannelSendError::SendError(err)
+        })
+    }
+
+    /// Reply to a call received with one of the `receive_call` methods.
+    pub fn reply(&self, call: CallId, message: &S) -> Result<(), ChannelSendError> {
+        let message = serialize(message, ptah::LATEST_VERSION)?;
+        syscall::send_reply(self.0, call.0, &message.bytes, &message.handles).map_err(|err| {
+            message.discard();
+            ChannelSendError::SendError(err)
+        })
+    }
+
+    /// Receive a message into `buffer`, if there's one waiting.
+    pub fn try_receive<'b, R>(&self, buffer: &'b mut ReceiveBuffer) -> Result<Option<R>, ChannelReceiveError>
+    where
+        R: Deserialize<'b>,
+    {
+        Ok(self.try_receive_call(buffer)?.map(|(message, _)| message))
+    }
+
+    /// Like `try_receive`, but also returns the `CallId` needed to reply to the message.
+    pub fn try_receive_call<'b, R>(
+        &self,
+        buffer: &'b mut ReceiveBuffer,
+    ) -> Result<Option<(R, CallId)>, ChannelReceiveError>
+    where
+        R: Deserialize<'b>,
+    {
+        match self.receive_into(buffer)? {
+            Some(txid) => Ok(Some((buffer.message()?, CallId(txid)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Wait for a message to arrive via the channel, and receive it into `buffer`.
+    pub fn receive_blocking<'b, R>(&self, buffer: &'b mut ReceiveBuffer) -> Result<R, ChannelReceiveError>
+    where
+        R: Deserialize<'b>,
+    {
+        Ok(self.receive_call_blocking(buffer)?.0)
+    }
+
+    /// Like `receive_blocking`, but also returns the `CallId` needed to reply to the message.
+    pub fn receive_call_blocking<'b, R>(
+        &self,
+        buffer: &'b mut ReceiveBuffer,
+    ) -> Result<(R, CallId), ChannelReceiveError>
+    where
+        R: Deserialize<'b>,
+    {
+        let txid = loop {
+            if let Some(txid) = self.receive_into(buffer)? {
+                break txid;
+            }
+
+            if let Err(err) = syscall::wait_for_message(self.0, None) {
+                panic!("Error waiting for message: {:?}", err);
+            }
+        };
+        Ok((buffer.message()?, CallId(txid)))
+    }
+
+    pub async fn receive<'b, R>(&self, buffer: &'b mut ReceiveBuffer) -> Result<R, ChannelReceiveError>
+    where
+        R: Deserialize<'b>,
+    {
+        Ok(self.receive_call(buffer).await?.0)
+    }
+
+    /// Like `receive`, but also returns the `CallId` needed to reply to the message.
+    pub async fn receive_call<'b, R>(
+        &self,
+        buffer: &'b mut ReceiveBuffer,
+    ) -> Result<(R, CallId), ChannelReceiveError>
+    where
+        R: Deserialize<'b>,
+    {
+        let txid = core::future::poll_fn(|context| match self.receive_into(buffer) {
+            Ok(Some(txid)) => Poll::Ready(Ok(txid)),
+            Ok(None) => {
+                crate::rt::RUNTIME.get().reactor.lock().register(
+                    self.0,
+                    Signals::READABLE,
+                    context.waker().clone(),
+                );
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        })
+        .await?;
+        Ok((buffer.message()?, CallId(txid)))
+    }
+
+    /// Wait until there's a message waiting to be received. This is useful when the buffer to receive the message
+    /// into can't be borrowed while waiting (e.g. because it's shared behind a lock).
+    pub fn wait_for_message(&self) -> impl Future<Output = ()> + '_ {
+        core::future::poll_fn(|context| match syscall::peek_message(self.0) {
+            Err(PeekMessageError::NoMessage) => {
+                crate::rt::RUNTIME.get().reactor.lock().register(
+                    self.0,
+                    Signals::READABLE,
+                    context.waker().clone(),
+                );
+                Poll::Pending
+            }
+            // If there's a message, or we can't tell, let the caller try to receive it
+            _ => Poll::Ready(()),
+        })
+    }
+
+    /// Receive the next message into `buffer`, if there's one waiting, without deserializing it. Returns the
+    /// transaction ID of the call the message was sent by.
+    fn receive_into(&self, buffer: &mut ReceiveBuffer) -> Result<Option<u16>, ChannelReceiveError> {
+        buffer.release_out_of_line();
+
+        /*
+         * The buffer can hold the largest message that can be sent inline, so we don't need to peek at the size of
+         * the message first.
+         */
+        let (num_bytes, num_handles, txid) =
+            match syscall::get_message(self.0, &mut buffer.bytes, &mut buffer.handles) {
+                Ok((bytes, handles, txid)) => (bytes.len(), handles.len(), txid),
+                Err(GetMessageError::NoMessage) => return Ok(None),
+                Err(err) => return Err(ChannelReceiveError::ReceiveError(err)),
+            };
+        buffer.num_bytes = num_bytes;
+        buffer.num_handles = num_handles;
+
+        if num_bytes == 0 && num_handles != 0 {
+            let buffer_handle = buffer.handles[0];
+            let mapped = syscall::memory_object_size(buffer_handle).ok().and_then(|size| {
+                unsafe { MemoryObject::from_handle(buffer_handle, size, MemoryObjectFlags::empty()).map() }.ok()
+            });
+            match mapped {
+                Some(mapped) => buffer.out_of_line = Some(mapped),
+                None => {
+                    let _ = syscall::handle_close(buffer_handle);
+                    return Err(ChannelReceiveError::InvalidOutOfLineMessage);
+                }
+            }
         }
+
+        Ok(Some(txid))
     }
+}
 
-    pub fn bytes(&self) -> &[u8] {
-        &self.byte_buffer
+fn serialize<T>(message: &T, version: ptah::Version) -> Result<SerializedMessage, ChannelSendError>
+where
+    T: Serialize,
+{
+    let size = ptah::serialized_size_with_version(message, version)
+        .map_err(|err| ChannelSendError::FailedToSerialize(err))?;
+    if size > MAX_INLINE_MESSAGE_SIZE {
+        return serialize_out_of_line(message, size, version);
     }
 
-    pub fn handles(&self) -> &[Handle] {
-        &self.handle_buffer[0..(self.num_handles as usize)]
+    let mut writer = ChannelWriter::new(MessageBytes::Inline(Vec::with_capacity(size)));
+    ptah::to_wire_with_version(message, &mut writer, version)
+        .map_err(|err| ChannelSendError::FailedToSerialize(err))?;
+    let MessageBytes::Inline(bytes) = writer.bytes else { unreachable!() };
+    Ok(SerializedMessage { bytes, handles: writer.handle_buffer, out_of_line: false })
+}
+
+fn serialize_out_of_line<T>(
+    message: &T,
+    size: usize,
+    version: ptah::Version,
+) -> Result<SerializedMessage, ChannelSendError>
+where
+    T: Serialize,
+{
+    let buffer_size = align_up(OUT_OF_LINE_HEADER_SIZE + size, 0x1000);
+    let memory_object = MemoryObject::create_lazy(buffer_size, MemoryObjectFlags::WRITABLE)
+        .map_err(|err| ChannelSendError::CreateBufferError(err))?;
+    let buffer_handle = memory_object.handle;
+    let mapped = match unsafe { memory_object.map() } {
+        Ok(mapped) => mapped,
+        Err(err) => {
+            let _ = syscall::handle_close(buffer_handle);
+            return Err(ChannelSendError::MapBufferError(err));
+        }
+    };
+
+    let buffer = unsafe { slice::from_raw_parts_mut(mapped.mapped_at as *mut u8, buffer_size) };
+    buffer[0..OUT_OF_LINE_HEADER_SIZE].copy_from_slice(&(size as u64).to_le_bytes());
+    let mut writer =
+        ChannelWriter::new(MessageBytes::OutOfLine { buffer: &mut buffer[OUT_OF_LINE_HEADER_SIZE..], cursor: 0 });
+    let result = ptah::to_wire_with_version(message, &mut writer, version);
+    let handles = writer.handle_buffer;
+    // The memory object isn't physically contiguous, so this can't fail
+    unsafe { mapped.unmap() }.unwrap();
+
+    /*
+     * The receiver is given a read-only handle to the buffer, and we close our own handle, so the message
+     * can't be changed once it's been sent.
+     */
+    let read_only_handle = syscall::handle_duplicate_with_rights(
+        buffer_handle,
+        HandleRights::READ | HandleRights::MAP | HandleRights::TRANSFER,
+    );
+    let _ = syscall::handle_close(buffer_handle);
+    result.map_err(|err| ChannelSendError::FailedToSerialize(err))?;
+    let read_only_handle = read_only_handle.map_err(|err| ChannelSendError::DuplicateBufferError(err))?;
+
+    let mut message_handles = Vec::with_capacity(handles.len() + 1);
+    message_handles.push(read_only_handle);
+    message_handles.extend_from_slice(&handles);
+    Ok(SerializedMessage { bytes: Vec::new(), handles: message_handles, out_of_line: true })
+}
+
+/// Get the bytes of the message in the buffer of an out-of-line message, if the buffer is valid.
+fn message_bytes(buffer: &[u8]) -> Option<&[u8]> {
+    let header = buffer.get(0..OUT_OF_LINE_HEADER_SIZE)?;
+    let length = u64::from_le_bytes(header.try_into().unwrap()) as usize;
+    buffer.get(OUT_OF_LINE_HEADER_SIZE..)?.get(..length)
+}
+
+struct ChannelWriter<'a> {
+    bytes: MessageBytes<'a>,
+    handle_buffer: Vec<Handle>,
+}
+
+/// Where the bytes of a message are written. Inline messages are built up in a `Vec` to be copied through the
+/// kernel, while out-of-line messages are written straight into the memory object they're sent in.
+enum MessageBytes<'a> {
+    Inline(Vec<u8>),
+    OutOfLine { buffer: &'a mut [u8], cursor: usize },
+}
+
+impl<'a> ChannelWriter<'a> {
+    pub fn new(bytes: MessageBytes<'a>) -> ChannelWriter<'a> {
+        ChannelWriter { bytes, handle_buffer: Vec::new() }
     }
 }
 
-impl<'a> ptah::Writer for &'a mut ChannelWriter {
+impl<'a, 'b> ptah::Writer for &'b mut ChannelWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> ptah::ser::Result<()> {
-        self.byte_buffer.extend_from_slice(buf);
+        match self.bytes {
+            MessageBytes::Inline(ref mut bytes) => bytes.extend_from_slice(buf),
+            MessageBytes::OutOfLine { ref mut buffer, ref mut cursor } => {
+                let Some(dest) = buffer.get_mut(*cursor..(*cursor + buf.len())) else {
+                    return Err(ptah::ser::Error::WriterFullOfBytes);
+                };
+                dest.copy_from_slice(buf);
+                *cursor += buf.len();
+            }
+        }
         Ok(())
     }
 
     fn push_handle(&mut self, handle: ptah::Handle) -> ptah::ser::Result<ptah::HandleSlot> {
         /*
-         * Check if we're full of handles yet.
+         * Check if we're full of handles yet. Out-of-line messages also need a slot for the handle to their
+         * memory object.
          */
-        if (self.num_handles as usize + 1) > CHANNEL_MAX_NUM_HANDLES {
+        let max_handles = match self.bytes {
+            MessageBytes::Inline(_) => CHANNEL_MAX_NUM_HANDLES,
+            MessageBytes::OutOfLine { .. } => CHANNEL_MAX_NUM_HANDLES - 1,
+        };
+        if self.handle_buffer.len() >= max_handles {
             return Err(ptah::ser::Error::WriterFullOfHandles);
         }
 
-        self.handle_buffer[self.num_handles as usize] = Handle(handle);
-
-        let slot = ptah::make_handle_slot(self.num_handles);
-        self.num_handles += 1;
+        let slot = ptah::make_handle_slot(self.handle_buffer.len() as u8);
+        self.handle_buffer.push(Handle(handle));
         Ok(slot)
     }
 
     fn bytes_written(&self) -> usize {
-        self.byte_buffer.len()
+        match self.bytes {
+            MessageBytes::Inline(ref bytes) => bytes.len(),
+            MessageBytes::OutOfLine { cursor, .. } => cursor,
+        }
     }
 }
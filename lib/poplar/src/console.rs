@@ -0,0 +1,50 @@
+//! Consoles (such as `fb_console`) provide a stdin/stdout-style interface to other tasks through the `console`
+//! service. Clients send `ConsoleRequest`s over the service channel to write text to the screen, and can ask to
+//! be sent the characters typed into the console as `ConsoleEvent`s.
+
+use crate::channel::{Channel, ChannelSendError};
+use alloc::string::{String, ToString};
+use ptah::{Deserialize, Serialize};
+
+pub const CONSOLE_SERVICE: &str = "console";
+
+/// The maximum number of bytes of text that should be sent in a single `ConsoleRequest::Write`. This keeps each
+/// message well within the size of a channel message - longer text should be split across multiple requests
+/// (`write` does this for you).
+pub const MAX_WRITE_LENGTH: usize = 1024;
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ConsoleRequest {
+    /// Write text to the console. The text can contain ANSI escape sequences to move the cursor, change colors,
+    /// and so on.
+    Write(String),
+    /// Start receiving the characters typed into the console. Only one client receives input at a time - the
+    /// client that most recently attached. When it detaches, input is sent to the client that was attached
+    /// before it.
+    AttachInput,
+    DetachInput,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ConsoleEvent {
+    /// Characters typed into the console. Input is not buffered or echoed by the console, so this is usually a
+    /// single key press. Backspace is sent as an ASCII `DEL` (`\x7f`), and keys without a character (such as the
+    /// arrow keys) are sent as the ANSI escape sequences a VT100 would send (e.g. `ESC [ A` for up).
+    Input(String),
+}
+
+/// Write `text` to the console, splitting it over as many `ConsoleRequest::Write`s as needed.
+pub fn write(channel: &Channel<ConsoleRequest, ConsoleEvent>, mut text: &str) -> Result<(), ChannelSendError> {
+    while !text.is_empty() {
+        let mut split = usize::min(text.len(), MAX_WRITE_LENGTH);
+        while !text.is_char_boundary(split) {
+            split -= 1;
+        }
+
+        let (chunk, rest) = text.split_at(split);
+        channel.send(&ConsoleRequest::Write(chunk.to_string()))?;
+        text = rest;
+    }
+
+    Ok(())
+}
@@ -1,2 +1,3 @@
 pub mod dma;
 pub mod pci;
+pub mod platform;
@@ -1,6 +1,18 @@
-use crate::{syscall::pci::PciGetInfoError, Handle};
+use crate::{
+    syscall::pci::{
+        DmaDomainAttachError,
+        DmaDomainDetachError,
+        PciConfigReadError,
+        PciConfigWriteError,
+        PciGetInfoError,
+    },
+    Handle,
+};
 use pci_types::{BaseClass, DeviceId, DeviceRevision, Interface, PciAddress, SubClass, VendorId};
 
+/// The most interrupts the kernel will configure for a single PCI device.
+pub const MAX_INTERRUPTS: usize = 16;
+
 #[derive(Debug, Default)]
 #[repr(C)]
 pub struct PciDeviceInfo {
@@ -20,8 +32,10 @@ pub struct PciDeviceInfo {
     /// device.
     pub interface: Interface,
     pub bars: [Option<Bar>; 6],
-    /// A handle to an `Event` that is signalled when this PCI device issues an interrupt.
-    pub interrupt: Option<Handle>,
+    /// Handles to `Event`s that are signalled when this PCI device issues an interrupt. Devices that use MSI-X
+    /// can have an `Event` for each entry of their MSI-X table (in order, up to `MAX_INTERRUPTS`). Other devices
+    /// have at most one, in the first element.
+    pub interrupts: [Option<Handle>; MAX_INTERRUPTS],
 }
 
 #[derive(Debug)]
@@ -64,3 +78,37 @@ pub fn pci_get_info_vec() -> Result<alloc::vec::Vec<PciDeviceInfo>, PciGetInfoEr
 
     Ok(descriptors)
 }
+
+/// Encode a PCI address as `segment << 16 | bus << 8 | device << 3 | function`, which is how the kernel expects
+/// addresses to be passed to system calls.
+pub fn address_to_raw(address: PciAddress) -> u32 {
+    (address.segment() as u32) << 16
+        | (address.bus() as u32) << 8
+        | (address.device() as u32) << 3
+        | address.function() as u32
+}
+
+pub fn address_from_raw(raw: u32) -> PciAddress {
+    PciAddress::new((raw >> 16) as u16, (raw >> 8) as u8, ((raw >> 3) & 0x1f) as u8, (raw & 0x7) as u8)
+}
+
+/// Attach a PCI device to a `DmaDomain`. After this, the device can only access memory that has been allocated
+/// in the domain. This requires the `PCI_BUS_DRIVER` capability.
+pub fn dma_domain_attach(domain: Handle, device: PciAddress) -> Result<(), DmaDomainAttachError> {
+    crate::syscall::pci::dma_domain_attach(domain, address_to_raw(device))
+}
+
+/// Detach a PCI device from a `DmaDomain`. This requires the `PCI_BUS_DRIVER` capability.
+pub fn dma_domain_detach(domain: Handle, device: PciAddress) -> Result<(), DmaDomainDetachError> {
+    crate::syscall::pci::dma_domain_detach(domain, address_to_raw(device))
+}
+
+/// Read a dword from the configuration space of a PCI function. This requires the `PCI_BUS_DRIVER` capability.
+pub fn config_read(device: PciAddress, offset: u16) -> Result<u32, PciConfigReadError> {
+    crate::syscall::pci::pci_config_read(address_to_raw(device), offset)
+}
+
+/// Write a dword to the configuration space of a PCI function. This requires the `PCI_BUS_DRIVER` capability.
+pub fn config_write(device: PciAddress, offset: u16, value: u32) -> Result<(), PciConfigWriteError> {
+    crate::syscall::pci::pci_config_write(address_to_raw(device), offset, value)
+}
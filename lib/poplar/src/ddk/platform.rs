@@ -0,0 +1,85 @@
+//! Platform devices are devices that are described by the platform's firmware (e.g. by nodes of the device tree),
+//! rather than found by enumerating a bus like PCI. The kernel provides their memory-mapped regions, I/O port
+//! ranges, and interrupts, and they're generally passed on to their drivers by the Platform Bus.
+
+use crate::{syscall::GetPlatformDevicesError, Handle};
+
+/// The most memory-mapped regions the kernel will provide for a single platform device.
+pub const MAX_REGIONS: usize = 4;
+/// The most I/O port ranges the kernel will describe for a single platform device.
+pub const MAX_IO_PORT_RANGES: usize = 4;
+pub const MAX_NAME_LENGTH: usize = 64;
+pub const MAX_COMPATIBLE_LENGTH: usize = 128;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct PlatformDeviceInfo {
+    /// The name of the device, padded with zeros. For devices in the device tree, this is the path of the node,
+    /// and for devices in the ACPI namespace, it's the device's absolute path in the namespace.
+    pub name: [u8; MAX_NAME_LENGTH],
+    /// The devices the device is compatible with, most specific first. Each is terminated by a zero, as in the
+    /// device tree's `compatible` property.
+    pub compatible: [u8; MAX_COMPATIBLE_LENGTH],
+    pub regions: [Option<Region>; MAX_REGIONS],
+    pub io_ports: [Option<IoPortRange>; MAX_IO_PORT_RANGES],
+    /// A handle to an `Event` that is signalled when the device raises an interrupt. The interrupt is masked until
+    /// it is acknowledged with `Event::ack_interrupt`.
+    pub interrupt: Option<Handle>,
+}
+
+/// A memory-mapped region of a device. Memory objects have to start on a page boundary, so the region starts
+/// `offset` bytes into the memory object.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Region {
+    pub memory_object: Handle,
+    pub size: usize,
+    pub offset: usize,
+}
+
+/// A range of I/O ports used by a device. These only exist on x86_64.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct IoPortRange {
+    pub base: u16,
+    pub length: u16,
+}
+
+impl PlatformDeviceInfo {
+    pub fn name(&self) -> &str {
+        let length = self.name.iter().position(|&b| b == 0).unwrap_or(MAX_NAME_LENGTH);
+        core::str::from_utf8(&self.name[0..length]).unwrap_or("")
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &str> {
+        self.compatible
+            .split(|&b| b == 0)
+            .filter(|compatible| !compatible.is_empty())
+            .filter_map(|compatible| core::str::from_utf8(compatible).ok())
+    }
+}
+
+#[cfg(feature = "can_alloc")]
+pub fn get_platform_devices_vec() -> Result<alloc::vec::Vec<PlatformDeviceInfo>, GetPlatformDevicesError> {
+    use alloc::vec::Vec;
+
+    // Make an initial call to find out how many descriptors there are
+    let num_descriptors = match crate::syscall::get_platform_devices(0x0 as *mut u8, 0) {
+        Ok(_) => return Ok(Vec::new()),
+        Err(GetPlatformDevicesError::BufferNotLargeEnough(0)) => return Ok(Vec::new()),
+        Err(GetPlatformDevicesError::BufferNotLargeEnough(num_descriptors)) => num_descriptors as usize,
+        Err(err) => return Err(err),
+    };
+
+    // Then actually fetch the data
+    let mut descriptors: Vec<PlatformDeviceInfo> = Vec::with_capacity(num_descriptors);
+    assert_eq!(
+        crate::syscall::get_platform_devices(descriptors.as_mut_ptr() as *mut u8, num_descriptors)?,
+        num_descriptors
+    );
+    unsafe {
+        descriptors.set_len(num_descriptors);
+    }
+
+    Ok(descriptors)
+}
@@ -1,8 +1,8 @@
 use crate::{
-    syscall::{self, WaitForEventError},
+    syscall::{self, CreateEventError, EventFlags, Signals, WaitForEventError},
     Handle,
 };
-use core::{future::Future, task::Poll};
+use core::{future::Future, task::Poll, time::Duration};
 
 pub struct Event(Handle);
 
@@ -11,17 +11,42 @@ impl Event {
         Event(handle)
     }
 
+    /// Create a new `Event`. Other tasks can be given a handle to it (e.g. with only the `SIGNAL` right) to notify
+    /// this one. If `EventFlags::COUNTING` is passed, each wait for the event consumes one signal, instead of every
+    /// signal since the last wait being coalesced into one.
+    pub fn create(flags: EventFlags) -> Result<Event, CreateEventError> {
+        Ok(Event(syscall::create_event(flags)?))
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.0
+    }
+
+    /// Signal the event, waking any task waiting for it.
+    pub fn signal(&self) {
+        syscall::signal_event(self.0).unwrap();
+    }
+
+    /// Clear the event, discarding any signals that haven't been waited for yet.
+    pub fn clear(&self) {
+        syscall::clear_event(self.0).unwrap();
+    }
+
     pub fn wait_for_event(&self) -> impl Future<Output = ()> + '_ {
         core::future::poll_fn(|context| {
             /*
              * We call `wait_for_event`, but don't allow it to block. This effectively just clears
              * the event if there is one pending to be handled - the async side handles waiting for
-             * events through `poll_interest` via the reactor.
+             * events through `object_wait_many` via the reactor.
              */
             match syscall::wait_for_event(self.0, false) {
                 Ok(()) => Poll::Ready(()),
                 Err(WaitForEventError::NoEvent) => {
-                    crate::rt::RUNTIME.get().reactor.lock().register(self.0, context.waker().clone());
+                    crate::rt::RUNTIME.get().reactor.lock().register(
+                        self.0,
+                        Signals::SIGNALLED,
+                        context.waker().clone(),
+                    );
                     Poll::Pending
                 }
                 Err(other) => panic!("Error waiting for event: {:?}", other),
@@ -32,4 +57,20 @@ impl Event {
     pub fn wait_for_event_blocking(&self) {
         syscall::wait_for_event(self.0, true).unwrap();
     }
+
+    /// Acknowledge the interrupt that signalled this event, once the device that raised it has been serviced. See
+    /// `syscall::ack_interrupt`.
+    pub fn ack_interrupt(&self) {
+        syscall::ack_interrupt(self.0).unwrap();
+    }
+
+    /// Block until the event is signalled, or until `timeout` has elapsed. Returns `true` if the
+    /// event was signalled.
+    pub fn wait_for_event_timeout(&self, timeout: Duration) -> bool {
+        match syscall::wait_for_event_timeout(self.0, timeout) {
+            Ok(()) => true,
+            Err(WaitForEventError::TimedOut) => false,
+            Err(other) => panic!("Error waiting for event: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,388 @@
+//! Files are provided to tasks by the VFS, which is reached through the `vfs` service. Clients make each
+//! `FileRequest` as a call over the service channel (see `Channel::call`), and the VFS replies to each request
+//! with a single `FileResponse`. Paths are absolute, with components separated by `/`.
+//!
+//! The VFS owns a mount table, and forwards requests to the filesystem mounted at the longest prefix of each
+//! path. Filesystem drivers subscribe to the `vfs.filesystem` service, and mount a filesystem by sending a
+//! `FilesystemMessage::Mount` containing a channel. The driver then receives `FileRequest`s over that channel,
+//! with paths relative to the root of the filesystem, and replies to them in the same way as the VFS.
+//!
+//! Data is transferred in the messages themselves, and so the amount of data that can be read or written by a
+//! single request is limited to `MAX_TRANSFER_SIZE`. Larger parts of a file can instead be mapped with `Map`,
+//! which gives the client a read-only `MemoryObject` holding them.
+//!
+//! Each channel to the VFS sees its own namespace: the tree of files that paths sent over it are resolved in.
+//! Tasks that subscribe to the `vfs` service see every filesystem, while a new namespace can be made out of
+//! directories in an existing one with `FileRequest::Namespace`, and handed to another task (usually when it's
+//! spawned). Paths can't contain `..`, and symlinks are followed by the VFS within the namespace, so nothing
+//! outside of a namespace can be reached through it.
+
+use crate::{
+    channel::{CallError, Channel},
+    memory_object::MemoryObject,
+    service_host::{ServiceHostClient, NAMESPACE_RESOURCE},
+    syscall::MemoryObjectFlags,
+    Handle,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use mulch::math::align_up;
+use ptah::{Deserialize, Serialize};
+use spinning_top::Spinlock;
+
+pub const VFS_SERVICE: &str = "vfs";
+pub const VFS_FILESYSTEM_SERVICE: &str = "vfs.filesystem";
+
+/// The maximum number of bytes that can be read or written by a single request. This is limited by the size of
+/// the buffer messages are received into.
+pub const MAX_TRANSFER_SIZE: usize = 1024;
+/// The maximum number of entries that are returned by a single `ReadDir` request.
+pub const MAX_DIR_ENTRIES: usize = 8;
+
+/// Identifies an open file. These are allocated by whoever opened the file, and are only meaningful on the
+/// channel they were returned over.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub struct FileId(pub u64);
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum FileRequest {
+    Open {
+        path: String,
+        options: OpenOptions,
+    },
+    /// Read up to `length` bytes from an open file, starting at `offset`. Fewer bytes are returned if the end of
+    /// the file is reached.
+    Read {
+        file: FileId,
+        offset: u64,
+        length: u32,
+    },
+    /// Write `data` to an open file, starting at `offset`. The file is extended if needed.
+    Write {
+        file: FileId,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Get a read-only `MemoryObject` holding `length` bytes of an open file, starting at `offset`. The object's
+    /// size is rounded up to a whole number of pages, and any part of it past the end of the file is zeroed.
+    /// Later writes to the file aren't visible through the object.
+    ///
+    /// This is handled by the VFS itself, from its page cache, and so isn't forwarded to filesystems.
+    Map {
+        file: FileId,
+        offset: u64,
+        length: u64,
+    },
+    Stat {
+        path: String,
+    },
+    /// List the entries of a directory, skipping the first `start` of them. An empty list of entries marks the
+    /// end of the directory.
+    ReadDir {
+        path: String,
+        start: u32,
+    },
+    Close {
+        file: FileId,
+    },
+    /// Create a new namespace, made up of the directories bound into it by `bindings`. Later bindings cover up
+    /// anything bound at or below their path. The reply is a handle to a new channel to the VFS, over which paths
+    /// are resolved in the new namespace. The new namespace can only contain what can be reached in this one.
+    ///
+    /// This is handled by the VFS itself, and so isn't forwarded to filesystems.
+    Namespace {
+        bindings: Vec<Binding>,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum FileResponse {
+    /// The file has been opened. Also carries the file's `FileStat`, so the VFS doesn't need to make another
+    /// request to find out which file it is.
+    Opened(FileId, FileStat),
+    Data(Vec<u8>),
+    Written(u32),
+    /// A handle to the `MemoryObject` requested with `Map`.
+    Mapped(Handle),
+    /// A handle to the channel requested with `Namespace`.
+    Namespace(Handle),
+    Stat(FileStat),
+    Entries(Vec<DirEntry>),
+    Done,
+    Error(FileError),
+    /// Sent by filesystems in reply to a request with a path, if the path goes through a symlink. `depth` is the
+    /// number of components of the path up to and including the symlink. The VFS follows the symlink, and makes
+    /// the request again with the path it leads to, so this is never sent to clients.
+    Symlink {
+        depth: u32,
+        target: String,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+pub struct OpenOptions {
+    pub write: bool,
+    /// Create the file if it does not exist. Requires `write`.
+    pub create: bool,
+    /// Truncate the file to zero length when it is opened. Requires `write`.
+    pub truncate: bool,
+}
+
+impl OpenOptions {
+    pub fn read() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn write() -> OpenOptions {
+        OpenOptions { write: true, create: true, truncate: false }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum FileKind {
+    File,
+    Directory,
+    /// The VFS follows symlinks when it looks up paths, so these are only seen in the entries of a directory.
+    Symlink,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct FileStat {
+    pub kind: FileKind,
+    pub size: u64,
+    /// Identifies the file within its filesystem, and doesn't change for as long as the file exists. The VFS uses
+    /// this to key the data it caches for each file, so different paths to the same file share their cached data.
+    pub inode: u64,
+    /// The Unix permission bits of the file (e.g. `0o644`). Filesystems that don't have permissions report the
+    /// access they allow to everyone.
+    pub permissions: u16,
+}
+
+/// Makes the directory at `target` appear at `path` in a new namespace (see `FileRequest::Namespace`).
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Binding {
+    pub path: String,
+    /// The path of the directory in the namespace the new one is created from.
+    pub target: String,
+}
+
+impl Binding {
+    pub fn new(path: &str, target: &str) -> Binding {
+        Binding { path: String::from(path), target: String::from(target) }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum FileError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    /// The path is malformed, goes round a loop of symlinks, or a filesystem is already mounted there.
+    InvalidPath,
+    /// The `FileId` does not refer to an open file.
+    InvalidFile,
+    /// The file was not opened for writing, or the filesystem is read-only.
+    ReadOnly,
+    /// The request would transfer more than `MAX_TRANSFER_SIZE` bytes.
+    TooLarge,
+    NoSpace,
+    /// The filesystem failed to access its underlying storage.
+    Io,
+    /// The request isn't supported by whoever received it.
+    Unsupported,
+}
+
+/// Sent by filesystem drivers over the `vfs.filesystem` service channel.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FilesystemMessage {
+    /// Mount a filesystem at `path`. The VFS makes requests of the filesystem over `channel`, which should be
+    /// a `Channel<FileResponse, FileRequest>` on the driver's side.
+    Mount { path: String, channel: Handle },
+}
+
+/// Sent by the VFS in response to each `FilesystemMessage`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum FilesystemResponse {
+    Mounted,
+    Error(FileError),
+}
+
+#[derive(Debug)]
+pub enum FileClientError {
+    File(FileError),
+    Call(CallError),
+    /// The VFS replied with a response that doesn't make sense for the request.
+    UnexpectedResponse,
+}
+
+/// A client of the VFS, for use by tasks that consume the `vfs` service. Requests are made synchronously.
+#[derive(Clone)]
+pub struct Vfs {
+    channel: Arc<Channel<FileRequest, FileResponse>>,
+}
+
+impl Vfs {
+    pub fn new(channel: Channel<FileRequest, FileResponse>) -> Vfs {
+        Vfs { channel: Arc::new(channel) }
+    }
+
+    pub fn open(&self, path: &str, options: OpenOptions) -> Result<File, FileClientError> {
+        match self.request(&FileRequest::Open { path: String::from(path), options })? {
+            FileResponse::Opened(id, _) => Ok(File { vfs: self.clone(), id, position: 0 }),
+            _ => Err(FileClientError::UnexpectedResponse),
+        }
+    }
+
+    pub fn stat(&self, path: &str) -> Result<FileStat, FileClientError> {
+        match self.request(&FileRequest::Stat { path: String::from(path) })? {
+            FileResponse::Stat(stat) => Ok(stat),
+            _ => Err(FileClientError::UnexpectedResponse),
+        }
+    }
+
+    /// List all the entries of a directory. This makes as many requests as are needed to read the whole
+    /// directory.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FileClientError> {
+        let mut entries = Vec::new();
+        loop {
+            let request = FileRequest::ReadDir { path: String::from(path), start: entries.len() as u32 };
+            match self.request(&request)? {
+                FileResponse::Entries(batch) if batch.is_empty() => return Ok(entries),
+                FileResponse::Entries(batch) => entries.extend(batch),
+                _ => return Err(FileClientError::UnexpectedResponse),
+            }
+        }
+    }
+
+    /// Create a new namespace out of directories in this one (see `FileRequest::Namespace`). Returns a handle to
+    /// a channel to the VFS that sees the new namespace, which is usually given to a new task when it's spawned.
+    pub fn create_namespace(&self, bindings: Vec<Binding>) -> Result<Handle, FileClientError> {
+        match self.request(&FileRequest::Namespace { bindings })? {
+            FileResponse::Namespace(handle) => Ok(handle),
+            _ => Err(FileClientError::UnexpectedResponse),
+        }
+    }
+
+    fn request(&self, request: &FileRequest) -> Result<FileResponse, FileClientError> {
+        match self.channel.call_blocking(request).map_err(FileClientError::Call)? {
+            FileResponse::Error(err) => Err(FileClientError::File(err)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// A file opened through the VFS. Reads and writes are made at the current position, which is advanced past
+/// the data transferred. The file is closed when this is dropped.
+pub struct File {
+    vfs: Vfs,
+    id: FileId,
+    position: u64,
+}
+
+impl File {
+    /// Read into `buffer`, returning the number of bytes read. Returns `0` at the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, FileClientError> {
+        let length = usize::min(buffer.len(), MAX_TRANSFER_SIZE) as u32;
+        match self.vfs.request(&FileRequest::Read { file: self.id, offset: self.position, length })? {
+            FileResponse::Data(data) if data.len() <= buffer.len() => {
+                buffer[0..data.len()].copy_from_slice(&data);
+                self.position += data.len() as u64;
+                Ok(data.len())
+            }
+            _ => Err(FileClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Read from the current position to the end of the file.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, FileClientError> {
+        let mut data = Vec::new();
+        let mut buffer = [0u8; MAX_TRANSFER_SIZE];
+        loop {
+            match self.read(&mut buffer)? {
+                0 => return Ok(data),
+                n => data.extend_from_slice(&buffer[0..n]),
+            }
+        }
+    }
+
+    /// Write all of `data`, splitting it into multiple requests if needed.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), FileClientError> {
+        for chunk in data.chunks(MAX_TRANSFER_SIZE) {
+            let request = FileRequest::Write { file: self.id, offset: self.position, data: chunk.to_vec() };
+            match self.vfs.request(&request)? {
+                FileResponse::Written(written) => self.position += written as u64,
+                _ => return Err(FileClientError::UnexpectedResponse),
+            }
+        }
+        Ok(())
+    }
+
+    /// Map `length` bytes of the file, starting at `offset`, into a read-only `MemoryObject`. The data is
+    /// transferred through memory, rather than in messages, so this is much faster than reading large files. The
+    /// object is a snapshot of the file, so later writes to the file aren't visible through it.
+    pub fn map(&self, offset: u64, length: usize) -> Result<MemoryObject, FileClientError> {
+        match self.vfs.request(&FileRequest::Map { file: self.id, offset, length: length as u64 })? {
+            FileResponse::Mapped(handle) => Ok(unsafe {
+                MemoryObject::from_handle(handle, align_up(length, 0x1000), MemoryObjectFlags::empty())
+            }),
+            _ => Err(FileClientError::UnexpectedResponse),
+        }
+    }
+
+    pub fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = self.vfs.request(&FileRequest::Close { file: self.id });
+    }
+}
+
+/// The client of the VFS that sees this task's namespace, once it's been found. This is `Some(None)` if the task
+/// doesn't have a namespace.
+static NAMESPACE: Spinlock<Option<Option<Vfs>>> = Spinlock::new(None);
+
+/// Get a client of the VFS that sees this task's filesystem namespace, which is what `std::fs` uses. The first
+/// call asks `service_host` for the namespace the task was spawned with (see `TaskPermissions::namespace`), so it
+/// shouldn't be made while another thread is waiting for a reply from `service_host`. Returns `None` if the task
+/// wasn't spawned with a namespace, and one hasn't been provided with `set_namespace`.
+pub fn namespace() -> Option<Vfs> {
+    NAMESPACE
+        .lock()
+        .get_or_insert_with(|| {
+            ServiceHostClient::new()
+                .request_resource(NAMESPACE_RESOURCE)
+                .ok()
+                .map(|handle| Vfs::new(Channel::new_from_handle(handle)))
+        })
+        .clone()
+}
+
+/// Provide the client of the VFS returned by `namespace`. This is used by tasks that weren't spawned with a
+/// namespace, but can reach the VFS some other way (e.g. boot tasks that subscribe to the `vfs` service).
+pub fn set_namespace(vfs: Vfs) {
+    *NAMESPACE.lock() = Some(Some(vfs));
+}
+
+/// Split an absolute path into its components, ignoring empty components (so `/a//b/` is the same as `/a/b`).
+/// Returns `None` if the path is not absolute, or contains `.` or `..` components.
+pub fn path_components(path: &str) -> Option<Vec<&str>> {
+    let path = path.strip_prefix('/')?;
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+    if components.iter().any(|&component| component == "." || component == "..") {
+        return None;
+    }
+    Some(components)
+}
@@ -1,22 +1,47 @@
 #![no_std]
-#![feature(decl_macro, never_type, allocator_api, ptr_as_uninit)]
+#![feature(decl_macro, never_type, allocator_api, ptr_as_uninit, naked_functions, thread_local)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
 #[cfg(feature = "can_alloc")]
 extern crate alloc;
 
+#[cfg(feature = "can_alloc")]
+pub mod block;
+pub mod caps;
 #[cfg(feature = "can_alloc")]
 pub mod channel;
+#[cfg(feature = "can_alloc")]
+pub mod console;
 #[cfg(feature = "ddk")]
 pub mod ddk;
 #[cfg(feature = "can_alloc")]
 pub mod early_logger;
 pub mod event;
+#[cfg(feature = "can_alloc")]
+pub mod file;
+#[cfg(feature = "can_alloc")]
+pub mod logger;
 pub mod manifest;
 pub mod memory_object;
+#[cfg(feature = "can_alloc")]
+pub mod net;
+pub mod pipe;
+pub mod profile;
 #[cfg(feature = "async")]
 pub mod rt;
+#[cfg(feature = "can_alloc")]
+pub mod service_host;
+#[cfg(feature = "can_alloc")]
+pub mod stdio;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod sync;
 pub mod syscall;
+#[cfg(feature = "can_alloc")]
+pub mod thread;
+pub mod time;
+pub mod trace;
+pub mod vdso;
 
 use core::num::TryFromIntError;
 
@@ -59,22 +84,33 @@ impl<'de> ptah::Deserialize<'de> for Handle {
     }
 }
 
-// TODO: I don't think rights are implemented at all are they? Work out if we want them / remove
-// this.
 bitflags::bitflags! {
-    struct HandleRights: u32 {
-        /// Whether the handle's owner can use it to modify the kernel object it points to. What is means to
-        /// "modify" a kernel object differs depending on the type of the kernel object.
-        const MODIFY = 0b1;
-        /// Whether the handle can be duplicated.
-        const DUPLICATE = 0b10;
-        /// Whether the handle can be transferred over a `Channel`.
-        const TRANSFER = 0b100;
-        /// For `MemoryObject`s, whether the memory can be mapped into the handle owner's `AddressSpace`.
-        const MAP = 0x1000;
-        /// For `Channel` ends, whether the `send_message` system call can be used on this `Channel` end.
-        const SEND = 0x1_0000;
-        /// For `Channel` ends, whether the `receive_message` & co. system calls can be used on this `Channel` end.
-        const RECEIVE = 0x10_0000;
+    /// The rights a `Handle` gives its owner over the kernel object it refers to. Each handle has its own set of
+    /// rights, and so a task can hand out handles with reduced rights to an object it has full access to (e.g. a
+    /// read-only view of a memory object) by duplicating a handle with `handle_duplicate_with_rights`. Handles
+    /// created by the kernel for a task have all rights. Rights can only ever be removed from a handle - a
+    /// duplicate can't have rights that the original does not.
+    ///
+    /// What it means to read, write, or signal a kernel object depends on the type of the object. See the
+    /// documentation for each system call for the rights it requires.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct HandleRights: u32 {
+        /// For `Channel` ends, whether messages can be received. For `Event`s, whether the event can be waited
+        /// for. For `MemoryObject`s, whether the memory can be read (e.g. by cloning the object). For
+        /// `AddressSpace`s, whether information about the address space can be read.
+        const READ = 1 << 0;
+        /// For `Channel` ends, whether messages can be sent. For `MemoryObject`s, whether the memory can be
+        /// mapped writable. For `AddressSpace`s and `Task`s, whether they can be modified (e.g. by mapping memory
+        /// into an address space, or changing the priority of a task).
+        const WRITE = 1 << 1;
+        /// For `MemoryObject`s, whether the memory can be mapped into an `AddressSpace`.
+        const MAP = 1 << 2;
+        /// Whether the handle can be duplicated with `handle_duplicate_with_rights`.
+        const DUPLICATE = 1 << 3;
+        /// Whether the handle can be transferred to another task, either over a `Channel` or when spawning a
+        /// task.
+        const TRANSFER = 1 << 4;
+        /// For `Event`s, whether the event can be signalled or cleared.
+        const SIGNAL = 1 << 5;
     }
 }
@@ -0,0 +1,252 @@
+//! Tasks log through `log_server`, which they reach through the `log` service. Records are sent to it in a
+//! structured, binary form (rather than as formatted text, like `early_log`), and are batched up so that logging
+//! doesn't cost a message per record. `log_server` keeps the most recent records from every task, so they can be
+//! queried, and can change the level each task logs at while it's running.
+//!
+//! Tasks install `LOGGER` as their logger as early as they can, and connect it to the `log` service once they've
+//! found it. Until then, records are logged with `early_log`.
+
+use crate::{
+    channel::Channel,
+    syscall::{self, early_log},
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt::Write, time::Duration};
+use log::{Log, Metadata, Record};
+use ptah::{Deserialize, Serialize};
+use spinning_top::Spinlock;
+
+pub const LOG_SERVICE: &str = "log";
+
+/// Records are sent once this many have been buffered, even if they're not yet `MAX_BATCH_AGE` old.
+pub const MAX_BATCH_SIZE: usize = 32;
+/// Records are sent once the oldest buffered record is this old. This is only checked when something is logged,
+/// so a task that stops logging keeps its last records until it logs again (or calls `Logger::flush`).
+pub const MAX_BATCH_AGE: Duration = Duration::from_millis(100);
+/// The maximum number of records returned by a single `LogRequest::Query`.
+pub const MAX_QUERY_RECORDS: u32 = 64;
+
+/// The severity of a record. These are ordered from most to least severe, like `log::Level`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+
+    /// Parse a level from its name, ignoring case.
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace]
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> LogLevel {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+/// Convert the most verbose level a task should log at (`None` if it shouldn't log at all) into a `LevelFilter`.
+pub fn level_filter(max_level: Option<LogLevel>) -> log::LevelFilter {
+    match max_level {
+        None => log::LevelFilter::Off,
+        Some(LogLevel::Error) => log::LevelFilter::Error,
+        Some(LogLevel::Warn) => log::LevelFilter::Warn,
+        Some(LogLevel::Info) => log::LevelFilter::Info,
+        Some(LogLevel::Debug) => log::LevelFilter::Debug,
+        Some(LogLevel::Trace) => log::LevelFilter::Trace,
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// The module path the record was logged from, by default.
+    pub target: String,
+    /// When the record was logged, in nanoseconds since boot (from `clock_monotonic`).
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// A record, as kept by `log_server`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct TaskLogRecord {
+    /// Records are numbered in the order `log_server` receives them, starting from `0`.
+    pub sequence: u64,
+    /// Identifies the connection the record was sent over. Each connection to the `log` service is given a new
+    /// ID, so this tells apart different runs of tasks with the same name.
+    pub task_id: u64,
+    pub task_name: String,
+    pub record: LogRecord,
+}
+
+/// Selects the records returned by `LogRequest::Query`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct LogQuery {
+    /// Only return records from tasks with this name.
+    pub task: Option<String>,
+    /// Only return records of this level, or more severe.
+    pub level: LogLevel,
+    /// Only return records with a sequence number of at least this.
+    pub since: u64,
+    /// Return at most this many records. This is limited to `MAX_QUERY_RECORDS`.
+    pub max_records: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum LogRequest {
+    /// A batch of records, in the order they were logged.
+    Log(Vec<LogRecord>),
+    /// Set the most verbose level that tasks called `task` log at (`None` turns their logging off). `task` can be
+    /// `*` to set the level of every task, including tasks that connect later. This should be made as a call,
+    /// and is replied to with `LevelSet`.
+    SetLevel { task: String, level: Option<LogLevel> },
+    /// Get the records `log_server` has kept that match a query. This should be made as a call, and is replied
+    /// to with `Records`.
+    Query(LogQuery),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum LogEvent {
+    /// Sent to a client when the most verbose level it should log at changes.
+    SetMaxLevel(Option<LogLevel>),
+    /// The reply to `SetLevel`, with the number of connected tasks whose level was changed.
+    LevelSet { num_tasks: u32 },
+    /// The reply to `Query`. `next` is the sequence number to query from to get the records after these.
+    Records { records: Vec<TaskLogRecord>, next: u64 },
+}
+
+/// The logger tasks should install with `log::set_logger`.
+pub static LOGGER: Logger = Logger::new();
+
+pub struct Logger {
+    inner: Spinlock<Option<Connection>>,
+}
+
+struct Connection {
+    channel: Channel<LogRequest, LogEvent>,
+    batch: Vec<LogRecord>,
+}
+
+impl Logger {
+    const fn new() -> Logger {
+        Logger { inner: Spinlock::new(None) }
+    }
+
+    /// Start sending records to `log_server` over `channel`, which should be a channel subscribed to the `log`
+    /// service.
+    pub fn connect(&self, channel: Channel<LogRequest, LogEvent>) {
+        *self.inner.lock() = Some(Connection { channel, batch: Vec::new() });
+    }
+
+    /// Send any buffered records to `log_server` now. This is worth doing before a task exits.
+    pub fn flush(&self) {
+        if let Some(connection) = self.inner.lock().as_mut() {
+            connection.flush();
+        }
+    }
+
+    /// Like `flush`, but does nothing if the logger is already in use. This is used when a task exits, which can
+    /// happen from a panic while the logger is locked.
+    pub fn try_flush(&self) {
+        if let Some(mut inner) = self.inner.try_lock() {
+            if let Some(connection) = inner.as_mut() {
+                connection.flush();
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn flush(&mut self) {
+        if !self.batch.is_empty() {
+            let request = LogRequest::Log(core::mem::take(&mut self.batch));
+            if self.channel.send(&request).is_err() {
+                // We can't log the failure, so at least make sure the records aren't lost
+                let LogRequest::Log(batch) = &request else { unreachable!() };
+                for record in batch {
+                    log_early(record);
+                }
+            }
+        }
+
+        // Apply any changes to our level that `log_server` has sent us since we last flushed
+        while let Ok(Some(event)) = self.channel.try_receive() {
+            if let LogEvent::SetMaxLevel(level) = event {
+                log::set_max_level(level_filter(level));
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = syscall::clock_monotonic();
+        let record = LogRecord {
+            level: record.level().into(),
+            target: record.target().to_string(),
+            timestamp: now.as_nanos() as u64,
+            message: record.args().to_string(),
+        };
+
+        let mut inner = self.inner.lock();
+        let Some(connection) = inner.as_mut() else {
+            log_early(&record);
+            return;
+        };
+
+        let level = record.level;
+        connection.batch.push(record);
+
+        // Warnings and errors are sent straight away, as they're often followed by the task crashing
+        let oldest = Duration::from_nanos(connection.batch[0].timestamp);
+        if connection.batch.len() >= MAX_BATCH_SIZE
+            || level <= LogLevel::Warn
+            || now.saturating_sub(oldest) >= MAX_BATCH_AGE
+        {
+            connection.flush();
+        }
+    }
+
+    fn flush(&self) {
+        Logger::flush(self);
+    }
+}
+
+fn log_early(record: &LogRecord) {
+    let mut message = String::new();
+    write!(message, "[{}] {}", record.level.name(), record.message).unwrap();
+    let _ = early_log(&message);
+}
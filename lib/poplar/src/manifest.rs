@@ -4,10 +4,18 @@
 use alloc::{string::String, vec::Vec};
 use ptah::{Deserialize, Serialize};
 
+/// The address the kernel maps a task's manifest at. The encoded manifest is preceded by its length, as a
+/// little-endian `u32`. The bootstrap task is passed a `BootstrapManifest`, and every other task (whether it's
+/// created with `task_create` or `spawn_task`) is passed a `TaskManifest`.
+pub const MANIFEST_ADDRESS: usize = 0x2000_0000;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BootstrapManifest {
     pub task_name: String,
     pub boot_tasks: Vec<BootTask>,
+    /// The initial ramdisk loaded by Seed, if there is one. In the format `(handle to MemoryObject, size of the
+    /// archive)`. The memory object is read-only.
+    pub initramfs: Option<(u32, usize)>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -18,3 +26,42 @@ pub struct BootTask {
     /// address, handle to MemoryObject)`.
     pub segments: Vec<(usize, u32)>,
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskManifest {
+    pub task_name: String,
+    pub arguments: Vec<String>,
+    /// The handles the task was created with, in the order they were passed to `task_create`.
+    pub handles: Vec<u32>,
+    pub stdio: StdioHandles,
+}
+
+/// The handles the task was given to use as its standard input, output, and error (see `crate::stdio`). Tasks
+/// created with `spawn_task` aren't given any.
+#[derive(Clone, Copy, Default, Debug, Serialize, Deserialize)]
+pub struct StdioHandles {
+    pub stdin: Option<StdioHandle>,
+    pub stdout: Option<StdioHandle>,
+    pub stderr: Option<StdioHandle>,
+}
+
+/// A standard handle, along with what it's a handle to. The kernel works this out from the handle it's given.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum StdioHandle {
+    /// A `Channel` that speaks the console protocol.
+    Console(u32),
+    /// The read end of a pipe for stdin, or the write end of one for stdout and stderr.
+    Pipe(u32),
+}
+
+impl TaskManifest {
+    /// Read the manifest of the running task.
+    ///
+    /// ### Safety
+    /// This can't be called from the bootstrap task, which is passed a `BootstrapManifest` instead.
+    pub unsafe fn read() -> TaskManifest {
+        let length = unsafe { core::ptr::read(MANIFEST_ADDRESS as *const u32) };
+        let data = unsafe { core::slice::from_raw_parts((MANIFEST_ADDRESS + 4) as *const u8, length as usize) };
+        ptah::from_wire(data, &[]).unwrap()
+    }
+}
@@ -1,7 +1,24 @@
 use crate::{
-    syscall::{self, CreateMemoryObjectError, MapMemoryObjectError, MemoryObjectFlags},
+    syscall::{
+        self,
+        CloneMemoryObjectError,
+        CreateDmaMemoryObjectError,
+        CreateMemoryObjectError,
+        DmaConstraints,
+        DmaDirection,
+        DmaSegment,
+        DmaSyncError,
+        MapMemoryObjectError,
+        MapMemoryObjectFlags,
+        MemoryObjectFlags,
+        ProtectMemoryObjectError,
+        SetMemoryObjectDiscardableError,
+        UnmapMemoryObjectError,
+    },
     Handle,
 };
+#[cfg(feature = "can_alloc")]
+use alloc::{vec, vec::Vec};
 use core::ptr;
 
 #[derive(Debug)]
@@ -22,6 +39,23 @@ impl MemoryObject {
         Ok(MemoryObject { handle, size, flags, phys_address: None })
     }
 
+    /// Create a `MemoryObject` that physical memory is only committed to as each page is first accessed. The
+    /// memory is zeroed when it's committed.
+    pub fn create_lazy(size: usize, flags: MemoryObjectFlags) -> Result<MemoryObject, CreateMemoryObjectError> {
+        let flags = flags | MemoryObjectFlags::LAZY;
+        let handle = unsafe { crate::syscall::create_memory_object(size, flags, ptr::null_mut())? };
+        Ok(MemoryObject { handle, size, flags, phys_address: None })
+    }
+
+    /// Create a lazy `MemoryObject` whose memory the kernel can discard when memory is short, while it's marked
+    /// as discardable with `set_discardable`. This is useful for caches of data that can be fetched again.
+    pub fn create_discardable(
+        size: usize,
+        flags: MemoryObjectFlags,
+    ) -> Result<MemoryObject, CreateMemoryObjectError> {
+        Self::create_lazy(size, flags | MemoryObjectFlags::DISCARDABLE)
+    }
+
     pub unsafe fn create_physical(
         size: usize,
         flags: MemoryObjectFlags,
@@ -32,17 +66,73 @@ impl MemoryObject {
         Ok(MemoryObject { handle, size, flags, phys_address: Some(phys_address) })
     }
 
+    /// Create a `MemoryObject` that devices can access through DMA, and which satisfies the given `constraints`.
+    /// Also returns the segments devices should use to access the memory. The memory is zeroed. This requires the
+    /// `DMA` capability.
+    ///
+    /// The memory is contiguous as seen by devices, and so `phys_address` is set to the bus address of its start.
+    #[cfg(feature = "can_alloc")]
+    pub fn create_dma(
+        size: usize,
+        flags: MemoryObjectFlags,
+        constraints: DmaConstraints,
+    ) -> Result<(MemoryObject, Vec<DmaSegment>), CreateDmaMemoryObjectError> {
+        let mut segments = vec![DmaSegment::default(); constraints.num_segments(size)];
+        let handle = syscall::create_dma_memory_object(size, flags, &constraints, &mut segments)?;
+        let phys_address = segments.first().map(|segment| segment.bus_address as usize);
+        Ok((MemoryObject { handle, size, flags, phys_address }, segments))
+    }
+
+    /// Make part of a DMA memory object coherent between the CPU and devices. See `syscall::dma_sync`.
+    pub fn dma_sync(&self, offset: usize, length: usize, direction: DmaDirection) -> Result<(), DmaSyncError> {
+        syscall::dma_sync(self.handle, offset, length, direction)
+    }
+
+    /// Mark whether the kernel can discard this object's memory. Returns `true` if any of it has been discarded
+    /// since this was last called. See `syscall::set_memory_object_discardable`.
+    pub fn set_discardable(&self, discardable: bool) -> Result<bool, SetMemoryObjectDiscardableError> {
+        syscall::set_memory_object_discardable(self.handle, discardable)
+    }
+
+    /// Create a copy-on-write clone of this `MemoryObject`. Clones aren't physically contiguous, and so
+    /// don't have a known physical address.
+    pub fn clone_cow(&self) -> Result<MemoryObject, CloneMemoryObjectError> {
+        let handle = syscall::clone_memory_object(self.handle)?;
+        Ok(MemoryObject { handle, size: self.size, flags: self.flags, phys_address: None })
+    }
+
+    /// Map the memory object at a free address chosen by the kernel. This should be preferred to `map_at`, as
+    /// the kernel keeps track of which parts of the address space are in use, and so mappings can't collide.
     pub unsafe fn map(self) -> Result<MappedMemoryObject, MapMemoryObjectError> {
+        unsafe { self.map_with_flags(MapMemoryObjectFlags::empty()) }
+    }
+
+    /// Map the memory object at a free address chosen by the kernel, with the given `flags`.
+    pub unsafe fn map_with_flags(
+        self,
+        flags: MapMemoryObjectFlags,
+    ) -> Result<MappedMemoryObject, MapMemoryObjectError> {
         let mut address = 0usize;
         unsafe {
-            syscall::map_memory_object(self.handle, Handle::ZERO, None, &mut address as *mut usize)?;
+            syscall::map_memory_object(self.handle, Handle::ZERO, None, &mut address as *mut usize, flags)?;
         }
         Ok(MappedMemoryObject { inner: self, mapped_at: address })
     }
 
     pub unsafe fn map_at(self, address: usize) -> Result<MappedMemoryObject, MapMemoryObjectError> {
+        unsafe { self.map_at_with_flags(address, MapMemoryObjectFlags::empty()) }
+    }
+
+    /// Map the memory object at `address`, with the given `flags`. Large physically contiguous objects, such as
+    /// framebuffers, can be mapped with `MapMemoryObjectFlags::HUGE_PAGES` to use fewer TLB entries, as long as
+    /// `address` is aligned to a huge page with respect to the object's physical memory.
+    pub unsafe fn map_at_with_flags(
+        self,
+        address: usize,
+        flags: MapMemoryObjectFlags,
+    ) -> Result<MappedMemoryObject, MapMemoryObjectError> {
         unsafe {
-            syscall::map_memory_object(self.handle, Handle::ZERO, Some(address), ptr::null_mut())?;
+            syscall::map_memory_object(self.handle, Handle::ZERO, Some(address), ptr::null_mut(), flags)?;
         }
         Ok(MappedMemoryObject { inner: self, mapped_at: address })
     }
@@ -69,4 +159,20 @@ impl MappedMemoryObject {
     pub fn virt_to_phys(&self, virt: usize) -> Option<usize> {
         self.inner.phys_address.map(|phys_base| phys_base + (virt - self.mapped_at))
     }
+
+    /// Change whether the mapping is writable and executable, from the `WRITABLE` and `EXECUTABLE` bits of
+    /// `flags`. This is unsafe because references into the mapped memory may no longer be valid to use (e.g.
+    /// mutable references, if the mapping is made read-only).
+    pub unsafe fn protect(&self, flags: MemoryObjectFlags) -> Result<(), ProtectMemoryObjectError> {
+        unsafe { syscall::protect_memory_object(Handle::ZERO, self.mapped_at, flags) }
+    }
+
+    /// Unmap the memory object, returning it so it can be mapped again or passed on. This is unsafe because any
+    /// references into the mapped memory are left dangling.
+    pub unsafe fn unmap(self) -> Result<MemoryObject, UnmapMemoryObjectError> {
+        unsafe {
+            syscall::unmap_memory_object(Handle::ZERO, self.mapped_at)?;
+        }
+        Ok(self.inner)
+    }
 }
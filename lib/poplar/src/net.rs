@@ -0,0 +1,106 @@
+//! The network stack can be controlled and queried by other tasks through the `net.control` service. Clients send
+//! `NetControlRequest`s over the service channel, and the stack replies to each request, in order, with a single
+//! `NetControlResponse`.
+//!
+//! Tasks send and receive UDP datagrams through the `net.socket` service, which is what `std::net` uses. Each
+//! channel to the service is a single socket: the client first binds it to a port with `SocketRequest::BindUdp`,
+//! and is then sent a `SocketEvent::Received` for each datagram that arrives at that port. The socket is closed,
+//! and its port freed, when the channel is.
+
+use alloc::vec::Vec;
+use core::fmt;
+use ptah::{Deserialize, Serialize};
+
+pub const NET_CONTROL_SERVICE: &str = "net.control";
+pub const NET_SOCKET_SERVICE: &str = "net.socket";
+
+/// The largest payload that can be sent in a single UDP datagram. The stack doesn't fragment packets, so each
+/// datagram has to fit in a single Ethernet frame.
+pub const MAX_DATAGRAM_SIZE: usize = 1472;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0, 0, 0, 0]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([255, 255, 255, 255]);
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl fmt::Debug for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum NetControlRequest {
+    /// List the interfaces managed by the stack, and how each is configured.
+    GetInterfaces,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum NetControlResponse {
+    Interfaces(Vec<InterfaceInfo>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct InterfaceInfo {
+    pub mac_address: [u8; 6],
+    /// The IPv4 configuration of the interface. This is `None` until the interface has been configured (e.g. by
+    /// DHCP), and if the configuration is lost (e.g. if a DHCP lease expires without being renewed).
+    pub ipv4: Option<Ipv4Config>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Ipv4Config {
+    pub address: Ipv4Address,
+    pub prefix_length: u8,
+    pub gateway: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address>,
+    /// If the configuration was obtained by DHCP, the address of the server that leased it to us.
+    pub dhcp_server: Option<Ipv4Address>,
+    /// If the configuration was obtained by DHCP, the number of seconds left before the lease expires.
+    pub lease_remaining: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum SocketRequest {
+    /// Bind the socket to a local UDP port, or to any free port if `port` is `0`. This must be the first request
+    /// made over the channel, and is replied to with either `SocketEvent::Bound` or `SocketEvent::Error`.
+    BindUdp { port: u16 },
+    /// Send a datagram from the socket's port. This isn't replied to - like any UDP datagram, it may not arrive,
+    /// and datagrams that can't be sent (e.g. because no interface has been configured yet) are dropped.
+    SendTo { address: Ipv4Address, port: u16, data: Vec<u8> },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum SocketEvent {
+    /// The socket has been bound to `port`.
+    Bound {
+        port: u16,
+    },
+    Error(SocketError),
+    /// A datagram has arrived at the socket's port.
+    Received {
+        address: Ipv4Address,
+        port: u16,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum SocketError {
+    /// Another socket is already bound to the port.
+    AddressInUse,
+    /// The socket has already been bound.
+    AlreadyBound,
+    /// There are no free ports left to bind the socket to.
+    NoFreePorts,
+}
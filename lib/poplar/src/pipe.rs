@@ -0,0 +1,141 @@
+//! Pipes carry a stream of bytes from one task to another, through a kernel object with a buffer of
+//! `PIPE_CAPACITY` bytes. Unlike channels, pipes don't preserve the boundaries between writes, and writers have
+//! to wait for the reader once the pipe is full. Once every handle to the write end has been closed, reads return
+//! `0` bytes after the rest of the data has been read, and writes fail once the read end has been closed.
+//!
+//! Each end is closed when it's dropped, so a `PipeWriter` should be dropped once there's nothing more to write,
+//! so that the reader sees the end of the stream. Ends that are handed to another task should be turned back into
+//! handles with `into_handle` first.
+
+use crate::{
+    syscall::{self, CreatePipeError, PipeReadError, PipeWriteError, Signals},
+    Handle,
+};
+use core::{future::Future, task::Poll};
+
+/// Create a pipe, returning its read end and its write end.
+pub fn pipe() -> Result<(PipeReader, PipeWriter), CreatePipeError> {
+    let (read_end, write_end) = syscall::create_pipe()?;
+    Ok((PipeReader(read_end), PipeWriter(write_end)))
+}
+
+pub struct PipeReader(Handle);
+
+impl PipeReader {
+    pub fn new_from_handle(handle: Handle) -> PipeReader {
+        PipeReader(handle)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.0
+    }
+
+    /// Give up ownership of the read end, without closing it.
+    pub fn into_handle(self) -> Handle {
+        let handle = self.0;
+        core::mem::forget(self);
+        handle
+    }
+
+    /// Read from the pipe, if there's data waiting to be read. Returns `Ok(None)` if the pipe is empty, and
+    /// `Ok(Some(0))` once the write end has been closed and everything written has been read.
+    pub fn try_read(&self, buffer: &mut [u8]) -> Result<Option<usize>, PipeReadError> {
+        match syscall::pipe_read(self.0, buffer, false) {
+            Ok(length) => Ok(Some(length)),
+            Err(PipeReadError::WouldBlock) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Read from the pipe, blocking until there's data to read (or the write end has been closed).
+    pub fn read_blocking(&self, buffer: &mut [u8]) -> Result<usize, PipeReadError> {
+        syscall::pipe_read(self.0, buffer, true)
+    }
+
+    /// Read from the pipe, waiting until there's data to read (or the write end has been closed).
+    pub fn read<'a>(&'a self, buffer: &'a mut [u8]) -> impl Future<Output = Result<usize, PipeReadError>> + 'a {
+        core::future::poll_fn(move |context| match self.try_read(buffer) {
+            Ok(Some(length)) => Poll::Ready(Ok(length)),
+            Ok(None) => {
+                crate::rt::RUNTIME.get().reactor.lock().register(
+                    self.0,
+                    Signals::READABLE | Signals::PEER_CLOSED,
+                    context.waker().clone(),
+                );
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        })
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let _ = syscall::handle_close(self.0);
+    }
+}
+
+pub struct PipeWriter(Handle);
+
+impl PipeWriter {
+    pub fn new_from_handle(handle: Handle) -> PipeWriter {
+        PipeWriter(handle)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.0
+    }
+
+    /// Give up ownership of the write end, without closing it.
+    pub fn into_handle(self) -> Handle {
+        let handle = self.0;
+        core::mem::forget(self);
+        handle
+    }
+
+    /// Write as much of `data` as there's space for in the pipe, returning the number of bytes written. Returns
+    /// `Ok(None)` if the pipe is full.
+    pub fn try_write(&self, data: &[u8]) -> Result<Option<usize>, PipeWriteError> {
+        match syscall::pipe_write(self.0, data, false) {
+            Ok(length) => Ok(Some(length)),
+            Err(PipeWriteError::WouldBlock) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Write all of `data` to the pipe, blocking whenever it's full until the reader makes space.
+    pub fn write_all_blocking(&self, mut data: &[u8]) -> Result<(), PipeWriteError> {
+        while !data.is_empty() {
+            let written = syscall::pipe_write(self.0, data, true)?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+
+    /// Write all of `data` to the pipe, waiting whenever it's full until the reader makes space.
+    pub async fn write_all(&self, mut data: &[u8]) -> Result<(), PipeWriteError> {
+        while !data.is_empty() {
+            let written = core::future::poll_fn(|context| match self.try_write(data) {
+                Ok(Some(written)) => Poll::Ready(Ok(written)),
+                Ok(None) => {
+                    crate::rt::RUNTIME.get().reactor.lock().register(
+                        self.0,
+                        Signals::WRITABLE | Signals::PEER_CLOSED,
+                        context.waker().clone(),
+                    );
+                    Poll::Pending
+                }
+                Err(err) => Poll::Ready(Err(err)),
+            })
+            .await?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let _ = syscall::handle_close(self.0);
+    }
+}
@@ -0,0 +1,63 @@
+//! The kernel has a sampling profiler, which is driven by each CPU's timer interrupt. While profiling is enabled,
+//! each tick records what the CPU was doing when it was interrupted - the task that was running, the interrupted
+//! instruction, and the stack of return addresses above it - into a ring buffer for the CPU. The stack is walked
+//! using frame pointers, and user stacks are only walked if asked for with `set_profiling`. A task with the
+//! `TRACE` capability can get read-only `MemoryObject`s for the buffers with `get_profile_buffers`.
+//!
+//! Each buffer starts with a `ProfileBufferHeader`, followed by space for `PROFILE_BUFFER_SAMPLES` samples. Like
+//! the trace buffers, samples are numbered by how many have ever been written to the buffer, and sample `n` is
+//! kept at index `n % PROFILE_BUFFER_SAMPLES` until it's overwritten. The buffers are much smaller than the number
+//! of samples taken over a typical profile, so they should be read regularly while profiling.
+
+use core::mem;
+
+/// The size of each CPU's profile buffer, in bytes.
+pub const PROFILE_BUFFER_SIZE: usize = 0x40000;
+/// The offset of the first sample from the start of a profile buffer.
+pub const PROFILE_SAMPLES_OFFSET: usize = 64;
+pub const PROFILE_BUFFER_SAMPLES: usize =
+    (PROFILE_BUFFER_SIZE - PROFILE_SAMPLES_OFFSET) / mem::size_of::<ProfileSample>();
+/// The most frames recorded in a sample. Frames beyond this (the outermost) are dropped.
+pub const PROFILE_MAX_FRAMES: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ProfileBufferHeader {
+    /// The number of samples that have ever been written to the buffer. This is updated after each sample has
+    /// been written.
+    pub head: u64,
+    /// The ID of the CPU the buffer belongs to.
+    pub cpu: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ProfileSample {
+    /// When the sample was taken, in nanoseconds since boot (from `clock_monotonic`).
+    pub timestamp: u64,
+    /// The ID of the task that was running, or `0` if the CPU was idle.
+    pub task: u64,
+    /// The start of the name of the task, packed in the same way as in a `TaskName` trace record (see
+    /// `poplar::trace::pack_task_name`).
+    pub task_name: [u64; 2],
+    pub cpu: u32,
+    /// The number of frames that are in the kernel. These come first in `frames`.
+    pub num_kernel_frames: u16,
+    /// The number of frames that are in the task. These come after the kernel's frames.
+    pub num_user_frames: u16,
+    /// The kernel's frames, and then the task's, each starting with the innermost. The first frame of the
+    /// interrupted context is the address of the interrupted instruction, and the rest are return addresses.
+    /// The kernel's frames are at the addresses it's linked at, rather than where it has been loaded.
+    pub frames: [u64; PROFILE_MAX_FRAMES],
+}
+
+impl ProfileSample {
+    pub fn kernel_frames(&self) -> &[u64] {
+        &self.frames[0..self.num_kernel_frames as usize]
+    }
+
+    pub fn user_frames(&self) -> &[u64] {
+        let start = self.num_kernel_frames as usize;
+        &self.frames[start..(start + self.num_user_frames as usize)]
+    }
+}
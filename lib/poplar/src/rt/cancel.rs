@@ -0,0 +1,135 @@
+//! Cooperative cancellation. A `CancellationToken` is shared between the code that decides when some work should
+//! stop, and the tasks doing the work, which observe the token and finish up cleanly when it's cancelled (unlike
+//! `JoinHandle::abort`, which stops a task wherever it's waiting).
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+use spinning_top::Spinlock;
+
+#[derive(Clone)]
+pub struct CancellationToken(Arc<Inner>);
+
+struct Inner {
+    cancelled: AtomicBool,
+    state: Spinlock<State>,
+}
+
+struct State {
+    /// The wakers of the futures waiting for the token to be cancelled.
+    wakers: Vec<Waker>,
+    /// Tokens created with `child_token`, which are cancelled along with this one.
+    children: Vec<Weak<Inner>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken(Arc::new(Inner {
+            cancelled: AtomicBool::new(false),
+            state: Spinlock::new(State { wakers: Vec::new(), children: Vec::new() }),
+        }))
+    }
+
+    /// Create a token that is cancelled when this one is, but that can also be cancelled by itself without
+    /// affecting this one. This is useful for stopping part of a larger piece of work.
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        let mut state = self.0.state.lock();
+        if self.is_cancelled() {
+            child.cancel();
+        } else {
+            state.children.retain(|child| child.strong_count() > 0);
+            state.children.push(Arc::downgrade(&child.0));
+        }
+        child
+    }
+
+    /// Cancel the token, and all of its children. Futures waiting for it to be cancelled are woken.
+    pub fn cancel(&self) {
+        /*
+         * The flag is set with the lock held, so a future can't check it and then register its waker after we've
+         * woken the others.
+         */
+        let (wakers, children) = {
+            let mut state = self.0.state.lock();
+            if self.0.cancelled.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            (core::mem::take(&mut state.wakers), core::mem::take(&mut state.children))
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
+        for child in children.iter().filter_map(Weak::upgrade) {
+            CancellationToken(child).cancel();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the token is cancelled.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + '_ {
+        core::future::poll_fn(
+            |context| {
+                if self.register(context.waker()) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            },
+        )
+    }
+
+    /// Run `future` until it completes, or until the token is cancelled, in which case `future` is dropped and
+    /// this resolves to `None`.
+    pub async fn run_until_cancelled<F>(&self, future: F) -> Option<F::Output>
+    where
+        F: Future,
+    {
+        let mut future = pin!(future);
+        core::future::poll_fn(|context| {
+            if self.is_cancelled() {
+                return Poll::Ready(None);
+            }
+            if let Poll::Ready(output) = future.as_mut().poll(context) {
+                return Poll::Ready(Some(output));
+            }
+            if self.register(context.waker()) {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Register `waker` to be woken when the token is cancelled. Returns `true` if it has already been cancelled.
+    fn register(&self, waker: &Waker) -> bool {
+        let mut state = self.0.state.lock();
+        if self.is_cancelled() {
+            return true;
+        }
+
+        // Futures register their waker each time they're polled, so only keep one for each of them
+        if !state.wakers.iter().any(|other| other.will_wake(waker)) {
+            state.wakers.push(waker.clone());
+        }
+        false
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> CancellationToken {
+        CancellationToken::new()
+    }
+}
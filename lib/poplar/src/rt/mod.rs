@@ -1,44 +1,343 @@
 //! Poplar's `async` runtime. This provides an executor based on
 //! [`maitake`](https://github.com/hawkw/mycelium/tree/main/maitake) and a reactor compatible with
 //! Poplar's system call layer.
+//!
+//! The executor can run tasks on several worker threads. Each worker has its own run queue, and workers that run
+//! out of tasks steal them from the runtime's global queue, and then from the queues of other workers. By default,
+//! the runtime only has a single worker - the thread that calls `enter_loop`. More can be configured with
+//! `Builder::worker_threads`.
+//!
+//! Tasks can be stopped by aborting them through their `JoinHandle`s, or cooperatively with a
+//! `CancellationToken`. The whole runtime can be shut down with `shutdown`, which gives its tasks a chance to
+//! finish before aborting them, after which `enter_loop` returns. This allows a task (such as a driver) to be
+//! restarted cleanly.
 
+mod cancel;
 mod reactor;
+mod select;
+mod task;
+pub mod time;
 
+pub use cancel::CancellationToken;
 pub use maitake;
+pub use select::{select, Select};
+pub use task::{JoinError, JoinHandle};
 
-use self::reactor::Reactor;
-use core::future::Future;
-use maitake::{scheduler::Scheduler, task::JoinHandle};
+use self::{reactor::Reactor, task::AbortState};
+use crate::{
+    syscall::{self, ObjectWaitManyError, OBJECT_WAIT_MANY_MAX_ITEMS},
+    time::Instant,
+};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use maitake::{
+    scheduler::{Injector, Scheduler},
+    time::{Timer, Turn},
+};
 use mulch::InitGuard;
 use spinning_top::Spinlock;
 
-// TODO: if we want support for multiple tasks in an address space, this needs to be thread-local
+/// The runtime is shared by all of the task's threads, so tasks can be spawned from any of them.
 pub(crate) static RUNTIME: InitGuard<Runtime> = InitGuard::uninit();
 
+/// The longest a worker waits in the kernel when it has nothing to do. Tasks can be woken by other threads
+/// without the kernel knowing about it, so workers check for work at least this often even if nothing they're
+/// waiting on happens.
+const MAX_IDLE_TIME: Duration = Duration::from_millis(10);
+
+/// The index of the worker the current thread is running, if it's one of the runtime's worker threads.
+#[thread_local]
+static CURRENT_WORKER: Cell<Option<usize>> = Cell::new(None);
+
 pub struct Runtime {
-    scheduler: Scheduler,
-    // TODO: maintain a timer wheel so time-based futures work in userspace
+    workers: Vec<Worker>,
+    /// Tasks spawned from threads that aren't workers are put in this queue, and picked up by the first worker
+    /// to run out of work.
+    injector: Injector<Scheduler>,
     pub reactor: Spinlock<Reactor>,
+    /// Drives time-based futures (see the `time` module). This is advanced using the kernel's
+    /// uptime each time around the runtime's loop.
+    pub timer: Timer,
+    /// The kernel's uptime when `timer` was last advanced.
+    last_uptime: Spinlock<Duration>,
+    /// The tasks that haven't yet completed (or been aborted), by ID. The runtime uses this to abort them all
+    /// when it shuts down.
+    tasks: Spinlock<BTreeMap<u64, Arc<AbortState>>>,
+    next_task_id: AtomicU64,
+    /// Cancelled when the runtime starts to shut down. See `shutdown_token`.
+    shutdown_token: CancellationToken,
+    /// Once the runtime has been asked to shut down, the time after which any tasks that are still running are
+    /// aborted.
+    shutdown_deadline: Spinlock<Option<Instant>>,
+}
+
+struct Worker {
+    /// Tasks spawned on this worker. Other workers steal from this queue when they run out of tasks.
+    scheduler: Scheduler,
+    /// Tasks that have been pinned to this worker with `spawn_local`. These are never stolen by other workers.
+    local: Scheduler,
 }
 
+impl Runtime {
+    fn advance_timer(&self) -> Turn {
+        let uptime = syscall::get_uptime();
+        let mut last_uptime = self.last_uptime.lock();
+        let turn = self.timer.force_advance(uptime.saturating_sub(*last_uptime));
+        *last_uptime = uptime;
+        turn
+    }
+
+    /// Run tasks on the calling thread, as the worker at `index`, until the runtime has shut down.
+    fn run_worker(&self, index: usize) {
+        CURRENT_WORKER.set(Some(index));
+        let worker = &self.workers[index];
+
+        loop {
+            self.advance_timer();
+            /*
+             * The reactor polls every object any task is interested in, so only one worker needs to poll it each
+             * time around. The others can get on with running tasks.
+             */
+            if let Some(mut reactor) = self.reactor.try_lock() {
+                reactor.poll();
+            }
+
+            let local = worker.local.tick();
+            let tick = worker.scheduler.tick();
+
+            if let Some(deadline) = *self.shutdown_deadline.lock() {
+                if self.tasks.lock().is_empty() {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    self.abort_all();
+                }
+            }
+
+            if local.has_remaining || tick.has_remaining || self.steal(index) {
+                syscall::yield_to_kernel();
+            } else {
+                self.idle();
+            }
+        }
+
+        CURRENT_WORKER.set(None);
+    }
+
+    fn spawn_task<F>(&self, future: F, spawn: impl FnOnce(task::TaskFuture<F>)) -> JoinHandle<F::Output>
+    where
+        F: Future,
+    {
+        let id = self.next_task_id.fetch_add(1, Ordering::Relaxed);
+        let (task, abort, handle) = task::new_task(future, id);
+        self.tasks.lock().insert(id, abort);
+        spawn(task);
+        handle
+    }
+
+    fn abort_all(&self) {
+        /*
+         * Aborted tasks remove themselves from `tasks` when they're dropped, which could happen as soon as
+         * they're woken, so we can't hold the lock while we abort them.
+         */
+        let tasks: Vec<Arc<AbortState>> = self.tasks.lock().values().cloned().collect();
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    /// Called when a worker has nothing to do. Instead of spinning until a task is woken, the worker blocks in
+    /// the kernel until one of the objects the runtime's tasks are waiting on is signalled, or until the next
+    /// timer is due to fire.
+    fn idle(&self) {
+        let turn = self.advance_timer();
+        if turn.expired() > 0 {
+            return;
+        }
+        let timeout = match turn.time_to_next_deadline() {
+            Some(deadline) => Duration::min(deadline, MAX_IDLE_TIME),
+            None => MAX_IDLE_TIME,
+        };
+
+        /*
+         * We don't hold the reactor's lock while we're blocked, so tasks on other workers can still register
+         * their interests. Objects that are signalled while we're blocked are picked up by the next poll of the
+         * reactor, so we don't care which objects woke us (or whether the wait timed out).
+         */
+        let mut items = self.reactor.lock().wait_items();
+        items.truncate(OBJECT_WAIT_MANY_MAX_ITEMS);
+        match syscall::object_wait_many(&mut items, true, Some(timeout)) {
+            Ok(()) | Err(ObjectWaitManyError::TimedOut) => (),
+            // A handle may have been closed since its interest was registered, which is dealt with when the
+            // reactor is next polled
+            Err(ObjectWaitManyError::InvalidHandle) => (),
+            Err(err) => panic!("Error waiting for kernel objects: {:?}", err),
+        }
+    }
+
+    /// Steal tasks for the worker at `index`, which has run out of work. Tasks are taken from the global queue if
+    /// there are any, and otherwise from the first other worker that has tasks to spare. Half of the tasks in the
+    /// queue are taken, so work is spread between the workers quickly. Returns `true` if any tasks were stolen.
+    fn steal(&self, index: usize) -> bool {
+        let worker = &self.workers[index];
+
+        if let Ok(stealer) = self.injector.try_steal() {
+            if stealer.spawn_half(&worker.scheduler) > 0 {
+                return true;
+            }
+        }
+
+        let others = self.workers.iter().cycle().skip(index + 1).take(self.workers.len() - 1);
+        for other in others {
+            if let Ok(stealer) = other.scheduler.try_steal() {
+                if stealer.spawn_half(&worker.scheduler) > 0 {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+/// Configures the runtime before it's initialized. `init_runtime` can be used instead for the default
+/// configuration.
+pub struct Builder {
+    worker_threads: usize,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder { worker_threads: 1 }
+    }
+
+    /// Run tasks on `count` worker threads. The thread that calls `enter_loop` becomes the first worker, and the
+    /// others are created when the runtime is initialized. The default is a single worker.
+    pub fn worker_threads(mut self, count: usize) -> Builder {
+        assert!(count > 0, "Runtime must have at least one worker thread");
+        self.worker_threads = count;
+        self
+    }
+
+    pub fn init(self) {
+        RUNTIME.initialize(Runtime {
+            workers: (0..self.worker_threads)
+                .map(|_| Worker { scheduler: Scheduler::new(), local: Scheduler::new() })
+                .collect(),
+            injector: Injector::new(),
+            reactor: Spinlock::new(Reactor::new()),
+            timer: Timer::new(Duration::from_millis(1)),
+            last_uptime: Spinlock::new(syscall::get_uptime()),
+            tasks: Spinlock::new(BTreeMap::new()),
+            next_task_id: AtomicU64::new(0),
+            shutdown_token: CancellationToken::new(),
+            shutdown_deadline: Spinlock::new(None),
+        });
+        maitake::time::set_global_timer(&RUNTIME.get().timer).unwrap();
+
+        for index in 1..self.worker_threads {
+            crate::thread::spawn_raw(Box::new(move || RUNTIME.get().run_worker(index)))
+                .expect("Failed to create runtime worker thread");
+        }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+/// Initialize the runtime with the default configuration, which runs tasks on a single worker thread.
 pub fn init_runtime() {
-    RUNTIME.initialize(Runtime { scheduler: Scheduler::new(), reactor: Spinlock::new(Reactor::new()) });
+    Builder::new().init();
 }
 
+/// Run the runtime's tasks on the calling thread, which becomes the runtime's first worker. This only returns once
+/// the runtime has been shut down with `shutdown`, and all of its tasks have completed or been aborted.
 pub fn enter_loop() {
-    loop {
-        crate::syscall::yield_to_kernel();
+    RUNTIME.get().run_worker(0)
+}
 
-        let runtime = RUNTIME.get();
-        runtime.reactor.lock().poll();
-        runtime.scheduler.tick();
+/// Start shutting the runtime down. The runtime's shutdown token (see `shutdown_token`) is cancelled, so tasks
+/// observing it can finish what they're doing, and the runtime keeps running its tasks until they've all
+/// completed. Any that are still running after `grace_period` are aborted. Once there are no tasks left, the
+/// workers stop, and `enter_loop` returns.
+pub fn shutdown(grace_period: Duration) {
+    let runtime = RUNTIME.get();
+    {
+        let mut deadline = runtime.shutdown_deadline.lock();
+        if deadline.is_some() {
+            return;
+        }
+        *deadline = Some(Instant::now() + grace_period);
     }
+    runtime.shutdown_token.cancel();
+}
+
+/// Get a token that is cancelled when the runtime starts to shut down. Long-running tasks (such as those serving a
+/// channel) should observe this, or a child of it, so they can stop cleanly.
+pub fn shutdown_token() -> CancellationToken {
+    RUNTIME.get().shutdown_token.clone()
 }
 
+/// Spawn a task onto the runtime. Tasks spawned from one of the runtime's workers are queued on that worker, and
+/// others are queued globally. Either way, the task may be stolen by another worker and run there.
 pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
 where
     F: Future + Send + 'static,
     F::Output: Send + 'static,
 {
-    RUNTIME.get().scheduler.spawn(future)
+    let runtime = RUNTIME.get();
+    runtime.spawn_task(future, |task| {
+        // We don't need maitake's handle to the task, as the output is passed back through our own `JoinHandle`
+        let _ = match CURRENT_WORKER.get() {
+            Some(index) => runtime.workers[index].scheduler.spawn(task),
+            None => runtime.injector.spawn(task),
+        };
+    })
+}
+
+/// Spawn a task that is pinned to the current worker, so it's always polled on this thread. Unlike with
+/// `spawn`, the future doesn't need to be `Send`. This panics if the calling thread isn't one of the runtime's
+/// workers (i.e. it should be called from within another task).
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: Send + 'static,
+{
+    let index = CURRENT_WORKER.get().expect("`spawn_local` called from outside the runtime");
+    let runtime = RUNTIME.get();
+    runtime.spawn_task(LocalFuture(future), |task| {
+        let _ = runtime.workers[index].local.spawn(task);
+    })
+}
+
+/// A future that is only ever polled by the worker it was spawned on. See `spawn_local`.
+struct LocalFuture<F>(F);
+
+/*
+ * SAFETY: local tasks are spawned onto a worker's `local` scheduler, which is only ever ticked by that worker's
+ * thread and never has tasks stolen from it, so the future is never polled (or dropped) on any other thread.
+ * Wakers can be sent to other threads, but waking a task only queues it to be polled by its scheduler.
+ */
+unsafe impl<F> Send for LocalFuture<F> {}
+
+impl<F> Future for LocalFuture<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: we never move the inner future
+        unsafe { self.map_unchecked_mut(|future| &mut future.0) }.poll(context)
+    }
 }
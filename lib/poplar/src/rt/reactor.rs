@@ -1,34 +1,96 @@
-use crate::Handle;
-use alloc::{collections::BTreeMap, vec::Vec};
-use core::task::Waker;
+use crate::{
+    syscall::{self, ObjectWaitManyError, Signals, WaitItem, OBJECT_WAIT_MANY_MAX_ITEMS},
+    Handle,
+};
+use alloc::{vec, vec::Vec};
+use core::{slice, task::Waker};
 
 /// The `Reactor` is a component of the Poplar userspace async runtime that processes events from
 /// kernel objects in order to wake futures when they have work to do.
 pub struct Reactor {
-    interests: BTreeMap<Handle, Waker>,
+    interests: Vec<Interest>,
+}
+
+struct Interest {
+    handle: Handle,
+    signals: Signals,
+    waker: Waker,
 }
 
 impl Reactor {
     pub fn new() -> Reactor {
-        Reactor { interests: BTreeMap::new() }
+        Reactor { interests: Vec::new() }
+    }
+
+    /// Register interest in any of `signals` being asserted on the object `handle` refers to. `waker` is woken
+    /// the next time the reactor is polled after this happens.
+    pub fn register(&mut self, handle: Handle, signals: Signals, waker: Waker) {
+        /*
+         * Futures re-register each time they're polled, so check if this future is already
+         * waiting on this object.
+         */
+        match self
+            .interests
+            .iter_mut()
+            .find(|interest| interest.handle == handle && interest.waker.will_wake(&waker))
+        {
+            Some(interest) => interest.signals = signals,
+            None => self.interests.push(Interest { handle, signals, waker }),
+        }
     }
 
-    pub fn register(&mut self, handle: Handle, waker: Waker) {
-        self.interests.insert(handle, waker);
+    /// Remove the interest `waker` has registered in the object `handle` refers to, if it has one. Futures should
+    /// do this if they stop waiting on an object before it's signalled, as they might not wait on it again.
+    pub fn deregister(&mut self, handle: Handle, waker: &Waker) {
+        self.interests.retain(|interest| !(interest.handle == handle && interest.waker.will_wake(waker)));
+    }
+
+    /// The objects that tasks are waiting on, and the signals they're waiting for.
+    pub fn wait_items(&self) -> Vec<WaitItem> {
+        self.interests.iter().map(|interest| WaitItem::new(interest.handle, interest.signals)).collect()
     }
 
     pub fn poll(&mut self) {
+        let mut items = self.wait_items();
+
         /*
-         * Make a copy of the current list of handles we're interested in. We do this so we can
-         * later remove events that have been awoken.
+         * Poll all the objects we're interested in, in as few system calls as we can. The kernel
+         * writes back the signals asserted on each object, so we don't care whether it thinks
+         * the wait was successful.
          */
-        let handles: Vec<Handle> = self.interests.keys().copied().collect();
-
-        for handle in handles {
-            if crate::syscall::poll_interest(handle).unwrap() {
-                let waker = self.interests.remove(&handle).unwrap();
-                waker.wake();
+        let mut invalid = vec![false; items.len()];
+        for (chunk, invalid) in
+            items.chunks_mut(OBJECT_WAIT_MANY_MAX_ITEMS).zip(invalid.chunks_mut(OBJECT_WAIT_MANY_MAX_ITEMS))
+        {
+            match syscall::object_wait_many(chunk, false, None) {
+                Ok(()) | Err(ObjectWaitManyError::NoSignals) => (),
+                /*
+                 * One of the handles has been closed since its interest was registered, which fails the whole
+                 * chunk. Poll its objects one at a time to find out which, and wake the futures waiting on
+                 * them, so they can see the error for themselves.
+                 */
+                Err(ObjectWaitManyError::InvalidHandle) => {
+                    for (item, invalid) in chunk.iter_mut().zip(invalid.iter_mut()) {
+                        match syscall::object_wait_many(slice::from_mut(item), false, None) {
+                            Ok(()) | Err(ObjectWaitManyError::NoSignals) => (),
+                            Err(ObjectWaitManyError::InvalidHandle) => *invalid = true,
+                            Err(err) => panic!("Error polling kernel object: {:?}", err),
+                        }
+                    }
+                }
+                Err(err) => panic!("Error polling kernel objects: {:?}", err),
             }
         }
+
+        let mut items = items.into_iter().zip(invalid);
+        self.interests.retain(|interest| {
+            let (item, invalid) = items.next().unwrap();
+            if item.is_satisfied() || invalid {
+                interest.waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
     }
 }
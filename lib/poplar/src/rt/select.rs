@@ -0,0 +1,71 @@
+use super::RUNTIME;
+use crate::syscall::{self, ObjectWaitManyError, WaitItem};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Wait until any of the kernel objects in `items` have one of the signals they're being waited on for
+/// asserted, resolving to the index of the first such item. The signals asserted on every object are written
+/// back to `items`, so the caller can check whether more than one of them is ready.
+///
+/// This is the async counterpart of `syscall::object_wait_many`, and allows a single task to wait on a
+/// set of `Channel`s and `Event`s at once.
+pub fn select<'a>(items: &'a mut [WaitItem]) -> Select<'a> {
+    Select { items, waker: None }
+}
+
+/// The future returned by `select`. Its interest in each of the objects is registered with the reactor while
+/// it's waiting, and removed again when it resolves or is dropped - otherwise, the reactor would keep polling
+/// objects nothing is waiting on, which may since have been closed.
+pub struct Select<'a> {
+    items: &'a mut [WaitItem],
+    /// The waker the interests are currently registered with, if they are.
+    waker: Option<Waker>,
+}
+
+impl Select<'_> {
+    fn deregister(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            let mut reactor = RUNTIME.get().reactor.lock();
+            for item in self.items.iter() {
+                reactor.deregister(item.handle(), &waker);
+            }
+        }
+    }
+}
+
+impl Future for Select<'_> {
+    type Output = usize;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<usize> {
+        let this = &mut *self;
+        match syscall::object_wait_many(this.items, false, None) {
+            Ok(()) => {
+                this.deregister();
+                Poll::Ready(this.items.iter().position(|item| item.is_satisfied()).unwrap())
+            }
+            Err(ObjectWaitManyError::NoSignals) => {
+                // If we've been moved to another task, the interests registered with the old waker are stale
+                if this.waker.as_ref().is_some_and(|waker| !waker.will_wake(context.waker())) {
+                    this.deregister();
+                }
+
+                let mut reactor = RUNTIME.get().reactor.lock();
+                for item in this.items.iter() {
+                    reactor.register(item.handle(), item.signals(), context.waker().clone());
+                }
+                this.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+            Err(err) => panic!("Error waiting on kernel objects: {:?}", err),
+        }
+    }
+}
+
+impl Drop for Select<'_> {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
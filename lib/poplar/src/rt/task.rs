@@ -0,0 +1,153 @@
+//! Handles to the tasks spawned onto the runtime. Each task is wrapped in a `TaskFuture`, which lets it be aborted
+//! through its `JoinHandle` (or by the runtime shutting down), and passes its output back to the `JoinHandle`.
+
+use super::RUNTIME;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spinning_top::Spinlock;
+
+/// An owned handle to a task spawned onto the runtime. It can be awaited to get the task's output, or used to
+/// abort the task. Dropping the handle detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    abort: Arc<AbortState>,
+    packet: Arc<Spinlock<Packet<T>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JoinError {
+    /// The task was aborted, either with `JoinHandle::abort` or because the runtime was shut down, before it
+    /// completed.
+    Aborted,
+}
+
+impl<T> JoinHandle<T> {
+    /// Abort the task. It's dropped the next time the runtime gets round to it, without being polled again, so it
+    /// stops at whichever `await` it's waiting at. Awaiting the handle then resolves to `JoinError::Aborted`
+    /// (unless the task had already completed). For tasks that need to finish up cleanly, use a
+    /// `CancellationToken` instead.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// Whether the task has completed, or been aborted.
+    pub fn is_finished(&self) -> bool {
+        self.packet.lock().result.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut packet = self.packet.lock();
+        match packet.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                packet.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Shared between a task and the runtime, so the runtime can abort every task when it shuts down.
+pub(super) struct AbortState {
+    aborted: AtomicBool,
+    /// The waker of the task, so it can be woken when it's aborted.
+    waker: Spinlock<Option<Waker>>,
+}
+
+impl AbortState {
+    pub(super) fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Where a task's output is put for its `JoinHandle` to pick up.
+struct Packet<T> {
+    result: Option<Result<T, JoinError>>,
+    /// The waker of the future awaiting the `JoinHandle`, if there is one.
+    waker: Option<Waker>,
+}
+
+impl<T> Packet<T> {
+    fn complete(&mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The future that is actually spawned onto the runtime's schedulers for each task.
+pub(super) struct TaskFuture<F>
+where
+    F: Future,
+{
+    future: F,
+    id: u64,
+    abort: Arc<AbortState>,
+    packet: Arc<Spinlock<Packet<F::Output>>>,
+    finished: bool,
+}
+
+/// Wrap `future` so it can be spawned as task `id`. Returns the future to spawn, the state the runtime needs to
+/// abort the task, and the task's `JoinHandle`.
+pub(super) fn new_task<F>(future: F, id: u64) -> (TaskFuture<F>, Arc<AbortState>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let abort = Arc::new(AbortState { aborted: AtomicBool::new(false), waker: Spinlock::new(None) });
+    let packet = Arc::new(Spinlock::new(Packet { result: None, waker: None }));
+    let task = TaskFuture { future, id, abort: abort.clone(), packet: packet.clone(), finished: false };
+    (task, abort.clone(), JoinHandle { abort, packet })
+}
+
+impl<F> Future for TaskFuture<F>
+where
+    F: Future,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        // SAFETY: `future` is never moved out of the `TaskFuture`
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.abort.aborted.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        *this.abort.waker.lock() = Some(context.waker().clone());
+
+        match unsafe { Pin::new_unchecked(&mut this.future) }.poll(context) {
+            Poll::Ready(output) => {
+                this.finished = true;
+                this.packet.lock().complete(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F> Drop for TaskFuture<F>
+where
+    F: Future,
+{
+    fn drop(&mut self) {
+        /*
+         * Tasks are dropped once they complete. If this one hasn't, it's been aborted (or the scheduler it was
+         * spawned on has been dropped), so let its `JoinHandle` know it's not going to complete.
+         */
+        if !self.finished {
+            self.packet.lock().complete(Err(JoinError::Aborted));
+        }
+        RUNTIME.get().tasks.lock().remove(&self.id);
+    }
+}
@@ -0,0 +1,60 @@
+//! Futures for waiting on time to pass within the async runtime. These are driven by the runtime's timer, and
+//! so have the granularity of the kernel's timer.
+
+use super::RUNTIME;
+use crate::time::Instant;
+use core::{future::Future, time::Duration};
+use maitake::time::{Sleep, Timeout};
+
+/// Wait until `duration` has elapsed.
+pub fn sleep(duration: Duration) -> Sleep<'static> {
+    RUNTIME.get().timer.sleep(duration)
+}
+
+/// Wait for `future` to complete, giving up if it hasn't done so after `duration`. The returned future resolves
+/// to `Err` if the timeout elapsed first.
+pub fn timeout<F>(duration: Duration, future: F) -> Timeout<'static, F>
+where
+    F: Future,
+{
+    RUNTIME.get().timer.timeout(duration, future)
+}
+
+/// Create an `Interval` that ticks every `period`. The first tick completes immediately.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "Interval period must be non-zero");
+    Interval { period, next: Instant::now() }
+}
+
+/// Ticks at a fixed rate, created with `interval`. Unlike sleeping for `period` in a loop, the time spent between
+/// ticks doesn't cause the ticks to drift. If ticks are missed (e.g. because the task took longer than `period`
+/// to get round to waiting for the next one), they're skipped, rather than all completing at once.
+pub struct Interval {
+    period: Duration,
+    /// When the next tick is due.
+    next: Instant,
+}
+
+impl Interval {
+    /// Wait for the next tick. Returns the time the tick was due.
+    pub async fn tick(&mut self) -> Instant {
+        let now = Instant::now();
+        if self.next > now {
+            sleep(self.next - now).await;
+        }
+
+        let tick = self.next;
+        let missed = (Instant::now() - tick).as_nanos() / self.period.as_nanos();
+        self.next = tick + self.period * (missed as u32 + 1);
+        tick
+    }
+
+    /// Start the interval again, so the next tick is due a whole `period` from now.
+    pub fn reset(&mut self) {
+        self.next = Instant::now() + self.period;
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
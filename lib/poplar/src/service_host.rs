@@ -0,0 +1,203 @@
+//! Tasks find each other through `service_host`, the bootstrap task. Each task it starts is created with a channel
+//! to it, over which the task can register services, subscribe to the services of other tasks, ask for resources
+//! it has been given by the kernel, and spawn new tasks. This is the client side of that protocol, which lives
+//! here (rather than in `service_host` itself) so that `std` can use it to reach the VFS and the network stack.
+
+use crate::{caps::Capabilities, channel::Channel, syscall::Stdio, Handle};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use ptah::{Deserialize, DeserializeOwned, Serialize};
+
+/// The name tasks use to request the channel to the VFS that sees their filesystem namespace with
+/// `RequestResource`. This is only available to tasks that were spawned with a namespace, and is usually requested
+/// through `file::namespace`.
+pub const NAMESPACE_RESOURCE: &str = "namespace";
+
+/// Check if the service called `name` matches `pattern`. Tasks are given the services they can subscribe to as a list
+/// of patterns, each of which is either the full name of a service, or a prefix followed by `*`, which matches every
+/// service whose name starts with the prefix (e.g. `platform_bus.*` matches `platform_bus.device_driver`).
+///
+/// As `*` is only allowed at the end of a pattern, this can also check if one pattern allows everything another does,
+/// by passing the second pattern as `name`.
+pub fn service_pattern_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// What a task spawned through `service_host` is allowed to do. A task can't give the tasks it spawns permissions
+/// it doesn't have itself.
+#[derive(Clone, PartialEq, Default, Debug, Serialize, Deserialize)]
+pub struct TaskPermissions {
+    /// The capabilities the task is created with, as the bits of a `Capabilities`.
+    pub capabilities: u32,
+    /// Patterns matching the services the task can subscribe to (see `service_pattern_matches`).
+    pub services: Vec<String>,
+    /// A channel to the VFS that sees the filesystem namespace the task is given (see `Vfs::create_namespace`).
+    /// Tasks spawned without one can't access any files, as only boot tasks can subscribe to the `vfs` service.
+    /// The namespace can only contain files the spawning task can see, so it doesn't need to be checked.
+    pub namespace: Option<Handle>,
+}
+
+impl TaskPermissions {
+    pub fn new(capabilities: Capabilities, services: Vec<String>) -> TaskPermissions {
+        TaskPermissions { capabilities: capabilities.bits(), services, namespace: None }
+    }
+}
+
+/// A request sent by a client task to `service_host`
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ServiceHostRequest {
+    RegisterService {
+        name: String,
+    },
+    SubscribeService(String),
+    // TODO: should this be typed, stringy, or something else?
+    RequestResource(String),
+    /// Spawn a new task from an ELF image held in a `MemoryObject`. The new task can get `arguments` with
+    /// `std::env::args`, and is created with the channels in `stdio` as its standard handles (see
+    /// `std::poplar::stdio`).
+    SpawnTask {
+        name: String,
+        image: Handle,
+        image_size: usize,
+        arguments: Vec<String>,
+        stdio: Stdio,
+        permissions: TaskPermissions,
+    },
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ServiceHostResponse {
+    ServiceRegistered(Handle),
+    SubscribedToService(Handle),
+    NoSuchService,
+    /// The task is not allowed to subscribe to the service.
+    ServiceRefused,
+    Resource(Handle),
+    /// A resource that is a `MemoryObject`, along with the size of the data it holds.
+    MemoryResource {
+        handle: Handle,
+        size: usize,
+    },
+    ResourceRefused,
+    /// The task was spawned. The handle to it has the `READ` and `WRITE` rights, so it can be used to wait for
+    /// the task to exit, and to handle its exceptions.
+    TaskSpawned(Handle),
+    SpawnTaskFailed,
+}
+
+/// A message sent by `service_host` to a service provider when another task subscribes to a
+/// service.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ServiceChannelMessage {
+    NewClient { name: String, channel: Handle },
+}
+
+/// Represents a channel connected to `service_host` for a client task to make requests through.
+pub struct ServiceHostClient {
+    channel: Channel<ServiceHostRequest, ServiceHostResponse>,
+}
+
+impl ServiceHostClient {
+    /// Find the channel to `service_host` and create a `ServiceHostClient`.
+    // TODO: how should we find the right handle from a random task? Manifest? Just guess?
+    pub fn new() -> ServiceHostClient {
+        /*
+         * TODO: this is very janky for now, but we basically abuse our knowledge of how
+         * `service_host` constructs tasks for now. We only add the address space and the task
+         * channel to the handle set for now, so we know the channel is going to be handle `2`.
+         */
+        let channel = Channel::new_from_handle(Handle(2));
+        ServiceHostClient { channel }
+    }
+
+    // TODO: probs need async and blocking versions of these? (actually it's quite a lot simpler to
+    // just allow blocking here I think. Probs what we'll want in the clients anyway.)
+    pub fn register_service(&self, name: impl ToString) -> Result<Channel<(), ServiceChannelMessage>, ()> {
+        self.channel.send(&ServiceHostRequest::RegisterService { name: name.to_string() }).unwrap();
+        match self.channel.receive_blocking().unwrap() {
+            ServiceHostResponse::ServiceRegistered(channel) => Ok(Channel::new_from_handle(channel)),
+            _ => {
+                panic!("Received incorrect response to RegisterService request");
+            }
+        }
+    }
+
+    /// Subscribe to the service called `name`. Returns `Err` if this task isn't allowed to subscribe to it.
+    pub fn subscribe_service<S, R>(&self, name: impl ToString) -> Result<Channel<S, R>, ()>
+    where
+        S: Serialize + DeserializeOwned,
+        R: Serialize + DeserializeOwned,
+    {
+        self.channel.send(&ServiceHostRequest::SubscribeService(name.to_string())).unwrap();
+        match self.channel.receive_blocking().unwrap() {
+            ServiceHostResponse::SubscribedToService(channel) => Ok(Channel::new_from_handle(channel)),
+            ServiceHostResponse::ServiceRefused => Err(()),
+            _ => {
+                panic!("Received incorrect response to SubscribeService request");
+            }
+        }
+    }
+
+    /// Ask `service_host` for a resource it has been given by the kernel, such as the initramfs. Returns `Err`
+    /// if the resource doesn't exist, or this task isn't allowed access to it.
+    pub fn request_resource(&self, name: impl ToString) -> Result<Handle, ()> {
+        self.channel.send(&ServiceHostRequest::RequestResource(name.to_string())).unwrap();
+        match self.channel.receive_blocking().unwrap() {
+            ServiceHostResponse::Resource(handle) => Ok(handle),
+            ServiceHostResponse::MemoryResource { handle, .. } => Ok(handle),
+            ServiceHostResponse::ResourceRefused => Err(()),
+            _ => {
+                panic!("Received incorrect response to RequestResource request");
+            }
+        }
+    }
+
+    /// Like `request_resource`, but for resources that are `MemoryObject`s. Also returns the size of the data held
+    /// in the memory object, which may be smaller than the memory object itself.
+    pub fn request_memory_resource(&self, name: impl ToString) -> Result<(Handle, usize), ()> {
+        self.channel.send(&ServiceHostRequest::RequestResource(name.to_string())).unwrap();
+        match self.channel.receive_blocking().unwrap() {
+            ServiceHostResponse::MemoryResource { handle, size } => Ok((handle, size)),
+            ServiceHostResponse::Resource(_) | ServiceHostResponse::ResourceRefused => Err(()),
+            _ => {
+                panic!("Received incorrect response to RequestResource request");
+            }
+        }
+    }
+
+    /// Ask `service_host` to spawn a new task from the ELF image in the `MemoryObject` `image`, which contains
+    /// `image_size` bytes of data. The task is given `permissions`, which must all be held by this task. Returns a
+    /// handle to the new task, which can be used to wait for it to exit, and to handle its exceptions.
+    pub fn spawn_task(
+        &self,
+        name: impl ToString,
+        image: Handle,
+        image_size: usize,
+        arguments: Vec<String>,
+        stdio: Stdio,
+        permissions: TaskPermissions,
+    ) -> Result<Handle, ()> {
+        self.channel
+            .send(&ServiceHostRequest::SpawnTask {
+                name: name.to_string(),
+                image,
+                image_size,
+                arguments,
+                stdio,
+                permissions,
+            })
+            .unwrap();
+        match self.channel.receive_blocking().unwrap() {
+            ServiceHostResponse::TaskSpawned(task) => Ok(task),
+            ServiceHostResponse::SpawnTaskFailed => Err(()),
+            _ => {
+                panic!("Received incorrect response to SpawnTask request");
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Tasks can be given handles to use as their standard input, output, and error when they're created (see
+//! `syscall::task_create`). Each is either a console channel or an end of a pipe (see `pipe`):
+//!    - A console channel speaks the console protocol (see `console`), with the task on the client side: text
+//!      written to stdout or stderr is sent as `ConsoleRequest::Write`s, and characters typed into stdin are
+//!      received as `ConsoleEvent::Input`s, once the task has asked for them with `ConsoleRequest::AttachInput`.
+//!    - A pipe carries the bytes written to stdout (or stderr) to another task, or carries another task's output
+//!      to stdin. This is how the shell connects the tasks in a pipeline like `a | b`.
+//!
+//! Whoever creates the task decides what's on the other end of each - the shell serves console channels from its
+//! own console, but shows stderr in a different color.
+//!
+//! Tasks find their standard handles in their manifest. `std`'s `print!` and `eprint!` (and friends) write to
+//! stdout and stderr, falling back to `early_log` for tasks that weren't given them, and `std::io::stdin` reads
+//! from stdin. The bootstrap task doesn't have a `TaskManifest`, so can't use any of this.
+
+use crate::{
+    channel::{Channel, ChannelSendError},
+    console::{self, ConsoleEvent, ConsoleRequest},
+    manifest::{StdioHandle, TaskManifest},
+    pipe::{PipeReader, PipeWriter},
+    syscall::PipeWriteError,
+    Handle,
+};
+use alloc::boxed::Box;
+use spinning_top::Spinlock;
+
+pub type StdioChannel = Channel<ConsoleRequest, ConsoleEvent>;
+
+/// Where the task's standard input comes from.
+pub enum Input {
+    Console(StdioChannel),
+    Pipe(PipeReader),
+}
+
+/// Where the task's standard output or error goes.
+pub enum Output {
+    Console(StdioChannel),
+    Pipe(PipeWriter),
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    Console(ChannelSendError),
+    Pipe(PipeWriteError),
+}
+
+impl Output {
+    /// Write `text`, waiting for the reader to make space if it's being written to a full pipe.
+    pub fn write(&self, text: &str) -> Result<(), WriteError> {
+        match self {
+            Output::Console(channel) => console::write(channel, text).map_err(WriteError::Console),
+            Output::Pipe(pipe) => pipe.write_all_blocking(text.as_bytes()).map_err(WriteError::Pipe),
+        }
+    }
+}
+
+struct Handles {
+    stdin: Option<Input>,
+    stdout: Option<Output>,
+    stderr: Option<Output>,
+}
+
+/// The task's standard handles. These are read from the manifest the first time they're needed.
+static HANDLES: Spinlock<Option<&'static Handles>> = Spinlock::new(None);
+
+fn handles() -> &'static Handles {
+    *HANDLES.lock().get_or_insert_with(|| {
+        let stdio = unsafe { TaskManifest::read() }.stdio;
+        let output = |handle: Option<StdioHandle>| {
+            handle.map(|handle| match handle {
+                StdioHandle::Console(handle) => Output::Console(Channel::new_from_handle(Handle(handle))),
+                StdioHandle::Pipe(handle) => Output::Pipe(PipeWriter::new_from_handle(Handle(handle))),
+            })
+        };
+        let stdin = stdio.stdin.map(|handle| match handle {
+            StdioHandle::Console(handle) => Input::Console(Channel::new_from_handle(Handle(handle))),
+            StdioHandle::Pipe(handle) => Input::Pipe(PipeReader::new_from_handle(Handle(handle))),
+        });
+        Box::leak(Box::new(Handles { stdin, stdout: output(stdio.stdout), stderr: output(stdio.stderr) }))
+    })
+}
+
+pub fn stdin() -> Option<&'static Input> {
+    handles().stdin.as_ref()
+}
+
+pub fn stdout() -> Option<&'static Output> {
+    handles().stdout.as_ref()
+}
+
+pub fn stderr() -> Option<&'static Output> {
+    handles().stderr.as_ref()
+}
@@ -0,0 +1,300 @@
+//! Combinators for `Stream`s, the asynchronous counterpart of iterators. Many of the sources of events in
+//! Poplar are streams - `Channel`s are streams of the messages they receive, and the servers generated by
+//! `interface_gen` (including input devices) are streams of the calls they receive - so a task that handles events
+//! from several sources can combine them into a single stream and handle each event as it arrives, rather than
+//! spawning a task for each source:
+//!
+//! ```ignore
+//! enum Event {
+//!     Window(Result<WindowEvent, ChannelReceiveError>),
+//!     Client(Result<ServiceChannelMessage, ChannelReceiveError>),
+//! }
+//!
+//! let mut events = stream::select(window_channel.map(Event::Window), service_channel.map(Event::Client));
+//! while let Some(event) = events.next().await {
+//!     ...
+//! }
+//! ```
+//!
+//! These only need `alloc`, and so can be used by tasks that don't link against `std`.
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+pub use futures_core::Stream;
+
+/// Create a stream from a function that polls for the next item. This is useful for making a stream from a source
+/// that has a `poll_*` method, but doesn't implement `Stream` itself.
+pub fn poll_fn<T, F>(f: F) -> PollFn<F>
+where
+    F: FnMut(&mut Context) -> Poll<Option<T>>,
+{
+    PollFn(f)
+}
+
+pub struct PollFn<F>(F);
+
+impl<F> Unpin for PollFn<F> {}
+
+impl<T, F> Stream for PollFn<F>
+where
+    F: FnMut(&mut Context) -> Poll<Option<T>>,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        (self.get_mut().0)(context)
+    }
+}
+
+/// Combine two streams into one that yields the items of both, as they become ready. The streams take turns to be
+/// polled first, so a busy stream can't starve the other. The combined stream ends once both streams have ended.
+pub fn select<A, B>(a: A, b: B) -> Select<A, B>
+where
+    A: Stream,
+    B: Stream<Item = A::Item>,
+{
+    Select { a: Some(a), b: Some(b), poll_b_first: false }
+}
+
+pub struct Select<A, B> {
+    a: Option<A>,
+    b: Option<B>,
+    poll_b_first: bool,
+}
+
+impl<A, B> Stream for Select<A, B>
+where
+    A: Stream,
+    B: Stream<Item = A::Item>,
+{
+    type Item = A::Item;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<A::Item>> {
+        // SAFETY: the streams are never moved out of the `Select`, only dropped in place once they've ended
+        let this = unsafe { self.get_unchecked_mut() };
+        let poll_b_first = this.poll_b_first;
+        this.poll_b_first = !this.poll_b_first;
+
+        let first = if poll_b_first { poll_side(&mut this.b, context) } else { poll_side(&mut this.a, context) };
+        if let Poll::Ready(item) = first {
+            return Poll::Ready(item);
+        }
+        let second = if poll_b_first { poll_side(&mut this.a, context) } else { poll_side(&mut this.b, context) };
+        if let Poll::Ready(item) = second {
+            return Poll::Ready(item);
+        }
+
+        if this.a.is_none() && this.b.is_none() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Poll one of the streams of a `Select`, dropping it if it's ended. This only returns `Ready` with an item, as
+/// the combined stream only ends once both streams have.
+fn poll_side<S>(side: &mut Option<S>, context: &mut Context) -> Poll<Option<S::Item>>
+where
+    S: Stream,
+{
+    let Some(stream) = side.as_mut() else {
+        return Poll::Pending;
+    };
+    // SAFETY: the stream is pinned inside its `Select`
+    match unsafe { Pin::new_unchecked(stream) }.poll_next(context) {
+        Poll::Ready(Some(item)) => Poll::Ready(Some(item)),
+        Poll::Ready(None) => {
+            *side = None;
+            Poll::Pending
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+/// Combine any number of streams of the same type into one that yields the items of all of them, as they become
+/// ready. Like `select`, the streams take turns to be polled first. The combined stream ends once all the streams
+/// have ended. Streams that aren't `Unpin` can be merged by pinning them with `Box::pin`.
+pub fn merge<I>(streams: I) -> Merge<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Stream + Unpin,
+{
+    Merge { streams: streams.into_iter().collect(), next: 0 }
+}
+
+pub struct Merge<S> {
+    streams: Vec<S>,
+    /// The index of the stream to poll first next time.
+    next: usize,
+}
+
+impl<S> Merge<S>
+where
+    S: Stream + Unpin,
+{
+    /// Add another stream to be merged. This allows sources of events that appear while the stream is in use
+    /// (e.g. input devices that are plugged in) to be handled in the same place as the others.
+    pub fn push(&mut self, stream: S) {
+        self.streams.push(stream);
+    }
+
+    /// The number of streams that haven't ended yet.
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+}
+
+impl<S> Stream for Merge<S>
+where
+    S: Stream + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        if this.streams.is_empty() {
+            return Poll::Ready(None);
+        }
+
+        let start = this.next % this.streams.len();
+        this.next = start + 1;
+
+        /*
+         * Walk the streams from `start`, wrapping around. Streams that have ended are removed as we go, which
+         * moves the following streams down, so `i` only advances past streams that are still going.
+         */
+        let mut i = start;
+        let mut polled = 0;
+        let total = this.streams.len();
+        while polled < total {
+            if i >= this.streams.len() {
+                i = 0;
+            }
+            polled += 1;
+            match Pin::new(&mut this.streams[i]).poll_next(context) {
+                Poll::Ready(Some(item)) => return Poll::Ready(Some(item)),
+                Poll::Ready(None) => {
+                    this.streams.remove(i);
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+
+        if this.streams.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Extension methods for `Stream`s.
+pub trait StreamExt: Stream {
+    /// Wait for the next item from the stream. Resolves to `None` if the stream has ended.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next(self)
+    }
+
+    /// Transform each item of the stream with `f`.
+    fn map<T, F>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Self::Item) -> T,
+    {
+        Map { stream: self, f }
+    }
+
+    /// Transform each item of the stream with `f`, skipping the items it returns `None` for.
+    fn filter_map<T, F>(self, f: F) -> FilterMap<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Self::Item) -> Option<T>,
+    {
+        FilterMap { stream: self, f }
+    }
+}
+
+impl<S> StreamExt for S where S: Stream + ?Sized {}
+
+pub struct Next<'a, S>(&'a mut S)
+where
+    S: ?Sized;
+
+impl<S> Future for Next<'_, S>
+where
+    S: Stream + Unpin + ?Sized,
+{
+    type Output = Option<S::Item>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<S::Item>> {
+        Pin::new(&mut *self.get_mut().0).poll_next(context)
+    }
+}
+
+pub struct Map<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<S, F> Unpin for Map<S, F> where S: Unpin {}
+
+impl<T, S, F> Stream for Map<S, F>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> T,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        // SAFETY: `stream` is never moved out of the `Map`
+        let this = unsafe { self.get_unchecked_mut() };
+        match unsafe { Pin::new_unchecked(&mut this.stream) }.poll_next(context) {
+            Poll::Ready(item) => Poll::Ready(item.map(&mut this.f)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub struct FilterMap<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<S, F> Unpin for FilterMap<S, F> where S: Unpin {}
+
+impl<T, S, F> Stream for FilterMap<S, F>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Option<T>,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        // SAFETY: `stream` is never moved out of the `FilterMap`
+        let this = unsafe { self.get_unchecked_mut() };
+        loop {
+            match unsafe { Pin::new_unchecked(&mut this.stream) }.poll_next(context) {
+                Poll::Ready(Some(item)) => {
+                    if let Some(item) = (this.f)(item) {
+                        return Poll::Ready(Some(item));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Blocking synchronization primitives, built on top of the `wait_on_address` and `wake_address` system calls.
+//! Unlike a spinlock, a thread that can't take a `Mutex` is blocked by the kernel, so it doesn't waste time that
+//! could be spent running the thread holding the lock.
+//!
+//! These mirror the types in `std::sync`, but as Poplar tasks abort on panic, there is no lock poisoning.
+
+use crate::syscall;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+/*
+ * The states of a mutex's `state`. We keep track of whether there might be threads waiting for the lock, so
+ * unlocking an uncontended mutex doesn't need a system call.
+ */
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+/// How many times to spin on a locked mutex before asking the kernel to block, as it's common for locks to only
+/// be held for a short time.
+const SPIN_LIMIT: usize = 100;
+
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex { state: AtomicU32::new(UNLOCKED), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Take the lock, blocking the calling thread until it is available.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self.state.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            self.lock_contended();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Take the lock if it's available, without blocking.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn lock_contended(&self) {
+        for _ in 0..SPIN_LIMIT {
+            if self.state.load(Ordering::Relaxed) == UNLOCKED
+                && self.state.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_ok()
+            {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+
+        /*
+         * Mark the mutex as contended before blocking, so the thread holding it knows to wake us. As we can't
+         * tell if there are other threads waiting, we have to take the lock in the contended state too.
+         */
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            let _ = syscall::wait_on_address(&self.state, CONTENDED, None);
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            syscall::wake_address(&self.state, 1);
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("value", &&*guard).finish(),
+            None => f.debug_struct("Mutex").field("value", &"<locked>").finish(),
+        }
+    }
+}
+
+/// Holds the lock on a `Mutex`, and releases it when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable, which allows threads to block until another thread notifies them that something has
+/// changed. It is used alongside a `Mutex` that protects the data the condition depends on.
+///
+/// Like in `std`, waits can end spuriously, so callers should check their condition again after waking (or use
+/// `wait_while`).
+pub struct Condvar {
+    /// Incremented each time the condition variable is notified. Waiters wait on this changing, so a notification
+    /// that happens between releasing the mutex and blocking isn't missed.
+    sequence: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar { sequence: AtomicU32::new(0) }
+    }
+
+    /// Release the lock held by `guard`, and block until this condition variable is notified. The lock is taken
+    /// again before returning.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    /// Like `wait`, but gives up after `timeout`. Also returns whether the timeout elapsed.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        self.wait_inner(guard, Some(timeout))
+    }
+
+    /// Block until `condition` returns `false`, releasing the lock held by `guard` while waiting.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake up one of the threads waiting on this condition variable, if there are any.
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        syscall::wake_address(&self.sequence, 1);
+    }
+
+    /// Wake up all of the threads waiting on this condition variable.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        syscall::wake_address(&self.sequence, usize::MAX);
+    }
+
+    fn wait_inner<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+
+        let timed_out = matches!(
+            syscall::wait_on_address(&self.sequence, sequence, timeout),
+            Err(syscall::WaitOnAddressError::TimedOut)
+        );
+        (mutex.lock(), timed_out)
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Condvar").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,315 @@
+use super::{
+    raw,
+    result::{define_error_type, handle_from_syscall_repr, status_from_syscall_repr},
+    SYSCALL_TASK_EXCEPTION_CHANNEL,
+    SYSCALL_TASK_MEMORY_REGIONS,
+    SYSCALL_TASK_READ_MEMORY,
+    SYSCALL_TASK_RESUME,
+};
+use crate::Handle;
+use bit_field::BitField;
+
+/// The status a task exits with when it's killed because of an exception, either because nothing was handling
+/// its exceptions, or because the exception was resolved with `ExceptionResolution::Kill`.
+pub const EXCEPTION_EXIT_STATUS: i32 = -1;
+
+/// The message the kernel sends down a task's exception channel when the task causes an exception it can't
+/// resolve itself. The task is stopped until the exception is resolved with `task_resume`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "can_alloc", derive(ptah::Serialize, ptah::Deserialize))]
+pub struct ExceptionInfo {
+    pub kind: ExceptionKind,
+    /// The address that caused the exception, for page faults and misaligned accesses. For other exceptions, this
+    /// is the address of the faulting instruction.
+    pub address: usize,
+    /// The task's registers when the exception occurred.
+    pub registers: Registers,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "can_alloc", derive(ptah::Serialize, ptah::Deserialize))]
+pub enum ExceptionKind {
+    /// An access to memory that the kernel couldn't resolve, e.g. because nothing is mapped at the address, or
+    /// the mapping doesn't allow this kind of access.
+    PageFault(FaultAccess),
+    IllegalInstruction,
+    /// On x86_64, a general protection fault. These are caused by things like executing a privileged
+    /// instruction, or using a non-canonical address.
+    ProtectionFault,
+    MisalignedAccess,
+    /// An access to the guard region below the task's user stack, or to memory below its stack that it isn't
+    /// allowed to grow into. This usually means the stack has overflowed.
+    StackOverflow,
+    /// An unmasked floating-point exception, such as a division by zero. Tasks start with every floating-point
+    /// exception masked, so this only happens if the task unmasks them.
+    FloatingPoint,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "can_alloc", derive(ptah::Serialize, ptah::Deserialize))]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        /// The user-visible registers of a task.
+        #[derive(Clone, Copy, Default, Debug)]
+        #[cfg_attr(feature = "can_alloc", derive(ptah::Serialize, ptah::Deserialize))]
+        #[repr(C)]
+        pub struct Registers {
+            pub rax: u64,
+            pub rbx: u64,
+            pub rcx: u64,
+            pub rdx: u64,
+            pub rsi: u64,
+            pub rdi: u64,
+            pub rbp: u64,
+            pub rsp: u64,
+            pub r8: u64,
+            pub r9: u64,
+            pub r10: u64,
+            pub r11: u64,
+            pub r12: u64,
+            pub r13: u64,
+            pub r14: u64,
+            pub r15: u64,
+            pub rip: u64,
+            /// Only the status flags, and the direction flag, can be changed when a task is resumed.
+            pub rflags: u64,
+        }
+
+        impl Registers {
+            pub fn instruction_pointer(&self) -> usize {
+                self.rip as usize
+            }
+
+            pub fn set_instruction_pointer(&mut self, address: usize) {
+                self.rip = address as u64;
+            }
+        }
+    } else if #[cfg(target_arch = "riscv64")] {
+        /// The user-visible registers of a task.
+        #[derive(Clone, Copy, Default, Debug)]
+        #[cfg_attr(feature = "can_alloc", derive(ptah::Serialize, ptah::Deserialize))]
+        #[repr(C)]
+        pub struct Registers {
+            pub pc: usize,
+            pub ra: usize,
+            pub sp: usize,
+            pub gp: usize,
+            pub tp: usize,
+            pub t0: usize,
+            pub t1: usize,
+            pub t2: usize,
+            pub s0: usize,
+            pub s1: usize,
+            pub a0: usize,
+            pub a1: usize,
+            pub a2: usize,
+            pub a3: usize,
+            pub a4: usize,
+            pub a5: usize,
+            pub a6: usize,
+            pub a7: usize,
+            pub s2: usize,
+            pub s3: usize,
+            pub s4: usize,
+            pub s5: usize,
+            pub s6: usize,
+            pub s7: usize,
+            pub s8: usize,
+            pub s9: usize,
+            pub s10: usize,
+            pub s11: usize,
+            pub t3: usize,
+            pub t4: usize,
+            pub t5: usize,
+            pub t6: usize,
+        }
+
+        impl Registers {
+            pub fn instruction_pointer(&self) -> usize {
+                self.pc
+            }
+
+            pub fn set_instruction_pointer(&mut self, address: usize) {
+                self.pc = address;
+            }
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        /// The user-visible registers of a task.
+        #[derive(Clone, Copy, Default, Debug)]
+        #[cfg_attr(feature = "can_alloc", derive(ptah::Serialize, ptah::Deserialize))]
+        #[repr(C)]
+        pub struct Registers {
+            pub pc: usize,
+            pub sp: usize,
+            pub x0: usize,
+            pub x1: usize,
+            pub x2: usize,
+            pub x3: usize,
+            pub x4: usize,
+            pub x5: usize,
+            pub x6: usize,
+            pub x7: usize,
+            pub x8: usize,
+            pub x9: usize,
+            pub x10: usize,
+            pub x11: usize,
+            pub x12: usize,
+            pub x13: usize,
+            pub x14: usize,
+            pub x15: usize,
+            pub x16: usize,
+            pub x17: usize,
+            pub x18: usize,
+            pub x19: usize,
+            pub x20: usize,
+            pub x21: usize,
+            pub x22: usize,
+            pub x23: usize,
+            pub x24: usize,
+            pub x25: usize,
+            pub x26: usize,
+            pub x27: usize,
+            pub x28: usize,
+            pub x29: usize,
+            pub x30: usize,
+            /// Only the condition flags (`NZCV`) can be changed when a task is resumed.
+            pub pstate: usize,
+        }
+
+        impl Registers {
+            pub fn instruction_pointer(&self) -> usize {
+                self.pc
+            }
+
+            pub fn set_instruction_pointer(&mut self, address: usize) {
+                self.pc = address;
+            }
+        }
+    }
+}
+
+define_error_type!(TaskExceptionChannelError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+});
+
+/// Create a channel that the exceptions caused by `task` are delivered to, as `ExceptionInfo` messages. If `task`
+/// is `None`, the channel receives the calling thread's exceptions (which can then be handled by another of its
+/// threads). This replaces any previous exception channel of the task.
+///
+/// Each thread has its own exception channel - threads don't inherit the channel of the task that created them.
+/// If a thread causes an exception and there's nothing to deliver it to (the thread doesn't have a channel, or
+/// the handle to it has been closed), it is killed.
+pub fn task_exception_channel(task: Option<Handle>) -> Result<Handle, TaskExceptionChannelError> {
+    handle_from_syscall_repr(unsafe {
+        raw::syscall1(SYSCALL_TASK_EXCEPTION_CHANNEL, task.unwrap_or(Handle::ZERO).0 as usize)
+    })
+}
+
+/// How to resolve an exception that has stopped a task.
+#[derive(Clone, Copy, Debug)]
+pub enum ExceptionResolution<'a> {
+    /// Retry the instruction that caused the exception. This is useful if the cause has been fixed (e.g. by
+    /// mapping memory at the faulting address).
+    Resume,
+    /// Resume the task with the given registers (e.g. to skip the faulting instruction).
+    ResumeWithRegisters(&'a Registers),
+    /// Kill the task. It exits with `EXCEPTION_EXIT_STATUS`.
+    Kill,
+}
+
+define_error_type!(TaskResumeError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+    /// The task isn't stopped because of an exception.
+    NotStopped => 4,
+    InvalidResolution => 5,
+    RegistersAddressInvalid => 6,
+});
+
+/// Resolve the exception that has stopped `task`. The handle to the task must have the `WRITE` right.
+pub fn task_resume(task: Handle, resolution: ExceptionResolution<'_>) -> Result<(), TaskResumeError> {
+    let (resolution, registers) = match resolution {
+        ExceptionResolution::Resume => (0, 0x0),
+        ExceptionResolution::ResumeWithRegisters(registers) => (1, registers as *const Registers as usize),
+        ExceptionResolution::Kill => (2, 0x0),
+    };
+    status_from_syscall_repr(unsafe { raw::syscall3(SYSCALL_TASK_RESUME, task.0 as usize, resolution, registers) })
+}
+
+/// An area of a task's memory, as described by `task_memory_regions`.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub address: usize,
+    pub size: usize,
+    pub writable: bool,
+    pub executable: bool,
+    /// Whether the region is device memory (e.g. a device's registers). Reading device memory can have side
+    /// effects, so it can't be read with `task_read_memory`.
+    pub device: bool,
+}
+
+define_error_type!(TaskMemoryRegionsError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+    /// The task isn't stopped because of an exception.
+    NotStopped => 4,
+    BufferAddressInvalid => 5,
+});
+
+/// Describe the memory of `task`, which must be stopped because of an exception: the memory objects mapped into
+/// its address space, and its stack, in order of address. The stacks of the task's other threads aren't included.
+/// Returns the number of regions the task has, which may be more than fit in `regions` - only the first
+/// `regions.len()` are written. The handle to the task must have the `WRITE` right.
+pub fn task_memory_regions(task: Handle, regions: &mut [MemoryRegion]) -> Result<usize, TaskMemoryRegionsError> {
+    let result = unsafe {
+        raw::syscall3(SYSCALL_TASK_MEMORY_REGIONS, task.0 as usize, regions.as_mut_ptr() as usize, regions.len())
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
+}
+
+define_error_type!(TaskReadMemoryError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+    /// The task isn't stopped because of an exception.
+    NotStopped => 4,
+    BufferAddressInvalid => 5,
+    /// The address isn't in one of the task's memory regions.
+    AddressNotMapped => 6,
+    /// The address is in device memory, which can't be read.
+    DeviceMemory => 7,
+});
+
+/// Read the memory of `task`, which must be stopped because of an exception, at `address` into `buffer`. Returns
+/// the number of bytes read, which is less than the length of `buffer` if the read reaches the end of the memory
+/// region `address` is in. Memory that hasn't been committed yet reads as zeros. The handle to the task must have
+/// the `WRITE` right.
+pub fn task_read_memory(task: Handle, address: usize, buffer: &mut [u8]) -> Result<usize, TaskReadMemoryError> {
+    let result = unsafe {
+        raw::syscall4(
+            SYSCALL_TASK_READ_MEMORY,
+            task.0 as usize,
+            address,
+            buffer.as_mut_ptr() as usize,
+            buffer.len(),
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
+}
@@ -0,0 +1,170 @@
+use super::{
+    raw,
+    result::{define_error_type, handle_from_syscall_repr, status_from_syscall_repr},
+    MemoryUsage,
+    SYSCALL_JOB_CREATE,
+    SYSCALL_JOB_GET_LIMITS,
+    SYSCALL_JOB_KILL,
+    SYSCALL_JOB_SET_LIMITS,
+    SYSCALL_JOB_USAGE,
+};
+use crate::Handle;
+
+/// The status a task exits with when it's killed because its job (or one of the job's ancestors) was killed.
+pub const JOB_KILLED_EXIT_STATUS: i32 = -2;
+
+/// The resources used by the tasks in a job and all of its descendants.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct JobUsage {
+    /// The memory used by the address spaces of the job's tasks. Each address space is only counted once, even
+    /// if several of the tasks share it.
+    pub memory: MemoryUsage,
+    /// The CPU time used by the job's tasks (including those that have exited), in nanoseconds. This is sampled
+    /// on each timer tick, so is only as accurate as the platform's timer period.
+    pub cpu_time_ns: u64,
+    /// The number of tasks in the job that haven't exited.
+    pub num_tasks: usize,
+}
+
+define_error_type!(JobCreateError {
+    InvalidHandle => 1,
+    NotAJob => 2,
+    /// The handle to the parent `Job` does not have the `WRITE` right.
+    JobCannotBeModified => 3,
+    /// The parent job has been killed, so can't have new jobs created in it.
+    JobKilled => 4,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 5,
+});
+
+/// Create a new `Job` as a child of `parent`. If `parent` is `None`, the new job is a child of the calling
+/// task's job. Tasks can be created in the new job by passing it to `task_create`.
+pub fn job_create(parent: Option<Handle>) -> Result<Handle, JobCreateError> {
+    handle_from_syscall_repr(unsafe {
+        raw::syscall1(SYSCALL_JOB_CREATE, parent.unwrap_or(Handle::ZERO).0 as usize)
+    })
+}
+
+define_error_type!(JobKillError {
+    InvalidHandle => 1,
+    NotAJob => 2,
+    /// The handle to the `Job` does not have the `WRITE` right.
+    JobCannotBeModified => 3,
+});
+
+/// Kill every task in `job`, and in all of its descendants. The tasks exit with `JOB_KILLED_EXIT_STATUS` the
+/// next time they enter the kernel, or on the next timer tick if they're running. No more tasks or jobs can be
+/// created in a job that has been killed.
+pub fn job_kill(job: Handle) -> Result<(), JobKillError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_JOB_KILL, job.0 as usize) })
+}
+
+define_error_type!(JobUsageError {
+    InvalidHandle => 1,
+    NotAJob => 2,
+    /// The handle to the `Job` does not have the `READ` right.
+    JobCannotBeRead => 3,
+    UsageAddressInvalid => 4,
+});
+
+/// Get the resources used by `job` and all of its descendants. If `job` is `None`, the usage of the calling
+/// task's job is returned.
+pub fn job_usage(job: Option<Handle>) -> Result<JobUsage, JobUsageError> {
+    let mut usage = JobUsage::default();
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(
+            SYSCALL_JOB_USAGE,
+            job.unwrap_or(Handle::ZERO).0 as usize,
+            &mut usage as *mut JobUsage as usize,
+        )
+    })?;
+    Ok(usage)
+}
+
+/// Limits on the resources the tasks in a job can use. A job's limits also apply to all of its descendants, so the
+/// limits a task is subject to are the tightest of those of its job and each of the job's ancestors.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct JobLimits {
+    /// The maximum amount of memory, in bytes, that can be committed on behalf of the job's tasks. This covers
+    /// the memory objects they create, and the memory committed to lazy and copy-on-write objects when they
+    /// access them. A task that faults on memory that can't be committed because of this limit gets a page fault
+    /// exception.
+    pub max_committed_memory: usize,
+    /// The maximum number of handles each of the job's tasks can hold. System calls that would create a new handle
+    /// fail with a `TooManyHandles` error once a task has this many.
+    pub max_handles: usize,
+    /// The percentage of a single CPU's time the job's tasks can use while other tasks are waiting to run, between
+    /// `1` and `100`. Tasks that have used up their share are only run if no other tasks are ready.
+    pub cpu_share: u32,
+    /// The size, in bytes, that the user stacks of the job's tasks can grow to. Stacks start small, and grow when
+    /// a task accesses the memory below its stack. Accesses below this limit cause a stack overflow exception
+    /// instead, so setting it to `0` stops stacks from growing at all.
+    pub max_stack_size: usize,
+}
+
+impl JobLimits {
+    pub const UNLIMITED: JobLimits = JobLimits {
+        max_committed_memory: usize::MAX,
+        max_handles: usize::MAX,
+        cpu_share: 100,
+        max_stack_size: usize::MAX,
+    };
+
+    /// Combine two sets of limits, taking the tightest of each.
+    pub fn min(self, other: JobLimits) -> JobLimits {
+        JobLimits {
+            max_committed_memory: self.max_committed_memory.min(other.max_committed_memory),
+            max_handles: self.max_handles.min(other.max_handles),
+            cpu_share: self.cpu_share.min(other.cpu_share),
+            max_stack_size: self.max_stack_size.min(other.max_stack_size),
+        }
+    }
+}
+
+impl Default for JobLimits {
+    fn default() -> Self {
+        JobLimits::UNLIMITED
+    }
+}
+
+define_error_type!(JobSetLimitsError {
+    InvalidHandle => 1,
+    NotAJob => 2,
+    /// The handle to the `Job` does not have the `WRITE` right.
+    JobCannotBeModified => 3,
+    LimitsAddressInvalid => 4,
+    /// The CPU share is not between `1` and `100`.
+    InvalidLimits => 5,
+});
+
+/// Set the resource limits of `job`. A task can't change the limits of its own job without a handle to it, so a
+/// task that creates a job for another task can give it limits it can't escape.
+pub fn job_set_limits(job: Handle, limits: &JobLimits) -> Result<(), JobSetLimitsError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_JOB_SET_LIMITS, job.0 as usize, limits as *const JobLimits as usize)
+    })
+}
+
+define_error_type!(JobGetLimitsError {
+    InvalidHandle => 1,
+    NotAJob => 2,
+    /// The handle to the `Job` does not have the `READ` right.
+    JobCannotBeRead => 3,
+    LimitsAddressInvalid => 4,
+});
+
+/// Get the resource limits set on `job`. If `job` is `None`, the limits of the calling task's job are returned.
+/// This doesn't include the limits of the job's ancestors.
+pub fn job_get_limits(job: Option<Handle>) -> Result<JobLimits, JobGetLimitsError> {
+    let mut limits = JobLimits::default();
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(
+            SYSCALL_JOB_GET_LIMITS,
+            job.unwrap_or(Handle::ZERO).0 as usize,
+            &mut limits as *mut JobLimits as usize,
+        )
+    })?;
+    Ok(limits)
+}
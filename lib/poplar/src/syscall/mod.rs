@@ -1,11 +1,58 @@
+pub mod exception;
 pub mod get_framebuffer;
+pub mod job;
 pub mod pci;
+pub mod platform;
 pub mod result;
 
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, sync::atomic::AtomicU32, time::Duration};
 
+pub use exception::{
+    task_exception_channel,
+    task_memory_regions,
+    task_read_memory,
+    task_resume,
+    ExceptionInfo,
+    ExceptionKind,
+    ExceptionResolution,
+    FaultAccess,
+    MemoryRegion,
+    Registers,
+    TaskExceptionChannelError,
+    TaskMemoryRegionsError,
+    TaskReadMemoryError,
+    TaskResumeError,
+    EXCEPTION_EXIT_STATUS,
+};
 pub use get_framebuffer::{get_framebuffer, FramebufferInfo, GetFramebufferError, PixelFormat};
-pub use pci::{pci_get_info, PciGetInfoError};
+pub use job::{
+    job_create,
+    job_get_limits,
+    job_kill,
+    job_set_limits,
+    job_usage,
+    JobCreateError,
+    JobGetLimitsError,
+    JobKillError,
+    JobLimits,
+    JobSetLimitsError,
+    JobUsage,
+    JobUsageError,
+    JOB_KILLED_EXIT_STATUS,
+};
+pub use pci::{
+    dma_domain_attach,
+    dma_domain_detach,
+    pci_config_read,
+    pci_config_write,
+    pci_get_info,
+    DmaDomainAttachError,
+    DmaDomainDetachError,
+    PciConfigReadError,
+    PciConfigWriteError,
+    PciGetInfoError,
+};
+pub use platform::{get_platform_devices, GetPlatformDevicesError};
 
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
@@ -14,12 +61,15 @@ cfg_if::cfg_if! {
     } else if #[cfg(target_arch = "riscv64")] {
         pub mod raw_riscv;
         pub use raw_riscv as raw;
+    } else if #[cfg(target_arch = "aarch64")] {
+        pub mod raw_aarch64;
+        pub use raw_aarch64 as raw;
     } else {
         compile_error!("Poplar does not support this target architecture!");
     }
 }
 
-use crate::Handle;
+use crate::{caps::Capabilities, Handle, HandleRights};
 use bit_field::BitField;
 use result::{define_error_type, handle_from_syscall_repr, status_from_syscall_repr};
 
@@ -37,6 +87,69 @@ pub const SYSCALL_WAIT_FOR_EVENT: usize = 12;
 pub const SYSCALL_POLL_INTEREST: usize = 13;
 pub const SYSCALL_CREATE_ADDRESS_SPACE: usize = 14;
 pub const SYSCALL_SPAWN_TASK: usize = 15;
+pub const SYSCALL_SLEEP: usize = 16;
+pub const SYSCALL_GET_UPTIME: usize = 17;
+pub const SYSCALL_SET_PRIORITY: usize = 18;
+pub const SYSCALL_OBJECT_WAIT_MANY: usize = 19;
+pub const SYSCALL_CLONE_MEMORY_OBJECT: usize = 20;
+pub const SYSCALL_GET_MEMORY_USAGE: usize = 21;
+pub const SYSCALL_HANDLE_DUPLICATE_WITH_RIGHTS: usize = 22;
+pub const SYSCALL_CREATE_DMA_MEMORY_OBJECT: usize = 23;
+pub const SYSCALL_DMA_SYNC: usize = 24;
+pub const SYSCALL_CREATE_DMA_DOMAIN: usize = 25;
+pub const SYSCALL_DMA_DOMAIN_ATTACH: usize = 26;
+pub const SYSCALL_DMA_DOMAIN_DETACH: usize = 27;
+pub const SYSCALL_PCI_CONFIG_READ: usize = 28;
+pub const SYSCALL_PCI_CONFIG_WRITE: usize = 29;
+pub const SYSCALL_ACK_INTERRUPT: usize = 30;
+pub const SYSCALL_GET_PLATFORM_DEVICES: usize = 31;
+pub const SYSCALL_GET_TIME: usize = 32;
+pub const SYSCALL_SET_TIME: usize = 33;
+pub const SYSCALL_CLOCK_MONOTONIC: usize = 34;
+pub const SYSCALL_TASK_CREATE: usize = 35;
+pub const SYSCALL_TASK_EXIT: usize = 36;
+pub const SYSCALL_TASK_WAIT: usize = 37;
+pub const SYSCALL_THREAD_CREATE: usize = 38;
+pub const SYSCALL_WAIT_ON_ADDRESS: usize = 39;
+pub const SYSCALL_WAKE_ADDRESS: usize = 40;
+pub const SYSCALL_TASK_EXCEPTION_CHANNEL: usize = 41;
+pub const SYSCALL_TASK_RESUME: usize = 42;
+pub const SYSCALL_JOB_CREATE: usize = 43;
+pub const SYSCALL_JOB_KILL: usize = 44;
+pub const SYSCALL_JOB_USAGE: usize = 45;
+pub const SYSCALL_JOB_SET_LIMITS: usize = 46;
+pub const SYSCALL_JOB_GET_LIMITS: usize = 47;
+pub const SYSCALL_TASK_GRANT_CAPABILITIES: usize = 48;
+pub const SYSCALL_TASK_REVOKE_CAPABILITIES: usize = 49;
+pub const SYSCALL_PEEK_MESSAGE: usize = 50;
+pub const SYSCALL_UNMAP_MEMORY_OBJECT: usize = 51;
+pub const SYSCALL_HANDLE_CLOSE: usize = 52;
+pub const SYSCALL_MEMORY_OBJECT_SIZE: usize = 53;
+pub const SYSCALL_CHANNEL_CALL: usize = 54;
+pub const SYSCALL_CREATE_EVENT: usize = 55;
+pub const SYSCALL_SIGNAL_EVENT: usize = 56;
+pub const SYSCALL_CLEAR_EVENT: usize = 57;
+pub const SYSCALL_READ_KERNEL_LOG: usize = 58;
+pub const SYSCALL_GET_TRACE_BUFFERS: usize = 59;
+pub const SYSCALL_SET_TRACING: usize = 60;
+pub const SYSCALL_TASK_SET_AUDIT: usize = 61;
+pub const SYSCALL_PERF_CONFIGURE: usize = 62;
+pub const SYSCALL_PERF_READ: usize = 63;
+pub const SYSCALL_GET_PROFILE_BUFFERS: usize = 64;
+pub const SYSCALL_SET_PROFILING: usize = 65;
+pub const SYSCALL_TASK_MEMORY_REGIONS: usize = 66;
+pub const SYSCALL_TASK_READ_MEMORY: usize = 67;
+pub const SYSCALL_SYSTEM_SHUTDOWN: usize = 68;
+pub const SYSCALL_SYSTEM_REBOOT: usize = 69;
+pub const SYSCALL_GET_KERNEL_MEMORY_STATS: usize = 70;
+pub const SYSCALL_PROTECT_MEMORY_OBJECT: usize = 71;
+pub const SYSCALL_TASK_INFO: usize = 72;
+pub const SYSCALL_SET_MEMORY_OBJECT_DISCARDABLE: usize = 73;
+pub const SYSCALL_SUBSCRIBE_MEMORY_PRESSURE: usize = 74;
+pub const SYSCALL_CREATE_PIPE: usize = 75;
+pub const SYSCALL_PIPE_READ: usize = 76;
+pub const SYSCALL_PIPE_WRITE: usize = 77;
+pub const SYSCALL_CHANNEL_CANCEL_CALL: usize = 78;
 
 pub fn yield_to_kernel() {
     unsafe {
@@ -60,6 +173,17 @@ define_error_type!(CreateMemoryObjectError {
     InvalidFlags => 1,
     InvalidSize => 2,
     InvalidPhysicalAddressPointer => 3,
+    /// Lazy memory objects are not physically contiguous, and so don't have a physical address.
+    LazyObjectHasNoPhysicalAddress => 4,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 5,
+    /// Committing memory to the object would exceed the memory limit of the calling task's job.
+    MemoryLimitExceeded => 6,
+    /// The memory object was requested to be both writable and executable, which requires the
+    /// `WRITABLE_EXECUTABLE_MEMORY` capability.
+    WritableAndExecutable => 7,
+    /// There was not enough free physical memory to create the memory object.
+    OutOfMemory => 8,
 });
 
 bitflags::bitflags! {
@@ -67,11 +191,20 @@ bitflags::bitflags! {
     pub struct MemoryObjectFlags: u32 {
         const WRITABLE = 1 << 0;
         const EXECUTABLE = 1 << 1;
+        /// Only commit physical memory to each page of the memory object when it's first accessed.
+        const LAZY = 1 << 2;
+        /// Allow the kernel to discard the memory committed to the object when memory is short, while it's
+        /// marked as discardable with `set_memory_object_discardable`. Discarded pages are zeroed when they're
+        /// next accessed. Must be used with `LAZY`, and the object can only be mapped into a single address
+        /// space.
+        const DISCARDABLE = 1 << 3;
     }
 }
 
 /// Create a MemoryObject kernel object of the given size (in bytes). Returns a handle to the new
-/// MemoryObject, if the call was successful.
+/// MemoryObject, if the call was successful. A memory object can only be both `WRITABLE` and `EXECUTABLE` if the
+/// calling task has the `WRITABLE_EXECUTABLE_MEMORY` capability, and even then is never mapped as both at once:
+/// it's mapped writable, and must be switched to executable with `protect_memory_object`.
 pub unsafe fn create_memory_object(
     size: usize,
     flags: MemoryObjectFlags,
@@ -87,27 +220,328 @@ define_error_type!(MapMemoryObjectError {
     InvalidAddressSpaceHandle => 2,
     RegionAlreadyMapped => 3,
     AddressPointerInvalid => 4,
+    /// Copy-on-write and discardable memory objects can only be mapped into a single address space.
+    CopyOnWriteAlreadyMapped => 5,
+    /// The handle to the `MemoryObject` does not have the `MAP` right.
+    MemoryObjectCannotBeMapped => 6,
+    /// The handle to the `AddressSpace` does not have the `WRITE` right.
+    AddressSpaceCannotBeModified => 7,
+    /// No address was given, and there isn't a free region of the address space large enough to map the memory
+    /// object into.
+    NoSpaceForMapping => 8,
+    /// Huge pages were requested, but the memory object isn't physically contiguous, or the given address isn't
+    /// aligned with its physical memory.
+    CannotUseHugePages => 9,
+    /// Part of the region that would be mapped isn't in the part of the address space that userspace can use.
+    AddressNotInUserSpace => 10,
 });
 
+bitflags::bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct MapMemoryObjectFlags: u32 {
+        /// Map the memory object with huge pages (e.g. 2MiB and 1GiB pages on x86_64), to reduce the pressure
+        /// that large mappings put on the TLB. The memory object must be physically contiguous, and the address
+        /// it's mapped at must be aligned to a huge page with respect to its physical memory. The parts of the
+        /// object that can't be covered by a huge page (and objects smaller than one) are still mapped with
+        /// smaller pages.
+        const HUGE_PAGES = 1 << 0;
+    }
+}
+
+/// Map a `MemoryObject` into an `AddressSpace` (`Handle::ZERO` maps it into the calling task's). If
+/// `virtual_address` is `None`, the kernel chooses a free address to map it at, and writes it to
+/// `address_pointer` if it isn't null.
 pub unsafe fn map_memory_object(
     memory_object: Handle,
     address_space: Handle,
     virtual_address: Option<usize>,
     address_pointer: *mut usize,
+    flags: MapMemoryObjectFlags,
 ) -> Result<(), MapMemoryObjectError> {
     status_from_syscall_repr(unsafe {
-        raw::syscall4(
+        raw::syscall5(
             SYSCALL_MAP_MEMORY_OBJECT,
             memory_object.0 as usize,
             address_space.0 as usize,
             if virtual_address.is_some() { virtual_address.unwrap() } else { 0x0 },
             address_pointer as usize,
+            flags.bits() as usize,
         )
     })
 }
 
+define_error_type!(UnmapMemoryObjectError {
+    InvalidAddressSpaceHandle => 1,
+    /// The handle to the `AddressSpace` does not have the `WRITE` right.
+    AddressSpaceCannotBeModified => 2,
+    /// There isn't a memory object mapped at the given address. The address must be the start of the mapping.
+    NotMapped => 3,
+});
+
+/// Unmap the `MemoryObject` mapped at `virtual_address` from an `AddressSpace` (`Handle::ZERO` unmaps it from the
+/// calling task's). The memory object itself isn't affected, and can be mapped again.
+///
+/// This is unsafe because any references into the unmapped memory are left dangling.
+pub unsafe fn unmap_memory_object(
+    address_space: Handle,
+    virtual_address: usize,
+) -> Result<(), UnmapMemoryObjectError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_UNMAP_MEMORY_OBJECT, address_space.0 as usize, virtual_address)
+    })
+}
+
+define_error_type!(ProtectMemoryObjectError {
+    InvalidAddressSpaceHandle => 1,
+    /// The handle to the `AddressSpace` does not have the `WRITE` right.
+    AddressSpaceCannotBeModified => 2,
+    /// There isn't a memory object mapped at the given address. The address must be the start of the mapping.
+    NotMapped => 3,
+    /// The mapping can't be made writable or executable, because it wasn't when it was created.
+    NotPermitted => 4,
+    /// Mappings can't be writable and executable at the same time.
+    WritableAndExecutable => 5,
+});
+
+/// Change whether the `MemoryObject` mapped at `virtual_address` in an `AddressSpace` (`Handle::ZERO` for the
+/// calling task's) is writable and executable, from the `WRITABLE` and `EXECUTABLE` bits of `flags`. A mapping
+/// can be made more restrictive, and then restored, but can't be given permissions it wasn't mapped with, and
+/// can't be made writable and executable at the same time.
+///
+/// This is unsafe because any references into the memory may no longer be valid to use.
+pub unsafe fn protect_memory_object(
+    address_space: Handle,
+    virtual_address: usize,
+    flags: MemoryObjectFlags,
+) -> Result<(), ProtectMemoryObjectError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(
+            SYSCALL_PROTECT_MEMORY_OBJECT,
+            address_space.0 as usize,
+            virtual_address,
+            flags.bits() as usize,
+        )
+    })
+}
+
+define_error_type!(MemoryObjectSizeError {
+    InvalidHandle => 1,
+    NotAMemoryObject => 2,
+    SizeAddressInvalid => 3,
+});
+
+/// Get the size of a `MemoryObject`, in bytes.
+pub fn memory_object_size(memory_object: Handle) -> Result<usize, MemoryObjectSizeError> {
+    let mut size = 0usize;
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_MEMORY_OBJECT_SIZE, memory_object.0 as usize, &mut size as *mut usize as usize)
+    })?;
+    Ok(size)
+}
+
+define_error_type!(CloneMemoryObjectError {
+    InvalidMemoryObjectHandle => 1,
+    /// The handle to the `MemoryObject` does not have the `READ` right.
+    MemoryObjectCannotBeRead => 2,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 3,
+    /// Discardable memory objects can't be cloned, as their memory could be discarded from under the clone.
+    MemoryObjectIsDiscardable => 4,
+});
+
+/// Create a copy-on-write clone of a MemoryObject, as it is when the clone is created. The clone shares memory
+/// with the original until a page of either of them is written to, at which point that page is copied. Writes to
+/// the original are never visible through the clone. Returns a handle to the clone.
+pub fn clone_memory_object(memory_object: Handle) -> Result<Handle, CloneMemoryObjectError> {
+    handle_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CLONE_MEMORY_OBJECT, memory_object.0 as usize) })
+}
+
+define_error_type!(SetMemoryObjectDiscardableError {
+    InvalidMemoryObjectHandle => 1,
+    /// The handle to the `MemoryObject` does not have the `WRITE` right.
+    MemoryObjectCannotBeModified => 2,
+    /// The memory object was not created with `MemoryObjectFlags::DISCARDABLE`.
+    NotDiscardable => 3,
+});
+
+/// Mark whether the kernel can discard the memory committed to a `DISCARDABLE` memory object when memory is short.
+/// Objects start off not discardable. Caches should mark their memory as discardable while it holds clean data
+/// that can be fetched again, and as not discardable while they're using it.
+///
+/// Returns whether any of the object's memory has been discarded since this was last called. If marking an object
+/// as not discardable returns `true`, its contents have been lost (and read as zero), and must be fetched again.
+pub fn set_memory_object_discardable(
+    memory_object: Handle,
+    discardable: bool,
+) -> Result<bool, SetMemoryObjectDiscardableError> {
+    let result = unsafe {
+        raw::syscall2(SYSCALL_SET_MEMORY_OBJECT_DISCARDABLE, memory_object.0 as usize, discardable as usize)
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bit(16))
+}
+
+/// How much memory is mapped into an address space. Memory is `reserved` when it's mapped, but might not have
+/// physical memory `committed` to it until it's accessed (e.g. for lazy and copy-on-write memory objects). Both
+/// are in bytes.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct MemoryUsage {
+    pub reserved: usize,
+    pub committed: usize,
+}
+
+define_error_type!(GetMemoryUsageError {
+    InvalidAddressSpaceHandle => 1,
+    UsageAddressInvalid => 2,
+    /// The handle to the `AddressSpace` does not have the `READ` right.
+    AddressSpaceCannotBeRead => 3,
+});
+
+/// Get the memory usage of an address space. If `address_space` is `None`, the usage of the calling task's address
+/// space is returned.
+pub fn get_memory_usage(address_space: Option<Handle>) -> Result<MemoryUsage, GetMemoryUsageError> {
+    let mut usage = MemoryUsage::default();
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(
+            SYSCALL_GET_MEMORY_USAGE,
+            address_space.unwrap_or(Handle::ZERO).0 as usize,
+            &mut usage as *mut MemoryUsage as usize,
+        )
+    })?;
+    Ok(usage)
+}
+
+/// The number of orders of blocks of physical memory reported by `get_kernel_memory_stats`.
+pub const PHYSICAL_MEMORY_ORDERS: usize = 13;
+/// The maximum number of the kernel's object caches reported by `get_kernel_memory_stats`.
+pub const MAX_OBJECT_CACHES: usize = 8;
+
+/// How the kernel is using memory. Physical memory is managed in blocks of `2^n` 4KiB frames, where `n` is the
+/// block's order. The kernel heap includes the slabs of the object caches. All sizes are in bytes.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct KernelMemoryStats {
+    pub physical_total: usize,
+    pub physical_free: usize,
+    /// The number of free blocks of physical memory of each order.
+    pub free_blocks: [usize; PHYSICAL_MEMORY_ORDERS],
+    pub heap_size: usize,
+    pub heap_used: usize,
+    pub num_object_caches: usize,
+    pub object_caches: [ObjectCacheStats; MAX_OBJECT_CACHES],
+}
+
+/// One of the kernel's caches of frequently-allocated objects. Objects are allocated out of slabs of
+/// `slab_size` bytes, which are allocated from the kernel heap.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct ObjectCacheStats {
+    /// The name of the cache, as UTF-8 padded with zeros.
+    pub name: [u8; 16],
+    pub object_size: usize,
+    pub objects_in_use: usize,
+    pub slabs: usize,
+    pub slab_size: usize,
+}
+
+impl ObjectCacheStats {
+    pub fn name(&self) -> &str {
+        let length = self.name.iter().position(|&byte| byte == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[0..length]).unwrap_or("")
+    }
+}
+
+define_error_type!(GetKernelMemoryStatsError {
+    StatsAddressInvalid => 1,
+});
+
+/// Get statistics about how the kernel is using physical memory and its heap.
+pub fn get_kernel_memory_stats() -> Result<KernelMemoryStats, GetKernelMemoryStatsError> {
+    let mut stats = KernelMemoryStats::default();
+    status_from_syscall_repr(unsafe {
+        raw::syscall1(SYSCALL_GET_KERNEL_MEMORY_STATS, &mut stats as *mut KernelMemoryStats as usize)
+    })?;
+    Ok(stats)
+}
+
+define_error_type!(SubscribeMemoryPressureError {
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 1,
+});
+
+/// Get an `Event` that is signalled whenever free memory falls below the kernel's low watermark. Tasks that cache
+/// data they can fetch again (e.g. file contents or network buffers) should wait for it, and free what they can
+/// (or mark it as discardable) when it's signalled. Each call creates a new event, so every subscriber is told
+/// about each period of memory pressure. If memory is already short, the event starts off signalled.
+pub fn subscribe_memory_pressure() -> Result<Handle, SubscribeMemoryPressureError> {
+    handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_SUBSCRIBE_MEMORY_PRESSURE) })
+}
+
+/// The longest name of a task reported by `task_info`. This is also the longest name the kernel accepts for a
+/// task, so names are never cut short.
+pub const TASK_INFO_NAME_LENGTH: usize = 32;
+
+/// A task, as described by `task_info`.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct TaskInfo {
+    pub id: u64,
+    /// The name of the task, as UTF-8 padded with zeros.
+    pub name: [u8; TASK_INFO_NAME_LENGTH],
+    /// The ID of the job the task belongs to.
+    pub job: u64,
+    /// The ID of the address space the task runs in. Threads run in the address space of the task that created
+    /// them, so this can be used to tell which tasks are threads of the same task.
+    pub address_space: u64,
+    /// The memory used by the task's address space. This is shared with any other tasks in the same address
+    /// space, so should only be counted once for each of them.
+    pub memory: MemoryUsage,
+    /// The number of handles the task holds. Like memory, handles are shared between a task's threads.
+    pub num_handles: usize,
+    /// The task's effective priority, which may have been raised by priority inheritance.
+    pub priority: Priority,
+    pub state: TaskInfoState,
+}
+
+impl TaskInfo {
+    pub fn name(&self) -> &str {
+        let length = self.name.iter().position(|&byte| byte == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[0..length]).unwrap_or("")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[repr(u8)]
+pub enum TaskInfoState {
+    /// The task is waiting to be run.
+    #[default]
+    Ready = 0,
+    /// The task is running on one of the CPUs.
+    Running = 1,
+    /// The task is waiting for something (e.g. a message, an event, or a timer).
+    Blocked = 2,
+}
+
+define_error_type!(TaskInfoError {
+    /// The calling task does not have the `TRACE` capability.
+    TaskDoesNotHaveCorrectCapability => 1,
+    BufferAddressInvalid => 2,
+});
+
+/// Describe every task that is running on the system (including the calling task), in no particular order.
+/// Returns the number of tasks, which may be more than fit in `tasks` - only the first `tasks.len()` are written.
+/// This requires the `TRACE` capability.
+pub fn task_info(tasks: &mut [TaskInfo]) -> Result<usize, TaskInfoError> {
+    let result = unsafe { raw::syscall2(SYSCALL_TASK_INFO, tasks.as_mut_ptr() as usize, tasks.len()) };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
+}
+
 define_error_type!(CreateChannelError {
     InvalidHandleAddress => 1,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 2,
 });
 
 pub fn create_channel() -> Result<(Handle, Handle), CreateChannelError> {
@@ -119,7 +553,8 @@ pub fn create_channel() -> Result<(Handle, Handle), CreateChannelError> {
 }
 
 pub const CHANNEL_MAX_NUM_BYTES: usize = 4096;
-pub const CHANNEL_MAX_NUM_HANDLES: usize = 4;
+/// The maximum number of handles that can be transferred by a single message. This must fit in a Ptah handle slot.
+pub const CHANNEL_MAX_NUM_HANDLES: usize = 64;
 
 define_error_type!(SendMessageError {
     /// The `Channel` handle is invalid.
@@ -128,7 +563,7 @@ define_error_type!(SendMessageError {
     NotAChannel => 2,
     /// The `Channel` handle must have the `SEND` right to use the `send_message` system call.
     ChannelCannotSend => 3,
-    /// A handle to be transferred is invalid.
+    /// A handle to be transferred is invalid, or appears more than once.
     InvalidTransferredHandle => 4,
     /// Transferred handles must have the `TRANSFER` right.
     CannotTransferHandle => 5,
@@ -140,10 +575,21 @@ define_error_type!(SendMessageError {
 });
 
 pub fn send_message(channel: Handle, bytes: &[u8], handles: &[Handle]) -> Result<(), SendMessageError> {
+    send_message_raw(channel, 0, bytes, handles)
+}
+
+/// Send a message as the reply to the call with the transaction ID `txid`, which is returned by `get_message`
+/// when the message making the call is received. Replies to calls that aren't waiting for one (e.g. because the
+/// caller has given up on the call) are discarded by the kernel.
+pub fn send_reply(channel: Handle, txid: u16, bytes: &[u8], handles: &[Handle]) -> Result<(), SendMessageError> {
+    send_message_raw(channel, txid, bytes, handles)
+}
+
+fn send_message_raw(channel: Handle, txid: u16, bytes: &[u8], handles: &[Handle]) -> Result<(), SendMessageError> {
     status_from_syscall_repr(unsafe {
         raw::syscall5(
             SYSCALL_SEND_MESSAGE,
-            channel.0 as usize,
+            channel_with_txid(channel, txid),
             if bytes.len() == 0 { 0x0 } else { bytes.as_ptr() as usize },
             bytes.len(),
             if handles.len() == 0 { 0x0 } else { handles.as_ptr() as usize },
@@ -160,17 +606,43 @@ define_error_type!(GetMessageError {
     BytesBufferTooSmall => 5,
     HandlesAddressInvalid => 6,
     HandlesBufferTooSmall => 7,
+    /// The handle to the `Channel` end does not have the `READ` right.
+    ChannelCannotReceive => 8,
 });
 
+/// Receive the next message waiting on the given `Channel`. As well as the message's bytes and handles, this
+/// returns the transaction ID of the call the message was sent by, which should be passed to `send_reply` to reply
+/// to it. This is `0` if the message was not sent by a call. Replies to calls made through this `Channel` are not
+/// received by `get_message` - use `get_reply` to receive them.
 pub fn get_message<'b, 'h>(
     channel: Handle,
     byte_buffer: &'b mut [u8],
     handle_buffer: &'h mut [Handle],
+) -> Result<(&'b mut [u8], &'h mut [Handle], u16), GetMessageError> {
+    get_message_raw(channel, 0, byte_buffer, handle_buffer)
+}
+
+/// Receive the reply to the call with the transaction ID `txid`, if it has arrived. This completes the call.
+pub fn get_reply<'b, 'h>(
+    channel: Handle,
+    txid: u16,
+    byte_buffer: &'b mut [u8],
+    handle_buffer: &'h mut [Handle],
 ) -> Result<(&'b mut [u8], &'h mut [Handle]), GetMessageError> {
+    let (bytes, handles, _) = get_message_raw(channel, txid, byte_buffer, handle_buffer)?;
+    Ok((bytes, handles))
+}
+
+fn get_message_raw<'b, 'h>(
+    channel: Handle,
+    txid: u16,
+    byte_buffer: &'b mut [u8],
+    handle_buffer: &'h mut [Handle],
+) -> Result<(&'b mut [u8], &'h mut [Handle], u16), GetMessageError> {
     let result = unsafe {
         raw::syscall5(
             SYSCALL_GET_MESSAGE,
-            channel.0 as usize,
+            channel_with_txid(channel, txid),
             if byte_buffer.len() == 0 { 0x0 } else { byte_buffer.as_ptr() as usize },
             byte_buffer.len(),
             if handle_buffer.len() == 0 { 0x0 } else { handle_buffer.as_ptr() as usize },
@@ -181,8 +653,285 @@ pub fn get_message<'b, 'h>(
 
     let valid_bytes_len = result.get_bits(16..32);
     let valid_handles_len = result.get_bits(32..48);
+    let txid = result.get_bits(48..64) as u16;
+
+    Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len], txid))
+}
+
+/// The size of a message waiting to be received on a `Channel`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MessageInfo {
+    pub num_bytes: usize,
+    pub num_handles: usize,
+}
+
+define_error_type!(PeekMessageError {
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    NoMessage => 3,
+    /// The handle to the `Channel` end does not have the `READ` right.
+    ChannelCannotReceive => 4,
+});
+
+/// Get the size of the next message waiting to be received on the given `Channel`, without receiving it. This
+/// allows buffers of the right size to be allocated before the message is received with `get_message`.
+pub fn peek_message(channel: Handle) -> Result<MessageInfo, PeekMessageError> {
+    peek_message_raw(channel, 0)
+}
+
+/// Get the size of the reply to the call with the transaction ID `txid`, if it has arrived, without receiving it.
+pub fn peek_reply(channel: Handle, txid: u16) -> Result<MessageInfo, PeekMessageError> {
+    peek_message_raw(channel, txid)
+}
+
+fn peek_message_raw(channel: Handle, txid: u16) -> Result<MessageInfo, PeekMessageError> {
+    let result = unsafe { raw::syscall1(SYSCALL_PEEK_MESSAGE, channel_with_txid(channel, txid)) };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+
+    Ok(MessageInfo { num_bytes: result.get_bits(16..32), num_handles: result.get_bits(32..48) })
+}
 
-    Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len]))
+/// The message-passing system calls take the transaction ID of a call alongside the `Channel` handle, in the
+/// bits above it.
+fn channel_with_txid(channel: Handle, txid: u16) -> usize {
+    let mut value = channel.0 as usize;
+    value.set_bits(32..48, txid as usize);
+    value
+}
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct ChannelCallFlags: u32 {
+        /// Send the message making the call, but don't wait for the reply. The call's transaction ID is returned,
+        /// and the reply can be received later with `get_reply`.
+        const NO_WAIT = 1 << 0;
+    }
+}
+
+#[repr(C)]
+pub struct ChannelCallDetails {
+    pub bytes_ptr: *const u8,
+    pub num_bytes: usize,
+    pub handles_ptr: *const u32,
+    pub num_handles: usize,
+    pub reply_bytes_ptr: *mut u8,
+    pub reply_bytes_len: usize,
+    pub reply_handles_ptr: *mut u32,
+    pub reply_handles_len: usize,
+    pub flags: u32,
+    /// Written by the kernel with the transaction ID of the call.
+    pub txid: u16,
+}
+
+define_error_type!(ChannelCallError {
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    /// The `Channel` handle must have both the `READ` and `WRITE` rights to make a call through it.
+    ChannelCannotCall => 3,
+    DetailsAddressInvalid => 4,
+    /// A handle to be transferred is invalid, or appears more than once.
+    InvalidTransferredHandle => 5,
+    /// Transferred handles must have the `TRANSFER` right.
+    CannotTransferHandle => 6,
+    BytesAddressInvalid => 7,
+    TooManyBytes => 8,
+    HandlesAddressInvalid => 9,
+    TooManyHandles => 10,
+    OtherEndDisconnected => 11,
+    /// The reply has not been received, because `ChannelCallFlags::NO_WAIT` was passed. The call has still been
+    /// made, and the reply can be received later with `get_reply`, or the call abandoned with
+    /// `channel_cancel_call`.
+    ReplyPending => 12,
+    ReplyBytesAddressInvalid => 13,
+    /// The reply is too large for the buffer given for it. It can still be received with `get_reply`.
+    ReplyBytesBufferTooSmall => 14,
+    ReplyHandlesAddressInvalid => 15,
+    ReplyHandlesBufferTooSmall => 16,
+    /// Every transaction ID is in use by a call that is still in progress through this `Channel` end.
+    TooManyPendingCalls => 17,
+    /// The reply didn't arrive before the timeout elapsed. The call has been abandoned, so the reply is
+    /// discarded if it arrives later.
+    TimedOut => 18,
+});
+
+/// Make a call through the given `Channel` - send a message, and wait for the reply to it, in a single system
+/// call. The message is sent with a transaction ID chosen by the kernel, which the receiver passes back to
+/// `send_reply` to reply to it. Other messages received by the calling end while the call is in progress are left
+/// to be received as normal.
+///
+/// On success, the reply's bytes and handles are returned. If the reply is too large for the buffers given for
+/// it, the call's transaction ID is written to `txid`, so the reply can be received later.
+pub fn channel_call<'b, 'h>(
+    channel: Handle,
+    bytes: &[u8],
+    handles: &[Handle],
+    reply_byte_buffer: &'b mut [u8],
+    reply_handle_buffer: &'h mut [Handle],
+    timeout: Option<Duration>,
+    txid: &mut u16,
+) -> Result<(&'b mut [u8], &'h mut [Handle]), ChannelCallError> {
+    let mut details = ChannelCallDetails {
+        bytes_ptr: bytes.as_ptr(),
+        num_bytes: bytes.len(),
+        handles_ptr: handles.as_ptr() as *const u32,
+        num_handles: handles.len(),
+        reply_bytes_ptr: reply_byte_buffer.as_mut_ptr(),
+        reply_bytes_len: reply_byte_buffer.len(),
+        reply_handles_ptr: reply_handle_buffer.as_mut_ptr() as *mut u32,
+        reply_handles_len: reply_handle_buffer.len(),
+        flags: ChannelCallFlags::empty().bits(),
+        txid: 0,
+    };
+    let result = channel_call_raw(channel, &mut details, timeout);
+    *txid = details.txid;
+    status_from_syscall_repr(result.get_bits(0..16))?;
+
+    let valid_bytes_len = result.get_bits(16..32);
+    let valid_handles_len = result.get_bits(32..48);
+
+    Ok((&mut reply_byte_buffer[0..valid_bytes_len], &mut reply_handle_buffer[0..valid_handles_len]))
+}
+
+/// Make a call through the given `Channel`, without waiting for the reply. Returns the call's transaction ID, which
+/// can be passed to `get_reply` to receive the reply once it arrives (which is signalled by `Signals::REPLIED`).
+pub fn channel_call_no_wait(channel: Handle, bytes: &[u8], handles: &[Handle]) -> Result<u16, ChannelCallError> {
+    let mut details = ChannelCallDetails {
+        bytes_ptr: bytes.as_ptr(),
+        num_bytes: bytes.len(),
+        handles_ptr: handles.as_ptr() as *const u32,
+        num_handles: handles.len(),
+        reply_bytes_ptr: core::ptr::null_mut(),
+        reply_bytes_len: 0,
+        reply_handles_ptr: core::ptr::null_mut(),
+        reply_handles_len: 0,
+        flags: ChannelCallFlags::NO_WAIT.bits(),
+        txid: 0,
+    };
+    match status_from_syscall_repr(channel_call_raw(channel, &mut details, None).get_bits(0..16)) {
+        Err(ChannelCallError::ReplyPending) => Ok(details.txid),
+        Err(err) => Err(err),
+        Ok(()) => unreachable!("Call made with NO_WAIT completed"),
+    }
+}
+
+fn channel_call_raw(channel: Handle, details: &mut ChannelCallDetails, timeout: Option<Duration>) -> usize {
+    unsafe {
+        raw::syscall3(
+            SYSCALL_CHANNEL_CALL,
+            channel.0 as usize,
+            details as *mut ChannelCallDetails as usize,
+            timeout_to_syscall_repr(timeout),
+        )
+    }
+}
+
+define_error_type!(ChannelCancelCallError {
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    /// The handle to the `Channel` end does not have the `READ` right.
+    ChannelCannotReceive => 3,
+});
+
+/// Abandon the call with the transaction ID `txid`, made through the given `Channel` with
+/// `channel_call_no_wait`. The reply is discarded if it has already arrived, or when it arrives later. This
+/// should be used when the caller is no longer interested in the reply, so the transaction ID can be reused.
+pub fn channel_cancel_call(channel: Handle, txid: u16) -> Result<(), ChannelCancelCallError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall1(SYSCALL_CHANNEL_CANCEL_CALL, channel_with_txid(channel, txid))
+    })
+}
+
+define_error_type!(WaitForMessageError {
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    /// No message arrived before the timeout elapsed.
+    TimedOut => 3,
+    /// The handle to the `Channel` end does not have the `READ` right.
+    ChannelCannotReceive => 4,
+});
+
+/// Block until a message is waiting to be received on the given `Channel`, or until `timeout` has elapsed, if one
+/// is given. The message can then be received with `get_message`.
+pub fn wait_for_message(channel: Handle, timeout: Option<Duration>) -> Result<(), WaitForMessageError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_WAIT_FOR_MESSAGE, channel.0 as usize, timeout_to_syscall_repr(timeout))
+    })
+}
+
+define_error_type!(CreatePipeError {
+    InvalidHandleAddress => 1,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 2,
+});
+
+/// The number of bytes a pipe can hold. Writes to a full pipe wait (or fail with `WouldBlock`) until it has been
+/// read from.
+pub const PIPE_CAPACITY: usize = 16384;
+
+/// Create a pipe, which carries a stream of bytes from its write end to its read end. Returns the handles to the
+/// read end and the write end, in that order. Once every handle to the write end has been closed, reads from the
+/// read end return `0` bytes after all of the data written has been read.
+pub fn create_pipe() -> Result<(Handle, Handle), CreatePipeError> {
+    let mut write_end: MaybeUninit<Handle> = MaybeUninit::uninit();
+    let read_end =
+        handle_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CREATE_PIPE, write_end.as_mut_ptr() as usize) })?;
+    Ok((read_end, unsafe { write_end.assume_init() }))
+}
+
+define_error_type!(PipeReadError {
+    InvalidHandle => 1,
+    NotAPipe => 2,
+    /// The handle is to the write end of the pipe, or does not have the `READ` right.
+    PipeCannotBeRead => 3,
+    BufferAddressInvalid => 4,
+    /// The pipe is empty, and the caller does not want the kernel to block.
+    WouldBlock => 5,
+});
+
+/// Read up to `buffer.len()` bytes from the read end of a pipe, returning the number of bytes read. If the pipe is
+/// empty and `block` is `true`, this waits until data is written to it. `0` bytes are read once the write end has
+/// been closed and everything written to the pipe has been read.
+pub fn pipe_read(pipe: Handle, buffer: &mut [u8], block: bool) -> Result<usize, PipeReadError> {
+    let result = unsafe {
+        raw::syscall4(
+            SYSCALL_PIPE_READ,
+            pipe.0 as usize,
+            buffer.as_mut_ptr() as usize,
+            buffer.len(),
+            if block { 1 } else { 0 },
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
+}
+
+define_error_type!(PipeWriteError {
+    InvalidHandle => 1,
+    NotAPipe => 2,
+    /// The handle is to the read end of the pipe, or does not have the `WRITE` right.
+    PipeCannotBeWritten => 3,
+    BufferAddressInvalid => 4,
+    /// The pipe is full, and the caller does not want the kernel to block.
+    WouldBlock => 5,
+    /// The read end of the pipe has been closed, so nothing written to it would be read.
+    ReadEndClosed => 6,
+});
+
+/// Write up to `data.len()` bytes to the write end of a pipe, returning the number of bytes written. This writes
+/// as much as there is space for in the pipe - if it is full and `block` is `true`, this waits until there is
+/// space for at least one byte.
+pub fn pipe_write(pipe: Handle, data: &[u8], block: bool) -> Result<usize, PipeWriteError> {
+    let result = unsafe {
+        raw::syscall4(
+            SYSCALL_PIPE_WRITE,
+            pipe.0 as usize,
+            data.as_ptr() as usize,
+            data.len(),
+            if block { 1 } else { 0 },
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
 }
 
 define_error_type!(WaitForEventError {
@@ -190,15 +939,89 @@ define_error_type!(WaitForEventError {
     NotAnEvent => 2,
     /// No event has occured, and the caller does not want the kernel to block.
     NoEvent => 3,
+    /// No event occured before the timeout elapsed.
+    TimedOut => 4,
+    /// The handle to the `Event` does not have the `READ` right.
+    EventCannotBeWaitedFor => 5,
 });
 
 pub fn wait_for_event(event: Handle, block: bool) -> Result<(), WaitForEventError> {
-    let result = unsafe { raw::syscall2(SYSCALL_WAIT_FOR_EVENT, event.0 as usize, if block { 1 } else { 0 }) };
+    let result = unsafe { raw::syscall3(SYSCALL_WAIT_FOR_EVENT, event.0 as usize, if block { 1 } else { 0 }, 0) };
+    status_from_syscall_repr(result)
+}
+
+/// Block until the event is signalled, or until `timeout` has elapsed.
+pub fn wait_for_event_timeout(event: Handle, timeout: Duration) -> Result<(), WaitForEventError> {
+    let result = unsafe {
+        raw::syscall3(SYSCALL_WAIT_FOR_EVENT, event.0 as usize, 1, timeout_to_syscall_repr(Some(timeout)))
+    };
     status_from_syscall_repr(result)
 }
 
+define_error_type!(AckInterruptError {
+    InvalidHandle => 1,
+    NotAnEvent => 2,
+    /// The handle to the `Event` does not have the `READ` right.
+    EventCannotBeAcknowledged => 3,
+});
+
+/// Acknowledge the interrupt that last signalled an `Event`. Events signalled by level-triggered interrupts (such
+/// as legacy PCI interrupts) stop being signalled until this is called, which should be done once the device has
+/// been serviced (e.g. once its interrupt status register has been cleared). This does nothing for other events,
+/// so drivers can call it regardless of how their device's interrupts are delivered.
+pub fn ack_interrupt(event: Handle) -> Result<(), AckInterruptError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_ACK_INTERRUPT, event.0 as usize) })
+}
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct EventFlags: u32 {
+        /// Count the number of times the event is signalled, rather than coalescing signals that haven't been
+        /// waited for yet. Each successful wait on the event consumes one signal.
+        const COUNTING = 1 << 0;
+    }
+}
+
+define_error_type!(CreateEventError {
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 1,
+});
+
+/// Create an `Event`, which can be signalled with `signal_event` and waited for with `wait_for_event`. The
+/// returned handle has all rights, and can be duplicated with fewer rights (e.g. only `SIGNAL`) to hand to other
+/// tasks.
+pub fn create_event(flags: EventFlags) -> Result<Handle, CreateEventError> {
+    handle_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CREATE_EVENT, flags.bits() as usize) })
+}
+
+define_error_type!(SignalEventError {
+    InvalidHandle => 1,
+    NotAnEvent => 2,
+    /// The handle to the `Event` does not have the `SIGNAL` right.
+    EventCannotBeSignalled => 3,
+});
+
+/// Signal an `Event`, waking any tasks waiting for it.
+pub fn signal_event(event: Handle) -> Result<(), SignalEventError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_SIGNAL_EVENT, event.0 as usize) })
+}
+
+define_error_type!(ClearEventError {
+    InvalidHandle => 1,
+    NotAnEvent => 2,
+    /// The handle to the `Event` does not have the `SIGNAL` right.
+    EventCannotBeCleared => 3,
+});
+
+/// Clear an `Event`, discarding any signals that haven't been waited for yet.
+pub fn clear_event(event: Handle) -> Result<(), ClearEventError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CLEAR_EVENT, event.0 as usize) })
+}
+
 define_error_type!(PollInterestError {
     InvalidHandle => 1,
+    /// The handle does not have the `READ` right.
+    HandleCannotBeRead => 2,
 });
 
 pub fn poll_interest(object: Handle) -> Result<bool, PollInterestError> {
@@ -207,7 +1030,10 @@ pub fn poll_interest(object: Handle) -> Result<bool, PollInterestError> {
     Ok(result.get_bits(16..64) != 0)
 }
 
-define_error_type!(CreateAddressSpaceError {});
+define_error_type!(CreateAddressSpaceError {
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 1,
+});
 
 pub fn create_address_space() -> Result<Handle, CreateAddressSpaceError> {
     handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_CREATE_ADDRESS_SPACE) })
@@ -217,6 +1043,12 @@ define_error_type!(SpawnTaskError {
     InvalidTaskName => 1,
     NotAnAddressSpace => 2,
     InvalidHandleToTransfer => 3,
+    /// The handle to the `AddressSpace` does not have the `WRITE` right.
+    AddressSpaceCannotBeModified => 4,
+    /// One of the handles to transfer does not have the `TRANSFER` right.
+    CannotTransferHandle => 5,
+    /// Something is already mapped where the task's manifest should go (see `manifest::MANIFEST_ADDRESS`).
+    ManifestCannotBeMapped => 6,
 });
 
 #[repr(C)]
@@ -248,3 +1080,886 @@ pub fn spawn_task(
         raw::syscall1(SYSCALL_SPAWN_TASK, &details as *const SpawnTaskDetails as usize)
     })
 }
+
+define_error_type!(TaskCreateError {
+    /// The calling task does not have the `SpawnTask` capability.
+    TaskDoesNotHaveCorrectCapability => 1,
+    InvalidTaskName => 2,
+    /// The handle to the image is invalid, or does not point to a `MemoryObject`.
+    InvalidImage => 3,
+    /// The handle to the image does not have the `READ` right.
+    ImageCannotBeRead => 4,
+    /// The image is not a valid ELF for this platform, or a segment could not be loaded (e.g. because it is both
+    /// writable and executable).
+    InvalidElf => 5,
+    /// One of the handles to transfer is invalid, or appears more than once.
+    InvalidHandleToTransfer => 6,
+    /// One of the handles to transfer does not have the `TRANSFER` right.
+    CannotTransferHandle => 7,
+    /// The arguments are not valid UTF-8.
+    InvalidArguments => 8,
+    /// The new task was asked to have capabilities that the calling task does not have.
+    CapabilitiesNotHeld => 9,
+    /// The handle to the job is invalid, or does not point to a `Job`.
+    InvalidJob => 10,
+    /// The handle to the job does not have the `WRITE` right.
+    JobCannotBeModified => 11,
+    /// The job has been killed, so can't have new tasks created in it.
+    JobKilled => 12,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 13,
+    /// One of the standard handles is not a `Channel` or the right end of a pipe - stdin can be the read end of a
+    /// pipe, and stdout and stderr can be write ends.
+    InvalidStdioHandle => 14,
+    /// Loading the image would exceed the memory limit of the new task's job.
+    MemoryLimitExceeded => 15,
+    /// The pointer to the `TaskCreateDetails` is invalid.
+    InvalidDetails => 16,
+    /// The kernel could not map the new task's `TaskManifest` into its address space.
+    CannotMapManifest => 17,
+    /// The kernel has run out of space for the new task's stacks.
+    OutOfResources => 18,
+});
+
+#[repr(C)]
+pub struct TaskCreateDetails {
+    pub name_ptr: *const u8,
+    pub name_len: usize,
+    pub image: u32,
+    pub image_size: usize,
+    pub object_array: *const u32,
+    pub object_array_len: usize,
+    pub arguments_ptr: *const u8,
+    pub arguments_len: usize,
+    pub capabilities: u32,
+    pub job: u32,
+    /// The handles in `Stdio`, or `0` for those that aren't given.
+    pub stdin: u32,
+    pub stdout: u32,
+    pub stderr: u32,
+}
+
+/// The handles a task is given to use as its standard input, output, and error when it's created with
+/// `task_create` (see `crate::stdio`). Each is either a `Channel` or an end of a pipe, and any of them can be left
+/// out.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[cfg_attr(feature = "can_alloc", derive(ptah::Serialize, ptah::Deserialize))]
+pub struct Stdio {
+    pub stdin: Option<Handle>,
+    pub stdout: Option<Handle>,
+    pub stderr: Option<Handle>,
+}
+
+/// Create a new task from the ELF image held in the `MemoryObject` `image`, which contains `image_size` bytes of
+/// data. The kernel loads the image into a new address space, and transfers `objects` to the new task - if it's
+/// created, the handles are removed from the calling task. The new task can find these, along with its name and
+/// `arguments`, in its `TaskManifest`. `arguments` contains each of the task's arguments, each terminated by a NUL
+/// character. The handles in `stdio` are transferred in the same way, and recorded in the manifest as the new
+/// task's standard handles. The new task is given `capabilities`, which must be a subset of the calling task's
+/// capabilities.
+///
+/// The new task is created in `job`, which must be held with the `WRITE` right. If `job` is `None`, it is
+/// created in the calling task's job.
+pub fn task_create(
+    task_name: &str,
+    image: Handle,
+    image_size: usize,
+    objects: &[Handle],
+    arguments: &str,
+    stdio: Stdio,
+    capabilities: Capabilities,
+    job: Option<Handle>,
+) -> Result<Handle, TaskCreateError> {
+    let details = TaskCreateDetails {
+        name_ptr: task_name as *const str as *const u8,
+        name_len: task_name.len(),
+        image: image.0,
+        image_size,
+        object_array: objects as *const [Handle] as *const u32,
+        object_array_len: objects.len(),
+        arguments_ptr: arguments as *const str as *const u8,
+        arguments_len: arguments.len(),
+        capabilities: capabilities.bits(),
+        job: job.unwrap_or(Handle::ZERO).0,
+        stdin: stdio.stdin.unwrap_or(Handle::ZERO).0,
+        stdout: stdio.stdout.unwrap_or(Handle::ZERO).0,
+        stderr: stdio.stderr.unwrap_or(Handle::ZERO).0,
+    };
+
+    handle_from_syscall_repr(unsafe {
+        raw::syscall1(SYSCALL_TASK_CREATE, &details as *const TaskCreateDetails as usize)
+    })
+}
+
+/// Exit the calling task with the given status. The task's handles are closed, and tasks waiting for it to exit
+/// (with `task_wait`, or by waiting for `Signals::TERMINATED` on its handle) are released.
+pub fn task_exit(status: i32) -> ! {
+    unsafe {
+        raw::syscall1(SYSCALL_TASK_EXIT, status as u32 as usize);
+    }
+    unreachable!("Returned from a task_exit system call!");
+}
+
+define_error_type!(TaskWaitError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `READ` right.
+    TaskCannotBeWaitedFor => 3,
+    StatusAddressInvalid => 4,
+    /// The task did not exit before the timeout elapsed.
+    TimedOut => 5,
+});
+
+/// Block until the task `task` has exited, and get its exit status. If `timeout` is `Some`, give up if the task
+/// hasn't exited after that long.
+pub fn task_wait(task: Handle, timeout: Option<Duration>) -> Result<i32, TaskWaitError> {
+    let mut status: MaybeUninit<i32> = MaybeUninit::uninit();
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(
+            SYSCALL_TASK_WAIT,
+            task.0 as usize,
+            status.as_mut_ptr() as usize,
+            timeout_to_syscall_repr(timeout),
+        )
+    })?;
+    Ok(unsafe { status.assume_init() })
+}
+
+define_error_type!(ThreadCreateError {
+    /// The task's `AddressSpace` cannot contain any more threads, or the kernel has run out of space for their
+    /// stacks.
+    TooManyThreads => 1,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 2,
+    /// The entry point is not in the part of the address space userspace can use.
+    InvalidEntryPoint => 3,
+});
+
+/// Create a new thread of the calling task, which starts executing at `entry_point` with `argument` as its first
+/// argument (the entry point should never return). The thread shares the task's address space, handles, and capabilities, but has its own stack.
+/// Returns a handle to the new thread, which can be used to wait for it to exit with `task_wait`. A thread
+/// exits with `task_exit`, and the task's handles are closed when the last of its threads exits.
+pub fn thread_create(entry_point: usize, argument: usize) -> Result<Handle, ThreadCreateError> {
+    handle_from_syscall_repr(unsafe { raw::syscall2(SYSCALL_THREAD_CREATE, entry_point, argument) })
+}
+
+define_error_type!(WaitOnAddressError {
+    /// The address is not aligned to 4 bytes, or cannot be read by the calling task.
+    AddressInvalid => 1,
+    /// The value at the address was not the expected value, so the task did not wait.
+    ValueChanged => 2,
+    /// The task was not woken before the timeout elapsed.
+    TimedOut => 3,
+});
+
+/// Block until another thread wakes `address` with `wake_address`, as long as it still contains `expected`.
+/// Checking the value and starting to wait happens atomically with respect to `wake_address`, so a wake can't be
+/// missed between the two. If `timeout` is `Some`, give up if the thread hasn't been woken after that long.
+///
+/// This is the building block for blocking locks and condition variables (see `crate::sync`). Waits may also end
+/// spuriously, so the caller should check whatever condition it was waiting for again.
+pub fn wait_on_address(
+    address: &AtomicU32,
+    expected: u32,
+    timeout: Option<Duration>,
+) -> Result<(), WaitOnAddressError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(
+            SYSCALL_WAIT_ON_ADDRESS,
+            address.as_ptr() as usize,
+            expected as usize,
+            timeout_to_syscall_repr(timeout),
+        )
+    })
+}
+
+/// Wake up to `count` of the threads waiting on `address` with `wait_on_address`, in the order they started
+/// waiting. Returns the number of threads that were woken. Only threads in the calling task's address space can be
+/// woken.
+pub fn wake_address(address: &AtomicU32, count: usize) -> usize {
+    unsafe { raw::syscall2(SYSCALL_WAKE_ADDRESS, address.as_ptr() as usize, count) }
+}
+
+/// Block the calling task for (at least) the given duration. The kernel's timer has a fairly coarse granularity,
+/// so this may sleep for up to a timer period longer than requested.
+pub fn sleep(duration: Duration) {
+    unsafe {
+        raw::syscall1(SYSCALL_SLEEP, duration.as_nanos().min(usize::MAX as u128) as usize);
+    }
+}
+
+/// Get the time since the kernel started its timer. This is monotonic, but has the same coarse granularity as
+/// the kernel's timer.
+pub fn get_uptime() -> Duration {
+    Duration::from_nanos(unsafe { raw::syscall0(SYSCALL_GET_UPTIME) } as u64)
+}
+
+/// Get the time since boot from the platform's high-resolution monotonic clock. Unlike `get_uptime`, this is not
+/// limited to the granularity of the kernel's timer, so is suitable for measuring short intervals. See
+/// `crate::time::Instant` for a nicer interface.
+pub fn clock_monotonic() -> Duration {
+    Duration::from_nanos(unsafe { raw::syscall0(SYSCALL_CLOCK_MONOTONIC) } as u64)
+}
+
+define_error_type!(GetTimeError {
+    PlatformDoesNotHaveRtc => 1,
+    TimeAddressInvalid => 2,
+});
+
+/// Get the wall-clock time, as a duration since the Unix epoch. See `crate::time::SystemTime` for a nicer
+/// interface.
+pub fn get_time() -> Result<Duration, GetTimeError> {
+    let mut nanos: MaybeUninit<u64> = MaybeUninit::uninit();
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_GET_TIME, nanos.as_mut_ptr() as usize) })?;
+    Ok(Duration::from_nanos(unsafe { nanos.assume_init() }))
+}
+
+define_error_type!(SetTimeError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    PlatformDoesNotHaveRtc => 2,
+    /// The time is before the Unix epoch, or too far in the future for the platform's real-time clock.
+    InvalidTime => 3,
+});
+
+/// Set the wall-clock time, as a duration since the Unix epoch. This also sets the platform's real-time clock, so
+/// the time persists across reboots. The task must have the `SET_TIME` capability.
+pub fn set_time(time: Duration) -> Result<(), SetTimeError> {
+    let nanos = u64::try_from(time.as_nanos()).map_err(|_| SetTimeError::InvalidTime)?;
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_SET_TIME, nanos as usize) })
+}
+
+define_error_type!(SystemShutdownError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The platform can't be powered off by the kernel, or didn't power off when asked to.
+    NotSupported => 2,
+});
+
+/// Power the system off. This only returns if it fails. The kernel doesn't stop devices or flush any data first,
+/// so this should only be called once they have been (the Platform Bus does this before shutting down - see
+/// `platform_bus::SystemPower`). This requires the `SYSTEM_POWER` capability.
+pub fn system_shutdown() -> Result<(), SystemShutdownError> {
+    status_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_SYSTEM_SHUTDOWN) })
+}
+
+define_error_type!(SystemRebootError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The platform can't be reset by the kernel, or didn't reset when asked to.
+    NotSupported => 2,
+});
+
+/// Reset the system. Like `system_shutdown`, this only returns if it fails, and devices should be stopped first.
+/// This requires the `SYSTEM_POWER` capability.
+pub fn system_reboot() -> Result<(), SystemRebootError> {
+    status_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_SYSTEM_REBOOT) })
+}
+
+/// The priority of a task decides how it is scheduled relative to other tasks. A ready task is always scheduled in
+/// preference to ready tasks of a lower priority, and tasks of the same priority share the CPU in turn.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+#[repr(u8)]
+pub enum Priority {
+    Idle = 0,
+    Low = 1,
+    #[default]
+    Normal = 2,
+    High = 3,
+    Realtime = 4,
+}
+
+impl Priority {
+    pub const NUM_PRIORITIES: usize = 5;
+}
+
+impl TryFrom<usize> for Priority {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Priority::Idle),
+            1 => Ok(Priority::Low),
+            2 => Ok(Priority::Normal),
+            3 => Ok(Priority::High),
+            4 => Ok(Priority::Realtime),
+            _ => Err(()),
+        }
+    }
+}
+
+define_error_type!(SetPriorityError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    InvalidPriority => 3,
+    TaskDoesNotHaveCorrectCapability => 4,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 5,
+});
+
+/// Set the priority of a task. If `task` is `None`, the priority of the calling task is set. This requires the
+/// `SET_PRIORITY` capability.
+pub fn set_priority(task: Option<Handle>, priority: Priority) -> Result<(), SetPriorityError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_SET_PRIORITY, task.unwrap_or(Handle::ZERO).0 as usize, priority as usize)
+    })
+}
+
+define_error_type!(ReadKernelLogError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    PositionAddressInvalid => 2,
+    BufferAddressInvalid => 3,
+});
+
+/// Read the kernel's log into `buffer`, starting from position `from` (positions count every byte the kernel has
+/// ever logged). The kernel only keeps the most recent part of its log, so if `from` has already been overwritten,
+/// the read starts from the oldest byte still kept instead. Returns the position the read started at, and the
+/// number of bytes read - the next read should start from their sum. This requires the `READ_KERNEL_LOG`
+/// capability.
+pub fn read_kernel_log(from: u64, buffer: &mut [u8]) -> Result<(u64, usize), ReadKernelLogError> {
+    let mut position = from;
+    let result = unsafe {
+        raw::syscall3(
+            SYSCALL_READ_KERNEL_LOG,
+            &mut position as *mut u64 as usize,
+            buffer.as_mut_ptr() as usize,
+            buffer.len(),
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok((position, result.get_bits(16..48)))
+}
+
+define_error_type!(GetTraceBuffersError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The kernel has not set up trace buffers on this platform.
+    TracingNotSupported => 2,
+    BufferAddressInvalid => 3,
+    /// The buffer does not have space for a handle for each CPU.
+    BufferNotLargeEnough => 4,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 5,
+});
+
+/// Get a handle to a read-only `MemoryObject` for each CPU's trace buffer, in order of CPU ID, and put them in
+/// `buffer`. Returns the number of handles, which is the number of CPUs. See `poplar::trace` for the layout of
+/// the buffers. This requires the `TRACE` capability.
+pub fn get_trace_buffers(buffer: &mut [Handle]) -> Result<usize, GetTraceBuffersError> {
+    let result = unsafe { raw::syscall2(SYSCALL_GET_TRACE_BUFFERS, buffer.as_mut_ptr() as usize, buffer.len()) };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
+}
+
+define_error_type!(SetTracingError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The kernel has not set up trace buffers on this platform.
+    TracingNotSupported => 2,
+});
+
+/// Turn the kernel's tracepoints on or off. When tracing is turned on, a `TaskName` record is written for every
+/// existing task. This requires the `TRACE` capability.
+pub fn set_tracing(enabled: bool) -> Result<(), SetTracingError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_SET_TRACING, enabled as usize) })
+}
+
+define_error_type!(TaskSetAuditError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+    TaskDoesNotHaveCorrectCapability => 4,
+});
+
+/// Turn auditing of a task's system calls on or off. While a task is audited, the kernel writes a record into the
+/// trace buffers when each of its system calls is made and when it returns (see `poplar::trace`), whether or not
+/// tracing is enabled. If `task` is `None`, the calling task is audited. The flag is shared by all of a task's
+/// threads. This requires the `TRACE` capability.
+pub fn task_set_audit(task: Option<Handle>, enabled: bool) -> Result<(), TaskSetAuditError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_TASK_SET_AUDIT, task.unwrap_or(Handle::ZERO).0 as usize, enabled as usize)
+    })
+}
+
+/// The most events that can be counted for a task at once. The CPU may not be able to count this many, or some
+/// combinations of events, at the same time.
+pub const PERF_MAX_EVENTS: usize = 4;
+
+/// A hardware event that can be counted by the CPU's performance counters, with `perf_configure`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(usize)]
+pub enum PerfEvent {
+    /// Cycles the CPU spent running the task.
+    Cycles = 0,
+    /// Instructions the task executed (retired).
+    Instructions = 1,
+    /// Memory accesses that missed the CPU's caches. On x86_64, these are misses in the last-level cache.
+    CacheMisses = 2,
+    /// Branches that the CPU mispredicted.
+    BranchMisses = 3,
+}
+
+impl TryFrom<usize> for PerfEvent {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PerfEvent::Cycles),
+            1 => Ok(PerfEvent::Instructions),
+            2 => Ok(PerfEvent::CacheMisses),
+            3 => Ok(PerfEvent::BranchMisses),
+            _ => Err(()),
+        }
+    }
+}
+
+define_error_type!(PerfConfigureError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+    TaskDoesNotHaveCorrectCapability => 4,
+    EventsAddressInvalid => 5,
+    /// More than `PERF_MAX_EVENTS` events were given.
+    TooManyEvents => 6,
+    InvalidEvent => 7,
+    /// The CPU can't count the events at the same time, or doesn't have performance counters at all.
+    EventsNotSupported => 8,
+});
+
+/// Start counting `events` for a task, from zero. If `task` is `None`, events are counted for the calling task.
+/// Events are only counted while the task is running in userspace, and are counted for each thread separately.
+/// Any events that were being counted for the task before are replaced - passing no events stops counting. This
+/// requires the `PERF` capability.
+pub fn perf_configure(task: Option<Handle>, events: &[PerfEvent]) -> Result<(), PerfConfigureError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(
+            SYSCALL_PERF_CONFIGURE,
+            task.unwrap_or(Handle::ZERO).0 as usize,
+            events.as_ptr() as usize,
+            events.len(),
+        )
+    })
+}
+
+define_error_type!(PerfReadError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `READ` right.
+    TaskCannotBeRead => 3,
+    TaskDoesNotHaveCorrectCapability => 4,
+    BufferAddressInvalid => 5,
+    /// The buffer does not have space for a count for each event being counted.
+    BufferNotLargeEnough => 6,
+});
+
+/// Read the counts of the events being counted for a task into `counts`, in the order the events were passed to
+/// `perf_configure`. If `task` is `None`, the calling task's counts are read. Returns the number of counts. The
+/// counts of other tasks are only brought up to date when they stop running (e.g. when they block or are
+/// pre-empted), while the calling task's are current. This requires the `PERF` capability.
+pub fn perf_read(task: Option<Handle>, counts: &mut [u64]) -> Result<usize, PerfReadError> {
+    let result = unsafe {
+        raw::syscall3(
+            SYSCALL_PERF_READ,
+            task.unwrap_or(Handle::ZERO).0 as usize,
+            counts.as_mut_ptr() as usize,
+            counts.len(),
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
+}
+
+define_error_type!(GetProfileBuffersError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The kernel has not set up profile buffers on this platform.
+    ProfilingNotSupported => 2,
+    BufferAddressInvalid => 3,
+    /// The buffer does not have space for a handle for each CPU.
+    BufferNotLargeEnough => 4,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 5,
+});
+
+/// Get a handle to a read-only `MemoryObject` for each CPU's profile buffer, in order of CPU ID, and put them in
+/// `buffer`. Returns the number of handles, which is the number of CPUs. See `poplar::profile` for the layout of
+/// the buffers. This requires the `TRACE` capability.
+pub fn get_profile_buffers(buffer: &mut [Handle]) -> Result<usize, GetProfileBuffersError> {
+    let result = unsafe { raw::syscall2(SYSCALL_GET_PROFILE_BUFFERS, buffer.as_mut_ptr() as usize, buffer.len()) };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..48))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(usize)]
+pub enum ProfilingMode {
+    Off = 0,
+    /// Sample the kernel's stack when the kernel is interrupted, and only the interrupted instruction when a task
+    /// is interrupted.
+    Kernel = 1,
+    /// Also walk the stacks of interrupted tasks.
+    KernelAndUser = 2,
+}
+
+impl TryFrom<usize> for ProfilingMode {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ProfilingMode::Off),
+            1 => Ok(ProfilingMode::Kernel),
+            2 => Ok(ProfilingMode::KernelAndUser),
+            _ => Err(()),
+        }
+    }
+}
+
+define_error_type!(SetProfilingError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The kernel has not set up profile buffers on this platform.
+    ProfilingNotSupported => 2,
+    InvalidMode => 3,
+});
+
+/// Turn the kernel's sampling profiler on or off. While it's on, each CPU's timer interrupt writes a sample into
+/// the CPU's profile buffer. This requires the `TRACE` capability.
+pub fn set_profiling(mode: ProfilingMode) -> Result<(), SetProfilingError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_SET_PROFILING, mode as usize) })
+}
+
+define_error_type!(TaskGrantCapabilitiesError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+    TaskDoesNotHaveCorrectCapability => 4,
+    /// The calling task tried to grant capabilities it does not hold itself.
+    CapabilitiesNotHeld => 5,
+    /// The capabilities contain bits that don't correspond to any capability.
+    InvalidCapabilities => 6,
+});
+
+/// Grant `capabilities` to a running task, which can use them from its next system call. The calling task must
+/// have the `MANAGE_CAPABILITIES` capability, and can only grant capabilities it holds itself. The capabilities
+/// are shared by all of the task's threads.
+pub fn task_grant_capabilities(
+    task: Handle,
+    capabilities: Capabilities,
+) -> Result<(), TaskGrantCapabilitiesError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_TASK_GRANT_CAPABILITIES, task.0 as usize, capabilities.bits() as usize)
+    })
+}
+
+define_error_type!(TaskRevokeCapabilitiesError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The handle to the `Task` does not have the `WRITE` right.
+    TaskCannotBeModified => 3,
+    TaskDoesNotHaveCorrectCapability => 4,
+    /// The capabilities contain bits that don't correspond to any capability.
+    InvalidCapabilities => 5,
+});
+
+/// Revoke `capabilities` from a running task (and all of its threads), from its next system call. Revoking a
+/// capability doesn't take away anything the task has already used it to get, such as handles to devices. This
+/// requires the `MANAGE_CAPABILITIES` capability, unless `task` is `None`, in which case the capabilities are
+/// revoked from the calling task - this allows a task to drop capabilities it only needs while initializing.
+pub fn task_revoke_capabilities(
+    task: Option<Handle>,
+    capabilities: Capabilities,
+) -> Result<(), TaskRevokeCapabilitiesError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(
+            SYSCALL_TASK_REVOKE_CAPABILITIES,
+            task.unwrap_or(Handle::ZERO).0 as usize,
+            capabilities.bits() as usize,
+        )
+    })
+}
+
+bitflags::bitflags! {
+    /// Signals describe changes to the state of a kernel object that a task might want to wait for.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Signals: u32 {
+        /// For `Channel` ends, there is at least one message waiting to be received. For the read ends of pipes,
+        /// there is data waiting to be read.
+        const READABLE = 1 << 0;
+        /// For `Event`s, the event has been signalled.
+        const SIGNALLED = 1 << 1;
+        /// For `Channel` ends, the other end of the channel has been closed. For the ends of pipes, every handle
+        /// to the other end has been closed.
+        const PEER_CLOSED = 1 << 2;
+        /// For `Task`s, the task has exited.
+        const TERMINATED = 1 << 3;
+        /// For `Channel` ends, the reply to a call made through the end has arrived.
+        const REPLIED = 1 << 4;
+        /// For the write ends of pipes, there is space in the pipe to write to.
+        const WRITABLE = 1 << 5;
+    }
+}
+
+/// One of the objects to wait on with `object_wait_many`. The kernel writes the signals that are asserted on the
+/// object to `observed`, whether or not they're ones that are being waited for.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct WaitItem {
+    pub handle: u32,
+    pub signals: u32,
+    pub observed: u32,
+}
+
+impl WaitItem {
+    pub fn new(handle: Handle, signals: Signals) -> WaitItem {
+        WaitItem { handle: handle.0, signals: signals.bits(), observed: 0 }
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle(self.handle)
+    }
+
+    pub fn signals(&self) -> Signals {
+        Signals::from_bits_truncate(self.signals)
+    }
+
+    pub fn observed(&self) -> Signals {
+        Signals::from_bits_truncate(self.observed)
+    }
+
+    /// Whether any of the signals being waited for were observed.
+    pub fn is_satisfied(&self) -> bool {
+        self.observed().intersects(self.signals())
+    }
+}
+
+pub const OBJECT_WAIT_MANY_MAX_ITEMS: usize = 32;
+
+define_error_type!(ObjectWaitManyError {
+    ItemsAddressInvalid => 1,
+    TooManyItems => 2,
+    /// One of the handles is invalid.
+    InvalidHandle => 3,
+    /// None of the signals are asserted, and the caller does not want the kernel to block.
+    NoSignals => 4,
+    /// None of the signals were asserted before the timeout elapsed.
+    TimedOut => 5,
+    /// One of the handles does not have the `READ` right.
+    HandleCannotBeRead => 6,
+});
+
+/// Wait until any of the objects in `items` have any of the signals waited on asserted. If `block` is `false`,
+/// this returns `NoSignals` instead of blocking, which can be used to poll many objects at once. The signals
+/// asserted on each object are written back to `items`, including if the wait is unsuccessful.
+pub fn object_wait_many(
+    items: &mut [WaitItem],
+    block: bool,
+    timeout: Option<Duration>,
+) -> Result<(), ObjectWaitManyError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall4(
+            SYSCALL_OBJECT_WAIT_MANY,
+            if items.len() == 0 { 0x0 } else { items.as_mut_ptr() as usize },
+            items.len(),
+            if block { 1 } else { 0 },
+            timeout_to_syscall_repr(timeout),
+        )
+    })
+}
+
+define_error_type!(HandleDuplicateWithRightsError {
+    InvalidHandle => 1,
+    /// The handle does not have the `DUPLICATE` right.
+    HandleCannotBeDuplicated => 2,
+    /// The requested rights are not a subset of the rights of the original handle.
+    RightsNotHeld => 3,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 4,
+});
+
+/// Create a new handle to the same kernel object as `handle`, with only the given `rights`. The rights must be a
+/// subset of the original handle's rights. This can be used to hand out handles with reduced rights to other
+/// tasks.
+pub fn handle_duplicate_with_rights(
+    handle: Handle,
+    rights: HandleRights,
+) -> Result<Handle, HandleDuplicateWithRightsError> {
+    handle_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_HANDLE_DUPLICATE_WITH_RIGHTS, handle.0 as usize, rights.bits() as usize)
+    })
+}
+
+define_error_type!(HandleCloseError {
+    InvalidHandle => 1,
+});
+
+/// Close `handle`, removing it from the calling task. The kernel object it refers to is freed once there are no
+/// more handles to it (and it isn't otherwise in use).
+pub fn handle_close(handle: Handle) -> Result<(), HandleCloseError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_HANDLE_CLOSE, handle.0 as usize) })
+}
+
+/// Describes which memory a device can access through DMA.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct DmaConstraints {
+    /// The highest bus address the device can access. For example, devices that can only produce 32-bit addresses
+    /// should use `0xffff_ffff`.
+    pub max_address: u64,
+    /// The largest area of contiguous memory the device can describe with a single descriptor. The memory object
+    /// is reported as segments no larger than this (rounded down to a whole number of pages).
+    pub max_segment_size: usize,
+    /// A handle to the `DmaDomain` the device is attached to, or `0` if it isn't attached to one. Devices
+    /// attached to a domain can only access memory that has been allocated in that domain.
+    pub domain: u32,
+}
+
+impl DmaConstraints {
+    pub const NONE: DmaConstraints =
+        DmaConstraints { max_address: u64::MAX, max_segment_size: usize::MAX, domain: Handle::ZERO.0 };
+    pub const ADDRESS_32_BIT: DmaConstraints = DmaConstraints { max_address: 0xffff_ffff, ..Self::NONE };
+
+    /// Allocate the memory in the given `DmaDomain`.
+    pub const fn in_domain(self, domain: Handle) -> DmaConstraints {
+        DmaConstraints { domain: domain.0, ..self }
+    }
+
+    /// DMA memory objects are allocated, and split into segments, in whole pages.
+    const PAGE_SIZE: usize = 0x1000;
+
+    /// The size of each segment a DMA memory object will be split into (apart from the last, which may be
+    /// smaller).
+    pub fn segment_size(&self) -> usize {
+        usize::max(mulch::math::align_down(self.max_segment_size, Self::PAGE_SIZE), Self::PAGE_SIZE)
+    }
+
+    /// The number of segments a DMA memory object of `size` bytes will be reported as.
+    pub fn num_segments(&self, size: usize) -> usize {
+        mulch::math::align_up(size, Self::PAGE_SIZE).div_ceil(self.segment_size())
+    }
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        DmaConstraints::NONE
+    }
+}
+
+/// A contiguous area of a DMA memory object, as seen by devices.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[repr(C)]
+pub struct DmaSegment {
+    /// The address a device should use to access this segment. This is not necessarily the same as its physical
+    /// address (e.g. if the device accesses memory through an IOMMU).
+    pub bus_address: u64,
+    pub size: usize,
+}
+
+define_error_type!(CreateDmaMemoryObjectError {
+    InvalidSize => 1,
+    ConstraintsAddressInvalid => 2,
+    SegmentsAddressInvalid => 3,
+    /// The segments buffer is not large enough to describe the memory object. Use
+    /// `DmaConstraints::num_segments` to find how many segments are needed.
+    SegmentsBufferTooSmall => 4,
+    /// There is not enough free memory that satisfies the constraints.
+    CannotSatisfyConstraints => 5,
+    TaskDoesNotHaveCorrectCapability => 6,
+    InvalidDomainHandle => 7,
+    /// The handle to the `DmaDomain` does not have the `WRITE` right.
+    DomainCannotBeModified => 8,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 9,
+    /// Allocating the memory would exceed the memory limit of the calling task's job.
+    MemoryLimitExceeded => 10,
+    /// The memory object was requested to be both writable and executable, which requires the
+    /// `WRITABLE_EXECUTABLE_MEMORY` capability.
+    WritableAndExecutable => 11,
+});
+
+/// Create a MemoryObject that devices can access through DMA. Its memory is physically contiguous, zeroed, and
+/// satisfies the given `constraints`. The bus addresses devices should use to access it are written to `segments`
+/// (in order). This requires the `DMA` capability.
+pub fn create_dma_memory_object(
+    size: usize,
+    flags: MemoryObjectFlags,
+    constraints: &DmaConstraints,
+    segments: &mut [DmaSegment],
+) -> Result<Handle, CreateDmaMemoryObjectError> {
+    handle_from_syscall_repr(unsafe {
+        raw::syscall5(
+            SYSCALL_CREATE_DMA_MEMORY_OBJECT,
+            size,
+            flags.bits() as usize,
+            constraints as *const DmaConstraints as usize,
+            if segments.len() == 0 { 0x0 } else { segments.as_mut_ptr() as usize },
+            segments.len(),
+        )
+    })
+}
+
+/// Which way data is moving through a DMA buffer, which decides which cache maintenance operations are needed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(usize)]
+pub enum DmaDirection {
+    /// The CPU has written data that the device is going to read. Dirty cache lines are written back to memory.
+    ToDevice = 0,
+    /// The device has written data that the CPU is going to read. Stale cache lines are invalidated.
+    FromDevice = 1,
+    /// Cache lines are written back and then invalidated.
+    Bidirectional = 2,
+}
+
+impl TryFrom<usize> for DmaDirection {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DmaDirection::ToDevice),
+            1 => Ok(DmaDirection::FromDevice),
+            2 => Ok(DmaDirection::Bidirectional),
+            _ => Err(()),
+        }
+    }
+}
+
+define_error_type!(DmaSyncError {
+    InvalidMemoryObjectHandle => 1,
+    /// The handle to the `MemoryObject` does not have the `READ` right.
+    MemoryObjectCannotBeRead => 2,
+    /// The `MemoryObject` is not physically contiguous, and so can't be used for DMA.
+    NotPhysicallyContiguous => 3,
+    /// The region to synchronise does not lie within the `MemoryObject`.
+    InvalidRegion => 4,
+    InvalidDirection => 5,
+});
+
+define_error_type!(CreateDmaDomainError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    /// The calling task has reached its job's handle limit.
+    TooManyHandles => 2,
+});
+
+/// Create a `DmaDomain`, which restricts the memory that devices attached to it can access through DMA. Devices
+/// are attached to domains with `dma_domain_attach`. This requires the `PCI_BUS_DRIVER` capability.
+pub fn create_dma_domain() -> Result<Handle, CreateDmaDomainError> {
+    handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_CREATE_DMA_DOMAIN) })
+}
+
+/// Make `length` bytes of a DMA memory object, starting at `offset`, coherent between the CPU and devices. This
+/// must be done before a device reads memory the CPU has written to, and before the CPU reads memory a device has
+/// written to. On platforms where DMA is cache-coherent, this only acts as a memory barrier.
+pub fn dma_sync(
+    memory_object: Handle,
+    offset: usize,
+    length: usize,
+    direction: DmaDirection,
+) -> Result<(), DmaSyncError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall4(SYSCALL_DMA_SYNC, memory_object.0 as usize, offset, length, direction as usize)
+    })
+}
+
+/// Timeouts are passed to the kernel in nanoseconds, with `0` meaning that there is no timeout. A timeout of zero
+/// is therefore rounded up to a nanosecond.
+fn timeout_to_syscall_repr(timeout: Option<Duration>) -> usize {
+    match timeout {
+        Some(timeout) => timeout.as_nanos().clamp(1, usize::MAX as u128) as usize,
+        None => 0,
+    }
+}
@@ -1,4 +1,13 @@
-use super::{raw, SYSCALL_PCI_GET_INFO};
+use super::{
+    raw,
+    result::{define_error_type, status_from_syscall_repr},
+    SYSCALL_DMA_DOMAIN_ATTACH,
+    SYSCALL_DMA_DOMAIN_DETACH,
+    SYSCALL_PCI_CONFIG_READ,
+    SYSCALL_PCI_CONFIG_WRITE,
+    SYSCALL_PCI_GET_INFO,
+};
+use crate::Handle;
 use bit_field::BitField;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -7,6 +16,7 @@ pub enum PciGetInfoError {
     BufferPointerInvalid,
     BufferNotLargeEnough(u32),
     PlatformDoesNotSupportPci,
+    TooManyHandles,
 }
 
 // TODO: it would be cool if we could do this with the define_error_type macro
@@ -19,6 +29,7 @@ impl TryFrom<usize> for PciGetInfoError {
             2 => Ok(Self::BufferPointerInvalid),
             3 => Ok(Self::BufferNotLargeEnough(status.get_bits(16..48) as u32)),
             4 => Ok(Self::PlatformDoesNotSupportPci),
+            5 => Ok(Self::TooManyHandles),
             _ => Err(()),
         }
     }
@@ -35,6 +46,7 @@ impl Into<usize> for PciGetInfoError {
                 result
             }
             Self::PlatformDoesNotSupportPci => 4,
+            Self::TooManyHandles => 5,
         }
     }
 }
@@ -52,3 +64,75 @@ pub fn pci_get_info(buffer_ptr: *mut u8, buffer_size: usize) -> Result<usize, Pc
         Err(PciGetInfoError::try_from(result).unwrap())
     }
 }
+
+define_error_type!(DmaDomainAttachError {
+    InvalidDomainHandle => 1,
+    /// The handle to the `DmaDomain` does not have the `WRITE` right.
+    DomainCannotBeModified => 2,
+    TaskDoesNotHaveCorrectCapability => 3,
+    NoSuchDevice => 4,
+    /// The device is already attached to a domain. It must be detached from it first.
+    DeviceAlreadyAttached => 5,
+});
+
+/// Makes a raw `dma_domain_attach` system call. `device` is the address of a PCI function, encoded as
+/// `segment << 16 | bus << 8 | device << 3 | function`. See [`crate::ddk::pci::dma_domain_attach`] for a nicer
+/// interface.
+pub fn dma_domain_attach(domain: Handle, device: u32) -> Result<(), DmaDomainAttachError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_DMA_DOMAIN_ATTACH, domain.0 as usize, device as usize)
+    })
+}
+
+define_error_type!(DmaDomainDetachError {
+    InvalidDomainHandle => 1,
+    /// The handle to the `DmaDomain` does not have the `WRITE` right.
+    DomainCannotBeModified => 2,
+    TaskDoesNotHaveCorrectCapability => 3,
+    /// The device is not attached to the domain.
+    DeviceNotAttached => 4,
+});
+
+/// Makes a raw `dma_domain_detach` system call. `device` is encoded as for [`dma_domain_attach`]. See
+/// [`crate::ddk::pci::dma_domain_detach`] for a nicer interface.
+pub fn dma_domain_detach(domain: Handle, device: u32) -> Result<(), DmaDomainDetachError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_DMA_DOMAIN_DETACH, domain.0 as usize, device as usize)
+    })
+}
+
+define_error_type!(PciConfigReadError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    PlatformDoesNotSupportPci => 2,
+    NoSuchDevice => 3,
+    /// The offset is not aligned to 4 bytes, or lies outside the device's configuration space.
+    InvalidOffset => 4,
+    ValueAddressInvalid => 5,
+});
+
+/// Makes a raw `pci_config_read` system call, which reads a dword from the configuration space of a PCI
+/// function. `device` is encoded as for [`dma_domain_attach`]. This requires the `PCI_BUS_DRIVER` capability -
+/// device drivers should instead access their device's configuration space through the Platform Bus.
+pub fn pci_config_read(device: u32, offset: u16) -> Result<u32, PciConfigReadError> {
+    let mut value = 0u32;
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_PCI_CONFIG_READ, device as usize, offset as usize, &mut value as *mut u32 as usize)
+    })?;
+    Ok(value)
+}
+
+define_error_type!(PciConfigWriteError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    PlatformDoesNotSupportPci => 2,
+    NoSuchDevice => 3,
+    /// The offset is not aligned to 4 bytes, or lies outside the device's configuration space.
+    InvalidOffset => 4,
+});
+
+/// Makes a raw `pci_config_write` system call, which writes a dword to the configuration space of a PCI
+/// function. See [`pci_config_read`].
+pub fn pci_config_write(device: u32, offset: u16, value: u32) -> Result<(), PciConfigWriteError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_PCI_CONFIG_WRITE, device as usize, offset as usize, value as usize)
+    })
+}
@@ -0,0 +1,52 @@
+use super::{raw, SYSCALL_GET_PLATFORM_DEVICES};
+use bit_field::BitField;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GetPlatformDevicesError {
+    TaskDoesNotHaveCorrectCapability,
+    BufferPointerInvalid,
+    BufferNotLargeEnough(u32),
+    TooManyHandles,
+}
+
+impl TryFrom<usize> for GetPlatformDevicesError {
+    type Error = ();
+
+    fn try_from(status: usize) -> Result<Self, Self::Error> {
+        match status.get_bits(0..16) {
+            1 => Ok(Self::TaskDoesNotHaveCorrectCapability),
+            2 => Ok(Self::BufferPointerInvalid),
+            3 => Ok(Self::BufferNotLargeEnough(status.get_bits(16..48) as u32)),
+            4 => Ok(Self::TooManyHandles),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Into<usize> for GetPlatformDevicesError {
+    fn into(self) -> usize {
+        match self {
+            Self::TaskDoesNotHaveCorrectCapability => 1,
+            Self::BufferPointerInvalid => 2,
+            Self::BufferNotLargeEnough(num_needed) => {
+                let mut result = 3;
+                result.set_bits(16..48, num_needed as usize);
+                result
+            }
+            Self::TooManyHandles => 4,
+        }
+    }
+}
+
+/// Makes a raw `get_platform_devices` system call, given a pointer to a buffer and the size of the buffer. On
+/// success, returns the number of entries written into the buffer. See
+/// [`crate::ddk::platform::get_platform_devices_vec`] for a nicer interface.
+pub fn get_platform_devices(buffer_ptr: *mut u8, buffer_size: usize) -> Result<usize, GetPlatformDevicesError> {
+    let result = unsafe { raw::syscall2(SYSCALL_GET_PLATFORM_DEVICES, buffer_ptr as usize, buffer_size) };
+
+    if result.get_bits(0..16) == 0 {
+        Ok(result.get_bits(16..48))
+    } else {
+        Err(GetPlatformDevicesError::try_from(result).unwrap())
+    }
+}
@@ -0,0 +1,57 @@
+use core::arch::asm;
+
+pub unsafe fn syscall0(number: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0", inlateout("x0") number => result);
+    }
+    result
+}
+
+pub unsafe fn syscall1(number: usize, a: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0", inlateout("x0") number => result, in("x1") a);
+    }
+    result
+}
+
+pub unsafe fn syscall2(number: usize, a: usize, b: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0", inlateout("x0") number => result, in("x1") a, in("x2") b);
+    }
+    result
+}
+
+pub unsafe fn syscall3(number: usize, a: usize, b: usize, c: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0", inlateout("x0") number => result, in("x1") a, in("x2") b, in("x3") c);
+    }
+    result
+}
+
+pub unsafe fn syscall4(number: usize, a: usize, b: usize, c: usize, d: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("svc #0", inlateout("x0") number => result, in("x1") a, in("x2") b, in("x3") c, in("x4") d);
+    }
+    result
+}
+
+pub unsafe fn syscall5(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!(
+            "svc #0",
+            inlateout("x0") number => result,
+            in("x1") a,
+            in("x2") b,
+            in("x3") c,
+            in("x4") d,
+            in("x5") e
+        );
+    }
+    result
+}
@@ -0,0 +1,54 @@
+//! Creating threads of the calling task. `std::thread` is built on top of this, and should usually be used
+//! instead - this exists so that `poplar` itself can create threads (e.g. the async runtime's worker threads).
+
+use crate::{
+    syscall::{self, ThreadCreateError},
+    Handle,
+};
+use alloc::boxed::Box;
+
+/// Create a new thread of the calling task, which runs `main` and then exits. Returns a handle to the thread,
+/// which can be used to wait for it to exit.
+pub fn spawn_raw(main: Box<dyn FnOnce() + Send>) -> Result<Handle, ThreadCreateError> {
+    // Box the closure again to get a thin pointer to pass to the new thread
+    let main = Box::into_raw(Box::new(main));
+    syscall::thread_create(_thread_start as usize, main as usize).map_err(|err| {
+        drop(unsafe { Box::from_raw(main) });
+        err
+    })
+}
+
+/// New threads start here, with a pointer to their boxed closure as their argument. Like `_start`, this makes sure
+/// the thread is set up to run Rust code before jumping to `rust_thread_entry`.
+#[cfg(target_arch = "x86_64")]
+#[naked]
+unsafe extern "C" fn _thread_start() -> ! {
+    core::arch::naked_asm!("jmp {}", sym rust_thread_entry)
+}
+
+#[cfg(target_arch = "riscv64")]
+#[naked]
+unsafe extern "C" fn _thread_start() -> ! {
+    core::arch::naked_asm!(
+        "
+        .option push
+        .option norelax
+        lla gp, __global_pointer$
+        .option pop
+        j {}
+        ",
+        sym rust_thread_entry
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+#[naked]
+unsafe extern "C" fn _thread_start() -> ! {
+    core::arch::naked_asm!("b {}", sym rust_thread_entry)
+}
+
+unsafe extern "C" fn rust_thread_entry(main: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    syscall::task_exit(0)
+}
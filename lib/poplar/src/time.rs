@@ -0,0 +1,256 @@
+//! Measuring time. `Instant` is a monotonic, high-resolution clock, suitable for measuring how long things take.
+//! `SystemTime` is wall-clock time, which the kernel keeps track of using the platform's real-time clock. Unlike
+//! `Instant`, it is not monotonic - it can be changed with `syscall::set_time`.
+//!
+//! Both are read from the vDSO page (see `vdso`) where possible, falling back to system calls where they aren't.
+
+use crate::{syscall, vdso};
+use core::{error, fmt, ops, time::Duration};
+
+/// A measurement of the platform's monotonic clock, which is guaranteed to never go backwards. Instants are
+/// only meaningful relative to each other. This mirrors `Instant` in Rust's real `std`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(vdso::clock_monotonic().unwrap_or_else(syscall::clock_monotonic))
+    }
+
+    /// Get the time elapsed from `earlier` to this instant, or zero if `earlier` is later than this instant.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Instant)
+    }
+}
+
+impl ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("Overflow when adding duration to Instant")
+    }
+}
+
+impl ops::Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("Overflow when subtracting duration from Instant")
+    }
+}
+
+impl ops::AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl ops::SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+
+/// A point in wall-clock time, represented as a duration since the Unix epoch. This mirrors `SystemTime` in Rust's
+/// real `std`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct SystemTime(Duration);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+/// Returned by `SystemTime::duration_since` if the other time is later. `duration` is how much later it is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SystemTimeError(pub Duration);
+
+impl SystemTimeError {
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl fmt::Display for SystemTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "second time provided was later than self")
+    }
+}
+
+impl error::Error for SystemTimeError {}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = UNIX_EPOCH;
+
+    /// Get the current wall-clock time. Panics if the platform does not have a real-time clock.
+    pub fn now() -> SystemTime {
+        match vdso::clock_realtime() {
+            Some(time) => SystemTime(time),
+            None => SystemTime(syscall::get_time().expect("Failed to get the time")),
+        }
+    }
+
+    pub const fn from_unix_duration(duration: Duration) -> SystemTime {
+        SystemTime(duration)
+    }
+
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        self.0.checked_sub(earlier.0).ok_or_else(|| SystemTimeError(earlier.0 - self.0))
+    }
+
+    pub fn elapsed(&self) -> Result<Duration, SystemTimeError> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration).map(SystemTime)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration).map(SystemTime)
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_timestamp(self.0.as_secs())
+    }
+}
+
+impl ops::Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        self.checked_add(duration).expect("Overflow when adding duration to SystemTime")
+    }
+}
+
+impl ops::Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        self.checked_sub(duration).expect("Overflow when subtracting duration from SystemTime")
+    }
+}
+
+impl ops::AddAssign<Duration> for SystemTime {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl ops::SubAssign<Duration> for SystemTime {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+/// A calendar date and time of day, in UTC. This is the form real-time clocks and filesystems generally store
+/// times in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    /// `1` to `12`
+    pub month: u8,
+    /// `1` to `31`
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert a number of seconds since the Unix epoch to a date and time. Times after the year 65535 are not
+    /// supported.
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = timestamp / 86400;
+        let seconds_of_day = timestamp % 86400;
+
+        /*
+         * This is Howard Hinnant's `civil_from_days` algorithm, which works in 400-year eras starting on the 1st
+         * of March, so the leap day is at the end of each year.
+         */
+        let z = days + 719468;
+        let era = z / 146097;
+        let day_of_era = z % 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+
+    /// Convert the date and time to a number of seconds since the Unix epoch. Dates before the epoch are not
+    /// supported, and return `None`, as do invalid dates.
+    pub fn to_unix_timestamp(&self) -> Option<u64> {
+        if self.year < 1970
+            || !(1..=12).contains(&self.month)
+            || self.day == 0
+            || self.day > days_in_month(self.year, self.month)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+        {
+            return None;
+        }
+
+        // This is the inverse of the algorithm in `from_unix_timestamp` (`days_from_civil`)
+        let year = self.year as u64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let shifted_month = if self.month > 2 { self.month - 3 } else { self.month + 9 } as u64;
+        let day_of_year = (153 * shifted_month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        Some(days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
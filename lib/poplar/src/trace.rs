@@ -0,0 +1,118 @@
+//! The kernel has static tracepoints in its scheduler, IPC, and interrupt paths, which write fixed-size records
+//! into a ring buffer for each CPU while tracing is enabled. A task with the `TRACE` capability can get read-only
+//! `MemoryObject`s for the buffers with `get_trace_buffers`, and turn tracing on and off with `set_tracing`. It
+//! can also audit individual tasks with `task_set_audit`, which records every system call they make.
+//!
+//! Each buffer starts with a `TraceBufferHeader`, followed by space for `TRACE_BUFFER_RECORDS` records. Like the
+//! kernel log, records are numbered by how many have ever been written to the buffer, and record `n` is kept at
+//! index `n % TRACE_BUFFER_RECORDS` until it's overwritten.
+
+use core::mem;
+
+/// The size of each CPU's trace buffer, in bytes.
+pub const TRACE_BUFFER_SIZE: usize = 0x10000;
+/// The offset of the first record from the start of a trace buffer.
+pub const TRACE_RECORDS_OFFSET: usize = 64;
+pub const TRACE_BUFFER_RECORDS: usize = (TRACE_BUFFER_SIZE - TRACE_RECORDS_OFFSET) / mem::size_of::<TraceRecord>();
+/// The number of bytes of a task's name that are kept in a `TaskName` record.
+pub const TRACE_TASK_NAME_LENGTH: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TraceBufferHeader {
+    /// The number of records that have ever been written to the buffer. This is updated after each record has
+    /// been written.
+    pub head: u64,
+    /// The ID of the CPU the buffer belongs to.
+    pub cpu: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TraceRecord {
+    /// When the record was written, in nanoseconds since boot (from `clock_monotonic`).
+    pub timestamp: u64,
+    /// The `TraceEvent` this record is for.
+    pub event: u32,
+    pub cpu: u32,
+    /// Data specific to the type of event. See `TraceEvent`.
+    pub data: [u64; 3],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum TraceEvent {
+    /// The CPU switched from one task to another. `data` is `[from, to, 0]`, with the IDs of the tasks.
+    TaskSwitch = 1,
+    /// Gives the name of a task, so the task IDs in other records can be named. The kernel writes one of these
+    /// for every task when tracing is enabled, and for each task created while it is. `data` is
+    /// `[task, name...]`, where the rest of the data is the first `TRACE_TASK_NAME_LENGTH` bytes of the name,
+    /// padded with zeros.
+    TaskName = 2,
+    /// The CPU started handling an interrupt. `data` is `[vector, 0, 0]`, with the platform's number for the
+    /// interrupt.
+    InterruptEnter = 3,
+    /// The CPU finished handling an interrupt. `data` is the same as for the `InterruptEnter` record.
+    InterruptExit = 4,
+    /// A message was sent down a channel. `data` is `[channel, bytes, handles]`, with the ID of the end it was
+    /// sent from.
+    ChannelSend = 5,
+    /// A message was received from a channel. `data` is `[channel, bytes, handles]`, with the ID of the end it was
+    /// received from.
+    ChannelReceive = 6,
+    /// An audited task made a system call. `data` is `[task | (number << 48), a, b]`, with the ID of the task in
+    /// the lower 48 bits of the first item, and the first two parameters of the call. Records of audited tasks'
+    /// system calls are written even when tracing isn't enabled.
+    SyscallEnter = 7,
+    /// A system call made by an audited task returned. `data` is `[task | (number << 48), result, 0]`, where
+    /// `result` is the raw value returned to the task. For calls that block, this is written once the task is
+    /// woken up and the call returns.
+    SyscallExit = 8,
+}
+
+impl TryFrom<u32> for TraceEvent {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(TraceEvent::TaskSwitch),
+            2 => Ok(TraceEvent::TaskName),
+            3 => Ok(TraceEvent::InterruptEnter),
+            4 => Ok(TraceEvent::InterruptExit),
+            5 => Ok(TraceEvent::ChannelSend),
+            6 => Ok(TraceEvent::ChannelReceive),
+            7 => Ok(TraceEvent::SyscallEnter),
+            8 => Ok(TraceEvent::SyscallExit),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Pack the ID of a task and the number of a system call it made into the first item of the data of a
+/// `SyscallEnter` or `SyscallExit` record.
+pub fn pack_syscall(task: u64, number: usize) -> u64 {
+    (task & 0xffff_ffff_ffff) | ((number as u64) << 48)
+}
+
+/// Unpack the ID of a task and the number of a system call from the first item of the data of a `SyscallEnter`
+/// or `SyscallExit` record.
+pub fn unpack_syscall(packed: u64) -> (u64, usize) {
+    (packed & 0xffff_ffff_ffff, (packed >> 48) as usize)
+}
+
+/// Pack the start of a task's name into the data of a `TaskName` record.
+pub fn pack_task_name(name: &str) -> [u64; 2] {
+    let mut bytes = [0u8; TRACE_TASK_NAME_LENGTH];
+    let length = usize::min(name.len(), TRACE_TASK_NAME_LENGTH);
+    bytes[0..length].copy_from_slice(&name.as_bytes()[0..length]);
+    [u64::from_le_bytes(bytes[0..8].try_into().unwrap()), u64::from_le_bytes(bytes[8..16].try_into().unwrap())]
+}
+
+/// Unpack the name of a task from the data of a `TaskName` record. This stops at the first zero, and returns
+/// `None` if the name isn't valid UTF-8 (e.g. because it was cut off part-way through a character).
+pub fn unpack_task_name(data: &[u64; 2], buffer: &mut [u8; TRACE_TASK_NAME_LENGTH]) -> Option<&str> {
+    buffer[0..8].copy_from_slice(&data[0].to_le_bytes());
+    buffer[8..16].copy_from_slice(&data[1].to_le_bytes());
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(TRACE_TASK_NAME_LENGTH);
+    core::str::from_utf8(&buffer[0..length]).ok()
+}
@@ -0,0 +1,148 @@
+//! The kernel maps a read-only page into every address space at `VDSO_ADDRESS`, which it keeps up to date with the
+//! parameters of the platform's clocks and some information about the system. This module reads it, which lets
+//! tasks get the time without paying for a system call each time - `time::Instant` and `time::SystemTime` use it
+//! where they can.
+//!
+//! The kernel can update the page at any time, so it's protected by a sequence lock: the kernel makes
+//! `VdsoData::sequence` odd while it's writing to the page, and even again once it's done, and readers retry if
+//! the sequence was odd or changed while they were reading.
+
+use core::{
+    hint,
+    sync::atomic::{fence, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The address the kernel maps the vDSO page at, just below the task's manifest.
+pub const VDSO_ADDRESS: usize = 0x1fff_f000;
+
+/// Set if `counter_frequency` and `counter_boot_value` describe the monotonic clock, and userspace can read its
+/// counter directly.
+pub const FLAG_COUNTER: u32 = 1 << 0;
+/// Set if `realtime_offset` is valid. This isn't set until the kernel first reads the platform's real-time clock,
+/// which happens the first time a task gets the time with `syscall::get_time`.
+pub const FLAG_REALTIME: u32 = 1 << 1;
+
+/// The layout of the vDSO page. This is written by the kernel, and read by userspace with the functions in this
+/// module. Every field is atomic so the kernel can update it while tasks are reading it.
+#[derive(Debug)]
+#[repr(C)]
+pub struct VdsoData {
+    /// Odd while the kernel is updating the page. See the module documentation.
+    pub sequence: AtomicU32,
+    /// A combination of the `FLAG_*` constants, which say which of the other fields are valid.
+    pub flags: AtomicU32,
+    /// The frequency of the counter behind the monotonic clock, in Hz. This is the TSC on x86_64, the `time` CSR
+    /// on RISC-V, and the system counter (`CNTPCT_EL0`) on AArch64.
+    pub counter_frequency: AtomicU64,
+    /// The value of the counter when the monotonic clock was zero.
+    pub counter_boot_value: AtomicU64,
+    /// The wall-clock time when the monotonic clock was zero, in nanoseconds since the Unix epoch.
+    pub realtime_offset: AtomicU64,
+    /// The number of CPUs the kernel is scheduling tasks on, or zero if it hasn't said yet.
+    pub cpu_count: AtomicU32,
+}
+
+impl VdsoData {
+    pub const fn new() -> VdsoData {
+        VdsoData {
+            sequence: AtomicU32::new(0),
+            flags: AtomicU32::new(0),
+            counter_frequency: AtomicU64::new(0),
+            counter_boot_value: AtomicU64::new(0),
+            realtime_offset: AtomicU64::new(0),
+            cpu_count: AtomicU32::new(0),
+        }
+    }
+
+    /// Update the page. Callers must make sure only one writer can be updating the page at once.
+    pub fn write(&self, f: impl FnOnce(&VdsoData)) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        f(self);
+        self.sequence.store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Read from the page, retrying until we get a consistent view of it. `f` may be called more than once.
+    pub fn read<T>(&self, f: impl Fn(&VdsoData) -> T) -> T {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+
+            let value = f(self);
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return value;
+            }
+        }
+    }
+}
+
+/// Get the vDSO page mapped into the calling task's address space.
+pub fn data() -> &'static VdsoData {
+    unsafe { &*(VDSO_ADDRESS as *const VdsoData) }
+}
+
+/// Get the time since boot from the platform's monotonic clock, without making a system call. This is computed in
+/// the same way as the kernel does for `syscall::clock_monotonic`, so the two always agree. Returns `None` if the
+/// platform doesn't let userspace read its counter, in which case the system call should be used instead.
+pub fn clock_monotonic() -> Option<Duration> {
+    data().read(monotonic)
+}
+
+/// Get the wall-clock time, as a duration since the Unix epoch, without making a system call. Returns `None` if
+/// the time isn't available from the vDSO page, in which case `syscall::get_time` should be used instead.
+pub fn clock_realtime() -> Option<Duration> {
+    data().read(|data| {
+        if data.flags.load(Ordering::Relaxed) & FLAG_REALTIME == 0 {
+            return None;
+        }
+        let offset = Duration::from_nanos(data.realtime_offset.load(Ordering::Relaxed));
+        monotonic(data).map(|monotonic| offset + monotonic)
+    })
+}
+
+/// Get the number of CPUs the kernel is scheduling tasks on, if it has told us.
+pub fn cpu_count() -> Option<usize> {
+    let count = data().cpu_count.load(Ordering::Relaxed);
+    (count != 0).then_some(count as usize)
+}
+
+fn monotonic(data: &VdsoData) -> Option<Duration> {
+    if data.flags.load(Ordering::Relaxed) & FLAG_COUNTER == 0 {
+        return None;
+    }
+    let frequency = data.counter_frequency.load(Ordering::Relaxed);
+    let ticks = read_counter().saturating_sub(data.counter_boot_value.load(Ordering::Relaxed));
+    // Split the calculation so the nanoseconds don't overflow until the clock has been running for centuries
+    Some(Duration::new(ticks / frequency, ((ticks % frequency) * 1_000_000_000 / frequency) as u32))
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        fn read_counter() -> u64 {
+            unsafe { core::arch::x86_64::_rdtsc() }
+        }
+    } else if #[cfg(target_arch = "riscv64")] {
+        fn read_counter() -> u64 {
+            let value: u64;
+            unsafe {
+                core::arch::asm!("rdtime {}", out(reg) value, options(nostack));
+            }
+            value
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        fn read_counter() -> u64 {
+            let value: u64;
+            unsafe {
+                // The `isb` stops the processor reading the counter early, before the sequence count
+                core::arch::asm!("isb", "mrs {}, cntpct_el0", out(reg) value, options(nostack));
+            }
+            value
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::convert::TryFrom;
+use syn::{Attribute, Error, Lit, Meta, NestedMeta, Result};
+
+/*
+ * Attributes are of the form `#[ptah(tagged)]`, `#[ptah(since = 2)]` etc. This collects the items from every
+ * `ptah` attribute on an item, so they can be interpreted by the type of item they're on.
+ */
+fn ptah_items(attrs: &[Attribute]) -> Result<Vec<NestedMeta>> {
+    let mut items = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("ptah")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => items.extend(list.nested),
+            other => return Err(Error::new_spanned(other, "expected `#[ptah(...)]`")),
+        }
+    }
+    Ok(items)
+}
+
+fn parse_int<T>(lit: &Lit) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match lit {
+        Lit::Int(int) => int.base10_parse(),
+        other => Err(Error::new_spanned(other, "expected an integer")),
+    }
+}
+
+/// Attributes on a struct or enum.
+#[derive(Default)]
+pub struct ContainerAttrs {
+    /// Whether the struct's fields should be serialized with tags, so fields can be added to it later.
+    pub tagged: bool,
+}
+
+impl ContainerAttrs {
+    pub fn parse(attrs: &[Attribute]) -> Result<ContainerAttrs> {
+        let mut result = ContainerAttrs::default();
+        for item in ptah_items(attrs)? {
+            match item {
+                NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("tagged") => result.tagged = true,
+                other => return Err(Error::new_spanned(other, "unknown ptah attribute")),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Attributes on a field of a struct.
+#[derive(Default)]
+pub struct FieldAttrs {
+    /// The field's tag, if it's not the index of the field.
+    pub tag: Option<u16>,
+    /// The version the field was added in.
+    pub since: Option<u32>,
+    /// Whether the field should take its default value if it's missing.
+    pub default: bool,
+}
+
+impl FieldAttrs {
+    pub fn parse(attrs: &[Attribute]) -> Result<FieldAttrs> {
+        let mut result = FieldAttrs::default();
+        for item in ptah_items(attrs)? {
+            match item {
+                NestedMeta::Meta(Meta::NameValue(ref name_value)) if name_value.path.is_ident("tag") => {
+                    result.tag = Some(parse_int(&name_value.lit)?)
+                }
+                NestedMeta::Meta(Meta::NameValue(ref name_value)) if name_value.path.is_ident("since") => {
+                    result.since = Some(parse_int(&name_value.lit)?)
+                }
+                NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("default") => result.default = true,
+                other => return Err(Error::new_spanned(other, "unknown ptah attribute")),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Fields that were added in a later version of a struct may be missing, and so take their default value.
+    pub fn optional(&self) -> bool {
+        self.default || self.since.is_some()
+    }
+
+    pub fn is_plain(&self) -> bool {
+        self.tag.is_none() && self.since.is_none() && !self.default
+    }
+}
+
+/// Attributes on a variant of an enum.
+#[derive(Default)]
+pub struct VariantAttrs {
+    /// The version the variant was added in.
+    pub since: Option<u32>,
+}
+
+impl VariantAttrs {
+    pub fn parse(attrs: &[Attribute]) -> Result<VariantAttrs> {
+        let mut result = VariantAttrs::default();
+        for item in ptah_items(attrs)? {
+            match item {
+                NestedMeta::Meta(Meta::NameValue(ref name_value)) if name_value.path.is_ident("since") => {
+                    result.since = Some(parse_int(&name_value.lit)?)
+                }
+                other => return Err(Error::new_spanned(other, "unknown ptah attribute")),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// A field of a tagged struct, with its attributes resolved.
+pub struct TaggedField<'a> {
+    pub field: &'a syn::Field,
+    pub tag: u16,
+    pub attrs: FieldAttrs,
+}
+
+/// Resolve the tags of the fields of a tagged struct. Fields are tagged with their index unless they have an
+/// explicit `#[ptah(tag = N)]`, and each tag must be unique.
+pub fn tagged_fields(fields: &syn::FieldsNamed) -> Result<Vec<TaggedField<'_>>> {
+    let mut result: Vec<TaggedField> = Vec::new();
+    for (i, field) in fields.named.iter().enumerate() {
+        let attrs = FieldAttrs::parse(&field.attrs)?;
+        let tag = match attrs.tag {
+            Some(tag) => tag,
+            None => u16::try_from(i).map_err(|_| Error::new_spanned(field, "too many fields to tag"))?,
+        };
+        if result.iter().any(|other| other.tag == tag) {
+            return Err(Error::new_spanned(field, format!("duplicate field tag: {}", tag)));
+        }
+        result.push(TaggedField { field, tag, attrs });
+    }
+    Ok(result)
+}
+
+/// Check that none of the fields have attributes that only make sense on the fields of tagged structs.
+pub fn check_untagged_fields(fields: &syn::Fields) -> Result<()> {
+    for field in fields.iter() {
+        if !FieldAttrs::parse(&field.attrs)?.is_plain() {
+            return Err(Error::new_spanned(
+                field,
+                "field attributes can only be used in `#[ptah(tagged)]` structs",
+            ));
+        }
+    }
+    Ok(())
+}
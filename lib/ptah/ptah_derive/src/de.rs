@@ -1,3 +1,4 @@
+use crate::attr::{self, ContainerAttrs};
 use proc_macro2::TokenStream;
 use quote::{format_ident, quote, quote_spanned};
 use syn::{
@@ -13,25 +14,34 @@ use syn::{
     Generics,
     Ident,
     Index,
+    LifetimeDef,
+    Result,
 };
 
 // TODO: work out how to throw errors properly (apparently there's an experimental Diagnostics API?)
 // Serde doesn't use it but it might just not have been updated yet / waiting for it to be stable
 pub fn impl_deserialize(input: DeriveInput) -> proc_macro::TokenStream {
+    let body = match generate_body(&input) {
+        Ok(body) => body,
+        Err(err) => return err.to_compile_error().into(),
+    };
     let name = input.ident;
-    let body = generate_body(&name, &input.data);
 
     /*
      * We need to split the generics into different parts that can be `quote!`ed to produce the `impl` block. We
      * need to add a new lifetime `'de`, but we can't just add it to the `Generics` as you might expect because
      * this adds it to the type (e.g. it emits `Foo<'de>`), so we have to do... this.
      *
-     * We call the lifetime `'_de` to reduce the chance it collides with a lifetime on the type.
+     * We call the lifetime `'_de` to reduce the chance it collides with a lifetime on the type. Types can borrow
+     * from the buffer they're deserialized from (e.g. with `&'a str` fields), so `'_de` must outlive each of the
+     * type's lifetimes.
      */
     let generics = add_trait_bounds(input.generics);
     let generics_with_de_lifetime = {
         let mut generics_with_lifetime = generics.clone();
-        generics_with_lifetime.params.push(parse_quote!('_de));
+        let mut de_lifetime: LifetimeDef = parse_quote!('_de);
+        de_lifetime.bounds.extend(generics.lifetimes().map(|param| param.lifetime.clone()));
+        generics_with_lifetime.params.push(GenericParam::Lifetime(de_lifetime));
         generics_with_lifetime
     };
     let (impl_generics, ty_generics, where_clause) = {
@@ -63,14 +73,22 @@ fn add_trait_bounds(mut generics: Generics) -> Generics {
     generics
 }
 
-fn generate_body(name: &Ident, data: &Data) -> TokenStream {
-    match data {
+fn generate_body(input: &DeriveInput) -> Result<TokenStream> {
+    let name = &input.ident;
+    let container_attrs = ContainerAttrs::parse(&input.attrs)?;
+
+    /*
+     * Unsupported uses of attributes are reported by the `Serialize` derive, so we only need to check the
+     * attributes that change what we generate here.
+     */
+    match &input.data {
         Data::Struct(ref struct_data) => match struct_data.fields {
-            Fields::Named(ref fields) => generate_for_struct(name, fields),
-            Fields::Unnamed(ref fields) => generate_for_tuple(name, fields),
-            Fields::Unit => quote! {},
+            Fields::Named(ref fields) if container_attrs.tagged => generate_for_tagged_struct(name, fields),
+            Fields::Named(ref fields) => Ok(generate_for_struct(name, fields)),
+            Fields::Unnamed(ref fields) => Ok(generate_for_tuple(name, fields)),
+            Fields::Unit => Ok(quote! {}),
         },
-        Data::Enum(ref enum_data) => generate_for_enum(name, enum_data),
+        Data::Enum(ref enum_data) => Ok(generate_for_enum(name, enum_data)),
         Data::Union(_) => todo!(),
     }
 }
@@ -97,6 +115,51 @@ fn generate_for_struct(name: &Ident, fields: &FieldsNamed) -> TokenStream {
     }
 }
 
+fn generate_for_tagged_struct(name: &Ident, fields: &FieldsNamed) -> Result<TokenStream> {
+    /*
+     * The fields of a tagged struct can arrive in any order, and can be missing (if they were added in a later
+     * version than the sender was built against) or unknown to us (if they were added in a later version than we
+     * were built against). We deserialize each field we know about into an `Option` local, skip any we don't, and
+     * then check that we've got every field we need.
+     */
+    let fields = attr::tagged_fields(fields)?;
+
+    let declare_each = fields.iter().map(|field| {
+        let local = format_ident!("field_{}", field.field.ident.as_ref().unwrap());
+        let field_type = &field.field.ty;
+        quote_spanned!(field.field.span() => let mut #local: Option<#field_type> = None;)
+    });
+
+    let match_each = fields.iter().map(|field| {
+        let local = format_ident!("field_{}", field.field.ident.as_ref().unwrap());
+        let tag = field.tag;
+        quote_spanned!(field.field.span() => #tag => #local = Some(ptah::de::TaggedField::deserialize(field)?),)
+    });
+
+    let struct_init = fields.iter().map(|field| {
+        let field_name = &field.field.ident;
+        let local = format_ident!("field_{}", field.field.ident.as_ref().unwrap());
+        let tag = field.tag;
+        if field.attrs.optional() {
+            quote_spanned!(field.field.span() => #field_name: #local.unwrap_or_default(),)
+        } else {
+            quote_spanned!(field.field.span() => #field_name: #local.ok_or(ptah::de::Error::MissingField(#tag))?,)
+        }
+    });
+
+    Ok(quote! {
+        #(#declare_each)*
+        let mut fields = ptah::Deserializer::deserialize_tagged_struct(deserializer)?;
+        while let Some((tag, field)) = ptah::de::TaggedStructDeserializer::next_field(&mut fields)? {
+            match tag {
+                #(#match_each)*
+                _ => (),
+            }
+        }
+        Ok(#name { #(#struct_init)* })
+    })
+}
+
 fn generate_for_tuple(name: &Ident, fields: &FieldsUnnamed) -> TokenStream {
     let deserialize_each = fields.unnamed.iter().enumerate().map(|(i, field)| {
         let field_name = format_ident!("field_{}", i);
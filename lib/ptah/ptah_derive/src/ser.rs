@@ -1,3 +1,4 @@
+use crate::attr::{self, ContainerAttrs, VariantAttrs};
 use proc_macro2::TokenStream;
 use quote::{format_ident, quote, quote_spanned};
 use syn::{
@@ -12,18 +13,21 @@ use syn::{
     GenericParam,
     Generics,
     Index,
+    Result,
 };
 
 // TODO: work out how to throw errors properly (apparently there's an experimental Diagnostics API?)
 // Serde doesn't use it but it might just not have been updated yet / waiting for it to be stable
 pub fn impl_serialize(input: DeriveInput) -> proc_macro::TokenStream {
-    let name = input.ident;
+    let body = match generate_body(&input) {
+        Ok(body) => body,
+        Err(err) => return err.to_compile_error().into(),
+    };
 
+    let name = input.ident;
     let generics = add_trait_bounds(input.generics);
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
-    let body = generate_body(&input.data);
-
     let expanded = quote! {
         #[automatically_derived]
         impl #impl_generics ptah::Serialize for #name #ty_generics #where_clause {
@@ -51,14 +55,30 @@ fn add_trait_bounds(mut generics: Generics) -> Generics {
     generics
 }
 
-fn generate_body(data: &Data) -> TokenStream {
-    match data {
+fn generate_body(input: &DeriveInput) -> Result<TokenStream> {
+    let container_attrs = ContainerAttrs::parse(&input.attrs)?;
+
+    match &input.data {
         Data::Struct(ref struct_data) => match struct_data.fields {
-            Fields::Named(ref fields) => generate_for_struct(fields),
-            Fields::Unnamed(ref fields) => generate_for_tuple(fields),
-            Fields::Unit => quote! {},
+            Fields::Named(ref fields) if container_attrs.tagged => generate_for_tagged_struct(fields),
+            _ if container_attrs.tagged => Err(syn::Error::new_spanned(
+                &struct_data.fields,
+                "only structs with named fields can be `#[ptah(tagged)]`",
+            )),
+            Fields::Named(ref fields) => {
+                attr::check_untagged_fields(&struct_data.fields)?;
+                Ok(generate_for_struct(fields))
+            }
+            Fields::Unnamed(ref fields) => {
+                attr::check_untagged_fields(&struct_data.fields)?;
+                Ok(generate_for_tuple(fields))
+            }
+            Fields::Unit => Ok(quote! {}),
         },
-        Data::Enum(enum_data) => generate_for_enum(&enum_data),
+        Data::Enum(_) if container_attrs.tagged => {
+            Err(syn::Error::new_spanned(&input.ident, "only structs can be `#[ptah(tagged)]`"))
+        }
+        Data::Enum(enum_data) => generate_for_enum(enum_data),
         // TODO: I'm not sure we want to support this from Rust. Probably throw a compile error here?
         Data::Union(_union_data) => todo!(),
     }
@@ -79,6 +99,38 @@ fn generate_for_struct(fields: &FieldsNamed) -> TokenStream {
     }
 }
 
+fn generate_for_tagged_struct(fields: &FieldsNamed) -> Result<TokenStream> {
+    /*
+     * Tagged structs are serialized as the number of fields, followed by each field with its tag and length.
+     * Fields that were added after the version we're serializing for are left out, so we count the fields that
+     * are going to be serialized first.
+     */
+    let fields = attr::tagged_fields(fields)?;
+    let num_always = fields.iter().filter(|field| field.attrs.since.is_none()).count() as u32;
+    let num_since = fields
+        .iter()
+        .filter_map(|field| field.attrs.since)
+        .map(|since| quote!(+ if version >= #since { 1 } else { 0 }));
+
+    let serialize_each = fields.iter().map(|field| {
+        let name = &field.field.ident;
+        let tag = field.tag;
+        let serialize =
+            quote_spanned!(field.field.span() => ptah::ser::TaggedStructSerializer::serialize_field(&mut fields, #tag, &self.#name)?;);
+        match field.attrs.since {
+            Some(since) => quote!(if version >= #since { #serialize }),
+            None => serialize,
+        }
+    });
+
+    Ok(quote! {
+        let version = ptah::Serializer::version(serializer);
+        let num_fields: u32 = #num_always #(#num_since)*;
+        let mut fields = ptah::Serializer::serialize_tagged_struct(serializer, num_fields)?;
+        #(#serialize_each)*
+    })
+}
+
 fn generate_for_tuple(fields: &FieldsUnnamed) -> TokenStream {
     /*
      * Similar to named fields, serialize each one, but we need to enumerate over them to get the indices into
@@ -94,8 +146,9 @@ fn generate_for_tuple(fields: &FieldsUnnamed) -> TokenStream {
     }
 }
 
-fn generate_for_enum(data: &DataEnum) -> TokenStream {
-    let variants = data.variants.iter().enumerate().map(|(i, variant)| {
+fn generate_for_enum(data: &DataEnum) -> Result<TokenStream> {
+    let mut variants = Vec::new();
+    for (i, variant) in data.variants.iter().enumerate() {
         /*
          * XXX: we basically don't handle enum descriminants, but will serialize enums that use
          * them anyway. We do this by indexing them normally as they appear in the enum - this
@@ -112,7 +165,17 @@ fn generate_for_enum(data: &DataEnum) -> TokenStream {
         let index = Index::from(i);
         let variant_name = &variant.ident;
 
-        match &variant.fields {
+        /*
+         * Variants that were added in a later version of the protocol can't be understood by peers built against
+         * an older version, so we refuse to serialize them for it.
+         */
+        attr::check_untagged_fields(&variant.fields)?;
+        let require_version = match VariantAttrs::parse(&variant.attrs)?.since {
+            Some(since) => quote!(ptah::Serializer::require_version(serializer, #since)?;),
+            None => quote!(),
+        };
+
+        variants.push(match &variant.fields {
             Fields::Named(ref fields) => {
                 let serialize_fields = fields.named.iter().map(|field| {
                     let field_name = &field.ident;
@@ -124,6 +187,7 @@ fn generate_for_enum(data: &DataEnum) -> TokenStream {
                 });
 
                 quote_spanned!(variant.span() => Self::#variant_name { #(#field_names)* } => {
+                    #require_version
                     ptah::Serializer::serialize_enum_variant(serializer, #index)?;
                     #(#serialize_fields)*
                 })
@@ -139,19 +203,21 @@ fn generate_for_enum(data: &DataEnum) -> TokenStream {
                 });
 
                 quote_spanned!(variant.span() => Self::#variant_name(#(#field_names)*) => {
+                    #require_version
                     ptah::Serializer::serialize_enum_variant(serializer, #index)?;
                     #(#serialize_fields)*
                 })
             }
             Fields::Unit => quote_spanned!(variant.span() => Self::#variant_name => {
+                #require_version
                 ptah::Serializer::serialize_enum_variant(serializer, #index)?;
             }),
-        }
-    });
+        });
+    }
 
-    quote! {
+    Ok(quote! {
         match self {
             #(#variants)*
         }
-    }
+    })
 }
@@ -45,6 +45,12 @@ impl<'de> Deserialize<'de> for &'de str {
     }
 }
 
+impl<'de> Deserialize<'de> for &'de [u8] {
+    fn deserialize(deserializer: &mut Deserializer<'de>) -> Result<&'de [u8]> {
+        deserializer.deserialize_bytes()
+    }
+}
+
 #[cfg(feature = "alloc")]
 impl<'de> Deserialize<'de> for alloc::string::String {
     fn deserialize(deserializer: &mut Deserializer<'de>) -> Result<alloc::string::String> {
@@ -3,7 +3,7 @@ mod impls;
 #[cfg(feature = "heapless")]
 mod heapless;
 
-use crate::Handle;
+use crate::{FieldTag, Handle};
 use core::str;
 
 /// Errors that can occur during deserialization.
@@ -18,6 +18,8 @@ pub enum Error {
     InvalidBoolMarker(u8),
     InvalidOptionMarker(u8),
     InvalidEnumTag(u32),
+    /// A tagged struct is missing a field that is required.
+    MissingField(FieldTag),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -107,6 +109,13 @@ impl<'de> Deserializer<'de> {
         str::from_utf8(bytes).map_err(|_| Error::InvalidUtf8)
     }
 
+    /// Deserialize a `seq` of `u8`s, borrowing them straight out of the buffer. This can be used to deserialize
+    /// anything serialized as a `[u8]` or `Vec<u8>` without copying it.
+    pub fn deserialize_bytes(&mut self) -> Result<&'de [u8]> {
+        let length = self.deserialize_seq_length()?;
+        self.take_n(length as usize)
+    }
+
     pub fn deserialize_option<T>(&mut self) -> Result<Option<T>>
     where
         T: ?Sized + Deserialize<'de>,
@@ -134,6 +143,12 @@ impl<'de> Deserializer<'de> {
         self.deserialize_u32()
     }
 
+    /// Start deserializing a tagged struct. Its fields should be read with `TaggedStructDeserializer::next_field`.
+    pub fn deserialize_tagged_struct(&mut self) -> Result<TaggedStructDeserializer<'_, 'de>> {
+        let remaining = self.deserialize_u32()?;
+        Ok(TaggedStructDeserializer { deserializer: self, remaining })
+    }
+
     pub fn deserialize_handle(&mut self) -> Result<crate::Handle> {
         let slot = self.deserialize_u8()?;
         match self.handles.get(crate::index_from_handle_slot(slot) as usize) {
@@ -168,3 +183,47 @@ impl<'de> Deserializer<'de> {
         Ok(bytes.try_into().unwrap())
     }
 }
+
+pub struct TaggedStructDeserializer<'a, 'de> {
+    deserializer: &'a mut Deserializer<'de>,
+    remaining: u32,
+}
+
+impl<'a, 'de> TaggedStructDeserializer<'a, 'de> {
+    /// Get the tag of the next field of the struct, and the field itself, or `None` if all the fields have been
+    /// read. Fields with tags the caller doesn't know about (e.g. because they were added in a later version of the
+    /// struct) can just be ignored, and are skipped.
+    pub fn next_field(&mut self) -> Result<Option<(FieldTag, TaggedField<'de>)>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        let tag = self.deserializer.deserialize_u16()?;
+        let length = self.deserializer.deserialize_u32()?;
+        let bytes = self.deserializer.take_n(length as usize)?;
+        Ok(Some((tag, TaggedField { bytes, handles: self.deserializer.handles })))
+    }
+}
+
+/// A field of a tagged struct, which has not yet been deserialized.
+pub struct TaggedField<'de> {
+    bytes: &'de [u8],
+    handles: &'de [Handle],
+}
+
+impl<'de> TaggedField<'de> {
+    pub fn deserialize<T>(self) -> Result<T>
+    where
+        T: Deserialize<'de>,
+    {
+        let mut deserializer = Deserializer::from_wire(self.bytes, self.handles);
+        let value = T::deserialize(&mut deserializer)?;
+
+        if deserializer.bytes.is_empty() {
+            Ok(value)
+        } else {
+            Err(Error::TrailingBytes)
+        }
+    }
+}
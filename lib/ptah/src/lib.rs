@@ -16,15 +16,32 @@ pub mod ser;
 pub use de::{Deserialize, DeserializeOwned, Deserializer};
 pub use ser::{Serialize, Serializer};
 
+/// Messages can be serialized for a particular version of a protocol, so that they can be understood by peers that
+/// were built against an older version of it. Fields and enum variants marked with `#[ptah(since = N)]` were added
+/// in version `N` of their protocol, and are only serialized for versions `N` and above.
+pub type Version = u32;
+
+/// Serialize values with every field they have. Peers that were built against an older version of a type skip the
+/// fields of tagged structs that they don't know about, so this is only a problem for new enum variants.
+pub const LATEST_VERSION: Version = Version::MAX;
+
 /// It can sometimes be useful to know the size of a value in its serialized form (e.g. to reserve space for it in
 /// a ring buffer). This calculates the number of bytes taken to serialize some `value` of `T` into Ptah's wire
 /// format. Note that this size is for the specific `value`, and may differ between values of `T`.
 pub fn serialized_size<T>(value: &T) -> ser::Result<usize>
 where
     T: Serialize,
+{
+    serialized_size_with_version(value, LATEST_VERSION)
+}
+
+/// Like `serialized_size`, but calculates the size of `value` when it's serialized for `version`.
+pub fn serialized_size_with_version<T>(value: &T, version: Version) -> ser::Result<usize>
+where
+    T: ?Sized + Serialize,
 {
     let mut size = 0;
-    let mut serializer = Serializer::new(SizeCalculator { size: &mut size });
+    let mut serializer = Serializer::with_version(SizeCalculator { size: &mut size }, version);
 
     value.serialize(&mut serializer)?;
     Ok(size)
@@ -35,7 +52,17 @@ where
     T: Serialize,
     W: Writer,
 {
-    let mut serializer = Serializer::new(writer);
+    to_wire_with_version(value, writer, LATEST_VERSION)
+}
+
+/// Serialize `value` for `version` of its protocol. Fields and variants that were added after `version` are not
+/// serialized.
+pub fn to_wire_with_version<T, W>(value: &T, writer: W, version: Version) -> ser::Result<usize>
+where
+    T: Serialize,
+    W: Writer,
+{
+    let mut serializer = Serializer::with_version(writer, version);
 
     value.serialize(&mut serializer)?;
     Ok(serializer.writer.bytes_written())
@@ -60,6 +87,8 @@ where
 
 pub type Handle = u32;
 pub type HandleSlot = u8;
+/// Identifies a field of a tagged struct on the wire.
+pub type FieldTag = u16;
 
 /*
  * These are constants that are used in the wire format.
@@ -68,29 +97,15 @@ pub(crate) const MARKER_FALSE: u8 = 0x0;
 pub(crate) const MARKER_TRUE: u8 = 0x1;
 pub(crate) const MARKER_NONE: u8 = 0x0;
 pub(crate) const MARKER_SOME: u8 = 0x1;
-pub(crate) const HANDLE_SLOT_0: u8 = 0xf0;
-pub(crate) const HANDLE_SLOT_1: u8 = 0xf1;
-pub(crate) const HANDLE_SLOT_2: u8 = 0xf2;
-pub(crate) const HANDLE_SLOT_3: u8 = 0xf3;
 
+/// Handles are encoded as a slot, which is the index of the handle in the array of handles transferred
+/// out-of-band alongside the message. This allows up to 256 handles to be transferred by each message.
 pub fn make_handle_slot(index: u8) -> HandleSlot {
-    match index {
-        0 => HANDLE_SLOT_0,
-        1 => HANDLE_SLOT_1,
-        2 => HANDLE_SLOT_2,
-        3 => HANDLE_SLOT_3,
-        _ => panic!("Invalid handle slot index!"),
-    }
+    index
 }
 
 pub fn index_from_handle_slot(slot: HandleSlot) -> u8 {
-    match slot {
-        HANDLE_SLOT_0 => 0,
-        HANDLE_SLOT_1 => 1,
-        HANDLE_SLOT_2 => 2,
-        HANDLE_SLOT_3 => 3,
-        _ => panic!("Invalid handle slot!"),
-    }
+    slot
 }
 
 /// A `Writer` represents a consumer of the bytes produced by serializing a message. In cases where you can
@@ -171,7 +186,7 @@ impl<'a> Writer for SizeCalculator<'a> {
          * When calculating the size, we simply accept as many handles as we're passed. The encoded slot is always
          * the same size, so it doesn't matter what we return.
          */
-        Ok(HANDLE_SLOT_0)
+        Ok(0)
     }
 
     fn bytes_written(&self) -> usize {
@@ -112,6 +112,18 @@ where
     }
 }
 
+impl<T> Serialize for &T
+where
+    T: ?Sized + Serialize,
+{
+    fn serialize<W>(&self, serializer: &mut Serializer<W>) -> Result<()>
+    where
+        W: Writer,
+    {
+        (*self).serialize(serializer)
+    }
+}
+
 impl Serialize for () {
     fn serialize<W>(&self, _serializer: &mut Serializer<W>) -> Result<()>
     where
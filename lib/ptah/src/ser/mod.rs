@@ -3,13 +3,20 @@ mod impls;
 #[cfg(feature = "heapless")]
 mod heapless;
 
-use crate::Writer;
+use crate::{FieldTag, Version, Writer};
 
 /// Errors that can occur during serialization.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Error {
     WriterFullOfBytes,
     WriterFullOfHandles,
+    /// The value can't be serialized for the version being serialized for, because it's (or it contains) an enum
+    /// variant that was added in version `since`.
+    NotInVersion {
+        since: Version,
+    },
+    /// A field of a tagged struct is too large to be serialized.
+    FieldTooLarge,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -25,6 +32,7 @@ where
     W: Writer,
 {
     pub(crate) writer: W,
+    version: Version,
 }
 
 impl<W> Serializer<W>
@@ -32,7 +40,26 @@ where
     W: Writer,
 {
     pub fn new(writer: W) -> Serializer<W> {
-        Serializer { writer }
+        Self::with_version(writer, crate::LATEST_VERSION)
+    }
+
+    /// Create a `Serializer` that serializes values for `version` of their protocol.
+    pub fn with_version(writer: W, version: Version) -> Serializer<W> {
+        Serializer { writer, version }
+    }
+
+    /// The version of the protocol that values are being serialized for.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Check that something added in version `since` can be serialized for the version we're serializing for.
+    pub fn require_version(&self, since: Version) -> Result<()> {
+        if self.version >= since {
+            Ok(())
+        } else {
+            Err(Error::NotInVersion { since })
+        }
     }
 
     pub fn serialize_bool(&mut self, value: bool) -> Result<()> {
@@ -132,6 +159,13 @@ where
         self.serialize_u32(variant_index)
     }
 
+    /// Start serializing a tagged struct with `num_fields` fields. Each field should then be serialized with
+    /// `TaggedStructSerializer::serialize_field`.
+    pub fn serialize_tagged_struct(&mut self, num_fields: u32) -> Result<TaggedStructSerializer<'_, W>> {
+        self.serialize_u32(num_fields)?;
+        Ok(TaggedStructSerializer(self))
+    }
+
     pub fn serialize_handle(&mut self, handle: crate::Handle) -> Result<()> {
         let slot = self.writer.push_handle(handle)?;
         self.serialize_u8(slot)
@@ -176,3 +210,24 @@ where
         value.serialize(self.0)
     }
 }
+
+pub struct TaggedStructSerializer<'a, W>(&'a mut Serializer<W>)
+where
+    W: Writer;
+
+impl<'a, W> TaggedStructSerializer<'a, W>
+where
+    W: Writer,
+{
+    /// Serialize a field, with its tag and its length, so that receivers that don't know about it can skip it.
+    pub fn serialize_field<T>(&mut self, tag: FieldTag, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let length = crate::serialized_size_with_version(value, self.0.version)?;
+        let length = u32::try_from(length).map_err(|_| Error::FieldTooLarge)?;
+        self.0.serialize_u16(tag)?;
+        self.0.serialize_u32(length)?;
+        value.serialize(self.0)
+    }
+}
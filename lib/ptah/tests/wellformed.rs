@@ -15,7 +15,7 @@ where
      */
     let mut buffer = [0u8; BUFFER_SIZE];
     match ptah::to_wire(&value, CursorWriter::new(&mut buffer)) {
-        Ok(()) => (),
+        Ok(_) => (),
         Err(err) => panic!("Failed to serialize value: {:?} (err = {:?})", value, err),
     }
     println!("Encoded: {:x?}", buffer);
@@ -328,3 +328,144 @@ fn maps() {
 
     test_value(map);
 }
+
+/*
+ * Serialize `value` for `version`, and then deserialize it as a `U`, which can be a different version of the same
+ * type.
+ */
+fn reencode<T, U>(value: &T, version: ptah::Version) -> ptah::de::Result<U>
+where
+    T: Serialize + Debug,
+    U: DeserializeOwned,
+{
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let size = match ptah::to_wire_with_version(value, CursorWriter::new(&mut buffer), version) {
+        Ok(size) => size,
+        Err(err) => panic!("Failed to serialize value: {:?} (err = {:?})", value, err),
+    };
+    assert_eq!(size, ptah::serialized_size_with_version(value, version).unwrap());
+    ptah::from_wire(&buffer[0..size], &[])
+}
+
+#[test]
+fn tagged_structs() {
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[ptah(tagged)]
+    struct Foo {
+        a: u8,
+        #[ptah(tag = 7)]
+        b: String,
+        #[ptah(since = 2)]
+        c: Vec<u16>,
+    }
+
+    test_value(Foo { a: 4, b: "Hello, World!".to_string(), c: vec![8, 11] });
+}
+
+#[test]
+fn tagged_struct_evolution() {
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[ptah(tagged)]
+    struct FooV1 {
+        a: u8,
+        b: String,
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[ptah(tagged)]
+    struct FooV2 {
+        a: u8,
+        b: String,
+        #[ptah(since = 2)]
+        c: Option<u32>,
+        #[ptah(since = 2)]
+        d: Vec<u8>,
+    }
+
+    /*
+     * Old readers skip fields they don't know about, and new readers default fields that are missing.
+     */
+    let new = FooV2 { a: 3, b: "Foo".to_string(), c: Some(9), d: vec![1, 2] };
+    assert_eq!(reencode::<_, FooV1>(&new, ptah::LATEST_VERSION), Ok(FooV1 { a: 3, b: "Foo".to_string() }));
+
+    let old = FooV1 { a: 5, b: "Bar".to_string() };
+    assert_eq!(
+        reencode::<_, FooV2>(&old, ptah::LATEST_VERSION),
+        Ok(FooV2 { a: 5, b: "Bar".to_string(), c: None, d: vec![] })
+    );
+
+    /*
+     * Fields added after the version being serialized for are left out.
+     */
+    assert_eq!(reencode::<_, FooV2>(&new, 1), Ok(FooV2 { a: 3, b: "Foo".to_string(), c: None, d: vec![] }));
+    assert_eq!(reencode::<_, FooV2>(&new, 2), Ok(new));
+}
+
+#[test]
+fn tagged_struct_missing_field() {
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[ptah(tagged)]
+    struct Foo {
+        a: u8,
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[ptah(tagged)]
+    struct Bar {
+        a: u8,
+        #[ptah(tag = 4)]
+        b: u8,
+    }
+
+    assert_eq!(reencode::<_, Bar>(&Foo { a: 8 }, ptah::LATEST_VERSION), Err(ptah::de::Error::MissingField(4)));
+}
+
+#[test]
+fn enum_variant_versions() {
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    enum Foo {
+        A(u8),
+        #[ptah(since = 3)]
+        B,
+    }
+
+    test_value(Foo::A(7));
+    test_value(Foo::B);
+
+    assert_eq!(reencode::<_, Foo>(&Foo::A(7), 1), Ok(Foo::A(7)));
+    let mut buffer = [0u8; BUFFER_SIZE];
+    assert_eq!(
+        ptah::to_wire_with_version(&Foo::B, CursorWriter::new(&mut buffer), 2),
+        Err(ptah::ser::Error::NotInVersion { since: 3 })
+    );
+}
+
+#[test]
+fn borrowed() {
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    struct Foo<'a> {
+        name: &'a str,
+        data: &'a [u8],
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    struct OwnedFoo {
+        name: String,
+        data: Vec<u8>,
+    }
+
+    /*
+     * Borrowed fields are encoded in the same way as their owned equivalents, and point into the buffer they were
+     * deserialized from.
+     */
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let foo = Foo { name: "Hello, World!", data: &[4, 8, 15, 16, 23, 42] };
+    let size = ptah::to_wire(&foo, CursorWriter::new(&mut buffer)).unwrap();
+    let decoded: Foo = ptah::from_wire(&buffer[0..size], &[]).unwrap();
+    assert_eq!(decoded, foo);
+    assert!(buffer[0..size].as_ptr_range().contains(&decoded.name.as_ptr()));
+    assert!(buffer[0..size].as_ptr_range().contains(&decoded.data.as_ptr()));
+
+    let owned: OwnedFoo = ptah::from_wire(&buffer[0..size], &[]).unwrap();
+    assert_eq!(owned, OwnedFoo { name: "Hello, World!".to_string(), data: vec![4, 8, 15, 16, 23, 42] });
+}
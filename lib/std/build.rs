@@ -15,6 +15,10 @@ fn main() {
             println!("cargo:rerun-if-changed=rv64.x");
             File::create(out.join("link.ld")).unwrap().write_all(include_bytes!("rv64.ld")).unwrap();
         }
+        Some("aarch64-unknown-none-softfloat") => {
+            println!("cargo:rerun-if-changed=aarch64.ld");
+            File::create(out.join("link.ld")).unwrap().write_all(include_bytes!("aarch64.ld")).unwrap();
+        }
         _ => panic!("Building Poplar `std` for unsupported target!"),
     }
     println!("cargo:rustc-link-search={}", out.display());
@@ -0,0 +1,18 @@
+//! Inspection of the task's environment. This mirrors a small part of `std::env` in Rust's real `std`.
+
+use alloc::{string::String, vec, vec::Vec};
+use poplar::manifest::TaskManifest;
+
+pub type Args = vec::IntoIter<String>;
+
+/// Get the arguments the task was created with. Like in real `std`, the first argument is the task's name.
+///
+/// This can only be used by tasks created with `task_create` (i.e. tasks spawned at runtime, rather than those
+/// loaded by the bootloader), which are passed their arguments in their manifest.
+pub fn args() -> Args {
+    let manifest = unsafe { TaskManifest::read() };
+    let mut args = Vec::with_capacity(1 + manifest.arguments.len());
+    args.push(manifest.task_name);
+    args.extend(manifest.arguments);
+    args.into_iter()
+}
@@ -0,0 +1,170 @@
+//! Strings used to talk to the OS. This mirrors `OsStr` and `OsString` in Rust's real `std`, which `path` is
+//! built on. Poplar's paths (and everything else it passes around as text) are UTF-8, so unlike on other
+//! platforms, these are just wrappers around `str` and `String`, and converting them to strings never fails.
+
+use alloc::{borrow::Cow, borrow::ToOwned, string::String};
+use core::{borrow::Borrow, fmt, ops::Deref};
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct OsStr {
+    inner: str,
+}
+
+impl OsStr {
+    pub fn new<S: AsRef<OsStr> + ?Sized>(s: &S) -> &OsStr {
+        s.as_ref()
+    }
+
+    pub(crate) fn from_str(s: &str) -> &OsStr {
+        // SAFETY: `OsStr` is a `repr(transparent)` wrapper around `str`
+        unsafe { &*(s as *const str as *const OsStr) }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        Some(&self.inner)
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.inner)
+    }
+
+    pub fn to_os_string(&self) -> OsString {
+        OsString { inner: String::from(&self.inner) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn as_encoded_bytes(&self) -> &[u8] {
+        self.inner.as_bytes()
+    }
+
+    pub fn display(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl fmt::Debug for OsStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl AsRef<OsStr> for OsStr {
+    fn as_ref(&self) -> &OsStr {
+        self
+    }
+}
+
+impl AsRef<OsStr> for str {
+    fn as_ref(&self) -> &OsStr {
+        OsStr::from_str(self)
+    }
+}
+
+impl AsRef<OsStr> for String {
+    fn as_ref(&self) -> &OsStr {
+        OsStr::from_str(self)
+    }
+}
+
+impl PartialEq<str> for OsStr {
+    fn eq(&self, other: &str) -> bool {
+        &self.inner == other
+    }
+}
+
+impl ToOwned for OsStr {
+    type Owned = OsString;
+
+    fn to_owned(&self) -> OsString {
+        self.to_os_string()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct OsString {
+    inner: String,
+}
+
+impl OsString {
+    pub fn new() -> OsString {
+        OsString { inner: String::new() }
+    }
+
+    pub fn as_os_str(&self) -> &OsStr {
+        OsStr::from_str(&self.inner)
+    }
+
+    pub fn into_string(self) -> Result<String, OsString> {
+        Ok(self.inner)
+    }
+
+    pub fn push<S: AsRef<OsStr>>(&mut self, s: S) {
+        self.inner.push_str(s.as_ref().as_str());
+    }
+
+    pub(crate) fn as_mut_string(&mut self) -> &mut String {
+        &mut self.inner
+    }
+}
+
+impl fmt::Debug for OsString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_os_str(), f)
+    }
+}
+
+impl Deref for OsString {
+    type Target = OsStr;
+
+    fn deref(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+impl Borrow<OsStr> for OsString {
+    fn borrow(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+impl AsRef<OsStr> for OsString {
+    fn as_ref(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+impl From<String> for OsString {
+    fn from(s: String) -> OsString {
+        OsString { inner: s }
+    }
+}
+
+impl From<&str> for OsString {
+    fn from(s: &str) -> OsString {
+        OsString { inner: String::from(s) }
+    }
+}
+
+impl PartialEq<str> for OsString {
+    fn eq(&self, other: &str) -> bool {
+        self.inner == other
+    }
+}
+
+impl PartialEq<&str> for OsString {
+    fn eq(&self, other: &&str) -> bool {
+        self.inner == *other
+    }
+}
@@ -0,0 +1,412 @@
+//! Accessing files. This mirrors `std::fs` in Rust's real `std`, on top of the VFS client in `poplar::file`.
+//!
+//! Paths are resolved in the task's filesystem namespace (see `poplar::file::namespace`), so each task only sees
+//! the files it was given when it was spawned. Tasks without a namespace get `PermissionDenied` from every
+//! function here. The VFS can't yet create directories, or remove or rename files, so those functions always
+//! return `Unsupported`.
+
+use crate::{
+    ffi::OsString,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{self, Path, PathBuf},
+};
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use poplar::file::{self as vfs, FileClientError, FileError, FileKind, FileStat, Vfs};
+
+/// Convert an error from the VFS client to an `io::Error`.
+fn error(err: FileClientError) -> io::Error {
+    let kind = match err {
+        FileClientError::File(FileError::NotFound) => io::ErrorKind::NotFound,
+        FileClientError::File(FileError::AlreadyExists) => io::ErrorKind::AlreadyExists,
+        FileClientError::File(FileError::NotADirectory) => io::ErrorKind::NotADirectory,
+        FileClientError::File(FileError::IsADirectory) => io::ErrorKind::IsADirectory,
+        FileClientError::File(FileError::InvalidPath) => io::ErrorKind::InvalidInput,
+        FileClientError::File(FileError::ReadOnly) => io::ErrorKind::PermissionDenied,
+        FileClientError::File(FileError::NoSpace) => io::ErrorKind::StorageFull,
+        FileClientError::File(FileError::Unsupported) => io::ErrorKind::Unsupported,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::from(kind)
+}
+
+fn namespace() -> io::Result<Vfs> {
+    vfs::namespace()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "task has no filesystem namespace"))
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "operation not supported by the VFS")
+}
+
+/// A file opened in the task's namespace. The file is closed when this is dropped.
+pub struct File {
+    inner: vfs::File,
+    /// The resolved path the file was opened with, which is used to find its metadata.
+    path: String,
+}
+
+impl File {
+    /// Open a file for reading.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    /// Open a file for writing, creating it if it doesn't exist and truncating it if it does.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)
+    }
+
+    /// Create a new file for reading and writing, failing if it already exists.
+    pub fn create_new<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().read(true).write(true).create_new(true).open(path)
+    }
+
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        metadata(&self.path)
+    }
+
+    /// Writes go straight to the filesystem, so there's nothing to flush.
+    pub fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn set_len(&self, _size: u64) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("File").field("path", &self.path).finish()
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(error)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).map_err(error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(position) => {
+                self.inner.seek(position);
+                return Ok(position);
+            }
+            SeekFrom::End(offset) => (self.metadata()?.len(), offset),
+            SeekFrom::Current(offset) => (self.inner.position(), offset),
+        };
+        let position = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        self.inner.seek(position);
+        Ok(position)
+    }
+}
+
+/// Options for how a file is opened, used with `OpenOptions::open`. This mirrors `OpenOptions` in Rust's real
+/// `std`, and is translated to the VFS's `OpenOptions` (which every opened file can be read from).
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.write = write;
+        self
+    }
+
+    /// Open the file for writing, with the position starting at the end of the file.
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
+
+    /// Create a new file, failing with `AlreadyExists` if it already exists. This overrides `create` and
+    /// `truncate`.
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<File> {
+        let write = self.write || self.append;
+        if !self.read && !write {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "file must be opened for reading or writing"));
+        }
+        if !write && (self.truncate || self.create || self.create_new) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "creating or truncating needs write access"));
+        }
+
+        let vfs = namespace()?;
+        let path = path::resolve(path.as_ref());
+        /*
+         * The VFS can't create a file only if it doesn't exist, so we check first. This can race with another task
+         * creating the file, but nothing in Poplar relies on `create_new` for locking yet.
+         */
+        if self.create_new {
+            match vfs.stat(&path) {
+                Ok(_) => return Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+                Err(FileClientError::File(FileError::NotFound)) => (),
+                Err(err) => return Err(error(err)),
+            }
+        }
+
+        let options = vfs::OpenOptions {
+            write,
+            create: self.create || self.create_new,
+            truncate: self.truncate && !self.create_new,
+        };
+        let mut inner = vfs.open(&path, options).map_err(error)?;
+        if self.append {
+            inner.seek(vfs.stat(&path).map_err(error)?.size);
+        }
+        Ok(File { inner, path })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileType(FileKind);
+
+impl FileType {
+    pub fn is_file(&self) -> bool {
+        self.0 == FileKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.0 == FileKind::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.0 == FileKind::Symlink
+    }
+}
+
+/// Information about a file, returned by `metadata` and `File::metadata`.
+#[derive(Clone, Debug)]
+pub struct Metadata(FileStat);
+
+impl Metadata {
+    pub fn file_type(&self) -> FileType {
+        FileType(self.0.kind)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type().is_file()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type().is_dir()
+    }
+
+    /// The VFS follows symlinks when it looks up paths, so this is only `true` for the metadata of a `DirEntry`.
+    pub fn is_symlink(&self) -> bool {
+        self.file_type().is_symlink()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.size
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        Permissions(self.0.permissions)
+    }
+
+    /// The VFS doesn't track when files were changed, so this always returns `Unsupported`.
+    pub fn modified(&self) -> io::Result<crate::time::SystemTime> {
+        Err(unsupported())
+    }
+
+    pub fn accessed(&self) -> io::Result<crate::time::SystemTime> {
+        Err(unsupported())
+    }
+
+    pub fn created(&self) -> io::Result<crate::time::SystemTime> {
+        Err(unsupported())
+    }
+}
+
+/// The Unix permission bits of a file (see `FileStat::permissions`).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Permissions(u16);
+
+impl Permissions {
+    /// Whether nobody can write to the file.
+    pub fn readonly(&self) -> bool {
+        self.0 & 0o222 == 0
+    }
+
+    /// Changing permissions isn't supported by the VFS, so this only changes this value, and not the file.
+    pub fn set_readonly(&mut self, readonly: bool) {
+        if readonly {
+            self.0 &= !0o222;
+        } else {
+            self.0 |= 0o200;
+        }
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.0 as u32
+    }
+}
+
+/// An iterator over the entries of a directory, returned by `read_dir`. The VFS lists the whole directory when
+/// this is created, so later changes to the directory aren't seen.
+#[derive(Debug)]
+pub struct ReadDir {
+    directory: PathBuf,
+    entries: alloc::vec::IntoIter<vfs::DirEntry>,
+}
+
+impl Iterator for ReadDir {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        let entry = self.entries.next()?;
+        Some(Ok(DirEntry { path: self.directory.join(&entry.name), name: entry.name, kind: entry.kind }))
+    }
+}
+
+#[derive(Debug)]
+pub struct DirEntry {
+    path: PathBuf,
+    name: String,
+    kind: FileKind,
+}
+
+impl DirEntry {
+    pub fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    pub fn file_name(&self) -> OsString {
+        OsString::from(self.name.as_str())
+    }
+
+    pub fn file_type(&self) -> io::Result<FileType> {
+        Ok(FileType(self.kind))
+    }
+
+    /// Get the metadata of the entry. Unlike `metadata`, this doesn't follow the entry if it's a symlink.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        let mut stat = metadata(&self.path)?.0;
+        stat.kind = self.kind;
+        Ok(Metadata(stat))
+    }
+}
+
+pub fn metadata<P: AsRef<Path>>(path: P) -> io::Result<Metadata> {
+    let stat = namespace()?.stat(&path::resolve(path.as_ref())).map_err(error)?;
+    Ok(Metadata(stat))
+}
+
+/// The VFS follows symlinks when it looks up paths, so this is the same as `metadata`.
+pub fn symlink_metadata<P: AsRef<Path>>(path: P) -> io::Result<Metadata> {
+    metadata(path)
+}
+
+pub fn read_dir<P: AsRef<Path>>(path: P) -> io::Result<ReadDir> {
+    let entries = namespace()?.read_dir(&path::resolve(path.as_ref())).map_err(error)?;
+    Ok(ReadDir { directory: path.as_ref().to_path_buf(), entries: entries.into_iter() })
+}
+
+/// Read the whole of a file.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Read the whole of a file, which must be valid UTF-8.
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut string = String::new();
+    File::open(path)?.read_to_string(&mut string)?;
+    Ok(string)
+}
+
+/// Write `contents` to a file, creating it if it doesn't exist and replacing its contents if it does.
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    File::create(path)?.write_all(contents.as_ref())
+}
+
+/// Copy the contents of one file to another, which is created if it doesn't exist. Returns the number of bytes
+/// copied.
+pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<u64> {
+    let mut from = File::open(from)?;
+    let mut to = File::create(to)?;
+    io::copy(&mut from, &mut to)
+}
+
+pub fn create_dir<P: AsRef<Path>>(_path: P) -> io::Result<()> {
+    Err(unsupported())
+}
+
+/// Directories can't be created, so this only succeeds if `path` is already a directory.
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    match metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        _ => Err(unsupported()),
+    }
+}
+
+pub fn remove_file<P: AsRef<Path>>(_path: P) -> io::Result<()> {
+    Err(unsupported())
+}
+
+pub fn remove_dir<P: AsRef<Path>>(_path: P) -> io::Result<()> {
+    Err(unsupported())
+}
+
+pub fn remove_dir_all<P: AsRef<Path>>(_path: P) -> io::Result<()> {
+    Err(unsupported())
+}
+
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(_from: P, _to: Q) -> io::Result<()> {
+    Err(unsupported())
+}
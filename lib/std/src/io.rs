@@ -0,0 +1,803 @@
+//! Input and output. This mirrors a subset of `std::io` in Rust's real `std`: the `Read`, `Write`, `Seek`, and
+//! `BufRead` traits, the `Error` type used by the rest of `std`, and the task's standard streams.
+//!
+//! Text is written to the handles the task was given as its stdout and stderr (see `poplar::stdio`). Tasks that
+//! weren't given them (such as services) write each line to the kernel's log with `early_log` instead. Input is
+//! read from stdin with `Stdin`, whether it's a console or a pipe - tasks that want more control over a console
+//! (e.g. to see each key as it's pressed) can use the channel returned by `poplar::stdio::stdin` directly.
+
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec, vec::Vec};
+use core::{error, fmt};
+use poplar::{
+    console::{ConsoleEvent, ConsoleRequest},
+    stdio::{self, Input, Output},
+    sync::{Mutex, MutexGuard},
+};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The error type for I/O operations, and for the parts of `std` built on them (`fs` and `net`).
+pub struct Error {
+    kind: ErrorKind,
+    error: Option<Box<dyn error::Error + Send + Sync>>,
+}
+
+impl Error {
+    /// Create an error of the given kind, with a payload describing it in more detail (often a string).
+    pub fn new<E>(kind: ErrorKind, error: E) -> Error
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Error { kind, error: Some(error.into()) }
+    }
+
+    pub fn other<E>(error: E) -> Error
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Error::new(ErrorKind::Other, error)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Poplar doesn't have OS error codes, so this always returns `None`.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        None
+    }
+
+    pub fn get_ref(&self) -> Option<&(dyn error::Error + Send + Sync + 'static)> {
+        self.error.as_deref()
+    }
+
+    pub fn into_inner(self) -> Option<Box<dyn error::Error + Send + Sync>> {
+        self.error
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error { kind, error: None }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(error) => f.debug_struct("Error").field("kind", &self.kind).field("error", error).finish(),
+            None => f.debug_tuple("Kind").field(&self.kind).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(error) => fmt::Display::fmt(error, f),
+            None => f.write_str(self.kind.description()),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    ConnectionRefused,
+    ConnectionReset,
+    ConnectionAborted,
+    NotConnected,
+    AddrInUse,
+    AddrNotAvailable,
+    BrokenPipe,
+    AlreadyExists,
+    WouldBlock,
+    NotADirectory,
+    IsADirectory,
+    InvalidInput,
+    InvalidData,
+    TimedOut,
+    WriteZero,
+    StorageFull,
+    Interrupted,
+    Unsupported,
+    UnexpectedEof,
+    OutOfMemory,
+    Other,
+}
+
+impl ErrorKind {
+    fn description(&self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "entity not found",
+            ErrorKind::PermissionDenied => "permission denied",
+            ErrorKind::ConnectionRefused => "connection refused",
+            ErrorKind::ConnectionReset => "connection reset",
+            ErrorKind::ConnectionAborted => "connection aborted",
+            ErrorKind::NotConnected => "not connected",
+            ErrorKind::AddrInUse => "address in use",
+            ErrorKind::AddrNotAvailable => "address not available",
+            ErrorKind::BrokenPipe => "broken pipe",
+            ErrorKind::AlreadyExists => "entity already exists",
+            ErrorKind::WouldBlock => "operation would block",
+            ErrorKind::NotADirectory => "not a directory",
+            ErrorKind::IsADirectory => "is a directory",
+            ErrorKind::InvalidInput => "invalid input parameter",
+            ErrorKind::InvalidData => "invalid data",
+            ErrorKind::TimedOut => "timed out",
+            ErrorKind::WriteZero => "write zero",
+            ErrorKind::StorageFull => "no storage space",
+            ErrorKind::Interrupted => "operation interrupted",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::UnexpectedEof => "unexpected end of file",
+            ErrorKind::OutOfMemory => "out of memory",
+            ErrorKind::Other => "other error",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// A source of bytes.
+pub trait Read {
+    /// Read some bytes into `buffer`, returning how many were read. `0` is returned at the end of the stream (or
+    /// if `buffer` is empty).
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
+
+    /// Read the rest of the stream, and append it to `buffer`. Returns the number of bytes read.
+    fn read_to_end(&mut self, buffer: &mut Vec<u8>) -> Result<usize> {
+        let start = buffer.len();
+        let mut chunk = [0; 1024];
+        loop {
+            match self.read(&mut chunk) {
+                Ok(0) => return Ok(buffer.len() - start),
+                Ok(length) => buffer.extend_from_slice(&chunk[0..length]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Read the rest of the stream, and append it to `text`. Fails with `InvalidData` (without changing `text`) if
+    /// the data isn't valid UTF-8.
+    fn read_to_string(&mut self, text: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        let length = self.read_to_end(&mut bytes)?;
+        let string = String::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid UTF-8"))?;
+        text.push_str(&string);
+        Ok(length)
+    }
+
+    /// Fill `buffer` completely, failing with `UnexpectedEof` if the stream ends first.
+    fn read_exact(&mut self, mut buffer: &mut [u8]) -> Result<()> {
+        while !buffer.is_empty() {
+            match self.read(buffer) {
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+                Ok(length) => buffer = &mut buffer[length..],
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn by_ref(&mut self) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self
+    }
+
+    fn bytes(self) -> Bytes<Self>
+    where
+        Self: Sized,
+    {
+        Bytes { inner: self }
+    }
+
+    /// Read at most `limit` bytes from the stream.
+    fn take(self, limit: u64) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        Take { inner: self, limit }
+    }
+}
+
+/// A sink for bytes.
+pub trait Write {
+    /// Write some of `data`, returning how many bytes were written.
+    fn write(&mut self, data: &[u8]) -> Result<usize>;
+
+    /// Make sure everything written so far has reached its destination.
+    fn flush(&mut self) -> Result<()>;
+
+    fn write_all(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            match self.write(data) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "failed to write whole buffer")),
+                Ok(length) => data = &data[length..],
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write formatted text. This is what `write!` and `writeln!` call.
+    fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> Result<()> {
+        self.write_all(alloc::fmt::format(args).as_bytes())
+    }
+
+    fn by_ref(&mut self) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// A stream with a position that can be moved.
+pub trait Seek {
+    /// Move to a new position in the stream, returning the new position from the start of the stream.
+    fn seek(&mut self, position: SeekFrom) -> Result<u64>;
+
+    fn rewind(&mut self) -> Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::Current(0))
+    }
+}
+
+/// A `Read` with an internal buffer, which allows it to be read a line at a time.
+pub trait BufRead: Read {
+    /// Get the contents of the internal buffer, filling it from the underlying stream if it's empty. An empty
+    /// slice is returned at the end of the stream.
+    fn fill_buf(&mut self) -> Result<&[u8]>;
+
+    /// Mark `amount` bytes of the buffer as read, so they aren't returned by `fill_buf` again.
+    fn consume(&mut self, amount: usize);
+
+    /// Read until `delimiter` (which is included) or the end of the stream, appending to `buffer`. Returns the
+    /// number of bytes read.
+    fn read_until(&mut self, delimiter: u8, buffer: &mut Vec<u8>) -> Result<usize> {
+        let mut read = 0;
+        loop {
+            let (done, used) = {
+                let available = self.fill_buf()?;
+                match available.iter().position(|&byte| byte == delimiter) {
+                    Some(end) => {
+                        buffer.extend_from_slice(&available[..=end]);
+                        (true, end + 1)
+                    }
+                    None => {
+                        buffer.extend_from_slice(available);
+                        (available.is_empty(), available.len())
+                    }
+                }
+            };
+            self.consume(used);
+            read += used;
+            if done {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Read a line, and append it to `line` (including the `\n` at its end, if there is one). Returns the number
+    /// of bytes read, which is `0` at the end of the stream.
+    fn read_line(&mut self, line: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        let length = self.read_until(b'\n', &mut bytes)?;
+        let string = String::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid UTF-8"))?;
+        line.push_str(&string);
+        Ok(length)
+    }
+
+    /// Iterate over the lines of the stream, without their line endings.
+    fn lines(self) -> Lines<Self>
+    where
+        Self: Sized,
+    {
+        Lines { inner: self }
+    }
+}
+
+/// An iterator over the bytes of a `Read`, created with `Read::bytes`.
+pub struct Bytes<R> {
+    inner: R,
+}
+
+impl<R: Read> Iterator for Bytes<R> {
+    type Item = Result<u8>;
+
+    fn next(&mut self) -> Option<Result<u8>> {
+        let mut byte = 0;
+        loop {
+            return match self.inner.read(core::slice::from_mut(&mut byte)) {
+                Ok(0) => None,
+                Ok(_) => Some(Ok(byte)),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => Some(Err(err)),
+            };
+        }
+    }
+}
+
+/// A `Read` that reads at most a limited number of bytes, created with `Read::take`.
+pub struct Take<R> {
+    inner: R,
+    limit: u64,
+}
+
+impl<R> Take<R> {
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Take<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let length = usize::min(buffer.len(), self.limit.min(usize::MAX as u64) as usize);
+        let read = self.inner.read(&mut buffer[0..length])?;
+        self.limit -= read as u64;
+        Ok(read)
+    }
+}
+
+/// An iterator over the lines of a `BufRead`, created with `BufRead::lines`.
+pub struct Lines<B> {
+    inner: B,
+}
+
+impl<B: BufRead> Iterator for Lines<B> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        let mut line = String::new();
+        match self.inner.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Some(Ok(line))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Adds a buffer to a `Read`, so that small reads don't each have to be made of the underlying stream (which can
+/// be expensive - e.g. each read of a `File` is a request to the VFS).
+pub struct BufReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    position: usize,
+    filled: usize,
+}
+
+impl<R: Read> BufReader<R> {
+    pub fn new(inner: R) -> BufReader<R> {
+        BufReader::with_capacity(4096, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: R) -> BufReader<R> {
+        BufReader { inner, buffer: vec![0; capacity], position: 0, filled: 0 }
+    }
+}
+
+impl<R> BufReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Get the underlying stream. Any data still in the buffer is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer[self.position..self.filled]
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        // Large reads skip the buffer entirely if it's empty
+        if self.position == self.filled && buffer.len() >= self.buffer.len() {
+            return self.inner.read(buffer);
+        }
+        let available = self.fill_buf()?;
+        let length = usize::min(available.len(), buffer.len());
+        buffer[0..length].copy_from_slice(&available[0..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl<R: Read> BufRead for BufReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.position == self.filled {
+            self.filled = self.inner.read(&mut self.buffer)?;
+            self.position = 0;
+        }
+        Ok(&self.buffer[self.position..self.filled])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = usize::min(self.position + amount, self.filled);
+    }
+}
+
+impl<R: Read + ?Sized> Read for &mut R {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read(buffer)
+    }
+}
+
+impl<R: Read + ?Sized> Read for Box<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read(buffer)
+    }
+}
+
+impl<B: BufRead + ?Sized> BufRead for &mut B {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        (**self).fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        (**self).consume(amount)
+    }
+}
+
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        (**self).write(data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+impl<W: Write + ?Sized> Write for Box<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        (**self).write(data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+impl<S: Seek + ?Sized> Seek for &mut S {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64> {
+        (**self).seek(position)
+    }
+}
+
+impl Read for &[u8] {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let length = usize::min(buffer.len(), self.len());
+        buffer[0..length].copy_from_slice(&self[0..length]);
+        *self = &self[length..];
+        Ok(length)
+    }
+}
+
+impl BufRead for &[u8] {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        Ok(*self)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        *self = &self[amount..];
+    }
+}
+
+impl Write for Vec<u8> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Copy the whole of `reader` into `writer`, returning the number of bytes copied.
+pub fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut buffer = [0; 1024];
+    let mut copied = 0;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(length) => {
+                writer.write_all(&buffer[0..length])?;
+                copied += length as u64;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// A handle to the task's standard output.
+pub struct Stdout;
+
+/// A handle to the task's standard error.
+pub struct Stderr;
+
+/// A locked handle to the task's standard output, returned by `Stdout::lock`. Each write is sent on its own, so
+/// unlike in real `std`, this doesn't stop other threads writing in between.
+pub struct StdoutLock<'a>(core::marker::PhantomData<&'a ()>);
+
+pub fn stdout() -> Stdout {
+    Stdout
+}
+
+pub fn stderr() -> Stderr {
+    Stderr
+}
+
+impl Stdout {
+    pub fn lock(&self) -> StdoutLock<'static> {
+        StdoutLock(core::marker::PhantomData)
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        write(stdio::stdout(), &String::from_utf8_lossy(data))?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for StdoutLock<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        Stdout.write(data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Stderr {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        write(stdio::stderr(), &String::from_utf8_lossy(data))?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn write(output: Option<&Output>, text: &str) -> Result<()> {
+    match output {
+        Some(output) => output.write(text).map_err(|err| Error::new(ErrorKind::BrokenPipe, format!("{:?}", err))),
+        None => {
+            for line in text.lines() {
+                poplar::syscall::early_log(line).map_err(|err| Error::other(format!("{:?}", err)))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A handle to the task's standard input.
+pub struct Stdin;
+
+/// A locked handle to the task's standard input, returned by `Stdin::lock`. Other threads can't read from stdin
+/// until it's dropped.
+pub struct StdinLock<'a> {
+    state: MutexGuard<'a, InputState>,
+}
+
+pub fn stdin() -> Stdin {
+    Stdin
+}
+
+/// The state of reading from stdin, shared by every `Stdin`.
+struct InputState {
+    /// Input that has been received, but not yet read.
+    pending: VecDeque<u8>,
+    /// Whether we've asked the console to send us input.
+    attached: bool,
+    /// Whether the end of the input has been reached.
+    ended: bool,
+}
+
+static INPUT: Mutex<InputState> =
+    Mutex::new(InputState { pending: VecDeque::new(), attached: false, ended: false });
+
+/// Typing Ctrl+D into a console ends the input.
+const END_OF_INPUT: u8 = 0x04;
+
+impl InputState {
+    /// Wait for more input, if there isn't any pending. Returns `false` once the end of the input has been
+    /// reached, and everything before it has been read.
+    fn fill(&mut self) -> bool {
+        while self.pending.is_empty() && !self.ended {
+            match stdio::stdin() {
+                Some(Input::Pipe(pipe)) => {
+                    let mut buffer = [0; 512];
+                    match pipe.read_blocking(&mut buffer) {
+                        Ok(0) | Err(_) => self.ended = true,
+                        Ok(length) => self.pending.extend(&buffer[0..length]),
+                    }
+                }
+                Some(Input::Console(channel)) => {
+                    if !self.attached {
+                        self.attached = true;
+                        self.ended = channel.send(&ConsoleRequest::AttachInput).is_err();
+                    }
+                    if !self.ended {
+                        match channel.receive_blocking() {
+                            Ok(ConsoleEvent::Input(input)) => {
+                                match input.bytes().position(|byte| byte == END_OF_INPUT) {
+                                    Some(end) => {
+                                        self.pending.extend(&input.as_bytes()[0..end]);
+                                        self.ended = true;
+                                    }
+                                    None => self.pending.extend(input.as_bytes()),
+                                }
+                            }
+                            Err(_) => self.ended = true,
+                        }
+                    }
+                    if self.ended {
+                        let _ = channel.send(&ConsoleRequest::DetachInput);
+                    }
+                }
+                None => self.ended = true,
+            }
+        }
+        !self.pending.is_empty()
+    }
+}
+
+impl Stdin {
+    pub fn lock(&self) -> StdinLock<'static> {
+        StdinLock { state: INPUT.lock() }
+    }
+
+    /// Read a line of input, and append it to `line` (including the `\n` at its end, if there is one). Returns
+    /// the number of bytes read, which is `0` at the end of the input.
+    pub fn read_line(&self, line: &mut String) -> Result<usize> {
+        self.lock().read_line(line)
+    }
+
+    pub fn lines(self) -> Lines<StdinLock<'static>> {
+        self.lock().lines()
+    }
+}
+
+/*
+ * Reads block until there's some input. `0` bytes are read at the end of the input: for a pipe, once the task
+ * writing to it is done, and for a console, once Ctrl+D is typed. Tasks with no stdin are always at the end of
+ * their input. Input from a console isn't echoed, so it isn't shown as it's typed.
+ */
+impl Read for Stdin {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.lock().read(buffer)
+    }
+}
+
+impl Read for StdinLock<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let available = self.fill_buf()?;
+        let length = usize::min(buffer.len(), available.len());
+        buffer[0..length].copy_from_slice(&available[0..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl BufRead for StdinLock<'_> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.state.fill();
+        Ok(self.state.pending.make_contiguous())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.state.pending.drain(0..amount);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    print_to(Stdout, args)
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    print_to(Stderr, args)
+}
+
+/*
+ * The text is formatted before it's written, so it's sent in as few messages as possible, and each line reaches
+ * `early_log` in one piece.
+ */
+fn print_to(mut to: impl Write, args: fmt::Arguments) {
+    let _ = to.write_fmt(args);
+}
+
+/// Print to stdout.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::io::_print(format_args!($($arg)*))
+    };
+}
+
+/// Print to stdout, followed by a newline.
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::io::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Print to stderr.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::io::_eprint(format_args!($($arg)*))
+    };
+}
+
+/// Print to stderr, followed by a newline.
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        $crate::eprint!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::io::_eprint(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
@@ -1,5 +1,13 @@
 #![allow(internal_features)]
-#![feature(lang_items, prelude_import, async_iterator, core_intrinsics, naked_functions)]
+#![feature(
+    lang_items,
+    prelude_import,
+    async_iterator,
+    core_intrinsics,
+    naked_functions,
+    thread_local,
+    allow_internal_unstable
+)]
 #![no_std]
 
 extern crate alloc;
@@ -11,6 +19,7 @@ extern crate alloc;
 pub use alloc::{borrow, boxed, collections, fmt, format, rc, slice, str, string, sync, vec};
 pub use core::{
     any,
+    arch,
     array,
     async_iter,
     cell,
@@ -19,6 +28,7 @@ pub use core::{
     cmp,
     convert,
     default,
+    error,
     future,
     hash,
     hint,
@@ -26,6 +36,7 @@ pub use core::{
     iter,
     marker,
     mem,
+    num,
     ops,
     option,
     pin,
@@ -35,6 +46,20 @@ pub use core::{
 };
 pub use poplar;
 
+pub mod env;
+pub mod ffi;
+pub mod fs;
+pub mod io;
+pub mod net;
+pub mod path;
+pub mod process;
+pub mod thread;
+
+pub mod time {
+    pub use core::time::*;
+    pub use poplar::time::{Instant, SystemTime, SystemTimeError, UNIX_EPOCH};
+}
+
 // Import our own prelude for this crate
 #[allow(unused_imports)] // Not sure why this counts as unused but the compiler thinks it is.
 #[prelude_import]
@@ -67,6 +92,7 @@ pub mod prelude {
 
 use core::panic::PanicInfo;
 use linked_list_allocator::LockedHeap;
+use mulch::backtrace::Backtrace;
 use poplar::{memory_object::MemoryObject, syscall::MemoryObjectFlags};
 
 #[global_allocator]
@@ -94,6 +120,13 @@ unsafe extern "C" fn _start() -> ! {
     )
 }
 
+#[cfg(target_arch = "aarch64")]
+#[no_mangle]
+#[naked]
+unsafe extern "C" fn _start() -> ! {
+    core::arch::naked_asm!("b rust_entry")
+}
+
 #[no_mangle]
 unsafe extern "C" fn rust_entry() -> ! {
     extern "C" {
@@ -107,25 +140,23 @@ unsafe extern "C" fn rust_entry() -> ! {
      * it, and then increase the size of it as it becomes exhausted. At some point, we should
      * probably free massive heaps if tasks have a high tidemark but low normal usage or whatever.
      */
-    const HEAP_START: usize = 0x600000000;
     const HEAP_SIZE: usize = 0x800000;
+    // The kernel chooses where the heap goes, which is randomized for each task
     let heap = MemoryObject::create(HEAP_SIZE, MemoryObjectFlags::WRITABLE).unwrap();
-    let _mapped_heap = heap.map_at(HEAP_START).unwrap();
-    ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+    let mapped_heap = heap.map().unwrap();
+    ALLOCATOR.lock().init(mapped_heap.mapped_at as *mut u8, HEAP_SIZE);
 
-    main(0, core::ptr::null());
-
-    poplar::syscall::early_log("Returned from main. Looping.").unwrap();
-    loop {
-        poplar::syscall::yield_to_kernel();
-        // TODO: actually this should call an exit system call or something
-    }
+    thread::init_main_thread();
+    let status = main(0, core::ptr::null());
+    process::exit(status as i32)
 }
 
 #[lang = "start"]
-fn lang_start<T>(main: fn() -> T, _argc: isize, _argv: *const *const u8, _sigpipe: u8) -> isize {
-    main();
-    0
+fn lang_start<T>(main: fn() -> T, _argc: isize, _argv: *const *const u8, _sigpipe: u8) -> isize
+where
+    T: process::Termination + 'static,
+{
+    main().report().to_i32() as isize
 }
 
 #[panic_handler]
@@ -147,11 +178,32 @@ pub fn handle_panic(info: &PanicInfo) -> ! {
     }
     let _ = poplar::syscall::early_log(buffer.as_str());
 
-    loop {}
+    /*
+     * Log the backtrace a frame at a time, so it doesn't need a large buffer. Addresses in the backtrace can be
+     * resolved with `cargo xtask symbolicate`.
+     */
+    let backtrace = Backtrace::capture(|address| address != 0 && address <= USER_ADDRESS_SPACE_END);
+    let _ = poplar::syscall::early_log("Backtrace:");
+    for (i, address) in backtrace.frames().iter().enumerate() {
+        let mut buffer = PanicBuffer::new();
+        let _ = write!(buffer, "  #{}: {:#x}", i, address);
+        let _ = poplar::syscall::early_log(buffer.as_str());
+    }
+
+    // Exit with the same status as a panicking task does in real `std`
+    process::exit(101)
 }
 
 const PANIC_BUFFER_LEN: usize = 256;
 
+/// The highest address in the lower half of the address space, which is where tasks live. Frame records above this
+/// aren't followed when capturing a backtrace. On x86_64 and RISC-V, the size of the lower half depends on the
+/// paging mode the kernel picked at boot, so we use the largest it can be.
+#[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
+const USER_ADDRESS_SPACE_END: usize = 0x00ff_ffff_ffff_ffff;
+#[cfg(target_arch = "aarch64")]
+const USER_ADDRESS_SPACE_END: usize = 0x0000_7fff_ffff_ffff;
+
 pub struct PanicBuffer {
     buffer: [u8; PANIC_BUFFER_LEN],
     len: usize,
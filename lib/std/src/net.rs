@@ -0,0 +1,1071 @@
+//! Networking. This mirrors `std::net` in Rust's real `std`: IP addresses and socket addresses, and UDP sockets,
+//! which are provided by the network stack through the `net.socket` service (see `poplar::net`).
+//!
+//! The network stack only supports IPv4 and UDP for now, so `TcpStream` and `TcpListener` exist only so code that
+//! names them compiles - creating one always fails with `Unsupported`, as does binding or sending to an IPv6
+//! address. There's no DNS resolver either, so only addresses can be used with `ToSocketAddrs`, not hostnames.
+
+use crate::{
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+use alloc::{string::String, vec, vec::Vec};
+use core::{error, fmt, str::FromStr};
+use poplar::{
+    channel::Channel,
+    net::{Ipv4Address, SocketError, SocketEvent, SocketRequest, MAX_DATAGRAM_SIZE, NET_SOCKET_SERVICE},
+    service_host::ServiceHostClient,
+    sync::Mutex,
+    syscall::{self, ObjectWaitManyError, Signals, WaitItem},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Addr {
+    octets: [u8; 4],
+}
+
+impl Ipv4Addr {
+    pub const LOCALHOST: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 255);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Ipv4Addr {
+        Ipv4Addr { octets: [a, b, c, d] }
+    }
+
+    pub const fn octets(&self) -> [u8; 4] {
+        self.octets
+    }
+
+    pub const fn is_unspecified(&self) -> bool {
+        u32::from_be_bytes(self.octets) == 0
+    }
+
+    pub const fn is_loopback(&self) -> bool {
+        self.octets[0] == 127
+    }
+
+    pub const fn is_private(&self) -> bool {
+        match self.octets {
+            [10, ..] => true,
+            [172, b, ..] => b >= 16 && b <= 31,
+            [192, 168, ..] => true,
+            _ => false,
+        }
+    }
+
+    pub const fn is_link_local(&self) -> bool {
+        matches!(self.octets, [169, 254, ..])
+    }
+
+    pub const fn is_multicast(&self) -> bool {
+        self.octets[0] >= 224 && self.octets[0] <= 239
+    }
+
+    pub const fn is_broadcast(&self) -> bool {
+        u32::from_be_bytes(self.octets) == u32::MAX
+    }
+
+    pub const fn to_ipv6_mapped(&self) -> Ipv6Addr {
+        let [a, b, c, d] = self.octets;
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d]))
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.octets;
+        f.pad(&format!("{}.{}.{}.{}", a, b, c, d))
+    }
+}
+
+impl fmt::Debug for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Ipv4Addr, AddrParseError> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            let part = parts.next().ok_or(AddrParseError(AddrKind::Ipv4))?;
+            // Leading zeros are rejected, as some parsers treat them as meaning the octet is in octal
+            let has_leading_zero = part.len() > 1 && part.starts_with('0');
+            if part.is_empty() || has_leading_zero || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(AddrParseError(AddrKind::Ipv4));
+            }
+            *octet = part.parse().map_err(|_| AddrParseError(AddrKind::Ipv4))?;
+        }
+        match parts.next() {
+            Some(_) => Err(AddrParseError(AddrKind::Ipv4)),
+            None => Ok(Ipv4Addr { octets }),
+        }
+    }
+}
+
+impl From<[u8; 4]> for Ipv4Addr {
+    fn from(octets: [u8; 4]) -> Ipv4Addr {
+        Ipv4Addr { octets }
+    }
+}
+
+impl From<u32> for Ipv4Addr {
+    fn from(address: u32) -> Ipv4Addr {
+        Ipv4Addr { octets: address.to_be_bytes() }
+    }
+}
+
+impl From<Ipv4Addr> for u32 {
+    fn from(address: Ipv4Addr) -> u32 {
+        u32::from_be_bytes(address.octets)
+    }
+}
+
+impl From<Ipv4Address> for Ipv4Addr {
+    fn from(address: Ipv4Address) -> Ipv4Addr {
+        Ipv4Addr { octets: address.0 }
+    }
+}
+
+impl From<Ipv4Addr> for Ipv4Address {
+    fn from(address: Ipv4Addr) -> Ipv4Address {
+        Ipv4Address(address.octets)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv6Addr {
+    segments: [u16; 8],
+}
+
+impl Ipv6Addr {
+    pub const LOCALHOST: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
+    pub const UNSPECIFIED: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
+
+    #[allow(clippy::too_many_arguments)]
+    pub const fn new(a: u16, b: u16, c: u16, d: u16, e: u16, f: u16, g: u16, h: u16) -> Ipv6Addr {
+        Ipv6Addr { segments: [a, b, c, d, e, f, g, h] }
+    }
+
+    pub const fn segments(&self) -> [u16; 8] {
+        self.segments
+    }
+
+    pub fn octets(&self) -> [u8; 16] {
+        let mut octets = [0u8; 16];
+        for (i, segment) in self.segments.iter().enumerate() {
+            octets[(i * 2)..(i * 2 + 2)].copy_from_slice(&segment.to_be_bytes());
+        }
+        octets
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == Ipv6Addr::UNSPECIFIED
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        *self == Ipv6Addr::LOCALHOST
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.segments[0] & 0xff00 == 0xff00
+    }
+
+    /// Get the IPv4 address this address maps (`::ffff:a.b.c.d`), if it is one.
+    pub fn to_ipv4_mapped(&self) -> Option<Ipv4Addr> {
+        match self.segments {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                let [a, b] = high.to_be_bytes();
+                let [c, d] = low.to_be_bytes();
+                Some(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Ipv6Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ipv4) = self.to_ipv4_mapped() {
+            return f.pad(&format!("::ffff:{}", ipv4));
+        }
+
+        // The longest run of two or more zero segments is replaced with `::`
+        let mut longest = (0, 0);
+        let mut run = (0, 0);
+        for (i, &segment) in self.segments.iter().enumerate() {
+            if segment == 0 {
+                if run.1 == 0 {
+                    run.0 = i;
+                }
+                run.1 += 1;
+                if run.1 > longest.1 {
+                    longest = run;
+                }
+            } else {
+                run = (0, 0);
+            }
+        }
+
+        let join = |segments: &[u16]| segments.iter().map(|segment| format!("{:x}", segment)).collect::<Vec<_>>();
+        let address = if longest.1 >= 2 {
+            let (start, length) = longest;
+            format!(
+                "{}::{}",
+                join(&self.segments[..start]).join(":"),
+                join(&self.segments[(start + length)..]).join(":")
+            )
+        } else {
+            join(&self.segments).join(":")
+        };
+        f.pad(&address)
+    }
+}
+
+impl fmt::Debug for Ipv6Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Ipv6Addr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Ipv6Addr, AddrParseError> {
+        fn parse_segments(s: &str) -> Result<Vec<u16>, AddrParseError> {
+            if s.is_empty() {
+                return Ok(Vec::new());
+            }
+            s.split(':')
+                .map(|segment| {
+                    if segment.is_empty() || segment.len() > 4 {
+                        return Err(AddrParseError(AddrKind::Ipv6));
+                    }
+                    u16::from_str_radix(segment, 16).map_err(|_| AddrParseError(AddrKind::Ipv6))
+                })
+                .collect()
+        }
+
+        let mut segments = [0u16; 8];
+        match s.split_once("::") {
+            Some((head, tail)) => {
+                let head = parse_segments(head)?;
+                let tail = parse_segments(tail)?;
+                // `::` has to stand for at least one zero segment
+                if head.len() + tail.len() > 7 {
+                    return Err(AddrParseError(AddrKind::Ipv6));
+                }
+                segments[..head.len()].copy_from_slice(&head);
+                segments[(8 - tail.len())..].copy_from_slice(&tail);
+            }
+            None => {
+                let parsed = parse_segments(s)?;
+                if parsed.len() != 8 {
+                    return Err(AddrParseError(AddrKind::Ipv6));
+                }
+                segments.copy_from_slice(&parsed);
+            }
+        }
+        Ok(Ipv6Addr { segments })
+    }
+}
+
+impl From<[u16; 8]> for Ipv6Addr {
+    fn from(segments: [u16; 8]) -> Ipv6Addr {
+        Ipv6Addr { segments }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IpAddr {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+}
+
+impl IpAddr {
+    pub fn is_unspecified(&self) -> bool {
+        match self {
+            IpAddr::V4(address) => address.is_unspecified(),
+            IpAddr::V6(address) => address.is_unspecified(),
+        }
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        match self {
+            IpAddr::V4(address) => address.is_loopback(),
+            IpAddr::V6(address) => address.is_loopback(),
+        }
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        match self {
+            IpAddr::V4(address) => address.is_multicast(),
+            IpAddr::V6(address) => address.is_multicast(),
+        }
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        matches!(self, IpAddr::V4(_))
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, IpAddr::V6(_))
+    }
+}
+
+impl fmt::Display for IpAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpAddr::V4(address) => fmt::Display::fmt(address, f),
+            IpAddr::V6(address) => fmt::Display::fmt(address, f),
+        }
+    }
+}
+
+impl fmt::Debug for IpAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for IpAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<IpAddr, AddrParseError> {
+        if let Ok(address) = s.parse() {
+            return Ok(IpAddr::V4(address));
+        }
+        s.parse().map(IpAddr::V6).map_err(|_| AddrParseError(AddrKind::Ip))
+    }
+}
+
+impl From<Ipv4Addr> for IpAddr {
+    fn from(address: Ipv4Addr) -> IpAddr {
+        IpAddr::V4(address)
+    }
+}
+
+impl From<Ipv6Addr> for IpAddr {
+    fn from(address: Ipv6Addr) -> IpAddr {
+        IpAddr::V6(address)
+    }
+}
+
+impl From<[u8; 4]> for IpAddr {
+    fn from(octets: [u8; 4]) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(octets))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SocketAddrV4 {
+    ip: Ipv4Addr,
+    port: u16,
+}
+
+impl SocketAddrV4 {
+    pub const fn new(ip: Ipv4Addr, port: u16) -> SocketAddrV4 {
+        SocketAddrV4 { ip, port }
+    }
+
+    pub const fn ip(&self) -> &Ipv4Addr {
+        &self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: Ipv4Addr) {
+        self.ip = ip;
+    }
+
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+}
+
+impl fmt::Display for SocketAddrV4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("{}:{}", self.ip, self.port))
+    }
+}
+
+impl fmt::Debug for SocketAddrV4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for SocketAddrV4 {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<SocketAddrV4, AddrParseError> {
+        let (ip, port) = s.rsplit_once(':').ok_or(AddrParseError(AddrKind::SocketV4))?;
+        let ip = ip.parse().map_err(|_| AddrParseError(AddrKind::SocketV4))?;
+        let port = parse_port(port).ok_or(AddrParseError(AddrKind::SocketV4))?;
+        Ok(SocketAddrV4::new(ip, port))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SocketAddrV6 {
+    ip: Ipv6Addr,
+    port: u16,
+    flowinfo: u32,
+    scope_id: u32,
+}
+
+impl SocketAddrV6 {
+    pub const fn new(ip: Ipv6Addr, port: u16, flowinfo: u32, scope_id: u32) -> SocketAddrV6 {
+        SocketAddrV6 { ip, port, flowinfo, scope_id }
+    }
+
+    pub const fn ip(&self) -> &Ipv6Addr {
+        &self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: Ipv6Addr) {
+        self.ip = ip;
+    }
+
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    pub const fn flowinfo(&self) -> u32 {
+        self.flowinfo
+    }
+
+    pub const fn scope_id(&self) -> u32 {
+        self.scope_id
+    }
+}
+
+impl fmt::Display for SocketAddrV6 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("[{}]:{}", self.ip, self.port))
+    }
+}
+
+impl fmt::Debug for SocketAddrV6 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for SocketAddrV6 {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<SocketAddrV6, AddrParseError> {
+        let (ip, port) =
+            s.strip_prefix('[').and_then(|s| s.split_once("]:")).ok_or(AddrParseError(AddrKind::SocketV6))?;
+        let ip = ip.parse().map_err(|_| AddrParseError(AddrKind::SocketV6))?;
+        let port = parse_port(port).ok_or(AddrParseError(AddrKind::SocketV6))?;
+        Ok(SocketAddrV6::new(ip, port, 0, 0))
+    }
+}
+
+fn parse_port(port: &str) -> Option<u16> {
+    if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    port.parse().ok()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SocketAddr {
+    V4(SocketAddrV4),
+    V6(SocketAddrV6),
+}
+
+impl SocketAddr {
+    pub fn new(ip: IpAddr, port: u16) -> SocketAddr {
+        match ip {
+            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+        }
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            SocketAddr::V4(address) => IpAddr::V4(*address.ip()),
+            SocketAddr::V6(address) => IpAddr::V6(*address.ip()),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            SocketAddr::V4(address) => address.port(),
+            SocketAddr::V6(address) => address.port(),
+        }
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        match self {
+            SocketAddr::V4(address) => address.set_port(port),
+            SocketAddr::V6(address) => address.set_port(port),
+        }
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        matches!(self, SocketAddr::V4(_))
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, SocketAddr::V6(_))
+    }
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketAddr::V4(address) => fmt::Display::fmt(address, f),
+            SocketAddr::V6(address) => fmt::Display::fmt(address, f),
+        }
+    }
+}
+
+impl fmt::Debug for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for SocketAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<SocketAddr, AddrParseError> {
+        if let Ok(address) = s.parse() {
+            return Ok(SocketAddr::V4(address));
+        }
+        s.parse().map(SocketAddr::V6).map_err(|_| AddrParseError(AddrKind::Socket))
+    }
+}
+
+impl From<SocketAddrV4> for SocketAddr {
+    fn from(address: SocketAddrV4) -> SocketAddr {
+        SocketAddr::V4(address)
+    }
+}
+
+impl From<SocketAddrV6> for SocketAddr {
+    fn from(address: SocketAddrV6) -> SocketAddr {
+        SocketAddr::V6(address)
+    }
+}
+
+impl<I: Into<IpAddr>> From<(I, u16)> for SocketAddr {
+    fn from((ip, port): (I, u16)) -> SocketAddr {
+        SocketAddr::new(ip.into(), port)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum AddrKind {
+    Ip,
+    Ipv4,
+    Ipv6,
+    Socket,
+    SocketV4,
+    SocketV6,
+}
+
+/// Returned when parsing an IP address or socket address fails.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AddrParseError(AddrKind);
+
+impl fmt::Display for AddrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            AddrKind::Ip => "invalid IP address syntax",
+            AddrKind::Ipv4 => "invalid IPv4 address syntax",
+            AddrKind::Ipv6 => "invalid IPv6 address syntax",
+            AddrKind::Socket => "invalid socket address syntax",
+            AddrKind::SocketV4 => "invalid IPv4 socket address syntax",
+            AddrKind::SocketV6 => "invalid IPv6 socket address syntax",
+        })
+    }
+}
+
+impl error::Error for AddrParseError {}
+
+/// Types that can be converted to one or more socket addresses. Poplar doesn't have a DNS resolver, so strings
+/// must contain an address, rather than a hostname.
+pub trait ToSocketAddrs {
+    type Iter: Iterator<Item = SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter>;
+}
+
+impl ToSocketAddrs for SocketAddr {
+    type Iter = core::option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        Ok(Some(*self).into_iter())
+    }
+}
+
+impl ToSocketAddrs for SocketAddrV4 {
+    type Iter = core::option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        SocketAddr::V4(*self).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for SocketAddrV6 {
+    type Iter = core::option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        SocketAddr::V6(*self).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for (IpAddr, u16) {
+    type Iter = core::option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        SocketAddr::new(self.0, self.1).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for (Ipv4Addr, u16) {
+    type Iter = core::option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        SocketAddr::from(*self).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for (Ipv6Addr, u16) {
+    type Iter = core::option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        SocketAddr::from(*self).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for (&str, u16) {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        let ip: IpAddr = self.0.parse().map_err(|_| unresolvable())?;
+        Ok(vec![SocketAddr::new(ip, self.1)].into_iter())
+    }
+}
+
+impl ToSocketAddrs for (String, u16) {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        (self.0.as_str(), self.1).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for str {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        if let Ok(address) = self.parse() {
+            return Ok(vec![address].into_iter());
+        }
+        let (host, port) = self.rsplit_once(':').ok_or_else(|| invalid_input("invalid socket address"))?;
+        let port = parse_port(port).ok_or_else(|| invalid_input("invalid port value"))?;
+        (host, port).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for String {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        self.as_str().to_socket_addrs()
+    }
+}
+
+impl<'a> ToSocketAddrs for &'a [SocketAddr] {
+    type Iter = core::iter::Cloned<core::slice::Iter<'a, SocketAddr>>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        Ok(self.iter().cloned())
+    }
+}
+
+impl<T: ToSocketAddrs + ?Sized> ToSocketAddrs for &T {
+    type Iter = T::Iter;
+
+    fn to_socket_addrs(&self) -> io::Result<T::Iter> {
+        (**self).to_socket_addrs()
+    }
+}
+
+fn unresolvable() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "hostnames can't be resolved, as there is no DNS resolver")
+}
+
+fn invalid_input(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn ipv6_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "the network stack doesn't support IPv6")
+}
+
+/// Try `f` on each of the addresses `addresses` converts to, until it succeeds. Returns the last error if none of
+/// them do.
+fn each_address<A, F, T>(addresses: A, mut f: F) -> io::Result<T>
+where
+    A: ToSocketAddrs,
+    F: FnMut(SocketAddr) -> io::Result<T>,
+{
+    let mut last_error = None;
+    for address in addresses.to_socket_addrs()? {
+        match f(address) {
+            Ok(value) => return Ok(value),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| invalid_input("could not resolve to any addresses")))
+}
+
+fn to_ipv4(address: SocketAddr) -> io::Result<SocketAddrV4> {
+    match address {
+        SocketAddr::V4(address) => Ok(address),
+        SocketAddr::V6(address) => match address.ip().to_ipv4_mapped() {
+            Some(ip) => Ok(SocketAddrV4::new(ip, address.port())),
+            None => Err(ipv6_unsupported()),
+        },
+    }
+}
+
+/// A UDP socket, provided by the network stack. Each socket has its own channel to the `net.socket` service, and
+/// is closed when it's dropped.
+pub struct UdpSocket {
+    channel: Channel<SocketRequest, SocketEvent>,
+    local: SocketAddrV4,
+    state: Mutex<UdpState>,
+}
+
+#[derive(Default)]
+struct UdpState {
+    /// The only address `send` and `recv` send datagrams to and receive them from, once `connect` has been called.
+    peer: Option<SocketAddrV4>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+impl UdpSocket {
+    /// Create a socket bound to the given address. The network stack binds sockets to a port on all of its
+    /// interfaces, so only the port is used - a port of `0` binds the socket to any free port.
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<UdpSocket> {
+        each_address(address, |address| {
+            let address = to_ipv4(address)?;
+            let channel = ServiceHostClient::new()
+                .subscribe_service::<SocketRequest, SocketEvent>(NET_SOCKET_SERVICE)
+                .map_err(|()| io::Error::new(io::ErrorKind::PermissionDenied, "task can't use the network"))?;
+            let mut socket = UdpSocket { channel, local: address, state: Mutex::new(UdpState::default()) };
+
+            socket.request(&SocketRequest::BindUdp { port: address.port() })?;
+            match socket.channel.receive_blocking() {
+                Ok(SocketEvent::Bound { port }) => {
+                    socket.local.set_port(port);
+                    Ok(socket)
+                }
+                Ok(SocketEvent::Error(SocketError::AddressInUse)) => {
+                    Err(io::Error::from(io::ErrorKind::AddrInUse))
+                }
+                Ok(SocketEvent::Error(SocketError::NoFreePorts)) => {
+                    Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no free ports"))
+                }
+                _ => Err(io::Error::other("unexpected reply from the network stack")),
+            }
+        })
+    }
+
+    /// Send a datagram to `address`. Like any UDP datagram, it may not arrive, and the network stack drops
+    /// datagrams it can't route, so this only fails if the datagram can't be sent at all.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], address: A) -> io::Result<usize> {
+        let address = to_ipv4(
+            address.to_socket_addrs()?.next().ok_or_else(|| invalid_input("no addresses to send data to"))?,
+        )?;
+        if buf.len() > MAX_DATAGRAM_SIZE {
+            return Err(invalid_input("datagram is too large to be sent"));
+        }
+        self.request(&SocketRequest::SendTo {
+            address: Ipv4Address::from(*address.ip()),
+            port: address.port(),
+            data: buf.to_vec(),
+        })?;
+        Ok(buf.len())
+    }
+
+    /// Receive a datagram, returning its length and the address it was sent from. If the datagram is too long to
+    /// fit in `buf`, the rest of it is discarded.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (address, data) = self.receive()?;
+        let length = usize::min(buf.len(), data.len());
+        buf[..length].copy_from_slice(&data[..length]);
+        Ok((length, SocketAddr::V4(address)))
+    }
+
+    /// Only send datagrams to, and receive datagrams from, `address` with `send` and `recv`.
+    pub fn connect<A: ToSocketAddrs>(&self, address: A) -> io::Result<()> {
+        each_address(address, |address| {
+            self.state.lock().peer = Some(to_ipv4(address)?);
+            Ok(())
+        })
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let peer = self.state.lock().peer.ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        self.send_to(buf, peer)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.state.lock().peer.is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        self.recv_from(buf).map(|(length, _)| length)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::V4(self.local))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.state.lock().peer {
+            Some(peer) => Ok(SocketAddr::V4(peer)),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    /// Set how long `recv` and `recv_from` wait for a datagram before failing with `TimedOut`. `None` means they
+    /// wait forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(invalid_input("cannot set a zero duration timeout"));
+        }
+        self.state.lock().read_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.state.lock().read_timeout)
+    }
+
+    /// Sending never blocks, so this timeout is only recorded.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(invalid_input("cannot set a zero duration timeout"));
+        }
+        self.state.lock().write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.state.lock().write_timeout)
+    }
+
+    /// If `nonblocking` is `true`, `recv` and `recv_from` fail with `WouldBlock` instead of waiting for a
+    /// datagram.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.state.lock().nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// The network stack always allows sockets to send broadcast datagrams, so this does nothing.
+    pub fn set_broadcast(&self, _broadcast: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn request(&self, request: &SocketRequest) -> io::Result<()> {
+        self.channel.send(request).map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))
+    }
+
+    /// Wait for a datagram to arrive, honouring the socket's timeout and `nonblocking` setting. If the socket is
+    /// connected, datagrams from other addresses are discarded.
+    fn receive(&self) -> io::Result<(SocketAddrV4, Vec<u8>)> {
+        let (peer, timeout, nonblocking) = {
+            let state = self.state.lock();
+            (state.peer, state.read_timeout, state.nonblocking)
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            match self.channel.try_receive() {
+                Ok(Some(SocketEvent::Received { address, port, data })) => {
+                    let address = SocketAddrV4::new(Ipv4Addr::from(address), port);
+                    if peer.is_none() || peer == Some(address) {
+                        return Ok((address, data));
+                    }
+                    continue;
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => (),
+                Err(_) => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            }
+
+            if nonblocking {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => Some(remaining),
+                    _ => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+                },
+                None => None,
+            };
+            let mut items = [WaitItem::new(self.channel.handle(), Signals::READABLE)];
+            match syscall::object_wait_many(&mut items, true, timeout) {
+                Ok(()) | Err(ObjectWaitManyError::TimedOut) => (),
+                Err(_) => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for UdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdpSocket").field("addr", &self.local).finish()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let _ = syscall::handle_close(self.channel.handle());
+    }
+}
+
+/// Which halves of a TCP connection to shut down, used with `TcpStream::shutdown`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Shutdown {
+    Read,
+    Write,
+    Both,
+}
+
+/// The network stack doesn't support TCP yet, so `TcpStream`s and `TcpListener`s can't be created. They hold one
+/// of these, which can't exist, so their methods don't need to be implemented.
+#[derive(Debug)]
+enum Unsupported {}
+
+fn tcp_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "the network stack doesn't support TCP")
+}
+
+/// A TCP connection. The network stack doesn't support TCP yet, so `connect` always fails with `Unsupported`.
+#[derive(Debug)]
+pub struct TcpStream(Unsupported);
+
+impl TcpStream {
+    pub fn connect<A: ToSocketAddrs>(_address: A) -> io::Result<TcpStream> {
+        Err(tcp_unsupported())
+    }
+
+    pub fn connect_timeout(_address: &SocketAddr, _timeout: Duration) -> io::Result<TcpStream> {
+        Err(tcp_unsupported())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.0 {}
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.0 {}
+    }
+
+    pub fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        match self.0 {}
+    }
+
+    pub fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        match self.0 {}
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        match self.0 {}
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        match self.0 {}
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0 {}
+    }
+}
+
+/// Listens for TCP connections. The network stack doesn't support TCP yet, so `bind` always fails with
+/// `Unsupported`.
+#[derive(Debug)]
+pub struct TcpListener(Unsupported);
+
+impl TcpListener {
+    pub fn bind<A: ToSocketAddrs>(_address: A) -> io::Result<TcpListener> {
+        Err(tcp_unsupported())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.0 {}
+    }
+
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        match self.0 {}
+    }
+
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    pub fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        match self.0 {}
+    }
+}
+
+/// An iterator over the connections made to a `TcpListener`, returned by `TcpListener::incoming`.
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
@@ -0,0 +1,528 @@
+//! Paths to files. This mirrors `Path` and `PathBuf` in Rust's real `std`, with `/` as the only separator.
+//!
+//! Tasks don't have a current directory, so `fs` resolves relative paths from the root of the task's namespace.
+
+use crate::{
+    ffi::{OsStr, OsString},
+    fs, io,
+};
+use alloc::{
+    borrow::{Cow, ToOwned},
+    string::String,
+    vec::Vec,
+};
+use core::{borrow::Borrow, fmt, ops::Deref};
+
+pub const MAIN_SEPARATOR: char = '/';
+pub const MAIN_SEPARATOR_STR: &str = "/";
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Path {
+    inner: OsStr,
+}
+
+impl Path {
+    pub fn new<S: AsRef<OsStr> + ?Sized>(s: &S) -> &Path {
+        // SAFETY: `Path` is a `repr(transparent)` wrapper around `OsStr`
+        unsafe { &*(s.as_ref() as *const OsStr as *const Path) }
+    }
+
+    pub fn as_os_str(&self) -> &OsStr {
+        &self.inner
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        self.inner.as_str()
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        Some(self.as_str())
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.as_str())
+    }
+
+    pub fn to_path_buf(&self) -> PathBuf {
+        PathBuf::from(self.as_str())
+    }
+
+    pub fn display(&self) -> Display<'_> {
+        Display { path: self }
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.has_root()
+    }
+
+    pub fn is_relative(&self) -> bool {
+        !self.is_absolute()
+    }
+
+    pub fn has_root(&self) -> bool {
+        self.as_str().starts_with('/')
+    }
+
+    pub fn components(&self) -> Components<'_> {
+        Components { path: self.as_str(), has_root: self.has_root(), front: true }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &OsStr> {
+        self.components().map(Component::as_os_str)
+    }
+
+    /// Get the path without its final component, or `None` if the path is a root or empty.
+    pub fn parent(&self) -> Option<&Path> {
+        let mut components = self.components();
+        match components.next_back() {
+            Some(Component::Normal(_) | Component::CurDir | Component::ParentDir) => Some(components.as_path()),
+            _ => None,
+        }
+    }
+
+    pub fn file_name(&self) -> Option<&OsStr> {
+        match self.components().next_back() {
+            Some(Component::Normal(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The file name, without its extension.
+    pub fn file_stem(&self) -> Option<&OsStr> {
+        let name = self.file_name()?.as_str();
+        Some(OsStr::from_str(split_extension(name).0))
+    }
+
+    /// The part of the file name after its last `.`, unless the only `.` is at the start.
+    pub fn extension(&self) -> Option<&OsStr> {
+        let name = self.file_name()?.as_str();
+        split_extension(name).1.map(OsStr::from_str)
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut joined = self.to_path_buf();
+        joined.push(path);
+        joined
+    }
+
+    pub fn with_file_name<S: AsRef<OsStr>>(&self, name: S) -> PathBuf {
+        let mut path = self.to_path_buf();
+        path.set_file_name(name);
+        path
+    }
+
+    pub fn with_extension<S: AsRef<OsStr>>(&self, extension: S) -> PathBuf {
+        let mut path = self.to_path_buf();
+        path.set_extension(extension);
+        path
+    }
+
+    /// Whether `base` is a prefix of this path, comparing whole components.
+    pub fn starts_with<P: AsRef<Path>>(&self, base: P) -> bool {
+        let mut components = self.components();
+        base.as_ref().components().all(|component| components.next() == Some(component))
+    }
+
+    /// Whether `child` is a suffix of this path, comparing whole components.
+    pub fn ends_with<P: AsRef<Path>>(&self, child: P) -> bool {
+        let mut components = self.components();
+        child.as_ref().components().rev().all(|component| components.next_back() == Some(component))
+    }
+
+    pub fn strip_prefix<P: AsRef<Path>>(&self, base: P) -> Result<&Path, StripPrefixError> {
+        let mut components = self.components();
+        for component in base.as_ref().components() {
+            if components.next() != Some(component) {
+                return Err(StripPrefixError(()));
+            }
+        }
+        Ok(components.as_path())
+    }
+
+    pub fn exists(&self) -> bool {
+        fs::metadata(self).is_ok()
+    }
+
+    pub fn is_file(&self) -> bool {
+        fs::metadata(self).map(|metadata| metadata.is_file()).unwrap_or(false)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        fs::metadata(self).map(|metadata| metadata.is_dir()).unwrap_or(false)
+    }
+
+    pub fn metadata(&self) -> io::Result<fs::Metadata> {
+        fs::metadata(self)
+    }
+
+    pub fn read_dir(&self) -> io::Result<fs::ReadDir> {
+        fs::read_dir(self)
+    }
+}
+
+/// Split a file name into its stem and extension.
+fn split_extension(name: &str) -> (&str, Option<&str>) {
+    match name.rfind('.') {
+        Some(0) | None => (name, None),
+        Some(dot) => (&name[..dot], Some(&name[(dot + 1)..])),
+    }
+}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl AsRef<Path> for Path {
+    fn as_ref(&self) -> &Path {
+        self
+    }
+}
+
+impl AsRef<Path> for str {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for String {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for OsStr {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for OsString {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for Cow<'_, OsStr> {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<OsStr> for Path {
+    fn as_ref(&self) -> &OsStr {
+        &self.inner
+    }
+}
+
+impl ToOwned for Path {
+    type Owned = PathBuf;
+
+    fn to_owned(&self) -> PathBuf {
+        self.to_path_buf()
+    }
+}
+
+/// Displays a path, returned by `Path::display`.
+pub struct Display<'a> {
+    path: &'a Path,
+}
+
+impl fmt::Display for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.path.as_str(), f)
+    }
+}
+
+impl fmt::Debug for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.path, f)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct PathBuf {
+    inner: OsString,
+}
+
+impl PathBuf {
+    pub fn new() -> PathBuf {
+        PathBuf { inner: OsString::new() }
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(&self.inner)
+    }
+
+    pub fn into_os_string(self) -> OsString {
+        self.inner
+    }
+
+    /// Extend the path with `path`. If `path` is absolute, it replaces the whole path.
+    pub fn push<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref().as_str();
+        let inner = self.inner.as_mut_string();
+        if path.starts_with('/') {
+            inner.clear();
+        } else if !inner.is_empty() && !inner.ends_with('/') {
+            inner.push('/');
+        }
+        inner.push_str(path);
+    }
+
+    /// Remove the final component of the path. Returns `false` if there wasn't one to remove.
+    pub fn pop(&mut self) -> bool {
+        match self.as_path().parent().map(|parent| parent.as_str().len()) {
+            Some(length) => {
+                self.inner.as_mut_string().truncate(length);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_file_name<S: AsRef<OsStr>>(&mut self, name: S) {
+        if self.as_path().file_name().is_some() {
+            self.pop();
+        }
+        self.push(Path::new(name.as_ref()));
+    }
+
+    /// Replace the extension of the file name, or remove it if `extension` is empty. Returns `false` if there
+    /// isn't a file name.
+    pub fn set_extension<S: AsRef<OsStr>>(&mut self, extension: S) -> bool {
+        let Some(name) = self.as_path().file_name() else {
+            return false;
+        };
+        let mut name = String::from(split_extension(name.as_str()).0);
+        let extension = extension.as_ref().as_str();
+        if !extension.is_empty() {
+            name.push('.');
+            name.push_str(extension);
+        }
+        self.set_file_name(name);
+        true
+    }
+}
+
+impl fmt::Debug for PathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_path(), f)
+    }
+}
+
+impl Deref for PathBuf {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl Borrow<Path> for PathBuf {
+    fn borrow(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<Path> for PathBuf {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<OsStr> for PathBuf {
+    fn as_ref(&self) -> &OsStr {
+        self.as_path().as_os_str()
+    }
+}
+
+impl<T: ?Sized + AsRef<OsStr>> From<&T> for PathBuf {
+    fn from(s: &T) -> PathBuf {
+        PathBuf { inner: s.as_ref().to_os_string() }
+    }
+}
+
+impl From<String> for PathBuf {
+    fn from(s: String) -> PathBuf {
+        PathBuf { inner: OsString::from(s) }
+    }
+}
+
+impl From<OsString> for PathBuf {
+    fn from(s: OsString) -> PathBuf {
+        PathBuf { inner: s }
+    }
+}
+
+impl<P: AsRef<Path>> FromIterator<P> for PathBuf {
+    fn from_iter<I: IntoIterator<Item = P>>(iter: I) -> PathBuf {
+        let mut path = PathBuf::new();
+        for component in iter {
+            path.push(component);
+        }
+        path
+    }
+}
+
+/// A component of a path, returned by `Path::components`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Component<'a> {
+    RootDir,
+    CurDir,
+    ParentDir,
+    Normal(&'a OsStr),
+}
+
+impl<'a> Component<'a> {
+    pub fn as_os_str(self) -> &'a OsStr {
+        match self {
+            Component::RootDir => OsStr::from_str("/"),
+            Component::CurDir => OsStr::from_str("."),
+            Component::ParentDir => OsStr::from_str(".."),
+            Component::Normal(name) => name,
+        }
+    }
+}
+
+impl AsRef<Path> for Component<'_> {
+    fn as_ref(&self) -> &Path {
+        Path::new(self.as_os_str())
+    }
+}
+
+/// An iterator over the components of a path. Repeated separators and `.` components (other than at the start of
+/// the path) are skipped, as they don't change which file the path refers to.
+#[derive(Clone)]
+pub struct Components<'a> {
+    /// The part of the path that hasn't been iterated over yet.
+    path: &'a str,
+    /// Whether the root of the path hasn't been iterated over yet.
+    has_root: bool,
+    /// Whether we haven't taken any components from the front of the path yet.
+    front: bool,
+}
+
+impl<'a> Components<'a> {
+    /// Get the part of the path that hasn't been iterated over yet.
+    pub fn as_path(&self) -> &'a Path {
+        let mut path = self.path;
+        if !self.has_root {
+            path = path.trim_start_matches('/');
+        }
+        match path.trim_end_matches('/').trim_end_matches("/.").trim_end_matches('/') {
+            "" if self.has_root => Path::new("/"),
+            path => Path::new(path),
+        }
+    }
+
+    fn parse(&self, name: &'a str) -> Option<Component<'a>> {
+        match name {
+            "" => None,
+            "." if !self.front => None,
+            "." => Some(Component::CurDir),
+            ".." => Some(Component::ParentDir),
+            name => Some(Component::Normal(OsStr::from_str(name))),
+        }
+    }
+
+    /// Parse a component taken from the back of the path. `.` components are skipped, unless they're the whole
+    /// of a relative path, which `next_back` checks for itself.
+    fn parse_back(&self, name: &'a str) -> Option<Component<'a>> {
+        match name {
+            "" | "." => None,
+            ".." => Some(Component::ParentDir),
+            name => Some(Component::Normal(OsStr::from_str(name))),
+        }
+    }
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Component<'a>;
+
+    fn next(&mut self) -> Option<Component<'a>> {
+        if self.has_root {
+            self.has_root = false;
+            self.path = &self.path[1..];
+            self.front = false;
+            return Some(Component::RootDir);
+        }
+        while !self.path.is_empty() {
+            let (name, rest) = self.path.split_once('/').unwrap_or((self.path, ""));
+            self.path = rest;
+            let component = self.parse(name);
+            self.front = false;
+            if component.is_some() {
+                return component;
+            }
+        }
+        None
+    }
+}
+
+impl<'a> DoubleEndedIterator for Components<'a> {
+    fn next_back(&mut self) -> Option<Component<'a>> {
+        let start = if self.has_root { 1 } else { 0 };
+        while self.path.len() > start {
+            let (rest, name) = match self.path[start..].rfind('/') {
+                Some(index) => (&self.path[..(start + index)], &self.path[(start + index + 1)..]),
+                None => (&self.path[..start], &self.path[start..]),
+            };
+            // A `.` at the start of a relative path is only a component if it's the only one
+            let is_first = rest.len() == start && self.front && !self.has_root;
+            let component = match name {
+                "." if is_first => Some(Component::CurDir),
+                name => self.parse_back(name),
+            };
+            self.path = rest;
+            if component.is_some() {
+                return component;
+            }
+        }
+        if self.has_root {
+            self.has_root = false;
+            self.path = "";
+            return Some(Component::RootDir);
+        }
+        None
+    }
+}
+
+impl<'a> IntoIterator for &'a Path {
+    type Item = &'a OsStr;
+    type IntoIter = core::iter::Map<Components<'a>, fn(Component<'a>) -> &'a OsStr>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.components().map(Component::as_os_str)
+    }
+}
+
+/// Returned by `Path::strip_prefix` if the path doesn't start with the prefix.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StripPrefixError(());
+
+impl fmt::Display for StripPrefixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("prefix not found")
+    }
+}
+
+/// Split a path into the components the VFS expects. Relative paths are resolved from the root of the namespace,
+/// and `..` components remove the component before them.
+pub(crate) fn resolve(path: &Path) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.as_str()),
+            Component::ParentDir => {
+                components.pop();
+            }
+            Component::RootDir | Component::CurDir => (),
+        }
+    }
+    let mut resolved = String::from("/");
+    resolved.push_str(&components.join("/"));
+    resolved
+}
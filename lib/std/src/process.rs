@@ -0,0 +1,63 @@
+//! Exiting the current task. This mirrors a small part of `std::process` in Rust's real `std`.
+
+use core::fmt::Debug;
+
+/// Exit the calling thread with the given status. By convention, a status of `0` means the task succeeded. The
+/// task's handles are closed once all of its threads have exited.
+pub fn exit(code: i32) -> ! {
+    // Make sure records that are waiting to be sent to `log_server` aren't lost
+    poplar::logger::LOGGER.try_flush();
+    poplar::syscall::task_exit(code)
+}
+
+/// The status a task exits with when it returns from `main`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ExitCode(i32);
+
+impl ExitCode {
+    pub const SUCCESS: ExitCode = ExitCode(0);
+    pub const FAILURE: ExitCode = ExitCode(1);
+
+    pub fn to_i32(self) -> i32 {
+        self.0
+    }
+}
+
+impl From<u8> for ExitCode {
+    fn from(code: u8) -> ExitCode {
+        ExitCode(code as i32)
+    }
+}
+
+/// Implemented by the types that `main` can return, to turn them into the status the task exits with.
+pub trait Termination {
+    fn report(self) -> ExitCode;
+}
+
+impl Termination for () {
+    fn report(self) -> ExitCode {
+        ExitCode::SUCCESS
+    }
+}
+
+impl Termination for ExitCode {
+    fn report(self) -> ExitCode {
+        self
+    }
+}
+
+impl<T, E> Termination for Result<T, E>
+where
+    T: Termination,
+    E: Debug,
+{
+    fn report(self) -> ExitCode {
+        match self {
+            Ok(value) => value.report(),
+            Err(err) => {
+                let _ = poplar::syscall::early_log(&alloc::format!("Error: {:?}", err));
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
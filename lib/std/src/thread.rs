@@ -0,0 +1,327 @@
+//! Threads that share the task's address space. This mirrors `std::thread` in Rust's real `std`.
+
+use crate::io;
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::{
+    any::Any,
+    cell::{OnceCell, UnsafeCell},
+    fmt,
+    num::{NonZeroU64, NonZeroUsize},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+use poplar::{
+    syscall::{self, Signals, WaitItem},
+    Handle,
+};
+
+pub type Result<T> = core::result::Result<T, Box<dyn Any + Send + 'static>>;
+
+/// Spawn a new thread, which runs `f`. The thread can be waited for, and the value `f` returns retrieved, with
+/// the returned `JoinHandle`. Panics if the thread can't be created - use `Builder::spawn` to handle that instead.
+///
+/// Threads exit when `f` returns, or when they panic. The task continues running until all of its threads have
+/// exited (including the one running `main`).
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f).expect("Failed to spawn thread")
+}
+
+/// Configures a new thread before it's spawned.
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<String>,
+    stack_size: Option<usize>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    pub fn name(mut self, name: String) -> Builder {
+        self.name = Some(name);
+        self
+    }
+
+    /// The kernel decides how large each thread's stack is, so this is currently ignored.
+    pub fn stack_size(mut self, size: usize) -> Builder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let thread = Thread::new(self.name);
+        let their_thread = thread.clone();
+        let packet = Arc::new(Packet { result: UnsafeCell::new(None) });
+        let their_packet = packet.clone();
+        let main: Box<dyn FnOnce() + Send> = Box::new(move || unsafe {
+            CURRENT.with(|current| {
+                let _ = current.set(their_thread);
+            });
+            *their_packet.result.get() = Some(f());
+        });
+
+        match poplar::thread::spawn_raw(main) {
+            Ok(handle) => Ok(JoinHandle { handle, thread, packet }),
+            Err(err) => Err(io::Error::other(format!("failed to create thread: {:?}", err))),
+        }
+    }
+}
+
+/// Yield the rest of this thread's time slice to other threads and tasks.
+pub fn yield_now() {
+    syscall::yield_to_kernel();
+}
+
+/// Block this thread for (at least) the given duration.
+pub fn sleep(duration: Duration) {
+    syscall::sleep(duration);
+}
+
+/// Get the `Thread` for the calling thread.
+pub fn current() -> Thread {
+    CURRENT.with(|current| current.get_or_init(|| Thread::new(None)).clone())
+}
+
+/// Name the thread that runs `main`. This is called before `main` is, so `current` can find the name.
+pub(crate) fn init_main_thread() {
+    CURRENT.with(|current| {
+        let _ = current.set(Thread::new(Some(String::from("main"))));
+    });
+}
+
+/// Block this thread until its token is made available by `Thread::unpark`, and then consume the token. If the
+/// token is already available, this returns immediately. Like in real `std`, this can also return spuriously, so
+/// callers should check whatever condition they're waiting for in a loop.
+pub fn park() {
+    current().inner.park(None);
+}
+
+/// Like `park`, but returns after `timeout` if the token hasn't been made available by then.
+pub fn park_timeout(timeout: Duration) {
+    current().inner.park(Some(timeout));
+}
+
+/// Get the number of CPUs the kernel is scheduling tasks on, which it publishes on the vDSO page.
+pub fn available_parallelism() -> io::Result<NonZeroUsize> {
+    poplar::vdso::cpu_count()
+        .and_then(NonZeroUsize::new)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "the number of CPUs is not known"))
+}
+
+/// Tasks exit as soon as one of their threads panics, so no code ever runs while a thread is panicking, and this
+/// always returns `false`.
+pub fn panicking() -> bool {
+    false
+}
+
+crate::thread_local! {
+    static CURRENT: OnceCell<Thread> = OnceCell::new();
+}
+
+/// Uniquely identifies a thread within a task.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct ThreadId(NonZeroU64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NonZeroU64::new(NEXT_ID.fetch_add(1, Ordering::Relaxed)).unwrap())
+    }
+
+    pub fn as_u64(&self) -> NonZeroU64 {
+        self.0
+    }
+}
+
+/// A handle to a thread, which can be used to find its name or ID, or to unpark it.
+#[derive(Clone)]
+pub struct Thread {
+    inner: Arc<ThreadInner>,
+}
+
+struct ThreadInner {
+    id: ThreadId,
+    name: Option<String>,
+    /// The state of the thread's parking token. This is one of `EMPTY`, `NOTIFIED`, or `PARKED`.
+    parker: AtomicU32,
+}
+
+const EMPTY: u32 = 0;
+const NOTIFIED: u32 = 1;
+const PARKED: u32 = u32::MAX;
+
+impl Thread {
+    fn new(name: Option<String>) -> Thread {
+        Thread { inner: Arc::new(ThreadInner { id: ThreadId::new(), name, parker: AtomicU32::new(EMPTY) }) }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.inner.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.inner.name.as_deref()
+    }
+
+    /// Make the thread's token available, waking the thread if it's parked.
+    pub fn unpark(&self) {
+        if self.inner.parker.swap(NOTIFIED, Ordering::Release) == PARKED {
+            syscall::wake_address(&self.inner.parker, 1);
+        }
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Thread").field("id", &self.id()).field("name", &self.name()).finish()
+    }
+}
+
+impl ThreadInner {
+    fn park(&self, timeout: Option<Duration>) {
+        // Either consume the token (`NOTIFIED` -> `EMPTY`), or mark the thread as parked (`EMPTY` -> `PARKED`)
+        if self.parker.fetch_sub(1, Ordering::Acquire) == NOTIFIED {
+            return;
+        }
+
+        loop {
+            let _ = syscall::wait_on_address(&self.parker, PARKED, timeout);
+            if self.parker.compare_exchange(NOTIFIED, EMPTY, Ordering::Acquire, Ordering::Acquire).is_ok() {
+                return;
+            }
+            if timeout.is_some() {
+                // We either timed out or were woken spuriously. Either way, we stop waiting.
+                self.parker.swap(EMPTY, Ordering::Acquire);
+                return;
+            }
+        }
+    }
+}
+
+pub struct JoinHandle<T> {
+    handle: Handle,
+    thread: Thread,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Wait for the thread to exit. Returns the value returned by the thread's closure, or an error if the thread
+    /// panicked.
+    pub fn join(self) -> Result<T> {
+        syscall::task_wait(self.handle, None).expect("Failed to wait for thread to exit");
+        // SAFETY: the thread has exited, so it can't be accessing the result any more
+        unsafe { (*self.packet.result.get()).take() }.ok_or_else(|| Box::new("thread panicked") as Box<_>)
+    }
+
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        let mut items = [WaitItem::new(self.handle, Signals::TERMINATED)];
+        syscall::object_wait_many(&mut items, false, None).is_ok()
+    }
+}
+
+/// The result of a thread's closure. This is written by the thread before it exits, and only read once the
+/// thread has exited, so it doesn't need to be locked.
+struct Packet<T> {
+    result: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T> Sync for Packet<T> where T: Send {}
+
+/// Declare a new thread-local variable, of type `LocalKey`. Each thread gets its own copy of the variable, which
+/// is lazily initialized with the given expression on the first access from that thread.
+///
+/// Unlike real `std`, the values are not dropped when a thread exits.
+#[macro_export]
+#[allow_internal_unstable(thread_local)]
+macro_rules! thread_local {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init);
+        $crate::thread_local!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$attr])*
+        $vis const $name: $crate::thread::LocalKey<$t> = {
+            fn __get() -> *const $crate::thread::LazyValue<$t> {
+                #[thread_local]
+                static VALUE: $crate::thread::LazyValue<$t> = $crate::thread::LazyValue::new();
+                $crate::ptr::addr_of!(VALUE)
+            }
+
+            fn __init() -> $t {
+                $init
+            }
+
+            $crate::thread::LocalKey::new(__get, __init)
+        };
+    };
+}
+
+/// A key for a thread-local variable, created with the `thread_local!` macro.
+pub struct LocalKey<T: 'static> {
+    get: fn() -> *const LazyValue<T>,
+    init: fn() -> T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(get: fn() -> *const LazyValue<T>, init: fn() -> T) -> LocalKey<T> {
+        LocalKey { get, init }
+    }
+
+    /// Get a reference to this thread's copy of the variable, and pass it to `f`. The variable is initialized
+    /// first if this is the first time it's been accessed from this thread.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        // SAFETY: the value lives in this thread's TLS block, which is never freed while the thread is running
+        let value = unsafe { &*(self.get)() };
+        f(value.get_or_init(self.init))
+    }
+}
+
+/// The storage behind a thread-local variable. The TLS template is loaded from the image, so each value starts
+/// uninitialized and is initialized on first access.
+#[doc(hidden)]
+pub struct LazyValue<T> {
+    value: UnsafeCell<Option<T>>,
+}
+
+impl<T> LazyValue<T> {
+    pub const fn new() -> LazyValue<T> {
+        LazyValue { value: UnsafeCell::new(None) }
+    }
+
+    fn get_or_init(&self, init: fn() -> T) -> &T {
+        // SAFETY: only the owning thread can access the value, and we never hand out a mutable reference to it
+        unsafe {
+            if (*self.value.get()).is_none() {
+                let value = init();
+                /*
+                 * `init` could have accessed the variable itself, in which case it has already been initialized.
+                 * We keep that value, as references to it may have escaped.
+                 */
+                if (*self.value.get()).is_none() {
+                    *self.value.get() = Some(value);
+                }
+            }
+            (*self.value.get()).as_ref().unwrap()
+        }
+    }
+}
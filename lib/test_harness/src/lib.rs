@@ -0,0 +1,341 @@
+//! `test_harness` helps test tasks (see `cargo xtask test`) check the protocols spoken between userspace tasks. A
+//! test usually plays one side of a protocol itself, against the real task that provides the other side (e.g.
+//! mounting a fake filesystem with the VFS, and then making requests of the VFS that it passes on to it), and
+//! checks what is sent between them.
+//!
+//! A `Tap` is put on a channel to record every message sent through it, in both directions, while passing them on
+//! unchanged. The test can then assert on the whole exchange, including which messages were calls and which were
+//! replies. `expect_message` and `expect_call` receive from a channel with a timeout, so a test fails, rather than
+//! hanging, if the other side never sends what it should.
+
+use ptah::{DeserializeOwned, Serialize};
+use std::{
+    poplar::{
+        channel::{CallId, Channel},
+        event::Event,
+        memory_object::MemoryObject,
+        sync::{Condvar, Mutex},
+        syscall::{
+            self,
+            EventFlags,
+            GetMessageError,
+            MemoryObjectFlags,
+            Signals,
+            WaitItem,
+            CHANNEL_MAX_NUM_BYTES,
+            CHANNEL_MAX_NUM_HANDLES,
+        },
+        Handle,
+    },
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How long tests should usually wait for another task to send something. This is generous, as tests are run in
+/// QEMU at the same time as the rest of userspace is starting.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Receive a message from `channel`. Panics if one doesn't arrive within `timeout`.
+pub fn expect_message<S, R>(channel: &Channel<S, R>, timeout: Duration) -> R
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    expect_call(channel, timeout).0
+}
+
+/// Like `expect_message`, but also returns the `CallId` needed to reply to the message.
+pub fn expect_call<S, R>(channel: &Channel<S, R>, timeout: Duration) -> (R, CallId)
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        match channel.try_receive_call() {
+            Ok(Some(message)) => return message,
+            Ok(None) => (),
+            Err(err) => panic!("Failed to receive message: {:?}", err),
+        }
+
+        let Some(remaining) = remaining(deadline) else {
+            panic!("Timed out after {:?} waiting for a message", timeout);
+        };
+        // This fails if the wait times out, in which case we check for a message one last time
+        let _ = syscall::wait_for_message(channel.handle(), Some(remaining));
+    }
+}
+
+/// How long is left until `deadline`, or `None` if it has passed.
+fn remaining(deadline: Instant) -> Option<Duration> {
+    deadline.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero())
+}
+
+/// Which way a message recorded by a `Tap` was going.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// Sent through the handle returned by `Tap::insert`.
+    Sent,
+    /// Sent to the handle returned by `Tap::insert`.
+    Received,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MessageKind {
+    Message,
+    /// A call, which the sender waits for the reply to.
+    Call,
+    /// The reply to a call. It goes the other way to the call.
+    Reply,
+}
+
+/// A message recorded by a `Tap`.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub direction: Direction,
+    pub kind: MessageKind,
+    /// The serialized message. For out-of-line messages, this is the message held by the memory object it was
+    /// sent in.
+    pub bytes: Vec<u8>,
+    /// The handles sent with the message (not including the memory object of an out-of-line message). These have
+    /// been passed on, so aren't valid in this task.
+    pub handles: Vec<Handle>,
+}
+
+impl Message {
+    fn new(direction: Direction, kind: MessageKind, bytes: &[u8], handles: &[Handle]) -> Message {
+        // Out-of-line messages are sent with no bytes, and the memory object they're in as the first handle
+        if bytes.is_empty() && !handles.is_empty() {
+            let bytes = out_of_line_message(handles[0]);
+            return Message { direction, kind, bytes, handles: handles[1..].to_vec() };
+        }
+        Message { direction, kind, bytes: bytes.to_vec(), handles: handles.to_vec() }
+    }
+
+    /// Deserialize the message as a `T`. Panics if it isn't a valid `T`.
+    pub fn decode<T>(&self) -> T
+    where
+        T: DeserializeOwned,
+    {
+        let handles: Vec<u32> = self.handles.iter().map(|handle| handle.0).collect();
+        ptah::from_wire(&self.bytes, &handles)
+            .unwrap_or_else(|err| panic!("Failed to decode message {:?}: {:?}", self, err))
+    }
+}
+
+/// Records the messages sent through a channel, in both directions, while passing them on unchanged. Calls are
+/// passed on as calls, and their replies as replies, so neither side can tell the tap is there.
+pub struct Tap {
+    transcript: Arc<Transcript>,
+    stop: Event,
+    thread: JoinHandle<()>,
+}
+
+impl Tap {
+    /// Put a tap on `channel`, which is one end of a channel. Returns the tap, and a handle to use in place of
+    /// `channel` - usually, this is passed to the task on the other side of the protocol being tested. Messages
+    /// sent through the returned handle are passed on to `channel`'s peer, and the other way around.
+    pub fn insert(channel: Handle) -> (Tap, Handle) {
+        let (near, returned) = syscall::create_channel().expect("Failed to create channel for tap");
+        let stop = Event::create(EventFlags::empty()).expect("Failed to create event for tap");
+        let transcript = Arc::new(Transcript { messages: Mutex::new(Vec::new()), changed: Condvar::new() });
+
+        let thread = thread::spawn({
+            let transcript = transcript.clone();
+            let stop = stop.handle();
+            move || {
+                let forwarder = Forwarder {
+                    near,
+                    far: channel,
+                    transcript,
+                    calls: Vec::new(),
+                    bytes: vec![0; CHANNEL_MAX_NUM_BYTES],
+                    handles: vec![Handle::ZERO; CHANNEL_MAX_NUM_HANDLES],
+                };
+                forwarder.run(stop);
+            }
+        });
+
+        (Tap { transcript, stop, thread }, returned)
+    }
+
+    /// The messages that have been passed through the tap so far, in the order they were passed on.
+    pub fn messages(&self) -> Vec<Message> {
+        self.transcript.messages.lock().clone()
+    }
+
+    /// Wait until at least `count` messages have been passed through the tap, and return them. As messages are
+    /// recorded once they've been passed on, the last of them is ready to be received when this returns. Panics if
+    /// they haven't been passed on within `timeout`.
+    pub fn wait_for(&self, count: usize, timeout: Duration) -> Vec<Message> {
+        let deadline = Instant::now() + timeout;
+        let mut messages = self.transcript.messages.lock();
+        while messages.len() < count {
+            let Some(remaining) = remaining(deadline) else {
+                panic!("Timed out after {:?} waiting for {} messages. Got: {:#?}", timeout, count, *messages);
+            };
+            messages = self.transcript.changed.wait_timeout(messages, remaining).0;
+        }
+        messages.clone()
+    }
+
+    /// Stop the tap, and return every message that was passed through it. Both ends of the channel the tap was
+    /// on are closed, so each side sees the other close.
+    pub fn finish(self) -> Vec<Message> {
+        let Tap { transcript, stop, thread } = self;
+        stop.signal();
+        thread.join().expect("Tap thread panicked");
+
+        let messages = transcript.messages.lock().clone();
+        let _ = syscall::handle_close(stop.handle());
+        messages
+    }
+}
+
+struct Transcript {
+    messages: Mutex<Vec<Message>>,
+    /// Notified each time a message is recorded.
+    changed: Condvar,
+}
+
+impl Transcript {
+    fn record(&self, message: Message) {
+        self.messages.lock().push(message);
+        self.changed.notify_all();
+    }
+}
+
+/// Passes messages between the two ends a `Tap` sits between, recording them as it goes.
+struct Forwarder {
+    /// The end whose peer is the handle returned by `Tap::insert`.
+    near: Handle,
+    /// The end passed to `Tap::insert`.
+    far: Handle,
+    transcript: Arc<Transcript>,
+    /// Calls that have been passed on, but haven't been replied to yet.
+    calls: Vec<ForwardedCall>,
+    bytes: Vec<u8>,
+    handles: Vec<Handle>,
+}
+
+/// A call that has been passed on by a `Forwarder`. Passing it on makes a new call, with its own transaction ID,
+/// so the reply has to be passed back under the ID of the original call.
+#[derive(Clone, Copy)]
+struct ForwardedCall {
+    direction: Direction,
+    txid: u16,
+    forwarded_txid: u16,
+}
+
+impl Forwarder {
+    fn run(mut self, stop: Handle) {
+        loop {
+            let signals = Signals::READABLE | Signals::REPLIED | Signals::PEER_CLOSED;
+            let mut items = [
+                WaitItem::new(self.near, signals),
+                WaitItem::new(self.far, signals),
+                WaitItem::new(stop, Signals::SIGNALLED),
+            ];
+            syscall::object_wait_many(&mut items, true, None).expect("Tap failed to wait for messages");
+            if items[2].is_satisfied() {
+                break;
+            }
+
+            self.forward_messages(Direction::Sent);
+            self.forward_messages(Direction::Received);
+            self.forward_replies();
+
+            /*
+             * Once either side has closed its end, and everything it sent has been passed on, we close both of
+             * ours, so the other side sees it close too.
+             */
+            if items[0..2].iter().any(|item| item.observed().contains(Signals::PEER_CLOSED)) {
+                break;
+            }
+        }
+
+        let _ = syscall::handle_close(self.near);
+        let _ = syscall::handle_close(self.far);
+    }
+
+    /// The ends a message going in `direction` is received from, and passed on through.
+    fn ends(&self, direction: Direction) -> (Handle, Handle) {
+        match direction {
+            Direction::Sent => (self.near, self.far),
+            Direction::Received => (self.far, self.near),
+        }
+    }
+
+    /// Pass on all the messages waiting to go in `direction`.
+    fn forward_messages(&mut self, direction: Direction) {
+        let (from, to) = self.ends(direction);
+        loop {
+            let (bytes, handles, txid) = match syscall::get_message(from, &mut self.bytes, &mut self.handles) {
+                Ok((bytes, handles, txid)) => (bytes.to_vec(), handles.to_vec(), txid),
+                Err(GetMessageError::NoMessage) => return,
+                Err(err) => panic!("Tap failed to receive message: {:?}", err),
+            };
+
+            /*
+             * The message is read before it's passed on, as the memory object of an out-of-line message is
+             * transferred with it, but only recorded once it has been, so it can be received by the time it's
+             * recorded. If it can't be passed on, the other side has closed its end, which `run` will notice.
+             */
+            let kind = if txid == 0 { MessageKind::Message } else { MessageKind::Call };
+            let message = Message::new(direction, kind, &bytes, &handles);
+            if txid == 0 {
+                let _ = syscall::send_message(to, &bytes, &handles);
+            } else if let Ok(forwarded_txid) = syscall::channel_call_no_wait(to, &bytes, &handles) {
+                self.calls.push(ForwardedCall { direction, txid, forwarded_txid });
+            }
+            self.transcript.record(message);
+        }
+    }
+
+    /// Pass back the replies to forwarded calls that have arrived.
+    fn forward_replies(&mut self) {
+        let mut i = 0;
+        while i < self.calls.len() {
+            let call = self.calls[i];
+            let (caller, callee) = self.ends(call.direction);
+            let (bytes, handles) =
+                match syscall::get_reply(callee, call.forwarded_txid, &mut self.bytes, &mut self.handles) {
+                    Ok((bytes, handles)) => (bytes.to_vec(), handles.to_vec()),
+                    Err(GetMessageError::NoMessage) => {
+                        i += 1;
+                        continue;
+                    }
+                    Err(err) => panic!("Tap failed to receive reply: {:?}", err),
+                };
+            self.calls.remove(i);
+
+            let direction = match call.direction {
+                Direction::Sent => Direction::Received,
+                Direction::Received => Direction::Sent,
+            };
+            let message = Message::new(direction, MessageKind::Reply, &bytes, &handles);
+            let _ = syscall::send_reply(caller, call.txid, &bytes, &handles);
+            self.transcript.record(message);
+        }
+    }
+}
+
+/// Copy the message out of the memory object an out-of-line message was sent in. The memory object starts with the
+/// length of the message, as a little-endian `u64`, followed by the message (see `poplar::channel`).
+fn out_of_line_message(memory_object: Handle) -> Vec<u8> {
+    const HEADER_SIZE: usize = core::mem::size_of::<u64>();
+
+    let size = syscall::memory_object_size(memory_object).expect("Out-of-line message isn't in a memory object");
+    let mapped = unsafe { MemoryObject::from_handle(memory_object, size, MemoryObjectFlags::empty()).map() }
+        .expect("Failed to map out-of-line message");
+    let buffer = unsafe { core::slice::from_raw_parts(mapped.ptr(), size) };
+
+    let length = u64::from_le_bytes(buffer[0..HEADER_SIZE].try_into().unwrap()) as usize;
+    let message =
+        buffer.get(HEADER_SIZE..(HEADER_SIZE + length)).expect("Out-of-line message is larger than its buffer");
+    let message = message.to_vec();
+    // The handle is passed on with the message, so we only unmap the memory object
+    let _ = unsafe { mapped.unmap() };
+    message
+}
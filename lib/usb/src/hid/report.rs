@@ -1,11 +1,13 @@
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
 use bit_field::{BitArray, BitField};
 use core::ops::Range;
 use log::warn;
 
 #[derive(Debug)]
 pub struct ReportDescriptor {
-    fields: Vec<ReportField>,
+    /// The fields of each input report the device can produce, by report ID. Devices that don't use report IDs
+    /// produce a single report, which has an ID of `None`.
+    reports: BTreeMap<Option<u8>, Vec<ReportField>>,
 }
 
 #[derive(Debug)]
@@ -56,14 +58,44 @@ pub enum FieldValue {
 
 impl ReportDescriptor {
     pub fn interpret(&self, report: &[u8]) -> Vec<FieldValue> {
+        /*
+         * If the device uses report IDs, each report starts with a byte identifying which of its reports it is.
+         */
+        let uses_report_ids = self.reports.keys().any(|id| id.is_some());
+        let (fields, report) = if uses_report_ids {
+            let Some((&id, report)) = report.split_first() else {
+                return Vec::new();
+            };
+            match self.reports.get(&Some(id)) {
+                Some(fields) => (fields, report),
+                None => {
+                    warn!("Received HID report with unknown ID: {}", id);
+                    return Vec::new();
+                }
+            }
+        } else {
+            match self.reports.get(&None) {
+                Some(fields) => (fields, report),
+                None => return Vec::new(),
+            }
+        };
+
+        /*
+         * Some devices send reports that are shorter than they describe, so we only interpret as many fields as
+         * fit in the report we've been given.
+         */
+        let report_bits = report.len() as u32 * 8;
         let mut bit_offset = 0;
         let mut result = Vec::new();
 
-        for field in &self.fields {
+        for field in fields {
             match field {
                 ReportField::Padding { num_bits } => bit_offset += num_bits,
                 ReportField::Array { size, count, usage_page, usage_min, .. } => {
                     for _ in 0..*count {
+                        if bit_offset + size > report_bits {
+                            break;
+                        }
                         let value = Self::extract_field_as_u32(report, bit_offset..(bit_offset + size));
                         bit_offset += size;
                         let usage_id = usage_min + value;
@@ -78,6 +110,9 @@ impl ReportDescriptor {
                     }
                 }
                 ReportField::Variable { size, usage_page, usage_id, data_min, .. } => {
+                    if bit_offset + size > report_bits {
+                        break;
+                    }
                     if let Some(usage) = translate_usage(*usage_page, *usage_id) {
                         if *data_min < 0 {
                             let value = Self::extract_field_as_i32(report, bit_offset..(bit_offset + size));
@@ -142,7 +177,7 @@ impl ReportDescriptor {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct GlobalState {
     pub usage_page: Option<u16>,
     pub logical_min: Option<i32>,
@@ -151,6 +186,7 @@ struct GlobalState {
     pub report_count: Option<u32>,
     pub physical_min: Option<u32>,
     pub physical_max: Option<u32>,
+    pub report_id: Option<u8>,
 }
 
 impl GlobalState {
@@ -163,12 +199,14 @@ impl GlobalState {
             report_count: None,
             physical_min: None,
             physical_max: None,
+            report_id: None,
         }
     }
 }
 
 #[derive(Debug)]
 struct LocalState {
+    /// Usages can be given with an explicit usage page, in which case it's in the upper 16 bits.
     pub usage: Vec<u32>,
     pub usage_min: Option<u32>,
     pub usage_max: Option<u32>,
@@ -184,15 +222,18 @@ pub struct ReportDescriptorParser {
     descriptor: ReportDescriptor,
     local: LocalState,
     global: GlobalState,
+    /// Global states saved by `Push` items, to be restored by `Pop` items.
+    global_stack: Vec<GlobalState>,
 }
 
 impl ReportDescriptorParser {
     pub fn parse(bytes: &[u8]) -> ReportDescriptor {
         let tokenizer = ItemTokenizer::new(bytes);
         let mut parser = ReportDescriptorParser {
-            descriptor: ReportDescriptor { fields: Vec::new() },
+            descriptor: ReportDescriptor { reports: BTreeMap::new() },
             local: LocalState::new(),
             global: GlobalState::new(),
+            global_stack: Vec::new(),
         };
 
         for item in tokenizer {
@@ -211,8 +252,9 @@ impl ReportDescriptorParser {
         match item.tag {
             0b1000 => {
                 // Input
+                let is_constant = item.data_as_u32().get_bit(0);
                 let is_array = !item.data_as_u32().get_bit(1);
-                self.generate_fields(is_array);
+                self.generate_fields(is_constant, is_array);
                 self.local = LocalState::new();
             }
             0b1001 => {
@@ -257,28 +299,31 @@ impl ReportDescriptorParser {
                 // Physical maximum
                 self.global.physical_max = Some(item.data_as_u32());
             }
-            0b0101 => {
-                todo!("Unit exponent")
-            }
-            0b0110 => {
-                todo!("Unit")
+            0b0101 | 0b0110 => {
+                // Unit exponent and unit. We don't use physical values, so these are ignored.
             }
             0b0111 => {
                 // Report size
                 self.global.report_size = Some(item.data_as_u32());
             }
             0b1000 => {
-                todo!("Report ID")
+                // Report ID
+                self.global.report_id = Some(item.data_as_u32() as u8);
             }
             0b1001 => {
                 // Report count
                 self.global.report_count = Some(item.data_as_u32());
             }
             0b1010 => {
-                todo!("Push")
+                // Push
+                self.global_stack.push(self.global.clone());
             }
             0b1011 => {
-                todo!("Pop")
+                // Pop
+                match self.global_stack.pop() {
+                    Some(global) => self.global = global,
+                    None => warn!("HID report descriptor pops more global states than it pushes"),
+                }
             }
             _ => panic!("Reserved tag on global item!"),
         }
@@ -302,34 +347,25 @@ impl ReportDescriptorParser {
                 let max = item.data_as_u32();
                 self.local.usage_max = Some(max);
             }
-            0b0011 => {
-                todo!("Designator index")
-            }
-            0b0100 => {
-                todo!("Designator minimum")
-            }
-            0b0101 => {
-                todo!("Designator maximum")
-            }
-            0b0111 => {
-                todo!("String index")
-            }
-            0b1000 => {
-                todo!("Delimiter")
+            0b0011..=0b0101 | 0b0111..=0b1010 => {
+                // Designators, strings, and delimiters describe how a control is used, which we don't need
             }
             _ => panic!("Reserved tag on local item!"),
         }
     }
 
-    fn generate_fields(&mut self, is_array: bool) {
+    fn generate_fields(&mut self, is_constant: bool, is_array: bool) {
         if self.global.report_size.is_none() || self.global.report_count.is_none() {
             panic!("Tried to generate fields without specified report size or count!");
         }
 
-        if self.local.usage.is_empty() && self.local.usage_min.is_none() && self.local.usage_max.is_none() {
-            // If no usages are specified, this field describes padding
+        let fields = self.descriptor.reports.entry(self.global.report_id).or_default();
+        let no_usages =
+            self.local.usage.is_empty() && self.local.usage_min.is_none() && self.local.usage_max.is_none();
+        if is_constant || no_usages {
+            // Constant fields never change (and often have no usages), so we treat them as padding
             let padding = self.global.report_size.unwrap() * self.global.report_count.unwrap();
-            self.descriptor.fields.push(ReportField::Padding { num_bits: padding });
+            fields.push(ReportField::Padding { num_bits: padding });
         } else if is_array {
             let logical_min = self.global.logical_min.unwrap();
             let logical_max = self.global.logical_max.unwrap();
@@ -337,29 +373,39 @@ impl ReportDescriptorParser {
             // TODO: support signed values if we end up needing to
             assert!(!i32::try_from(logical_min).unwrap().is_negative());
 
-            self.descriptor.fields.push(ReportField::Array {
+            // TODO: arrays can also select from a list of usages, rather than a range
+            fields.push(ReportField::Array {
                 size: self.global.report_size.unwrap(),
                 count: self.global.report_count.unwrap(),
                 logical_min,
                 logical_max,
                 usage_page: self.global.usage_page.unwrap(),
-                usage_min: self.local.usage_min.unwrap(),
-                usage_max: self.local.usage_max.unwrap(),
+                usage_min: self.local.usage_min.unwrap_or(0),
+                usage_max: self.local.usage_max.unwrap_or(logical_max as u32),
             });
         } else {
             for i in 0..self.global.report_count.unwrap() {
-                let usage_id = if self.local.usage.is_empty() {
+                /*
+                 * If fewer usages are given than there are fields, the last usage applies to the rest of the
+                 * fields.
+                 */
+                let usage = if self.local.usage.is_empty() {
                     self.local.usage_min.unwrap() + i
                 } else {
-                    *self.local.usage.get(i as usize).unwrap()
+                    *self.local.usage.get(i as usize).or(self.local.usage.last()).unwrap()
+                };
+                let (usage_page, usage_id) = if usage > 0xffff {
+                    (usage.get_bits(16..32) as u16, usage.get_bits(0..16))
+                } else {
+                    (self.global.usage_page.unwrap(), usage)
                 };
 
-                self.descriptor.fields.push(ReportField::Variable {
+                fields.push(ReportField::Variable {
                     size: self.global.report_size.unwrap(),
                     data_min: self.global.logical_min.unwrap(),
                     data_max: self.global.logical_max.unwrap(),
 
-                    usage_page: self.global.usage_page.unwrap(),
+                    usage_page,
                     usage_id,
                 });
             }
@@ -791,3 +837,60 @@ pub fn translate_usage(usage_page: u16, usage_id: u32) -> Option<Usage> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    const BOOT_KEYBOARD: [u8; 63] = [
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01,
+        0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
+        0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
+        0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0,
+    ];
+
+    /// A mouse that uses a report ID, and pushes and pops the global state around its axes.
+    #[rustfmt::skip]
+    const MOUSE_WITH_REPORT_ID: [u8; 58] = [
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x02, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01,
+        0x29, 0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05,
+        0x81, 0x03, 0xa4, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95,
+        0x02, 0x65, 0x11, 0x55, 0x0e, 0x81, 0x06, 0xb4, 0xc0, 0xc0,
+    ];
+
+    #[test]
+    fn boot_keyboard() {
+        let descriptor = ReportDescriptorParser::parse(&BOOT_KEYBOARD);
+        let values = descriptor.interpret(&[0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        assert_eq!(values.len(), 14);
+        assert!(matches!(values[0], FieldValue::DynamicValue(Usage::KeyLeftControl, 0)));
+        assert!(matches!(values[1], FieldValue::DynamicValue(Usage::KeyLeftShift, 1)));
+        assert!(matches!(values[8], FieldValue::Selector(Usage::KeyA)));
+        assert!(matches!(values[9], FieldValue::UntranslatedSelector { usage_page: 0x07, usage: 0x00 }));
+    }
+
+    #[test]
+    fn short_report() {
+        let descriptor = ReportDescriptorParser::parse(&BOOT_KEYBOARD);
+        let values = descriptor.interpret(&[0x02, 0x00, 0x04]);
+
+        assert_eq!(values.len(), 9);
+        assert!(matches!(values[8], FieldValue::Selector(Usage::KeyA)));
+    }
+
+    #[test]
+    fn report_ids() {
+        let descriptor = ReportDescriptorParser::parse(&MOUSE_WITH_REPORT_ID);
+        let values = descriptor.interpret(&[0x02, 0x01, 0xfb, 0x03]);
+
+        assert_eq!(values.len(), 5);
+        assert!(matches!(values[0], FieldValue::DynamicValue(Usage::Button1, 1)));
+        assert!(matches!(values[1], FieldValue::DynamicValue(Usage::Button2, 0)));
+        assert!(matches!(values[3], FieldValue::DynamicValue(Usage::X, -5)));
+        assert!(matches!(values[4], FieldValue::DynamicValue(Usage::Y, 3)));
+
+        assert!(descriptor.interpret(&[0x01, 0x01, 0xfb, 0x03]).is_empty());
+    }
+}
@@ -4,13 +4,14 @@ extern crate alloc;
 
 pub mod descriptor;
 pub mod hid;
+pub mod mass_storage;
 pub mod setup;
 
 use alloc::vec::Vec;
 use descriptor::DescriptorType;
 use ptah::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub enum EndpointDirection {
     In,
     Out,
@@ -20,14 +21,56 @@ pub enum EndpointDirection {
 pub enum DeviceControlMessage {
     UseConfiguration(u8),
     UseInterface(u8, u8),
-    OpenEndpoint { number: u8, direction: EndpointDirection, max_packet_size: u16 },
-    GetInterfaceDescriptor { typ: DescriptorType, index: u8, length: u16 },
-    InterruptTransferIn { endpoint: u8, packet_size: u16 },
+    OpenEndpoint {
+        number: u8,
+        direction: EndpointDirection,
+        max_packet_size: u16,
+    },
+    GetInterfaceDescriptor {
+        typ: DescriptorType,
+        index: u8,
+        length: u16,
+    },
+    InterruptTransferIn {
+        endpoint: u8,
+        packet_size: u16,
+    },
+    /// Read `length` bytes from a Bulk IN endpoint. Replied to with `Data`, or `TransferFailed`.
+    BulkTransferIn {
+        endpoint: u8,
+        length: u32,
+    },
+    /// Write `data` to a Bulk OUT endpoint. Replied to with `TransferComplete`, or `TransferFailed`.
+    BulkTransferOut {
+        endpoint: u8,
+        data: Vec<u8>,
+    },
+    /// Make a class-specific request of an interface, reading `length` bytes back. Replied to with `Data`, or
+    /// `TransferFailed`.
+    ClassRequestIn {
+        interface: u8,
+        request: u8,
+        value: u16,
+        length: u16,
+    },
+    /// Clear the halt condition of an endpoint that has stalled, and reset its data toggle.
+    ClearHalt {
+        endpoint: u8,
+        direction: EndpointDirection,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum DeviceResponse {
     Data(Vec<u8>),
     NoData,
-    Descriptor { typ: DescriptorType, index: u8, bytes: Vec<u8> },
+    Descriptor {
+        typ: DescriptorType,
+        index: u8,
+        bytes: Vec<u8>,
+    },
+    TransferComplete,
+    /// The transfer failed. This is often because the endpoint stalled, in which case it needs to be cleared
+    /// with `ClearHalt` before it can be used again.
+    TransferFailed,
 }
@@ -0,0 +1,266 @@
+//! Support for USB Mass Storage devices that use the Bulk-Only Transport. Each command is sent to the device as a
+//! SCSI command block, wrapped in a Command Block Wrapper, over the device's Bulk OUT endpoint. Any data is then
+//! transferred over the Bulk IN or OUT endpoint, and finally the device reports the outcome of the command with
+//! a Command Status Wrapper over its Bulk IN endpoint.
+
+use alloc::string::{String, ToString};
+
+/// The interface class used by Mass Storage devices.
+pub const CLASS: u8 = 0x08;
+/// The interface subclass of devices that use the SCSI transparent command set.
+pub const SUBCLASS_SCSI: u8 = 0x06;
+/// The interface protocol of devices that use the Bulk-Only Transport.
+pub const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+/// Class-specific request to get the number of the highest Logical Unit supported by the device. Devices that
+/// only have one Logical Unit may stall this request.
+pub const GET_MAX_LUN: u8 = 0xfe;
+
+pub const COMMAND_BLOCK_WRAPPER_LENGTH: usize = 31;
+pub const COMMAND_STATUS_WRAPPER_LENGTH: usize = 13;
+
+const COMMAND_BLOCK_SIGNATURE: u32 = 0x43425355;
+const COMMAND_STATUS_SIGNATURE: u32 = 0x53425355;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DataDirection {
+    /// The command transfers data from the host to the device, or transfers no data.
+    Out,
+    /// The command transfers data from the device to the host.
+    In,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CommandBlockWrapper<'a> {
+    /// Chosen by the host, and echoed back by the device in the command's status, so the two can be matched up.
+    pub tag: u32,
+    /// The number of bytes the host expects to transfer for this command.
+    pub data_length: u32,
+    pub direction: DataDirection,
+    pub lun: u8,
+    /// The SCSI command block. Can be up to 16 bytes long.
+    pub command: &'a [u8],
+}
+
+impl<'a> CommandBlockWrapper<'a> {
+    pub fn to_bytes(&self) -> [u8; COMMAND_BLOCK_WRAPPER_LENGTH] {
+        assert!(!self.command.is_empty() && self.command.len() <= 16);
+
+        let mut bytes = [0; COMMAND_BLOCK_WRAPPER_LENGTH];
+        bytes[0..4].copy_from_slice(&COMMAND_BLOCK_SIGNATURE.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.data_length.to_le_bytes());
+        bytes[12] = match self.direction {
+            DataDirection::Out => 0x00,
+            DataDirection::In => 0x80,
+        };
+        bytes[13] = self.lun;
+        bytes[14] = self.command.len() as u8;
+        bytes[15..(15 + self.command.len())].copy_from_slice(self.command);
+        bytes
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CommandStatus {
+    Passed,
+    /// The command failed. The reason can be found by issuing a `REQUEST SENSE` command.
+    Failed,
+    /// The device didn't understand the transfer, and must be reset before it can be used again.
+    PhaseError,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CommandStatusWrapper {
+    pub tag: u32,
+    /// The difference between the amount of data the host expected to transfer, and the amount the device
+    /// actually transferred.
+    pub data_residue: u32,
+    pub status: CommandStatus,
+}
+
+impl CommandStatusWrapper {
+    pub fn parse(bytes: &[u8]) -> Option<CommandStatusWrapper> {
+        if bytes.len() < COMMAND_STATUS_WRAPPER_LENGTH || read_u32_le(bytes, 0) != COMMAND_STATUS_SIGNATURE {
+            return None;
+        }
+
+        let status = match bytes[12] {
+            0x00 => CommandStatus::Passed,
+            0x01 => CommandStatus::Failed,
+            0x02 => CommandStatus::PhaseError,
+            _ => return None,
+        };
+        Some(CommandStatusWrapper { tag: read_u32_le(bytes, 4), data_residue: read_u32_le(bytes, 8), status })
+    }
+}
+
+/// Builders for the SCSI commands needed to use a Mass Storage device as a block device, and parsers for the data
+/// they return. Multi-byte fields in SCSI commands are big-endian.
+pub mod scsi {
+    use super::*;
+
+    pub const INQUIRY_LENGTH: u8 = 36;
+    pub const READ_CAPACITY_10_LENGTH: u32 = 8;
+    pub const REQUEST_SENSE_LENGTH: u8 = 18;
+
+    pub fn test_unit_ready() -> [u8; 6] {
+        [0x00, 0, 0, 0, 0, 0]
+    }
+
+    pub fn request_sense() -> [u8; 6] {
+        [0x03, 0, 0, 0, REQUEST_SENSE_LENGTH, 0]
+    }
+
+    pub fn inquiry() -> [u8; 6] {
+        [0x12, 0, 0, 0, INQUIRY_LENGTH, 0]
+    }
+
+    pub fn read_capacity_10() -> [u8; 10] {
+        [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    }
+
+    pub fn read_10(block: u32, count: u16) -> [u8; 10] {
+        transfer_10(0x28, block, count)
+    }
+
+    pub fn write_10(block: u32, count: u16) -> [u8; 10] {
+        transfer_10(0x2a, block, count)
+    }
+
+    pub fn synchronize_cache_10() -> [u8; 10] {
+        [0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    }
+
+    fn transfer_10(opcode: u8, block: u32, count: u16) -> [u8; 10] {
+        let block = block.to_be_bytes();
+        let count = count.to_be_bytes();
+        [opcode, 0, block[0], block[1], block[2], block[3], 0, count[0], count[1], 0]
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct InquiryData {
+        /// The type of the device. Direct-access block devices, such as disks and flash drives, are type `0`.
+        pub device_type: u8,
+        pub removable: bool,
+        pub vendor: String,
+        pub product: String,
+    }
+
+    impl InquiryData {
+        pub const DIRECT_ACCESS: u8 = 0x00;
+
+        pub fn parse(bytes: &[u8]) -> Option<InquiryData> {
+            if bytes.len() < INQUIRY_LENGTH as usize {
+                return None;
+            }
+
+            let string = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().to_string();
+            Some(InquiryData {
+                device_type: bytes[0] & 0x1f,
+                removable: bytes[1] & 0x80 != 0,
+                vendor: string(&bytes[8..16]),
+                product: string(&bytes[16..32]),
+            })
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Capacity {
+        pub num_blocks: u64,
+        pub block_size: u32,
+    }
+
+    impl Capacity {
+        /// Parse the data returned by `READ CAPACITY (10)`, which gives the address of the last block, rather
+        /// than the number of blocks.
+        pub fn parse(bytes: &[u8]) -> Option<Capacity> {
+            if bytes.len() < READ_CAPACITY_10_LENGTH as usize {
+                return None;
+            }
+
+            let last_block = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+            let block_size = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+            Some(Capacity { num_blocks: last_block as u64 + 1, block_size })
+        }
+    }
+
+    /// The Sense Key from the data returned by `REQUEST SENSE`, which gives the category of the error that
+    /// caused the last command to fail.
+    pub fn sense_key(bytes: &[u8]) -> Option<u8> {
+        bytes.get(2).map(|byte| byte & 0x0f)
+    }
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..(offset + 4)].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scsi::*, *};
+
+    #[test]
+    fn command_block_wrapper() {
+        let command = read_10(0x01020304, 2);
+        let bytes = CommandBlockWrapper {
+            tag: 7,
+            data_length: 1024,
+            direction: DataDirection::In,
+            lun: 1,
+            command: &command,
+        }
+        .to_bytes();
+
+        assert_eq!(&bytes[0..4], b"USBC");
+        assert_eq!(&bytes[4..8], &[7, 0, 0, 0]);
+        assert_eq!(&bytes[8..12], &[0x00, 0x04, 0, 0]);
+        assert_eq!(bytes[12], 0x80);
+        assert_eq!(bytes[13], 1);
+        assert_eq!(bytes[14], 10);
+        assert_eq!(&bytes[15..25], &[0x28, 0, 0x01, 0x02, 0x03, 0x04, 0, 0x00, 0x02, 0]);
+        assert!(bytes[25..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn command_status_wrapper() {
+        let mut bytes = [0u8; 13];
+        bytes[0..4].copy_from_slice(b"USBS");
+        bytes[4] = 7;
+        bytes[8] = 0x10;
+        bytes[12] = 0x01;
+        assert_eq!(
+            CommandStatusWrapper::parse(&bytes),
+            Some(CommandStatusWrapper { tag: 7, data_residue: 0x10, status: CommandStatus::Failed })
+        );
+
+        bytes[0] = b'X';
+        assert_eq!(CommandStatusWrapper::parse(&bytes), None);
+        assert_eq!(CommandStatusWrapper::parse(&bytes[0..12]), None);
+    }
+
+    #[test]
+    fn capacity() {
+        assert_eq!(
+            Capacity::parse(&[0x00, 0x01, 0xff, 0xff, 0x00, 0x00, 0x02, 0x00]),
+            Some(Capacity { num_blocks: 0x20000, block_size: 512 })
+        );
+    }
+
+    #[test]
+    fn inquiry_data() {
+        let mut bytes = [0u8; 36];
+        bytes[1] = 0x80;
+        bytes[8..16].copy_from_slice(b"QEMU    ");
+        bytes[16..32].copy_from_slice(b"QEMU HARDDISK   ");
+        assert_eq!(
+            InquiryData::parse(&bytes),
+            Some(InquiryData {
+                device_type: InquiryData::DIRECT_ACCESS,
+                removable: true,
+                vendor: "QEMU".to_string(),
+                product: "QEMU HARDDISK".to_string(),
+            })
+        );
+    }
+}
@@ -2,7 +2,9 @@
 #[repr(C, align(8))]
 pub struct SetupPacket {
     pub typ: RequestType,
-    pub request: Request,
+    /// The request being made. Standard requests are listed in `Request` - the meaning of other requests
+    /// depends on the class of the device, or its vendor.
+    pub request: u8,
     pub value: u16,
     pub index: u16,
     pub length: u16,
@@ -1,6 +1,31 @@
 use crate::mmio::VirtioMmioHeader;
 use volatile::{Read, Volatile};
 
+bitflags::bitflags! {
+    /// Feature bits that can be negotiated with a block device. `VERSION_1` is a device-independent feature
+    /// bit, but is included here as it must be negotiated by drivers of non-legacy devices.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct BlockFeatures: u64 {
+        /// The maximum size of any single segment is in `size_max`.
+        const SIZE_MAX = 1 << 1;
+        /// The maximum number of segments in a request is in `seg_max`.
+        const SEG_MAX = 1 << 2;
+        /// The disk-style geometry of the device is in `geometry`.
+        const GEOMETRY = 1 << 4;
+        /// The device is read-only.
+        const RO = 1 << 5;
+        /// The block size of the device is in `block_size`.
+        const BLK_SIZE = 1 << 6;
+        /// The device supports flush requests.
+        const FLUSH = 1 << 9;
+        const VERSION_1 = 1 << 32;
+    }
+}
+
+/// The size of a sector, in bytes. Requests always address the device in sectors of this size, regardless of
+/// the device's block size.
+pub const SECTOR_SIZE: usize = 512;
+
 #[repr(C)]
 pub struct BlockDeviceConfig {
     // TODO: how to abstract over both MMIO and PCI Virtio devices?
@@ -33,6 +58,25 @@ impl BlockDeviceConfig {
     }
 }
 
+/// The device-specific configuration of a block device accessed over PCI. This is the same as the configuration
+/// of an MMIO device, without the transport header in front of it.
+#[repr(C)]
+pub struct PciBlockDeviceConfig {
+    pub capacity: Volatile<[u32; 2], Read>,
+    pub size_max: Volatile<u32, Read>,
+    pub seg_max: Volatile<u32, Read>,
+    pub geometry: Volatile<Geometry, Read>,
+    pub block_size: Volatile<u32, Read>,
+}
+
+impl PciBlockDeviceConfig {
+    /// The capacity of the device, in sectors.
+    pub fn capacity(&self) -> u64 {
+        let [lo, hi] = self.capacity.read();
+        (u64::from(hi) << 32) + u64::from(lo)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct Geometry {
@@ -68,6 +112,14 @@ impl Request {
     pub fn read(sector: u64) -> Request {
         Request { typ: RequestType::Read, _reserved0: 0, sector, status: RequestStatus::Ok }
     }
+
+    pub fn write(sector: u64) -> Request {
+        Request { typ: RequestType::Write, _reserved0: 0, sector, status: RequestStatus::Ok }
+    }
+
+    pub fn flush() -> Request {
+        Request { typ: RequestType::Flush, _reserved0: 0, sector: 0, status: RequestStatus::Ok }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
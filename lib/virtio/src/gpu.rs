@@ -205,11 +205,13 @@ pub struct TransferToHost2D {
 }
 
 impl TransferToHost2D {
-    pub fn new(width: u32, height: u32, offset: u64, resource_id: u32) -> TransferToHost2D {
+    /// Transfer the rectangle at `(x, y)` of size `width * height`. `offset` is the offset into the resource's
+    /// backing of the first pixel of the rectangle.
+    pub fn new(x: u32, y: u32, width: u32, height: u32, offset: u64, resource_id: u32) -> TransferToHost2D {
         TransferToHost2D {
             header: CtrlHeader::new(CtrlType::CmdTransferToHost2D),
-            x: 0,
-            y: 0,
+            x,
+            y,
             width,
             height,
             offset,
@@ -231,11 +233,11 @@ pub struct FlushResource {
 }
 
 impl FlushResource {
-    pub fn new(resource_id: u32, width: u32, height: u32) -> FlushResource {
+    pub fn new(resource_id: u32, x: u32, y: u32, width: u32, height: u32) -> FlushResource {
         FlushResource {
             header: CtrlHeader::new(CtrlType::CmdResourceFlush),
-            x: 0,
-            y: 0,
+            x,
+            y,
             width,
             height,
             resource_id,
@@ -243,3 +245,18 @@ impl FlushResource {
         }
     }
 }
+
+/// Destroy a resource. Resources that are no longer used (e.g. the old framebuffer after a mode change) should be
+/// unreferenced so the host can free them.
+#[repr(C)]
+pub struct ResourceUnref {
+    pub header: CtrlHeader,
+    pub resource_id: u32,
+    _padding: u32,
+}
+
+impl ResourceUnref {
+    pub fn new(resource_id: u32) -> ResourceUnref {
+        ResourceUnref { header: CtrlHeader::new(CtrlType::CmdResourceUnref), resource_id, _padding: 0 }
+    }
+}
@@ -0,0 +1,105 @@
+use volatile::{Read, ReadWrite, Volatile};
+
+/// The queue that the device places input events in. Buffers in this queue should be writable and fit an
+/// `InputEvent`.
+pub const EVENT_QUEUE: u16 = 0;
+/// The queue used to send status updates (such as the state of keyboard LEDs) to the device.
+pub const STATUS_QUEUE: u16 = 1;
+
+/// Selects what information the device's configuration space should contain. Some selections also need a
+/// sub-selection (e.g. `AbsInfo` needs the axis to get information about).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ConfigSelect {
+    Unset = 0x00,
+    IdName = 0x01,
+    IdSerial = 0x02,
+    IdDevids = 0x03,
+    PropBits = 0x10,
+    /// Sub-selected by event type. Produces a bitmap of the codes the device supports for that type.
+    EvBits = 0x11,
+    /// Sub-selected by axis. Produces the `AbsInfo` for that axis.
+    AbsInfo = 0x12,
+}
+
+#[repr(C)]
+pub struct InputDeviceConfig {
+    pub select: Volatile<u8, ReadWrite>,
+    pub subsel: Volatile<u8, ReadWrite>,
+    /// The size of the valid data in `data`. Zero if the selection is not supported by the device.
+    pub size: Volatile<u8, Read>,
+    _reserved: [u8; 5],
+    pub data: Volatile<[u8; 128], Read>,
+}
+
+impl InputDeviceConfig {
+    /// Make a selection, and return the data the device produces for it (or `None` if the selection isn't
+    /// supported).
+    pub fn query(&self, select: ConfigSelect, subsel: u8) -> Option<([u8; 128], usize)> {
+        self.select.write(select as u8);
+        self.subsel.write(subsel);
+        let size = self.size.read() as usize;
+        if size == 0 {
+            return None;
+        }
+        Some((self.data.read(), size))
+    }
+
+    pub fn abs_info(&self, axis: u16) -> Option<AbsInfo> {
+        let (data, size) = self.query(ConfigSelect::AbsInfo, axis as u8)?;
+        if size < 8 {
+            return None;
+        }
+        let field = |offset: usize| u32::from_le_bytes(data[offset..(offset + 4)].try_into().unwrap());
+        Some(AbsInfo { min: field(0), max: field(4) })
+    }
+
+    /// Whether the device produces any events of the given type (e.g. `EV_ABS` for a tablet).
+    pub fn supports_event_type(&self, typ: u16) -> bool {
+        self.query(ConfigSelect::EvBits, typ as u8).is_some()
+    }
+}
+
+/// The range of values an absolute axis can report. The device also reports the fuzz, flat, and resolution of
+/// the axis, but we don't need them.
+#[derive(Clone, Copy, Debug)]
+pub struct AbsInfo {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// An event produced by an input device. These use the same types and codes as Linux's `evdev` interface.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct InputEvent {
+    pub typ: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+/*
+ * Event types
+ */
+/// Marks the end of a group of events that happened at the same time (e.g. movement along both axes).
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+/*
+ * Codes for `EV_REL` and `EV_ABS` events
+ */
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+
+/*
+ * Codes for `EV_KEY` events produced by buttons
+ */
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+pub const BTN_SIDE: u16 = 0x113;
+pub const BTN_EXTRA: u16 = 0x114;
@@ -5,7 +5,9 @@ extern crate alloc;
 
 pub mod block;
 pub mod gpu;
+pub mod input;
 pub mod mmio;
+pub mod net;
 pub mod pci;
 pub mod virtqueue;
 
@@ -0,0 +1,52 @@
+use volatile::{Read, Volatile};
+
+bitflags::bitflags! {
+    /// Feature bits that can be negotiated with a network device. `VERSION_1` is a device-independent feature
+    /// bit, but is included here as it must be negotiated by drivers of non-legacy devices.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct NetFeatures: u64 {
+        /// The device can handle packets with partial checksums.
+        const CSUM = 1 << 0;
+        /// The driver can handle packets with partial checksums.
+        const GUEST_CSUM = 1 << 1;
+        /// The device reports its maximum MTU in the `mtu` field of its config.
+        const MTU = 1 << 3;
+        /// The device has a MAC address, found in the `mac` field of its config.
+        const MAC = 1 << 5;
+        /// The driver can merge receive buffers.
+        const MRG_RXBUF = 1 << 15;
+        /// The `status` field of the device's config is valid.
+        const STATUS = 1 << 16;
+        const VERSION_1 = 1 << 32;
+    }
+}
+
+#[repr(C)]
+pub struct NetDeviceConfig {
+    pub mac: Volatile<[u8; 6], Read>,
+    pub status: Volatile<u16, Read>,
+    pub max_virtqueue_pairs: Volatile<u16, Read>,
+    pub mtu: Volatile<u16, Read>,
+}
+
+impl NetDeviceConfig {
+    /// Whether the link is up. Only valid if the `STATUS` feature has been negotiated.
+    pub fn link_up(&self) -> bool {
+        self.status.read() & 0b1 != 0
+    }
+}
+
+/// Every packet sent or received by a network device is preceded by this header. When `VERSION_1` has been
+/// negotiated, the header is always this size (the `num_buffers` field is only meaningful if `MRG_RXBUF` has
+/// been negotiated too).
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct NetHeader {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub header_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+    pub num_buffers: u16,
+}
@@ -1,4 +1,4 @@
-use crate::StatusFlags;
+use crate::{virtqueue::Virtqueue, StatusFlags};
 use bit_field::BitField;
 use volatile::{Read, ReadWrite, Volatile};
 
@@ -68,6 +68,24 @@ impl VirtioPciCommonCfg {
         self.device_status.read() & flag as u8 == flag as u8
     }
 
+    /// Read the full set of feature bits offered by the device.
+    pub fn device_features(&mut self) -> u64 {
+        self.device_feature_select.write(0);
+        let low = self.device_feature.read();
+        self.device_feature_select.write(1);
+        let high = self.device_feature.read();
+        (u64::from(high) << 32) | u64::from(low)
+    }
+
+    /// Tell the device which of its features the driver is going to use. This must be followed by setting the
+    /// `FeaturesOk` status flag, and then checking the device accepted the features by checking it's still set.
+    pub fn set_driver_features(&mut self, features: u64) {
+        self.driver_feature_select.write(0);
+        self.driver_feature.write(features.get_bits(0..32) as u32);
+        self.driver_feature_select.write(1);
+        self.driver_feature.write(features.get_bits(32..64) as u32);
+    }
+
     pub fn select_queue(&mut self, queue: u16) {
         self.queue_select.write(queue);
     }
@@ -95,8 +113,109 @@ impl VirtioPciCommonCfg {
         self.queue_device[1].write(physical.get_bits(32..64) as u32);
     }
 
+    /// Get the offset of the selected queue's notification address, in units of the notify offset multiplier
+    /// (found in the notification capability).
+    pub fn queue_notify_offset(&self) -> u16 {
+        self.queue_notify_off.read()
+    }
+
     pub fn mark_queue_ready(&mut self) {
         // TODO: MMIO has a field called `queue_ready` - is that the same as being enabled?
         self.queue_enable.write(1);
     }
 }
+
+/*
+ * The offsets of the configuration structures in the BAR that holds them. These should be found from the device's
+ * vendor-specific capabilities (see `VirtioVendorCap`), but QEMU lays out the BAR of every modern Virtio device in
+ * the same way, so we rely on that for now.
+ */
+const COMMON_CFG_OFFSET: usize = 0;
+const DEVICE_CFG_OFFSET: usize = 0x2000;
+const NOTIFY_CFG_OFFSET: usize = 0x3000;
+const NOTIFY_OFFSET_MULTIPLIER: usize = 4;
+
+/// The PCI transport of a modern Virtio device. This handles the parts of initializing a device, and notifying it
+/// of new buffers, that are the same for every type of device, through the configuration structures in the
+/// device's BAR.
+pub struct PciTransport {
+    /// The virtual address the BAR holding the configuration structures is mapped at.
+    bar: usize,
+}
+
+impl PciTransport {
+    /// Access a device through the BAR mapped at `bar`. The BAR must stay mapped for as long as the transport is
+    /// used, and nothing else can access the common configuration structure through it.
+    pub unsafe fn new(bar: usize) -> PciTransport {
+        PciTransport { bar }
+    }
+
+    /// Get the common configuration structure. We create a reference to it each time it's needed, rather than
+    /// holding one, so it can't be aliased.
+    pub fn common_cfg(&mut self) -> &mut VirtioPciCommonCfg {
+        unsafe { &mut *((self.bar + COMMON_CFG_OFFSET) as *mut VirtioPciCommonCfg) }
+    }
+
+    /// Get the device-specific configuration structure. `T` must describe the layout of the configuration of this
+    /// type of device.
+    pub unsafe fn device_cfg<T>(&self) -> &T {
+        unsafe { &*((self.bar + DEVICE_CFG_OFFSET) as *const T) }
+    }
+
+    /// Reset the device, and start initializing it. Returns the features offered by the device, of which the ones
+    /// the driver is going to use should be passed to `set_features`.
+    pub fn begin_init(&mut self) -> u64 {
+        let common_cfg = self.common_cfg();
+        common_cfg.reset();
+        common_cfg.set_status_flag(StatusFlags::Acknowledge);
+        common_cfg.set_status_flag(StatusFlags::Driver);
+        common_cfg.device_features()
+    }
+
+    /// Tell the device which of its features the driver is going to use. Fails if the device doesn't accept them.
+    pub fn set_features(&mut self, features: u64) -> Result<(), ()> {
+        let common_cfg = self.common_cfg();
+        common_cfg.set_driver_features(features);
+        common_cfg.set_status_flag(StatusFlags::FeaturesOk);
+        if common_cfg.is_status_flag_set(StatusFlags::FeaturesOk) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Give the device the virtqueue to use as its queue with the given index, and enable it. The device signals
+    /// the MSI-X vector `msix_vector` when it has used buffers from the queue.
+    pub fn setup_queue(&mut self, index: u16, queue: &Virtqueue, msix_vector: u16) {
+        let common_cfg = self.common_cfg();
+        common_cfg.select_queue(index);
+        common_cfg.set_queue_size(queue.size());
+        common_cfg.set_queue_msix_vector(msix_vector);
+        common_cfg.set_queue_descriptor(queue.descriptor_table.physical as u64);
+        common_cfg.set_queue_driver(queue.available_ring.physical as u64);
+        common_cfg.set_queue_device(queue.used_ring.physical as u64);
+        common_cfg.mark_queue_ready();
+    }
+
+    /// Finish initializing the device, once its features have been negotiated and its queues set up. Fails if the
+    /// device reports that initialization has failed.
+    pub fn finish_init(&mut self) -> Result<(), ()> {
+        let common_cfg = self.common_cfg();
+        common_cfg.set_status_flag(StatusFlags::DriverOk);
+        if common_cfg.is_status_flag_set(StatusFlags::Failed) {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Tell the device that new buffers have been made available in the queue with the given index.
+    pub fn notify(&mut self, queue: u16) {
+        let common_cfg = self.common_cfg();
+        common_cfg.select_queue(queue);
+        let offset = common_cfg.queue_notify_offset() as usize * NOTIFY_OFFSET_MULTIPLIER;
+        unsafe {
+            core::ptr::write_volatile((self.bar + NOTIFY_CFG_OFFSET + offset) as *mut u16, queue);
+        }
+    }
+}
@@ -20,6 +20,9 @@ use core::{
 pub struct Virtqueue {
     size: u16,
     free_entries: VecDeque<u16>,
+    /// The index of the next entry of the used ring we expect the device to fill. Like the available ring's
+    /// index, this runs continuously, and is taken modulo the queue size to find the entry.
+    next_used: u16,
     pub descriptor_table: Mapped<[Descriptor]>,
     pub available_ring: Mapped<AvailableRing>,
     pub used_ring: Mapped<UsedRing>,
@@ -35,7 +38,12 @@ impl Virtqueue {
         let available_ring = unsafe { Mapped::new(queue_size as usize, mapper) };
         let used_ring = unsafe { Mapped::new(queue_size as usize, mapper) };
 
-        Virtqueue { size: queue_size, free_entries, descriptor_table, available_ring, used_ring }
+        Virtqueue { size: queue_size, free_entries, descriptor_table, available_ring, used_ring, next_used: 0 }
+    }
+
+    /// The number of descriptors in the queue.
+    pub fn size(&self) -> u16 {
+        self.size
     }
 
     /// Push a descriptor into the descriptor table, returning its index. Returns `None` if there is no space left
@@ -83,6 +91,33 @@ impl Virtqueue {
         }
     }
 
+    /// Get the next descriptor chain that the device has finished using, if there is one. The descriptors in the
+    /// chain are not freed - this is left to the caller, who will generally want to do something with the
+    /// buffers they describe first.
+    pub fn pop_used(&mut self) -> Option<UsedRingElement> {
+        let device_index = unsafe {
+            let base = self.used_ring.mapped.as_ptr() as *const u16;
+            ptr::read_volatile(base.byte_add(mem::offset_of!(UsedRing, index)))
+        };
+        if device_index == self.next_used {
+            return None;
+        }
+
+        // Make sure we don't read the entry before the device's update to the index
+        // TODO: make portable
+        unsafe {
+            core::arch::asm!("fence r, r");
+        }
+
+        let element = unsafe {
+            // XXX: we can't use `offset_of` on `ring` bc its dyn-sized.
+            let ring = self.used_ring.mapped.as_ptr().byte_add(4) as *const UsedRingElement;
+            ptr::read_volatile(ring.add((self.next_used % self.size) as usize))
+        };
+        self.next_used = self.next_used.wrapping_add(1);
+        Some(element)
+    }
+
     pub fn alloc_descriptor(&mut self) -> Option<u16> {
         self.free_entries.pop_back()
     }
@@ -130,6 +165,7 @@ pub struct UsedRing {
     pub ring: [UsedRingElement],
 }
 
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct UsedRingElement {
     /// The index of the first element of the used descriptor chain.
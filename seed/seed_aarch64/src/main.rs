@@ -0,0 +1,359 @@
+/*
+ * Copyright 2022, Isaac Woods
+ * SPDX-License-Identifier: MPL-2.0
+ */
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+/*
+ * The memory map, PCI resolver, and ramdisk don't depend on the architecture, so we share them with Seed's RISC-V
+ * port.
+ */
+#[path = "../../seed_riscv/src/fs/mod.rs"]
+mod fs;
+mod image;
+mod logger;
+#[path = "../../seed_riscv/src/memory.rs"]
+mod memory;
+#[path = "../../seed_riscv/src/pci.rs"]
+mod pci;
+
+use crate::{
+    fs::{ramdisk::Ramdisk, Filesystem},
+    memory::Region,
+};
+use core::{arch::asm, mem, ptr};
+use fdt::Fdt;
+use hal::memory::{Flags, FrameAllocator, FrameSize, PAddr, PageTable, Size4KiB, VAddr};
+use hal_aarch64::{
+    hw::sysreg::{ElrEl1, EsrEl1, FarEl1, Vbar},
+    paging,
+    platform::{memory::DRAM_START, PageTableImpl},
+};
+use linked_list_allocator::LockedHeap;
+use memory::{MemoryManager, MemoryRegions};
+use mulch::{linker::LinkerSymbol, math::align_up};
+use pci::PciResolver;
+use seed::{boot_info::BootInfo, SeedConfig};
+use tracing::info;
+
+/*
+ * This is the entry-point jumped to by QEMU. It needs to be at the very start of the ELF, so we put it in its own
+ * section and then place it manually during linking. We're started at EL1 with the MMU off. QEMU doesn't pass us
+ * anything in registers when booting an image that isn't a Linux kernel, but places the device tree at the start
+ * of RAM.
+ */
+core::arch::global_asm!(
+    "
+    .section .text.start
+    .global _start
+    _start:
+        // Zero the BSS
+        adrp x9, _bss_start
+        add x9, x9, :lo12:_bss_start
+        adrp x10, _bss_end
+        add x10, x10, :lo12:_bss_end
+        cmp x9, x10
+        b.hs .bss_zero_loop_end
+    .bss_zero_loop:
+        str xzr, [x9], #8
+        cmp x9, x10
+        b.lo .bss_zero_loop
+    .bss_zero_loop_end:
+
+        adrp x9, _stack_top
+        add x9, x9, :lo12:_stack_top
+        mov sp, x9
+
+        bl seed_main
+    .halt:
+        wfe
+        b .halt
+    "
+);
+
+/*
+ * Seed shouldn't take any exceptions, so every entry of its vector table just reports the exception and stops.
+ */
+core::arch::global_asm!(
+    "
+    .section .text
+    .balign 0x800
+    .global seed_vector_table
+    seed_vector_table:
+    .rept 16
+        .balign 0x80
+        b seed_exception_handler
+    .endr
+    "
+);
+
+extern "C" {
+    static _seed_start: LinkerSymbol;
+    static _bss_start: LinkerSymbol;
+    static _stack_bottom: LinkerSymbol;
+    static _stack_top: LinkerSymbol;
+    static _bss_end: LinkerSymbol;
+    static _seed_end: LinkerSymbol;
+    static seed_vector_table: LinkerSymbol;
+}
+
+static MEMORY_MANAGER: MemoryManager = MemoryManager::new();
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+#[no_mangle]
+pub fn seed_main() -> ! {
+    // QEMU places the device tree at the start of RAM
+    let fdt_address = DRAM_START;
+    let fdt_ptr = usize::from(fdt_address) as *const u8;
+    let fdt = unsafe { Fdt::from_ptr(fdt_ptr).expect("Failed to parse FDT") };
+
+    logger::init(&fdt);
+    info!("Hello, World!");
+    info!("Running at EL{}", hal_aarch64::hw::sysreg::current_el());
+    info!("FDT address: {:?}", fdt_ptr);
+
+    Vbar::set(VAddr::new(unsafe { seed_vector_table.ptr() as usize }));
+
+    /*
+     * Construct an initial map of memory - a series of usable and reserved regions, and what is in
+     * each of them.
+     */
+    let mut memory_regions = MemoryRegions::new(&fdt, fdt_address);
+
+    /*
+     * Find the loaded ramdisk (if there is one) and mark it as a reserved region before we
+     * initialize the physical memory manager (it is not otherwise described as a not-usable region).
+     */
+    let mut ramdisk = unsafe { Ramdisk::new(usize::from(hal_aarch64::platform::memory::RAMDISK_ADDR)) };
+    if let Some(ref ramdisk) = ramdisk {
+        let (address, size) = ramdisk.memory_region();
+        memory_regions.add_region(Region::reserved(
+            memory::Usage::Ramdisk,
+            address,
+            align_up(size, Size4KiB::SIZE),
+        ));
+    }
+
+    /*
+     * We can then use this mapping of memory regions to initialise the physical memory manager so we can allocate
+     * out of the usable regions.
+     */
+    info!("Memory regions: {:#?}", memory_regions);
+    MEMORY_MANAGER.init(memory_regions);
+    MEMORY_MANAGER.walk_usable_memory();
+
+    /*
+     * Allocate memory for and initialize Seed's heap.
+     */
+    const HEAP_SIZE: usize = hal::memory::kibibytes(200);
+    let heap_memory = MEMORY_MANAGER.allocate_n(Size4KiB::frames_needed(HEAP_SIZE));
+    unsafe {
+        ALLOCATOR.lock().init(usize::from(heap_memory.start.start) as *mut u8, HEAP_SIZE);
+    }
+
+    let config = if let Some(ref mut ramdisk) = ramdisk {
+        let config = ramdisk.load("config").expect("No config file found!");
+        picotoml::from_str::<SeedConfig>(core::str::from_utf8(config.data).unwrap()).unwrap()
+    } else {
+        panic!("No config file found!");
+    };
+    info!("Config: {:?}", config);
+
+    let mut kernel_page_table = PageTableImpl::new(MEMORY_MANAGER.allocate(), VAddr::new(0x0));
+    let kernel_file = if let Some(ref mut ramdisk) = ramdisk {
+        ramdisk.load("kernel_aarch64").unwrap()
+    } else {
+        panic!("No kernel source is present!");
+    };
+    let kernel = image::load_kernel(&kernel_file, &mut kernel_page_table, &MEMORY_MANAGER);
+    let mut next_available_kernel_address = kernel.next_available_address;
+
+    /*
+     * Enumerate and initialize PCI devices, if present. Even if Seed doesn't end up using them,
+     * we're responsible for allocating BAR memory etc.
+     */
+    PciResolver::initialize(&fdt);
+
+    /*
+     * Allocate memory for the boot info and start filling it out.
+     */
+    let (boot_info_kernel_address, boot_info) =
+        create_boot_info(&mut next_available_kernel_address, &mut kernel_page_table);
+    boot_info.magic = seed::boot_info::BOOT_INFO_MAGIC;
+    boot_info.fdt_address = Some(fdt_address);
+    boot_info.disable_aslr = config.disable_aslr;
+
+    /*
+     * Load desired early tasks.
+     */
+    for name in &config.user_tasks {
+        let file = if let Some(ref mut ramdisk) = ramdisk {
+            ramdisk.load(&name).unwrap()
+        } else {
+            panic!("No user tasks source is present!");
+        };
+        let info = image::load_image(&file, name, &MEMORY_MANAGER);
+        boot_info.loaded_images.push(info).unwrap();
+    }
+
+    /*
+     * Construct the direct physical memory map. On QEMU's `virt` machine, everything below the start of RAM is
+     * devices, which must be mapped as device memory so accesses to them aren't cached, merged, or reordered.
+     * TODO: we should probably do this properly by walking the FDT (you need RAM + devices) but we currently just
+     * map 16GiB.
+     */
+    use hal_aarch64::platform::kernel_map::PHYSICAL_MAP_BASE;
+    const PHYSICAL_MAP_SIZE: usize = hal::memory::gibibytes(16);
+    kernel_page_table
+        .map_area(
+            PHYSICAL_MAP_BASE,
+            PAddr::new(0x0).unwrap(),
+            usize::from(DRAM_START),
+            Flags { writable: true, cached: false, ..Default::default() },
+            &MEMORY_MANAGER,
+        )
+        .unwrap();
+    kernel_page_table
+        .map_area(
+            PHYSICAL_MAP_BASE + usize::from(DRAM_START),
+            DRAM_START,
+            PHYSICAL_MAP_SIZE - usize::from(DRAM_START),
+            Flags { writable: true, ..Default::default() },
+            &MEMORY_MANAGER,
+        )
+        .unwrap();
+
+    alloc_and_map_kernel_heap(&mut next_available_kernel_address, &mut kernel_page_table, boot_info);
+
+    /*
+     * Identity-map all of Seed into the kernel's page tables, so we don't fault when the MMU is turned on.
+     * TODO: this could maybe be reduced to just a tiny trampoline, maybe with linker symbols plus a custom section
+     * so we don't have to map as much.
+     */
+    let seed_size = align_up(unsafe { _seed_end.ptr() as usize - _seed_start.ptr() as usize }, Size4KiB::SIZE);
+    info!(
+        "Mapping Seed: {:#x} to {:#x} ({} bytes)",
+        PAddr::new(unsafe { _seed_start.ptr() as usize }).unwrap(),
+        PAddr::new(unsafe { _seed_end.ptr() as usize }).unwrap(),
+        seed_size,
+    );
+    kernel_page_table
+        .map_area(
+            VAddr::new(unsafe { _seed_start.ptr() as usize }),
+            PAddr::new(unsafe { _seed_start.ptr() as usize }).unwrap(),
+            seed_size,
+            Flags { writable: false, executable: true, ..Default::default() },
+            &MEMORY_MANAGER,
+        )
+        .unwrap();
+
+    /*
+     * Now that we've finished allocating memory, we can create the memory map we pass to the kernel. From here, we
+     * can't allocate physical memory from the bootloader.
+     */
+    MEMORY_MANAGER.populate_memory_map(&mut boot_info.memory_map);
+
+    /*
+     * Jump into the kernel by configuring translation, installing the kernel's page tables, turning on the MMU and
+     * caches, and then jumping to the kernel's entry point.
+     */
+    info!("Jumping into the kernel!");
+    unsafe {
+        paging::configure_translation();
+        asm!(
+            "
+                msr ttbr0_el1, {table}
+                msr ttbr1_el1, {table}
+                isb
+                tlbi vmalle1
+                dsb nsh
+                isb
+
+                // Enable the MMU (`M`), and the data and instruction caches (`C` and `I`)
+                mrs {sctlr}, sctlr_el1
+                orr {sctlr}, {sctlr}, #(1 << 0)
+                orr {sctlr}, {sctlr}, #(1 << 2)
+                orr {sctlr}, {sctlr}, #(1 << 12)
+                msr sctlr_el1, {sctlr}
+                isb
+
+                mov sp, {new_sp}
+                br {entry_point}
+            ",
+            table = in(reg) usize::from(kernel_page_table.address()),
+            sctlr = out(reg) _,
+            new_sp = in(reg) usize::from(kernel.stack_top),
+            entry_point = in(reg) usize::from(kernel.entry_point),
+            in("x0") usize::from(boot_info_kernel_address),
+            options(nostack, noreturn)
+        )
+    }
+}
+
+/// Allocate memory for the boot info, and dynamically map it into the address space after the kernel.
+fn create_boot_info<'a>(
+    next_available_kernel_address: &mut VAddr,
+    kernel_page_table: &mut PageTableImpl,
+) -> (VAddr, &'a mut BootInfo) {
+    let boot_info_physical_start =
+        MEMORY_MANAGER.allocate_n(Size4KiB::frames_needed(mem::size_of::<BootInfo>())).start.start;
+    let identity_boot_info_ptr = usize::from(boot_info_physical_start) as *mut BootInfo;
+    unsafe {
+        ptr::write(identity_boot_info_ptr, BootInfo::default());
+    }
+
+    let boot_info_kernel_address = *next_available_kernel_address;
+    *next_available_kernel_address += align_up(mem::size_of::<BootInfo>(), Size4KiB::SIZE);
+
+    kernel_page_table
+        .map_area(
+            boot_info_kernel_address,
+            boot_info_physical_start,
+            align_up(mem::size_of::<BootInfo>(), Size4KiB::SIZE),
+            Flags::default(),
+            &MEMORY_MANAGER,
+        )
+        .unwrap();
+
+    (boot_info_kernel_address, unsafe { &mut *identity_boot_info_ptr })
+}
+
+/// Allocate memory for the kernel heap, and dynamically map it into the address space after the kernel. We tell
+/// the kernel where to find it via the boot info.
+fn alloc_and_map_kernel_heap(
+    next_available_kernel_address: &mut VAddr,
+    kernel_page_table: &mut PageTableImpl,
+    boot_info: &mut BootInfo,
+) {
+    const KERNEL_HEAP_SIZE: hal::memory::Bytes = hal::memory::kibibytes(800);
+
+    boot_info.heap_address = *next_available_kernel_address;
+    boot_info.heap_size = KERNEL_HEAP_SIZE;
+    *next_available_kernel_address += KERNEL_HEAP_SIZE;
+
+    let kernel_heap_physical_start =
+        MEMORY_MANAGER.allocate_n(Size4KiB::frames_needed(KERNEL_HEAP_SIZE)).start.start;
+    kernel_page_table
+        .map_area(
+            boot_info.heap_address,
+            kernel_heap_physical_start,
+            KERNEL_HEAP_SIZE,
+            Flags { writable: true, ..Default::default() },
+            &MEMORY_MANAGER,
+        )
+        .unwrap();
+}
+
+#[no_mangle]
+extern "C" fn seed_exception_handler() -> ! {
+    let esr = EsrEl1::read();
+    let elr = ElrEl1::read();
+    let far = FarEl1::read();
+    panic!("Exception! ESR = {:#x}, ELR = {:#x}, FAR = {:#x}", esr, elr, far);
+}
@@ -4,6 +4,7 @@
  */
 
 use crate::{fs::File, memory::MemoryManager};
+use alloc::vec::Vec;
 use core::{
     ptr,
     slice,
@@ -20,28 +21,38 @@ use seed::boot_info::{LoadedImage, Segment};
 
 #[derive(Clone, Debug)]
 pub struct LoadedKernel {
+    /// The kernel's entry point, after the kernel has been relocated.
     pub entry_point: VAddr,
     pub stack_top: VAddr,
     pub global_pointer: VAddr,
 
-    /// The kernel is loaded to the base of the kernel address space, and then we dynamically map stuff into the
+    /// The kernel is loaded near the base of the kernel address space, and then we dynamically map stuff into the
     /// space after it. This is the address of the first available page after the loaded kernel.
     pub next_available_address: VAddr,
 }
 
-pub fn load_kernel<P>(file: &File<'_>, page_table: &mut P, memory_manager: &MemoryManager) -> LoadedKernel
+/// Load the kernel, moving it `slide` bytes up from the address it's linked at. The kernel is built as a
+/// position-independent executable, so it can be relocated to run at any page-aligned slide.
+pub fn load_kernel<P>(
+    file: &File<'_>,
+    page_table: &mut P,
+    memory_manager: &MemoryManager,
+    slide: usize,
+) -> LoadedKernel
 where
     P: PageTable<Size4KiB>,
 {
     let elf = Elf::new(file.data).expect("Failed to parse kernel ELF");
 
-    let entry_point = VAddr::new(elf.entry_point());
-    let mut next_available_address = kernel_map::KERNEL_BASE;
+    let entry_point = VAddr::new(elf.entry_point()) + slide;
+    let mut next_available_address = kernel_map::KERNEL_BASE + slide;
+    let mut segments = Vec::new();
 
     for segment in elf.segments() {
         match segment.segment_type() {
             SegmentType::Load if segment.mem_size > 0 => {
-                let segment = load_segment(segment, &elf, false, memory_manager);
+                let mut segment = load_segment(segment, &elf, false, memory_manager);
+                segment.virtual_address += slide;
 
                 /*
                  * If this segment loads past `next_available_address`, update it.
@@ -69,25 +80,28 @@ where
                         memory_manager,
                     )
                     .unwrap();
+                segments.push(segment);
             }
 
             _ => (),
         }
     }
 
+    relocate_kernel(&elf, &segments, slide);
+
     let stack_top = match elf.symbols().find(|symbol| symbol.name(&elf) == Some("_stack_top")) {
-        Some(symbol) => VAddr::new(symbol.value as usize),
+        Some(symbol) => VAddr::new(symbol.value as usize) + slide,
         None => panic!("Kernel does not have a '_stack_top' symbol!"),
     };
 
     let global_pointer = match elf.symbols().find(|symbol| symbol.name(&elf) == Some("__global_pointer$")) {
-        Some(symbol) => VAddr::new(symbol.value as usize),
+        Some(symbol) => VAddr::new(symbol.value as usize) + slide,
         None => panic!("Kernel does not have a '__global_pointer$' symbol!"),
     };
 
     // Unmap the stack guard page
     let guard_page_address = match elf.symbols().find(|symbol| symbol.name(&elf) == Some("_guard_page")) {
-        Some(symbol) => VAddr::new(symbol.value as usize),
+        Some(symbol) => VAddr::new(symbol.value as usize) + slide,
         None => panic!("Kernel does not have a '_guard_page' symbol!"),
     };
     assert!(guard_page_address.is_aligned(Size4KiB::SIZE), "Guard page address is not page aligned");
@@ -96,6 +110,42 @@ where
     LoadedKernel { entry_point, stack_top, global_pointer, next_available_address }
 }
 
+/// Apply the kernel's relocations, now it has been loaded `slide` bytes from where it was linked. The kernel is
+/// statically linked, so it should only contain relative relocations. Seed runs with paging disabled, so the
+/// relocations are written through the physical memory each segment has been loaded into.
+fn relocate_kernel(elf: &Elf, segments: &[Segment], slide: usize) {
+    const R_RISCV_RELATIVE: u32 = 3;
+
+    let relocations = match elf.rela_entries() {
+        Some(relocations) => relocations,
+        None => panic!("Kernel's relocations are not inside a loadable segment!"),
+    };
+
+    for relocation in relocations {
+        assert!(
+            relocation.relocation_type() == R_RISCV_RELATIVE && relocation.symbol_index() == 0,
+            "Kernel contains an unsupported relocation: {:?}",
+            relocation
+        );
+
+        let address = VAddr::new(relocation.offset as usize) + slide;
+        let segment = segments
+            .iter()
+            .find(|segment| {
+                address >= segment.virtual_address && (address + 8) <= (segment.virtual_address + segment.size)
+            })
+            .expect("Kernel relocation is not inside a loaded segment!");
+        let physical = segment.physical_address + (usize::from(address) - usize::from(segment.virtual_address));
+
+        unsafe {
+            ptr::write_unaligned(
+                usize::from(physical) as *mut u64,
+                (relocation.addend as u64).wrapping_add(slide as u64),
+            );
+        }
+    }
+}
+
 pub fn load_image(file: &File<'_>, name: &str, memory_manager: &MemoryManager) -> LoadedImage {
     let elf = Elf::new(file.data).expect("Failed to parse user task ELF");
     let mut image_data = LoadedImage::default();
@@ -125,6 +175,11 @@ fn load_segment(
     user_accessible: bool,
     memory_manager: &MemoryManager,
 ) -> Segment {
+    assert!(
+        !(segment.is_writable() && segment.is_executable()),
+        "Segment is both writable and executable, which is not allowed"
+    );
+
     /*
      * We don't require each segment to fill up all the pages it needs - as long as the start of each segment is
      * page-aligned so they don't overlap, it's fine. This is mainly to support images linked by `lld` with the `-z
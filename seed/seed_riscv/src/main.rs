@@ -22,11 +22,18 @@ use crate::{
 };
 use core::{arch::asm, mem, ptr};
 use fdt::Fdt;
-use hal::memory::{Flags, FrameAllocator, FrameSize, PAddr, PageTable, Size4KiB, VAddr};
-use hal_riscv::{hw::csr::Stvec, platform::PageTableImpl};
+use hal::memory::{Flags, Frame, FrameAllocator, FrameSize, PAddr, Page, PageTable, Size1GiB, Size4KiB, VAddr};
+use hal_riscv::{
+    hw::csr::{Stvec, Time},
+    paging::PagingMode,
+    platform::{PageTableImpl, PAGING_MODES},
+};
 use linked_list_allocator::LockedHeap;
 use memory::{MemoryManager, MemoryRegions};
-use mulch::{linker::LinkerSymbol, math::align_up};
+use mulch::{
+    linker::LinkerSymbol,
+    math::{align_down, align_up},
+};
 use pci::PciResolver;
 use seed::{boot_info::BootInfo, SeedConfig};
 use tracing::info;
@@ -135,13 +142,29 @@ pub fn seed_main(hart_id: u64, fdt_ptr: *const u8) -> ! {
     };
     info!("Config: {:?}", config);
 
-    let mut kernel_page_table = PageTableImpl::new(MEMORY_MANAGER.allocate(), VAddr::new(0x0));
+    let paging_mode = pick_paging_mode();
+    info!("Using paging mode: {:?}", paging_mode);
+    let mut kernel_page_table = PageTableImpl::new(MEMORY_MANAGER.allocate(), paging_mode, VAddr::new(0x0));
     let kernel_file = if let Some(ref mut ramdisk) = ramdisk {
         ramdisk.load("kernel_riscv").unwrap()
     } else {
         panic!("No kernel source is present!");
     };
-    let kernel = image::load_kernel(&kernel_file, &mut kernel_page_table, &MEMORY_MANAGER);
+
+    /*
+     * Pick how far to move the kernel from the address it's linked at, so its addresses can't be relied on by an
+     * attacker. The slide is page-aligned, and small enough that the kernel stays within its region of the
+     * address space.
+     */
+    let kernel_slide = if config.disable_aslr {
+        0
+    } else {
+        let num_slides = hal_riscv::platform::kernel_map::KERNEL_SLIDE_RANGE / Size4KiB::SIZE;
+        (random_u64(&fdt) as usize % num_slides) * Size4KiB::SIZE
+    };
+    info!("Sliding kernel by {:#x}", kernel_slide);
+
+    let kernel = image::load_kernel(&kernel_file, &mut kernel_page_table, &MEMORY_MANAGER, kernel_slide);
     let mut next_available_kernel_address = kernel.next_available_address;
 
     /*
@@ -176,6 +199,8 @@ pub fn seed_main(hart_id: u64, fdt_ptr: *const u8) -> ! {
         create_boot_info(&mut next_available_kernel_address, &mut kernel_page_table);
     boot_info.magic = seed::boot_info::BOOT_INFO_MAGIC;
     boot_info.fdt_address = Some(PAddr::new(fdt_ptr as usize).unwrap());
+    boot_info.disable_aslr = config.disable_aslr;
+    boot_info.kernel_slide = kernel_slide;
 
     /*
      * Load desired early tasks.
@@ -259,11 +284,63 @@ pub fn seed_main(hart_id: u64, fdt_ptr: *const u8) -> ! {
             new_gp = in(reg) usize::from(kernel.global_pointer),
             entry_point = in(reg) usize::from(kernel.entry_point),
             in("a0") usize::from(boot_info_kernel_address),
+            in("a1") hart_id as usize,
             options(nostack, noreturn)
         )
     }
 }
 
+/// Pick the paging mode to run the kernel with. This is the first of the platform's `PAGING_MODES` that the hart
+/// supports. We have to try each mode to find out if it's supported, apart from the last, which the platform
+/// guarantees is.
+fn pick_paging_mode() -> PagingMode {
+    let (&fallback, modes) = PAGING_MODES.split_last().unwrap();
+    for &mode in modes {
+        /*
+         * Build a set of page tables that identity-map the 1GiB of memory that Seed is in, so we can carry on
+         * fetching instructions while translation is turned on. We can't give memory back to the memory manager,
+         * so these tables are leaked, but it's only a few pages.
+         */
+        let mut page_table = PageTableImpl::new(MEMORY_MANAGER.allocate(), mode, VAddr::new(0x0));
+        let seed_region = align_down(unsafe { _seed_start.ptr() as usize }, Size1GiB::SIZE);
+        page_table
+            .map::<Size1GiB, _>(
+                Page::starts_with(VAddr::new(seed_region)),
+                Frame::starts_with(PAddr::new(seed_region).unwrap()),
+                Flags { executable: true, ..Default::default() },
+                &MEMORY_MANAGER,
+            )
+            .unwrap();
+
+        if unsafe { mode.probe(page_table.frame().start) } {
+            return mode;
+        }
+        info!("Paging mode {:?} is not supported by this hart", mode);
+    }
+    fallback
+}
+
+/// Get a random number to slide the kernel by. Firmware can pass us a random seed in the device tree's `/chosen`
+/// node. If it hasn't, we fall back to the timer, which is far easier to guess but better than nothing.
+fn random_u64(fdt: &Fdt) -> u64 {
+    let seed = fdt
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property("rng-seed").or_else(|| chosen.property("kaslr-seed")));
+
+    let mut random = Time::read() as u64;
+    match seed {
+        Some(seed) => {
+            for chunk in seed.value.chunks(8) {
+                let mut bytes = [0u8; 8];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                random = (random ^ u64::from_le_bytes(bytes)).rotate_left(29).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            }
+        }
+        None => info!("Device tree does not provide a random seed. Falling back to the timer."),
+    }
+    random
+}
+
 /// Allocate memory for the boot info, and dynamically map it into the address space after the kernel.
 fn create_boot_info<'a>(
     next_available_kernel_address: &mut VAddr,
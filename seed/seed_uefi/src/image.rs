@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use core::{
     ptr,
     slice,
@@ -23,21 +24,25 @@ use uefi::{
 };
 
 pub struct KernelInfo {
+    /// The kernel's entry point, after the kernel has been relocated.
     pub entry_point: VAddr,
     pub stack_top: VAddr,
 
-    /// We load the kernel at the base of the kernel address space. We want to put other stuff after it, and so
+    /// We load the kernel near the base of the kernel address space. We want to put other stuff after it, and so
     /// need to know how much memory the loaded image has taken up. During loading, we calculate the address of
     /// the next available page (this) to use.
     pub next_safe_address: VAddr,
 }
 
+/// Load the kernel, moving it `slide` bytes up from the address it's linked at. The kernel is built as a
+/// position-independent executable, so it can be relocated to run at any page-aligned slide.
 pub fn load_kernel<A, P>(
     boot_services: &BootServices,
     volume_handle: Handle,
     path: &Path,
     page_table: &mut P,
     allocator: &A,
+    slide: usize,
 ) -> KernelInfo
 where
     A: FrameAllocator<Size4KiB>,
@@ -45,14 +50,16 @@ where
 {
     info!("Loading kernel from: {}", path);
     let (elf, pool_addr) = load_elf(boot_services, volume_handle, path);
-    let entry_point = VAddr::new(elf.entry_point());
+    let entry_point = VAddr::new(elf.entry_point()) + slide;
 
-    let mut next_safe_address = kernel_map::KERNEL_BASE;
+    let mut next_safe_address = kernel_map::KERNEL_BASE + slide;
+    let mut segments = Vec::new();
 
     for segment in elf.segments() {
         match segment.segment_type() {
             SegmentType::Load if segment.mem_size > 0 => {
-                let segment = load_segment(boot_services, segment, crate::KERNEL_MEMORY_TYPE, &elf, false);
+                let mut segment = load_segment(boot_services, segment, crate::KERNEL_MEMORY_TYPE, &elf, false);
+                segment.virtual_address += slide;
 
                 /*
                  * If this segment loads past `next_safe_address`, update it.
@@ -80,20 +87,23 @@ where
                         allocator,
                     )
                     .unwrap();
+                segments.push(segment);
             }
 
             _ => (),
         }
     }
 
+    relocate_kernel(&elf, &segments, slide);
+
     let stack_top = match elf.symbols().find(|symbol| symbol.name(&elf) == Some("_stack_top")) {
-        Some(symbol) => VAddr::new(symbol.value as usize),
+        Some(symbol) => VAddr::new(symbol.value as usize) + slide,
         None => panic!("Kernel does not have a '_stack_top' symbol!"),
     };
 
     // Unmap the stack guard page
     let guard_page_address = match elf.symbols().find(|symbol| symbol.name(&elf) == Some("_guard_page")) {
-        Some(symbol) => VAddr::new(symbol.value as usize),
+        Some(symbol) => VAddr::new(symbol.value as usize) + slide,
         None => panic!("Kernel does not have a '_guard_page' symbol!"),
     };
     assert!(guard_page_address.is_aligned(Size4KiB::SIZE), "Guard page address is not page aligned");
@@ -103,6 +113,42 @@ where
     KernelInfo { entry_point, stack_top, next_safe_address }
 }
 
+/// Apply the kernel's relocations, now it has been loaded `slide` bytes from where it was linked. The kernel is
+/// statically linked, so it should only contain relative relocations. We're still identity-mapped, so the
+/// relocations are written through the physical memory each segment has been loaded into.
+fn relocate_kernel(elf: &Elf, segments: &[Segment], slide: usize) {
+    const R_X86_64_RELATIVE: u32 = 8;
+
+    let relocations = match elf.rela_entries() {
+        Some(relocations) => relocations,
+        None => panic!("Kernel's relocations are not inside a loadable segment!"),
+    };
+
+    for relocation in relocations {
+        assert!(
+            relocation.relocation_type() == R_X86_64_RELATIVE && relocation.symbol_index() == 0,
+            "Kernel contains an unsupported relocation: {:?}",
+            relocation
+        );
+
+        let address = VAddr::new(relocation.offset as usize) + slide;
+        let segment = segments
+            .iter()
+            .find(|segment| {
+                address >= segment.virtual_address && (address + 8) <= (segment.virtual_address + segment.size)
+            })
+            .expect("Kernel relocation is not inside a loaded segment!");
+        let physical = segment.physical_address + (usize::from(address) - usize::from(segment.virtual_address));
+
+        unsafe {
+            ptr::write_unaligned(
+                usize::from(physical) as *mut u64,
+                (relocation.addend as u64).wrapping_add(slide as u64),
+            );
+        }
+    }
+}
+
 pub fn load_image(boot_services: &BootServices, volume_handle: Handle, name: &str, path: &Path) -> LoadedImage {
     info!("Loading requested '{}' image from: {}", name, path);
     let (elf, pool_addr) = load_elf(boot_services, volume_handle, path);
@@ -175,6 +221,11 @@ fn load_segment(
     elf: &Elf,
     user_accessible: bool,
 ) -> Segment {
+    assert!(
+        !(segment.is_writable() && segment.is_executable()),
+        "Segment is both writable and executable, which is not allowed"
+    );
+
     /*
      * We don't require each segment to fill up all the pages it needs - as long as the start of each segment is
      * page-aligned so they don't overlap, it's fine. This is mainly to support images linked by `lld` with the `-z
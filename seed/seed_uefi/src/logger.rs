@@ -1,5 +1,5 @@
 use core::fmt;
-use gfxconsole::{Framebuffer, GfxConsole};
+use gfxconsole::{Format, Framebuffer, GfxConsole};
 use hal_x86_64::hw::serial::SerialPort;
 use log::{LevelFilter, Log, Metadata, Record};
 use seed::boot_info::VideoModeInfo;
@@ -44,14 +44,12 @@ impl Logger {
     pub fn switch_to_graphical(
         VideoModeInfo { framebuffer_address, pixel_format, width, height, stride }: &VideoModeInfo,
     ) {
-        let framebuffer = match pixel_format {
-            seed::boot_info::PixelFormat::Rgb32 => {
-                Framebuffer::new(usize::from(*framebuffer_address) as *mut u32, *width, *height, *stride, 0, 8, 16)
-            }
-            seed::boot_info::PixelFormat::Bgr32 => {
-                Framebuffer::new(usize::from(*framebuffer_address) as *mut u32, *width, *height, *stride, 16, 8, 0)
-            }
+        let format = match pixel_format {
+            seed::boot_info::PixelFormat::Rgb32 => Format::Rgb32,
+            seed::boot_info::PixelFormat::Bgr32 => Format::Bgr32,
         };
+        let framebuffer =
+            Framebuffer::new(usize::from(*framebuffer_address) as *mut u8, *width, *height, *stride, format);
         *LOGGER.lock() = Logger::Graphical {
             serial_port: unsafe { SerialPort::new(hal_x86_64::hw::serial::COM1) },
             console: GfxConsole::new(framebuffer, 0x0000aaff, 0xffffffff),
@@ -11,11 +11,14 @@ mod logger;
 use allocator::BootFrameAllocator;
 use core::{arch::asm, convert::TryFrom, mem, panic::PanicInfo, ptr};
 use hal::memory::{kibibytes, Bytes, Flags, FrameAllocator, FrameSize, PAddr, Page, PageTable, Size4KiB, VAddr};
-use hal_x86_64::paging::PageTableImpl;
+use hal_x86_64::{
+    hw::cpu::CpuInfo,
+    paging::{PageTableImpl, PagingMode},
+};
 use log::{error, info};
 use logger::Logger;
 use seed::{
-    boot_info::{BootInfo, VideoModeInfo},
+    boot_info::{BootInfo, Initramfs, VideoModeInfo},
     SeedConfig,
 };
 use uefi::{
@@ -37,6 +40,7 @@ pub const PAGE_TABLE_MEMORY_TYPE: MemoryType = MemoryType::custom(0x80000002);
 pub const MEMORY_MAP_MEMORY_TYPE: MemoryType = MemoryType::custom(0x80000003);
 pub const BOOT_INFO_MEMORY_TYPE: MemoryType = MemoryType::custom(0x80000004);
 pub const KERNEL_HEAP_MEMORY_TYPE: MemoryType = MemoryType::custom(0x80000005);
+pub const INITRAMFS_MEMORY_TYPE: MemoryType = MemoryType::custom(0x80000006);
 
 const KERNEL_HEAP_SIZE: Bytes = kibibytes(800);
 
@@ -55,9 +59,17 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     /*
      * We create a set of page tables for the kernel. Because memory is identity-mapped in UEFI, we can act as
      * if we've placed the physical mapping at 0x0.
+     *
+     * The paging mode can't be changed while we're in long mode, so the kernel uses whichever mode the firmware
+     * has left enabled.
      */
+    let paging_mode = PagingMode::current();
+    info!("Using paging mode: {:?}", paging_mode);
+    if paging_mode == PagingMode::FourLevel && CpuInfo::new().supports_la57() {
+        info!("CPU supports 5-level paging, but it has not been enabled by the firmware");
+    }
     let allocator = BootFrameAllocator::new(system_table.boot_services(), 64);
-    let mut page_table = PageTableImpl::new(allocator.allocate(), VAddr::new(0x0));
+    let mut page_table = PageTableImpl::new(allocator.allocate(), paging_mode, VAddr::new(0x0));
 
     /*
      * Get the handle of the volume that the loader's image was loaded off. This will allow us to get access to the
@@ -79,6 +91,19 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     };
     info!("Config: {:?}", config);
 
+    /*
+     * Pick how far to move the kernel from the address it's linked at, so its addresses can't be relied on by an
+     * attacker. The slide is page-aligned, and small enough that the kernel stays within its region of the
+     * address space.
+     */
+    let kernel_slide = if config.disable_aslr {
+        0
+    } else {
+        let num_slides = hal_x86_64::kernel_map::KERNEL_SLIDE_RANGE / Size4KiB::SIZE;
+        (random_u64(system_table.boot_services()) as usize % num_slides) * Size4KiB::SIZE
+    };
+    info!("Sliding kernel by {:#x}", kernel_slide);
+
     let kernel_path = CString16::try_from("kernel.elf").unwrap();
     let kernel_info = {
         image::load_kernel(
@@ -87,6 +112,7 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
             Path::new(&kernel_path),
             &mut page_table,
             &allocator,
+            kernel_slide,
         )
     };
     let mut next_safe_address = kernel_info.next_safe_address;
@@ -129,6 +155,8 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     boot_info.magic = seed::boot_info::BOOT_INFO_MAGIC;
     boot_info.video_mode = Some(video_mode);
     boot_info.rsdp_address = find_rsdp(&system_table);
+    boot_info.disable_aslr = config.disable_aslr;
+    boot_info.kernel_slide = kernel_slide;
 
     /*
      * Allocate the kernel heap.
@@ -155,6 +183,8 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
         boot_info.loaded_images.push(info).unwrap();
     }
 
+    boot_info.initramfs = load_initramfs(system_table.boot_services(), loader_image_device);
+
     uefi::allocator::exit_boot_services();
     let (_system_table, memory_map) = system_table.exit_boot_services();
     process_memory_map(memory_map, boot_info, &mut page_table, &allocator);
@@ -164,7 +194,7 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
      */
     info!("Entering kernel!\n\n\n");
     unsafe {
-        let page_table_address = page_table.p4() as *const _ as usize;
+        let page_table_address = usize::from(page_table.frame().start);
         let kernel_rsp = usize::from(kernel_info.stack_top.align_down(8));
         let kernel_entry_point = usize::from(kernel_info.entry_point);
         let boot_info_address = usize::from(boot_info_kernel_address);
@@ -218,6 +248,58 @@ fn find_rsdp(system_table: &SystemTable<Boot>) -> Option<PAddr> {
         })
 }
 
+/// Get a random number to slide the kernel by. This uses the firmware's random number generator if it has one, and
+/// falls back to the timestamp counter if not, which is far easier to guess but better than nothing.
+fn random_u64(boot_services: &BootServices) -> u64 {
+    use uefi::proto::rng::Rng;
+
+    let from_firmware = boot_services.get_handle_for_protocol::<Rng>().ok().and_then(|handle| {
+        let mut rng = boot_services.open_protocol_exclusive::<Rng>(handle).ok()?;
+        let mut bytes = [0u8; 8];
+        rng.get_rng(None, &mut bytes).ok()?;
+        Some(u64::from_le_bytes(bytes))
+    });
+
+    from_firmware.unwrap_or_else(|| {
+        info!("Firmware does not provide a random number generator. Falling back to the timestamp counter.");
+        unsafe { core::arch::x86_64::_rdtsc() }
+    })
+}
+
+/// Load the initial ramdisk, if there is one on the boot volume. This is handed to userspace by the kernel, so is
+/// placed in memory that is left out of the kernel's memory map.
+fn load_initramfs(boot_services: &BootServices, volume_handle: Handle) -> Option<Initramfs> {
+    use uefi::proto::media::fs::SimpleFileSystem;
+
+    let root_file_protocol =
+        boot_services.open_protocol_exclusive::<SimpleFileSystem>(volume_handle).expect("Failed to get volume");
+    let mut filesystem = uefi::fs::FileSystem::new(root_file_protocol);
+    let data = match filesystem.read(Path::new(&CString16::try_from("initramfs.tar").unwrap())) {
+        Ok(data) => data,
+        Err(_) => {
+            info!("No initramfs found on boot volume");
+            return None;
+        }
+    };
+
+    let num_frames = Size4KiB::frames_needed(data.len());
+    let physical_start = boot_services
+        .allocate_pages(AllocateType::AnyPages, INITRAMFS_MEMORY_TYPE, num_frames)
+        .expect("Failed to allocate memory for initramfs");
+    unsafe {
+        ptr::copy_nonoverlapping(data.as_ptr(), physical_start as *mut u8, data.len());
+        ptr::write_bytes(
+            (physical_start as usize + data.len()) as *mut u8,
+            0,
+            num_frames * Size4KiB::SIZE - data.len(),
+        );
+    }
+
+    let initramfs = Initramfs { address: PAddr::new(physical_start as usize).unwrap(), size: data.len() };
+    info!("Loaded initramfs: {:?}", initramfs);
+    Some(initramfs)
+}
+
 /// Process the final UEFI memory map when after we've exited boot services:
 ///    * Identity-map the loader, so it doesn't disappear from under us.
 ///    * Construct the memory map passed to the kernel, and add it to the boot info.
@@ -308,6 +390,10 @@ fn process_memory_map<'a, A, P>(
         .map(|entry| entry.phys_start as usize + entry.page_count as usize * Size4KiB::SIZE)
         .max()
         .unwrap();
+    assert!(
+        max_physical_address <= hal_x86_64::kernel_map::PHYSICAL_MAPPING_SIZE,
+        "Physical memory extends past the end of the physical mapping!"
+    );
     info!(
         "Constructing physical mapping 0x0..{:#x} at {:#x}",
         max_physical_address,
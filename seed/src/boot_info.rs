@@ -38,6 +38,18 @@ pub struct BootInfo {
 
     /// The physical address of the device tree, if one is present.
     pub fdt_address: Option<PAddr>,
+
+    /// The initial ramdisk loaded alongside the kernel, if the loader found one.
+    pub initramfs: Option<Initramfs>,
+
+    /// If this is set, the kernel should not randomize the layout of tasks' address spaces, so that addresses are
+    /// the same on every boot. This is useful for debugging.
+    pub disable_aslr: bool,
+
+    /// How far the kernel has been moved from the address it was linked at. The loader relocates the kernel by a
+    /// random slide to make its addresses harder to guess, and the kernel needs this to turn addresses back into
+    /// ones that match its symbols. This is zero if ASLR is disabled.
+    pub kernel_slide: usize,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
@@ -127,6 +139,17 @@ pub struct Segment {
     pub flags: Flags,
 }
 
+/// Describes an initial ramdisk (initramfs) loaded by the loader. This is a `ustar` archive of files that early
+/// userspace needs before it can access any other filesystem. The kernel does not interpret it, and instead hands
+/// it to userspace as a read-only `MemoryObject`. The memory it occupies is not included in the memory map.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Initramfs {
+    pub address: PAddr,
+    /// The size of the archive. The memory after the archive, up to the next page boundary, is zeroed.
+    pub size: Bytes,
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct VideoModeInfo {
@@ -11,4 +11,8 @@ use serde::Deserialize;
 #[derive(Clone, Debug, Deserialize)]
 pub struct SeedConfig {
     pub user_tasks: Vec<String>,
+    /// Load the kernel at the address it was linked at, and ask it not to randomize the layout of tasks' address
+    /// spaces.
+    #[serde(default)]
+    pub disable_aslr: bool,
 }
@@ -0,0 +1,125 @@
+use crate::ramdisk::Ramdisk;
+use eyre::{Result, WrapErr};
+use std::{path::PathBuf, process::Command};
+
+pub struct RunQemuAarch64 {
+    pub seed: PathBuf,
+    pub ramdisk: Option<Ramdisk>,
+    pub disk_image: Option<PathBuf>,
+
+    pub open_display: bool,
+    pub debug_int_firehose: bool,
+    pub trace: Option<String>,
+}
+
+impl RunQemuAarch64 {
+    pub fn new(seed: PathBuf, disk_image: Option<PathBuf>) -> RunQemuAarch64 {
+        RunQemuAarch64 {
+            seed,
+            ramdisk: None,
+            disk_image,
+            open_display: false,
+            debug_int_firehose: false,
+            trace: None,
+        }
+    }
+
+    pub fn ramdisk(self, ramdisk: Option<Ramdisk>) -> Self {
+        Self { ramdisk, ..self }
+    }
+
+    pub fn open_display(self, open_display: bool) -> Self {
+        Self { open_display, ..self }
+    }
+
+    pub fn debug_int_firehose(self, enabled: bool) -> Self {
+        Self { debug_int_firehose: enabled, ..self }
+    }
+
+    pub fn trace(self, trace: Option<String>) -> Self {
+        Self { trace, ..self }
+    }
+
+    pub fn run(self) -> Result<()> {
+        let mut qemu = self.command();
+        println!("QEMU command: {:?}", qemu);
+        crate::qemu_result(qemu.status().wrap_err("Failed to invoke qemu-system-aarch64")?)
+    }
+
+    /// Build the command to run QEMU with, without running it.
+    pub fn command(self) -> Command {
+        let mut qemu = Command::new("qemu-system-aarch64");
+
+        /*
+         * XXX: current versions of QEMU on Wayland do not capture the mouse correctly for me
+         * (mouse events are not generated with relative x/y on mouse movement). This could be an
+         * oddity of my current setup / versions of things, but for now at least force QEMU's GTK
+         * backend to use X / XWayland until hopefully that's fixed.
+         */
+        qemu.env("GDK_BACKEND", "x11");
+
+        /*
+         * We only support GICv3, and keep every device below 4GiB (with `highmem=off`) so the physical mapping
+         * Seed creates covers all of them. Seed is started at EL1, as we don't enable EL2 or EL3.
+         */
+        qemu.args(&["-M", "virt,gic-version=3,highmem=off"]);
+        qemu.args(&["-cpu", "cortex-a72"]);
+        qemu.args(&["-m", "1G"]);
+        qemu.args(&["-kernel", self.seed.to_str().unwrap()]);
+        // The kernel exits QEMU through semihosting
+        qemu.args(&["-semihosting"]);
+        if self.debug_int_firehose {
+            qemu.args(&["-d", "int"]);
+        }
+
+        if let Some(ramdisk) = self.ramdisk {
+            const RAMDISK_BASE_ADDR: u64 = 0x7000_0000;
+            let (header, entries) = ramdisk.create();
+            qemu.args(&[
+                "-device",
+                &format!("loader,addr={},file={},force-raw=on", RAMDISK_BASE_ADDR, header.display()),
+            ]);
+            for (offset, source) in entries {
+                qemu.args(&[
+                    "-device",
+                    &format!(
+                        "loader,addr={},file={},force-raw=on",
+                        RAMDISK_BASE_ADDR + offset as u64,
+                        source.display()
+                    ),
+                ]);
+            }
+        }
+
+        // Emit serial on both stdio and to a file
+        qemu.args(&["-chardev", "stdio,id=char0,logfile=qemu_serial_aarch64.log"]);
+        qemu.args(&["-serial", "chardev:char0"]);
+
+        qemu.args(&["-global", "virtio-mmio.force-legacy=false"]);
+
+        qemu.args(&["-device", "virtio-gpu"]);
+        // Use a modern-only network device, so it has the non-transitional PCI device ID
+        qemu.args(&["-netdev", "user,id=net0"]);
+        qemu.args(&["-device", "virtio-net-pci,netdev=net0,disable-legacy=on"]);
+        // Add a tablet, so the pointer follows the host's cursor
+        qemu.args(&["-device", "virtio-tablet-pci"]);
+
+        if let Some(disk_image) = self.disk_image {
+            // Add the disk image as a modern-only Virtio block device
+            qemu.args(&["-drive", &format!("id=disk0,format=raw,if=none,file={}", disk_image.to_str().unwrap())]);
+            qemu.args(&["-device", "virtio-blk-pci,drive=disk0,disable-legacy=on"]);
+        }
+
+        if !self.open_display {
+            qemu.args(&["-display", "none"]);
+            // If we're not opening a display, allow connections to the monitor over TCP (open with `nc 127.0.0.1 55555`)
+            qemu.args(&["-monitor", "tcp:127.0.0.1:55555,server,nowait"]);
+        }
+
+        if let Some(trace) = self.trace {
+            qemu.args(&["--trace", &trace]);
+        }
+
+        qemu
+    }
+}
@@ -12,7 +12,15 @@ pub struct Config {
     pub release: bool,
     pub kernel_features: Vec<String>,
     pub user_tasks: Vec<UserTask>,
+    /// Tasks that are built and placed in the initramfs, rather than being loaded by Seed.
+    pub initramfs_tasks: Vec<UserTask>,
+    /// Other files that are placed in the initramfs.
+    pub initramfs_files: Vec<InitramfsFile>,
     pub qemu_trace: Option<String>,
+    /// Whether Seed should load the kernel at a random address, and the kernel should randomize the layout of
+    /// tasks' address spaces. Turning this off makes addresses the same on every boot, which is useful for
+    /// debugging.
+    pub aslr: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -21,6 +29,13 @@ pub struct UserTask {
     pub source_dir: PathBuf,
 }
 
+#[derive(Clone, Debug)]
+pub struct InitramfsFile {
+    /// The path to place the file at in the initramfs.
+    pub path: String,
+    pub source: PathBuf,
+}
+
 /// This represents the options that are read out of the persistent config file. These are then merged with the CLI
 /// options and defaults filled in to create a `Config`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -28,6 +43,7 @@ struct ConfigFile {
     platform: Option<Platform>,
     x64: Option<PlatformInfo>,
     rv64_virt: Option<PlatformInfo>,
+    aarch64_virt: Option<PlatformInfo>,
     mq_pro: Option<PlatformInfo>,
     uconsole: Option<PlatformInfo>,
 }
@@ -37,7 +53,10 @@ pub struct PlatformInfo {
     pub release: Option<bool>,
     pub kernel_features: Option<Vec<String>>,
     pub user_tasks: Option<Vec<String>>,
+    pub initramfs_tasks: Option<Vec<String>>,
+    pub initramfs_files: Option<Vec<String>>,
     pub qemu_trace: Option<String>,
+    pub aslr: Option<bool>,
 }
 
 impl Config {
@@ -55,6 +74,7 @@ impl Config {
         let platform_info = match platform {
             Platform::X64 => file.x64.as_ref(),
             Platform::Rv64Virt => file.rv64_virt.as_ref(),
+            Platform::Aarch64Virt => file.aarch64_virt.as_ref(),
             Platform::MqPro => file.mq_pro.as_ref(),
             Platform::Uconsole => file.uconsole.as_ref(),
         };
@@ -67,31 +87,69 @@ impl Config {
                 platform_info.map(|info| info.kernel_features.clone().unwrap_or(vec![])).unwrap_or(vec![])
             }
         };
-        let user_tasks: Vec<String> =
-            platform_info.map(|info| info.user_tasks.clone().unwrap_or(vec![])).unwrap_or(vec![]);
-        let user_tasks = user_tasks
-            .into_iter()
-            .map(|entry| {
-                let mut split = entry.split_whitespace();
-                let name = split.next().unwrap().to_string();
-                let source_dir = PathBuf::from(split.next().unwrap());
-                assert_eq!(split.next(), None);
-
-                UserTask { name, source_dir }
-            })
-            .collect();
+        let user_tasks = parse_user_tasks(
+            platform_info.map(|info| info.user_tasks.clone().unwrap_or(vec![])).unwrap_or(vec![]),
+        );
+        let initramfs_tasks = parse_user_tasks(
+            platform_info.map(|info| info.initramfs_tasks.clone().unwrap_or(vec![])).unwrap_or(vec![]),
+        );
+        let initramfs_files = parse_initramfs_files(
+            platform_info.map(|info| info.initramfs_files.clone().unwrap_or(vec![])).unwrap_or(vec![]),
+        );
         let qemu_trace = platform_info.and_then(|info| info.qemu_trace.clone());
+        let aslr = platform_info.and_then(|info| info.aslr).unwrap_or(true);
 
-        Config { platform, release, kernel_features, user_tasks, qemu_trace }
+        Config {
+            platform,
+            release,
+            kernel_features,
+            user_tasks,
+            initramfs_tasks,
+            initramfs_files,
+            qemu_trace,
+            aslr,
+        }
     }
 }
 
+/// Parse a list of user tasks, each in the form `"name source_dir"`.
+fn parse_user_tasks(entries: Vec<String>) -> Vec<UserTask> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            let mut split = entry.split_whitespace();
+            let name = split.next().unwrap().to_string();
+            let source_dir = PathBuf::from(split.next().unwrap());
+            assert_eq!(split.next(), None);
+
+            UserTask { name, source_dir }
+        })
+        .collect()
+}
+
+/// Parse a list of files to place in the initramfs, each in the form `"path source"`.
+fn parse_initramfs_files(entries: Vec<String>) -> Vec<InitramfsFile> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            let mut split = entry.split_whitespace();
+            let path = split.next().unwrap().to_string();
+            let source = PathBuf::from(split.next().unwrap());
+            assert_eq!(split.next(), None);
+
+            InitramfsFile { path, source }
+        })
+        .collect()
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Platform {
     #[serde(alias = "x64")]
     X64,
     #[serde(alias = "rv64_virt")]
     Rv64Virt,
+    #[serde(alias = "aarch64_virt")]
+    Aarch64Virt,
     #[serde(alias = "mq_pro")]
     MqPro,
     #[serde(alias = "uconsole")]
@@ -109,6 +167,7 @@ impl fmt::Display for Platform {
         match self {
             Self::X64 => write!(f, "x64"),
             Self::Rv64Virt => write!(f, "rv64_virt"),
+            Self::Aarch64Virt => write!(f, "aarch64_virt"),
             Self::MqPro => write!(f, "mq_pro"),
             Self::Uconsole => write!(f, "uconsole"),
         }
@@ -122,6 +181,7 @@ impl std::str::FromStr for Platform {
         match s.to_lowercase().as_ref() {
             "x64" => Ok(Platform::X64),
             "rv64_virt" => Ok(Platform::Rv64Virt),
+            "aarch64_virt" => Ok(Platform::Aarch64Virt),
             "mq_pro" => Ok(Platform::MqPro),
             "uconsole" => Ok(Platform::Uconsole),
             _ => Err("Unrecognised platform string"),
@@ -0,0 +1,349 @@
+//! `service_manager` writes a core dump of each service that causes an exception (see its `core_dump` module for
+//! the format). This prints a report of one: the exception, the registers of the thread that caused it, a
+//! backtrace resolved using the task's ELF, and the task's memory regions.
+//!
+//! The backtrace is found by walking the chain of frame records from the frame pointer, in the same way as
+//! `mulch::backtrace`, reading the stack out of the core dump. Core dumps are usually written to the EFI system
+//! partition, so they can also be read straight out of a disk image.
+
+use crate::{flags::Coredump as CoredumpFlags, symbolicate::Symbolicator};
+use eyre::{eyre, Result, WrapErr};
+use std::{convert::TryInto, fs, io::Read, path::Path};
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const EM_RISCV: u16 = 243;
+const EM_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_POPLAR_EXCEPTION: u32 = 1;
+const NT_POPLAR_TASK: u32 = 2;
+
+/// The offset of the registers (`pr_reg`) into an `NT_PRSTATUS` note.
+const PRSTATUS_REGISTERS_OFFSET: usize = 112;
+/// The most frames that are walked. Anything deeper probably means the chain of frame records is corrupt.
+const MAX_FRAMES: usize = 64;
+
+/// The registers in an `NT_PRSTATUS` note, in the order of Linux's `user_regs_struct`.
+const X86_64_REGISTERS: [&str; 27] = [
+    "r15", "r14", "r13", "r12", "rbp", "rbx", "r11", "r10", "r9", "r8", "rax", "rcx", "rdx", "rsi", "rdi",
+    "orig_rax", "rip", "cs", "rflags", "rsp", "ss", "fs_base", "gs_base", "ds", "es", "fs", "gs",
+];
+const RISCV_REGISTERS: [&str; 32] = [
+    "pc", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+    "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+const AARCH64_REGISTERS: [&str; 34] = [
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13", "x14", "x15", "x16",
+    "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28", "x29", "x30", "sp", "pc",
+    "pstate",
+];
+
+pub fn coredump(flags: CoredumpFlags) -> Result<()> {
+    let data = match flags.image {
+        Some(ref image) => read_from_image(image, &flags.core)?,
+        None => fs::read(&flags.core)
+            .wrap_err_with(|| format!("Failed to read core dump: {}", flags.core.display()))?,
+    };
+    let core = Core::parse(&data)?;
+
+    let elf = fs::read(&flags.elf).wrap_err_with(|| format!("Failed to read ELF: {}", flags.elf.display()))?;
+    let object = addr2line::object::File::parse(&*elf).map_err(|err| eyre!("Failed to parse ELF: {}", err))?;
+    let symbolicator = Symbolicator::new(&object)?;
+
+    println!("Core dump of task '{}'", core.task_name.as_deref().unwrap_or("<unknown>"));
+    if let Some(ref exception) = core.exception {
+        println!("{} at {:#x}", exception.description(), exception.address);
+    }
+
+    println!();
+    println!("Registers:");
+    for (name, value) in core.arch.register_names().iter().zip(&core.registers) {
+        println!("    {:>8} {:#018x}", name, value);
+    }
+
+    println!();
+    println!("Backtrace:");
+    for (i, &frame) in core.backtrace().iter().enumerate() {
+        println!("    #{}: {:#x}", i, frame);
+        // Return addresses point to the instruction after the call, which may be on a different line
+        let address = if i == 0 { frame } else { frame.saturating_sub(1) };
+        for location in symbolicator.resolve(address)? {
+            println!("        {}", location);
+        }
+    }
+
+    println!();
+    println!("Memory regions:");
+    let stack_pointer = core.stack_pointer();
+    for segment in &core.segments {
+        println!(
+            "    {:#018x}-{:#018x} r{}{} {:>8} bytes dumped{}",
+            segment.address,
+            segment.address + segment.memory_size,
+            if segment.flags & PF_W != 0 { "w" } else { "-" },
+            if segment.flags & PF_X != 0 { "x" } else { "-" },
+            segment.data.len(),
+            if segment.contains(stack_pointer) { " (stack)" } else { "" },
+        );
+    }
+
+    Ok(())
+}
+
+/// Read the core dump at `path` on the EFI system partition of the disk image at `image`.
+fn read_from_image(image: &Path, path: &Path) -> Result<Vec<u8>> {
+    use gpt::{disk::LogicalBlockSize, GptConfig};
+
+    let disk = GptConfig::default()
+        .writable(false)
+        .logical_block_size(LogicalBlockSize::Lb512)
+        .open(image)
+        .wrap_err_with(|| format!("Failed to open disk image: {}", image.display()))?;
+    let (start, end) = {
+        let partition = disk
+            .partitions()
+            .values()
+            .find(|partition| partition.part_type_guid == gpt::partition_types::EFI)
+            .ok_or_else(|| eyre!("Disk image doesn't have an EFI system partition"))?;
+        let start = partition.bytes_start(LogicalBlockSize::Lb512)?;
+        (start, start + partition.bytes_len(LogicalBlockSize::Lb512)?)
+    };
+
+    let image_file = fs::File::open(image).wrap_err("Failed to open disk image")?;
+    let fat_partition = fscommon::StreamSlice::new(image_file, start, end)
+        .wrap_err("Failed to construct StreamSlice of FAT partition")?;
+    let fat = fatfs::FileSystem::new(fat_partition, fatfs::FsOptions::new())
+        .wrap_err("Failed to read FAT filesystem of EFI system partition")?;
+
+    let fat_path = path.to_str().ok_or_else(|| eyre!("Invalid path: {}", path.display()))?.trim_start_matches('/');
+    let mut data = Vec::new();
+    fat.root_dir()
+        .open_file(fat_path)
+        .wrap_err_with(|| format!("Failed to open core dump on EFI system partition: {}", fat_path))?
+        .read_to_end(&mut data)
+        .wrap_err("Failed to read core dump from EFI system partition")?;
+    Ok(data)
+}
+
+#[derive(Clone, Copy)]
+enum Arch {
+    X86_64,
+    RiscV,
+    Aarch64,
+}
+
+impl Arch {
+    fn register_names(self) -> &'static [&'static str] {
+        match self {
+            Arch::X86_64 => &X86_64_REGISTERS,
+            Arch::RiscV => &RISCV_REGISTERS,
+            Arch::Aarch64 => &AARCH64_REGISTERS,
+        }
+    }
+}
+
+struct Exception {
+    kind: u32,
+    access: u32,
+    address: u64,
+}
+
+impl Exception {
+    fn description(&self) -> String {
+        match (self.kind, self.access) {
+            (0, 1) => "Page fault (read)".to_string(),
+            (0, 2) => "Page fault (write)".to_string(),
+            (0, 3) => "Page fault (instruction fetch)".to_string(),
+            (1, _) => "Illegal instruction".to_string(),
+            (2, _) => "Protection fault".to_string(),
+            (3, _) => "Misaligned access".to_string(),
+            (4, _) => "Stack overflow".to_string(),
+            (5, _) => "Floating-point exception".to_string(),
+            (kind, access) => format!("Unknown exception (kind {}, access {})", kind, access),
+        }
+    }
+}
+
+struct Segment {
+    address: u64,
+    memory_size: u64,
+    flags: u32,
+    /// The part of the segment that's in the core dump. The rest of it reads as zeros.
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn contains(&self, address: u64) -> bool {
+        address >= self.address && address < self.address + self.memory_size
+    }
+}
+
+struct Core {
+    arch: Arch,
+    task_name: Option<String>,
+    exception: Option<Exception>,
+    registers: Vec<u64>,
+    segments: Vec<Segment>,
+}
+
+impl Core {
+    fn parse(data: &[u8]) -> Result<Core> {
+        if !data.starts_with(b"\x7fELF") || data.get(4) != Some(&2) || data.get(5) != Some(&1) {
+            return Err(eyre!("Core dump is not a 64-bit little-endian ELF"));
+        }
+        if read_u16(data, 16)? != ET_CORE {
+            return Err(eyre!("ELF is not a core dump"));
+        }
+        let arch = match read_u16(data, 18)? {
+            EM_X86_64 => Arch::X86_64,
+            EM_RISCV => Arch::RiscV,
+            EM_AARCH64 => Arch::Aarch64,
+            other => return Err(eyre!("Core dump is for an unsupported machine: {}", other)),
+        };
+
+        let program_headers_offset = read_u64(data, 32)? as usize;
+        let program_header_size = read_u16(data, 54)? as usize;
+        let num_program_headers = read_u16(data, 56)? as usize;
+
+        let mut core =
+            Core { arch, task_name: None, exception: None, registers: Vec::new(), segments: Vec::new() };
+        for i in 0..num_program_headers {
+            let header = program_headers_offset + i * program_header_size;
+            let typ = read_u32(data, header)?;
+            let flags = read_u32(data, header + 4)?;
+            let offset = read_u64(data, header + 8)? as usize;
+            let address = read_u64(data, header + 16)?;
+            let file_size = read_u64(data, header + 32)? as usize;
+            let memory_size = read_u64(data, header + 40)?;
+            let contents = data
+                .get(offset..(offset + file_size))
+                .ok_or_else(|| eyre!("Segment at {:#x} goes past the end of the core dump", address))?;
+
+            match typ {
+                PT_NOTE => core.parse_notes(contents)?,
+                PT_LOAD => core.segments.push(Segment { address, memory_size, flags, data: contents.to_vec() }),
+                _ => (),
+            }
+        }
+
+        if core.registers.is_empty() {
+            return Err(eyre!("Core dump doesn't contain the task's registers"));
+        }
+        Ok(core)
+    }
+
+    fn parse_notes(&mut self, mut notes: &[u8]) -> Result<()> {
+        while notes.len() >= 12 {
+            let name_size = read_u32(notes, 0)? as usize;
+            let desc_size = read_u32(notes, 4)? as usize;
+            let typ = read_u32(notes, 8)?;
+            let desc_offset = 12 + align_up(name_size, 4);
+            let name = notes.get(12..(12 + name_size)).ok_or_else(|| eyre!("Note goes past end of segment"))?;
+            let desc = notes
+                .get(desc_offset..(desc_offset + desc_size))
+                .ok_or_else(|| eyre!("Note goes past end of segment"))?;
+
+            match (name, typ) {
+                (b"CORE\0", NT_PRSTATUS) => {
+                    let num_registers = self.arch.register_names().len();
+                    self.registers = (0..num_registers)
+                        .map(|i| read_u64(desc, PRSTATUS_REGISTERS_OFFSET + i * 8))
+                        .collect::<Result<_>>()?;
+                }
+                (b"POPLAR\0", NT_POPLAR_EXCEPTION) => {
+                    self.exception = Some(Exception {
+                        kind: read_u32(desc, 0)?,
+                        access: read_u32(desc, 4)?,
+                        address: read_u64(desc, 8)?,
+                    });
+                }
+                (b"POPLAR\0", NT_POPLAR_TASK) => self.task_name = Some(String::from_utf8_lossy(desc).into_owned()),
+                _ => (),
+            }
+
+            notes = notes.get((desc_offset + align_up(desc_size, 4))..).unwrap_or(&[]);
+        }
+        Ok(())
+    }
+
+    fn register(&self, name: &str) -> u64 {
+        let index = self.arch.register_names().iter().position(|&register| register == name).unwrap();
+        self.registers[index]
+    }
+
+    fn stack_pointer(&self) -> u64 {
+        match self.arch {
+            Arch::X86_64 => self.register("rsp"),
+            Arch::RiscV | Arch::Aarch64 => self.register("sp"),
+        }
+    }
+
+    /// Read a word of the task's memory, if it's in one of the segments of the core dump.
+    fn read_word(&self, address: u64) -> Option<u64> {
+        let segment =
+            self.segments.iter().find(|segment| segment.contains(address) && segment.contains(address + 7))?;
+        let offset = (address - segment.address) as usize;
+        let mut bytes = [0; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = segment.data.get(offset + i).copied().unwrap_or(0);
+        }
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    /// Walk the chain of frame records from the frame pointer. The first frame is the address of the instruction
+    /// that caused the exception, and the rest are return addresses.
+    fn backtrace(&self) -> Vec<u64> {
+        /*
+         * The frame pointer points at the frame record on x86_64 and AArch64, and to just above it on RISC-V. In
+         * all cases, the record holds the caller's frame pointer, and then the return address.
+         */
+        let (mut frame_pointer, record_offset, instruction_pointer) = match self.arch {
+            Arch::X86_64 => (self.register("rbp"), 0, self.register("rip")),
+            Arch::RiscV => (self.register("s0"), 16, self.register("pc")),
+            Arch::Aarch64 => (self.register("x29"), 0, self.register("pc")),
+        };
+
+        let mut frames = vec![instruction_pointer];
+        while frames.len() < MAX_FRAMES && frame_pointer >= record_offset && frame_pointer % 8 == 0 {
+            let record = frame_pointer - record_offset;
+            let (Some(next), Some(return_address)) = (self.read_word(record), self.read_word(record + 8)) else {
+                break;
+            };
+            if return_address == 0 {
+                break;
+            }
+            frames.push(return_address);
+
+            // The stack grows down, so each caller's frame record is above the last
+            if next <= frame_pointer {
+                break;
+            }
+            frame_pointer = next;
+        }
+        frames
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..(offset + 2)).ok_or_else(|| eyre!("Core dump is truncated"))?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..(offset + 4)).ok_or_else(|| eyre!("Core dump is truncated"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data.get(offset..(offset + 8)).ok_or_else(|| eyre!("Core dump is truncated"))?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
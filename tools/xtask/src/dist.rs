@@ -1,4 +1,4 @@
-use crate::{config::Platform, image::MakeGptImage, ramdisk::Ramdisk};
+use crate::{config::Platform, image::MakeGptImage, initramfs::Initramfs, ramdisk::Ramdisk};
 use colored::Colorize;
 use serde::Serialize;
 use std::path::PathBuf;
@@ -67,6 +67,16 @@ impl DistResult {
             }
         }
 
+        let mut initramfs = Initramfs::new(self.platform);
+        for artifact in &self.artifacts {
+            if let Some(initramfs_path) = &artifact.initramfs_path {
+                initramfs.add(initramfs_path, &artifact.source);
+            }
+        }
+        if !initramfs.is_empty() {
+            image = image.copy_efi_file("initramfs.tar", initramfs.create());
+        }
+
         // If a config file for Seed is required, add it here
         if let Some(config) = &self.seed_config {
             image = image.add_efi_file("config.toml", toml::to_string(config).unwrap());
@@ -83,6 +93,8 @@ pub enum ArtifactType {
     Bootloader,
     Kernel,
     UserTask,
+    /// A file that is included in the image as-is, rather than being built.
+    File,
 }
 
 #[derive(Clone, Debug)]
@@ -93,11 +105,20 @@ pub struct Artifact {
 
     pub include_in_ramdisk: bool,
     pub disk_path: Option<String>,
+    /// The path to place the artifact at in the initramfs, if it should be included in one.
+    pub initramfs_path: Option<String>,
 }
 
 impl Artifact {
     pub fn new(name: &str, typ: ArtifactType, source: PathBuf) -> Artifact {
-        Artifact { name: name.to_string(), typ, source, include_in_ramdisk: false, disk_path: None }
+        Artifact {
+            name: name.to_string(),
+            typ,
+            source,
+            include_in_ramdisk: false,
+            disk_path: None,
+            initramfs_path: None,
+        }
     }
 
     pub fn include_in_ramdisk(self) -> Artifact {
@@ -107,6 +128,10 @@ impl Artifact {
     pub fn include_in_disk_image(self, path: String) -> Artifact {
         Artifact { disk_path: Some(path), ..self }
     }
+
+    pub fn include_in_initramfs(self, path: String) -> Artifact {
+        Artifact { initramfs_path: Some(path), ..self }
+    }
 }
 
 /// This represents the expected structure of a Seed config file. It is constructed and serialized
@@ -114,4 +139,5 @@ impl Artifact {
 #[derive(Clone, Debug, Serialize)]
 pub struct SeedConfig {
     pub user_tasks: Vec<String>,
+    pub disable_aslr: bool,
 }
@@ -23,6 +23,10 @@ xflags::xflags! {
             optional --debug_int_firehose
             optional --debug_mmu_firehose
             optional --debug_cpu_firehose
+            optional --sata
+            // Build the kernel with its GDB stub, and connect the serial port it uses to a socket GDB can attach
+            // to. Only supported on x64.
+            optional --gdb
         }
 
         cmd boot {
@@ -45,6 +49,51 @@ xflags::xflags! {
             required path: PathBuf
         }
 
+        // Resolve the addresses in backtraces using the ELF of the kernel or task that printed them. Reads from
+        // standard input if a log isn't given.
+        cmd symbolicate {
+            required elf: PathBuf
+            optional log: PathBuf
+        }
+
+        // Convert the records captured by the `trace` task into Chrome's trace-event format, which can be opened
+        // with Perfetto or `chrome://tracing`. Writes to `trace.json` if an output isn't given.
+        cmd trace {
+            required log: PathBuf
+            optional -o, --output output: PathBuf
+        }
+
+        // Turn the samples captured by the `profile` task into folded stacks, which can be made into a flamegraph
+        // with `inferno-flamegraph` or `flamegraph.pl`. Kernel frames are resolved using the kernel's ELF, and
+        // each task's frames using the ELF with the task's name in the `--user` directory. Writes to
+        // `profile.folded` if an output isn't given.
+        cmd profile {
+            required log: PathBuf
+            optional --kernel kernel: PathBuf
+            optional --user user: PathBuf
+            optional -o, --output output: PathBuf
+        }
+
+        // Print a report of a core dump written by `service_manager`: the exception the task caused, its registers,
+        // a backtrace resolved using the task's ELF, and its memory regions. With `--image`, the core dump is read
+        // from that path on the EFI system partition of a disk image, rather than from the host's filesystem.
+        cmd coredump {
+            required core: PathBuf
+            required elf: PathBuf
+            optional --image image: PathBuf
+        }
+
+        // Build an image with the kernel's test runner and every `user/test_*` task, boot it in QEMU without a
+        // display, and report the results of the tests. Fails if any test fails, if the kernel crashes, or if the
+        // tests don't finish within the timeout (in seconds, 300 if not given).
+        cmd test {
+            optional --config config_path: PathBuf
+            optional --release
+            optional -p,--platform platform: Platform
+            optional --kernel_features kernel_features: String
+            optional --timeout timeout: u64
+        }
+
         cmd clean {}
     }
 }
@@ -89,6 +138,17 @@ impl From<&Qemu> for DistOptions {
     }
 }
 
+impl From<&Test> for DistOptions {
+    fn from(flags: &Test) -> DistOptions {
+        DistOptions {
+            config_path: flags.config.clone().unwrap_or(PathBuf::from("Poplar.toml")),
+            release: flags.release,
+            kernel_features: flags.kernel_features.clone(),
+            platform: flags.platform,
+        }
+    }
+}
+
 // XXX: this feels pretty janky, and is only used to pass the platform into the config system. Better approach?
 impl From<&Opensbi> for DistOptions {
     fn from(flags: &Opensbi) -> DistOptions {
@@ -117,6 +177,11 @@ pub enum TaskCmd {
     Opensbi(Opensbi),
     Devicetree(Devicetree),
     Doc(Doc),
+    Symbolicate(Symbolicate),
+    Trace(Trace),
+    Profile(Profile),
+    Coredump(Coredump),
+    Test(Test),
     Clean(Clean),
 }
 
@@ -138,6 +203,8 @@ pub struct Qemu {
     pub debug_int_firehose: bool,
     pub debug_mmu_firehose: bool,
     pub debug_cpu_firehose: bool,
+    pub sata: bool,
+    pub gdb: bool,
 }
 
 #[derive(Debug)]
@@ -163,6 +230,45 @@ pub struct Doc {
     pub path: PathBuf,
 }
 
+#[derive(Debug)]
+pub struct Symbolicate {
+    pub elf: PathBuf,
+    pub log: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    pub log: PathBuf,
+
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct Profile {
+    pub log: PathBuf,
+
+    pub kernel: Option<PathBuf>,
+    pub user: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct Coredump {
+    pub core: PathBuf,
+    pub elf: PathBuf,
+
+    pub image: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct Test {
+    pub config: Option<PathBuf>,
+    pub release: bool,
+    pub platform: Option<Platform>,
+    pub kernel_features: Option<String>,
+    pub timeout: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct Clean;
 
@@ -0,0 +1,72 @@
+use crate::config::Platform;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Builds the initial ramdisk that Seed loads alongside the kernel on platforms that boot from a disk image. This
+/// is a `ustar` archive, which userspace reads the contents of through `ramfs`.
+pub struct Initramfs {
+    entries: Vec<(String, PathBuf)>,
+    platform: Platform,
+}
+
+impl Initramfs {
+    pub fn new(platform: Platform) -> Initramfs {
+        Initramfs { entries: Vec::new(), platform }
+    }
+
+    pub fn add(&mut self, path: &str, source: &Path) {
+        self.entries.push((path.to_string(), source.to_owned()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Create the archive, returning the path to it.
+    pub fn create(&self) -> PathBuf {
+        let archive_path = PathBuf::from(format!("initramfs_{}.tar", self.platform));
+        let mut file = File::create(&archive_path).unwrap();
+
+        for (path, source) in &self.entries {
+            let data = std::fs::read(source).unwrap();
+            file.write_all(&header(path, data.len())).unwrap();
+            file.write_all(&data).unwrap();
+            let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+            file.write_all(&vec![0; padding]).unwrap();
+        }
+
+        // The end of the archive is marked by two zeroed blocks
+        file.write_all(&[0; 2 * BLOCK_SIZE]).unwrap();
+        archive_path
+    }
+}
+
+/// Create the header of a regular file in a `ustar` archive.
+fn header(path: &str, size: usize) -> [u8; BLOCK_SIZE] {
+    assert!(path.len() < 100, "Path of initramfs entry is too long: {}", path);
+    let mut header = [0; BLOCK_SIZE];
+
+    let mut write_field =
+        |offset: usize, value: &[u8]| header[offset..(offset + value.len())].copy_from_slice(value);
+    write_field(0, path.as_bytes());
+    write_field(100, b"0000644\0");
+    write_field(108, b"0000000\0");
+    write_field(116, b"0000000\0");
+    write_field(124, format!("{:011o}\0", size).as_bytes());
+    write_field(136, b"00000000000\0");
+    write_field(156, b"0");
+    write_field(257, b"ustar\0");
+    write_field(263, b"00");
+
+    // The checksum is calculated with the checksum field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    header
+}
@@ -4,18 +4,26 @@
  */
 #![allow(dead_code)]
 
+mod aarch64;
 mod cargo;
 mod config;
+mod coredump;
 mod dist;
 mod doc;
 mod flags;
 mod image;
+mod initramfs;
+mod profile;
 mod ramdisk;
 mod riscv;
 mod serial;
+mod symbolicate;
+mod test;
+mod trace;
 mod x64;
 
 use crate::{
+    aarch64::qemu::RunQemuAarch64,
     cargo::RunCargo,
     dist::{Artifact, ArtifactType, DistResult, SeedConfig},
 };
@@ -29,7 +37,7 @@ use riscv::qemu::RunQemuRiscV;
 use std::{
     env,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, ExitStatus},
 };
 use x64::qemu::RunQemuX64;
 use xshell::pushd;
@@ -47,7 +55,13 @@ fn main() -> Result<()> {
         }
 
         TaskCmd::Qemu(flags) => {
-            let config = config::Config::new(Some(&DistOptions::from(&flags)));
+            let mut config = config::Config::new(Some(&DistOptions::from(&flags)));
+            if flags.gdb {
+                if !matches!(config.platform, Platform::X64) {
+                    return Err(eyre!("The kernel's GDB stub is only supported on x64"));
+                }
+                config.kernel_features.push("gdb".to_string());
+            }
             let dist_result = dist(&config)?;
 
             match config.platform {
@@ -57,13 +71,34 @@ fn main() -> Result<()> {
                     .debug_mmu_firehose(flags.debug_mmu_firehose)
                     .debug_cpu_firehose(flags.debug_cpu_firehose)
                     .trace(config.qemu_trace)
+                    .sata(flags.sata)
+                    .gdb_stub(
+                        flags
+                            .gdb
+                            .then(|| dist_result.artifact_by_type(ArtifactType::Kernel).unwrap().source.clone()),
+                    )
                     .run(),
                 Platform::Rv64Virt => {
                     let ramdisk = dist_result.build_ramdisk();
-                    // TODO: support disk images here again at some point
+                    /*
+                     * Seed loads everything from the ramdisk, but we still attach a disk image for
+                     * userspace to access through the Virtio block driver.
+                     */
                     RunQemuRiscV::new(
                         dist_result.artifact_by_type(ArtifactType::Bootloader).unwrap().source.clone(),
-                        None,
+                        Some(dist_result.build_disk_image()),
+                    )
+                    .ramdisk(Some(ramdisk))
+                    .open_display(flags.display)
+                    .debug_int_firehose(flags.debug_int_firehose)
+                    .trace(config.qemu_trace)
+                    .run()
+                }
+                Platform::Aarch64Virt => {
+                    let ramdisk = dist_result.build_ramdisk();
+                    RunQemuAarch64::new(
+                        dist_result.artifact_by_type(ArtifactType::Bootloader).unwrap().source.clone(),
+                        Some(dist_result.build_disk_image()),
                     )
                     .ramdisk(Some(ramdisk))
                     .open_display(flags.display)
@@ -143,6 +178,16 @@ fn main() -> Result<()> {
             generator.generate()
         }
 
+        TaskCmd::Symbolicate(flags) => symbolicate::symbolicate(flags),
+
+        TaskCmd::Trace(flags) => trace::trace(flags),
+
+        TaskCmd::Profile(flags) => profile::profile(flags),
+
+        TaskCmd::Coredump(flags) => coredump::coredump(flags),
+
+        TaskCmd::Test(flags) => test::test(flags),
+
         TaskCmd::Clean(_) => {
             // TODO: put a big list of crates that need cleaning etc. in the config?
             clean(PathBuf::from("seed/"))?;
@@ -167,20 +212,31 @@ fn dist(config: &Config) -> Result<DistResult> {
         release: config.release,
         kernel_features: config.kernel_features.clone(),
         user_tasks: config.user_tasks.clone(),
+        initramfs_tasks: config.initramfs_tasks.clone(),
+        initramfs_files: config.initramfs_files.clone(),
+        aslr: config.aslr,
     };
 
     match config.platform {
         Platform::X64 => dist.build_x64(),
         Platform::Rv64Virt => dist.build_rv64_virt(),
+        Platform::Aarch64Virt => dist.build_aarch64_virt(),
         Platform::MqPro => dist.build_mq_pro(),
         Platform::Uconsole => dist.build_uconsole(),
     }
 }
 
+/// Frame pointers are used to capture backtraces when the kernel panics. The kernel is built as a
+/// position-independent executable, so Seed can load it at a random address.
+const KERNEL_RUSTFLAGS: &str = "-Cforce-frame-pointers=yes -Crelocation-model=pie -Clink-arg=-pie";
+
 struct Dist {
     release: bool,
     kernel_features: Vec<String>,
     user_tasks: Vec<config::UserTask>,
+    initramfs_tasks: Vec<config::UserTask>,
+    initramfs_files: Vec<config::InitramfsFile>,
+    aslr: bool,
 }
 
 impl Dist {
@@ -206,7 +262,7 @@ impl Dist {
             .features(vec!["platform_rv64_virt".to_string()])
             .features(self.kernel_features.clone())
             .std_components(vec!["core".to_string(), "alloc".to_string()])
-            .rustflags("-Clink-arg=-Tkernel_riscv/rv64_virt.ld")
+            .rustflags(format!("-Clink-arg=-Tkernel_riscv/rv64_virt.ld {}", KERNEL_RUSTFLAGS))
             .run()?;
         result.add(Artifact::new("kernel_riscv", ArtifactType::Kernel, kernel).include_in_ramdisk());
 
@@ -215,6 +271,48 @@ impl Dist {
                 &task.name,
                 task.source_dir.clone(),
                 Target::Triple("riscv64gc-unknown-none-elf".to_string()),
+                false,
+            )?;
+            result.add(Artifact::new(&task.name, ArtifactType::UserTask, artifact).include_in_ramdisk());
+        }
+
+        result.add_seed_config(self.generate_seed_config());
+
+        Ok(result)
+    }
+
+    pub fn build_aarch64_virt(self) -> Result<DistResult> {
+        let mut result = DistResult::new(Platform::Aarch64Virt);
+
+        println!("{}", "[*] Building Seed for AArch64".bold().magenta());
+        let seed_aarch64 = RunCargo::new("seed_aarch64", PathBuf::from("seed/seed_aarch64/"))
+            .workspace(PathBuf::from("seed/"))
+            .target(Target::Triple("aarch64-unknown-none-softfloat".to_string()))
+            .release(self.release)
+            .features(vec!["platform_aarch64_virt".to_string()])
+            .std_components(vec!["core".to_string(), "alloc".to_string()])
+            .rustflags("-Clink-arg=-Tseed_aarch64/aarch64_virt.ld")
+            .run()?;
+        result.add(Artifact::new("seed_aarch64", ArtifactType::Bootloader, seed_aarch64));
+
+        println!("{}", "[*] Building the kernel for AArch64".bold().magenta());
+        let kernel = RunCargo::new("kernel_aarch64", PathBuf::from("kernel/kernel_aarch64/"))
+            .workspace(PathBuf::from("kernel/"))
+            .target(Target::Triple("aarch64-unknown-none-softfloat".to_string()))
+            .release(self.release)
+            .features(vec!["platform_aarch64_virt".to_string()])
+            .features(self.kernel_features.clone())
+            .std_components(vec!["core".to_string(), "alloc".to_string()])
+            .rustflags("-Clink-arg=-Tkernel_aarch64/aarch64_virt.ld -Cforce-frame-pointers=yes")
+            .run()?;
+        result.add(Artifact::new("kernel_aarch64", ArtifactType::Kernel, kernel).include_in_ramdisk());
+
+        for task in &self.user_tasks {
+            let artifact = self.build_userspace_task(
+                &task.name,
+                task.source_dir.clone(),
+                Target::Triple("aarch64-unknown-none-softfloat".to_string()),
+                false,
             )?;
             result.add(Artifact::new(&task.name, ArtifactType::UserTask, artifact).include_in_ramdisk());
         }
@@ -257,7 +355,7 @@ impl Dist {
             .features(vec!["platform_mq_pro".to_string()])
             .features(self.kernel_features.clone())
             .std_components(vec!["core".to_string(), "alloc".to_string()])
-            .rustflags("-Clink-arg=-Tkernel_riscv/mq_pro.ld")
+            .rustflags(format!("-Clink-arg=-Tkernel_riscv/mq_pro.ld {}", KERNEL_RUSTFLAGS))
             .run()?;
         result.add(Artifact::new("kernel_riscv", ArtifactType::Kernel, kernel).include_in_ramdisk());
 
@@ -266,6 +364,7 @@ impl Dist {
                 &task.name,
                 task.source_dir.clone(),
                 Target::Triple("riscv64gc-unknown-none-elf".to_string()),
+                false,
             )?;
             result.add(Artifact::new(&task.name, ArtifactType::UserTask, artifact).include_in_ramdisk());
         }
@@ -319,6 +418,7 @@ impl Dist {
             .features(self.kernel_features.clone())
             .std_components(vec!["core".to_string(), "alloc".to_string()])
             .std_features(vec!["compiler-builtins-mem".to_string()])
+            .rustflags(KERNEL_RUSTFLAGS)
             .run()?;
         result.add(
             Artifact::new("kernel", ArtifactType::Kernel, kernel).include_in_disk_image("kernel.elf".to_string()),
@@ -332,32 +432,67 @@ impl Dist {
                     triple: "x86_64-poplar".to_string(),
                     spec: PathBuf::from("user/x86_64-poplar.json"),
                 },
+                false,
             )?;
             let path = format!("{}.elf", task.name);
             result.add(Artifact::new(&task.name, ArtifactType::UserTask, artifact).include_in_disk_image(path));
         }
 
+        for task in &self.initramfs_tasks {
+            let artifact = self.build_userspace_task(
+                &task.name,
+                task.source_dir.clone(),
+                Target::Custom {
+                    triple: "x86_64-poplar".to_string(),
+                    spec: PathBuf::from("user/x86_64-poplar.json"),
+                },
+                true,
+            )?;
+            let path = format!("bin/{}", task.name);
+            result.add(Artifact::new(&task.name, ArtifactType::UserTask, artifact).include_in_initramfs(path));
+        }
+
+        for file in &self.initramfs_files {
+            result.add(
+                Artifact::new(&file.path, ArtifactType::File, file.source.clone())
+                    .include_in_initramfs(file.path.clone()),
+            );
+        }
+
         result.add_seed_config(self.generate_seed_config());
 
         Ok(result)
     }
 
-    fn build_userspace_task(&self, name: &str, source_dir: PathBuf, target: Target) -> Result<PathBuf> {
+    /// Build a user task. Tasks loaded by Seed are linked at fixed addresses, but tasks loaded by the kernel (e.g.
+    /// from the initramfs) are built as position-independent executables (`pie`), so the kernel can load them at
+    /// a random address.
+    fn build_userspace_task(&self, name: &str, source_dir: PathBuf, target: Target, pie: bool) -> Result<PathBuf> {
         println!("{}", format!("[*] Building user task '{}'", name).bold().magenta());
 
+        /*
+         * Tasks are statically-linked, so TLS can always use the local-exec model. Frame pointers are used to
+         * capture backtraces when a task panics.
+         */
+        let mut rustflags =
+            "-C link-arg=-Tlink.ld -Z tls-model=local-exec -C force-frame-pointers=yes".to_string();
+        if pie {
+            rustflags += " -C relocation-model=pie -C link-arg=-pie";
+        }
+
         RunCargo::new(name.to_string(), source_dir)
             .workspace(PathBuf::from("user/")) // TODO: we probably need to provide control over this too
             .target(target)
             .release(self.release)
             .std_components(vec!["core".to_string(), "alloc".to_string()])
             .std_features(vec!["compiler-builtins-mem".to_string()])
-            .rustflags("-C link-arg=-Tlink.ld")
+            .rustflags(rustflags)
             .run()
     }
 
     fn generate_seed_config(&self) -> SeedConfig {
         let user_tasks = self.user_tasks.iter().map(|task| task.name.clone()).collect();
-        SeedConfig { user_tasks }
+        SeedConfig { user_tasks, disable_aslr: !self.aslr }
     }
 }
 
@@ -407,6 +542,21 @@ pub fn compile_device_tree(path: &Path) -> Result<PathBuf> {
     Ok(blob_path)
 }
 
+/// The status QEMU exits with when the kernel crashes, if it's been built with the `qemu_exit` feature. This is
+/// the same on every platform.
+const KERNEL_CRASHED_STATUS: i32 = 0x23;
+
+/// Turn the status QEMU exited with into a result, picking out the kernel crashing from QEMU failing.
+fn qemu_result(status: ExitStatus) -> Result<()> {
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(KERNEL_CRASHED_STATUS) => {
+            Err(eyre!("The kernel crashed (see the crash report in the serial output)"))
+        }
+        _ => Err(eyre!("Qemu returned an error code")),
+    }
+}
+
 fn clean(manifest_dir: PathBuf) -> Result<()> {
     Command::new("cargo")
         .arg("clean")
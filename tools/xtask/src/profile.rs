@@ -0,0 +1,172 @@
+//! The `profile` task writes the samples it reads from the kernel's profile buffers to the serial port, as lines
+//! of the form `PROFILE {cpu} {task} {task name} kernel {frames...} user {frames...}`. This picks those lines out
+//! of a log, resolves their frames to functions, and counts how many times each distinct stack was sampled. The
+//! result is written in the "folded stacks" format - a line per stack, of the form `{root};{...};{leaf} {count}` -
+//! which flamegraph tools take as input.
+//!
+//! Each stack starts with the task that was running (or `idle`), then the task's frames, and then the kernel's,
+//! each from the outermost frame inwards. Frames that can't be resolved, because we weren't given the ELF they're
+//! from, are shown as addresses.
+
+use crate::{flags::Profile as ProfileFlags, symbolicate::Symbolicator};
+use eyre::{eyre, Result, WrapErr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, BufRead},
+    path::{Path, PathBuf},
+};
+
+/// The number of bytes of a task's name that are kept in a sample. This matches
+/// `poplar::trace::TRACE_TASK_NAME_LENGTH`.
+const TASK_NAME_LENGTH: usize = 16;
+
+pub fn profile(flags: ProfileFlags) -> Result<()> {
+    let log = io::BufReader::new(
+        fs::File::open(&flags.log).wrap_err_with(|| format!("Failed to open log: {}", flags.log.display()))?,
+    );
+
+    let mut samples = Vec::new();
+    for line in log.lines() {
+        if let Some(sample) = parse_sample(&line?) {
+            samples.push(sample);
+        }
+    }
+
+    /*
+     * The ELFs have to outlive the symbolicators, which borrow their symbol tables, so they're all read before any
+     * of the symbolicators are made.
+     */
+    let kernel_elf = match flags.kernel {
+        Some(ref path) => Some(read_elf(path)?),
+        None => None,
+    };
+    let mut task_elfs = BTreeMap::new();
+    if let Some(ref user) = flags.user {
+        let task_names: BTreeSet<&str> = samples.iter().filter_map(|sample| sample.task_name.as_deref()).collect();
+        for name in task_names {
+            match find_task_elf(user, name)? {
+                Some(path) => {
+                    task_elfs.insert(name.to_string(), read_elf(&path)?);
+                }
+                None => println!("Couldn't find an ELF for task '{}' in {}", name, user.display()),
+            }
+        }
+    }
+
+    let kernel = match kernel_elf {
+        Some(ref data) => Some(load_symbolicator(data)?),
+        None => None,
+    };
+    let mut tasks = BTreeMap::new();
+    for (name, data) in &task_elfs {
+        tasks.insert(name.as_str(), load_symbolicator(data)?);
+    }
+
+    let mut stacks: BTreeMap<String, usize> = BTreeMap::new();
+    for sample in &samples {
+        let mut stack = vec![match sample.task_name {
+            Some(ref name) => name.clone(),
+            None if sample.task == 0 => "idle".to_string(),
+            None => format!("Task {}", sample.task),
+        }];
+        let task = sample.task_name.as_deref().and_then(|name| tasks.get(name));
+        resolve_frames(task, &sample.user_frames, &mut stack)?;
+        resolve_frames(kernel.as_ref(), &sample.kernel_frames, &mut stack)?;
+        *stacks.entry(stack.join(";")).or_insert(0) += 1;
+    }
+
+    let output = flags.output.unwrap_or_else(|| PathBuf::from("profile.folded"));
+    let folded: String = stacks.iter().map(|(stack, count)| format!("{} {}\n", stack, count)).collect();
+    fs::write(&output, folded).wrap_err_with(|| format!("Failed to write profile: {}", output.display()))?;
+    println!("Folded {} samples into {} stacks in {}", samples.len(), stacks.len(), output.display());
+
+    Ok(())
+}
+
+struct Sample {
+    task: u64,
+    task_name: Option<String>,
+    /// The kernel's frames and the task's frames, each starting with the innermost.
+    kernel_frames: Vec<u64>,
+    user_frames: Vec<u64>,
+}
+
+/// Parse a sample (`PROFILE {cpu} {task} {task name} kernel {frames...} user {frames...}`) out of a line of a log.
+fn parse_sample(line: &str) -> Option<Sample> {
+    let (_, sample) = line.split_once("PROFILE ")?;
+    let mut fields = sample.split_whitespace();
+    let _cpu: u32 = fields.next()?.parse().ok()?;
+    let task = fields.next()?.parse().ok()?;
+    let task_name = match fields.next()? {
+        "-" => None,
+        name => Some(name.to_string()),
+    };
+    if fields.next()? != "kernel" {
+        return None;
+    }
+
+    let mut kernel_frames = Vec::new();
+    let mut user_frames = None;
+    for field in fields {
+        if field == "user" && user_frames.is_none() {
+            user_frames = Some(Vec::new());
+            continue;
+        }
+        let address = u64::from_str_radix(field.strip_prefix("0x")?, 16).ok()?;
+        user_frames.as_mut().unwrap_or(&mut kernel_frames).push(address);
+    }
+
+    // Lines without the user frames have been cut off, so we don't know what the rest of the stack was
+    Some(Sample { task, task_name, kernel_frames, user_frames: user_frames? })
+}
+
+/// Resolve `frames` (innermost first) using `symbolicator`, and add them to `stack` from the outermost inwards.
+/// The innermost frame is the address of the interrupted instruction, while the rest are return addresses.
+fn resolve_frames(symbolicator: Option<&Symbolicator>, frames: &[u64], stack: &mut Vec<String>) -> Result<()> {
+    for (i, &frame) in frames.iter().enumerate().rev() {
+        let Some(symbolicator) = symbolicator else {
+            stack.push(format!("{:#x}", frame));
+            continue;
+        };
+
+        // Return addresses point to the instruction after the call, which may be in a different function
+        let address = if i == 0 { frame } else { frame.saturating_sub(1) };
+        for location in symbolicator.resolve(address)?.into_iter().rev() {
+            // Frames are separated by semicolons in the folded format, so they can't appear in function names
+            stack.push(location.function.replace(';', ","));
+        }
+    }
+    Ok(())
+}
+
+fn read_elf(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).wrap_err_with(|| format!("Failed to read ELF: {}", path.display()))
+}
+
+fn load_symbolicator(data: &[u8]) -> Result<Symbolicator> {
+    let object = addr2line::object::File::parse(data).map_err(|err| eyre!("Failed to parse ELF: {}", err))?;
+    Symbolicator::new(&object)
+}
+
+/// Find the ELF of the task called `name` in `directory`. Samples only hold the start of a task's name, so if the
+/// name might have been cut off, this looks for an ELF whose name starts with it.
+fn find_task_elf(directory: &Path, name: &str) -> Result<Option<PathBuf>> {
+    let path = directory.join(name);
+    if path.is_file() {
+        return Ok(Some(path));
+    }
+    if name.len() < TASK_NAME_LENGTH {
+        return Ok(None);
+    }
+
+    for entry in fs::read_dir(directory)
+        .wrap_err_with(|| format!("Failed to read task directory: {}", directory.display()))?
+    {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(name) && entry.path().is_file() {
+            return Ok(Some(entry.path()));
+        }
+    }
+    Ok(None)
+}
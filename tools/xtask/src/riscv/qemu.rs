@@ -1,5 +1,5 @@
 use crate::ramdisk::Ramdisk;
-use eyre::{eyre, Result, WrapErr};
+use eyre::{Result, WrapErr};
 use std::{path::PathBuf, process::Command};
 
 pub struct RunQemuRiscV {
@@ -48,6 +48,13 @@ impl RunQemuRiscV {
     }
 
     pub fn run(self) -> Result<()> {
+        let mut qemu = self.command();
+        println!("QEMU command: {:?}", qemu);
+        crate::qemu_result(qemu.status().wrap_err("Failed to invoke qemu-system-riscv")?)
+    }
+
+    /// Build the command to run QEMU with, without running it.
+    pub fn command(self) -> Command {
         let mut qemu = Command::new("qemu-system-riscv64");
 
         /*
@@ -95,11 +102,16 @@ impl RunQemuRiscV {
         qemu.args(&["-global", "virtio-mmio.force-legacy=false"]);
 
         qemu.args(&["-device", "virtio-gpu"]);
+        // Use a modern-only network device, so it has the non-transitional PCI device ID
+        qemu.args(&["-netdev", "user,id=net0"]);
+        qemu.args(&["-device", "virtio-net-pci,netdev=net0,disable-legacy=on"]);
+        // Add a tablet, so the pointer follows the host's cursor
+        qemu.args(&["-device", "virtio-tablet-pci"]);
 
         if let Some(disk_image) = self.disk_image {
-            // Add the disk image as an NVME device
+            // Add the disk image as a modern-only Virtio block device
             qemu.args(&["-drive", &format!("id=disk0,format=raw,if=none,file={}", disk_image.to_str().unwrap())]);
-            qemu.args(&["-device", "virtio-blk-device,drive=disk0"]);
+            qemu.args(&["-device", "virtio-blk-pci,drive=disk0,disable-legacy=on"]);
         }
 
         // Add an EHCI controller and test devices. We use EHCI on RV because hardware we're
@@ -118,11 +130,6 @@ impl RunQemuRiscV {
             qemu.args(&["--trace", &trace]);
         }
 
-        println!("QEMU command: {:?}", qemu);
-        qemu.status()
-            .wrap_err("Failed to invoke qemu-system-riscv")?
-            .success()
-            .then_some(())
-            .ok_or(eyre!("Qemu returned an error code"))
+        qemu
     }
 }
@@ -0,0 +1,132 @@
+//! The kernel and tasks print backtraces as a list of raw return addresses when they panic, as they don't carry
+//! their symbols or debug info around. This maps the addresses back to functions and source locations, using the
+//! ELF that was built for the kernel or task.
+//!
+//! Backtraces are picked out of a log (e.g. a copy of the serial output) by looking for lines containing frames of
+//! the form `#{n}: {address}`. Each of these is printed with the function (and any functions inlined into it) and
+//! source location it's in. Other lines are passed through unchanged, so a whole log can be symbolicated at once.
+//!
+//! The kernel is loaded at a random slide from the address it's linked at, which its crash reports give on a
+//! `CRASH SLIDE {slide}` line. This is subtracted from each frame until the end of the report (`CRASH END`).
+
+use crate::flags::Symbolicate as SymbolicateFlags;
+use addr2line::{object::Object, Context};
+use eyre::{eyre, Result, WrapErr};
+use std::{
+    fmt,
+    fs,
+    io::{self, BufRead},
+};
+
+pub fn symbolicate(flags: SymbolicateFlags) -> Result<()> {
+    let data = fs::read(&flags.elf).wrap_err_with(|| format!("Failed to read ELF: {}", flags.elf.display()))?;
+    let object = addr2line::object::File::parse(&*data).map_err(|err| eyre!("Failed to parse ELF: {}", err))?;
+    let symbolicator = Symbolicator::new(&object)?;
+
+    let input: Box<dyn BufRead> = match flags.log {
+        Some(ref path) => Box::new(io::BufReader::new(
+            fs::File::open(path).wrap_err_with(|| format!("Failed to open log: {}", path.display()))?,
+        )),
+        None => Box::new(io::stdin().lock()),
+    };
+
+    let mut slide = 0;
+    for line in input.lines() {
+        let line = line?;
+        println!("{}", line);
+
+        if let Some(new_slide) = parse_slide(&line) {
+            slide = new_slide;
+        } else if line.contains("CRASH END") {
+            slide = 0;
+        }
+
+        if let Some(address) = parse_frame(&line) {
+            // Return addresses point to the instruction after the call, which may be on a different line
+            for location in symbolicator.resolve(address.wrapping_sub(slide).saturating_sub(1))? {
+                println!("        {}", location);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the slide of the kernel in a `CRASH SLIDE {slide}` line of a log.
+fn parse_slide(line: &str) -> Option<u64> {
+    let (_, slide) = line.split_once("CRASH SLIDE ")?;
+    u64::from_str_radix(slide.trim().strip_prefix("0x")?, 16).ok()
+}
+
+/// Find the address of a backtrace frame (`#{n}: {address}`) in a line of a log.
+fn parse_frame(line: &str) -> Option<u64> {
+    let (_, frame) = line.split_once('#')?;
+    let (index, address) = frame.split_once(':')?;
+    if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let address = address.trim().strip_prefix("0x")?;
+    u64::from_str_radix(address, 16).ok()
+}
+
+pub struct Symbolicator<'a> {
+    context: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    symbols: addr2line::object::SymbolMap<addr2line::object::SymbolMapName<'a>>,
+}
+
+/// A function an address is in, and the source location of the address, if there's debug info for it.
+pub struct Location {
+    pub function: String,
+    pub source: Option<String>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Some(ref source) => write!(f, "{} at {}", self.function, source),
+            None => write!(f, "{}", self.function),
+        }
+    }
+}
+
+impl<'a> Symbolicator<'a> {
+    pub fn new(object: &addr2line::object::File<'a>) -> Result<Symbolicator<'a>> {
+        let context = Context::new(object).map_err(|err| eyre!("Failed to load debug info: {}", err))?;
+        Ok(Symbolicator { context, symbols: object.symbol_map() })
+    }
+
+    /// Resolve an address to the function it's in, and any functions inlined at that point (innermost first). To
+    /// resolve a return address to the call it was pushed by, pass the address before it.
+    pub fn resolve(&self, address: u64) -> Result<Vec<Location>> {
+        let mut locations = Vec::new();
+        let mut frames = self
+            .context
+            .find_frames(address)
+            .skip_all_loads()
+            .map_err(|err| eyre!("Failed to read debug info: {}", err))?;
+        while let Some(frame) = frames.next().map_err(|err| eyre!("Failed to read debug info: {}", err))? {
+            let function = match frame.function {
+                Some(ref function) => function.demangle()?.into_owned(),
+                None => "<unknown>".to_string(),
+            };
+            let source = match frame.location {
+                Some(location) => {
+                    format!("{}:{}", location.file.unwrap_or("<unknown>"), location.line.unwrap_or(0))
+                }
+                None => "<unknown>".to_string(),
+            };
+            locations.push(Location { function, source: Some(source) });
+        }
+
+        // Without debug info, fall back to the symbol table, which at least gives us the function
+        if locations.is_empty() {
+            let function = match self.symbols.get(address) {
+                Some(symbol) => addr2line::demangle_auto(symbol.name().into(), None).into_owned(),
+                None => "<unknown>".to_string(),
+            };
+            locations.push(Location { function, source: None });
+        }
+
+        Ok(locations)
+    }
+}
@@ -0,0 +1,196 @@
+//! `xtask test` builds an image with the kernel's test runner (the kernel's `test_runner` feature) and every task
+//! in a `user/test_*` directory, which are loaded as boot tasks. It boots the image in QEMU without a display, and
+//! follows the serial output for the markers the test runner logs (see `kernel::test_runner`) and for kernel crash
+//! reports. The test runner exits QEMU once every test has finished.
+
+use crate::{
+    aarch64::qemu::RunQemuAarch64,
+    config::{Config, Platform, UserTask},
+    dist::ArtifactType,
+    flags::{DistOptions, Test as TestFlags},
+    riscv::qemu::RunQemuRiscV,
+    x64::qemu::RunQemuX64,
+};
+use colored::Colorize;
+use eyre::{eyre, Result, WrapErr};
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The status QEMU exits with on x86_64 when every test passed. The exit port can't make QEMU exit with `0`,
+/// which is what it exits with on RISC-V and AArch64.
+const X64_TESTS_PASSED_STATUS: i32 = 0x21;
+/// The status QEMU exits with when the test runner finished, but some tests failed.
+const TESTS_FAILED_STATUS: i32 = 0x25;
+
+pub fn test(flags: TestFlags) -> Result<()> {
+    let mut config = Config::new(Some(&DistOptions::from(&flags)));
+    if !config.kernel_features.iter().any(|feature| feature == "test_runner") {
+        config.kernel_features.push("test_runner".to_string());
+    }
+    config.user_tasks.extend(find_test_tasks()?);
+
+    let dist_result = crate::dist(&config)?;
+    let qemu = match config.platform {
+        Platform::X64 => RunQemuX64::new(dist_result.build_disk_image()).command(),
+        Platform::Rv64Virt => RunQemuRiscV::new(
+            dist_result.artifact_by_type(ArtifactType::Bootloader).unwrap().source.clone(),
+            Some(dist_result.build_disk_image()),
+        )
+        .ramdisk(Some(dist_result.build_ramdisk()))
+        .command(),
+        Platform::Aarch64Virt => RunQemuAarch64::new(
+            dist_result.artifact_by_type(ArtifactType::Bootloader).unwrap().source.clone(),
+            Some(dist_result.build_disk_image()),
+        )
+        .ramdisk(Some(dist_result.build_ramdisk()))
+        .command(),
+        other => return Err(eyre!("Platform '{}' does not support running tests in QEMU", other)),
+    };
+
+    println!("{}", "[*] Running tests".bold().magenta());
+    let timeout = flags.timeout.map(Duration::from_secs).unwrap_or(DEFAULT_TIMEOUT);
+    let (results, status) = run(qemu, timeout)?;
+    results.report();
+
+    if results.crashed || status == Some(crate::KERNEL_CRASHED_STATUS) {
+        return Err(eyre!("The kernel crashed while running the tests (see the crash report above)"));
+    }
+    let Some(status) = status else {
+        return Err(eyre!("The tests didn't finish within {} seconds", timeout.as_secs()));
+    };
+    if !results.done {
+        return Err(eyre!("QEMU exited (with status {}) before the tests finished", status));
+    }
+    if !results.failed.is_empty() || status == TESTS_FAILED_STATUS {
+        return Err(eyre!("{} tests failed", results.failed.len()));
+    }
+    if status != 0 && status != X64_TESTS_PASSED_STATUS {
+        return Err(eyre!("QEMU exited with an unexpected status: {}", status));
+    }
+    Ok(())
+}
+
+/// Find the test tasks, which are the crates in `user/` whose names start with `test_`.
+fn find_test_tasks() -> Result<Vec<UserTask>> {
+    let mut tasks = Vec::new();
+    for entry in fs::read_dir("user").wrap_err("Failed to read the user directory")? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if name.starts_with("test_") && path.join("Cargo.toml").exists() {
+            tasks.push(UserTask { name: name.to_string(), source_dir: PathBuf::from("user").join(name) });
+        }
+    }
+    tasks.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(tasks)
+}
+
+/// Run QEMU until it exits, or until `timeout` has passed, in which case it's killed. Serial output is passed
+/// through to our standard output as it's read. Returns the results of the tests, and the status QEMU exited with
+/// (or `None` if it timed out).
+fn run(mut qemu: Command, timeout: Duration) -> Result<(Results, Option<i32>)> {
+    qemu.stdin(Stdio::null()).stdout(Stdio::piped());
+    println!("QEMU command: {:?}", qemu);
+    let mut child = qemu.spawn().wrap_err("Failed to invoke QEMU")?;
+
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            println!("{}", line);
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut results = Results::default();
+    let deadline = Instant::now() + timeout;
+    loop {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => results.parse_line(&line),
+            // QEMU has exited, and closed its standard output
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                child.kill().wrap_err("Failed to kill QEMU")?;
+                child.wait()?;
+                return Ok((results, None));
+            }
+        }
+    }
+
+    let status = child.wait().wrap_err("Failed to wait for QEMU")?;
+    Ok((results, status.code()))
+}
+
+#[derive(Default)]
+struct Results {
+    /// Tests that have started, but haven't passed or failed yet.
+    running: Vec<String>,
+    passed: Vec<String>,
+    /// Tests that have failed, with the reason they failed.
+    failed: Vec<(String, String)>,
+    /// Whether the test runner said it had finished.
+    done: bool,
+    crashed: bool,
+}
+
+impl Results {
+    fn parse_line(&mut self, line: &str) {
+        if line.contains("CRASH BEGIN") {
+            self.crashed = true;
+            return;
+        }
+
+        let Some((_, marker)) = line.split_once("TEST ") else {
+            return;
+        };
+        let (kind, rest) = marker.trim_end().split_once(' ').unwrap_or((marker.trim_end(), ""));
+        let (name, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+        match kind {
+            "BEGIN" => self.running.push(name.to_string()),
+            "PASS" => {
+                self.running.retain(|test| test != name);
+                self.passed.push(name.to_string());
+            }
+            "FAIL" => {
+                self.running.retain(|test| test != name);
+                self.failed.push((name.to_string(), reason.to_string()));
+            }
+            "DONE" => self.done = true,
+            _ => (),
+        }
+    }
+
+    fn report(&self) {
+        println!("{}", "[*] Test results".bold().magenta());
+        for name in &self.passed {
+            println!("{} {}", "PASS".bold().green(), name);
+        }
+        for (name, reason) in &self.failed {
+            println!("{} {}: {}", "FAIL".bold().red(), name, reason);
+        }
+        for name in &self.running {
+            println!("{} {}", "DIDN'T FINISH".bold().yellow(), name);
+        }
+        println!(
+            "{} passed, {} failed, {} didn't finish",
+            self.passed.len(),
+            self.failed.len(),
+            self.running.len()
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! The `trace` task writes the records it captures from the kernel's trace buffers to the serial port, as lines of
+//! the form `TRACE {cpu} {timestamp} {event} {data0} {data1} {data2}`. This picks those lines out of a log and
+//! converts them into Chrome's trace-event format, with a process for each CPU. Each CPU has a thread showing the
+//! task it was running, and a thread showing the interrupts it handled. Channel messages are shown as instant
+//! events on the task thread. The system calls of audited tasks are shown in a separate process, with a thread
+//! for each task, as they can start on one CPU and return on another.
+//!
+//! The event numbers and layout of the data match `poplar::trace::TraceEvent`.
+
+use crate::flags::Trace as TraceFlags;
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, BufRead},
+    path::PathBuf,
+};
+
+const TASK_SWITCH: u32 = 1;
+const TASK_NAME: u32 = 2;
+const INTERRUPT_ENTER: u32 = 3;
+const INTERRUPT_EXIT: u32 = 4;
+const CHANNEL_SEND: u32 = 5;
+const CHANNEL_RECEIVE: u32 = 6;
+const SYSCALL_ENTER: u32 = 7;
+const SYSCALL_EXIT: u32 = 8;
+
+const TASKS_THREAD: u32 = 0;
+const INTERRUPTS_THREAD: u32 = 1;
+/// The process audited tasks' system calls are shown in. This is far above any CPU ID.
+const SYSCALLS_PROCESS: u32 = 0x10000;
+
+pub fn trace(flags: TraceFlags) -> Result<()> {
+    let log = io::BufReader::new(
+        fs::File::open(&flags.log).wrap_err_with(|| format!("Failed to open log: {}", flags.log.display()))?,
+    );
+
+    let mut records = Vec::new();
+    for line in log.lines() {
+        if let Some(record) = parse_record(&line?) {
+            records.push(record);
+        }
+    }
+    records.sort_by_key(|record| (record.cpu, record.timestamp));
+
+    let events = Converter::new(&records).convert(&records);
+    let output = flags.output.unwrap_or_else(|| PathBuf::from("trace.json"));
+    let json = serde_json::to_string(&TraceFile { trace_events: events })?;
+    fs::write(&output, json).wrap_err_with(|| format!("Failed to write trace: {}", output.display()))?;
+    println!("Converted {} records into {}", records.len(), output.display());
+
+    Ok(())
+}
+
+struct Record {
+    cpu: u32,
+    timestamp: u64,
+    event: u32,
+    data: [u64; 3],
+}
+
+/// Parse a record (`TRACE {cpu} {timestamp} {event} {data0} {data1} {data2}`) out of a line of a log.
+fn parse_record(line: &str) -> Option<Record> {
+    let (_, record) = line.split_once("TRACE ")?;
+    let mut fields = record.split_whitespace();
+    let cpu = fields.next()?.parse().ok()?;
+    let timestamp = fields.next()?.parse().ok()?;
+    let event = fields.next()?.parse().ok()?;
+    let mut data = [0; 3];
+    for value in data.iter_mut() {
+        *value = u64::from_str_radix(fields.next()?.strip_prefix("0x")?, 16).ok()?;
+    }
+    Some(Record { cpu, timestamp, event, data })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceFile {
+    trace_events: Vec<Event>,
+}
+
+#[derive(Serialize)]
+struct Event {
+    name: String,
+    ph: &'static str,
+    /// In microseconds.
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: u32,
+    tid: u32,
+    /// The scope of instant events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<&'static str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    args: BTreeMap<&'static str, String>,
+}
+
+impl Event {
+    fn new(name: String, ph: &'static str, timestamp: u64, pid: u32, tid: u32) -> Event {
+        Event { name, ph, ts: micros(timestamp), dur: None, pid, tid, s: None, args: BTreeMap::new() }
+    }
+
+    fn metadata(kind: &str, name: String, pid: u32, tid: u32) -> Event {
+        let mut event = Event::new(kind.to_string(), "M", 0, pid, tid);
+        event.args.insert("name", name);
+        event
+    }
+}
+
+struct Converter {
+    task_names: BTreeMap<u64, String>,
+}
+
+impl Converter {
+    fn new(records: &[Record]) -> Converter {
+        let task_names = records
+            .iter()
+            .filter(|record| record.event == TASK_NAME)
+            .map(|record| (record.data[0], unpack_task_name([record.data[1], record.data[2]])))
+            .collect();
+        Converter { task_names }
+    }
+
+    fn convert(&self, records: &[Record]) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut cpu_records = records;
+        while let Some(first) = cpu_records.first() {
+            let length = cpu_records.iter().take_while(|record| record.cpu == first.cpu).count();
+            let (this_cpu, rest) = cpu_records.split_at(length);
+            self.convert_cpu(first.cpu, this_cpu, &mut events);
+            cpu_records = rest;
+        }
+        self.convert_syscalls(records, &mut events);
+        events
+    }
+
+    /// Convert the records of audited tasks' system calls. A call's records can be on different CPUs, if the task
+    /// blocked in the call and was woken up elsewhere, so these are matched up by task.
+    fn convert_syscalls(&self, records: &[Record], events: &mut Vec<Event>) {
+        let mut syscall_records: Vec<&Record> = records
+            .iter()
+            .filter(|record| record.event == SYSCALL_ENTER || record.event == SYSCALL_EXIT)
+            .collect();
+        if syscall_records.is_empty() {
+            return;
+        }
+        syscall_records.sort_by_key(|record| record.timestamp);
+        events.push(Event::metadata("process_name", "Audited system calls".to_string(), SYSCALLS_PROCESS, 0));
+
+        let mut named_tasks = BTreeSet::new();
+        // The `SyscallEnter` record of the call each task is in
+        let mut in_progress: BTreeMap<u64, &Record> = BTreeMap::new();
+        for record in syscall_records {
+            let (task, number) = unpack_syscall(record.data[0]);
+            if named_tasks.insert(task) {
+                events.push(Event::metadata("thread_name", self.task_name(task), SYSCALLS_PROCESS, task as u32));
+            }
+            if record.event == SYSCALL_ENTER {
+                in_progress.insert(task, record);
+                continue;
+            }
+
+            // Calls that were made before the trace started have no `SyscallEnter` record, so start at the exit
+            let enter = in_progress.remove(&task);
+            let start = enter.map_or(record.timestamp, |enter| enter.timestamp);
+            let name = format!("Syscall {}", number);
+            let mut event = self.slice(name, start, record.timestamp, SYSCALLS_PROCESS, task as u32);
+            if let Some(enter) = enter {
+                event.args.insert("a", format!("{:#x}", enter.data[1]));
+                event.args.insert("b", format!("{:#x}", enter.data[2]));
+            }
+            event.args.insert("result", format!("{:#x}", record.data[1]));
+            events.push(event);
+        }
+
+        // Calls that hadn't returned when the trace was captured (e.g. because the task is still blocked)
+        for (task, enter) in in_progress {
+            let (_, number) = unpack_syscall(enter.data[0]);
+            let mut event =
+                Event::new(format!("Syscall {}", number), "i", enter.timestamp, SYSCALLS_PROCESS, task as u32);
+            event.s = Some("t");
+            event.args.insert("a", format!("{:#x}", enter.data[1]));
+            event.args.insert("b", format!("{:#x}", enter.data[2]));
+            events.push(event);
+        }
+    }
+
+    /// Convert the records from a single CPU, which must be in the order they were written.
+    fn convert_cpu(&self, cpu: u32, records: &[Record], events: &mut Vec<Event>) {
+        events.push(Event::metadata("process_name", format!("CPU {}", cpu), cpu, TASKS_THREAD));
+        events.push(Event::metadata("thread_name", "Tasks".to_string(), cpu, TASKS_THREAD));
+        events.push(Event::metadata("thread_name", "Interrupts".to_string(), cpu, INTERRUPTS_THREAD));
+
+        // The task that's running, and when it started
+        let mut running: Option<(u64, u64)> = None;
+        // The interrupts that are being handled, and when each started
+        let mut interrupts: Vec<(u64, u64)> = Vec::new();
+
+        for record in records {
+            match record.event {
+                TASK_SWITCH => {
+                    if let Some((task, start)) = running {
+                        events.push(self.slice(self.task_name(task), start, record.timestamp, cpu, TASKS_THREAD));
+                    }
+                    running = Some((record.data[1], record.timestamp));
+                }
+                INTERRUPT_ENTER => interrupts.push((record.data[0], record.timestamp)),
+                INTERRUPT_EXIT => {
+                    if let Some((vector, start)) = interrupts.pop() {
+                        let name = format!("Interrupt {:#x}", vector);
+                        events.push(self.slice(name, start, record.timestamp, cpu, INTERRUPTS_THREAD));
+                    }
+                }
+                CHANNEL_SEND | CHANNEL_RECEIVE => {
+                    let name = if record.event == CHANNEL_SEND { "Send" } else { "Receive" };
+                    let mut event = Event::new(name.to_string(), "i", record.timestamp, cpu, TASKS_THREAD);
+                    event.s = Some("t");
+                    event.args.insert("channel", record.data[0].to_string());
+                    event.args.insert("bytes", record.data[1].to_string());
+                    event.args.insert("handles", record.data[2].to_string());
+                    events.push(event);
+                }
+                _ => (),
+            }
+        }
+
+        // Close off the task that was running when tracing stopped
+        if let (Some((task, start)), Some(last)) = (running, records.last()) {
+            events.push(self.slice(self.task_name(task), start, last.timestamp, cpu, TASKS_THREAD));
+        }
+    }
+
+    fn slice(&self, name: String, start: u64, end: u64, pid: u32, tid: u32) -> Event {
+        let mut event = Event::new(name, "X", start, pid, tid);
+        event.dur = Some(micros(end - start));
+        event
+    }
+
+    fn task_name(&self, task: u64) -> String {
+        match self.task_names.get(&task) {
+            Some(name) => format!("{} ({})", name, task),
+            None => format!("Task {}", task),
+        }
+    }
+}
+
+fn micros(nanos: u64) -> f64 {
+    nanos as f64 / 1000.0
+}
+
+/// Unpack the name of a task from the data of a `TaskName` record. This matches `poplar::trace::unpack_task_name`.
+fn unpack_task_name(data: [u64; 2]) -> String {
+    let mut bytes = Vec::with_capacity(16);
+    bytes.extend_from_slice(&data[0].to_le_bytes());
+    bytes.extend_from_slice(&data[1].to_le_bytes());
+    let length = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[0..length]).into_owned()
+}
+
+/// Unpack the ID of a task and the number of a system call from the first item of the data of a `SyscallEnter` or
+/// `SyscallExit` record. This matches `poplar::trace::unpack_syscall`.
+fn unpack_syscall(packed: u64) -> (u64, u64) {
+    (packed & 0xffff_ffff_ffff, packed >> 48)
+}
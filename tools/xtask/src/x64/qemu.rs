@@ -1,6 +1,9 @@
-use eyre::{eyre, Result, WrapErr};
+use eyre::{Result, WrapErr};
 use std::{path::PathBuf, process::Command};
 
+/// The port the kernel's GDB stub is made available on. QEMU's own GDB server uses port `1234`.
+const GDB_STUB_PORT: u16 = 55556;
+
 pub struct RunQemuX64 {
     pub image: PathBuf,
 
@@ -19,6 +22,9 @@ pub struct RunQemuX64 {
     /// Passes `-d cpu` to QEMU. Note that this disables KVM even if `kvm` is set.
     pub debug_cpu_firehose: bool,
     pub trace: Option<String>,
+    /// Connect the kernel's GDB stub, which uses the second serial port, to a socket GDB can attach to. This is
+    /// the path to the kernel's ELF, which is loaded into GDB for its symbols.
+    pub gdb_stub: Option<PathBuf>,
 
     /*
      * Firmware
@@ -30,6 +36,8 @@ pub struct RunQemuX64 {
      * Devices
      */
     pub qemu_exit_device: bool,
+    /// Attach the disk image as a SATA disk on the chipset's AHCI controller, rather than as an NVMe drive.
+    pub sata: bool,
 }
 
 impl RunQemuX64 {
@@ -46,11 +54,13 @@ impl RunQemuX64 {
             debug_mmu_firehose: false,
             debug_cpu_firehose: false,
             trace: None,
+            gdb_stub: None,
 
             ovmf_dir: PathBuf::from("bundled/ovmf/"),
             ovmf_debugcon_to_file: false,
 
             qemu_exit_device: true,
+            sata: false,
         }
     }
 
@@ -74,11 +84,26 @@ impl RunQemuX64 {
         Self { trace, ..self }
     }
 
+    pub fn gdb_stub(self, kernel_elf: Option<PathBuf>) -> Self {
+        Self { gdb_stub: kernel_elf, ..self }
+    }
+
+    pub fn sata(self, sata: bool) -> Self {
+        Self { sata, ..self }
+    }
+
     fn use_kvm(&self) -> bool {
         self.kvm && !(self.debug_int_firehose || self.debug_mmu_firehose || self.debug_cpu_firehose)
     }
 
     pub fn run(self) -> Result<()> {
+        let mut qemu = self.command();
+        println!("Qemu command: {:?}", qemu);
+        crate::qemu_result(qemu.status().wrap_err("Failed to invoke qemu-system-x86_64")?)
+    }
+
+    /// Build the command to run QEMU with, without running it.
+    pub fn command(self) -> Command {
         let mut qemu = Command::new("qemu-system-x86_64");
 
         /*
@@ -117,6 +142,20 @@ impl RunQemuX64 {
         qemu.args(&["-chardev", "stdio,id=char0,logfile=qemu_serial_x64.log"]);
         qemu.args(&["-serial", "chardev:char0"]);
 
+        // The kernel's GDB stub talks over COM2, so this has to be the second serial port
+        if let Some(ref kernel_elf) = self.gdb_stub {
+            qemu.args(&[
+                "-chardev",
+                &format!("socket,id=gdb0,host=127.0.0.1,port={},server=on,wait=off", GDB_STUB_PORT),
+            ]);
+            qemu.args(&["-serial", "chardev:gdb0"]);
+            println!(
+                "The kernel will wait for GDB to connect. Attach with: gdb {} -ex 'target remote localhost:{}'",
+                kernel_elf.display(),
+                GDB_STUB_PORT
+            );
+        }
+
         if !self.open_display {
             qemu.args(&["-display", "none"]);
             // If we're not opening a display, allow connections to the monitor over TCP (open with `nc 127.0.0.1 55555`)
@@ -135,7 +174,9 @@ impl RunQemuX64 {
          * Add hardware.
          * TODO: it would be cool to define devices programmatically, and then have it emit the right config
          */
-        qemu.args(&["-net", "none"]);
+        // QEMU's default NIC for x86_64, which is driven by our `e1000` driver
+        qemu.args(&["-netdev", "user,id=net0"]);
+        qemu.args(&["-device", "e1000,netdev=net0"]);
         if self.qemu_exit_device {
             qemu.args(&["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
         }
@@ -147,6 +188,8 @@ impl RunQemuX64 {
         qemu.args(&["-device", "usb-ehci,id=ehci,bus=pcie.0"]);
         qemu.args(&["-device", "usb-kbd,bus=ehci.0"]);
         qemu.args(&["-device", "usb-mouse,bus=ehci.0"]);
+        // The display is driven by our `virtio_gpu` driver, rather than through the framebuffer from UEFI's GOP
+        qemu.args(&["-device", "virtio-gpu-pci"]);
 
         // XXX: for testing NUMA
         qemu.args(&["-smp", "8"]);
@@ -178,13 +221,15 @@ impl RunQemuX64 {
         /*
          * Add the image to run.
          */
-        qemu.args(&["-drive", &format!("if=ide,format=raw,file={}", self.image.to_str().unwrap())]);
+        qemu.args(&["-drive", &format!("id=disk0,if=none,format=raw,file={}", self.image.to_str().unwrap())]);
+        // The image is attached as an NVMe drive, so it can be used by our `nvme` driver once we've booted, or
+        // to the `q35` machine's AHCI controller, to be used by our `ahci` driver
+        if self.sata {
+            qemu.args(&["-device", "ide-hd,bus=ide.0,drive=disk0"]);
+        } else {
+            qemu.args(&["-device", "nvme,serial=poplar,drive=disk0"]);
+        }
 
-        println!("Qemu command: {:?}", qemu);
-        qemu.status()
-            .wrap_err("Failed to invoke qemu-system-x86_64")?
-            .success()
-            .then_some(())
-            .ok_or(eyre!("Qemu returned an error code"))
+        qemu
     }
 }
@@ -0,0 +1,508 @@
+//! `ahci` is a driver for AHCI SATA controllers (such as the ICH9 controller QEMU's `q35` machine has). Each
+//! SATA disk attached to the controller is provided to other tasks as a block device.
+//!
+//! Each port of the controller has its own command list, and we issue commands to it without Native Command
+//! Queueing, using the `DMA EXT` commands. The controller signals the completion of commands with a single
+//! interrupt for all of its ports.
+
+mod port;
+mod reg;
+
+use crate::{
+    port::{Command, CommandError, Port},
+    reg::{GlobalControl, RegisterBlock, SIGNATURE_ATA},
+};
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use log::{info, warn};
+use platform_bus::{pci::PciConfig, DeviceDriverClient, DeviceHandoffCall, Filter, HandoffInfo, Property};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use spinning_top::{RwSpinlock, Spinlock};
+use std::{
+    poplar::{
+        block::{
+            BlockDeviceInfo,
+            BlockError,
+            BlockRequest,
+            BlockResponse,
+            BLOCK_DEVICE_SERVICE,
+            MAX_TRANSFER_SIZE,
+        },
+        channel::Channel,
+        ddk::dma::{DmaBuffer, DmaPool},
+        early_logger::EarlyLogger,
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+    sync::Arc,
+};
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY_DEVICE: u8 = 0xec;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+const IDENTIFY_DATA_SIZE: usize = 512;
+/// Disks report their logical sector size if it isn't 512 bytes.
+const DEFAULT_SECTOR_SIZE: u32 = 512;
+
+/// How long to wait for the HBA to reset, in milliseconds.
+const RESET_TIMEOUT_MS: u64 = 1000;
+
+/// The number of disks we've provided as block devices, across every controller we drive. Used to give each its
+/// own service name.
+static NUM_BLOCK_DEVICES: AtomicUsize = AtomicUsize::new(0);
+/// Every disk we're serving, so their write caches can be flushed before the system is shut down.
+static DISKS: Spinlock<Vec<DiskClient>> = Spinlock::new(Vec::new());
+
+pub fn map_memory_object(memory_object: MemoryObject) -> MappedMemoryObject {
+    unsafe { memory_object.map().unwrap() }
+}
+
+pub struct Controller {
+    registers: RegisterBlock,
+    /// The ports with a disk attached, along with the number of each.
+    ports: RwSpinlock<Vec<(u8, Arc<Spinlock<Port>>)>>,
+    buffer_pool: Spinlock<DmaPool>,
+}
+
+impl Controller {
+    pub async fn command(&self, port: &Spinlock<Port>, command: Command) -> Result<(), CommandError> {
+        loop {
+            // Don't hold the lock across the `await`, or we'd block the interrupt task from completing the command
+            let future = port.lock().submit(command);
+            match future {
+                Some(future) => return future.await,
+                None => std::poplar::rt::time::sleep(Duration::from_millis(1)).await,
+            }
+        }
+    }
+
+    /// Make an `IDENTIFY DEVICE` command, returning the data it produces.
+    pub async fn identify(&self, port: &Spinlock<Port>) -> Result<DmaBuffer, CommandError> {
+        let mut buffer = self.buffer_pool.lock().create_buffer(IDENTIFY_DATA_SIZE).unwrap();
+        let command =
+            Command { buffer: Some((buffer.phys, buffer.length)), count: 1, ..Command::new(ATA_IDENTIFY_DEVICE) };
+
+        let token = buffer.token().unwrap();
+        let result = self.command(port, command).await;
+        drop(token);
+        result.map(|()| buffer)
+    }
+
+    /// Process the interrupts of every port that has signalled one. Returns the ports that need to be recovered
+    /// after a command failed.
+    pub fn process_interrupts(&self) -> Vec<Arc<Spinlock<Port>>> {
+        let pending = self.registers.interrupt_status();
+        let mut failed = Vec::new();
+        for (number, port) in self.ports.read().iter() {
+            if pending & (1 << number) != 0 && port.lock().process_completions() {
+                failed.push(port.clone());
+            }
+        }
+        self.registers.clear_interrupt_status(pending);
+        failed
+    }
+}
+
+/// Read an ASCII string from the identify data. Each word holds two characters, with the first in the upper
+/// byte, and the string is padded with spaces.
+fn read_string(data: &[u8]) -> String {
+    let bytes: Vec<u8> = data.chunks_exact(2).flat_map(|word| [word[1], word[0]]).collect();
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+fn read_u16(data: &[u8], word: usize) -> u16 {
+    u16::from_le_bytes(data[(word * 2)..(word * 2 + 2)].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], word: usize) -> u32 {
+    u32::from_le_bytes(data[(word * 2)..(word * 2 + 4)].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], word: usize) -> u64 {
+    u64::from_le_bytes(data[(word * 2)..(word * 2 + 8)].try_into().unwrap())
+}
+
+/// Reset the HBA, which resets every port, and put it into AHCI mode.
+async fn reset(registers: &RegisterBlock) -> Result<(), ()> {
+    unsafe {
+        registers.write_global_control(GlobalControl::AHCI_ENABLE);
+        registers.write_global_control(GlobalControl::AHCI_ENABLE | GlobalControl::RESET);
+    }
+
+    let mut waited_ms = 0;
+    while registers.read_global_control().contains(GlobalControl::RESET) {
+        if waited_ms >= RESET_TIMEOUT_MS {
+            return Err(());
+        }
+        std::poplar::rt::time::sleep(Duration::from_millis(1)).await;
+        waited_ms += 1;
+    }
+
+    // The reset clears `AHCI_ENABLE`, so it needs to be set again
+    unsafe {
+        registers.write_global_control(GlobalControl::AHCI_ENABLE);
+    }
+    Ok(())
+}
+
+async fn drive_controller(handoff_info: HandoffInfo, service_host_client: Arc<ServiceHostClient>) {
+    let registers = {
+        let bar = MemoryObject {
+            handle: handoff_info.get_as_memory_object("pci.bar5.handle").unwrap(),
+            size: handoff_info.get_as_integer("pci.bar5.size").unwrap() as usize,
+            flags: MemoryObjectFlags::WRITABLE,
+            phys_address: None,
+        };
+        // The registers are accessed through `RegisterBlock`, so we don't need to keep the mapping around
+        RegisterBlock::new(map_memory_object(bar).mapped_at)
+    };
+    let Some(interrupt_event) = handoff_info.get_pci_interrupt(0) else {
+        warn!("AHCI controller does not have an interrupt");
+        return;
+    };
+
+    if let Some(pci_config) = handoff_info.get_as_channel("pci.config").map(PciConfig::new) {
+        if let Err(err) = pci_config.enable_bus_mastering().await {
+            warn!("Failed to enable bus mastering for AHCI controller: {:?}", err);
+        }
+    }
+
+    if reset(&registers).await.is_err() {
+        warn!("AHCI controller failed to reset");
+        return;
+    }
+
+    let capabilities = registers.capabilities();
+    let (major, minor) = registers.version();
+    info!(
+        "AHCI controller version {}.{:02x}: {} command slots, ports implemented = {:#b}",
+        major,
+        minor,
+        capabilities.num_command_slots(),
+        registers.ports_implemented()
+    );
+    if capabilities.supports_staggered_spin_up() {
+        // TODO: spin up each port's device before we try to use it
+        warn!("AHCI controller supports staggered spin-up, which we don't support. Disks may not be detected.");
+    }
+
+    let dma_constraints = if capabilities.supports_64_bit_addressing() {
+        DmaConstraints::NONE
+    } else {
+        DmaConstraints::ADDRESS_32_BIT
+    };
+    let dma_constraints = match handoff_info.get_as_dma_domain("pci.dma_domain") {
+        Some(domain) => dma_constraints.in_domain(domain),
+        None => dma_constraints,
+    };
+
+    let buffer_pool = {
+        const BUFFER_POOL_SIZE: usize = 0x8000;
+        let (memory_object, _) =
+            MemoryObject::create_dma(BUFFER_POOL_SIZE, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        DmaPool::new(map_memory_object(memory_object))
+    };
+
+    let controller = Arc::new(Controller {
+        registers,
+        ports: RwSpinlock::new(Vec::new()),
+        buffer_pool: Spinlock::new(buffer_pool),
+    });
+
+    /*
+     * Spawn a task to complete commands when the controller signals us. Ports that have a failed command are
+     * recovered here, once the interrupt has been acknowledged.
+     */
+    {
+        let controller = controller.clone();
+        std::poplar::rt::spawn(async move {
+            loop {
+                interrupt_event.wait_for_event().await;
+                let failed = controller.process_interrupts();
+                interrupt_event.ack_interrupt();
+
+                for port in failed {
+                    port::recover(&port).await;
+                }
+            }
+        });
+    }
+
+    /*
+     * Set up each port with a disk attached, and then enable interrupts from the HBA.
+     */
+    let ports_implemented = controller.registers.ports_implemented();
+    for number in (0..32).filter(|number| ports_implemented & (1 << number) != 0) {
+        let port_registers = controller.registers.port(number);
+        if !port_registers.device_present() {
+            continue;
+        }
+        if port_registers.signature() != SIGNATURE_ATA {
+            info!("Ignoring device on AHCI port {} with signature {:#x}", number, port_registers.signature());
+            continue;
+        }
+
+        match Port::new(port_registers, capabilities.num_command_slots(), dma_constraints).await {
+            Some(port) => controller.ports.write().push((number, Arc::new(Spinlock::new(port)))),
+            None => warn!("Failed to start AHCI port {}", number),
+        }
+    }
+    unsafe {
+        controller.registers.write_global_control(GlobalControl::AHCI_ENABLE | GlobalControl::INTERRUPT_ENABLE);
+    }
+
+    let ports = controller.ports.read().clone();
+    for (number, port) in ports {
+        let disk = match controller.identify(&port).await {
+            Ok(data) => Disk::from_identify_data(number, data.read()),
+            Err(err) => {
+                warn!("Failed to identify disk on AHCI port {}: {:?}", number, err);
+                continue;
+            }
+        };
+        info!(
+            "Disk on AHCI port {}: {} (serial number {}, firmware {}). Write cache: {}",
+            number, disk.model, disk.serial_number, disk.firmware, disk.write_cache
+        );
+        if !disk.supports_lba48 {
+            warn!("Disk on AHCI port {} does not support 48-bit LBAs. Ignoring it.", number);
+            continue;
+        }
+        if disk.block_size as usize > MAX_TRANSFER_SIZE {
+            warn!("Disk on AHCI port {} has unsupported block size of {} bytes", number, disk.block_size);
+            continue;
+        }
+
+        serve_disk(controller.clone(), port, disk, &service_host_client);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Disk {
+    port: u8,
+    model: String,
+    serial_number: String,
+    firmware: String,
+    block_size: u32,
+    num_blocks: u64,
+    supports_lba48: bool,
+    /// Whether the disk has a volatile write cache enabled. If it doesn't, there's no need to flush.
+    write_cache: bool,
+}
+
+impl Disk {
+    fn from_identify_data(port: u8, data: &[u8]) -> Disk {
+        /*
+         * Word 106 says whether the logical sector size is given in words 117-118 (if bit 14 is set and bit 15
+         * is clear, the word is valid).
+         */
+        let sector_size_info = read_u16(data, 106);
+        let block_size = if sector_size_info & 0xc000 == 0x4000 && sector_size_info & (1 << 12) != 0 {
+            read_u32(data, 117) * 2
+        } else {
+            DEFAULT_SECTOR_SIZE
+        };
+
+        Disk {
+            port,
+            model: read_string(&data[54..94]),
+            serial_number: read_string(&data[20..40]),
+            firmware: read_string(&data[46..54]),
+            block_size,
+            num_blocks: read_u64(data, 100),
+            supports_lba48: read_u16(data, 83) & (1 << 10) != 0,
+            write_cache: read_u16(data, 85) & (1 << 5) != 0,
+        }
+    }
+}
+
+/// A client's view of a disk. Commands from every client of a disk are issued to the same port.
+#[derive(Clone)]
+struct DiskClient {
+    controller: Arc<Controller>,
+    port: Arc<Spinlock<Port>>,
+    disk: Arc<Disk>,
+}
+
+impl DiskClient {
+    async fn handle_request(&self, request: BlockRequest) -> BlockResponse {
+        let result = match request {
+            BlockRequest::GetInfo => Ok(BlockResponse::Info(BlockDeviceInfo {
+                block_size: self.disk.block_size,
+                num_blocks: self.disk.num_blocks,
+                read_only: false,
+            })),
+            BlockRequest::Read { block, count } => self.read(block, count).await.map(BlockResponse::Data),
+            BlockRequest::Write { block, data } => self.write(block, &data).await.map(|()| BlockResponse::Done),
+            BlockRequest::Flush => self.flush().await.map(|()| BlockResponse::Done),
+        };
+        result.unwrap_or_else(BlockResponse::Error)
+    }
+
+    async fn read(&self, block: u64, count: u32) -> Result<Vec<u8>, BlockError> {
+        let length = self.check_range(block, count as usize * self.disk.block_size as usize)?;
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let mut buffer =
+            self.controller.buffer_pool.lock().create_buffer(length).map_err(|()| BlockError::DeviceError)?;
+        self.transfer(ATA_READ_DMA_EXT, block, count, &mut buffer).await?;
+        Ok(buffer.read().to_vec())
+    }
+
+    async fn write(&self, block: u64, data: &[u8]) -> Result<(), BlockError> {
+        if data.len() % self.disk.block_size as usize != 0 {
+            return Err(BlockError::InvalidLength);
+        }
+        let length = self.check_range(block, data.len())?;
+        if length == 0 {
+            return Ok(());
+        }
+        let mut buffer =
+            self.controller.buffer_pool.lock().create_buffer(length).map_err(|()| BlockError::DeviceError)?;
+        buffer.write().copy_from_slice(data);
+        let count = (length / self.disk.block_size as usize) as u32;
+        self.transfer(ATA_WRITE_DMA_EXT, block, count, &mut buffer).await
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        if !self.disk.write_cache {
+            return Ok(());
+        }
+        self.controller.command(&self.port, Command::new(ATA_FLUSH_CACHE_EXT)).await.map_err(|err| {
+            warn!("AHCI flush failed: {:?}", err);
+            BlockError::DeviceError
+        })
+    }
+
+    async fn transfer(
+        &self,
+        command: u8,
+        block: u64,
+        count: u32,
+        buffer: &mut DmaBuffer,
+    ) -> Result<(), BlockError> {
+        let command = Command {
+            lba: block,
+            count: count as u16,
+            buffer: Some((buffer.phys, buffer.length)),
+            write: command == ATA_WRITE_DMA_EXT,
+            ..Command::new(command)
+        };
+
+        let token = buffer.token().unwrap();
+        let result = self.controller.command(&self.port, command).await;
+        drop(token);
+        result.map_err(|err| {
+            warn!("AHCI command {:#x} to block {} failed: {:?}", command.command, block, err);
+            BlockError::DeviceError
+        })
+    }
+
+    /// Check that a transfer of `length` bytes, starting at `block`, can be made. Returns the length.
+    fn check_range(&self, block: u64, length: usize) -> Result<usize, BlockError> {
+        if length > MAX_TRANSFER_SIZE {
+            return Err(BlockError::TooLarge);
+        }
+        let end =
+            block.checked_add((length / self.disk.block_size as usize) as u64).ok_or(BlockError::OutOfRange)?;
+        if end > self.disk.num_blocks {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(length)
+    }
+}
+
+/// Provide a disk to other tasks as a block device. The first is provided as `block.device`, and any more as
+/// `block.device.1`, `block.device.2`, etc.
+fn serve_disk(
+    controller: Arc<Controller>,
+    port: Arc<Spinlock<Port>>,
+    disk: Disk,
+    service_host_client: &ServiceHostClient,
+) {
+    let service_name = match NUM_BLOCK_DEVICES.fetch_add(1, Ordering::Relaxed) {
+        0 => BLOCK_DEVICE_SERVICE.to_string(),
+        n => format!("{}.{}", BLOCK_DEVICE_SERVICE, n),
+    };
+    info!(
+        "Providing disk on AHCI port {} ({} blocks of {} bytes) as '{}'",
+        disk.port, disk.num_blocks, disk.block_size, service_name
+    );
+
+    let disk = Arc::new(disk);
+    DISKS.lock().push(DiskClient { controller: controller.clone(), port: port.clone(), disk: disk.clone() });
+    let service_channel = service_host_client.register_service(service_name).unwrap();
+    std::poplar::rt::spawn(async move {
+        loop {
+            match service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    info!("Task '{}' is using disk on AHCI port {}", name, disk.port);
+                    let channel: Channel<BlockResponse, BlockRequest> = Channel::new_from_handle(channel);
+
+                    let client =
+                        DiskClient { controller: controller.clone(), port: port.clone(), disk: disk.clone() };
+                    std::poplar::rt::spawn(async move {
+                        loop {
+                            let request = channel.receive().await.unwrap();
+                            let response = client.handle_request(request).await;
+                            channel.send(&response).unwrap();
+                        }
+                    });
+                }
+            }
+        }
+    });
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("AHCI driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = Arc::new(ServiceHostClient::new());
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    // AHCI controllers have a class code of `0x010601` (Mass Storage Controller, SATA, AHCI 1.0)
+    let handoff_server = platform_bus_device_client
+        .register(vec![
+            Filter::Matches(String::from("pci.class"), Property::Integer(0x01)),
+            Filter::Matches(String::from("pci.sub_class"), Property::Integer(0x06)),
+            Filter::Matches(String::from("pci.interface"), Property::Integer(0x01)),
+        ])
+        .unwrap();
+
+    std::poplar::rt::spawn(async move {
+        loop {
+            match handoff_server.next().await.unwrap() {
+                DeviceHandoffCall::QuerySupport { responder, .. } => {
+                    responder.reply(true).unwrap();
+                }
+                DeviceHandoffCall::HandoffDevice { name, handoff_info, .. } => {
+                    info!("Started driving AHCI controller: {}", name);
+                    std::poplar::rt::spawn(drive_controller(handoff_info, service_host_client.clone()));
+                }
+                DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                    let disks = DISKS.lock().clone();
+                    for disk in disks {
+                        if disk.flush().await.is_err() {
+                            warn!("Failed to flush disk on AHCI port {} before shutdown", disk.disk.port);
+                        }
+                    }
+                    responder.reply(()).unwrap();
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
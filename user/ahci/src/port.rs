@@ -0,0 +1,301 @@
+use crate::{
+    reg::{PortCommand, PortInterrupts, PortRegisters, TaskFile},
+    PAGE_SIZE,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Poll, Waker},
+    time::Duration,
+};
+use spinning_top::Spinlock;
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+    sync::Arc,
+};
+
+/*
+ * Each port's structures live in a single memory object, laid out like so:
+ *    - The command list, which has a 32-byte header for each command slot
+ *    - The received FIS area, where the HBA copies FISes sent to us by the device
+ *    - A command table for each command slot, which holds the command FIS and the PRDT
+ */
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLES_OFFSET: usize = 0x800;
+const COMMAND_TABLE_SIZE: usize = 0x100;
+const MAX_COMMAND_SLOTS: usize = 32;
+const PORT_MEMORY_SIZE: usize = COMMAND_TABLES_OFFSET + MAX_COMMAND_SLOTS * COMMAND_TABLE_SIZE;
+
+/// The offset of the Physical Region Descriptor Table within a command table.
+const PRDT_OFFSET: usize = 0x80;
+
+const FIS_TYPE_REGISTER_HOST_TO_DEVICE: u8 = 0x27;
+/// The length of a Register Host to Device FIS, in dwords.
+const REGISTER_FIS_LENGTH: u32 = 5;
+
+/// How long to wait for the port's DMA engines to stop or start, or for the device to become ready, in
+/// milliseconds.
+const PORT_TIMEOUT_MS: u64 = 500;
+
+/// An ATA command, which we send to the device in a Register Host to Device FIS. We only use commands that take
+/// 48-bit LBAs.
+#[derive(Clone, Copy, Debug)]
+pub struct Command {
+    pub command: u8,
+    pub lba: u64,
+    pub count: u16,
+    /// The physical address and length of the buffer to transfer data to or from, if the command transfers
+    /// data.
+    pub buffer: Option<(usize, usize)>,
+    /// Whether data is transferred to the device, rather than from it.
+    pub write: bool,
+}
+
+impl Command {
+    pub fn new(command: u8) -> Command {
+        Command { command, lba: 0, count: 0, buffer: None, write: false }
+    }
+}
+
+/// The ATA Status and Error registers reported by the device when a command failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CommandError {
+    pub status: u8,
+    pub error: u8,
+}
+
+struct CommandState {
+    result: Option<Result<(), CommandError>>,
+    waker: Option<Waker>,
+}
+
+pub struct CommandFuture(Arc<Spinlock<CommandState>>);
+
+impl Future for CommandFuture {
+    type Output = Result<(), CommandError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0.lock();
+        match state.result {
+            Some(result) => Poll::Ready(result),
+            None => {
+                // Replace the waker each time, as the future can move between tasks on the executor
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A port of the HBA with a SATA device attached to it. We issue commands to the port's command slots without
+/// Native Command Queueing, so the device processes them one at a time, but we can still have a command in
+/// every slot in flight.
+pub struct Port {
+    pub registers: PortRegisters,
+    memory: MappedMemoryObject,
+    num_slots: u8,
+    in_flight: BTreeMap<u8, Arc<Spinlock<CommandState>>>,
+    /// Set while the port is being recovered after a command fails. Commands can't be issued until the port is
+    /// restarted.
+    recovering: bool,
+}
+
+impl Port {
+    /// Set up a port to issue commands to its device. This should only be called on ports with a device
+    /// present.
+    pub async fn new(registers: PortRegisters, num_slots: u8, dma_constraints: DmaConstraints) -> Option<Port> {
+        stop(registers).await.ok()?;
+
+        /*
+         * DMA memory is zeroed, so none of the command headers point to a command table until we fill them in.
+         */
+        let size = mulch::math::align_up(PORT_MEMORY_SIZE, PAGE_SIZE);
+        let (memory_object, _) =
+            MemoryObject::create_dma(size, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        let memory = crate::map_memory_object(memory_object);
+        let phys = memory.inner.phys_address.unwrap() as u64;
+        unsafe {
+            registers.set_bases(phys + COMMAND_LIST_OFFSET as u64, phys + RECEIVED_FIS_OFFSET as u64);
+        }
+
+        start(registers).await.ok()?;
+        registers.enable_interrupts(
+            PortInterrupts::DEVICE_TO_HOST_REGISTER_FIS | PortInterrupts::PIO_SETUP_FIS | PortInterrupts::ERRORS,
+        );
+
+        Some(Port {
+            registers,
+            memory,
+            num_slots: num_slots.min(MAX_COMMAND_SLOTS as u8),
+            in_flight: BTreeMap::new(),
+            recovering: false,
+        })
+    }
+
+    /// Issue a command to the device. Returns `None` if there are no free command slots, or if the port is
+    /// being recovered.
+    pub fn submit(&mut self, command: Command) -> Option<CommandFuture> {
+        if self.recovering {
+            return None;
+        }
+        let issued = self.registers.commands_issued();
+        let slot =
+            (0..self.num_slots).find(|&slot| !self.in_flight.contains_key(&slot) && issued & (1 << slot) == 0)?;
+
+        let table_offset = COMMAND_TABLES_OFFSET + slot as usize * COMMAND_TABLE_SIZE;
+        let table_phys = self.memory.inner.phys_address.unwrap() as u64 + table_offset as u64;
+
+        /*
+         * Build the command FIS.
+         */
+        let mut fis = [0u8; 64];
+        fis[0] = FIS_TYPE_REGISTER_HOST_TO_DEVICE;
+        // Mark the FIS as updating the Command register, rather than the Device Control register
+        fis[1] = 1 << 7;
+        fis[2] = command.command;
+        fis[4] = command.lba as u8;
+        fis[5] = (command.lba >> 8) as u8;
+        fis[6] = (command.lba >> 16) as u8;
+        // Use LBA addressing
+        fis[7] = 1 << 6;
+        fis[8] = (command.lba >> 24) as u8;
+        fis[9] = (command.lba >> 32) as u8;
+        fis[10] = (command.lba >> 40) as u8;
+        fis[12] = command.count as u8;
+        fis[13] = (command.count >> 8) as u8;
+
+        /*
+         * Describe the data buffer with a single entry of the PRDT. Entries can describe up to 4MiB of
+         * physically-contiguous memory, and the byte count is 0-based.
+         */
+        let num_prd_entries = match command.buffer {
+            Some((phys, length)) => {
+                assert!(phys % 2 == 0 && length % 2 == 0, "AHCI data buffers must be word-aligned");
+                let prd = [phys as u32, (phys as u64 >> 32) as u32, 0, (length - 1) as u32];
+                unsafe {
+                    std::ptr::write_volatile(self.ptr_at::<[u32; 4]>(table_offset + PRDT_OFFSET), prd);
+                }
+                1
+            }
+            None => 0,
+        };
+
+        let write_flag = if command.write { 1 << 6 } else { 0 };
+        let flags = REGISTER_FIS_LENGTH | write_flag | num_prd_entries << 16;
+        let header = [flags, 0, table_phys as u32, (table_phys >> 32) as u32, 0, 0, 0, 0];
+
+        let state = Arc::new(Spinlock::new(CommandState { result: None, waker: None }));
+        self.in_flight.insert(slot, state.clone());
+
+        unsafe {
+            std::ptr::write_volatile(self.ptr_at::<[u8; 64]>(table_offset), fis);
+            std::ptr::write_volatile(self.ptr_at::<[u32; 8]>(COMMAND_LIST_OFFSET + slot as usize * 32), header);
+        }
+        self.registers.issue_command(slot);
+
+        Some(CommandFuture(state))
+    }
+
+    /// Process the port's interrupts, completing any commands the device has finished. Returns `true` if a
+    /// command failed, in which case the port needs to be recovered with `recover` before more commands can be
+    /// issued.
+    pub fn process_completions(&mut self) -> bool {
+        let status = self.registers.read_interrupt_status();
+        self.registers.clear_interrupt_status(status);
+
+        let issued = self.registers.commands_issued();
+        let completed: Vec<u8> = self.in_flight.keys().copied().filter(|slot| issued & (1 << slot) == 0).collect();
+        for slot in completed {
+            let state = self.in_flight.remove(&slot).unwrap();
+            complete(&state, Ok(()));
+        }
+
+        if status.intersects(PortInterrupts::ERRORS) {
+            /*
+             * When a command fails, the HBA stops processing the command list, so every command that's still in
+             * flight fails too.
+             */
+            let error = CommandError {
+                status: self.registers.task_file().bits() as u8,
+                error: self.registers.task_file_error(),
+            };
+            for (_, state) in core::mem::take(&mut self.in_flight) {
+                complete(&state, Err(error));
+            }
+            self.recovering = true;
+            return true;
+        }
+        false
+    }
+
+    fn ptr_at<T>(&self, offset: usize) -> *mut T {
+        (self.memory.ptr() as usize + offset) as *mut T
+    }
+}
+
+fn complete(state: &Spinlock<CommandState>, result: Result<(), CommandError>) {
+    let mut state = state.lock();
+    state.result = Some(result);
+    if let Some(waker) = state.waker.take() {
+        waker.wake();
+    }
+}
+
+/// Recover a port after a command has failed, by restarting it. This clears the error, and allows commands to be
+/// issued again.
+pub async fn recover(port: &Spinlock<Port>) {
+    let registers = port.lock().registers;
+    if stop(registers).await.is_err() || start(registers).await.is_err() {
+        log::warn!("Failed to recover AHCI port after an error. Commands will no longer be issued to it.");
+        return;
+    }
+    port.lock().recovering = false;
+}
+
+/// Stop the port's command list and FIS receive DMA engines. This must be done before the port's structures can
+/// be changed.
+async fn stop(registers: PortRegisters) -> Result<(), ()> {
+    let command = registers.read_command();
+    unsafe {
+        registers.write_command(command.difference(PortCommand::START));
+    }
+    wait_for(|| !registers.read_command().contains(PortCommand::COMMAND_LIST_RUNNING)).await?;
+
+    unsafe {
+        registers.write_command(registers.read_command().difference(PortCommand::FIS_RECEIVE_ENABLE));
+    }
+    wait_for(|| !registers.read_command().contains(PortCommand::FIS_RECEIVE_RUNNING)).await
+}
+
+/// Start processing the port's command list. Any errors are cleared first, and we wait for the device to become
+/// ready to accept commands.
+async fn start(registers: PortRegisters) -> Result<(), ()> {
+    registers.clear_errors();
+    registers.clear_interrupt_status(PortInterrupts::all());
+
+    unsafe {
+        registers.write_command(registers.read_command() | PortCommand::FIS_RECEIVE_ENABLE);
+    }
+    wait_for(|| !registers.task_file().intersects(TaskFile::BUSY | TaskFile::DATA_REQUEST)).await?;
+    unsafe {
+        registers.write_command(registers.read_command() | PortCommand::START);
+    }
+    Ok(())
+}
+
+async fn wait_for(condition: impl Fn() -> bool) -> Result<(), ()> {
+    let mut waited_ms = 0;
+    while !condition() {
+        if waited_ms >= PORT_TIMEOUT_MS {
+            return Err(());
+        }
+        std::poplar::rt::time::sleep(Duration::from_millis(1)).await;
+        waited_ms += 1;
+    }
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use bitflags::bitflags;
+
+/// The HBA's registers, which are found in BAR5 (the AHCI Base Address). The generic host control registers are
+/// followed by a block of registers for each port.
+pub struct RegisterBlock {
+    base: usize,
+}
+
+impl RegisterBlock {
+    pub fn new(base: usize) -> RegisterBlock {
+        RegisterBlock { base }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities(unsafe { self.read_register(Register::Capabilities) })
+    }
+
+    pub fn version(&self) -> (u16, u16) {
+        let version = unsafe { self.read_register(Register::Version) };
+        ((version >> 16) as u16, version as u16)
+    }
+
+    pub fn read_global_control(&self) -> GlobalControl {
+        GlobalControl::from_bits_retain(unsafe { self.read_register(Register::GlobalControl) })
+    }
+
+    pub unsafe fn write_global_control(&self, value: GlobalControl) {
+        unsafe {
+            self.write_register(Register::GlobalControl, value.bits());
+        }
+    }
+
+    /// Get the ports that have a pending interrupt. Each bit is cleared by writing `1` to it, once the port's
+    /// own interrupt status has been cleared.
+    pub fn interrupt_status(&self) -> u32 {
+        unsafe { self.read_register(Register::InterruptStatus) }
+    }
+
+    pub fn clear_interrupt_status(&self, ports: u32) {
+        unsafe {
+            self.write_register(Register::InterruptStatus, ports);
+        }
+    }
+
+    /// Get the ports that are exposed by the HBA, as a bitmap.
+    pub fn ports_implemented(&self) -> u32 {
+        unsafe { self.read_register(Register::PortsImplemented) }
+    }
+
+    pub fn port(&self, port: u8) -> PortRegisters {
+        PortRegisters { base: self.base + PORT_REGISTERS_BASE + port as usize * PORT_REGISTERS_SIZE }
+    }
+
+    unsafe fn read_register(&self, reg: Register) -> u32 {
+        unsafe { std::ptr::read_volatile((self.base + reg as usize) as *const u32) }
+    }
+
+    unsafe fn write_register(&self, reg: Register, value: u32) {
+        unsafe {
+            std::ptr::write_volatile((self.base + reg as usize) as *mut u32, value);
+        }
+    }
+}
+
+const PORT_REGISTERS_BASE: usize = 0x100;
+const PORT_REGISTERS_SIZE: usize = 0x80;
+
+#[repr(usize)]
+#[derive(Clone, Copy)]
+enum Register {
+    Capabilities = 0x00,
+    GlobalControl = 0x04,
+    InterruptStatus = 0x08,
+    PortsImplemented = 0x0c,
+    Version = 0x10,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub fn num_command_slots(&self) -> u8 {
+        ((self.0 >> 8) & 0x1f) as u8 + 1
+    }
+
+    pub fn supports_64_bit_addressing(&self) -> bool {
+        self.0 & (1 << 31) != 0
+    }
+
+    pub fn supports_staggered_spin_up(&self) -> bool {
+        self.0 & (1 << 27) != 0
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct GlobalControl: u32 {
+        const RESET = 1 << 0;
+        const INTERRUPT_ENABLE = 1 << 1;
+        const AHCI_ENABLE = 1 << 31;
+    }
+}
+
+/// The registers of a single port.
+#[derive(Clone, Copy)]
+pub struct PortRegisters {
+    base: usize,
+}
+
+impl PortRegisters {
+    /// Set the physical addresses of the port's command list and received FIS area. Can only be done while the
+    /// port is idle.
+    pub unsafe fn set_bases(&self, command_list: u64, received_fis: u64) {
+        unsafe {
+            self.write_register(PortRegister::CommandListBase, command_list as u32);
+            self.write_register(PortRegister::CommandListBaseUpper, (command_list >> 32) as u32);
+            self.write_register(PortRegister::FisBase, received_fis as u32);
+            self.write_register(PortRegister::FisBaseUpper, (received_fis >> 32) as u32);
+        }
+    }
+
+    pub fn read_interrupt_status(&self) -> PortInterrupts {
+        PortInterrupts::from_bits_retain(unsafe { self.read_register(PortRegister::InterruptStatus) })
+    }
+
+    /// Clear the given interrupt status bits, which are write-one-to-clear.
+    pub fn clear_interrupt_status(&self, value: PortInterrupts) {
+        unsafe {
+            self.write_register(PortRegister::InterruptStatus, value.bits());
+        }
+    }
+
+    pub fn enable_interrupts(&self, value: PortInterrupts) {
+        unsafe {
+            self.write_register(PortRegister::InterruptEnable, value.bits());
+        }
+    }
+
+    pub fn read_command(&self) -> PortCommand {
+        PortCommand::from_bits_retain(unsafe { self.read_register(PortRegister::Command) })
+    }
+
+    pub unsafe fn write_command(&self, value: PortCommand) {
+        unsafe {
+            self.write_register(PortRegister::Command, value.bits());
+        }
+    }
+
+    pub fn task_file(&self) -> TaskFile {
+        TaskFile::from_bits_retain(unsafe { self.read_register(PortRegister::TaskFileData) } & 0xff)
+    }
+
+    /// The ATA Error register of the device, as last reported to the port. Only meaningful if the Task File
+    /// has `ERROR` set.
+    pub fn task_file_error(&self) -> u8 {
+        (unsafe { self.read_register(PortRegister::TaskFileData) } >> 8) as u8
+    }
+
+    /// The signature of the attached device, from the first D2H Register FIS it sent.
+    pub fn signature(&self) -> u32 {
+        unsafe { self.read_register(PortRegister::Signature) }
+    }
+
+    /// Whether a device is present and communication with it has been established.
+    pub fn device_present(&self) -> bool {
+        let status = unsafe { self.read_register(PortRegister::SataStatus) };
+        let detection = status & 0xf;
+        let power_management = (status >> 8) & 0xf;
+        detection == 0x3 && power_management == 0x1
+    }
+
+    /// Clear any errors recorded in the SATA Error register, which is write-one-to-clear.
+    pub fn clear_errors(&self) {
+        unsafe {
+            self.write_register(PortRegister::SataError, u32::MAX);
+        }
+    }
+
+    /// Get the command slots that have been issued, and not yet completed, as a bitmap.
+    pub fn commands_issued(&self) -> u32 {
+        unsafe { self.read_register(PortRegister::CommandIssue) }
+    }
+
+    pub fn issue_command(&self, slot: u8) {
+        unsafe {
+            self.write_register(PortRegister::CommandIssue, 1 << slot);
+        }
+    }
+
+    unsafe fn read_register(&self, reg: PortRegister) -> u32 {
+        unsafe { std::ptr::read_volatile((self.base + reg as usize) as *const u32) }
+    }
+
+    unsafe fn write_register(&self, reg: PortRegister, value: u32) {
+        unsafe {
+            std::ptr::write_volatile((self.base + reg as usize) as *mut u32, value);
+        }
+    }
+}
+
+#[repr(usize)]
+#[derive(Clone, Copy)]
+enum PortRegister {
+    CommandListBase = 0x00,
+    CommandListBaseUpper = 0x04,
+    FisBase = 0x08,
+    FisBaseUpper = 0x0c,
+    InterruptStatus = 0x10,
+    InterruptEnable = 0x14,
+    Command = 0x18,
+    TaskFileData = 0x20,
+    Signature = 0x24,
+    SataStatus = 0x28,
+    SataError = 0x30,
+    CommandIssue = 0x38,
+}
+
+/// The signature of a device that uses the ATA command set (i.e. a SATA disk, rather than an ATAPI device).
+pub const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct PortCommand: u32 {
+        const START = 1 << 0;
+        const SPIN_UP_DEVICE = 1 << 1;
+        const POWER_ON_DEVICE = 1 << 2;
+        const FIS_RECEIVE_ENABLE = 1 << 4;
+        const FIS_RECEIVE_RUNNING = 1 << 14;
+        const COMMAND_LIST_RUNNING = 1 << 15;
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct PortInterrupts: u32 {
+        const DEVICE_TO_HOST_REGISTER_FIS = 1 << 0;
+        const PIO_SETUP_FIS = 1 << 1;
+        const DMA_SETUP_FIS = 1 << 2;
+        const SET_DEVICE_BITS_FIS = 1 << 3;
+        const DESCRIPTOR_PROCESSED = 1 << 5;
+        const INTERFACE_FATAL_ERROR = 1 << 27;
+        const HOST_BUS_DATA_ERROR = 1 << 28;
+        const HOST_BUS_FATAL_ERROR = 1 << 29;
+        const TASK_FILE_ERROR = 1 << 30;
+
+        const ERRORS = Self::INTERFACE_FATAL_ERROR.bits()
+            | Self::HOST_BUS_DATA_ERROR.bits()
+            | Self::HOST_BUS_FATAL_ERROR.bits()
+            | Self::TASK_FILE_ERROR.bits();
+    }
+}
+
+bitflags! {
+    /// The ATA Status register of the device, as last reported to the port.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct TaskFile: u32 {
+        const ERROR = 1 << 0;
+        const DATA_REQUEST = 1 << 3;
+        const BUSY = 1 << 7;
+    }
+}
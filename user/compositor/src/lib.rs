@@ -0,0 +1,104 @@
+//! `compositor` owns the display and the input devices, and shares them between tasks by giving each of them
+//! windows to draw into. Clients subscribe to the `compositor` service, and send `CompositorRequest`s down the
+//! service channel to create windows.
+//!
+//! Each window has a surface - a memory object shared between the client and the compositor, which the client
+//! draws the contents of the window into - and a channel of its own. The client sends `WindowRequest`s down the
+//! window's channel to tell the compositor which parts of the surface have changed, and the compositor sends
+//! `WindowEvent`s back when the window receives input. Surfaces use the same pixel format as the display, so
+//! they can be copied straight onto it.
+
+use platform_bus::input::{Button, Key, KeyState};
+use ptah::{Deserialize, Serialize};
+use std::poplar::Handle;
+
+pub const COMPOSITOR_SERVICE: &str = "compositor";
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum CompositorRequest {
+    /// Ask for the size and pixel format of the display. The compositor replies with
+    /// `CompositorResponse::DisplayInfo`.
+    GetDisplayInfo,
+    /// Create a window with its top-left corner at `(x, y)` on the display. The window is placed on top of
+    /// every other window, and is given the keyboard focus. The compositor replies with
+    /// `CompositorResponse::WindowCreated`.
+    CreateWindow { x: u32, y: u32, width: u32, height: u32 },
+    /// Change the resolution of the display. Every window is sent `WindowEvent::DisplayResized` if it changes,
+    /// and the compositor replies with `CompositorResponse::DisplayModeSet` or
+    /// `CompositorResponse::DisplayModeRefused`.
+    SetDisplayMode { width: u32, height: u32 },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum CompositorResponse {
+    /// The size of the display, in pixels, and the name of its pixel format (e.g. `"rgb32"`).
+    DisplayInfo {
+        width: u32,
+        height: u32,
+        format: String,
+    },
+    /// A window has been created. `surface` is a memory object holding `width * height` pixels (with no padding
+    /// between rows) in the display's pixel format, and `channel` speaks the window protocol
+    /// (`WindowRequest` and `WindowEvent`). The window is destroyed when the client closes its channel.
+    WindowCreated {
+        surface: Handle,
+        surface_size: usize,
+        channel: Handle,
+    },
+    /// The window could not be created (e.g. because it doesn't fit on the display).
+    WindowRefused,
+    DisplayModeSet,
+    /// The display's driver doesn't support the requested resolution.
+    DisplayModeRefused,
+}
+
+/// Messages sent from the client of a window to the compositor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum WindowRequest {
+    /// A region of the window's surface has been drawn to, and should be redrawn on the display. The region is
+    /// relative to the top-left of the window. The compositor may read the surface at any time, so clients that
+    /// don't want partly-drawn changes to be visible should draw into a buffer of their own and copy the changes
+    /// to the surface before sending this.
+    Damage { x: u32, y: u32, width: u32, height: u32 },
+    /// Change the size of the window, keeping its top-left corner where it is. The compositor replies with
+    /// `WindowEvent::Resized` or `WindowEvent::ResizeRefused`.
+    Resize { width: u32, height: u32 },
+}
+
+/// Events sent from the compositor to a window. Keyboard input is sent to the window with the focus, and pointer
+/// input to the window under the pointer. Clicking on a window raises it to the top and gives it the focus.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum WindowEvent {
+    KeyPressed {
+        key: Key,
+        state: KeyState,
+    },
+    KeyReleased {
+        key: Key,
+        state: KeyState,
+    },
+    /// The pointer has moved to `(x, y)`, relative to the top-left of the window.
+    PointerMoved {
+        x: u32,
+        y: u32,
+    },
+    ButtonPressed(Button),
+    ButtonReleased(Button),
+    /// The scroll wheel has been turned by the given number of detents.
+    Scroll(i32),
+    /// The resolution of the display has changed. Windows that no longer fit on the display are clipped, so
+    /// clients may want to `Resize` their windows.
+    DisplayResized {
+        width: u32,
+        height: u32,
+    },
+    /// The window has been resized, and has a new surface. The old surface is no longer shown, and any damage
+    /// reported from now on refers to the new one.
+    Resized {
+        surface: Handle,
+        surface_size: usize,
+        width: u32,
+        height: u32,
+    },
+    ResizeRefused,
+}
@@ -0,0 +1,538 @@
+use compositor::{CompositorRequest, CompositorResponse, WindowEvent, WindowRequest, COMPOSITOR_SERVICE};
+use gfxconsole::{
+    pointer::{self, POINTER_HEIGHT, POINTER_WIDTH},
+    Format,
+    Framebuffer,
+    Rect,
+    Rgb32,
+};
+use log::{info, warn};
+use mulch::math::align_up;
+use platform_bus::{
+    framebuffer::{FramebufferEvent, FramebufferRequest},
+    input::{InputDeviceCall, InputDeviceServer, InputEvent},
+    DeviceDriverClient,
+    DeviceHandoffCall,
+    Filter,
+    Property,
+};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use spinning_top::Spinlock;
+use std::{
+    poplar::{
+        channel::{Channel, ChannelReceiveError},
+        early_logger::EarlyLogger,
+        memory_object::{MappedMemoryObject, MemoryObject},
+        stream::{self, StreamExt},
+        syscall::{MapMemoryObjectFlags, MemoryObjectFlags},
+        Handle,
+    },
+    sync::Arc,
+};
+
+/// The color drawn where there are no windows.
+const BACKGROUND_COLOR: Rgb32 = 0x203040;
+
+struct Display {
+    width: usize,
+    height: usize,
+    format: Format,
+    /// The windows are composited into an off-screen back buffer, and the changes are then copied to the
+    /// framebuffer, so that the background is never visible through windows while they're being drawn.
+    front_buffer: Framebuffer,
+    back_buffer: Framebuffer,
+    _framebuffer_memory: MappedMemoryObject,
+    _back_buffer_memory: MappedMemoryObject,
+    channel: Arc<Channel<FramebufferRequest, FramebufferEvent>>,
+}
+
+impl Display {
+    /// Map the framebuffer in `framebuffer`, and create a back buffer to go with it.
+    // TODO: unmap the buffers of the old display mode when the mode is changed
+    fn new(
+        width: usize,
+        height: usize,
+        format: Format,
+        framebuffer: Handle,
+        channel: Arc<Channel<FramebufferRequest, FramebufferEvent>>,
+    ) -> Display {
+        let size = width * height * format.bytes_per_pixel();
+        let framebuffer =
+            unsafe { MemoryObject::from_handle(framebuffer, size, MemoryObjectFlags::WRITABLE).map().unwrap() };
+        let back_buffer = unsafe {
+            MemoryObject::create(size, MemoryObjectFlags::WRITABLE)
+                .unwrap()
+                .map_with_flags(MapMemoryObjectFlags::HUGE_PAGES)
+                .unwrap()
+        };
+
+        Display {
+            width,
+            height,
+            format,
+            front_buffer: Framebuffer::new(framebuffer.ptr() as *mut u8, width, height, width, format),
+            back_buffer: Framebuffer::new(back_buffer.ptr() as *mut u8, width, height, width, format),
+            _framebuffer_memory: framebuffer,
+            _back_buffer_memory: back_buffer,
+            channel,
+        }
+    }
+}
+
+/// The memory a window is drawn into, and where it's shown on the display. This is replaced when the window is
+/// resized.
+struct Surface {
+    rect: Rect,
+    framebuffer: Framebuffer,
+    _memory: MappedMemoryObject,
+}
+
+struct Window {
+    surface: Spinlock<Surface>,
+    channel: Channel<WindowEvent, WindowRequest>,
+}
+
+struct Compositor {
+    display: Display,
+    /// The windows, from the bottom of the stack to the top. The top window has the keyboard focus.
+    windows: Vec<Arc<Window>>,
+    /// The position of the pointer's hotspot, in pixels.
+    pointer: (usize, usize),
+}
+
+impl Compositor {
+    fn new(display: Display) -> Compositor {
+        // Start with the pointer in the middle of the screen
+        let pointer = (display.width / 2, display.height / 2);
+        Compositor { display, windows: Vec::new(), pointer }
+    }
+
+    fn display_rect(&self) -> Rect {
+        Rect::new(0, 0, self.display.width, self.display.height)
+    }
+
+    fn pointer_rect(&self) -> Rect {
+        Rect::new(self.pointer.0, self.pointer.1, POINTER_WIDTH, POINTER_HEIGHT)
+    }
+
+    /// Redraw a region of the display from the windows that cover it, and flush it to the display.
+    fn composite(&mut self, rect: Rect) {
+        let Some(rect) = rect.intersection(self.display_rect()) else {
+            return;
+        };
+
+        let pointer_rect = self.pointer_rect();
+        let back_buffer = &mut self.display.back_buffer;
+        back_buffer.draw_rect(rect.x, rect.y, rect.width, rect.height, BACKGROUND_COLOR);
+        for window in &self.windows {
+            let surface = window.surface.lock();
+            if let Some(visible) = rect.intersection(surface.rect) {
+                let source = Rect::new(
+                    visible.x - surface.rect.x,
+                    visible.y - surface.rect.y,
+                    visible.width,
+                    visible.height,
+                );
+                surface.framebuffer.blit(back_buffer, source, visible.x, visible.y);
+            }
+        }
+
+        /*
+         * The whole pointer is drawn to the back buffer, even if only part of it is in the region. This is fine,
+         * as the rest of it is already there.
+         */
+        if rect.intersection(pointer_rect).is_some() {
+            let (width, height) = (self.display.width, self.display.height);
+            pointer::draw_pointer(back_buffer, pointer_rect.x, pointer_rect.y, width, height);
+        }
+
+        back_buffer.copy_to(&mut self.display.front_buffer, rect);
+        self.display
+            .channel
+            .send(&FramebufferRequest::Flush {
+                x: rect.x as u32,
+                y: rect.y as u32,
+                width: rect.width as u32,
+                height: rect.height as u32,
+            })
+            .unwrap();
+    }
+
+    /// Create a surface covering `rect`, which must fit on the display. Returns the surface, along with the
+    /// handle and size of its memory object.
+    fn create_surface(&mut self, rect: Rect) -> Option<(Surface, Handle, usize)> {
+        if rect.width == 0 || rect.height == 0 || rect.intersection(self.display_rect()) != Some(rect) {
+            return None;
+        }
+
+        let size = align_up(rect.width * rect.height * self.display.format.bytes_per_pixel(), 0x1000);
+        let memory = match unsafe { MemoryObject::create(size, MemoryObjectFlags::WRITABLE) } {
+            Ok(memory) => memory,
+            Err(err) => {
+                warn!("Failed to create memory object for window surface: {:?}", err);
+                return None;
+            }
+        };
+        let handle = memory.handle;
+        let memory = match unsafe { memory.map_with_flags(MapMemoryObjectFlags::HUGE_PAGES) } {
+            Ok(memory) => memory,
+            Err(err) => {
+                warn!("Failed to map window surface: {:?}", err);
+                return None;
+            }
+        };
+
+        let framebuffer =
+            Framebuffer::new(memory.ptr() as *mut u8, rect.width, rect.height, rect.width, self.display.format);
+        Some((Surface { rect, framebuffer, _memory: memory }, handle, size))
+    }
+
+    /// Create a window covering `rect`, and place it on top of the other windows. Returns the window, along with
+    /// the handle and size of its surface, and the client's end of its channel.
+    fn create_window(&mut self, rect: Rect) -> Option<(Arc<Window>, Handle, usize, Handle)> {
+        let (surface, surface_handle, surface_size) = self.create_surface(rect)?;
+        let (channel, client_channel) = Channel::create().unwrap();
+        let window = Arc::new(Window { surface: Spinlock::new(surface), channel });
+        self.windows.push(window.clone());
+        self.composite(rect);
+
+        Some((window, surface_handle, surface_size, client_channel))
+    }
+
+    fn resize_window(&mut self, window: &Window, width: usize, height: usize) {
+        let old_rect = window.surface.lock().rect;
+        let rect = Rect::new(old_rect.x, old_rect.y, width, height);
+        match self.create_surface(rect) {
+            Some((surface, surface_handle, surface_size)) => {
+                *window.surface.lock() = surface;
+                self.composite(old_rect.union(rect));
+                send_event(
+                    window,
+                    WindowEvent::Resized {
+                        surface: surface_handle,
+                        surface_size,
+                        width: width as u32,
+                        height: height as u32,
+                    },
+                );
+            }
+            None => send_event(window, WindowEvent::ResizeRefused),
+        }
+    }
+
+    fn destroy_window(&mut self, window: &Arc<Window>) {
+        self.windows.retain(|other| !Arc::ptr_eq(other, window));
+        let rect = window.surface.lock().rect;
+        self.composite(rect);
+    }
+
+    /// Switch to a new framebuffer, after the display's driver has changed its resolution.
+    fn set_display_mode(&mut self, width: usize, height: usize, framebuffer: Handle) {
+        let channel = self.display.channel.clone();
+        self.display = Display::new(width, height, self.display.format, framebuffer, channel);
+        self.pointer = (self.pointer.0.min(width - 1), self.pointer.1.min(height - 1));
+        self.composite(self.display_rect());
+
+        for window in &self.windows {
+            send_event(window, WindowEvent::DisplayResized { width: width as u32, height: height as u32 });
+        }
+    }
+
+    /// The index of the topmost window at `(x, y)` on the display.
+    fn window_at(&self, x: usize, y: usize) -> Option<usize> {
+        self.windows.iter().rposition(|window| window.surface.lock().rect.contains(x, y))
+    }
+
+    fn send_to_pointer_window(&self, event: WindowEvent) {
+        if let Some(index) = self.window_at(self.pointer.0, self.pointer.1) {
+            send_event(&self.windows[index], event);
+        }
+    }
+
+    fn move_pointer(&mut self, x: usize, y: usize) {
+        let old_rect = self.pointer_rect();
+        self.pointer = (x.min(self.display.width - 1), y.min(self.display.height - 1));
+        self.composite(old_rect.union(self.pointer_rect()));
+
+        if let Some(index) = self.window_at(self.pointer.0, self.pointer.1) {
+            let window = &self.windows[index];
+            let rect = window.surface.lock().rect;
+            let (x, y) = (self.pointer.0 - rect.x, self.pointer.1 - rect.y);
+            send_event(window, WindowEvent::PointerMoved { x: x as u32, y: y as u32 });
+        }
+    }
+
+    fn handle_input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::KeyPressed { key, state } => {
+                if let Some(window) = self.windows.last() {
+                    send_event(window, WindowEvent::KeyPressed { key, state });
+                }
+            }
+            InputEvent::KeyReleased { key, state } => {
+                if let Some(window) = self.windows.last() {
+                    send_event(window, WindowEvent::KeyReleased { key, state });
+                }
+            }
+            InputEvent::PointerMoved { dx, dy } => {
+                let x = self.pointer.0.saturating_add_signed(dx as isize);
+                let y = self.pointer.1.saturating_add_signed(dy as isize);
+                self.move_pointer(x, y);
+            }
+            InputEvent::PointerMovedTo { x, y } => {
+                let x = x as usize * (self.display.width - 1) / u16::MAX as usize;
+                let y = y as usize * (self.display.height - 1) / u16::MAX as usize;
+                self.move_pointer(x, y);
+            }
+            InputEvent::ButtonPressed(button) => {
+                // Clicking on a window raises it to the top, and gives it the focus
+                if let Some(index) = self.window_at(self.pointer.0, self.pointer.1) {
+                    if index != self.windows.len() - 1 {
+                        let window = self.windows.remove(index);
+                        self.windows.push(window.clone());
+                        let rect = window.surface.lock().rect;
+                        self.composite(rect);
+                    }
+                }
+                self.send_to_pointer_window(WindowEvent::ButtonPressed(button));
+            }
+            InputEvent::ButtonReleased(button) => self.send_to_pointer_window(WindowEvent::ButtonReleased(button)),
+            InputEvent::RelWheel(detents) => self.send_to_pointer_window(WindowEvent::Scroll(detents)),
+            InputEvent::RelZ(_) => (),
+        }
+    }
+}
+
+/// Send an event to a window. If this fails, the window will be destroyed when its channel is found to be closed,
+/// so we don't need to do anything else here.
+fn send_event(window: &Window, event: WindowEvent) {
+    if let Err(err) = window.channel.send(&event) {
+        warn!("Failed to send event to window: {:?}", err);
+    }
+}
+
+/// Serve a window's channel, until the client closes it.
+async fn serve_window(compositor: Arc<Spinlock<Compositor>>, window: Arc<Window>) {
+    while let Ok(request) = window.channel.receive().await {
+        match request {
+            WindowRequest::Damage { x, y, width, height } => {
+                let mut compositor = compositor.lock();
+                let rect = window.surface.lock().rect;
+                let damage = Rect::new(x as usize, y as usize, width as usize, height as usize);
+                if let Some(damage) = damage.intersection(Rect::new(0, 0, rect.width, rect.height)) {
+                    compositor.composite(Rect::new(
+                        rect.x + damage.x,
+                        rect.y + damage.y,
+                        damage.width,
+                        damage.height,
+                    ));
+                }
+            }
+            WindowRequest::Resize { width, height } => {
+                compositor.lock().resize_window(&window, width as usize, height as usize);
+            }
+        }
+    }
+
+    compositor.lock().destroy_window(&window);
+}
+
+/// Serve a client of the `compositor` service.
+async fn serve_client(
+    compositor: Arc<Spinlock<Compositor>>,
+    name: String,
+    channel: Channel<CompositorResponse, CompositorRequest>,
+) {
+    loop {
+        let Ok(request) = channel.receive().await else {
+            warn!("Failed to receive message from compositor client '{}'", name);
+            break;
+        };
+
+        let response = match request {
+            CompositorRequest::GetDisplayInfo => {
+                let compositor = compositor.lock();
+                CompositorResponse::DisplayInfo {
+                    width: compositor.display.width as u32,
+                    height: compositor.display.height as u32,
+                    format: compositor.display.format.name().to_string(),
+                }
+            }
+            CompositorRequest::CreateWindow { x, y, width, height } => {
+                let rect = Rect::new(x as usize, y as usize, width as usize, height as usize);
+                let window = compositor.lock().create_window(rect);
+                match window {
+                    Some((window, surface, surface_size, channel)) => {
+                        info!("Task '{}' created a window at {:?}", name, rect);
+                        std::poplar::rt::spawn(serve_window(compositor.clone(), window));
+                        CompositorResponse::WindowCreated { surface, surface_size, channel }
+                    }
+                    None => {
+                        warn!("Refused to create window at {:?} for task '{}'", rect, name);
+                        CompositorResponse::WindowRefused
+                    }
+                }
+            }
+            CompositorRequest::SetDisplayMode { width, height } => {
+                // Don't hold the lock while we wait for the driver, so the display can still be drawn to
+                let display_channel = compositor.lock().display.channel.clone();
+                display_channel.send(&FramebufferRequest::SetMode { width, height }).unwrap();
+                match display_channel.receive().await {
+                    Ok(FramebufferEvent::ModeSet { width, height, framebuffer }) => {
+                        info!("Task '{}' set the display mode to {}x{}", name, width, height);
+                        compositor.lock().set_display_mode(width as usize, height as usize, framebuffer);
+                        CompositorResponse::DisplayModeSet
+                    }
+                    Ok(FramebufferEvent::ModeRefused) => CompositorResponse::DisplayModeRefused,
+                    Err(err) => {
+                        warn!("Failed to receive response from display driver: {:?}", err);
+                        CompositorResponse::DisplayModeRefused
+                    }
+                }
+            }
+        };
+
+        if let Err(err) = channel.send(&response) {
+            warn!("Failed to send response to compositor client '{}': {:?}", name, err);
+            break;
+        }
+    }
+}
+
+fn spawn_compositor(
+    display: Display,
+    input_events: thingbuf::mpsc::Receiver<Option<InputEvent>>,
+    service_channel: Channel<(), ServiceChannelMessage>,
+) {
+    let compositor = Arc::new(Spinlock::new(Compositor::new(display)));
+    {
+        let mut compositor = compositor.lock();
+        let display_rect = compositor.display_rect();
+        compositor.composite(display_rect);
+    }
+
+    /*
+     * Handle input events, and serve clients of the `compositor` service (including any that subscribed before we
+     * found the display), from a single task.
+     */
+    enum Event {
+        Input(Option<InputEvent>),
+        Service(Result<ServiceChannelMessage, ChannelReceiveError>),
+    }
+    let input_events = stream::poll_fn(move |context| input_events.poll_recv(context)).map(Event::Input);
+    let mut events = stream::select(input_events, service_channel.map(Event::Service));
+
+    std::poplar::rt::spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Input(Some(event)) => compositor.lock().handle_input(event),
+                Event::Input(None) => (),
+                Event::Service(message) => match message.unwrap() {
+                    ServiceChannelMessage::NewClient { name, channel } => {
+                        info!("Task '{}' subscribed to the compositor", name);
+                        let channel = Channel::new_from_handle(channel);
+                        std::poplar::rt::spawn(serve_client(compositor.clone(), name, channel));
+                    }
+                },
+            }
+        }
+    });
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Compositor is running!");
+
+    std::poplar::rt::init_runtime();
+
+    // `thingbuf` needs the messages it carries to implement `Default`, so input events are wrapped in `Option`s
+    let (input_sender, input_receiver) = thingbuf::mpsc::channel(16);
+
+    std::poplar::rt::spawn(async move {
+        let mut input_receiver = Some(input_receiver);
+
+        let service_host_client = ServiceHostClient::new();
+        /*
+         * Provide the `compositor` service. We register it straight away, so tasks can subscribe to it before
+         * we've found a display - they'll be served once we have.
+         */
+        let mut service_channel = Some(service_host_client.register_service(COMPOSITOR_SERVICE).unwrap());
+        // We act as a device driver to find framebuffers and input devices
+        let platform_bus_device_client =
+            DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+        let handoff_server = platform_bus_device_client
+            .register(vec![
+                Filter::Matches(String::from("type"), Property::String("framebuffer".to_string())),
+                Filter::Matches(String::from("hid.type"), Property::String("keyboard".to_string())),
+                Filter::Matches(String::from("hid.type"), Property::String("mouse".to_string())),
+                Filter::Matches(String::from("hid.type"), Property::String("tablet".to_string())),
+            ])
+            .unwrap();
+
+        loop {
+            let message = handoff_server.next().await.unwrap();
+            match message {
+                DeviceHandoffCall::QuerySupport { device_info, responder, .. } => {
+                    // We only drive a single display, so leave any others for other drivers
+                    let supported =
+                        device_info.get_as_str("type") != Some("framebuffer") || service_channel.is_some();
+                    responder.reply(supported).unwrap();
+                }
+                DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                    responder.reply(()).unwrap();
+                }
+                DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info } => {
+                    if let Some("framebuffer") = device_info.get_as_str("type") {
+                        info!("Found framebuffer device: {}", name);
+
+                        let (width, height) = (
+                            device_info.get_as_integer("width").unwrap() as usize,
+                            device_info.get_as_integer("height").unwrap() as usize,
+                        );
+                        let format = match device_info.get_as_str("format").map(Format::from_name) {
+                            Some(Some(format)) => format,
+                            Some(None) | None => {
+                                warn!(
+                                    "Framebuffer device '{}' has unsupported pixel format: {:?}",
+                                    name,
+                                    device_info.get_as_str("format")
+                                );
+                                continue;
+                            }
+                        };
+                        let channel =
+                            Arc::new(Channel::new_from_handle(handoff_info.get_as_channel("channel").unwrap()));
+                        let display = Display::new(
+                            width,
+                            height,
+                            format,
+                            handoff_info.get_as_memory_object("framebuffer").unwrap(),
+                            channel,
+                        );
+                        spawn_compositor(display, input_receiver.take().unwrap(), service_channel.take().unwrap());
+                    } else if device_info.get_as_str("hid.type").is_some() {
+                        info!("Found HID-compatible input device: {}", name);
+
+                        let input_device =
+                            InputDeviceServer::from_handle(handoff_info.get_as_channel("hid.channel").unwrap());
+                        let input_sender = input_sender.clone();
+                        std::poplar::rt::spawn(async move {
+                            loop {
+                                match input_device.next().await.unwrap() {
+                                    InputDeviceCall::Event { event } => {
+                                        input_sender.send(Some(event)).await.unwrap()
+                                    }
+                                }
+                            }
+                        });
+                    } else {
+                        panic!("Passed unsupported device!");
+                    }
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
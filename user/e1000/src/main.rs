@@ -0,0 +1,481 @@
+//! `e1000` is a driver for Intel's 8254x (e1000) and 82574 (e1000e) families of Gigabit Ethernet controllers.
+//! These are the default network devices of QEMU's x86_64 machines. Like `virtio_net`, it adds each device it
+//! drives to the Platform Bus as an abstract network device.
+
+#![feature(never_type)]
+
+mod reg;
+
+use crate::reg::{
+    Control,
+    Interrupts,
+    ReceiveControl,
+    Register,
+    RegisterBlock,
+    Status,
+    TransmitControl,
+    MULTICAST_TABLE_LENGTH,
+};
+use core::time::Duration;
+use log::{info, warn};
+use platform_bus::{
+    net::{NetworkEvent, NetworkRequest},
+    BusDriverClient,
+    DeviceDriverClient,
+    DeviceHandoffCall,
+    DeviceInfo,
+    Filter,
+    HandoffInfo,
+    HandoffProperty,
+    Property,
+};
+use service_host::ServiceHostClient;
+use spinning_top::Spinlock;
+use std::{
+    collections::BTreeMap,
+    mem,
+    poplar::{
+        channel::Channel,
+        ddk::dma::{DmaArray, DmaBuffer, DmaPool},
+        early_logger::EarlyLogger,
+        memory_object::MemoryObject,
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+    sync::Arc,
+};
+
+/// The device IDs of the controllers we support, and whether each is an 82574 (which has a few differences from
+/// the older 8254x controllers).
+const SUPPORTED_DEVICES: [(u16, bool); 4] = [
+    // 82540EM - emulated by QEMU's `e1000` device
+    (0x100e, false),
+    // 82545EM
+    (0x100f, false),
+    // 82543GC
+    (0x1004, false),
+    // 82574L - emulated by QEMU's `e1000e` device
+    (0x10d3, true),
+];
+
+/// The number of descriptors in each ring. The size of each ring must be a multiple of 128 bytes (8 descriptors).
+const NUM_RECEIVE_DESCRIPTORS: usize = 32;
+const NUM_TRANSMIT_DESCRIPTORS: usize = 32;
+/// The size of each receive buffer. This is the default buffer size selected in `RCTL`.
+const RECEIVE_BUFFER_SIZE: usize = 2048;
+/// The largest Ethernet frame we can send (a 1500-byte payload, plus the header).
+const MAX_FRAME_SIZE: usize = 1514;
+
+/*
+ * To avoid being interrupted for every packet under load, the device is asked to limit how often it raises
+ * interrupts, and to wait a short time after receiving a packet in case more arrive. The throttling interval is
+ * in units of 256ns, and the receive delays are in units of 1.024µs.
+ */
+const MAX_INTERRUPTS_PER_SECOND: u32 = 8000;
+const INTERRUPT_THROTTLE_INTERVAL: u32 = 1_000_000_000 / (MAX_INTERRUPTS_PER_SECOND * 256);
+const RECEIVE_DELAY: u32 = 32;
+const RECEIVE_ABSOLUTE_DELAY: u32 = 128;
+/// How often to check the device for received packets if it doesn't interrupt us.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A legacy receive descriptor. The device writes each received packet into the buffer of the next descriptor,
+/// and then sets the `DD` bit in its status.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C, align(16))]
+pub struct ReceiveDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+impl ReceiveDescriptor {
+    const STATUS_DONE: u8 = 1 << 0;
+    const STATUS_END_OF_PACKET: u8 = 1 << 1;
+}
+
+/// A legacy transmit descriptor.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C, align(16))]
+pub struct TransmitDescriptor {
+    address: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+impl TransmitDescriptor {
+    const COMMAND_END_OF_PACKET: u8 = 1 << 0;
+    const COMMAND_INSERT_FCS: u8 = 1 << 1;
+    const COMMAND_REPORT_STATUS: u8 = 1 << 3;
+    const STATUS_DONE: u8 = 1 << 0;
+}
+
+pub struct E1000 {
+    registers: RegisterBlock,
+    receive_ring: DmaArray<ReceiveDescriptor>,
+    /// The buffer of each receive descriptor. These are owned by the device until it marks the descriptor done.
+    receive_buffers: Vec<DmaBuffer>,
+    /// The next receive descriptor we expect the device to fill.
+    next_receive: usize,
+    transmit_ring: DmaArray<TransmitDescriptor>,
+    /// The buffers of packets that are being sent, by the descriptor that describes them.
+    transmit_buffers: Vec<Option<DmaBuffer>>,
+    /// The next transmit descriptor to use.
+    next_transmit: usize,
+    /// The oldest transmit descriptor that we haven't reclaimed yet.
+    next_reclaim: usize,
+    buffer_pool: DmaPool,
+}
+
+impl E1000 {
+    pub fn new(mut registers: RegisterBlock, is_82574: bool, buffer_pool: DmaPool) -> E1000 {
+        /*
+         * Reset the device, with interrupts masked (reading the Interrupt Cause register clears any that are
+         * pending).
+         */
+        registers.write(Register::InterruptMaskClear, u32::MAX);
+        let control = Control::from_bits_retain(registers.read(Register::Control));
+        registers.write(Register::Control, (control | Control::RESET).bits());
+        while Control::from_bits_retain(registers.read(Register::Control)).contains(Control::RESET) {}
+        registers.write(Register::InterruptMaskClear, u32::MAX);
+        registers.read(Register::InterruptCause);
+
+        let control = Control::from_bits_retain(registers.read(Register::Control));
+        registers.write(
+            Register::Control,
+            (control.difference(Control::PHY_RESET) | Control::SET_LINK_UP | Control::AUTO_SPEED_DETECTION).bits(),
+        );
+
+        // We don't accept any multicast packets
+        for i in 0..MULTICAST_TABLE_LENGTH {
+            registers.write_table(Register::MulticastTable, i, 0);
+        }
+
+        let mut receive_ring = buffer_pool
+            .create_array(NUM_RECEIVE_DESCRIPTORS, ReceiveDescriptor::default())
+            .expect("Failed to allocate receive ring");
+        let mut receive_buffers = Vec::with_capacity(NUM_RECEIVE_DESCRIPTORS);
+        for i in 0..NUM_RECEIVE_DESCRIPTORS {
+            let buffer =
+                buffer_pool.create_buffer(RECEIVE_BUFFER_SIZE).expect("Failed to allocate receive buffer");
+            receive_ring.write(i, ReceiveDescriptor { address: buffer.phys as u64, ..Default::default() });
+            receive_buffers.push(buffer);
+        }
+        let transmit_ring = buffer_pool
+            .create_array(NUM_TRANSMIT_DESCRIPTORS, TransmitDescriptor::default())
+            .expect("Failed to allocate transmit ring");
+
+        registers.write(Register::ReceiveDescriptorBaseLow, receive_ring.phys as u32);
+        registers.write(Register::ReceiveDescriptorBaseHigh, (receive_ring.phys as u64 >> 32) as u32);
+        registers.write(
+            Register::ReceiveDescriptorLength,
+            (NUM_RECEIVE_DESCRIPTORS * mem::size_of::<ReceiveDescriptor>()) as u32,
+        );
+        registers.write(Register::ReceiveDescriptorHead, 0);
+        // Give every descriptor but one to the device - if head and tail are equal, the ring is empty
+        registers.write(Register::ReceiveDescriptorTail, NUM_RECEIVE_DESCRIPTORS as u32 - 1);
+        registers.write(Register::ReceiveDelayTimer, RECEIVE_DELAY);
+        registers.write(Register::ReceiveAbsoluteDelayTimer, RECEIVE_ABSOLUTE_DELAY);
+        registers.write(
+            Register::ReceiveControl,
+            (ReceiveControl::ENABLE | ReceiveControl::BROADCAST_ACCEPT | ReceiveControl::STRIP_CRC).bits(),
+        );
+
+        registers.write(Register::TransmitDescriptorBaseLow, transmit_ring.phys as u32);
+        registers.write(Register::TransmitDescriptorBaseHigh, (transmit_ring.phys as u64 >> 32) as u32);
+        registers.write(
+            Register::TransmitDescriptorLength,
+            (NUM_TRANSMIT_DESCRIPTORS * mem::size_of::<TransmitDescriptor>()) as u32,
+        );
+        registers.write(Register::TransmitDescriptorHead, 0);
+        registers.write(Register::TransmitDescriptorTail, 0);
+        registers.write(
+            Register::TransmitControl,
+            (TransmitControl::ENABLE
+                | TransmitControl::PAD_SHORT_PACKETS
+                | TransmitControl::COLLISION_THRESHOLD
+                | TransmitControl::COLLISION_DISTANCE)
+                .bits(),
+        );
+        // The recommended Inter-Packet Gap values for copper links
+        let ipg_transmit_time = if is_82574 { 8 } else { 10 };
+        registers.write(Register::TransmitInterPacketGap, ipg_transmit_time | (8 << 10) | (6 << 20));
+
+        /*
+         * The 82574 signals MSI-X interrupts, and needs to be told which vector to use for each cause. We only
+         * have one vector, so use it for everything.
+         */
+        if is_82574 {
+            const VALID: u32 = 0b1000;
+            registers.write(Register::InterruptVectorAllocation, VALID | (VALID << 8) | (VALID << 16));
+        }
+        registers.write(Register::InterruptThrottle, INTERRUPT_THROTTLE_INTERVAL);
+        registers.write(
+            Register::InterruptMaskSet,
+            (Interrupts::RECEIVE_TIMER
+                | Interrupts::RECEIVE_OVERRUN
+                | Interrupts::RECEIVE_DESCRIPTOR_MIN_THRESHOLD
+                | Interrupts::LINK_STATUS_CHANGE)
+                .bits(),
+        );
+
+        E1000 {
+            registers,
+            receive_ring,
+            receive_buffers,
+            next_receive: 0,
+            transmit_ring,
+            transmit_buffers: (0..NUM_TRANSMIT_DESCRIPTORS).map(|_| None).collect(),
+            next_transmit: 0,
+            next_reclaim: 0,
+            buffer_pool,
+        }
+    }
+
+    /// Read the MAC address that the device has been programmed with. This is loaded from the EEPROM into the
+    /// first Receive Address registers when the device is reset.
+    pub fn mac_address(&self) -> [u8; 6] {
+        let low = self.registers.read(Register::ReceiveAddressLow).to_le_bytes();
+        let high = self.registers.read(Register::ReceiveAddressHigh).to_le_bytes();
+        [low[0], low[1], low[2], low[3], high[0], high[1]]
+    }
+
+    pub fn link_up(&self) -> bool {
+        Status::from_bits_retain(self.registers.read(Register::Status)).contains(Status::LINK_UP)
+    }
+
+    /// Acknowledge the device's interrupt, returning its causes.
+    pub fn acknowledge_interrupt(&mut self) -> Interrupts {
+        Interrupts::from_bits_retain(self.registers.read(Register::InterruptCause))
+    }
+
+    /// Send a packet. Fails if the packet is too large, or if every transmit descriptor is in use (e.g. because
+    /// the device is yet to send earlier packets).
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), ()> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(());
+        }
+
+        self.reclaim_transmit_buffers();
+        let next = (self.next_transmit + 1) % NUM_TRANSMIT_DESCRIPTORS;
+        if next == self.next_reclaim {
+            return Err(());
+        }
+
+        let mut buffer = self.buffer_pool.create_buffer(frame.len())?;
+        buffer.write().copy_from_slice(frame);
+        self.transmit_ring.write(
+            self.next_transmit,
+            TransmitDescriptor {
+                address: buffer.phys as u64,
+                length: frame.len() as u16,
+                command: TransmitDescriptor::COMMAND_END_OF_PACKET
+                    | TransmitDescriptor::COMMAND_INSERT_FCS
+                    | TransmitDescriptor::COMMAND_REPORT_STATUS,
+                ..Default::default()
+            },
+        );
+        self.transmit_buffers[self.next_transmit] = Some(buffer);
+        self.next_transmit = next;
+        self.registers.write(Register::TransmitDescriptorTail, next as u32);
+
+        Ok(())
+    }
+
+    /// Free the buffers of packets that the device has finished sending.
+    fn reclaim_transmit_buffers(&mut self) {
+        while self.next_reclaim != self.next_transmit {
+            let descriptor =
+                unsafe { std::ptr::read_volatile(self.transmit_ring.ptr.as_ptr().add(self.next_reclaim)) };
+            if descriptor.status & TransmitDescriptor::STATUS_DONE == 0 {
+                break;
+            }
+            self.transmit_buffers[self.next_reclaim] = None;
+            self.next_reclaim = (self.next_reclaim + 1) % NUM_TRANSMIT_DESCRIPTORS;
+        }
+    }
+
+    /// Receive the next packet that the device has received, if there is one.
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let index = self.next_receive;
+            let descriptor = unsafe { std::ptr::read_volatile(self.receive_ring.ptr.as_ptr().add(index)) };
+            if descriptor.status & ReceiveDescriptor::STATUS_DONE == 0 {
+                return None;
+            }
+
+            /*
+             * Our buffers are large enough for any packet we accept, so each packet should fit in a single
+             * descriptor. Drop any that don't, or that the device found errors in.
+             */
+            let complete = descriptor.status & ReceiveDescriptor::STATUS_END_OF_PACKET != 0;
+            let frame = if complete && descriptor.errors == 0 {
+                Some(self.receive_buffers[index].read()[0..(descriptor.length as usize)].to_vec())
+            } else {
+                warn!(
+                    "Dropping received packet (status = {:#x}, errors = {:#x})",
+                    descriptor.status, descriptor.errors
+                );
+                None
+            };
+
+            // Give the descriptor back to the device
+            self.receive_ring.write(
+                index,
+                ReceiveDescriptor { address: self.receive_buffers[index].phys as u64, ..Default::default() },
+            );
+            self.registers.write(Register::ReceiveDescriptorTail, index as u32);
+            self.next_receive = (index + 1) % NUM_RECEIVE_DESCRIPTORS;
+
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("E1000 network driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = ServiceHostClient::new();
+    // We act as a bus driver to create the abstract network device
+    let platform_bus_bus_client =
+        BusDriverClient::new(service_host_client.subscribe_service("platform_bus.bus_driver").unwrap());
+    // And also as a device driver to find supported Ethernet controllers
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    let handoff_server = platform_bus_device_client
+        .register(vec![
+            Filter::Matches(String::from("pci.vendor_id"), Property::Integer(0x8086)),
+            Filter::Matches(String::from("pci.class"), Property::Integer(0x02)),
+        ])
+        .unwrap();
+
+    let (device_info, handoff_info) = loop {
+        match handoff_server.next_blocking().unwrap() {
+            DeviceHandoffCall::QuerySupport { device_info, responder, .. } => {
+                let device_id = device_info.get_as_integer("pci.device_id").unwrap();
+                let supported = SUPPORTED_DEVICES.iter().any(|&(id, _)| id as u64 == device_id);
+                responder.reply(supported).unwrap();
+            }
+            DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                responder.reply(()).unwrap();
+            }
+            DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info } => {
+                info!("Started driving device: {}", name);
+                break (device_info, handoff_info);
+            }
+        }
+    };
+
+    let device_id = device_info.get_as_integer("pci.device_id").unwrap();
+    let is_82574 = SUPPORTED_DEVICES.iter().any(|&(id, is_82574)| id as u64 == device_id && is_82574);
+    let registers = {
+        let bar = MemoryObject {
+            handle: handoff_info.get_as_memory_object("pci.bar0.handle").unwrap(),
+            size: handoff_info.get_as_integer("pci.bar0.size").unwrap() as usize,
+            flags: MemoryObjectFlags::WRITABLE,
+            phys_address: None,
+        };
+        let mapped_bar = unsafe { bar.map().unwrap() };
+        RegisterBlock::new(mapped_bar.mapped_at)
+    };
+    let interrupt_event = handoff_info.get_as_event("pci.interrupt").unwrap();
+    let dma_constraints = handoff_info
+        .get_as_dma_domain("pci.dma_domain")
+        .map_or(DmaConstraints::NONE, |domain| DmaConstraints::NONE.in_domain(domain));
+
+    let buffer_pool = {
+        /*
+         * This needs to hold the descriptor rings and all the receive buffers, plus the buffers of packets that
+         * are in the process of being sent (and the allocator's own bookkeeping).
+         */
+        const BUFFER_POOL_SIZE: usize = 0x40000;
+        let (memory_object, _) =
+            MemoryObject::create_dma(BUFFER_POOL_SIZE, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        const BUFFER_POOL_ADDRESS: usize = 0x00000005_20000000;
+        let memory_object = unsafe { memory_object.map_at(BUFFER_POOL_ADDRESS).unwrap() };
+        DmaPool::new(memory_object)
+    };
+
+    let e1000 = E1000::new(registers, is_82574, buffer_pool);
+    let mac_address = e1000.mac_address();
+    info!("E1000 device {:#x} has MAC address {:02x?} (link up: {})", device_id, mac_address, e1000.link_up());
+    let e1000 = Arc::new(Spinlock::new(e1000));
+
+    // Add the network device to the Platform Bus
+    let channel = {
+        let device_info = {
+            let mut properties = BTreeMap::new();
+            properties.insert("type".to_string(), Property::String("network".to_string()));
+            properties.insert("net.mac_address".to_string(), Property::Bytes(mac_address.to_vec()));
+            properties.insert("net.mtu".to_string(), Property::Integer(1500));
+            DeviceInfo(properties)
+        };
+        let (channel, channel_handle) = Channel::<NetworkEvent, NetworkRequest>::create().unwrap();
+        let handoff_info = {
+            let mut properties = BTreeMap::new();
+            properties.insert("net.channel".to_string(), HandoffProperty::Channel(channel_handle));
+            HandoffInfo(properties)
+        };
+        platform_bus_bus_client.register_device("e1000".to_string(), device_info, handoff_info).unwrap();
+        Arc::new(channel)
+    };
+
+    // Pass received packets on to whoever is using the device
+    std::poplar::rt::spawn({
+        let e1000 = e1000.clone();
+        let channel = channel.clone();
+        async move {
+            loop {
+                /*
+                 * Devices without MSI support (including the 82540EM emulated by QEMU's `e1000` device) use legacy
+                 * interrupts, which aren't delivered on all platforms yet. We poll the device as well, so they
+                 * still work (if slowly).
+                 */
+                let _ = std::poplar::rt::time::timeout(POLL_INTERVAL, interrupt_event.wait_for_event()).await;
+
+                let causes = e1000.lock().acknowledge_interrupt();
+                interrupt_event.ack_interrupt();
+                if causes.contains(Interrupts::LINK_STATUS_CHANGE) {
+                    info!("E1000 link status changed (link up: {})", e1000.lock().link_up());
+                }
+                if causes.contains(Interrupts::RECEIVE_OVERRUN) {
+                    warn!("E1000 receive ring overran. Packets have been dropped.");
+                }
+
+                loop {
+                    let Some(frame) = e1000.lock().receive() else {
+                        break;
+                    };
+                    channel.send(&NetworkEvent::PacketReceived(frame)).unwrap();
+                }
+            }
+        }
+    });
+
+    // Send packets we're asked to
+    std::poplar::rt::spawn(async move {
+        loop {
+            match channel.receive().await.unwrap() {
+                NetworkRequest::SendPacket(frame) => {
+                    if e1000.lock().send(&frame).is_err() {
+                        warn!("Failed to send packet of length {}. Dropping.", frame.len());
+                    }
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
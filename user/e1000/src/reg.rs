@@ -0,0 +1,127 @@
+use bitflags::bitflags;
+
+/// The device's registers, which are memory-mapped through BAR0.
+pub struct RegisterBlock {
+    base: usize,
+}
+
+impl RegisterBlock {
+    pub fn new(base: usize) -> RegisterBlock {
+        RegisterBlock { base }
+    }
+
+    pub fn read(&self, reg: Register) -> u32 {
+        self.read_offset(reg as usize)
+    }
+
+    pub fn write(&mut self, reg: Register, value: u32) {
+        self.write_offset(reg as usize, value);
+    }
+
+    /// Write to the `index`th register of a table of registers, such as the Multicast Table Array.
+    pub fn write_table(&mut self, reg: Register, index: usize, value: u32) {
+        self.write_offset(reg as usize + index * 4, value);
+    }
+
+    fn read_offset(&self, offset: usize) -> u32 {
+        unsafe { std::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_offset(&mut self, offset: usize, value: u32) {
+        unsafe {
+            std::ptr::write_volatile((self.base + offset) as *mut u32, value);
+        }
+    }
+}
+
+#[repr(usize)]
+#[derive(Clone, Copy, Debug)]
+pub enum Register {
+    Control = 0x0000,
+    Status = 0x0008,
+    InterruptCause = 0x00c0,
+    InterruptThrottle = 0x00c4,
+    InterruptMaskSet = 0x00d0,
+    InterruptMaskClear = 0x00d8,
+    /// Maps interrupt causes to MSI-X vectors. Only present on the 82574 (e1000e).
+    InterruptVectorAllocation = 0x00e4,
+    ReceiveControl = 0x0100,
+    TransmitControl = 0x0400,
+    TransmitInterPacketGap = 0x0410,
+    ReceiveDescriptorBaseLow = 0x2800,
+    ReceiveDescriptorBaseHigh = 0x2804,
+    ReceiveDescriptorLength = 0x2808,
+    ReceiveDescriptorHead = 0x2810,
+    ReceiveDescriptorTail = 0x2818,
+    ReceiveDelayTimer = 0x2820,
+    ReceiveAbsoluteDelayTimer = 0x282c,
+    TransmitDescriptorBaseLow = 0x3800,
+    TransmitDescriptorBaseHigh = 0x3804,
+    TransmitDescriptorLength = 0x3808,
+    TransmitDescriptorHead = 0x3810,
+    TransmitDescriptorTail = 0x3818,
+    MulticastTable = 0x5200,
+    ReceiveAddressLow = 0x5400,
+    ReceiveAddressHigh = 0x5404,
+}
+
+/// The number of registers in the Multicast Table Array.
+pub const MULTICAST_TABLE_LENGTH: usize = 128;
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Control: u32 {
+        const AUTO_SPEED_DETECTION = 1 << 5;
+        const SET_LINK_UP = 1 << 6;
+        const RESET = 1 << 26;
+        const PHY_RESET = 1 << 31;
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Status: u32 {
+        const FULL_DUPLEX = 1 << 0;
+        const LINK_UP = 1 << 1;
+    }
+}
+
+bitflags! {
+    /// The causes of interrupts. Used by the Interrupt Cause, Interrupt Mask Set, and Interrupt Mask Clear
+    /// registers.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Interrupts: u32 {
+        const TRANSMIT_DESCRIPTOR_WRITTEN_BACK = 1 << 0;
+        const TRANSMIT_QUEUE_EMPTY = 1 << 1;
+        const LINK_STATUS_CHANGE = 1 << 2;
+        const RECEIVE_DESCRIPTOR_MIN_THRESHOLD = 1 << 4;
+        const RECEIVE_OVERRUN = 1 << 6;
+        const RECEIVE_TIMER = 1 << 7;
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct ReceiveControl: u32 {
+        const ENABLE = 1 << 1;
+        const STORE_BAD_PACKETS = 1 << 2;
+        const UNICAST_PROMISCUOUS = 1 << 3;
+        const MULTICAST_PROMISCUOUS = 1 << 4;
+        const BROADCAST_ACCEPT = 1 << 15;
+        /// Leaving the Buffer Size field clear (with this bit also clear) selects 2048-byte buffers.
+        const BUFFER_SIZE_EXTENSION = 1 << 25;
+        const STRIP_CRC = 1 << 26;
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct TransmitControl: u32 {
+        const ENABLE = 1 << 1;
+        const PAD_SHORT_PACKETS = 1 << 3;
+        /// The number of times to retry after a collision. The recommended value is `15`.
+        const COLLISION_THRESHOLD = 0x0f << 4;
+        /// The collision distance. The recommended value for full-duplex links is `0x3f`.
+        const COLLISION_DISTANCE = 0x3f << 12;
+    }
+}
@@ -0,0 +1,381 @@
+//! `ext2_fs` serves an ext2 filesystem on the system's block device through the VFS. The filesystem is found by
+//! looking for a Linux filesystem partition in the device's GPT, or the first Linux partition in its MBR, and is
+//! mounted at `/data`. Unlike the FAT boot volume, files on it have permissions, and it can hold symlinks. We
+//! don't follow symlinks ourselves - the VFS does, so they're followed within the namespace of whoever made the
+//! request.
+
+use ext2::{BlockDevice, Ext2Error, FileSystem, FileType, Inode, Resolved, SECTOR_SIZE};
+use gpt::{GptHeader, Guid, PartitionEntry};
+use log::{info, warn};
+use service_host::ServiceHostClient;
+use std::{
+    collections::BTreeMap,
+    mem,
+    poplar::{
+        block::{BlockDeviceClient, BLOCK_DEVICE_SERVICE},
+        channel::Channel,
+        early_logger::EarlyLogger,
+        file::{
+            self,
+            path_components,
+            FileError,
+            FileId,
+            FileKind,
+            FileRequest,
+            FileResponse,
+            FileStat,
+            FilesystemMessage,
+            FilesystemResponse,
+            OpenOptions,
+            MAX_DIR_ENTRIES,
+            MAX_TRANSFER_SIZE,
+            VFS_FILESYSTEM_SERVICE,
+        },
+    },
+};
+
+const MOUNT_POINT: &str = "/data";
+
+/// A range of sectors on the block device, which an ext2 filesystem is stored in.
+pub struct Partition {
+    client: BlockDeviceClient,
+    start: u64,
+    num_sectors: u64,
+}
+
+impl Partition {
+    fn check_range(&self, sector: u64, length: usize) -> Result<u64, Ext2Error> {
+        let count = (length / SECTOR_SIZE) as u64;
+        if sector + count > self.num_sectors {
+            return Err(Ext2Error::Io);
+        }
+        Ok(self.start + sector)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+        let block = self.check_range(sector, buffer.len())?;
+        let data = self.client.read(block, (buffer.len() / SECTOR_SIZE) as u32).map_err(|_| Ext2Error::Io)?;
+        if data.len() != buffer.len() {
+            return Err(Ext2Error::Io);
+        }
+        buffer.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), Ext2Error> {
+        let block = self.check_range(sector, data.len())?;
+        self.client.write(block, data).map_err(|_| Ext2Error::Io)
+    }
+
+    fn flush(&mut self) -> Result<(), Ext2Error> {
+        self.client.flush().map_err(|_| Ext2Error::Io)
+    }
+}
+
+/// The partition type used in the MBR for Linux filesystems.
+const MBR_LINUX_PARTITION_TYPE: u8 = 0x83;
+
+/// Find the partition holding an ext2 filesystem on the block device. If the device doesn't have a GPT, we look
+/// for a Linux partition in its MBR, and otherwise assume the whole device is the filesystem. Returns `None` if
+/// the device has a partition table without a Linux partition in it.
+fn find_partition(client: BlockDeviceClient) -> Result<Option<Partition>, Ext2Error> {
+    let num_blocks = client.info.num_blocks;
+    let header = client.read(1, 1).map_err(|_| Ext2Error::Io)?;
+    let header = unsafe { core::ptr::read_unaligned(header.as_ptr() as *const GptHeader) };
+    if header.validate().is_err() {
+        let first_sector = client.read(0, 1).map_err(|_| Ext2Error::Io)?;
+        if has_mbr(&first_sector) {
+            return Ok(find_mbr_partition(&first_sector).map(|(start, num_sectors)| {
+                info!("Found Linux partition in MBR at sectors {}..{}", start, start + num_sectors);
+                Partition { client, start, num_sectors }
+            }));
+        }
+
+        info!("Block device does not have a partition table. Looking for an ext2 filesystem on the whole device.");
+        return Ok(Some(Partition { client, start: 0, num_sectors: num_blocks }));
+    }
+
+    let entry_size = header.size_of_partition_entry as usize;
+    if entry_size < mem::size_of::<PartitionEntry>() || SECTOR_SIZE % entry_size != 0 {
+        warn!("GPT has unsupported partition entry size: {}", entry_size);
+        return Err(Ext2Error::Unsupported);
+    }
+    let entries_per_sector = SECTOR_SIZE / entry_size;
+
+    let mut sector_data = Vec::new();
+    for i in 0..(header.num_partition_entries as usize) {
+        // Each sector holds multiple entries, so we only read it when we reach the first of them
+        if i % entries_per_sector == 0 {
+            let sector = header.partition_entry_lba + (i / entries_per_sector) as u64;
+            sector_data = client.read(sector, 1).map_err(|_| Ext2Error::Io)?;
+        }
+        let offset = (i % entries_per_sector) * entry_size;
+        let entry = unsafe { core::ptr::read_unaligned(sector_data[offset..].as_ptr() as *const PartitionEntry) };
+
+        if entry.partition_type_guid == Guid::LINUX_FILESYSTEM {
+            info!("Found Linux filesystem partition at sectors {}..={}", entry.starting_lba, entry.ending_lba);
+            return Ok(Some(Partition {
+                client,
+                start: entry.starting_lba,
+                num_sectors: entry.ending_lba + 1 - entry.starting_lba,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether a sector is an MBR. FAT boot sectors end in the same signature, so we tell them apart by the jump
+/// instruction a FAT boot sector starts with.
+fn has_mbr(sector: &[u8]) -> bool {
+    sector.len() >= SECTOR_SIZE && sector[510..512] == [0x55, 0xaa] && !matches!(sector[0], 0xeb | 0xe9)
+}
+
+/// Look for the first Linux partition in an MBR. Returns its starting sector and size in sectors.
+fn find_mbr_partition(sector: &[u8]) -> Option<(u64, u64)> {
+    sector[446..510].chunks_exact(16).find_map(|entry| {
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let num_sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        (entry[4] == MBR_LINUX_PARTITION_TYPE && num_sectors != 0).then_some((start, num_sectors))
+    })
+}
+
+/// Why a request couldn't be handled.
+enum Failure {
+    Error(FileError),
+    /// The path of the request goes through a symlink, which the VFS needs to follow.
+    Symlink {
+        depth: u32,
+        target: String,
+    },
+}
+
+impl From<FileError> for Failure {
+    fn from(err: FileError) -> Failure {
+        Failure::Error(err)
+    }
+}
+
+struct OpenFile {
+    inode: Inode,
+    writable: bool,
+}
+
+pub struct Ext2Server {
+    fs: FileSystem<Partition>,
+    open_files: BTreeMap<FileId, OpenFile>,
+    next_file_id: u64,
+}
+
+impl Ext2Server {
+    pub fn handle_request(&mut self, request: FileRequest) -> FileResponse {
+        let result = match request {
+            FileRequest::Open { path, options } => {
+                self.open(&path, options).map(|(id, stat)| FileResponse::Opened(id, stat))
+            }
+            FileRequest::Read { file, offset, length } => self.read(file, offset, length).map(FileResponse::Data),
+            FileRequest::Write { file, offset, data } => {
+                self.write(file, offset, &data).map(|()| FileResponse::Written(data.len() as u32))
+            }
+            FileRequest::Map { .. } | FileRequest::Namespace { .. } => Err(FileError::Unsupported.into()),
+            FileRequest::Stat { path } => self.stat(&path).map(FileResponse::Stat),
+            FileRequest::ReadDir { path, start } => self.read_dir(&path, start).map(FileResponse::Entries),
+            FileRequest::Close { file } => self.close(file).map(|()| FileResponse::Done),
+        };
+        result.unwrap_or_else(|failure| match failure {
+            Failure::Error(err) => FileResponse::Error(err),
+            Failure::Symlink { depth, target } => FileResponse::Symlink { depth, target },
+        })
+    }
+
+    fn open(&mut self, path: &str, options: OpenOptions) -> Result<(FileId, FileStat), Failure> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        let mut inode = match self.fs.resolve(&components) {
+            Ok(Resolved::Inode(inode)) => inode,
+            Ok(Resolved::Symlink { depth, target }) => {
+                return Err(Failure::Symlink { depth: depth as u32, target });
+            }
+            Err(Ext2Error::NotFound) if options.write && options.create => {
+                let (name, parent) = components.split_last().ok_or(FileError::InvalidPath)?;
+                let parent = self.lookup(parent)?;
+                self.fs.create_file(&parent, name).map_err(to_file_error)?
+            }
+            Err(err) => return Err(to_file_error(err).into()),
+        };
+
+        match inode.file_type() {
+            FileType::File => (),
+            FileType::Directory => return Err(FileError::IsADirectory.into()),
+            FileType::Symlink | FileType::Other => return Err(FileError::Unsupported.into()),
+        }
+        if options.write && options.truncate {
+            self.fs.truncate(&mut inode).map_err(to_file_error)?;
+            self.sync_open_files(&inode);
+        }
+
+        let id = FileId(self.next_file_id);
+        self.next_file_id += 1;
+        let stat = file_stat(&inode);
+        self.open_files.insert(id, OpenFile { inode, writable: options.write });
+        Ok((id, stat))
+    }
+
+    fn read(&mut self, file: FileId, offset: u64, length: u32) -> Result<Vec<u8>, Failure> {
+        if length as usize > MAX_TRANSFER_SIZE {
+            return Err(FileError::TooLarge.into());
+        }
+        let file = self.open_files.get(&file).ok_or(FileError::InvalidFile)?;
+        let mut buffer = vec![0; length as usize];
+        let read = self.fs.read(&file.inode, offset, &mut buffer).map_err(to_file_error)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    fn write(&mut self, file: FileId, offset: u64, data: &[u8]) -> Result<(), Failure> {
+        if data.len() > MAX_TRANSFER_SIZE {
+            return Err(FileError::TooLarge.into());
+        }
+        let file = self.open_files.get_mut(&file).ok_or(FileError::InvalidFile)?;
+        if !file.writable {
+            return Err(FileError::ReadOnly.into());
+        }
+        self.fs.write(&mut file.inode, offset, data).map_err(to_file_error)?;
+
+        let inode = file.inode.clone();
+        self.sync_open_files(&inode);
+        Ok(())
+    }
+
+    fn stat(&mut self, path: &str) -> Result<FileStat, Failure> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        let inode = self.lookup(&components)?;
+        Ok(file_stat(&inode))
+    }
+
+    fn read_dir(&mut self, path: &str, start: u32) -> Result<Vec<file::DirEntry>, Failure> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        let dir = self.lookup(&components)?;
+        let entries = self.fs.read_dir(&dir).map_err(to_file_error)?;
+        Ok(entries
+            .into_iter()
+            // Devices, FIFOs, and sockets can't be used through the VFS, so we don't list them
+            .filter_map(|entry| Some(file::DirEntry { kind: file_kind(entry.file_type)?, name: entry.name }))
+            .skip(start as usize)
+            .take(MAX_DIR_ENTRIES)
+            .collect())
+    }
+
+    fn close(&mut self, file: FileId) -> Result<(), Failure> {
+        let file = self.open_files.remove(&file).ok_or(FileError::InvalidFile)?;
+        if file.writable {
+            self.fs.flush().map_err(to_file_error)?;
+        }
+        Ok(())
+    }
+
+    /// Find the file at `path`. If the path goes through a symlink, the VFS is asked to follow it.
+    fn lookup(&mut self, path: &[&str]) -> Result<Inode, Failure> {
+        match self.fs.resolve(path).map_err(to_file_error)? {
+            Resolved::Inode(inode) => Ok(inode),
+            Resolved::Symlink { depth, target } => Err(Failure::Symlink { depth: depth as u32, target }),
+        }
+    }
+
+    /// The same file can be opened more than once, so make sure every open copy of a file's inode agrees with
+    /// one that has just changed.
+    fn sync_open_files(&mut self, inode: &Inode) {
+        for file in self.open_files.values_mut() {
+            if file.inode.number == inode.number {
+                file.inode = inode.clone();
+            }
+        }
+    }
+}
+
+fn file_kind(file_type: FileType) -> Option<FileKind> {
+    match file_type {
+        FileType::File => Some(FileKind::File),
+        FileType::Directory => Some(FileKind::Directory),
+        FileType::Symlink => Some(FileKind::Symlink),
+        FileType::Other => None,
+    }
+}
+
+fn file_stat(inode: &Inode) -> FileStat {
+    FileStat {
+        kind: file_kind(inode.file_type()).unwrap_or(FileKind::File),
+        size: inode.size,
+        inode: inode.number as u64,
+        permissions: inode.permissions(),
+    }
+}
+
+fn to_file_error(err: Ext2Error) -> FileError {
+    match err {
+        Ext2Error::NotFound => FileError::NotFound,
+        Ext2Error::AlreadyExists => FileError::AlreadyExists,
+        Ext2Error::NotADirectory => FileError::NotADirectory,
+        Ext2Error::IsADirectory => FileError::IsADirectory,
+        Ext2Error::InvalidName | Ext2Error::SymlinkLoop => FileError::InvalidPath,
+        Ext2Error::NoSpace => FileError::NoSpace,
+        Ext2Error::ReadOnly => FileError::ReadOnly,
+        Ext2Error::Io | Ext2Error::Unsupported | Ext2Error::Corrupt | Ext2Error::NotASymlink => FileError::Io,
+    }
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("ext2 filesystem driver is running!");
+
+    let service_host_client = ServiceHostClient::new();
+
+    let block_device =
+        BlockDeviceClient::new(service_host_client.subscribe_service(BLOCK_DEVICE_SERVICE).unwrap())
+            .expect("Failed to connect to block device");
+    if block_device.info.block_size as usize != SECTOR_SIZE {
+        panic!("Block devices with a block size of {} are not supported", block_device.info.block_size);
+    }
+    let read_only = block_device.info.read_only;
+
+    // Most systems boot without an ext2 filesystem, so not finding one isn't an error
+    let partition = match find_partition(block_device) {
+        Ok(Some(partition)) => partition,
+        Ok(None) => {
+            info!("Block device does not have a Linux partition. Not mounting anything.");
+            return;
+        }
+        Err(err) => panic!("Failed to read partition table: {:?}", err),
+    };
+    let fs = match FileSystem::mount(partition, !read_only) {
+        Ok(fs) => fs,
+        Err(Ext2Error::Unsupported) => {
+            info!("Partition does not hold an ext2 filesystem we support. Not mounting anything.");
+            return;
+        }
+        Err(err) => panic!("Failed to mount ext2 filesystem: {:?}", err),
+    };
+    if !fs.is_writable() {
+        info!("Mounting ext2 filesystem read-only");
+    }
+
+    let vfs_channel: Channel<FilesystemMessage, FilesystemResponse> =
+        service_host_client.subscribe_service(VFS_FILESYSTEM_SERVICE).unwrap();
+    let (channel, channel_handle) = Channel::<FileResponse, FileRequest>::create().unwrap();
+    vfs_channel
+        .send(&FilesystemMessage::Mount { path: MOUNT_POINT.to_string(), channel: channel_handle })
+        .unwrap();
+    match vfs_channel.receive_blocking().unwrap() {
+        FilesystemResponse::Mounted => info!("Mounted ext2 filesystem at {}", MOUNT_POINT),
+        FilesystemResponse::Error(err) => panic!("Failed to mount ext2 filesystem: {:?}", err),
+    }
+
+    let mut server = Ext2Server { fs, open_files: BTreeMap::new(), next_file_id: 0 };
+    loop {
+        let (request, call) = channel.receive_call_blocking().unwrap();
+        let response = server.handle_request(request);
+        channel.reply(call, &response).unwrap();
+    }
+}
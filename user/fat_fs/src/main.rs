@@ -0,0 +1,324 @@
+//! `fat_fs` serves the FAT32 boot volume on the system's block device through the VFS. The volume is found by
+//! looking for the EFI System Partition in the device's GPT, or the first FAT partition in its MBR, and is
+//! mounted at `/boot`.
+
+use fat::{Attributes, BlockDevice, DirEntry, FatError, FileSystem, SECTOR_SIZE};
+use gpt::{GptHeader, Guid, PartitionEntry};
+use log::{info, warn};
+use service_host::ServiceHostClient;
+use std::{
+    collections::BTreeMap,
+    mem,
+    poplar::{
+        block::{BlockDeviceClient, BLOCK_DEVICE_SERVICE},
+        channel::Channel,
+        early_logger::EarlyLogger,
+        file::{
+            self,
+            path_components,
+            FileError,
+            FileId,
+            FileKind,
+            FileRequest,
+            FileResponse,
+            FileStat,
+            FilesystemMessage,
+            FilesystemResponse,
+            OpenOptions,
+            MAX_DIR_ENTRIES,
+            MAX_TRANSFER_SIZE,
+            VFS_FILESYSTEM_SERVICE,
+        },
+    },
+};
+
+const MOUNT_POINT: &str = "/boot";
+
+/// A range of sectors on the block device, which a FAT volume is stored in.
+pub struct Partition {
+    client: BlockDeviceClient,
+    start: u64,
+    num_sectors: u64,
+}
+
+impl Partition {
+    fn check_range(&self, sector: u64, length: usize) -> Result<u64, FatError> {
+        let count = (length / SECTOR_SIZE) as u64;
+        if sector + count > self.num_sectors {
+            return Err(FatError::Io);
+        }
+        Ok(self.start + sector)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), FatError> {
+        let block = self.check_range(sector, buffer.len())?;
+        let data = self.client.read(block, (buffer.len() / SECTOR_SIZE) as u32).map_err(|_| FatError::Io)?;
+        if data.len() != buffer.len() {
+            return Err(FatError::Io);
+        }
+        buffer.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), FatError> {
+        let block = self.check_range(sector, data.len())?;
+        self.client.write(block, data).map_err(|_| FatError::Io)
+    }
+
+    fn flush(&mut self) -> Result<(), FatError> {
+        self.client.flush().map_err(|_| FatError::Io)
+    }
+}
+
+/// Partition types used in the MBR for FAT volumes.
+const MBR_FAT_PARTITION_TYPES: [u8; 6] = [0x01, 0x04, 0x06, 0x0b, 0x0c, 0x0e];
+
+/// Find the EFI System Partition on the block device. If the device doesn't have a GPT, we look for a FAT
+/// partition in its MBR (which is how most removable drives are formatted), and otherwise assume the whole
+/// device is the volume.
+fn find_boot_partition(client: BlockDeviceClient) -> Result<Partition, FatError> {
+    let num_blocks = client.info.num_blocks;
+    let header = client.read(1, 1).map_err(|_| FatError::Io)?;
+    let header = unsafe { core::ptr::read_unaligned(header.as_ptr() as *const GptHeader) };
+    if header.validate().is_err() {
+        let first_sector = client.read(0, 1).map_err(|_| FatError::Io)?;
+        if let Some((start, num_sectors)) = find_mbr_partition(&first_sector) {
+            info!("Found FAT partition in MBR at sectors {}..{}", start, start + num_sectors);
+            return Ok(Partition { client, start, num_sectors });
+        }
+
+        info!("Block device does not have a GPT. Looking for a FAT volume on the whole device.");
+        return Ok(Partition { client, start: 0, num_sectors: num_blocks });
+    }
+
+    let entry_size = header.size_of_partition_entry as usize;
+    if entry_size < mem::size_of::<PartitionEntry>() || SECTOR_SIZE % entry_size != 0 {
+        warn!("GPT has unsupported partition entry size: {}", entry_size);
+        return Err(FatError::Unsupported);
+    }
+    let entries_per_sector = SECTOR_SIZE / entry_size;
+
+    let mut sector_data = Vec::new();
+    for i in 0..(header.num_partition_entries as usize) {
+        // Each sector holds multiple entries, so we only read it when we reach the first of them
+        if i % entries_per_sector == 0 {
+            let sector = header.partition_entry_lba + (i / entries_per_sector) as u64;
+            sector_data = client.read(sector, 1).map_err(|_| FatError::Io)?;
+        }
+        let offset = (i % entries_per_sector) * entry_size;
+        let entry = unsafe { core::ptr::read_unaligned(sector_data[offset..].as_ptr() as *const PartitionEntry) };
+
+        if entry.partition_type_guid == Guid::EFI_SYSTEM_PARTITION {
+            info!("Found EFI System Partition at sectors {}..={}", entry.starting_lba, entry.ending_lba);
+            return Ok(Partition {
+                client,
+                start: entry.starting_lba,
+                num_sectors: entry.ending_lba + 1 - entry.starting_lba,
+            });
+        }
+    }
+
+    Err(FatError::NotFound)
+}
+
+/// Look for the first FAT partition in an MBR. Returns its starting sector and size in sectors. Both MBRs and
+/// FAT boot sectors end in the same signature, so we tell them apart by the jump instruction a FAT boot sector
+/// starts with.
+fn find_mbr_partition(sector: &[u8]) -> Option<(u64, u64)> {
+    if sector.len() < SECTOR_SIZE || sector[510..512] != [0x55, 0xaa] || matches!(sector[0], 0xeb | 0xe9) {
+        return None;
+    }
+
+    sector[446..510].chunks_exact(16).find_map(|entry| {
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let num_sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        (MBR_FAT_PARTITION_TYPES.contains(&entry[4]) && num_sectors != 0).then_some((start, num_sectors))
+    })
+}
+
+struct OpenFile {
+    entry: DirEntry,
+    writable: bool,
+}
+
+pub struct FatServer {
+    fs: FileSystem<Partition>,
+    open_files: BTreeMap<FileId, OpenFile>,
+    next_file_id: u64,
+}
+
+impl FatServer {
+    pub fn handle_request(&mut self, request: FileRequest) -> FileResponse {
+        let result = match request {
+            FileRequest::Open { path, options } => {
+                self.open(&path, options).map(|(id, stat)| FileResponse::Opened(id, stat))
+            }
+            FileRequest::Read { file, offset, length } => self.read(file, offset, length).map(FileResponse::Data),
+            FileRequest::Write { file, offset, data } => {
+                self.write(file, offset, &data).map(|()| FileResponse::Written(data.len() as u32))
+            }
+            FileRequest::Map { .. } | FileRequest::Namespace { .. } => Err(FileError::Unsupported),
+            FileRequest::Stat { path } => self.stat(&path).map(FileResponse::Stat),
+            FileRequest::ReadDir { path, start } => self.read_dir(&path, start).map(FileResponse::Entries),
+            FileRequest::Close { file } => self.close(file).map(|()| FileResponse::Done),
+        };
+        result.unwrap_or_else(FileResponse::Error)
+    }
+
+    fn open(&mut self, path: &str, options: OpenOptions) -> Result<(FileId, FileStat), FileError> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        let mut entry = match self.fs.lookup(&components) {
+            Ok(entry) => entry,
+            Err(FatError::NotFound) if options.write && options.create => {
+                let (name, parent) = components.split_last().ok_or(FileError::InvalidPath)?;
+                let parent = self.fs.lookup(parent).map_err(to_file_error)?;
+                self.fs.create_file(&parent, name).map_err(to_file_error)?
+            }
+            Err(err) => return Err(to_file_error(err)),
+        };
+
+        if entry.is_dir() {
+            return Err(FileError::IsADirectory);
+        }
+        if options.write && options.truncate {
+            self.fs.truncate(&mut entry).map_err(to_file_error)?;
+            self.sync_open_files(&entry);
+        }
+
+        let id = FileId(self.next_file_id);
+        self.next_file_id += 1;
+        let stat = file_stat(&entry);
+        self.open_files.insert(id, OpenFile { entry, writable: options.write });
+        Ok((id, stat))
+    }
+
+    fn read(&mut self, file: FileId, offset: u64, length: u32) -> Result<Vec<u8>, FileError> {
+        if length as usize > MAX_TRANSFER_SIZE {
+            return Err(FileError::TooLarge);
+        }
+        let file = self.open_files.get(&file).ok_or(FileError::InvalidFile)?;
+        let mut buffer = vec![0; length as usize];
+        let read = self.fs.read(&file.entry, offset, &mut buffer).map_err(to_file_error)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    fn write(&mut self, file: FileId, offset: u64, data: &[u8]) -> Result<(), FileError> {
+        if data.len() > MAX_TRANSFER_SIZE {
+            return Err(FileError::TooLarge);
+        }
+        let file = self.open_files.get_mut(&file).ok_or(FileError::InvalidFile)?;
+        if !file.writable {
+            return Err(FileError::ReadOnly);
+        }
+        self.fs.write(&mut file.entry, offset, data).map_err(to_file_error)?;
+
+        let entry = file.entry.clone();
+        self.sync_open_files(&entry);
+        Ok(())
+    }
+
+    fn stat(&mut self, path: &str) -> Result<FileStat, FileError> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        let entry = self.fs.lookup(&components).map_err(to_file_error)?;
+        Ok(file_stat(&entry))
+    }
+
+    fn read_dir(&mut self, path: &str, start: u32) -> Result<Vec<file::DirEntry>, FileError> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        let dir = self.fs.lookup(&components).map_err(to_file_error)?;
+        let entries = self.fs.read_dir(&dir).map_err(to_file_error)?;
+        Ok(entries
+            .into_iter()
+            .skip(start as usize)
+            .take(MAX_DIR_ENTRIES)
+            .map(|entry| file::DirEntry { kind: file_kind(&entry), name: entry.name })
+            .collect())
+    }
+
+    fn close(&mut self, file: FileId) -> Result<(), FileError> {
+        let file = self.open_files.remove(&file).ok_or(FileError::InvalidFile)?;
+        if file.writable {
+            self.fs.flush().map_err(to_file_error)?;
+        }
+        Ok(())
+    }
+
+    /// The same file can be opened more than once, so make sure every open copy of a file's entry agrees with
+    /// one that has just changed.
+    fn sync_open_files(&mut self, entry: &DirEntry) {
+        for file in self.open_files.values_mut() {
+            if file.entry.is_same_file(entry) {
+                file.entry = entry.clone();
+            }
+        }
+    }
+}
+
+fn file_kind(entry: &DirEntry) -> FileKind {
+    if entry.is_dir() {
+        FileKind::Directory
+    } else {
+        FileKind::File
+    }
+}
+
+fn file_stat(entry: &DirEntry) -> FileStat {
+    // FAT volumes don't have permissions, so anyone can do anything the read-only attribute doesn't prevent
+    let permissions = match (entry.is_dir(), entry.attributes.contains(Attributes::READ_ONLY)) {
+        (true, false) => 0o777,
+        (true, true) => 0o555,
+        (false, false) => 0o666,
+        (false, true) => 0o444,
+    };
+    FileStat { kind: file_kind(entry), size: entry.size as u64, inode: entry.inode(), permissions }
+}
+
+fn to_file_error(err: FatError) -> FileError {
+    match err {
+        FatError::NotFound => FileError::NotFound,
+        FatError::AlreadyExists => FileError::AlreadyExists,
+        FatError::NotADirectory => FileError::NotADirectory,
+        FatError::IsADirectory => FileError::IsADirectory,
+        FatError::InvalidName => FileError::InvalidPath,
+        FatError::NoSpace => FileError::NoSpace,
+        FatError::Io | FatError::Unsupported | FatError::Corrupt => FileError::Io,
+    }
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("FAT filesystem driver is running!");
+
+    let service_host_client = ServiceHostClient::new();
+
+    let block_device =
+        BlockDeviceClient::new(service_host_client.subscribe_service(BLOCK_DEVICE_SERVICE).unwrap())
+            .expect("Failed to connect to block device");
+    if block_device.info.block_size as usize != SECTOR_SIZE {
+        panic!("Block devices with a block size of {} are not supported", block_device.info.block_size);
+    }
+    let partition = find_boot_partition(block_device).expect("Failed to find boot volume");
+    let fs = FileSystem::mount(partition).expect("Failed to mount FAT filesystem");
+
+    let vfs_channel: Channel<FilesystemMessage, FilesystemResponse> =
+        service_host_client.subscribe_service(VFS_FILESYSTEM_SERVICE).unwrap();
+    let (channel, channel_handle) = Channel::<FileResponse, FileRequest>::create().unwrap();
+    vfs_channel
+        .send(&FilesystemMessage::Mount { path: MOUNT_POINT.to_string(), channel: channel_handle })
+        .unwrap();
+    match vfs_channel.receive_blocking().unwrap() {
+        FilesystemResponse::Mounted => info!("Mounted boot volume at {}", MOUNT_POINT),
+        FilesystemResponse::Error(err) => panic!("Failed to mount boot volume: {:?}", err),
+    }
+
+    let mut server = FatServer { fs, open_files: BTreeMap::new(), next_file_id: 0 };
+    loop {
+        let (request, call) = channel.receive_call_blocking().unwrap();
+        let response = server.handle_request(request);
+        channel.reply(call, &response).unwrap();
+    }
+}
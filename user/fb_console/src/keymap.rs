@@ -0,0 +1,241 @@
+//! Keymaps translate the keys reported by HID devices into the characters they produce, which depends on the
+//! layout of the keyboard. Keys are reported by their position (using the names of the keys on a US keyboard),
+//! so the same key can produce different characters on different layouts (e.g. `Key::KeyZ` produces a `y` on a
+//! German keyboard).
+
+use platform_bus::input::{Key, KeyState};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "us" => Some(Layout::Us),
+            "uk" => Some(Layout::Uk),
+            "de" => Some(Layout::De),
+            _ => None,
+        }
+    }
+}
+
+/// The characters produced by a key, without any modifiers, with Shift held, and with AltGr held (if it produces
+/// anything).
+struct Entry(char, char, Option<char>);
+
+pub struct Keymap {
+    pub layout: Layout,
+    caps_lock: bool,
+}
+
+impl Keymap {
+    pub fn new(layout: Layout) -> Keymap {
+        Keymap { layout, caps_lock: false }
+    }
+
+    /// Should be called for every key press, so the keymap can track the state of the lock keys.
+    pub fn key_pressed(&mut self, key: Key) {
+        if key == Key::KeyCapslock {
+            self.caps_lock = !self.caps_lock;
+        }
+    }
+
+    /// Map a key to the character it produces with the given modifiers. Returns `None` for keys that don't produce
+    /// characters, such as the arrow keys and the modifier keys themselves.
+    pub fn map(&self, key: Key, state: KeyState) -> Option<char> {
+        match key {
+            Key::KeyReturn | Key::KeypadEnter => return Some('\n'),
+            /*
+             * XXX: confusingly, `KeyDelete` is actually backspace, and delete is `KeyDeleteForward`.
+             * We map to an `0x7f` ASCII `DEL`, which differs from an ASCII backspace (`0x08`), which
+             * moves the cursor but does not delete a character.
+             */
+            Key::KeyDelete => return Some('\x7f'),
+            Key::KeyTab => return Some('\t'),
+            Key::KeySpace => return Some(' '),
+            Key::KeypadSlash => return Some('/'),
+            Key::KeypadAsterix => return Some('*'),
+            Key::KeypadDash => return Some('-'),
+            Key::KeypadPlus => return Some('+'),
+            Key::KeypadDot => return Some('.'),
+            Key::Keypad1 => return Some('1'),
+            Key::Keypad2 => return Some('2'),
+            Key::Keypad3 => return Some('3'),
+            Key::Keypad4 => return Some('4'),
+            Key::Keypad5 => return Some('5'),
+            Key::Keypad6 => return Some('6'),
+            Key::Keypad7 => return Some('7'),
+            Key::Keypad8 => return Some('8'),
+            Key::Keypad9 => return Some('9'),
+            Key::Keypad0 => return Some('0'),
+            _ => (),
+        }
+
+        let Entry(normal, shifted, alt_gr) = self.entry(key)?;
+        // AltGr is the right Alt key. Ctrl+Alt is also commonly used in its place.
+        if state.right_alt || (state.ctrl() && state.left_alt) {
+            return alt_gr;
+        }
+
+        // Ctrl+<letter> produces the corresponding ASCII control character (e.g. Ctrl+C produces `0x03`)
+        if state.ctrl() {
+            return match normal {
+                'a'..='z' => Some((normal as u8 - b'a' + 1) as char),
+                '[' => Some('\x1b'),
+                _ => None,
+            };
+        }
+
+        // Caps Lock only affects letters, and inverts the effect of Shift
+        let shift = if normal.is_alphabetic() { state.shift() != self.caps_lock } else { state.shift() };
+        Some(if shift { shifted } else { normal })
+    }
+
+    fn entry(&self, key: Key) -> Option<Entry> {
+        if let Some(letter) = letter(key) {
+            let letter = match (self.layout, letter) {
+                (Layout::De, 'y') => 'z',
+                (Layout::De, 'z') => 'y',
+                (_, letter) => letter,
+            };
+            let alt_gr = match (self.layout, letter) {
+                (Layout::De, 'q') => Some('@'),
+                (Layout::De, 'e') => Some('€'),
+                (Layout::De, 'm') => Some('µ'),
+                _ => None,
+            };
+            return Some(Entry(letter, letter.to_ascii_uppercase(), alt_gr));
+        }
+
+        match self.layout {
+            Layout::Us => us(key),
+            Layout::Uk => uk(key),
+            Layout::De => de(key),
+        }
+    }
+}
+
+fn letter(key: Key) -> Option<char> {
+    Some(match key {
+        Key::KeyA => 'a',
+        Key::KeyB => 'b',
+        Key::KeyC => 'c',
+        Key::KeyD => 'd',
+        Key::KeyE => 'e',
+        Key::KeyF => 'f',
+        Key::KeyG => 'g',
+        Key::KeyH => 'h',
+        Key::KeyI => 'i',
+        Key::KeyJ => 'j',
+        Key::KeyK => 'k',
+        Key::KeyL => 'l',
+        Key::KeyM => 'm',
+        Key::KeyN => 'n',
+        Key::KeyO => 'o',
+        Key::KeyP => 'p',
+        Key::KeyQ => 'q',
+        Key::KeyR => 'r',
+        Key::KeyS => 's',
+        Key::KeyT => 't',
+        Key::KeyU => 'u',
+        Key::KeyV => 'v',
+        Key::KeyW => 'w',
+        Key::KeyX => 'x',
+        Key::KeyY => 'y',
+        Key::KeyZ => 'z',
+        _ => return None,
+    })
+}
+
+/*
+ * XXX: the names of `KeyForwardSlash` and `KeyBackSlash` are swapped - `KeyForwardSlash` is the key that produces
+ * `\` on a US keyboard, and `KeyBackSlash` produces `/`. `KeypadNonUsBackSlash` is the extra key next to the left
+ * Shift on ISO keyboards.
+ */
+
+fn us(key: Key) -> Option<Entry> {
+    Some(match key {
+        Key::Key1 => Entry('1', '!', None),
+        Key::Key2 => Entry('2', '@', None),
+        Key::Key3 => Entry('3', '#', None),
+        Key::Key4 => Entry('4', '$', None),
+        Key::Key5 => Entry('5', '%', None),
+        Key::Key6 => Entry('6', '^', None),
+        Key::Key7 => Entry('7', '&', None),
+        Key::Key8 => Entry('8', '*', None),
+        Key::Key9 => Entry('9', '(', None),
+        Key::Key0 => Entry('0', ')', None),
+        Key::KeyDash => Entry('-', '_', None),
+        Key::KeyEquals => Entry('=', '+', None),
+        Key::KeyLeftBracket => Entry('[', '{', None),
+        Key::KeyRightBracket => Entry(']', '}', None),
+        Key::KeyForwardSlash => Entry('\\', '|', None),
+        Key::KeyPound => Entry('#', '~', None),
+        Key::KeySemicolon => Entry(';', ':', None),
+        Key::KeyApostrophe => Entry('\'', '"', None),
+        Key::KeyGrave => Entry('`', '~', None),
+        Key::KeyComma => Entry(',', '<', None),
+        Key::KeyDot => Entry('.', '>', None),
+        Key::KeyBackSlash => Entry('/', '?', None),
+        Key::KeypadNonUsBackSlash => Entry('\\', '|', None),
+        _ => return None,
+    })
+}
+
+fn uk(key: Key) -> Option<Entry> {
+    Some(match key {
+        Key::Key2 => Entry('2', '"', None),
+        Key::Key3 => Entry('3', '£', None),
+        Key::Key4 => Entry('4', '$', Some('€')),
+        Key::KeyApostrophe => Entry('\'', '@', None),
+        Key::KeyGrave => Entry('`', '¬', Some('¦')),
+        _ => return us(key),
+    })
+}
+
+fn de(key: Key) -> Option<Entry> {
+    Some(match key {
+        Key::Key1 => Entry('1', '!', None),
+        Key::Key2 => Entry('2', '"', Some('²')),
+        Key::Key3 => Entry('3', '§', Some('³')),
+        Key::Key4 => Entry('4', '$', None),
+        Key::Key5 => Entry('5', '%', None),
+        Key::Key6 => Entry('6', '&', None),
+        Key::Key7 => Entry('7', '/', Some('{')),
+        Key::Key8 => Entry('8', '(', Some('[')),
+        Key::Key9 => Entry('9', ')', Some(']')),
+        Key::Key0 => Entry('0', '=', Some('}')),
+        Key::KeyDash => Entry('ß', '?', Some('\\')),
+        Key::KeyEquals => Entry('´', '`', None),
+        Key::KeyLeftBracket => Entry('ü', 'Ü', None),
+        Key::KeyRightBracket => Entry('+', '*', Some('~')),
+        Key::KeyForwardSlash | Key::KeyPound => Entry('#', '\'', None),
+        Key::KeySemicolon => Entry('ö', 'Ö', None),
+        Key::KeyApostrophe => Entry('ä', 'Ä', None),
+        Key::KeyGrave => Entry('^', '°', None),
+        Key::KeyComma => Entry(',', ';', None),
+        Key::KeyDot => Entry('.', ':', None),
+        Key::KeyBackSlash => Entry('-', '_', None),
+        Key::KeypadNonUsBackSlash => Entry('<', '>', Some('|')),
+        _ => return None,
+    })
+}
+
+/// Map keys that don't produce a character to the escape sequence a VT100 (or `xterm`, for keys a VT100 doesn't
+/// have) would send for them.
+pub fn map_key_to_sequence(key: Key) -> Option<&'static str> {
+    match key {
+        Key::KeyUpArrow => Some("\x1b[A"),
+        Key::KeyDownArrow => Some("\x1b[B"),
+        Key::KeyRightArrow => Some("\x1b[C"),
+        Key::KeyLeftArrow => Some("\x1b[D"),
+        Key::KeyHome => Some("\x1b[H"),
+        Key::KeyEnd => Some("\x1b[F"),
+        Key::KeyDeleteForward => Some("\x1b[3~"),
+        _ => None,
+    }
+}
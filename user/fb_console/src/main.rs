@@ -1,33 +1,41 @@
-//! `fb_console` is a console running on top of a framebuffer device, either provided through the
-//! kernel or by a driver for a graphics-capable device.
-
-// TODO: make a window manager and then make it so that this can drive a framebuffer directly, or
-// create a window for itself.
-
-use gfxconsole::{Framebuffer, GfxConsole};
+//! `fb_console` is a console running in a window provided by the compositor. It covers the whole display, and
+//! takes its input from the keyboard events the compositor sends to its window.
+
+mod keymap;
+mod repeat;
+
+use compositor::{CompositorRequest, CompositorResponse, WindowEvent, WindowRequest, COMPOSITOR_SERVICE};
+use core::time::Duration;
+use gfxconsole::{
+    font::{Font, Psf2Font},
+    Format,
+    Framebuffer,
+    GfxConsole,
+};
 use ginkgo::{
     ast::BindingResolver,
     interpreter::{Interpreter, Value},
     parse::Parser,
 };
-use log::info;
-use platform_bus::{
-    input::{InputEvent as PlatformBusInputEvent, Key, KeyState},
-    DeviceDriverMessage,
-    DeviceDriverRequest,
-    Filter,
-    Property,
-};
-use service_host::ServiceHostClient;
+use keymap::{map_key_to_sequence, Keymap, Layout};
+use log::{info, warn};
+use repeat::{KeyRepeat, KeyRepeatConfig};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
 use spinning_top::Spinlock;
 use std::{
     fmt::Write,
+    mem,
     poplar::{
         channel::Channel,
+        console::{ConsoleEvent, ConsoleRequest, CONSOLE_SERVICE},
         early_logger::EarlyLogger,
+        file::{OpenOptions, Vfs, VFS_SERVICE},
         memory_object::{MappedMemoryObject, MemoryObject},
-        syscall::MemoryObjectFlags,
+        sync::Mutex,
+        syscall::{MapMemoryObjectFlags, MemoryObjectFlags},
+        Handle,
     },
+    sync::Arc,
 };
 
 #[derive(Clone, Copy, Default, Debug)]
@@ -36,53 +44,228 @@ enum InputEvent {
     #[default]
     Default,
     KeyPressed(char),
-    RelX(i32),
-    RelY(i32),
+    /// A key that doesn't produce a character, such as an arrow key, as the escape sequence a VT100 would send
+    /// for it.
+    KeySequence(&'static str),
+    /// The compositor has resized our window, and given us a new surface to draw into.
+    Resized {
+        surface: Handle,
+        surface_size: usize,
+        width: usize,
+        height: usize,
+    },
 }
 
+/// How long the cursor is shown or hidden for each time it blinks.
+const CURSOR_BLINK_PERIOD: Duration = Duration::from_millis(500);
+
 struct Console {
-    framebuffer: MappedMemoryObject,
-    control_channel: Channel<(), ()>,
-    width: usize,
-    height: usize,
-    console: Spinlock<GfxConsole>,
-    input_events: thingbuf::mpsc::Receiver<InputEvent>,
+    format: Format,
+    surface: Spinlock<MappedMemoryObject>,
+    /// The console is drawn into an off-screen back buffer, and changes are copied to our window's surface (the
+    /// front buffer) when they're flushed. This stops partly-drawn changes (e.g. during a scroll) from being
+    /// visible, as the compositor can read the surface at any time.
+    back_buffer: Spinlock<MappedMemoryObject>,
+    front_buffer: Spinlock<Framebuffer>,
+    compositor: Channel<CompositorRequest, CompositorResponse>,
+    window_channel: Arc<Channel<WindowRequest, WindowEvent>>,
+    console: Mutex<GfxConsole>,
+    /// The clients of the `console` service that have asked to receive input. Input is sent to the last client
+    /// in the list, and goes to the built-in shell if no clients are attached.
+    input_clients: Spinlock<Vec<Arc<ConsoleClient>>>,
 
     // TODO: we really need to separate out the like rendering/input management layer and the shell
     // logic
-    platform_bus_inspect: Channel<(), platform_bus::PlatformBusInspect>,
+    platform_bus_inspect: Channel<platform_bus::InspectRequest, platform_bus::PlatformBusInspect>,
+}
+
+struct ConsoleClient {
+    name: String,
+    channel: Channel<ConsoleEvent, ConsoleRequest>,
+}
+
+/// Map a window surface that the compositor has given us, and create a back buffer of the same size.
+fn map_buffers(
+    surface: Handle,
+    surface_size: usize,
+    width: usize,
+    height: usize,
+    format: Format,
+) -> (MappedMemoryObject, MappedMemoryObject) {
+    let surface =
+        unsafe { MemoryObject::from_handle(surface, surface_size, MemoryObjectFlags::WRITABLE).map().unwrap() };
+
+    let back_buffer_size = width * height * format.bytes_per_pixel();
+    let back_buffer = unsafe {
+        MemoryObject::create(back_buffer_size, MemoryObjectFlags::WRITABLE)
+            .unwrap()
+            .map_with_flags(MapMemoryObjectFlags::HUGE_PAGES)
+            .unwrap()
+    };
+
+    (surface, back_buffer)
+}
+
+impl Console {
+    /// Ask the compositor to redraw the parts of our window that have changed.
+    fn flush(&self) {
+        let damage = self.console.lock().present(&mut self.front_buffer.lock());
+        if let Some(damage) = damage {
+            self.window_channel
+                .send(&WindowRequest::Damage {
+                    x: damage.x as u32,
+                    y: damage.y as u32,
+                    width: damage.width as u32,
+                    height: damage.height as u32,
+                })
+                .unwrap();
+        }
+    }
+
+    /// Switch to a new surface after our window has been resized. The contents of the console are kept, although
+    /// they may be cut off if the window has got smaller.
+    fn resize(&self, surface: Handle, surface_size: usize, width: usize, height: usize) {
+        let (surface, back_buffer) = map_buffers(surface, surface_size, width, height, self.format);
+
+        *self.front_buffer.lock() = Framebuffer::new(surface.ptr() as *mut u8, width, height, width, self.format);
+        self.console.lock().resize(Framebuffer::new(
+            back_buffer.ptr() as *mut u8,
+            width,
+            height,
+            width,
+            self.format,
+        ));
+
+        // Nothing draws into the old buffers anymore, so they can be unmapped
+        let old_surface = mem::replace(&mut *self.surface.lock(), surface);
+        let old_back_buffer = mem::replace(&mut *self.back_buffer.lock(), back_buffer);
+        unsafe {
+            old_surface.unmap().unwrap();
+            old_back_buffer.unmap().unwrap();
+        }
+        self.flush();
+    }
+
+    /// Send some input to the attached client, if there is one. Returns `false` if there are no clients that
+    /// want input, in which case the input should be handled by the built-in shell.
+    fn send_input_to_client(&self, input: &str) -> bool {
+        let mut input_clients = self.input_clients.lock();
+        while let Some(client) = input_clients.last() {
+            match client.channel.send(&ConsoleEvent::Input(input.to_string())) {
+                Ok(()) => return true,
+                Err(err) => {
+                    warn!("Failed to send input to console client '{}' ({:?}). Detaching it.", client.name, err);
+                    input_clients.pop();
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Serve a client of the `console` service.
+    async fn serve_client(&self, client: Arc<ConsoleClient>) {
+        loop {
+            let Ok(request) = client.channel.receive().await else {
+                warn!("Failed to receive message from console client '{}'", client.name);
+                break;
+            };
+
+            match request {
+                ConsoleRequest::Write(text) => {
+                    write!(self.console.lock(), "{}", text).unwrap();
+                    self.flush();
+                }
+                ConsoleRequest::AttachInput => {
+                    let mut input_clients = self.input_clients.lock();
+                    input_clients.retain(|other| !Arc::ptr_eq(other, &client));
+                    input_clients.push(client.clone());
+                }
+                ConsoleRequest::DetachInput => {
+                    self.input_clients.lock().retain(|other| !Arc::ptr_eq(other, &client));
+                }
+            }
+        }
+
+        self.input_clients.lock().retain(|other| !Arc::ptr_eq(other, &client));
+    }
 }
 
-fn spawn_framebuffer(
-    framebuffer: MappedMemoryObject,
-    channel: Channel<(), ()>,
+fn spawn_console(
+    surface: Handle,
+    surface_size: usize,
+    compositor: Channel<CompositorRequest, CompositorResponse>,
+    window_channel: Arc<Channel<WindowRequest, WindowEvent>>,
     width: usize,
     height: usize,
+    format: Format,
     input_events: thingbuf::mpsc::Receiver<InputEvent>,
+    console_service_channel: Channel<(), ServiceChannelMessage>,
+    keymap: Arc<Spinlock<Keymap>>,
+    key_repeat_config: Arc<Spinlock<KeyRepeatConfig>>,
     service_host_client: &ServiceHostClient,
 ) {
     let platform_bus_inspect = service_host_client.subscribe_service("platform_bus.inspect").unwrap();
+    // Used to load fonts from the initramfs
+    let vfs = Vfs::new(service_host_client.subscribe_service(VFS_SERVICE).unwrap());
 
-    let console = Spinlock::new(GfxConsole::new(
-        Framebuffer::new(framebuffer.ptr() as *mut u32, width, height, width, 0, 8, 16),
+    let (surface, back_buffer) = map_buffers(surface, surface_size, width, height, format);
+
+    let front_buffer = Framebuffer::new(surface.ptr() as *mut u8, width, height, width, format);
+    let console = Mutex::new(GfxConsole::new(
+        Framebuffer::new(back_buffer.ptr() as *mut u8, width, height, width, format),
         0x00000000,
         0xffffffff,
     ));
-    let console = Console {
-        framebuffer,
-        control_channel: channel,
-        width,
-        height,
+    let console = Arc::new(Console {
+        format,
+        surface: Spinlock::new(surface),
+        back_buffer: Spinlock::new(back_buffer),
+        front_buffer: Spinlock::new(front_buffer),
+        compositor,
+        window_channel,
         console,
-        input_events,
+        input_clients: Spinlock::new(Vec::new()),
         platform_bus_inspect,
-    };
+    });
+
+    // Blink the cursor
+    std::poplar::rt::spawn({
+        let console = console.clone();
+        async move {
+            let mut interval = std::poplar::rt::time::interval(CURSOR_BLINK_PERIOD);
+            let mut shown = false;
+            loop {
+                interval.tick().await;
+                shown = !shown;
+                console.console.lock().set_cursor_shown(shown);
+                console.flush();
+            }
+        }
+    });
+
+    // Serve clients of the `console` service, including any that subscribed before our window was created
+    std::poplar::rt::spawn({
+        let console = console.clone();
+        async move {
+            loop {
+                match console_service_channel.receive().await.unwrap() {
+                    ServiceChannelMessage::NewClient { name, channel } => {
+                        info!("Task '{}' subscribed to the console", name);
+                        let client = Arc::new(ConsoleClient { name, channel: Channel::new_from_handle(channel) });
+                        let console = console.clone();
+                        std::poplar::rt::spawn(async move { console.serve_client(client).await });
+                    }
+                }
+            }
+        }
+    });
 
     std::poplar::rt::spawn(async move {
         // TODO: separate out graphical layer and shell layer with another channel maybe??
         writeln!(console.console.lock(), "Welcome to Poplar!").unwrap();
         write!(console.console.lock(), "> ").unwrap();
-        console.control_channel.send(&()).unwrap();
+        console.flush();
 
         let (output_sender, output_receiver) = thingbuf::mpsc::channel(16);
 
@@ -107,22 +290,104 @@ fn spawn_framebuffer(
             Value::String("Poplar 0.1.0".to_string())
         });
 
+        interpreter.define_native_function("set_keymap", |params| {
+            assert!(params.len() == 1);
+            match params.get(0).unwrap() {
+                Value::String(name) => match Layout::from_name(name) {
+                    Some(layout) => {
+                        keymap.lock().layout = layout;
+                        Value::Bool(true)
+                    }
+                    None => Value::Bool(false),
+                },
+                _ => Value::Bool(false),
+            }
+        });
+
+        interpreter.define_native_function("set_key_repeat", |params| {
+            assert!(params.len() == 2);
+            match (params.get(0).unwrap(), params.get(1).unwrap()) {
+                (Value::Integer(delay), Value::Integer(rate)) if *delay >= 0 && *rate >= 0 => {
+                    *key_repeat_config.lock() =
+                        KeyRepeatConfig { delay: Duration::from_millis(*delay as u64), rate: *rate as u32 };
+                    Value::Bool(true)
+                }
+                _ => Value::Bool(false),
+            }
+        });
+
         interpreter.define_native_function("inspect_platform_bus", |params| {
             assert!(params.len() == 0);
-            console.platform_bus_inspect.send(&()).unwrap();
+            console
+                .platform_bus_inspect
+                .send(&platform_bus::InspectRequest { version: platform_bus::INSPECT_VERSION })
+                .unwrap();
             let info = console.platform_bus_inspect.receive_blocking().unwrap();
             output_sender.try_send(Value::String(format!("{:#?}", info))).unwrap();
             Value::Bool(true)
         });
 
-        let mut mouse_x = 300u32;
-        let mut mouse_y = 300u32;
+        interpreter.define_native_function("set_font", |params| {
+            assert!(params.len() == 1);
+            let path = match params.get(0).unwrap() {
+                Value::String(path) => path,
+                _ => return Value::Bool(false),
+            };
+
+            let font = if path == "builtin" {
+                Font::Builtin
+            } else {
+                let data = match vfs.open(path, OpenOptions::read()).and_then(|mut file| file.read_to_end()) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        warn!("Failed to read font from '{}': {:?}", path, err);
+                        return Value::Bool(false);
+                    }
+                };
+                match Psf2Font::parse(data) {
+                    Ok(font) => Font::Psf2(font),
+                    Err(err) => {
+                        warn!("Failed to parse font '{}': {:?}", path, err);
+                        return Value::Bool(false);
+                    }
+                }
+            };
+            console.console.lock().set_font(font);
+            console.flush();
+            Value::Bool(true)
+        });
+
+        interpreter.define_native_function("set_resolution", |params| {
+            assert!(params.len() == 2);
+            let (width, height) = match (params.get(0).unwrap(), params.get(1).unwrap()) {
+                (Value::Integer(width), Value::Integer(height)) if *width > 0 && *height > 0 => {
+                    (*width as u32, *height as u32)
+                }
+                _ => return Value::Bool(false),
+            };
+
+            /*
+             * If the mode is set, the compositor tells our window about the new size of the display, and we ask
+             * for the window to be resized to match.
+             */
+            console.compositor.send(&CompositorRequest::SetDisplayMode { width, height }).unwrap();
+            match console.compositor.receive_blocking().unwrap() {
+                CompositorResponse::DisplayModeSet => Value::Bool(true),
+                _ => Value::Bool(false),
+            }
+        });
 
         loop {
             let mut needs_redraw = false;
 
-            if let Some(event) = console.input_events.recv().await {
+            if let Some(event) = input_events.recv().await {
                 match event {
+                    InputEvent::KeyPressed(key) if console.send_input_to_client(key.encode_utf8(&mut [0; 4])) => {
+                        ()
+                    }
+                    InputEvent::KeySequence(sequence) if console.send_input_to_client(sequence) => (),
+                    // The built-in shell doesn't support any keys that don't produce characters
+                    InputEvent::KeySequence(_) => (),
                     InputEvent::KeyPressed(key) => {
                         // TODO: `noline` is a no-std REPL impl crate thingy that could be useful
                         // for improving this experience
@@ -177,23 +442,15 @@ fn spawn_framebuffer(
                             }
                         }
                     }
-                    InputEvent::RelX(value) => {
-                        mouse_x = mouse_x.saturating_add_signed(value);
-                        needs_redraw = true;
+                    InputEvent::Resized { surface, surface_size, width, height } => {
+                        console.resize(surface, surface_size, width, height);
                     }
-                    InputEvent::RelY(value) => {
-                        mouse_y = mouse_y.saturating_add_signed(value);
-                        needs_redraw = true;
-                    }
-
                     InputEvent::Default => panic!(),
                 }
             }
 
             if needs_redraw {
-                // TODO: this obvs won't remove the old cursor - we need a proper thing for that...
-                console.console.lock().framebuffer.draw_rect(mouse_x as usize, mouse_y as usize, 4, 4, 0xffff00ff);
-                console.control_channel.send(&()).unwrap();
+                console.flush();
             }
         }
     });
@@ -207,219 +464,110 @@ fn main() {
     std::poplar::rt::init_runtime();
 
     let (input_sender, input_receiver) = thingbuf::mpsc::channel(16);
+    // TODO: the default layout should be configurable
+    let keymap = Arc::new(Spinlock::new(Keymap::new(Layout::Us)));
+    let key_repeat_config = Arc::new(Spinlock::new(KeyRepeatConfig::default()));
 
     std::poplar::rt::spawn(async move {
-        let mut input_receiver = Some(input_receiver);
-
         let service_host_client = ServiceHostClient::new();
-        // We act as a device driver to find framebuffers and input devices
-        let platform_bus_device_channel: Channel<DeviceDriverMessage, DeviceDriverRequest> =
-            service_host_client.subscribe_service("platform_bus.device_driver").unwrap();
-        platform_bus_device_channel
-            .send(&DeviceDriverMessage::RegisterInterest(vec![
-                Filter::Matches(String::from("type"), Property::String("framebuffer".to_string())),
-                Filter::Matches(String::from("hid.type"), Property::String("keyboard".to_string())),
-                Filter::Matches(String::from("hid.type"), Property::String("mouse".to_string())),
-            ]))
-            .unwrap();
-
-        loop {
-            let message = platform_bus_device_channel.receive().await.unwrap();
-            match message {
-                DeviceDriverRequest::QuerySupport(name, _) => {
-                    platform_bus_device_channel.send(&DeviceDriverMessage::CanSupport(name, true)).unwrap();
-                }
-                DeviceDriverRequest::HandoffDevice(name, device_info, handoff_info) => {
-                    if let Some("framebuffer") = device_info.get_as_str("type") {
-                        info!("Found framebuffer device: {}", name);
-
-                        let (width, height) = (
-                            device_info.get_as_integer("width").unwrap() as usize,
-                            device_info.get_as_integer("height").unwrap() as usize,
-                        );
-                        let framebuffer = unsafe {
-                            MemoryObject::from_handle(
-                                handoff_info.get_as_memory_object("framebuffer").unwrap(),
-                                width * height * 4,
-                                MemoryObjectFlags::WRITABLE,
-                            )
-                        };
-                        let channel: Channel<(), ()> =
-                            Channel::new_from_handle(handoff_info.get_as_channel("channel").unwrap());
-
-                        // Map the framebuffer into our address space
-                        const FRAMEBUFFER_ADDDRESS: usize = 0x00000005_00000000;
-                        let framebuffer = unsafe { framebuffer.map_at(FRAMEBUFFER_ADDDRESS).unwrap() };
-
-                        spawn_framebuffer(
-                            framebuffer,
-                            channel,
-                            width,
-                            height,
-                            input_receiver.take().unwrap(),
-                            &service_host_client,
-                        );
-                    } else if device_info.get_as_str("hid.type").is_some() {
-                        info!("Found HID-compatible input device: {}", name);
-
-                        let channel: Channel<(), PlatformBusInputEvent> =
-                            Channel::new_from_handle(handoff_info.get_as_channel("hid.channel").unwrap());
-                        let input_sender = input_sender.clone();
-
-                        std::poplar::rt::spawn(async move {
-                            loop {
-                                let event = channel.receive().await.unwrap();
-                                match event {
-                                    PlatformBusInputEvent::KeyPressed { key, state } => match key {
-                                        Key::BtnLeft => {
-                                            info!("Left mouse button");
-                                        }
-                                        Key::BtnRight => {
-                                            info!("Right mouse button");
-                                        }
-                                        Key::BtnMiddle => {
-                                            info!("Middle mouse button");
-                                        }
-                                        Key::BtnSide | Key::BtnExtra => {}
-
-                                        other => {
-                                            input_sender
-                                                .send(InputEvent::KeyPressed(map_key(key, state).unwrap()))
-                                                .await
-                                                .unwrap();
-                                        }
-                                    },
-                                    PlatformBusInputEvent::RelX(value) => {
-                                        input_sender.send(InputEvent::RelX(value)).await.unwrap();
-                                    }
-                                    PlatformBusInputEvent::RelY(value) => {
-                                        input_sender.send(InputEvent::RelY(value)).await.unwrap();
-                                    }
-                                    PlatformBusInputEvent::RelWheel(_) => {}
-                                    _ => (),
-                                }
-                            }
-                        });
-                    } else {
-                        panic!("Passed unsupported device!");
+        /*
+         * Provide the `console` service, so other tasks can use the console. We register it straight away, so
+         * tasks can subscribe to it before we've got a window - they'll be served once we have.
+         */
+        let console_service_channel = service_host_client.register_service(CONSOLE_SERVICE).unwrap();
+
+        // Ask the compositor for a window that covers the whole display
+        let compositor: Channel<CompositorRequest, CompositorResponse> =
+            service_host_client.subscribe_service(COMPOSITOR_SERVICE).unwrap();
+        compositor.send(&CompositorRequest::GetDisplayInfo).unwrap();
+        let (width, height, format) = match compositor.receive().await.unwrap() {
+            CompositorResponse::DisplayInfo { width, height, format } => (width, height, format),
+            other => panic!("Unexpected response from compositor: {:?}", other),
+        };
+        let Some(format) = Format::from_name(&format) else {
+            panic!("Display has unsupported pixel format: {}", format);
+        };
+        compositor.send(&CompositorRequest::CreateWindow { x: 0, y: 0, width, height }).unwrap();
+        let (surface, surface_size, window_channel) = match compositor.receive().await.unwrap() {
+            CompositorResponse::WindowCreated { surface, surface_size, channel } => {
+                (surface, surface_size, channel)
+            }
+            other => panic!("Failed to create window for console: {:?}", other),
+        };
+        info!("Created {}x{} window for console", width, height);
+
+        let window_channel: Arc<Channel<WindowRequest, WindowEvent>> =
+            Arc::new(Channel::new_from_handle(window_channel));
+
+        // Translate the keys pressed in our window into characters
+        std::poplar::rt::spawn({
+            let window_channel = window_channel.clone();
+            let keymap = keymap.clone();
+            let key_repeat = KeyRepeat::new(key_repeat_config.clone());
+            async move {
+                loop {
+                    match window_channel.receive().await.unwrap() {
+                        WindowEvent::KeyPressed { key, state } => {
+                            let c = {
+                                let mut keymap = keymap.lock();
+                                keymap.key_pressed(key);
+                                keymap.map(key, state)
+                            };
+                            let event = if let Some(c) = c {
+                                InputEvent::KeyPressed(c)
+                            } else if let Some(sequence) = map_key_to_sequence(key) {
+                                InputEvent::KeySequence(sequence)
+                            } else {
+                                continue;
+                            };
+                            input_sender.send(event).await.unwrap();
+
+                            let input_sender = input_sender.clone();
+                            key_repeat.key_pressed(key, move || {
+                                let input_sender = input_sender.clone();
+                                async move { input_sender.send(event).await.unwrap() }
+                            });
+                        }
+                        WindowEvent::KeyReleased { key, .. } => key_repeat.key_released(key),
+                        // Keep our window covering the whole display
+                        WindowEvent::DisplayResized { width, height } => {
+                            window_channel.send(&WindowRequest::Resize { width, height }).unwrap();
+                        }
+                        WindowEvent::Resized { surface, surface_size, width, height } => {
+                            let event = InputEvent::Resized {
+                                surface,
+                                surface_size,
+                                width: width as usize,
+                                height: height as usize,
+                            };
+                            input_sender.send(event).await.unwrap();
+                        }
+                        WindowEvent::ResizeRefused => warn!("Compositor refused to resize console window"),
+                        // TODO: nothing on the console can be clicked on yet
+                        WindowEvent::PointerMoved { .. }
+                        | WindowEvent::ButtonPressed(_)
+                        | WindowEvent::ButtonReleased(_)
+                        | WindowEvent::Scroll(_) => (),
                     }
                 }
             }
-        }
+        });
+
+        spawn_console(
+            surface,
+            surface_size,
+            compositor,
+            window_channel,
+            width as usize,
+            height as usize,
+            format,
+            input_receiver,
+            console_service_channel,
+            keymap,
+            key_repeat_config,
+            &service_host_client,
+        );
     });
 
     std::poplar::rt::enter_loop();
 }
-
-// TODO: we should probably be able to define a keymap in a more data-oriented way in the future
-// TODO: I'm not sure if we'll want to map everything to UTF-8 or if some would need different
-// control-esque types or something?
-pub fn map_key(usage: Key, state: KeyState) -> Option<char> {
-    match (usage, state.shift()) {
-        (Key::KeyA, false) => Some('a'),
-        (Key::KeyA, true) => Some('A'),
-        (Key::KeyB, false) => Some('b'),
-        (Key::KeyB, true) => Some('B'),
-        (Key::KeyC, false) => Some('c'),
-        (Key::KeyC, true) => Some('C'),
-        (Key::KeyD, false) => Some('d'),
-        (Key::KeyD, true) => Some('D'),
-        (Key::KeyE, false) => Some('e'),
-        (Key::KeyE, true) => Some('E'),
-        (Key::KeyF, false) => Some('f'),
-        (Key::KeyF, true) => Some('F'),
-        (Key::KeyG, false) => Some('g'),
-        (Key::KeyG, true) => Some('G'),
-        (Key::KeyH, false) => Some('h'),
-        (Key::KeyH, true) => Some('H'),
-        (Key::KeyI, false) => Some('i'),
-        (Key::KeyI, true) => Some('I'),
-        (Key::KeyJ, false) => Some('j'),
-        (Key::KeyJ, true) => Some('J'),
-        (Key::KeyK, false) => Some('k'),
-        (Key::KeyK, true) => Some('K'),
-        (Key::KeyL, false) => Some('l'),
-        (Key::KeyL, true) => Some('L'),
-        (Key::KeyM, false) => Some('m'),
-        (Key::KeyM, true) => Some('M'),
-        (Key::KeyN, false) => Some('n'),
-        (Key::KeyN, true) => Some('N'),
-        (Key::KeyO, false) => Some('o'),
-        (Key::KeyO, true) => Some('O'),
-        (Key::KeyP, false) => Some('p'),
-        (Key::KeyP, true) => Some('P'),
-        (Key::KeyQ, false) => Some('q'),
-        (Key::KeyQ, true) => Some('Q'),
-        (Key::KeyR, false) => Some('r'),
-        (Key::KeyR, true) => Some('R'),
-        (Key::KeyS, false) => Some('s'),
-        (Key::KeyS, true) => Some('S'),
-        (Key::KeyT, false) => Some('t'),
-        (Key::KeyT, true) => Some('T'),
-        (Key::KeyU, false) => Some('u'),
-        (Key::KeyU, true) => Some('U'),
-        (Key::KeyV, false) => Some('v'),
-        (Key::KeyV, true) => Some('V'),
-        (Key::KeyW, false) => Some('w'),
-        (Key::KeyW, true) => Some('W'),
-        (Key::KeyX, false) => Some('x'),
-        (Key::KeyX, true) => Some('X'),
-        (Key::KeyY, false) => Some('y'),
-        (Key::KeyY, true) => Some('Y'),
-        (Key::KeyZ, false) => Some('z'),
-        (Key::Key1, false) => Some('1'),
-        (Key::Key1, true) => Some('!'),
-        (Key::Key2, false) => Some('2'),
-        (Key::Key2, true) => Some('@'),
-        (Key::Key3, false) => Some('3'),
-        (Key::Key3, true) => Some('#'),
-        (Key::Key4, false) => Some('4'),
-        (Key::Key4, true) => Some('$'),
-        (Key::Key5, false) => Some('5'),
-        (Key::Key5, true) => Some('%'),
-        (Key::Key6, false) => Some('6'),
-        (Key::Key6, true) => Some('^'),
-        (Key::Key7, false) => Some('7'),
-        (Key::Key7, true) => Some('&'),
-        (Key::Key8, false) => Some('8'),
-        (Key::Key8, true) => Some('*'),
-        (Key::Key9, false) => Some('9'),
-        (Key::Key9, true) => Some('('),
-        (Key::Key0, false) => Some('0'),
-        (Key::Key0, true) => Some(')'),
-        (Key::KeyReturn, _) => Some('\n'),
-        (Key::KeyEscape, _) => None,
-        /*
-         * XXX: confusingly, `KeyDelete` is actually backspace, and delete is `KeyDeleteForward`.
-         * We map to an `0x7f` ASCII `DEL`, which differs from an ASCII backspace (`0x08`), which
-         * moves the cursor but does not delete a character.
-         */
-        (Key::KeyDelete, _) => Some('\x7f'),
-        (Key::KeyTab, _) => Some('\t'),
-        (Key::KeySpace, _) => Some(' '),
-        (Key::KeyDash, false) => Some('-'),
-        (Key::KeyDash, true) => Some('_'),
-        (Key::KeyEquals, false) => Some('='),
-        (Key::KeyEquals, true) => Some('+'),
-        (Key::KeyLeftBracket, false) => Some('['),
-        (Key::KeyLeftBracket, true) => Some('{'),
-        (Key::KeyRightBracket, false) => Some(']'),
-        (Key::KeyRightBracket, true) => Some('}'),
-        (Key::KeyForwardSlash, false) => Some('\\'),
-        (Key::KeyForwardSlash, true) => Some('|'),
-        (Key::KeyPound, _) => Some('#'),
-        (Key::KeySemicolon, false) => Some(';'),
-        (Key::KeySemicolon, true) => Some(':'),
-        (Key::KeyApostrophe, false) => Some('\''),
-        (Key::KeyApostrophe, true) => Some('"'),
-        (Key::KeyGrave, false) => Some('`'),
-        (Key::KeyGrave, true) => Some('~'),
-        (Key::KeyComma, false) => Some(','),
-        (Key::KeyComma, true) => Some('<'),
-        (Key::KeyDot, false) => Some('.'),
-        (Key::KeyDot, true) => Some('>'),
-        (Key::KeyBackSlash, false) => Some('/'),
-        (Key::KeyBackSlash, true) => Some('?'),
-        _ => None,
-    }
-}
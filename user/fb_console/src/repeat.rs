@@ -0,0 +1,86 @@
+//! Keyboards only report when keys are pressed and released, so we repeat keys that are held down ourselves.
+//! After a key has been held for `KeyRepeatConfig::delay`, it is repeated at `KeyRepeatConfig::rate` times per
+//! second until it's released or another key is pressed.
+
+use core::{future::Future, time::Duration};
+use platform_bus::input::Key;
+use spinning_top::Spinlock;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug)]
+pub struct KeyRepeatConfig {
+    pub delay: Duration,
+    /// The number of times a key is repeated per second. Key repeat is disabled if this is zero.
+    pub rate: u32,
+}
+
+impl Default for KeyRepeatConfig {
+    fn default() -> Self {
+        KeyRepeatConfig { delay: Duration::from_millis(500), rate: 25 }
+    }
+}
+
+/// Tracks the key that's currently held on a keyboard. There should be one of these for each keyboard.
+#[derive(Clone)]
+pub struct KeyRepeat {
+    config: Arc<Spinlock<KeyRepeatConfig>>,
+    held: Arc<Spinlock<Held>>,
+}
+
+struct Held {
+    key: Option<Key>,
+    /// Incremented each time the held key changes, so a repeating key can tell if it's been pressed again in the
+    /// meantime.
+    generation: u64,
+}
+
+impl KeyRepeat {
+    pub fn new(config: Arc<Spinlock<KeyRepeatConfig>>) -> KeyRepeat {
+        KeyRepeat { config, held: Arc::new(Spinlock::new(Held { key: None, generation: 0 })) }
+    }
+
+    /// Start repeating `key`, by calling `repeat` each time it should be repeated. This stops any other key that
+    /// is being repeated on this keyboard.
+    pub fn key_pressed<F, R>(&self, key: Key, repeat: F)
+    where
+        F: Fn() -> R + Send + 'static,
+        R: Future<Output = ()> + Send,
+    {
+        let generation = {
+            let mut held = self.held.lock();
+            held.key = Some(key);
+            held.generation += 1;
+            held.generation
+        };
+
+        let config = *self.config.lock();
+        if config.rate == 0 {
+            return;
+        }
+
+        let held = self.held.clone();
+        let still_held = move || {
+            let held = held.lock();
+            held.key == Some(key) && held.generation == generation
+        };
+        std::poplar::rt::spawn(async move {
+            std::poplar::rt::time::sleep(config.delay).await;
+            let mut interval = std::poplar::rt::time::interval(Duration::from_secs(1) / config.rate);
+            loop {
+                interval.tick().await;
+                if !still_held() {
+                    break;
+                }
+                repeat().await;
+            }
+        });
+    }
+
+    pub fn key_released(&self, key: Key) {
+        let mut held = self.held.lock();
+        if held.key == Some(key) {
+            held.key = None;
+            held.generation += 1;
+        }
+    }
+}
@@ -2,8 +2,18 @@ use service_host::ServiceHostClient;
 
 fn main() {
     std::poplar::syscall::early_log("Hello, World!").unwrap();
-    // println!("Hello, world!");
 
     let service_host = ServiceHostClient::new();
+
+    // If we were launched from a shell, say hello on its console too (to whoever we were asked to greet)
+    if std::poplar::stdio::stdout().is_some() {
+        let names: Vec<String> = std::env::args().skip(1).collect();
+        if names.is_empty() {
+            println!("Hello, World!");
+        } else {
+            println!("Hello, {}!", names.join(" "));
+        }
+    }
+
     let service_channel = service_host.register_service("hello_world").unwrap();
 }
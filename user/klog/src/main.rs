@@ -0,0 +1,76 @@
+//! `klog` streams the kernel's log to the console, so it can be seen on the display as well as on the serial port.
+//! It polls the kernel log with `read_kernel_log`, which requires the `READ_KERNEL_LOG` capability.
+
+use log::{info, warn};
+use service_host::ServiceHostClient;
+use std::{
+    borrow::Cow,
+    poplar::{
+        channel::Channel,
+        console::{self, ConsoleEvent, ConsoleRequest, CONSOLE_SERVICE},
+        early_logger::EarlyLogger,
+        syscall,
+    },
+    time::Duration,
+};
+
+/// How often to check the kernel log for new output.
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("klog is running!");
+
+    std::poplar::rt::init_runtime();
+
+    std::poplar::rt::spawn(async move {
+        let service_host = ServiceHostClient::new();
+        let console: Channel<ConsoleRequest, ConsoleEvent> =
+            service_host.subscribe_service(CONSOLE_SERVICE).unwrap();
+
+        let mut buffer = vec![0u8; console::MAX_WRITE_LENGTH];
+        let mut position = 0;
+        let mut interval = std::poplar::rt::time::interval(POLL_PERIOD);
+
+        loop {
+            let (start, length) = match syscall::read_kernel_log(position, &mut buffer) {
+                Ok(read) => read,
+                Err(err) => {
+                    // Anything we log ends up in the kernel log, so don't keep trying if this fails
+                    warn!("Failed to read kernel log: {:?}", err);
+                    break;
+                }
+            };
+
+            if length == 0 {
+                interval.tick().await;
+                continue;
+            }
+
+            if start > position {
+                let message = format!("\x1b[33m[{} bytes of the kernel log were lost]\x1b[0m\n", start - position);
+                console::write(&console, &message).unwrap();
+            }
+
+            let (text, consumed) = decode(&buffer[0..length]);
+            console::write(&console, &text).unwrap();
+            position = start + consumed as u64;
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
+
+/// Decode a chunk of the kernel log. A read can end part-way through a character, in which case the partial
+/// character is left to be read again with the rest of it. Returns the text, and the number of bytes used.
+fn decode(bytes: &[u8]) -> (Cow<'_, str>, usize) {
+    match core::str::from_utf8(bytes) {
+        Ok(text) => (Cow::Borrowed(text), bytes.len()),
+        Err(err) if err.error_len().is_none() && err.valid_up_to() > 0 => {
+            let valid = &bytes[0..err.valid_up_to()];
+            (Cow::Borrowed(core::str::from_utf8(valid).unwrap()), valid.len())
+        }
+        Err(_) => (String::from_utf8_lossy(bytes), bytes.len()),
+    }
+}
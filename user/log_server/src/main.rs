@@ -0,0 +1,196 @@
+//! `log_server` provides the `log` service, which tasks send their log records to (see `std::poplar::logger`).
+//! Records are written to the serial port (through `early_log`), and the most recent are kept so they can be
+//! queried. It also decides the level each task logs at, which can be changed while tasks are running.
+
+use log::{info, warn};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use spinning_top::Spinlock;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    poplar::{
+        channel::{CallId, Channel},
+        logger::{
+            LogEvent,
+            LogLevel,
+            LogQuery,
+            LogRecord,
+            LogRequest,
+            TaskLogRecord,
+            LOGGER,
+            LOG_SERVICE,
+            MAX_QUERY_RECORDS,
+        },
+        syscall,
+    },
+    sync::Arc,
+};
+
+/// The number of records that are kept to be queried. Once this many have been received, the oldest are dropped.
+const MAX_RECORDS: usize = 1024;
+/// The level tasks log at, unless it's been changed with `SetLevel`.
+const DEFAULT_LEVEL: LogLevel = LogLevel::Info;
+
+struct Client {
+    id: u64,
+    name: String,
+    channel: Channel<LogEvent, LogRequest>,
+}
+
+struct LogServer {
+    records: VecDeque<TaskLogRecord>,
+    next_sequence: u64,
+    next_client_id: u64,
+    clients: Vec<Arc<Client>>,
+    default_level: Option<LogLevel>,
+    /// The levels of tasks that have had their level set by name. These override `default_level`.
+    task_levels: BTreeMap<String, Option<LogLevel>>,
+}
+
+impl LogServer {
+    fn new() -> LogServer {
+        LogServer {
+            records: VecDeque::new(),
+            next_sequence: 0,
+            next_client_id: 0,
+            clients: Vec::new(),
+            default_level: Some(DEFAULT_LEVEL),
+            task_levels: BTreeMap::new(),
+        }
+    }
+
+    fn level_for(&self, task: &str) -> Option<LogLevel> {
+        self.task_levels.get(task).copied().unwrap_or(self.default_level)
+    }
+
+    fn add_client(&mut self, name: String, channel: Channel<LogEvent, LogRequest>) -> Arc<Client> {
+        let client = Arc::new(Client { id: self.next_client_id, name, channel });
+        self.next_client_id += 1;
+
+        if client.channel.send(&LogEvent::SetMaxLevel(self.level_for(&client.name))).is_err() {
+            warn!("Failed to send log level to task '{}'", client.name);
+        }
+        self.clients.push(client.clone());
+        client
+    }
+
+    fn log(&mut self, client: &Client, records: Vec<LogRecord>) {
+        for record in records {
+            let timestamp = record.timestamp / 1_000_000;
+            let _ = syscall::early_log(&format!(
+                "[{}.{:03}] [{:5}] {} ({}): {}",
+                timestamp / 1000,
+                timestamp % 1000,
+                record.level.name(),
+                client.name,
+                record.target,
+                record.message
+            ));
+
+            if self.records.len() == MAX_RECORDS {
+                self.records.pop_front();
+            }
+            self.records.push_back(TaskLogRecord {
+                sequence: self.next_sequence,
+                task_id: client.id,
+                task_name: client.name.clone(),
+                record,
+            });
+            self.next_sequence += 1;
+        }
+    }
+
+    /// Set the level of the tasks called `task` (or all tasks, if `task` is `*`). Returns the number of connected
+    /// tasks that were told about their new level.
+    fn set_level(&mut self, task: String, level: Option<LogLevel>) -> u32 {
+        info!("Setting log level of '{}' to {}", task, level.map_or("OFF", |level| level.name()));
+        if task == "*" {
+            self.default_level = level;
+            self.task_levels.clear();
+        } else {
+            self.task_levels.insert(task.clone(), level);
+        }
+
+        let mut num_tasks = 0;
+        for client in self.clients.iter().filter(|client| task == "*" || client.name == task) {
+            if client.channel.send(&LogEvent::SetMaxLevel(level)).is_ok() {
+                num_tasks += 1;
+            }
+        }
+        num_tasks
+    }
+
+    fn query(&self, query: LogQuery) -> LogEvent {
+        let max_records = query.max_records.min(MAX_QUERY_RECORDS) as usize;
+        let records: Vec<TaskLogRecord> = self
+            .records
+            .iter()
+            .filter(|record| record.sequence >= query.since && record.record.level <= query.level)
+            .filter(|record| query.task.as_ref().map_or(true, |task| record.task_name == *task))
+            .take(max_records)
+            .cloned()
+            .collect();
+
+        // If we didn't return as many records as were asked for, there are no more matching records yet
+        let next = if records.len() == max_records {
+            records.last().map_or(query.since, |record| record.sequence + 1)
+        } else {
+            self.next_sequence
+        };
+        LogEvent::Records { records, next }
+    }
+}
+
+async fn serve_client(server: Arc<Spinlock<LogServer>>, client: Arc<Client>) {
+    loop {
+        let Ok((request, call)) = client.channel.receive_call().await else {
+            break;
+        };
+
+        match request {
+            LogRequest::Log(records) => server.lock().log(&client, records),
+            LogRequest::SetLevel { task, level } => {
+                let num_tasks = server.lock().set_level(task, level);
+                reply(&client, call, LogEvent::LevelSet { num_tasks });
+            }
+            LogRequest::Query(query) => {
+                let records = server.lock().query(query);
+                reply(&client, call, records);
+            }
+        }
+    }
+
+    server.lock().clients.retain(|other| !Arc::ptr_eq(other, &client));
+}
+
+fn reply(client: &Client, call: CallId, event: LogEvent) {
+    if let Err(err) = client.channel.reply(call, &event) {
+        warn!("Failed to reply to log client '{}': {:?}", client.name, err);
+    }
+}
+
+fn main() {
+    // We can't connect to ourselves, so our own records are always logged with `early_log`
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    info!("Log server is running!");
+
+    std::poplar::rt::init_runtime();
+
+    std::poplar::rt::spawn(async move {
+        let service_host = ServiceHostClient::new();
+        let service_channel = service_host.register_service(LOG_SERVICE).unwrap();
+        let server = Arc::new(Spinlock::new(LogServer::new()));
+
+        loop {
+            match service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    let client = server.lock().add_client(name, Channel::new_from_handle(channel));
+                    let server = server.clone();
+                    std::poplar::rt::spawn(async move { serve_client(server, client).await });
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
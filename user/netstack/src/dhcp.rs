@@ -0,0 +1,420 @@
+//! A DHCP client (RFC 2131), which gets an IPv4 configuration for an interface from a DHCP server, and keeps it
+//! by renewing the lease before it expires. The client doesn't send or receive packets itself - the interface
+//! polls it for messages to send, and passes it the messages it receives, so it can be driven by the interface's
+//! event loop.
+
+use crate::wire::{MacAddress, BROADCAST_MAC};
+use core::time::Duration;
+use log::{info, warn};
+use std::poplar::net::Ipv4Address;
+
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+/// The size of the fixed part of a DHCP message, before the magic cookie and options.
+const FIXED_SIZE: usize = 236;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+/// The number of times a `DHCPREQUEST` for an offered address is sent before starting again with a new
+/// `DHCPDISCOVER`.
+const MAX_REQUEST_ATTEMPTS: u32 = 4;
+/// The shortest time to wait before retransmitting a `DHCPREQUEST` while renewing or rebinding a lease.
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An IPv4 configuration leased from a DHCP server. Times are measured against the kernel's uptime.
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub address: Ipv4Address,
+    pub prefix_length: u8,
+    pub gateway: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address>,
+    pub server: Ipv4Address,
+    /// The Ethernet address that the server's messages come from, so we can send renewals directly to it.
+    pub server_mac: MacAddress,
+    /// When we should start trying to renew the lease with the server that granted it (T1).
+    pub renew_at: Duration,
+    /// When we should start trying to renew the lease with any server (T2).
+    pub rebind_at: Duration,
+    pub expires_at: Duration,
+}
+
+pub enum Destination {
+    Broadcast,
+    Unicast { address: Ipv4Address, mac: MacAddress },
+}
+
+impl Destination {
+    pub fn address(&self) -> Ipv4Address {
+        match self {
+            Destination::Broadcast => Ipv4Address::BROADCAST,
+            Destination::Unicast { address, .. } => *address,
+        }
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        match self {
+            Destination::Broadcast => BROADCAST_MAC,
+            Destination::Unicast { mac, .. } => *mac,
+        }
+    }
+}
+
+/// A DHCP message that the interface should send, from `CLIENT_PORT` to `SERVER_PORT`.
+pub struct Transmit {
+    pub source: Ipv4Address,
+    pub destination: Destination,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    /// Looking for a server by broadcasting `DHCPDISCOVER`s.
+    Selecting,
+    /// Requesting an address that a server has offered us.
+    Requesting { server: Ipv4Address, address: Ipv4Address },
+    /// We have a lease, and are waiting until it's time to renew it.
+    Bound,
+    /// Trying to renew our lease with the server that granted it.
+    Renewing,
+    /// Trying to renew our lease with any server, as the one that granted it hasn't replied.
+    Rebinding,
+}
+
+pub struct DhcpClient {
+    mac: MacAddress,
+    state: State,
+    lease: Option<Lease>,
+    /// The transaction ID of the current exchange with a server. Replies with a different ID are ignored.
+    xid: u32,
+    /// Used to generate transaction IDs. We don't have a source of randomness, so this is seeded from our MAC
+    /// address and the time the client was created.
+    xid_state: u32,
+    attempts: u32,
+    next_event: Duration,
+}
+
+impl DhcpClient {
+    pub fn new(mac: MacAddress, now: Duration) -> DhcpClient {
+        let seed = mac.iter().fold(now.as_nanos() as u32, |seed, &byte| seed.rotate_left(5) ^ byte as u32);
+        let mut client = DhcpClient {
+            mac,
+            state: State::Selecting,
+            lease: None,
+            xid: 0,
+            xid_state: seed | 1,
+            attempts: 0,
+            next_event: now,
+        };
+        client.xid = client.next_xid();
+        client
+    }
+
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// The time at which the client next needs to be polled, even if no messages have been received.
+    pub fn next_event(&self) -> Duration {
+        self.next_event
+    }
+
+    /// Move the client on if it's time to, returning a message to send if there is one.
+    pub fn poll(&mut self, now: Duration) -> Option<Transmit> {
+        if now < self.next_event {
+            return None;
+        }
+
+        loop {
+            match self.state {
+                State::Selecting => {
+                    self.schedule_retransmit(now);
+                    return Some(self.transmit(
+                        DHCPDISCOVER,
+                        Ipv4Address::UNSPECIFIED,
+                        Destination::Broadcast,
+                        None,
+                        None,
+                    ));
+                }
+                State::Requesting { server, address } => {
+                    if self.attempts >= MAX_REQUEST_ATTEMPTS {
+                        warn!("DHCP server {} did not reply to request for {}. Restarting.", server, address);
+                        self.restart(now);
+                        continue;
+                    }
+                    self.schedule_retransmit(now);
+                    return Some(self.transmit(
+                        DHCPREQUEST,
+                        Ipv4Address::UNSPECIFIED,
+                        Destination::Broadcast,
+                        Some(server),
+                        Some(address),
+                    ));
+                }
+                State::Bound => {
+                    info!("Renewing DHCP lease");
+                    self.state = State::Renewing;
+                    self.xid = self.next_xid();
+                }
+                State::Renewing => {
+                    let lease = self.lease.as_ref().unwrap();
+                    if now >= lease.rebind_at {
+                        warn!("DHCP server {} did not renew our lease. Rebinding.", lease.server);
+                        self.state = State::Rebinding;
+                        continue;
+                    }
+
+                    let address = lease.address;
+                    let destination = Destination::Unicast { address: lease.server, mac: lease.server_mac };
+                    self.next_event = retransmit_before(now, lease.rebind_at);
+                    return Some(self.transmit(DHCPREQUEST, address, destination, None, None));
+                }
+                State::Rebinding => {
+                    let lease = self.lease.as_ref().unwrap();
+                    if now >= lease.expires_at {
+                        warn!("DHCP lease for {} has expired", lease.address);
+                        self.lease = None;
+                        self.restart(now);
+                        continue;
+                    }
+
+                    let address = lease.address;
+                    self.next_event = retransmit_before(now, lease.expires_at);
+                    return Some(self.transmit(DHCPREQUEST, address, Destination::Broadcast, None, None));
+                }
+            }
+        }
+    }
+
+    /// Handle a DHCP message received on `CLIENT_PORT`. Returns `true` if our lease has changed (either because
+    /// we've been granted a new one, or because we've lost it).
+    pub fn handle_message(&mut self, data: &[u8], source_mac: MacAddress, now: Duration) -> bool {
+        let Some(message) = Message::parse(data) else {
+            return false;
+        };
+        if message.op != BOOTREPLY || message.xid != self.xid || message.client_mac != self.mac {
+            return false;
+        }
+
+        match (self.state, message.message_type) {
+            (State::Selecting, DHCPOFFER) => {
+                let Some(server) = message.server_id else {
+                    return false;
+                };
+                info!("DHCP server {} offered address {}", server, message.your_address);
+                self.state = State::Requesting { server, address: message.your_address };
+                self.attempts = 0;
+                self.next_event = now;
+                false
+            }
+            (State::Requesting { server, .. }, DHCPACK) => self.bind(&message, server, source_mac, now),
+            (State::Renewing | State::Rebinding, DHCPACK) => {
+                let server = self.lease.as_ref().unwrap().server;
+                self.bind(&message, server, source_mac, now)
+            }
+            (State::Requesting { .. } | State::Renewing | State::Rebinding, DHCPNAK) => {
+                warn!("DHCP server refused our request. Restarting.");
+                let had_lease = self.lease.take().is_some();
+                self.restart(now);
+                had_lease
+            }
+            _ => false,
+        }
+    }
+
+    fn bind(&mut self, message: &Message, server: Ipv4Address, source_mac: MacAddress, now: Duration) -> bool {
+        let Some(lease_time) = message.lease_time else {
+            warn!("DHCP server acknowledged our request without a lease time. Ignoring.");
+            return false;
+        };
+
+        // A lease time of all ones means the lease is infinite
+        let after = |seconds: u32| {
+            if lease_time == u32::MAX {
+                Duration::MAX
+            } else {
+                now + Duration::from_secs(seconds as u64)
+            }
+        };
+        let lease = Lease {
+            address: message.your_address,
+            prefix_length: message
+                .subnet_mask
+                .map(|mask| u32::from_be_bytes(mask.0).leading_ones() as u8)
+                .unwrap_or(32),
+            gateway: message.routers.first().copied(),
+            dns_servers: message.dns_servers.clone(),
+            server: message.server_id.unwrap_or(server),
+            server_mac: source_mac,
+            renew_at: after(message.renewal_time.unwrap_or(lease_time / 2)),
+            rebind_at: after(message.rebinding_time.unwrap_or(lease_time / 8 * 7)),
+            expires_at: after(lease_time),
+        };
+
+        self.next_event = lease.renew_at;
+        self.lease = Some(lease);
+        self.state = State::Bound;
+        true
+    }
+
+    /// Start again from scratch, looking for a server with a `DHCPDISCOVER`.
+    fn restart(&mut self, now: Duration) {
+        self.state = State::Selecting;
+        self.xid = self.next_xid();
+        self.attempts = 0;
+        self.next_event = now;
+    }
+
+    /// Schedule the next retransmission of a message, using the exponential backoff suggested by RFC 2131 (4
+    /// seconds, then 8, and so on up to 64).
+    fn schedule_retransmit(&mut self, now: Duration) {
+        self.next_event = now + Duration::from_secs(4 << self.attempts.min(4));
+        self.attempts += 1;
+    }
+
+    fn next_xid(&mut self) -> u32 {
+        // xorshift32
+        self.xid_state ^= self.xid_state << 13;
+        self.xid_state ^= self.xid_state >> 17;
+        self.xid_state ^= self.xid_state << 5;
+        self.xid_state
+    }
+
+    /// Create a message to send to the server. `client_address` is our current address, if we have one, and is
+    /// also used as the source address.
+    fn transmit(
+        &self,
+        message_type: u8,
+        client_address: Ipv4Address,
+        destination: Destination,
+        server: Option<Ipv4Address>,
+        requested_address: Option<Ipv4Address>,
+    ) -> Transmit {
+        let mut payload = vec![0; FIXED_SIZE];
+        payload[0] = BOOTREQUEST;
+        payload[1] = 1; // Hardware type: Ethernet
+        payload[2] = 6; // Hardware address length
+        payload[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        payload[12..16].copy_from_slice(&client_address.0);
+        payload[28..34].copy_from_slice(&self.mac);
+        payload.extend_from_slice(&MAGIC_COOKIE);
+
+        payload.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+        if let Some(address) = requested_address {
+            payload.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+            payload.extend_from_slice(&address.0);
+        }
+        if let Some(server) = server {
+            payload.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+            payload.extend_from_slice(&server.0);
+        }
+        payload.extend_from_slice(&[
+            OPTION_PARAMETER_REQUEST_LIST,
+            3,
+            OPTION_SUBNET_MASK,
+            OPTION_ROUTER,
+            OPTION_DNS_SERVER,
+        ]);
+        payload.push(OPTION_END);
+
+        Transmit { source: client_address, destination, payload }
+    }
+}
+
+/// Retransmit a `DHCPREQUEST` while renewing or rebinding at half the remaining time until `deadline`, but not
+/// more often than `MIN_RENEW_INTERVAL` (RFC 2131, section 4.4.5).
+fn retransmit_before(now: Duration, deadline: Duration) -> Duration {
+    let remaining = deadline.saturating_sub(now);
+    (now + (remaining / 2).max(MIN_RENEW_INTERVAL)).min(deadline)
+}
+
+/// The parts of a DHCP message sent by a server that we care about.
+struct Message {
+    op: u8,
+    xid: u32,
+    your_address: Ipv4Address,
+    client_mac: MacAddress,
+    message_type: u8,
+    server_id: Option<Ipv4Address>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+    subnet_mask: Option<Ipv4Address>,
+    routers: Vec<Ipv4Address>,
+    dns_servers: Vec<Ipv4Address>,
+}
+
+impl Message {
+    fn parse(data: &[u8]) -> Option<Message> {
+        if data.len() < FIXED_SIZE + MAGIC_COOKIE.len() || data[FIXED_SIZE..(FIXED_SIZE + 4)] != MAGIC_COOKIE {
+            return None;
+        }
+
+        let mut message = Message {
+            op: data[0],
+            xid: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            your_address: Ipv4Address(data[16..20].try_into().unwrap()),
+            client_mac: data[28..34].try_into().unwrap(),
+            message_type: 0,
+            server_id: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+            subnet_mask: None,
+            routers: Vec::new(),
+            dns_servers: Vec::new(),
+        };
+
+        let mut options = &data[(FIXED_SIZE + 4)..];
+        loop {
+            match *options {
+                [] | [OPTION_END, ..] => break,
+                [OPTION_PAD, ref rest @ ..] => options = rest,
+                [code, length, ref rest @ ..] if rest.len() >= length as usize => {
+                    let value = &rest[..length as usize];
+                    let address = || value.get(0..4).map(|bytes| Ipv4Address(bytes.try_into().unwrap()));
+                    let integer = || value.get(0..4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+                    let addresses =
+                        || value.chunks_exact(4).map(|bytes| Ipv4Address(bytes.try_into().unwrap())).collect();
+
+                    match code {
+                        OPTION_MESSAGE_TYPE => message.message_type = value.first().copied().unwrap_or(0),
+                        OPTION_SERVER_ID => message.server_id = address(),
+                        OPTION_LEASE_TIME => message.lease_time = integer(),
+                        OPTION_RENEWAL_TIME => message.renewal_time = integer(),
+                        OPTION_REBINDING_TIME => message.rebinding_time = integer(),
+                        OPTION_SUBNET_MASK => message.subnet_mask = address(),
+                        OPTION_ROUTER => message.routers = addresses(),
+                        OPTION_DNS_SERVER => message.dns_servers = addresses(),
+                        _ => (),
+                    }
+                    options = &rest[length as usize..];
+                }
+                _ => return None,
+            }
+        }
+
+        Some(message)
+    }
+}
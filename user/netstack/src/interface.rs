@@ -0,0 +1,283 @@
+use crate::{
+    dhcp::{self, DhcpClient, Transmit},
+    socket::Sockets,
+    wire::{
+        ArpOperation,
+        ArpPacket,
+        EthernetFrame,
+        Ipv4Packet,
+        MacAddress,
+        UdpDatagram,
+        BROADCAST_MAC,
+        ETHERTYPE_ARP,
+        ETHERTYPE_IPV4,
+        IP_PROTOCOL_UDP,
+    },
+};
+use core::time::Duration;
+use log::{info, warn};
+use platform_bus::net::{NetworkEvent, NetworkRequest};
+use spinning_top::Spinlock;
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        channel::{Channel, ChannelReceiveError},
+        net::{InterfaceInfo, Ipv4Address, Ipv4Config},
+        syscall::get_uptime,
+    },
+    sync::Arc,
+};
+
+/// The longest we'll wait for a packet before checking if there's anything else to do. This stops very long (or
+/// infinite) DHCP leases from producing timeouts the runtime's timer can't represent.
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+/// The most packets we'll hold on to while we wait for the addresses they're being sent to to be resolved. Once
+/// there are more than this, the oldest are dropped.
+const MAX_UNRESOLVED_PACKETS: usize = 16;
+
+/// A network interface, backed by a network device on the Platform Bus. Each interface is configured by its own
+/// DHCP client.
+pub struct Interface {
+    pub mac: MacAddress,
+    channel: Channel<NetworkRequest, NetworkEvent>,
+    dhcp: Spinlock<DhcpClient>,
+    sockets: Arc<Sockets>,
+    /// The Ethernet addresses of the hosts on our subnet that we've learned from ARP.
+    arp_cache: Spinlock<BTreeMap<Ipv4Address, MacAddress>>,
+    /// IPv4 packets waiting to be sent until we've resolved the Ethernet address of their next hop.
+    unresolved: Spinlock<Vec<(Ipv4Address, Vec<u8>)>>,
+}
+
+impl Interface {
+    pub fn new(
+        mac: MacAddress,
+        channel: Channel<NetworkRequest, NetworkEvent>,
+        sockets: Arc<Sockets>,
+    ) -> Interface {
+        Interface {
+            mac,
+            channel,
+            dhcp: Spinlock::new(DhcpClient::new(mac, get_uptime())),
+            sockets,
+            arp_cache: Spinlock::new(BTreeMap::new()),
+            unresolved: Spinlock::new(Vec::new()),
+        }
+    }
+
+    pub fn info(&self) -> InterfaceInfo {
+        let now = get_uptime();
+        let ipv4 = self.dhcp.lock().lease().map(|lease| Ipv4Config {
+            address: lease.address,
+            prefix_length: lease.prefix_length,
+            gateway: lease.gateway,
+            dns_servers: lease.dns_servers.clone(),
+            dhcp_server: Some(lease.server),
+            lease_remaining: Some(lease.expires_at.saturating_sub(now).as_secs().min(u32::MAX as u64) as u32),
+        });
+        InterfaceInfo { mac_address: self.mac, ipv4 }
+    }
+
+    /// The interface's event loop. This handles packets received from the device, and drives the DHCP client's
+    /// timers (e.g. to retransmit messages, or renew its lease).
+    pub async fn run(&self) {
+        loop {
+            let transmit = self.dhcp.lock().poll(get_uptime());
+            if let Some(transmit) = transmit {
+                self.send_dhcp(transmit);
+            }
+
+            let wait = self.dhcp.lock().next_event().saturating_sub(get_uptime()).min(MAX_WAIT);
+            match self.channel.receive_with_timeout(wait).await {
+                Ok(NetworkEvent::PacketReceived(frame)) => self.handle_frame(&frame),
+                Err(ChannelReceiveError::TimedOut) => (),
+                Err(err) => {
+                    warn!("Failed to receive from network device {}: {:?}", format_mac(self.mac), err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn address(&self) -> Option<Ipv4Address> {
+        self.dhcp.lock().lease().map(|lease| lease.address)
+    }
+
+    /// Find the host a packet to `destination` should be sent to next: the destination itself if it's on our
+    /// subnet (or is the broadcast address), and our gateway otherwise. Returns `None` if the interface isn't
+    /// configured, or the destination is off our subnet and we don't have a gateway.
+    fn next_hop(&self, destination: Ipv4Address) -> Option<Ipv4Address> {
+        let dhcp = self.dhcp.lock();
+        let lease = dhcp.lease()?;
+        let mask = u32::MAX.checked_shl(32 - lease.prefix_length as u32).unwrap_or(0);
+        let on_link = u32::from_be_bytes(destination.0) & mask == u32::from_be_bytes(lease.address.0) & mask;
+        if on_link || destination == Ipv4Address::BROADCAST {
+            Some(destination)
+        } else {
+            lease.gateway
+        }
+    }
+
+    /// Whether we can send packets to `destination` through this interface.
+    pub fn can_reach(&self, destination: Ipv4Address) -> bool {
+        self.next_hop(destination).is_some()
+    }
+
+    /// Send a UDP datagram to `destination`. Datagrams that can't be routed are dropped.
+    pub fn send_udp(&self, destination: Ipv4Address, source_port: u16, destination_port: u16, payload: &[u8]) {
+        let (Some(source), Some(next_hop)) = (self.address(), self.next_hop(destination)) else {
+            return;
+        };
+        let datagram = UdpDatagram::build(source, destination, source_port, destination_port, payload);
+        self.send_ipv4(next_hop, Ipv4Packet::build(source, destination, IP_PROTOCOL_UDP, &datagram));
+    }
+
+    /// Send an IPv4 packet to `next_hop`. If we don't know its Ethernet address yet, the packet is held on to, and
+    /// sent once it replies to the ARP request we send it.
+    fn send_ipv4(&self, next_hop: Ipv4Address, packet: Vec<u8>) {
+        let mac = match next_hop {
+            Ipv4Address::BROADCAST => Some(BROADCAST_MAC),
+            _ => self.arp_cache.lock().get(&next_hop).copied(),
+        };
+        if let Some(mac) = mac {
+            self.send(EthernetFrame::build(mac, self.mac, ETHERTYPE_IPV4, &packet));
+            return;
+        }
+
+        {
+            let mut unresolved = self.unresolved.lock();
+            if unresolved.len() == MAX_UNRESOLVED_PACKETS {
+                unresolved.remove(0);
+            }
+            unresolved.push((next_hop, packet));
+        }
+        let Some(address) = self.address() else {
+            return;
+        };
+        let request = ArpPacket {
+            operation: ArpOperation::Request,
+            sender_mac: self.mac,
+            sender_ip: address,
+            target_mac: [0; 6],
+            target_ip: next_hop,
+        };
+        self.send(EthernetFrame::build(BROADCAST_MAC, self.mac, ETHERTYPE_ARP, &request.to_bytes()));
+    }
+
+    fn handle_frame(&self, frame: &[u8]) {
+        let Some(frame) = EthernetFrame::parse(frame) else {
+            return;
+        };
+        if frame.destination != self.mac && frame.destination != BROADCAST_MAC {
+            return;
+        }
+
+        match frame.ethertype {
+            ETHERTYPE_ARP => self.handle_arp(frame.payload),
+            ETHERTYPE_IPV4 => self.handle_ipv4(frame.payload, frame.source),
+            _ => (),
+        }
+    }
+
+    /// Reply to ARP requests for our address, so other hosts (e.g. the gateway) can reach us, and learn the
+    /// addresses of the hosts that send us requests and replies.
+    fn handle_arp(&self, packet: &[u8]) {
+        let Some(packet) = ArpPacket::parse(packet) else {
+            return;
+        };
+        let Some(address) = self.address() else {
+            return;
+        };
+
+        if packet.target_ip == address && packet.sender_ip != Ipv4Address::UNSPECIFIED {
+            self.arp_cache.lock().insert(packet.sender_ip, packet.sender_mac);
+            let resolved: Vec<Vec<u8>> = {
+                let mut unresolved = self.unresolved.lock();
+                let (resolved, rest): (Vec<_>, Vec<_>) =
+                    unresolved.drain(..).partition(|(next_hop, _)| *next_hop == packet.sender_ip);
+                *unresolved = rest;
+                resolved.into_iter().map(|(_, packet)| packet).collect()
+            };
+            for ipv4_packet in resolved {
+                self.send(EthernetFrame::build(packet.sender_mac, self.mac, ETHERTYPE_IPV4, &ipv4_packet));
+            }
+        }
+
+        if packet.operation == ArpOperation::Request && packet.target_ip == address {
+            let reply = ArpPacket {
+                operation: ArpOperation::Reply,
+                sender_mac: self.mac,
+                sender_ip: address,
+                target_mac: packet.sender_mac,
+                target_ip: packet.sender_ip,
+            };
+            self.send(EthernetFrame::build(packet.sender_mac, self.mac, ETHERTYPE_ARP, &reply.to_bytes()));
+        }
+    }
+
+    fn handle_ipv4(&self, packet: &[u8], source_mac: MacAddress) {
+        let Some(packet) = Ipv4Packet::parse(packet) else {
+            return;
+        };
+        /*
+         * Before we have an address, we need to accept packets sent to the address we're being offered, as some
+         * DHCP servers unicast their replies.
+         */
+        let for_us = match self.address() {
+            Some(address) => packet.destination == address || packet.destination == Ipv4Address::BROADCAST,
+            None => true,
+        };
+        if !for_us || packet.protocol != IP_PROTOCOL_UDP {
+            return;
+        }
+
+        let Some(datagram) = UdpDatagram::parse(&packet) else {
+            return;
+        };
+        if datagram.source_port == dhcp::SERVER_PORT && datagram.destination_port == dhcp::CLIENT_PORT {
+            let lease_changed = self.dhcp.lock().handle_message(datagram.payload, source_mac, get_uptime());
+            if lease_changed {
+                self.log_config();
+            }
+        } else {
+            self.sockets.deliver(packet.source, datagram.source_port, datagram.destination_port, datagram.payload);
+        }
+    }
+
+    fn log_config(&self) {
+        match self.info().ipv4 {
+            Some(config) => info!(
+                "Interface {} configured with address {}/{} (gateway: {:?}, DNS servers: {:?}, lease: {}s)",
+                format_mac(self.mac),
+                config.address,
+                config.prefix_length,
+                config.gateway,
+                config.dns_servers,
+                config.lease_remaining.unwrap_or(0),
+            ),
+            None => info!("Interface {} is no longer configured", format_mac(self.mac)),
+        }
+    }
+
+    fn send_dhcp(&self, transmit: Transmit) {
+        let destination = transmit.destination.address();
+        let datagram = UdpDatagram::build(
+            transmit.source,
+            destination,
+            dhcp::CLIENT_PORT,
+            dhcp::SERVER_PORT,
+            &transmit.payload,
+        );
+        let packet = Ipv4Packet::build(transmit.source, destination, IP_PROTOCOL_UDP, &datagram);
+        self.send(EthernetFrame::build(transmit.destination.mac(), self.mac, ETHERTYPE_IPV4, &packet));
+    }
+
+    fn send(&self, frame: Vec<u8>) {
+        if let Err(err) = self.channel.send(&NetworkRequest::SendPacket(frame)) {
+            warn!("Failed to send packet on interface {}: {:?}", format_mac(self.mac), err);
+        }
+    }
+}
+
+pub fn format_mac(mac: MacAddress) -> String {
+    format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+}
@@ -0,0 +1,118 @@
+//! `netstack` is Poplar's network stack. It drives the network devices on the Platform Bus, configures each of
+//! them with DHCP, lets other tasks query their configuration through the `net.control` service, and lets them
+//! send and receive UDP datagrams through the `net.socket` service (see `std::poplar::net`).
+
+mod dhcp;
+mod interface;
+mod socket;
+mod wire;
+
+use interface::{format_mac, Interface};
+use log::{info, warn};
+use platform_bus::{DeviceDriverClient, DeviceHandoffCall, Filter, Property};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use socket::Sockets;
+use spinning_top::Spinlock;
+use std::{
+    poplar::{
+        channel::Channel,
+        early_logger::EarlyLogger,
+        net::{NetControlRequest, NetControlResponse, NET_CONTROL_SERVICE, NET_SOCKET_SERVICE},
+    },
+    sync::Arc,
+};
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Network stack is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let interfaces: Arc<Spinlock<Vec<Arc<Interface>>>> = Arc::new(Spinlock::new(Vec::new()));
+    let sockets = Arc::new(Sockets::new());
+
+    let service_host_client = ServiceHostClient::new();
+    let control_service_channel = service_host_client.register_service(NET_CONTROL_SERVICE).unwrap();
+    let socket_service_channel = service_host_client.register_service(NET_SOCKET_SERVICE).unwrap();
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+    let handoff_server = platform_bus_device_client
+        .register(vec![Filter::Matches(String::from("type"), Property::String("network".to_string()))])
+        .unwrap();
+
+    std::poplar::rt::spawn({
+        let interfaces = interfaces.clone();
+        let sockets = sockets.clone();
+        async move {
+            loop {
+                match handoff_server.next().await.unwrap() {
+                    DeviceHandoffCall::QuerySupport { responder, .. } => {
+                        responder.reply(true).unwrap();
+                    }
+                    DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                        responder.reply(()).unwrap();
+                    }
+                    DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info } => {
+                        let Some(mac) =
+                            device_info.get_as_bytes("net.mac_address").and_then(|mac| mac.try_into().ok())
+                        else {
+                            warn!("Network device '{}' does not have a valid MAC address. Ignoring.", name);
+                            continue;
+                        };
+                        info!("Found network device '{}' with MAC address {}", name, format_mac(mac));
+
+                        let channel =
+                            Channel::new_from_handle(handoff_info.get_as_channel("net.channel").unwrap());
+                        let interface = Arc::new(Interface::new(mac, channel, sockets.clone()));
+                        interfaces.lock().push(interface.clone());
+                        std::poplar::rt::spawn(async move { interface.run().await });
+                    }
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::spawn({
+        let interfaces = interfaces.clone();
+        async move {
+            loop {
+                match socket_service_channel.receive().await.unwrap() {
+                    ServiceChannelMessage::NewClient { name, channel } => {
+                        info!("Task '{}' opened a socket", name);
+                        let channel = Channel::new_from_handle(channel);
+                        std::poplar::rt::spawn(socket::serve(channel, sockets.clone(), interfaces.clone()));
+                    }
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::spawn(async move {
+        loop {
+            match control_service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    info!("Task '{}' subscribed to the network stack", name);
+                    let channel: Channel<NetControlResponse, NetControlRequest> =
+                        Channel::new_from_handle(channel);
+
+                    std::poplar::rt::spawn({
+                        let interfaces = interfaces.clone();
+                        async move {
+                            loop {
+                                let response = match channel.receive().await.unwrap() {
+                                    NetControlRequest::GetInterfaces => NetControlResponse::Interfaces(
+                                        interfaces.lock().iter().map(|interface| interface.info()).collect(),
+                                    ),
+                                };
+                                channel.send(&response).unwrap();
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
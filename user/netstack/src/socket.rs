@@ -0,0 +1,104 @@
+//! UDP sockets, which other tasks use through the `net.socket` service (see `std::poplar::net`). Each client of
+//! the service is a single socket, which is served by its own task until the client closes its channel.
+
+use crate::interface::Interface;
+use log::warn;
+use spinning_top::Spinlock;
+use std::{
+    collections::BTreeMap,
+    ops::RangeInclusive,
+    poplar::{
+        channel::Channel,
+        net::{Ipv4Address, SocketError, SocketEvent, SocketRequest, MAX_DATAGRAM_SIZE},
+        syscall,
+    },
+    sync::Arc,
+};
+
+pub type SocketChannel = Channel<SocketEvent, SocketRequest>;
+
+/// The ports handed out to sockets that ask for any free port. These are the dynamic ports set aside by IANA.
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// The sockets that are bound to each port.
+pub struct Sockets {
+    udp: Spinlock<BTreeMap<u16, Arc<SocketChannel>>>,
+}
+
+impl Sockets {
+    pub fn new() -> Sockets {
+        Sockets { udp: Spinlock::new(BTreeMap::new()) }
+    }
+
+    /// Bind a socket to `port`, or to a free ephemeral port if `port` is `0`. Returns the port it was bound to.
+    fn bind(&self, port: u16, channel: Arc<SocketChannel>) -> Result<u16, SocketError> {
+        let mut udp = self.udp.lock();
+        let port = match port {
+            0 => EPHEMERAL_PORTS.clone().find(|port| !udp.contains_key(port)).ok_or(SocketError::NoFreePorts)?,
+            port if udp.contains_key(&port) => return Err(SocketError::AddressInUse),
+            port => port,
+        };
+        udp.insert(port, channel);
+        Ok(port)
+    }
+
+    fn unbind(&self, port: u16) {
+        self.udp.lock().remove(&port);
+    }
+
+    /// Pass a datagram that has arrived on one of our interfaces to the socket bound to its destination port. If
+    /// no socket is bound to the port, the datagram is dropped.
+    pub fn deliver(&self, source: Ipv4Address, source_port: u16, destination_port: u16, data: &[u8]) {
+        let Some(channel) = self.udp.lock().get(&destination_port).cloned() else {
+            return;
+        };
+        let event = SocketEvent::Received { address: source, port: source_port, data: data.to_vec() };
+        if let Err(err) = channel.send(&event) {
+            warn!("Failed to deliver datagram to socket on port {}: {:?}", destination_port, err);
+        }
+    }
+}
+
+/// Serve a client of the `net.socket` service, until it closes its channel.
+pub async fn serve(channel: SocketChannel, sockets: Arc<Sockets>, interfaces: Arc<Spinlock<Vec<Arc<Interface>>>>) {
+    let channel = Arc::new(channel);
+    let mut bound = None;
+
+    while let Ok(request) = channel.receive().await {
+        match request {
+            SocketRequest::BindUdp { port } => {
+                let event = match bound {
+                    Some(_) => SocketEvent::Error(SocketError::AlreadyBound),
+                    None => match sockets.bind(port, channel.clone()) {
+                        Ok(port) => {
+                            bound = Some(port);
+                            SocketEvent::Bound { port }
+                        }
+                        Err(err) => SocketEvent::Error(err),
+                    },
+                };
+                if channel.send(&event).is_err() {
+                    break;
+                }
+            }
+            SocketRequest::SendTo { address, port, data } => {
+                // Like datagrams that can't be routed, datagrams sent before the socket is bound are dropped
+                let Some(source_port) = bound else {
+                    continue;
+                };
+                if data.len() > MAX_DATAGRAM_SIZE {
+                    continue;
+                }
+                let interface = interfaces.lock().iter().find(|interface| interface.can_reach(address)).cloned();
+                if let Some(interface) = interface {
+                    interface.send_udp(address, source_port, port, &data);
+                }
+            }
+        }
+    }
+
+    if let Some(port) = bound {
+        sockets.unbind(port);
+    }
+    let _ = syscall::handle_close(channel.handle());
+}
@@ -0,0 +1,243 @@
+//! Parsing and construction of the packets the stack understands. Parsers return `None` for packets that are
+//! malformed, or that use features we don't support, so they can be dropped.
+
+use std::poplar::net::Ipv4Address;
+
+pub type MacAddress = [u8; 6];
+
+pub const BROADCAST_MAC: MacAddress = [0xff; 6];
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const IP_PROTOCOL_UDP: u8 = 17;
+
+pub struct EthernetFrame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    const HEADER_SIZE: usize = 14;
+
+    pub fn parse(frame: &'a [u8]) -> Option<EthernetFrame<'a>> {
+        if frame.len() < Self::HEADER_SIZE {
+            return None;
+        }
+        Some(EthernetFrame {
+            destination: frame[0..6].try_into().unwrap(),
+            source: frame[6..12].try_into().unwrap(),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+            payload: &frame[Self::HEADER_SIZE..],
+        })
+    }
+
+    pub fn build(destination: MacAddress, source: MacAddress, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(Self::HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&destination);
+        frame.extend_from_slice(&source);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArpOperation {
+    Request,
+    Reply,
+}
+
+/// An ARP packet for resolving IPv4 addresses to Ethernet addresses, which is the only kind we support.
+pub struct ArpPacket {
+    pub operation: ArpOperation,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    const SIZE: usize = 28;
+    const HEADER: [u8; 6] = [0x00, 0x01, 0x08, 0x00, 6, 4];
+
+    pub fn parse(packet: &[u8]) -> Option<ArpPacket> {
+        if packet.len() < Self::SIZE || packet[0..6] != Self::HEADER {
+            return None;
+        }
+        let operation = match u16::from_be_bytes([packet[6], packet[7]]) {
+            1 => ArpOperation::Request,
+            2 => ArpOperation::Reply,
+            _ => return None,
+        };
+        Some(ArpPacket {
+            operation,
+            sender_mac: packet[8..14].try_into().unwrap(),
+            sender_ip: Ipv4Address(packet[14..18].try_into().unwrap()),
+            target_mac: packet[18..24].try_into().unwrap(),
+            target_ip: Ipv4Address(packet[24..28].try_into().unwrap()),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(Self::SIZE);
+        packet.extend_from_slice(&Self::HEADER);
+        packet
+            .extend_from_slice(&(if self.operation == ArpOperation::Request { 1u16 } else { 2u16 }).to_be_bytes());
+        packet.extend_from_slice(&self.sender_mac);
+        packet.extend_from_slice(&self.sender_ip.0);
+        packet.extend_from_slice(&self.target_mac);
+        packet.extend_from_slice(&self.target_ip.0);
+        packet
+    }
+}
+
+pub struct Ipv4Packet<'a> {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    const HEADER_SIZE: usize = 20;
+
+    /// Parse an IPv4 packet. Fragmented packets are not supported.
+    pub fn parse(packet: &'a [u8]) -> Option<Ipv4Packet<'a>> {
+        if packet.len() < Self::HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_length = (packet[0] & 0xf) as usize * 4;
+        let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_length < Self::HEADER_SIZE || total_length < header_length || total_length > packet.len() {
+            return None;
+        }
+        if checksum(&[&packet[0..header_length]]) != 0 {
+            return None;
+        }
+
+        // Drop fragments: either the "more fragments" flag is set, or the fragment offset is non-zero
+        let flags_and_offset = u16::from_be_bytes([packet[6], packet[7]]);
+        if flags_and_offset & 0x3fff != 0 {
+            return None;
+        }
+
+        Some(Ipv4Packet {
+            source: Ipv4Address(packet[12..16].try_into().unwrap()),
+            destination: Ipv4Address(packet[16..20].try_into().unwrap()),
+            protocol: packet[9],
+            payload: &packet[header_length..total_length],
+        })
+    }
+
+    pub fn build(source: Ipv4Address, destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        const TTL: u8 = 64;
+        const DONT_FRAGMENT: u16 = 1 << 14;
+
+        let total_length = (Self::HEADER_SIZE + payload.len()) as u16;
+        let mut packet = Vec::with_capacity(total_length as usize);
+        packet.extend_from_slice(&[0x45, 0x00]);
+        packet.extend_from_slice(&total_length.to_be_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+        packet.extend_from_slice(&DONT_FRAGMENT.to_be_bytes());
+        packet.extend_from_slice(&[TTL, protocol, 0x00, 0x00]);
+        packet.extend_from_slice(&source.0);
+        packet.extend_from_slice(&destination.0);
+
+        let header_checksum = checksum(&[&packet]);
+        packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+pub struct UdpDatagram<'a> {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    const HEADER_SIZE: usize = 8;
+
+    pub fn parse(packet: &Ipv4Packet<'a>) -> Option<UdpDatagram<'a>> {
+        let data = packet.payload;
+        if data.len() < Self::HEADER_SIZE {
+            return None;
+        }
+        let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+        if length < Self::HEADER_SIZE || length > data.len() {
+            return None;
+        }
+        // A checksum of zero means the sender didn't calculate one
+        let expected_checksum = u16::from_be_bytes([data[6], data[7]]);
+        if expected_checksum != 0
+            && checksum(&[&pseudo_header(packet.source, packet.destination, length as u16), &data[..length]]) != 0
+        {
+            return None;
+        }
+
+        Some(UdpDatagram {
+            source_port: u16::from_be_bytes([data[0], data[1]]),
+            destination_port: u16::from_be_bytes([data[2], data[3]]),
+            payload: &data[Self::HEADER_SIZE..length],
+        })
+    }
+
+    pub fn build(
+        source: Ipv4Address,
+        destination: Ipv4Address,
+        source_port: u16,
+        destination_port: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let length = (Self::HEADER_SIZE + payload.len()) as u16;
+        let mut datagram = Vec::with_capacity(length as usize);
+        datagram.extend_from_slice(&source_port.to_be_bytes());
+        datagram.extend_from_slice(&destination_port.to_be_bytes());
+        datagram.extend_from_slice(&length.to_be_bytes());
+        datagram.extend_from_slice(&[0x00, 0x00]);
+        datagram.extend_from_slice(payload);
+
+        // A calculated checksum of zero is sent as all ones, as zero means there is no checksum
+        let datagram_checksum = match checksum(&[&pseudo_header(source, destination, length), &datagram]) {
+            0 => 0xffff,
+            other => other,
+        };
+        datagram[6..8].copy_from_slice(&datagram_checksum.to_be_bytes());
+        datagram
+    }
+}
+
+/// The UDP checksum also covers a "pseudo-header" made up of parts of the IPv4 header.
+fn pseudo_header(source: Ipv4Address, destination: Ipv4Address, udp_length: u16) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&source.0);
+    header[4..8].copy_from_slice(&destination.0);
+    header[9] = IP_PROTOCOL_UDP;
+    header[10..12].copy_from_slice(&udp_length.to_be_bytes());
+    header
+}
+
+/// Calculate the Internet checksum (the ones' complement of the ones' complement sum of 16-bit words) over a
+/// series of buffers. Each buffer apart from the last must have an even length. Calculating the checksum of data
+/// that includes a correct checksum produces zero.
+pub fn checksum(buffers: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for buffer in buffers {
+        for chunk in buffer.chunks(2) {
+            let word = match *chunk {
+                [high, low] => u16::from_be_bytes([high, low]),
+                [high] => u16::from_be_bytes([high, 0]),
+                _ => unreachable!(),
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,596 @@
+//! `nvme` is a driver for NVM Express controllers. Each namespace of a controller is provided to other tasks as
+//! a block device.
+//!
+//! Commands are submitted to a set of I/O queue pairs, and each client of a block device is assigned one of them,
+//! so the requests of different clients can be in flight at the same time. The controller signals the
+//! completion of commands with MSI-X interrupts, which the kernel delivers to us as `Event`s. If we're given
+//! enough of them, each I/O queue signals its own interrupt.
+
+mod queue;
+mod reg;
+
+use crate::{
+    queue::{Command, CommandError, QueuePair},
+    reg::{Config, RegisterBlock, Status},
+};
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use log::{info, warn};
+use platform_bus::{pci::PciConfig, DeviceDriverClient, DeviceHandoffCall, Filter, HandoffInfo, Property};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use spinning_top::{RwSpinlock, Spinlock};
+use std::{
+    poplar::{
+        block::{
+            BlockDeviceInfo,
+            BlockError,
+            BlockRequest,
+            BlockResponse,
+            BLOCK_DEVICE_SERVICE,
+            MAX_TRANSFER_SIZE,
+        },
+        channel::Channel,
+        ddk::dma::{DmaBuffer, DmaPool},
+        early_logger::EarlyLogger,
+        event::Event,
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+    sync::Arc,
+};
+
+/// We use 4KiB pages for the controller's memory (this is the smallest page size, and so is supported by every
+/// controller).
+pub const PAGE_SIZE: usize = 0x1000;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+/// The number of I/O queue pairs we ask the controller for. We might be given fewer.
+const MAX_IO_QUEUES: u16 = 4;
+
+const ADMIN_CREATE_IO_SUBMISSION_QUEUE: u8 = 0x01;
+const ADMIN_CREATE_IO_COMPLETION_QUEUE: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const IDENTIFY_DATA_SIZE: usize = 0x1000;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// The number of namespaces we've provided as block devices, across every controller we drive. Used to give
+/// each its own service name.
+static NUM_BLOCK_DEVICES: AtomicUsize = AtomicUsize::new(0);
+/// Every namespace we're serving, so their writes can be flushed before the system is shut down.
+static NAMESPACES: Spinlock<Vec<(Arc<Controller>, Namespace)>> = Spinlock::new(Vec::new());
+
+pub fn map_memory_object(memory_object: MemoryObject) -> MappedMemoryObject {
+    unsafe { memory_object.map().unwrap() }
+}
+
+pub struct Controller {
+    admin_queue: Spinlock<QueuePair>,
+    io_queues: RwSpinlock<Vec<Spinlock<QueuePair>>>,
+    /// The number of interrupt vectors the controller can signal. The admin queue uses vector 0, and I/O queues are
+    /// spread across all of them.
+    num_interrupts: u16,
+    buffer_pool: Spinlock<DmaPool>,
+    /// Whether the controller caches writes. If it doesn't, there's no need to flush.
+    volatile_write_cache: AtomicBool,
+}
+
+impl Controller {
+    pub async fn admin_command(&self, command: Command) -> Result<u32, CommandError> {
+        loop {
+            // Don't hold the lock across the `await`, or we'd block the interrupt task from completing the command
+            let future = self.admin_queue.lock().submit(command);
+            match future {
+                Some(future) => return future.await,
+                None => std::poplar::rt::time::sleep(Duration::from_millis(1)).await,
+            }
+        }
+    }
+
+    pub async fn io_command(&self, queue: usize, command: Command) -> Result<u32, CommandError> {
+        loop {
+            let future = self.io_queues.read()[queue].lock().submit(command);
+            match future {
+                Some(future) => return future.await,
+                None => std::poplar::rt::time::sleep(Duration::from_millis(1)).await,
+            }
+        }
+    }
+
+    /// Make an `Identify` command, returning the data structure it produces.
+    pub async fn identify(&self, cns: u32, namespace: u32) -> Result<DmaBuffer, CommandError> {
+        let mut buffer = self.buffer_pool.lock().create_buffer(IDENTIFY_DATA_SIZE).unwrap();
+        let (prp1, prp2) = data_pointers(&buffer);
+        let mut command = Command::new(ADMIN_IDENTIFY, namespace);
+        command.prp1 = prp1;
+        command.prp2 = prp2;
+        command.dwords[0] = cns;
+
+        let token = buffer.token().unwrap();
+        let result = self.admin_command(command).await;
+        drop(token);
+        result.map(|_| buffer)
+    }
+
+    /// The interrupt vector that the I/O queue with the given ID signals.
+    pub fn io_queue_vector(&self, id: u16) -> u16 {
+        id % self.num_interrupts
+    }
+
+    /// Process the completion queues that signal interrupts on `vector`.
+    pub fn process_completions(&self, vector: u16) {
+        if vector == 0 {
+            self.admin_queue.lock().process_completions();
+        }
+        for (i, queue) in self.io_queues.read().iter().enumerate() {
+            if self.io_queue_vector(i as u16 + 1) == vector {
+                queue.lock().process_completions();
+            }
+        }
+    }
+}
+
+/// Get the Physical Region Page entries that describe a buffer. As we only use two entries, buffers can't cross
+/// more than one page boundary.
+fn data_pointers(buffer: &DmaBuffer) -> (u64, u64) {
+    let end = buffer.phys + buffer.length;
+    let next_page = mulch::math::align_up(buffer.phys + 1, PAGE_SIZE);
+    assert!(end <= next_page + PAGE_SIZE, "Buffer spans too many pages to be described by PRP entries");
+    (buffer.phys as u64, if end > next_page { next_page as u64 } else { 0 })
+}
+
+/// Wait for the controller's `READY` bit to become `ready`, which it does once it has finished being enabled or
+/// disabled.
+async fn wait_for_ready(registers: &RegisterBlock, ready: bool) -> Result<(), ()> {
+    let timeout_ms = registers.capabilities().timeout_ms();
+    let mut waited_ms = 0;
+    while registers.read_status().contains(Status::READY) != ready {
+        if waited_ms >= timeout_ms || registers.read_status().contains(Status::FATAL) {
+            return Err(());
+        }
+        std::poplar::rt::time::sleep(Duration::from_millis(1)).await;
+        waited_ms += 1;
+    }
+    Ok(())
+}
+
+/// Read an ASCII string from an identify data structure. These are padded with spaces.
+fn read_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end().to_string()
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..(offset + 8)].try_into().unwrap())
+}
+
+async fn drive_controller(handoff_info: HandoffInfo, service_host_client: Arc<ServiceHostClient>) {
+    let mut registers = {
+        let bar = MemoryObject {
+            handle: handoff_info.get_as_memory_object("pci.bar0.handle").unwrap(),
+            size: handoff_info.get_as_integer("pci.bar0.size").unwrap() as usize,
+            flags: MemoryObjectFlags::WRITABLE,
+            phys_address: None,
+        };
+        // The registers are accessed through `RegisterBlock`, so we don't need to keep the mapping around
+        RegisterBlock::new(map_memory_object(bar).mapped_at)
+    };
+    let interrupt_events: Vec<Event> = (0..).map_while(|i| handoff_info.get_pci_interrupt(i)).collect();
+    assert!(!interrupt_events.is_empty(), "NVMe controller does not have any interrupts");
+    let dma_constraints = handoff_info
+        .get_as_dma_domain("pci.dma_domain")
+        .map_or(DmaConstraints::NONE, |domain| DmaConstraints::NONE.in_domain(domain));
+
+    let capabilities = registers.capabilities();
+    let (major, minor) = registers.version();
+    info!("NVMe controller version {}.{}: {:?}", major, minor, capabilities);
+    if !capabilities.supports_nvm_command_set() || capabilities.min_page_size() != 0 {
+        warn!("NVMe controller does not support the NVM command set with 4KiB pages. Ignoring it.");
+        return;
+    }
+
+    if let Some(pci_config) = handoff_info.get_as_channel("pci.config").map(PciConfig::new) {
+        if let Err(err) = pci_config.enable_bus_mastering().await {
+            warn!("Failed to enable bus mastering for NVMe controller: {:?}", err);
+        }
+    }
+
+    /*
+     * Reset the controller by disabling it (it may have been enabled by the firmware), and then set up the admin
+     * queues and enable it again.
+     */
+    let config = registers.read_config();
+    if config.contains(Config::ENABLE) {
+        unsafe {
+            registers.write_config(config.difference(Config::ENABLE));
+        }
+    }
+    if wait_for_ready(&registers, false).await.is_err() {
+        warn!("NVMe controller failed to reset");
+        return;
+    }
+
+    let admin_queue = QueuePair::new(
+        ADMIN_QUEUE_SIZE,
+        registers.doorbell(0, false),
+        registers.doorbell(0, true),
+        dma_constraints,
+    );
+    unsafe {
+        registers.set_admin_queues(ADMIN_QUEUE_SIZE, admin_queue.submission_phys(), admin_queue.completion_phys());
+        registers.write_config(Config::ENABLE | Config::SUBMISSION_ENTRY_SIZE | Config::COMPLETION_ENTRY_SIZE);
+    }
+    if wait_for_ready(&registers, true).await.is_err() {
+        warn!("NVMe controller failed to become ready (status = {:?})", registers.read_status());
+        return;
+    }
+
+    let buffer_pool = {
+        const BUFFER_POOL_SIZE: usize = 0x8000;
+        let (memory_object, _) =
+            MemoryObject::create_dma(BUFFER_POOL_SIZE, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        DmaPool::new(map_memory_object(memory_object))
+    };
+
+    let controller = Arc::new(Controller {
+        admin_queue: Spinlock::new(admin_queue),
+        io_queues: RwSpinlock::new(Vec::new()),
+        num_interrupts: interrupt_events.len() as u16,
+        buffer_pool: Spinlock::new(buffer_pool),
+        volatile_write_cache: AtomicBool::new(false),
+    });
+
+    /*
+     * Spawn a task for each interrupt vector, to complete commands when the controller signals us.
+     */
+    for (vector, interrupt_event) in interrupt_events.into_iter().enumerate() {
+        let controller = controller.clone();
+        std::poplar::rt::spawn(async move {
+            loop {
+                interrupt_event.wait_for_event().await;
+                controller.process_completions(vector as u16);
+                interrupt_event.ack_interrupt();
+            }
+        });
+    }
+
+    let identify_data = match controller.identify(IDENTIFY_CONTROLLER, 0).await {
+        Ok(data) => data,
+        Err(err) => {
+            warn!("Failed to identify NVMe controller: {:?}", err);
+            return;
+        }
+    };
+    let identify_controller = identify_data.read();
+    let volatile_write_cache = identify_controller[525] & 0b1 != 0;
+    controller.volatile_write_cache.store(volatile_write_cache, Ordering::Relaxed);
+    info!(
+        "NVMe controller: {} (serial number {}, firmware {}). Volatile write cache: {}",
+        read_string(&identify_controller[24..64]),
+        read_string(&identify_controller[4..24]),
+        read_string(&identify_controller[64..72]),
+        volatile_write_cache,
+    );
+
+    /*
+     * Ask for as many I/O queues as we'd like. The controller tells us how many it has actually allocated (this
+     * and the value we ask for are both 0-based).
+     */
+    let num_io_queues = {
+        let mut command = Command::new(ADMIN_SET_FEATURES, 0);
+        command.dwords[0] = FEATURE_NUMBER_OF_QUEUES;
+        command.dwords[1] = ((MAX_IO_QUEUES as u32 - 1) << 16) | (MAX_IO_QUEUES as u32 - 1);
+        match controller.admin_command(command).await {
+            Ok(result) => {
+                let submission_queues = (result & 0xffff) as u16 + 1;
+                let completion_queues = (result >> 16) as u16 + 1;
+                MAX_IO_QUEUES.min(submission_queues).min(completion_queues)
+            }
+            Err(err) => {
+                warn!("Failed to set number of I/O queues: {:?}", err);
+                return;
+            }
+        }
+    };
+
+    let queue_size = IO_QUEUE_SIZE.min(capabilities.max_queue_entries().min(u16::MAX as u32) as u16);
+    for id in 1..=num_io_queues {
+        let queue = QueuePair::new(
+            queue_size,
+            registers.doorbell(id, false),
+            registers.doorbell(id, true),
+            dma_constraints,
+        );
+        let queue_info = ((queue_size as u32 - 1) << 16) | id as u32;
+
+        // Each completion queue is physically contiguous, and has interrupts enabled
+        let mut create_completion = Command::new(ADMIN_CREATE_IO_COMPLETION_QUEUE, 0);
+        create_completion.prp1 = queue.completion_phys();
+        create_completion.dwords[0] = queue_info;
+        create_completion.dwords[1] = ((controller.io_queue_vector(id) as u32) << 16) | 0b11;
+
+        let mut create_submission = Command::new(ADMIN_CREATE_IO_SUBMISSION_QUEUE, 0);
+        create_submission.prp1 = queue.submission_phys();
+        create_submission.dwords[0] = queue_info;
+        create_submission.dwords[1] = ((id as u32) << 16) | 0b1;
+
+        // The queue needs to be processed by the interrupt task as soon as it exists
+        controller.io_queues.write().push(Spinlock::new(queue));
+        if let Err(err) = controller.admin_command(create_completion).await {
+            warn!("Failed to create I/O completion queue {}: {:?}", id, err);
+            controller.io_queues.write().pop();
+            break;
+        }
+        if let Err(err) = controller.admin_command(create_submission).await {
+            warn!("Failed to create I/O submission queue {}: {:?}", id, err);
+            controller.io_queues.write().pop();
+            break;
+        }
+    }
+    let num_io_queues = controller.io_queues.read().len();
+    if num_io_queues == 0 {
+        warn!("NVMe controller has no usable I/O queues");
+        return;
+    }
+    info!(
+        "Using {} I/O queues of {} entries, across {} interrupt vectors",
+        num_io_queues, queue_size, controller.num_interrupts
+    );
+
+    let active_namespaces: Vec<u32> = match controller.identify(IDENTIFY_ACTIVE_NAMESPACES, 0).await {
+        Ok(data) => {
+            let data = data.read();
+            (0..(IDENTIFY_DATA_SIZE / 4)).map(|i| read_u32(data, i * 4)).take_while(|&id| id != 0).collect()
+        }
+        Err(err) => {
+            warn!("Failed to list active namespaces: {:?}", err);
+            Vec::new()
+        }
+    };
+
+    for id in active_namespaces {
+        let namespace = match controller.identify(IDENTIFY_NAMESPACE, id).await {
+            Ok(data) => Namespace::from_identify_data(id, data.read()),
+            Err(err) => {
+                warn!("Failed to identify namespace {}: {:?}", id, err);
+                continue;
+            }
+        };
+        if namespace.block_size as usize > MAX_TRANSFER_SIZE {
+            warn!("Namespace {} has unsupported block size of {} bytes", id, namespace.block_size);
+            continue;
+        }
+
+        serve_namespace(controller.clone(), namespace, &service_host_client);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Namespace {
+    id: u32,
+    block_size: u32,
+    num_blocks: u64,
+    read_only: bool,
+}
+
+impl Namespace {
+    fn from_identify_data(id: u32, data: &[u8]) -> Namespace {
+        // The LBA format in use is selected from a table of up to 16 formats
+        let format = (data[26] & 0xf) as usize;
+        let lba_data_size = data[128 + format * 4 + 2];
+        Namespace {
+            id,
+            block_size: 1 << lba_data_size,
+            num_blocks: read_u64(data, 0),
+            read_only: data[99] & 0b1 != 0,
+        }
+    }
+}
+
+/// A client's view of a namespace. Each client uses one of the controller's I/O queues.
+struct NamespaceClient {
+    controller: Arc<Controller>,
+    namespace: Namespace,
+    queue: usize,
+}
+
+impl NamespaceClient {
+    async fn handle_request(&self, request: BlockRequest) -> BlockResponse {
+        let result = match request {
+            BlockRequest::GetInfo => Ok(BlockResponse::Info(BlockDeviceInfo {
+                block_size: self.namespace.block_size,
+                num_blocks: self.namespace.num_blocks,
+                read_only: self.namespace.read_only,
+            })),
+            BlockRequest::Read { block, count } => self.read(block, count).await.map(BlockResponse::Data),
+            BlockRequest::Write { block, data } => self.write(block, &data).await.map(|()| BlockResponse::Done),
+            BlockRequest::Flush => self.flush().await.map(|()| BlockResponse::Done),
+        };
+        result.unwrap_or_else(BlockResponse::Error)
+    }
+
+    async fn read(&self, block: u64, count: u32) -> Result<Vec<u8>, BlockError> {
+        let length = self.check_range(block, count as usize * self.namespace.block_size as usize)?;
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let mut buffer =
+            self.controller.buffer_pool.lock().create_buffer(length).map_err(|()| BlockError::DeviceError)?;
+        self.transfer(IO_READ, block, count, &mut buffer).await?;
+        Ok(buffer.read().to_vec())
+    }
+
+    async fn write(&self, block: u64, data: &[u8]) -> Result<(), BlockError> {
+        if self.namespace.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        if data.len() % self.namespace.block_size as usize != 0 {
+            return Err(BlockError::InvalidLength);
+        }
+        let length = self.check_range(block, data.len())?;
+        if length == 0 {
+            return Ok(());
+        }
+        let mut buffer =
+            self.controller.buffer_pool.lock().create_buffer(length).map_err(|()| BlockError::DeviceError)?;
+        buffer.write().copy_from_slice(data);
+        let count = (length / self.namespace.block_size as usize) as u32;
+        self.transfer(IO_WRITE, block, count, &mut buffer).await
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        if !self.controller.volatile_write_cache.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.controller
+            .io_command(self.queue, Command::new(IO_FLUSH, self.namespace.id))
+            .await
+            .map(|_| ())
+            .map_err(|err| {
+                warn!("NVMe flush failed: {:?}", err);
+                BlockError::DeviceError
+            })
+    }
+
+    async fn transfer(
+        &self,
+        opcode: u8,
+        block: u64,
+        count: u32,
+        buffer: &mut DmaBuffer,
+    ) -> Result<(), BlockError> {
+        let (prp1, prp2) = data_pointers(buffer);
+        let mut command = Command::new(opcode, self.namespace.id);
+        command.prp1 = prp1;
+        command.prp2 = prp2;
+        command.dwords[0] = block as u32;
+        command.dwords[1] = (block >> 32) as u32;
+        // The number of blocks is 0-based
+        command.dwords[2] = count - 1;
+
+        let token = buffer.token().unwrap();
+        let result = self.controller.io_command(self.queue, command).await;
+        drop(token);
+        result.map(|_| ()).map_err(|err| {
+            warn!("NVMe command {:#x} to block {} failed: {:?}", opcode, block, err);
+            BlockError::DeviceError
+        })
+    }
+
+    /// Check that a transfer of `length` bytes, starting at `block`, can be made. Returns the length.
+    fn check_range(&self, block: u64, length: usize) -> Result<usize, BlockError> {
+        if length > MAX_TRANSFER_SIZE {
+            return Err(BlockError::TooLarge);
+        }
+        let end = block
+            .checked_add((length / self.namespace.block_size as usize) as u64)
+            .ok_or(BlockError::OutOfRange)?;
+        if end > self.namespace.num_blocks {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(length)
+    }
+}
+
+/// Provide a namespace to other tasks as a block device. The first is provided as `block.device`, and any more
+/// as `block.device.1`, `block.device.2`, etc.
+fn serve_namespace(controller: Arc<Controller>, namespace: Namespace, service_host_client: &ServiceHostClient) {
+    let service_name = match NUM_BLOCK_DEVICES.fetch_add(1, Ordering::Relaxed) {
+        0 => BLOCK_DEVICE_SERVICE.to_string(),
+        n => format!("{}.{}", BLOCK_DEVICE_SERVICE, n),
+    };
+    info!(
+        "Providing namespace {} ({} blocks of {} bytes, read-only: {}) as '{}'",
+        namespace.id, namespace.num_blocks, namespace.block_size, namespace.read_only, service_name
+    );
+
+    NAMESPACES.lock().push((controller.clone(), namespace));
+    let service_channel = service_host_client.register_service(service_name).unwrap();
+    std::poplar::rt::spawn(async move {
+        let mut next_queue = 0;
+        loop {
+            match service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    info!("Task '{}' is using NVMe namespace {} (I/O queue {})", name, namespace.id, next_queue);
+                    let channel: Channel<BlockResponse, BlockRequest> = Channel::new_from_handle(channel);
+
+                    /*
+                     * Each client gets a task to handle its requests, and spreads its commands over the I/O
+                     * queues. Commands from different clients can be in flight at the same time.
+                     */
+                    let client = NamespaceClient { controller: controller.clone(), namespace, queue: next_queue };
+                    next_queue = (next_queue + 1) % controller.io_queues.read().len();
+                    std::poplar::rt::spawn(async move {
+                        loop {
+                            let request = channel.receive().await.unwrap();
+                            let response = client.handle_request(request).await;
+                            channel.send(&response).unwrap();
+                        }
+                    });
+                }
+            }
+        }
+    });
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("NVMe driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = Arc::new(ServiceHostClient::new());
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    // NVMe controllers have a class code of `0x010802` (Mass Storage Controller, NVM, NVMe I/O controller)
+    let handoff_server = platform_bus_device_client
+        .register(vec![
+            Filter::Matches(String::from("pci.class"), Property::Integer(0x01)),
+            Filter::Matches(String::from("pci.sub_class"), Property::Integer(0x08)),
+            Filter::Matches(String::from("pci.interface"), Property::Integer(0x02)),
+        ])
+        .unwrap();
+
+    std::poplar::rt::spawn(async move {
+        loop {
+            match handoff_server.next().await.unwrap() {
+                DeviceHandoffCall::QuerySupport { responder, .. } => {
+                    responder.reply(true).unwrap();
+                }
+                DeviceHandoffCall::HandoffDevice { name, handoff_info, .. } => {
+                    info!("Started driving NVMe controller: {}", name);
+                    std::poplar::rt::spawn(drive_controller(handoff_info, service_host_client.clone()));
+                }
+                DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                    let namespaces = NAMESPACES.lock().clone();
+                    for (controller, namespace) in namespaces {
+                        let client = NamespaceClient { controller, namespace, queue: 0 };
+                        if client.flush().await.is_err() {
+                            warn!("Failed to flush NVMe namespace {} before shutdown", namespace.id);
+                        }
+                    }
+                    responder.reply(()).unwrap();
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
@@ -0,0 +1,211 @@
+use crate::PAGE_SIZE;
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Poll, Waker},
+};
+use spinning_top::Spinlock;
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+    sync::Arc,
+};
+
+/// An entry of a submission queue.
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct Command {
+    pub opcode: u8,
+    pub flags: u8,
+    pub id: u16,
+    pub namespace: u32,
+    _reserved: u64,
+    pub metadata: u64,
+    /// The data of the command is described by Physical Region Page entries. The first points to the start of the
+    /// data, and the second to the next page, if the data crosses a page boundary.
+    pub prp1: u64,
+    pub prp2: u64,
+    pub dwords: [u32; 6],
+}
+
+impl Command {
+    pub fn new(opcode: u8, namespace: u32) -> Command {
+        Command { opcode, namespace, ..Default::default() }
+    }
+}
+
+/// An entry of a completion queue.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Completion {
+    /// Command-specific result.
+    pub result: u32,
+    _reserved: u32,
+    pub submission_head: u16,
+    _submission_queue: u16,
+    pub command_id: u16,
+    /// Bit `0` is the Phase Tag, which is flipped by the controller each time it wraps around the queue. The rest
+    /// is the status of the command.
+    pub status: u16,
+}
+
+/// The Status Field of a command that failed. The Status Code is in the lower byte, and the Status Code Type in
+/// bits `8..11`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CommandError(pub u16);
+
+struct CommandState {
+    result: Option<Result<u32, CommandError>>,
+    waker: Option<Waker>,
+}
+
+pub struct CommandFuture(Arc<Spinlock<CommandState>>);
+
+impl Future for CommandFuture {
+    type Output = Result<u32, CommandError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0.lock();
+        match state.result {
+            Some(result) => Poll::Ready(result),
+            None => {
+                // Replace the waker each time, as the future can move between tasks on the executor
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A submission queue and the completion queue the controller reports the completion of its commands on. We
+/// only use a single submission queue per completion queue, and so each pair shares an ID.
+pub struct QueuePair {
+    size: u16,
+    submission: MappedMemoryObject,
+    completion: MappedMemoryObject,
+    submission_doorbell: usize,
+    completion_doorbell: usize,
+    submission_tail: u16,
+    /// The entry of the submission queue that the controller will consume next, as last reported by it.
+    submission_head: u16,
+    completion_head: u16,
+    /// The value of the Phase Tag that marks completion entries as new. Starts at `1` and flips each time we
+    /// wrap around the queue.
+    phase: bool,
+    next_command_id: u16,
+    in_flight: BTreeMap<u16, Arc<Spinlock<CommandState>>>,
+}
+
+impl QueuePair {
+    pub fn new(
+        size: u16,
+        submission_doorbell: usize,
+        completion_doorbell: usize,
+        dma_constraints: DmaConstraints,
+    ) -> QueuePair {
+        /*
+         * The queues must be physically contiguous and page-aligned, so each gets its own memory object. DMA
+         * memory is zeroed, so all the completion queue's entries start with a Phase Tag of `0` and aren't
+         * mistaken for new completions.
+         */
+        let create_queue = |entry_size: usize| {
+            let size = mulch::math::align_up(size as usize * entry_size, PAGE_SIZE);
+            let (memory_object, _) =
+                MemoryObject::create_dma(size, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+            crate::map_memory_object(memory_object)
+        };
+
+        QueuePair {
+            size,
+            submission: create_queue(mem::size_of::<Command>()),
+            completion: create_queue(mem::size_of::<Completion>()),
+            submission_doorbell,
+            completion_doorbell,
+            submission_tail: 0,
+            submission_head: 0,
+            completion_head: 0,
+            phase: true,
+            next_command_id: 0,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    pub fn submission_phys(&self) -> u64 {
+        self.submission.inner.phys_address.unwrap() as u64
+    }
+
+    pub fn completion_phys(&self) -> u64 {
+        self.completion.inner.phys_address.unwrap() as u64
+    }
+
+    /// Submit a command to the controller. Returns `None` if the submission queue is full.
+    pub fn submit(&mut self, mut command: Command) -> Option<CommandFuture> {
+        let next_tail = (self.submission_tail + 1) % self.size;
+        if next_tail == self.submission_head {
+            return None;
+        }
+
+        // Find an ID that isn't being used by a command that's still in flight
+        while self.in_flight.contains_key(&self.next_command_id) {
+            self.next_command_id = self.next_command_id.wrapping_add(1);
+        }
+        command.id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+
+        let state = Arc::new(Spinlock::new(CommandState { result: None, waker: None }));
+        self.in_flight.insert(command.id, state.clone());
+
+        unsafe {
+            let entry = (self.submission.ptr() as *mut Command).add(self.submission_tail as usize);
+            std::ptr::write_volatile(entry, command);
+            self.submission_tail = next_tail;
+            std::ptr::write_volatile(self.submission_doorbell as *mut u32, self.submission_tail as u32);
+        }
+
+        Some(CommandFuture(state))
+    }
+
+    /// Process any new entries in the completion queue, waking the tasks waiting on the completed commands.
+    pub fn process_completions(&mut self) {
+        let mut processed_any = false;
+
+        loop {
+            let completion = unsafe {
+                std::ptr::read_volatile(
+                    (self.completion.ptr() as *const Completion).add(self.completion_head as usize),
+                )
+            };
+            if (completion.status & 0b1 != 0) != self.phase {
+                break;
+            }
+
+            self.submission_head = completion.submission_head;
+            if let Some(state) = self.in_flight.remove(&completion.command_id) {
+                let mut state = state.lock();
+                let status = completion.status >> 1;
+                state.result = Some(if status == 0 { Ok(completion.result) } else { Err(CommandError(status)) });
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+
+            self.completion_head += 1;
+            if self.completion_head == self.size {
+                self.completion_head = 0;
+                self.phase = !self.phase;
+            }
+            processed_any = true;
+        }
+
+        // Let the controller know it can reuse the entries we've consumed
+        if processed_any {
+            unsafe {
+                std::ptr::write_volatile(self.completion_doorbell as *mut u32, self.completion_head as u32);
+            }
+        }
+    }
+}
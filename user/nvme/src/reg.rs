@@ -0,0 +1,140 @@
+use bitflags::bitflags;
+
+/// The controller's registers, which are found at the start of BAR0. The doorbells of each queue follow them,
+/// spaced out by the doorbell stride.
+pub struct RegisterBlock {
+    base: usize,
+    doorbell_stride: usize,
+}
+
+impl RegisterBlock {
+    pub fn new(base: usize) -> RegisterBlock {
+        let mut registers = RegisterBlock { base, doorbell_stride: 0 };
+        registers.doorbell_stride = 4 << registers.capabilities().doorbell_stride();
+        registers
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities(unsafe { self.read_register_64(Register::Capabilities) })
+    }
+
+    pub fn version(&self) -> (u16, u8) {
+        let version = unsafe { self.read_register(Register::Version) };
+        ((version >> 16) as u16, (version >> 8) as u8)
+    }
+
+    pub fn read_config(&self) -> Config {
+        Config::from_bits_retain(unsafe { self.read_register(Register::Config) })
+    }
+
+    pub unsafe fn write_config(&mut self, value: Config) {
+        unsafe {
+            self.write_register(Register::Config, value.bits());
+        }
+    }
+
+    pub fn read_status(&self) -> Status {
+        Status::from_bits_retain(unsafe { self.read_register(Register::Status) })
+    }
+
+    /// Set the sizes of the admin queues, and their physical addresses. Can only be done while the controller is
+    /// disabled.
+    pub unsafe fn set_admin_queues(&mut self, size: u16, submission: u64, completion: u64) {
+        let size = (size - 1) as u32;
+        unsafe {
+            self.write_register(Register::AdminQueueAttributes, (size << 16) | size);
+            self.write_register_64(Register::AdminSubmissionQueue, submission);
+            self.write_register_64(Register::AdminCompletionQueue, completion);
+        }
+    }
+
+    /// Get the address of the doorbell of the given queue. Each queue pair has a submission queue tail doorbell,
+    /// followed by a completion queue head doorbell.
+    pub fn doorbell(&self, queue: u16, completion: bool) -> usize {
+        self.base + DOORBELL_BASE + (2 * queue as usize + completion as usize) * self.doorbell_stride
+    }
+
+    unsafe fn read_register(&self, reg: Register) -> u32 {
+        unsafe { std::ptr::read_volatile((self.base + reg as usize) as *const u32) }
+    }
+
+    unsafe fn write_register(&mut self, reg: Register, value: u32) {
+        unsafe {
+            std::ptr::write_volatile((self.base + reg as usize) as *mut u32, value);
+        }
+    }
+
+    unsafe fn read_register_64(&self, reg: Register) -> u64 {
+        unsafe { std::ptr::read_volatile((self.base + reg as usize) as *const u64) }
+    }
+
+    unsafe fn write_register_64(&mut self, reg: Register, value: u64) {
+        unsafe {
+            std::ptr::write_volatile((self.base + reg as usize) as *mut u64, value);
+        }
+    }
+}
+
+const DOORBELL_BASE: usize = 0x1000;
+
+#[repr(usize)]
+#[derive(Clone, Copy)]
+enum Register {
+    Capabilities = 0x00,
+    Version = 0x08,
+    Config = 0x14,
+    Status = 0x1c,
+    AdminQueueAttributes = 0x24,
+    AdminSubmissionQueue = 0x28,
+    AdminCompletionQueue = 0x30,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// The maximum number of entries in each submission and completion queue.
+    pub fn max_queue_entries(&self) -> u32 {
+        (self.0 & 0xffff) as u32 + 1
+    }
+
+    /// How long to wait for the controller to become ready after it's enabled or disabled, in milliseconds.
+    pub fn timeout_ms(&self) -> u64 {
+        ((self.0 >> 24) & 0xff) * 500
+    }
+
+    /// The stride between doorbells, as a power of two multiple of 4 bytes.
+    pub fn doorbell_stride(&self) -> u8 {
+        ((self.0 >> 32) & 0xf) as u8
+    }
+
+    pub fn supports_nvm_command_set(&self) -> bool {
+        self.0 & (1 << 37) != 0
+    }
+
+    /// The smallest page size supported by the controller, as a power of two multiple of 4KiB.
+    pub fn min_page_size(&self) -> u8 {
+        ((self.0 >> 48) & 0xf) as u8
+    }
+}
+
+bitflags! {
+    /// Leaving the Command Set Selected and Memory Page Size fields clear selects the NVM command set and 4KiB
+    /// pages.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Config: u32 {
+        const ENABLE = 1 << 0;
+        /// Submission queue entries are 64 bytes (given as a power of two).
+        const SUBMISSION_ENTRY_SIZE = 6 << 16;
+        /// Completion queue entries are 16 bytes (given as a power of two).
+        const COMPLETION_ENTRY_SIZE = 4 << 20;
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Status: u32 {
+        const READY = 1 << 0;
+        const FATAL = 1 << 1;
+    }
+}
@@ -0,0 +1,36 @@
+//! Framebuffers can be provided by a variety of drivers (e.g. for a Virtio GPU), and so we model them abstractly
+//! as standard Platform Bus devices. A framebuffer is described by these properties:
+//!    - `type`: always `"framebuffer"`
+//!    - `width` and `height`: the size of the framebuffer, in pixels
+//!    - `format`: the pixel format of the framebuffer (e.g. `"rgb32"`)
+//!
+//! and two handoff properties: `framebuffer`, a memory object containing the pixels of the framebuffer, and
+//! `channel`, a channel that the user of the framebuffer sends `FramebufferRequest`s down, and receives
+//! `FramebufferEvent`s from.
+
+use ptah::{Deserialize, Serialize};
+use std::poplar::Handle;
+
+/// Messages sent from the user of a framebuffer to its driver.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum FramebufferRequest {
+    /// Make the changes made to a region of the framebuffer visible on the display. Only this region needs to be
+    /// copied to the display, so this should cover as little of the framebuffer as possible.
+    Flush { x: u32, y: u32, width: u32, height: u32 },
+    /// Change the resolution of the display. The driver replies with `FramebufferEvent::ModeSet` if it could,
+    /// or `FramebufferEvent::ModeRefused` if the resolution isn't supported.
+    SetMode { width: u32, height: u32 },
+}
+
+/// Messages sent from a framebuffer's driver to its user.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum FramebufferEvent {
+    /// The resolution of the display has changed. The framebuffer has been replaced by `framebuffer`, a new memory
+    /// object holding `width * height` pixels in the same format as before, and the old one is no longer shown.
+    ModeSet {
+        width: u32,
+        height: u32,
+        framebuffer: Handle,
+    },
+    ModeRefused,
+}
@@ -2,32 +2,57 @@
 //! computer. They can be connected to the platform via a variety of buses, and so we model them
 //! abstractly as standard Platform Bus devices.
 
+use interface_gen::interface;
 use ptah::{Deserialize, Serialize};
 
+interface! {
+    /*
+     * Used by the driver of an input device to send its events to whoever the device is handed off to. HID
+     * devices have a `hid.channel` handoff property, which is the server end of this interface.
+     */
+    pub interface InputDevice {
+        request: InputDeviceRequest,
+        reply: InputDeviceReply,
+        call: InputDeviceCall,
+        client: InputDeviceClient,
+        server: InputDeviceServer,
+
+        Event => fn event(event: InputEvent);
+    }
+}
+
+/// An event produced by an input device. Keys are identified by their position on the keyboard, not the
+/// character they produce - this depends on the layout of the keyboard, and so is left to the consumer of the
+/// events. `KeyPressed` and `KeyReleased` events are also produced for the modifier keys themselves, with `state`
+/// reflecting the change, so consumers can track them.
+///
+/// Pointing devices report movement either relative to the pointer's last position (`PointerMoved`, e.g. from a
+/// mouse), or as an absolute position (`PointerMovedTo`, e.g. from a tablet or touchscreen). Absolute positions are
+/// scaled so that `0..=u16::MAX` covers the device's whole range on each axis, and it's up to the consumer to
+/// scale them to the size of the screen.
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum InputEvent {
     KeyPressed { key: Key, state: KeyState },
     KeyReleased { key: Key, state: KeyState },
-    RelX(i32),
-    RelY(i32),
+    PointerMoved { dx: i32, dy: i32 },
+    PointerMovedTo { x: u16, y: u16 },
+    ButtonPressed(Button),
+    ButtonReleased(Button),
     RelZ(i32),
     RelWheel(i32),
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+    Side,
+    Extra,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Key {
-    /*
-     * Buttons
-     */
-    BtnLeft,
-    BtnMiddle,
-    BtnRight,
-    BtnSide,
-    BtnExtra,
-
-    /*
-     * Keys
-     */
     KeyA,
     KeyB,
     KeyC,
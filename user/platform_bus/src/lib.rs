@@ -12,16 +12,29 @@
 //!
 //! Sometimes, a Device Driver will need to inspect a device to know whether it can drive it. A
 //! driver may use a more permissive filter to attract devices it may be able to drive, and then
-//! filter them by replying to `QuerySupport` messages from the Platform Bus. Device Drivers that
+//! filter them by replying to `QuerySupport` calls from the Platform Bus. Device Drivers that
 //! can provide an exact filter for the devices they can drive can safely blindly return `true` to
 //! these queries.
+//!
+//! The protocols between the Platform Bus and its drivers are defined with `interface_gen`: Bus Drivers use
+//! the `BusDriver` interface, and Device Drivers register with the `DeviceDriver` interface, after which the
+//! Platform Bus offers them devices over the `DeviceHandoff` interface.
+//!
+//! The Platform Bus is also responsible for shutting the system down (through the `SystemPower` interface), as it
+//! knows which drivers need to flush and stop their devices first. Each Device Driver that has claimed a device is
+//! sent `PrepareForShutdown`, and the system is powered off once they have all replied (or after a timeout, so a
+//! misbehaving driver can't stop the system from shutting down).
 
+pub mod framebuffer;
 pub mod input;
+pub mod net;
+pub mod pci;
 
+use interface_gen::{interface, InterfaceError};
 use ptah::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
-    poplar::{event::Event, Handle},
+    poplar::{channel::Channel, event::Event, Handle},
 };
 
 type DeviceName = String;
@@ -79,6 +92,19 @@ impl HandoffInfo {
     pub fn get_as_channel(&self, name: &str) -> Option<Handle> {
         self.0.get(name)?.as_channel()
     }
+
+    pub fn get_as_dma_domain(&self, name: &str) -> Option<Handle> {
+        self.0.get(name)?.as_dma_domain()
+    }
+
+    /// Get the `Event` for the `index`th interrupt of a PCI device. See the `pci.interrupt` property.
+    pub fn get_pci_interrupt(&self, index: usize) -> Option<Event> {
+        if index == 0 {
+            self.get_as_event("pci.interrupt")
+        } else {
+            self.get_as_event(&format!("pci.interrupt.{}", index))
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -128,6 +154,7 @@ pub enum HandoffProperty {
     MemoryObject(Handle),
     Event(Handle),
     Channel(Handle),
+    DmaDomain(Handle),
 }
 
 impl HandoffProperty {
@@ -179,41 +206,106 @@ impl HandoffProperty {
             _ => None,
         }
     }
+
+    pub fn as_dma_domain(&self) -> Option<Handle> {
+        match self {
+            HandoffProperty::DmaDomain(value) => Some(*value),
+            _ => None,
+        }
+    }
 }
 
-/// These are messages sent from Bus Drivers to the Platform Bus.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum BusDriverMessage {
-    RegisterDevice(DeviceName, DeviceInfo, HandoffInfo),
-    // TODO: this could have messages to handle hot-plugging (Bus Driver tells Platform Bus a device was removed,
-    // we pass that on to the Device Driver if the device was claimed by one)
+interface! {
+    /*
+     * Used by Bus Drivers to tell the Platform Bus about the devices on their buses.
+     * TODO: this could have methods to handle hot-plugging (Bus Driver tells Platform Bus a device was removed,
+     * we pass that on to the Device Driver if the device was claimed by one)
+     */
+    pub interface BusDriver {
+        request: BusDriverRequest,
+        reply: BusDriverReply,
+        call: BusDriverCall,
+        client: BusDriverClient,
+        server: BusDriverServer,
+
+        RegisterDevice => fn register_device(name: DeviceName, device_info: DeviceInfo, handoff_info: HandoffInfo);
+    }
 }
 
-/// These are messages sent from Device Drivers to the Platform Bus.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum DeviceDriverMessage {
-    /// Register interest in a particular type of device. For a device to be managed by this device driver, all of
-    /// the `Filter`s must be fulfilled.
-    RegisterInterest(Vec<Filter>),
-    /// Response to a `QuerySupport` request, indicating that this Device Driver either can or
-    /// cannot drive the specified device.
-    CanSupport(DeviceName, bool),
+interface! {
+    /*
+     * Used by Device Drivers to register with the Platform Bus.
+     */
+    pub interface DeviceDriver {
+        request: DeviceDriverRequest,
+        reply: DeviceDriverReply,
+        call: DeviceDriverCall,
+        client: DeviceDriverClient,
+        server: DeviceDriverServer,
+
+        /// Register interest in a particular type of device. For a device to be managed by this device driver,
+        /// all of the `Filter`s must be fulfilled. `handoff` is a channel that the Device Driver serves the
+        /// `DeviceHandoff` interface on.
+        RegisterInterest => fn register_interest(filters: Vec<Filter>, handoff: Handle);
+    }
 }
 
-/// These are message sent from the Platform Bus to a Device Driver.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum DeviceDriverRequest {
-    /// Query whether a Device Driver can drive the specified device. Respond with a `CanSupport`
-    /// message.
-    QuerySupport(DeviceName, DeviceInfo),
-    /// Request that a Device Driver starts to handle the given Device.
-    HandoffDevice(DeviceName, DeviceInfo, HandoffInfo),
+interface! {
+    /*
+     * Used by the Platform Bus to offer devices to a Device Driver.
+     */
+    pub interface DeviceHandoff {
+        request: DeviceHandoffRequest,
+        reply: DeviceHandoffReply,
+        call: DeviceHandoffCall,
+        client: DeviceHandoffClient,
+        server: DeviceHandoffServer,
+
+        /// Query whether a Device Driver can drive the specified device.
+        QuerySupport => fn query_support(name: DeviceName, device_info: DeviceInfo) -> bool;
+        /// Request that a Device Driver starts to handle the given Device.
+        HandoffDevice => fn handoff_device(name: DeviceName, device_info: DeviceInfo, handoff_info: HandoffInfo);
+        /// Sent before the system is shut down (or rebooted, if `reboot` is set). The Device Driver should flush
+        /// any data its devices have cached, and stop them, before replying. Its devices may not be used after
+        /// this.
+        PrepareForShutdown => fn prepare_for_shutdown(reboot: bool) -> ();
+    }
+}
+
+interface! {
+    /*
+     * Used to shut down and reboot the system.
+     */
+    pub interface SystemPower {
+        request: SystemPowerRequest,
+        reply: SystemPowerReply,
+        call: SystemPowerCall,
+        client: SystemPowerClient,
+        server: SystemPowerServer,
+
+        /// Shut the system down, or reboot it if `reboot` is set. Every Device Driver is asked to prepare for
+        /// shutdown first. The Platform Bus only replies if the kernel fails to power off or reset the system.
+        Shutdown => fn shutdown(reboot: bool) -> ();
+    }
+}
+
+impl DeviceDriverClient {
+    /// Register interest in devices that match `filters`. Returns the server that the Platform Bus will query
+    /// support for, and hand off, matching devices through.
+    pub fn register(&self, filters: Vec<Filter>) -> Result<DeviceHandoffServer, InterfaceError> {
+        let (channel, other_end) = Channel::create().expect("Failed to create channel for device handoff");
+        self.register_interest(filters, other_end)?;
+        Ok(DeviceHandoffServer::new(channel))
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Filter {
     Matches(PropertyName, Property),
     All(Vec<Filter>),
+    /// Fulfilled if any of the `Filter`s are. This is useful for drivers that support several variants of a
+    /// device that are identified differently.
+    Any(Vec<Filter>),
 }
 
 impl Filter {
@@ -226,10 +318,25 @@ impl Filter {
             Filter::All(filters) => filters
                 .iter()
                 .fold(true, |matches_so_far, filter| matches_so_far && filter.match_against(&properties)),
+            Filter::Any(filters) => filters.iter().any(|filter| filter.match_against(&properties)),
         }
     }
 }
 
+/// The version of the inspection protocol that this crate implements. Clients of the inspection service send the
+/// version they were built against in an `InspectRequest`, and the Platform Bus replies with a
+/// `PlatformBusInspect` serialized for the older of the two versions. The inspection types are tagged, so fields
+/// can be added to them (with `#[ptah(since = N)]`, bumping this version) without the Platform Bus and its clients
+/// having to be rebuilt together.
+pub const INSPECT_VERSION: ptah::Version = 1;
+
+/// Sent to the PlatformBus's inspection service to query its state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[ptah(tagged)]
+pub struct InspectRequest {
+    pub version: ptah::Version,
+}
+
 /// Type returned by a query to the PlatformBus's inspection service. This is designed to be used
 /// from a shell/console to query the state of the PlatformBus and its devices.
 /*
@@ -239,6 +346,7 @@ impl Filter {
  * between a service-providing task and `fb_console`.
  */
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[ptah(tagged)]
 pub struct PlatformBusInspect {
     pub devices: Vec<DeviceInspect>,
     pub bus_drivers: Vec<BusDriverInspect>,
@@ -260,11 +368,13 @@ pub enum DeviceInspect {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[ptah(tagged)]
 pub struct BusDriverInspect {
     pub name: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[ptah(tagged)]
 pub struct DeviceDriverInspect {
     pub name: String,
     pub filters: Option<Vec<Filter>>,
@@ -2,22 +2,29 @@ mod service;
 
 use log::{info, warn};
 use platform_bus::{
-    BusDriverMessage,
-    DeviceDriverMessage,
-    DeviceDriverRequest,
+    BusDriverCall,
+    BusDriverServer,
+    DeviceDriverCall,
+    DeviceDriverServer,
+    DeviceHandoffClient,
     DeviceInfo,
     DeviceInspect,
     Filter,
     HandoffInfo,
+    InspectRequest,
     PlatformBusInspect,
+    SystemPowerCall,
+    SystemPowerServer,
+    INSPECT_VERSION,
 };
 use service_host::{ServiceChannelMessage, ServiceHostClient};
 use spinning_top::RwSpinlock;
 use std::{
     collections::BTreeMap,
     mem,
-    poplar::{channel::Channel, early_logger::EarlyLogger},
+    poplar::{channel::Channel, early_logger::EarlyLogger, syscall},
     sync::Arc,
+    time::Duration,
 };
 
 type BusDriverIndex = usize;
@@ -28,16 +35,20 @@ type DeviceDriverIndex = usize;
 /// the device tree, for example, are managed by the Platform Bus directly.
 pub const KERNEL_DEVICE: BusDriverIndex = usize::MAX;
 
+/// How long we wait for Device Drivers to prepare for the system shutting down, before shutting it down anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct BusDriver {
     name: String,
-    channel: Arc<Channel<(), BusDriverMessage>>,
+    server: Arc<BusDriverServer>,
 }
 
 struct DeviceDriver {
     name: String,
     /// If this is `None`, the driver hasn't registered its filters yet, and shouldn't be offered any devices.
     filters: Option<Vec<Filter>>,
-    channel: Arc<Channel<DeviceDriverRequest, DeviceDriverMessage>>,
+    /// The client we offer devices to the driver through. This is `None` until the driver registers its filters.
+    handoff: Option<Arc<DeviceHandoffClient>>,
 }
 
 #[derive(Debug)]
@@ -70,27 +81,18 @@ impl PlatformBus {
         })
     }
 
-    // TODO: not convinced the channels should be Arc'd
-    pub fn register_bus_driver(
-        &self,
-        name: String,
-        channel: Arc<Channel<(), BusDriverMessage>>,
-    ) -> BusDriverIndex {
+    // TODO: not convinced the servers should be Arc'd
+    pub fn register_bus_driver(&self, name: String, server: Arc<BusDriverServer>) -> BusDriverIndex {
         let mut bus_drivers = self.bus_drivers.write();
         let index = bus_drivers.len();
-        bus_drivers.push(BusDriver { name, channel });
+        bus_drivers.push(BusDriver { name, server });
         index
     }
 
-    // TODO: not convinced the channels should be Arc'd
-    pub fn register_device_driver(
-        &self,
-        name: String,
-        channel: Arc<Channel<DeviceDriverRequest, DeviceDriverMessage>>,
-    ) -> DeviceDriverIndex {
+    pub fn register_device_driver(&self, name: String) -> DeviceDriverIndex {
         let mut device_drivers = self.device_drivers.write();
         let index = device_drivers.len();
-        device_drivers.push(DeviceDriver { name, filters: None, channel });
+        device_drivers.push(DeviceDriver { name, filters: None, handoff: None });
         index
     }
 
@@ -103,7 +105,7 @@ impl PlatformBus {
     /// the driver for support. This should be called whenever a change is detected that could mean
     /// a device could be handed off (e.g. a new device is registered, or a device driver registers
     /// its interest).
-    pub fn check_devices(&self) {
+    pub fn check_devices(self: &Arc<Self>) {
         for (name, device) in self.devices.write().iter_mut() {
             // Skip devices that have already been handed off.
             if let Device::Claimed { .. } = device {
@@ -111,7 +113,9 @@ impl PlatformBus {
             }
 
             let device_drivers = self.device_drivers.read();
-            for device_driver in device_drivers.iter().filter(|driver| driver.filters.is_some()) {
+            for (device_driver_index, device_driver) in
+                device_drivers.iter().enumerate().filter(|(_, driver)| driver.filters.is_some())
+            {
                 let mut matches_filter = false;
                 for filter in device_driver.filters.as_ref().unwrap() {
                     match device {
@@ -129,10 +133,21 @@ impl PlatformBus {
                     info!("Asking device driver with matching filter if it can handle device {}", name);
                     match device {
                         Device::Unclaimed { device_info, .. } => {
-                            device_driver
-                                .channel
-                                .send(&DeviceDriverRequest::QuerySupport(name.clone(), device_info.clone()))
-                                .unwrap();
+                            /*
+                             * We can't wait for the driver to reply while holding the locks, so each query is
+                             * made from its own task, which hands the device off if the driver can support it.
+                             */
+                            let platform_bus = self.clone();
+                            let handoff = device_driver.handoff.clone().unwrap();
+                            let name = name.clone();
+                            let device_info = device_info.clone();
+                            std::poplar::rt::spawn(async move {
+                                match handoff.query_support(name.clone(), device_info).await {
+                                    Ok(true) => platform_bus.handoff_device(name, device_driver_index, &handoff),
+                                    Ok(false) => (),
+                                    Err(err) => warn!("Failed to query support for device '{}': {:?}", name, err),
+                                }
+                            });
                         }
                         _ => {}
                     }
@@ -141,6 +156,78 @@ impl PlatformBus {
         }
     }
 
+    /// Hand off the device called `name` to a device driver that has said that it can support it.
+    fn handoff_device(&self, name: String, device_driver: DeviceDriverIndex, handoff: &DeviceHandoffClient) {
+        let mut devices = self.devices.write();
+        let device = devices.get_mut(&name).unwrap();
+
+        if device.is_claimed() {
+            warn!(
+                "Device driver claimed support for '{}', but device has already been handed off! Ignoring.",
+                name
+            );
+            return;
+        }
+
+        info!("Handing off device '{}' to supporting device driver", name);
+        let claimed_device = if let Device::Unclaimed { bus_driver, device_info, .. } = &device {
+            Device::Claimed { bus_driver: *bus_driver, device_info: device_info.clone(), device_driver }
+        } else {
+            panic!()
+        };
+        let taken_device = mem::replace(device, claimed_device);
+        if let Device::Unclaimed { device_info, handoff_info, .. } = taken_device {
+            if let Err(err) = handoff.handoff_device(name, device_info, handoff_info) {
+                warn!("Failed to hand off device: {:?}", err);
+            }
+        } else {
+            panic!();
+        }
+    }
+
+    /// Ask each Device Driver that has claimed a device to flush and stop its devices, before the system is shut
+    /// down. Drivers are asked at the same time, and we stop waiting for them after `SHUTDOWN_TIMEOUT`.
+    pub async fn prepare_for_shutdown(&self, reboot: bool) {
+        let drivers: Vec<(String, Arc<DeviceHandoffClient>)> = {
+            let claimed_by: Vec<DeviceDriverIndex> = self
+                .devices
+                .read()
+                .values()
+                .filter_map(|device| match device {
+                    Device::Claimed { device_driver, .. } => Some(*device_driver),
+                    Device::Unclaimed { .. } => None,
+                })
+                .collect();
+            self.device_drivers
+                .read()
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| claimed_by.contains(index))
+                .filter_map(|(_, driver)| Some((driver.name.clone(), driver.handoff.clone()?)))
+                .collect()
+        };
+
+        let replies: Vec<_> = drivers
+            .into_iter()
+            .map(|(name, handoff)| {
+                std::poplar::rt::spawn(async move {
+                    match handoff.prepare_for_shutdown(reboot).await {
+                        Ok(()) => info!("Device driver '{}' is ready for shutdown", name),
+                        Err(err) => warn!("Device driver '{}' failed to prepare for shutdown: {:?}", name, err),
+                    }
+                })
+            })
+            .collect();
+        let all_replied = async {
+            for reply in replies {
+                let _ = reply.await;
+            }
+        };
+        if std::poplar::rt::time::timeout(SHUTDOWN_TIMEOUT, all_replied).await.is_err() {
+            warn!("Timed out waiting for device drivers to prepare for shutdown. Shutting down anyway.");
+        }
+    }
+
     pub fn inspect(&self) -> PlatformBusInspect {
         /*
          * TODO: we're getting a big stack overflow when adding all the properties to this and
@@ -203,6 +290,7 @@ pub fn main() {
     let device_driver_service_channel =
         service_host_client.register_service("platform_bus.device_driver").unwrap();
     let inspect_service_channel = service_host_client.register_service("platform_bus.inspect").unwrap();
+    let power_service_channel = service_host_client.register_service("platform_bus.power").unwrap();
 
     let platform_bus = PlatformBus::new();
 
@@ -210,6 +298,7 @@ pub fn main() {
      * Add devices from buses that the Platform Bus enumerates itself.
      */
     platform_bus.devices.write().append(&mut service::pci::enumerate_pci_devices());
+    platform_bus.devices.write().append(&mut service::device_tree::enumerate_platform_devices());
 
     /*
      * Listen for new bus drivers that want a channel to register devices.
@@ -221,9 +310,9 @@ pub fn main() {
                 match bus_driver_service_channel.receive().await.unwrap() {
                     ServiceChannelMessage::NewClient { name: driver_name, channel } => {
                         info!("Bus driver '{}' subscribed to PlatformBus!", driver_name);
-                        let channel = Arc::new(Channel::new_from_handle(channel));
+                        let server = Arc::new(BusDriverServer::from_handle(channel));
                         let bus_driver_index =
-                            platform_bus.register_bus_driver(driver_name.clone(), channel.clone());
+                            platform_bus.register_bus_driver(driver_name.clone(), server.clone());
 
                         /*
                          * Each new bus driver gets a task to listen for newly registered devices.
@@ -232,8 +321,8 @@ pub fn main() {
                             let platform_bus = platform_bus.clone();
                             async move {
                                 loop {
-                                    match channel.receive().await.unwrap() {
-                                        BusDriverMessage::RegisterDevice(name, device_info, handoff_info) => {
+                                    match server.next().await.unwrap() {
+                                        BusDriverCall::RegisterDevice { name, device_info, handoff_info } => {
                                             info!(
                                                 "Registering new device from '{}': Device: {:?}, Handoff: {:?} as {}",
                                                 driver_name, device_info, handoff_info, name
@@ -268,17 +357,17 @@ pub fn main() {
                 match device_driver_service_channel.receive().await.unwrap() {
                     ServiceChannelMessage::NewClient { name, channel } => {
                         info!("Device driver '{}' subscribed to PlatformBus!", name);
-                        let channel = Arc::new(Channel::new_from_handle(channel));
-                        let device_driver_index = platform_bus.register_device_driver(name, channel.clone());
+                        let server = DeviceDriverServer::from_handle(channel);
+                        let device_driver_index = platform_bus.register_device_driver(name);
 
                         /*
-                         * Each new device driver gets a task to listen for newly registered devices.
+                         * Each new device driver gets a task to listen for it registering its interests.
                          */
                         let platform_bus = platform_bus.clone();
                         std::poplar::rt::spawn(async move {
                             loop {
-                                match channel.receive().await.unwrap() {
-                                    DeviceDriverMessage::RegisterInterest(filters) => {
+                                match server.next().await.unwrap() {
+                                    DeviceDriverCall::RegisterInterest { filters, handoff } => {
                                         info!("Registering interest for devices with filters: {:?}", filters);
                                         {
                                             let mut device_drivers = platform_bus.device_drivers.write();
@@ -291,57 +380,39 @@ pub fn main() {
                                              */
                                             if device_driver.filters.is_none() {
                                                 device_driver.filters = Some(filters);
+                                                device_driver.handoff =
+                                                    Some(Arc::new(DeviceHandoffClient::from_handle(handoff)));
                                             } else {
                                                 warn!("Device driver tried to register interests more than one. Ignored.");
                                             }
                                         }
                                         platform_bus.check_devices();
                                     }
-                                    DeviceDriverMessage::CanSupport(device_name, does_support) => {
-                                        if does_support {
-                                            let mut device_drivers = platform_bus.device_drivers.write();
-                                            let device_driver =
-                                                device_drivers.get_mut(device_driver_index).unwrap();
-                                            let mut devices = platform_bus.devices.write();
-                                            let device = devices.get_mut(&device_name).unwrap();
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+        }
+    });
 
-                                            if device.is_claimed() {
-                                                warn!("Device driver claimed support for '{}', but device has already been handed off! Ignoring.", device_name);
-                                                continue;
-                                            }
+    std::poplar::rt::spawn({
+        let platform_bus = platform_bus.clone();
+        async move {
+            loop {
+                match inspect_service_channel.receive().await.unwrap() {
+                    ServiceChannelMessage::NewClient { name, channel } => {
+                        let channel: Channel<PlatformBusInspect, InspectRequest> =
+                            Channel::new_from_handle(channel);
 
-                                            info!(
-                                                "Handing off device '{}' to supporting device driver",
-                                                device_name
-                                            );
-                                            let claimed_device =
-                                                if let Device::Unclaimed { bus_driver, device_info, .. } = &device
-                                                {
-                                                    Device::Claimed {
-                                                        bus_driver: *bus_driver,
-                                                        device_info: device_info.clone(),
-                                                        device_driver: device_driver_index,
-                                                    }
-                                                } else {
-                                                    panic!()
-                                                };
-                                            let taken_device = mem::replace(device, claimed_device);
-                                            if let Device::Unclaimed { bus_driver, device_info, handoff_info } =
-                                                taken_device
-                                            {
-                                                device_driver
-                                                    .channel
-                                                    .send(&DeviceDriverRequest::HandoffDevice(
-                                                        device_name,
-                                                        device_info.clone(),
-                                                        handoff_info,
-                                                    ))
-                                                    .unwrap();
-                                            } else {
-                                                panic!();
-                                            }
-                                        }
-                                    }
+                        std::poplar::rt::spawn({
+                            let platform_bus = platform_bus.clone();
+                            async move {
+                                loop {
+                                    let InspectRequest { version } = channel.receive().await.unwrap();
+                                    let result = platform_bus.inspect();
+                                    channel.send_with_version(&result, version.min(INSPECT_VERSION)).unwrap();
                                 }
                             }
                         });
@@ -351,22 +422,34 @@ pub fn main() {
         }
     });
 
+    /*
+     * Listen for requests to shut down or reboot the system.
+     */
     std::poplar::rt::spawn({
         let platform_bus = platform_bus.clone();
         async move {
             loop {
-                match inspect_service_channel.receive().await.unwrap() {
+                match power_service_channel.receive().await.unwrap() {
                     ServiceChannelMessage::NewClient { name, channel } => {
-                        let channel = Channel::new_from_handle(channel);
+                        let server = SystemPowerServer::from_handle(channel);
 
                         std::poplar::rt::spawn({
                             let platform_bus = platform_bus.clone();
                             async move {
                                 loop {
-                                    match channel.receive().await.unwrap() {
-                                        () => {
-                                            let result = platform_bus.inspect();
-                                            channel.send(&result).unwrap();
+                                    match server.next().await.unwrap() {
+                                        SystemPowerCall::Shutdown { reboot, responder } => {
+                                            info!("Task '{}' asked for the system to shut down", name);
+                                            platform_bus.prepare_for_shutdown(reboot).await;
+
+                                            if reboot {
+                                                if let Err(err) = syscall::system_reboot() {
+                                                    warn!("Failed to reboot the system: {:?}", err);
+                                                }
+                                            } else if let Err(err) = syscall::system_shutdown() {
+                                                warn!("Failed to power off the system: {:?}", err);
+                                            }
+                                            responder.reply(()).unwrap();
                                         }
                                     }
                                 }
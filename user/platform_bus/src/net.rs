@@ -0,0 +1,26 @@
+//! Network devices allow the platform to send and receive packets. Like HIDs, they can be provided by a variety of
+//! drivers, and so we model them abstractly as standard Platform Bus devices. A network device is described by
+//! these properties:
+//!    - `type`: always `"network"`, so a network stack can find all network devices with a single filter
+//!    - `net.mac_address`: the device's MAC address, as 6 bytes
+//!    - `net.mtu`: the largest packet (excluding the link-layer header) the device can send or receive
+//!
+//! and a `net.channel` handoff property, which is a channel that a network stack can use to send
+//! `NetworkRequest`s to the driver and receive `NetworkEvent`s from it.
+
+use ptah::{Deserialize, Serialize};
+
+/// Messages sent from a network stack to the driver of a network device.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum NetworkRequest {
+    /// Send a packet. This should be a complete link-layer frame (e.g. an Ethernet frame, without the frame check
+    /// sequence).
+    SendPacket(Vec<u8>),
+}
+
+/// Messages sent from the driver of a network device to a network stack.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum NetworkEvent {
+    /// A packet has been received. Like sent packets, this is a complete link-layer frame.
+    PacketReceived(Vec<u8>),
+}
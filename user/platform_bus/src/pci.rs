@@ -0,0 +1,162 @@
+//! PCI devices are handed off with a `pci.config` channel, through which their driver can access the device's
+//! configuration space. Accesses are mediated by the Platform Bus: any dword can be read, but only the registers
+//! that drivers need to manage are writable, and only their driver-controlled bits:
+//!    - The Command register (IO space, memory space, bus mastering, and interrupt disable)
+//!    - The Power Management Control/Status register, if the device has the Power Management capability
+//!
+//! Everything else (BARs, MSI/MSI-X configuration, etc.) is owned by the kernel, and writes to it are denied.
+
+use ptah::{Deserialize, Serialize};
+use std::poplar::{channel::Channel, Handle};
+
+pub const COMMAND_OFFSET: u16 = 0x04;
+pub const COMMAND_IO_SPACE: u32 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u32 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+
+const CAPABILITIES_POINTER_OFFSET: u16 = 0x34;
+const STATUS_CAPABILITIES_LIST: u32 = 1 << 20;
+const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+
+pub const CAPABILITY_ID_POWER_MANAGEMENT: u8 = 0x01;
+
+/// Messages sent from a device driver to the Platform Bus, to access its device's configuration space. Offsets
+/// are in bytes, and must be aligned to 4 bytes.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum PciConfigRequest {
+    Read { offset: u16 },
+    Write { offset: u16, value: u32 },
+}
+
+/// Messages sent from the Platform Bus in response to a `PciConfigRequest`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum PciConfigResponse {
+    Read(u32),
+    Written,
+    Error(PciConfigError),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum PciConfigError {
+    /// The offset is not aligned to 4 bytes, or lies outside the configuration space.
+    InvalidOffset,
+    /// The register at the offset is not writable by device drivers.
+    AccessDenied,
+    /// The Platform Bus could not access the configuration space.
+    Failed,
+    /// The response from the Platform Bus could not be understood.
+    InvalidResponse,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+/// A driver's view of its PCI device's configuration space. Requests are answered in order, so this should only
+/// be used from one task at a time.
+pub struct PciConfig {
+    channel: Channel<PciConfigRequest, PciConfigResponse>,
+}
+
+impl PciConfig {
+    pub fn new(channel: Handle) -> PciConfig {
+        PciConfig { channel: Channel::new_from_handle(channel) }
+    }
+
+    pub async fn read(&self, offset: u16) -> Result<u32, PciConfigError> {
+        self.channel.send(&PciConfigRequest::Read { offset }).map_err(|_| PciConfigError::Failed)?;
+        match self.channel.receive().await.map_err(|_| PciConfigError::Failed)? {
+            PciConfigResponse::Read(value) => Ok(value),
+            PciConfigResponse::Error(err) => Err(err),
+            _ => Err(PciConfigError::InvalidResponse),
+        }
+    }
+
+    pub async fn write(&self, offset: u16, value: u32) -> Result<(), PciConfigError> {
+        self.channel.send(&PciConfigRequest::Write { offset, value }).map_err(|_| PciConfigError::Failed)?;
+        match self.channel.receive().await.map_err(|_| PciConfigError::Failed)? {
+            PciConfigResponse::Written => Ok(()),
+            PciConfigResponse::Error(err) => Err(err),
+            _ => Err(PciConfigError::InvalidResponse),
+        }
+    }
+
+    /// Set or clear bits in the Command register. Only the bits that drivers control (the `COMMAND_*`
+    /// constants) can be changed.
+    pub async fn update_command(&self, set: u32, clear: u32) -> Result<(), PciConfigError> {
+        // The upper half of the dword is the Status register, which is write-one-to-clear
+        let command = self.read(COMMAND_OFFSET).await? & 0xffff;
+        self.write(COMMAND_OFFSET, (command | set) & !clear).await
+    }
+
+    /// Allow the device to access memory through DMA. Most devices need this before they can be used.
+    pub async fn enable_bus_mastering(&self) -> Result<(), PciConfigError> {
+        self.update_command(COMMAND_BUS_MASTER | COMMAND_MEMORY_SPACE, 0).await
+    }
+
+    /// Get the offset of the first capability in the device's capability list with the given ID.
+    pub async fn find_capability(&self, id: u8) -> Result<Option<u16>, PciConfigError> {
+        if self.read(COMMAND_OFFSET).await? & STATUS_CAPABILITIES_LIST == 0 {
+            return Ok(None);
+        }
+
+        let mut offset = (self.read(CAPABILITIES_POINTER_OFFSET).await? & 0xfc) as u16;
+        // Limit how many capabilities we'll look at, in case a device has a looping list
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+            let header = self.read(offset).await?;
+            if header as u8 == id {
+                return Ok(Some(offset));
+            }
+            offset = ((header >> 8) & 0xfc) as u16;
+        }
+        Ok(None)
+    }
+
+    /// Get the offset of the first PCI Express extended capability with the given ID. Devices that are not PCI
+    /// Express devices do not have extended capabilities.
+    pub async fn find_extended_capability(&self, id: u16) -> Result<Option<u16>, PciConfigError> {
+        let mut offset = EXTENDED_CAPABILITIES_OFFSET;
+        for _ in 0..960 {
+            let header = self.read(offset).await?;
+            if header == 0 || header == 0xffff_ffff {
+                break;
+            }
+            if header as u16 == id {
+                return Ok(Some(offset));
+            }
+            offset = ((header >> 20) & 0xffc) as u16;
+            if offset < EXTENDED_CAPABILITIES_OFFSET {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Move the device into a different power state. Devices need time to recover after moving from `D3Hot` to
+    /// `D0` (10ms), which it's up to the caller to wait for. Returns `Ok(false)` if the device does not support
+    /// power management.
+    pub async fn set_power_state(&self, state: PowerState) -> Result<bool, PciConfigError> {
+        let Some(capability) = self.find_capability(CAPABILITY_ID_POWER_MANAGEMENT).await? else {
+            return Ok(false);
+        };
+        let state = match state {
+            PowerState::D0 => 0b00,
+            PowerState::D1 => 0b01,
+            PowerState::D2 => 0b10,
+            PowerState::D3Hot => 0b11,
+        };
+
+        // Don't write back `PME_Status`, as that would clear it
+        let control = self.read(capability + 4).await? & !(0b11 | 1 << 15);
+        self.write(capability + 4, control | state).await?;
+        Ok(true)
+    }
+}
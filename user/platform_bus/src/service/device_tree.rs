@@ -0,0 +1,69 @@
+use crate::Device;
+use log::{info, warn};
+use platform_bus::{DeviceInfo, HandoffInfo, HandoffProperty, Property};
+use std::collections::BTreeMap;
+
+/// Add the devices described by the platform's firmware (these come from the device tree, or from the ACPI
+/// namespace on x86_64) that the kernel has handed out to us. Drivers can match against each entry of the device's
+/// `compatible` property with a `dt.compatible.<entry>` property. For ACPI devices, the entries are the device's
+/// hardware ID, followed by its compatible IDs.
+pub fn enumerate_platform_devices() -> BTreeMap<String, Device> {
+    let mut devices = BTreeMap::new();
+    let descriptors = match std::poplar::ddk::platform::get_platform_devices_vec() {
+        Ok(descriptors) => descriptors,
+        Err(err) => {
+            warn!("Failed to get platform devices: {:?}", err);
+            return devices;
+        }
+    };
+
+    for descriptor in descriptors {
+        info!("Platform device at {}: compatible with {:?}", descriptor.name(), descriptor.compatible().next());
+
+        let name = "dt-".to_string() + descriptor.name();
+        let device_info = {
+            let mut properties = BTreeMap::new();
+            properties.insert("dt.path".to_string(), Property::String(descriptor.name().to_string()));
+            for compatible in descriptor.compatible() {
+                properties.insert(format!("dt.compatible.{}", compatible), Property::Bool(true));
+            }
+            DeviceInfo(properties)
+        };
+        let handoff_info = {
+            let mut properties = BTreeMap::new();
+
+            /*
+             * Regions don't have to start on a page boundary, but the memory objects that cover them do, so each
+             * region starts `dt.regN.offset` bytes into its memory object.
+             */
+            for (i, region) in descriptor.regions.iter().enumerate() {
+                if let Some(region) = region {
+                    properties.insert(
+                        format!("dt.reg{}.handle", i),
+                        HandoffProperty::MemoryObject(region.memory_object),
+                    );
+                    properties.insert(format!("dt.reg{}.size", i), HandoffProperty::Integer(region.size as u64));
+                    properties
+                        .insert(format!("dt.reg{}.offset", i), HandoffProperty::Integer(region.offset as u64));
+                }
+            }
+
+            for (i, range) in descriptor.io_ports.iter().enumerate() {
+                if let Some(range) = range {
+                    properties.insert(format!("dt.io{}.base", i), HandoffProperty::Integer(range.base as u64));
+                    properties.insert(format!("dt.io{}.length", i), HandoffProperty::Integer(range.length as u64));
+                }
+            }
+
+            if let Some(interrupt) = descriptor.interrupt {
+                properties.insert("dt.interrupt".to_string(), HandoffProperty::Event(interrupt));
+            }
+
+            HandoffInfo(properties)
+        };
+
+        devices.insert(name, Device::Unclaimed { bus_driver: crate::KERNEL_DEVICE, device_info, handoff_info });
+    }
+
+    devices
+}
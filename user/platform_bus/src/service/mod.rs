@@ -1 +1,2 @@
+pub mod device_tree;
 pub mod pci;
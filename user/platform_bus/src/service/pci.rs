@@ -1,8 +1,35 @@
 use crate::Device;
-use log::info;
-use pci_types::device_type::{DeviceType, UsbType};
-use platform_bus::{DeviceInfo, HandoffInfo, HandoffProperty, Property};
-use std::{collections::BTreeMap, poplar::ddk::pci::Bar};
+use log::{info, warn};
+use pci_types::{
+    device_type::{DeviceType, UsbType},
+    PciAddress,
+};
+use platform_bus::{
+    pci::{
+        PciConfigError,
+        PciConfigRequest,
+        PciConfigResponse,
+        CAPABILITY_ID_POWER_MANAGEMENT,
+        COMMAND_BUS_MASTER,
+        COMMAND_INTERRUPT_DISABLE,
+        COMMAND_IO_SPACE,
+        COMMAND_MEMORY_SPACE,
+        COMMAND_OFFSET,
+    },
+    DeviceInfo,
+    HandoffInfo,
+    HandoffProperty,
+    Property,
+};
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        channel::Channel,
+        ddk::pci::{config_read, config_write, dma_domain_attach, Bar},
+        syscall::create_dma_domain,
+        Handle,
+    },
+};
 
 pub fn enumerate_pci_devices() -> BTreeMap<String, Device> {
     let mut devices = BTreeMap::new();
@@ -37,8 +64,46 @@ pub fn enumerate_pci_devices() -> BTreeMap<String, Device> {
         let handoff_info = {
             let mut properties = BTreeMap::new();
 
-            if let Some(interrupt) = descriptor.interrupt {
-                properties.insert("pci.interrupt".to_string(), HandoffProperty::Event(interrupt));
+            /*
+             * The first interrupt is always provided as `pci.interrupt`. Devices with multiple MSI-X vectors have
+             * the rest provided as `pci.interrupt.N`, where `N` is the index of the entry in the MSI-X table.
+             */
+            let interrupts: Vec<Handle> = descriptor.interrupts.iter().map_while(|&interrupt| interrupt).collect();
+            properties.insert("pci.num_interrupts".to_string(), HandoffProperty::Integer(interrupts.len() as u64));
+            for (i, interrupt) in interrupts.into_iter().enumerate() {
+                let name = if i == 0 { "pci.interrupt".to_string() } else { format!("pci.interrupt.{}", i) };
+                properties.insert(name, HandoffProperty::Event(interrupt));
+            }
+
+            /*
+             * Give each device its own DMA domain, so it can only access memory its driver has allocated for it.
+             * This has no effect if the platform doesn't have an IOMMU.
+             */
+            match create_dma_domain() {
+                Ok(domain) => match dma_domain_attach(domain, descriptor.address) {
+                    Ok(()) => {
+                        properties.insert("pci.dma_domain".to_string(), HandoffProperty::DmaDomain(domain));
+                    }
+                    Err(err) => {
+                        warn!("Failed to attach PCI device {} to a DMA domain: {:?}", descriptor.address, err)
+                    }
+                },
+                Err(err) => warn!("Failed to create DMA domain: {:?}", err),
+            }
+
+            match Channel::create() {
+                Ok((channel, channel_handle)) => {
+                    properties.insert("pci.config".to_string(), HandoffProperty::Channel(channel_handle));
+                    let mediator = ConfigSpaceMediator::new(descriptor.address);
+                    std::poplar::rt::spawn(async move {
+                        while let Ok(request) = channel.receive().await {
+                            if channel.send(&mediator.handle_request(request)).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+                Err(err) => warn!("Failed to create config space channel for PCI device: {:?}", err),
             }
 
             for (i, bar) in descriptor.bars.into_iter().enumerate() {
@@ -70,3 +135,85 @@ pub fn enumerate_pci_devices() -> BTreeMap<String, Device> {
 
     devices
 }
+
+/// A register in configuration space that device drivers are allowed to write to. Only the bits in `mask` are
+/// taken from the value the driver writes - the rest keep their current value, apart from those in `clear`, which
+/// are written as zero (e.g. because they're write-one-to-clear).
+struct WritableRegister {
+    offset: u16,
+    mask: u32,
+    clear: u32,
+}
+
+/// Mediates a device driver's accesses to its device's configuration space. See `platform_bus::pci` for what
+/// drivers are allowed to do.
+struct ConfigSpaceMediator {
+    address: PciAddress,
+    writable: Vec<WritableRegister>,
+}
+
+impl ConfigSpaceMediator {
+    pub fn new(address: PciAddress) -> ConfigSpaceMediator {
+        let mut writable = vec![WritableRegister {
+            offset: COMMAND_OFFSET,
+            mask: COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE,
+            // The upper half of the dword is the Status register, which is write-one-to-clear
+            clear: 0xffff_0000,
+        }];
+
+        /*
+         * Allow the driver to control the device's power state and PME# generation, if it supports power
+         * management. `PME_Status` is write-one-to-clear, and so is only written if the driver does so.
+         */
+        if let Some(capability) = Self::find_capability(address, CAPABILITY_ID_POWER_MANAGEMENT) {
+            writable.push(WritableRegister { offset: capability + 4, mask: 0b11 | 1 << 8 | 1 << 15, clear: 0 });
+        }
+
+        ConfigSpaceMediator { address, writable }
+    }
+
+    pub fn handle_request(&self, request: PciConfigRequest) -> PciConfigResponse {
+        let result = match request {
+            PciConfigRequest::Read { offset } => self.read(offset).map(PciConfigResponse::Read),
+            PciConfigRequest::Write { offset, value } => {
+                self.write(offset, value).map(|()| PciConfigResponse::Written)
+            }
+        };
+        result.unwrap_or_else(PciConfigResponse::Error)
+    }
+
+    fn read(&self, offset: u16) -> Result<u32, PciConfigError> {
+        use std::poplar::syscall::PciConfigReadError;
+        config_read(self.address, offset).map_err(|err| match err {
+            PciConfigReadError::InvalidOffset => PciConfigError::InvalidOffset,
+            _ => PciConfigError::Failed,
+        })
+    }
+
+    fn write(&self, offset: u16, value: u32) -> Result<(), PciConfigError> {
+        let register =
+            self.writable.iter().find(|register| register.offset == offset).ok_or(PciConfigError::AccessDenied)?;
+        let current = self.read(offset)?;
+        let value = (current & !register.mask & !register.clear) | (value & register.mask);
+        config_write(self.address, offset, value).map_err(|_| PciConfigError::Failed)
+    }
+
+    fn find_capability(address: PciAddress, id: u8) -> Option<u16> {
+        if config_read(address, COMMAND_OFFSET).ok()? & (1 << 20) == 0 {
+            return None;
+        }
+
+        let mut offset = (config_read(address, 0x34).ok()? & 0xfc) as u16;
+        for _ in 0..48 {
+            if offset == 0 {
+                return None;
+            }
+            let header = config_read(address, offset).ok()?;
+            if header as u8 == id {
+                return Some(offset);
+            }
+            offset = ((header >> 8) & 0xfc) as u16;
+        }
+        None
+    }
+}
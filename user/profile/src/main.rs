@@ -0,0 +1,151 @@
+//! `profile` runs the kernel's sampling profiler for a while (one second, or the number of milliseconds it's given
+//! as an argument), and writes each sample to the serial port with `early_log`, as lines of the form:
+//! ```text
+//! PROFILE {cpu} {task} {task name} kernel {frames...} user {frames...}
+//! ```
+//! where the frames are the addresses of the sample's frames, innermost first. Tasks' stacks are only walked if
+//! `--user` is passed - otherwise, only the interrupted instruction is recorded when a task is interrupted.
+//! `cargo xtask profile` turns these into folded stacks, which can be made into a flamegraph. This requires the
+//! `TRACE` capability.
+
+use std::{
+    fmt::Write,
+    poplar::{
+        memory_object::{MappedMemoryObject, MemoryObject},
+        profile::{
+            ProfileBufferHeader,
+            ProfileSample,
+            PROFILE_BUFFER_SAMPLES,
+            PROFILE_BUFFER_SIZE,
+            PROFILE_SAMPLES_OFFSET,
+        },
+        syscall::{self, MemoryObjectFlags, ProfilingMode},
+        trace::{unpack_task_name, TRACE_TASK_NAME_LENGTH},
+        Handle,
+    },
+    time::{Duration, Instant},
+};
+
+/// The most CPUs we can get profile buffers for.
+const MAX_CPUS: usize = 64;
+const DEFAULT_DURATION: Duration = Duration::from_secs(1);
+/// How often the buffers are read while profiling. This needs to be often enough that the buffers don't fill up
+/// between reads.
+const READ_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A CPU's profile buffer, and the number of the next sample to read from it.
+struct Buffer {
+    buffer: MappedMemoryObject,
+    next: u64,
+}
+
+fn main() {
+    let report = |message: &str| {
+        let _ = syscall::early_log(message);
+        if let Some(stdout) = std::poplar::stdio::stdout() {
+            let _ = stdout.write(&format!("{}\n", message));
+        }
+    };
+
+    let mut duration = DEFAULT_DURATION;
+    let mut mode = ProfilingMode::Kernel;
+    for arg in std::env::args().skip(1) {
+        if arg == "--user" {
+            mode = ProfilingMode::KernelAndUser;
+        } else {
+            match arg.parse() {
+                Ok(millis) => duration = Duration::from_millis(millis),
+                Err(_) => {
+                    report(&format!("profile: '{}' is not a number of milliseconds", arg));
+                    return;
+                }
+            }
+        }
+    }
+
+    let mut handles = [Handle::ZERO; MAX_CPUS];
+    let mut buffers: Vec<Buffer> = match syscall::get_profile_buffers(&mut handles) {
+        Ok(num_cpus) => handles[0..num_cpus]
+            .iter()
+            .map(|&handle| {
+                let memory_object =
+                    unsafe { MemoryObject::from_handle(handle, PROFILE_BUFFER_SIZE, MemoryObjectFlags::empty()) };
+                let buffer = unsafe { memory_object.map() }.unwrap();
+                // Skip any samples left over from the last time the profiler was run
+                let next = read_header(&buffer).head;
+                Buffer { buffer, next }
+            })
+            .collect(),
+        Err(err) => {
+            report(&format!("profile: failed to get profile buffers: {:?}", err));
+            return;
+        }
+    };
+
+    report(&format!("profile: profiling {} CPUs for {:?} ({:?})", buffers.len(), duration, mode));
+    syscall::set_profiling(mode).unwrap();
+
+    let mut num_samples = 0;
+    let mut num_lost = 0;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        std::thread::sleep(READ_INTERVAL);
+        for buffer in &mut buffers {
+            let (read, lost) = read_samples(buffer);
+            num_samples += read;
+            num_lost += lost;
+        }
+    }
+
+    syscall::set_profiling(ProfilingMode::Off).unwrap();
+    for buffer in &mut buffers {
+        let (read, lost) = read_samples(buffer);
+        num_samples += read;
+        num_lost += lost;
+    }
+
+    if num_lost > 0 {
+        report(&format!("profile: {} samples were lost because the buffers filled up", num_lost));
+    }
+    report(&format!("profile: captured {} samples", num_samples));
+}
+
+fn read_header(buffer: &MappedMemoryObject) -> ProfileBufferHeader {
+    unsafe { core::ptr::read_volatile(buffer.ptr() as *const ProfileBufferHeader) }
+}
+
+/// Write out the samples that have been added to a buffer since it was last read. Returns the number of samples
+/// written, and the number that were overwritten before they could be read.
+fn read_samples(buffer: &mut Buffer) -> (u64, u64) {
+    let head = read_header(&buffer.buffer).head;
+    let first = u64::max(buffer.next, head.saturating_sub(PROFILE_BUFFER_SAMPLES as u64));
+    let lost = first - buffer.next;
+
+    let mut line = String::new();
+    let mut name_buffer = [0u8; TRACE_TASK_NAME_LENGTH];
+    for index in first..head {
+        let sample = unsafe {
+            let offset = PROFILE_SAMPLES_OFFSET
+                + (index as usize % PROFILE_BUFFER_SAMPLES) * core::mem::size_of::<ProfileSample>();
+            core::ptr::read_volatile(buffer.buffer.ptr().add(offset) as *const ProfileSample)
+        };
+
+        line.clear();
+        let name = match unpack_task_name(&sample.task_name, &mut name_buffer) {
+            Some(name) if !name.is_empty() => name,
+            _ => "-",
+        };
+        write!(line, "PROFILE {} {} {} kernel", sample.cpu, sample.task, name).unwrap();
+        for frame in sample.kernel_frames() {
+            write!(line, " {:#x}", frame).unwrap();
+        }
+        line.push_str(" user");
+        for frame in sample.user_frames() {
+            write!(line, " {:#x}", frame).unwrap();
+        }
+        let _ = syscall::early_log(&line);
+    }
+
+    buffer.next = head;
+    (head - first, lost)
+}
@@ -0,0 +1,177 @@
+//! `ramfs` serves the contents of the initramfs through the VFS. The initramfs is a `ustar` archive loaded by Seed
+//! alongside the kernel, which is handed to userspace as a read-only `MemoryObject`. It's mounted at the root of
+//! the VFS, so that early userspace can find the files it needs before any other filesystems are available.
+
+mod tar;
+
+use log::info;
+use service_host::ServiceHostClient;
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        channel::Channel,
+        early_logger::EarlyLogger,
+        file::{
+            self,
+            path_components,
+            FileError,
+            FileId,
+            FileKind,
+            FileRequest,
+            FileResponse,
+            FileStat,
+            FilesystemMessage,
+            FilesystemResponse,
+            OpenOptions,
+            MAX_DIR_ENTRIES,
+            MAX_TRANSFER_SIZE,
+            VFS_FILESYSTEM_SERVICE,
+        },
+        memory_object::MemoryObject,
+        syscall::MemoryObjectFlags,
+    },
+};
+use tar::{Archive, Node, NodeKind};
+
+const MOUNT_POINT: &str = "/";
+const INITRAMFS_RESOURCE: &str = "initramfs";
+const INITRAMFS_ADDRESS: usize = 0x5_0000_0000;
+
+pub struct RamfsServer<'a> {
+    archive: Archive<'a>,
+    /// The data of each open file. Files can't change, so we don't need to keep track of anything else.
+    open_files: BTreeMap<FileId, &'a [u8]>,
+    next_file_id: u64,
+}
+
+impl<'a> RamfsServer<'a> {
+    pub fn handle_request(&mut self, request: FileRequest) -> FileResponse {
+        let result = match request {
+            FileRequest::Open { path, options } => {
+                self.open(&path, options).map(|(id, stat)| FileResponse::Opened(id, stat))
+            }
+            FileRequest::Read { file, offset, length } => self.read(file, offset, length).map(FileResponse::Data),
+            FileRequest::Write { .. } => Err(FileError::ReadOnly),
+            FileRequest::Map { .. } | FileRequest::Namespace { .. } => Err(FileError::Unsupported),
+            FileRequest::Stat { path } => self.stat(&path).map(FileResponse::Stat),
+            FileRequest::ReadDir { path, start } => self.read_dir(&path, start).map(FileResponse::Entries),
+            FileRequest::Close { file } => self.close(file).map(|()| FileResponse::Done),
+        };
+        result.unwrap_or_else(FileResponse::Error)
+    }
+
+    fn open(&mut self, path: &str, options: OpenOptions) -> Result<(FileId, FileStat), FileError> {
+        let node = self.lookup(path)?;
+        if node.kind == NodeKind::Directory {
+            return Err(FileError::IsADirectory);
+        }
+        if options.write {
+            return Err(FileError::ReadOnly);
+        }
+
+        let id = FileId(self.next_file_id);
+        self.next_file_id += 1;
+        self.open_files.insert(id, node.data);
+        Ok((id, file_stat(node)))
+    }
+
+    fn read(&mut self, file: FileId, offset: u64, length: u32) -> Result<Vec<u8>, FileError> {
+        if length as usize > MAX_TRANSFER_SIZE {
+            return Err(FileError::TooLarge);
+        }
+        let data = self.open_files.get(&file).ok_or(FileError::InvalidFile)?;
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(data.len());
+        let end = (start + length as usize).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn stat(&mut self, path: &str) -> Result<FileStat, FileError> {
+        let node = self.lookup(path)?;
+        Ok(file_stat(node))
+    }
+
+    fn read_dir(&mut self, path: &str, start: u32) -> Result<Vec<file::DirEntry>, FileError> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        let dir = self.archive.get(&components).ok_or(FileError::NotFound)?;
+        if dir.kind != NodeKind::Directory {
+            return Err(FileError::NotADirectory);
+        }
+
+        Ok(dir
+            .children
+            .iter()
+            .skip(start as usize)
+            .take(MAX_DIR_ENTRIES)
+            .map(|name| {
+                let mut child_path = components.clone();
+                child_path.push(name);
+                let kind = file_kind(self.archive.get(&child_path).unwrap());
+                file::DirEntry { name: name.clone(), kind }
+            })
+            .collect())
+    }
+
+    fn close(&mut self, file: FileId) -> Result<(), FileError> {
+        self.open_files.remove(&file).ok_or(FileError::InvalidFile)?;
+        Ok(())
+    }
+
+    fn lookup(&self, path: &str) -> Result<&Node<'a>, FileError> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        self.archive.get(&components).ok_or(FileError::NotFound)
+    }
+}
+
+fn file_stat(node: &Node) -> FileStat {
+    FileStat {
+        kind: file_kind(node),
+        size: node.data.len() as u64,
+        inode: node.inode,
+        permissions: node.permissions,
+    }
+}
+
+fn file_kind(node: &Node) -> FileKind {
+    match node.kind {
+        NodeKind::File => FileKind::File,
+        NodeKind::Directory => FileKind::Directory,
+    }
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Ramfs is running!");
+
+    let service_host_client = ServiceHostClient::new();
+
+    let Ok((handle, size)) = service_host_client.request_memory_resource(INITRAMFS_RESOURCE) else {
+        info!("No initramfs has been loaded. Exiting.");
+        return;
+    };
+    let initramfs = unsafe {
+        MemoryObject::from_handle(handle, size, MemoryObjectFlags::empty())
+            .map_at(INITRAMFS_ADDRESS)
+            .expect("Failed to map initramfs")
+    };
+    let data: &'static [u8] = unsafe { core::slice::from_raw_parts(initramfs.ptr(), size) };
+    let archive = Archive::parse(data).expect("Failed to parse initramfs");
+
+    let vfs_channel: Channel<FilesystemMessage, FilesystemResponse> =
+        service_host_client.subscribe_service(VFS_FILESYSTEM_SERVICE).unwrap();
+    let (channel, channel_handle) = Channel::<FileResponse, FileRequest>::create().unwrap();
+    vfs_channel
+        .send(&FilesystemMessage::Mount { path: MOUNT_POINT.to_string(), channel: channel_handle })
+        .unwrap();
+    match vfs_channel.receive_blocking().unwrap() {
+        FilesystemResponse::Mounted => info!("Mounted initramfs at {}", MOUNT_POINT),
+        FilesystemResponse::Error(err) => panic!("Failed to mount initramfs: {:?}", err),
+    }
+
+    let mut server = RamfsServer { archive, open_files: BTreeMap::new(), next_file_id: 0 };
+    loop {
+        let (request, call) = channel.receive_call_blocking().unwrap();
+        let response = server.handle_request(request);
+        channel.reply(call, &response).unwrap();
+    }
+}
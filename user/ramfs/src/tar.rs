@@ -0,0 +1,182 @@
+//! A parser for the `ustar` archive format, which the initramfs is stored in. We only support what we need to
+//! represent a tree of files: regular files and directories. Other entries (links, devices, etc.) are skipped.
+
+use std::collections::BTreeMap;
+
+const BLOCK_SIZE: usize = 512;
+/// The permissions of directories that are implied by the paths of other entries, rather than having entries of
+/// their own.
+const IMPLIED_DIR_PERMISSIONS: u16 = 0o755;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TarError {
+    /// The archive ends in the middle of a header or the data of an entry.
+    Truncated,
+    /// A header has an invalid checksum, or a field that can't be parsed.
+    InvalidHeader,
+    /// An entry has a path that isn't valid.
+    InvalidPath,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+pub struct Node<'a> {
+    pub kind: NodeKind,
+    pub data: &'a [u8],
+    /// A number unique to this node within the archive. The root directory is `0`.
+    pub inode: u64,
+    pub permissions: u16,
+    /// The names of the entries in this directory, in order. Empty for files.
+    pub children: Vec<String>,
+}
+
+/// The files and directories held in an archive. Nodes are keyed by their path, without a leading `/` (so the
+/// root directory is at `""`).
+pub struct Archive<'a> {
+    nodes: BTreeMap<String, Node<'a>>,
+}
+
+impl<'a> Archive<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Archive<'a>, TarError> {
+        let mut archive = Archive { nodes: BTreeMap::new() };
+        archive.nodes.insert(
+            String::new(),
+            Node {
+                kind: NodeKind::Directory,
+                data: &[],
+                inode: 0,
+                permissions: IMPLIED_DIR_PERMISSIONS,
+                children: Vec::new(),
+            },
+        );
+
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= data.len() {
+            let header = &data[offset..(offset + BLOCK_SIZE)];
+            // The end of the archive is marked by zeroed blocks
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            if !checksum_valid(header) {
+                return Err(TarError::InvalidHeader);
+            }
+
+            let size = parse_octal(&header[124..136]).ok_or(TarError::InvalidHeader)?;
+            let permissions = parse_octal(&header[100..108]).ok_or(TarError::InvalidHeader)? as u16 & 0o7777;
+            let data_start = offset + BLOCK_SIZE;
+            let data_end = data_start.checked_add(size).ok_or(TarError::Truncated)?;
+            if data_end > data.len() {
+                return Err(TarError::Truncated);
+            }
+
+            let path = entry_path(header)?;
+            match header[156] {
+                b'0' | b'\0' => archive.insert(&path, NodeKind::File, &data[data_start..data_end], permissions)?,
+                b'5' => archive.insert(&path, NodeKind::Directory, &[], permissions)?,
+                typ => log::warn!("Skipping entry '{}' of unsupported type '{}' in archive", path, typ as char),
+            }
+
+            offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        }
+
+        Ok(archive)
+    }
+
+    pub fn get(&self, path: &[&str]) -> Option<&Node<'a>> {
+        self.nodes.get(&path.join("/"))
+    }
+
+    /// Add a node at `path`, creating any parent directories that don't have entries of their own. Archives
+    /// aren't required to contain entries for every directory.
+    fn insert(&mut self, path: &str, kind: NodeKind, data: &'a [u8], permissions: u16) -> Result<(), TarError> {
+        // Archives created from a directory often name their entries `./a/b`, and have an entry for `./`
+        let components: Vec<&str> =
+            path.split('/').filter(|&component| !component.is_empty() && component != ".").collect();
+        if components.iter().any(|&component| component == "..") {
+            return Err(TarError::InvalidPath);
+        }
+
+        for i in 0..components.len() {
+            let is_last = i == components.len() - 1;
+            let key = components[..=i].join("/");
+
+            if let Some(node) = self.nodes.get_mut(&key) {
+                if !is_last && node.kind != NodeKind::Directory {
+                    return Err(TarError::InvalidPath);
+                }
+                if is_last {
+                    if node.kind != kind {
+                        return Err(TarError::InvalidPath);
+                    }
+                    // A later entry for the same file replaces the earlier one
+                    node.data = data;
+                    node.permissions = permissions;
+                }
+                continue;
+            }
+
+            let inode = self.nodes.len() as u64;
+            let node = if is_last {
+                Node { kind, data, inode, permissions, children: Vec::new() }
+            } else {
+                Node {
+                    kind: NodeKind::Directory,
+                    data: &[],
+                    inode,
+                    permissions: IMPLIED_DIR_PERMISSIONS,
+                    children: Vec::new(),
+                }
+            };
+            self.nodes.insert(key, node);
+            self.nodes.get_mut(&components[..i].join("/")).unwrap().children.push(components[i].to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// The checksum is the sum of the bytes of the header, with the checksum field itself treated as spaces.
+fn checksum_valid(header: &[u8]) -> bool {
+    let Some(expected) = parse_octal(&header[148..156]) else {
+        return false;
+    };
+    let sum: usize = header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| if (148..156).contains(&i) { b' ' as usize } else { byte as usize })
+        .sum();
+    sum == expected
+}
+
+/// Numeric fields are stored as ASCII octal, padded with leading zeros or spaces, and terminated by a NUL or
+/// space.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let field = core::str::from_utf8(field).ok()?;
+    let digits = field.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(digits, 8).ok()
+}
+
+/// Entry paths are split between the `name` field and, in `ustar` archives, a `prefix` field, which is joined
+/// onto the front of the name.
+fn entry_path(header: &[u8]) -> Result<String, TarError> {
+    let name = nul_terminated(&header[0..100])?;
+    if &header[257..262] == b"ustar" {
+        let prefix = nul_terminated(&header[345..500])?;
+        if !prefix.is_empty() {
+            return Ok(format!("{}/{}", prefix, name));
+        }
+    }
+    Ok(name.to_string())
+}
+
+fn nul_terminated(field: &[u8]) -> Result<&str, TarError> {
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).map_err(|_| TarError::InvalidPath)
+}
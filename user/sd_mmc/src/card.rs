@@ -0,0 +1,190 @@
+//! The SD protocol, used to bring up a card and transfer blocks to and from it. This is the same for any host
+//! controller in SD mode.
+
+use crate::sunxi::{BusWidth, Command, Data, HostError, ResponseType, Smhc, BLOCK_SIZE};
+use core::time::Duration;
+use log::info;
+
+const GO_IDLE_STATE: u8 = 0;
+const ALL_SEND_CID: u8 = 2;
+const SEND_RELATIVE_ADDR: u8 = 3;
+const SELECT_CARD: u8 = 7;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const READ_MULTIPLE_BLOCK: u8 = 18;
+const WRITE_BLOCK: u8 = 24;
+const WRITE_MULTIPLE_BLOCK: u8 = 25;
+const APP_CMD: u8 = 55;
+
+/// Application-specific commands, which must be preceded by `APP_CMD`.
+const SET_BUS_WIDTH: u8 = 6;
+const SD_SEND_OP_COND: u8 = 41;
+
+/// The argument to `SEND_IF_COND`: we supply 2.7-3.6V, and the card should echo the check pattern back to us.
+const IF_COND_ARGUMENT: u32 = 0x1aa;
+/// The voltage window we support (2.7-3.6V), as encoded in the OCR.
+const OCR_VOLTAGE_WINDOW: u32 = 0x00ff_8000;
+const OCR_HIGH_CAPACITY: u32 = 1 << 30;
+/// Set in the OCR once the card has finished powering up.
+const OCR_READY: u32 = 1 << 31;
+
+/// The card clock to use once the card has been identified. All cards support this (Default Speed).
+const TRANSFER_CLOCK_HZ: u32 = 25_000_000;
+/// How long to wait for the card to power up, in milliseconds.
+const POWER_UP_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CardError {
+    /// There's no card in the slot, or it's not an SD card (e.g. it's an MMC card, which we don't support).
+    NoCard,
+    /// The card doesn't support the voltages we can supply.
+    UnsupportedVoltage,
+    Host(HostError),
+}
+
+impl From<HostError> for CardError {
+    fn from(err: HostError) -> Self {
+        CardError::Host(err)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Card {
+    /// The Relative Card Address, which the card is addressed with once it's been identified.
+    rca: u16,
+    /// High Capacity (SDHC and SDXC) cards are addressed in blocks, rather than bytes.
+    high_capacity: bool,
+    pub num_blocks: u64,
+    pub product_name: String,
+    pub manufacturer_id: u8,
+}
+
+impl Card {
+    /// Identify the card in the slot, and move it into the transfer state so blocks can be read and written.
+    pub async fn init(host: &Smhc) -> Result<Card, CardError> {
+        host.command(Command::new(GO_IDLE_STATE, 0, ResponseType::None)).await?;
+
+        /*
+         * Cards that support version 2.00 of the Physical Layer Specification respond to `SEND_IF_COND`, and can
+         * be High Capacity. Version 1 cards don't respond at all.
+         */
+        let version_2 = match host.command(Command::new(SEND_IF_COND, IF_COND_ARGUMENT, ResponseType::Short)).await
+        {
+            Ok(response) if response.short() & 0xfff == IF_COND_ARGUMENT => true,
+            Ok(_) => return Err(CardError::UnsupportedVoltage),
+            Err(HostError::ResponseTimeout) => false,
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut argument = OCR_VOLTAGE_WINDOW;
+        if version_2 {
+            argument |= OCR_HIGH_CAPACITY;
+        }
+        let mut waited_ms = 0;
+        let ocr = loop {
+            let ocr = match app_command(host, 0, Command::new(SD_SEND_OP_COND, argument, ResponseType::ShortNoCrc))
+                .await
+            {
+                Ok(response) => response,
+                Err(CardError::Host(HostError::ResponseTimeout)) => return Err(CardError::NoCard),
+                Err(err) => return Err(err),
+            };
+            if ocr & OCR_READY != 0 {
+                break ocr;
+            }
+            if waited_ms >= POWER_UP_TIMEOUT_MS {
+                return Err(CardError::UnsupportedVoltage);
+            }
+            std::poplar::rt::time::sleep(Duration::from_millis(10)).await;
+            waited_ms += 10;
+        };
+        let high_capacity = ocr & OCR_HIGH_CAPACITY != 0;
+
+        let cid = host.command(Command::new(ALL_SEND_CID, 0, ResponseType::Long)).await?.long();
+        let rca =
+            (host.command(Command::new(SEND_RELATIVE_ADDR, 0, ResponseType::Short)).await?.short() >> 16) as u16;
+        let csd = host.command(Command::new(SEND_CSD, (rca as u32) << 16, ResponseType::Long)).await?.long();
+        host.command(Command::new(SELECT_CARD, (rca as u32) << 16, ResponseType::ShortBusy)).await?;
+
+        // All SD cards support a 4-bit bus
+        app_command(host, rca, Command::new(SET_BUS_WIDTH, 0b10, ResponseType::Short)).await?;
+        host.set_bus_width(BusWidth::Four);
+
+        if !high_capacity {
+            host.command(Command::new(SET_BLOCKLEN, BLOCK_SIZE as u32, ResponseType::Short)).await?;
+        }
+        host.set_clock(TRANSFER_CLOCK_HZ).await?;
+
+        let card = Card {
+            rca,
+            high_capacity,
+            num_blocks: num_blocks_from_csd(csd),
+            product_name: String::from_utf8_lossy(&bits(cid, 64..104).to_be_bytes()[3..8]).to_string(),
+            manufacturer_id: bits(cid, 120..128) as u8,
+        };
+        info!("SD card has RCA {:#x}. High capacity: {}", card.rca, card.high_capacity);
+        Ok(card)
+    }
+
+    pub async fn read(&self, host: &Smhc, block: u64, buffer: &mut [u8]) -> Result<(), CardError> {
+        let multiple = buffer.len() > BLOCK_SIZE;
+        let index = if multiple { READ_MULTIPLE_BLOCK } else { READ_SINGLE_BLOCK };
+        let command = Command {
+            data: Some(Data::Read(buffer)),
+            auto_stop: multiple,
+            ..Command::new(index, self.address(block), ResponseType::Short)
+        };
+        host.command(command).await?;
+        Ok(())
+    }
+
+    pub async fn write(&self, host: &Smhc, block: u64, data: &[u8]) -> Result<(), CardError> {
+        let multiple = data.len() > BLOCK_SIZE;
+        let index = if multiple { WRITE_MULTIPLE_BLOCK } else { WRITE_BLOCK };
+        let command = Command {
+            data: Some(Data::Write(data)),
+            auto_stop: multiple,
+            ..Command::new(index, self.address(block), ResponseType::Short)
+        };
+        host.command(command).await?;
+        Ok(())
+    }
+
+    /// Get the address to use in data transfer commands for a block.
+    fn address(&self, block: u64) -> u32 {
+        if self.high_capacity {
+            block as u32
+        } else {
+            (block * BLOCK_SIZE as u64) as u32
+        }
+    }
+}
+
+/// Issue an application-specific command. These are preceded by `APP_CMD`, addressed to the card with the given
+/// RCA (or `0` before the card has one).
+async fn app_command(host: &Smhc, rca: u16, command: Command<'_>) -> Result<u32, CardError> {
+    host.command(Command::new(APP_CMD, (rca as u32) << 16, ResponseType::Short)).await?;
+    Ok(host.command(command).await?.short())
+}
+
+fn bits(register: u128, range: core::ops::Range<u32>) -> u64 {
+    ((register >> range.start) & ((1 << (range.end - range.start)) - 1)) as u64
+}
+
+/// Work out the capacity of the card, in 512-byte blocks, from its CSD register. The layout of the register
+/// depends on its version.
+fn num_blocks_from_csd(csd: u128) -> u64 {
+    match bits(csd, 126..128) {
+        0 => {
+            let c_size = bits(csd, 62..74);
+            let c_size_mult = bits(csd, 47..50);
+            let read_bl_len = bits(csd, 80..84);
+            ((c_size + 1) << (c_size_mult + 2) << read_bl_len) / BLOCK_SIZE as u64
+        }
+        // Later versions give the capacity in units of 512KiB. `C_SIZE` is wider in version 3, but the extra bits
+        // are reserved (and zero) in version 2.
+        _ => (bits(csd, 48..76) + 1) * 1024,
+    }
+}
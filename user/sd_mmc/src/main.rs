@@ -0,0 +1,275 @@
+//! `sd_mmc` is a driver for the SD/MMC host controllers found in the SoCs of RISC-V boards, such as the Allwinner
+//! D1 on the MangoPi MQ-Pro. The SD card in the controller's slot is provided to other tasks as a block device.
+//!
+//! Controllers are found in the device tree, through the Platform Bus. We currently only support Allwinner's
+//! controllers (see `sunxi`), and only SD cards (not MMC or eMMC).
+// TODO: support the SD slot on SiFive boards, which is driven over SPI (`sifive,spi0` with an `mmc-spi-slot`)
+
+mod card;
+mod sunxi;
+
+use crate::{
+    card::{Card, CardError},
+    sunxi::{Smhc, BLOCK_SIZE},
+};
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use log::{info, warn};
+use platform_bus::{DeviceDriverClient, DeviceHandoffCall, DeviceInfo, Filter, HandoffInfo, Property};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use std::{
+    poplar::{
+        block::{
+            BlockDeviceInfo,
+            BlockError,
+            BlockRequest,
+            BlockResponse,
+            BLOCK_DEVICE_SERVICE,
+            MAX_TRANSFER_SIZE,
+        },
+        channel::Channel,
+        early_logger::EarlyLogger,
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::MemoryObjectFlags,
+    },
+    sync::Arc,
+};
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// The controllers we can drive, as they appear in the `compatible` property of their device tree nodes.
+const COMPATIBLE: &[&str] = &[
+    "allwinner,sun4i-a10-mmc",
+    "allwinner,sun5i-a13-mmc",
+    "allwinner,sun7i-a20-mmc",
+    "allwinner,sun8i-a83t-emmc",
+    "allwinner,sun9i-a80-mmc",
+    "allwinner,sun50i-a64-mmc",
+    "allwinner,sun50i-a100-mmc",
+    "allwinner,sun20i-d1-mmc",
+];
+
+/// The number of cards we've provided as block devices, across every controller we drive. Used to give each its
+/// own service name.
+static NUM_BLOCK_DEVICES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn map_memory_object(memory_object: MemoryObject) -> MappedMemoryObject {
+    unsafe { memory_object.map().unwrap() }
+}
+
+/// A controller with a card in its slot. The controller can only issue one command at a time, so clients take
+/// turns to use it.
+struct Slot {
+    host: Smhc,
+    card: Card,
+    busy: AtomicBool,
+}
+
+impl Slot {
+    async fn acquire(&self) -> SlotGuard<'_> {
+        while self.busy.swap(true, Ordering::Acquire) {
+            std::poplar::rt::time::sleep(Duration::from_millis(1)).await;
+        }
+        SlotGuard(self)
+    }
+}
+
+struct SlotGuard<'a>(&'a Slot);
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        self.0.busy.store(false, Ordering::Release);
+    }
+}
+
+async fn drive_controller(
+    device_info: DeviceInfo,
+    handoff_info: HandoffInfo,
+    service_host_client: Arc<ServiceHostClient>,
+) {
+    let Some(compatible) = COMPATIBLE
+        .iter()
+        .find(|compatible| device_info.get_as_bool(&format!("dt.compatible.{}", compatible)).is_some())
+    else {
+        warn!("SD/MMC controller is not compatible with any controller we support");
+        return;
+    };
+    let Some(interrupt) = handoff_info.get_as_event("dt.interrupt") else {
+        warn!("SD/MMC controller does not have an interrupt");
+        return;
+    };
+    let base = {
+        let offset = handoff_info.get_as_integer("dt.reg0.offset").unwrap() as usize;
+        let registers = MemoryObject {
+            handle: handoff_info.get_as_memory_object("dt.reg0.handle").unwrap(),
+            size: offset + handoff_info.get_as_integer("dt.reg0.size").unwrap() as usize,
+            flags: MemoryObjectFlags::WRITABLE,
+            phys_address: None,
+        };
+        // The registers are accessed through `Smhc`, so we don't need to keep the mapping around
+        map_memory_object(registers).mapped_at + offset
+    };
+
+    let host = Smhc::new(base, interrupt, compatible);
+    if let Err(err) = host.init().await {
+        warn!("Failed to initialize SD/MMC controller: {:?}", err);
+        return;
+    }
+
+    let card = match Card::init(&host).await {
+        Ok(card) => card,
+        Err(CardError::NoCard) => {
+            info!("No SD card found in slot");
+            return;
+        }
+        Err(err) => {
+            warn!("Failed to initialize SD card: {:?}", err);
+            return;
+        }
+    };
+    info!(
+        "SD card: {} (manufacturer {:#x}), {} blocks of {} bytes",
+        card.product_name, card.manufacturer_id, card.num_blocks, BLOCK_SIZE
+    );
+
+    serve_card(Arc::new(Slot { host, card, busy: AtomicBool::new(false) }), &service_host_client);
+}
+
+/// A client's view of a card. Requests from every client of a card are made through the same slot.
+struct CardClient {
+    slot: Arc<Slot>,
+}
+
+impl CardClient {
+    async fn handle_request(&self, request: BlockRequest) -> BlockResponse {
+        let result = match request {
+            BlockRequest::GetInfo => Ok(BlockResponse::Info(BlockDeviceInfo {
+                block_size: BLOCK_SIZE as u32,
+                num_blocks: self.slot.card.num_blocks,
+                read_only: false,
+            })),
+            BlockRequest::Read { block, count } => self.read(block, count).await.map(BlockResponse::Data),
+            BlockRequest::Write { block, data } => self.write(block, &data).await.map(|()| BlockResponse::Done),
+            // Cards don't have a volatile write cache unless we enable one, so there's nothing to flush
+            BlockRequest::Flush => Ok(BlockResponse::Done),
+        };
+        result.unwrap_or_else(BlockResponse::Error)
+    }
+
+    async fn read(&self, block: u64, count: u32) -> Result<Vec<u8>, BlockError> {
+        let length = self.check_range(block, count as usize * BLOCK_SIZE)?;
+        let mut buffer = vec![0; length];
+        if length == 0 {
+            return Ok(buffer);
+        }
+
+        let _guard = self.slot.acquire().await;
+        self.slot.card.read(&self.slot.host, block, &mut buffer).await.map_err(|err| {
+            warn!("SD card read from block {} failed: {:?}", block, err);
+            BlockError::DeviceError
+        })?;
+        Ok(buffer)
+    }
+
+    async fn write(&self, block: u64, data: &[u8]) -> Result<(), BlockError> {
+        if data.len() % BLOCK_SIZE != 0 {
+            return Err(BlockError::InvalidLength);
+        }
+        if self.check_range(block, data.len())? == 0 {
+            return Ok(());
+        }
+
+        let _guard = self.slot.acquire().await;
+        self.slot.card.write(&self.slot.host, block, data).await.map_err(|err| {
+            warn!("SD card write to block {} failed: {:?}", block, err);
+            BlockError::DeviceError
+        })
+    }
+
+    /// Check that a transfer of `length` bytes, starting at `block`, can be made. Returns the length.
+    fn check_range(&self, block: u64, length: usize) -> Result<usize, BlockError> {
+        if length > MAX_TRANSFER_SIZE {
+            return Err(BlockError::TooLarge);
+        }
+        let end = block.checked_add((length / BLOCK_SIZE) as u64).ok_or(BlockError::OutOfRange)?;
+        if end > self.slot.card.num_blocks {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(length)
+    }
+}
+
+/// Provide a card to other tasks as a block device. The first is provided as `block.device`, and any more as
+/// `block.device.1`, `block.device.2`, etc.
+fn serve_card(slot: Arc<Slot>, service_host_client: &ServiceHostClient) {
+    let service_name = match NUM_BLOCK_DEVICES.fetch_add(1, Ordering::Relaxed) {
+        0 => BLOCK_DEVICE_SERVICE.to_string(),
+        n => format!("{}.{}", BLOCK_DEVICE_SERVICE, n),
+    };
+    info!("Providing SD card as '{}'", service_name);
+
+    let service_channel = service_host_client.register_service(service_name).unwrap();
+    std::poplar::rt::spawn(async move {
+        loop {
+            match service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    info!("Task '{}' is using SD card", name);
+                    let channel: Channel<BlockResponse, BlockRequest> = Channel::new_from_handle(channel);
+
+                    let client = CardClient { slot: slot.clone() };
+                    std::poplar::rt::spawn(async move {
+                        loop {
+                            let request = channel.receive().await.unwrap();
+                            let response = client.handle_request(request).await;
+                            channel.send(&response).unwrap();
+                        }
+                    });
+                }
+            }
+        }
+    });
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("SD/MMC driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = Arc::new(ServiceHostClient::new());
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    let filters = COMPATIBLE
+        .iter()
+        .map(|compatible| Filter::Matches(format!("dt.compatible.{}", compatible), Property::Bool(true)))
+        .collect();
+    let handoff_server = platform_bus_device_client.register(vec![Filter::Any(filters)]).unwrap();
+
+    std::poplar::rt::spawn(async move {
+        loop {
+            match handoff_server.next().await.unwrap() {
+                DeviceHandoffCall::QuerySupport { responder, .. } => {
+                    responder.reply(true).unwrap();
+                }
+                DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info } => {
+                    info!("Started driving SD/MMC controller: {}", name);
+                    std::poplar::rt::spawn(drive_controller(
+                        device_info,
+                        handoff_info,
+                        service_host_client.clone(),
+                    ));
+                }
+                DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                    // Writes are made directly to the card, so there's nothing to flush
+                    responder.reply(()).unwrap();
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
@@ -0,0 +1,462 @@
+//! Driver for the SD/MMC host controllers (SMHC) found in Allwinner SoCs, such as the D1. The controller is
+//! derived from the Synopsys DesignWare controller, but its registers are laid out differently.
+//!
+//! We transfer data through the controller's FIFO (PIO), rather than with its internal DMA controller. This
+//! assumes the firmware has enabled the controller's bus and module clocks, and de-asserted its reset.
+
+use crate::PAGE_SIZE;
+use bitflags::bitflags;
+use core::time::Duration;
+use std::poplar::event::Event;
+
+/// The rate the firmware has left the controller's module clock at. The card clock is derived from it.
+// TODO: set this up ourselves through the CCU, so we can run cards faster
+pub const MODULE_CLOCK_HZ: u32 = 24_000_000;
+
+/// How long to wait for the controller to reset, or to accept a command, in milliseconds.
+const HOST_TIMEOUT_MS: u64 = 500;
+/// How long to wait for the card to finish programming after a write, in milliseconds.
+const BUSY_TIMEOUT_MS: u64 = 1000;
+
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResponseType {
+    None,
+    /// A 48-bit response with a CRC (R1, R6, and R7).
+    Short,
+    /// A 48-bit response, after which the card may signal that it's busy on the data line (R1b).
+    ShortBusy,
+    /// A 48-bit response without a valid CRC (R3).
+    ShortNoCrc,
+    /// A 136-bit response (R2), used to read the CID and CSD registers.
+    Long,
+}
+
+pub enum Data<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+pub struct Command<'a> {
+    pub index: u8,
+    pub argument: u32,
+    pub response: ResponseType,
+    /// Data transferred by the command. The length must be a multiple of `BLOCK_SIZE`.
+    pub data: Option<Data<'a>>,
+    /// Make the controller send `STOP_TRANSMISSION` once the data has been transferred, for multiple-block
+    /// transfers.
+    pub auto_stop: bool,
+}
+
+impl<'a> Command<'a> {
+    pub fn new(index: u8, argument: u32, response: ResponseType) -> Command<'a> {
+        Command { index, argument, response, data: None, auto_stop: false }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Response {
+    None,
+    Short(u32),
+    Long(u128),
+}
+
+impl Response {
+    pub fn short(self) -> u32 {
+        match self {
+            Response::Short(response) => response,
+            _ => panic!("Expected a short response, got {:?}", self),
+        }
+    }
+
+    pub fn long(self) -> u128 {
+        match self {
+            Response::Long(response) => response,
+            _ => panic!("Expected a long response, got {:?}", self),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HostError {
+    /// The card did not respond to the command. This is expected for some commands (e.g. `SEND_IF_COND` to
+    /// version 1 cards).
+    ResponseTimeout,
+    /// The controller reported an error, with the given raw interrupt status.
+    Failed(Interrupts),
+    /// The controller did not accept the command, or the card stayed busy for too long.
+    TimedOut,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BusWidth {
+    One,
+    Four,
+}
+
+pub struct Smhc {
+    base: usize,
+    fifo_offset: usize,
+    interrupt: Event,
+}
+
+impl Smhc {
+    /// Create a driver for a controller with its registers mapped at `base`. `compatible` is the entry of the
+    /// controller's `compatible` property we matched against, as the FIFO moved in later SoCs.
+    pub fn new(base: usize, interrupt: Event, compatible: &str) -> Smhc {
+        let fifo_offset = if ["allwinner,sun4i-a10-mmc", "allwinner,sun5i-a13-mmc", "allwinner,sun7i-a20-mmc"]
+            .contains(&compatible)
+        {
+            0x100
+        } else {
+            0x200
+        };
+        assert!(fifo_offset + 4 <= PAGE_SIZE);
+        Smhc { base, fifo_offset, interrupt }
+    }
+
+    /// Reset the controller, and prepare it to issue commands with the card clock running at a low speed, suitable
+    /// for identifying the card.
+    pub async fn init(&self) -> Result<(), HostError> {
+        unsafe {
+            self.write(Register::GlobalControl, GlobalControl::RESETS.bits());
+        }
+        wait_for(HOST_TIMEOUT_MS, || {
+            !GlobalControl::from_bits_retain(unsafe { self.read(Register::GlobalControl) })
+                .intersects(GlobalControl::RESETS)
+        })
+        .await?;
+
+        unsafe {
+            self.write(Register::Timeout, 0xffff_ff40);
+            self.write(Register::RawInterruptStatus, u32::MAX);
+            self.write(Register::InterruptMask, (Interrupts::DONE | Interrupts::ERRORS).bits());
+            // The FIFO is accessed by the CPU, rather than by the internal DMA controller
+            self.write(
+                Register::GlobalControl,
+                (GlobalControl::INTERRUPT_ENABLE | GlobalControl::FIFO_ACCESS_BY_AHB).bits(),
+            );
+        }
+
+        self.set_bus_width(BusWidth::One);
+        self.set_clock(400_000).await
+    }
+
+    /// Run the card clock at (no more than) `frequency` Hz.
+    pub async fn set_clock(&self, frequency: u32) -> Result<(), HostError> {
+        // The card clock is the module clock divided by `2 * divider`, or the module clock if `divider` is `0`
+        let divider = if frequency >= MODULE_CLOCK_HZ { 0 } else { MODULE_CLOCK_HZ.div_ceil(2 * frequency) };
+        assert!(divider <= 0xff);
+
+        unsafe {
+            self.write(Register::ClockControl, 0);
+        }
+        self.update_clock().await?;
+        unsafe {
+            self.write(Register::ClockControl, divider);
+        }
+        self.update_clock().await?;
+        unsafe {
+            self.write(Register::ClockControl, CLOCK_ENABLE | divider);
+        }
+        self.update_clock().await
+    }
+
+    pub fn set_bus_width(&self, width: BusWidth) {
+        let width = match width {
+            BusWidth::One => 0,
+            BusWidth::Four => 1,
+        };
+        unsafe {
+            self.write(Register::BusWidth, width);
+        }
+    }
+
+    /// Issue a command to the card, transferring its data through the FIFO, and wait for it to complete.
+    pub async fn command(&self, command: Command<'_>) -> Result<Response, HostError> {
+        let mut flags = CommandFlags::START | CommandFlags::WAIT_PREVIOUS_OVER;
+        if command.index == 0 {
+            flags |= CommandFlags::SEND_INIT_SEQUENCE;
+        }
+        match command.response {
+            ResponseType::None => (),
+            ResponseType::Short | ResponseType::ShortBusy => {
+                flags |= CommandFlags::RESPONSE_EXPECTED | CommandFlags::CHECK_RESPONSE_CRC
+            }
+            ResponseType::ShortNoCrc => flags |= CommandFlags::RESPONSE_EXPECTED,
+            ResponseType::Long => {
+                flags |= CommandFlags::RESPONSE_EXPECTED
+                    | CommandFlags::LONG_RESPONSE
+                    | CommandFlags::CHECK_RESPONSE_CRC
+            }
+        }
+
+        let mut wait_for_interrupts = Interrupts::COMMAND_DONE;
+        if let Some(ref data) = command.data {
+            let length = match data {
+                Data::Read(buffer) => buffer.len(),
+                Data::Write(buffer) => buffer.len(),
+            };
+            assert!(length % BLOCK_SIZE == 0);
+
+            flags |= CommandFlags::DATA_EXPECTED;
+            if let Data::Write(_) = data {
+                flags |= CommandFlags::WRITE;
+            }
+            wait_for_interrupts |= Interrupts::DATA_OVER;
+            if command.auto_stop {
+                flags |= CommandFlags::SEND_AUTO_STOP;
+                wait_for_interrupts |= Interrupts::AUTO_COMMAND_DONE;
+            }
+
+            self.reset_fifo().await?;
+            unsafe {
+                self.write(Register::BlockSize, BLOCK_SIZE as u32);
+                self.write(Register::ByteCount, length as u32);
+            }
+        }
+
+        unsafe {
+            self.write(Register::RawInterruptStatus, u32::MAX);
+            self.write(Register::Argument, command.argument);
+            self.write(Register::Command, flags.bits() | command.index as u32);
+        }
+
+        let result = match command.data {
+            Some(Data::Read(buffer)) => self.read_fifo(buffer),
+            Some(Data::Write(buffer)) => self.write_fifo(buffer),
+            None => Ok(()),
+        };
+        let status = self.wait_for_interrupts(wait_for_interrupts).await;
+        result?;
+        status?;
+
+        let response = match command.response {
+            ResponseType::None => Response::None,
+            ResponseType::Long => Response::Long(unsafe {
+                (self.read(Register::Response3) as u128) << 96
+                    | (self.read(Register::Response2) as u128) << 64
+                    | (self.read(Register::Response1) as u128) << 32
+                    | self.read(Register::Response0) as u128
+            }),
+            _ => Response::Short(unsafe { self.read(Register::Response0) }),
+        };
+
+        /*
+         * Cards signal that they're busy (e.g. while programming data they've been sent) by holding the data line
+         * low, and can't accept another data transfer until they're done.
+         */
+        if command.response == ResponseType::ShortBusy || flags.contains(CommandFlags::WRITE) {
+            wait_for(BUSY_TIMEOUT_MS, || {
+                !Status::from_bits_retain(unsafe { self.read(Register::Status) }).contains(Status::CARD_DATA_BUSY)
+            })
+            .await?;
+        }
+
+        Ok(response)
+    }
+
+    /// Wait until all of `interrupts` have been raised, or an error occurs. The interrupts are cleared.
+    async fn wait_for_interrupts(&self, interrupts: Interrupts) -> Result<(), HostError> {
+        loop {
+            let status = Interrupts::from_bits_retain(unsafe { self.read(Register::RawInterruptStatus) });
+            if status.intersects(Interrupts::ERRORS) || status.contains(interrupts) {
+                unsafe {
+                    self.write(Register::RawInterruptStatus, status.bits());
+                }
+                // Acknowledging the interrupt unmasks it, so this has to be done after clearing its cause
+                self.interrupt.ack_interrupt();
+
+                return if status.contains(Interrupts::RESPONSE_TIMEOUT) {
+                    Err(HostError::ResponseTimeout)
+                } else if status.intersects(Interrupts::ERRORS) {
+                    Err(HostError::Failed(status))
+                } else {
+                    Ok(())
+                };
+            }
+
+            self.interrupt.wait_for_event().await;
+        }
+    }
+
+    fn read_fifo(&self, buffer: &mut [u8]) -> Result<(), HostError> {
+        for word in buffer.chunks_exact_mut(4) {
+            while Status::from_bits_retain(unsafe { self.read(Register::Status) }).contains(Status::FIFO_EMPTY) {
+                self.check_for_errors()?;
+                core::hint::spin_loop();
+            }
+            let value = unsafe { std::ptr::read_volatile((self.base + self.fifo_offset) as *const u32) };
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn write_fifo(&self, buffer: &[u8]) -> Result<(), HostError> {
+        for word in buffer.chunks_exact(4) {
+            while Status::from_bits_retain(unsafe { self.read(Register::Status) }).contains(Status::FIFO_FULL) {
+                self.check_for_errors()?;
+                core::hint::spin_loop();
+            }
+            let value = u32::from_le_bytes(word.try_into().unwrap());
+            unsafe {
+                std::ptr::write_volatile((self.base + self.fifo_offset) as *mut u32, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check for errors while we're moving data through the FIFO. The error interrupts are left for
+    /// `wait_for_interrupts` to clear.
+    fn check_for_errors(&self) -> Result<(), HostError> {
+        let status = Interrupts::from_bits_retain(unsafe { self.read(Register::RawInterruptStatus) });
+        if status.intersects(Interrupts::ERRORS) {
+            Err(HostError::Failed(status))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn reset_fifo(&self) -> Result<(), HostError> {
+        unsafe {
+            self.write(
+                Register::GlobalControl,
+                self.read(Register::GlobalControl) | GlobalControl::FIFO_RESET.bits(),
+            );
+        }
+        wait_for(HOST_TIMEOUT_MS, || {
+            !GlobalControl::from_bits_retain(unsafe { self.read(Register::GlobalControl) })
+                .contains(GlobalControl::FIFO_RESET)
+        })
+        .await
+    }
+
+    /// Make the controller load a new configuration of the card clock. This is done with a special command that
+    /// isn't sent to the card.
+    async fn update_clock(&self) -> Result<(), HostError> {
+        let flags = CommandFlags::START | CommandFlags::UPDATE_CLOCK_ONLY | CommandFlags::WAIT_PREVIOUS_OVER;
+        unsafe {
+            self.write(Register::Command, flags.bits());
+        }
+        wait_for(HOST_TIMEOUT_MS, || {
+            !CommandFlags::from_bits_retain(unsafe { self.read(Register::Command) }).contains(CommandFlags::START)
+        })
+        .await
+    }
+
+    unsafe fn read(&self, reg: Register) -> u32 {
+        unsafe { std::ptr::read_volatile((self.base + reg as usize) as *const u32) }
+    }
+
+    unsafe fn write(&self, reg: Register, value: u32) {
+        unsafe {
+            std::ptr::write_volatile((self.base + reg as usize) as *mut u32, value);
+        }
+    }
+}
+
+async fn wait_for(timeout_ms: u64, condition: impl Fn() -> bool) -> Result<(), HostError> {
+    let mut waited_ms = 0;
+    while !condition() {
+        if waited_ms >= timeout_ms {
+            return Err(HostError::TimedOut);
+        }
+        std::poplar::rt::time::sleep(Duration::from_millis(1)).await;
+        waited_ms += 1;
+    }
+    Ok(())
+}
+
+#[repr(usize)]
+#[derive(Clone, Copy)]
+enum Register {
+    GlobalControl = 0x00,
+    ClockControl = 0x04,
+    Timeout = 0x08,
+    BusWidth = 0x0c,
+    BlockSize = 0x10,
+    ByteCount = 0x14,
+    Command = 0x18,
+    Argument = 0x1c,
+    Response0 = 0x20,
+    Response1 = 0x24,
+    Response2 = 0x28,
+    Response3 = 0x2c,
+    InterruptMask = 0x30,
+    RawInterruptStatus = 0x38,
+    Status = 0x3c,
+}
+
+const CLOCK_ENABLE: u32 = 1 << 16;
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    struct GlobalControl: u32 {
+        const SOFT_RESET = 1 << 0;
+        const FIFO_RESET = 1 << 1;
+        const DMA_RESET = 1 << 2;
+        const INTERRUPT_ENABLE = 1 << 4;
+        const FIFO_ACCESS_BY_AHB = 1 << 31;
+
+        const RESETS = Self::SOFT_RESET.bits() | Self::FIFO_RESET.bits() | Self::DMA_RESET.bits();
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    struct CommandFlags: u32 {
+        const RESPONSE_EXPECTED = 1 << 6;
+        const LONG_RESPONSE = 1 << 7;
+        const CHECK_RESPONSE_CRC = 1 << 8;
+        const DATA_EXPECTED = 1 << 9;
+        const WRITE = 1 << 10;
+        const SEND_AUTO_STOP = 1 << 12;
+        const WAIT_PREVIOUS_OVER = 1 << 13;
+        const SEND_INIT_SEQUENCE = 1 << 15;
+        const UPDATE_CLOCK_ONLY = 1 << 21;
+        const START = 1 << 31;
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Interrupts: u32 {
+        const RESPONSE_ERROR = 1 << 1;
+        const COMMAND_DONE = 1 << 2;
+        const DATA_OVER = 1 << 3;
+        const TX_DATA_REQUEST = 1 << 4;
+        const RX_DATA_REQUEST = 1 << 5;
+        const RESPONSE_CRC_ERROR = 1 << 6;
+        const DATA_CRC_ERROR = 1 << 7;
+        const RESPONSE_TIMEOUT = 1 << 8;
+        const DATA_TIMEOUT = 1 << 9;
+        const DATA_STARVATION_TIMEOUT = 1 << 10;
+        const FIFO_UNDERRUN_OVERFLOW = 1 << 11;
+        const COMMAND_BUSY = 1 << 12;
+        const DATA_START_ERROR = 1 << 13;
+        const AUTO_COMMAND_DONE = 1 << 14;
+        const DATA_END_BIT_ERROR = 1 << 15;
+
+        const DONE = Self::COMMAND_DONE.bits() | Self::DATA_OVER.bits() | Self::AUTO_COMMAND_DONE.bits();
+        const ERRORS = Self::RESPONSE_ERROR.bits()
+            | Self::RESPONSE_CRC_ERROR.bits()
+            | Self::DATA_CRC_ERROR.bits()
+            | Self::RESPONSE_TIMEOUT.bits()
+            | Self::DATA_TIMEOUT.bits()
+            | Self::DATA_STARVATION_TIMEOUT.bits()
+            | Self::FIFO_UNDERRUN_OVERFLOW.bits()
+            | Self::COMMAND_BUSY.bits()
+            | Self::DATA_START_ERROR.bits()
+            | Self::DATA_END_BIT_ERROR.bits();
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    struct Status: u32 {
+        const FIFO_EMPTY = 1 << 2;
+        const FIFO_FULL = 1 << 3;
+        const CARD_DATA_BUSY = 1 << 9;
+    }
+}
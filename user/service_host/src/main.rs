@@ -21,18 +21,98 @@
  */
 
 use log::{info, warn};
-use service_host::{ServiceChannelMessage, ServiceHostRequest, ServiceHostResponse};
+use service_host::{
+    service_pattern_matches,
+    ServiceChannelMessage,
+    ServiceHostRequest,
+    ServiceHostResponse,
+    TaskPermissions,
+    NAMESPACE_RESOURCE,
+};
 use std::{
     collections::btree_map::BTreeMap,
-    poplar::{channel::Channel, early_logger::EarlyLogger, manifest::BootstrapManifest, Handle},
+    poplar::{
+        caps::Capabilities,
+        channel::Channel,
+        early_logger::EarlyLogger,
+        file::VFS_SERVICE,
+        manifest::{BootstrapManifest, MANIFEST_ADDRESS},
+        syscall::{self, handle_duplicate_with_rights, Signals, Stdio, WaitItem},
+        Handle,
+        HandleRights,
+    },
 };
 
+/// The name tasks use to request the initramfs with `RequestResource`.
+const INITRAMFS_RESOURCE: &str = "initramfs";
+
+/// The services each boot task can subscribe to, as patterns (see `service_pattern_matches`). Boot tasks are
+/// matched by name, which can also be a pattern. Boot tasks that aren't listed here can't subscribe to any
+/// services. Tasks spawned later are given their services by the task that spawns them.
+const BOOT_TASK_SERVICES: &[(&str, &[&str])] = &[
+    ("ramfs", &["vfs.filesystem"]),
+    ("fat_fs", &["block.device", "vfs.filesystem"]),
+    ("ext2_fs", &["block.device", "vfs.filesystem"]),
+    ("usb_bus_ehci", &["platform_bus.bus_driver", "platform_bus.device_driver"]),
+    ("usb_hid", &["platform_bus.bus_driver", "platform_bus.device_driver"]),
+    ("usb_mass_storage", &["platform_bus.device_driver"]),
+    ("nvme", &["platform_bus.device_driver"]),
+    ("ahci", &["platform_bus.device_driver"]),
+    ("sd_mmc", &["platform_bus.device_driver"]),
+    ("e1000", &["platform_bus.bus_driver", "platform_bus.device_driver"]),
+    ("virtio_gpu", &["platform_bus.bus_driver", "platform_bus.device_driver"]),
+    ("virtio_net", &["platform_bus.bus_driver", "platform_bus.device_driver"]),
+    ("virtio_input", &["platform_bus.bus_driver", "platform_bus.device_driver"]),
+    ("virtio_blk", &["platform_bus.bus_driver", "platform_bus.device_driver"]),
+    ("netstack", &["platform_bus.device_driver"]),
+    ("compositor", &["platform_bus.device_driver"]),
+    ("fb_console", &["platform_bus.*", "vfs", "compositor"]),
+    ("shell", &["console", "vfs", "log", "platform_bus.power", "net.socket"]),
+    ("klog", &["console"]),
+    ("service_manager", &["*"]),
+    // Test tasks (see `kernel::test_runner`) exercise the protocols of other services
+    ("test_*", &["*"]),
+];
+
 pub struct Task {
     name: String,
-    address_space: Handle,
-    segments: Vec<(Handle, usize)>,
     task: Handle,
+    /// Each task spawned from an image is created in its own job, so it can be torn down along with any tasks it
+    /// creates. Boot tasks are created in our job, as `spawn_task` can't create them in another.
+    job: Option<Handle>,
     task_channel: Channel<ServiceHostResponse, ServiceHostRequest>,
+    /// The channel to the VFS the task was spawned with, which sees its namespace, until it asks for it.
+    namespace: Option<Handle>,
+    /// Patterns matching the services the task can subscribe to.
+    services: Vec<String>,
+    capabilities: Capabilities,
+    /// Services the task has subscribed to that haven't been registered yet. The task is connected to each one
+    /// once it is.
+    pending_subscriptions: Vec<String>,
+}
+
+impl Task {
+    fn can_subscribe(&self, service: &str) -> bool {
+        // Spawned tasks are given a filesystem namespace instead, so they can only see the files they're meant to
+        if service == VFS_SERVICE && self.job.is_some() {
+            return false;
+        }
+        self.services.iter().any(|pattern| service_pattern_matches(pattern, service))
+    }
+
+    /// Check that this task holds all of `permissions`, so it can give them to a task it spawns. Patterns can be
+    /// checked against each other in the same way as service names.
+    fn check_can_grant(&self, permissions: &TaskPermissions) -> Result<(), String> {
+        let capabilities = Capabilities::from_bits(permissions.capabilities)
+            .ok_or_else(|| format!("invalid capabilities {:#x}", permissions.capabilities))?;
+        if !self.capabilities.contains(capabilities) {
+            return Err(format!("it doesn't have capabilities {:?}", capabilities - self.capabilities));
+        }
+        if let Some(service) = permissions.services.iter().find(|service| !self.can_subscribe(service)) {
+            return Err(format!("it can't subscribe to services matching '{}'", service));
+        }
+        Ok(())
+    }
 }
 
 fn main() {
@@ -41,7 +121,6 @@ fn main() {
     info!("ServiceHost is running!");
 
     let manifest: BootstrapManifest = {
-        const MANIFEST_ADDRESS: usize = 0x20000000;
         let manifest_len = unsafe { core::ptr::read(MANIFEST_ADDRESS as *const u32) };
         let data =
             unsafe { core::slice::from_raw_parts((MANIFEST_ADDRESS + 4) as *const u8, manifest_len as usize) };
@@ -54,7 +133,6 @@ fn main() {
     for task in &manifest.boot_tasks {
         info!("Spawning task '{}'", task.name);
         let address_space = std::poplar::syscall::create_address_space().unwrap();
-        let mut segments = Vec::new();
         for (map_at, memory_object) in &task.segments {
             let memory_object = Handle(*memory_object);
             unsafe {
@@ -63,10 +141,10 @@ fn main() {
                     address_space,
                     Some(*map_at),
                     0x0 as *mut _,
+                    std::poplar::syscall::MapMemoryObjectFlags::empty(),
                 )
                 .unwrap();
             }
-            segments.push((memory_object, *map_at));
         }
 
         // Create a channel to communicate with the task through
@@ -75,14 +153,29 @@ fn main() {
         let spawned_task =
             std::poplar::syscall::spawn_task(&task.name, address_space, task.entry_point, &[channel_handle])
                 .unwrap();
-        tasks.push(Task { name: task.name.clone(), address_space, segments, task: spawned_task, task_channel });
+        let services = BOOT_TASK_SERVICES
+            .iter()
+            .find(|(name, _)| service_pattern_matches(name, &task.name))
+            .map_or(Vec::new(), |(_, services)| services.iter().map(|service| service.to_string()).collect());
+        tasks.push(Task {
+            name: task.name.clone(),
+            task: spawned_task,
+            job: None,
+            task_channel,
+            namespace: None,
+            services,
+            // Boot tasks are created with our capabilities by `spawn_task`
+            capabilities: Capabilities::all(),
+            pending_subscriptions: Vec::new(),
+        });
     }
 
     // Monitor each task's channel for requests
     // TODO: this should probs be async in the future
     loop {
         std::poplar::syscall::yield_to_kernel();
-        for task in &tasks {
+        let mut spawned_tasks = Vec::new();
+        for task in &mut tasks {
             if let Some(request) = task.task_channel.try_receive().unwrap() {
                 match request {
                     ServiceHostRequest::RegisterService { name } => {
@@ -94,30 +187,175 @@ fn main() {
                     }
                     ServiceHostRequest::SubscribeService(name) => {
                         info!("Task '{}' subscribing to service called '{}'", task.name, name);
-                        if let Some(ref service_channel) = services.get(&name) {
-                            let (channel_a, channel_b) = std::poplar::syscall::create_channel().unwrap();
-                            service_channel
-                                .send(&ServiceChannelMessage::NewClient {
-                                    name: task.name.clone(),
-                                    channel: channel_a,
-                                })
-                                .unwrap();
-                            task.task_channel.send(&ServiceHostResponse::SubscribedToService(channel_b)).unwrap();
+                        if !task.can_subscribe(&name) {
+                            warn!("Task '{}' is not allowed to subscribe to service '{}'", task.name, name);
+                            task.task_channel.send(&ServiceHostResponse::ServiceRefused).unwrap();
+                        } else if let Some(service_channel) = services.get(&name) {
+                            connect_to_service(task, service_channel);
                         } else {
                             /*
-                             * Now there's more to service registration, we probs need to actually
-                             * handle this... I wonder if we should keep a list of 'waiting' tasks
-                             * that want access to a service, and check it when a new service is
-                             * registered. We defo can't just ignore it (but this should be
-                             * customizable behaviour. Some clients might just want to check if a
-                             * service is available, but not block on it becoming available).
+                             * The service might not have been registered yet, as the task providing it is started
+                             * at the same time as its clients. We connect the task to it once it is.
                              */
-                            warn!("Tried to subscribe to service but it has not been registered!");
+                            info!("Service '{}' has not been registered yet. Waiting for it to be.", name);
+                            task.pending_subscriptions.push(name);
+                        }
+                    }
+                    ServiceHostRequest::RequestResource(name) => {
+                        info!("Task '{}' requesting resource '{}'", task.name, name);
+                        let response = match (name.as_str(), manifest.initramfs) {
+                            (NAMESPACE_RESOURCE, _) => match task.namespace.take() {
+                                Some(namespace) => ServiceHostResponse::Resource(namespace),
+                                None => {
+                                    warn!("Task '{}' does not have a filesystem namespace", task.name);
+                                    ServiceHostResponse::ResourceRefused
+                                }
+                            },
+                            (INITRAMFS_RESOURCE, Some((handle, size))) => {
+                                /*
+                                 * Each task gets its own handle to the initramfs, which can only be used to map
+                                 * it (read-only) into the task's address space.
+                                 */
+                                let rights = HandleRights::READ | HandleRights::MAP | HandleRights::TRANSFER;
+                                match handle_duplicate_with_rights(Handle(handle), rights) {
+                                    Ok(handle) => ServiceHostResponse::MemoryResource { handle, size },
+                                    Err(err) => {
+                                        warn!("Failed to duplicate handle to initramfs: {:?}", err);
+                                        ServiceHostResponse::ResourceRefused
+                                    }
+                                }
+                            }
+                            _ => {
+                                warn!("Task '{}' requested unknown resource '{}'", task.name, name);
+                                ServiceHostResponse::ResourceRefused
+                            }
+                        };
+                        task.task_channel.send(&response).unwrap();
+                    }
+                    ServiceHostRequest::SpawnTask { name, image, image_size, arguments, stdio, permissions } => {
+                        info!("Task '{}' spawning new task '{}'", task.name, name);
+                        let spawned = match task.check_can_grant(&permissions) {
+                            Ok(()) => spawn_task(name, image, image_size, &arguments, stdio, permissions),
+                            Err(reason) => {
+                                warn!("Task '{}' can't spawn task '{}': {}", task.name, name, reason);
+                                Err(())
+                            }
+                        };
+                        match spawned {
+                            Ok(spawned) => {
+                                let task_handle = handle_duplicate_with_rights(
+                                    spawned.task,
+                                    HandleRights::READ | HandleRights::WRITE | HandleRights::TRANSFER,
+                                )
+                                .unwrap();
+                                spawned_tasks.push(spawned);
+                                task.task_channel.send(&ServiceHostResponse::TaskSpawned(task_handle)).unwrap();
+                            }
+                            Err(()) => task.task_channel.send(&ServiceHostResponse::SpawnTaskFailed).unwrap(),
                         }
                     }
-                    ServiceHostRequest::RequestResource(name) => todo!(),
                 }
             }
         }
+        tasks.extend(spawned_tasks);
+
+        for task in &mut tasks {
+            let pending = core::mem::take(&mut task.pending_subscriptions);
+            for name in pending {
+                match services.get(&name) {
+                    Some(service_channel) => connect_to_service(task, service_channel),
+                    None => task.pending_subscriptions.push(name),
+                }
+            }
+        }
+
+        /*
+         * Stop monitoring tasks that have exited. Any tasks they created are killed along with their job, so
+         * they don't outlive the task that was responsible for them.
+         */
+        tasks.retain(|task| {
+            let mut items = [WaitItem::new(task.task, Signals::TERMINATED)];
+            let exited = syscall::object_wait_many(&mut items, false, None).is_ok();
+            if exited {
+                info!("Task '{}' has exited", task.name);
+                if let Some(job) = task.job {
+                    let _ = syscall::job_kill(job);
+                }
+            }
+            !exited
+        });
     }
 }
+
+fn spawn_task(
+    name: String,
+    image: Handle,
+    image_size: usize,
+    arguments: &[String],
+    stdio: Stdio,
+    permissions: TaskPermissions,
+) -> Result<Task, ()> {
+    // Each argument is terminated by a NUL, which is how the kernel expects them to be passed
+    let arguments: String = arguments.iter().flat_map(|argument| [argument.as_str(), "\0"]).collect();
+
+    /*
+     * The kernel loads the image into a new address space for us. The task's channel to us is always the first
+     * handle it's created with, which `ServiceHostClient` relies on. The permissions have already been checked
+     * against those of the task that asked for it to be spawned.
+     */
+    let capabilities = Capabilities::from_bits_truncate(permissions.capabilities);
+    let (task_channel, channel_handle) = Channel::create().unwrap();
+    let job = syscall::job_create(None).map_err(|err| {
+        warn!("Failed to create job for task '{}': {:?}", name, err);
+    })?;
+    let task = syscall::task_create(
+        &name,
+        image,
+        image_size,
+        &[channel_handle],
+        &arguments,
+        stdio,
+        capabilities,
+        Some(job),
+    )
+    .map_err(|err| {
+        warn!("Failed to spawn task '{}': {:?}", name, err);
+    });
+
+    /*
+     * The image has been loaded into the new task's address space, so we're done with it either way. The kernel
+     * moves the other handles we passed it to the new task, so we only need to close them if it wasn't created.
+     */
+    let _ = syscall::handle_close(image);
+    let task = match task {
+        Ok(task) => task,
+        Err(()) => {
+            let handles = [Some(channel_handle), Some(task_channel.handle()), Some(job)];
+            for handle in handles.into_iter().chain([stdio.stdin, stdio.stdout, stdio.stderr]).flatten() {
+                let _ = syscall::handle_close(handle);
+            }
+            return Err(());
+        }
+    };
+
+    Ok(Task {
+        name,
+        task,
+        job: Some(job),
+        task_channel,
+        namespace: permissions.namespace,
+        services: permissions.services,
+        capabilities,
+        pending_subscriptions: Vec::new(),
+    })
+}
+
+/// Create a channel between `task` and the provider of a service it has subscribed to, and send each of them its
+/// end.
+fn connect_to_service(task: &Task, service_channel: &Channel<ServiceChannelMessage, ()>) {
+    let (channel_a, channel_b) = syscall::create_channel().unwrap();
+    service_channel
+        .send(&ServiceChannelMessage::NewClient { name: task.name.clone(), channel: channel_a })
+        .unwrap();
+    task.task_channel.send(&ServiceHostResponse::SubscribedToService(channel_b)).unwrap();
+}
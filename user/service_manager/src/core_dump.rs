@@ -0,0 +1,255 @@
+//! Core dumps of services that cause exceptions. A core dump is an ELF file of type `ET_CORE`, laid out like the
+//! core files Linux writes, so it can be read by `cargo xtask coredump` (or loaded into GDB along with the
+//! service's binary):
+//!  - A `PT_NOTE` segment holds an `NT_PRSTATUS` note (named `CORE`) with the registers of the thread that
+//!    caused the exception, in the layout of Linux's `elf_prstatus`. It's followed by Poplar's own notes (named
+//!    `POPLAR`): `NT_POPLAR_EXCEPTION`, which describes the exception, and `NT_POPLAR_TASK`, which holds the name
+//!    of the task.
+//!  - A `PT_LOAD` segment for each of the task's memory regions. Pages of zeros at the end of a region are left
+//!    out of the file (the segment's `p_filesz` is less than its `p_memsz`), as are the contents of device
+//!    memory.
+//!
+//! The `NT_POPLAR_EXCEPTION` note holds the kind of exception as a `u32` (`0` for a page fault, `1` for an illegal
+//! instruction, `2` for a protection fault, `3` for a misaligned access, `4` for a stack overflow, and `5` for a
+//! floating-point exception), then the access that caused a page fault as a `u32` (`1` for a read, `2` for a
+//! write, and `3` for an instruction fetch, or `0` for other exceptions), and then the address of the exception as
+//! a `u64`.
+
+use mulch::math::align_up;
+use std::poplar::{
+    file::{OpenOptions, Vfs},
+    syscall::{self, ExceptionInfo, ExceptionKind, FaultAccess, MemoryRegion, Registers},
+    Handle,
+};
+
+/// The most memory regions that are included in a core dump. Any more are left out.
+const MAX_REGIONS: usize = 64;
+const PAGE_SIZE: usize = 0x1000;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_POPLAR_EXCEPTION: u32 = 1;
+const NT_POPLAR_TASK: u32 = 2;
+
+const SIGILL: u32 = 4;
+const SIGBUS: u32 = 7;
+const SIGFPE: u32 = 8;
+const SIGSEGV: u32 = 11;
+
+#[cfg(target_arch = "x86_64")]
+const EM_MACHINE: u16 = 62;
+#[cfg(target_arch = "riscv64")]
+const EM_MACHINE: u16 = 243;
+#[cfg(target_arch = "aarch64")]
+const EM_MACHINE: u16 = 183;
+
+/// Write a core dump of `task`, which is stopped because of the exception described by `info`, to `path`.
+pub fn write(vfs: &Vfs, path: &str, name: &str, task: Handle, info: &ExceptionInfo) -> Result<(), String> {
+    let mut regions = [MemoryRegion::default(); MAX_REGIONS];
+    let num_regions = syscall::task_memory_regions(task, &mut regions)
+        .map_err(|err| format!("Failed to get memory regions: {:?}", err))?;
+    let regions = &regions[0..usize::min(num_regions, MAX_REGIONS)];
+
+    let mut page = [0u8; PAGE_SIZE];
+    let mut dumped_sizes = Vec::with_capacity(regions.len());
+    for region in regions {
+        dumped_sizes.push(dumped_size(task, region, &mut page)?);
+    }
+
+    let notes = notes(name, info);
+    let num_headers = regions.len() + 1;
+    let notes_offset = ELF_HEADER_SIZE + num_headers * PROGRAM_HEADER_SIZE;
+
+    let mut headers = Vec::new();
+    write_elf_header(&mut headers, num_headers as u16);
+    write_program_header(&mut headers, PT_NOTE, 0, notes_offset, 0, notes.len(), 0);
+    let mut offset = align_up(notes_offset + notes.len(), PAGE_SIZE);
+    for (region, &dumped_size) in regions.iter().zip(&dumped_sizes) {
+        let flags = PF_R
+            | if region.writable { PF_W } else { 0 }
+            | if region.executable { PF_X } else { 0 };
+        write_program_header(&mut headers, PT_LOAD, flags, offset, region.address, dumped_size, region.size);
+        offset += align_up(dumped_size, PAGE_SIZE);
+    }
+    headers.extend_from_slice(&notes);
+    headers.resize(align_up(headers.len(), PAGE_SIZE), 0);
+
+    let mut file = vfs
+        .open(path, OpenOptions { write: true, create: true, truncate: true })
+        .map_err(|err| format!("Failed to open {}: {:?}", path, err))?;
+    let write_error = |err| format!("Failed to write to {}: {:?}", path, err);
+    file.write(&headers).map_err(write_error)?;
+
+    for (region, &dumped_size) in regions.iter().zip(&dumped_sizes) {
+        let end = region.address + dumped_size;
+        for address in (region.address..end).step_by(PAGE_SIZE) {
+            let length = read(task, address, &mut page[0..usize::min(PAGE_SIZE, end - address)])?;
+            // Segments are padded to a page boundary, so the next one starts page-aligned
+            page[length..].fill(0);
+            file.write(&page).map_err(write_error)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Work out how much of `region` needs to be in the core dump - everything apart from the pages of zeros at the
+/// end of it.
+fn dumped_size(task: Handle, region: &MemoryRegion, page: &mut [u8; PAGE_SIZE]) -> Result<usize, String> {
+    if region.device {
+        return Ok(0);
+    }
+
+    let end = region.address + region.size;
+    let mut size = 0;
+    for address in (region.address..end).step_by(PAGE_SIZE) {
+        let length = read(task, address, &mut page[0..usize::min(PAGE_SIZE, end - address)])?;
+        if page[0..length].iter().any(|&byte| byte != 0) {
+            size = address + length - region.address;
+        }
+    }
+    Ok(size)
+}
+
+/// Read the task's memory at `address` into `buffer`, which must not go past the end of the region the address is
+/// in. Returns the number of bytes read.
+fn read(task: Handle, address: usize, buffer: &mut [u8]) -> Result<usize, String> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match syscall::task_read_memory(task, address + read, &mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(length) => read += length,
+            Err(err) => return Err(format!("Failed to read memory at {:#x}: {:?}", address + read, err)),
+        }
+    }
+    Ok(read)
+}
+
+fn write_elf_header(bytes: &mut Vec<u8>, num_program_headers: u16) {
+    // Magic, 64-bit, little-endian, version 1, System V ABI
+    bytes.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&ET_CORE.to_le_bytes());
+    bytes.extend_from_slice(&EM_MACHINE.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend_from_slice(&num_program_headers.to_le_bytes());
+    // There are no section headers
+    bytes.extend_from_slice(&[0; 6]);
+}
+
+/// Write a program header. `PT_LOAD` segments are page-aligned, both in memory and in the file.
+fn write_program_header(
+    bytes: &mut Vec<u8>,
+    typ: u32,
+    flags: u32,
+    offset: usize,
+    address: usize,
+    file_size: usize,
+    memory_size: usize,
+) {
+    let align = if typ == PT_LOAD { PAGE_SIZE } else { 0 };
+    bytes.extend_from_slice(&typ.to_le_bytes());
+    bytes.extend_from_slice(&flags.to_le_bytes());
+    for field in [offset, address, address, file_size, memory_size, align] {
+        bytes.extend_from_slice(&(field as u64).to_le_bytes());
+    }
+}
+
+fn notes(name: &str, info: &ExceptionInfo) -> Vec<u8> {
+    let (kind, access, signal) = match info.kind {
+        ExceptionKind::PageFault(FaultAccess::Read) => (0u32, 1u32, SIGSEGV),
+        ExceptionKind::PageFault(FaultAccess::Write) => (0, 2, SIGSEGV),
+        ExceptionKind::PageFault(FaultAccess::Execute) => (0, 3, SIGSEGV),
+        ExceptionKind::IllegalInstruction => (1, 0, SIGILL),
+        ExceptionKind::ProtectionFault => (2, 0, SIGSEGV),
+        ExceptionKind::MisalignedAccess => (3, 0, SIGBUS),
+        ExceptionKind::StackOverflow => (4, 0, SIGSEGV),
+        ExceptionKind::FloatingPoint => (5, 0, SIGFPE),
+    };
+
+    /*
+     * `elf_prstatus` starts with the signal (in `pr_info.si_signo` and `pr_cursig`), followed by fields Poplar has
+     * no equivalent of (pending signals, IDs, and CPU times), and then the registers.
+     */
+    let mut prstatus = Vec::new();
+    prstatus.extend_from_slice(&signal.to_le_bytes());
+    prstatus.extend_from_slice(&[0; 8]);
+    prstatus.extend_from_slice(&(signal as u16).to_le_bytes());
+    prstatus.resize(112, 0);
+    for register in prstatus_registers(&info.registers) {
+        prstatus.extend_from_slice(&register.to_le_bytes());
+    }
+    // `pr_fpvalid`, and padding
+    prstatus.extend_from_slice(&[0; 8]);
+
+    let mut exception = Vec::new();
+    exception.extend_from_slice(&kind.to_le_bytes());
+    exception.extend_from_slice(&access.to_le_bytes());
+    exception.extend_from_slice(&(info.address as u64).to_le_bytes());
+
+    let mut notes = Vec::new();
+    write_note(&mut notes, "CORE", NT_PRSTATUS, &prstatus);
+    write_note(&mut notes, "POPLAR", NT_POPLAR_EXCEPTION, &exception);
+    write_note(&mut notes, "POPLAR", NT_POPLAR_TASK, name.as_bytes());
+    notes
+}
+
+fn write_note(bytes: &mut Vec<u8>, name: &str, typ: u32, desc: &[u8]) {
+    // The name includes its null terminator, and the name and descriptor are each padded to four bytes
+    bytes.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    bytes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&typ.to_le_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.resize(align_up(bytes.len() + 1, 4), 0);
+    bytes.extend_from_slice(desc);
+    bytes.resize(align_up(bytes.len(), 4), 0);
+}
+
+/// The registers in the order of Linux's `user_regs_struct`. Poplar doesn't report the segment registers or the
+/// base of the FS and GS segments, so these are left as zero, as is `orig_rax` (which is only meaningful in system
+/// calls).
+#[cfg(target_arch = "x86_64")]
+fn prstatus_registers(registers: &Registers) -> [u64; 27] {
+    let r = registers;
+    [
+        r.r15, r.r14, r.r13, r.r12, r.rbp, r.rbx, r.r11, r.r10, r.r9, r.r8, r.rax, r.rcx, r.rdx, r.rsi, r.rdi, 0,
+        r.rip, 0, r.rflags, r.rsp, 0, 0, 0, 0, 0, 0, 0,
+    ]
+}
+
+/// The registers in the order of Linux's `user_regs_struct`, which is the same as ours.
+#[cfg(target_arch = "riscv64")]
+fn prstatus_registers(registers: &Registers) -> [u64; 32] {
+    let r = registers;
+    [
+        r.pc, r.ra, r.sp, r.gp, r.tp, r.t0, r.t1, r.t2, r.s0, r.s1, r.a0, r.a1, r.a2, r.a3, r.a4, r.a5, r.a6, r.a7,
+        r.s2, r.s3, r.s4, r.s5, r.s6, r.s7, r.s8, r.s9, r.s10, r.s11, r.t3, r.t4, r.t5, r.t6,
+    ]
+    .map(|register| register as u64)
+}
+
+/// The registers in the order of Linux's `user_pt_regs`.
+#[cfg(target_arch = "aarch64")]
+fn prstatus_registers(registers: &Registers) -> [u64; 34] {
+    let r = registers;
+    [
+        r.x0, r.x1, r.x2, r.x3, r.x4, r.x5, r.x6, r.x7, r.x8, r.x9, r.x10, r.x11, r.x12, r.x13, r.x14, r.x15,
+        r.x16, r.x17, r.x18, r.x19, r.x20, r.x21, r.x22, r.x23, r.x24, r.x25, r.x26, r.x27, r.x28, r.x29, r.x30,
+        r.sp, r.pc, r.pstate,
+    ]
+    .map(|register| register as u64)
+}
@@ -0,0 +1,269 @@
+//! `service_manager` starts the system's services, and keeps them running. The services are described by a
+//! manifest in the initramfs (see the `manifest` module for its format), which gives each service's image, the
+//! permissions it should be given, and the services it depends on. Services are started after their
+//! dependencies, and are restarted according to their restart policy when they exit. A service that keeps
+//! failing is restarted with an exponential backoff, so it doesn't take up the whole system. If a service causes
+//! an exception, a core dump of it is written to `CORE_DUMP_DIRECTORY` before it's killed (see the `core_dump`
+//! module).
+
+mod core_dump;
+mod manifest;
+
+use log::{error, info, warn};
+use manifest::{RestartPolicy, Service};
+use service_host::{ServiceHostClient, TaskPermissions};
+use std::{
+    collections::BTreeSet,
+    poplar::{
+        early_logger::EarlyLogger,
+        channel::Channel,
+        file::{FileClientError, FileError, OpenOptions, Vfs, VFS_SERVICE},
+        syscall::{self, ExceptionInfo, ExceptionResolution, Signals, Stdio, WaitItem},
+        Handle,
+        HandleRights,
+    },
+    sync::Arc,
+    time::Duration,
+};
+
+const MANIFEST_PATH: &str = "/etc/services.conf";
+/// Where core dumps of services are written, as `{service}.core`. This is the EFI system partition, so core dumps
+/// can be read from the disk image after the system has been shut down.
+const CORE_DUMP_DIRECTORY: &str = "/boot";
+
+/// How long to wait before restarting a service after it first fails. This is doubled each time it fails again, up
+/// to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// If a service runs for at least this long before exiting, it's treated as having started successfully, and its
+/// backoff is reset.
+const STABLE_RUN_TIME: Duration = Duration::from_secs(30);
+
+/// A service's image, mapped into a `MemoryObject` by the VFS. We keep our handle to it for as long as we're
+/// running, so services can be restarted without mapping their image again.
+#[derive(Clone, Copy)]
+struct Image {
+    memory_object: Handle,
+    size: usize,
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Service manager is running!");
+
+    std::poplar::rt::init_runtime();
+
+    std::poplar::rt::spawn(async move {
+        let service_host = Arc::new(ServiceHostClient::new());
+        let vfs = Vfs::new(service_host.subscribe_service(VFS_SERVICE).unwrap());
+
+        let Some(manifest) = read_manifest(&vfs).await else {
+            return;
+        };
+        let services = match manifest::parse(&manifest) {
+            Ok(services) => services,
+            Err(err) => {
+                error!("Failed to parse {} (line {}): {}", MANIFEST_PATH, err.line, err.message);
+                return;
+            }
+        };
+
+        let order = manifest::start_order(&services);
+        for (index, service) in services.iter().enumerate() {
+            if !order.contains(&index) {
+                warn!(
+                    "Service '{}' depends on services that are missing, or that depend on it. Not starting it.",
+                    service.name
+                );
+            }
+        }
+
+        /*
+         * Start the services in dependency order. If a service can't be started, neither can the services that
+         * depend on it.
+         */
+        let mut started = BTreeSet::new();
+        for service in order.into_iter().map(|index| services[index].clone()) {
+            if let Some(dep) = service.depends.iter().find(|dep| !started.contains(*dep)) {
+                warn!("Not starting service '{}', as its dependency '{}' failed to start", service.name, dep);
+                continue;
+            }
+
+            let image = match load_image(&vfs, &service.binary) {
+                Ok(image) => image,
+                Err(message) => {
+                    warn!("Failed to load image for service '{}': {}", service.name, message);
+                    continue;
+                }
+            };
+            let Ok(task) = spawn(&service_host, &vfs, &service, image) else {
+                continue;
+            };
+
+            started.insert(service.name.clone());
+            let service_host = service_host.clone();
+            let vfs = vfs.clone();
+            std::poplar::rt::spawn(async move { supervise(service, image, task, service_host, vfs).await });
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
+
+/// Read the manifest from the initramfs. The initramfs may not have been mounted yet when we start, so we try a
+/// few times before giving up.
+async fn read_manifest(vfs: &Vfs) -> Option<String> {
+    const ATTEMPTS: usize = 50;
+
+    for _ in 0..ATTEMPTS {
+        match vfs.open(MANIFEST_PATH, OpenOptions::read()).and_then(|mut file| file.read_to_end()) {
+            Ok(data) => match String::from_utf8(data) {
+                Ok(manifest) => return Some(manifest),
+                Err(_) => {
+                    error!("{} is not valid UTF-8", MANIFEST_PATH);
+                    return None;
+                }
+            },
+            Err(FileClientError::File(FileError::NotFound)) => {
+                std::poplar::rt::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(err) => {
+                error!("Failed to read {}: {:?}", MANIFEST_PATH, err);
+                return None;
+            }
+        }
+    }
+
+    info!("No service manifest found at {}. Not starting any services.", MANIFEST_PATH);
+    None
+}
+
+fn load_image(vfs: &Vfs, path: &str) -> Result<Image, String> {
+    let size = vfs.stat(path).map_err(|err| format!("{}: {:?}", path, err))?.size as usize;
+    let file = vfs.open(path, OpenOptions::read()).map_err(|err| format!("{}: {:?}", path, err))?;
+    let memory_object = file.map(0, size).map_err(|err| format!("{}: failed to map image: {:?}", path, err))?;
+    Ok(Image { memory_object: memory_object.handle, size })
+}
+
+/// Ask `service_host` to spawn a new instance of `service`. Returns a handle to the new task.
+fn spawn(service_host: &ServiceHostClient, vfs: &Vfs, service: &Service, image: Image) -> Result<Handle, ()> {
+    /*
+     * The handle to the image is transferred to `service_host` with the request, so we send it a new handle each
+     * time, and keep our own to restart the service with.
+     */
+    let image_handle =
+        syscall::handle_duplicate_with_rights(image.memory_object, HandleRights::READ | HandleRights::TRANSFER)
+            .map_err(|err| {
+                warn!("Failed to duplicate handle to image of service '{}': {:?}", service.name, err)
+            })?;
+
+    // The channel to the namespace is transferred in the same way, so each instance is given a new one
+    let namespace = if service.namespace.is_empty() {
+        None
+    } else {
+        let namespace = vfs
+            .create_namespace(service.namespace.clone())
+            .map_err(|err| warn!("Failed to create namespace for service '{}': {:?}", service.name, err))?;
+        Some(namespace)
+    };
+    let permissions =
+        TaskPermissions { namespace, ..TaskPermissions::new(service.capabilities, service.services.clone()) };
+    let task = service_host
+        .spawn_task(
+            &service.name,
+            image_handle,
+            image.size,
+            service.arguments.clone(),
+            Stdio::default(),
+            permissions,
+        )
+        .map_err(|()| warn!("Failed to spawn service '{}'", service.name))?;
+    info!("Started service '{}'", service.name);
+    Ok(task)
+}
+
+/// Wait for `service` to exit, and restart it if its restart policy says it should be. If it causes an exception,
+/// a core dump of it is written before it's killed.
+async fn supervise(
+    service: Service,
+    image: Image,
+    mut task: Handle,
+    service_host: Arc<ServiceHostClient>,
+    vfs: Vfs,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut started_at = syscall::get_uptime();
+
+    loop {
+        /*
+         * The service is already running by the time we get a handle to it, so an exception it causes before we
+         * start handling them kills it without a core dump.
+         */
+        let exceptions = match syscall::task_exception_channel(Some(task)) {
+            Ok(handle) => Some(Channel::<(), ExceptionInfo>::new_from_handle(handle)),
+            Err(err) => {
+                warn!("Failed to handle exceptions of service '{}': {:?}", service.name, err);
+                None
+            }
+        };
+
+        loop {
+            let mut items = vec![WaitItem::new(task, Signals::TERMINATED)];
+            items.extend(exceptions.as_ref().map(|channel| WaitItem::new(channel.handle(), Signals::READABLE)));
+            if std::poplar::rt::select(&mut items).await == 0 {
+                break;
+            }
+
+            if let Some(Ok(Some(info))) = exceptions.as_ref().map(|channel| channel.try_receive()) {
+                warn!("Service '{}' caused an exception: {:?} at {:#x}", service.name, info.kind, info.address);
+                let path = format!("{}/{}.core", CORE_DUMP_DIRECTORY, service.name);
+                match core_dump::write(&vfs, &path, &service.name, task, &info) {
+                    Ok(()) => info!("Wrote core dump of service '{}' to {}", service.name, path),
+                    Err(message) => warn!("Failed to write core dump of service '{}': {}", service.name, message),
+                }
+                let _ = syscall::task_resume(task, ExceptionResolution::Kill);
+            }
+        }
+        if let Some(exceptions) = exceptions {
+            let _ = syscall::handle_close(exceptions.handle());
+        }
+
+        let status = syscall::task_wait(task, None);
+        let _ = syscall::handle_close(task);
+        let failed = !matches!(status, Ok(0));
+        match status {
+            Ok(status) => info!("Service '{}' exited with status {}", service.name, status),
+            Err(err) => warn!("Failed to get exit status of service '{}': {:?}", service.name, err),
+        }
+
+        let restart = match service.restart {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        };
+        if !restart {
+            return;
+        }
+
+        if syscall::get_uptime().saturating_sub(started_at) >= STABLE_RUN_TIME {
+            backoff = INITIAL_BACKOFF;
+        }
+
+        /*
+         * Keep trying to restart the service, backing off further each time it can't be restarted or fails
+         * again.
+         */
+        loop {
+            info!("Restarting service '{}' in {:?}", service.name, backoff);
+            std::poplar::rt::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+
+            if let Ok(new_task) = spawn(&service_host, &vfs, &service, image) {
+                task = new_task;
+                started_at = syscall::get_uptime();
+                break;
+            }
+        }
+    }
+}
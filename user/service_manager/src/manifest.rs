@@ -0,0 +1,157 @@
+//! The manifest describes the services that `service_manager` starts. It's made up of a section for each service,
+//! which starts with the service's name in square brackets, followed by `key = value` lines:
+//!    - `binary`: the path to the service's image (required)
+//!    - `arguments`: the arguments to start the service with, separated by whitespace
+//!    - `capabilities`: the capabilities to give the service, by the names of the `Capabilities` flags (e.g.
+//!      `SET_PRIORITY`), separated by whitespace
+//!    - `services`: patterns matching the services it can subscribe to, separated by whitespace
+//!    - `namespace`: the directories the service can access, separated by whitespace. Each is either a path,
+//!      which binds the directory at the same path in the service's namespace, or `path:target`, which binds the
+//!      directory at `target` at `path`. Services without a namespace can't access any files.
+//!    - `depends`: the services that must be started before this one, separated by whitespace
+//!    - `restart`: when to restart the service after it exits - `never`, `on-failure` (the default), or `always`
+//!
+//! Blank lines, and lines starting with `#`, are ignored.
+
+use std::poplar::{caps::Capabilities, file::Binding};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RestartPolicy {
+    Never,
+    /// Restart the service if it exits with a non-zero status.
+    OnFailure,
+    Always,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service {
+    pub name: String,
+    pub binary: String,
+    pub arguments: Vec<String>,
+    pub capabilities: Capabilities,
+    pub services: Vec<String>,
+    pub namespace: Vec<Binding>,
+    pub depends: Vec<String>,
+    pub restart: RestartPolicy,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ManifestError {
+    /// The line the error is on, starting from `1`.
+    pub line: usize,
+    pub message: String,
+}
+
+pub fn parse(source: &str) -> Result<Vec<Service>, ManifestError> {
+    let mut services: Vec<Service> = Vec::new();
+    /*
+     * Whether the current section has set its binary. We check this when the next section starts, or at the end
+     * of the manifest.
+     */
+    let mut has_binary = true;
+
+    for (index, line) in source.lines().enumerate() {
+        let error = |message: String| ManifestError { line: index + 1, message };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            let name = name.trim();
+            if !has_binary {
+                return Err(error(format!("service '{}' does not have a binary", services.last().unwrap().name)));
+            }
+            if name.is_empty() {
+                return Err(error("service has an empty name".to_string()));
+            }
+            if services.iter().any(|service| service.name == name) {
+                return Err(error(format!("service '{}' is defined more than once", name)));
+            }
+
+            services.push(Service {
+                name: name.to_string(),
+                binary: String::new(),
+                arguments: Vec::new(),
+                capabilities: Capabilities::empty(),
+                services: Vec::new(),
+                namespace: Vec::new(),
+                depends: Vec::new(),
+                restart: RestartPolicy::OnFailure,
+            });
+            has_binary = false;
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or_else(|| error("expected `key = value`".to_string()))?;
+        let (key, value) = (key.trim(), value.trim());
+        let list = || value.split_whitespace().map(str::to_string).collect();
+        let service = services.last_mut().ok_or_else(|| error(format!("'{}' is not in a service", key)))?;
+
+        match key {
+            "binary" => {
+                service.binary = value.to_string();
+                has_binary = true;
+            }
+            "arguments" => service.arguments = list(),
+            "capabilities" => {
+                for name in value.split_whitespace() {
+                    let capability = Capabilities::from_name(name)
+                        .ok_or_else(|| error(format!("unknown capability '{}'", name)))?;
+                    service.capabilities |= capability;
+                }
+            }
+            "services" => service.services = list(),
+            "namespace" => {
+                service.namespace = value
+                    .split_whitespace()
+                    .map(|binding| match binding.split_once(':') {
+                        Some((path, target)) => Binding::new(path, target),
+                        None => Binding::new(binding, binding),
+                    })
+                    .collect()
+            }
+            "depends" => service.depends = list(),
+            "restart" => {
+                service.restart = match value {
+                    "never" => RestartPolicy::Never,
+                    "on-failure" => RestartPolicy::OnFailure,
+                    "always" => RestartPolicy::Always,
+                    _ => return Err(error(format!("unknown restart policy '{}'", value))),
+                }
+            }
+            _ => return Err(error(format!("unknown key '{}'", key))),
+        }
+    }
+
+    if !has_binary {
+        return Err(ManifestError {
+            line: source.lines().count(),
+            message: format!("service '{}' does not have a binary", services.last().unwrap().name),
+        });
+    }
+    Ok(services)
+}
+
+/// Work out the order to start `services` in, so that each service is started after all of its dependencies.
+/// Services are otherwise started in the order they appear in the manifest. Returns the indices of the services
+/// in the order they should be started.
+///
+/// Services that depend on a service that isn't in the manifest, or that are part of a dependency cycle (or depend
+/// on a service that is), can never be started, and are left out of the order.
+pub fn start_order(services: &[Service]) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut ordered = vec![false; services.len()];
+
+    let is_ordered = |ordered: &[bool], name: &String| {
+        services.iter().position(|service| service.name == *name).map_or(false, |index| ordered[index])
+    };
+    while let Some(next) = (0..services.len())
+        .find(|&index| !ordered[index] && services[index].depends.iter().all(|dep| is_ordered(&ordered, dep)))
+    {
+        ordered[next] = true;
+        order.push(next);
+    }
+
+    order
+}
@@ -0,0 +1,188 @@
+//! A simple line editor. It's fed the characters typed into the console, and produces the text that needs to be
+//! written back to the console to show the line as it's edited. Lines can be edited with backspace, delete, and
+//! the left and right arrow keys, and previously entered lines can be recalled with the up and down arrow keys.
+
+use core::fmt::Write;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EscapeState {
+    None,
+    /// We've seen an `ESC`.
+    Escape,
+    /// We've seen `ESC [`, and any parameter bytes are collected in `LineEditor::escape_param`.
+    Csi,
+}
+
+pub struct LineEditor {
+    line: Vec<char>,
+    /// The position of the cursor within `line`.
+    cursor: usize,
+    history: Vec<String>,
+    /// If we're showing a line from the history, its index into `history`.
+    history_index: Option<usize>,
+    /// The line that was being edited before we started moving through the history.
+    saved_line: Vec<char>,
+    escape: EscapeState,
+    escape_param: u16,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor {
+            line: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_index: None,
+            saved_line: Vec::new(),
+            escape: EscapeState::None,
+            escape_param: 0,
+        }
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Handle a character typed into the console. Any text needed to update the console is appended to `echo`.
+    /// Returns the line when it is completed (by pressing enter).
+    pub fn input(&mut self, c: char, echo: &mut String) -> Option<String> {
+        match self.escape {
+            EscapeState::None => (),
+            EscapeState::Escape => {
+                self.escape = if c == '[' { EscapeState::Csi } else { EscapeState::None };
+                self.escape_param = 0;
+                return None;
+            }
+            EscapeState::Csi => {
+                match c {
+                    '0'..='9' => {
+                        self.escape_param =
+                            self.escape_param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                        return None;
+                    }
+                    'A' => self.history_previous(echo),
+                    'B' => self.history_next(echo),
+                    'C' if self.cursor < self.line.len() => {
+                        self.cursor += 1;
+                        echo.push_str("\x1b[C");
+                    }
+                    'D' if self.cursor > 0 => {
+                        self.cursor -= 1;
+                        echo.push_str("\x1b[D");
+                    }
+                    'H' => self.move_cursor(0, echo),
+                    'F' => self.move_cursor(self.line.len(), echo),
+                    // `ESC [ 3 ~` is sent by the delete key
+                    '~' if self.escape_param == 3 && self.cursor < self.line.len() => {
+                        self.line.remove(self.cursor);
+                        self.redraw_from_cursor(echo);
+                    }
+                    _ => (),
+                }
+                self.escape = EscapeState::None;
+                return None;
+            }
+        }
+
+        match c {
+            '\x1b' => self.escape = EscapeState::Escape,
+            '\n' | '\r' => {
+                echo.push('\n');
+                let line: String = self.line.drain(..).collect();
+                self.cursor = 0;
+                self.history_index = None;
+                if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                return Some(line);
+            }
+            // Backspace
+            '\x7f' | '\x08' if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                echo.push('\x08');
+                self.redraw_from_cursor(echo);
+            }
+            // Ctrl+C abandons the current line
+            '\x03' => {
+                echo.push_str("^C\n");
+                self.line.clear();
+                self.cursor = 0;
+                self.history_index = None;
+                return Some(String::new());
+            }
+            // Ctrl+U deletes everything before the cursor
+            '\x15' => {
+                self.line.drain(0..self.cursor);
+                self.move_cursor(0, echo);
+                self.redraw_from_cursor(echo);
+            }
+            c if !c.is_control() => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+                echo.push(c);
+                self.redraw_from_cursor(echo);
+            }
+            _ => (),
+        }
+
+        None
+    }
+
+    fn history_previous(&mut self, echo: &mut String) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.saved_line = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+        self.history_index = Some(index);
+        let line = self.history[index].chars().collect();
+        self.replace_line(line, echo);
+    }
+
+    fn history_next(&mut self, echo: &mut String) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        let line = if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.history[index + 1].chars().collect()
+        } else {
+            self.history_index = None;
+            core::mem::take(&mut self.saved_line)
+        };
+        self.replace_line(line, echo);
+    }
+
+    fn replace_line(&mut self, line: Vec<char>, echo: &mut String) {
+        self.move_cursor(0, echo);
+        self.line = line;
+        echo.extend(&self.line);
+        echo.push_str("\x1b[K");
+        self.cursor = self.line.len();
+    }
+
+    fn move_cursor(&mut self, position: usize, echo: &mut String) {
+        if position < self.cursor {
+            write!(echo, "\x1b[{}D", self.cursor - position).unwrap();
+        } else if position > self.cursor {
+            write!(echo, "\x1b[{}C", position - self.cursor).unwrap();
+        }
+        self.cursor = position;
+    }
+
+    /// Redraw the line from the cursor to the end, erasing anything left over after it, and leave the cursor
+    /// where it was.
+    fn redraw_from_cursor(&self, echo: &mut String) {
+        echo.extend(&self.line[self.cursor..]);
+        echo.push_str("\x1b[K");
+        let tail = self.line.len() - self.cursor;
+        if tail > 0 {
+            write!(echo, "\x1b[{}D", tail).unwrap();
+        }
+    }
+}
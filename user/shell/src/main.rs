@@ -0,0 +1,437 @@
+//! `shell` is an interactive shell that runs on a console provided through the `console` service (see
+//! `std::poplar::console`). It supports line editing and history, and can launch other tasks by name. Launched
+//! tasks are given standard handles (see `std::poplar::stdio`), which the shell connects to its own console, or
+//! to each other with pipes for pipelines like `a | b`.
+
+mod line;
+
+use line::LineEditor;
+use log::{info, warn};
+use platform_bus::SystemPowerClient;
+use service_host::{ServiceHostClient, TaskPermissions};
+use spinning_top::Spinlock;
+use std::{
+    poplar::{
+        caps::Capabilities,
+        channel::Channel,
+        console::{self, ConsoleEvent, ConsoleRequest, CONSOLE_SERVICE},
+        file::{Binding, OpenOptions, Vfs, VFS_SERVICE},
+        logger::{LogEvent, LogLevel, LogQuery, LogRequest, LOGGER, LOG_SERVICE, MAX_QUERY_RECORDS},
+        net::NET_SOCKET_SERVICE,
+        pipe::{self, PipeReader, PipeWriter},
+        syscall::{self, Signals, Stdio, WaitItem},
+        Handle,
+    },
+    sync::Arc,
+};
+
+/// The directories searched (in order) for a task's image when it's launched by name.
+const SEARCH_PATH: &[&str] = &["/bin", "/boot"];
+
+const PROMPT: &str = "\x1b[1;32m>\x1b[0m ";
+
+/// A task launched by the shell. Its standard handles are either channels connected to us, over which it speaks
+/// the console protocol (see `std::poplar::stdio`), or pipes to the other tasks in its pipeline. Its stderr is
+/// always connected to us.
+struct Child {
+    name: String,
+    stdin: Option<Channel<ConsoleEvent, ConsoleRequest>>,
+    stdout: Option<Channel<ConsoleEvent, ConsoleRequest>>,
+    stderr: Channel<ConsoleEvent, ConsoleRequest>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+impl Child {
+    fn channel(&self, stream: Stream) -> Option<&Channel<ConsoleEvent, ConsoleRequest>> {
+        match stream {
+            Stream::Stdin => self.stdin.as_ref(),
+            Stream::Stdout => self.stdout.as_ref(),
+            Stream::Stderr => Some(&self.stderr),
+        }
+    }
+}
+
+struct Shell {
+    console: Arc<Channel<ConsoleRequest, ConsoleEvent>>,
+    service_host: ServiceHostClient,
+    vfs: Vfs,
+    /// Our own connection to `log_server`, to query records and change log levels through. Our records are sent
+    /// over `LOGGER`'s connection instead.
+    log: Channel<LogRequest, LogEvent>,
+    line: LineEditor,
+    /// Launched tasks that have asked for input. Input is forwarded to the last task in the list, instead of
+    /// being handled by the shell.
+    input_children: Arc<Spinlock<Vec<Arc<Child>>>>,
+}
+
+impl Shell {
+    fn write(&self, text: &str) {
+        console::write(&self.console, text).unwrap();
+    }
+
+    fn handle_input(&mut self, input: &str) {
+        {
+            let mut input_children = self.input_children.lock();
+            while let Some(child) = input_children.last() {
+                // Only tasks whose stdin is connected to us are added to the list
+                match child.stdin.as_ref().unwrap().send(&ConsoleEvent::Input(input.to_string())) {
+                    Ok(()) => return,
+                    Err(err) => {
+                        warn!("Failed to send input to task '{}' ({:?}). Detaching it.", child.name, err);
+                        input_children.pop();
+                    }
+                }
+            }
+        }
+
+        let mut echo = String::new();
+        for c in input.chars() {
+            if let Some(line) = self.line.input(c, &mut echo) {
+                self.write(&echo);
+                echo.clear();
+                self.run_command(&line);
+                self.write(PROMPT);
+            }
+        }
+        self.write(&echo);
+    }
+
+    fn run_command(&mut self, line: &str) {
+        if line.contains('|') {
+            if let Err(message) = self.run_pipeline(line) {
+                self.write(&format!("\x1b[31m{}\x1b[0m\n", message));
+            }
+            return;
+        }
+
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return;
+        };
+
+        match command {
+            "help" => {
+                self.write("Built-in commands:\n");
+                self.write("    help       Show this message\n");
+                self.write("    clear      Clear the screen\n");
+                self.write("    history    List the commands that have been entered\n");
+                self.write("    logs [task] [level]      Show recent log records\n");
+                self.write("    loglevel <task> <level>  Set the log level of a task (or `*` for all tasks)\n");
+                self.write("    audit <task> [args]      Run a task, tracing its system calls\n");
+                self.write("    shutdown   Power the system off\n");
+                self.write("    reboot     Reboot the system\n");
+                self.write("Anything else is run as a task, which is searched for in: ");
+                self.write(&SEARCH_PATH.join(", "));
+                self.write("\n");
+                self.write("Tasks can be connected with pipes, e.g. `top | wc`\n");
+            }
+            "clear" => self.write("\x1b[2J\x1b[H"),
+            "history" => {
+                let mut output = String::new();
+                for (i, line) in self.line.history().iter().enumerate() {
+                    output += &format!("{:>4}  {}\n", i + 1, line);
+                }
+                self.write(&output);
+            }
+            "logs" => {
+                let task = words.next().filter(|task| *task != "*").map(ToString::to_string);
+                let level = match words.next().map(LogLevel::from_name) {
+                    None => LogLevel::Trace,
+                    Some(Some(level)) => level,
+                    Some(None) => return self.write("\x1b[31mlogs: invalid level\x1b[0m\n"),
+                };
+                self.show_logs(task, level);
+            }
+            "loglevel" => {
+                let (Some(task), Some(level)) = (words.next(), words.next()) else {
+                    return self.write("Usage: loglevel <task> <error|warn|info|debug|trace|off>\n");
+                };
+                let level = match LogLevel::from_name(level) {
+                    Some(level) => Some(level),
+                    None if level.eq_ignore_ascii_case("off") => None,
+                    None => return self.write("\x1b[31mloglevel: invalid level\x1b[0m\n"),
+                };
+                match self.log.call_blocking(&LogRequest::SetLevel { task: task.to_string(), level }) {
+                    Ok(LogEvent::LevelSet { num_tasks }) => {
+                        self.write(&format!("Changed the log level of {} running task(s)\n", num_tasks))
+                    }
+                    other => warn!("Unexpected response to SetLevel request: {:?}", other),
+                }
+            }
+            "audit" => {
+                let Some(name) = words.next() else {
+                    return self.write("Usage: audit <task> [args]\n");
+                };
+                let arguments = words.map(ToString::to_string).collect();
+                if let Err(message) = self.launch(name, arguments, true, None, None) {
+                    self.write(&format!("\x1b[31m{}\x1b[0m\n", message));
+                }
+            }
+            "shutdown" | "reboot" => self.shutdown(command == "reboot"),
+            name => {
+                let arguments = words.map(ToString::to_string).collect();
+                if let Err(message) = self.launch(name, arguments, false, None, None) {
+                    self.write(&format!("\x1b[31m{}\x1b[0m\n", message));
+                }
+            }
+        }
+    }
+
+    /// Ask the Platform Bus to shut the system down, once its drivers have stopped their devices.
+    fn shutdown(&self, reboot: bool) {
+        let Ok(channel) = self.service_host.subscribe_service("platform_bus.power") else {
+            return self.write("\x1b[31mNot allowed to shut the system down\x1b[0m\n");
+        };
+        let power = SystemPowerClient::new(channel);
+        self.write(if reboot { "Rebooting...\n" } else { "Shutting down...\n" });
+
+        let console = self.console.clone();
+        std::poplar::rt::spawn(async move {
+            // The Platform Bus only replies if the system couldn't be powered off or reset
+            match power.shutdown(reboot).await {
+                Ok(()) => console::write(&console, "\x1b[31mFailed to shut the system down\x1b[0m\n").unwrap(),
+                Err(err) => warn!("Failed to ask the Platform Bus to shut down: {:?}", err),
+            }
+        });
+    }
+
+    /// Show the records `log_server` has kept from `task` (or from every task), that are at least as severe as
+    /// `level`.
+    fn show_logs(&self, task: Option<String>, level: LogLevel) {
+        let mut since = 0;
+        loop {
+            let query = LogQuery { task: task.clone(), level, since, max_records: MAX_QUERY_RECORDS };
+            let (records, next) = match self.log.call_blocking(&LogRequest::Query(query)) {
+                Ok(LogEvent::Records { records, next }) => (records, next),
+                other => {
+                    warn!("Unexpected response to Query request: {:?}", other);
+                    return;
+                }
+            };
+
+            let mut output = String::new();
+            for record in &records {
+                let timestamp = record.record.timestamp / 1_000_000;
+                output += &format!(
+                    "[{:>5}.{:03}] {:5} {}: {}\n",
+                    timestamp / 1000,
+                    timestamp % 1000,
+                    record.record.level.name(),
+                    record.task_name,
+                    record.record.message
+                );
+            }
+            self.write(&output);
+
+            if records.len() < MAX_QUERY_RECORDS as usize {
+                break;
+            }
+            since = next;
+        }
+    }
+
+    /// Run the tasks in a pipeline like `a | b | c`, connecting the stdout of each task to the stdin of the next
+    /// with a pipe. Input typed into the console goes to the first task, and the output of the last task is shown
+    /// on the console. Built-in commands can't be used in pipelines.
+    fn run_pipeline(&mut self, line: &str) -> Result<(), String> {
+        let stages: Vec<Vec<&str>> = line.split('|').map(|stage| stage.split_whitespace().collect()).collect();
+        if stages.iter().any(Vec::is_empty) {
+            return Err("Each part of a pipeline needs a task to run".to_string());
+        }
+
+        let mut stdin = None;
+        for (i, stage) in stages.iter().enumerate() {
+            let (stdout, next_stdin) = if i + 1 < stages.len() {
+                let (reader, writer) = pipe::pipe().map_err(|err| format!("Failed to create pipe: {:?}", err))?;
+                (Some(writer), Some(reader))
+            } else {
+                (None, None)
+            };
+
+            /*
+             * If a task fails to launch, the ends of the pipes to it are closed when they're dropped, so the tasks
+             * before it see their output has nowhere to go, and the tasks after it see the end of their input.
+             */
+            let arguments = stage[1..].iter().map(ToString::to_string).collect();
+            self.launch(stage[0], arguments, false, stdin.take(), stdout)?;
+            stdin = next_stdin;
+        }
+
+        Ok(())
+    }
+
+    /// Find the image for a task called `name`, and ask `service_host` to spawn it. Its stdin and stdout are
+    /// connected to our console, unless pipes are given for them, and its stderr always is. If `audit` is set, the
+    /// task's system calls are recorded in the trace buffers - the first few may be missed, as the task can start
+    /// running before auditing is turned on.
+    fn launch(
+        &mut self,
+        name: &str,
+        arguments: Vec<String>,
+        audit: bool,
+        stdin_pipe: Option<PipeReader>,
+        stdout_pipe: Option<PipeWriter>,
+    ) -> Result<(), String> {
+        let (path, size) = SEARCH_PATH
+            .iter()
+            .map(|dir| format!("{}/{}", dir, name))
+            .find_map(|path| self.vfs.stat(&path).ok().map(|stat| (path, stat.size as usize)))
+            .ok_or_else(|| format!("{}: command not found", name))?;
+
+        // The VFS maps the image into a memory object for us, which we hand on to `service_host`
+        let file = self.vfs.open(&path, OpenOptions::read()).map_err(|err| format!("{}: {:?}", path, err))?;
+        let image_handle =
+            file.map(0, size).map_err(|err| format!("{}: failed to map image: {:?}", path, err))?.handle;
+
+        /*
+         * Tasks launched from the shell talk to it through their standard handles, and the only service they're
+         * given access to is `net.socket`, so they can use `std::net`. They see the same files we do, through a
+         * namespace of their own.
+         * TODO: tasks should be given the capabilities encoded in their images, not all of ours
+         */
+        let namespace = self
+            .vfs
+            .create_namespace(vec![Binding::new("/", "/")])
+            .map_err(|err| format!("{}: failed to create namespace: {:?}", name, err))?;
+        // Streams that aren't piped to another task are connected to us by a channel
+        let connect = |pipe: Option<Handle>| match pipe {
+            Some(handle) => (None, handle),
+            None => {
+                let (channel, handle) = Channel::create().unwrap();
+                (Some(channel), handle)
+            }
+        };
+        let (stdin, stdin_handle) = connect(stdin_pipe.map(PipeReader::into_handle));
+        let (stdout, stdout_handle) = connect(stdout_pipe.map(PipeWriter::into_handle));
+        let (stderr, stderr_handle) = Channel::create().unwrap();
+        let stdio = Stdio { stdin: Some(stdin_handle), stdout: Some(stdout_handle), stderr: Some(stderr_handle) };
+        let permissions = TaskPermissions {
+            namespace: Some(namespace),
+            ..TaskPermissions::new(Capabilities::all(), vec![NET_SOCKET_SERVICE.to_string()])
+        };
+        let task = self
+            .service_host
+            .spawn_task(name, image_handle, size, arguments, stdio, permissions)
+            .map_err(|()| format!("{}: failed to spawn task", name))?;
+        info!("Launched task '{}' from {}", name, path);
+        if audit {
+            if let Err(err) = syscall::task_set_audit(Some(task), true) {
+                self.write(&format!("\x1b[31m{}: failed to turn on auditing: {:?}\x1b[0m\n", name, err));
+            }
+        }
+
+        let child = Arc::new(Child { name: name.to_string(), stdin, stdout, stderr });
+        for stream in [Stream::Stdin, Stream::Stdout, Stream::Stderr] {
+            if child.channel(stream).is_none() {
+                continue;
+            }
+            let child = child.clone();
+            let console = self.console.clone();
+            let input_children = self.input_children.clone();
+            std::poplar::rt::spawn(async move { serve_child(child, stream, console, input_children).await });
+        }
+        let console = self.console.clone();
+        let input_children = self.input_children.clone();
+        std::poplar::rt::spawn(async move { wait_for_child(child, task, console, input_children).await });
+
+        Ok(())
+    }
+}
+
+/// Connect one of a launched task's standard channels to our own console. Text written to stderr is shown in red.
+/// Input can be asked for over any of them, and is always sent to the task's stdin (if that's connected to us).
+async fn serve_child(
+    child: Arc<Child>,
+    stream: Stream,
+    console: Arc<Channel<ConsoleRequest, ConsoleEvent>>,
+    input_children: Arc<Spinlock<Vec<Arc<Child>>>>,
+) {
+    loop {
+        let Ok(request) = child.channel(stream).unwrap().receive().await else {
+            warn!("Failed to receive message from {:?} of task '{}'", stream, child.name);
+            break;
+        };
+
+        match request {
+            ConsoleRequest::Write(text) if stream == Stream::Stderr => {
+                console::write(&console, &format!("\x1b[31m{}\x1b[0m", text)).unwrap();
+            }
+            ConsoleRequest::Write(text) => console.send(&ConsoleRequest::Write(text)).unwrap(),
+            ConsoleRequest::AttachInput if child.stdin.is_some() => {
+                let mut input_children = input_children.lock();
+                input_children.retain(|other| !Arc::ptr_eq(other, &child));
+                input_children.push(child.clone());
+            }
+            ConsoleRequest::AttachInput | ConsoleRequest::DetachInput => {
+                input_children.lock().retain(|other| !Arc::ptr_eq(other, &child));
+            }
+        }
+    }
+
+    input_children.lock().retain(|other| !Arc::ptr_eq(other, &child));
+}
+
+/// Wait for a launched task to exit. Input goes back to the shell (or to the previous task that asked for it), and
+/// the exit status is reported if the task failed.
+async fn wait_for_child(
+    child: Arc<Child>,
+    task: Handle,
+    console: Arc<Channel<ConsoleRequest, ConsoleEvent>>,
+    input_children: Arc<Spinlock<Vec<Arc<Child>>>>,
+) {
+    std::poplar::rt::select(&mut [WaitItem::new(task, Signals::TERMINATED)]).await;
+    input_children.lock().retain(|other| !Arc::ptr_eq(other, &child));
+
+    match syscall::task_wait(task, None) {
+        Ok(0) => (),
+        Ok(status) => {
+            console::write(&console, &format!("{}: exited with status {}\n", child.name, status)).unwrap();
+        }
+        Err(err) => warn!("Failed to get exit status of task '{}': {:?}", child.name, err),
+    }
+}
+
+fn main() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Shell is running!");
+
+    std::poplar::rt::init_runtime();
+
+    std::poplar::rt::spawn(async move {
+        let service_host = ServiceHostClient::new();
+        LOGGER.connect(service_host.subscribe_service(LOG_SERVICE).unwrap());
+        let console: Channel<ConsoleRequest, ConsoleEvent> =
+            service_host.subscribe_service(CONSOLE_SERVICE).unwrap();
+        let vfs = Vfs::new(service_host.subscribe_service(VFS_SERVICE).unwrap());
+        let log = service_host.subscribe_service(LOG_SERVICE).unwrap();
+
+        let mut shell = Shell {
+            console: Arc::new(console),
+            service_host,
+            vfs,
+            log,
+            line: LineEditor::new(),
+            input_children: Arc::new(Spinlock::new(Vec::new())),
+        };
+
+        shell.console.send(&ConsoleRequest::AttachInput).unwrap();
+        shell.write("Welcome to the Poplar shell! Type `help` for a list of commands.\n");
+        shell.write(PROMPT);
+
+        loop {
+            match shell.console.receive().await {
+                Ok(ConsoleEvent::Input(input)) => shell.handle_input(&input),
+                Err(err) => warn!("Failed to receive event from console: {:?}", err),
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
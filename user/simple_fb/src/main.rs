@@ -1,10 +1,10 @@
-use gfxconsole::Framebuffer;
+use gfxconsole::{Format, Framebuffer};
 use log::info;
 use std::{
     mem::MaybeUninit,
     poplar::{
         early_logger::EarlyLogger,
-        syscall::{self, FramebufferInfo, PixelFormat},
+        syscall::{self, FramebufferInfo, MapMemoryObjectFlags, PixelFormat},
         Handle,
     },
 };
@@ -48,18 +48,25 @@ fn make_framebuffer() -> Framebuffer {
     };
 
     unsafe {
-        syscall::map_memory_object(framebuffer_handle, Handle::ZERO, Some(FRAMEBUFFER_ADDRESS), 0x0 as *mut _)
-            .unwrap();
+        syscall::map_memory_object(
+            framebuffer_handle,
+            Handle::ZERO,
+            Some(FRAMEBUFFER_ADDRESS),
+            0x0 as *mut _,
+            MapMemoryObjectFlags::empty(),
+        )
+        .unwrap();
     }
-    assert_eq!(framebuffer_info.pixel_format, PixelFormat::Bgr32);
+    let format = match framebuffer_info.pixel_format {
+        PixelFormat::Rgb32 => Format::Rgb32,
+        PixelFormat::Bgr32 => Format::Bgr32,
+    };
 
     Framebuffer::new(
-        FRAMEBUFFER_ADDRESS as *mut u32,
+        FRAMEBUFFER_ADDRESS as *mut u8,
         framebuffer_info.width as usize,
         framebuffer_info.height as usize,
         framebuffer_info.stride as usize,
-        16,
-        8,
-        0,
+        format,
     )
 }
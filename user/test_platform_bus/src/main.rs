@@ -0,0 +1,83 @@
+//! Tests of the Platform Bus's device handoff protocol. This registers a fake device with the Platform Bus as a
+//! bus driver, and then claims it as a device driver, checking the Platform Bus queries whether we support the
+//! device before handing it off to us.
+
+use platform_bus::{
+    BusDriverClient,
+    DeviceDriverClient,
+    DeviceHandoffCall,
+    DeviceHandoffReply,
+    DeviceHandoffRequest,
+    DeviceHandoffServer,
+    DeviceInfo,
+    Filter,
+    HandoffInfo,
+    HandoffProperty,
+    Property,
+};
+use service_host::ServiceHostClient;
+use std::{collections::BTreeMap, poplar::channel::Channel};
+use test_harness::{expect_message, Direction, MessageKind, Tap, DEFAULT_TIMEOUT};
+
+const DEVICE_NAME: &str = "test_platform_bus.device";
+/// Only our fake device has this property, so no other device driver should be offered it, and we shouldn't be
+/// offered any other devices.
+const DEVICE_PROPERTY: &str = "test_platform_bus.fake";
+const CHANNEL_PROPERTY: &str = "test_platform_bus.channel";
+
+fn main() {
+    let service_host = ServiceHostClient::new();
+    let bus_driver = BusDriverClient::new(service_host.subscribe_service("platform_bus.bus_driver").unwrap());
+    let device_driver =
+        DeviceDriverClient::new(service_host.subscribe_service("platform_bus.device_driver").unwrap());
+
+    let (handoff, handoff_handle) = Channel::<DeviceHandoffReply, DeviceHandoffRequest>::create().unwrap();
+    let (tap, tapped_handle) = Tap::insert(handoff_handle);
+    let handoff = DeviceHandoffServer::new(handoff);
+    device_driver
+        .register_interest(vec![Filter::Matches(DEVICE_PROPERTY.to_string(), Property::Bool(true))], tapped_handle)
+        .unwrap();
+
+    // The device is handed off with one end of a channel, to check handles make it through the Platform Bus
+    let (device_channel, device_channel_handle) = Channel::<u64, u64>::create().unwrap();
+    let device_info = DeviceInfo(BTreeMap::from([(DEVICE_PROPERTY.to_string(), Property::Bool(true))]));
+    let handoff_info = HandoffInfo(BTreeMap::from([(
+        CHANNEL_PROPERTY.to_string(),
+        HandoffProperty::Channel(device_channel_handle),
+    )]));
+    bus_driver.register_device(DEVICE_NAME.to_string(), device_info, handoff_info).unwrap();
+
+    tap.wait_for(1, DEFAULT_TIMEOUT);
+    match handoff.try_next().unwrap() {
+        Some(DeviceHandoffCall::QuerySupport { name, device_info, responder }) => {
+            assert_eq!(name, DEVICE_NAME);
+            assert_eq!(device_info.get_as_bool(DEVICE_PROPERTY), Some(true));
+            responder.reply(true).unwrap();
+        }
+        other => panic!("Expected the Platform Bus to query support for the device, but got: {:?}", other),
+    }
+
+    // Wait for our reply to be passed back, and for the Platform Bus to hand the device off in return
+    tap.wait_for(3, DEFAULT_TIMEOUT);
+    let handed_off_channel = match handoff.try_next().unwrap() {
+        Some(DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info }) => {
+            assert_eq!(name, DEVICE_NAME);
+            assert_eq!(device_info.get_as_bool(DEVICE_PROPERTY), Some(true));
+            handoff_info.get_as_channel(CHANNEL_PROPERTY).expect("Device was handed off without its channel")
+        }
+        other => panic!("Expected the Platform Bus to hand off the device, but got: {:?}", other),
+    };
+    let handed_off_channel = Channel::<u64, u64>::new_from_handle(handed_off_channel);
+    handed_off_channel.send(&0xfeed).unwrap();
+    assert_eq!(expect_message(&device_channel, DEFAULT_TIMEOUT), 0xfeed);
+
+    let transcript = tap.finish();
+    assert_eq!(transcript.len(), 3, "Unexpected messages from the Platform Bus: {:#?}", transcript);
+    assert_eq!((transcript[0].direction, transcript[0].kind), (Direction::Sent, MessageKind::Call));
+    assert!(matches!(transcript[0].decode::<DeviceHandoffRequest>(), DeviceHandoffRequest::QuerySupport(..)));
+    assert_eq!((transcript[1].direction, transcript[1].kind), (Direction::Received, MessageKind::Reply));
+    assert!(matches!(transcript[1].decode::<DeviceHandoffReply>(), DeviceHandoffReply::QuerySupport(true)));
+    assert_eq!((transcript[2].direction, transcript[2].kind), (Direction::Sent, MessageKind::Message));
+    assert!(matches!(transcript[2].decode::<DeviceHandoffRequest>(), DeviceHandoffRequest::HandoffDevice(..)));
+    assert_eq!(transcript[2].handles.len(), 1);
+}
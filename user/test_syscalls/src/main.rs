@@ -0,0 +1,399 @@
+//! Tests of the system calls for creating and using kernel objects. Like every task named `test_*`, this is run by
+//! `cargo xtask test`, and passes if it exits with a status of `0` - any failed assertion panics, which exits the
+//! task with a status of `101`.
+
+use std::{
+    poplar::{
+        channel::Channel,
+        manifest::TaskManifest,
+        memory_object::MemoryObject,
+        pipe,
+        syscall::{
+            self,
+            ChannelCallError,
+            CloneMemoryObjectError,
+            CreateMemoryObjectError,
+            GetMessageError,
+            MapMemoryObjectError,
+            MemoryObjectFlags,
+            PipeReadError,
+            PipeWriteError,
+            ProtectMemoryObjectError,
+            SendMessageError,
+            Signals,
+            TaskInfo,
+            TaskInfoState,
+            ThreadCreateError,
+            WaitItem,
+            PIPE_CAPACITY,
+        },
+        vdso,
+    },
+    time::{Duration, Instant},
+};
+
+fn main() {
+    run("memory_objects", memory_objects);
+    run("mappings", mappings);
+    run("writable_executable", writable_executable);
+    run("channels", channels);
+    run("pipes", pipes);
+    run("threads", threads);
+    run("sleep", sleep);
+    run("stack_growth", stack_growth);
+    run("kernel_memory_stats", kernel_memory_stats);
+    run("task_info", task_info);
+    run("discardable_memory", discardable_memory);
+    run("manifest", manifest);
+    run("vdso", vdso);
+    #[cfg(target_arch = "x86_64")]
+    run("fpu_state", fpu_state);
+}
+
+fn run(name: &str, test: fn()) {
+    syscall::early_log(&format!("test_syscalls: running {}", name)).unwrap();
+    test();
+}
+
+fn memory_objects() {
+    const SIZE: usize = 0x4000;
+
+    let object = MemoryObject::create_lazy(SIZE, MemoryObjectFlags::WRITABLE).unwrap();
+    let mapped = unsafe { object.map().unwrap() };
+    let memory = unsafe { std::slice::from_raw_parts_mut(mapped.ptr() as *mut u8, SIZE) };
+    assert!(memory.iter().all(|&byte| byte == 0), "lazily-committed memory was not zeroed");
+    memory.fill(0x5a);
+
+    let clone = mapped.inner.clone_cow().unwrap();
+    let mapped_clone = unsafe { clone.map().unwrap() };
+    let clone_memory = unsafe { std::slice::from_raw_parts_mut(mapped_clone.ptr() as *mut u8, SIZE) };
+    assert!(clone_memory.iter().all(|&byte| byte == 0x5a), "clone does not have the original's contents");
+    clone_memory[0] = 0xff;
+    assert_eq!(memory[0], 0x5a, "writing to a copy-on-write clone changed the original");
+}
+
+fn mappings() {
+    const SIZE: usize = 0x4000;
+
+    // The kernel should pick a different free address for each mapping
+    let a = unsafe { MemoryObject::create(SIZE, MemoryObjectFlags::WRITABLE).unwrap().map().unwrap() };
+    let b = unsafe { MemoryObject::create(SIZE, MemoryObjectFlags::empty()).unwrap().map().unwrap() };
+    assert!(a.mapped_at + SIZE <= b.mapped_at || b.mapped_at + SIZE <= a.mapped_at, "mappings overlap");
+
+    // A mapping can be made read-only and then writable again, but never more permissive than it was mapped
+    unsafe {
+        a.protect(MemoryObjectFlags::empty()).unwrap();
+        a.protect(MemoryObjectFlags::WRITABLE).unwrap();
+        assert!(matches!(b.protect(MemoryObjectFlags::WRITABLE), Err(ProtectMemoryObjectError::NotPermitted)));
+    }
+    unsafe { (a.ptr() as *mut u8).write(0x5a) };
+
+    // Once unmapped, the object can be mapped again, and should still hold what was written to it
+    let object = unsafe { a.unmap().unwrap() };
+    let a = unsafe { object.map().unwrap() };
+    assert_eq!(unsafe { a.ptr().read() }, 0x5a, "memory object lost its contents when it was remapped");
+
+    // Memory can't be mapped into the kernel's part of the address space, or at a non-canonical address
+    for address in [0xffff_ffff_8000_0000, 0x8000_0000_0000_0000] {
+        let object = unsafe { MemoryObject::create(SIZE, MemoryObjectFlags::WRITABLE).unwrap() };
+        let result = unsafe { object.map_at(address) };
+        assert!(matches!(result, Err(MapMemoryObjectError::AddressNotInUserSpace)));
+    }
+}
+
+fn writable_executable() {
+    const SIZE: usize = 0x1000;
+    let flags = MemoryObjectFlags::WRITABLE | MemoryObjectFlags::EXECUTABLE;
+
+    // Without the `WRITABLE_EXECUTABLE_MEMORY` capability, memory can't be both writable and executable
+    let result = unsafe { MemoryObject::create(SIZE, flags) };
+    assert!(matches!(result, Err(CreateMemoryObjectError::WritableAndExecutable)));
+
+    // A mapping can never be made writable and executable at the same time
+    let mapped = unsafe { MemoryObject::create(SIZE, MemoryObjectFlags::WRITABLE).unwrap().map().unwrap() };
+    assert!(matches!(unsafe { mapped.protect(flags) }, Err(ProtectMemoryObjectError::WritableAndExecutable)));
+}
+
+fn channels() {
+    let (a, b) = Channel::<u64, u64>::create().unwrap();
+    let b = Channel::<u64, u64>::new_from_handle(b);
+
+    assert_eq!(a.try_receive().unwrap(), None);
+    for i in 0..16 {
+        a.send(&i).unwrap();
+    }
+    // Messages should arrive in the order they were sent
+    for i in 0..16 {
+        assert_eq!(b.receive_blocking().unwrap(), i);
+    }
+
+    b.send(&42).unwrap();
+    assert_eq!(a.receive_blocking().unwrap(), 42);
+
+    // A message that transfers the same handle twice is rejected, and the sender keeps the handle
+    let (c, _d) = syscall::create_channel().unwrap();
+    let object = unsafe { MemoryObject::create(0x1000, MemoryObjectFlags::empty()).unwrap() };
+    let result = syscall::send_message(c, &[], &[object.handle, object.handle]);
+    assert!(matches!(result, Err(SendMessageError::InvalidTransferredHandle)));
+    syscall::send_message(c, &[], &[object.handle]).unwrap();
+
+    // The reply to a call that has been cancelled is discarded
+    let (e, f) = syscall::create_channel().unwrap();
+    let txid = syscall::channel_call_no_wait(e, b"ping", &[]).unwrap();
+    let mut bytes = [0u8; 4];
+    let (_, _, received_txid) = syscall::get_message(f, &mut bytes, &mut []).unwrap();
+    assert_eq!(received_txid, txid);
+    syscall::channel_cancel_call(e, txid).unwrap();
+    syscall::send_reply(f, txid, b"pong", &[]).unwrap();
+    assert!(matches!(syscall::get_reply(e, txid, &mut bytes, &mut []), Err(GetMessageError::NoMessage)));
+
+    // A call that times out is abandoned, rather than left in progress
+    let mut txid = 0;
+    let result =
+        syscall::channel_call(e, b"ping", &[], &mut [], &mut [], Some(Duration::from_millis(1)), &mut txid);
+    assert!(matches!(result, Err(ChannelCallError::TimedOut)));
+    let (_, _, received_txid) = syscall::get_message(f, &mut bytes, &mut []).unwrap();
+    syscall::send_reply(f, received_txid, b"pong", &[]).unwrap();
+    assert!(matches!(syscall::get_reply(e, txid, &mut bytes, &mut []), Err(GetMessageError::NoMessage)));
+}
+
+fn pipes() {
+    let (reader, writer) = pipe::pipe().unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(reader.try_read(&mut buffer).unwrap(), None);
+    // Each end can only be used in one direction
+    assert_eq!(syscall::pipe_write(reader.handle(), b"x", false), Err(PipeWriteError::PipeCannotBeWritten));
+    assert_eq!(syscall::pipe_read(writer.handle(), &mut buffer, false), Err(PipeReadError::PipeCannotBeRead));
+
+    // Writes aren't kept separate, so both arrive in one read
+    writer.write_all_blocking(b"Hello, ").unwrap();
+    writer.write_all_blocking(b"pipe!").unwrap();
+    let mut items = [WaitItem::new(reader.handle(), Signals::READABLE)];
+    syscall::object_wait_many(&mut items, false, None).unwrap();
+    assert_eq!(reader.read_blocking(&mut buffer).unwrap(), 12);
+    assert_eq!(&buffer[0..12], b"Hello, pipe!");
+
+    // A full pipe takes no more data until it's read from
+    let data = vec![0xa5; PIPE_CAPACITY + 1];
+    assert_eq!(writer.try_write(&data).unwrap(), Some(PIPE_CAPACITY));
+    assert_eq!(writer.try_write(&data).unwrap(), None);
+    let mut items = [WaitItem::new(writer.handle(), Signals::WRITABLE)];
+    assert!(syscall::object_wait_many(&mut items, false, None).is_err());
+
+    // A writer blocked on a full pipe finishes once a reader on another thread drains it
+    let thread = std::thread::spawn(move || {
+        let mut buffer = vec![0; PIPE_CAPACITY];
+        let mut total = 0;
+        loop {
+            match reader.read_blocking(&mut buffer).unwrap() {
+                0 => break total,
+                length => total += length,
+            }
+        }
+    });
+    writer.write_all_blocking(&data).unwrap();
+    // Closing the write end ends the stream, once everything written to it has been read
+    drop(writer);
+    assert_eq!(thread.join().unwrap(), 2 * PIPE_CAPACITY + 1);
+
+    // Writing to a pipe with no reader fails
+    let (reader, writer) = pipe::pipe().unwrap();
+    drop(reader);
+    assert_eq!(writer.try_write(b"x"), Err(PipeWriteError::ReadEndClosed));
+}
+
+fn threads() {
+    let (channel, other_end) = Channel::<u64, u64>::create().unwrap();
+    let thread = std::thread::spawn(move || {
+        let channel = Channel::<u64, u64>::new_from_handle(other_end);
+        let value = channel.receive_blocking().unwrap();
+        channel.send(&(value * 2)).unwrap();
+        value
+    });
+
+    channel.send(&21).unwrap();
+    assert_eq!(channel.receive_blocking().unwrap(), 42);
+    assert_eq!(thread.join().unwrap(), 21);
+
+    // Threads can't be started in the kernel's part of the address space
+    assert!(matches!(syscall::thread_create(usize::MAX, 0), Err(ThreadCreateError::InvalidEntryPoint)));
+}
+
+fn sleep() {
+    let start = Instant::now();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(start.elapsed() >= Duration::from_millis(20), "woke up before the sleep had finished");
+}
+
+fn stack_growth() {
+    // Tasks start with a much smaller stack than this, so it only fits if the stack grows
+    let buffer = std::hint::black_box([0x5au8; 0x40000]);
+    assert!(buffer.iter().all(|&byte| byte == 0x5a), "stack memory was corrupted");
+}
+
+fn kernel_memory_stats() {
+    let channels_in_use = || {
+        let stats = syscall::get_kernel_memory_stats().unwrap();
+        let caches = &stats.object_caches[0..stats.num_object_caches];
+        caches.iter().find(|cache| cache.name() == "channel").expect("no cache for channels").objects_in_use
+    };
+
+    let stats = syscall::get_kernel_memory_stats().unwrap();
+    assert!(stats.physical_free <= stats.physical_total);
+    assert!(stats.heap_used <= stats.heap_size);
+    let free_in_blocks: usize =
+        stats.free_blocks.iter().enumerate().map(|(order, &count)| count * (0x1000 << order)).sum();
+    assert_eq!(free_in_blocks, stats.physical_free, "free blocks don't add up to the free physical memory");
+
+    // Each channel is made up of two ends, which should be allocated from the channel cache
+    let before = channels_in_use();
+    let _channels: Vec<_> = (0..4).map(|_| Channel::<u64, u64>::create().unwrap()).collect();
+    assert!(channels_in_use() >= before + 8, "channels were not allocated from the channel cache");
+}
+
+fn task_info() {
+    const SIZE: usize = 0x4000;
+
+    let this_task = || {
+        let mut tasks = [TaskInfo::default(); 128];
+        let num_tasks = syscall::task_info(&mut tasks).unwrap();
+        assert!(num_tasks <= tasks.len(), "too many tasks to fit in the buffer");
+        tasks[0..num_tasks]
+            .iter()
+            .find(|task| task.name() == "test_syscalls" && task.state == TaskInfoState::Running)
+            .copied()
+            .expect("task_info did not describe the calling task")
+    };
+
+    let before = this_task();
+    let _channel = Channel::<u64, u64>::create().unwrap();
+    let object = MemoryObject::create_lazy(SIZE, MemoryObjectFlags::WRITABLE).unwrap();
+    let mapped = unsafe { object.map().unwrap() };
+    unsafe {
+        (mapped.ptr() as *mut u8).write_volatile(0x5a);
+    }
+
+    let after = this_task();
+    assert_eq!(after.id, before.id);
+    assert!(after.num_handles >= before.num_handles + 3, "new handles were not counted");
+    assert!(after.memory.reserved >= before.memory.reserved + SIZE, "new mapping was not counted");
+    assert!(after.memory.committed > before.memory.committed, "newly-committed memory was not counted");
+}
+
+fn discardable_memory() {
+    const SIZE: usize = 0x4000;
+    let flags = MemoryObjectFlags::WRITABLE | MemoryObjectFlags::DISCARDABLE;
+
+    // Only lazy objects can be discardable
+    let result = unsafe { MemoryObject::create(SIZE, flags) };
+    assert!(matches!(result, Err(CreateMemoryObjectError::InvalidFlags)));
+
+    let object = MemoryObject::create_discardable(SIZE, MemoryObjectFlags::WRITABLE).unwrap();
+    assert!(matches!(object.clone_cow(), Err(CloneMemoryObjectError::MemoryObjectIsDiscardable)));
+    let handle = object.handle;
+    let mapped = unsafe { object.map().unwrap() };
+    unsafe { (mapped.ptr() as *mut u8).write_volatile(0x5a) };
+
+    // Discarding a page only unmaps it from one address space, so the object can't be mapped again
+    let again = unsafe { MemoryObject::from_handle(handle, SIZE, flags).map() };
+    assert!(matches!(again, Err(MapMemoryObjectError::CopyOnWriteAlreadyMapped)));
+
+    // Nothing can have been discarded while the object wasn't discardable
+    assert!(!mapped.inner.set_discardable(true).unwrap());
+
+    /*
+     * The memory is only discarded if the system runs out of memory while it's discardable, which we can't cause
+     * here. If it has been, it reads as zero.
+     */
+    let discarded = mapped.inner.set_discardable(false).unwrap();
+    let value = unsafe { (mapped.ptr() as *const u8).read_volatile() };
+    assert_eq!(value, if discarded { 0 } else { 0x5a });
+
+    // Any task can subscribe to memory pressure, and each subscriber gets its own event
+    let a = syscall::subscribe_memory_pressure().unwrap();
+    let b = syscall::subscribe_memory_pressure().unwrap();
+    assert_ne!(a, b);
+}
+
+fn manifest() {
+    // Boot tasks are spawned with `spawn_task`, which gives them a manifest with just their name and handles
+    let manifest = unsafe { TaskManifest::read() };
+    assert_eq!(manifest.task_name, "test_syscalls");
+    assert!(manifest.arguments.is_empty());
+    assert_eq!(manifest.handles.len(), 1, "the only handle should be the channel to service_host");
+    assert!(manifest.stdio.stdin.is_none() && manifest.stdio.stdout.is_none() && manifest.stdio.stderr.is_none());
+    assert_eq!(std::env::args().collect::<Vec<_>>(), ["test_syscalls"]);
+
+    // Without a stdout, printing falls back to `early_log`
+    println!("test_syscalls: printed without a stdout");
+}
+
+fn vdso() {
+    // The vDSO page should agree with the system calls it saves us from making
+    let before = syscall::clock_monotonic();
+    let now = vdso::clock_monotonic().expect("the monotonic clock is not available from the vDSO page");
+    let after = syscall::clock_monotonic();
+    assert!(before <= now && now <= after, "the vDSO page's monotonic clock disagrees with the kernel's");
+
+    // The wall-clock time is published once the kernel has read the RTC, which `get_time` makes it do
+    if let Ok(before) = syscall::get_time() {
+        let now = vdso::clock_realtime().expect("the wall-clock time is not available from the vDSO page");
+        let after = syscall::get_time().unwrap();
+        assert!(before <= now && now <= after, "the vDSO page's wall-clock time disagrees with the kernel's");
+    }
+
+    assert!(vdso::cpu_count().is_some_and(|count| count >= 1), "the vDSO page does not have the CPU count");
+    assert!(std::thread::available_parallelism().is_ok());
+
+    // The page is already mapped, so nothing else can be mapped over it
+    let object = unsafe { MemoryObject::create(0x1000, MemoryObjectFlags::WRITABLE).unwrap() };
+    let result = unsafe { object.map_at(vdso::VDSO_ADDRESS) };
+    assert!(matches!(result, Err(MapMemoryObjectError::RegionAlreadyMapped)));
+}
+
+#[cfg(target_arch = "x86_64")]
+fn fpu_state() {
+    /*
+     * Put a value in `xmm0`, and check it's still there after yielding to the kernel a few times. This is done in
+     * assembly, so the compiler can't spill the register across the system calls. With two threads doing this at
+     * once, the kernel switches between them, and each would see the other's value if it didn't save their
+     * registers.
+     */
+    fn xmm0_survives_yields(value: u64) -> bool {
+        let result: u64;
+        unsafe {
+            std::arch::asm!(
+                "movq xmm0, {value}",
+                "2:",
+                "mov rdi, {yield_number}",
+                "syscall",
+                "dec r12",
+                "jnz 2b",
+                "movq {result}, xmm0",
+                value = in(reg) value,
+                yield_number = const syscall::SYSCALL_YIELD,
+                result = out(reg) result,
+                inout("r12") 16u64 => _,
+                out("xmm0") _,
+                out("rax") _,
+                out("rdi") _,
+                out("rcx") _,
+                out("r11") _,
+                out("rdx") _,
+                out("rsi") _,
+                out("r8") _,
+                out("r9") _,
+                out("r10") _,
+            );
+        }
+        result == value
+    }
+
+    let thread = std::thread::spawn(|| xmm0_survives_yields(0x1111_2222_3333_4444));
+    assert!(xmm0_survives_yields(0x5555_6666_7777_8888), "xmm0 was changed while another thread was running");
+    assert!(thread.join().unwrap(), "xmm0 was changed while another thread was running");
+
+    // Check the compiler's use of floating point works too, now it's allowed to use SSE
+    let values = std::hint::black_box([1.5f64, 2.25, 3.125]);
+    assert_eq!(values.iter().sum::<f64>(), 6.875);
+}
@@ -0,0 +1,296 @@
+//! Tests of the VFS's side of the filesystem protocol (see `std::poplar::file`). This mounts a fake filesystem,
+//! which it serves itself, and checks the requests the VFS passes on to it when files on it are used through the
+//! `vfs` service. Once a file's data has been read, it should be served from the VFS's page cache, and data
+//! written to the file should be written back when it's closed.
+//!
+//! It then creates a namespace that only contains a directory on the fake filesystem, and checks that nothing
+//! outside of that directory can be reached through it, even by following a symlink.
+//!
+//! Finally, it mounts a second fake filesystem, which holds its files in memory, and checks that `std::fs` works
+//! on top of the VFS.
+
+use service_host::ServiceHostClient;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+    poplar::{
+        channel::Channel,
+        file::{
+            Binding,
+            DirEntry,
+            FileClientError,
+            FileError,
+            FileId,
+            FileKind,
+            FileRequest,
+            FileResponse,
+            FileStat,
+            FilesystemMessage,
+            FilesystemResponse,
+            OpenOptions,
+            Vfs,
+            MAX_DIR_ENTRIES,
+            MAX_TRANSFER_SIZE,
+            VFS_FILESYSTEM_SERVICE,
+            VFS_SERVICE,
+        },
+    },
+    time::Instant,
+};
+use test_harness::{expect_call, expect_message, Direction, MessageKind, Tap, DEFAULT_TIMEOUT};
+
+const MOUNT_POINT: &str = "/test_vfs";
+const CONTENTS: &[u8] = b"Hello from a fake filesystem";
+/// The ID our filesystem gives the file it opens. The VFS should give the client its own ID for the file.
+const FILE_ID: FileId = FileId(0x5a);
+const INODE: u64 = 0x17;
+const STAT: FileStat =
+    FileStat { kind: FileKind::File, size: CONTENTS.len() as u64, inode: INODE, permissions: 0o644 };
+const DIR_STAT: FileStat = FileStat { kind: FileKind::Directory, size: 0, inode: 0x18, permissions: 0o755 };
+const STD_MOUNT_POINT: &str = "/test_std";
+
+fn main() {
+    let service_host = ServiceHostClient::new();
+    let filesystem_service: Channel<FilesystemMessage, FilesystemResponse> =
+        service_host.subscribe_service(VFS_FILESYSTEM_SERVICE).unwrap();
+    let vfs = Vfs::new(service_host.subscribe_service(VFS_SERVICE).unwrap());
+
+    let (filesystem, filesystem_handle) = Channel::<FileResponse, FileRequest>::create().unwrap();
+    let (tap, tapped_handle) = Tap::insert(filesystem_handle);
+    filesystem_service
+        .send(&FilesystemMessage::Mount { path: MOUNT_POINT.to_string(), channel: tapped_handle })
+        .unwrap();
+    assert_eq!(expect_message(&filesystem_service, DEFAULT_TIMEOUT), FilesystemResponse::Mounted);
+
+    // The VFS waits for our filesystem to reply to each request, so the client needs its own thread
+    let client = std::thread::spawn(move || {
+        let path = format!("{}/greeting", MOUNT_POINT);
+        let mut file = vfs.open(&path, OpenOptions::read()).unwrap();
+        let contents = file.read_to_end().unwrap();
+        drop(file);
+        let stat = vfs.stat(&path).unwrap();
+
+        // The file is now cached, so reading it again shouldn't need to read from the filesystem
+        let mut file = vfs.open(&path, OpenOptions::read()).unwrap();
+        let cached_contents = file.read_to_end().unwrap();
+        drop(file);
+
+        let mut file = vfs.open(&path, OpenOptions::write()).unwrap();
+        file.seek(CONTENTS.len() as u64);
+        file.write(b"!").unwrap();
+        drop(file);
+
+        /*
+         * `/data` in the namespace is `/test_vfs/dir`, and `/data/escape` is a symlink that tries to lead out of
+         * it, to `/etc`. The namespace doesn't have an `/etc`, and following the symlink shouldn't leave it.
+         */
+        let namespace =
+            vfs.create_namespace(vec![Binding::new("/data", &format!("{}/dir", MOUNT_POINT))]).unwrap();
+        let namespace = Vfs::new(Channel::new_from_handle(namespace));
+        let dir_stat = namespace.stat("/data").unwrap();
+        let escaped = namespace.stat("/data/escape/passwd");
+        let outside = namespace.stat(&path);
+        let root = namespace.read_dir("/").unwrap();
+
+        (contents, stat, cached_contents, dir_stat, escaped, outside, root)
+    });
+
+    /*
+     * The size of the file is known once it's opened, so the VFS can tell the first read reaches the end of the
+     * file. Writing back the file writes the whole page that was written to.
+     */
+    let mut written = CONTENTS.to_vec();
+    written.push(b'!');
+    let expected_requests = [
+        FileRequest::Open { path: "/greeting".to_string(), options: OpenOptions::read() },
+        FileRequest::Read { file: FILE_ID, offset: 0, length: MAX_TRANSFER_SIZE as u32 },
+        FileRequest::Close { file: FILE_ID },
+        FileRequest::Stat { path: "/greeting".to_string() },
+        FileRequest::Open { path: "/greeting".to_string(), options: OpenOptions::read() },
+        FileRequest::Close { file: FILE_ID },
+        FileRequest::Open { path: "/greeting".to_string(), options: OpenOptions::write() },
+        FileRequest::Write { file: FILE_ID, offset: 0, data: written },
+        FileRequest::Close { file: FILE_ID },
+        FileRequest::Stat { path: "/dir".to_string() },
+        FileRequest::Stat { path: "/dir".to_string() },
+        FileRequest::Stat { path: "/dir/escape/passwd".to_string() },
+    ];
+    let responses = [
+        FileResponse::Opened(FILE_ID, STAT),
+        FileResponse::Data(CONTENTS.to_vec()),
+        FileResponse::Done,
+        FileResponse::Stat(STAT),
+        FileResponse::Opened(FILE_ID, STAT),
+        FileResponse::Done,
+        FileResponse::Opened(FILE_ID, STAT),
+        FileResponse::Written(CONTENTS.len() as u32 + 1),
+        FileResponse::Done,
+        FileResponse::Stat(DIR_STAT),
+        FileResponse::Stat(DIR_STAT),
+        FileResponse::Symlink { depth: 2, target: "../../../etc".to_string() },
+    ];
+    for (expected, response) in expected_requests.iter().zip(&responses) {
+        let (request, call) = expect_call(&filesystem, DEFAULT_TIMEOUT);
+        assert_eq!(&request, expected);
+        filesystem.reply(call, response).unwrap();
+    }
+
+    let (contents, stat, cached_contents, dir_stat, escaped, outside, root) = client.join().unwrap();
+    assert_eq!(contents, CONTENTS);
+    assert_eq!(stat, STAT);
+    assert_eq!(cached_contents, CONTENTS);
+    assert_eq!(dir_stat, DIR_STAT);
+    assert!(matches!(escaped, Err(FileClientError::File(FileError::NotFound))));
+    assert!(matches!(outside, Err(FileClientError::File(FileError::NotFound))));
+    assert_eq!(root, [DirEntry { name: "data".to_string(), kind: FileKind::Directory }]);
+
+    // Each request should have been made as a call, which we replied to
+    let transcript = tap.finish();
+    assert_eq!(transcript.len(), 2 * expected_requests.len());
+    for (i, (expected, response)) in expected_requests.iter().zip(&responses).enumerate() {
+        let (request_message, response_message) = (&transcript[2 * i], &transcript[2 * i + 1]);
+        assert_eq!((request_message.direction, request_message.kind), (Direction::Sent, MessageKind::Call));
+        assert_eq!(&request_message.decode::<FileRequest>(), expected);
+        assert_eq!((response_message.direction, response_message.kind), (Direction::Received, MessageKind::Reply));
+        assert_eq!(&response_message.decode::<FileResponse>(), response);
+    }
+
+    std_fs(&service_host, &filesystem_service);
+}
+
+/// Check that `std::fs` works on top of the VFS, using it to access files on a fake filesystem that we serve
+/// ourselves. Unlike the fake filesystem above, this one holds files in memory, and we only check what `std::fs`
+/// returns, rather than each request the VFS makes.
+fn std_fs(service_host: &ServiceHostClient, filesystem_service: &Channel<FilesystemMessage, FilesystemResponse>) {
+    let (filesystem, filesystem_handle) = Channel::<FileResponse, FileRequest>::create().unwrap();
+    filesystem_service
+        .send(&FilesystemMessage::Mount { path: STD_MOUNT_POINT.to_string(), channel: filesystem_handle })
+        .unwrap();
+    assert_eq!(expect_message(filesystem_service, DEFAULT_TIMEOUT), FilesystemResponse::Mounted);
+
+    // Boot tasks aren't spawned with a namespace, so we give `std::fs` one that sees everything
+    std::poplar::file::set_namespace(Vfs::new(service_host.subscribe_service(VFS_SERVICE).unwrap()));
+
+    let client = std::thread::spawn(|| {
+        let path = Path::new(STD_MOUNT_POINT).join("notes.txt");
+        assert_eq!(path.to_str(), Some("/test_std/notes.txt"));
+        assert_eq!(path.extension().and_then(|extension| extension.to_str()), Some("txt"));
+
+        fs::write(&path, "first line\nsecond line\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first line\nsecond line\n");
+        let lines: Vec<String> = BufReader::new(File::open(&path).unwrap()).lines().map(Result::unwrap).collect();
+        assert_eq!(lines, ["first line", "second line"]);
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"third line\n").unwrap();
+        drop(file);
+
+        // Relative paths are resolved from the root of the namespace
+        let contents = fs::read_to_string("test_std/notes.txt").unwrap();
+        assert_eq!(contents, "first line\nsecond line\nthird line\n");
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(file.seek(SeekFrom::End(-5)).unwrap(), contents.len() as u64 - 5);
+        let mut tail = String::new();
+        file.read_to_string(&mut tail).unwrap();
+        assert_eq!(tail, "line\n");
+        drop(file);
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), contents.len() as u64);
+        assert!(Path::new(STD_MOUNT_POINT).is_dir());
+        let names: Vec<_> =
+            fs::read_dir(STD_MOUNT_POINT).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["notes.txt"]);
+
+        assert_eq!(File::open(path.with_file_name("missing")).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(File::create_new(&path).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    });
+
+    // The client fails the test by panicking, so we only need to serve it until it's finished
+    let mut fake = MemoryFilesystem::default();
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    while !client.is_finished() {
+        assert!(Instant::now() < deadline, "Timed out waiting for std::fs client to finish");
+        match filesystem.try_receive_call().unwrap() {
+            Some((request, call)) => filesystem.reply(call, &fake.handle_request(request)).unwrap(),
+            None => std::thread::yield_now(),
+        }
+    }
+    client.join().unwrap();
+}
+
+/// A filesystem with a single directory, which holds the data of its files in memory.
+#[derive(Default)]
+struct MemoryFilesystem {
+    /// The inode and data of each file, by name.
+    files: BTreeMap<String, (u64, Vec<u8>)>,
+    open_files: BTreeMap<FileId, String>,
+    next_id: u64,
+}
+
+impl MemoryFilesystem {
+    fn handle_request(&mut self, request: FileRequest) -> FileResponse {
+        match request {
+            FileRequest::Open { path, options } => {
+                let name = path.trim_start_matches('/').to_string();
+                if !self.files.contains_key(&name) {
+                    if !(options.write && options.create) {
+                        return FileResponse::Error(FileError::NotFound);
+                    }
+                    self.files.insert(name.clone(), (0x100 + self.files.len() as u64, Vec::new()));
+                }
+                if options.write && options.truncate {
+                    self.files.get_mut(&name).unwrap().1.clear();
+                }
+                let id = FileId(self.next_id);
+                self.next_id += 1;
+                let stat = self.stat(&name).unwrap();
+                self.open_files.insert(id, name);
+                FileResponse::Opened(id, stat)
+            }
+            FileRequest::Read { file, offset, length } => {
+                let data = &self.files[&self.open_files[&file]].1;
+                let start = usize::min(offset as usize, data.len());
+                let end = usize::min(start + length as usize, data.len());
+                FileResponse::Data(data[start..end].to_vec())
+            }
+            FileRequest::Write { file, offset, data: written } => {
+                let data = &mut self.files.get_mut(&self.open_files[&file]).unwrap().1;
+                let (start, end) = (offset as usize, offset as usize + written.len());
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[start..end].copy_from_slice(&written);
+                FileResponse::Written(written.len() as u32)
+            }
+            FileRequest::Stat { path } => match path.trim_start_matches('/') {
+                "" => FileResponse::Stat(DIR_STAT),
+                name => {
+                    self.stat(name).map(FileResponse::Stat).unwrap_or(FileResponse::Error(FileError::NotFound))
+                }
+            },
+            FileRequest::ReadDir { start, .. } => FileResponse::Entries(
+                self.files
+                    .keys()
+                    .skip(start as usize)
+                    .take(MAX_DIR_ENTRIES)
+                    .map(|name| DirEntry { name: name.clone(), kind: FileKind::File })
+                    .collect(),
+            ),
+            FileRequest::Close { file } => {
+                self.open_files.remove(&file);
+                FileResponse::Done
+            }
+            _ => FileResponse::Error(FileError::Unsupported),
+        }
+    }
+
+    fn stat(&self, name: &str) -> Option<FileStat> {
+        let (inode, data) = self.files.get(name)?;
+        Some(FileStat { kind: FileKind::File, size: data.len() as u64, inode: *inode, permissions: 0o644 })
+    }
+}
@@ -0,0 +1,308 @@
+//! `tmpfs` serves an in-memory filesystem through the VFS, mounted at `/tmp`. It's started by `service_manager`,
+//! and gives tasks scratch space that doesn't need a disk. The data of each file is held in chunks, which are
+//! each their own `MemoryObject` and are only allocated once part of them is written to. Everything on the
+//! filesystem is lost when `tmpfs` exits.
+//!
+//! The filesystem protocol doesn't have a way to create directories, so every file is in the root directory.
+
+use log::info;
+use service_host::ServiceHostClient;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    mem,
+    ops::Range,
+    poplar::{
+        channel::Channel,
+        early_logger::EarlyLogger,
+        file::{
+            self,
+            path_components,
+            FileError,
+            FileId,
+            FileKind,
+            FileRequest,
+            FileResponse,
+            FileStat,
+            FilesystemMessage,
+            FilesystemResponse,
+            OpenOptions,
+            MAX_DIR_ENTRIES,
+            MAX_TRANSFER_SIZE,
+            VFS_FILESYSTEM_SERVICE,
+        },
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::MemoryObjectFlags,
+    },
+};
+
+const MOUNT_POINT: &str = "/tmp";
+
+const CHUNK_SIZE: usize = 0x10000;
+/// The most chunks that are allocated, which limits the filesystem to 64MiB of data. Writes that would need more
+/// fail with `FileError::NoSpace`.
+const MAX_CHUNKS: usize = 1024;
+
+/// There aren't any users to restrict access to, so everyone can do anything to anything.
+const ROOT_PERMISSIONS: u16 = 0o777;
+const FILE_PERMISSIONS: u16 = 0o666;
+const ROOT_STAT: FileStat =
+    FileStat { kind: FileKind::Directory, size: 0, inode: 1, permissions: ROOT_PERMISSIONS };
+
+struct Chunk {
+    memory: MappedMemoryObject,
+}
+
+impl Chunk {
+    fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.memory.ptr(), CHUNK_SIZE) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.memory.ptr() as *mut u8, CHUNK_SIZE) }
+    }
+}
+
+struct File {
+    inode: u64,
+    size: u64,
+    /// The chunks of the file that have been written to, keyed by their index into the file. The parts of the
+    /// file without a chunk read as zeros.
+    chunks: BTreeMap<u64, Chunk>,
+}
+
+/// Allocates the memory that chunks are held in.
+struct ChunkAllocator {
+    /// The number of chunks that have been created, including those in `free`.
+    allocated: usize,
+    /// Chunks that were freed when their file was truncated, which are reused for new chunks. These are marked as
+    /// discardable, so the kernel can reclaim their memory until they're reused.
+    free: Vec<Chunk>,
+}
+
+impl ChunkAllocator {
+    /// Whether `count` more chunks can be allocated.
+    fn has_space_for(&self, count: usize) -> bool {
+        count <= self.free.len() + (MAX_CHUNKS - self.allocated)
+    }
+
+    /// Allocate a new chunk, which is zeroed.
+    fn allocate(&mut self) -> Result<Chunk, FileError> {
+        if let Some(mut chunk) = self.free.pop() {
+            let _ = chunk.memory.inner.set_discardable(false);
+            chunk.data_mut().fill(0);
+            return Ok(chunk);
+        }
+
+        if self.allocated >= MAX_CHUNKS {
+            return Err(FileError::NoSpace);
+        }
+        let memory = MemoryObject::create_discardable(CHUNK_SIZE, MemoryObjectFlags::WRITABLE)
+            .map_err(|_| FileError::NoSpace)?;
+        let memory = unsafe { memory.map() }.map_err(|_| FileError::NoSpace)?;
+        self.allocated += 1;
+        Ok(Chunk { memory })
+    }
+
+    fn free(&mut self, chunk: Chunk) {
+        let _ = chunk.memory.inner.set_discardable(true);
+        self.free.push(chunk);
+    }
+}
+
+struct OpenFile {
+    /// Files can't be removed or renamed, so an open file can be found by its name for as long as it's open.
+    name: String,
+    writable: bool,
+}
+
+pub struct TmpfsServer {
+    files: BTreeMap<String, File>,
+    chunks: ChunkAllocator,
+    open_files: BTreeMap<FileId, OpenFile>,
+    next_file_id: u64,
+    next_inode: u64,
+}
+
+impl TmpfsServer {
+    pub fn handle_request(&mut self, request: FileRequest) -> FileResponse {
+        let result = match request {
+            FileRequest::Open { path, options } => {
+                self.open(&path, options).map(|(id, stat)| FileResponse::Opened(id, stat))
+            }
+            FileRequest::Read { file, offset, length } => self.read(file, offset, length).map(FileResponse::Data),
+            FileRequest::Write { file, offset, data } => {
+                self.write(file, offset, &data).map(|()| FileResponse::Written(data.len() as u32))
+            }
+            FileRequest::Map { .. } | FileRequest::Namespace { .. } => Err(FileError::Unsupported),
+            FileRequest::Stat { path } => self.stat(&path).map(FileResponse::Stat),
+            FileRequest::ReadDir { path, start } => self.read_dir(&path, start).map(FileResponse::Entries),
+            FileRequest::Close { file } => self.close(file).map(|()| FileResponse::Done),
+        };
+        result.unwrap_or_else(FileResponse::Error)
+    }
+
+    fn open(&mut self, path: &str, options: OpenOptions) -> Result<(FileId, FileStat), FileError> {
+        let name = self.file_name(path)?.ok_or(FileError::IsADirectory)?;
+        if !self.files.contains_key(name) {
+            if !(options.write && options.create) {
+                return Err(FileError::NotFound);
+            }
+            let inode = self.next_inode;
+            self.next_inode += 1;
+            self.files.insert(name.to_string(), File { inode, size: 0, chunks: BTreeMap::new() });
+        }
+
+        let file = self.files.get_mut(name).unwrap();
+        if options.write && options.truncate {
+            file.size = 0;
+            for chunk in mem::take(&mut file.chunks).into_values() {
+                self.chunks.free(chunk);
+            }
+        }
+
+        let id = FileId(self.next_file_id);
+        self.next_file_id += 1;
+        self.open_files.insert(id, OpenFile { name: name.to_string(), writable: options.write });
+        Ok((id, file_stat(&self.files[name])))
+    }
+
+    fn read(&mut self, file: FileId, offset: u64, length: u32) -> Result<Vec<u8>, FileError> {
+        if length as usize > MAX_TRANSFER_SIZE {
+            return Err(FileError::TooLarge);
+        }
+        let name = &self.open_files.get(&file).ok_or(FileError::InvalidFile)?.name;
+        let file = &self.files[name];
+
+        let start = offset.min(file.size);
+        let end = offset.saturating_add(length as u64).min(file.size);
+        let mut data = vec![0; (end - start) as usize];
+        for (index, chunk_offset, range) in chunk_ranges(start, data.len()) {
+            if let Some(chunk) = file.chunks.get(&index) {
+                data[range.clone()].copy_from_slice(&chunk.data()[chunk_offset..(chunk_offset + range.len())]);
+            }
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, file: FileId, offset: u64, data: &[u8]) -> Result<(), FileError> {
+        if data.len() > MAX_TRANSFER_SIZE {
+            return Err(FileError::TooLarge);
+        }
+        let open_file = self.open_files.get(&file).ok_or(FileError::InvalidFile)?;
+        if !open_file.writable {
+            return Err(FileError::ReadOnly);
+        }
+        let file = self.files.get_mut(&open_file.name).unwrap();
+        let end = offset.checked_add(data.len() as u64).ok_or(FileError::TooLarge)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // Check there's space for the whole write first, so a write that fails doesn't change the file
+        let needed =
+            chunk_ranges(offset, data.len()).filter(|(index, _, _)| !file.chunks.contains_key(index)).count();
+        if !self.chunks.has_space_for(needed) {
+            return Err(FileError::NoSpace);
+        }
+
+        for (index, chunk_offset, range) in chunk_ranges(offset, data.len()) {
+            let chunk = match file.chunks.entry(index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.chunks.allocate()?),
+            };
+            chunk.data_mut()[chunk_offset..(chunk_offset + range.len())].copy_from_slice(&data[range]);
+        }
+        file.size = file.size.max(end);
+        Ok(())
+    }
+
+    fn stat(&mut self, path: &str) -> Result<FileStat, FileError> {
+        match self.file_name(path)? {
+            Some(name) => self.files.get(name).map(file_stat).ok_or(FileError::NotFound),
+            None => Ok(ROOT_STAT),
+        }
+    }
+
+    fn read_dir(&mut self, path: &str, start: u32) -> Result<Vec<file::DirEntry>, FileError> {
+        if let Some(name) = self.file_name(path)? {
+            let err = if self.files.contains_key(name) { FileError::NotADirectory } else { FileError::NotFound };
+            return Err(err);
+        }
+
+        Ok(self
+            .files
+            .keys()
+            .skip(start as usize)
+            .take(MAX_DIR_ENTRIES)
+            .map(|name| file::DirEntry { name: name.clone(), kind: FileKind::File })
+            .collect())
+    }
+
+    fn close(&mut self, file: FileId) -> Result<(), FileError> {
+        self.open_files.remove(&file).ok_or(FileError::InvalidFile)?;
+        Ok(())
+    }
+
+    /// Find the name of the file a path refers to, or `None` if it refers to the root directory.
+    fn file_name<'p>(&self, path: &'p str) -> Result<Option<&'p str>, FileError> {
+        let components = path_components(path).ok_or(FileError::InvalidPath)?;
+        match components[..] {
+            [] => Ok(None),
+            [name] => Ok(Some(name)),
+            [first, ..] if self.files.contains_key(first) => Err(FileError::NotADirectory),
+            _ => Err(FileError::NotFound),
+        }
+    }
+}
+
+fn file_stat(file: &File) -> FileStat {
+    FileStat { kind: FileKind::File, size: file.size, inode: file.inode, permissions: FILE_PERMISSIONS }
+}
+
+/// Split the `length` bytes starting at `offset` into the parts that fall in each chunk. Yields the index of the
+/// chunk each part is in, the offset of the part into the chunk, and the range of the part within the bytes.
+fn chunk_ranges(offset: u64, length: usize) -> impl Iterator<Item = (u64, usize, Range<usize>)> {
+    let mut done = 0;
+    core::iter::from_fn(move || {
+        if done >= length {
+            return None;
+        }
+        let position = offset + done as u64;
+        let chunk_offset = (position % CHUNK_SIZE as u64) as usize;
+        let part = usize::min(CHUNK_SIZE - chunk_offset, length - done);
+        let range = done..(done + part);
+        done += part;
+        Some((position / CHUNK_SIZE as u64, chunk_offset, range))
+    })
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("tmpfs is running!");
+
+    let service_host_client = ServiceHostClient::new();
+    let vfs_channel: Channel<FilesystemMessage, FilesystemResponse> =
+        service_host_client.subscribe_service(VFS_FILESYSTEM_SERVICE).unwrap();
+    let (channel, channel_handle) = Channel::<FileResponse, FileRequest>::create().unwrap();
+    vfs_channel
+        .send(&FilesystemMessage::Mount { path: MOUNT_POINT.to_string(), channel: channel_handle })
+        .unwrap();
+    match vfs_channel.receive_blocking().unwrap() {
+        FilesystemResponse::Mounted => info!("Mounted tmpfs at {}", MOUNT_POINT),
+        FilesystemResponse::Error(err) => panic!("Failed to mount tmpfs: {:?}", err),
+    }
+
+    let mut server = TmpfsServer {
+        files: BTreeMap::new(),
+        chunks: ChunkAllocator { allocated: 0, free: Vec::new() },
+        open_files: BTreeMap::new(),
+        next_file_id: 0,
+        next_inode: ROOT_STAT.inode + 1,
+    };
+    loop {
+        let (request, call) = channel.receive_call_blocking().unwrap();
+        let response = server.handle_request(request);
+        channel.reply(call, &response).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! `top` shows how memory is being used: how much physical memory is free, how much of the kernel heap and its
+//! object caches are in use, and the memory and handles held by each task. It redraws every second (or the
+//! number of milliseconds it's given as an argument), showing how much each task's committed memory has changed
+//! since the last redraw, so a task that is leaking memory stands out. Press `q` to quit.
+//!
+//! When it isn't run from a console (e.g. when its output is piped to another task), it writes a single report to
+//! stdout instead, or to the serial port with `early_log` if it doesn't have a stdout. This requires the `TRACE`
+//! capability.
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fmt::Write,
+    poplar::{
+        console::{self, ConsoleEvent, ConsoleRequest},
+        stdio::{self, Input, Output},
+        syscall::{self, TaskInfo},
+    },
+    time::Duration,
+};
+
+/// The most tasks that are shown.
+const MAX_TASKS: usize = 256;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often we check for input while waiting to redraw.
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn main() {
+    let (Some(Input::Console(stdin)), Some(Output::Console(stdout))) = (stdio::stdin(), stdio::stdout()) else {
+        match report(&BTreeMap::new()) {
+            Ok((report, _)) => print!("{}", report),
+            Err(message) => println!("{}", message),
+        }
+        return;
+    };
+
+    let interval = match std::env::args().nth(1) {
+        Some(millis) => match millis.parse() {
+            Ok(millis) => Duration::from_millis(millis),
+            Err(_) => {
+                console::write(stdout, &format!("top: '{}' is not a number of milliseconds\n", millis)).unwrap();
+                return;
+            }
+        },
+        None => DEFAULT_INTERVAL,
+    };
+
+    stdin.send(&ConsoleRequest::AttachInput).unwrap();
+    let mut previous = BTreeMap::new();
+    'redraw: loop {
+        match report(&previous) {
+            Ok((report, committed)) => {
+                // Clear the screen and move the cursor to the top-left before drawing the report
+                console::write(stdout, &format!("\x1b[2J\x1b[H{}", report)).unwrap();
+                previous = committed;
+            }
+            Err(message) => {
+                console::write(stdout, &format!("{}\n", message)).unwrap();
+                break;
+            }
+        }
+
+        let mut waited = Duration::ZERO;
+        while waited < interval {
+            while let Ok(Some(ConsoleEvent::Input(input))) = stdin.try_receive() {
+                if input.contains('q') {
+                    break 'redraw;
+                }
+            }
+            std::thread::sleep(INPUT_POLL_INTERVAL);
+            waited += INPUT_POLL_INTERVAL;
+        }
+    }
+    stdin.send(&ConsoleRequest::DetachInput).unwrap();
+}
+
+/// Describe how memory is being used. Returns the report, and the memory committed in each address space (by
+/// ID), which is passed back in as `previous` to show how it has changed by the next report.
+fn report(previous: &BTreeMap<u64, usize>) -> Result<(String, BTreeMap<u64, usize>), String> {
+    let stats =
+        syscall::get_kernel_memory_stats().map_err(|err| format!("top: failed to get memory stats: {:?}", err))?;
+    let mut tasks = [TaskInfo::default(); MAX_TASKS];
+    let num_tasks =
+        syscall::task_info(&mut tasks).map_err(|err| format!("top: failed to get tasks: {:?}", err))?;
+    let tasks = &mut tasks[0..usize::min(num_tasks, MAX_TASKS)];
+
+    /*
+     * Threads share the memory and handles of the task that created them, so they're only shown once for each
+     * address space. Sorting by address space (and then ID) after committed memory keeps the threads of a task
+     * together, after the task itself.
+     */
+    tasks.sort_by_key(|task| (Reverse(task.memory.committed), task.address_space, task.id));
+
+    let mut report = String::new();
+    writeln!(
+        report,
+        "Memory: {} KiB used, {} KiB free, {} KiB total",
+        kib(stats.physical_total - stats.physical_free),
+        kib(stats.physical_free),
+        kib(stats.physical_total)
+    )
+    .unwrap();
+    writeln!(report, "Kernel heap: {} KiB used of {} KiB", kib(stats.heap_used), kib(stats.heap_size)).unwrap();
+    write!(report, "Kernel objects:").unwrap();
+    for cache in &stats.object_caches[0..stats.num_object_caches] {
+        write!(report, " {} {}", cache.objects_in_use, cache.name()).unwrap();
+    }
+    writeln!(report, "\nTasks: {}\n", num_tasks).unwrap();
+
+    writeln!(
+        report,
+        "{:>5} {:<16} {:<8} {:<8} {:>9} {:>9} {:>7} {:>7}",
+        "ID", "NAME", "STATE", "PRIORITY", "RESERVED", "COMMITTED", "CHANGE", "HANDLES"
+    )
+    .unwrap();
+
+    let mut committed = BTreeMap::new();
+    for task in tasks.iter() {
+        let state = format!("{:?}", task.state);
+        let priority = format!("{:?}", task.priority);
+
+        if committed.insert(task.address_space, task.memory.committed).is_none() {
+            let change = match previous.get(&task.address_space) {
+                Some(&before) => format!("{:+}", (task.memory.committed as i64 - before as i64) / 1024),
+                None => String::new(),
+            };
+            writeln!(
+                report,
+                "{:>5} {:<16.16} {:<8} {:<8} {:>9} {:>9} {:>7} {:>7}",
+                task.id,
+                task.name(),
+                state,
+                priority,
+                kib(task.memory.reserved),
+                kib(task.memory.committed),
+                change,
+                task.num_handles
+            )
+            .unwrap();
+        } else {
+            writeln!(
+                report,
+                "{:>5} {:<16.16} {:<8} {:<8} {:>9} {:>9} {:>7} {:>7}",
+                task.id,
+                task.name(),
+                state,
+                priority,
+                "-",
+                "-",
+                "",
+                "-"
+            )
+            .unwrap();
+        }
+    }
+    if num_tasks > MAX_TASKS {
+        writeln!(report, "({} more tasks not shown)", num_tasks - MAX_TASKS).unwrap();
+    }
+
+    Ok((report, committed))
+}
+
+fn kib(bytes: usize) -> usize {
+    bytes / 1024
+}
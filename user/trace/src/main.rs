@@ -0,0 +1,85 @@
+//! `trace` captures a trace of what the kernel is doing. It turns the kernel's tracepoints on for a while (one
+//! second, or the number of milliseconds it's given as an argument), and then writes every record left in the
+//! trace buffers to the serial port with `early_log`, as lines of the form:
+//! ```text
+//! TRACE {cpu} {timestamp} {event} {data0} {data1} {data2}
+//! ```
+//! `cargo xtask trace` turns these into a file that can be viewed with a trace viewer. This requires the `TRACE`
+//! capability.
+
+use std::{
+    poplar::{
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{self, MemoryObjectFlags},
+        trace::{TraceBufferHeader, TraceRecord, TRACE_BUFFER_RECORDS, TRACE_BUFFER_SIZE, TRACE_RECORDS_OFFSET},
+        Handle,
+    },
+    time::Duration,
+};
+
+/// The most CPUs we can get trace buffers for.
+const MAX_CPUS: usize = 64;
+const DEFAULT_DURATION: Duration = Duration::from_secs(1);
+
+fn main() {
+    let report = |message: &str| {
+        let _ = syscall::early_log(message);
+        if let Some(stdout) = std::poplar::stdio::stdout() {
+            let _ = stdout.write(&format!("{}\n", message));
+        }
+    };
+
+    let duration = match std::env::args().nth(1) {
+        Some(millis) => match millis.parse() {
+            Ok(millis) => Duration::from_millis(millis),
+            Err(_) => {
+                report(&format!("trace: '{}' is not a number of milliseconds", millis));
+                return;
+            }
+        },
+        None => DEFAULT_DURATION,
+    };
+
+    let mut handles = [Handle::ZERO; MAX_CPUS];
+    let buffers: Vec<MappedMemoryObject> = match syscall::get_trace_buffers(&mut handles) {
+        Ok(num_cpus) => handles[0..num_cpus]
+            .iter()
+            .map(|&handle| unsafe {
+                MemoryObject::from_handle(handle, TRACE_BUFFER_SIZE, MemoryObjectFlags::empty()).map().unwrap()
+            })
+            .collect(),
+        Err(err) => {
+            report(&format!("trace: failed to get trace buffers: {:?}", err));
+            return;
+        }
+    };
+
+    report(&format!("trace: tracing {} CPUs for {:?}", buffers.len(), duration));
+    syscall::set_tracing(true).unwrap();
+    std::thread::sleep(duration);
+    syscall::set_tracing(false).unwrap();
+
+    let mut num_records = 0;
+    for buffer in &buffers {
+        let header = unsafe { core::ptr::read_volatile(buffer.ptr() as *const TraceBufferHeader) };
+        let first = header.head.saturating_sub(TRACE_BUFFER_RECORDS as u64);
+        for index in first..header.head {
+            let record = unsafe {
+                let offset = TRACE_RECORDS_OFFSET
+                    + (index as usize % TRACE_BUFFER_RECORDS) * core::mem::size_of::<TraceRecord>();
+                core::ptr::read_volatile(buffer.ptr().add(offset) as *const TraceRecord)
+            };
+            let _ = syscall::early_log(&format!(
+                "TRACE {} {} {} {:#x} {:#x} {:#x}",
+                record.cpu, record.timestamp, record.event, record.data[0], record.data[1], record.data[2]
+            ));
+        }
+
+        if first > 0 {
+            report(&format!("trace: {} records were lost from CPU {}'s buffer", first, header.cpu));
+        }
+        num_records += header.head - first;
+    }
+
+    report(&format!("trace: captured {} records", num_records));
+}
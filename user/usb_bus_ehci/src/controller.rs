@@ -5,7 +5,7 @@ use crate::{
     ActiveDevice,
 };
 use log::{info, trace, warn};
-use platform_bus::{BusDriverMessage, DeviceInfo, HandoffInfo, HandoffProperty, Property};
+use platform_bus::{BusDriverClient, DeviceInfo, HandoffInfo, HandoffProperty, Property};
 use spinning_top::RwSpinlock;
 use std::{
     collections::BTreeMap,
@@ -16,7 +16,7 @@ use std::{
         ddk::dma::{DmaObject, DmaPool, DmaToken},
         event::Event,
         memory_object::MemoryObject,
-        syscall::MemoryObjectFlags,
+        syscall::{DmaConstraints, MemoryObjectFlags},
     },
     sync::Arc,
 };
@@ -33,7 +33,7 @@ pub struct Controller {
     free_addresses: RwSpinlock<Vec<u8>>,
     pub schedule_pool: RwSpinlock<DmaPool>,
     active_devices: RwSpinlock<BTreeMap<u8, Arc<RwSpinlock<ActiveDevice>>>>,
-    platform_bus_bus_channel: Arc<Channel<BusDriverMessage, !>>,
+    platform_bus_bus_client: Arc<BusDriverClient>,
 
     /// Holds references to all the queues that are currently in the asynchronous schedule. It's
     /// important we keep a central record of them, as they are linked together into a linked list
@@ -45,8 +45,9 @@ pub struct Controller {
 impl Controller {
     pub fn new(
         register_base: usize,
-        platform_bus_bus_channel: Arc<Channel<BusDriverMessage, !>>,
+        platform_bus_bus_client: Arc<BusDriverClient>,
         interrupt_event: Event,
+        dma_constraints: DmaConstraints,
     ) -> Arc<Controller> {
         let caps = Capabilities::read_from_registers(register_base);
         info!("Capabilites: {:#?}", caps);
@@ -54,9 +55,12 @@ impl Controller {
         // TODO: once we have kernel virtual address space management, just let it find an address
         // for us
         const SCHEDULE_POOL_ADDRESS: usize = 0x00000005_10000000;
+        // This also holds the buffers for data transfers, so needs room for the largest bulk transfers
+        const SCHEDULE_POOL_SIZE: usize = 0x8000;
         let schedule_pool = RwSpinlock::new(DmaPool::new(unsafe {
-            MemoryObject::create_physical(0x1000, MemoryObjectFlags::WRITABLE)
+            MemoryObject::create_dma(SCHEDULE_POOL_SIZE, MemoryObjectFlags::WRITABLE, dma_constraints)
                 .unwrap()
+                .0
                 .map_at(SCHEDULE_POOL_ADDRESS)
                 .unwrap()
         }));
@@ -68,7 +72,7 @@ impl Controller {
             free_addresses: RwSpinlock::new((1..128).collect()),
             schedule_pool,
             active_devices: RwSpinlock::new(BTreeMap::new()),
-            platform_bus_bus_channel,
+            platform_bus_bus_client,
 
             async_schedule: RwSpinlock::new(Vec::new()),
         });
@@ -94,6 +98,7 @@ impl Controller {
                                 .with(Status::PORT_CHANGE_DETECT, status.get(Status::PORT_CHANGE_DETECT)),
                         );
                     }
+                    interrupt_event.ack_interrupt();
 
                     if status.get(Status::ERR_INTERRUPT) {
                         panic!("EHCI controller has reported an error!");
@@ -241,7 +246,11 @@ impl Controller {
                 let address = self.free_addresses.write().pop().unwrap();
                 trace!("Device on port {} is high-speed. Allocated address {} for it to use.", port, address);
 
-                // Create a new queue for the new device's control endpoint
+                /*
+                 * Create a new queue for the new device's control endpoint. If any of the requests
+                 * we make to set up the device fail, we give up on it.
+                 * TODO: we should reset the port and try again before giving up
+                 */
                 let queue = self.create_queue(0, 0, 64);
                 self.add_to_async_schedule(queue.clone());
 
@@ -263,14 +272,15 @@ impl Controller {
                             .with(RequestType::RECIPIENT, Recipient::Device)
                             .with(RequestType::TYP, RequestTypeType::Standard)
                             .with(RequestType::DIRECTION, Direction::DeviceToHost),
-                        request: Request::GetDescriptor,
+                        request: Request::GetDescriptor as u8,
                         value: (DescriptorType::Device as u16) << 8,
                         index: 0,
                         length: 64,
                     };
                     let mut buffer = self.schedule_pool.write().create_buffer(64).unwrap();
                     self.do_control_transfer(&queue, get_descriptor_header, Some(buffer.token().unwrap()), false)
-                        .await;
+                        .await
+                        .ok()?;
 
                     // Manually extract the max packet size from the buffer (one byte at `0x7`)
                     let max_packet_size = buffer.read()[7];
@@ -289,12 +299,12 @@ impl Controller {
                         .with(RequestType::RECIPIENT, Recipient::Device)
                         .with(RequestType::TYP, RequestTypeType::Standard)
                         .with(RequestType::DIRECTION, Direction::HostToDevice),
-                    request: Request::SetAddress,
+                    request: Request::SetAddress as u8,
                     value: address as u16,
                     index: 0,
                     length: 0,
                 };
-                self.do_control_transfer(&queue, set_address, None, true).await;
+                self.do_control_transfer(&queue, set_address, None, true).await.ok()?;
 
                 queue.write().set_address(address);
 
@@ -305,7 +315,7 @@ impl Controller {
                             .with(RequestType::RECIPIENT, Recipient::Device)
                             .with(RequestType::TYP, RequestTypeType::Standard)
                             .with(RequestType::DIRECTION, Direction::DeviceToHost),
-                        request: Request::GetDescriptor,
+                        request: Request::GetDescriptor as u8,
                         value: (DescriptorType::Device as u16) << 8,
                         index: 0,
                         length: mem::size_of::<DeviceDescriptor>() as u16,
@@ -313,7 +323,8 @@ impl Controller {
                     let mut descriptor: DmaObject<DeviceDescriptor> =
                         self.schedule_pool.write().create(DeviceDescriptor::default()).unwrap();
                     self.do_control_transfer(&queue, get_descriptor, Some(descriptor.token().unwrap()), false)
-                        .await;
+                        .await
+                        .ok()?;
 
                     *descriptor.read()
                 };
@@ -331,7 +342,7 @@ impl Controller {
                             .with(RequestType::RECIPIENT, Recipient::Device)
                             .with(RequestType::TYP, RequestTypeType::Standard)
                             .with(RequestType::DIRECTION, Direction::DeviceToHost),
-                        request: Request::GetDescriptor,
+                        request: Request::GetDescriptor as u8,
                         value: (DescriptorType::Configuration as u16) << 8,
                         index: 0,
                         length: mem::size_of::<ConfigurationDescriptor>() as u16,
@@ -339,7 +350,8 @@ impl Controller {
                     let mut descriptor: DmaObject<ConfigurationDescriptor> =
                         self.schedule_pool.write().create(ConfigurationDescriptor::default()).unwrap();
                     self.do_control_transfer(&queue, get_descriptor, Some(descriptor.token().unwrap()), false)
-                        .await;
+                        .await
+                        .ok()?;
 
                     info!("ConfigurationDescriptor: {:#?}", descriptor.read());
 
@@ -348,7 +360,7 @@ impl Controller {
                             .with(RequestType::RECIPIENT, Recipient::Device)
                             .with(RequestType::TYP, RequestTypeType::Standard)
                             .with(RequestType::DIRECTION, Direction::DeviceToHost),
-                        request: Request::GetDescriptor,
+                        request: Request::GetDescriptor as u8,
                         value: (DescriptorType::Configuration as u16) << 8,
                         index: 0,
                         length: descriptor.read().total_length as u16,
@@ -356,7 +368,8 @@ impl Controller {
                     let mut buffer =
                         self.schedule_pool.write().create_buffer(descriptor.read().total_length as usize).unwrap();
                     self.do_control_transfer(&queue, get_configuration, Some(buffer.token().unwrap()), false)
-                        .await;
+                        .await
+                        .ok()?;
 
                     buffer.read().to_vec()
                 };
@@ -412,9 +425,7 @@ impl Controller {
             properties.insert("usb.channel".to_string(), HandoffProperty::Channel(device_channel_handle));
             HandoffInfo(properties)
         };
-        self.platform_bus_bus_channel
-            .send(&BusDriverMessage::RegisterDevice(name, device_info, handoff_info))
-            .unwrap();
+        self.platform_bus_bus_client.register_device(name, device_info, handoff_info).unwrap();
 
         let device = Arc::new(RwSpinlock::new(ActiveDevice {
             address,
@@ -498,7 +509,7 @@ impl Controller {
         setup: SetupPacket,
         data: Option<DmaToken>,
         transfer_to_device: bool,
-    ) {
+    ) -> Result<(), ()> {
         // XXX: create the future and drop the queue before awaiting the future, or we'll deadlock
         let future = queue.write().control_transfer(
             setup,
@@ -506,7 +517,7 @@ impl Controller {
             transfer_to_device,
             self.schedule_pool.write().deref_mut(),
         );
-        future.await;
+        future.await
     }
 
     pub async fn do_interrupt_transfer(
@@ -514,11 +525,22 @@ impl Controller {
         queue: &Arc<RwSpinlock<Queue>>,
         data: DmaToken,
         transfer_to_device: bool,
-    ) {
+    ) -> Result<(), ()> {
         // XXX: create the future and drop the queue before awaiting the future, or we'll deadlock
         let future =
             queue.write().interrupt_transfer(data, transfer_to_device, self.schedule_pool.write().deref_mut());
-        future.await;
+        future.await
+    }
+
+    pub async fn do_bulk_transfer(
+        &self,
+        queue: &Arc<RwSpinlock<Queue>>,
+        data: DmaToken,
+        transfer_to_device: bool,
+    ) -> Result<(), ()> {
+        // XXX: create the future and drop the queue before awaiting the future, or we'll deadlock
+        let future = queue.write().bulk_transfer(data, transfer_to_device, self.schedule_pool.write().deref_mut());
+        future.await
     }
 
     pub fn reset_port(&self, port: u8) {
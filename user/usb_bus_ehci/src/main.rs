@@ -12,8 +12,8 @@ mod reg;
 
 use crate::queue::Queue;
 use controller::Controller;
-use log::info;
-use platform_bus::{BusDriverMessage, DeviceDriverMessage, DeviceDriverRequest, Filter, Property};
+use log::{info, warn};
+use platform_bus::{BusDriverClient, DeviceDriverClient, DeviceHandoffCall, Filter, Property};
 use service_host::ServiceHostClient;
 use spinning_top::RwSpinlock;
 use std::{
@@ -23,7 +23,7 @@ use std::{
         channel::Channel,
         early_logger::EarlyLogger,
         memory_object::MemoryObject,
-        syscall::MemoryObjectFlags,
+        syscall::{DmaConstraints, MemoryObjectFlags},
     },
     sync::Arc,
 };
@@ -37,7 +37,8 @@ use usb::{
 pub struct ActiveDevice {
     pub address: u8,
     control_queue: Arc<RwSpinlock<Queue>>,
-    endpoints: BTreeMap<u8, Arc<RwSpinlock<Queue>>>,
+    /// The queues of the endpoints that have been opened, by endpoint number and direction.
+    endpoints: BTreeMap<(u8, EndpointDirection), Arc<RwSpinlock<Queue>>>,
     channel: Channel<DeviceResponse, DeviceControlMessage>,
 }
 
@@ -56,12 +57,18 @@ impl ActiveDevice {
                         .with(RequestType::RECIPIENT, Recipient::Device)
                         .with(RequestType::TYP, RequestTypeType::Standard)
                         .with(RequestType::DIRECTION, Direction::HostToDevice),
-                    request: Request::SetConfiguration,
+                    request: Request::SetConfiguration as u8,
                     value: config as u16,
                     index: 0,
                     length: 0,
                 };
-                controller.do_control_transfer(&self.control_queue, set_configuration, None, true).await;
+                if controller
+                    .do_control_transfer(&self.control_queue, set_configuration, None, true)
+                    .await
+                    .is_err()
+                {
+                    warn!("Failed to set configuration {} of device {}", config, self.address);
+                }
 
                 Ok(())
             }
@@ -71,37 +78,39 @@ impl ActiveDevice {
                         .with(RequestType::RECIPIENT, Recipient::Device)
                         .with(RequestType::TYP, RequestTypeType::Standard)
                         .with(RequestType::DIRECTION, Direction::HostToDevice),
-                    request: Request::SetInterface,
+                    request: Request::SetInterface as u8,
                     value: setting as u16,
                     index: interface as u16,
                     length: 0,
                 };
-                controller.do_control_transfer(&self.control_queue, set_configuration, None, true).await;
+                if controller
+                    .do_control_transfer(&self.control_queue, set_configuration, None, true)
+                    .await
+                    .is_err()
+                {
+                    warn!(
+                        "Failed to set interface {} of device {} to setting {}",
+                        interface, self.address, setting
+                    );
+                }
 
                 Ok(())
             }
             DeviceControlMessage::OpenEndpoint { number, direction, max_packet_size } => {
-                match direction {
-                    EndpointDirection::In => {
-                        info!(
-                            "Setting up IN pipe for endpoint {} (max packet size of {})",
-                            number, max_packet_size
-                        );
-
-                        let queue = controller.create_queue(self.address, number, max_packet_size);
-                        // TODO: I think in the long run things like Interrupt endpoints should
-                        // actually be in the periodic schedule no?
-                        controller.add_to_async_schedule(queue.clone());
-                        self.endpoints.insert(number, queue);
-                    }
-                    EndpointDirection::Out => {
-                        info!(
-                            "Setting up OUT pipe for endpoint {} (max packet size of {})",
-                            number, max_packet_size
-                        );
-                        todo!()
-                    }
-                }
+                info!(
+                    "Setting up {:?} pipe for endpoint {} (max packet size of {})",
+                    direction, number, max_packet_size
+                );
+
+                /*
+                 * Endpoints with the same number but different directions are separate endpoints,
+                 * and so each needs its own queue.
+                 */
+                let queue = controller.create_queue(self.address, number, max_packet_size);
+                // TODO: I think in the long run things like Interrupt endpoints should
+                // actually be in the periodic schedule no?
+                controller.add_to_async_schedule(queue.clone());
+                self.endpoints.insert((number, direction), queue);
 
                 Ok(())
             }
@@ -111,28 +120,112 @@ impl ActiveDevice {
                         .with(RequestType::RECIPIENT, Recipient::Interface)
                         .with(RequestType::TYP, RequestTypeType::Standard)
                         .with(RequestType::DIRECTION, Direction::DeviceToHost),
-                    request: Request::GetDescriptor,
+                    request: Request::GetDescriptor as u8,
                     value: (typ as u16) << 8 + index,
                     index: 0,
                     length,
                 };
                 let mut buffer = controller.schedule_pool.write().create_buffer(length as usize).unwrap();
-                controller
+                let response = match controller
                     .do_control_transfer(&self.control_queue, get_descriptor, Some(buffer.token().unwrap()), false)
-                    .await;
+                    .await
+                {
+                    Ok(()) => DeviceResponse::Descriptor { typ, index, bytes: buffer.read().to_vec() },
+                    Err(()) => DeviceResponse::TransferFailed,
+                };
 
-                self.channel
-                    .send(&DeviceResponse::Descriptor { typ, index, bytes: buffer.read().to_vec() })
-                    .unwrap();
+                self.channel.send(&response).unwrap();
                 Ok(())
             }
             DeviceControlMessage::InterruptTransferIn { endpoint, packet_size } => {
-                let endpoint = self.endpoints.get(&endpoint).unwrap();
-                // TODO: check that given direction is correct for this endpoint
+                let endpoint = self.endpoints.get(&(endpoint, EndpointDirection::In)).unwrap();
 
                 let mut buffer = controller.schedule_pool.write().create_buffer(packet_size as usize).unwrap();
-                controller.do_interrupt_transfer(&endpoint, buffer.token().unwrap(), false).await;
-                self.channel.send(&DeviceResponse::Data(buffer.read().to_vec())).unwrap();
+                let response =
+                    match controller.do_interrupt_transfer(&endpoint, buffer.token().unwrap(), false).await {
+                        Ok(()) => DeviceResponse::Data(buffer.read().to_vec()),
+                        Err(()) => DeviceResponse::TransferFailed,
+                    };
+                self.channel.send(&response).unwrap();
+                Ok(())
+            }
+            DeviceControlMessage::BulkTransferIn { endpoint, length } => {
+                let endpoint = self.endpoints.get(&(endpoint, EndpointDirection::In)).unwrap();
+
+                let Ok(mut buffer) = controller.schedule_pool.write().create_buffer(length as usize) else {
+                    warn!("Failed to allocate buffer for bulk transfer of {} bytes", length);
+                    self.channel.send(&DeviceResponse::TransferFailed).unwrap();
+                    return Ok(());
+                };
+                let response = match controller.do_bulk_transfer(&endpoint, buffer.token().unwrap(), false).await {
+                    Ok(()) => DeviceResponse::Data(buffer.read().to_vec()),
+                    Err(()) => DeviceResponse::TransferFailed,
+                };
+                self.channel.send(&response).unwrap();
+                Ok(())
+            }
+            DeviceControlMessage::BulkTransferOut { endpoint, data } => {
+                let endpoint = self.endpoints.get(&(endpoint, EndpointDirection::Out)).unwrap();
+
+                let Ok(mut buffer) = controller.schedule_pool.write().create_buffer(data.len()) else {
+                    warn!("Failed to allocate buffer for bulk transfer of {} bytes", data.len());
+                    self.channel.send(&DeviceResponse::TransferFailed).unwrap();
+                    return Ok(());
+                };
+                buffer.write().copy_from_slice(&data);
+                let response = match controller.do_bulk_transfer(&endpoint, buffer.token().unwrap(), true).await {
+                    Ok(()) => DeviceResponse::TransferComplete,
+                    Err(()) => DeviceResponse::TransferFailed,
+                };
+                self.channel.send(&response).unwrap();
+                Ok(())
+            }
+            DeviceControlMessage::ClassRequestIn { interface, request, value, length } => {
+                let setup = SetupPacket {
+                    typ: RequestType::new()
+                        .with(RequestType::RECIPIENT, Recipient::Interface)
+                        .with(RequestType::TYP, RequestTypeType::Class)
+                        .with(RequestType::DIRECTION, Direction::DeviceToHost),
+                    request,
+                    value,
+                    index: interface as u16,
+                    length,
+                };
+                let mut buffer = controller.schedule_pool.write().create_buffer(length as usize).unwrap();
+                let response = match controller
+                    .do_control_transfer(&self.control_queue, setup, Some(buffer.token().unwrap()), false)
+                    .await
+                {
+                    Ok(()) => DeviceResponse::Data(buffer.read().to_vec()),
+                    Err(()) => DeviceResponse::TransferFailed,
+                };
+                self.channel.send(&response).unwrap();
+                Ok(())
+            }
+            DeviceControlMessage::ClearHalt { endpoint, direction } => {
+                // The `ENDPOINT_HALT` feature is selected with a value of `0`
+                let clear_feature = SetupPacket {
+                    typ: RequestType::new()
+                        .with(RequestType::RECIPIENT, Recipient::Endpoint)
+                        .with(RequestType::TYP, RequestTypeType::Standard)
+                        .with(RequestType::DIRECTION, Direction::HostToDevice),
+                    request: Request::ClearFeature as u8,
+                    value: 0,
+                    index: match direction {
+                        EndpointDirection::In => 0x80 | endpoint as u16,
+                        EndpointDirection::Out => endpoint as u16,
+                    },
+                    length: 0,
+                };
+                if controller.do_control_transfer(&self.control_queue, clear_feature, None, true).await.is_err() {
+                    warn!(
+                        "Failed to clear halt on endpoint {} ({:?}) of device {}",
+                        endpoint, direction, self.address
+                    );
+                }
+                if let Some(queue) = self.endpoints.get(&(endpoint, direction)) {
+                    queue.write().reset_data_toggle();
+                }
                 Ok(())
             }
         }
@@ -148,38 +241,40 @@ fn main() {
 
     let service_host_client = ServiceHostClient::new();
     // This allows us to talk to the PlatformBus as a bus driver (to register USB devices).
-    let platform_bus_bus_channel: Arc<Channel<BusDriverMessage, !>> =
-        Arc::new(service_host_client.subscribe_service("platform_bus.bus_driver").unwrap());
+    let platform_bus_bus_client =
+        Arc::new(BusDriverClient::new(service_host_client.subscribe_service("platform_bus.bus_driver").unwrap()));
     // This allows us to talk to the PlatformBus as a device driver (to find controllers we can manage).
-    let platform_bus_device_channel: Channel<DeviceDriverMessage, DeviceDriverRequest> =
-        service_host_client.subscribe_service("platform_bus.device_driver").unwrap();
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
 
     // Tell PlatformBus that we're interested in EHCI controllers.
-    platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![
+    let handoff_server = platform_bus_device_client
+        .register(vec![
             Filter::Matches(String::from("pci.class"), Property::Integer(0x0c)),
             Filter::Matches(String::from("pci.sub_class"), Property::Integer(0x03)),
             Filter::Matches(String::from("pci.interface"), Property::Integer(0x20)),
-        ]))
+        ])
         .unwrap();
 
     // Spawn a task to listen for new controllers to drive
     std::poplar::rt::spawn(async move {
         loop {
-            match platform_bus_device_channel.receive().await.unwrap() {
-                DeviceDriverRequest::QuerySupport(device_name, _device_info) => {
+            match handoff_server.next().await.unwrap() {
+                DeviceHandoffCall::QuerySupport { responder, .. } => {
                     /*
                      * Our filters are specific enough that any device that matches should be an
                      * EHCI controller, so we always say we'll support it here.
                      */
-                    platform_bus_device_channel.send(&DeviceDriverMessage::CanSupport(device_name, true)).unwrap();
+                    responder.reply(true).unwrap();
                 }
-                DeviceDriverRequest::HandoffDevice(device_name, device_info, handoff_info) => {
+                DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                    responder.reply(()).unwrap();
+                }
+                DeviceHandoffCall::HandoffDevice { name: device_name, device_info, handoff_info } => {
                     info!("Started driving a EHCI controller: {}", device_name);
 
                     let register_space_size = handoff_info.get_as_integer("pci.bar0.size").unwrap() as usize;
 
-                    // TODO: let the kernel choose the address when it can - we don't care
                     // TODO: this trusts the data from the platform_bus. Maybe we shouldn't do that? One
                     // idea would be a syscall for querying info about the object?
                     let register_space = MemoryObject {
@@ -188,15 +283,16 @@ fn main() {
                         flags: MemoryObjectFlags::WRITABLE,
                         phys_address: None,
                     };
-                    const REGISTER_SPACE_ADDRESS: usize = 0x00000005_00000000;
-                    unsafe {
-                        register_space.map_at(REGISTER_SPACE_ADDRESS).unwrap();
-                    }
+                    let register_space = unsafe { register_space.map().unwrap() };
 
+                    let dma_constraints = handoff_info
+                        .get_as_dma_domain("pci.dma_domain")
+                        .map_or(DmaConstraints::NONE, |domain| DmaConstraints::NONE.in_domain(domain));
                     let controller = Controller::new(
-                        REGISTER_SPACE_ADDRESS,
-                        platform_bus_bus_channel.clone(),
+                        register_space.mapped_at,
+                        platform_bus_bus_client.clone(),
                         handoff_info.get_as_event("pci.interrupt").unwrap(),
+                        dma_constraints,
                     );
                     controller.initialize();
 
@@ -30,18 +30,20 @@ pub struct Transaction {
 
 pub struct TransactionState {
     complete: bool,
+    /// Set if the transaction finished because of an error, rather than completing successfully.
+    failed: bool,
     waker: Option<Waker>,
 }
 
 pub struct TransactionFuture(Arc<Spinlock<TransactionState>>);
 
 impl Future for TransactionFuture {
-    type Output = ();
+    type Output = Result<(), ()>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<Self::Output> {
         let mut state = self.0.lock();
         if state.complete {
-            Poll::Ready(())
+            Poll::Ready(if state.failed { Err(()) } else { Ok(()) })
         } else {
             /*
              * Set the current waker that should be woken when the transaction completes. We do
@@ -141,7 +143,7 @@ impl Queue {
             },
         );
 
-        let state = Arc::new(Spinlock::new(TransactionState { complete: false, waker: None }));
+        let state = Arc::new(Spinlock::new(TransactionState { complete: false, failed: false, waker: None }));
 
         self.transactions.push_back(Transaction {
             descriptors: transfers,
@@ -205,7 +207,7 @@ impl Queue {
             );
         }
 
-        let state = Arc::new(Spinlock::new(TransactionState { complete: false, waker: None }));
+        let state = Arc::new(Spinlock::new(TransactionState { complete: false, failed: false, waker: None }));
 
         self.transactions.push_back(Transaction {
             descriptors: transfers,
@@ -228,6 +230,71 @@ impl Queue {
         TransactionFuture(state)
     }
 
+    /// Transfer `data` to or from a Bulk endpoint. Large transfers are split across multiple transfer
+    /// descriptors, so `data` must be physically contiguous.
+    pub fn bulk_transfer(
+        &mut self,
+        data: DmaToken,
+        transfer_to_device: bool,
+        pool: &mut DmaPool,
+    ) -> TransactionFuture {
+        /*
+         * Each transfer descriptor can point to five pages of memory. We transfer at most four pages-worth of
+         * data with each, so it doesn't matter how the data is aligned. This is also a multiple of any max
+         * packet size, so only the last descriptor can produce a short packet.
+         */
+        const BYTES_PER_DESCRIPTOR: usize = 0x4000;
+        let num_data = usize::max(1, data.length.div_ceil(BYTES_PER_DESCRIPTOR));
+
+        let mut transfers = pool.create_array(num_data, TransferDescriptor::new()).unwrap();
+        for i in 0..num_data {
+            let start = data.phys + i * BYTES_PER_DESCRIPTOR;
+            let bytes_to_transfer = usize::min(BYTES_PER_DESCRIPTOR, data.length - i * BYTES_PER_DESCRIPTOR);
+            let page = |n: usize| ((start & !0xfff) + n * 0x1000) as u32;
+
+            transfers.write(
+                i,
+                TransferDescriptor {
+                    next_ptr: if (i + 1) < num_data {
+                        TdPtr::new(transfers.phys_of_element(i + 1) as u32, false)
+                    } else {
+                        TdPtr::new(0x0, true)
+                    },
+                    alt_ptr: TdPtr::new(0x0, true),
+                    token: TdToken::new()
+                        .with(TdToken::ACTIVE, true)
+                        .with(TdToken::INTERRUPT_ON_COMPLETE, (i + 1) == num_data)
+                        .with(TdToken::ERR_COUNTER, 3)
+                        .with(TdToken::PID_CODE, if transfer_to_device { PidCode::Out } else { PidCode::In })
+                        .with(TdToken::TOTAL_BYTES_TO_TRANSFER, bytes_to_transfer as u32),
+                    buffer_ptr_0: start as u32,
+                    buffer_ptr_1: page(1),
+                    buffer_ptr_2: page(2),
+                    buffer_ptr_3: page(3),
+                    buffer_ptr_4: page(4),
+                },
+            );
+        }
+
+        let state = Arc::new(Spinlock::new(TransactionState { complete: false, failed: false, waker: None }));
+
+        self.transactions.push_back(Transaction {
+            descriptors: transfers,
+            setup: None,
+            data: Some(data),
+            num_complete: 0,
+            state: state.clone(),
+        });
+
+        // If this is the only transaction in the queue, start it now (see `control_transfer`)
+        if self.transactions.len() == 1 {
+            let next_td = self.transactions.back().unwrap().descriptors.phys_of_element(0);
+            self.head.write().next_td = TdPtr::new(next_td as u32, false);
+        }
+
+        TransactionFuture(state)
+    }
+
     pub fn check_progress(&mut self) {
         let Some(current_transaction) = self.transactions.front_mut() else {
             return;
@@ -265,7 +332,21 @@ impl Queue {
          * Now we've processed completed transfers, check if the transaction as a whole is
          * complete. If it is, we can tell its initiator to proceed.
          */
-        if current_transaction.num_complete == current_transaction.descriptors.length {
+        let complete = current_transaction.num_complete == current_transaction.descriptors.length;
+        if complete || err_detected {
+            if err_detected {
+                error!("Transfer error detected!");
+
+                /*
+                 * The controller halts the queue when a transfer fails. Clear the overlay area so the
+                 * queue can be used for the next transaction. This also resets the data toggle.
+                 */
+                let mut head = self.head.write();
+                head.next_td = TdPtr::new(0x0, true);
+                head.alt_td = TdPtr::new(0x0, true);
+                head.status = TdToken::new();
+            }
+
             /*
              * Drop the transaction from the queue. This frees the data `DmaToken`, allowing the
              * caller to access the underlying data. We also mark the associated future as complete
@@ -274,6 +355,7 @@ impl Queue {
             let completed = self.transactions.pop_front().unwrap();
             let mut state = completed.state.lock();
             state.complete = true;
+            state.failed = err_detected;
             if let Some(waker) = state.waker.take() {
                 waker.wake();
             }
@@ -282,12 +364,16 @@ impl Queue {
             if let Some(next) = self.transactions.front() {
                 self.head.write().next_td = TdPtr::new(next.descriptors.phys_of_element(0) as u32, false);
             }
-        } else if err_detected {
-            error!("Transfer error detected!");
-            self.transactions.pop_front();
         }
     }
 
+    /// Reset the data toggle of the queue. This must be done when the endpoint's halt condition is cleared on the
+    /// device, as the device resets its own data toggle.
+    pub fn reset_data_toggle(&mut self) {
+        let status = self.head.read().status;
+        self.head.write().status = status.with(TdToken::DATA_TOGGLE, false);
+    }
+
     pub fn set_address(&mut self, address: u8) {
         let endpoint_characteristics = self.head.read().endpoint_characteristics;
         self.head.write().endpoint_characteristics =
@@ -347,8 +433,12 @@ impl QueueHead {
                 .with(EndpointCharacteristics::ENDPOINT_SPEED, EndpointSpeed::High)
                 .with(EndpointCharacteristics::ENDPOINT, endpoint as u32)
                 .with(EndpointCharacteristics::MAX_PACKET_SIZE, max_packet_size as u32)
-                .with(EndpointCharacteristics::DATA_TOGGLE_CONTROL, true), // TODO: I think this
-            // should only be true for control endpoints?
+                /*
+                 * Control transfers set the data toggle of each stage explicitly, so the toggle is
+                 * taken from each transfer descriptor. Other endpoints alternate between `DATA0`
+                 * and `DATA1`, which the controller tracks for us in the queue head.
+                 */
+                .with(EndpointCharacteristics::DATA_TOGGLE_CONTROL, endpoint == 0),
             endpoint_caps: EndpointCapabilities::new().with(EndpointCapabilities::HIGH_BANDWIDTH_MULTIPLIER, 0b01),
             current_td: TdPtr::new(0x0, false),
             next_td: TdPtr::new(0x0, true),
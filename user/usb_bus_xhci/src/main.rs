@@ -10,9 +10,8 @@ use caps::Capabilities;
 use log::info;
 use memory::MemoryArea;
 use operational::OperationRegisters;
-use platform_bus::{BusDriverMessage, DeviceDriverMessage, DeviceDriverRequest, Filter, Property};
+use platform_bus::{BusDriverClient, DeviceDriverClient, DeviceHandoffCall, Filter, Property};
 use std::poplar::{
-    channel::Channel,
     early_logger::EarlyLogger,
     memory_object::MemoryObject,
     syscall::{self, MemoryObjectFlags},
@@ -31,26 +30,28 @@ pub fn main() {
     info!("XHCI USB Bus Driver is running!");
 
     // This allows us to talk to the PlatformBus as a bus driver (to register USB devices).
-    let platform_bus_bus_channel: Channel<BusDriverMessage, !> =
-        Channel::from_handle(syscall::subscribe_to_service("platform_bus.bus_driver").unwrap());
+    let platform_bus_bus_client =
+        BusDriverClient::from_handle(syscall::subscribe_to_service("platform_bus.bus_driver").unwrap());
     // This allows us to talk to the PlatformBus as a device driver (to find controllers we can manage).
-    let platform_bus_device_channel: Channel<DeviceDriverMessage, DeviceDriverRequest> =
-        Channel::from_handle(syscall::subscribe_to_service("platform_bus.device_driver").unwrap());
+    let platform_bus_device_client =
+        DeviceDriverClient::from_handle(syscall::subscribe_to_service("platform_bus.device_driver").unwrap());
 
     // Tell PlatformBus that we're interested in XHCI controllers.
-    platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![
+    let handoff_server = platform_bus_device_client
+        .register(vec![
             Filter::Matches(String::from("pci.class"), Property::Integer(0x0c)),
             Filter::Matches(String::from("pci.sub_class"), Property::Integer(0x03)),
             Filter::Matches(String::from("pci.interface"), Property::Integer(0x30)),
-        ]))
+        ])
         .unwrap();
 
     // TODO: we currently only support one controller, and just stop listening after we find the first one
     // TODO: probably don't bother changing this until we have a futures-based message interface
     let mut controller_device = loop {
-        match platform_bus_device_channel.try_receive().unwrap() {
-            Some(DeviceDriverRequest::HandoffDevice(device_name, device)) => {
+        match handoff_server.try_next().unwrap() {
+            Some(DeviceHandoffCall::QuerySupport { responder, .. }) => responder.reply(true).unwrap(),
+            Some(DeviceHandoffCall::PrepareForShutdown { responder, .. }) => responder.reply(()).unwrap(),
+            Some(DeviceHandoffCall::HandoffDevice { name: device_name, handoff_info: device, .. }) => {
                 info!("Started driving a XHCI controller: {}", device_name);
                 break device;
             }
@@ -1,11 +1,12 @@
 #![feature(never_type)]
 
+use core::time::Duration;
 use log::{info, warn};
 use platform_bus::{
-    input::{InputEvent, Key, KeyState},
-    BusDriverMessage,
-    DeviceDriverMessage,
-    DeviceDriverRequest,
+    input::{Button, InputDeviceClient, InputEvent, Key, KeyState},
+    BusDriverClient,
+    DeviceDriverClient,
+    DeviceHandoffCall,
     DeviceInfo,
     Filter,
     HandoffInfo,
@@ -22,8 +23,10 @@ use usb::{
         ConfigurationDescriptor,
         DescriptorType,
         EndpointAddress,
+        EndpointAttributes,
         EndpointDescriptor,
         InterfaceDescriptor,
+        TransferType,
     },
     hid::{
         report::{FieldValue, Usage},
@@ -43,26 +46,26 @@ pub fn main() {
 
     let service_host_client = ServiceHostClient::new();
     // This allows us to talk to the PlatformBus as a bus driver (to register our abstract devices).
-    let platform_bus_bus_channel: Channel<BusDriverMessage, !> =
-        service_host_client.subscribe_service("platform_bus.bus_driver").unwrap();
+    let platform_bus_bus_client =
+        BusDriverClient::new(service_host_client.subscribe_service("platform_bus.bus_driver").unwrap());
     // This allows us to talk to the PlatformBus as a device driver (to find supported USB devices).
-    let platform_bus_device_channel: Channel<DeviceDriverMessage, DeviceDriverRequest> =
-        service_host_client.subscribe_service("platform_bus.device_driver").unwrap();
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
 
     // Tell PlatformBus that we're interested in USB devices that are specified per-interface
     // (we need to parse their configurations to tell if they're HID devices). A HID device is not
     // supposed to indicate its class at the device level so we don't need to test for that.
-    platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![
+    let handoff_server = platform_bus_device_client
+        .register(vec![
             Filter::Matches(String::from("usb.class"), Property::Integer(0x00)),
             Filter::Matches(String::from("usb.sub_class"), Property::Integer(0x00)),
-        ]))
+        ])
         .unwrap();
 
     std::poplar::rt::spawn(async move {
         loop {
-            match platform_bus_device_channel.receive().await.unwrap() {
-                DeviceDriverRequest::QuerySupport(device_name, device_info) => {
+            match handoff_server.next().await.unwrap() {
+                DeviceHandoffCall::QuerySupport { name: device_name, device_info, responder } => {
                     info!(
                         "Platform bus asked if we can support device {} with info {:?}",
                         device_name, device_info
@@ -86,11 +89,12 @@ pub fn main() {
                         usb::descriptor::walk_configuration(configuration, &mut visitor);
                         visitor.0
                     };
-                    platform_bus_device_channel
-                        .send(&DeviceDriverMessage::CanSupport(device_name, supported))
-                        .unwrap();
+                    responder.reply(supported).unwrap();
                 }
-                DeviceDriverRequest::HandoffDevice(device_name, device_info, handoff_info) => {
+                DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                    responder.reply(()).unwrap();
+                }
+                DeviceHandoffCall::HandoffDevice { name: device_name, device_info, handoff_info } => {
                     info!("Started driving HID device '{}'", device_name);
 
                     let control_channel: Channel<DeviceControlMessage, DeviceResponse> =
@@ -99,6 +103,13 @@ pub fn main() {
                     let config_info = {
                         // TODO: this assumes only one configuration
                         let bytes = device_info.get_as_bytes("usb.config0").unwrap();
+                        /*
+                         * Real devices often have more than one interface (e.g. keyboards that provide
+                         * media keys through a second HID interface, or composite devices that aren't
+                         * only HID), so we look for the first HID interface and only use the descriptors
+                         * that belong to it.
+                         * TODO: drive every HID interface of the device
+                         */
                         #[derive(Default)]
                         struct ConfigInfo {
                             config_value: u8,
@@ -107,7 +118,12 @@ pub fn main() {
                             interface_setting: u8,
                             endpoint_num: u8,
                             packet_size: u16,
+                            /// The interval the endpoint should be polled at, in frames (milliseconds).
+                            interval: u8,
                             hid_report_len: u16,
+                            found_interface: bool,
+                            in_hid_interface: bool,
+                            found_endpoint: bool,
                         }
                         impl usb::descriptor::ConfigurationVisitor for ConfigInfo {
                             fn visit_configuration(&mut self, descriptor: &ConfigurationDescriptor) {
@@ -115,21 +131,37 @@ pub fn main() {
                             }
 
                             fn visit_interface(&mut self, descriptor: &InterfaceDescriptor) {
-                                self.interface_num = descriptor.interface_num;
-                                self.interface_protocol = descriptor.interface_protocol;
-                                self.interface_setting = descriptor.alternate_setting;
+                                self.in_hid_interface = !self.found_interface && descriptor.interface_class == 3;
+                                if self.in_hid_interface {
+                                    self.found_interface = true;
+                                    self.interface_num = descriptor.interface_num;
+                                    self.interface_protocol = descriptor.interface_protocol;
+                                    self.interface_setting = descriptor.alternate_setting;
+                                }
                             }
 
                             fn visit_endpoint(&mut self, descriptor: &EndpointDescriptor) {
-                                self.endpoint_num = descriptor.endpoint_address.get(EndpointAddress::NUMBER);
-                                self.packet_size = descriptor.max_packet_size;
+                                // Reports are sent over the interface's Interrupt IN endpoint
+                                let attributes = descriptor.attributes;
+                                if self.in_hid_interface
+                                    && !self.found_endpoint
+                                    && descriptor.endpoint_address.get(EndpointAddress::DIRECTION)
+                                    && attributes.get(EndpointAttributes::TRANFER_TYPE) == TransferType::Interrupt
+                                {
+                                    self.found_endpoint = true;
+                                    self.endpoint_num = descriptor.endpoint_address.get(EndpointAddress::NUMBER);
+                                    self.packet_size = descriptor.max_packet_size;
+                                    self.interval = descriptor.interval;
+                                }
                             }
 
                             fn visit_hid(&mut self, descriptor: &HidDescriptor) {
                                 // TODO: we might want to handle more descriptors than just the
                                 // Report one (or it might not come first).
-                                assert!(descriptor.descriptor_typ == 34);
-                                self.hid_report_len = descriptor.descriptor_length;
+                                if self.in_hid_interface {
+                                    assert!(descriptor.descriptor_typ == 34);
+                                    self.hid_report_len = descriptor.descriptor_length;
+                                }
                             }
                         }
                         let mut info = ConfigInfo::default();
@@ -137,13 +169,19 @@ pub fn main() {
                         info
                     };
 
+                    if !config_info.found_endpoint {
+                        warn!("HID device '{}' doesn't have an Interrupt IN endpoint. Ignoring.", device_name);
+                        continue;
+                    }
+
                     /*
                      * Register the device as a abstract HID device on the Platform Bus.
                      * TODO: we need to work out what devices actually are don't we...
                      */
-                    let (device_channel, device_channel_other_end) = Channel::<InputEvent, ()>::create().unwrap();
-                    // TODO: proper name
-                    let name = "usb-hid".to_string();
+                    let (device_channel, device_channel_other_end) = Channel::create().unwrap();
+                    let input_device = InputDeviceClient::new(device_channel);
+                    // Name the HID device after the USB device, so we can tell multiple devices apart
+                    let name = format!("{}.hid", device_name);
                     // TODO: make this a proper enum I think?
                     let typ = match config_info.interface_protocol {
                         0 => "none",
@@ -164,9 +202,7 @@ pub fn main() {
                         info.insert("hid.channel".to_string(), HandoffProperty::Channel(device_channel_other_end));
                         HandoffInfo(info)
                     };
-                    platform_bus_bus_channel
-                        .send(&BusDriverMessage::RegisterDevice(name, device_info, handoff_info))
-                        .unwrap();
+                    platform_bus_bus_client.register_device(name, device_info, handoff_info).unwrap();
 
                     std::poplar::rt::spawn(async move {
                         // Get the report descriptor
@@ -209,9 +245,6 @@ pub fn main() {
                          * cycles each has been pressed for. This is at the heart of the driver's
                          * ability to debounce key presses and then re-add key repetition in
                          * software.
-                         * TODO: this currently just polls as-fast-as-it-can. We probably want to
-                         * not do that, so add timing or move to the periodic schedule and do it
-                         * properly.
                          * TODO: some drivers debounce keys that are only pressed for e.g. a few
                          * ms. I don't know if that's needed for real hardware, but something to
                          * consider (esp if we ever get spurious key presses).
@@ -219,9 +252,22 @@ pub fn main() {
                          * timing of each cycle.
                          */
                         let mut pressed_keys = BTreeMap::<Usage, u8>::new();
+                        // The state of the modifier keys in the last report, so we can report changes to them
+                        let mut previous_state = KeyState::default();
+                        // Buttons are reported as being held in every report, so we track them to report changes
+                        let mut pressed_buttons = BTreeSet::<Button>::new();
+
+                        /*
+                         * Poll the endpoint at the interval the device asks for. This is given in
+                         * frames for full- and low-speed devices, which is what most HID devices are.
+                         * TODO: move to the periodic schedule and do this properly
+                         */
+                        let poll_interval = Duration::from_millis(config_info.interval.max(1) as u64);
 
                         info!("Listening to reports from HID device '{}'", device_name);
                         loop {
+                            std::poplar::rt::time::sleep(poll_interval).await;
+
                             control_channel
                                 .send(&DeviceControlMessage::InterruptTransferIn {
                                     endpoint: config_info.endpoint_num,
@@ -232,8 +278,25 @@ pub fn main() {
                             match response {
                                 DeviceResponse::Data(data) => {
                                     let report = report_desc.interpret(&data);
+
+                                    /*
+                                     * If more keys are held than fit in a report, keyboards fill the key
+                                     * array with `ErrorRollOver`. The report doesn't tell us which keys
+                                     * are held, so we ignore it and keep the last state we knew about.
+                                     */
+                                    if report.iter().any(|field| {
+                                        matches!(
+                                            field,
+                                            FieldValue::UntranslatedSelector { usage_page: 7, usage: 0x01 }
+                                        )
+                                    }) {
+                                        continue;
+                                    }
+
                                     let mut state = KeyState::default();
                                     let mut current_keys = BTreeSet::new();
+                                    let mut current_buttons = BTreeSet::new();
+                                    let (mut dx, mut dy) = (0, 0);
 
                                     for field in report {
                                         match field {
@@ -250,23 +313,19 @@ pub fn main() {
                                             }
 
                                             FieldValue::DynamicValue(Usage::X, value) => {
-                                                if value != 0 {
-                                                    device_channel.send(&InputEvent::RelX(value)).unwrap();
-                                                }
+                                                dx += value;
                                             }
                                             FieldValue::DynamicValue(Usage::Y, value) => {
-                                                if value != 0 {
-                                                    device_channel.send(&InputEvent::RelY(value)).unwrap();
-                                                }
+                                                dy += value;
                                             }
                                             FieldValue::DynamicValue(Usage::Z, value) => {
                                                 if value != 0 {
-                                                    device_channel.send(&InputEvent::RelZ(value)).unwrap();
+                                                    input_device.event(InputEvent::RelZ(value)).unwrap();
                                                 }
                                             }
                                             FieldValue::DynamicValue(Usage::Wheel, value) => {
                                                 if value != 0 {
-                                                    device_channel.send(&InputEvent::RelWheel(value)).unwrap();
+                                                    input_device.event(InputEvent::RelWheel(value)).unwrap();
                                                 }
                                             }
                                             FieldValue::DynamicValue(
@@ -277,29 +336,8 @@ pub fn main() {
                                                 | Usage::Button5),
                                                 value,
                                             ) => {
-                                                let map_button = |usage| match usage {
-                                                    Usage::Button1 => Key::BtnLeft,
-                                                    Usage::Button2 => Key::BtnRight,
-                                                    Usage::Button3 => Key::BtnMiddle,
-                                                    Usage::Button4 => Key::BtnSide,
-                                                    Usage::Button5 => Key::BtnExtra,
-                                                    _ => unreachable!(),
-                                                };
-
                                                 if value != 0 {
-                                                    device_channel
-                                                        .send(&InputEvent::KeyPressed {
-                                                            key: map_button(usage),
-                                                            state: KeyState::default(),
-                                                        })
-                                                        .unwrap();
-                                                } else {
-                                                    device_channel
-                                                        .send(&InputEvent::KeyReleased {
-                                                            key: map_button(usage),
-                                                            state: KeyState::default(),
-                                                        })
-                                                        .unwrap();
+                                                    current_buttons.insert(map_button_usage(usage));
                                                 }
                                             }
 
@@ -337,31 +375,55 @@ pub fn main() {
                                         }
                                     }
 
+                                    // Report movement along both axes together, so the pointer moves smoothly
+                                    if dx != 0 || dy != 0 {
+                                        input_device.event(InputEvent::PointerMoved { dx, dy }).unwrap();
+                                    }
+                                    for &button in current_buttons.difference(&pressed_buttons) {
+                                        input_device.event(InputEvent::ButtonPressed(button)).unwrap();
+                                    }
+                                    for &button in pressed_buttons.difference(&current_buttons) {
+                                        input_device.event(InputEvent::ButtonReleased(button)).unwrap();
+                                    }
+                                    pressed_buttons = current_buttons;
+
+                                    for (key, pressed) in modifier_changes(previous_state, state) {
+                                        let event = if pressed {
+                                            InputEvent::KeyPressed { key, state }
+                                        } else {
+                                            InputEvent::KeyReleased { key, state }
+                                        };
+                                        input_device.event(event).unwrap();
+                                    }
+                                    previous_state = state;
+
                                     pressed_keys = pressed_keys
                                         .into_iter()
                                         .filter_map(|(usage, count)| {
                                             if current_keys.take(&usage).is_some() {
                                                 Some((usage, count + 1))
                                             } else {
-                                                device_channel
-                                                    .send(&InputEvent::KeyReleased {
-                                                        key: map_key_usage(usage),
-                                                        state,
-                                                    })
-                                                    .unwrap();
+                                                if let Some(key) = map_key_usage(usage) {
+                                                    input_device
+                                                        .event(InputEvent::KeyReleased { key, state })
+                                                        .unwrap();
+                                                }
                                                 None
                                             }
                                         })
                                         .collect();
                                     for new_key in current_keys.into_iter() {
                                         pressed_keys.insert(new_key, 1);
-                                        device_channel
-                                            .send(&InputEvent::KeyPressed { key: map_key_usage(new_key), state })
-                                            .unwrap();
+                                        match map_key_usage(new_key) {
+                                            Some(key) => {
+                                                input_device.event(InputEvent::KeyPressed { key, state }).unwrap()
+                                            }
+                                            None => warn!("Key with unsupported usage pressed: {:?}", new_key),
+                                        }
                                     }
                                 }
                                 DeviceResponse::NoData => {}
-                                _ => panic!("Unexpected message during report loop"),
+                                other => warn!("Unexpected message during report loop: {:?}", other),
                             }
                         }
                     });
@@ -373,146 +435,177 @@ pub fn main() {
     std::poplar::rt::enter_loop();
 }
 
-fn map_key_usage(usage: Usage) -> Key {
+/// Find the modifier keys that have been pressed or released between two reports. Returns each key that has
+/// changed, and whether it is now pressed.
+fn modifier_changes(old: KeyState, new: KeyState) -> impl Iterator<Item = (Key, bool)> {
+    [
+        (Key::KeyLeftControl, old.left_ctrl, new.left_ctrl),
+        (Key::KeyLeftShift, old.left_shift, new.left_shift),
+        (Key::KeyLeftAlt, old.left_alt, new.left_alt),
+        (Key::KeyLeftGui, old.left_gui, new.left_gui),
+        (Key::KeyRightControl, old.right_ctrl, new.right_ctrl),
+        (Key::KeyRightShift, old.right_shift, new.right_shift),
+        (Key::KeyRightAlt, old.right_alt, new.right_alt),
+        (Key::KeyRightGui, old.right_gui, new.right_gui),
+    ]
+    .into_iter()
+    .filter(|(_, old, new)| old != new)
+    .map(|(key, _, new)| (key, new))
+}
+
+fn map_button_usage(usage: Usage) -> Button {
+    match usage {
+        Usage::Button1 => Button::Left,
+        Usage::Button2 => Button::Right,
+        Usage::Button3 => Button::Middle,
+        Usage::Button4 => Button::Side,
+        Usage::Button5 => Button::Extra,
+        _ => panic!("Usage {:?} is not a button", usage),
+    }
+}
+
+/// Map a usage from the Keyboard/Keypad page to the key it represents. Returns `None` for usages that aren't
+/// keys, or that we don't support.
+fn map_key_usage(usage: Usage) -> Option<Key> {
     match usage {
-        Usage::KeyA => Key::KeyA,
-        Usage::KeyB => Key::KeyB,
-        Usage::KeyC => Key::KeyC,
-        Usage::KeyD => Key::KeyD,
-        Usage::KeyE => Key::KeyE,
-        Usage::KeyF => Key::KeyF,
-        Usage::KeyG => Key::KeyG,
-        Usage::KeyH => Key::KeyH,
-        Usage::KeyI => Key::KeyI,
-        Usage::KeyJ => Key::KeyJ,
-        Usage::KeyK => Key::KeyK,
-        Usage::KeyL => Key::KeyL,
-        Usage::KeyM => Key::KeyM,
-        Usage::KeyN => Key::KeyN,
-        Usage::KeyO => Key::KeyO,
-        Usage::KeyP => Key::KeyP,
-        Usage::KeyQ => Key::KeyQ,
-        Usage::KeyR => Key::KeyR,
-        Usage::KeyS => Key::KeyS,
-        Usage::KeyT => Key::KeyT,
-        Usage::KeyU => Key::KeyU,
-        Usage::KeyV => Key::KeyV,
-        Usage::KeyW => Key::KeyW,
-        Usage::KeyX => Key::KeyX,
-        Usage::KeyY => Key::KeyY,
-        Usage::KeyZ => Key::KeyZ,
-        Usage::Key1 => Key::Key1,
-        Usage::Key2 => Key::Key2,
-        Usage::Key3 => Key::Key3,
-        Usage::Key4 => Key::Key4,
-        Usage::Key5 => Key::Key5,
-        Usage::Key6 => Key::Key6,
-        Usage::Key7 => Key::Key7,
-        Usage::Key8 => Key::Key8,
-        Usage::Key9 => Key::Key9,
-        Usage::Key0 => Key::Key0,
-        Usage::KeyReturn => Key::KeyReturn,
-        Usage::KeyEscape => Key::KeyEscape,
-        Usage::KeyDelete => Key::KeyDelete,
-        Usage::KeyTab => Key::KeyTab,
-        Usage::KeySpace => Key::KeySpace,
-        Usage::KeyDash => Key::KeyDash,
-        Usage::KeyEquals => Key::KeyEquals,
-        Usage::KeyLeftBracket => Key::KeyLeftBracket,
-        Usage::KeyRightBracket => Key::KeyRightBracket,
-        Usage::KeyForwardSlash => Key::KeyForwardSlash,
-        Usage::KeyPound => Key::KeyPound,
-        Usage::KeySemicolon => Key::KeySemicolon,
-        Usage::KeyApostrophe => Key::KeyApostrophe,
-        Usage::KeyGrave => Key::KeyGrave,
-        Usage::KeyComma => Key::KeyComma,
-        Usage::KeyDot => Key::KeyDot,
-        Usage::KeyBackSlash => Key::KeyBackSlash,
-        Usage::KeyCapslock => Key::KeyCapslock,
-        Usage::KeyF1 => Key::KeyF1,
-        Usage::KeyF2 => Key::KeyF2,
-        Usage::KeyF3 => Key::KeyF3,
-        Usage::KeyF4 => Key::KeyF4,
-        Usage::KeyF5 => Key::KeyF5,
-        Usage::KeyF6 => Key::KeyF6,
-        Usage::KeyF7 => Key::KeyF7,
-        Usage::KeyF8 => Key::KeyF8,
-        Usage::KeyF9 => Key::KeyF9,
-        Usage::KeyF10 => Key::KeyF10,
-        Usage::KeyF11 => Key::KeyF11,
-        Usage::KeyF12 => Key::KeyF12,
-        Usage::KeyPrintScreen => Key::KeyPrintScreen,
-        Usage::KeyScrolllock => Key::KeyScrolllock,
-        Usage::KeyPause => Key::KeyPause,
-        Usage::KeyInsert => Key::KeyInsert,
-        Usage::KeyHome => Key::KeyHome,
-        Usage::KeyPageUp => Key::KeyPageUp,
-        Usage::KeyDeleteForward => Key::KeyDeleteForward,
-        Usage::KeyEnd => Key::KeyEnd,
-        Usage::KeyPageDown => Key::KeyPageDown,
-        Usage::KeyRightArrow => Key::KeyRightArrow,
-        Usage::KeyLeftArrow => Key::KeyLeftArrow,
-        Usage::KeyDownArrow => Key::KeyDownArrow,
-        Usage::KeyUpArrow => Key::KeyUpArrow,
-        Usage::KeyNumlock => Key::KeyNumlock,
-        Usage::KeypadSlash => Key::KeypadSlash,
-        Usage::KeypadAsterix => Key::KeypadAsterix,
-        Usage::KeypadDash => Key::KeypadDash,
-        Usage::KeypadPlus => Key::KeypadPlus,
-        Usage::KeypadEnter => Key::KeypadEnter,
-        Usage::Keypad1 => Key::Keypad1,
-        Usage::Keypad2 => Key::Keypad2,
-        Usage::Keypad3 => Key::Keypad3,
-        Usage::Keypad4 => Key::Keypad4,
-        Usage::Keypad5 => Key::Keypad5,
-        Usage::Keypad6 => Key::Keypad6,
-        Usage::Keypad7 => Key::Keypad7,
-        Usage::Keypad8 => Key::Keypad8,
-        Usage::Keypad9 => Key::Keypad9,
-        Usage::Keypad0 => Key::Keypad0,
-        Usage::KeypadDot => Key::KeypadDot,
-        Usage::KeypadNonUsBackSlash => Key::KeypadNonUsBackSlash,
-        Usage::KeyApplication => Key::KeyApplication,
-        Usage::KeyPower => Key::KeyPower,
-        Usage::KeypadEquals => Key::KeypadEquals,
-        Usage::KeyF13 => Key::KeyF13,
-        Usage::KeyF14 => Key::KeyF14,
-        Usage::KeyF15 => Key::KeyF15,
-        Usage::KeyF16 => Key::KeyF16,
-        Usage::KeyF17 => Key::KeyF17,
-        Usage::KeyF18 => Key::KeyF18,
-        Usage::KeyF19 => Key::KeyF19,
-        Usage::KeyF20 => Key::KeyF20,
-        Usage::KeyF21 => Key::KeyF21,
-        Usage::KeyF22 => Key::KeyF22,
-        Usage::KeyF23 => Key::KeyF23,
-        Usage::KeyF24 => Key::KeyF24,
-        Usage::KeyExecute => Key::KeyExecute,
-        Usage::KeyHelp => Key::KeyHelp,
-        Usage::KeyMenu => Key::KeyMenu,
-        Usage::KeySelect => Key::KeySelect,
-        Usage::KeyStop => Key::KeyStop,
-        Usage::KeyAgain => Key::KeyAgain,
-        Usage::KeyUndo => Key::KeyUndo,
-        Usage::KeyCut => Key::KeyCut,
-        Usage::KeyCopy => Key::KeyCopy,
-        Usage::KeyPaste => Key::KeyPaste,
-        Usage::KeyFind => Key::KeyFind,
-        Usage::KeyMute => Key::KeyMute,
-        Usage::KeyVolumeUp => Key::KeyVolumeUp,
-        Usage::KeyVolumeDown => Key::KeyVolumeDown,
-        Usage::KeyLockingCapslock => Key::KeyLockingCapslock,
-        Usage::KeyLockingNumlock => Key::KeyLockingNumlock,
-        Usage::KeyLockingScrolllock => Key::KeyLockingScrolllock,
-        Usage::KeypadComma => Key::KeypadComma,
-        Usage::KeyLeftControl => Key::KeyLeftControl,
-        Usage::KeyLeftShift => Key::KeyLeftShift,
-        Usage::KeyLeftAlt => Key::KeyLeftAlt,
-        Usage::KeyLeftGui => Key::KeyLeftGui,
-        Usage::KeyRightControl => Key::KeyRightControl,
-        Usage::KeyRightShift => Key::KeyRightShift,
-        Usage::KeyRightAlt => Key::KeyRightAlt,
-        Usage::KeyRightGui => Key::KeyRightGui,
-        _ => panic!("Unknown usage: {:?}", usage),
+        Usage::KeyA => Some(Key::KeyA),
+        Usage::KeyB => Some(Key::KeyB),
+        Usage::KeyC => Some(Key::KeyC),
+        Usage::KeyD => Some(Key::KeyD),
+        Usage::KeyE => Some(Key::KeyE),
+        Usage::KeyF => Some(Key::KeyF),
+        Usage::KeyG => Some(Key::KeyG),
+        Usage::KeyH => Some(Key::KeyH),
+        Usage::KeyI => Some(Key::KeyI),
+        Usage::KeyJ => Some(Key::KeyJ),
+        Usage::KeyK => Some(Key::KeyK),
+        Usage::KeyL => Some(Key::KeyL),
+        Usage::KeyM => Some(Key::KeyM),
+        Usage::KeyN => Some(Key::KeyN),
+        Usage::KeyO => Some(Key::KeyO),
+        Usage::KeyP => Some(Key::KeyP),
+        Usage::KeyQ => Some(Key::KeyQ),
+        Usage::KeyR => Some(Key::KeyR),
+        Usage::KeyS => Some(Key::KeyS),
+        Usage::KeyT => Some(Key::KeyT),
+        Usage::KeyU => Some(Key::KeyU),
+        Usage::KeyV => Some(Key::KeyV),
+        Usage::KeyW => Some(Key::KeyW),
+        Usage::KeyX => Some(Key::KeyX),
+        Usage::KeyY => Some(Key::KeyY),
+        Usage::KeyZ => Some(Key::KeyZ),
+        Usage::Key1 => Some(Key::Key1),
+        Usage::Key2 => Some(Key::Key2),
+        Usage::Key3 => Some(Key::Key3),
+        Usage::Key4 => Some(Key::Key4),
+        Usage::Key5 => Some(Key::Key5),
+        Usage::Key6 => Some(Key::Key6),
+        Usage::Key7 => Some(Key::Key7),
+        Usage::Key8 => Some(Key::Key8),
+        Usage::Key9 => Some(Key::Key9),
+        Usage::Key0 => Some(Key::Key0),
+        Usage::KeyReturn => Some(Key::KeyReturn),
+        Usage::KeyEscape => Some(Key::KeyEscape),
+        Usage::KeyDelete => Some(Key::KeyDelete),
+        Usage::KeyTab => Some(Key::KeyTab),
+        Usage::KeySpace => Some(Key::KeySpace),
+        Usage::KeyDash => Some(Key::KeyDash),
+        Usage::KeyEquals => Some(Key::KeyEquals),
+        Usage::KeyLeftBracket => Some(Key::KeyLeftBracket),
+        Usage::KeyRightBracket => Some(Key::KeyRightBracket),
+        Usage::KeyForwardSlash => Some(Key::KeyForwardSlash),
+        Usage::KeyPound => Some(Key::KeyPound),
+        Usage::KeySemicolon => Some(Key::KeySemicolon),
+        Usage::KeyApostrophe => Some(Key::KeyApostrophe),
+        Usage::KeyGrave => Some(Key::KeyGrave),
+        Usage::KeyComma => Some(Key::KeyComma),
+        Usage::KeyDot => Some(Key::KeyDot),
+        Usage::KeyBackSlash => Some(Key::KeyBackSlash),
+        Usage::KeyCapslock => Some(Key::KeyCapslock),
+        Usage::KeyF1 => Some(Key::KeyF1),
+        Usage::KeyF2 => Some(Key::KeyF2),
+        Usage::KeyF3 => Some(Key::KeyF3),
+        Usage::KeyF4 => Some(Key::KeyF4),
+        Usage::KeyF5 => Some(Key::KeyF5),
+        Usage::KeyF6 => Some(Key::KeyF6),
+        Usage::KeyF7 => Some(Key::KeyF7),
+        Usage::KeyF8 => Some(Key::KeyF8),
+        Usage::KeyF9 => Some(Key::KeyF9),
+        Usage::KeyF10 => Some(Key::KeyF10),
+        Usage::KeyF11 => Some(Key::KeyF11),
+        Usage::KeyF12 => Some(Key::KeyF12),
+        Usage::KeyPrintScreen => Some(Key::KeyPrintScreen),
+        Usage::KeyScrolllock => Some(Key::KeyScrolllock),
+        Usage::KeyPause => Some(Key::KeyPause),
+        Usage::KeyInsert => Some(Key::KeyInsert),
+        Usage::KeyHome => Some(Key::KeyHome),
+        Usage::KeyPageUp => Some(Key::KeyPageUp),
+        Usage::KeyDeleteForward => Some(Key::KeyDeleteForward),
+        Usage::KeyEnd => Some(Key::KeyEnd),
+        Usage::KeyPageDown => Some(Key::KeyPageDown),
+        Usage::KeyRightArrow => Some(Key::KeyRightArrow),
+        Usage::KeyLeftArrow => Some(Key::KeyLeftArrow),
+        Usage::KeyDownArrow => Some(Key::KeyDownArrow),
+        Usage::KeyUpArrow => Some(Key::KeyUpArrow),
+        Usage::KeyNumlock => Some(Key::KeyNumlock),
+        Usage::KeypadSlash => Some(Key::KeypadSlash),
+        Usage::KeypadAsterix => Some(Key::KeypadAsterix),
+        Usage::KeypadDash => Some(Key::KeypadDash),
+        Usage::KeypadPlus => Some(Key::KeypadPlus),
+        Usage::KeypadEnter => Some(Key::KeypadEnter),
+        Usage::Keypad1 => Some(Key::Keypad1),
+        Usage::Keypad2 => Some(Key::Keypad2),
+        Usage::Keypad3 => Some(Key::Keypad3),
+        Usage::Keypad4 => Some(Key::Keypad4),
+        Usage::Keypad5 => Some(Key::Keypad5),
+        Usage::Keypad6 => Some(Key::Keypad6),
+        Usage::Keypad7 => Some(Key::Keypad7),
+        Usage::Keypad8 => Some(Key::Keypad8),
+        Usage::Keypad9 => Some(Key::Keypad9),
+        Usage::Keypad0 => Some(Key::Keypad0),
+        Usage::KeypadDot => Some(Key::KeypadDot),
+        Usage::KeypadNonUsBackSlash => Some(Key::KeypadNonUsBackSlash),
+        Usage::KeyApplication => Some(Key::KeyApplication),
+        Usage::KeyPower => Some(Key::KeyPower),
+        Usage::KeypadEquals => Some(Key::KeypadEquals),
+        Usage::KeyF13 => Some(Key::KeyF13),
+        Usage::KeyF14 => Some(Key::KeyF14),
+        Usage::KeyF15 => Some(Key::KeyF15),
+        Usage::KeyF16 => Some(Key::KeyF16),
+        Usage::KeyF17 => Some(Key::KeyF17),
+        Usage::KeyF18 => Some(Key::KeyF18),
+        Usage::KeyF19 => Some(Key::KeyF19),
+        Usage::KeyF20 => Some(Key::KeyF20),
+        Usage::KeyF21 => Some(Key::KeyF21),
+        Usage::KeyF22 => Some(Key::KeyF22),
+        Usage::KeyF23 => Some(Key::KeyF23),
+        Usage::KeyF24 => Some(Key::KeyF24),
+        Usage::KeyExecute => Some(Key::KeyExecute),
+        Usage::KeyHelp => Some(Key::KeyHelp),
+        Usage::KeyMenu => Some(Key::KeyMenu),
+        Usage::KeySelect => Some(Key::KeySelect),
+        Usage::KeyStop => Some(Key::KeyStop),
+        Usage::KeyAgain => Some(Key::KeyAgain),
+        Usage::KeyUndo => Some(Key::KeyUndo),
+        Usage::KeyCut => Some(Key::KeyCut),
+        Usage::KeyCopy => Some(Key::KeyCopy),
+        Usage::KeyPaste => Some(Key::KeyPaste),
+        Usage::KeyFind => Some(Key::KeyFind),
+        Usage::KeyMute => Some(Key::KeyMute),
+        Usage::KeyVolumeUp => Some(Key::KeyVolumeUp),
+        Usage::KeyVolumeDown => Some(Key::KeyVolumeDown),
+        Usage::KeyLockingCapslock => Some(Key::KeyLockingCapslock),
+        Usage::KeyLockingNumlock => Some(Key::KeyLockingNumlock),
+        Usage::KeyLockingScrolllock => Some(Key::KeyLockingScrolllock),
+        Usage::KeypadComma => Some(Key::KeypadComma),
+        Usage::KeyLeftControl => Some(Key::KeyLeftControl),
+        Usage::KeyLeftShift => Some(Key::KeyLeftShift),
+        Usage::KeyLeftAlt => Some(Key::KeyLeftAlt),
+        Usage::KeyLeftGui => Some(Key::KeyLeftGui),
+        Usage::KeyRightControl => Some(Key::KeyRightControl),
+        Usage::KeyRightShift => Some(Key::KeyRightShift),
+        Usage::KeyRightAlt => Some(Key::KeyRightAlt),
+        Usage::KeyRightGui => Some(Key::KeyRightGui),
+        _ => None,
     }
 }
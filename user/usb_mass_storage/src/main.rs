@@ -0,0 +1,498 @@
+//! `usb_mass_storage` drives USB Mass Storage devices (such as flash drives) that use the SCSI command set over
+//! the Bulk-Only Transport. Each Logical Unit of a device is provided to other tasks as a block device.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, warn};
+use platform_bus::{DeviceDriverClient, DeviceHandoffCall, Filter, Property};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use spinning_top::Spinlock;
+use std::{
+    poplar::{
+        block::{
+            BlockDeviceInfo,
+            BlockError,
+            BlockRequest,
+            BlockResponse,
+            BLOCK_DEVICE_SERVICE,
+            MAX_TRANSFER_SIZE,
+        },
+        channel::Channel,
+        early_logger::EarlyLogger,
+    },
+    sync::Arc,
+};
+use usb::{
+    descriptor::{
+        ConfigurationDescriptor,
+        EndpointAddress,
+        EndpointAttributes,
+        EndpointDescriptor,
+        InterfaceDescriptor,
+        TransferType,
+    },
+    mass_storage::{
+        scsi::{self, Capacity, InquiryData},
+        CommandBlockWrapper,
+        CommandStatus,
+        CommandStatusWrapper,
+        DataDirection,
+        COMMAND_STATUS_WRAPPER_LENGTH,
+        GET_MAX_LUN,
+    },
+    DeviceControlMessage,
+    DeviceResponse,
+    EndpointDirection,
+};
+
+/// The number of Logical Units we've provided as block devices, across every device we drive. Used to give
+/// each its own service name.
+static NUM_BLOCK_DEVICES: AtomicUsize = AtomicUsize::new(0);
+/// Every Logical Unit we're serving, so their caches can be flushed before the system is shut down.
+static LOGICAL_UNITS: Spinlock<Vec<Arc<LogicalUnit>>> = Spinlock::new(Vec::new());
+
+/// How many times to try to get a Logical Unit ready before giving up on it. Devices often fail the first
+/// command after they're reset, to report that they've been reset.
+const READY_ATTEMPTS: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CommandError {
+    /// A transfer to or from the device failed.
+    Transfer,
+    /// The device sent back a status we didn't expect.
+    InvalidStatus,
+    /// The device reported that the command failed.
+    Failed,
+    /// The device didn't understand the transfer.
+    PhaseError,
+}
+
+/// The data transferred by a command.
+enum Data<'a> {
+    None,
+    /// Read this many bytes from the device.
+    In(u32),
+    Out(&'a [u8]),
+}
+
+/// The parts of a device's configuration we need to use its Mass Storage interface.
+#[derive(Default)]
+struct ConfigInfo {
+    config_value: u8,
+    interface_num: u8,
+    bulk_in: Option<(u8, u16)>,
+    bulk_out: Option<(u8, u16)>,
+    found_interface: bool,
+    in_interface: bool,
+}
+
+impl usb::descriptor::ConfigurationVisitor for ConfigInfo {
+    fn visit_configuration(&mut self, descriptor: &ConfigurationDescriptor) {
+        self.config_value = descriptor.configuration_value;
+    }
+
+    fn visit_interface(&mut self, descriptor: &InterfaceDescriptor) {
+        self.in_interface = !self.found_interface && is_bulk_only_interface(descriptor);
+        if self.in_interface {
+            self.found_interface = true;
+            self.interface_num = descriptor.interface_num;
+        }
+    }
+
+    fn visit_endpoint(&mut self, descriptor: &EndpointDescriptor) {
+        let attributes = descriptor.attributes;
+        if !self.in_interface || attributes.get(EndpointAttributes::TRANFER_TYPE) != TransferType::Bulk {
+            return;
+        }
+
+        let endpoint = (descriptor.endpoint_address.get(EndpointAddress::NUMBER), descriptor.max_packet_size);
+        if descriptor.endpoint_address.get(EndpointAddress::DIRECTION) {
+            self.bulk_in.get_or_insert(endpoint);
+        } else {
+            self.bulk_out.get_or_insert(endpoint);
+        }
+    }
+}
+
+fn is_bulk_only_interface(descriptor: &InterfaceDescriptor) -> bool {
+    descriptor.interface_class == usb::mass_storage::CLASS
+        && descriptor.interface_subclass == usb::mass_storage::SUBCLASS_SCSI
+        && descriptor.interface_protocol == usb::mass_storage::PROTOCOL_BULK_ONLY
+}
+
+/// A Mass Storage device. Commands are made synchronously, and so commands to different Logical Units of the
+/// same device are serialized by the lock on the device.
+struct MassStorageDevice {
+    control_channel: Channel<DeviceControlMessage, DeviceResponse>,
+    bulk_in: u8,
+    bulk_out: u8,
+    next_tag: u32,
+}
+
+impl MassStorageDevice {
+    /// Issue a SCSI command to a Logical Unit of the device. Returns any data read from the device.
+    fn command(&mut self, lun: u8, command: &[u8], data: Data) -> Result<Vec<u8>, CommandError> {
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1);
+
+        let (data_length, direction) = match data {
+            Data::None => (0, DataDirection::Out),
+            Data::In(length) => (length, DataDirection::In),
+            Data::Out(data) => (data.len() as u32, DataDirection::Out),
+        };
+        let wrapper = CommandBlockWrapper { tag, data_length, direction, lun, command }.to_bytes();
+        if !self.bulk_out(wrapper.to_vec()) {
+            self.reset_recovery();
+            return Err(CommandError::Transfer);
+        }
+
+        /*
+         * If the device stalls the data stage (e.g. because the command failed), it still sends the command's
+         * status once the endpoint has been cleared.
+         */
+        let result = match data {
+            Data::None => Vec::new(),
+            Data::In(length) => match self.bulk_in(length) {
+                Some(data) => data,
+                None => {
+                    self.clear_halt(self.bulk_in, EndpointDirection::In);
+                    Vec::new()
+                }
+            },
+            Data::Out(data) => {
+                if !self.bulk_out(data.to_vec()) {
+                    self.clear_halt(self.bulk_out, EndpointDirection::Out);
+                }
+                Vec::new()
+            }
+        };
+
+        // If getting the status fails, the endpoint has stalled. Clear it and try again.
+        let status = match self.bulk_in(COMMAND_STATUS_WRAPPER_LENGTH as u32) {
+            Some(status) => status,
+            None => {
+                self.clear_halt(self.bulk_in, EndpointDirection::In);
+                self.bulk_in(COMMAND_STATUS_WRAPPER_LENGTH as u32).ok_or(CommandError::Transfer)?
+            }
+        };
+        let status = match CommandStatusWrapper::parse(&status) {
+            Some(status) if status.tag == tag => status,
+            _ => {
+                self.reset_recovery();
+                return Err(CommandError::InvalidStatus);
+            }
+        };
+
+        match status.status {
+            CommandStatus::Passed => Ok(result),
+            CommandStatus::Failed => Err(CommandError::Failed),
+            CommandStatus::PhaseError => {
+                self.reset_recovery();
+                Err(CommandError::PhaseError)
+            }
+        }
+    }
+
+    /// Get the Logical Unit ready to be used, and find its capacity.
+    fn init_lun(&mut self, lun: u8) -> Result<Capacity, CommandError> {
+        let inquiry = self.command(lun, &scsi::inquiry(), Data::In(scsi::INQUIRY_LENGTH as u32))?;
+        let inquiry = InquiryData::parse(&inquiry).ok_or(CommandError::InvalidStatus)?;
+        info!("LUN {}: {:?}", lun, inquiry);
+        if inquiry.device_type != InquiryData::DIRECT_ACCESS {
+            warn!("LUN {} is not a direct-access block device (type {:#x})", lun, inquiry.device_type);
+            return Err(CommandError::Failed);
+        }
+
+        let mut attempts = 0;
+        loop {
+            match self.command(lun, &scsi::test_unit_ready(), Data::None) {
+                Ok(_) => break,
+                Err(CommandError::Failed) if attempts < READY_ATTEMPTS => {
+                    // Find out why the unit isn't ready. This also clears the condition that caused it.
+                    let sense =
+                        self.command(lun, &scsi::request_sense(), Data::In(scsi::REQUEST_SENSE_LENGTH as u32))?;
+                    info!("LUN {} is not ready (sense key = {:?}). Retrying.", lun, scsi::sense_key(&sense));
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let capacity = self.command(lun, &scsi::read_capacity_10(), Data::In(scsi::READ_CAPACITY_10_LENGTH))?;
+        Capacity::parse(&capacity).ok_or(CommandError::InvalidStatus)
+    }
+
+    fn bulk_in(&self, length: u32) -> Option<Vec<u8>> {
+        self.control_channel
+            .send(&DeviceControlMessage::BulkTransferIn { endpoint: self.bulk_in, length })
+            .unwrap();
+        match self.control_channel.receive_blocking().unwrap() {
+            DeviceResponse::Data(data) => Some(data),
+            DeviceResponse::TransferFailed => None,
+            other => panic!("Unexpected response to bulk transfer: {:?}", other),
+        }
+    }
+
+    fn bulk_out(&self, data: Vec<u8>) -> bool {
+        self.control_channel
+            .send(&DeviceControlMessage::BulkTransferOut { endpoint: self.bulk_out, data })
+            .unwrap();
+        match self.control_channel.receive_blocking().unwrap() {
+            DeviceResponse::TransferComplete => true,
+            DeviceResponse::TransferFailed => false,
+            other => panic!("Unexpected response to bulk transfer: {:?}", other),
+        }
+    }
+
+    fn clear_halt(&self, endpoint: u8, direction: EndpointDirection) {
+        self.control_channel.send(&DeviceControlMessage::ClearHalt { endpoint, direction }).unwrap();
+    }
+
+    /// Get the device back into a known state after it has become confused about which stage of a command it's
+    /// in.
+    // TODO: this should also issue a Bulk-Only Mass Storage Reset request, but we can't make class-specific
+    // requests that transfer data to the device yet
+    fn reset_recovery(&self) {
+        self.clear_halt(self.bulk_in, EndpointDirection::In);
+        self.clear_halt(self.bulk_out, EndpointDirection::Out);
+    }
+}
+
+/// A Logical Unit of a device, provided as a block device.
+struct LogicalUnit {
+    device: Arc<Spinlock<MassStorageDevice>>,
+    lun: u8,
+    capacity: Capacity,
+}
+
+impl LogicalUnit {
+    fn handle_request(&self, request: BlockRequest) -> BlockResponse {
+        let result = match request {
+            BlockRequest::GetInfo => Ok(BlockResponse::Info(BlockDeviceInfo {
+                block_size: self.capacity.block_size,
+                num_blocks: self.capacity.num_blocks,
+                // TODO: find out if the device is write-protected with `MODE SENSE`
+                read_only: false,
+            })),
+            BlockRequest::Read { block, count } => self.read(block, count).map(BlockResponse::Data),
+            BlockRequest::Write { block, data } => self.write(block, &data).map(|()| BlockResponse::Done),
+            BlockRequest::Flush => self.flush().map(|()| BlockResponse::Done),
+        };
+        result.unwrap_or_else(BlockResponse::Error)
+    }
+
+    fn read(&self, block: u64, count: u32) -> Result<Vec<u8>, BlockError> {
+        let length = self.check_range(block, count as usize * self.capacity.block_size as usize)?;
+        self.device
+            .lock()
+            .command(self.lun, &scsi::read_10(block as u32, count as u16), Data::In(length as u32))
+            .map_err(|_| BlockError::DeviceError)
+    }
+
+    fn write(&self, block: u64, data: &[u8]) -> Result<(), BlockError> {
+        if data.len() % self.capacity.block_size as usize != 0 {
+            return Err(BlockError::InvalidLength);
+        }
+        self.check_range(block, data.len())?;
+        let count = data.len() / self.capacity.block_size as usize;
+        self.device
+            .lock()
+            .command(self.lun, &scsi::write_10(block as u32, count as u16), Data::Out(data))
+            .map(|_| ())
+            .map_err(|_| BlockError::DeviceError)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        /*
+         * Not every device supports `SYNCHRONIZE CACHE`. Those that don't are expected to not cache writes,
+         * so there is nothing to do if it fails.
+         */
+        let _ = self.device.lock().command(self.lun, &scsi::synchronize_cache_10(), Data::None);
+        Ok(())
+    }
+
+    /// Check that a transfer of `length` bytes, starting at `block`, can be made. Returns the length.
+    fn check_range(&self, block: u64, length: usize) -> Result<usize, BlockError> {
+        if length > MAX_TRANSFER_SIZE {
+            return Err(BlockError::TooLarge);
+        }
+        let end = block
+            .checked_add((length / self.capacity.block_size as usize) as u64)
+            .ok_or(BlockError::OutOfRange)?;
+        if end > self.capacity.num_blocks {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(length)
+    }
+}
+
+/// Provide a Logical Unit to other tasks as a block device. The first is provided as `block.device`, and any
+/// more as `block.device.1`, `block.device.2`, etc.
+fn serve_logical_unit(unit: LogicalUnit, service_host_client: &ServiceHostClient) {
+    let service_name = match NUM_BLOCK_DEVICES.fetch_add(1, Ordering::Relaxed) {
+        0 => BLOCK_DEVICE_SERVICE.to_string(),
+        n => format!("{}.{}", BLOCK_DEVICE_SERVICE, n),
+    };
+    info!(
+        "Providing LUN {} ({} blocks of {} bytes) as '{}'",
+        unit.lun, unit.capacity.num_blocks, unit.capacity.block_size, service_name
+    );
+
+    let unit = Arc::new(unit);
+    LOGICAL_UNITS.lock().push(unit.clone());
+    let service_channel = service_host_client.register_service(service_name).unwrap();
+    std::poplar::rt::spawn(async move {
+        loop {
+            match service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    info!("Task '{}' is using LUN {}", name, unit.lun);
+                    let channel: Channel<BlockResponse, BlockRequest> = Channel::new_from_handle(channel);
+                    std::poplar::rt::spawn({
+                        let unit = unit.clone();
+                        async move {
+                            loop {
+                                let request = channel.receive().await.unwrap();
+                                let response = unit.handle_request(request);
+                                channel.send(&response).unwrap();
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    });
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("USB Mass Storage driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = ServiceHostClient::new();
+    // This allows us to talk to the PlatformBus as a device driver (to find supported USB devices).
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    // Like HID devices, Mass Storage devices specify their class per-interface
+    let handoff_server = platform_bus_device_client
+        .register(vec![
+            Filter::Matches(String::from("usb.class"), Property::Integer(0x00)),
+            Filter::Matches(String::from("usb.sub_class"), Property::Integer(0x00)),
+        ])
+        .unwrap();
+
+    std::poplar::rt::spawn(async move {
+        loop {
+            match handoff_server.next().await.unwrap() {
+                DeviceHandoffCall::QuerySupport { device_info, responder, .. } => {
+                    // TODO: consider each config if multiple?
+                    let configuration = device_info.get_as_bytes("usb.config0").unwrap();
+
+                    struct Visitor(pub bool);
+                    impl usb::descriptor::ConfigurationVisitor for Visitor {
+                        fn visit_interface(&mut self, descriptor: &InterfaceDescriptor) {
+                            if is_bulk_only_interface(descriptor) {
+                                self.0 = true;
+                            }
+                        }
+                    }
+
+                    let supported = {
+                        let mut visitor = Visitor(false);
+                        usb::descriptor::walk_configuration(configuration, &mut visitor);
+                        visitor.0
+                    };
+                    responder.reply(supported).unwrap();
+                }
+                DeviceHandoffCall::HandoffDevice { name: device_name, device_info, handoff_info } => {
+                    info!("Started driving Mass Storage device '{}'", device_name);
+
+                    let config_info = {
+                        let mut info = ConfigInfo::default();
+                        usb::descriptor::walk_configuration(
+                            device_info.get_as_bytes("usb.config0").unwrap(),
+                            &mut info,
+                        );
+                        info
+                    };
+                    let (Some((bulk_in, in_packet_size)), Some((bulk_out, out_packet_size))) =
+                        (config_info.bulk_in, config_info.bulk_out)
+                    else {
+                        warn!("Mass Storage device '{}' doesn't have Bulk IN and OUT endpoints", device_name);
+                        continue;
+                    };
+
+                    let control_channel: Channel<DeviceControlMessage, DeviceResponse> =
+                        Channel::new_from_handle(handoff_info.get_as_channel("usb.channel").unwrap());
+                    control_channel
+                        .send(&DeviceControlMessage::UseConfiguration(config_info.config_value))
+                        .unwrap();
+                    control_channel
+                        .send(&DeviceControlMessage::OpenEndpoint {
+                            number: bulk_in,
+                            direction: EndpointDirection::In,
+                            max_packet_size: in_packet_size,
+                        })
+                        .unwrap();
+                    control_channel
+                        .send(&DeviceControlMessage::OpenEndpoint {
+                            number: bulk_out,
+                            direction: EndpointDirection::Out,
+                            max_packet_size: out_packet_size,
+                        })
+                        .unwrap();
+
+                    // Devices with a single Logical Unit are allowed to stall this request
+                    control_channel
+                        .send(&DeviceControlMessage::ClassRequestIn {
+                            interface: config_info.interface_num,
+                            request: GET_MAX_LUN,
+                            value: 0,
+                            length: 1,
+                        })
+                        .unwrap();
+                    let max_lun = match control_channel.receive().await.unwrap() {
+                        DeviceResponse::Data(data) => data[0],
+                        _ => 0,
+                    };
+                    info!("Device '{}' has {} Logical Units", device_name, max_lun + 1);
+
+                    let device = Arc::new(Spinlock::new(MassStorageDevice {
+                        control_channel,
+                        bulk_in,
+                        bulk_out,
+                        next_tag: 0,
+                    }));
+                    for lun in 0..=max_lun {
+                        let capacity = match device.lock().init_lun(lun) {
+                            Ok(capacity) => capacity,
+                            Err(err) => {
+                                warn!("Failed to initialize LUN {} of '{}': {:?}", lun, device_name, err);
+                                continue;
+                            }
+                        };
+                        if capacity.block_size as usize > MAX_TRANSFER_SIZE {
+                            warn!("LUN {} has unsupported block size of {} bytes", lun, capacity.block_size);
+                            continue;
+                        }
+
+                        serve_logical_unit(
+                            LogicalUnit { device: device.clone(), lun, capacity },
+                            &service_host_client,
+                        );
+                    }
+                }
+                DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                    let units = LOGICAL_UNITS.lock().clone();
+                    for unit in units {
+                        let _ = unit.flush();
+                    }
+                    responder.reply(()).unwrap();
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
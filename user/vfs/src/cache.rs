@@ -0,0 +1,495 @@
+//! The page cache holds the data of files that are read and written through the VFS, so that repeated accesses
+//! don't each need a round-trip to the filesystem (and often to the block device behind it). Data is cached in
+//! pages, keyed by the filesystem the file is on, the file's inode (see `FileStat::inode`), and the offset of the
+//! page into the file. Pages are grouped into chunks, which are each held in their own `MemoryObject`.
+//!
+//! When a read misses the cache, the pages following the one that missed are fetched too (read-ahead), as files
+//! are usually read sequentially. Writes are only made to the cache, and the pages they dirty are written back
+//! to the filesystem periodically, when a handle that was opened for writing is closed, and before the chunk
+//! they're in is evicted. When the system is short of memory, clean chunks are marked as discardable, so the
+//! kernel can reclaim their memory. Their pages are fetched again if they're used after being discarded.
+//!
+//! Ranges of files can also be mapped (see `FileRequest::Map`), which copies them from the cache into a
+//! `MemoryObject` that clients are given read-only handles to. These are kept until the file is next written to,
+//! so mapping the same range again (e.g. to spawn another task from the same image) doesn't copy it again.
+
+use crate::Mount;
+use log::warn;
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        file::{FileError, FileId, FileRequest, FileResponse, OpenOptions, MAX_TRANSFER_SIZE},
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{self, MemoryObjectFlags},
+        Handle,
+        HandleRights,
+    },
+    sync::Arc,
+    time::Duration,
+};
+
+pub const PAGE_SIZE: usize = 0x1000;
+/// The number of pages in each chunk. This can be at most 64, so that a chunk's pages fit in a bitmap.
+const PAGES_PER_CHUNK: usize = 16;
+const CHUNK_SIZE: usize = PAGE_SIZE * PAGES_PER_CHUNK;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    /// The number of pages fetched after a page that misses the cache, if they're not already cached.
+    pub read_ahead: usize,
+    /// The most chunks the cache holds. Once it's full, the least recently used chunk is evicted to make room
+    /// for a new one. This must be at least `2`.
+    pub max_chunks: usize,
+    /// How often dirty pages are written back to their filesystems.
+    pub write_back_interval: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig { read_ahead: 8, max_chunks: 128, write_back_interval: Duration::from_secs(5) }
+    }
+}
+
+/// Identifies a file in the cache, by the ID of the mount it's on and its inode on that filesystem.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct FileKey {
+    pub mount: u64,
+    pub inode: u64,
+}
+
+struct Chunk {
+    memory: MappedMemoryObject,
+    /// A bitmap of the pages of the chunk that hold the file's data.
+    loaded: u64,
+    /// A bitmap of the pages of the chunk that have been written to since they were last written back.
+    dirty: u64,
+    /// When the chunk was last used, which is used to pick the chunk to evict.
+    last_used: u64,
+    /// Whether the chunk's memory is marked as discardable, in which case the kernel may have freed it.
+    discardable: bool,
+}
+
+impl Chunk {
+    fn page(&mut self, index: usize) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut((self.memory.ptr() as *mut u8).add(index * PAGE_SIZE), PAGE_SIZE)
+        }
+    }
+
+    fn is_loaded(&self, index: usize) -> bool {
+        self.loaded & (1 << index) != 0
+    }
+}
+
+/// A file that's open through the VFS.
+struct CachedFile {
+    mount: Arc<Mount>,
+    /// The size of the file, including any data that hasn't been written back yet.
+    size: u64,
+    open_handles: usize,
+    /// The filesystem's IDs for the handles to the file that were opened for writing. Dirty pages are written
+    /// back through the first of them.
+    writers: Vec<FileId>,
+}
+
+pub struct PageCache {
+    config: CacheConfig,
+    files: BTreeMap<FileKey, CachedFile>,
+    /// Chunks are keyed by their file, and the index of the chunk within the file. Chunks are kept after their
+    /// file is closed, so it can be opened again without fetching its data again.
+    chunks: BTreeMap<(FileKey, u64), Chunk>,
+    /// The memory of chunks that have been evicted, which is reused for new chunks. These are discardable, so the
+    /// kernel can reclaim the memory they hold until they're reused.
+    // TODO: we can drop these once the kernel frees the memory of `MemoryObject`s when they're freed
+    free: Vec<MappedMemoryObject>,
+    /// The ranges of files that have been mapped, keyed by their file, offset, and length.
+    mappings: BTreeMap<(FileKey, u64, u64), MemoryObject>,
+    clock: u64,
+}
+
+impl PageCache {
+    pub fn new(config: CacheConfig) -> PageCache {
+        assert!(config.max_chunks >= 2, "Page cache must be able to hold at least two chunks");
+        PageCache {
+            config,
+            files: BTreeMap::new(),
+            chunks: BTreeMap::new(),
+            free: Vec::new(),
+            mappings: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Track a handle to a file that the filesystem on `mount` has opened with `options`, and given the ID
+    /// `file`. `size` is the size of the file reported by the filesystem. Returns the size of the file, which
+    /// differs from `size` if data written through the cache hasn't been written back yet.
+    pub fn open(
+        &mut self,
+        key: FileKey,
+        mount: &Arc<Mount>,
+        file: FileId,
+        size: u64,
+        options: OpenOptions,
+    ) -> u64 {
+        let truncated = options.write && options.truncate;
+        if truncated {
+            // The filesystem has already truncated the file, so anything we have cached for it is out of date
+            self.remove_chunks(key);
+            self.remove_mappings(key);
+        }
+
+        let cached = self.files.entry(key).or_insert_with(|| CachedFile {
+            mount: mount.clone(),
+            size,
+            open_handles: 0,
+            writers: Vec::new(),
+        });
+        if truncated {
+            cached.size = 0;
+        }
+        cached.open_handles += 1;
+        if options.write {
+            cached.writers.push(file);
+        }
+        cached.size
+    }
+
+    /// Stop tracking a handle to a file, before it's closed. If the handle was opened for writing, the file's
+    /// dirty pages are written back first, as they might not be able to be once it's closed.
+    pub fn close(&mut self, key: FileKey, file: FileId) -> Result<(), FileError> {
+        let cached = self.files.get(&key).ok_or(FileError::InvalidFile)?;
+        let result = if cached.writers.contains(&file) { self.write_back(key) } else { Ok(()) };
+        if let Err(err) = result {
+            warn!("Failed to write back cached data of {:?}. It has been lost: {:?}", key, err);
+            self.remove_chunks(key);
+        }
+
+        let cached = self.files.get_mut(&key).unwrap();
+        cached.writers.retain(|&writer| writer != file);
+        cached.open_handles -= 1;
+        if cached.open_handles == 0 {
+            self.files.remove(&key);
+        }
+        result
+    }
+
+    /// The size of a file that's open through the VFS, including data that hasn't been written back yet.
+    pub fn size(&self, key: FileKey) -> Option<u64> {
+        self.files.get(&key).map(|cached| cached.size)
+    }
+
+    /// Read up to `length` bytes of a file, starting at `offset`. Pages that aren't cached are fetched through the
+    /// handle `file`.
+    pub fn read(&mut self, key: FileKey, file: FileId, offset: u64, length: u32) -> Result<Vec<u8>, FileError> {
+        let size = self.files.get(&key).ok_or(FileError::InvalidFile)?.size;
+        let end = u64::min(offset.saturating_add(length as u64), size);
+
+        let mut data = Vec::new();
+        self.for_each_page(key, file, offset, end, |bytes| data.extend_from_slice(bytes))?;
+        Ok(data)
+    }
+
+    /// Get a handle to a read-only `MemoryObject` holding `length` bytes of a file, starting at `offset`. If the
+    /// range hasn't been mapped since the file was last written to, it's copied into a new object from the
+    /// cache, fetching any pages that aren't cached through the handle `file`.
+    pub fn map(&mut self, key: FileKey, file: FileId, offset: u64, length: u64) -> Result<Handle, FileError> {
+        let size = self.files.get(&key).ok_or(FileError::InvalidFile)?.size;
+        if !self.mappings.contains_key(&(key, offset, length)) {
+            let object_size = usize::try_from(length)
+                .ok()
+                .and_then(|length| length.checked_next_multiple_of(PAGE_SIZE))
+                .ok_or(FileError::TooLarge)?;
+            let memory = MemoryObject::create_lazy(object_size, MemoryObjectFlags::WRITABLE)
+                .map_err(|_| FileError::NoSpace)?;
+            let handle = memory.handle;
+            let mapped = match unsafe { memory.map() } {
+                Ok(mapped) => mapped,
+                Err(_) => {
+                    let _ = syscall::handle_close(handle);
+                    return Err(FileError::NoSpace);
+                }
+            };
+
+            // Only the part of the range inside the file is copied, as the rest of the object is already zeroed
+            let end = u64::min(offset.saturating_add(length), size);
+            let destination = unsafe {
+                core::slice::from_raw_parts_mut(mapped.ptr() as *mut u8, end.saturating_sub(offset) as usize)
+            };
+            let mut copied = 0;
+            let result = self.for_each_page(key, file, offset, end, |bytes| {
+                destination[copied..(copied + bytes.len())].copy_from_slice(bytes);
+                copied += bytes.len();
+            });
+
+            let memory = unsafe { mapped.unmap() }.expect("Failed to unmap file mapping");
+            if let Err(err) = result {
+                let _ = syscall::handle_close(memory.handle);
+                return Err(err);
+            }
+            self.mappings.insert((key, offset, length), memory);
+        }
+
+        let rights = HandleRights::READ | HandleRights::MAP | HandleRights::TRANSFER;
+        syscall::handle_duplicate_with_rights(self.mappings[&(key, offset, length)].handle, rights)
+            .map_err(|_| FileError::NoSpace)
+    }
+
+    /// Write `data` to a file, starting at `offset`, extending the file if needed. The data is only written to
+    /// the cache, and is written back to the filesystem later. Pages that are only partly overwritten are fetched
+    /// through the handle `file` first.
+    pub fn write(&mut self, key: FileKey, file: FileId, offset: u64, data: &[u8]) -> Result<(), FileError> {
+        let size = self.files.get(&key).ok_or(FileError::InvalidFile)?.size;
+        let end = offset.checked_add(data.len() as u64).ok_or(FileError::TooLarge)?;
+        // Mappings are snapshots of the file, so the next mapping of it needs to be made afresh
+        self.remove_mappings(key);
+
+        /*
+         * Writing past the end of the file fills the gap with zeroes. The gap is dirtied along with the data, so
+         * filesystems aren't asked to write past the end of a file when it's written back.
+         */
+        let mut position = u64::min(offset, size);
+        while position < end {
+            let page = position / PAGE_SIZE as u64;
+            let page_start = page * PAGE_SIZE as u64;
+            let page_offset = (position - page_start) as usize;
+            let count = usize::min(PAGE_SIZE - page_offset, (end - position) as usize);
+
+            // Pages past the end of the file don't hold anything we need to keep
+            if page_start < size && count != PAGE_SIZE {
+                self.load(key, file, page, 0)?;
+            }
+            let (chunk, index) = self.chunk(key, page)?;
+            if !chunk.is_loaded(index) {
+                chunk.page(index).fill(0);
+            }
+
+            let gap = usize::min(offset.saturating_sub(position) as usize, count);
+            let start = (position + gap as u64 - offset) as usize;
+            let bytes = &mut chunk.page(index)[page_offset..(page_offset + count)];
+            bytes[..gap].fill(0);
+            bytes[gap..].copy_from_slice(&data[start..(start + count - gap)]);
+
+            chunk.loaded |= 1 << index;
+            chunk.dirty |= 1 << index;
+            position += count as u64;
+        }
+
+        let cached = self.files.get_mut(&key).unwrap();
+        cached.size = u64::max(cached.size, end);
+        Ok(())
+    }
+
+    /// Write back every dirty page in the cache. Pages that fail to be written back stay dirty, and are tried
+    /// again next time.
+    pub fn write_back_all(&mut self) {
+        let dirty: Vec<(FileKey, u64)> =
+            self.chunks.iter().filter(|(_, chunk)| chunk.dirty != 0).map(|(&chunk_key, _)| chunk_key).collect();
+        for chunk_key in dirty {
+            if let Err(err) = self.write_back_chunk(chunk_key) {
+                warn!("Failed to write back cached data of {:?}: {:?}", chunk_key.0, err);
+            }
+        }
+    }
+
+    /// Free up memory when the system is short of it. Dirty pages are written back, and then the kernel is
+    /// allowed to discard the memory of every clean chunk. We also stop keeping mappings around, so their memory
+    /// can be freed once no clients are using them.
+    pub fn relieve_pressure(&mut self) {
+        self.write_back_all();
+        for (_, memory) in core::mem::take(&mut self.mappings) {
+            let _ = syscall::handle_close(memory.handle);
+        }
+        for chunk in self.chunks.values_mut().filter(|chunk| chunk.dirty == 0 && !chunk.discardable) {
+            if chunk.memory.inner.set_discardable(true).is_ok() {
+                chunk.discardable = true;
+            }
+        }
+    }
+
+    /// Pass the data of a file from `offset` up to `end` (which must not be past the end of the file) to `f`, a
+    /// page at a time. Pages that aren't cached are fetched through the handle `file`.
+    fn for_each_page(
+        &mut self,
+        key: FileKey,
+        file: FileId,
+        offset: u64,
+        end: u64,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<(), FileError> {
+        let mut position = offset;
+        while position < end {
+            let page = position / PAGE_SIZE as u64;
+            let page_offset = (position % PAGE_SIZE as u64) as usize;
+            let count = usize::min(PAGE_SIZE - page_offset, (end - position) as usize);
+
+            self.load(key, file, page, self.config.read_ahead)?;
+            let (chunk, index) = self.chunk(key, page)?;
+            f(&chunk.page(index)[page_offset..(page_offset + count)]);
+            position += count as u64;
+        }
+        Ok(())
+    }
+
+    /// Make sure `page` of a file is cached. If it isn't, it's fetched through the handle `file`, along with up
+    /// to `read_ahead` pages after it.
+    fn load(&mut self, key: FileKey, file: FileId, page: u64, read_ahead: usize) -> Result<(), FileError> {
+        let (chunk, index) = self.chunk(key, page)?;
+        if chunk.is_loaded(index) {
+            return Ok(());
+        }
+        self.fetch(key, file, page)?;
+
+        // Reading ahead stops at the end of the file, or at the first page that's already cached
+        let num_pages = self.files[&key].size.div_ceil(PAGE_SIZE as u64);
+        for page in (page + 1)..u64::min(page + 1 + read_ahead as u64, num_pages) {
+            if self.is_loaded(key, page) || self.fetch(key, file, page).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch a page of a file from its filesystem into the cache. Any part of the page past the end of the file
+    /// is zeroed.
+    fn fetch(&mut self, key: FileKey, file: FileId, page: u64) -> Result<(), FileError> {
+        let cached = self.files.get(&key).ok_or(FileError::InvalidFile)?;
+        let start = page * PAGE_SIZE as u64;
+
+        let mut buffer = vec![0; PAGE_SIZE];
+        let mut read = 0;
+        while read < PAGE_SIZE && start + (read as u64) < cached.size {
+            let length = usize::min(PAGE_SIZE - read, MAX_TRANSFER_SIZE) as u32;
+            match cached.mount.request(&FileRequest::Read { file, offset: start + read as u64, length }) {
+                FileResponse::Data(data) if data.len() <= length as usize => {
+                    buffer[read..(read + data.len())].copy_from_slice(&data);
+                    read += data.len();
+                    // A short read means we've reached the end of the file
+                    if data.len() < length as usize {
+                        break;
+                    }
+                }
+                FileResponse::Error(err) => return Err(err),
+                _ => return Err(FileError::Io),
+            }
+        }
+
+        let (chunk, index) = self.chunk(key, page)?;
+        chunk.page(index).copy_from_slice(&buffer);
+        chunk.loaded |= 1 << index;
+        Ok(())
+    }
+
+    fn is_loaded(&self, key: FileKey, page: u64) -> bool {
+        self.chunks
+            .get(&(key, page / PAGES_PER_CHUNK as u64))
+            .map_or(false, |chunk| !chunk.discardable && chunk.is_loaded((page % PAGES_PER_CHUNK as u64) as usize))
+    }
+
+    /// Get the chunk that holds `page` of a file, and the index of the page within it. The chunk is added to the
+    /// cache if it's not already there, which can cause another chunk to be evicted.
+    fn chunk(&mut self, key: FileKey, page: u64) -> Result<(&mut Chunk, usize), FileError> {
+        let chunk_key = (key, page / PAGES_PER_CHUNK as u64);
+        if !self.chunks.contains_key(&chunk_key) {
+            let memory = self.allocate()?;
+            self.chunks.insert(chunk_key, Chunk { memory, loaded: 0, dirty: 0, last_used: 0, discardable: false });
+        }
+
+        self.clock += 1;
+        let chunk = self.chunks.get_mut(&chunk_key).unwrap();
+        chunk.last_used = self.clock;
+        if chunk.discardable {
+            chunk.discardable = false;
+            // If the kernel has discarded the chunk's memory, its pages need to be fetched again
+            if chunk.memory.inner.set_discardable(false).unwrap_or(true) {
+                chunk.loaded = 0;
+            }
+        }
+        Ok((chunk, (page % PAGES_PER_CHUNK as u64) as usize))
+    }
+
+    /// Get the memory for a new chunk, evicting the least recently used chunk if the cache is full.
+    fn allocate(&mut self) -> Result<MappedMemoryObject, FileError> {
+        if self.chunks.len() >= self.config.max_chunks {
+            let (&chunk_key, _) = self.chunks.iter().min_by_key(|(_, chunk)| chunk.last_used).unwrap();
+            self.write_back_chunk(chunk_key)?;
+            let chunk = self.chunks.remove(&chunk_key).unwrap();
+            self.recycle(chunk.memory);
+        }
+
+        if let Some(memory) = self.free.pop() {
+            let _ = memory.inner.set_discardable(false);
+            return Ok(memory);
+        }
+        let memory = MemoryObject::create_discardable(CHUNK_SIZE, MemoryObjectFlags::WRITABLE)
+            .map_err(|_| FileError::NoSpace)?;
+        unsafe { memory.map() }.map_err(|_| FileError::NoSpace)
+    }
+
+    fn recycle(&mut self, memory: MappedMemoryObject) {
+        let _ = memory.inner.set_discardable(true);
+        self.free.push(memory);
+    }
+
+    /// Remove every chunk of a file from the cache, without writing any of them back.
+    fn remove_chunks(&mut self, key: FileKey) {
+        let chunk_keys: Vec<(FileKey, u64)> =
+            self.chunks.range((key, 0)..=(key, u64::MAX)).map(|(&chunk_key, _)| chunk_key).collect();
+        for chunk_key in chunk_keys {
+            let chunk = self.chunks.remove(&chunk_key).unwrap();
+            self.recycle(chunk.memory);
+        }
+    }
+
+    fn remove_mappings(&mut self, key: FileKey) {
+        let mapping_keys: Vec<(FileKey, u64, u64)> = self
+            .mappings
+            .range((key, 0, 0)..=(key, u64::MAX, u64::MAX))
+            .map(|(&mapping_key, _)| mapping_key)
+            .collect();
+        for mapping_key in mapping_keys {
+            let memory = self.mappings.remove(&mapping_key).unwrap();
+            let _ = syscall::handle_close(memory.handle);
+        }
+    }
+
+    /// Write back all the dirty pages of a file.
+    fn write_back(&mut self, key: FileKey) -> Result<(), FileError> {
+        let chunk_keys: Vec<(FileKey, u64)> =
+            self.chunks.range((key, 0)..=(key, u64::MAX)).map(|(&chunk_key, _)| chunk_key).collect();
+        for chunk_key in chunk_keys {
+            self.write_back_chunk(chunk_key)?;
+        }
+        Ok(())
+    }
+
+    /// Write the dirty pages of a chunk back to its file's filesystem.
+    fn write_back_chunk(&mut self, (key, chunk_index): (FileKey, u64)) -> Result<(), FileError> {
+        let Some(chunk) = self.chunks.get_mut(&(key, chunk_index)) else {
+            return Ok(());
+        };
+        if chunk.dirty == 0 {
+            return Ok(());
+        }
+        let cached = self.files.get(&key).ok_or(FileError::InvalidFile)?;
+        let writer = *cached.writers.first().ok_or(FileError::ReadOnly)?;
+
+        let dirty = chunk.dirty;
+        for index in (0..PAGES_PER_CHUNK).filter(|index| dirty & (1 << index) != 0) {
+            let start = (chunk_index * PAGES_PER_CHUNK as u64 + index as u64) * PAGE_SIZE as u64;
+            let length = u64::min(cached.size.saturating_sub(start), PAGE_SIZE as u64) as usize;
+            let page = chunk.page(index);
+
+            for offset in (0..length).step_by(MAX_TRANSFER_SIZE) {
+                let data = page[offset..usize::min(offset + MAX_TRANSFER_SIZE, length)].to_vec();
+                let request = FileRequest::Write { file: writer, offset: start + offset as u64, data };
+                match cached.mount.request(&request) {
+                    FileResponse::Written(_) => (),
+                    FileResponse::Error(err) => return Err(err),
+                    _ => return Err(FileError::Io),
+                }
+            }
+            chunk.dirty &= !(1 << index);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,522 @@
+//! The VFS presents the filesystems mounted by filesystem drivers as a single tree of files, through the `vfs`
+//! service. Requests are forwarded to the filesystem mounted at the longest prefix of the requested path, with
+//! the path made relative to the root of the filesystem. See `std::poplar::file` for the protocol. The data of
+//! files is cached by the VFS (see `cache`), so most reads and writes don't need to be forwarded to a filesystem.
+//! Each client sees its own namespace (see `namespace`), which paths are resolved in before they're forwarded.
+
+mod cache;
+mod namespace;
+
+use cache::{CacheConfig, FileKey, PageCache};
+use log::{info, warn};
+use namespace::Namespace;
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use spinning_top::Spinlock;
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        channel::Channel,
+        early_logger::EarlyLogger,
+        event::Event,
+        file::{
+            path_components,
+            Binding,
+            DirEntry,
+            FileError,
+            FileId,
+            FileKind,
+            FileRequest,
+            FileResponse,
+            FileStat,
+            FilesystemMessage,
+            FilesystemResponse,
+            MAX_DIR_ENTRIES,
+            MAX_TRANSFER_SIZE,
+            VFS_FILESYSTEM_SERVICE,
+            VFS_SERVICE,
+        },
+        syscall,
+        Handle,
+    },
+    sync::Arc,
+};
+
+/// The `FileStat` of directories that only exist to hold mount points or bindings. They can't be changed, as they
+/// aren't on any filesystem.
+const MOUNT_POINT_STAT: FileStat = FileStat { kind: FileKind::Directory, size: 0, inode: 0, permissions: 0o555 };
+
+/// The most symlinks that are followed while resolving a single path, so a loop of symlinks can't send us round in
+/// circles forever.
+const MAX_SYMLINKS: usize = 8;
+
+pub struct Mount {
+    /// Identifies the mount in the page cache. These aren't reused, even if the filesystem is unmounted.
+    id: u64,
+    /// The components of the path the filesystem is mounted at.
+    path: Vec<String>,
+    channel: Channel<FileRequest, FileResponse>,
+}
+
+impl Mount {
+    /// Make a request of the filesystem, and wait for its response.
+    ///
+    /// This blocks the whole VFS until the filesystem replies.
+    // TODO: requests are made as calls, so their replies can't get mixed up. We could make this async, and let
+    // the VFS handle other requests while it waits.
+    fn request(&self, request: &FileRequest) -> FileResponse {
+        match self.channel.call_blocking(request) {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("Request to filesystem at {:?} failed: {:?}", self.path, err);
+                FileResponse::Error(FileError::Io)
+            }
+        }
+    }
+}
+
+pub struct MountTable {
+    mounts: Vec<Arc<Mount>>,
+    next_mount_id: u64,
+}
+
+impl MountTable {
+    pub fn new() -> MountTable {
+        MountTable { mounts: Vec::new(), next_mount_id: 0 }
+    }
+
+    pub fn mount(&mut self, path: &str, channel: Channel<FileRequest, FileResponse>) -> Result<(), FileError> {
+        let path: Vec<String> =
+            path_components(path).ok_or(FileError::InvalidPath)?.into_iter().map(String::from).collect();
+        if self.mounts.iter().any(|mount| mount.path == path) {
+            return Err(FileError::InvalidPath);
+        }
+        let id = self.next_mount_id;
+        self.next_mount_id += 1;
+        self.mounts.push(Arc::new(Mount { id, path, channel }));
+        Ok(())
+    }
+
+    /// Find the filesystem that `path` is on, and the path relative to the root of that filesystem.
+    pub fn resolve(&self, path: &[&str]) -> Option<(Arc<Mount>, String)> {
+        let mount = self
+            .mounts
+            .iter()
+            .filter(|mount| mount.path.len() <= path.len() && mount.path.iter().zip(path).all(|(a, b)| a == b))
+            .max_by_key(|mount| mount.path.len())?;
+
+        let mut relative = String::from("/");
+        relative.push_str(&path[mount.path.len()..].join("/"));
+        Some((mount.clone(), relative))
+    }
+
+    /// The names of the mount points directly below `path`. These need to appear as directories, even if the
+    /// filesystem `path` is on (if there is one) doesn't contain them.
+    pub fn mount_points_below(&self, path: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .mounts
+            .iter()
+            .filter(|mount| mount.path.len() > path.len() && mount.path.iter().zip(path).all(|(a, b)| a == b))
+            .map(|mount| mount.path[path.len()].clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+struct OpenFile {
+    mount: Arc<Mount>,
+    /// The ID the filesystem gave the file when it was opened.
+    inner_id: FileId,
+    key: FileKey,
+    writable: bool,
+}
+
+/// The state of a single client of the VFS. Each client has its own table of open files, mapping the IDs we
+/// give out to the filesystem the file is on and the ID that filesystem gave it, and its own namespace.
+pub struct Client {
+    mount_table: Arc<Spinlock<MountTable>>,
+    cache: Arc<Spinlock<PageCache>>,
+    namespace: Namespace,
+    open_files: BTreeMap<FileId, OpenFile>,
+    next_file_id: u64,
+}
+
+/// The outcome of a request about the file at a path. See `Client::request_path`.
+struct PathRequest {
+    /// The path the request was finally made about, after following any symlinks.
+    path: Vec<String>,
+    /// The filesystem the path leads to, and its response to the request. This is `None` if the path doesn't lead
+    /// into a filesystem, or no request was made.
+    response: Option<(Arc<Mount>, FileResponse)>,
+    /// The names of the mount points and bindings directly below the path. These need to appear as directories,
+    /// even if the filesystem the path is on doesn't contain them.
+    virtual_dirs: Vec<String>,
+}
+
+impl Client {
+    pub fn new(
+        mount_table: Arc<Spinlock<MountTable>>,
+        cache: Arc<Spinlock<PageCache>>,
+        namespace: Namespace,
+    ) -> Client {
+        Client { mount_table, cache, namespace, open_files: BTreeMap::new(), next_file_id: 0 }
+    }
+
+    pub fn handle_request(&mut self, request: FileRequest) -> FileResponse {
+        match request {
+            FileRequest::Open { path, options } => {
+                let request = match self.request_path(&path, |path, _| Some(FileRequest::Open { path, options })) {
+                    Ok(request) => request,
+                    Err(err) => return FileResponse::Error(err),
+                };
+                let Some((mount, response)) = request.response else {
+                    return FileResponse::Error(FileError::NotFound);
+                };
+                match response {
+                    FileResponse::Opened(inner_id, stat) => {
+                        let key = FileKey { mount: mount.id, inode: stat.inode };
+                        let size = self.cache.lock().open(key, &mount, inner_id, stat.size, options);
+
+                        let id = FileId(self.next_file_id);
+                        self.next_file_id += 1;
+                        self.open_files.insert(id, OpenFile { mount, inner_id, key, writable: options.write });
+                        FileResponse::Opened(id, FileStat { size, ..stat })
+                    }
+                    response => response,
+                }
+            }
+            FileRequest::Read { file, offset, length } => {
+                if length as usize > MAX_TRANSFER_SIZE {
+                    return FileResponse::Error(FileError::TooLarge);
+                }
+                let Some(file) = self.open_files.get(&file) else {
+                    return FileResponse::Error(FileError::InvalidFile);
+                };
+                match self.cache.lock().read(file.key, file.inner_id, offset, length) {
+                    Ok(data) => FileResponse::Data(data),
+                    Err(err) => FileResponse::Error(err),
+                }
+            }
+            FileRequest::Write { file, offset, data } => {
+                if data.len() > MAX_TRANSFER_SIZE {
+                    return FileResponse::Error(FileError::TooLarge);
+                }
+                let Some(file) = self.open_files.get(&file) else {
+                    return FileResponse::Error(FileError::InvalidFile);
+                };
+                if !file.writable {
+                    return FileResponse::Error(FileError::ReadOnly);
+                }
+                match self.cache.lock().write(file.key, file.inner_id, offset, &data) {
+                    Ok(()) => FileResponse::Written(data.len() as u32),
+                    Err(err) => FileResponse::Error(err),
+                }
+            }
+            FileRequest::Map { file, offset, length } => {
+                let Some(file) = self.open_files.get(&file) else {
+                    return FileResponse::Error(FileError::InvalidFile);
+                };
+                match self.cache.lock().map(file.key, file.inner_id, offset, length) {
+                    Ok(memory_object) => FileResponse::Mapped(memory_object),
+                    Err(err) => FileResponse::Error(err),
+                }
+            }
+            FileRequest::Stat { path } => {
+                let request = match self.request_path(&path, |path, _| Some(FileRequest::Stat { path })) {
+                    Ok(request) => request,
+                    Err(err) => return FileResponse::Error(err),
+                };
+
+                match request.response {
+                    // Open files may have been written to without the filesystem knowing yet
+                    Some((mount, FileResponse::Stat(stat))) => {
+                        let key = FileKey { mount: mount.id, inode: stat.inode };
+                        let size = self.cache.lock().size(key).unwrap_or(stat.size);
+                        FileResponse::Stat(FileStat { size, ..stat })
+                    }
+                    /*
+                     * Directories that only exist to hold mount points or bindings (e.g. `/mnt` if nothing is
+                     * mounted at `/`) aren't on any filesystem, so we make them up.
+                     */
+                    Some((_, FileResponse::Error(FileError::NotFound))) | None
+                        if !request.virtual_dirs.is_empty() =>
+                    {
+                        FileResponse::Stat(MOUNT_POINT_STAT)
+                    }
+                    Some((_, response)) => response,
+                    None => FileResponse::Error(FileError::NotFound),
+                }
+            }
+            FileRequest::ReadDir { path, start } => {
+                /*
+                 * The mount points and bindings below the directory are listed first, followed by the entries
+                 * the filesystem has for the directory.
+                 */
+                let start = start as usize;
+                let request = self.request_path(&path, |path, virtual_dirs| {
+                    let start = start.checked_sub(virtual_dirs.len())? as u32;
+                    Some(FileRequest::ReadDir { path, start })
+                });
+                let request = match request {
+                    Ok(request) => request,
+                    Err(err) => return FileResponse::Error(err),
+                };
+
+                if start < request.virtual_dirs.len() {
+                    let entries = request
+                        .virtual_dirs
+                        .into_iter()
+                        .skip(start)
+                        .take(MAX_DIR_ENTRIES)
+                        .map(|name| DirEntry { name, kind: FileKind::Directory })
+                        .collect();
+                    return FileResponse::Entries(entries);
+                }
+                match request.response {
+                    Some((_, FileResponse::Error(FileError::NotFound))) | None
+                        if !request.virtual_dirs.is_empty() =>
+                    {
+                        FileResponse::Entries(Vec::new())
+                    }
+                    Some((_, response)) => response,
+                    None => FileResponse::Error(FileError::NotFound),
+                }
+            }
+            FileRequest::Namespace { bindings } => match self.create_namespace(&bindings) {
+                Ok(handle) => FileResponse::Namespace(handle),
+                Err(err) => FileResponse::Error(err),
+            },
+            FileRequest::Close { file } => {
+                let Some(file) = self.open_files.remove(&file) else {
+                    return FileResponse::Error(FileError::InvalidFile);
+                };
+                // Dirty pages are written back through the file, so we need to do that before it's closed
+                let written_back = self.cache.lock().close(file.key, file.inner_id);
+                match (file.mount.request(&FileRequest::Close { file: file.inner_id }), written_back) {
+                    (FileResponse::Done, Err(err)) => FileResponse::Error(err),
+                    (response, _) => response,
+                }
+            }
+        }
+    }
+
+    /// Make a request about the file at `path` in the client's namespace. `make_request` is given the path
+    /// relative to the root of the filesystem the path leads to, and the names of the mount points and bindings
+    /// below it, and returns the request to make (if any). If the path goes through a symlink, the symlink is
+    /// followed, and the request is made again.
+    fn request_path(
+        &self,
+        path: &str,
+        make_request: impl Fn(String, &[String]) -> Option<FileRequest>,
+    ) -> Result<PathRequest, FileError> {
+        let mut path = owned_components(path)?;
+
+        for _ in 0..=MAX_SYMLINKS {
+            let (target, binding_depth, virtual_dirs) = self.resolve(&path);
+            let Some((mount, relative)) = target else {
+                return Ok(PathRequest { path, response: None, virtual_dirs });
+            };
+            let Some(request) = make_request(relative.clone(), &virtual_dirs) else {
+                return Ok(PathRequest { path, response: None, virtual_dirs });
+            };
+
+            match mount.request(&request) {
+                FileResponse::Symlink { depth, target } => {
+                    /*
+                     * The path on the filesystem ends with the same components as the path in the namespace, so
+                     * we can tell where the symlink is in the namespace. Symlinks that are part of the directory
+                     * a binding binds aren't in the namespace, and so can't be followed.
+                     */
+                    let relative_len = path_components(&relative).map_or(0, |components| components.len());
+                    let after =
+                        relative_len.checked_sub(depth as usize).filter(|_| depth != 0).ok_or(FileError::Io)?;
+                    let link = path
+                        .len()
+                        .checked_sub(after)
+                        .filter(|&link| link > binding_depth)
+                        .ok_or(FileError::NotFound)?;
+                    path = follow_symlink(&path[..link], &target, &path[link..]);
+                }
+                response => return Ok(PathRequest { path, response: Some((mount, response)), virtual_dirs }),
+            }
+        }
+
+        Err(FileError::InvalidPath)
+    }
+
+    /// Find where `path` in the client's namespace leads. Returns the filesystem it's on and the path relative to
+    /// the root of that filesystem (if it leads into one), the number of components of `path` that are the path
+    /// of the binding it's translated through, and the names of the mount points and bindings directly below it.
+    fn resolve(&self, path: &[String]) -> (Option<(Arc<Mount>, String)>, usize, Vec<String>) {
+        let mut virtual_dirs = self.namespace.bindings_below(path);
+        let Some((global, binding_depth)) = self.namespace.translate(path) else {
+            return (None, 0, virtual_dirs);
+        };
+
+        let global: Vec<&str> = global.iter().map(String::as_str).collect();
+        let mount_table = self.mount_table.lock();
+        virtual_dirs.extend(mount_table.mount_points_below(&global));
+        virtual_dirs.sort();
+        virtual_dirs.dedup();
+        (mount_table.resolve(&global), binding_depth, virtual_dirs)
+    }
+
+    /// Create a namespace made up of `bindings` of directories in this client's namespace, and start serving a
+    /// new client that sees it. Returns the handle to the other end of the new client's channel.
+    fn create_namespace(&self, bindings: &[Binding]) -> Result<Handle, FileError> {
+        let mut namespace = Namespace::default();
+        for binding in bindings {
+            let path = owned_components(&binding.path)?;
+
+            // Symlinks on the way to the target are followed, so what's bound is the directory they lead to
+            let target = self.request_path(&binding.target, |path, _| Some(FileRequest::Stat { path }))?;
+            match target.response {
+                Some((_, FileResponse::Stat(stat))) if stat.kind == FileKind::Directory => (),
+                Some((_, FileResponse::Stat(_))) => return Err(FileError::NotADirectory),
+                Some((_, FileResponse::Error(FileError::NotFound))) | None if !target.virtual_dirs.is_empty() => {}
+                Some((_, FileResponse::Error(err))) => return Err(err),
+                Some(_) => return Err(FileError::Io),
+                None => return Err(FileError::NotFound),
+            }
+            self.namespace.bind_into(&target.path, &mut namespace, &path);
+        }
+
+        let (channel, handle) = Channel::create().map_err(|_| FileError::NoSpace)?;
+        serve(channel, Client::new(self.mount_table.clone(), self.cache.clone(), namespace));
+        Ok(handle)
+    }
+}
+
+fn owned_components(path: &str) -> Result<Vec<String>, FileError> {
+    Ok(path_components(path).ok_or(FileError::InvalidPath)?.into_iter().map(String::from).collect())
+}
+
+/// Work out the path that a path through a symlink leads to. `link` is the path of the symlink, and `rest` is the
+/// part of the path after it. Absolute targets are followed from the root of the namespace, and relative targets
+/// from the directory holding the symlink. `..` can't go above the root of the namespace, so symlinks can't lead
+/// out of it.
+fn follow_symlink(link: &[String], target: &str, rest: &[String]) -> Vec<String> {
+    let mut path = if target.starts_with('/') { Vec::new() } else { link[..(link.len() - 1)].to_vec() };
+    for component in target.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                path.pop();
+            }
+            _ => path.push(component.to_string()),
+        }
+    }
+    path.extend_from_slice(rest);
+    path
+}
+
+/// Serve the requests a client makes over `channel`.
+fn serve(channel: Channel<FileResponse, FileRequest>, mut client: Client) {
+    std::poplar::rt::spawn(async move {
+        loop {
+            let (request, call) = channel.receive_call().await.unwrap();
+            let response = client.handle_request(request);
+            channel.reply(call, &response).unwrap();
+        }
+    });
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("VFS is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let mount_table = Arc::new(Spinlock::new(MountTable::new()));
+    let cache_config = CacheConfig::default();
+    let cache = Arc::new(Spinlock::new(PageCache::new(cache_config)));
+
+    let service_host_client = ServiceHostClient::new();
+    let client_service_channel = service_host_client.register_service(VFS_SERVICE).unwrap();
+    let filesystem_service_channel = service_host_client.register_service(VFS_FILESYSTEM_SERVICE).unwrap();
+
+    std::poplar::rt::spawn({
+        let mount_table = mount_table.clone();
+        async move {
+            loop {
+                match filesystem_service_channel.receive().await.unwrap() {
+                    ServiceChannelMessage::NewClient { name: driver_name, channel } => {
+                        info!("Filesystem driver '{}' subscribed to the VFS", driver_name);
+                        let channel: Channel<FilesystemResponse, FilesystemMessage> =
+                            Channel::new_from_handle(channel);
+
+                        std::poplar::rt::spawn({
+                            let mount_table = mount_table.clone();
+                            async move {
+                                loop {
+                                    match channel.receive().await.unwrap() {
+                                        FilesystemMessage::Mount { path, channel: filesystem } => {
+                                            let filesystem = Channel::new_from_handle(filesystem);
+                                            let response = match mount_table.lock().mount(&path, filesystem) {
+                                                Ok(()) => {
+                                                    info!(
+                                                        "Driver '{}' mounted filesystem at {}",
+                                                        driver_name, path
+                                                    );
+                                                    FilesystemResponse::Mounted
+                                                }
+                                                Err(err) => {
+                                                    warn!(
+                                                        "Driver '{}' failed to mount filesystem at {}: {:?}",
+                                                        driver_name, path, err
+                                                    );
+                                                    FilesystemResponse::Error(err)
+                                                }
+                                            };
+                                            channel.send(&response).unwrap();
+                                        }
+                                    }
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+        }
+    });
+
+    // Dirty pages are written back periodically, so that not much is lost if the system goes down
+    std::poplar::rt::spawn({
+        let cache = cache.clone();
+        async move {
+            let mut interval = std::poplar::rt::time::interval(cache_config.write_back_interval);
+            loop {
+                interval.tick().await;
+                cache.lock().write_back_all();
+            }
+        }
+    });
+
+    std::poplar::rt::spawn({
+        let cache = cache.clone();
+        let memory_pressure = Event::new_from_handle(syscall::subscribe_memory_pressure().unwrap());
+        async move {
+            loop {
+                memory_pressure.wait_for_event().await;
+                info!("System is under memory pressure. Letting the kernel reclaim clean cached pages.");
+                cache.lock().relieve_pressure();
+            }
+        }
+    });
+
+    std::poplar::rt::spawn(async move {
+        loop {
+            match client_service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    info!("Task '{}' subscribed to the VFS", name);
+                    let channel = Channel::new_from_handle(channel);
+                    serve(channel, Client::new(mount_table.clone(), cache.clone(), Namespace::global()));
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
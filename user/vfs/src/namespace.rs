@@ -0,0 +1,67 @@
+//! Each client of the VFS sees its own namespace: the tree of files that the paths it sends are resolved in. A
+//! namespace is made up of bindings, which each make a directory of the global tree (the one filesystems are
+//! mounted in) appear at a path in the namespace. Paths are translated through the binding at the longest prefix
+//! of them, so a binding inside the directory of another covers up whatever the other has at its path.
+//!
+//! Clients of the `vfs` service see the global tree, as their namespace binds its root at their root. New
+//! namespaces can only be made out of directories that the client creating them can see (see
+//! `FileRequest::Namespace`), so access to files can be handed on, but never gained.
+
+use std::collections::BTreeMap;
+
+#[derive(Clone, Default, Debug)]
+pub struct Namespace {
+    /// The path each binding is at in the namespace, and the path of the directory it binds in the global tree.
+    bindings: BTreeMap<Vec<String>, Vec<String>>,
+}
+
+impl Namespace {
+    /// The namespace that sees the whole of the global tree.
+    pub fn global() -> Namespace {
+        Namespace { bindings: BTreeMap::from([(Vec::new(), Vec::new())]) }
+    }
+
+    /// Make the directory at `target` in this namespace appear at `path` in `other`, covering up anything that
+    /// was already bound at or below `path` there. Everything this namespace sees at and below `target`,
+    /// including the directories bound below it, is seen at and below `path` in `other`.
+    pub fn bind_into(&self, target: &[String], other: &mut Namespace, path: &[String]) {
+        other.bindings.retain(|bound, _| !bound.starts_with(path));
+        if let Some((global, _)) = self.translate(target) {
+            other.bindings.insert(path.to_vec(), global);
+        }
+        for (bound, global) in &self.bindings {
+            if bound.len() > target.len() && bound.starts_with(target) {
+                let mut bound_in_other = path.to_vec();
+                bound_in_other.extend_from_slice(&bound[target.len()..]);
+                other.bindings.insert(bound_in_other, global.clone());
+            }
+        }
+    }
+
+    /// Translate `path` to a path in the global tree. Also returns the number of components of `path` that are
+    /// the path of the binding it's translated through. Returns `None` if no binding covers `path`.
+    pub fn translate(&self, path: &[String]) -> Option<(Vec<String>, usize)> {
+        let (bound, global) = self
+            .bindings
+            .iter()
+            .filter(|(bound, _)| path.starts_with(bound))
+            .max_by_key(|(bound, _)| bound.len())?;
+
+        let mut translated = global.clone();
+        translated.extend_from_slice(&path[bound.len()..]);
+        Some((translated, bound.len()))
+    }
+
+    /// The names of the bindings directly below `path`, or of the directories leading to them. These need to
+    /// appear as directories, even if the directory `path` leads to (if there is one) doesn't contain them.
+    pub fn bindings_below(&self, path: &[String]) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .bindings
+            .keys()
+            .filter(|bound| bound.len() > path.len() && bound.starts_with(path))
+            .map(|bound| bound[path.len()].clone())
+            .collect();
+        names.dedup();
+        names
+    }
+}
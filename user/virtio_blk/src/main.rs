@@ -0,0 +1,355 @@
+#![feature(never_type)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, warn};
+use platform_bus::{DeviceDriverClient, DeviceHandoffCall, Filter, Property};
+use service_host::{ServiceChannelMessage, ServiceHostClient};
+use spinning_top::Spinlock;
+use std::{
+    poplar::{
+        block::{
+            BlockDeviceInfo,
+            BlockError,
+            BlockRequest,
+            BlockResponse,
+            BLOCK_DEVICE_SERVICE,
+            MAX_TRANSFER_SIZE,
+        },
+        channel::Channel,
+        ddk::dma::{DmaBuffer, DmaPool},
+        early_logger::EarlyLogger,
+        event::Event,
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+    sync::Arc,
+};
+use virtio::{
+    block::{BlockFeatures, PciBlockDeviceConfig, Request, RequestStatus, SECTOR_SIZE},
+    pci::PciTransport,
+    virtqueue::{Descriptor, DescriptorFlags, Virtqueue},
+};
+
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 16;
+
+/// The size of the part of a `Request` that is read by the device. The status byte follows it, and is written
+/// by the device after the data.
+const REQUEST_HEADER_SIZE: usize = 16;
+
+pub struct VirtioBlock {
+    /// The BAR `transport` accesses the device through. We hold it to keep it mapped.
+    _mapped_bar: MappedMemoryObject,
+    transport: PciTransport,
+    queue: Virtqueue,
+    buffer_pool: DmaPool,
+    interrupt_event: Event,
+    features: BlockFeatures,
+    /// The capacity of the device, in sectors.
+    capacity: u64,
+}
+
+impl VirtioBlock {
+    pub fn info(&self) -> BlockDeviceInfo {
+        BlockDeviceInfo {
+            block_size: SECTOR_SIZE as u32,
+            num_blocks: self.capacity,
+            read_only: self.features.contains(BlockFeatures::RO),
+        }
+    }
+
+    pub fn handle_request(&mut self, request: BlockRequest) -> BlockResponse {
+        let result = match request {
+            BlockRequest::GetInfo => Ok(BlockResponse::Info(self.info())),
+            BlockRequest::Read { block, count } => self.read(block, count).map(BlockResponse::Data),
+            BlockRequest::Write { block, data } => self.write(block, &data).map(|()| BlockResponse::Done),
+            BlockRequest::Flush => self.flush().map(|()| BlockResponse::Done),
+        };
+        result.unwrap_or_else(BlockResponse::Error)
+    }
+
+    pub fn read(&mut self, sector: u64, count: u32) -> Result<Vec<u8>, BlockError> {
+        let length = self.check_range(sector, count as usize * SECTOR_SIZE)?;
+        let mut buffer = self.buffer_pool.create_buffer(length).map_err(|()| BlockError::DeviceError)?;
+        self.do_request(Request::read(sector), Some((&mut buffer, true)))?;
+        Ok(buffer.read().to_vec())
+    }
+
+    pub fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), BlockError> {
+        if self.features.contains(BlockFeatures::RO) {
+            return Err(BlockError::ReadOnly);
+        }
+        if data.len() % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidLength);
+        }
+        let length = self.check_range(sector, data.len())?;
+        let mut buffer = self.buffer_pool.create_buffer(length).map_err(|()| BlockError::DeviceError)?;
+        buffer.write().copy_from_slice(data);
+        self.do_request(Request::write(sector), Some((&mut buffer, false)))
+    }
+
+    pub fn flush(&mut self) -> Result<(), BlockError> {
+        /*
+         * If the device doesn't support flushing, it doesn't cache writes, and so there is nothing
+         * to do.
+         */
+        if !self.features.contains(BlockFeatures::FLUSH) {
+            return Ok(());
+        }
+        self.do_request(Request::flush(), None)
+    }
+
+    /// Check that a transfer of `length` bytes, starting at `sector`, can be made. Returns the length.
+    fn check_range(&self, sector: u64, length: usize) -> Result<usize, BlockError> {
+        if length > MAX_TRANSFER_SIZE {
+            return Err(BlockError::TooLarge);
+        }
+        let end = sector.checked_add((length / SECTOR_SIZE) as u64).ok_or(BlockError::OutOfRange)?;
+        if end > self.capacity {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(length)
+    }
+
+    /// Make a request of the device, and wait for it to complete. `data` is the buffer to transfer, if the
+    /// request has one, and whether the device should write into it.
+    fn do_request(&mut self, request: Request, data: Option<(&mut DmaBuffer, bool)>) -> Result<(), BlockError> {
+        let header = self.buffer_pool.create(request).map_err(|()| BlockError::DeviceError)?;
+
+        /*
+         * Each request is made up of a chain of descriptors: the header is read by the device, then
+         * the data is read or written, and finally the device writes the status of the request.
+         */
+        let mut chain = Vec::with_capacity(3);
+        chain.push((header.phys as u64, REQUEST_HEADER_SIZE as u32, DescriptorFlags::empty()));
+        if let Some((buffer, device_writes)) = data {
+            let flags = if device_writes { DescriptorFlags::WRITE } else { DescriptorFlags::empty() };
+            chain.push((buffer.phys as u64, buffer.length as u32, flags));
+        }
+        chain.push(((header.phys + REQUEST_HEADER_SIZE) as u64, 1, DescriptorFlags::WRITE));
+
+        let mut descriptors = Vec::with_capacity(chain.len());
+        for _ in 0..chain.len() {
+            match self.queue.alloc_descriptor() {
+                Some(descriptor) => descriptors.push(descriptor),
+                None => {
+                    for descriptor in descriptors {
+                        self.queue.free_descriptor(descriptor);
+                    }
+                    return Err(BlockError::DeviceError);
+                }
+            }
+        }
+        for (i, (address, len, flags)) in chain.into_iter().enumerate() {
+            let next = descriptors.get(i + 1).copied();
+            self.queue.push_descriptor(
+                descriptors[i],
+                Descriptor {
+                    address,
+                    len,
+                    flags: if next.is_some() { flags | DescriptorFlags::NEXT } else { flags },
+                    next: next.unwrap_or(0),
+                },
+            );
+        }
+        self.queue.make_descriptor_available(descriptors[0]);
+        self.transport.notify(REQUEST_QUEUE);
+
+        /*
+         * We only have one request in flight at a time, so the next used element is always the
+         * completion of this request.
+         */
+        loop {
+            if self.queue.pop_used().is_some() {
+                break;
+            }
+            self.interrupt_event.wait_for_event_blocking();
+            self.interrupt_event.ack_interrupt();
+        }
+        for descriptor in descriptors {
+            self.queue.free_descriptor(descriptor);
+        }
+
+        match header.read().status {
+            RequestStatus::Ok => Ok(()),
+            status => {
+                warn!("Virtio block request {:?} failed: {:?}", request.typ, status);
+                Err(BlockError::DeviceError)
+            }
+        }
+    }
+}
+
+/*
+ * `Virtqueue` isn't `Send`, because it points into the memory its rings are in. That memory is mapped into the
+ * whole address space, so can be used from any thread, and only the `VirtioBlock` that owns the queue accesses it.
+ */
+unsafe impl Send for VirtioBlock {}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Virtio block driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = ServiceHostClient::new();
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    let handoff_server = platform_bus_device_client
+        .register(vec![
+            Filter::Matches(String::from("pci.vendor_id"), Property::Integer(0x1af4)),
+            Filter::Matches(String::from("pci.device_id"), Property::Integer(0x1042)),
+        ])
+        .unwrap();
+
+    let (_device_info, handoff_info) = loop {
+        match handoff_server.next_blocking().unwrap() {
+            DeviceHandoffCall::QuerySupport { responder, .. } => {
+                responder.reply(true).unwrap();
+            }
+            DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info } => {
+                info!("Started driving device: {}", name);
+                break (device_info, handoff_info);
+            }
+            DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                responder.reply(()).unwrap();
+            }
+        }
+    };
+
+    let mapped_bar = {
+        let bar = MemoryObject {
+            handle: handoff_info.get_as_memory_object("pci.bar4.handle").unwrap(),
+            size: handoff_info.get_as_integer("pci.bar4.size").unwrap() as usize,
+            flags: MemoryObjectFlags::WRITABLE,
+            phys_address: None,
+        };
+        unsafe { bar.map().unwrap() }
+    };
+    let interrupt_event = handoff_info.get_as_event("pci.interrupt").unwrap();
+    let dma_constraints = handoff_info
+        .get_as_dma_domain("pci.dma_domain")
+        .map_or(DmaConstraints::NONE, |domain| DmaConstraints::NONE.in_domain(domain));
+
+    let memory_manager = VirtioMemoryManager::new(dma_constraints);
+    let queue = Virtqueue::new(QUEUE_SIZE, &memory_manager);
+    let buffer_pool = {
+        const BUFFER_POOL_SIZE: usize = 0x4000;
+        let (memory_object, _) =
+            MemoryObject::create_dma(BUFFER_POOL_SIZE, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        const BUFFER_POOL_ADDRESS: usize = 0x00000005_20000000;
+        let memory_object = unsafe { memory_object.map_at(BUFFER_POOL_ADDRESS).unwrap() };
+        DmaPool::new(memory_object)
+    };
+
+    let mut transport = unsafe { PciTransport::new(mapped_bar.mapped_at) };
+    let features = BlockFeatures::from_bits_truncate(transport.begin_init());
+    if !features.contains(BlockFeatures::VERSION_1) {
+        panic!("Virtio block device does not support required features: {:?}", features);
+    }
+    let features = features & (BlockFeatures::VERSION_1 | BlockFeatures::RO | BlockFeatures::FLUSH);
+    transport.set_features(features.bits()).expect("Virtio block device did not accept features");
+    transport.setup_queue(REQUEST_QUEUE, &queue, 0);
+    transport.finish_init().expect("Virtio device initialization failed");
+
+    let capacity = unsafe { transport.device_cfg::<PciBlockDeviceConfig>() }.capacity();
+    info!(
+        "Virtio block device has {} sectors ({} bytes, read-only: {})",
+        capacity,
+        capacity * SECTOR_SIZE as u64,
+        features.contains(BlockFeatures::RO)
+    );
+
+    let block = Arc::new(Spinlock::new(VirtioBlock {
+        _mapped_bar: mapped_bar,
+        transport,
+        queue,
+        buffer_pool,
+        interrupt_event,
+        features,
+        capacity,
+    }));
+
+    /*
+     * We only drive a single device, but keep serving the Platform Bus so we can flush the device's write cache
+     * before the system is shut down.
+     */
+    std::poplar::rt::spawn({
+        let block = block.clone();
+        async move {
+            loop {
+                match handoff_server.next().await.unwrap() {
+                    DeviceHandoffCall::QuerySupport { responder, .. } => {
+                        responder.reply(false).unwrap();
+                    }
+                    DeviceHandoffCall::HandoffDevice { name, .. } => {
+                        warn!("Handed off device '{}', but we're already driving a device!", name);
+                    }
+                    DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                        if block.lock().flush().is_err() {
+                            warn!("Failed to flush virtio block device before shutdown");
+                        }
+                        responder.reply(()).unwrap();
+                    }
+                }
+            }
+        }
+    });
+
+    let service_channel = service_host_client.register_service(BLOCK_DEVICE_SERVICE).unwrap();
+    std::poplar::rt::spawn(async move {
+        loop {
+            match service_channel.receive().await.unwrap() {
+                ServiceChannelMessage::NewClient { name, channel } => {
+                    info!("Task '{}' is using the block device", name);
+                    let channel: Channel<BlockResponse, BlockRequest> = Channel::new_from_handle(channel);
+
+                    /*
+                     * Each client gets a task to handle its requests. Requests are carried out
+                     * synchronously, so requests from different clients are serialized by the
+                     * lock on the device.
+                     */
+                    std::poplar::rt::spawn({
+                        let block = block.clone();
+                        async move {
+                            loop {
+                                let request = channel.receive().await.unwrap();
+                                let response = block.lock().handle_request(request);
+                                channel.send(&response).unwrap();
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
+
+pub struct VirtioMemoryManager {
+    area: MappedMemoryObject,
+    offset: AtomicUsize,
+}
+
+impl VirtioMemoryManager {
+    pub fn new(dma_constraints: DmaConstraints) -> VirtioMemoryManager {
+        let (memory_object, _) =
+            MemoryObject::create_dma(0x1000, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        const QUEUE_AREA_ADDRESS: usize = 0x00000005_10000000;
+        let memory_object = unsafe { memory_object.map_at(QUEUE_AREA_ADDRESS).unwrap() };
+
+        VirtioMemoryManager { area: memory_object, offset: AtomicUsize::new(0) }
+    }
+}
+
+impl virtio::virtqueue::Mapper for VirtioMemoryManager {
+    fn alloc(&self, size: usize) -> (usize, usize) {
+        // Keep each part of the virtqueue aligned to 16 bytes (the strictest alignment any of them require)
+        let size = size.next_multiple_of(16);
+        let virt = self.area.mapped_at + self.offset.fetch_add(size, Ordering::Relaxed);
+        (self.area.virt_to_phys(virt).unwrap(), virt)
+    }
+}
@@ -1,11 +1,12 @@
 #![feature(never_type)]
 
 use core::sync::atomic::{AtomicUsize, Ordering};
-use log::info;
+use log::{info, warn};
 use platform_bus::{
-    BusDriverMessage,
-    DeviceDriverMessage,
-    DeviceDriverRequest,
+    framebuffer::{FramebufferEvent, FramebufferRequest},
+    BusDriverClient,
+    DeviceDriverClient,
+    DeviceHandoffCall,
     DeviceInfo,
     Filter,
     HandoffInfo,
@@ -22,7 +23,7 @@ use std::{
         early_logger::EarlyLogger,
         event::Event,
         memory_object::{MappedMemoryObject, MemoryObject},
-        syscall::{self, MemoryObjectFlags},
+        syscall::{self, DmaConstraints, MapMemoryObjectFlags, MemoryObjectFlags},
     },
 };
 use virtio::{
@@ -32,6 +33,7 @@ use virtio::{
         CtrlType,
         DisplayInfo,
         FlushResource,
+        ResourceUnref,
         SetScanout,
         SimpleResourceAttachBacking,
         TransferToHost2D,
@@ -54,6 +56,10 @@ const ISR_CFG_OFFSET: usize = 0x1000;
 const DEVICE_CFG_OFFSET: usize = 0x2000;
 const NOTIFY_CFG_OFFSET: usize = 0x3000;
 
+/// The smallest resolution we'll set the display to.
+const MIN_WIDTH: u32 = 320;
+const MIN_HEIGHT: u32 = 200;
+
 pub type ResourceIndex = u32;
 pub struct ScanoutInfo {
     width: u32,
@@ -61,6 +67,21 @@ pub struct ScanoutInfo {
     scanout_id: u32,
 }
 
+/// A resource that is shown on the scanout, along with the guest memory backing it.
+pub struct Framebuffer {
+    resource: ResourceIndex,
+    width: u32,
+    height: u32,
+    memory: MappedMemoryObject,
+}
+
+impl Framebuffer {
+    /// The number of bytes in each row of the framebuffer. All our resources use 4-byte pixels.
+    pub fn stride(&self) -> u32 {
+        self.width * 4
+    }
+}
+
 pub struct VirtioGpu<'a> {
     mapped_bar: MappedMemoryObject,
     // TODO: This is located in `mapped_bar`, so we need to be very careful not to create aliasing
@@ -69,6 +90,8 @@ pub struct VirtioGpu<'a> {
     interrupt_event: Event,
     queue: Virtqueue,
     request_pool: DmaPool,
+    /// Describes how to allocate memory the device can access, such as the backing of framebuffers.
+    dma_constraints: DmaConstraints,
     next_resource_id: ResourceIndex,
 }
 
@@ -79,32 +102,51 @@ impl<'a> VirtioGpu<'a> {
         interrupt_event: Event,
         queue: Virtqueue,
         request_pool: DmaPool,
+        dma_constraints: DmaConstraints,
     ) -> VirtioGpu<'a> {
-        VirtioGpu { mapped_bar, common_cfg, interrupt_event, queue, request_pool, next_resource_id: 1 }
+        VirtioGpu {
+            mapped_bar,
+            common_cfg,
+            interrupt_event,
+            queue,
+            request_pool,
+            dma_constraints,
+            next_resource_id: 1,
+        }
     }
 
-    pub fn get_scanout_info(&mut self, override_size: Option<(u32, u32)>) -> ScanoutInfo {
+    /// Get the preferred mode of the first enabled scanout.
+    pub fn get_scanout_info(&mut self) -> ScanoutInfo {
         let response: DisplayInfo = self.make_request(CtrlHeader::new(CtrlType::CmdGetDisplayInfo));
         assert!(response.header.typ == CtrlType::OkDisplayInfo);
         // XXX: we'll only support one display for now, so find the first enabled scanout
         let (scanout_id, mode) = response.modes.iter().enumerate().find(|(_, mode)| mode.enabled != 0).unwrap();
         info!("Display info: {:?}", mode);
 
-        if let Some((width, height)) = override_size {
-            ScanoutInfo { width, height, scanout_id: scanout_id as u32 }
-        } else {
-            ScanoutInfo { width: mode.width, height: mode.height, scanout_id: scanout_id as u32 }
-        }
+        ScanoutInfo { width: mode.width, height: mode.height, scanout_id: scanout_id as u32 }
     }
 
-    pub fn create_resource(&mut self, format: VirtioGpuFormat, width: u32, height: u32) -> ResourceIndex {
+    /// Create a 2D resource. This can fail if the host can't allocate memory for a resource of the given size.
+    pub fn create_resource(
+        &mut self,
+        format: VirtioGpuFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<ResourceIndex, CtrlType> {
         let id = self.next_resource_id;
         self.next_resource_id += 1;
         let response: CtrlHeader = self.make_request(CreateResource2D::new(id, format, width, height));
         if response.typ != CtrlType::OkNoData {
-            panic!("Error creating GPU resource: {:?}", response.typ);
+            return Err(response.typ);
+        }
+        Ok(id)
+    }
+
+    pub fn unref_resource(&mut self, resource: ResourceIndex) {
+        let response: CtrlHeader = self.make_request(ResourceUnref::new(resource));
+        if response.typ != CtrlType::OkNoData {
+            panic!("Error unreferencing GPU resource: {:?}", response.typ);
         }
-        id
     }
 
     pub fn attach_backing(&mut self, resource: ResourceIndex, address: u64, length: u32) {
@@ -126,20 +168,63 @@ impl<'a> VirtioGpu<'a> {
         }
     }
 
-    pub fn transfer_to_host_2d(&mut self, resource: ResourceIndex, width: u32, height: u32) {
-        let response: CtrlHeader = self.make_request(TransferToHost2D::new(width, height, 0, resource));
+    /// Transfer a region of a resource to the host. `stride` is the number of bytes in each row of the resource's
+    /// backing.
+    pub fn transfer_to_host_2d(
+        &mut self,
+        resource: ResourceIndex,
+        stride: u32,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) {
+        let offset = y as u64 * stride as u64 + x as u64 * 4;
+        let response: CtrlHeader = self.make_request(TransferToHost2D::new(x, y, width, height, offset, resource));
         if response.typ != CtrlType::OkNoData {
             panic!("Error transfering resource to host (2D): {:?}", response.typ);
         }
     }
 
-    pub fn flush_resource(&mut self, resource: ResourceIndex, width: u32, height: u32) {
-        let response: CtrlHeader = self.make_request(FlushResource::new(resource, width, height));
+    pub fn flush_resource(&mut self, resource: ResourceIndex, x: u32, y: u32, width: u32, height: u32) {
+        let response: CtrlHeader = self.make_request(FlushResource::new(resource, x, y, width, height));
         if response.typ != CtrlType::OkNoData {
             panic!("Error flushing resource: {:?}", response.typ);
         }
     }
 
+    /// Create a framebuffer of the given size, and show it on the scanout. The framebuffer is cleared to black.
+    /// Returns `None` if the framebuffer can't be allocated.
+    pub fn create_framebuffer(&mut self, scanout_id: u32, width: u32, height: u32) -> Option<Framebuffer> {
+        let size = width * height * 4;
+        let memory =
+            match MemoryObject::create_dma(size as usize, MemoryObjectFlags::WRITABLE, self.dma_constraints) {
+                Ok((memory, _)) => unsafe { memory.map_with_flags(MapMemoryObjectFlags::HUGE_PAGES).unwrap() },
+                Err(err) => {
+                    warn!("Failed to allocate memory for {}x{} framebuffer: {:?}", width, height, err);
+                    return None;
+                }
+            };
+        let resource = match self.create_resource(VirtioGpuFormat::R8G8B8X8Unorm, width, height) {
+            Ok(resource) => resource,
+            Err(err) => {
+                warn!("Failed to create {}x{} framebuffer resource: {:?}", width, height, err);
+                return None;
+            }
+        };
+        self.attach_backing(resource, memory.inner.phys_address.unwrap() as u64, size);
+        self.set_scanout(&ScanoutInfo { width, height, scanout_id }, resource);
+
+        let framebuffer = Framebuffer { resource, width, height, memory };
+        unsafe {
+            std::ptr::write_bytes(framebuffer.memory.ptr() as *mut u8, 0, size as usize);
+        }
+        self.transfer_to_host_2d(resource, framebuffer.stride(), 0, 0, width, height);
+        self.flush_resource(resource, 0, 0, width, height);
+
+        Some(framebuffer)
+    }
+
     fn make_request<T, R>(&mut self, request: T) -> R {
         use virtio::virtqueue::{Descriptor, DescriptorFlags};
 
@@ -170,9 +255,12 @@ impl<'a> VirtioGpu<'a> {
 
         self.queue.make_descriptor_available(descriptor_0);
 
+        #[cfg(target_arch = "riscv64")]
         unsafe {
             core::arch::asm!("fence ow, ow");
         }
+        #[cfg(not(target_arch = "riscv64"))]
+        core::sync::atomic::fence(Ordering::SeqCst);
 
         /*
          * TODO: notifying the device of an available virtqueue is much harder via PCI than MMIO -
@@ -197,6 +285,7 @@ impl<'a> VirtioGpu<'a> {
     /// Wait for dispatched requests to complete, clearing the used ring as we go.
     fn wait_for_request(&mut self) {
         self.interrupt_event.wait_for_event_blocking();
+        self.interrupt_event.ack_interrupt();
 
         // TODO: we're sent interrupts for various things - do we need to check??
     }
@@ -209,25 +298,28 @@ fn main() {
 
     let service_host_client = ServiceHostClient::new();
     // We act as a bus driver to create the framebuffer device
-    let platform_bus_bus_channel: Channel<BusDriverMessage, !> =
-        service_host_client.subscribe_service("platform_bus.bus_driver").unwrap();
+    let platform_bus_bus_client =
+        BusDriverClient::new(service_host_client.subscribe_service("platform_bus.bus_driver").unwrap());
     // And also as a device driver to find Virtio GPU devices
-    let platform_bus_device_channel: Channel<DeviceDriverMessage, DeviceDriverRequest> =
-        service_host_client.subscribe_service("platform_bus.device_driver").unwrap();
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
 
-    platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![
+    let handoff_server = platform_bus_device_client
+        .register(vec![
             Filter::Matches(String::from("pci.vendor_id"), Property::Integer(0x1af4)),
             Filter::Matches(String::from("pci.device_id"), Property::Integer(0x1050)),
-        ]))
+        ])
         .unwrap();
 
     let (device_info, handoff_info) = loop {
-        match platform_bus_device_channel.try_receive().unwrap() {
-            Some(DeviceDriverRequest::QuerySupport(name, _)) => {
-                platform_bus_device_channel.send(&DeviceDriverMessage::CanSupport(name, true)).unwrap();
+        match handoff_server.try_next().unwrap() {
+            Some(DeviceHandoffCall::QuerySupport { responder, .. }) => {
+                responder.reply(true).unwrap();
             }
-            Some(DeviceDriverRequest::HandoffDevice(name, device_info, handoff_info)) => {
+            Some(DeviceHandoffCall::PrepareForShutdown { responder, .. }) => {
+                responder.reply(()).unwrap();
+            }
+            Some(DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info }) => {
                 info!("Started driving device: {}", name);
                 break (device_info, handoff_info);
             }
@@ -236,7 +328,6 @@ fn main() {
     };
 
     let mapped_bar = {
-        // TODO: let the kernel choose the address when it can - we don't care
         // TODO: maybe confirm info from platform_bus
         let bar = MemoryObject {
             handle: handoff_info.get_as_memory_object("pci.bar4.handle").unwrap(),
@@ -244,15 +335,18 @@ fn main() {
             flags: MemoryObjectFlags::WRITABLE,
             phys_address: None,
         };
-        const BAR_SPACE_ADDRESS: usize = 0x00000005_00000000;
-        unsafe { bar.map_at(BAR_SPACE_ADDRESS).unwrap() }
+        unsafe { bar.map().unwrap() }
     };
     let interrupt_event = handoff_info.get_as_event("pci.interrupt").unwrap();
+    let dma_constraints = handoff_info
+        .get_as_dma_domain("pci.dma_domain")
+        .map_or(DmaConstraints::NONE, |domain| DmaConstraints::NONE.in_domain(domain));
 
-    let memory_manager = VirtioMemoryManager::new();
+    let memory_manager = VirtioMemoryManager::new(dma_constraints);
     let queue = Virtqueue::new(64, &memory_manager);
     let request_pool = {
-        let memory_object = unsafe { MemoryObject::create_physical(0x1000, MemoryObjectFlags::WRITABLE).unwrap() };
+        let (memory_object, _) =
+            MemoryObject::create_dma(0x1000, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
         const REQUEST_POOL_ADDRESS: usize = 0x00000005_20000000;
         let memory_object = unsafe { memory_object.map_at(REQUEST_POOL_ADDRESS).unwrap() };
         DmaPool::new(memory_object)
@@ -281,69 +375,102 @@ fn main() {
     }
     assert!(common_cfg.num_queues.read() == 2);
 
-    let mut gpu = VirtioGpu::new(mapped_bar, common_cfg, interrupt_event, queue, request_pool);
-    // TODO: we currently set the resolution to always be 800x600, but this should of course be up
-    // to the layer above us in the future
-    let scanout_info = gpu.get_scanout_info(Some((800, 600)));
-    let framebuffer_resource =
-        gpu.create_resource(VirtioGpuFormat::R8G8B8X8Unorm, scanout_info.width, scanout_info.height);
-
-    // Allocate guest memory for the framebuffer
-    let framebuffer_size = scanout_info.width * scanout_info.height * 4;
-    let framebuffer = {
-        let memory_object = unsafe {
-            MemoryObject::create_physical(framebuffer_size as usize, MemoryObjectFlags::WRITABLE).unwrap()
-        };
-        const FRAMEBUFFER_ADDDRESS: usize = 0x00000005_30000000;
-        unsafe { memory_object.map_at(FRAMEBUFFER_ADDDRESS).unwrap() }
-    };
-    gpu.attach_backing(framebuffer_resource, framebuffer.inner.phys_address.unwrap() as u64, framebuffer_size);
-    gpu.set_scanout(&scanout_info, framebuffer_resource);
-
-    let framebuffer_base = framebuffer.ptr() as *mut u32;
-    for y in 0..scanout_info.height {
-        for x in 0..scanout_info.width {
-            unsafe {
-                std::ptr::write_volatile(framebuffer_base.add((y * scanout_info.width + x) as usize), 0xffff00ff);
-            }
-        }
-    }
-
-    // Flush the framebuffer to the host for the first time
-    gpu.transfer_to_host_2d(framebuffer_resource, scanout_info.width, scanout_info.height);
-    gpu.flush_resource(framebuffer_resource, scanout_info.width, scanout_info.height);
+    let mut gpu = VirtioGpu::new(mapped_bar, common_cfg, interrupt_event, queue, request_pool, dma_constraints);
+    // Start with the display's preferred mode. The user of the framebuffer can change it later.
+    let scanout_info = gpu.get_scanout_info();
+    let mut framebuffer = gpu
+        .create_framebuffer(scanout_info.scanout_id, scanout_info.width, scanout_info.height)
+        .expect("Failed to create framebuffer");
 
     // Add the framebuffer as a device to the Platform Bus
     let channel = {
         let device_info = {
             let mut properties = BTreeMap::new();
             properties.insert("type".to_string(), Property::String("framebuffer".to_string()));
-            properties.insert("width".to_string(), Property::Integer(scanout_info.width as u64));
-            properties.insert("height".to_string(), Property::Integer(scanout_info.height as u64));
+            properties.insert("width".to_string(), Property::Integer(framebuffer.width as u64));
+            properties.insert("height".to_string(), Property::Integer(framebuffer.height as u64));
+            // The resource is `R8G8B8X8Unorm`, so red is in the lowest byte of each pixel
+            properties.insert("format".to_string(), Property::String("rgb32".to_string()));
             DeviceInfo(properties)
         };
-        let (control_channel, control_channel_handle) = Channel::<(), ()>::create().unwrap();
+        let (control_channel, control_channel_handle) =
+            Channel::<FramebufferEvent, FramebufferRequest>::create().unwrap();
         let handoff_info = {
             let mut properties = BTreeMap::new();
-            properties.insert("framebuffer".to_string(), HandoffProperty::MemoryObject(framebuffer.inner.handle));
+            properties
+                .insert("framebuffer".to_string(), HandoffProperty::MemoryObject(framebuffer.memory.inner.handle));
             properties.insert("channel".to_string(), HandoffProperty::Channel(control_channel_handle));
             HandoffInfo(properties)
         };
-        platform_bus_bus_channel
-            .send(&BusDriverMessage::RegisterDevice("virtio-fb".to_string(), device_info, handoff_info))
-            .unwrap();
+        platform_bus_bus_client.register_device("virtio-fb".to_string(), device_info, handoff_info).unwrap();
         control_channel
     };
 
     loop {
-        match channel.try_receive() {
-            Ok(Some(message)) => {
-                // Flush the entire framebuffer to the host
-                gpu.transfer_to_host_2d(framebuffer_resource, scanout_info.width, scanout_info.height);
-                gpu.flush_resource(framebuffer_resource, scanout_info.width, scanout_info.height);
+        /*
+         * Collect all the regions we've been asked to flush since we last checked into a single region. This
+         * avoids falling behind if we're asked to flush faster than we can.
+         */
+        let mut region: Option<(u32, u32, u32, u32)> = None;
+        loop {
+            match channel.try_receive() {
+                Ok(Some(FramebufferRequest::Flush { x, y, width, height })) => {
+                    // Clip the region to the framebuffer
+                    let right = u32::min(x.saturating_add(width), framebuffer.width);
+                    let bottom = u32::min(y.saturating_add(height), framebuffer.height);
+                    if x >= right || y >= bottom {
+                        continue;
+                    }
+
+                    region = Some(match region {
+                        Some((left, top, old_right, old_bottom)) => (
+                            u32::min(left, x),
+                            u32::min(top, y),
+                            u32::max(old_right, right),
+                            u32::max(old_bottom, bottom),
+                        ),
+                        None => (x, y, right, bottom),
+                    });
+                }
+                Ok(Some(FramebufferRequest::SetMode { width, height })) => {
+                    info!("Setting display mode to {}x{}", width, height);
+                    let new_framebuffer = if width >= MIN_WIDTH && height >= MIN_HEIGHT {
+                        gpu.create_framebuffer(scanout_info.scanout_id, width, height)
+                    } else {
+                        None
+                    };
+
+                    let event = match new_framebuffer {
+                        Some(new_framebuffer) => {
+                            let old_framebuffer = mem::replace(&mut framebuffer, new_framebuffer);
+                            gpu.unref_resource(old_framebuffer.resource);
+                            unsafe {
+                                old_framebuffer.memory.unmap().unwrap();
+                            }
+                            // Anything we were asked to flush was drawn to the old framebuffer
+                            region = None;
+                            FramebufferEvent::ModeSet {
+                                width,
+                                height,
+                                framebuffer: framebuffer.memory.inner.handle,
+                            }
+                        }
+                        None => FramebufferEvent::ModeRefused,
+                    };
+                    channel.send(&event).unwrap();
+                }
+                Ok(None) => break,
+                Err(err) => panic!("Error receiving message from control channel: {:?}", err),
+            }
+        }
+
+        match region {
+            Some((left, top, right, bottom)) => {
+                let (width, height) = (right - left, bottom - top);
+                gpu.transfer_to_host_2d(framebuffer.resource, framebuffer.stride(), left, top, width, height);
+                gpu.flush_resource(framebuffer.resource, left, top, width, height);
             }
-            Ok(None) => std::poplar::syscall::yield_to_kernel(),
-            Err(err) => panic!("Error receiving message from control channel: {:?}", err),
+            None => std::poplar::syscall::yield_to_kernel(),
         }
     }
 }
@@ -354,8 +481,9 @@ pub struct VirtioMemoryManager {
 }
 
 impl VirtioMemoryManager {
-    pub fn new() -> VirtioMemoryManager {
-        let memory_object = unsafe { MemoryObject::create_physical(0x1000, MemoryObjectFlags::WRITABLE).unwrap() };
+    pub fn new(dma_constraints: DmaConstraints) -> VirtioMemoryManager {
+        let (memory_object, _) =
+            MemoryObject::create_dma(0x1000, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
         const QUEUE_AREA_ADDRESS: usize = 0x00000005_10000000;
         let memory_object = unsafe { memory_object.map_at(QUEUE_AREA_ADDRESS).unwrap() };
 
@@ -0,0 +1,325 @@
+//! `virtio_input` drives Virtio input devices that act as pointing devices - QEMU's `virtio-mouse` and
+//! `virtio-tablet`. Events from the device are translated into Platform Bus `InputEvent`s, so consumers don't
+//! need to care whether the pointer is attached by USB or Virtio.
+
+#![feature(never_type)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, warn};
+use platform_bus::{
+    input::{Button, InputDeviceClient, InputEvent},
+    BusDriverClient,
+    DeviceDriverClient,
+    DeviceHandoffCall,
+    DeviceInfo,
+    Filter,
+    HandoffInfo,
+    HandoffProperty,
+    Property,
+};
+use service_host::ServiceHostClient;
+use std::{
+    collections::BTreeMap,
+    poplar::{
+        channel::Channel,
+        ddk::dma::{DmaArray, DmaPool},
+        early_logger::EarlyLogger,
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+};
+use virtio::{
+    input::{self as virtio_input, AbsInfo, InputDeviceConfig, EVENT_QUEUE},
+    pci::PciTransport,
+    virtqueue::{Descriptor, DescriptorFlags, Virtqueue},
+};
+
+const QUEUE_SIZE: u16 = 64;
+/// The only feature we need (and the only one input devices have) is the device-independent `VERSION_1`.
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+pub struct VirtioInput {
+    /// The BAR `transport` accesses the device through. We hold it to keep it mapped.
+    _mapped_bar: MappedMemoryObject,
+    transport: PciTransport,
+    event_queue: Virtqueue,
+    /// The device writes each event into one of these. Each descriptor in the event queue describes the
+    /// element with the same index.
+    events: DmaArray<virtio_input::InputEvent>,
+}
+
+impl VirtioInput {
+    /// Make every event buffer available to the device.
+    fn fill_event_queue(&mut self) {
+        while let Some(descriptor) = self.event_queue.alloc_descriptor() {
+            self.event_queue.push_descriptor(
+                descriptor,
+                Descriptor {
+                    address: self.events.phys_of_element(descriptor as usize) as u64,
+                    len: core::mem::size_of::<virtio_input::InputEvent>() as u32,
+                    flags: DescriptorFlags::WRITE,
+                    next: 0,
+                },
+            );
+            self.event_queue.make_descriptor_available(descriptor);
+        }
+        self.transport.notify(EVENT_QUEUE);
+    }
+
+    /// Take the next event the device has produced, if there is one, and give its buffer back to the device.
+    fn next_event(&mut self) -> Option<virtio_input::InputEvent> {
+        let used = self.event_queue.pop_used()?;
+        let descriptor = used.start as u16;
+        let event = unsafe { core::ptr::read_volatile(self.events.read(descriptor as usize)) };
+
+        // The descriptor still describes the same buffer, so we can just make it available again
+        self.event_queue.make_descriptor_available(descriptor);
+        self.transport.notify(EVENT_QUEUE);
+        Some(event)
+    }
+}
+
+/*
+ * `VirtioInput` is moved into the task that handles the device's interrupts, which needs it to be `Send`. The
+ * event queue isn't `Send`, as it points into the memory its rings are in, but that memory is mapped into the
+ * whole address space, and the queue is only used by that task.
+ */
+unsafe impl Send for VirtioInput {}
+
+/// Virtio input devices report changes as a series of events, finished by an `EV_SYN` event. We collect the
+/// changes to the pointer's position until the end of each series, so movement along both axes is reported
+/// together.
+struct EventTranslator {
+    /// The range of each absolute axis, if the device is a tablet.
+    abs_info: Option<(AbsInfo, AbsInfo)>,
+    dx: i32,
+    dy: i32,
+    x: Option<u16>,
+    y: Option<u16>,
+    /// The last absolute position we reported, as an axis can be left out if it hasn't changed.
+    last_position: (u16, u16),
+}
+
+impl EventTranslator {
+    fn translate(&mut self, event: virtio_input::InputEvent, output: &mut Vec<InputEvent>) {
+        match (event.typ, event.code) {
+            (virtio_input::EV_SYN, _) => {
+                if self.dx != 0 || self.dy != 0 {
+                    output.push(InputEvent::PointerMoved { dx: self.dx, dy: self.dy });
+                }
+                if self.x.is_some() || self.y.is_some() {
+                    let x = self.x.take().unwrap_or(self.last_position.0);
+                    let y = self.y.take().unwrap_or(self.last_position.1);
+                    self.last_position = (x, y);
+                    output.push(InputEvent::PointerMovedTo { x, y });
+                }
+                (self.dx, self.dy) = (0, 0);
+            }
+            (virtio_input::EV_REL, virtio_input::REL_X) => self.dx += event.value as i32,
+            (virtio_input::EV_REL, virtio_input::REL_Y) => self.dy += event.value as i32,
+            (virtio_input::EV_REL, virtio_input::REL_WHEEL) => {
+                output.push(InputEvent::RelWheel(event.value as i32))
+            }
+            (virtio_input::EV_ABS, virtio_input::ABS_X) => {
+                if let Some((x_info, _)) = self.abs_info {
+                    self.x = Some(scale_abs(event.value, x_info));
+                }
+            }
+            (virtio_input::EV_ABS, virtio_input::ABS_Y) => {
+                if let Some((_, y_info)) = self.abs_info {
+                    self.y = Some(scale_abs(event.value, y_info));
+                }
+            }
+            (virtio_input::EV_KEY, code) => {
+                let button = match code {
+                    virtio_input::BTN_LEFT => Button::Left,
+                    virtio_input::BTN_RIGHT => Button::Right,
+                    virtio_input::BTN_MIDDLE => Button::Middle,
+                    virtio_input::BTN_SIDE => Button::Side,
+                    virtio_input::BTN_EXTRA => Button::Extra,
+                    _ => {
+                        warn!("Unsupported key event from Virtio input device: {:#x}", code);
+                        return;
+                    }
+                };
+                // A value of `2` means the key is being held down, which we don't care about for buttons
+                match event.value {
+                    0 => output.push(InputEvent::ButtonReleased(button)),
+                    1 => output.push(InputEvent::ButtonPressed(button)),
+                    _ => (),
+                }
+            }
+            (typ, code) => warn!("Unsupported event from Virtio input device: type={:#x}, code={:#x}", typ, code),
+        }
+    }
+}
+
+/// Scale a value from an absolute axis to cover `0..=u16::MAX`, as `InputEvent::PointerMovedTo` expects.
+fn scale_abs(value: u32, info: AbsInfo) -> u16 {
+    if info.max <= info.min {
+        return 0;
+    }
+    let value = (value.clamp(info.min, info.max) - info.min) as u64;
+    (value * u16::MAX as u64 / (info.max - info.min) as u64) as u16
+}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Virtio input driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = ServiceHostClient::new();
+    // We act as a bus driver to create the abstract input device
+    let platform_bus_bus_client =
+        BusDriverClient::new(service_host_client.subscribe_service("platform_bus.bus_driver").unwrap());
+    // And also as a device driver to find Virtio input devices
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    let handoff_server = platform_bus_device_client
+        .register(vec![
+            Filter::Matches(String::from("pci.vendor_id"), Property::Integer(0x1af4)),
+            Filter::Matches(String::from("pci.device_id"), Property::Integer(0x1052)),
+        ])
+        .unwrap();
+
+    // TODO: support more than one input device
+    let (device_name, _device_info, handoff_info) = loop {
+        match handoff_server.next_blocking().unwrap() {
+            DeviceHandoffCall::QuerySupport { responder, .. } => {
+                responder.reply(true).unwrap();
+            }
+            DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                responder.reply(()).unwrap();
+            }
+            DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info } => {
+                info!("Started driving device: {}", name);
+                break (name, device_info, handoff_info);
+            }
+        }
+    };
+
+    let mapped_bar = {
+        let bar = MemoryObject {
+            handle: handoff_info.get_as_memory_object("pci.bar4.handle").unwrap(),
+            size: handoff_info.get_as_integer("pci.bar4.size").unwrap() as usize,
+            flags: MemoryObjectFlags::WRITABLE,
+            phys_address: None,
+        };
+        unsafe { bar.map().unwrap() }
+    };
+    let interrupt_event = handoff_info.get_as_event("pci.interrupt").unwrap();
+    let dma_constraints = handoff_info
+        .get_as_dma_domain("pci.dma_domain")
+        .map_or(DmaConstraints::NONE, |domain| DmaConstraints::NONE.in_domain(domain));
+
+    let memory_manager = VirtioMemoryManager::new(dma_constraints);
+    let event_queue = Virtqueue::new(QUEUE_SIZE, &memory_manager);
+    let events = {
+        const EVENT_POOL_SIZE: usize = 0x1000;
+        let (memory_object, _) =
+            MemoryObject::create_dma(EVENT_POOL_SIZE, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        const EVENT_POOL_ADDRESS: usize = 0x00000005_20000000;
+        let memory_object = unsafe { memory_object.map_at(EVENT_POOL_ADDRESS).unwrap() };
+        DmaPool::new(memory_object)
+            .create_array(QUEUE_SIZE as usize, virtio_input::InputEvent::default())
+            .expect("Failed to allocate event buffers")
+    };
+
+    let mut transport = unsafe { PciTransport::new(mapped_bar.mapped_at) };
+    if transport.begin_init() & FEATURE_VERSION_1 == 0 {
+        panic!("Virtio input device does not support VERSION_1");
+    }
+    transport.set_features(FEATURE_VERSION_1).expect("Virtio input device did not accept features");
+
+    // We don't use the status queue, so only set up the event queue
+    transport.setup_queue(EVENT_QUEUE, &event_queue, 0);
+    transport.finish_init().expect("Virtio device initialization failed");
+    let device_cfg = unsafe { transport.device_cfg::<InputDeviceConfig>() };
+
+    /*
+     * Tablets report the absolute position of the pointer, and mice report how far it has moved. We need the
+     * range of each axis to scale absolute positions.
+     */
+    let abs_info = if device_cfg.supports_event_type(virtio_input::EV_ABS) {
+        match (device_cfg.abs_info(virtio_input::ABS_X), device_cfg.abs_info(virtio_input::ABS_Y)) {
+            (Some(x_info), Some(y_info)) => Some((x_info, y_info)),
+            _ => {
+                warn!(
+                    "Virtio input device reports absolute events, but not the range of its axes. Ignoring them."
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let hid_type = if abs_info.is_some() { "tablet" } else { "mouse" };
+    info!("Virtio input device '{}' is a {}", device_name, hid_type);
+
+    let mut input = VirtioInput { _mapped_bar: mapped_bar, transport, event_queue, events };
+    input.fill_event_queue();
+
+    // Add the input device to the Platform Bus
+    let input_device = {
+        let device_info = {
+            let mut properties = BTreeMap::new();
+            properties.insert("hid.type".to_string(), Property::String(hid_type.to_string()));
+            DeviceInfo(properties)
+        };
+        let (channel, channel_handle) = Channel::create().unwrap();
+        let handoff_info = {
+            let mut properties = BTreeMap::new();
+            properties.insert("hid.channel".to_string(), HandoffProperty::Channel(channel_handle));
+            HandoffInfo(properties)
+        };
+        platform_bus_bus_client.register_device("virtio-input".to_string(), device_info, handoff_info).unwrap();
+        InputDeviceClient::new(channel)
+    };
+
+    std::poplar::rt::spawn(async move {
+        let mut translator = EventTranslator { abs_info, dx: 0, dy: 0, x: None, y: None, last_position: (0, 0) };
+        let mut output = Vec::new();
+
+        loop {
+            interrupt_event.wait_for_event().await;
+
+            while let Some(event) = input.next_event() {
+                translator.translate(event, &mut output);
+            }
+            interrupt_event.ack_interrupt();
+            for event in output.drain(..) {
+                input_device.event(event).unwrap();
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
+
+pub struct VirtioMemoryManager {
+    area: MappedMemoryObject,
+    offset: AtomicUsize,
+}
+
+impl VirtioMemoryManager {
+    pub fn new(dma_constraints: DmaConstraints) -> VirtioMemoryManager {
+        let (memory_object, _) =
+            MemoryObject::create_dma(0x1000, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        const QUEUE_AREA_ADDRESS: usize = 0x00000005_10000000;
+        let memory_object = unsafe { memory_object.map_at(QUEUE_AREA_ADDRESS).unwrap() };
+
+        VirtioMemoryManager { area: memory_object, offset: AtomicUsize::new(0) }
+    }
+}
+
+impl virtio::virtqueue::Mapper for VirtioMemoryManager {
+    fn alloc(&self, size: usize) -> (usize, usize) {
+        // Keep each part of the virtqueue aligned to 16 bytes (the strictest alignment required by any part)
+        let size = size.next_multiple_of(16);
+        let virt = self.area.mapped_at + self.offset.fetch_add(size, Ordering::Relaxed);
+        (self.area.virt_to_phys(virt).unwrap(), virt)
+    }
+}
@@ -0,0 +1,358 @@
+#![feature(never_type)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, warn};
+use platform_bus::{
+    net::{NetworkEvent, NetworkRequest},
+    BusDriverClient,
+    DeviceDriverClient,
+    DeviceHandoffCall,
+    DeviceInfo,
+    Filter,
+    HandoffInfo,
+    HandoffProperty,
+    Property,
+};
+use service_host::ServiceHostClient;
+use spinning_top::Spinlock;
+use std::{
+    collections::BTreeMap,
+    mem,
+    poplar::{
+        channel::Channel,
+        ddk::dma::{DmaBuffer, DmaPool},
+        early_logger::EarlyLogger,
+        memory_object::{MappedMemoryObject, MemoryObject},
+        syscall::{DmaConstraints, MemoryObjectFlags},
+    },
+    sync::Arc,
+};
+use virtio::{
+    net::{NetDeviceConfig, NetFeatures, NetHeader},
+    pci::PciTransport,
+    virtqueue::{Descriptor, DescriptorFlags, Virtqueue},
+};
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 64;
+
+/// The largest Ethernet frame we can send or receive (a 1500-byte payload, plus the header). Each buffer also
+/// holds the header the device puts before each packet.
+const MAX_FRAME_SIZE: usize = 1514;
+const BUFFER_SIZE: usize = mem::size_of::<NetHeader>() + MAX_FRAME_SIZE;
+const NUM_RECEIVE_BUFFERS: usize = 32;
+
+pub struct VirtioNet {
+    /// The BAR `transport` accesses the device through. We hold it to keep it mapped.
+    _mapped_bar: MappedMemoryObject,
+    transport: PciTransport,
+    receive_queue: Virtqueue,
+    transmit_queue: Virtqueue,
+    buffer_pool: DmaPool,
+    /// The buffers currently owned by the device, keyed by the descriptor that describes them.
+    receive_buffers: BTreeMap<u16, DmaBuffer>,
+    transmit_buffers: BTreeMap<u16, DmaBuffer>,
+}
+
+impl VirtioNet {
+    pub fn new(
+        mapped_bar: MappedMemoryObject,
+        transport: PciTransport,
+        receive_queue: Virtqueue,
+        transmit_queue: Virtqueue,
+        buffer_pool: DmaPool,
+    ) -> VirtioNet {
+        let mut net = VirtioNet {
+            _mapped_bar: mapped_bar,
+            transport,
+            receive_queue,
+            transmit_queue,
+            buffer_pool,
+            receive_buffers: BTreeMap::new(),
+            transmit_buffers: BTreeMap::new(),
+        };
+        net.fill_receive_queue();
+        net
+    }
+
+    /// Send a packet. Fails if the packet is too large, or if we're out of space to send it (e.g. because the
+    /// device is yet to process earlier packets).
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), ()> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(());
+        }
+
+        // Reclaim the buffers of any packets that have been sent since we last checked
+        self.reclaim_transmit_buffers();
+
+        let length = mem::size_of::<NetHeader>() + frame.len();
+        let mut buffer = self.buffer_pool.create_buffer(length)?;
+        // The header is zeroed by `create_buffer`, which is what we want as we don't use any offloads
+        buffer.write()[mem::size_of::<NetHeader>()..].copy_from_slice(frame);
+
+        let descriptor = self.transmit_queue.alloc_descriptor().ok_or(())?;
+        self.transmit_queue.push_descriptor(
+            descriptor,
+            Descriptor {
+                address: buffer.phys as u64,
+                len: length as u32,
+                flags: DescriptorFlags::empty(),
+                next: 0,
+            },
+        );
+        self.transmit_buffers.insert(descriptor, buffer);
+        self.transmit_queue.make_descriptor_available(descriptor);
+        self.transport.notify(TRANSMIT_QUEUE);
+
+        Ok(())
+    }
+
+    /// Free the buffers of packets that the device has finished sending.
+    pub fn reclaim_transmit_buffers(&mut self) {
+        while let Some(used) = self.transmit_queue.pop_used() {
+            let descriptor = used.start as u16;
+            self.transmit_buffers.remove(&descriptor);
+            self.transmit_queue.free_descriptor(descriptor);
+        }
+    }
+
+    /// Receive the next packet that the device has received, if there is one.
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        let used = self.receive_queue.pop_used()?;
+        let descriptor = used.start as u16;
+        let buffer = self.receive_buffers.remove(&descriptor).expect("Device used unknown receive buffer");
+        self.receive_queue.free_descriptor(descriptor);
+
+        let frame = buffer.read()[mem::size_of::<NetHeader>()..(used.length as usize)].to_vec();
+
+        // Give the device a new buffer to replace the one we've taken
+        self.fill_receive_queue();
+        Some(frame)
+    }
+
+    /// Make receive buffers available to the device until we have `NUM_RECEIVE_BUFFERS` in the queue.
+    fn fill_receive_queue(&mut self) {
+        while self.receive_buffers.len() < NUM_RECEIVE_BUFFERS {
+            let Some(descriptor) = self.receive_queue.alloc_descriptor() else {
+                break;
+            };
+            let buffer = self.buffer_pool.create_buffer(BUFFER_SIZE).expect("Failed to allocate receive buffer");
+            self.receive_queue.push_descriptor(
+                descriptor,
+                Descriptor {
+                    address: buffer.phys as u64,
+                    len: BUFFER_SIZE as u32,
+                    flags: DescriptorFlags::WRITE,
+                    next: 0,
+                },
+            );
+            self.receive_buffers.insert(descriptor, buffer);
+            self.receive_queue.make_descriptor_available(descriptor);
+        }
+
+        self.transport.notify(RECEIVE_QUEUE);
+    }
+}
+
+/*
+ * The receive and transmit `Virtqueue`s aren't `Send`, as they point into the memory their rings are in. We share
+ * `VirtioNet` between the tasks that receive and send packets, which can run on any thread, but it's only accessed
+ * through a lock, and the rings are mapped into the whole address space.
+ */
+unsafe impl Send for VirtioNet {}
+
+fn main() {
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("Virtio network driver is running!");
+
+    std::poplar::rt::init_runtime();
+
+    let service_host_client = ServiceHostClient::new();
+    // We act as a bus driver to create the abstract network device
+    let platform_bus_bus_client =
+        BusDriverClient::new(service_host_client.subscribe_service("platform_bus.bus_driver").unwrap());
+    // And also as a device driver to find Virtio network devices
+    let platform_bus_device_client =
+        DeviceDriverClient::new(service_host_client.subscribe_service("platform_bus.device_driver").unwrap());
+
+    let handoff_server = platform_bus_device_client
+        .register(vec![
+            Filter::Matches(String::from("pci.vendor_id"), Property::Integer(0x1af4)),
+            Filter::Matches(String::from("pci.device_id"), Property::Integer(0x1041)),
+        ])
+        .unwrap();
+
+    let (_device_info, handoff_info) = loop {
+        match handoff_server.next_blocking().unwrap() {
+            DeviceHandoffCall::QuerySupport { responder, .. } => {
+                responder.reply(true).unwrap();
+            }
+            DeviceHandoffCall::PrepareForShutdown { responder, .. } => {
+                responder.reply(()).unwrap();
+            }
+            DeviceHandoffCall::HandoffDevice { name, device_info, handoff_info } => {
+                info!("Started driving device: {}", name);
+                break (device_info, handoff_info);
+            }
+        }
+    };
+
+    let mapped_bar = {
+        let bar = MemoryObject {
+            handle: handoff_info.get_as_memory_object("pci.bar4.handle").unwrap(),
+            size: handoff_info.get_as_integer("pci.bar4.size").unwrap() as usize,
+            flags: MemoryObjectFlags::WRITABLE,
+            phys_address: None,
+        };
+        unsafe { bar.map().unwrap() }
+    };
+    /*
+     * The receive queue signals the first interrupt. If the device has a second, the transmit queue signals it,
+     * so we can free the buffers of sent packets promptly. Otherwise, they're freed the next time we send one.
+     */
+    let receive_interrupt = handoff_info.get_pci_interrupt(0).unwrap();
+    let transmit_interrupt = handoff_info.get_pci_interrupt(1);
+    let dma_constraints = handoff_info
+        .get_as_dma_domain("pci.dma_domain")
+        .map_or(DmaConstraints::NONE, |domain| DmaConstraints::NONE.in_domain(domain));
+
+    let memory_manager = VirtioMemoryManager::new(dma_constraints);
+    let receive_queue = Virtqueue::new(QUEUE_SIZE, &memory_manager);
+    let transmit_queue = Virtqueue::new(QUEUE_SIZE, &memory_manager);
+    let buffer_pool = {
+        /*
+         * This needs to hold all the receive buffers, plus the buffers of packets that are in the
+         * process of being sent (and the allocator's own bookkeeping).
+         */
+        const BUFFER_POOL_SIZE: usize = 0x40000;
+        let (memory_object, _) =
+            MemoryObject::create_dma(BUFFER_POOL_SIZE, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        const BUFFER_POOL_ADDRESS: usize = 0x00000005_20000000;
+        let memory_object = unsafe { memory_object.map_at(BUFFER_POOL_ADDRESS).unwrap() };
+        DmaPool::new(memory_object)
+    };
+
+    let mut transport = unsafe { PciTransport::new(mapped_bar.mapped_at) };
+
+    /*
+     * We don't support any offloads, so only need the device to tell us its MAC address (and the
+     * status of its link, if it can).
+     */
+    let features = NetFeatures::from_bits_truncate(transport.begin_init());
+    if !features.contains(NetFeatures::VERSION_1 | NetFeatures::MAC) {
+        panic!("Virtio network device does not support required features: {:?}", features);
+    }
+    let features = features & (NetFeatures::VERSION_1 | NetFeatures::MAC | NetFeatures::STATUS);
+    transport.set_features(features.bits()).expect("Virtio network device did not accept features");
+
+    transport.setup_queue(RECEIVE_QUEUE, &receive_queue, 0);
+    transport.setup_queue(TRANSMIT_QUEUE, &transmit_queue, if transmit_interrupt.is_some() { 1 } else { 0 });
+    transport.finish_init().expect("Virtio device initialization failed");
+
+    let device_cfg = unsafe { transport.device_cfg::<NetDeviceConfig>() };
+    let mac_address = device_cfg.mac.read();
+    info!(
+        "Virtio network device has MAC address {:02x?} (link up: {})",
+        mac_address,
+        !features.contains(NetFeatures::STATUS) || device_cfg.link_up()
+    );
+
+    let net =
+        Arc::new(Spinlock::new(VirtioNet::new(mapped_bar, transport, receive_queue, transmit_queue, buffer_pool)));
+
+    // Add the network device to the Platform Bus
+    let channel = {
+        let device_info = {
+            let mut properties = BTreeMap::new();
+            properties.insert("type".to_string(), Property::String("network".to_string()));
+            properties.insert("net.mac_address".to_string(), Property::Bytes(mac_address.to_vec()));
+            properties.insert("net.mtu".to_string(), Property::Integer(1500));
+            DeviceInfo(properties)
+        };
+        let (channel, channel_handle) = Channel::<NetworkEvent, NetworkRequest>::create().unwrap();
+        let handoff_info = {
+            let mut properties = BTreeMap::new();
+            properties.insert("net.channel".to_string(), HandoffProperty::Channel(channel_handle));
+            HandoffInfo(properties)
+        };
+        platform_bus_bus_client.register_device("virtio-net".to_string(), device_info, handoff_info).unwrap();
+        Arc::new(channel)
+    };
+
+    // Pass received packets on to whoever is using the device
+    std::poplar::rt::spawn({
+        let net = net.clone();
+        let channel = channel.clone();
+        async move {
+            loop {
+                receive_interrupt.wait_for_event().await;
+
+                // TODO: we're sent interrupts for various things - do we need to check??
+                loop {
+                    let Some(frame) = net.lock().receive() else {
+                        break;
+                    };
+                    channel.send(&NetworkEvent::PacketReceived(frame)).unwrap();
+                }
+                receive_interrupt.ack_interrupt();
+            }
+        }
+    });
+
+    if let Some(transmit_interrupt) = transmit_interrupt {
+        std::poplar::rt::spawn({
+            let net = net.clone();
+            async move {
+                loop {
+                    transmit_interrupt.wait_for_event().await;
+                    net.lock().reclaim_transmit_buffers();
+                }
+            }
+        });
+    }
+
+    // Send packets we're asked to
+    std::poplar::rt::spawn(async move {
+        loop {
+            match channel.receive().await.unwrap() {
+                NetworkRequest::SendPacket(frame) => {
+                    if net.lock().send(&frame).is_err() {
+                        warn!("Failed to send packet of length {}. Dropping.", frame.len());
+                    }
+                }
+            }
+        }
+    });
+
+    std::poplar::rt::enter_loop();
+}
+
+pub struct VirtioMemoryManager {
+    area: MappedMemoryObject,
+    offset: AtomicUsize,
+}
+
+impl VirtioMemoryManager {
+    pub fn new(dma_constraints: DmaConstraints) -> VirtioMemoryManager {
+        let (memory_object, _) =
+            MemoryObject::create_dma(0x2000, MemoryObjectFlags::WRITABLE, dma_constraints).unwrap();
+        const QUEUE_AREA_ADDRESS: usize = 0x00000005_10000000;
+        let memory_object = unsafe { memory_object.map_at(QUEUE_AREA_ADDRESS).unwrap() };
+
+        VirtioMemoryManager { area: memory_object, offset: AtomicUsize::new(0) }
+    }
+}
+
+impl virtio::virtqueue::Mapper for VirtioMemoryManager {
+    fn alloc(&self, size: usize) -> (usize, usize) {
+        /*
+         * We allocate two virtqueues from the same area, so round each allocation up to keep them
+         * all aligned to 16 bytes (the strictest alignment required by any part of a virtqueue).
+         */
+        let size = size.next_multiple_of(16);
+        let virt = self.area.mapped_at + self.offset.fetch_add(size, Ordering::Relaxed);
+        (self.area.virt_to_phys(virt).unwrap(), virt)
+    }
+}
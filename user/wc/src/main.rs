@@ -0,0 +1,37 @@
+//! `wc` counts the lines, words, and bytes of its input, and prints them in the form:
+//! ```text
+//! {lines} {words} {bytes}
+//! ```
+//! It's mostly useful at the end of a pipeline (e.g. `top | wc`). When reading from a console, typing Ctrl+D ends
+//! the input.
+
+use std::io::Read;
+
+fn main() {
+    let mut stdin = std::io::stdin();
+    let (mut lines, mut words, mut bytes) = (0, 0, 0);
+    let mut in_word = false;
+
+    let mut buffer = [0; 512];
+    loop {
+        let length = match stdin.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(length) => length,
+        };
+
+        bytes += length;
+        for &byte in &buffer[0..length] {
+            if byte == b'\n' {
+                lines += 1;
+            }
+            if byte.is_ascii_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                words += 1;
+            }
+        }
+    }
+
+    println!("{} {} {}", lines, words, bytes);
+}